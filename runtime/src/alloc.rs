@@ -0,0 +1,447 @@
+//! Allocators over linear memory, of which a module selects one with
+//! `__replica_set_allocator`:
+//!
+//! - `bump`, the default, never returns memory to the host; `__replica_free`
+//!   only reclaims the most recent allocation, which covers the common
+//!   temporary-buffer pattern.
+//! - `freelist` keeps freed blocks on a list and hands them out again to
+//!   allocations that fit.
+//! - `external` forwards both calls to allocation routines the host imports
+//!   into the module, so memory the host already manages is not managed twice.
+//!
+//! Modules may cap the memory the heap grows into and choose whether running out
+//! traps or returns null, see `__replica_set_memory_policy`.
+
+use crate::metrics::{self, METRICS};
+use crate::scheduler::trap;
+use crate::sync::Lock;
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+
+const ALIGN: usize = 8;
+
+/// Allocator a module runs with, numbered as in the ABI handshake
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum AllocatorKind {
+    Bump = 0,
+    FreeList = 1,
+    External = 2,
+}
+
+impl AllocatorKind {
+    pub fn from_raw(raw: u32) -> Option<Self> {
+        match raw {
+            0 => Some(AllocatorKind::Bump),
+            1 => Some(AllocatorKind::FreeList),
+            2 => Some(AllocatorKind::External),
+            _ => None,
+        }
+    }
+}
+
+/// Host routine returning `size` bytes of linear memory aligned to 8, or null
+pub type HostAllocFn = extern "C" fn(usize) -> *mut u8;
+
+/// Host routine taking back a block its allocation routine returned
+pub type HostFreeFn = extern "C" fn(*mut u8);
+
+/// Selected `AllocatorKind`
+static ALLOCATOR: AtomicU32 = AtomicU32::new(AllocatorKind::Bump as u32);
+/// Addresses of the host's routines under the `external` allocator
+static HOST_ALLOC: AtomicUsize = AtomicUsize::new(0);
+static HOST_FREE: AtomicUsize = AtomicUsize::new(0);
+
+/// Next free address; zero until the heap is initialized
+static HEAP_NEXT: AtomicUsize = AtomicUsize::new(0);
+/// End of the memory currently available to the heap
+static HEAP_END: AtomicUsize = AtomicUsize::new(0);
+/// Start of the most recent allocation, for `__replica_free`
+static LAST_ALLOC: AtomicUsize = AtomicUsize::new(0);
+/// Address the heap must not grow past; zero when unlimited
+static MEMORY_LIMIT: AtomicUsize = AtomicUsize::new(0);
+/// Whether exhausting memory traps instead of returning null
+static TRAP_ON_EXHAUSTION: AtomicBool = AtomicBool::new(false);
+
+/// Sets the memory budget: `limit` bytes of linear memory (0 for no limit), and
+/// whether allocations that do not fit trap (`trap != 0`) or return null.
+/// Generated modules call this from a constructor before any actor runs.
+#[no_mangle]
+pub extern "C" fn __replica_set_memory_policy(limit: usize, trap: i32) {
+    MEMORY_LIMIT.store(limit, Ordering::Release);
+    TRAP_ON_EXHAUSTION.store(trap != 0, Ordering::Release);
+}
+
+/// Selects the allocator `__replica_alloc` and `__replica_free` use. `alloc` and
+/// `free` are the host's routines, required by `AllocatorKind::External` and
+/// ignored otherwise. Generated modules call this from the memory policy
+/// constructor; traps for an unknown kind, and when the heap has already been
+/// used by another allocator.
+#[no_mangle]
+pub extern "C" fn __replica_set_allocator(
+    kind: u32,
+    alloc: Option<HostAllocFn>,
+    free: Option<HostFreeFn>,
+) {
+    set_allocator(kind, alloc, free)
+}
+
+fn set_allocator(kind: u32, alloc: Option<HostAllocFn>, free: Option<HostFreeFn>) {
+    let Some(kind) = AllocatorKind::from_raw(kind) else {
+        trap()
+    };
+    // 既に確保したブロックは新しいアロケータでは解放できない
+    if kind != allocator() && HEAP_NEXT.load(Ordering::Acquire) != 0 {
+        trap()
+    }
+    if kind == AllocatorKind::External {
+        let (Some(alloc), Some(free)) = (alloc, free) else {
+            trap()
+        };
+        HOST_ALLOC.store(alloc as usize, Ordering::Release);
+        HOST_FREE.store(free as usize, Ordering::Release);
+    }
+    ALLOCATOR.store(kind as u32, Ordering::Release);
+}
+
+fn allocator() -> AllocatorKind {
+    AllocatorKind::from_raw(ALLOCATOR.load(Ordering::Acquire)).unwrap_or(AllocatorKind::Bump)
+}
+
+fn host_alloc() -> Option<HostAllocFn> {
+    // SAFETY: the address is zero or was stored from a `HostAllocFn`
+    unsafe {
+        core::mem::transmute::<usize, Option<HostAllocFn>>(HOST_ALLOC.load(Ordering::Acquire))
+    }
+}
+
+fn host_free() -> Option<HostFreeFn> {
+    // SAFETY: the address is zero or was stored from a `HostFreeFn`
+    unsafe { core::mem::transmute::<usize, Option<HostFreeFn>>(HOST_FREE.load(Ordering::Acquire)) }
+}
+
+/// Result of an allocation that does not fit in the budget
+fn exhausted() -> *mut u8 {
+    if TRAP_ON_EXHAUSTION.load(Ordering::Acquire) {
+        trap()
+    }
+    core::ptr::null_mut()
+}
+
+#[cfg(target_arch = "wasm32")]
+mod memory {
+    const PAGE_SIZE: usize = 65536;
+
+    /// Returns the current end of linear memory
+    pub fn end() -> usize {
+        core::arch::wasm32::memory_size(0) * PAGE_SIZE
+    }
+
+    /// Grows linear memory so that `needed` bytes past `end` are available
+    pub fn grow(end: usize, needed: usize) -> Option<usize> {
+        let pages = needed.div_ceil(PAGE_SIZE);
+        if core::arch::wasm32::memory_grow(0, pages) == usize::MAX {
+            None
+        } else {
+            Some(end + pages * PAGE_SIZE)
+        }
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+mod memory {
+    use core::ptr::addr_of_mut;
+
+    const ARENA_SIZE: usize = 1 << 20;
+    static mut ARENA: [u8; ARENA_SIZE] = [0; ARENA_SIZE];
+
+    /// Returns the end of the fixed host arena
+    pub fn end() -> usize {
+        addr_of_mut!(ARENA) as usize + ARENA_SIZE
+    }
+
+    /// The host arena cannot grow
+    pub fn grow(_end: usize, _needed: usize) -> Option<usize> {
+        None
+    }
+
+    /// Start of the fixed host arena
+    pub fn start() -> usize {
+        addr_of_mut!(ARENA) as usize
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+extern "C" {
+    /// First address past static data, provided by `wasm-ld`
+    static __heap_base: u8;
+}
+
+fn heap_start() -> usize {
+    #[cfg(target_arch = "wasm32")]
+    {
+        // SAFETY: only the address of the linker-provided symbol is taken
+        unsafe { core::ptr::addr_of!(__heap_base) as usize }
+    }
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        memory::start()
+    }
+}
+
+/// Carves `size` bytes aligned to 8 off the end of the heap, growing linear
+/// memory as needed. `None` when the budget or linear memory is exhausted.
+fn bump(size: usize) -> Option<usize> {
+    if HEAP_NEXT.load(Ordering::Acquire) == 0 {
+        let start = heap_start();
+        HEAP_END.store(memory::end(), Ordering::Release);
+        let _ = HEAP_NEXT.compare_exchange(0, start, Ordering::AcqRel, Ordering::Acquire);
+    }
+
+    loop {
+        let current = HEAP_NEXT.load(Ordering::Acquire);
+        let start = (current + ALIGN - 1) & !(ALIGN - 1);
+        let new_next = start.checked_add(size.max(1))?;
+
+        let limit = MEMORY_LIMIT.load(Ordering::Acquire);
+        if limit != 0 && new_next > limit {
+            return None;
+        }
+
+        let end = HEAP_END.load(Ordering::Acquire);
+        if new_next > end {
+            HEAP_END.store(memory::grow(end, new_next - end)?, Ordering::Release);
+        }
+
+        if HEAP_NEXT
+            .compare_exchange(current, new_next, Ordering::AcqRel, Ordering::Acquire)
+            .is_ok()
+        {
+            metrics::set(&METRICS.heap_used, new_next - heap_start());
+            return Some(start);
+        }
+    }
+}
+
+/// Bytes before every block of the `freelist` allocator, holding the block's
+/// capacity; a multiple of `ALIGN` so that blocks stay aligned
+const HEADER: usize = ALIGN;
+
+/// Blocks released under the `freelist` allocator. A free block's first word
+/// is the address of the next one.
+struct FreeList {
+    lock: Lock,
+    head: UnsafeCell<usize>,
+}
+
+// SAFETY: the list is only accessed while holding `lock`
+unsafe impl Sync for FreeList {}
+
+static FREE_LIST: FreeList = FreeList::new();
+
+impl FreeList {
+    const fn new() -> Self {
+        FreeList {
+            lock: Lock::new(),
+            head: UnsafeCell::new(0),
+        }
+    }
+
+    /// Returns a block of at least `size` bytes: the first free block large
+    /// enough, or a new one taken from `carve`. A free block with room for
+    /// another block after the request is split in two.
+    fn alloc(&self, size: usize, carve: impl FnOnce(usize) -> Option<usize>) -> Option<usize> {
+        let size = size.max(1).checked_add(ALIGN - 1)? & !(ALIGN - 1);
+        // SAFETY: the lock gives exclusive access to the list, whose blocks all
+        // have a header written by an earlier allocation
+        let reused = self.lock.with(|| unsafe {
+            let head = self.head.get();
+            let mut link = head;
+            while *link != 0 {
+                let block = *link;
+                let capacity = *((block - HEADER) as *const usize);
+                if capacity >= size {
+                    *link = *(block as *const usize);
+                    if capacity - size >= HEADER + ALIGN {
+                        // 残りは新しい空きブロックとしてリストに戻す
+                        let rest = block + size + HEADER;
+                        *((rest - HEADER) as *mut usize) = capacity - size - HEADER;
+                        *(rest as *mut usize) = *head;
+                        *head = rest;
+                        *((block - HEADER) as *mut usize) = size;
+                    }
+                    return Some(block);
+                }
+                link = block as *mut usize;
+            }
+            None
+        });
+        if reused.is_some() {
+            return reused;
+        }
+
+        let block = carve(size.checked_add(HEADER)?)? + HEADER;
+        // SAFETY: `carve` returned `HEADER + size` writable bytes
+        unsafe { *((block - HEADER) as *mut usize) = size };
+        Some(block)
+    }
+
+    /// Puts a block `alloc` returned back on the list
+    ///
+    /// # Safety
+    /// `block` must have been returned by `alloc` and not freed since.
+    unsafe fn free(&self, block: usize) {
+        self.lock.with(|| {
+            let head = self.head.get();
+            *(block as *mut usize) = *head;
+            *head = block;
+        })
+    }
+}
+
+/// Allocates `size` bytes aligned to 8 with the selected allocator, returning
+/// null when memory is exhausted
+#[no_mangle]
+pub extern "C" fn __replica_alloc(size: usize) -> *mut u8 {
+    let block = match allocator() {
+        AllocatorKind::Bump => {
+            bump(size).inspect(|&start| LAST_ALLOC.store(start, Ordering::Release))
+        }
+        AllocatorKind::FreeList => FREE_LIST.alloc(size, bump),
+        AllocatorKind::External => host_alloc()
+            .map(|alloc| alloc(size) as usize)
+            .filter(|&start| start != 0),
+    };
+    match block {
+        Some(start) => {
+            metrics::add(&METRICS.allocations, 1);
+            metrics::add(&METRICS.bytes_allocated, size);
+            start as *mut u8
+        }
+        None => exhausted(),
+    }
+}
+
+/// Whether `ptr` points into memory handed out by `__replica_alloc`
+pub fn is_heap(ptr: *const u8) -> bool {
+    let address = ptr as usize;
+    let start = heap_start();
+    match allocator() {
+        // ホストの確保するメモリは静的データより後ろのどこか
+        AllocatorKind::External => address >= start,
+        AllocatorKind::Bump | AllocatorKind::FreeList => {
+            address >= start && address < HEAP_NEXT.load(Ordering::Acquire)
+        }
+    }
+}
+
+/// Releases an allocation. The `bump` allocator only reclaims the most recent
+/// allocation; the others take back any block.
+#[no_mangle]
+pub extern "C" fn __replica_free(ptr: *mut u8) {
+    if ptr.is_null() {
+        return;
+    }
+    match allocator() {
+        AllocatorKind::Bump => rewind(ptr),
+        // SAFETY: `ptr` was returned by `__replica_alloc` under this allocator
+        AllocatorKind::FreeList => unsafe { FREE_LIST.free(ptr as usize) },
+        AllocatorKind::External => {
+            if let Some(free) = host_free() {
+                free(ptr)
+            }
+        }
+    }
+}
+
+/// Reclaims `ptr` under the `bump` allocator if it is the most recent allocation
+fn rewind(ptr: *mut u8) {
+    if LAST_ALLOC
+        .compare_exchange(ptr as usize, 0, Ordering::AcqRel, Ordering::Acquire)
+        .is_ok()
+    {
+        // Only rewind if nothing was allocated after `ptr` in the meantime
+        if HEAP_NEXT
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |next| {
+                (next >= ptr as usize).then_some(ptr as usize)
+            })
+            .is_ok()
+        {
+            metrics::set(&METRICS.heap_used, ptr as usize - heap_start());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_alloc_alignment() {
+        let a = __replica_alloc(3);
+        let b = __replica_alloc(16);
+        assert!(!a.is_null() && !b.is_null());
+        assert_eq!(a as usize % ALIGN, 0);
+        assert_eq!(b as usize % ALIGN, 0);
+        assert_ne!(a, b);
+    }
+
+    #[test]
+    fn test_memory_policy() {
+        // 巨大な要求はデフォルトでは null を返す
+        assert!(__replica_alloc(usize::MAX).is_null());
+
+        __replica_set_memory_policy(0, 1);
+        let trapped = std::panic::catch_unwind(exhausted).is_err();
+        __replica_set_memory_policy(0, 0);
+        assert!(trapped);
+        assert!(exhausted().is_null());
+    }
+
+    #[test]
+    fn test_free_list_reuses_blocks() {
+        #[repr(align(8))]
+        struct Arena([u8; 256]);
+        let mut arena = Arena([0; 256]);
+        let base = arena.0.as_mut_ptr() as usize;
+        let used = core::cell::Cell::new(0);
+        let carve = |size: usize| {
+            let start = base + used.get();
+            used.set(used.get() + size);
+            (used.get() <= 256).then_some(start)
+        };
+        let list = FreeList::new();
+
+        let a = list.alloc(3, carve).unwrap();
+        let b = list.alloc(64, carve).unwrap();
+        assert_eq!((a - base, b - base), (HEADER, 2 * HEADER + 8));
+        assert_eq!(used.get(), 2 * HEADER + 72);
+
+        // 解放したブロックは収まる要求に再利用され、余りは分割される
+        unsafe { list.free(b) };
+        assert_eq!(list.alloc(16, carve), Some(b));
+        let rest = list.alloc(40, carve).unwrap();
+        assert_eq!(rest, b + 16 + HEADER);
+        assert_eq!(used.get(), 2 * HEADER + 72);
+
+        unsafe { list.free(a) };
+        assert_eq!(list.alloc(8, carve), Some(a));
+        assert!(list.alloc(200, carve).is_none());
+    }
+
+    #[test]
+    fn test_allocator_selection() {
+        assert_eq!(AllocatorKind::from_raw(1), Some(AllocatorKind::FreeList));
+        assert_eq!(AllocatorKind::from_raw(3), None);
+
+        // 未知の種類や、ホストの関数がない external は trap する
+        let trapped =
+            |kind, alloc| std::panic::catch_unwind(|| set_allocator(kind, alloc, None)).is_err();
+        extern "C" fn host(_size: usize) -> *mut u8 {
+            core::ptr::null_mut()
+        }
+        assert!(trapped(3, None));
+        assert!(trapped(AllocatorKind::External as u32, Some(host)));
+        assert_eq!(allocator(), AllocatorKind::Bump);
+    }
+}
@@ -0,0 +1,119 @@
+//! Array helpers. Arrays are `{ ptr, len }` pairs of fixed-size elements whose
+//! buffers are copy-on-write objects (see [`crate::rc`]).
+
+use crate::alloc::__replica_alloc;
+use crate::rc::__replica_object_new;
+
+/// Runtime array representation shared with generated code
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct ReplicaArray {
+    pub ptr: *mut u8,
+    pub len: usize,
+}
+
+/// Allocates a zeroed buffer for `len` elements of `elem_size` bytes,
+/// returning null on overflow or allocation failure
+#[no_mangle]
+pub extern "C" fn __replica_array_new(len: usize, elem_size: usize) -> *mut u8 {
+    let bytes = match len.checked_mul(elem_size) {
+        Some(bytes) => bytes,
+        None => return core::ptr::null_mut(),
+    };
+    let ptr = __replica_object_new(bytes, None);
+    if !ptr.is_null() {
+        // SAFETY: the allocation is `bytes` long
+        unsafe { core::ptr::write_bytes(ptr, 0, bytes) };
+    }
+    ptr
+}
+
+/// Returns a pointer to element `index`, or traps when out of bounds
+///
+/// # Safety
+/// `ptr` must point to an array of `len` elements of `elem_size` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn __replica_array_element(
+    ptr: *mut u8,
+    len: usize,
+    index: usize,
+    elem_size: usize,
+) -> *mut u8 {
+    if index >= len {
+        crate::scheduler::trap();
+    }
+    ptr.add(index * elem_size)
+}
+
+/// Allocates a descriptor and a zeroed buffer of `len` elements of
+/// `elem_size` bytes, returning null when memory is exhausted
+#[no_mangle]
+pub extern "C" fn __replica_array_alloc(len: usize, elem_size: usize) -> *mut ReplicaArray {
+    let buffer = __replica_array_new(len, elem_size);
+    let array = __replica_alloc(core::mem::size_of::<ReplicaArray>()) as *mut ReplicaArray;
+    if buffer.is_null() || array.is_null() {
+        return core::ptr::null_mut();
+    }
+    // SAFETY: the allocation is large enough for a descriptor
+    unsafe { array.write(ReplicaArray { ptr: buffer, len }) };
+    array
+}
+
+/// Returns the number of elements of `array`; null is the empty array
+///
+/// # Safety
+/// `array` must be null or point to a valid descriptor.
+#[no_mangle]
+pub unsafe extern "C" fn __replica_array_len(array: *const ReplicaArray) -> usize {
+    if array.is_null() {
+        0
+    } else {
+        (*array).len
+    }
+}
+
+/// Returns a pointer to element `index` of `array`, or traps when out of bounds
+///
+/// # Safety
+/// `array` must be null or point to a valid array of `elem_size`-byte elements.
+#[no_mangle]
+pub unsafe extern "C" fn __replica_array_get(
+    array: *const ReplicaArray,
+    index: usize,
+    elem_size: usize,
+) -> *mut u8 {
+    let len = __replica_array_len(array);
+    if index >= len {
+        crate::scheduler::trap();
+    }
+    __replica_array_element((*array).ptr, len, index, elem_size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_array_elements() {
+        let array = __replica_array_new(4, 4);
+        assert!(!array.is_null());
+        unsafe {
+            let third = __replica_array_element(array, 4, 2, 4) as *mut i32;
+            *third = 42;
+            assert_eq!(*(array as *const i32).add(2), 42);
+        }
+    }
+
+    #[test]
+    fn test_array_descriptors() {
+        let array = __replica_array_alloc(3, 4);
+        assert!(!array.is_null());
+        unsafe {
+            assert_eq!(__replica_array_len(array), 3);
+            assert_eq!(__replica_array_len(core::ptr::null()), 0);
+            let last = __replica_array_get(array, 2, 4) as *mut i32;
+            *last = 7;
+            assert_eq!(*((*array).ptr as *const i32).add(2), 7);
+        }
+    }
+}
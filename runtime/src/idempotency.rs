@@ -0,0 +1,82 @@
+//! Idempotency keys of remote calls.
+//! The `<method>.remote` proxy of an `@idempotent` method sends every call with
+//! a key from the host, keeping it across retries. On the receiving node,
+//! `<method>.deliver` asks whether the key was already delivered before it
+//! queues the call and records the key once the call is queued, so a call the
+//! sender retried after a lost acknowledgement runs only once. The runtime
+//! remembers the last `KEY_CAPACITY` keys, which bounds how late a duplicate
+//! can arrive and still be dropped.
+
+use crate::sync::Lock;
+use core::cell::UnsafeCell;
+
+/// Keys remembered at once; recording one more forgets the oldest
+pub const KEY_CAPACITY: usize = 1024;
+
+struct KeyRing {
+    lock: Lock,
+    /// The keys, the slot the next one goes to and how many slots are used
+    keys: UnsafeCell<([u64; KEY_CAPACITY], usize, usize)>,
+}
+
+// SAFETY: the keys are only accessed while holding `lock`
+unsafe impl Sync for KeyRing {}
+
+impl KeyRing {
+    fn with<R>(&self, f: impl FnOnce(&mut [u64; KEY_CAPACITY], &mut usize, &mut usize) -> R) -> R {
+        // SAFETY: the lock gives exclusive access to the keys
+        self.lock.with(|| {
+            let (keys, next, len) = unsafe { &mut *self.keys.get() };
+            f(keys, next, len)
+        })
+    }
+}
+
+static DELIVERED: KeyRing = KeyRing {
+    lock: Lock::new(),
+    keys: UnsafeCell::new(([0; KEY_CAPACITY], 0, 0)),
+};
+
+/// Returns 1 if a call with `key` was recorded as delivered, 0 otherwise
+#[no_mangle]
+pub extern "C" fn __replica_idempotency_seen(key: u64) -> i32 {
+    DELIVERED.with(|keys, _, len| keys[..*len].contains(&key) as i32)
+}
+
+/// Records that the call with `key` was delivered. Recording a key twice
+/// keeps a single entry.
+#[no_mangle]
+pub extern "C" fn __replica_idempotency_record(key: u64) {
+    DELIVERED.with(|keys, next, len| {
+        if keys[..*len].contains(&key) {
+            return;
+        }
+        keys[*next] = key;
+        *next = (*next + 1) % KEY_CAPACITY;
+        *len = (*len + 1).min(KEY_CAPACITY);
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_recorded_keys_are_seen_until_evicted() {
+        // グローバルな表に触るのはこのテストだけ
+        let base = 0x1d3e_0000_0000;
+        assert_eq!(__replica_idempotency_seen(base), 0);
+        __replica_idempotency_record(base);
+        __replica_idempotency_record(base);
+        assert_eq!(__replica_idempotency_seen(base), 1);
+
+        // 容量を超えると最も古い鍵から忘れる
+        for offset in 1..KEY_CAPACITY as u64 {
+            __replica_idempotency_record(base + offset);
+        }
+        assert_eq!(__replica_idempotency_seen(base), 1);
+        __replica_idempotency_record(base + KEY_CAPACITY as u64);
+        assert_eq!(__replica_idempotency_seen(base), 0);
+        assert_eq!(__replica_idempotency_seen(base + 1), 1);
+    }
+}
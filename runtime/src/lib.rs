@@ -0,0 +1,58 @@
+//! Replica runtime support library.
+//!
+//! Allocation, reference counting, string/array helpers, actor mailboxes, locks, metrics,
+//! random numbers, topic routing, pool member selection, idempotency keys, the actor
+//! registry and the scheduler glue used by compiled Replica modules. The crate is
+//! compiled once per target, e.g.
+//!
+//! ```text
+//! cargo build -p replica-runtime --release --target wasm32-unknown-unknown
+//! ```
+//!
+//! and the resulting `libreplica_runtime.a` is linked into user output instead of
+//! the compiler re-emitting these routines for every build. Compiled modules record
+//! the runtime ABI version they expect, which must match [`RUNTIME_ABI_VERSION`].
+
+#![cfg_attr(target_arch = "wasm32", no_std)]
+
+pub mod alloc;
+pub mod array;
+pub mod idempotency;
+pub mod mailbox;
+pub mod metrics;
+pub mod pool;
+pub mod proto;
+pub mod random;
+pub mod rc;
+pub mod registry;
+pub mod scheduler;
+pub mod string;
+pub mod sync;
+pub mod topic;
+
+/// ABI version implemented by this runtime; bumped on every incompatible change
+pub const RUNTIME_ABI_VERSION: u32 = 3;
+
+/// Function every compiled module exports so hosts can check at instantiation that
+/// the module, the runtime linked into it and the host glue agree on the ABI. It
+/// returns `(allocator << 24) | (module_version << 16) | runtime_version`, where
+/// `allocator` is the module's [`alloc::AllocatorKind`] so that glue can refuse a
+/// module whose allocator it cannot serve.
+pub const ABI_HANDSHAKE_EXPORT: &str = "__replica_abi_handshake";
+
+/// Function every compiled module exports to run its start-up initialization:
+/// the memory policy, then the registration of its actors, imported modules
+/// first. Hosts call it once after the handshake, before any other export.
+pub const INIT_EXPORT: &str = "__replica_init";
+
+/// Returns the runtime ABI version so hosts and modules can verify compatibility
+#[no_mangle]
+pub extern "C" fn __replica_runtime_abi_version() -> u32 {
+    RUNTIME_ABI_VERSION
+}
+
+#[cfg(target_arch = "wasm32")]
+#[panic_handler]
+fn panic(_info: &core::panic::PanicInfo) -> ! {
+    core::arch::wasm32::unreachable()
+}
@@ -0,0 +1,184 @@
+//! Actor mailboxes: fixed-capacity FIFO queues of messages.
+//! Every operation holds the mailbox's lock, so workers may post to the same
+//! actor concurrently when modules share memory.
+
+use crate::alloc::__replica_alloc;
+use crate::metrics::{self, MailboxMetrics, METRICS};
+use crate::sync::Lock;
+
+/// A message queued for an actor: the method tag plus serialized arguments
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct Message {
+    pub tag: u32,
+    pub payload: *mut u8,
+    pub len: usize,
+}
+
+/// Ring buffer of messages, followed in memory by its slots
+#[repr(C)]
+pub struct Mailbox {
+    lock: Lock,
+    head: usize,
+    len: usize,
+    capacity: usize,
+    /// Actor state pointer passed to the dispatch function
+    pub actor: *mut u8,
+    metrics: MailboxMetrics,
+}
+
+impl Mailbox {
+    /// Messages are accessed through raw pointers so that workers waiting on
+    /// `lock` never alias a mutable reference to the header
+    unsafe fn slots(mailbox: *mut Mailbox) -> *mut Message {
+        // SAFETY: slots are allocated directly after the header by `__replica_mailbox_new`
+        mailbox.add(1) as *mut Message
+    }
+}
+
+/// Allocates a mailbox for `actor` holding up to `capacity` messages
+#[no_mangle]
+pub extern "C" fn __replica_mailbox_new(actor: *mut u8, capacity: usize) -> *mut Mailbox {
+    let size = core::mem::size_of::<Mailbox>() + capacity * core::mem::size_of::<Message>();
+    let mailbox = __replica_alloc(size) as *mut Mailbox;
+    if !mailbox.is_null() {
+        // SAFETY: the allocation is large enough for the header
+        unsafe {
+            mailbox.write(Mailbox {
+                lock: Lock::new(),
+                head: 0,
+                len: 0,
+                capacity,
+                actor,
+                metrics: MailboxMetrics::default(),
+            })
+        };
+    }
+    mailbox
+}
+
+/// Enqueues a message, returning 0 on success and -1 when the mailbox is full
+///
+/// # Safety
+/// `mailbox` must come from `__replica_mailbox_new`.
+#[no_mangle]
+pub unsafe extern "C" fn __replica_mailbox_push(
+    mailbox: *mut Mailbox,
+    tag: u32,
+    payload: *mut u8,
+    len: usize,
+) -> i32 {
+    let message = Message { tag, payload, len };
+    (*mailbox).lock.acquire();
+    let result = if (*mailbox).len == (*mailbox).capacity {
+        metrics::add(&METRICS.messages_rejected, 1);
+        -1
+    } else {
+        let index = ((*mailbox).head + (*mailbox).len) % (*mailbox).capacity;
+        Mailbox::slots(mailbox).add(index).write(message);
+        (*mailbox).len += 1;
+        let depth = (*mailbox).len as u32;
+        (*mailbox).metrics.peak_depth = (*mailbox).metrics.peak_depth.max(depth);
+        metrics::add(&METRICS.messages_enqueued, 1);
+        0
+    };
+    (*mailbox).lock.release();
+    result
+}
+
+/// Dequeues the oldest message into `out`, returning 0 on success and -1 when empty
+///
+/// # Safety
+/// `mailbox` must come from `__replica_mailbox_new` and `out` must be writable.
+#[no_mangle]
+pub unsafe extern "C" fn __replica_mailbox_pop(mailbox: *mut Mailbox, out: *mut Message) -> i32 {
+    (*mailbox).lock.acquire();
+    let result = if (*mailbox).len == 0 {
+        -1
+    } else {
+        out.write(Mailbox::slots(mailbox).add((*mailbox).head).read());
+        (*mailbox).head = ((*mailbox).head + 1) % (*mailbox).capacity;
+        (*mailbox).len -= 1;
+        (*mailbox).metrics.processed = (*mailbox).metrics.processed.wrapping_add(1);
+        metrics::add(&METRICS.messages_processed, 1);
+        0
+    };
+    (*mailbox).lock.release();
+    result
+}
+
+/// Number of queued messages
+///
+/// # Safety
+/// `mailbox` must come from `__replica_mailbox_new`.
+#[no_mangle]
+pub unsafe extern "C" fn __replica_mailbox_len(mailbox: *const Mailbox) -> usize {
+    (*mailbox).lock.with(|| (*mailbox).len)
+}
+
+/// Returns the address of the mailbox's counters, which stay valid as long as
+/// the mailbox itself
+///
+/// # Safety
+/// `mailbox` must come from `__replica_mailbox_new`.
+#[no_mangle]
+pub unsafe extern "C" fn replica_mailbox_metrics(mailbox: *const Mailbox) -> *const MailboxMetrics {
+    core::ptr::addr_of!((*mailbox).metrics)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn push(mailbox: *mut Mailbox, tag: u32) -> i32 {
+        unsafe { __replica_mailbox_push(mailbox, tag, core::ptr::null_mut(), 0) }
+    }
+
+    #[test]
+    fn test_mailbox_fifo() {
+        let mailbox = __replica_mailbox_new(core::ptr::null_mut(), 2);
+        unsafe {
+            assert_eq!(push(mailbox, 1), 0);
+            assert_eq!(push(mailbox, 2), 0);
+            assert_eq!(push(mailbox, 3), -1);
+            assert_eq!(__replica_mailbox_len(mailbox), 2);
+
+            let mut out = Message {
+                tag: 0,
+                payload: core::ptr::null_mut(),
+                len: 0,
+            };
+            assert_eq!(__replica_mailbox_pop(mailbox, &mut out), 0);
+            assert_eq!(out.tag, 1);
+            assert_eq!(push(mailbox, 3), 0);
+            assert_eq!(__replica_mailbox_pop(mailbox, &mut out), 0);
+            assert_eq!(out.tag, 2);
+            assert_eq!(__replica_mailbox_pop(mailbox, &mut out), 0);
+            assert_eq!(out.tag, 3);
+            assert_eq!(__replica_mailbox_pop(mailbox, &mut out), -1);
+
+            let metrics = *replica_mailbox_metrics(mailbox);
+            assert_eq!(metrics.processed, 3);
+            assert_eq!(metrics.peak_depth, 2);
+        }
+    }
+
+    #[test]
+    fn test_concurrent_push() {
+        let mailbox = __replica_mailbox_new(core::ptr::null_mut(), 400) as usize;
+        let workers: Vec<_> = (0..4)
+            .map(|_| {
+                std::thread::spawn(move || {
+                    (0..100).all(|tag| push(mailbox as *mut Mailbox, tag) == 0)
+                })
+            })
+            .collect();
+        for worker in workers {
+            assert!(worker.join().unwrap());
+        }
+        unsafe {
+            assert_eq!(__replica_mailbox_len(mailbox as *const Mailbox), 400);
+            assert_eq!(push(mailbox as *mut Mailbox, 0), -1);
+        }
+    }
+}
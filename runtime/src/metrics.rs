@@ -0,0 +1,95 @@
+//! Counters hosts can scrape without custom instrumentation.
+//! `replica_metrics` returns the address of a static, versioned struct that the
+//! runtime updates in place, so a host reads it straight out of linear memory.
+//! Per-actor counters live in each mailbox header and are returned by
+//! `replica_mailbox_metrics`.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+/// Layout version of [`Metrics`] and [`MailboxMetrics`]; bumped whenever a field
+/// is added, removed or reordered
+pub const METRICS_VERSION: u32 = 1;
+
+/// Module-wide counters. All fields are little-endian `u32`s.
+#[repr(C)]
+pub struct Metrics {
+    /// Always [`METRICS_VERSION`]
+    pub version: u32,
+    /// Size of this struct in bytes
+    pub size: u32,
+    /// Messages accepted by any mailbox
+    pub messages_enqueued: AtomicU32,
+    /// Messages taken out of a mailbox for dispatch
+    pub messages_processed: AtomicU32,
+    /// Messages rejected because the mailbox was full
+    pub messages_rejected: AtomicU32,
+    /// Successful calls to `__replica_alloc`
+    pub allocations: AtomicU32,
+    /// Bytes requested from `__replica_alloc` over the module's lifetime
+    pub bytes_allocated: AtomicU32,
+    /// Bytes between the heap start and the next free address
+    pub heap_used: AtomicU32,
+    /// Reference-counted objects whose strong count has not yet dropped to zero
+    pub live_objects: AtomicU32,
+}
+
+/// Counters of a single actor's mailbox
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct MailboxMetrics {
+    /// Messages taken out of this mailbox for dispatch
+    pub processed: u32,
+    /// Largest number of messages queued at once
+    pub peak_depth: u32,
+}
+
+pub static METRICS: Metrics = Metrics {
+    version: METRICS_VERSION,
+    size: core::mem::size_of::<Metrics>() as u32,
+    messages_enqueued: AtomicU32::new(0),
+    messages_processed: AtomicU32::new(0),
+    messages_rejected: AtomicU32::new(0),
+    allocations: AtomicU32::new(0),
+    bytes_allocated: AtomicU32::new(0),
+    heap_used: AtomicU32::new(0),
+    live_objects: AtomicU32::new(0),
+};
+
+/// Adds `amount` to a counter, wrapping on overflow
+pub fn add(counter: &AtomicU32, amount: usize) {
+    counter.fetch_add(amount as u32, Ordering::Relaxed);
+}
+
+/// Subtracts one from a counter
+pub fn decrement(counter: &AtomicU32) {
+    counter.fetch_sub(1, Ordering::Relaxed);
+}
+
+/// Sets a gauge such as `heap_used`
+pub fn set(counter: &AtomicU32, value: usize) {
+    counter.store(value as u32, Ordering::Relaxed);
+}
+
+/// Returns the address of the module-wide metrics struct
+#[no_mangle]
+pub extern "C" fn replica_metrics() -> *const Metrics {
+    &METRICS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metrics_header() {
+        let metrics = unsafe { &*replica_metrics() };
+        assert_eq!(metrics.version, METRICS_VERSION);
+        assert_eq!(metrics.size as usize, 9 * 4);
+
+        let before = metrics.allocations.load(Ordering::Relaxed);
+        assert!(!crate::alloc::__replica_alloc(24).is_null());
+        // 他のテストも並行して割り当てるので増加量の下限だけを確認する
+        assert!(metrics.allocations.load(Ordering::Relaxed) - before >= 1);
+        assert!(metrics.heap_used.load(Ordering::Relaxed) >= 24);
+    }
+}
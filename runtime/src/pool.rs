@@ -0,0 +1,76 @@
+//! Member selection for actor pools.
+//! A `pool` router forwards every call to one of its instances. Round-robin
+//! routers count calls themselves; consistent-hash routers reduce the key
+//! parameter to 64 bits (directly for an `Int`, with `__replica_string_hash` for
+//! a `String`) and pick the instance with `__replica_jump_hash`, so calls with
+//! the same key always reach the same instance.
+
+use crate::string::ReplicaString;
+
+/// FNV-1a offset basis and prime for 64-bit hashes
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+/// Hashes the bytes of a `String` with 64-bit FNV-1a
+///
+/// # Safety
+/// `string` must point to a valid `String` descriptor.
+#[no_mangle]
+pub unsafe extern "C" fn __replica_string_hash(string: *const ReplicaString) -> u64 {
+    let string = *string;
+    fnv1a(core::slice::from_raw_parts(string.ptr, string.len))
+}
+
+/// 64-bit FNV-1a hash of `bytes`, which the compiler also uses to sort the
+/// `String` keys of constant maps
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(FNV_OFFSET, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(FNV_PRIME)
+    })
+}
+
+/// Maps `key` to a bucket in `0..buckets` with Lamping and Veach's jump
+/// consistent hash: growing a pool by one instance moves only a `1/buckets`
+/// share of the keys. Returns 0 if `buckets` is not positive.
+#[no_mangle]
+pub extern "C" fn __replica_jump_hash(key: u64, buckets: i32) -> i32 {
+    let mut key = key;
+    let (mut bucket, mut next) = (-1i64, 0i64);
+    while next < buckets as i64 {
+        bucket = next;
+        key = key.wrapping_mul(2_862_933_555_777_941_757).wrapping_add(1);
+        next = ((bucket + 1) as f64 * ((1u64 << 31) as f64 / ((key >> 33) + 1) as f64)) as i64;
+    }
+    bucket.max(0) as i32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_jump_hash_is_stable_and_consistent() {
+        assert_eq!(__replica_jump_hash(42, 0), 0);
+        for key in 0..1000u64 {
+            let bucket = __replica_jump_hash(key, 4);
+            assert!((0..4).contains(&bucket));
+            assert_eq!(__replica_jump_hash(key, 4), bucket);
+            // 一つ増やしたときに動くキーは新しいインスタンスへ移るだけ
+            let grown = __replica_jump_hash(key, 5);
+            assert!(grown == bucket || grown == 4);
+        }
+    }
+
+    #[test]
+    fn test_string_hash_is_fnv1a() {
+        let hash = |text: &str| unsafe {
+            __replica_string_hash(&ReplicaString {
+                ptr: text.as_ptr(),
+                len: text.len(),
+            })
+        };
+        assert_eq!(hash(""), FNV_OFFSET);
+        // FNV-1a の参照値
+        assert_eq!(hash("a"), 0xaf63_dc4c_8601_ec8c);
+    }
+}
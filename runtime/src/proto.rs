@@ -0,0 +1,751 @@
+//! Protocol Buffers wire format for records imported with `replicac import-proto`.
+//! The compiler describes the layout of each message record with a
+//! [`MessageDescriptor`] in static data; the codec walks the descriptor to encode a
+//! record in linear memory and to decode bytes into one. Encoded messages are
+//! `[Int]` arrays holding one byte per element, since Replica has no byte type.
+
+use crate::alloc::{__replica_alloc, __replica_free};
+use crate::array::{__replica_array_alloc, ReplicaArray};
+use crate::rc::__replica_object_new;
+use crate::string::ReplicaString;
+
+/// Layout of a message record
+#[repr(C)]
+pub struct MessageDescriptor {
+    /// Size of the record in bytes
+    pub size: u32,
+    pub field_count: u32,
+    pub fields: *const FieldDescriptor,
+}
+
+// 記述子はコンパイラが静的データとして出力し、書き換えられることはない
+unsafe impl Sync for MessageDescriptor {}
+
+/// One field of a message record
+#[repr(C)]
+pub struct FieldDescriptor {
+    /// Field number on the wire
+    pub number: u32,
+    /// One of the `KIND_*` constants, or'ed with [`REPEATED`] for `[T]` fields
+    pub kind: u32,
+    /// Byte offset of the field in the record
+    pub offset: u32,
+    /// Record layout of a `KIND_MESSAGE` field, null otherwise
+    pub message: *const MessageDescriptor,
+}
+
+/// `int32` and enums: `Int`, sign-extended varint
+pub const KIND_INT32: u32 = 0;
+/// `sint32`: `Int`, zigzag varint
+pub const KIND_SINT32: u32 = 1;
+/// `uint32`: `Int` holding the bits of the unsigned value, varint
+pub const KIND_UINT32: u32 = 2;
+/// `fixed32`: `Int` holding the bits of the unsigned value
+pub const KIND_FIXED32: u32 = 3;
+/// `sfixed32`: `Int`
+pub const KIND_SFIXED32: u32 = 4;
+/// `bool`: `Bool`
+pub const KIND_BOOL: u32 = 5;
+/// `float`: `Float`, narrowed to 32 bits on the wire
+pub const KIND_FLOAT: u32 = 6;
+/// `double`: `Float`
+pub const KIND_DOUBLE: u32 = 7;
+/// `string`: `String`
+pub const KIND_STRING: u32 = 8;
+/// `bytes`: `[Int]` with one byte per element
+pub const KIND_BYTES: u32 = 9;
+/// Embedded message: a record stored inline
+pub const KIND_MESSAGE: u32 = 10;
+/// Flag marking a `repeated` field, stored as `[T]`
+pub const REPEATED: u32 = 0x100;
+
+const WIRE_VARINT: u32 = 0;
+const WIRE_FIXED64: u32 = 1;
+const WIRE_LEN: u32 = 2;
+const WIRE_FIXED32: u32 = 5;
+
+const POINTER_SIZE: usize = core::mem::size_of::<usize>();
+
+impl FieldDescriptor {
+    fn base_kind(&self) -> u32 {
+        self.kind & !REPEATED
+    }
+
+    fn is_repeated(&self) -> bool {
+        self.kind & REPEATED != 0
+    }
+
+    fn wire_type(&self) -> u32 {
+        match self.base_kind() {
+            KIND_INT32 | KIND_SINT32 | KIND_UINT32 | KIND_BOOL => WIRE_VARINT,
+            KIND_FIXED32 | KIND_SFIXED32 | KIND_FLOAT => WIRE_FIXED32,
+            KIND_DOUBLE => WIRE_FIXED64,
+            _ => WIRE_LEN,
+        }
+    }
+
+    /// Size of one value in memory, which is the element size of repeated fields
+    ///
+    /// # Safety
+    /// `message` must be valid for `KIND_MESSAGE` fields.
+    unsafe fn value_size(&self) -> usize {
+        match self.base_kind() {
+            KIND_BOOL => 1,
+            KIND_FLOAT | KIND_DOUBLE => 8,
+            KIND_STRING | KIND_BYTES => POINTER_SIZE,
+            KIND_MESSAGE => (*self.message).size as usize,
+            _ => 4,
+        }
+    }
+}
+
+impl MessageDescriptor {
+    /// # Safety
+    /// `fields` must point to `field_count` descriptors.
+    unsafe fn fields(&self) -> &[FieldDescriptor] {
+        if self.field_count == 0 {
+            return &[];
+        }
+        core::slice::from_raw_parts(self.fields, self.field_count as usize)
+    }
+}
+
+/// Output of the encoder. Without a buffer it only counts bytes, which is how
+/// the lengths of embedded messages and packed fields are found.
+struct Writer {
+    out: *mut i32,
+    len: usize,
+}
+
+impl Writer {
+    fn counting() -> Self {
+        Writer {
+            out: core::ptr::null_mut(),
+            len: 0,
+        }
+    }
+
+    fn byte(&mut self, byte: u8) {
+        if !self.out.is_null() {
+            // SAFETY: the buffer was sized by a counting pass over the same input
+            unsafe { self.out.add(self.len).write(byte as i32) };
+        }
+        self.len += 1;
+    }
+
+    fn varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.byte(value as u8 | 0x80);
+            value >>= 7;
+        }
+        self.byte(value as u8);
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.byte(byte);
+        }
+    }
+
+    fn tag(&mut self, number: u32, wire_type: u32) {
+        self.varint(((number as u64) << 3) | wire_type as u64);
+    }
+}
+
+/// Encodes the value at `value` without its tag
+///
+/// # Safety
+/// `value` must hold a value of the field's kind.
+unsafe fn encode_value(writer: &mut Writer, field: &FieldDescriptor, value: *const u8) {
+    match field.base_kind() {
+        KIND_INT32 => writer.varint(*(value as *const i32) as i64 as u64),
+        KIND_SINT32 => {
+            let n = *(value as *const i32);
+            writer.varint(((n << 1) ^ (n >> 31)) as u32 as u64);
+        }
+        KIND_UINT32 => writer.varint(*(value as *const i32) as u32 as u64),
+        KIND_FIXED32 | KIND_SFIXED32 => writer.bytes(&(*(value as *const i32)).to_le_bytes()),
+        KIND_BOOL => writer.byte((*value != 0) as u8),
+        KIND_FLOAT => writer.bytes(&(*(value as *const f64) as f32).to_le_bytes()),
+        KIND_DOUBLE => writer.bytes(&(*(value as *const f64)).to_le_bytes()),
+        KIND_STRING => {
+            let string = *(value as *const *const ReplicaString);
+            let bytes = string_bytes(string);
+            writer.varint(bytes.len() as u64);
+            writer.bytes(bytes);
+        }
+        KIND_BYTES => {
+            let array = *(value as *const *const ReplicaArray);
+            let elements = array_elements::<i32>(array);
+            writer.varint(elements.len() as u64);
+            for &byte in elements {
+                writer.byte(byte as u8);
+            }
+        }
+        KIND_MESSAGE => {
+            writer.varint(message_size(&*field.message, value) as u64);
+            encode_message(writer, &*field.message, value);
+        }
+        _ => {}
+    }
+}
+
+/// Whether a singular field holds its default value, which proto3 leaves out
+///
+/// # Safety
+/// `value` must hold a value of the field's kind.
+unsafe fn is_default(field: &FieldDescriptor, value: *const u8) -> bool {
+    match field.base_kind() {
+        KIND_BOOL => *value == 0,
+        KIND_FLOAT | KIND_DOUBLE => (*(value as *const f64)).to_bits() == 0,
+        KIND_STRING => string_bytes(*(value as *const *const ReplicaString)).is_empty(),
+        KIND_BYTES => array_elements::<i32>(*(value as *const *const ReplicaArray)).is_empty(),
+        KIND_MESSAGE => message_size(&*field.message, value) == 0,
+        _ => *(value as *const i32) == 0,
+    }
+}
+
+/// # Safety
+/// `record` must point to a record laid out as `message` describes.
+unsafe fn encode_message(writer: &mut Writer, message: &MessageDescriptor, record: *const u8) {
+    for field in message.fields() {
+        let slot = record.add(field.offset as usize);
+        if !field.is_repeated() {
+            if !is_default(field, slot) {
+                writer.tag(field.number, field.wire_type());
+                encode_value(writer, field, slot);
+            }
+            continue;
+        }
+
+        let array = *(slot as *const *const ReplicaArray);
+        if array.is_null() || (*array).len == 0 {
+            continue;
+        }
+        let size = field.value_size();
+        let elements = (0..(*array).len).map(|index| (*array).ptr.add(index * size) as *const u8);
+        if field.wire_type() == WIRE_LEN {
+            for element in elements {
+                writer.tag(field.number, WIRE_LEN);
+                encode_value(writer, field, element);
+            }
+        } else {
+            // スカラーの繰り返しは packed 形式で書き出す
+            let mut counter = Writer::counting();
+            for element in elements.clone() {
+                encode_value(&mut counter, field, element);
+            }
+            writer.tag(field.number, WIRE_LEN);
+            writer.varint(counter.len as u64);
+            for element in elements {
+                encode_value(writer, field, element);
+            }
+        }
+    }
+}
+
+/// # Safety
+/// `record` must point to a record laid out as `message` describes.
+unsafe fn message_size(message: &MessageDescriptor, record: *const u8) -> usize {
+    let mut counter = Writer::counting();
+    encode_message(&mut counter, message, record);
+    counter.len
+}
+
+/// # Safety
+/// `string` must be null or point to a valid string.
+unsafe fn string_bytes<'a>(string: *const ReplicaString) -> &'a [u8] {
+    if string.is_null() || (*string).len == 0 {
+        return &[];
+    }
+    core::slice::from_raw_parts((*string).ptr, (*string).len)
+}
+
+/// # Safety
+/// `array` must be null or point to a valid array of `T`.
+unsafe fn array_elements<'a, T>(array: *const ReplicaArray) -> &'a [T] {
+    if array.is_null() || (*array).len == 0 {
+        return &[];
+    }
+    core::slice::from_raw_parts((*array).ptr as *const T, (*array).len)
+}
+
+/// Encodes the record at `record`, returning a `[Int]` of bytes or null when
+/// memory is exhausted
+///
+/// # Safety
+/// `message` must describe the layout of the record at `record`.
+#[no_mangle]
+pub unsafe extern "C" fn __replica_proto_encode(
+    message: *const MessageDescriptor,
+    record: *const u8,
+) -> *mut ReplicaArray {
+    let message = &*message;
+    let array = __replica_array_alloc(message_size(message, record), 4);
+    if !array.is_null() {
+        let mut writer = Writer {
+            out: (*array).ptr as *mut i32,
+            len: 0,
+        };
+        encode_message(&mut writer, message, record);
+    }
+    array
+}
+
+/// Encoded bytes being decoded, one byte per `[Int]` element
+#[derive(Clone, Copy)]
+struct Reader<'a> {
+    bytes: &'a [i32],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn at_end(&self) -> bool {
+        self.pos >= self.bytes.len()
+    }
+
+    fn byte(&mut self) -> Option<u8> {
+        let value = *self.bytes.get(self.pos)?;
+        self.pos += 1;
+        u8::try_from(value).ok()
+    }
+
+    fn varint(&mut self) -> Option<u64> {
+        let mut value = 0u64;
+        for shift in (0..70).step_by(7) {
+            let byte = self.byte()?;
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Some(value);
+            }
+        }
+        None
+    }
+
+    fn fixed<const N: usize>(&mut self) -> Option<[u8; N]> {
+        let mut bytes = [0u8; N];
+        for byte in &mut bytes {
+            *byte = self.byte()?;
+        }
+        Some(bytes)
+    }
+
+    /// Splits off the next `len` bytes
+    fn take(&mut self, len: usize) -> Option<Reader<'a>> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|&end| end <= self.bytes.len())?;
+        let taken = Reader {
+            bytes: &self.bytes[self.pos..end],
+            pos: 0,
+        };
+        self.pos = end;
+        Some(taken)
+    }
+
+    fn skip(&mut self, wire_type: u32) -> Option<()> {
+        match wire_type {
+            WIRE_VARINT => self.varint().map(drop),
+            WIRE_FIXED64 => self.take(8).map(drop),
+            WIRE_LEN => {
+                let len = self.varint()? as usize;
+                self.take(len).map(drop)
+            }
+            WIRE_FIXED32 => self.take(4).map(drop),
+            _ => None,
+        }
+    }
+}
+
+/// Decodes one value of the field's kind into `slot`
+///
+/// # Safety
+/// `slot` must be writable and sized for a value of the field's kind.
+unsafe fn decode_value(reader: &mut Reader, field: &FieldDescriptor, slot: *mut u8) -> Option<()> {
+    match field.base_kind() {
+        KIND_INT32 | KIND_UINT32 => (slot as *mut i32).write(reader.varint()? as i32),
+        KIND_SINT32 => {
+            let n = reader.varint()? as u32;
+            (slot as *mut i32).write((n >> 1) as i32 ^ -((n & 1) as i32));
+        }
+        KIND_FIXED32 | KIND_SFIXED32 => {
+            (slot as *mut i32).write(i32::from_le_bytes(reader.fixed()?))
+        }
+        KIND_BOOL => slot.write((reader.varint()? != 0) as u8),
+        KIND_FLOAT => (slot as *mut f64).write(f32::from_le_bytes(reader.fixed()?) as f64),
+        KIND_DOUBLE => (slot as *mut f64).write(f64::from_le_bytes(reader.fixed()?)),
+        KIND_STRING => {
+            let len = reader.varint()? as usize;
+            let payload = reader.take(len)?;
+            let buffer = __replica_object_new(len, None);
+            let string =
+                __replica_alloc(core::mem::size_of::<ReplicaString>()) as *mut ReplicaString;
+            if buffer.is_null() || string.is_null() {
+                return None;
+            }
+            for (index, &byte) in payload.bytes.iter().enumerate() {
+                buffer.add(index).write(u8::try_from(byte).ok()?);
+            }
+            core::str::from_utf8(core::slice::from_raw_parts(buffer, len)).ok()?;
+            string.write(ReplicaString { ptr: buffer, len });
+            (slot as *mut *mut ReplicaString).write(string);
+        }
+        KIND_BYTES => {
+            let len = reader.varint()? as usize;
+            let payload = reader.take(len)?;
+            let array = __replica_array_alloc(len, 4);
+            if array.is_null() {
+                return None;
+            }
+            for (index, &byte) in payload.bytes.iter().enumerate() {
+                ((*array).ptr as *mut i32)
+                    .add(index)
+                    .write(u8::try_from(byte).ok()? as i32);
+            }
+            (slot as *mut *mut ReplicaArray).write(array);
+        }
+        KIND_MESSAGE => {
+            let len = reader.varint()? as usize;
+            decode_message(&*field.message, reader.take(len)?, slot)?;
+        }
+        _ => return None,
+    }
+    Some(())
+}
+
+/// Number of values in a packed run
+fn packed_count(field: &FieldDescriptor, mut payload: Reader) -> Option<usize> {
+    match field.wire_type() {
+        WIRE_FIXED32 | WIRE_FIXED64 => {
+            let size = if field.wire_type() == WIRE_FIXED32 {
+                4
+            } else {
+                8
+            };
+            let len = payload.bytes.len();
+            len.is_multiple_of(size).then_some(len / size)
+        }
+        _ => {
+            let mut count = 0;
+            while !payload.at_end() {
+                payload.varint()?;
+                count += 1;
+            }
+            Some(count)
+        }
+    }
+}
+
+/// Decodes `reader` into the record at `record`, merging into the values already
+/// there. Repeated fields are counted in a first pass so their arrays are
+/// allocated once.
+///
+/// # Safety
+/// `record` must point to a writable record laid out as `message` describes.
+unsafe fn decode_message(
+    message: &MessageDescriptor,
+    reader: Reader,
+    record: *mut u8,
+) -> Option<()> {
+    let fields = message.fields();
+    let field_for = |number: u32| fields.iter().find(|field| field.number == number);
+
+    // 1 回目: 繰り返しフィールドの要素数を数える
+    let mut counts = core::ptr::null_mut::<usize>();
+    if fields.iter().any(FieldDescriptor::is_repeated) {
+        counts = __replica_alloc(fields.len() * core::mem::size_of::<usize>()) as *mut usize;
+        if counts.is_null() {
+            return None;
+        }
+        core::ptr::write_bytes(counts, 0, fields.len());
+    }
+    let counted = (|| {
+        let mut scan = reader;
+        while !scan.at_end() {
+            let key = scan.varint()?;
+            let (number, wire_type) = ((key >> 3) as u32, (key & 7) as u32);
+            let index = fields.iter().position(|field| field.number == number);
+            match index.map(|index| (index, &fields[index])) {
+                Some((index, field)) if field.is_repeated() => {
+                    if wire_type == WIRE_LEN && field.wire_type() != WIRE_LEN {
+                        let len = scan.varint()? as usize;
+                        *counts.add(index) += packed_count(field, scan.take(len)?)?;
+                    } else {
+                        scan.skip(wire_type)?;
+                        *counts.add(index) += 1;
+                    }
+                }
+                _ => scan.skip(wire_type)?,
+            }
+        }
+        Some(())
+    })();
+
+    let decoded = counted.and_then(|()| {
+        for (index, field) in fields.iter().enumerate() {
+            if field.is_repeated() && *counts.add(index) > 0 {
+                let array = __replica_array_alloc(*counts.add(index), field.value_size());
+                if array.is_null() {
+                    return None;
+                }
+                // 要素は 2 回目で先頭から詰める
+                (*array).len = 0;
+                (record.add(field.offset as usize) as *mut *mut ReplicaArray).write(array);
+            }
+        }
+
+        // 2 回目: 値を読み込む
+        let mut reader = reader;
+        while !reader.at_end() {
+            let key = reader.varint()?;
+            let (number, wire_type) = ((key >> 3) as u32, (key & 7) as u32);
+            let Some(field) = field_for(number) else {
+                reader.skip(wire_type)?;
+                continue;
+            };
+            let slot = record.add(field.offset as usize);
+            if !field.is_repeated() {
+                if wire_type != field.wire_type() {
+                    return None;
+                }
+                decode_value(&mut reader, field, slot)?;
+                continue;
+            }
+
+            let array = *(slot as *const *mut ReplicaArray);
+            let size = field.value_size();
+            let push = |reader: &mut Reader| {
+                decode_value(reader, field, (*array).ptr.add((*array).len * size))?;
+                (*array).len += 1;
+                Some(())
+            };
+            if wire_type == WIRE_LEN && field.wire_type() != WIRE_LEN {
+                let len = reader.varint()? as usize;
+                let mut payload = reader.take(len)?;
+                while !payload.at_end() {
+                    push(&mut payload)?;
+                }
+            } else if wire_type == field.wire_type() {
+                push(&mut reader)?;
+            } else {
+                return None;
+            }
+        }
+        Some(())
+    });
+
+    if fields.iter().any(FieldDescriptor::is_repeated) {
+        __replica_free(counts as *mut u8);
+    }
+    decoded
+}
+
+/// Decodes `bytes`, a `[Int]` of bytes, into the record at `record`, returning 1
+/// on success and 0 on malformed input or exhausted memory. Fields missing from
+/// the input keep their zero value; strings and arrays allocated before an error
+/// is found are not released.
+///
+/// # Safety
+/// `message` must describe the layout of the writable record at `record`, and
+/// `bytes` must point to a valid array.
+#[no_mangle]
+pub unsafe extern "C" fn __replica_proto_decode(
+    message: *const MessageDescriptor,
+    bytes: *const ReplicaArray,
+    record: *mut u8,
+) -> i32 {
+    let message = &*message;
+    core::ptr::write_bytes(record, 0, message.size as usize);
+    let reader = Reader {
+        bytes: array_elements(bytes),
+        pos: 0,
+    };
+    decode_message(message, reader, record).is_some() as i32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::array::__replica_array_new;
+    use core::mem::{offset_of, size_of};
+    use core::ptr::null;
+
+    /// `message Point { sint32 x = 1; sint32 y = 2; }`
+    #[repr(C)]
+    struct Point {
+        x: i32,
+        y: i32,
+    }
+
+    /// `message Shape { string name = 1; repeated int32 sides = 2; Point origin = 3;
+    /// repeated Point corners = 4; double area = 5; bool filled = 6; }`
+    #[repr(C)]
+    struct Shape {
+        name: *mut ReplicaString,
+        sides: *mut ReplicaArray,
+        origin: Point,
+        corners: *mut ReplicaArray,
+        area: f64,
+        filled: u8,
+    }
+
+    const POINT_FIELDS: [FieldDescriptor; 2] = [
+        FieldDescriptor {
+            number: 1,
+            kind: KIND_SINT32,
+            offset: offset_of!(Point, x) as u32,
+            message: null(),
+        },
+        FieldDescriptor {
+            number: 2,
+            kind: KIND_SINT32,
+            offset: offset_of!(Point, y) as u32,
+            message: null(),
+        },
+    ];
+
+    static POINT: MessageDescriptor = MessageDescriptor {
+        size: size_of::<Point>() as u32,
+        field_count: 2,
+        fields: POINT_FIELDS.as_ptr(),
+    };
+
+    struct Descriptor(MessageDescriptor, [FieldDescriptor; 6]);
+
+    fn shape_descriptor() -> Box<Descriptor> {
+        let field = |number, kind, offset: usize, message| FieldDescriptor {
+            number,
+            kind,
+            offset: offset as u32,
+            message,
+        };
+        let mut descriptor = Box::new(Descriptor(
+            MessageDescriptor {
+                size: size_of::<Shape>() as u32,
+                field_count: 6,
+                fields: null(),
+            },
+            [
+                field(1, KIND_STRING, offset_of!(Shape, name), null()),
+                field(2, KIND_INT32 | REPEATED, offset_of!(Shape, sides), null()),
+                field(3, KIND_MESSAGE, offset_of!(Shape, origin), &POINT),
+                field(
+                    4,
+                    KIND_MESSAGE | REPEATED,
+                    offset_of!(Shape, corners),
+                    &POINT,
+                ),
+                field(5, KIND_DOUBLE, offset_of!(Shape, area), null()),
+                field(6, KIND_BOOL, offset_of!(Shape, filled), null()),
+            ],
+        ));
+        descriptor.0.fields = descriptor.1.as_ptr();
+        descriptor
+    }
+
+    fn bytes(array: *mut ReplicaArray) -> Vec<u8> {
+        unsafe { array_elements::<i32>(array) }
+            .iter()
+            .map(|&byte| byte as u8)
+            .collect()
+    }
+
+    fn array_of(bytes: &[u8]) -> *mut ReplicaArray {
+        let array = __replica_array_alloc(bytes.len(), 4);
+        for (index, &byte) in bytes.iter().enumerate() {
+            unsafe { ((*array).ptr as *mut i32).add(index).write(byte as i32) };
+        }
+        array
+    }
+
+    #[test]
+    fn test_encode_matches_protobuf() {
+        // protoc --encode=Point: x: -1, y: 150
+        let point = Point { x: -1, y: 150 };
+        let encoded =
+            unsafe { __replica_proto_encode(&POINT, &point as *const Point as *const u8) };
+        assert_eq!(bytes(encoded), [0x08, 0x01, 0x10, 0xac, 0x02]);
+
+        // 既定値のフィールドは書き出さない
+        let origin = Point { x: 0, y: 0 };
+        let encoded =
+            unsafe { __replica_proto_encode(&POINT, &origin as *const Point as *const u8) };
+        assert!(bytes(encoded).is_empty());
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let descriptor = shape_descriptor();
+        let name = "tri";
+        let mut name = ReplicaString {
+            ptr: name.as_ptr(),
+            len: name.len(),
+        };
+        let sides = array_of(&[]);
+        unsafe {
+            let buffer = __replica_array_new(2, 4) as *mut i32;
+            buffer.write(3);
+            buffer.add(1).write(-4);
+            (*sides).ptr = buffer as *mut u8;
+            (*sides).len = 2;
+        }
+        let corners = __replica_array_alloc(2, size_of::<Point>());
+        unsafe {
+            let corner = (*corners).ptr as *mut Point;
+            corner.write(Point { x: 1, y: 2 });
+            corner.add(1).write(Point { x: -3, y: 0 });
+        }
+        let shape = Shape {
+            name: &mut name,
+            sides,
+            origin: Point { x: 5, y: -6 },
+            corners,
+            area: 2.5,
+            filled: 1,
+        };
+
+        let encoded =
+            unsafe { __replica_proto_encode(&descriptor.0, &shape as *const Shape as *const u8) };
+        let wire = bytes(encoded);
+        // 名前と packed 形式の sides (-4 は 10 バイトの varint)
+        assert_eq!(&wire[..5], &[0x0a, 0x03, b't', b'r', b'i']);
+        assert_eq!(&wire[5..8], &[0x12, 11, 0x03]);
+
+        let mut decoded = core::mem::MaybeUninit::<Shape>::uninit();
+        let ok = unsafe {
+            __replica_proto_decode(&descriptor.0, encoded, decoded.as_mut_ptr() as *mut u8)
+        };
+        assert_eq!(ok, 1);
+        let decoded = unsafe { decoded.assume_init() };
+        unsafe {
+            assert_eq!(string_bytes(decoded.name), b"tri");
+            assert_eq!(array_elements::<i32>(decoded.sides), &[3, -4]);
+            assert_eq!((decoded.origin.x, decoded.origin.y), (5, -6));
+            let corners = array_elements::<Point>(decoded.corners);
+            assert_eq!(corners.len(), 2);
+            assert_eq!((corners[1].x, corners[1].y), (-3, 0));
+        }
+        assert_eq!(decoded.area, 2.5);
+        assert_eq!(decoded.filled, 1);
+    }
+
+    #[test]
+    fn test_decode_skips_unknown_fields_and_rejects_truncated_input() {
+        let mut point = Point { x: 7, y: 7 };
+        let out = &mut point as *mut Point as *mut u8;
+
+        // フィールド 9 (文字列) は未知なので読み飛ばす
+        let input = array_of(&[0x4a, 0x01, b'z', 0x10, 0x04]);
+        assert_eq!(unsafe { __replica_proto_decode(&POINT, input, out) }, 1);
+        assert_eq!((point.x, point.y), (0, 2));
+
+        let truncated = array_of(&[0x10]);
+        assert_eq!(unsafe { __replica_proto_decode(&POINT, truncated, out) }, 0);
+        let wrong_wire_type = array_of(&[0x0d, 0, 0, 0, 0]);
+        assert_eq!(
+            unsafe { __replica_proto_decode(&POINT, wrong_wire_type, out) },
+            0
+        );
+    }
+}
@@ -0,0 +1,285 @@
+//! Reference counting for heap-allocated actor instances.
+//! Each object is preceded by a header holding its strong and weak counts and the
+//! deinitializer generated for its type, which runs when the strong count drops to
+//! zero. The memory itself stays allocated until the last weak reference is gone,
+//! so weak slots can observe that the object died and zero themselves.
+//!
+//! String and array buffers are objects as well, which gives collections value
+//! semantics with copy-on-write: a copy only retains the buffer, and generated code
+//! calls `__replica_make_unique` before mutating it. Pointers outside the heap
+//! (string literals in static data) are immortal: retaining and releasing them is
+//! a no-op and they are always copied before mutation.
+
+use crate::alloc::{__replica_alloc, __replica_free, is_heap};
+use crate::metrics::{self, METRICS};
+
+/// Deinitializer generated by the compiler, called with the object before it is freed
+pub type DeinitFn = unsafe extern "C" fn(*mut u8);
+
+#[repr(C, align(8))]
+struct Header {
+    strong: usize,
+    weak: usize,
+    deinit: Option<DeinitFn>,
+}
+
+const HEADER_SIZE: usize = core::mem::size_of::<Header>();
+
+/// Returns the header of an object allocated by `__replica_object_new`
+///
+/// # Safety
+/// `object` must have been returned by `__replica_object_new`.
+unsafe fn header(object: *mut u8) -> *mut Header {
+    object.sub(HEADER_SIZE) as *mut Header
+}
+
+/// Allocates an object of `size` bytes with a strong count of one.
+/// `deinit` may be null for types without a deinitializer.
+#[no_mangle]
+pub extern "C" fn __replica_object_new(size: usize, deinit: Option<DeinitFn>) -> *mut u8 {
+    let total = match size.checked_add(HEADER_SIZE) {
+        Some(total) => total,
+        None => return core::ptr::null_mut(),
+    };
+    let base = __replica_alloc(total) as *mut Header;
+    if base.is_null() {
+        return core::ptr::null_mut();
+    }
+    // SAFETY: the allocation is large enough for the header and aligned to 8
+    unsafe {
+        base.write(Header {
+            strong: 1,
+            weak: 0,
+            deinit,
+        });
+        metrics::add(&METRICS.live_objects, 1);
+        (base as *mut u8).add(HEADER_SIZE)
+    }
+}
+
+/// Increments the strong count of `object`
+///
+/// # Safety
+/// `object` must be null or a live object returned by `__replica_object_new`.
+#[no_mangle]
+pub unsafe extern "C" fn __replica_retain(object: *mut u8) {
+    if is_heap(object) {
+        (*header(object)).strong += 1;
+    }
+}
+
+/// Decrements the strong count of `object`, running its deinitializer and
+/// freeing it when the count reaches zero. Returns 1 if the object was destroyed.
+///
+/// # Safety
+/// `object` must be null or a live object returned by `__replica_object_new`.
+#[no_mangle]
+pub unsafe extern "C" fn __replica_release(object: *mut u8) -> i32 {
+    if !is_heap(object) {
+        return 0;
+    }
+    let header = header(object);
+    (*header).strong -= 1;
+    if (*header).strong > 0 {
+        return 0;
+    }
+    metrics::decrement(&METRICS.live_objects);
+
+    if let Some(deinit) = (*header).deinit {
+        deinit(object);
+    }
+    if (*header).weak == 0 {
+        __replica_free(header as *mut u8);
+    }
+    1
+}
+
+/// Drops one weak reference, freeing the object if it was the last reference
+unsafe fn weak_release(object: *mut u8) {
+    let header = header(object);
+    (*header).weak -= 1;
+    if (*header).weak == 0 && (*header).strong == 0 {
+        __replica_free(header as *mut u8);
+    }
+}
+
+/// Stores a weak reference to `object` in `slot`, releasing the slot's previous target
+///
+/// # Safety
+/// `slot` must be valid for writes and hold null or a weak reference; `object` must be
+/// null or a live object returned by `__replica_object_new`.
+#[no_mangle]
+pub unsafe extern "C" fn __replica_weak_store(slot: *mut *mut u8, object: *mut u8) {
+    __replica_weak_release(slot);
+    if !object.is_null() {
+        (*header(object)).weak += 1;
+    }
+    slot.write(object);
+}
+
+/// Loads a weak reference. Returns a retained (strong) reference, or null if the
+/// target has been destroyed, in which case the slot is zeroed.
+///
+/// # Safety
+/// `slot` must be valid for reads and writes and hold null or a weak reference.
+#[no_mangle]
+pub unsafe extern "C" fn __replica_weak_load(slot: *mut *mut u8) -> *mut u8 {
+    let object = slot.read();
+    if object.is_null() {
+        return object;
+    }
+    if (*header(object)).strong == 0 {
+        slot.write(core::ptr::null_mut());
+        weak_release(object);
+        return core::ptr::null_mut();
+    }
+    (*header(object)).strong += 1;
+    object
+}
+
+/// Clears a weak reference slot
+///
+/// # Safety
+/// `slot` must be valid for reads and writes and hold null or a weak reference.
+#[no_mangle]
+pub unsafe extern "C" fn __replica_weak_release(slot: *mut *mut u8) {
+    let object = slot.read();
+    if !object.is_null() {
+        slot.write(core::ptr::null_mut());
+        weak_release(object);
+    }
+}
+
+/// Returns 1 if `object` is a heap object referenced only once, so it may be
+/// mutated in place
+///
+/// # Safety
+/// `object` must be null, static data or a live object returned by
+/// `__replica_object_new`.
+#[no_mangle]
+pub unsafe extern "C" fn __replica_is_unique(object: *mut u8) -> i32 {
+    (is_heap(object) && (*header(object)).strong == 1) as i32
+}
+
+/// Prepares a buffer of `size` bytes for mutation. Returns `object` itself if it is
+/// unique, otherwise a fresh copy of it, giving up the caller's reference to
+/// `object`. Returns null if the copy cannot be allocated.
+///
+/// # Safety
+/// `object` must be null, static data or a live object returned by
+/// `__replica_object_new` that the caller holds a strong reference to, and must be
+/// at least `size` bytes long.
+#[no_mangle]
+pub unsafe extern "C" fn __replica_make_unique(object: *mut u8, size: usize) -> *mut u8 {
+    if object.is_null() || __replica_is_unique(object) == 1 {
+        return object;
+    }
+
+    let copy = __replica_object_new(size, None);
+    if copy.is_null() {
+        return copy;
+    }
+    core::ptr::copy_nonoverlapping(object, copy, size);
+    __replica_release(object);
+    copy
+}
+
+/// Returns the strong count of `object`
+///
+/// # Safety
+/// `object` must be a live object returned by `__replica_object_new`.
+#[no_mangle]
+pub unsafe extern "C" fn __replica_strong_count(object: *mut u8) -> usize {
+    (*header(object)).strong
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    static DEINIT_CALLS: AtomicUsize = AtomicUsize::new(0);
+
+    unsafe extern "C" fn count_deinit(object: *mut u8) {
+        DEINIT_CALLS.fetch_add(*(object as *const u32) as usize, Ordering::SeqCst);
+    }
+
+    #[test]
+    fn test_release_runs_deinit_once() {
+        let object = __replica_object_new(4, Some(count_deinit));
+        assert!(!object.is_null());
+        assert_eq!(object as usize % 8, 0);
+
+        unsafe {
+            (object as *mut u32).write(7);
+            __replica_retain(object);
+            assert_eq!(__replica_strong_count(object), 2);
+
+            assert_eq!(__replica_release(object), 0);
+            assert_eq!(DEINIT_CALLS.load(Ordering::SeqCst), 0);
+            assert_eq!(__replica_release(object), 1);
+        }
+        assert_eq!(DEINIT_CALLS.load(Ordering::SeqCst), 7);
+    }
+
+    #[test]
+    fn test_weak_reference_is_zeroed() {
+        let object = __replica_object_new(8, None);
+        let mut slot = core::ptr::null_mut();
+
+        unsafe {
+            __replica_weak_store(&mut slot, object);
+            let loaded = __replica_weak_load(&mut slot);
+            assert_eq!(loaded, object);
+            assert_eq!(__replica_strong_count(object), 2);
+            assert_eq!(__replica_release(loaded), 0);
+
+            assert_eq!(__replica_release(object), 1);
+            assert!(__replica_weak_load(&mut slot).is_null());
+            assert!(slot.is_null());
+        }
+    }
+
+    #[test]
+    fn test_copy_on_write() {
+        let buffer = __replica_object_new(4, None);
+        unsafe {
+            (buffer as *mut u32).write(1);
+            assert_eq!(__replica_make_unique(buffer, 4), buffer);
+
+            // A logical copy shares the buffer until one side writes to it
+            __replica_retain(buffer);
+            let copy = __replica_make_unique(buffer, 4);
+            assert_ne!(copy, buffer);
+            (copy as *mut u32).write(2);
+
+            assert_eq!(*(buffer as *const u32), 1);
+            assert_eq!(*(copy as *const u32), 2);
+            assert_eq!(__replica_is_unique(buffer), 1);
+            assert_eq!(__replica_is_unique(copy), 1);
+        }
+    }
+
+    #[test]
+    fn test_static_data_is_copied_before_mutation() {
+        let literal = b"replica\0";
+        let object = literal.as_ptr() as *mut u8;
+        unsafe {
+            __replica_retain(object);
+            assert_eq!(__replica_release(object), 0);
+
+            let copy = __replica_make_unique(object, literal.len());
+            assert_ne!(copy, object);
+            assert_eq!(core::slice::from_raw_parts(copy, literal.len()), literal);
+        }
+    }
+
+    #[test]
+    fn test_object_without_deinit() {
+        let object = __replica_object_new(16, None);
+        unsafe {
+            assert_eq!(__replica_release(object), 1);
+            assert_eq!(__replica_release(core::ptr::null_mut()), 0);
+        }
+    }
+}
@@ -0,0 +1,144 @@
+//! Actor registry: the constructors of a module's actors and pools by name.
+//! `__replica_init` registers every `<Actor>.new` under its actor's name, so a
+//! host can create an instance with `replica_actor_new` without knowing the
+//! name the constructor is exported under.
+
+use crate::scheduler::trap;
+use crate::string::ReplicaString;
+use crate::sync::Lock;
+use core::cell::UnsafeCell;
+
+const REGISTRY_CAPACITY: usize = 64;
+
+/// `<Actor>.new`, which returns a new instance or null when out of memory
+pub type ConstructorFn = extern "C" fn() -> *mut u8;
+
+#[derive(Clone, Copy)]
+struct Entry {
+    /// Address of the actor's name, a `String` descriptor in static data
+    name: usize,
+    new: Option<ConstructorFn>,
+}
+
+struct Registry {
+    lock: Lock,
+    entries: UnsafeCell<([Entry; REGISTRY_CAPACITY], usize)>,
+}
+
+// SAFETY: the entries are only accessed while holding `lock`
+unsafe impl Sync for Registry {}
+
+impl Registry {
+    fn with<R>(&self, f: impl FnOnce(&mut [Entry; REGISTRY_CAPACITY], &mut usize) -> R) -> R {
+        // SAFETY: the lock gives exclusive access to the entries
+        self.lock.with(|| {
+            let (entries, len) = unsafe { &mut *self.entries.get() };
+            f(entries, len)
+        })
+    }
+}
+
+static REGISTRY: Registry = Registry {
+    lock: Lock::new(),
+    entries: UnsafeCell::new(([Entry { name: 0, new: None }; REGISTRY_CAPACITY], 0)),
+};
+
+/// Whether the `String` descriptor at `name` holds `bytes`
+///
+/// # Safety
+/// `name` must point to a valid `String` descriptor.
+unsafe fn has_name(name: usize, bytes: &[u8]) -> bool {
+    let name = *(name as *const ReplicaString);
+    core::slice::from_raw_parts(name.ptr, name.len) == bytes
+}
+
+/// Records `new` as the constructor of the actor called `name`, replacing an
+/// earlier registration of the same name. Traps when the registry is full.
+///
+/// # Safety
+/// `name` must point to a `String` descriptor in static data.
+#[no_mangle]
+pub unsafe extern "C" fn __replica_register_actor(name: *const ReplicaString, new: ConstructorFn) {
+    let bytes = {
+        let name = *name;
+        core::slice::from_raw_parts(name.ptr, name.len)
+    };
+    let entry = Entry {
+        name: name as usize,
+        new: Some(new),
+    };
+    let added = REGISTRY.with(|entries, len| {
+        if let Some(known) = entries[..*len].iter_mut().find(|e| has_name(e.name, bytes)) {
+            *known = entry;
+            return true;
+        }
+        if *len == REGISTRY_CAPACITY {
+            return false;
+        }
+        entries[*len] = entry;
+        *len += 1;
+        true
+    });
+    if !added {
+        trap();
+    }
+}
+
+/// Creates an instance of the actor whose name is the `len` UTF-8 bytes at
+/// `name`. Returns null when no actor of that name is registered or the
+/// instance cannot be allocated.
+///
+/// # Safety
+/// `name` must point to `len` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn replica_actor_new(name: *const u8, len: usize) -> *mut u8 {
+    let bytes = core::slice::from_raw_parts(name, len);
+    // 生成中に登録が変わってもよいよう、ロックの外で呼ぶ
+    let new = REGISTRY.with(|entries, len| {
+        entries[..*len]
+            .iter()
+            .find(|e| has_name(e.name, bytes))
+            .and_then(|e| e.new)
+    });
+    match new {
+        Some(new) => new(),
+        None => core::ptr::null_mut(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static mut CREATED: [u8; 2] = [0; 2];
+
+    extern "C" fn first() -> *mut u8 {
+        unsafe { core::ptr::addr_of_mut!(CREATED[0]) }
+    }
+
+    extern "C" fn second() -> *mut u8 {
+        unsafe { core::ptr::addr_of_mut!(CREATED[1]) }
+    }
+
+    fn name(text: &'static str) -> ReplicaString {
+        ReplicaString {
+            ptr: text.as_ptr(),
+            len: text.len(),
+        }
+    }
+
+    #[test]
+    fn test_actors_are_created_by_name() {
+        let (counter, copy) = (name("test.Counter"), name("test.Counter"));
+        let new = |text: &str| unsafe { replica_actor_new(text.as_ptr(), text.len()) };
+        unsafe {
+            __replica_register_actor(&counter, first);
+            assert_eq!(new("test.Counter"), first());
+            assert!(new("test.Count").is_null());
+
+            // 同じ名前をもう一度登録すると置き換わる
+            __replica_register_actor(&copy, second);
+            assert_eq!(new("test.Counter"), second());
+        }
+    }
+}
@@ -0,0 +1,309 @@
+//! Scheduler glue: a run queue of mailboxes with pending messages.
+//! Generated modules register a dispatch function per actor type; the host drives
+//! execution by calling `__replica_run` until it reports no remaining work.
+//! With shared memory several workers may call `__replica_run` at once; a mailbox
+//! being dispatched by one worker is skipped by the others, so each actor still
+//! handles one message at a time.
+
+use crate::mailbox::{__replica_mailbox_len, __replica_mailbox_pop, Mailbox, Message};
+use crate::sync::Lock;
+use core::cell::UnsafeCell;
+
+/// Dispatch function generated per actor type. `mailbox` is the mailbox the
+/// message was taken from, where an async method queues the calls it awaits.
+pub type DispatchFn =
+    extern "C" fn(actor: *mut u8, tag: u32, payload: *mut u8, len: usize, mailbox: *mut Mailbox);
+
+const RUN_QUEUE_CAPACITY: usize = 256;
+
+#[derive(Clone, Copy)]
+struct Entry {
+    mailbox: usize,
+    dispatch: Option<DispatchFn>,
+    /// A worker is currently dispatching a message of this mailbox
+    running: bool,
+}
+
+struct RunQueue {
+    entries: [Entry; RUN_QUEUE_CAPACITY],
+    len: usize,
+}
+
+impl RunQueue {
+    /// Marks the first idle mailbox as running and returns it
+    fn claim(&mut self) -> Option<(usize, Option<DispatchFn>)> {
+        let entry = self.entries[..self.len].iter_mut().find(|e| !e.running)?;
+        entry.running = true;
+        Some((entry.mailbox, entry.dispatch))
+    }
+
+    /// Marks `mailbox` as running, queueing it first when it is not scheduled,
+    /// and returns whether it was idle
+    fn claim_mailbox(&mut self, mailbox: usize, dispatch: DispatchFn) -> bool {
+        if let Some(entry) = self.entries[..self.len]
+            .iter_mut()
+            .find(|e| e.mailbox == mailbox)
+        {
+            if entry.running {
+                return false;
+            }
+            entry.running = true;
+            return true;
+        }
+        if self.len == RUN_QUEUE_CAPACITY {
+            return false;
+        }
+        self.entries[self.len] = Entry {
+            mailbox,
+            dispatch: Some(dispatch),
+            running: true,
+        };
+        self.len += 1;
+        true
+    }
+
+    /// Rotates a claimed mailbox to the back, or drops it once drained
+    ///
+    /// # Safety
+    /// The mailbox must still be alive.
+    unsafe fn finish(&mut self, mailbox: usize) {
+        let Some(index) = self.entries[..self.len]
+            .iter()
+            .position(|e| e.mailbox == mailbox)
+        else {
+            return;
+        };
+        let mut entry = self.entries[index];
+        self.entries.copy_within(index + 1..self.len, index);
+        if __replica_mailbox_len(mailbox as *const Mailbox) > 0 {
+            entry.running = false;
+            self.entries[self.len - 1] = entry;
+        } else {
+            self.len -= 1;
+        }
+    }
+}
+
+struct Scheduler {
+    lock: Lock,
+    queue: UnsafeCell<RunQueue>,
+}
+
+// SAFETY: the run queue is only accessed while holding `lock`
+unsafe impl Sync for Scheduler {}
+
+impl Scheduler {
+    fn with<R>(&self, f: impl FnOnce(&mut RunQueue) -> R) -> R {
+        // SAFETY: the lock gives exclusive access to the queue
+        self.lock.with(|| f(unsafe { &mut *self.queue.get() }))
+    }
+}
+
+static SCHEDULER: Scheduler = Scheduler {
+    lock: Lock::new(),
+    queue: UnsafeCell::new(RunQueue {
+        entries: [Entry {
+            mailbox: 0,
+            dispatch: None,
+            running: false,
+        }; RUN_QUEUE_CAPACITY],
+        len: 0,
+    }),
+};
+
+/// Aborts execution
+pub fn trap() -> ! {
+    #[cfg(target_arch = "wasm32")]
+    {
+        core::arch::wasm32::unreachable()
+    }
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        panic!("replica runtime trap")
+    }
+}
+
+/// Called by the stack guard of a recursive method that is about to overflow.
+/// Trapping from a dedicated function names the cause in the host's backtrace.
+#[no_mangle]
+pub extern "C" fn __replica_stack_overflow() -> ! {
+    trap()
+}
+
+/// Called by `value!` when the optional is `nil`.
+#[no_mangle]
+pub extern "C" fn __replica_unwrap_nil() -> ! {
+    trap()
+}
+
+/// Marks `mailbox` as runnable, returning -1 when the run queue is full
+#[no_mangle]
+pub extern "C" fn __replica_schedule(mailbox: *mut Mailbox, dispatch: DispatchFn) -> i32 {
+    let key = mailbox as usize;
+    SCHEDULER.with(|queue| {
+        if queue.entries[..queue.len].iter().any(|e| e.mailbox == key) {
+            return 0;
+        }
+        if queue.len == RUN_QUEUE_CAPACITY {
+            return -1;
+        }
+        queue.entries[queue.len] = Entry {
+            mailbox: key,
+            dispatch: Some(dispatch),
+            running: false,
+        };
+        queue.len += 1;
+        0
+    })
+}
+
+/// Processes up to `budget` messages, returning the number still pending
+///
+/// # Safety
+/// Every scheduled mailbox must still be alive.
+#[no_mangle]
+pub unsafe extern "C" fn __replica_run(budget: u32) -> u32 {
+    let mut remaining = budget;
+
+    while remaining > 0 {
+        // 他のワーカーが処理中のメールボックスは飛ばす
+        let Some((key, dispatch)) = SCHEDULER.with(|queue| queue.claim()) else {
+            break;
+        };
+        let mailbox = key as *mut Mailbox;
+        let mut message = Message {
+            tag: 0,
+            payload: core::ptr::null_mut(),
+            len: 0,
+        };
+
+        if __replica_mailbox_pop(mailbox, &mut message) == 0 {
+            if let Some(dispatch) = dispatch {
+                dispatch(
+                    (*mailbox).actor,
+                    message.tag,
+                    message.payload,
+                    message.len,
+                    mailbox,
+                );
+            }
+            remaining -= 1;
+        }
+
+        SCHEDULER.with(|queue| queue.finish(key));
+    }
+
+    SCHEDULER.with(|queue| {
+        queue.entries[..queue.len]
+            .iter()
+            .map(|e| __replica_mailbox_len(e.mailbox as *const Mailbox) as u32)
+            .sum()
+    })
+}
+
+/// Processes up to `budget` messages of one mailbox in order, returning the
+/// number still queued. Nothing is dispatched while another worker is running
+/// the mailbox, so its actor still handles one message at a time.
+///
+/// # Safety
+/// `mailbox` must be a live mailbox.
+#[no_mangle]
+pub unsafe extern "C" fn __replica_drain(
+    mailbox: *mut Mailbox,
+    dispatch: DispatchFn,
+    budget: u32,
+) -> u32 {
+    let key = mailbox as usize;
+
+    if SCHEDULER.with(|queue| queue.claim_mailbox(key, dispatch)) {
+        let mut message = Message {
+            tag: 0,
+            payload: core::ptr::null_mut(),
+            len: 0,
+        };
+        for _ in 0..budget {
+            if __replica_mailbox_pop(mailbox, &mut message) != 0 {
+                break;
+            }
+            dispatch(
+                (*mailbox).actor,
+                message.tag,
+                message.payload,
+                message.len,
+                mailbox,
+            );
+        }
+        // 残りのメッセージは __replica_run に引き継ぐ
+        SCHEDULER.with(|queue| queue.finish(key));
+    }
+
+    __replica_mailbox_len(mailbox) as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mailbox::{__replica_mailbox_new, __replica_mailbox_push};
+    use core::sync::atomic::{AtomicU32, Ordering};
+
+    static DISPATCHED: AtomicU32 = AtomicU32::new(0);
+
+    extern "C" fn count_dispatch(
+        _actor: *mut u8,
+        tag: u32,
+        _payload: *mut u8,
+        _len: usize,
+        _mailbox: *mut Mailbox,
+    ) {
+        DISPATCHED.fetch_add(tag, Ordering::SeqCst);
+    }
+
+    #[test]
+    fn test_run_drains_scheduled_mailboxes() {
+        let mailbox = __replica_mailbox_new(core::ptr::null_mut(), 4);
+        unsafe {
+            for tag in [1, 2, 3] {
+                assert_eq!(
+                    __replica_mailbox_push(mailbox, tag, core::ptr::null_mut(), 0),
+                    0
+                );
+            }
+            assert_eq!(__replica_schedule(mailbox, count_dispatch), 0);
+
+            assert_eq!(__replica_run(2), 1);
+            assert_eq!(__replica_run(10), 0);
+        }
+        assert_eq!(DISPATCHED.load(Ordering::SeqCst), 6);
+    }
+
+    static DRAINED: AtomicU32 = AtomicU32::new(0);
+
+    extern "C" fn record_dispatch(
+        _actor: *mut u8,
+        tag: u32,
+        _payload: *mut u8,
+        _len: usize,
+        _mailbox: *mut Mailbox,
+    ) {
+        // 到着順に処理されたことを桁で確かめる
+        let seen = DRAINED.load(Ordering::SeqCst);
+        DRAINED.store(seen * 10 + tag, Ordering::SeqCst);
+    }
+
+    #[test]
+    fn test_drain_dispatches_one_mailbox_in_order() {
+        let mailbox = __replica_mailbox_new(core::ptr::null_mut(), 4);
+        unsafe {
+            for tag in [1, 2, 3] {
+                assert_eq!(
+                    __replica_mailbox_push(mailbox, tag, core::ptr::null_mut(), 0),
+                    0
+                );
+            }
+
+            assert_eq!(__replica_drain(mailbox, record_dispatch, 2), 1);
+            assert_eq!(__replica_drain(mailbox, record_dispatch, 10), 0);
+        }
+        assert_eq!(DRAINED.load(Ordering::SeqCst), 123);
+    }
+}
@@ -0,0 +1,389 @@
+//! String helpers. Strings are `{ ptr, len }` pairs of UTF-8 bytes in linear memory
+//! whose buffers are copy-on-write objects (see [`crate::rc`]).
+
+use crate::rc::__replica_object_new;
+use core::fmt::Write;
+
+/// Longest text [`format_int`] produces, `-2147483648`
+pub const INT_TEXT_CAPACITY: usize = 11;
+
+/// Longest text [`format_float`] produces, e.g. `-2.2250738585072014e-308`
+pub const FLOAT_TEXT_CAPACITY: usize = 32;
+
+/// Runtime string representation shared with generated code
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct ReplicaString {
+    pub ptr: *const u8,
+    pub len: usize,
+}
+
+/// Concatenates two strings into a newly allocated buffer written to `out`.
+/// On allocation failure `out` receives a null, empty string.
+///
+/// # Safety
+/// Both inputs must point to `len` readable bytes and `out` must be writable.
+#[no_mangle]
+pub unsafe extern "C" fn __replica_string_concat(
+    left_ptr: *const u8,
+    left_len: usize,
+    right_ptr: *const u8,
+    right_len: usize,
+    out: *mut ReplicaString,
+) {
+    let len = left_len + right_len;
+    let ptr = __replica_object_new(len, None);
+    if ptr.is_null() {
+        out.write(ReplicaString {
+            ptr: core::ptr::null(),
+            len: 0,
+        });
+        return;
+    }
+    core::ptr::copy_nonoverlapping(left_ptr, ptr, left_len);
+    core::ptr::copy_nonoverlapping(right_ptr, ptr.add(left_len), right_len);
+    out.write(ReplicaString { ptr, len });
+}
+
+/// Compares two strings byte-wise, returning 1 when equal
+///
+/// # Safety
+/// Both inputs must point to `len` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn __replica_string_eq(
+    left_ptr: *const u8,
+    left_len: usize,
+    right_ptr: *const u8,
+    right_len: usize,
+) -> i32 {
+    if left_len != right_len {
+        return 0;
+    }
+    let left = core::slice::from_raw_parts(left_ptr, left_len);
+    let right = core::slice::from_raw_parts(right_ptr, right_len);
+    (left == right) as i32
+}
+
+/// Formats `value` in decimal, with a leading `-` when negative
+pub fn format_int(value: i32, buf: &mut [u8; INT_TEXT_CAPACITY]) -> &str {
+    // 下の桁から後ろ向きに書き込む
+    let mut magnitude = value.unsigned_abs();
+    let mut start = INT_TEXT_CAPACITY;
+    loop {
+        start -= 1;
+        buf[start] = b'0' + (magnitude % 10) as u8;
+        magnitude /= 10;
+        if magnitude == 0 {
+            break;
+        }
+    }
+    if value < 0 {
+        start -= 1;
+        buf[start] = b'-';
+    }
+    core::str::from_utf8(&buf[start..]).unwrap_or_default()
+}
+
+/// Parses a whole string as a decimal `Int`: an optional `+` or `-` followed by
+/// ASCII digits, without whitespace, within the 32-bit range
+pub fn parse_int(text: &[u8]) -> Option<i32> {
+    let (negative, digits) = match text {
+        [b'-', rest @ ..] => (true, rest),
+        [b'+', rest @ ..] => (false, rest),
+        _ => (false, text),
+    };
+    if digits.is_empty() {
+        return None;
+    }
+    // 負の側で積み上げると i32::MIN も表せる
+    digits.iter().try_fold(0i32, |value, &digit| {
+        if !digit.is_ascii_digit() {
+            return None;
+        }
+        let value = value.checked_mul(10)?;
+        let digit = (digit - b'0') as i32;
+        if negative {
+            value.checked_sub(digit)
+        } else {
+            value.checked_add(digit)
+        }
+    })
+}
+
+/// Writes the decimal text of `value` into a newly allocated buffer written to
+/// `out`. Does nothing when `out` is null, so a failed descriptor allocation
+/// leaves the empty string; on allocation failure of the buffer `out` receives
+/// a null, empty string.
+///
+/// # Safety
+/// `out` must be null or writable.
+#[no_mangle]
+pub unsafe extern "C" fn __replica_int_to_string(value: i32, out: *mut ReplicaString) {
+    if out.is_null() {
+        return;
+    }
+    let mut buf = [0; INT_TEXT_CAPACITY];
+    let text = format_int(value, &mut buf);
+    let ptr = __replica_object_new(text.len(), None);
+    if ptr.is_null() {
+        out.write(ReplicaString {
+            ptr: core::ptr::null(),
+            len: 0,
+        });
+        return;
+    }
+    core::ptr::copy_nonoverlapping(text.as_ptr(), ptr, text.len());
+    out.write(ReplicaString {
+        ptr,
+        len: text.len(),
+    });
+}
+
+/// Writes `true` when `value` is non-zero and `false` otherwise to `out`. The
+/// text lives in static data like a string literal, so nothing is allocated.
+/// Does nothing when `out` is null.
+///
+/// # Safety
+/// `out` must be null or writable.
+#[no_mangle]
+pub unsafe extern "C" fn __replica_bool_to_string(value: i32, out: *mut ReplicaString) {
+    if out.is_null() {
+        return;
+    }
+    let text = if value != 0 { "true" } else { "false" };
+    out.write(ReplicaString {
+        ptr: text.as_ptr(),
+        len: text.len(),
+    });
+}
+
+/// Parses `string` with [`parse_int`], writing the value to `out` and returning
+/// 1 on success and 0 otherwise. A null `string` is the empty string.
+///
+/// # Safety
+/// `string` must be null or point to a descriptor of `len` readable bytes, and
+/// `out` must be writable.
+#[no_mangle]
+pub unsafe extern "C" fn __replica_string_to_int(
+    string: *const ReplicaString,
+    out: *mut i32,
+) -> i32 {
+    if string.is_null() || (*string).ptr.is_null() {
+        return 0;
+    }
+    let string = string.read();
+    match parse_int(core::slice::from_raw_parts(string.ptr, string.len)) {
+        Some(value) => {
+            out.write(value);
+            1
+        }
+        None => 0,
+    }
+}
+
+/// Formats `value` as the shortest text that parses back to the same `f64`.
+/// Finite values always contain a `.` or an exponent (`1.0`, `1e16`, `5e-324`), so
+/// they read back as `Float` literals; non-finite values are `inf`, `-inf` and `nan`.
+pub fn format_float(value: f64, buf: &mut [u8; FLOAT_TEXT_CAPACITY]) -> &str {
+    let mut writer = FixedBuffer { buf, len: 0 };
+    if value.is_nan() {
+        writer.push_str("nan");
+    } else {
+        // Debug は最短の往復可能な桁を選び、大きな値と小さな値は指数表記にする
+        let _ = write!(writer, "{:?}", value);
+    }
+    let len = writer.len;
+    // 書き込んだのは ASCII だけ
+    core::str::from_utf8(&buf[..len]).unwrap_or_default()
+}
+
+/// Writes the text of `value` (see [`format_float`]) into a newly allocated
+/// buffer written to `out`. On allocation failure `out` receives a null, empty string.
+///
+/// # Safety
+/// `out` must be writable.
+#[no_mangle]
+pub unsafe extern "C" fn __replica_float_to_string(value: f64, out: *mut ReplicaString) {
+    let mut buf = [0; FLOAT_TEXT_CAPACITY];
+    let text = format_float(value, &mut buf);
+    let ptr = __replica_object_new(text.len(), None);
+    if ptr.is_null() {
+        out.write(ReplicaString {
+            ptr: core::ptr::null(),
+            len: 0,
+        });
+        return;
+    }
+    core::ptr::copy_nonoverlapping(text.as_ptr(), ptr, text.len());
+    out.write(ReplicaString {
+        ptr,
+        len: text.len(),
+    });
+}
+
+/// `fmt::Write` sink over a fixed buffer; text beyond its capacity is dropped
+struct FixedBuffer<'a> {
+    buf: &'a mut [u8; FLOAT_TEXT_CAPACITY],
+    len: usize,
+}
+
+impl FixedBuffer<'_> {
+    fn push_str(&mut self, text: &str) {
+        let end = (self.len + text.len()).min(FLOAT_TEXT_CAPACITY);
+        self.buf[self.len..end].copy_from_slice(&text.as_bytes()[..end - self.len]);
+        self.len = end;
+    }
+}
+
+impl Write for FixedBuffer<'_> {
+    fn write_str(&mut self, text: &str) -> core::fmt::Result {
+        self.push_str(text);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_concat_and_eq() {
+        let (left, right) = ("Hello, ", "Replica");
+        let mut joined = ReplicaString {
+            ptr: core::ptr::null(),
+            len: 0,
+        };
+        unsafe {
+            __replica_string_concat(left.as_ptr(), left.len(), right.as_ptr(), 7, &mut joined);
+            assert_eq!(joined.len, 14);
+
+            let expected = "Hello, Replica";
+            assert_eq!(
+                __replica_string_eq(joined.ptr, joined.len, expected.as_ptr(), expected.len()),
+                1
+            );
+            assert_eq!(
+                __replica_string_eq(joined.ptr, joined.len, left.as_ptr(), 7),
+                0
+            );
+        }
+    }
+
+    #[test]
+    fn test_format_float_round_trips() {
+        let mut buf = [0; FLOAT_TEXT_CAPACITY];
+        let cases = [
+            (1.0, "1.0"),
+            (0.1, "0.1"),
+            (-2.5, "-2.5"),
+            (1e16, "1e16"),
+            (1.5e-7, "1.5e-7"),
+            (f64::MIN_POSITIVE, "2.2250738585072014e-308"),
+            (f64::INFINITY, "inf"),
+            (f64::NEG_INFINITY, "-inf"),
+            (f64::NAN, "nan"),
+        ];
+        for (value, text) in cases {
+            assert_eq!(format_float(value, &mut buf), text);
+        }
+
+        for value in [
+            0.1 + 0.2,
+            1.0 / 3.0,
+            f64::MAX,
+            -f64::MIN_POSITIVE,
+            5e-324,
+            123456.789,
+        ] {
+            let text = format_float(value, &mut buf);
+            assert!(text.contains(['.', 'e']), "{}", text);
+            assert_eq!(text.parse::<f64>().unwrap().to_bits(), value.to_bits());
+        }
+    }
+
+    #[test]
+    fn test_bool_to_string() {
+        let mut text = ReplicaString {
+            ptr: core::ptr::null(),
+            len: 0,
+        };
+        unsafe {
+            for (value, expected) in [(1, "true"), (0, "false"), (-1, "true")] {
+                __replica_bool_to_string(value, &mut text);
+                assert_eq!(
+                    __replica_string_eq(text.ptr, text.len, expected.as_ptr(), expected.len()),
+                    1
+                );
+            }
+            __replica_bool_to_string(1, core::ptr::null_mut());
+        }
+    }
+
+    #[test]
+    fn test_float_to_string() {
+        let mut text = ReplicaString {
+            ptr: core::ptr::null(),
+            len: 0,
+        };
+        unsafe {
+            __replica_float_to_string(0.30000000000000004, &mut text);
+            let expected = "0.30000000000000004";
+            assert_eq!(
+                __replica_string_eq(text.ptr, text.len, expected.as_ptr(), expected.len()),
+                1
+            );
+        }
+    }
+
+    #[test]
+    fn test_format_and_parse_int() {
+        let mut buf = [0; INT_TEXT_CAPACITY];
+        for value in [0, 7, -42, 1_000_000, i32::MAX, i32::MIN] {
+            let text = format_int(value, &mut buf);
+            assert_eq!(text, value.to_string());
+            assert_eq!(parse_int(text.as_bytes()), Some(value));
+        }
+
+        assert_eq!(parse_int(b"+15"), Some(15));
+        assert_eq!(parse_int(b"007"), Some(7));
+        for invalid in [
+            "",
+            "-",
+            "+",
+            " 1",
+            "1 ",
+            "1.0",
+            "0x10",
+            "2147483648",
+            "-2147483649",
+        ] {
+            assert_eq!(parse_int(invalid.as_bytes()), None, "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_int_string_conversions() {
+        let mut text = ReplicaString {
+            ptr: core::ptr::null(),
+            len: 0,
+        };
+        let mut value = 0;
+        unsafe {
+            __replica_int_to_string(-305, &mut text);
+            assert_eq!(text.len, 4);
+            assert_eq!(__replica_string_to_int(&text, &mut value), 1);
+            assert_eq!(value, -305);
+
+            let invalid = ReplicaString {
+                ptr: "12a".as_ptr(),
+                len: 3,
+            };
+            assert_eq!(__replica_string_to_int(&invalid, &mut value), 0);
+            assert_eq!(__replica_string_to_int(core::ptr::null(), &mut value), 0);
+            assert_eq!(value, -305);
+
+            // 記述子の確保に失敗した場合は何もしない
+            __replica_int_to_string(1, core::ptr::null_mut());
+        }
+    }
+}
@@ -0,0 +1,112 @@
+//! Locks for state touched by several workers when modules are built with the
+//! `threads` WASM feature. Without shared memory only one thread ever runs, so
+//! the locks are always free and cost a single compare-and-swap.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+const UNLOCKED: u32 = 0;
+const LOCKED: u32 = 1;
+
+/// Spin lock stored as one 32-bit word: 0 when free, 1 when held.
+/// Generated code embeds the same word in actor state structs with `Shared` fields.
+#[repr(transparent)]
+pub struct Lock(AtomicU32);
+
+impl Lock {
+    pub const fn new() -> Self {
+        Lock(AtomicU32::new(UNLOCKED))
+    }
+
+    /// Spins until the lock is acquired
+    pub fn acquire(&self) {
+        while self
+            .0
+            .compare_exchange_weak(UNLOCKED, LOCKED, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            // 保持者が解放するまで読み取りだけで待つ
+            while self.0.load(Ordering::Relaxed) == LOCKED {
+                core::hint::spin_loop();
+            }
+        }
+    }
+
+    pub fn release(&self) {
+        self.0.store(UNLOCKED, Ordering::Release);
+    }
+
+    /// Runs `f` while holding the lock
+    pub fn with<R>(&self, f: impl FnOnce() -> R) -> R {
+        self.acquire();
+        let result = f();
+        self.release();
+        result
+    }
+}
+
+impl Default for Lock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Acquires the lock word at `lock`
+///
+/// # Safety
+/// `lock` must point to a 4-byte aligned lock word.
+#[no_mangle]
+pub unsafe extern "C" fn __replica_lock_acquire(lock: *const Lock) {
+    (*lock).acquire()
+}
+
+/// Releases the lock word at `lock`
+///
+/// # Safety
+/// `lock` must point to a lock word held by the caller.
+#[no_mangle]
+pub unsafe extern "C" fn __replica_lock_release(lock: *const Lock) {
+    (*lock).release()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::cell::UnsafeCell;
+    use std::sync::Arc;
+
+    struct Counter {
+        lock: Lock,
+        value: UnsafeCell<u32>,
+    }
+
+    // SAFETY: `value` is only accessed while holding `lock`
+    unsafe impl Sync for Counter {}
+
+    #[test]
+    fn test_lock_serializes_workers() {
+        let counter = Arc::new(Counter {
+            lock: Lock::new(),
+            value: UnsafeCell::new(0),
+        });
+
+        let workers: Vec<_> = (0..4)
+            .map(|_| {
+                let counter = Arc::clone(&counter);
+                std::thread::spawn(move || {
+                    for _ in 0..1000 {
+                        unsafe {
+                            __replica_lock_acquire(&counter.lock);
+                            *counter.value.get() += 1;
+                            __replica_lock_release(&counter.lock);
+                        }
+                    }
+                })
+            })
+            .collect();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+
+        assert_eq!(counter.lock.with(|| unsafe { *counter.value.get() }), 4000);
+    }
+}
@@ -0,0 +1,251 @@
+//! Topic routing table: which mailboxes receive the values published on a topic.
+//! `subscribe(Topic)` records the instance's inbox with the dispatch function and
+//! message tag of its `on<Topic>` method; `publish(Topic, value)` copies the value
+//! into a message for every inbox subscribed to the topic and schedules the inbox,
+//! as `<method>.post` does for a single call.
+
+use crate::alloc::{__replica_alloc, __replica_free};
+use crate::mailbox::{__replica_mailbox_push, Mailbox};
+use crate::scheduler::{__replica_schedule, trap, DispatchFn};
+use crate::string::ReplicaString;
+use crate::sync::Lock;
+use core::cell::UnsafeCell;
+
+const ROUTE_CAPACITY: usize = 256;
+
+#[derive(Clone, Copy)]
+struct Route {
+    /// Address of the topic's name, a `String` descriptor in static data
+    topic: usize,
+    mailbox: usize,
+    dispatch: Option<DispatchFn>,
+    tag: u32,
+}
+
+struct RoutingTable {
+    lock: Lock,
+    routes: UnsafeCell<([Route; ROUTE_CAPACITY], usize)>,
+}
+
+// SAFETY: the routes are only accessed while holding `lock`
+unsafe impl Sync for RoutingTable {}
+
+impl RoutingTable {
+    fn with<R>(&self, f: impl FnOnce(&mut [Route; ROUTE_CAPACITY], &mut usize) -> R) -> R {
+        // SAFETY: the lock gives exclusive access to the routes
+        self.lock.with(|| {
+            let (routes, len) = unsafe { &mut *self.routes.get() };
+            f(routes, len)
+        })
+    }
+}
+
+static ROUTES: RoutingTable = RoutingTable {
+    lock: Lock::new(),
+    routes: UnsafeCell::new((
+        [Route {
+            topic: 0,
+            mailbox: 0,
+            dispatch: None,
+            tag: 0,
+        }; ROUTE_CAPACITY],
+        0,
+    )),
+};
+
+/// Whether two topic names are the same; modules linked together each carry
+/// their own copy of a name
+///
+/// # Safety
+/// Both must point to valid `String` descriptors.
+unsafe fn same_topic(left: usize, right: usize) -> bool {
+    let (left, right) = (
+        *(left as *const ReplicaString),
+        *(right as *const ReplicaString),
+    );
+    left.len == right.len
+        && core::slice::from_raw_parts(left.ptr, left.len)
+            == core::slice::from_raw_parts(right.ptr, right.len)
+}
+
+/// Delivers the values published on `topic` to `mailbox` as messages with `tag`,
+/// dispatched through `dispatch`. Subscribing the same mailbox twice has no
+/// effect. Traps when `mailbox` is null (its allocation failed) or the routing
+/// table is full.
+///
+/// # Safety
+/// `topic` must point to a `String` descriptor that outlives the subscription,
+/// and `mailbox` must come from `__replica_mailbox_new`.
+#[no_mangle]
+pub unsafe extern "C" fn __replica_subscribe(
+    topic: *const ReplicaString,
+    mailbox: *mut Mailbox,
+    dispatch: DispatchFn,
+    tag: u32,
+) {
+    if mailbox.is_null() {
+        trap();
+    }
+    let route = Route {
+        topic: topic as usize,
+        mailbox: mailbox as usize,
+        dispatch: Some(dispatch),
+        tag,
+    };
+    let added = ROUTES.with(|routes, len| {
+        if routes[..*len]
+            .iter()
+            .any(|r| r.mailbox == route.mailbox && same_topic(r.topic, route.topic))
+        {
+            return true;
+        }
+        if *len == ROUTE_CAPACITY {
+            return false;
+        }
+        routes[*len] = route;
+        *len += 1;
+        true
+    });
+    if !added {
+        trap();
+    }
+}
+
+/// Removes every subscription of `mailbox`, before the mailbox is freed
+#[no_mangle]
+pub extern "C" fn __replica_unsubscribe(mailbox: *mut Mailbox) {
+    let key = mailbox as usize;
+    ROUTES.with(|routes, len| {
+        let mut kept = 0;
+        for index in 0..*len {
+            if routes[index].mailbox != key {
+                routes[kept] = routes[index];
+                kept += 1;
+            }
+        }
+        *len = kept;
+    });
+}
+
+/// Queues a copy of the `len` bytes at `value` on every mailbox subscribed to
+/// `topic` and schedules it, returning the number of mailboxes that accepted it.
+/// Subscribers whose mailbox or the run queue is full, or whose copy cannot be
+/// allocated, miss the value.
+///
+/// # Safety
+/// `topic` must point to a `String` descriptor and `value` to `len` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn __replica_publish(
+    topic: *const ReplicaString,
+    value: *const u8,
+    len: usize,
+) -> u32 {
+    fan_out(topic, value, len, |mailbox, dispatch| {
+        __replica_schedule(mailbox, dispatch)
+    })
+}
+
+/// Body of `__replica_publish`, which schedules the mailboxes with `schedule`
+///
+/// # Safety
+/// As for `__replica_publish`.
+unsafe fn fan_out(
+    topic: *const ReplicaString,
+    value: *const u8,
+    len: usize,
+    schedule: impl Fn(*mut Mailbox, DispatchFn) -> i32,
+) -> u32 {
+    let key = topic as usize;
+    let mut delivered = 0;
+    // 配送中に購読が変わってもよいよう、ロックは一件ずつ取り直す
+    let mut next = 0;
+    while let Some(route) = ROUTES.with(|routes, len| {
+        let found = routes[next.min(*len)..*len]
+            .iter()
+            .position(|r| same_topic(r.topic, key))?;
+        next += found + 1;
+        Some(routes[next - 1])
+    }) {
+        let payload = __replica_alloc(len);
+        if payload.is_null() {
+            continue;
+        }
+        core::ptr::copy_nonoverlapping(value, payload, len);
+        let mailbox = route.mailbox as *mut Mailbox;
+        if __replica_mailbox_push(mailbox, route.tag, payload, len) != 0 {
+            __replica_free(payload);
+            continue;
+        }
+        if route
+            .dispatch
+            .is_some_and(|dispatch| schedule(mailbox, dispatch) == 0)
+        {
+            delivered += 1;
+        }
+    }
+    delivered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mailbox::{__replica_mailbox_new, __replica_mailbox_pop, Message};
+
+    extern "C" fn ignore(
+        _actor: *mut u8,
+        _tag: u32,
+        _payload: *mut u8,
+        _len: usize,
+        _mailbox: *mut Mailbox,
+    ) {
+    }
+
+    fn topic(name: &'static str) -> ReplicaString {
+        ReplicaString {
+            ptr: name.as_ptr(),
+            len: name.len(),
+        }
+    }
+
+    #[test]
+    fn test_publish_reaches_every_subscriber() {
+        // 別のモジュールが持つ同じ名前も同じトピック
+        let (prices, copy, news) = (
+            topic("test.Prices"),
+            topic("test.Prices"),
+            topic("test.News"),
+        );
+        let first = __replica_mailbox_new(core::ptr::null_mut(), 4);
+        let second = __replica_mailbox_new(core::ptr::null_mut(), 4);
+        unsafe {
+            __replica_subscribe(&prices, first, ignore, 2);
+            __replica_subscribe(&prices, first, ignore, 2);
+            __replica_subscribe(&copy, second, ignore, 5);
+            __replica_subscribe(&news, second, ignore, 7);
+
+            // スケジューラは他のテストと共有なので積むだけにする
+            let publish = |topic: &ReplicaString, value: &[u8]| {
+                fan_out(topic, value.as_ptr(), value.len(), |_, _| 0)
+            };
+            let value = 42i32.to_le_bytes();
+            assert_eq!(publish(&copy, &value), 2);
+
+            let mut message = Message {
+                tag: 0,
+                payload: core::ptr::null_mut(),
+                len: 0,
+            };
+            assert_eq!(__replica_mailbox_pop(first, &mut message), 0);
+            assert_eq!((message.tag, message.len), (2, 4));
+            assert_eq!(*(message.payload as *const [u8; 4]), value);
+            assert_eq!(__replica_mailbox_pop(first, &mut message), -1);
+            assert_eq!(__replica_mailbox_pop(second, &mut message), 0);
+            assert_eq!(message.tag, 5);
+
+            __replica_unsubscribe(second);
+            assert_eq!(publish(&prices, &value), 1);
+            assert_eq!(publish(&news, &value), 0);
+            __replica_unsubscribe(first);
+        }
+    }
+}
@@ -1,60 +1,525 @@
-#[derive(Debug, Clone)]
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::str::FromStr;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum Type {
     Int,
     Float,
     String,
     Bool,
     Custom(String),
+    /// `Cache<String>`, a named type applied to type arguments. Parsed so the
+    /// grammar is settled, but rejected by semantic analysis for now, except for
+    /// `AsyncStream<T>` as the return type of a method.
+    Generic {
+        name: String,
+        args: Vec<Type>,
+    },
     Array(Box<Type>),
+    /// `[Int; 4]`, stored inline rather than behind a heap buffer
+    FixedArray(Box<Type>, usize),
     Optional(Box<Type>),
+    Tuple(Vec<Type>),
+    /// Anonymous record such as `(count: Int, ok: Bool)`
+    Record(Vec<(String, Type)>),
+    /// `func(Int) -> Bool`, a reference to a method, e.g. a callback for the host
+    Function {
+        params: Vec<Type>,
+        return_type: Option<Box<Type>>,
+    },
+}
+
+/// Writes `value` as a string literal that lexes back to it
+fn write_string(f: &mut fmt::Formatter<'_>, value: &str) -> fmt::Result {
+    f.write_str("\"")?;
+    for c in value.chars() {
+        match c {
+            '"' => f.write_str("\\\"")?,
+            '\\' => f.write_str("\\\\")?,
+            '\n' => f.write_str("\\n")?,
+            '\t' => f.write_str("\\t")?,
+            '\r' => f.write_str("\\r")?,
+            '\0' => f.write_str("\\0")?,
+            c if c.is_control() => write!(f, "\\u{{{:X}}}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    f.write_str("\"")
 }
 
-#[derive(Debug)]
+/// Writes `items` separated by commas
+fn list<T>(
+    f: &mut fmt::Formatter<'_>,
+    items: &[T],
+    item: impl Fn(&mut fmt::Formatter<'_>, &T) -> fmt::Result,
+) -> fmt::Result {
+    for (i, value) in items.iter().enumerate() {
+        if i > 0 {
+            f.write_str(", ")?;
+        }
+        item(f, value)?;
+    }
+    Ok(())
+}
+
+/// Name of the built-in generic type of values a method yields over time
+pub const ASYNC_STREAM: &str = "AsyncStream";
+
+impl Type {
+    /// Record type with `fields` in label order. Records with the same labels
+    /// are one type whatever order they are written in, so they are laid out
+    /// in this order.
+    pub fn record(mut fields: Vec<(String, Type)>) -> Type {
+        fields.sort_by(|(a, _), (b, _)| a.cmp(b));
+        Type::Record(fields)
+    }
+
+    /// Item type of `AsyncStream<T>`
+    pub fn stream_item(&self) -> Option<&Type> {
+        match self {
+            Type::Generic { name, args } if name == ASYNC_STREAM => match args.as_slice() {
+                [item] => Some(item),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+}
+
+/// Renders the type the way it is written in Replica source
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Type::Int => f.write_str("Int"),
+            Type::Float => f.write_str("Float"),
+            Type::String => f.write_str("String"),
+            Type::Bool => f.write_str("Bool"),
+            Type::Custom(name) => f.write_str(name),
+            Type::Generic { name, args } => {
+                write!(f, "{}<", name)?;
+                list(f, args, |f, arg| write!(f, "{}", arg))?;
+                f.write_str(">")
+            }
+            Type::Array(element) => write!(f, "[{}]", element),
+            Type::FixedArray(element, length) => write!(f, "[{}; {}]", element, length),
+            Type::Optional(inner) => write!(f, "{}?", inner),
+            Type::Tuple(elements) => {
+                f.write_str("(")?;
+                list(f, elements, |f, element| write!(f, "{}", element))?;
+                f.write_str(")")
+            }
+            Type::Record(fields) => {
+                f.write_str("(")?;
+                list(f, fields, |f, (name, ty)| write!(f, "{}: {}", name, ty))?;
+                f.write_str(")")
+            }
+            Type::Function {
+                params,
+                return_type,
+            } => {
+                f.write_str("func(")?;
+                list(f, params, |f, param| write!(f, "{}", param))?;
+                f.write_str(")")?;
+                match return_type {
+                    Some(return_type) => write!(f, " -> {}", return_type),
+                    None => Ok(()),
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct Parameter {
     pub name: String,
     pub param_type: Type,
     pub ownership: OwnershipType,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum ActorType {
     Distributed,
     Single,
 }
 
-#[derive(Debug)]
+/// Parsed actor. `==` and hashing compare every node, source lines included;
+/// [`Actor::structurally_eq`] ignores where the nodes came from.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct Actor {
     pub name: String,
     pub actor_type: ActorType,
     pub methods: Vec<Method>,
     pub fields: Vec<Field>,
+    pub init: Option<Init>,
+    pub deinit: Option<Deinit>,
+    pub layout: Layout,
+    pub externs: Vec<Extern>,
+    /// Modules named by the `import` lines before the declaration, in source
+    /// order. A module is the actor of `<name>.replica` next to this file.
+    pub imports: Vec<String>,
+    /// Topics declared by `topic` lines before the declaration
+    pub topics: Vec<Topic>,
+    /// Routers declared by `pool` lines before the declaration
+    pub pools: Vec<Pool>,
+    /// Plain data types declared by `struct` declarations before the actor
+    pub structs: Vec<Struct>,
+    /// Sum types declared by `enum` declarations before the actor
+    pub enums: Vec<Enum>,
+    /// Tables of literals declared by `const` members
+    pub constants: Vec<Constant>,
+    /// `@protocol(...)` constraining the order of calls to the methods
+    pub protocol: Option<Protocol>,
+    /// Text of the `///` comment before the declaration
+    pub doc: Option<String>,
+}
+
+impl Actor {
+    /// Copy of the actor with every recorded source line reset to 0
+    pub fn without_spans(&self) -> Actor {
+        let mut actor = self.clone();
+        let bodies = actor.methods.iter_mut().filter_map(|m| m.body.as_mut());
+        let init = actor.init.as_mut().map(|init| &mut init.body);
+        for body in bodies
+            .chain(init)
+            .chain(actor.deinit.as_mut().map(|deinit| &mut deinit.body))
+        {
+            body.clear_spans();
+        }
+        actor
+    }
+
+    /// Whether two actors have the same tree, regardless of source lines
+    pub fn structurally_eq(&self, other: &Actor) -> bool {
+        self.without_spans() == other.without_spans()
+    }
+}
+
+/// `topic Prices: Int`: a named channel carrying values of one type from
+/// `publish` to every instance that called `subscribe` on it
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Topic {
+    pub name: String,
+    pub value_type: Type,
+}
+
+impl Topic {
+    /// Name of the method `subscribe` delivers the topic's values to
+    pub fn handler_name(name: &str) -> String {
+        format!("on{}", name)
+    }
+}
+
+/// `struct Point { var x: Int  var y: Int }`: a plain data type, stored inline
+/// and copied by value like a record, e.g. for message payloads
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Struct {
+    pub name: String,
+    pub fields: Vec<Field>,
+    /// Text of the `///` comment before the declaration
+    pub doc: Option<String>,
 }
 
-#[derive(Debug)]
+impl Struct {
+    /// Declaration position of the field `name`
+    pub fn field_index(&self, name: &str) -> Option<usize> {
+        self.fields.iter().position(|field| field.name == name)
+    }
+}
+
+/// `enum Shape { case circle(Float)  case empty }`: a value that is one of
+/// several cases, each carrying its own associated values
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Enum {
+    pub name: String,
+    pub cases: Vec<EnumCase>,
+    /// Text of the `///` comment before the declaration
+    pub doc: Option<String>,
+}
+
+impl Enum {
+    /// Declaration position of the case `name`, which is also its discriminant
+    pub fn case_index(&self, name: &str) -> Option<usize> {
+        self.cases.iter().position(|case| case.name == name)
+    }
+}
+
+/// `case rect(Float, Float)`: a case of an enum with the types of its
+/// associated values, empty for `case empty`
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct EnumCase {
+    pub name: String,
+    pub values: Vec<Type>,
+}
+
+/// `pool Workers of Worker(size: 4, strategy: roundRobin)`: a router actor
+/// named `Workers` that owns `size` instances of `Worker` and forwards every
+/// call of an async method to one of them
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Pool {
+    pub name: String,
+    pub actor: String,
+    pub size: u32,
+    pub strategy: PoolStrategy,
+}
+
+impl Pool {
+    /// Most instances a pool may own; the router keeps one mailbox per instance
+    pub const MAX_SIZE: u32 = 64;
+}
+
+/// How a pool picks the instance that receives a call
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub enum PoolStrategy {
+    /// Each instance in turn
+    #[default]
+    RoundRobin,
+    /// `consistentHash(key)`: the instance the hash of the parameter `key`
+    /// maps to, so calls with the same key reach the same instance
+    ConsistentHash(String),
+}
+
+/// Item provided by the host rather than defined in Replica
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Extern {
+    /// `extern type FileHandle`, an opaque handle to a host object
+    Type(String),
+    /// `extern func open(path: String) -> FileHandle`
+    Function(ExternFunction),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ExternFunction {
+    pub name: String,
+    pub params: Vec<Parameter>,
+    pub return_type: Option<Type>,
+    /// `@proto("1:string, ...")`: a protobuf codec the compiler defines instead of importing
+    pub proto: Option<String>,
+    /// `@requires(capability: "net")`: capabilities the host must grant before the import may run
+    pub requires: Vec<String>,
+}
+
+/// Layout attributes of an actor's state struct, e.g. `@packed @align(8) actor Header`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct Layout {
+    /// `@packed`: fields are not padded to their natural alignment
+    pub packed: bool,
+    /// `@align(n)`: minimum alignment of the struct
+    pub align: Option<u32>,
+}
+
+/// `@protocol("open write* close")`: the order in which the actor's methods
+/// must be called, as a sequence of method names each optionally followed by
+/// `?` (at most once), `*` (any number of times) or `+` (at least once)
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Protocol {
+    pub steps: Vec<(String, Repeat)>,
+}
+
+/// How many times a step of a [`Protocol`] is taken
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Repeat {
+    Once,
+    /// `?`
+    Optional,
+    /// `*`
+    Many,
+    /// `+`
+    OneOrMore,
+}
+
+impl FromStr for Protocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let steps = s
+            .split_whitespace()
+            .map(|step| {
+                let (name, repeat) = match step.char_indices().last() {
+                    Some((i, '?')) => (&step[..i], Repeat::Optional),
+                    Some((i, '*')) => (&step[..i], Repeat::Many),
+                    Some((i, '+')) => (&step[..i], Repeat::OneOrMore),
+                    _ => (step, Repeat::Once),
+                };
+                let mut chars = name.chars();
+                let valid = chars.next().is_some_and(|c| c.is_alphabetic() || c == '_')
+                    && chars.all(|c| c.is_alphanumeric() || c == '_');
+                if valid {
+                    Ok((name.to_string(), repeat))
+                } else {
+                    Err(format!("Invalid protocol step '{}'", step))
+                }
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        if steps.is_empty() {
+            return Err("Protocol names no methods".to_string());
+        }
+        Ok(Protocol { steps })
+    }
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (name, repeat)) in self.steps.iter().enumerate() {
+            if i > 0 {
+                f.write_str(" ")?;
+            }
+            let suffix = match repeat {
+                Repeat::Once => "",
+                Repeat::Optional => "?",
+                Repeat::Many => "*",
+                Repeat::OneOrMore => "+",
+            };
+            write!(f, "{}{}", name, suffix)?;
+        }
+        Ok(())
+    }
+}
+
+/// `init(name: String) { ... }` block `<Actor>.new` runs on a new instance
+/// before returning it. Unlike methods, it may assign `let` fields.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Init {
+    pub is_immediate: bool,
+    pub params: Vec<Parameter>,
+    pub body: MethodBody,
+}
+
+/// `deinit { ... }` block run when an instance is destroyed
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Deinit {
+    pub is_async: bool,
+    pub body: MethodBody,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct Method {
     pub name: String,
     pub is_async: bool,
     pub is_sequential: bool,
     pub is_immediate: bool,
+    /// `pure func`: depends only on its arguments, so it can run at compile time
+    pub is_pure: bool,
+    /// `@nonreentrant`, on the method or its actor: other messages to the
+    /// instance are deferred while the method runs
+    pub is_nonreentrant: bool,
+    /// Parameter marked `@shardKey`, whose hash picks the node a call of the
+    /// method is sent to through `<method>.remote`
+    pub shard_key: Option<String>,
+    /// `@retry(times: n, backoff: d)`: how `<method>.remote` retries a send
+    /// the host reports as failed
+    pub retry: Option<RetryPolicy>,
+    /// `@idempotent`: remote calls carry an idempotency key, and the receiving
+    /// node drops a call whose key it has already delivered
+    pub is_idempotent: bool,
+    /// `@since(n)`: API version of the actor the method was added in
+    pub since: Option<u32>,
+    /// `@removed(n)`: API version the method was removed in. Its body stays,
+    /// so clients of older versions can still call it.
+    pub removed: Option<u32>,
     pub params: Vec<Parameter>,
     pub return_type: Option<Type>,
     pub body: Option<MethodBody>,
+    /// Text of the `///` comment before the declaration
+    pub doc: Option<String>,
+}
+
+impl Method {
+    /// Whether the method gets a `<method>.remote` proxy, which sends its calls
+    /// to another node through the host
+    pub fn is_remote(&self) -> bool {
+        self.shard_key.is_some() || self.retry.is_some() || self.is_idempotent
+    }
+
+    /// Whether clients of API version `version` can call the method
+    pub fn is_available_in(&self, version: u32) -> bool {
+        self.since.unwrap_or(1) <= version && self.removed.is_none_or(|removed| version < removed)
+    }
+
+    /// Item type of the `AsyncStream<T>` the method returns, if it is a stream
+    pub fn stream_item(&self) -> Option<&Type> {
+        self.return_type.as_ref().and_then(Type::stream_item)
+    }
+}
+
+/// `@retry(times: 3, backoff: 100ms)`: up to `times` more attempts after a
+/// failed send, waiting `backoff_ms` before the first and twice as long
+/// before each one after it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct RetryPolicy {
+    pub times: u32,
+    pub backoff_ms: u32,
+}
+
+impl RetryPolicy {
+    /// Most retries of one call; the backoff doubles with each of them
+    pub const MAX_TIMES: u32 = 10;
+    /// Longest first backoff, so the last wait stays under an hour and a half
+    pub const MAX_BACKOFF_MS: u32 = 5_000;
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct Field {
     pub name: String,
     pub field_type: Type,
     pub is_mutable: bool,
     pub ownership: OwnershipType,
+    /// Byte offset given with `@offset(n)`
+    pub offset: Option<u32>,
+    /// Text of the `///` comment before the declaration
+    pub doc: Option<String>,
 }
 
-#[derive(Debug, Clone)]
+/// `const NAME: [T] = [...]` or `const NAME: [K: V] = [key: value, ...]`, a
+/// table of literals laid out in the data segment instead of built at run time
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Constant {
+    pub name: String,
+    pub table: ConstantTable,
+    /// Text of the `///` comment before the declaration
+    pub doc: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ConstantTable {
+    /// Read like any other `[T]`
+    Array {
+        element_type: Type,
+        values: Vec<LiteralValue>,
+    },
+    /// `NAME[key]` gives a `V?` that is nil for keys missing from the table
+    Map {
+        key_type: Type,
+        value_type: Type,
+        entries: Vec<(LiteralValue, LiteralValue)>,
+    },
+}
+
+impl ConstantTable {
+    /// Type of the table's name in expressions, or `None` for a map, which
+    /// can only be indexed
+    pub fn value_type(&self) -> Option<Type> {
+        match self {
+            ConstantTable::Array { element_type, .. } => {
+                Some(Type::Array(Box::new(element_type.clone())))
+            }
+            ConstantTable::Map { .. } => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum OwnershipType {
     Owned,
     Moved,
     Shared,
     Copied,
+    /// Non-owning reference that is zeroed when the target is destroyed
+    Weak,
+    /// Non-owning reference that must not outlive the target
+    Unowned,
+    /// Parameter that borrows a caller's variable for the duration of the call
+    Inout,
 }
 
 pub struct OwnershipInfo {
@@ -62,7 +527,7 @@ pub struct OwnershipInfo {
     pub is_mutable: bool,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum Expression {
     BinaryOp {
         left: Box<Expression>,
@@ -70,18 +535,452 @@ pub enum Expression {
         right: Box<Expression>,
     },
     Literal(LiteralValue),
+    /// A local variable, parameter or method name, or else a field of the actor
     Variable(String),
+    /// `self.name`, a field of the actor the method runs on
+    Field(String),
+    /// `callee(args)`, or `callee<T, ...>(args)` with explicit type arguments,
+    /// which semantic analysis does not accept yet
+    Call {
+        callee: String,
+        type_args: Vec<Type>,
+        args: Vec<Expression>,
+    },
+    /// `&name` argument passed to an `inout` parameter
+    Inout(String),
+    Tuple(Vec<Expression>),
+    /// Record literal such as `(count: 3, ok: true)`
+    Record(Vec<(String, Expression)>),
+    /// `[1, 2, 3]`, a fixed-size array literal
+    ArrayLiteral(Vec<Expression>),
+    /// `base[index]`
+    Index {
+        base: Box<Expression>,
+        index: Box<Expression>,
+    },
+    /// Atomic built-in on a `Shared` field, e.g. `atomicAdd(&hits, 1, ordering: relaxed)`
+    Atomic {
+        operation: AtomicOperation,
+        field: String,
+        args: Vec<Expression>,
+        ordering: MemoryOrdering,
+    },
+    /// `comptime table(8)`, a call to a pure method evaluated during compilation
+    Comptime {
+        callee: String,
+        args: Vec<Expression>,
+    },
+    /// Built-in conversion called with method syntax, e.g. `id.toString()`
+    Conversion {
+        conversion: Conversion,
+        value: Box<Expression>,
+    },
+    /// Random number built-in, e.g. `randomInt(6)`
+    Random {
+        operation: RandomOperation,
+        args: Vec<Expression>,
+    },
+    /// `config("KEY")`, a `String` from the `[config]` section of the manifest
+    Config(String),
+    /// `Point(x: 1, y: 2)`, a value of a declared struct with every field labeled
+    StructLiteral {
+        name: String,
+        fields: Vec<(String, Expression)>,
+    },
+    /// `value.name`, a field of a struct value
+    Member {
+        base: Box<Expression>,
+        name: String,
+    },
+    /// `Shape.circle(1.5)` or `Shape.empty`, a case of a declared enum
+    EnumCase {
+        enum_name: String,
+        case: String,
+        args: Vec<Expression>,
+    },
+    /// `match value { pattern => result, ... }`, the result of the first arm
+    /// whose pattern matches
+    Match {
+        value: Box<Expression>,
+        arms: Vec<(Pattern, Expression)>,
+    },
+    /// `nil`, the absent value of the optional type its context expects
+    Nil,
+    /// `value!`, the value inside an optional; traps if it is `nil`
+    Unwrap(Box<Expression>),
+    /// `value ?? default`, the value inside an optional, or `default` (only
+    /// evaluated then) if it is `nil`
+    Coalesce {
+        value: Box<Expression>,
+        default: Box<Expression>,
+    },
+    /// `await call`, the result of an async method once it has run
+    Await(Box<Expression>),
+}
+
+/// Renders the expression the way it is written in Replica source, with
+/// nested binary operations and `??` in parentheses
+impl fmt::Display for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let arg = |f: &mut fmt::Formatter<'_>, arg: &Expression| write!(f, "{}", arg);
+        match self {
+            Expression::BinaryOp {
+                left,
+                operator,
+                right,
+            } => {
+                for (i, operand) in [left, right].into_iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " {} ", operator.symbol())?;
+                    }
+                    match operand.as_ref() {
+                        Expression::BinaryOp { .. } | Expression::Coalesce { .. } => {
+                            write!(f, "({})", operand)?
+                        }
+                        operand => write!(f, "{}", operand)?,
+                    }
+                }
+                Ok(())
+            }
+            Expression::Literal(LiteralValue::Int(value)) => write!(f, "{}", value),
+            Expression::Literal(LiteralValue::Float(value)) => write!(f, "{:?}", value),
+            Expression::Literal(LiteralValue::String(value)) => write_string(f, value),
+            Expression::Literal(LiteralValue::Bool(value)) => write!(f, "{}", value),
+            Expression::Variable(name) => f.write_str(name),
+            Expression::Field(name) => write!(f, "self.{}", name),
+            Expression::Call {
+                callee,
+                type_args,
+                args,
+            } => {
+                f.write_str(callee)?;
+                if !type_args.is_empty() {
+                    f.write_str("<")?;
+                    list(f, type_args, |f, ty| write!(f, "{}", ty))?;
+                    f.write_str(">")?;
+                }
+                f.write_str("(")?;
+                list(f, args, arg)?;
+                f.write_str(")")
+            }
+            Expression::Inout(name) => write!(f, "&{}", name),
+            Expression::Tuple(elements) => {
+                f.write_str("(")?;
+                list(f, elements, arg)?;
+                f.write_str(")")
+            }
+            Expression::Record(fields) => {
+                f.write_str("(")?;
+                list(f, fields, |f, (label, value)| {
+                    write!(f, "{}: {}", label, value)
+                })?;
+                f.write_str(")")
+            }
+            Expression::ArrayLiteral(elements) => {
+                f.write_str("[")?;
+                list(f, elements, arg)?;
+                f.write_str("]")
+            }
+            Expression::Index { base, index } => write!(f, "{}[{}]", base, index),
+            Expression::Atomic {
+                operation,
+                field,
+                args,
+                ordering,
+            } => {
+                write!(f, "{}(&{}", operation.name(), field)?;
+                for value in args {
+                    write!(f, ", {}", value)?;
+                }
+                write!(f, ", ordering: {})", ordering.name())
+            }
+            Expression::Comptime { callee, args } => {
+                write!(f, "comptime {}(", callee)?;
+                list(f, args, arg)?;
+                f.write_str(")")
+            }
+            Expression::Conversion {
+                conversion: Conversion::Interpolate,
+                value,
+            } => write!(f, "\"\\({})\"", value),
+            Expression::Conversion { conversion, value } => {
+                write!(f, "{}.{}()", value, conversion.name())
+            }
+            Expression::Random { operation, args } => {
+                write!(f, "{}(", operation.name())?;
+                list(f, args, arg)?;
+                f.write_str(")")
+            }
+            Expression::Config(key) => write!(f, "config(\"{}\")", key),
+            Expression::StructLiteral { name, fields } => {
+                write!(f, "{}(", name)?;
+                list(f, fields, |f, (label, value)| {
+                    write!(f, "{}: {}", label, value)
+                })?;
+                f.write_str(")")
+            }
+            Expression::Member { base, name } => write!(f, "{}.{}", base, name),
+            Expression::EnumCase {
+                enum_name,
+                case,
+                args,
+            } => {
+                write!(f, "{}.{}", enum_name, case)?;
+                if !args.is_empty() {
+                    f.write_str("(")?;
+                    list(f, args, arg)?;
+                    f.write_str(")")?;
+                }
+                Ok(())
+            }
+            Expression::Match { value, arms } => {
+                write!(f, "match {} {{ ", value)?;
+                list(f, arms, |f, (pattern, result)| {
+                    write!(f, "{} => {}", pattern, result)
+                })?;
+                f.write_str(" }")
+            }
+            Expression::Nil => f.write_str("nil"),
+            Expression::Unwrap(value) => match value.as_ref() {
+                Expression::Coalesce { .. }
+                | Expression::BinaryOp { .. }
+                | Expression::Await(_) => write!(f, "({})!", value),
+                value => write!(f, "{}!", value),
+            },
+            Expression::Coalesce { value, default } => {
+                for (i, operand) in [value, default].into_iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(" ?? ")?;
+                    }
+                    match operand.as_ref() {
+                        Expression::BinaryOp { .. } => write!(f, "({})", operand)?,
+                        Expression::Coalesce { .. } if i == 0 => write!(f, "({})", operand)?,
+                        operand => write!(f, "{}", operand)?,
+                    }
+                }
+                Ok(())
+            }
+            Expression::Await(value) => match value.as_ref() {
+                Expression::Coalesce { .. } | Expression::BinaryOp { .. } => {
+                    write!(f, "await ({})", value)
+                }
+                value => write!(f, "await {}", value),
+            },
+        }
+    }
+}
+
+/// Random number built-ins, drawn from the module's generator or the host's
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RandomOperation {
+    /// `randomInt(bound)`, an `Int` in `0..bound`, or 0 if `bound` is not positive
+    Int,
+    /// `randomFloat()`, a `Float` in `[0, 1)`
+    Float,
+}
+
+impl RandomOperation {
+    /// Resolves the name of a built-in
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "randomInt" => Some(RandomOperation::Int),
+            "randomFloat" => Some(RandomOperation::Float),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            RandomOperation::Int => "randomInt",
+            RandomOperation::Float => "randomFloat",
+        }
+    }
+
+    /// Types of the arguments the built-in takes
+    pub fn param_types(&self) -> Vec<Type> {
+        match self {
+            RandomOperation::Int => vec![Type::Int],
+            RandomOperation::Float => vec![],
+        }
+    }
+
+    pub fn result_type(&self) -> Type {
+        match self {
+            RandomOperation::Int => Type::Int,
+            RandomOperation::Float => Type::Float,
+        }
+    }
+}
+
+/// Built-in conversions between `Int` and `String`, and the conversion of
+/// interpolated values to text
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Conversion {
+    /// `Int.toString()`, the decimal text of the value
+    IntToString,
+    /// `String.toInt()`, `nil` unless the whole string is a decimal `Int`
+    StringToInt,
+    /// `\(value)` in a string literal: the text of an `Int`, `Float`, `Bool`
+    /// or `String`, as printing shows it
+    Interpolate,
+}
+
+impl Conversion {
+    /// Resolves the method name of a built-in
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "toString" => Some(Conversion::IntToString),
+            "toInt" => Some(Conversion::StringToInt),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Conversion::IntToString | Conversion::Interpolate => "toString",
+            Conversion::StringToInt => "toInt",
+        }
+    }
+
+    /// Types of the receivers the conversion is defined on
+    pub fn receiver_types(&self) -> Vec<Type> {
+        match self {
+            Conversion::IntToString => vec![Type::Int],
+            Conversion::StringToInt => vec![Type::String],
+            Conversion::Interpolate => vec![Type::Int, Type::Float, Type::Bool, Type::String],
+        }
+    }
+
+    pub fn result_type(&self) -> Type {
+        match self {
+            Conversion::IntToString | Conversion::Interpolate => Type::String,
+            Conversion::StringToInt => Type::Optional(Box::new(Type::Int)),
+        }
+    }
+}
+
+/// Atomic built-ins restricted to `Shared` fields
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AtomicOperation {
+    /// `atomicLoad(&field)`
+    Load,
+    /// `atomicStore(&field, value)`
+    Store,
+    /// `atomicAdd(&field, delta)`, returning the previous value
+    Add,
+    /// `compareExchange(&field, expected, desired)`, returning
+    /// `(exchanged: Bool, original: Int)`
+    CompareExchange,
+}
+
+impl AtomicOperation {
+    /// Resolves the name of a built-in
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "atomicLoad" => Some(AtomicOperation::Load),
+            "atomicStore" => Some(AtomicOperation::Store),
+            "atomicAdd" => Some(AtomicOperation::Add),
+            "compareExchange" => Some(AtomicOperation::CompareExchange),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            AtomicOperation::Load => "atomicLoad",
+            AtomicOperation::Store => "atomicStore",
+            AtomicOperation::Add => "atomicAdd",
+            AtomicOperation::CompareExchange => "compareExchange",
+        }
+    }
+
+    /// Number of value arguments after the field
+    pub fn arity(&self) -> usize {
+        match self {
+            AtomicOperation::Load => 0,
+            AtomicOperation::Store | AtomicOperation::Add => 1,
+            AtomicOperation::CompareExchange => 2,
+        }
+    }
+}
+
+/// Memory ordering of an atomic built-in, given as `ordering: <name>`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum MemoryOrdering {
+    Relaxed,
+    Acquire,
+    Release,
+    AcquireRelease,
+    #[default]
+    SequentiallyConsistent,
 }
 
-#[derive(Debug)]
+impl MemoryOrdering {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "relaxed" => Some(MemoryOrdering::Relaxed),
+            "acquire" => Some(MemoryOrdering::Acquire),
+            "release" => Some(MemoryOrdering::Release),
+            "acqRel" => Some(MemoryOrdering::AcquireRelease),
+            "seqCst" => Some(MemoryOrdering::SequentiallyConsistent),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            MemoryOrdering::Relaxed => "relaxed",
+            MemoryOrdering::Acquire => "acquire",
+            MemoryOrdering::Release => "release",
+            MemoryOrdering::AcquireRelease => "acqRel",
+            MemoryOrdering::SequentiallyConsistent => "seqCst",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum Operator {
     Add,
     Subtract,
     Multiply,
     Divide,
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+}
+
+impl Operator {
+    /// Symbol of the operator in source
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            Operator::Add => "+",
+            Operator::Subtract => "-",
+            Operator::Multiply => "*",
+            Operator::Divide => "/",
+            Operator::Equal => "==",
+            Operator::NotEqual => "!=",
+            Operator::Less => "<",
+            Operator::LessEqual => "<=",
+            Operator::Greater => ">",
+            Operator::GreaterEqual => ">=",
+        }
+    }
+
+    /// Whether the operator compares its operands, producing a `Bool`
+    pub fn is_comparison(&self) -> bool {
+        !matches!(
+            self,
+            Operator::Add | Operator::Subtract | Operator::Multiply | Operator::Divide
+        )
+    }
 }
 
-#[derive(Debug)]
+/// Literal in the source. Floats compare and hash by their bits, so trees stay
+/// `Eq` and a literal equals itself even if it is NaN.
+#[derive(Debug, Clone)]
 pub enum LiteralValue {
     Int(i32),
     Float(f64),
@@ -89,13 +988,245 @@ pub enum LiteralValue {
     Bool(bool),
 }
 
-#[derive(Debug)]
+impl PartialEq for LiteralValue {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (LiteralValue::Int(a), LiteralValue::Int(b)) => a == b,
+            (LiteralValue::Float(a), LiteralValue::Float(b)) => a.to_bits() == b.to_bits(),
+            (LiteralValue::String(a), LiteralValue::String(b)) => a == b,
+            (LiteralValue::Bool(a), LiteralValue::Bool(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+impl Eq for LiteralValue {}
+
+impl Hash for LiteralValue {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        std::mem::discriminant(self).hash(state);
+        match self {
+            LiteralValue::Int(value) => value.hash(state),
+            LiteralValue::Float(value) => value.to_bits().hash(state),
+            LiteralValue::String(value) => value.hash(state),
+            LiteralValue::Bool(value) => value.hash(state),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct MethodBody {
     pub statements: Vec<Statement>,
 }
 
-#[derive(Debug)]
+impl MethodBody {
+    /// Resets the source lines recorded in the body, including nested blocks
+    pub fn clear_spans(&mut self) {
+        self.update_lines(&|_| 0);
+    }
+
+    /// Moves the source lines recorded in the body by `lines`, as when lines
+    /// are inserted or removed above it
+    pub fn move_lines(&mut self, lines: isize) {
+        self.update_lines(&|line| line.saturating_add_signed(lines));
+    }
+
+    fn update_lines(&mut self, update: &impl Fn(usize) -> usize) {
+        for statement in &mut self.statements {
+            match statement {
+                Statement::Log { line, .. } => *line = update(*line),
+                Statement::If {
+                    then_body,
+                    else_body,
+                    ..
+                } => {
+                    then_body.update_lines(update);
+                    if let Some(else_body) = else_body {
+                        else_body.update_lines(update);
+                    }
+                }
+                Statement::While { body, .. } | Statement::For { body, .. } => {
+                    body.update_lines(update)
+                }
+                Statement::Match { arms, .. } => {
+                    for (_, body) in arms {
+                        body.update_lines(update);
+                    }
+                }
+                Statement::Return(_)
+                | Statement::Yield(_)
+                | Statement::Expression(_)
+                | Statement::Let { .. }
+                | Statement::Break(_)
+                | Statement::Continue(_)
+                | Statement::Publish { .. }
+                | Statement::Subscribe(_)
+                | Statement::Assign { .. } => {}
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum Statement {
     Return(Expression),
+    /// `yield value` in a method returning `AsyncStream<T>`: hands `value` to
+    /// the consumer and suspends until the next item is pulled
+    Yield(Expression),
     Expression(Expression),
+    /// `break` or `break label`
+    Break(Option<String>),
+    /// `continue` or `continue label`
+    Continue(Option<String>),
+    /// `let pattern = value` or `var pattern = value`
+    Let {
+        pattern: Pattern,
+        is_mutable: bool,
+        value: Expression,
+    },
+    /// `if condition { ... } else { ... }`; `else if` is an `If` alone in `else_body`
+    If {
+        condition: Expression,
+        then_body: MethodBody,
+        else_body: Option<MethodBody>,
+    },
+    /// `while condition { ... }`, or `label: while ...`
+    While {
+        label: Option<String>,
+        condition: Expression,
+        body: MethodBody,
+    },
+    /// `match value { pattern => { ... } ... }`: runs the body of the first arm
+    /// whose pattern matches
+    Match {
+        value: Expression,
+        arms: Vec<(Pattern, MethodBody)>,
+    },
+    /// `for variable in start..end { ... }` over the `Int`s from `start` up to but
+    /// excluding `end`, or `label: for ...`
+    For {
+        label: Option<String>,
+        variable: String,
+        start: Expression,
+        end: Expression,
+        body: MethodBody,
+    },
+    /// `log.info(message)`, with the source line the call starts on (0 if unknown)
+    Log {
+        level: LogLevel,
+        message: Expression,
+        line: usize,
+    },
+    /// `publish(Topic, value)`: sends `value` to every subscriber of the topic
+    Publish {
+        topic: String,
+        value: Expression,
+    },
+    /// `subscribe(Topic)`: has the topic's values delivered to the instance's
+    /// `on<Topic>` method
+    Subscribe(String),
+    /// `target = value`, or `target op= value` when `operator` is set.
+    /// `target` is an `Expression::Field`, or an `Expression::Variable` naming a local
+    /// `var` binding or, when no local hides it, a field.
+    Assign {
+        target: Expression,
+        operator: Option<Operator>,
+        value: Expression,
+    },
+}
+
+/// Severity of a logging built-in, from least to most severe
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub enum LogLevel {
+    #[default]
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+impl LogLevel {
+    /// Names of the `log.<level>` built-ins, also accepted by `--log-level`
+    pub const NAMES: &'static [&'static str] = &["debug", "info", "warn", "error"];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "debug" => Some(LogLevel::Debug),
+            "info" => Some(LogLevel::Info),
+            "warn" => Some(LogLevel::Warn),
+            "error" => Some(LogLevel::Error),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            LogLevel::Debug => "debug",
+            LogLevel::Info => "info",
+            LogLevel::Warn => "warn",
+            LogLevel::Error => "error",
+        }
+    }
+}
+
+impl FromStr for LogLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::from_name(s).ok_or_else(|| {
+            format!(
+                "Unknown log level '{}' (expected one of: {})",
+                s,
+                Self::NAMES.join(", ")
+            )
+        })
+    }
+}
+
+impl fmt::Display for LogLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Destructuring pattern shared by bindings and match arms
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Pattern {
+    /// `_`
+    Wildcard,
+    /// `name`, or `let name` inside a case pattern
+    Binding(String),
+    Literal(LiteralValue),
+    /// `(first, second)`
+    Tuple(Vec<Pattern>),
+    /// `.name(patterns)`, e.g. `.some(let value)` or `.none`
+    Case {
+        name: String,
+        args: Vec<Pattern>,
+    },
+}
+
+/// Renders the pattern the way it is written in Replica source
+impl fmt::Display for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Pattern::Wildcard => f.write_str("_"),
+            Pattern::Binding(name) => write!(f, "let {}", name),
+            Pattern::Literal(value) => write!(f, "{}", Expression::Literal(value.clone())),
+            Pattern::Tuple(patterns) => {
+                f.write_str("(")?;
+                list(f, patterns, |f, pattern| write!(f, "{}", pattern))?;
+                f.write_str(")")
+            }
+            Pattern::Case { name, args } => {
+                write!(f, ".{}", name)?;
+                if !args.is_empty() {
+                    f.write_str("(")?;
+                    list(f, args, |f, pattern| write!(f, "{}", pattern))?;
+                    f.write_str(")")?;
+                }
+                Ok(())
+            }
+        }
+    }
 }
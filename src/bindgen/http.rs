@@ -0,0 +1,275 @@
+//! HTTP facade for distributed actors.
+//! Describes the methods distributed actors export as `POST /<Actor>/<method>`
+//! endpoints taking a JSON object of arguments: as an OpenAPI document for
+//! `--emit openapi`, and as a routing table embedded in the module with
+//! `--http-facade`, so gateways can expose actors without reading their source.
+
+use super::{exports, Abi, Bindings, Scalar, Signature, Skipped};
+use crate::ast::{Actor, ActorType};
+use serde_json::{json, Map, Value};
+
+/// Custom section holding the routing table, one JSON route per line
+pub const ROUTES_SECTION: &str = "replica.routes";
+
+/// Endpoint calling one exported method
+pub struct Route<'a> {
+    pub actor: &'a str,
+    pub export: &'a str,
+    signature: Signature,
+}
+
+impl Route<'_> {
+    pub fn path(&self) -> String {
+        format!("/{}/{}", self.actor, self.export)
+    }
+
+    /// Module export the gateway calls, e.g. `Counter.add`
+    pub fn symbol(&self) -> String {
+        format!("{}.{}", self.actor, self.export)
+    }
+}
+
+/// Routes for the exported methods of a distributed actor. Single actors are
+/// not reachable over the network, so they have none.
+pub fn routes(actor: &Actor) -> (Vec<Route<'_>>, Vec<Skipped>) {
+    if !matches!(actor.actor_type, ActorType::Distributed) {
+        return (Vec::new(), Vec::new());
+    }
+
+    let (exports, messages) = exports(actor);
+    let mut skipped: Vec<Skipped> = messages
+        .into_iter()
+        .map(|message| Skipped {
+            actor: actor.name.clone(),
+            message,
+        })
+        .collect();
+
+    let mut routes = Vec::new();
+    for (export, signature) in exports {
+        // ハンドルはインスタンスの外では意味を持たない
+        let handle = signature
+            .params
+            .iter()
+            .find(|(_, abi)| *abi == Abi::Handle)
+            .map(|(name, _)| format!("parameter `{}`", name))
+            .or_else(|| (signature.result == Some(Abi::Handle)).then(|| "return type".to_string()));
+        match handle {
+            Some(position) => skipped.push(Skipped {
+                actor: actor.name.clone(),
+                message: format!(
+                    "method `{}` was skipped: {} is a handle, which cannot be sent over HTTP",
+                    export, position
+                ),
+            }),
+            None => routes.push(Route {
+                actor: &actor.name,
+                export,
+                signature,
+            }),
+        }
+    }
+    (routes, skipped)
+}
+
+/// Routing table stored in the `replica.routes` section. Each line is a
+/// self-contained JSON object, so tables of linked modules concatenate.
+pub fn routing_table(routes: &[Route]) -> String {
+    routes
+        .iter()
+        .map(|route| {
+            let params: Vec<Value> = route
+                .signature
+                .params
+                .iter()
+                .map(|(name, abi)| json!({ "name": name, "type": type_name(*abi) }))
+                .collect();
+            let entry = json!({
+                "method": "POST",
+                "path": route.path(),
+                "export": route.symbol(),
+                "params": params,
+                "result": route.signature.result.map(type_name),
+            });
+            entry.to_string() + "\n"
+        })
+        .collect()
+}
+
+/// OpenAPI 3.1 document describing the routes of `actors`
+pub fn openapi(actors: &[&Actor], source_name: &str) -> Bindings {
+    let mut paths = Map::new();
+    let mut skipped = Vec::new();
+    for actor in actors {
+        let (routes, mut actor_skipped) = routes(actor);
+        skipped.append(&mut actor_skipped);
+        for route in &routes {
+            paths.insert(route.path(), json!({ "post": operation(route) }));
+        }
+    }
+
+    let document = json!({
+        "openapi": "3.1.0",
+        "info": {
+            "title": source_name,
+            "version": "unversioned",
+            "description": format!("Generated by `replicac build --emit openapi` from {}. Do not edit.", source_name),
+        },
+        "paths": paths,
+    });
+    let mut source = serde_json::to_string_pretty(&document).unwrap_or_default();
+    source.push('\n');
+    Bindings { source, skipped }
+}
+
+fn operation(route: &Route) -> Value {
+    let mut operation = json!({
+        "operationId": format!("{}_{}", route.actor, route.export),
+        "tags": [route.actor],
+        "summary": format!("Calls `{}` on `{}`", route.export, route.actor),
+        "responses": match route.signature.result {
+            Some(abi) => json!({
+                "200": {
+                    "description": format!("Value returned by `{}`", route.export),
+                    "content": { "application/json": { "schema": schema(abi) } },
+                },
+            }),
+            None => json!({ "204": { "description": format!("`{}` completed", route.export) } }),
+        },
+    });
+
+    if !route.signature.params.is_empty() {
+        let properties: Map<String, Value> = route
+            .signature
+            .params
+            .iter()
+            .map(|(name, abi)| (name.clone(), schema(*abi)))
+            .collect();
+        let required: Vec<&str> = route
+            .signature
+            .params
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect();
+        operation["requestBody"] = json!({
+            "required": true,
+            "content": {
+                "application/json": {
+                    "schema": {
+                        "type": "object",
+                        "properties": properties,
+                        "required": required,
+                        "additionalProperties": false,
+                    },
+                },
+            },
+        });
+    }
+    operation
+}
+
+fn scalar_schema(scalar: Scalar) -> Value {
+    match scalar {
+        Scalar::Int => json!({ "type": "integer", "format": "int32" }),
+        Scalar::Float => json!({ "type": "number", "format": "double" }),
+        Scalar::Bool => json!({ "type": "boolean" }),
+    }
+}
+
+fn schema(abi: Abi) -> Value {
+    match abi {
+        Abi::Scalar(scalar) => scalar_schema(scalar),
+        Abi::String => json!({ "type": "string" }),
+        Abi::Array(scalar) => json!({ "type": "array", "items": scalar_schema(scalar) }),
+        Abi::Handle => unreachable!("routes never carry handles"),
+    }
+}
+
+/// Replica spelling of a routable type, e.g. `[Int]`
+fn type_name(abi: Abi) -> String {
+    let scalar = |scalar| match scalar {
+        Scalar::Int => "Int",
+        Scalar::Float => "Float",
+        Scalar::Bool => "Bool",
+    };
+    match abi {
+        Abi::Scalar(s) => scalar(s).to_string(),
+        Abi::String => "String".to_string(),
+        Abi::Array(s) => format!("[{}]", scalar(s)),
+        Abi::Handle => unreachable!("routes never carry handles"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bindgen::tests::parse;
+
+    /// `Counter` declared as `single` or, by default, distributed
+    fn counter(modifier: &str) -> Actor {
+        parse(&format!(
+            "{}actor Counter {{
+                func add(amount: Int) -> Int {{ return amount }}
+                func reset() {{}}
+                func tag(labels: [Bool]) -> String {{ return \"\" }}
+                func link(peer: Counter) {{}}
+                func pair() -> (Int, Int) {{ return (0, 0) }}
+            }}",
+            modifier
+        ))
+    }
+
+    #[test]
+    fn test_routing_table() {
+        let actor = counter("");
+        let (routes, skipped) = routes(&actor);
+        assert_eq!(routes.len(), 3);
+        assert_eq!(skipped.len(), 2);
+        assert!(skipped[0].message.contains("method `pair`"));
+        assert!(skipped[1].message.contains("parameter `peer` is a handle"));
+
+        let table = routing_table(&routes);
+        let lines: Vec<Value> = table
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0]["path"], "/Counter/add");
+        assert_eq!(lines[0]["export"], "Counter.add");
+        assert_eq!(
+            lines[0]["params"][0],
+            json!({ "name": "amount", "type": "Int" })
+        );
+        assert_eq!(lines[1]["result"], Value::Null);
+        assert_eq!(lines[2]["params"][0]["type"], "[Bool]");
+
+        // 単一アクターはネットワーク越しに公開しない
+        let single = counter("single ");
+        let (routes, skipped) = super::routes(&single);
+        assert!(routes.is_empty() && skipped.is_empty());
+    }
+
+    #[test]
+    fn test_openapi_document() {
+        let actor = counter("");
+        let bindings = openapi(&[&actor], "counter.replica");
+        let document: Value = serde_json::from_str(&bindings.source).unwrap();
+
+        assert_eq!(document["openapi"], "3.1.0");
+        let add = &document["paths"]["/Counter/add"]["post"];
+        assert_eq!(add["operationId"], "Counter_add");
+        let body = &add["requestBody"]["content"]["application/json"]["schema"];
+        assert_eq!(body["properties"]["amount"]["type"], "integer");
+        assert_eq!(body["required"], json!(["amount"]));
+        assert_eq!(
+            add["responses"]["200"]["content"]["application/json"]["schema"]["format"],
+            "int32"
+        );
+
+        let reset = &document["paths"]["/Counter/reset"]["post"];
+        assert!(reset.get("requestBody").is_none());
+        assert!(reset["responses"].get("204").is_some());
+        assert!(document["paths"].get("/Counter/pair").is_none());
+        assert_eq!(bindings.skipped.len(), 2);
+    }
+}
@@ -0,0 +1,321 @@
+//! Host bindings generation.
+//! Decides how the parameters and results of an actor's imports and exports cross
+//! the WASM boundary, and generates glue from that for Rust (`replicac bindgen`)
+//! and Python (`--emit py-bindings`) hosts embedding wasmtime, and HTTP route
+//! descriptions for gateways. Strings and scalar arrays are copied in and out of
+//! linear memory, so hosts never touch raw pointers.
+
+pub mod http;
+pub mod python;
+pub mod rust;
+
+use crate::ast::{Actor, Extern, OwnershipType, Parameter, Type};
+use std::collections::HashSet;
+
+/// Module name `wasm-ld` gives undefined functions
+const IMPORT_MODULE: &str = "env";
+
+/// Generated bindings, plus the exported methods that could not be wrapped
+#[derive(Debug)]
+pub struct Bindings {
+    pub source: String,
+    pub skipped: Vec<Skipped>,
+}
+
+/// Exported method left out of the bindings because its signature cannot cross the boundary
+#[derive(Debug)]
+pub struct Skipped {
+    pub actor: String,
+    pub message: String,
+}
+
+/// Element type of an array that can cross the host boundary
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Scalar {
+    Int,
+    Float,
+    Bool,
+}
+
+impl Scalar {
+    /// Size of one element in linear memory
+    fn size(&self) -> u32 {
+        match self {
+            Scalar::Int => 4,
+            Scalar::Float => 8,
+            Scalar::Bool => 1,
+        }
+    }
+}
+
+/// How a value crosses the host boundary
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Abi {
+    Scalar(Scalar),
+    /// UTF-8 bytes behind a `{ ptr, len }` descriptor
+    String,
+    /// Scalars behind a `{ ptr, len }` descriptor
+    Array(Scalar),
+    /// Actor pointer, extern handle or function table index
+    Handle,
+}
+
+impl Abi {
+    /// Core WASM type of the value
+    fn wasm_type(&self) -> &'static str {
+        match self {
+            Abi::Scalar(Scalar::Float) => "f64",
+            _ => "i32",
+        }
+    }
+
+    fn uses_memory(&self) -> bool {
+        matches!(self, Abi::String | Abi::Array(_))
+    }
+}
+
+/// Boundary representation of a function's parameters and result
+struct Signature {
+    /// Replica parameter names, which each backend escapes for its language
+    params: Vec<(String, Abi)>,
+    result: Option<Abi>,
+}
+
+impl Signature {
+    fn uses_memory(&self) -> bool {
+        self.params.iter().any(|(_, abi)| abi.uses_memory())
+            || self.result.is_some_and(|abi| abi.uses_memory())
+    }
+}
+
+/// Imports and exports of one actor that bindings can be generated for
+struct Interface<'a> {
+    actor: &'a str,
+    /// Parameters of `<Actor>.new`, which are those of the actor's `init`
+    constructor: Signature,
+    imports: Vec<(&'a str, Signature)>,
+    exports: Vec<(&'a str, Signature)>,
+    /// One message per exported method left out of the bindings
+    skipped: Vec<String>,
+}
+
+impl Interface<'_> {
+    fn skipped(&self) -> impl Iterator<Item = Skipped> + '_ {
+        self.skipped.iter().map(|message| Skipped {
+            actor: self.actor.to_string(),
+            message: message.clone(),
+        })
+    }
+
+    fn uses_memory(&self) -> bool {
+        self.constructor.uses_memory()
+            || self
+                .imports
+                .iter()
+                .chain(&self.exports)
+                .any(|(_, signature)| signature.uses_memory())
+    }
+}
+
+/// Works out the boundary signatures of `actor`. Imports that cannot cross the
+/// boundary are errors, since the module cannot be instantiated without them;
+/// such exports are only skipped.
+fn analyze(actor: &Actor) -> Result<Interface<'_>, String> {
+    let types = TypeMap::new(actor);
+
+    let mut imports = Vec::new();
+    for item in &actor.externs {
+        // コーデックはモジュール内で定義されるのでインポートではない
+        if let Extern::Function(function) = item {
+            if function.proto.is_some() {
+                continue;
+            }
+            let signature = types
+                .signature(&function.params, &function.return_type)
+                .map_err(|e| format!("extern func `{}` cannot be bound: {}", function.name, e))?;
+            imports.push((function.name.as_str(), signature));
+        }
+    }
+
+    // インスタンスを作れなければ他のエクスポートも呼べない
+    let init_params = actor.init.as_ref().map_or(&[][..], |init| &init.params[..]);
+    let constructor = types
+        .signature(init_params, &None)
+        .map_err(|e| format!("init cannot be bound: {}", e))?;
+
+    let (exports, skipped) = exports(actor);
+    Ok(Interface {
+        actor: &actor.name,
+        constructor,
+        imports,
+        exports,
+        skipped,
+    })
+}
+
+/// Boundary signatures of the methods `actor` exports, plus one message per
+/// method whose signature cannot cross the boundary
+fn exports(actor: &Actor) -> (Vec<(&str, Signature)>, Vec<String>) {
+    let types = TypeMap::new(actor);
+    let mut exports = Vec::new();
+    let mut skipped = Vec::new();
+    for method in &actor.methods {
+        // ストリームはフレームを受け渡す next/close の組で、単一の呼び出しではない
+        if method.stream_item().is_some() {
+            skipped.push(format!(
+                "method `{0}` was skipped: streams are pulled through `{1}.{0}.next` and `{1}.{0}.close`, which bindings do not wrap",
+                method.name, actor.name
+            ));
+            continue;
+        }
+        match types.signature(&method.params, &method.return_type) {
+            Ok(signature) => exports.push((method.name.as_str(), signature)),
+            Err(e) => skipped.push(format!("method `{}` was skipped: {}", method.name, e)),
+        }
+    }
+    (exports, skipped)
+}
+
+/// Maps Replica types to their boundary representation
+struct TypeMap<'a> {
+    actor: &'a str,
+    extern_types: HashSet<&'a str>,
+    structs: HashSet<&'a str>,
+    enums: HashSet<&'a str>,
+}
+
+impl<'a> TypeMap<'a> {
+    fn new(actor: &'a Actor) -> Self {
+        TypeMap {
+            actor: &actor.name,
+            extern_types: actor
+                .externs
+                .iter()
+                .filter_map(|item| match item {
+                    Extern::Type(name) => Some(name.as_str()),
+                    Extern::Function(_) => None,
+                })
+                .collect(),
+            structs: actor
+                .structs
+                .iter()
+                .map(|declaration| declaration.name.as_str())
+                .collect(),
+            enums: actor
+                .enums
+                .iter()
+                .map(|declaration| declaration.name.as_str())
+                .collect(),
+        }
+    }
+
+    fn abi(&self, ty: &Type) -> Result<Abi, String> {
+        match ty {
+            Type::Int => Ok(Abi::Scalar(Scalar::Int)),
+            Type::Float => Ok(Abi::Scalar(Scalar::Float)),
+            Type::Bool => Ok(Abi::Scalar(Scalar::Bool)),
+            Type::String => Ok(Abi::String),
+            Type::Array(element) => match self.abi(element)? {
+                Abi::Scalar(scalar) => Ok(Abi::Array(scalar)),
+                _ => Err(format!(
+                    "{:?} has elements that are not Int, Float or Bool",
+                    ty
+                )),
+            },
+            Type::Custom(name)
+                if name == self.actor || self.extern_types.contains(name.as_str()) =>
+            {
+                Ok(Abi::Handle)
+            }
+            Type::Function { .. } => Ok(Abi::Handle),
+            Type::Custom(name) if self.structs.contains(name.as_str()) => Err(format!(
+                "struct {} is passed by value in a target-specific way",
+                name
+            )),
+            Type::Custom(name) if self.enums.contains(name.as_str()) => Err(format!(
+                "enum {} is passed by value in a target-specific way",
+                name
+            )),
+            Type::Custom(name) | Type::Generic { name, .. } => {
+                Err(format!("unknown type `{}`", name))
+            }
+            // 値渡しの集約型はターゲット ABI によって引数の並びが変わる
+            Type::FixedArray(..) | Type::Optional(_) | Type::Tuple(_) | Type::Record(_) => Err(
+                format!("{:?} is passed by value in a target-specific way", ty),
+            ),
+        }
+    }
+
+    fn param(&self, param: &Parameter) -> Result<Abi, String> {
+        match param.ownership {
+            OwnershipType::Inout => Err(format!(
+                "`inout {}` points into the caller's frame",
+                param.name
+            )),
+            _ => self
+                .abi(&param.param_type)
+                .map_err(|e| format!("parameter `{}`: {}", param.name, e)),
+        }
+    }
+
+    fn signature(
+        &self,
+        params: &[Parameter],
+        return_type: &Option<Type>,
+    ) -> Result<Signature, String> {
+        Ok(Signature {
+            params: params
+                .iter()
+                .map(|param| Ok((param.name.clone(), self.param(param)?)))
+                .collect::<Result<_, String>>()?,
+            result: return_type
+                .as_ref()
+                .map(|ty| self.abi(ty).map_err(|e| format!("return type: {}", e)))
+                .transpose()?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer;
+    use crate::parser::Parser;
+
+    /// Actor declared by `source`, for the binding generators' tests
+    pub(super) fn parse(source: &str) -> Actor {
+        let tokens = lexer::tokenize_with_lines(source).unwrap();
+        Parser::with_lines(tokens).parse_actor().unwrap()
+    }
+
+    #[test]
+    fn test_analyze_boundary_types() {
+        let actor = parse(
+            "actor Cache {
+                func store(key: String, peer: Cache) {}
+                func bump(count: inout Int) {}
+                func matrix(rows: [[Int]]) {}
+            }",
+        );
+
+        let interface = analyze(&actor).unwrap();
+        assert_eq!(interface.exports.len(), 1);
+        assert_eq!(
+            interface.exports[0].1.params,
+            vec![
+                ("key".to_string(), Abi::String),
+                ("peer".to_string(), Abi::Handle)
+            ]
+        );
+        assert!(interface.uses_memory());
+        assert_eq!(interface.skipped.len(), 2);
+        assert!(interface.skipped[0].contains("`inout count`"));
+    }
+
+    #[test]
+    fn test_unsupported_import_is_an_error() {
+        let actor = parse("actor Cache { extern func lookup(key: Int) -> Int? }");
+        let error = analyze(&actor).err().unwrap();
+        assert!(error.contains("extern func `lookup`"), "{}", error);
+    }
+}
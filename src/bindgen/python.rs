@@ -0,0 +1,857 @@
+//! Python bindings for `--emit py-bindings`.
+//! Generates a wasmtime-py module with one class per actor, whose exported methods
+//! are coroutines. Calls into the instance are serialized by a lock and run on a
+//! worker thread, so long calls do not block the event loop. `ReplicaModule.reload`
+//! swaps in a rebuilt module between two calls, carrying the state of the live
+//! actors over as the module's `replica.state` section describes.
+
+use super::{analyze, Abi, Bindings, Interface, Scalar, Signature, IMPORT_MODULE};
+use crate::ast::Actor;
+use crate::reload::{self, Carry, STATE_SECTION};
+use replica_runtime::{ABI_HANDSHAKE_EXPORT, INIT_EXPORT, RUNTIME_ABI_VERSION};
+
+/// Generates Python bindings for the actors linked into one module from the files
+/// named in `source_name`. `wasi` adds WASI preview 1 to the linker.
+pub fn generate(actors: &[&Actor], source_name: &str, wasi: bool) -> Result<Bindings, String> {
+    let interfaces = actors
+        .iter()
+        .map(|actor| analyze(actor))
+        .collect::<Result<Vec<_>, String>>()?;
+    // 文字列のフィールドをリロードで引き継ぐにも線形メモリの操作が要る
+    let uses_memory = interfaces.iter().any(Interface::uses_memory)
+        || actors.iter().any(|actor| {
+            actor
+                .fields
+                .iter()
+                .any(|field| reload::carry(&field.field_type) == Carry::String)
+        });
+    let names: Vec<&str> = interfaces.iter().map(|interface| interface.actor).collect();
+
+    let mut source = format!(
+        "# Generated by `replicac build --emit py-bindings` from {}. Do not edit.\n\
+         \"\"\"wasmtime glue for the Replica actors {}.\n\n\
+         `String` and `[T]` values are `{{ ptr, len }}` descriptors in linear memory;\n\
+         the helpers below copy them to and from Python values. Calls into the module\n\
+         are serialized and run off the event loop, so every method is a coroutine.\n\
+         \"\"\"\n\n\
+         from __future__ import annotations\n\n\
+         import asyncio\n\
+         import json\n\
+         import struct\n\
+         import weakref\n\
+         from typing import Any, Callable, Dict, List, Protocol, Tuple\n\n\
+         import wasmtime\n\n\
+         IMPORT_MODULE = \"{}\"\n\
+         # Runtime ABI version these bindings were generated for\n\
+         ABI_VERSION = {}\n",
+        source_name,
+        names
+            .iter()
+            .map(|name| format!("`{}`", name))
+            .collect::<Vec<_>>()
+            .join(", "),
+        IMPORT_MODULE,
+        RUNTIME_ABI_VERSION
+    );
+    source.push_str(HANDLES);
+    source.push_str(&ABI_CHECK.replace("{handshake}", ABI_HANDSHAKE_EXPORT));
+    source.push_str(&INITIALIZE.replace("{init}", INIT_EXPORT));
+    source.push_str(&RELOAD.replace("{section}", STATE_SECTION));
+    if uses_memory {
+        source.push_str(GUEST_MEMORY);
+    }
+
+    let importers: Vec<&Interface> = interfaces
+        .iter()
+        .filter(|interface| !interface.imports.is_empty())
+        .collect();
+    for interface in &importers {
+        source.push_str(&imports_protocol(interface));
+    }
+    let imports_type = match importers.as_slice() {
+        [] => None,
+        [interface] => Some(format!("{}Imports", interface.actor)),
+        importers => {
+            let bases: Vec<String> = importers
+                .iter()
+                .map(|interface| format!("{}Imports", interface.actor))
+                .collect();
+            source.push_str(&format!(
+                "\n\nclass Imports({}, Protocol):\n    \"\"\"Host functions the module imports\"\"\"\n",
+                bases.join(", ")
+            ));
+            Some("Imports".to_string())
+        }
+    };
+
+    for interface in &interfaces {
+        source.push_str(&actor_class(interface));
+    }
+    source.push_str(&module_class(
+        &interfaces,
+        imports_type.as_deref(),
+        uses_memory,
+        wasi,
+    ));
+
+    Ok(Bindings {
+        source,
+        skipped: interfaces.iter().flat_map(Interface::skipped).collect(),
+    })
+}
+
+/// Python annotation of a value
+fn annotation(abi: Abi) -> &'static str {
+    match abi {
+        Abi::Scalar(Scalar::Int) | Abi::Handle => "int",
+        Abi::Scalar(Scalar::Float) => "float",
+        Abi::Scalar(Scalar::Bool) => "bool",
+        Abi::String => "str",
+        Abi::Array(Scalar::Int) => "List[int]",
+        Abi::Array(Scalar::Float) => "List[float]",
+        Abi::Array(Scalar::Bool) => "List[bool]",
+    }
+}
+
+fn result_annotation(signature: &Signature) -> &'static str {
+    signature.result.map_or("None", annotation)
+}
+
+/// `struct` format character of an array element
+fn format_code(scalar: Scalar) -> &'static str {
+    match scalar {
+        Scalar::Int => "i",
+        Scalar::Float => "d",
+        Scalar::Bool => "?",
+    }
+}
+
+/// Expression converting the Python value `value` to its WASM value
+fn lower(abi: Abi, value: &str) -> String {
+    match abi {
+        Abi::Scalar(Scalar::Int | Scalar::Float) => value.to_string(),
+        Abi::Scalar(Scalar::Bool) => format!("int({})", value),
+        Abi::Handle => format!("_i32({})", value),
+        Abi::String => format!("guest.write_string({})", value),
+        Abi::Array(scalar) => format!("guest.write_array({}, \"{}\")", value, format_code(scalar)),
+    }
+}
+
+/// Expression converting the WASM value `value` to its Python value
+fn lift(abi: Abi, value: &str) -> String {
+    match abi {
+        Abi::Scalar(Scalar::Int | Scalar::Float) => value.to_string(),
+        Abi::Scalar(Scalar::Bool) => format!("bool({})", value),
+        Abi::Handle => format!("_u32({})", value),
+        Abi::String => format!("guest.read_string({})", value),
+        Abi::Array(scalar) => format!(
+            "guest.read_array({}, \"{}\", {})",
+            value,
+            format_code(scalar),
+            scalar.size()
+        ),
+    }
+}
+
+fn val_types(abis: impl Iterator<Item = Abi>) -> String {
+    let types: Vec<String> = abis
+        .map(|abi| format!("wasmtime.ValType.{}()", abi.wasm_type()))
+        .collect();
+    format!("[{}]", types.join(", "))
+}
+
+/// `, name: annotation` for every parameter
+fn typed_params(signature: &Signature) -> String {
+    signature
+        .params
+        .iter()
+        .map(|(name, abi)| format!(", {}: {}", python_ident(name), annotation(*abi)))
+        .collect()
+}
+
+/// Protocol the host implements for an actor's imports, and the function defining them in a linker
+fn imports_protocol(interface: &Interface) -> String {
+    let mut methods = String::new();
+    let mut wrappers = String::new();
+
+    for (name, signature) in &interface.imports {
+        methods.push_str(&format!(
+            "\n    def {}(self{}) -> {}: ...\n",
+            python_ident(name),
+            typed_params(signature),
+            result_annotation(signature)
+        ));
+
+        let params: String = (0..signature.params.len())
+            .map(|i| format!(", arg{}: {}", i, annotation_of_wasm(signature.params[i].1)))
+            .collect();
+        let mut body = String::new();
+        if signature.uses_memory() {
+            body.push_str("        guest = _Guest(caller, caller)\n");
+        }
+        let args: Vec<String> = signature
+            .params
+            .iter()
+            .enumerate()
+            .map(|(i, (_, abi))| lift(*abi, &format!("arg{}", i)))
+            .collect();
+        let call = format!("imports.{}({})", python_ident(name), args.join(", "));
+        match signature.result {
+            Some(abi) => body.push_str(&format!(
+                "        result = {}\n        return {}\n",
+                call,
+                lower(abi, "result")
+            )),
+            None => body.push_str(&format!("        {}\n", call)),
+        }
+
+        wrappers.push_str(&format!(
+            "\n    def _{name}(caller: wasmtime.Caller{params}) -> {result}:\n{body}\n    \
+             linker.define_func(\n        \
+                 IMPORT_MODULE,\n        \
+                 \"{name}\",\n        \
+                 wasmtime.FuncType({param_types}, {result_types}),\n        \
+                 _{name},\n        \
+                 access_caller=True,\n    \
+             )\n",
+            name = name,
+            params = params,
+            result = signature.result.map_or("None", annotation_of_wasm),
+            body = body,
+            param_types = val_types(signature.params.iter().map(|(_, abi)| *abi)),
+            result_types = val_types(signature.result.into_iter()),
+        ));
+    }
+
+    format!(
+        "\n\nclass {actor}Imports(Protocol):\n    \
+             \"\"\"Host functions the `{actor}` actor imports\"\"\"\n{methods}\n\n\
+         def _define_{snake}_imports(linker: wasmtime.Linker, imports: {actor}Imports) -> None:\n    \
+             \"\"\"Defines the `{actor}` imports in `linker`, calling `imports`\"\"\"\n{wrappers}",
+        actor = interface.actor,
+        snake = snake_case(interface.actor),
+    )
+}
+
+/// Python annotation of a raw WASM value
+fn annotation_of_wasm(abi: Abi) -> &'static str {
+    match abi.wasm_type() {
+        "f64" => "float",
+        _ => "int",
+    }
+}
+
+/// Class exposing an actor's exported methods as coroutines
+fn actor_class(interface: &Interface) -> String {
+    let mut lookups = String::new();
+    let mut methods = String::new();
+
+    for (name, signature) in &interface.exports {
+        let ident = python_ident(name);
+        lookups.push_str(&format!(
+            "        self._{} = self._module.exports[\"{}.{}\"]\n",
+            name, interface.actor, name
+        ));
+
+        let param_names: Vec<String> = signature
+            .params
+            .iter()
+            .map(|(param, _)| python_ident(param))
+            .collect();
+        let forwarded: String = param_names
+            .iter()
+            .map(|param| format!(", {}", param))
+            .collect();
+
+        let mut body = String::new();
+        body.push_str("        store = self._module.store\n");
+        if signature.uses_memory() {
+            body.push_str("        guest = self._module.guest\n");
+        }
+        for (i, (param, (_, abi))) in param_names.iter().zip(&signature.params).enumerate() {
+            body.push_str(&format!("        arg{} = {}\n", i, lower(*abi, param)));
+        }
+        let args: String = (0..signature.params.len())
+            .map(|i| format!(", arg{}", i))
+            .collect();
+        let call = format!("self._{}(store, self._this{})", name, args);
+        body.push_str(&match signature.result {
+            Some(_) => format!("        result = {}\n", call),
+            None => format!("        {}\n", call),
+        });
+        // 引数の記述子はホストが確保したので解放し、バッファの所有権は呼び出し先に渡す
+        for (i, (_, abi)) in signature.params.iter().enumerate() {
+            if abi.uses_memory() {
+                body.push_str(&format!("        guest.free(arg{})\n", i));
+            }
+        }
+        match signature.result {
+            Some(abi) if abi.uses_memory() => body.push_str(&format!(
+                "        value = {}\n        guest.release(result)\n        return value\n",
+                lift(abi, "result")
+            )),
+            Some(abi) => body.push_str(&format!("        return {}\n", lift(abi, "result"))),
+            None => {}
+        }
+
+        methods.push_str(&format!(
+            "\n    async def {ident}(self{params}) -> {result}:\n        \
+                 \"\"\"Calls the exported `{name}` method\"\"\"\n        \
+                 return await self._module.run(self._call_{name}{forwarded})\n\n    \
+             def _call_{name}(self{params}) -> {result}:\n{body}",
+            ident = ident,
+            name = name,
+            params = typed_params(signature),
+            result = result_annotation(signature),
+            forwarded = forwarded,
+            body = body,
+        ));
+    }
+
+    // init の引数はメソッドと同じく記述子にして渡し、呼び出し後に解放する
+    let constructor = &interface.constructor;
+    let mut construct = String::new();
+    if constructor.uses_memory() {
+        construct.push_str("        guest = module.guest\n");
+    }
+    for (i, (param, abi)) in constructor.params.iter().enumerate() {
+        construct.push_str(&format!(
+            "        arg{} = {}\n",
+            i,
+            lower(*abi, &python_ident(param))
+        ));
+    }
+    let args: String = (0..constructor.params.len())
+        .map(|i| format!(", arg{}", i))
+        .collect();
+    construct.push_str(&format!(
+        "        this = module.exports[\"{}.new\"](module.store{})\n",
+        interface.actor, args
+    ));
+    for (i, (_, abi)) in constructor.params.iter().enumerate() {
+        if abi.uses_memory() {
+            construct.push_str(&format!("        guest.free(arg{})\n", i));
+        }
+    }
+    let forwarded: String = constructor
+        .params
+        .iter()
+        .map(|(param, _)| format!(", {}", python_ident(param)))
+        .collect();
+    // リロードで生成し直すときに同じ引数で init を走らせる
+    let remembered = match constructor.params.as_slice() {
+        [] => String::new(),
+        [(param, _)] => format!(", ({},)", python_ident(param)),
+        params => format!(
+            ", ({})",
+            params
+                .iter()
+                .map(|(param, _)| python_ident(param))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    };
+    if lookups.is_empty() {
+        lookups.push_str("        pass\n");
+    }
+
+    format!(
+        "\n\nclass {actor}:\n    \
+             \"\"\"Exported methods of the `{actor}` actor\"\"\"\n\n    \
+             # name of the actor in the module's {section} section\n    \
+             _ACTOR = \"{actor}\"\n\n    \
+             def __init__(self, module: ReplicaModule, this: int, params: Tuple[Any, ...] = ()) -> None:\n        \
+                 self._module = module\n        \
+                 # instance the methods run on, passed to each as the first argument\n        \
+                 self._this = this\n        \
+                 # arguments of `init`, passed again when a reload creates the instance anew\n        \
+                 self._params = params\n        \
+                 self._bind()\n        \
+                 module._live.add(self)\n\n    \
+             def _bind(self) -> None:\n        \
+                 \"\"\"Looks up the exported methods in the module's current instance\"\"\"\n\
+             {lookups}\n    \
+             @classmethod\n    \
+             async def create(cls, module: ReplicaModule{params}) -> \"{actor}\":\n        \
+                 \"\"\"Creates an instance, running `init` under the module's lock like a method call\"\"\"\n        \
+                 return cls(module, await module.run(cls._new, module{forwarded}){remembered})\n\n    \
+             @staticmethod\n    \
+             def _new(module: ReplicaModule{params}) -> int:\n\
+             {construct}        \
+                 return this\n\
+             {methods}",
+        actor = interface.actor,
+        params = typed_params(constructor),
+        section = STATE_SECTION,
+    )
+}
+
+/// Class instantiating the module, with one attribute per actor
+fn module_class(
+    interfaces: &[Interface],
+    imports_type: Option<&str>,
+    uses_memory: bool,
+    wasi: bool,
+) -> String {
+    let imports_param = imports_type
+        .map(|ty| format!(", imports: {}", ty))
+        .unwrap_or_default();
+    let mut setup = String::new();
+    let mut store_setup = String::new();
+    if wasi {
+        setup.push_str("        self._linker.define_wasi()\n");
+        store_setup.push_str("        self.store.set_wasi(wasmtime.WasiConfig())\n");
+    }
+    for interface in interfaces
+        .iter()
+        .filter(|interface| !interface.imports.is_empty())
+    {
+        setup.push_str(&format!(
+            "        _define_{}_imports(self._linker, imports)\n",
+            snake_case(interface.actor)
+        ));
+    }
+    let (guest, saved, carry_string) = if uses_memory {
+        (
+            "        self.guest = _Guest(self.store, self.exports)\n",
+            "self.store, self.exports, self.guest, self._schemas",
+            // 置き換える初期値は定数の記述子かもしれないので解放しない
+            "            if field[\"carry\"] == \"string\":\n                \
+                 (descriptor,) = struct.unpack(\"<I\", data)\n                \
+                 if descriptor != 0:\n                    \
+                     text = _Guest(store, exports).read_bytes(descriptor, 1)\n                    \
+                     data = struct.pack(\"<I\", _u32(self.guest.write_bytes(text, len(text))))\n",
+        )
+    } else {
+        ("", "self.store, self.exports, self._schemas", "")
+    };
+    let old_schemas = if uses_memory { "old[3]" } else { "old[2]" };
+    // init が引数を取るアクターはホストが create で生成する。ここではまだ
+    // 他の呼び出しがないので、ロックを取らずに生成してよい
+    let actors: String = interfaces
+        .iter()
+        .filter(|interface| interface.constructor.params.is_empty())
+        .map(|interface| {
+            format!(
+                "        self.{} = {actor}(self, {actor}._new(self))\n",
+                snake_case(interface.actor),
+                actor = interface.actor
+            )
+        })
+        .collect();
+
+    format!(
+        "\n\nclass ReplicaModule:\n    \
+             \"\"\"A compiled Replica module instantiated with wasmtime\"\"\"\n\n    \
+             def __init__(self, path: str{imports_param}) -> None:\n        \
+                 self._engine = wasmtime.Engine()\n        \
+                 self._linker = wasmtime.Linker(self._engine)\n\
+         {setup}        \
+                 # actors whose state a reload carries over\n        \
+                 self._live: Any = weakref.WeakSet()\n        \
+                 self._lock = asyncio.Lock()\n        \
+                 self._instantiate(path)\n\
+         {actors}\n    \
+             def _instantiate(self, path: str) -> None:\n        \
+                 \"\"\"Instantiates the module at `path` in a new store\"\"\"\n        \
+                 with open(path, \"rb\") as file:\n            \
+                     wasm = file.read()\n        \
+                 self.store = wasmtime.Store(self._engine)\n\
+         {store_setup}        \
+                 module = wasmtime.Module(self._engine, wasm)\n        \
+                 self.exports = self._linker.instantiate(self.store, module).exports(self.store)\n        \
+                 _check_abi(self.store, self.exports)\n        \
+                 _initialize(self.store, self.exports)\n\
+         {guest}        \
+                 self._schemas = _state_schemas(wasm)\n\n    \
+             async def run(self, call: Callable[..., Any], *args: Any) -> Any:\n        \
+                 \"\"\"Runs `call` on a worker thread, one call at a time\"\"\"\n        \
+                 async with self._lock:\n            \
+                     return await asyncio.to_thread(call, *args)\n\n    \
+             async def reload(self, path: str) -> None:\n        \
+                 \"\"\"Replaces the module with the one at `path` once the call in progress returns.\n\n        \
+                 Every live actor is created again with the arguments of its `init`, then gets\n        \
+                 the values of the fields both versions declare with the same type. Raises\n        \
+                 ReloadError and goes on running the current module when a field changed type\n        \
+                 or holds values that cannot be carried over, or a live actor was removed.\n        \
+                 \"\"\"\n        \
+                 async with self._lock:\n            \
+                     await asyncio.to_thread(self._reload, path)\n\n    \
+             def _reload(self, path: str) -> None:\n        \
+                 old = ({saved})\n        \
+                 live = list(self._live)\n        \
+                 self._instantiate(path)\n        \
+                 try:\n            \
+                     problems = _reload_problems({old_schemas}, self._schemas, [actor._ACTOR for actor in live])\n            \
+                     if problems:\n                \
+                         raise ReloadError(f\"cannot reload {{path}}: \" + \"; \".join(problems))\n            \
+                     moved = [(actor, self._carry(actor, old[0], old[1], {old_schemas})) for actor in live]\n        \
+                 except BaseException:\n            \
+                     {saved} = old\n            \
+                     raise\n        \
+                 for actor, this in moved:\n            \
+                     actor._this = this\n            \
+                     actor._bind()\n\n    \
+             def _carry(self, actor: Any, store: Any, exports: Any, schemas: Dict[str, Dict[str, Any]]) -> int:\n        \
+                 \"\"\"Creates `actor` again in the new instance and copies the fields both versions\n        \
+                 declare into it, returning the new instance\"\"\"\n        \
+                 this = _u32(actor._new(self, *actor._params))\n        \
+                 if this == 0:\n            \
+                     raise MemoryError(f\"guest memory exhausted creating {{actor._ACTOR}}\")\n        \
+                 old_memory, new_memory = exports[\"memory\"], self.exports[\"memory\"]\n        \
+                 for name, field in self._schemas[actor._ACTOR].items():\n            \
+                     before = schemas[actor._ACTOR].get(name)\n            \
+                     if before is None:\n                \
+                         continue\n            \
+                     start = _u32(actor._this) + before[\"offset\"]\n            \
+                     data = bytes(old_memory.read(store, start, start + before[\"size\"]))\n\
+         {carry_string}            \
+                     new_memory.write(self.store, data, this + field[\"offset\"])\n        \
+                 return _i32(this)\n"
+    )
+}
+
+/// Reading the `replica.state` section and checking that a reload can carry
+/// the state of the live actors over
+const RELOAD: &str = r#"
+
+
+class ReloadError(Exception):
+    """The state of the running module cannot be carried into the reloaded one"""
+
+
+def _state_schemas(wasm: bytes) -> Dict[str, Dict[str, Any]]:
+    """Fields of each actor by name, from the module's {section} sections"""
+    schemas: Dict[str, Dict[str, Any]] = {}
+    for payload in _custom_sections(wasm, "{section}"):
+        for line in payload.decode("utf-8").splitlines():
+            if line.strip():
+                entry = json.loads(line)
+                schemas[entry["actor"]] = {field["name"]: field for field in entry["fields"]}
+    return schemas
+
+
+def _custom_sections(wasm: bytes, name: str) -> List[bytes]:
+    """Payloads of the custom sections named `name`"""
+    sections = []
+    position = 8
+    while position < len(wasm):
+        section_id = wasm[position]
+        size, position = _leb128(wasm, position + 1)
+        end = position + size
+        if section_id == 0:
+            length, start = _leb128(wasm, position)
+            if wasm[start : start + length] == name.encode():
+                sections.append(wasm[start + length : end])
+        position = end
+    return sections
+
+
+def _leb128(data: bytes, position: int) -> Tuple[int, int]:
+    """Reads an unsigned LEB128 number, returning it with the position after it"""
+    value = shift = 0
+    while True:
+        byte = data[position]
+        position += 1
+        value |= (byte & 0x7F) << shift
+        shift += 7
+        if byte & 0x80 == 0:
+            return value, position
+
+
+def _reload_problems(
+    old: Dict[str, Dict[str, Any]], new: Dict[str, Dict[str, Any]], actors: List[str]
+) -> List[str]:
+    """Reasons the state of the live `actors` cannot be carried from `old` to `new`"""
+    problems = []
+    for actor in sorted(set(actors)):
+        if actor not in old:
+            problems.append(f"the running module does not describe the state of {actor}; rebuild it")
+        elif actor not in new:
+            problems.append(f"actor {actor} was removed")
+        else:
+            for name, field in old[actor].items():
+                kept = new[actor].get(name)
+                if kept is None:
+                    continue
+                if kept["type"] != field["type"]:
+                    problems.append(f"{actor}.{name} changed type from {field['type']} to {kept['type']}")
+                elif kept["carry"] == "never":
+                    problems.append(
+                        f"{actor}.{name} has type {field['type']}, whose values cannot be carried over"
+                    )
+    return problems
+"#;
+
+/// Conversions between unsigned handles and `i32` WASM values
+const HANDLES: &str = r#"
+
+def _u32(value: int) -> int:
+    return value & 0xFFFFFFFF
+
+
+def _i32(value: int) -> int:
+    value &= 0xFFFFFFFF
+    return value - (1 << 32) if value >= (1 << 31) else value
+"#;
+
+/// Handshake run before any export is used, so that a module or runtime built for
+/// another ABI fails to load instead of corrupting memory
+const ABI_CHECK: &str = r#"
+
+
+# Allocators a module may be compiled with, by their number in the handshake
+_ALLOCATORS = ("bump", "freelist", "external")
+
+
+def _check_abi(store: Any, exports: Any) -> None:
+    """Fails unless the module and the runtime linked into it both use ABI_VERSION
+    and the module's allocator is known"""
+    try:
+        handshake = exports["{handshake}"]
+    except KeyError:
+        raise RuntimeError(
+            "module does not export `{handshake}`; it was built by an older replicac, recompile it"
+        ) from None
+    versions = _u32(handshake(store))
+    allocator, module, runtime = versions >> 24, (versions >> 16) & 0xFF, versions & 0xFFFF
+    if module != ABI_VERSION:
+        raise RuntimeError(
+            f"module was compiled for runtime ABI v{module}, but these bindings expect "
+            f"v{ABI_VERSION}; regenerate them with the replicac that built the module"
+        )
+    if runtime != module:
+        raise RuntimeError(
+            f"module was compiled for runtime ABI v{module}, but is linked with a runtime "
+            f"implementing v{runtime}; rebuild libreplica_runtime.a from the same release as replicac"
+        )
+    if allocator >= len(_ALLOCATORS):
+        raise RuntimeError(
+            f"module was compiled with allocator {allocator}, which these bindings do not know; "
+            f"regenerate them with the replicac that built the module"
+        )
+"#;
+
+/// Start-up initialization run after the handshake, before any method is called
+const INITIALIZE: &str = r#"
+
+
+def _initialize(store: Any, exports: Any) -> None:
+    """Runs the module's start-up initialization; calls after the first do nothing"""
+    exports["{init}"](store)
+"#;
+
+/// Access to linear memory and the runtime allocator, emitted when a signature
+/// uses strings or arrays
+const GUEST_MEMORY: &str = r#"
+
+class _Guest:
+    """Linear memory of an instance and the runtime routines managing it"""
+
+    def __init__(self, store: Any, exports: Any) -> None:
+        self._store = store
+        self._memory = exports["memory"]
+        self._object_new = exports["__replica_object_new"]
+        self._alloc = exports["__replica_alloc"]
+        self._free = exports["__replica_free"]
+        self._release = exports["__replica_release"]
+
+    def read_bytes(self, descriptor: int, elem_size: int) -> bytes:
+        """Copies the contents of the `{ ptr, len }` descriptor at `descriptor`"""
+        descriptor = _u32(descriptor)
+        header = self._memory.read(self._store, descriptor, descriptor + 8)
+        ptr, length = struct.unpack("<II", header)
+        return bytes(self._memory.read(self._store, ptr, ptr + length * elem_size))
+
+    def write_bytes(self, data: bytes, length: int) -> int:
+        """Copies `data` into a new buffer of `length` elements, returning its descriptor"""
+        buffer = _u32(self._object_new(self._store, len(data), 0))
+        descriptor = _u32(self._alloc(self._store, 8))
+        if buffer == 0 or descriptor == 0:
+            raise MemoryError("guest memory exhausted")
+        self._memory.write(self._store, data, buffer)
+        self._memory.write(self._store, struct.pack("<II", buffer, length), descriptor)
+        return _i32(descriptor)
+
+    def free(self, descriptor: int) -> None:
+        """Frees a descriptor allocated by `write_bytes`, leaving its buffer alive"""
+        self._free(self._store, descriptor)
+
+    def release(self, descriptor: int) -> None:
+        """Drops a returned value: releases its buffer and frees its descriptor"""
+        start = _u32(descriptor)
+        (buffer,) = struct.unpack("<i", self._memory.read(self._store, start, start + 4))
+        if buffer != 0:
+            self._release(self._store, buffer)
+        self._free(self._store, descriptor)
+
+    def read_string(self, descriptor: int) -> str:
+        return self.read_bytes(descriptor, 1).decode("utf-8")
+
+    def write_string(self, value: str) -> int:
+        data = value.encode("utf-8")
+        return self.write_bytes(data, len(data))
+
+    def read_array(self, descriptor: int, code: str, size: int) -> List[Any]:
+        data = self.read_bytes(descriptor, size)
+        return list(struct.unpack(f"<{len(data) // size}{code}", data))
+
+    def write_array(self, values: List[Any], code: str) -> int:
+        return self.write_bytes(struct.pack(f"<{len(values)}{code}", *values), len(values))
+"#;
+
+/// `CounterService` becomes `counter_service`
+fn snake_case(name: &str) -> String {
+    let mut snake = String::new();
+    for (i, c) in name.chars().enumerate() {
+        if c.is_ascii_uppercase() {
+            if i > 0 {
+                snake.push('_');
+            }
+            snake.push(c.to_ascii_lowercase());
+        } else {
+            snake.push(c);
+        }
+    }
+    snake
+}
+
+/// Escapes Replica names that are Python keywords
+fn python_ident(name: &str) -> String {
+    const KEYWORDS: &[&str] = &[
+        "False", "None", "True", "and", "as", "assert", "async", "await", "break", "class",
+        "continue", "def", "del", "elif", "else", "except", "finally", "for", "from", "global",
+        "if", "import", "in", "is", "lambda", "nonlocal", "not", "or", "pass", "raise", "return",
+        "self", "try", "while", "with", "yield",
+    ];
+    if KEYWORDS.contains(&name) {
+        format!("{}_", name)
+    } else {
+        name.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bindgen::tests::parse;
+
+    const COUNTER: &str = "actor Counter { func add(amount: Int) -> Int { return amount } }";
+
+    #[test]
+    fn test_generate_python_module() {
+        let logger = parse(
+            "actor EventLog {
+                extern func emit(line: String)
+                func mean(samples: [Float]) -> Float { return 0.0 }
+                func label(from: Int) -> String { return \"\" }
+            }",
+        );
+        let counter = parse(COUNTER);
+
+        let bindings = generate(&[&logger, &counter], "app.replica", true).unwrap();
+        let source = &bindings.source;
+        assert!(source.contains("class EventLogImports(Protocol):"));
+        assert!(source.contains("    def emit(self, line: str) -> None: ..."));
+        assert!(source.contains("def _define_event_log_imports(linker: wasmtime.Linker, imports: EventLogImports) -> None:"));
+        assert!(source.contains("        imports.emit(guest.read_string(arg0))"));
+        assert!(source.contains("wasmtime.FuncType([wasmtime.ValType.i32()], [])"));
+
+        assert!(source.contains("    async def mean(self, samples: List[float]) -> float:"));
+        assert!(source.contains("        arg0 = guest.write_array(samples, \"d\")"));
+        assert!(source.contains("    async def label(self, from_: int) -> str:"));
+        assert!(source.contains("        guest.release(result)"));
+
+        assert!(
+            source.contains("    def __init__(self, path: str, imports: EventLogImports) -> None:")
+        );
+        assert!(source.contains("        self._linker.define_wasi()"));
+        // リロードでも WASI を使えるよう、ストアごとに設定する
+        assert!(source.contains(
+            "        self.store = wasmtime.Store(self._engine)\n        self.store.set_wasi(wasmtime.WasiConfig())\n"
+        ));
+        assert!(source.contains("        self.event_log = EventLog(self, EventLog._new(self))"));
+        assert!(source.contains("        self.counter = Counter(self, Counter._new(self))"));
+        assert!(bindings.skipped.is_empty());
+    }
+
+    #[test]
+    fn test_scalar_module_has_no_memory_glue() {
+        let counter = parse(COUNTER);
+        let source = generate(&[&counter], "counter.replica", false)
+            .unwrap()
+            .source;
+        assert!(source.contains("    def __init__(self, path: str) -> None:"));
+        assert!(source.contains(
+            "        this = module.exports[\"Counter.new\"](module.store)\n        return this\n"
+        ));
+        assert!(source.contains(
+            "        result = self._add(store, self._this, arg0)\n        return result\n"
+        ));
+        assert!(!source.contains("_Guest"));
+        assert!(!source.contains("define_wasi"));
+
+        assert!(source.contains(&format!("ABI_VERSION = {}\n", RUNTIME_ABI_VERSION)));
+        assert!(source.contains(
+            "        _check_abi(self.store, self.exports)\n        _initialize(self.store, self.exports)\n"
+        ));
+        assert!(source.contains("    exports[\"__replica_init\"](store)\n"));
+        assert!(source.contains("handshake = exports[\"__replica_abi_handshake\"]"));
+        assert!(source.contains("    if allocator >= len(_ALLOCATORS):\n"));
+    }
+
+    #[test]
+    fn test_init_arguments_are_passed_by_the_host() {
+        let greeter = parse("actor Greeter { init(name: String) {} }");
+        let source = generate(&[&greeter], "greeter.replica", false)
+            .unwrap()
+            .source;
+        assert!(source.contains(
+            "    def __init__(self, module: ReplicaModule, this: int, params: Tuple[Any, ...] = ()) -> None:"
+        ));
+        // init もメソッドと同じくロックの下で走る
+        assert!(source.contains(
+            "    async def create(cls, module: ReplicaModule, name: str) -> \"Greeter\":\n"
+        ));
+        assert!(source.contains(
+            "        return cls(module, await module.run(cls._new, module, name), (name,))\n"
+        ));
+        assert!(source.contains(
+            "    def _new(module: ReplicaModule, name: str) -> int:\n        guest = module.guest\n        arg0 = guest.write_string(name)\n        this = module.exports[\"Greeter.new\"](module.store, arg0)\n        guest.free(arg0)\n        return this\n"
+        ));
+        // 引数が要るのでモジュールは自動では生成しない
+        assert!(!source.contains("Greeter(self"));
+        assert!(source.contains("class _Guest"));
+    }
+
+    #[test]
+    fn test_reload_carries_state() {
+        let counter = parse(
+            "actor Counter {
+                var label: String
+                func add(amount: Int) -> Int { return amount }
+            }",
+        );
+        let source = generate(&[&counter], "counter.replica", false)
+            .unwrap()
+            .source;
+        // リロード後は新しいインスタンスのエクスポートを引き直す
+        assert!(source.contains("    _ACTOR = \"Counter\"\n"));
+        assert!(source.contains(
+            "    def _bind(self) -> None:\n        \"\"\"Looks up the exported methods in the module's current instance\"\"\"\n        self._add = self._module.exports[\"Counter.add\"]\n"
+        ));
+        assert!(source.contains("        module._live.add(self)\n"));
+        assert!(source.contains("    async def reload(self, path: str) -> None:\n"));
+        assert!(source.contains("    for payload in _custom_sections(wasm, \"replica.state\"):\n"));
+        assert!(source.contains("        this = _u32(actor._new(self, *actor._params))\n"));
+        // 失敗したら元のモジュールに戻す
+        assert!(source.contains(
+            "            self.store, self.exports, self.guest, self._schemas = old\n            raise\n"
+        ));
+        // 文字列のフィールドは新しいモジュールに記述子を作り直して引き継ぐ
+        assert!(source.contains("class _Guest"));
+        assert!(source.contains(
+            "                    text = _Guest(store, exports).read_bytes(descriptor, 1)\n"
+        ));
+    }
+}
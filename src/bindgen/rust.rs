@@ -0,0 +1,667 @@
+//! Rust bindings for `replicac bindgen`.
+//! Generates a Rust module that registers an actor's `extern func` imports with a
+//! wasmtime `Linker` through a typed trait, and wraps its exported methods in typed
+//! functions.
+
+use super::{analyze, Abi, Bindings, Scalar, Signature, IMPORT_MODULE};
+use crate::ast::Actor;
+use replica_runtime::{ABI_HANDSHAKE_EXPORT, INIT_EXPORT, RUNTIME_ABI_VERSION};
+
+impl Scalar {
+    fn rust_type(&self) -> &'static str {
+        match self {
+            Scalar::Int => "i32",
+            Scalar::Float => "f64",
+            Scalar::Bool => "bool",
+        }
+    }
+
+    /// Suffix of the generated `encode_*`/`decode_*` helpers
+    fn helper(&self) -> &'static str {
+        match self {
+            Scalar::Int => "ints",
+            Scalar::Float => "floats",
+            Scalar::Bool => "bools",
+        }
+    }
+}
+
+impl Abi {
+    /// Rust type of a value owned by the receiver
+    fn owned_type(&self) -> String {
+        match self {
+            Abi::Scalar(scalar) => scalar.rust_type().to_string(),
+            Abi::String => "String".to_string(),
+            Abi::Array(scalar) => format!("Vec<{}>", scalar.rust_type()),
+            Abi::Handle => "u32".to_string(),
+        }
+    }
+
+    /// Rust type of an argument the host passes in
+    fn borrowed_type(&self) -> String {
+        match self {
+            Abi::String => "&str".to_string(),
+            Abi::Array(scalar) => format!("&[{}]", scalar.rust_type()),
+            _ => self.owned_type(),
+        }
+    }
+
+    /// Expression converting the Rust value `value` to its WASM value
+    fn lower(&self, value: &str, store: &str) -> String {
+        match self {
+            Abi::Scalar(Scalar::Int | Scalar::Float) => value.to_string(),
+            Abi::Scalar(Scalar::Bool) | Abi::Handle => format!("{} as i32", value),
+            Abi::String => format!(
+                "guest.write_bytes({}, {}.as_bytes(), {}.len())?",
+                store, value, value
+            ),
+            Abi::Array(scalar) => format!(
+                "guest.write_bytes({}, &encode_{}(&{}), {}.len())?",
+                store,
+                scalar.helper(),
+                value,
+                value
+            ),
+        }
+    }
+
+    /// Expression converting the WASM value `value` to its Rust value
+    fn lift(&self, value: &str, store: &str) -> String {
+        match self {
+            Abi::Scalar(Scalar::Int | Scalar::Float) => value.to_string(),
+            Abi::Scalar(Scalar::Bool) => format!("{} != 0", value),
+            Abi::Handle => format!("{} as u32", value),
+            Abi::String => format!("decode_string(guest.read_bytes({}, {}, 1)?)?", store, value),
+            Abi::Array(scalar) => format!(
+                "decode_{}(guest.read_bytes({}, {}, {})?)",
+                scalar.helper(),
+                store,
+                value,
+                scalar.size()
+            ),
+        }
+    }
+}
+
+impl Signature {
+    /// `(i32, f64)` tuple of the WASM parameter types of an exported method, as
+    /// wasmtime expects for typed functions. The instance comes first.
+    fn wasm_params(&self) -> String {
+        tuple(
+            std::iter::once("i32".to_string()).chain(
+                self.params
+                    .iter()
+                    .map(|(_, abi)| abi.wasm_type().to_string()),
+            ),
+        )
+    }
+
+    fn wasm_result(&self) -> &'static str {
+        self.result.map_or("()", |abi| abi.wasm_type())
+    }
+
+    fn rust_result(&self) -> String {
+        self.result.map_or("()".to_string(), |abi| abi.owned_type())
+    }
+
+    /// `(self.this, arg0, arg1)` tuple passed to an exported method
+    fn arg_tuple(&self) -> String {
+        tuple(
+            std::iter::once("self.this".to_string())
+                .chain((0..self.params.len()).map(|i| format!("arg{}", i))),
+        )
+    }
+}
+
+/// Rust tuple of `items`, with the trailing comma a single element needs
+fn tuple(items: impl Iterator<Item = String>) -> String {
+    let items: Vec<String> = items.collect();
+    match items.as_slice() {
+        [item] => format!("({},)", item),
+        items => format!("({})", items.join(", ")),
+    }
+}
+
+/// Generates Rust bindings for `actor`, compiled from the file named `source_name`
+pub fn generate(actor: &Actor, source_name: &str) -> Result<Bindings, String> {
+    let interface = analyze(actor)?;
+    let uses_memory = interface.uses_memory();
+
+    let mut source = format!(
+        "// Generated by `replicac bindgen` from {}. Do not edit.\n\
+         //\n\
+         // wasmtime glue for the `{}` actor. `String` and `[T]` values are\n\
+         // `{{ ptr, len }}` descriptors in linear memory; the functions below copy them\n\
+         // to and from Rust values.\n\n\
+         #![allow(dead_code, unused_imports, clippy::all)]\n\n\
+         use wasmtime::{{AsContext, AsContextMut, Caller, Instance, Linker, Memory, TypedFunc}};\n\n\
+         /// Module the actor's imports are resolved from\n\
+         pub const IMPORT_MODULE: &str = \"{}\";\n\n\
+         /// Runtime ABI version these bindings were generated for\n\
+         pub const ABI_VERSION: u32 = {};\n",
+        source_name, interface.actor, IMPORT_MODULE, RUNTIME_ABI_VERSION
+    );
+    source.push_str(&ABI_CHECK.replace("{handshake}", ABI_HANDSHAKE_EXPORT));
+    source.push_str(&INITIALIZE.replace("{init}", INIT_EXPORT));
+
+    if !interface.imports.is_empty() {
+        source.push_str(&imports_trait(interface.actor, &interface.imports));
+    }
+    source.push_str(&exports_struct(
+        interface.actor,
+        &interface.constructor,
+        &interface.exports,
+        uses_memory,
+    ));
+    if uses_memory {
+        source.push_str(GUEST_MEMORY);
+    }
+
+    Ok(Bindings {
+        source,
+        skipped: interface.skipped().collect(),
+    })
+}
+
+/// Trait the host implements for the actor's imports, and the linker glue calling it
+fn imports_trait(actor: &str, imports: &[(&str, Signature)]) -> String {
+    let trait_name = format!("{}Imports", actor);
+    let mut methods = String::new();
+    let mut wrappers = String::new();
+
+    for (name, signature) in imports {
+        let params: Vec<String> = signature
+            .params
+            .iter()
+            .map(|(param, abi)| format!(", {}: {}", rust_ident(param), abi.owned_type()))
+            .collect();
+        let result = match signature.result {
+            Some(abi) => format!(" -> {}", abi.owned_type()),
+            None => String::new(),
+        };
+        methods.push_str(&format!(
+            "    fn {}(&mut self{}){};\n",
+            rust_ident(name),
+            params.concat(),
+            result
+        ));
+
+        let closure_params: Vec<String> = signature
+            .params
+            .iter()
+            .enumerate()
+            .map(|(i, (_, abi))| format!(", arg{}: {}", i, abi.wasm_type()))
+            .collect();
+        let mut body = String::new();
+        if signature.uses_memory() {
+            body.push_str("            let guest = Guest::from_caller(&mut caller)?;\n");
+        }
+        for (i, (_, abi)) in signature.params.iter().enumerate() {
+            let arg = format!("arg{}", i);
+            body.push_str(&format!(
+                "            let {} = {};\n",
+                arg,
+                abi.lift(&arg, "&mut caller")
+            ));
+        }
+        let args: Vec<String> = (0..signature.params.len())
+            .map(|i| format!("arg{}", i))
+            .collect();
+        let call = format!(
+            "get(caller.data_mut()).{}({})",
+            rust_ident(name),
+            args.join(", ")
+        );
+        body.push_str(&match signature.result {
+            Some(abi) => format!(
+                "            let result = {};\n            Ok({})\n",
+                call,
+                abi.lower("result", "&mut caller")
+            ),
+            None => format!("            {};\n            Ok(())\n", call),
+        });
+
+        wrappers.push_str(&format!(
+            "    linker.func_wrap(\n        IMPORT_MODULE,\n        \"{}\",\n        move |mut caller: Caller<'_, T>{}| -> wasmtime::Result<{}> {{\n{}        }},\n    )?;\n",
+            name,
+            closure_params.concat(),
+            signature.wasm_result(),
+            body
+        ));
+    }
+
+    format!(
+        "\n/// Host functions the `{actor}` actor imports\n\
+         pub trait {trait_name} {{\n{methods}}}\n\n\
+         /// Defines the `{actor}` imports in `linker`, calling the implementation `get`\n\
+         /// returns for the store's data\n\
+         pub fn add_to_linker<T, U>(\n    \
+             linker: &mut Linker<T>,\n    \
+             get: impl Fn(&mut T) -> &mut U + Send + Sync + Copy + 'static,\n\
+         ) -> wasmtime::Result<()>\n\
+         where\n    \
+             T: 'static,\n    \
+             U: {trait_name},\n\
+         {{\n{wrappers}    Ok(())\n}}\n"
+    )
+}
+
+/// Struct holding typed handles to the actor's exported methods
+fn exports_struct(
+    actor: &str,
+    constructor: &Signature,
+    exports: &[(&str, Signature)],
+    uses_memory: bool,
+) -> String {
+    // 各メソッドは `<Actor>.new` が返すインスタンスを第一引数に取る
+    let mut fields = String::from("    this: i32,\n");
+    let mut lookups = String::from("            this,\n");
+    let new_params: Vec<String> = constructor
+        .params
+        .iter()
+        .map(|(param, abi)| format!(", {}: {}", rust_ident(param), abi.borrowed_type()))
+        .collect();
+    let mut construct = String::new();
+    if uses_memory {
+        construct.push_str("        let guest = Guest::from_instance(&mut store, instance)?;\n");
+    }
+    for (i, (param, abi)) in constructor.params.iter().enumerate() {
+        construct.push_str(&format!(
+            "        let arg{} = {};\n",
+            i,
+            abi.lower(&rust_ident(param), "&mut store")
+        ));
+    }
+    let args: Vec<String> = (0..constructor.params.len())
+        .map(|i| format!("arg{}", i))
+        .collect();
+    construct.push_str(&format!(
+        "        let this = instance\n            \
+             .get_typed_func::<{}, i32>(&mut store, \"{}.new\")?\n            \
+             .call(&mut store, {})?;\n",
+        tuple(
+            constructor
+                .params
+                .iter()
+                .map(|(_, abi)| abi.wasm_type().to_string())
+        ),
+        actor,
+        tuple(args.into_iter())
+    ));
+    for (i, (_, abi)) in constructor.params.iter().enumerate() {
+        if abi.uses_memory() {
+            construct.push_str(&format!("        guest.free(&mut store, arg{})?;\n", i));
+        }
+    }
+    let mut methods = String::new();
+    if uses_memory {
+        fields.push_str("    guest: Guest,\n");
+        lookups.push_str("            guest,\n");
+    }
+
+    for (name, signature) in exports {
+        let field = format!("func_{}", name);
+        fields.push_str(&format!(
+            "    {}: TypedFunc<{}, {}>,\n",
+            field,
+            signature.wasm_params(),
+            signature.wasm_result()
+        ));
+        lookups.push_str(&format!(
+            "            {}: instance.get_typed_func(&mut store, \"{}.{}\")?,\n",
+            field, actor, name
+        ));
+
+        let params: Vec<String> = signature
+            .params
+            .iter()
+            .map(|(param, abi)| format!(", {}: {}", rust_ident(param), abi.borrowed_type()))
+            .collect();
+        let mut body = String::new();
+        if signature.uses_memory() {
+            body.push_str("        let guest = &self.guest;\n");
+        }
+        for (i, (param, abi)) in signature.params.iter().enumerate() {
+            body.push_str(&format!(
+                "        let arg{} = {};\n",
+                i,
+                abi.lower(&rust_ident(param), "&mut store")
+            ));
+        }
+        let call = format!(
+            "self.{}.call(&mut store, {})?",
+            field,
+            signature.arg_tuple()
+        );
+        body.push_str(&match signature.result {
+            Some(_) => format!("        let result = {};\n", call),
+            None => format!("        {};\n", call),
+        });
+        // 引数の記述子はホストが確保したので解放し、バッファの所有権は呼び出し先に渡す
+        for (i, (_, abi)) in signature.params.iter().enumerate() {
+            if abi.uses_memory() {
+                body.push_str(&format!("        guest.free(&mut store, arg{})?;\n", i));
+            }
+        }
+        match signature.result {
+            Some(abi) if abi.uses_memory() => {
+                body.push_str(&format!(
+                    "        let value = {};\n        guest.release(&mut store, result)?;\n        Ok(value)\n",
+                    abi.lift("result", "&mut store")
+                ));
+            }
+            Some(abi) => body.push_str(&format!(
+                "        Ok({})\n",
+                abi.lift("result", "&mut store")
+            )),
+            None => body.push_str("        Ok(())\n"),
+        }
+
+        methods.push_str(&format!(
+            "\n    /// Calls the exported `{}` method\n    pub fn {}(&self, mut store: impl AsContextMut{}) -> wasmtime::Result<{}> {{\n{}    }}\n",
+            name,
+            rust_ident(name),
+            params.concat(),
+            signature.rust_result(),
+            body
+        ));
+    }
+
+    format!(
+        "\n/// Typed handles to the methods the `{actor}` actor exports\n\
+         pub struct {actor} {{\n{fields}}}\n\n\
+         impl {actor} {{\n    \
+             /// Looks up the exports of an instantiated `{actor}` module and creates\n    \
+             /// the actor instance its methods run on{init_doc}\n    \
+             pub fn new(mut store: impl AsContextMut, instance: &Instance{new_params}) -> wasmtime::Result<Self> {{\n        \
+                 check_abi(&mut store, instance)?;\n        \
+                 initialize(&mut store, instance)?;\n\
+                 {construct}        \
+                 Ok({actor} {{\n{lookups}        }})\n    \
+             }}\n{methods}}}\n",
+        new_params = new_params.concat(),
+        init_doc = if constructor.params.is_empty() {
+            ""
+        } else {
+            ", passing the arguments to its `init`"
+        }
+    )
+}
+
+/// Handshake run before any export is used, so that a module or runtime built for
+/// another ABI fails to load instead of corrupting memory
+const ABI_CHECK: &str = r#"
+/// Allocators a module may be compiled with, by their number in the handshake
+const ALLOCATORS: [&str; 3] = ["bump", "freelist", "external"];
+
+/// Fails unless the module and the runtime linked into it both use [`ABI_VERSION`]
+/// and the module's allocator is known
+fn check_abi(mut store: impl AsContextMut, instance: &Instance) -> wasmtime::Result<()> {
+    let handshake: TypedFunc<(), i32> = instance
+        .get_typed_func(&mut store, "{handshake}")
+        .map_err(|_| {
+            wasmtime::Error::msg(
+                "module does not export `{handshake}`; it was built by an older replicac, recompile it",
+            )
+        })?;
+    let versions = handshake.call(&mut store, ())? as u32;
+    let (allocator, module, runtime) = (versions >> 24, (versions >> 16) & 0xff, versions & 0xffff);
+    if module != ABI_VERSION {
+        return Err(wasmtime::Error::msg(format!(
+            "module was compiled for runtime ABI v{}, but these bindings expect v{}; regenerate them with the replicac that built the module",
+            module, ABI_VERSION
+        )));
+    }
+    if runtime != module {
+        return Err(wasmtime::Error::msg(format!(
+            "module was compiled for runtime ABI v{}, but is linked with a runtime implementing v{}; rebuild libreplica_runtime.a from the same release as replicac",
+            module, runtime
+        )));
+    }
+    if allocator as usize >= ALLOCATORS.len() {
+        return Err(wasmtime::Error::msg(format!(
+            "module was compiled with allocator {}, which these bindings do not know; regenerate them with the replicac that built the module",
+            allocator
+        )));
+    }
+    Ok(())
+}
+"#;
+
+/// Start-up initialization run after the handshake, before any method is called
+const INITIALIZE: &str = r#"
+/// Runs the module's start-up initialization; calls after the first do nothing
+fn initialize(mut store: impl AsContextMut, instance: &Instance) -> wasmtime::Result<()> {
+    let init: TypedFunc<(), ()> = instance.get_typed_func(&mut store, "{init}")?;
+    init.call(&mut store, ())
+}
+"#;
+
+/// Access to linear memory and the runtime allocator, emitted when a signature
+/// uses strings or arrays
+const GUEST_MEMORY: &str = r#"
+/// Linear memory of an instance and the runtime routines managing it
+struct Guest {
+    memory: Memory,
+    object_new: TypedFunc<(i32, i32), i32>,
+    alloc: TypedFunc<i32, i32>,
+    free: TypedFunc<i32, ()>,
+    release: TypedFunc<i32, i32>,
+}
+
+fn caller_func<T>(caller: &mut Caller<'_, T>, name: &str) -> wasmtime::Result<wasmtime::Func> {
+    caller
+        .get_export(name)
+        .and_then(|export| export.into_func())
+        .ok_or_else(|| wasmtime::Error::msg(format!("module does not export `{}`", name)))
+}
+
+impl Guest {
+    fn from_instance(mut store: impl AsContextMut, instance: &Instance) -> wasmtime::Result<Self> {
+        Ok(Guest {
+            memory: instance
+                .get_memory(&mut store, "memory")
+                .ok_or_else(|| wasmtime::Error::msg("module does not export `memory`"))?,
+            object_new: instance.get_typed_func(&mut store, "__replica_object_new")?,
+            alloc: instance.get_typed_func(&mut store, "__replica_alloc")?,
+            free: instance.get_typed_func(&mut store, "__replica_free")?,
+            release: instance.get_typed_func(&mut store, "__replica_release")?,
+        })
+    }
+
+    fn from_caller<T>(caller: &mut Caller<'_, T>) -> wasmtime::Result<Self> {
+        let memory = caller
+            .get_export("memory")
+            .and_then(|export| export.into_memory())
+            .ok_or_else(|| wasmtime::Error::msg("module does not export `memory`"))?;
+        Ok(Guest {
+            memory,
+            object_new: caller_func(caller, "__replica_object_new")?.typed(&*caller)?,
+            alloc: caller_func(caller, "__replica_alloc")?.typed(&*caller)?,
+            free: caller_func(caller, "__replica_free")?.typed(&*caller)?,
+            release: caller_func(caller, "__replica_release")?.typed(&*caller)?,
+        })
+    }
+
+    /// Copies the contents of the `{ ptr, len }` descriptor at `descriptor`
+    fn read_bytes(&self, store: impl AsContext, descriptor: i32, elem_size: usize) -> wasmtime::Result<Vec<u8>> {
+        let mut header = [0u8; 8];
+        self.memory.read(&store, descriptor as u32 as usize, &mut header)?;
+        let ptr = u32::from_le_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        let len = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as usize;
+        let mut bytes = vec![0u8; len * elem_size];
+        self.memory.read(&store, ptr, &mut bytes)?;
+        Ok(bytes)
+    }
+
+    /// Copies `bytes` into a new buffer holding `len` elements, returning its descriptor
+    fn write_bytes(&self, mut store: impl AsContextMut, bytes: &[u8], len: usize) -> wasmtime::Result<i32> {
+        let buffer = self.object_new.call(&mut store, (bytes.len() as i32, 0))?;
+        let descriptor = self.alloc.call(&mut store, 8)?;
+        if buffer == 0 || descriptor == 0 {
+            return Err(wasmtime::Error::msg("guest memory exhausted"));
+        }
+        self.memory.write(&mut store, buffer as u32 as usize, bytes)?;
+        let mut header = [0u8; 8];
+        header[..4].copy_from_slice(&(buffer as u32).to_le_bytes());
+        header[4..].copy_from_slice(&(len as u32).to_le_bytes());
+        self.memory.write(&mut store, descriptor as u32 as usize, &header)?;
+        Ok(descriptor)
+    }
+
+    /// Frees a descriptor allocated by `write_bytes`, leaving its buffer alive
+    fn free(&self, store: impl AsContextMut, descriptor: i32) -> wasmtime::Result<()> {
+        self.free.call(store, descriptor)
+    }
+
+    /// Drops a returned value: releases its buffer and frees its descriptor
+    fn release(&self, mut store: impl AsContextMut, descriptor: i32) -> wasmtime::Result<()> {
+        let mut header = [0u8; 4];
+        self.memory.read(&store, descriptor as u32 as usize, &mut header)?;
+        let buffer = i32::from_le_bytes(header);
+        if buffer != 0 {
+            self.release.call(&mut store, buffer)?;
+        }
+        self.free.call(&mut store, descriptor)
+    }
+}
+
+fn decode_string(bytes: Vec<u8>) -> wasmtime::Result<String> {
+    String::from_utf8(bytes).map_err(|e| wasmtime::Error::msg(format!("invalid UTF-8 from guest: {}", e)))
+}
+
+fn decode_ints(bytes: Vec<u8>) -> Vec<i32> {
+    bytes
+        .chunks_exact(4)
+        .map(|chunk| i32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]))
+        .collect()
+}
+
+fn decode_floats(bytes: Vec<u8>) -> Vec<f64> {
+    bytes
+        .chunks_exact(8)
+        .map(|chunk| {
+            let mut bits = [0u8; 8];
+            bits.copy_from_slice(chunk);
+            f64::from_le_bytes(bits)
+        })
+        .collect()
+}
+
+fn decode_bools(bytes: Vec<u8>) -> Vec<bool> {
+    bytes.into_iter().map(|byte| byte != 0).collect()
+}
+
+fn encode_ints(values: &[i32]) -> Vec<u8> {
+    values.iter().flat_map(|value| value.to_le_bytes()).collect()
+}
+
+fn encode_floats(values: &[f64]) -> Vec<u8> {
+    values.iter().flat_map(|value| value.to_le_bytes()).collect()
+}
+
+fn encode_bools(values: &[bool]) -> Vec<u8> {
+    values.iter().map(|&value| value as u8).collect()
+}
+"#;
+
+/// Escapes Replica names that are Rust keywords
+fn rust_ident(name: &str) -> String {
+    const KEYWORDS: &[&str] = &[
+        "abstract", "as", "async", "await", "become", "box", "break", "const", "continue", "do",
+        "dyn", "else", "enum", "extern", "false", "final", "fn", "for", "if", "impl", "in", "let",
+        "loop", "macro", "match", "mod", "move", "mut", "override", "priv", "pub", "ref", "return",
+        "static", "struct", "trait", "true", "try", "type", "typeof", "unsafe", "unsized", "use",
+        "virtual", "where", "while", "yield",
+    ];
+    match name {
+        // 生識別子にできないキーワードと生成コードが使う名前
+        "self" | "Self" | "super" | "crate" | "store" => format!("{}_", name),
+        _ if KEYWORDS.contains(&name) => format!("r#{}", name),
+        _ => name.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bindgen::tests::parse;
+
+    #[test]
+    fn test_generate_imports_and_exports() {
+        let actor = parse(
+            "actor Files {
+                extern type FileHandle
+                extern func open(path: String) -> FileHandle
+                extern func flush(file: FileHandle, sync: Bool)
+                func sum(values: [Float]) -> Float { return 0.0 }
+                func greet(type: String) -> String { return type }
+                func reset() {}
+                func pair() -> (Int, Int) { return (0, 0) }
+            }",
+        );
+
+        let bindings = generate(&actor, "files.replica").unwrap();
+        let source = &bindings.source;
+        assert!(source.contains("pub trait FilesImports {"));
+        assert!(source.contains("    fn open(&mut self, path: String) -> u32;"));
+        assert!(source.contains("    fn flush(&mut self, file: u32, sync: bool);"));
+        assert!(source.contains(
+            "move |mut caller: Caller<'_, T>, arg0: i32, arg1: i32| -> wasmtime::Result<()>"
+        ));
+        assert!(source.contains("let arg1 = arg1 != 0;"));
+
+        assert!(source.contains("    func_sum: TypedFunc<(i32, i32), f64>,"));
+        assert!(source.contains("func_sum: instance.get_typed_func(&mut store, \"Files.sum\")?,"));
+        assert!(source.contains("pub fn sum(&self, mut store: impl AsContextMut, values: &[f64]) -> wasmtime::Result<f64>"));
+        assert!(source.contains("&encode_floats(&values)"));
+        assert!(source.contains("pub fn greet(&self, mut store: impl AsContextMut, r#type: &str) -> wasmtime::Result<String>"));
+        assert!(source.contains("guest.release(&mut store, result)?;"));
+        assert!(source.contains("    func_reset: TypedFunc<(i32,), ()>,"));
+        assert!(source.contains(".get_typed_func::<(), i32>(&mut store, \"Files.new\")?"));
+        assert!(source.contains("struct Guest {"));
+
+        assert_eq!(bindings.skipped.len(), 1);
+        assert!(bindings.skipped[0].message.contains("method `pair`"));
+    }
+
+    #[test]
+    fn test_scalar_only_bindings_skip_memory_glue() {
+        let actor = parse(
+            "actor Files {
+                extern func now() -> Float
+                func add(amount: Int) -> Int { return amount }
+            }",
+        );
+        let source = generate(&actor, "clock.replica").unwrap().source;
+        assert!(source.contains("    fn now(&mut self) -> f64;"));
+        assert!(source.contains("let result = self.func_add.call(&mut store, (self.this, arg0))?;"));
+        assert!(!source.contains("Guest"));
+
+        // エクスポートを使う前に ABI を照合する
+        assert!(source.contains(&format!(
+            "pub const ABI_VERSION: u32 = {};",
+            RUNTIME_ABI_VERSION
+        )));
+        assert!(source.contains(
+            "        check_abi(&mut store, instance)?;\n        initialize(&mut store, instance)?;\n        let this = instance\n"
+        ));
+        assert!(source.contains(".get_typed_func(&mut store, \"__replica_init\")"));
+        assert!(source.contains(".get_typed_func(&mut store, \"__replica_abi_handshake\")"));
+        assert!(source.contains("if allocator as usize >= ALLOCATORS.len() {"));
+    }
+
+    #[test]
+    fn test_constructor_passes_init_arguments() {
+        let actor = parse("actor Files { init(root: String, limit: Int) {} }");
+        let source = generate(&actor, "files.replica").unwrap().source;
+        assert!(source.contains(
+            "pub fn new(mut store: impl AsContextMut, instance: &Instance, root: &str, limit: i32) -> wasmtime::Result<Self>"
+        ));
+        assert!(source.contains(".get_typed_func::<(i32, i32), i32>(&mut store, \"Files.new\")?"));
+        assert!(source.contains(".call(&mut store, (arg0, arg1))?;"));
+        // 記述子は呼び出し後に解放し、ゲストのメモリはインスタンスと共有する
+        assert!(source.contains("        guest.free(&mut store, arg0)?;\n        Ok(Files {"));
+        assert!(source.contains("            guest,\n"));
+    }
+}
@@ -0,0 +1,221 @@
+//! Capabilities required by extern imports.
+//! `@requires(capability: "net")` on an `extern func` is recorded in the
+//! module's `replica.capabilities` custom section, so hosts can audit what a
+//! module may do before instantiating it. `replicac run` refuses to start a
+//! module unless every capability it requires was granted with `--allow`.
+
+use crate::ast::{Actor, Extern};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+
+/// Custom section listing the capabilities of each import, one JSON object per line
+pub const CAPABILITIES_SECTION: &str = "replica.capabilities";
+
+/// Capability an import needs before it may be called
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Requirement {
+    pub capability: String,
+    pub import: String,
+}
+
+/// Whether `name` is a valid capability name: lowercase letters, digits and
+/// `-._:` starting with a letter, such as `net` or `fs.read`
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.starts_with(|c: char| c.is_ascii_lowercase())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || "-.:_".contains(c))
+}
+
+/// Capabilities required by the extern functions of `actor`
+pub fn requirements(actor: &Actor) -> Vec<Requirement> {
+    actor
+        .externs
+        .iter()
+        .filter_map(|item| match item {
+            Extern::Function(function) => Some(function),
+            Extern::Type(_) => None,
+        })
+        .flat_map(|function| {
+            function.requires.iter().map(|capability| Requirement {
+                capability: capability.clone(),
+                import: function.name.clone(),
+            })
+        })
+        .collect()
+}
+
+/// Contents of the `replica.capabilities` section. Lines are self-contained,
+/// so the sections of linked modules concatenate.
+pub fn section(requirements: &[Requirement]) -> String {
+    requirements
+        .iter()
+        .map(|requirement| {
+            let entry = json!({
+                "capability": requirement.capability,
+                "import": requirement.import,
+            });
+            entry.to_string() + "\n"
+        })
+        .collect()
+}
+
+/// Reads the requirements recorded in a WebAssembly binary
+pub fn read_requirements(wasm: &[u8]) -> Result<Vec<Requirement>, String> {
+    let mut requirements = Vec::new();
+    for payload in custom_sections(wasm, CAPABILITIES_SECTION)? {
+        let text = std::str::from_utf8(payload)
+            .map_err(|_| format!("{} section is not UTF-8", CAPABILITIES_SECTION))?;
+        for line in text.lines().filter(|line| !line.trim().is_empty()) {
+            let entry: Value = serde_json::from_str(line)
+                .map_err(|e| format!("malformed {} entry: {}", CAPABILITIES_SECTION, e))?;
+            match (entry["capability"].as_str(), entry["import"].as_str()) {
+                (Some(capability), Some(import)) => requirements.push(Requirement {
+                    capability: capability.to_string(),
+                    import: import.to_string(),
+                }),
+                _ => {
+                    return Err(format!(
+                        "malformed {} entry: {}",
+                        CAPABILITIES_SECTION, line
+                    ))
+                }
+            }
+        }
+    }
+    Ok(requirements)
+}
+
+/// Fails with a message naming each capability `requirements` needs but
+/// `allowed` does not grant, and the imports that need it
+pub fn check(requirements: &[Requirement], allowed: &[String]) -> Result<(), String> {
+    let mut missing: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+    for requirement in requirements {
+        if !allowed.contains(&requirement.capability) {
+            let imports = missing.entry(&requirement.capability).or_default();
+            if !imports.contains(&requirement.import.as_str()) {
+                imports.push(&requirement.import);
+            }
+        }
+    }
+    if missing.is_empty() {
+        return Ok(());
+    }
+
+    let lines: Vec<String> = missing
+        .iter()
+        .map(|(capability, imports)| format!("  {} (used by {})", capability, imports.join(", ")))
+        .collect();
+    let names: Vec<&str> = missing.keys().copied().collect();
+    Err(format!(
+        "The module requires capabilities that were not granted:\n{}\nRe-run with --allow {} to grant them",
+        lines.join("\n"),
+        names.join(",")
+    ))
+}
+
+/// Payloads of the custom sections named `name`
+pub(crate) fn custom_sections<'a>(wasm: &'a [u8], name: &str) -> Result<Vec<&'a [u8]>, String> {
+    let invalid = || "not a WebAssembly binary".to_string();
+    if wasm.get(..4) != Some(b"\0asm".as_slice()) || wasm.len() < 8 {
+        return Err(invalid());
+    }
+
+    let mut sections = Vec::new();
+    let mut rest = &wasm[8..];
+    while let Some((&id, after)) = rest.split_first() {
+        let (size, after) = read_leb128(after).ok_or_else(invalid)?;
+        let payload = after.get(..size).ok_or_else(invalid)?;
+        rest = &after[size..];
+
+        // ID 0 はカスタムセクション (名前 + 任意のバイト列)
+        if id == 0 {
+            let (name_len, body) = read_leb128(payload).ok_or_else(invalid)?;
+            let section_name = body.get(..name_len).ok_or_else(invalid)?;
+            if section_name == name.as_bytes() {
+                sections.push(&body[name_len..]);
+            }
+        }
+    }
+    Ok(sections)
+}
+
+/// Reads an unsigned LEB128 number, returning it with the remaining bytes
+fn read_leb128(bytes: &[u8]) -> Option<(usize, &[u8])> {
+    let mut value = 0usize;
+    for (index, &byte) in bytes.iter().enumerate().take(5) {
+        value |= ((byte & 0x7f) as usize) << (7 * index);
+        if byte & 0x80 == 0 {
+            return Some((value, &bytes[index + 1..]));
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn requirement(capability: &str, import: &str) -> Requirement {
+        Requirement {
+            capability: capability.to_string(),
+            import: import.to_string(),
+        }
+    }
+
+    /// Wraps `payload` in a custom section named `name`
+    fn custom_section(name: &str, payload: &str) -> Vec<u8> {
+        let mut body = vec![name.len() as u8];
+        body.extend_from_slice(name.as_bytes());
+        body.extend_from_slice(payload.as_bytes());
+        let mut section = vec![0, body.len() as u8];
+        section.extend(body);
+        section
+    }
+
+    #[test]
+    fn test_read_requirements_from_sections() {
+        let requirements = [requirement("net", "fetch"), requirement("fs", "open")];
+        let mut wasm = b"\0asm\x01\0\0\0".to_vec();
+        // 型セクションとリンクで連結された 2 つの表
+        wasm.extend_from_slice(&[1, 1, 0]);
+        wasm.extend(custom_section(
+            CAPABILITIES_SECTION,
+            &section(&requirements[..1]),
+        ));
+        wasm.extend(custom_section("name", "ignored"));
+        wasm.extend(custom_section(
+            CAPABILITIES_SECTION,
+            &section(&requirements[1..]),
+        ));
+
+        assert_eq!(read_requirements(&wasm).unwrap(), requirements);
+        assert!(read_requirements(b"not wasm").is_err());
+        assert!(read_requirements(&wasm[..wasm.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_check_names_missing_capabilities() {
+        let requirements = [
+            requirement("net", "fetch"),
+            requirement("net", "connect"),
+            requirement("fs", "open"),
+        ];
+        assert!(check(&requirements, &["fs".to_string(), "net".to_string()]).is_ok());
+
+        let error = check(&requirements, &["fs".to_string()]).unwrap_err();
+        assert!(error.contains("net (used by fetch, connect)"), "{}", error);
+        assert!(error.ends_with("Re-run with --allow net to grant them"));
+        assert!(!error.contains("  fs"));
+    }
+
+    #[test]
+    fn test_capability_names() {
+        assert!(is_valid_name("net"));
+        assert!(is_valid_name("fs.read"));
+        assert!(!is_valid_name(""));
+        assert!(!is_valid_name("Net"));
+        assert!(!is_valid_name("net access"));
+    }
+}
@@ -0,0 +1,113 @@
+//! Writer for GNU-style `ar` archives used by `--emit staticlib`.
+//! The archive carries a symbol index so `wasm-ld` and other linkers can
+//! resolve Replica symbols without running `ranlib`.
+
+/// A single object file stored in an archive
+pub struct ArchiveMember<'a> {
+    /// Member file name (e.g. `counter.o`)
+    pub name: &'a str,
+    /// Object file contents
+    pub data: &'a [u8],
+    /// Symbols defined by this member, recorded in the archive index
+    pub symbols: Vec<String>,
+}
+
+const MAGIC: &[u8] = b"!<arch>\n";
+const HEADER_SIZE: usize = 60;
+
+/// Serializes `members` into an archive with a GNU symbol index
+pub fn write_archive(members: &[ArchiveMember]) -> Vec<u8> {
+    let symbol_count: usize = members.iter().map(|m| m.symbols.len()).sum();
+    let names_size: usize = members
+        .iter()
+        .flat_map(|m| m.symbols.iter())
+        .map(|s| s.len() + 1)
+        .sum();
+    let index_size = 4 + 4 * symbol_count + names_size;
+
+    // Compute the offset of each member header
+    let mut offsets = Vec::with_capacity(members.len());
+    let mut offset = MAGIC.len() + HEADER_SIZE + padded(index_size);
+    for member in members {
+        offsets.push(offset as u32);
+        offset += HEADER_SIZE + padded(member.data.len());
+    }
+
+    let mut out = Vec::with_capacity(offset);
+    out.extend_from_slice(MAGIC);
+
+    // Symbol index: count, member offset per symbol, NUL-terminated names
+    let mut index = Vec::with_capacity(index_size);
+    index.extend_from_slice(&(symbol_count as u32).to_be_bytes());
+    for (member, member_offset) in members.iter().zip(&offsets) {
+        for _ in &member.symbols {
+            index.extend_from_slice(&member_offset.to_be_bytes());
+        }
+    }
+    for symbol in members.iter().flat_map(|m| m.symbols.iter()) {
+        index.extend_from_slice(symbol.as_bytes());
+        index.push(0);
+    }
+    write_member(&mut out, "/", &index);
+
+    for member in members {
+        write_member(&mut out, &format!("{}/", member.name), member.data);
+    }
+
+    out
+}
+
+fn write_member(out: &mut Vec<u8>, name: &str, data: &[u8]) {
+    let header = format!(
+        "{:<16}{:<12}{:<6}{:<6}{:<8}{:<10}`\n",
+        name,
+        0,
+        0,
+        0,
+        644,
+        data.len()
+    );
+    debug_assert_eq!(header.len(), HEADER_SIZE);
+    out.extend_from_slice(header.as_bytes());
+    out.extend_from_slice(data);
+    if data.len() % 2 == 1 {
+        out.push(b'\n');
+    }
+}
+
+fn padded(size: usize) -> usize {
+    size + size % 2
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_archive_layout() {
+        let members = [ArchiveMember {
+            name: "counter.o",
+            data: b"obj",
+            symbols: vec!["increment".to_string(), "get_value".to_string()],
+        }];
+        let archive = write_archive(&members);
+
+        assert!(archive.starts_with(MAGIC));
+        // Index header follows the magic
+        assert_eq!(&archive[8..9], b"/");
+        // Member data is padded to an even size
+        assert_eq!(archive.len() % 2, 0);
+
+        // Both symbols point to the first member header
+        let index = &archive[MAGIC.len() + HEADER_SIZE..];
+        assert_eq!(u32::from_be_bytes(index[0..4].try_into().unwrap()), 2);
+        let first_offset = u32::from_be_bytes(index[4..8].try_into().unwrap()) as usize;
+        assert_eq!(&archive[first_offset..first_offset + 10], b"counter.o/");
+    }
+
+    #[test]
+    fn test_empty_archive_has_index() {
+        let archive = write_archive(&[]);
+        assert_eq!(archive.len(), MAGIC.len() + HEADER_SIZE + 4);
+    }
+}
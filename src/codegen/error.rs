@@ -1,3 +1,4 @@
+use codespan_reporting::diagnostic::Diagnostic;
 use std::fmt;
 use thiserror::Error;
 
@@ -58,10 +59,17 @@ pub enum CodeGenError {
     /// Internal compiler error
     #[error("Internal compiler error: {0}")]
     Internal(String),
+
+    /// Error annotated with location, context and suggestion
+    #[error("{error}{context}")]
+    WithContext {
+        error: Box<CodeGenError>,
+        context: ErrorContext,
+    },
 }
 
 /// Detailed error information for debugging
-#[derive(Debug)]
+#[derive(Debug, Clone, Default)]
 pub struct ErrorContext {
     /// Source location where the error occurred
     pub location: Option<SourceLocation>,
@@ -85,22 +93,116 @@ impl fmt::Display for SourceLocation {
     }
 }
 
+impl ErrorContext {
+    /// Merges `other` into this context, keeping existing values where `other` has none
+    fn merge(&mut self, other: ErrorContext) {
+        if other.location.is_some() {
+            self.location = other.location;
+        }
+        if !other.context.is_empty() {
+            self.context = other.context;
+        }
+        if other.suggestion.is_some() {
+            self.suggestion = other.suggestion;
+        }
+    }
+}
+
+impl fmt::Display for ErrorContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(location) = &self.location {
+            write!(f, "\n  --> {}", location)?;
+        }
+        if !self.context.is_empty() {
+            write!(f, "\n  = note: {}", self.context)?;
+        }
+        if let Some(suggestion) = &self.suggestion {
+            write!(f, "\n  = help: {}", suggestion)?;
+        }
+        Ok(())
+    }
+}
+
 impl CodeGenError {
     /// Creates a new error with additional context
     pub fn with_context(self, context: ErrorContext) -> Self {
-        // 将来的にエラーコンテキストを保持する機能を追加可能
-        self
+        match self {
+            CodeGenError::WithContext {
+                error,
+                context: mut existing,
+            } => {
+                existing.merge(context);
+                CodeGenError::WithContext {
+                    error,
+                    context: existing,
+                }
+            }
+            error => CodeGenError::WithContext {
+                error: Box::new(error),
+                context,
+            },
+        }
     }
 
     /// Adds a suggestion to the error
     pub fn with_suggestion(self, suggestion: String) -> Self {
-        // 将来的に提案を保持する機能を追加可能
-        self
+        self.with_context(ErrorContext {
+            suggestion: Some(suggestion),
+            ..ErrorContext::default()
+        })
+    }
+
+    /// Attaches a source location to the error
+    pub fn at(self, location: SourceLocation) -> Self {
+        self.with_context(ErrorContext {
+            location: Some(location),
+            ..ErrorContext::default()
+        })
+    }
+
+    /// Returns the underlying error without any attached context
+    pub fn root(&self) -> &CodeGenError {
+        match self {
+            CodeGenError::WithContext { error, .. } => error.root(),
+            error => error,
+        }
+    }
+
+    /// Returns the attached context, if any
+    pub fn context(&self) -> Option<&ErrorContext> {
+        match self {
+            CodeGenError::WithContext { context, .. } => Some(context),
+            _ => None,
+        }
+    }
+
+    /// Converts the error into a diagnostic for `codespan-reporting`
+    pub fn to_diagnostic<FileId>(&self) -> Diagnostic<FileId> {
+        let root = self.root();
+        let mut notes = Vec::new();
+
+        if let Some(context) = self.context() {
+            if let Some(location) = &context.location {
+                notes.push(format!("at {}", location));
+            }
+            if !context.context.is_empty() {
+                notes.push(context.context.clone());
+            }
+            if let Some(suggestion) = &context.suggestion {
+                notes.push(format!("help: {}", suggestion));
+            }
+        }
+
+        Diagnostic::error()
+            .with_message(root.to_string())
+            .with_code(root.category().to_string())
+            .with_notes(notes)
     }
 
     /// Returns true if the error is recoverable
     pub fn is_recoverable(&self) -> bool {
         match self {
+            CodeGenError::WithContext { error, .. } => error.is_recoverable(),
             CodeGenError::TypeConversion(_)
             | CodeGenError::ExpressionCompilation(_)
             | CodeGenError::MethodCompilation(_) => true,
@@ -125,6 +227,7 @@ impl CodeGenError {
             CodeGenError::MemoryError(_) => ErrorCategory::Memory,
             CodeGenError::LLVMError(_) => ErrorCategory::LLVM,
             CodeGenError::Internal(_) => ErrorCategory::Internal,
+            CodeGenError::WithContext { error, .. } => error.category(),
         }
     }
 }
@@ -190,7 +293,54 @@ mod tests {
             suggestion: Some("Try this".to_string()),
         };
         let error = CodeGenError::TypeConversion("test".to_string()).with_context(context);
-        assert!(matches!(error, CodeGenError::TypeConversion(_)));
+        assert!(matches!(error.root(), CodeGenError::TypeConversion(_)));
+
+        let rendered = error.to_string();
+        assert!(rendered.contains("Type conversion error: test"));
+        assert!(rendered.contains("--> test.rs:1:1"));
+        assert!(rendered.contains("note: Test context"));
+        assert!(rendered.contains("help: Try this"));
+    }
+
+    #[test]
+    fn test_error_suggestion() {
+        let error = CodeGenError::UndefinedVariable("x".to_string())
+            .with_suggestion("Declare `x` before use".to_string());
+        assert_eq!(error.category(), ErrorCategory::Variable);
+        assert_eq!(
+            error.to_string(),
+            "Undefined variable: x\n  = help: Declare `x` before use"
+        );
+    }
+
+    #[test]
+    fn test_error_context_merging() {
+        let location = SourceLocation {
+            file: "main.replica".to_string(),
+            line: 3,
+            column: 5,
+        };
+        let error = CodeGenError::TypeConversion("test".to_string())
+            .with_suggestion("Use Int".to_string())
+            .at(location);
+
+        let context = error.context().expect("context should be attached");
+        assert_eq!(context.suggestion.as_deref(), Some("Use Int"));
+        assert_eq!(context.location.as_ref().unwrap().line, 3);
+        assert!(matches!(error.root(), CodeGenError::TypeConversion(_)));
+    }
+
+    #[test]
+    fn test_error_to_diagnostic() {
+        let error = CodeGenError::MethodCompilation("bad body".to_string())
+            .with_suggestion("Add a return statement".to_string());
+        let diagnostic = error.to_diagnostic::<()>();
+        assert_eq!(diagnostic.message, "Method compilation error: bad body");
+        assert_eq!(diagnostic.code.as_deref(), Some("Method Error"));
+        assert!(diagnostic
+            .notes
+            .iter()
+            .any(|note| note.contains("Add a return statement")));
     }
 
     #[test]
@@ -1,44 +1,338 @@
 use inkwell::{
     builder::Builder,
     context::Context,
-    values::{BasicValue, BasicValueEnum},
-    FloatPredicate, IntPredicate,
+    types::{BasicType, BasicTypeEnum, StructType},
+    values::{
+        ArrayValue, BasicMetadataValueEnum, BasicValue, BasicValueEnum, FloatValue, FunctionValue,
+        IntValue, PointerValue, StructValue,
+    },
+    AddressSpace, AtomicOrdering, AtomicRMWBinOp, FloatPredicate, IntPredicate,
 };
-use std::collections::HashMap;
+use replica_runtime::string;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
 
 use super::{
     error::{CodeGenError, CodeGenResult},
+    runtime::RuntimeFunction,
     type_converter::TypeConverter,
 };
-use crate::ast::{Expression, LiteralValue, Operator};
+use crate::ast::{
+    AtomicOperation, Conversion, EnumCase, Expression, LiteralValue, MemoryOrdering, Operator,
+    Pattern, RandomOperation,
+};
+
+/// Storage a name is read through: a field of the method's instance, or the
+/// caller's variable behind an `inout` parameter
+#[derive(Debug, Clone, Copy)]
+pub struct Slot<'ctx> {
+    pub address: PointerValue<'ctx>,
+    pub value_type: BasicTypeEnum<'ctx>,
+    /// Weak fields are read through the runtime, which yields null once the target is gone
+    pub weak: bool,
+}
+
+/// Where the value of a `config("KEY")` built-in comes from
+#[derive(Debug, Clone, Copy)]
+pub enum ConfigSource<'ctx> {
+    /// Constant `String` descriptor baked into the module
+    Fixed(PointerValue<'ctx>),
+    /// Lookup of the `key` descriptor through the host import, with `default`
+    /// used when the host returns null
+    Runtime {
+        import: FunctionValue<'ctx>,
+        key: PointerValue<'ctx>,
+        default: PointerValue<'ctx>,
+    },
+}
+
+/// Where a `const` table of the actor lives in the data segment
+#[derive(Debug, Clone, Copy)]
+pub enum ConstantSource<'ctx> {
+    /// Constant `[T]` descriptor, read like any other array
+    Array {
+        descriptor: PointerValue<'ctx>,
+        element_type: BasicTypeEnum<'ctx>,
+    },
+    /// Values in the order of the sorted keys, and the function giving the
+    /// position of a key among them, or -1
+    Map {
+        find: FunctionValue<'ctx>,
+        values: PointerValue<'ctx>,
+        value_type: BasicTypeEnum<'ctx>,
+    },
+}
+
+/// Local bindings visible at some point of a method, saved when a block starts
+/// and restored when it ends so that the block's bindings go out of scope
+#[derive(Debug, Clone)]
+pub struct Scope<'ctx> {
+    variables: HashMap<String, BasicValueEnum<'ctx>>,
+    addresses: HashMap<String, PointerValue<'ctx>>,
+    slots: HashMap<String, Slot<'ctx>>,
+    arrays: HashMap<String, BasicTypeEnum<'ctx>>,
+    records: HashMap<String, Vec<String>>,
+}
 
 /// Compiles Replica expressions to LLVM IR
 pub struct ExpressionCompiler<'ctx> {
     context: &'ctx Context,
-    builder: &'ctx Builder<'ctx>,
+    /// Shared with the code generator, which positions it between expressions
+    builder: Rc<Builder<'ctx>>,
     type_converter: TypeConverter<'ctx>,
-    variables: HashMap<String, BasicValueEnum<'ctx>>,
+    /// Bindings by value; a cell so that match arms can bind while compiling
+    /// their result
+    variables: RefCell<HashMap<String, BasicValueEnum<'ctx>>>,
+    addresses: HashMap<String, PointerValue<'ctx>>,
+    slots: HashMap<String, Slot<'ctx>>,
+    fields: HashMap<String, Slot<'ctx>>,
+    /// Element types of `[T]` locals, fields and method results, which are only
+    /// pointers in IR
+    arrays: HashMap<String, BasicTypeEnum<'ctx>>,
+    field_arrays: HashMap<String, BasicTypeEnum<'ctx>>,
+    array_results: HashMap<String, BasicTypeEnum<'ctx>>,
+    /// Labels, in layout order, of record locals, fields and method results,
+    /// which are anonymous structs in IR
+    records: HashMap<String, Vec<String>>,
+    field_records: HashMap<String, Vec<String>>,
+    record_results: HashMap<String, Vec<String>>,
+    /// Whether dynamic indices go through the runtime, which traps when out of bounds
+    bounds_checks: bool,
+    instance: Option<PointerValue<'ctx>>,
+    functions: HashMap<String, FunctionValue<'ctx>>,
+    methods: HashSet<String>,
+    runtime_functions: HashMap<RuntimeFunction, FunctionValue<'ctx>>,
+    funcref_helper: Option<FunctionValue<'ctx>>,
+    random_source: Option<FunctionValue<'ctx>>,
+    config: HashMap<String, ConfigSource<'ctx>>,
+    constants: HashMap<String, ConstantSource<'ctx>>,
+    /// LLVM type and field names of each `struct` declaration
+    structs: HashMap<String, (StructType<'ctx>, Vec<String>)>,
+    /// `enum` declarations by name: their tagged union type and cases
+    enums: HashMap<String, (StructType<'ctx>, Vec<EnumCase>)>,
 }
 
 impl<'ctx> ExpressionCompiler<'ctx> {
     /// Creates a new ExpressionCompiler instance
-    pub fn new(context: &'ctx Context, builder: &'ctx Builder<'ctx>) -> Self {
+    pub fn new(context: &'ctx Context, builder: Rc<Builder<'ctx>>) -> Self {
         ExpressionCompiler {
             context,
             builder,
             type_converter: TypeConverter::new(context),
-            variables: HashMap::new(),
+            variables: RefCell::new(HashMap::new()),
+            addresses: HashMap::new(),
+            slots: HashMap::new(),
+            fields: HashMap::new(),
+            arrays: HashMap::new(),
+            field_arrays: HashMap::new(),
+            array_results: HashMap::new(),
+            records: HashMap::new(),
+            field_records: HashMap::new(),
+            record_results: HashMap::new(),
+            bounds_checks: true,
+            instance: None,
+            functions: HashMap::new(),
+            methods: HashSet::new(),
+            runtime_functions: HashMap::new(),
+            funcref_helper: None,
+            random_source: None,
+            config: HashMap::new(),
+            constants: HashMap::new(),
+            structs: HashMap::new(),
+            enums: HashMap::new(),
         }
     }
 
-    /// Registers a variable in the current scope
+    /// Registers a variable in the current scope, hiding any binding of the same name
     pub fn register_variable(&mut self, name: String, value: BasicValueEnum<'ctx>) {
-        self.variables.insert(name, value);
+        self.slots.remove(&name);
+        self.addresses.remove(&name);
+        self.arrays.remove(&name);
+        self.records.remove(&name);
+        self.variables.get_mut().insert(name, value);
+    }
+
+    /// Registers the storage of a variable that can be passed to `inout` parameters
+    pub fn register_address(&mut self, name: String, address: PointerValue<'ctx>) {
+        self.addresses.insert(name, address);
+    }
+
+    /// Registers a local read and assigned through a pointer, such as an `inout`
+    /// parameter or a `var` binding, which can also be passed to `inout` parameters
+    pub fn register_slot(&mut self, name: String, slot: Slot<'ctx>) {
+        self.variables.get_mut().remove(&name);
+        self.arrays.remove(&name);
+        self.records.remove(&name);
+        self.addresses.insert(name.clone(), slot.address);
+        self.slots.insert(name, slot);
+    }
+
+    /// Registers the element type of the `[T]` local or parameter `name`,
+    /// after the variable itself
+    pub fn register_array(&mut self, name: String, element_type: BasicTypeEnum<'ctx>) {
+        self.arrays.insert(name, element_type);
+    }
+
+    /// Registers the element type of the `[T]` field `name`
+    pub fn register_field_array(&mut self, name: String, element_type: BasicTypeEnum<'ctx>) {
+        self.field_arrays.insert(name, element_type);
+    }
+
+    /// Registers the element type of the `[T]` the method `name` returns
+    pub fn register_array_result(&mut self, name: String, element_type: BasicTypeEnum<'ctx>) {
+        self.array_results.insert(name, element_type);
+    }
+
+    /// Registers the labels of the record local or parameter `name`, after the
+    /// variable itself
+    pub fn register_record(&mut self, name: String, labels: Vec<String>) {
+        self.records.insert(name, labels);
+    }
+
+    /// Registers the labels of the record field `name`
+    pub fn register_field_record(&mut self, name: String, labels: Vec<String>) {
+        self.field_records.insert(name, labels);
+    }
+
+    /// Registers the labels of the record the method `name` returns
+    pub fn register_record_result(&mut self, name: String, labels: Vec<String>) {
+        self.record_results.insert(name, labels);
+    }
+
+    /// Sets whether dynamic array indices are checked
+    pub fn set_bounds_checks(&mut self, bounds_checks: bool) {
+        self.bounds_checks = bounds_checks;
+    }
+
+    /// Sizes allocations with `data_layout`, the layout string of the target
+    pub fn set_data_layout(&mut self, data_layout: &str) {
+        self.type_converter.set_data_layout(data_layout);
+    }
+
+    /// Storage of the local `name` if it can be assigned
+    pub fn slot(&self, name: &str) -> Option<Slot<'ctx>> {
+        self.slots.get(name).copied()
+    }
+
+    /// Whether `name` is a local bound by `let` or a parameter passed by value
+    pub fn has_variable(&self, name: &str) -> bool {
+        self.variables.borrow().contains_key(name)
+    }
+
+    /// Saves the local bindings before a block
+    pub fn save_scope(&self) -> Scope<'ctx> {
+        Scope {
+            variables: self.variables.borrow().clone(),
+            addresses: self.addresses.clone(),
+            slots: self.slots.clone(),
+            arrays: self.arrays.clone(),
+            records: self.records.clone(),
+        }
+    }
+
+    /// Restores the local bindings saved before a block once it ends
+    pub fn restore_scope(&mut self, scope: Scope<'ctx>) {
+        *self.variables.get_mut() = scope.variables;
+        self.addresses = scope.addresses;
+        self.slots = scope.slots;
+        self.arrays = scope.arrays;
+        self.records = scope.records;
+    }
+
+    /// Registers a field of the instance, read as `self.name` or as `name` when no
+    /// variable hides it
+    pub fn register_field(&mut self, name: String, slot: Slot<'ctx>) {
+        self.fields.insert(name, slot);
+    }
+
+    /// Sets the instance the current method runs on, passed on to method calls
+    pub fn set_instance(&mut self, instance: PointerValue<'ctx>) {
+        self.instance = Some(instance);
+    }
+
+    /// Instance the current method runs on
+    pub fn instance(&self) -> Option<PointerValue<'ctx>> {
+        self.instance
+    }
+
+    /// Registers a function that expressions can call
+    pub fn register_function(&mut self, name: String, function: FunctionValue<'ctx>) {
+        self.functions.insert(name, function);
+    }
+
+    /// Registers an actor method, which is called with the current instance first
+    pub fn register_method(&mut self, name: String, function: FunctionValue<'ctx>) {
+        self.methods.insert(name.clone());
+        self.functions.insert(name, function);
+    }
+
+    /// Registers the declaration of a runtime routine used by generated expressions
+    pub fn register_runtime_function(
+        &mut self,
+        function: RuntimeFunction,
+        declaration: FunctionValue<'ctx>,
+    ) {
+        self.runtime_functions.insert(function, declaration);
+    }
+
+    /// Registers the function that turns a function pointer into a `funcref`,
+    /// used when passing callbacks with reference types enabled
+    pub fn register_funcref_helper(&mut self, helper: FunctionValue<'ctx>) {
+        self.funcref_helper = Some(helper);
+    }
+
+    /// Registers the function the random built-ins take their 64 random bits from
+    pub fn register_random_source(&mut self, source: FunctionValue<'ctx>) {
+        self.random_source = Some(source);
+    }
+
+    /// Registers where `config(key)` takes its value from
+    pub fn register_config(&mut self, key: String, source: ConfigSource<'ctx>) {
+        self.config.insert(key, source);
+    }
+
+    /// Registers a `const` table of the actor, read by its name
+    pub fn register_constant(&mut self, name: String, source: ConstantSource<'ctx>) {
+        self.constants.insert(name, source);
     }
 
-    /// Clears all registered variables
+    /// Registers a `struct` declaration whose values expressions construct and
+    /// read fields of, with its field names in declaration order
+    pub fn register_struct(
+        &mut self,
+        name: String,
+        struct_type: StructType<'ctx>,
+        fields: Vec<String>,
+    ) {
+        self.type_converter
+            .register_plain_struct(&name, struct_type);
+        self.structs.insert(name, (struct_type, fields));
+    }
+
+    /// Registers an `enum` declaration whose cases expressions construct and
+    /// patterns match, with the tagged union type of its values
+    pub fn register_enum(
+        &mut self,
+        name: String,
+        enum_type: StructType<'ctx>,
+        cases: Vec<EnumCase>,
+    ) {
+        self.type_converter.register_plain_struct(&name, enum_type);
+        self.enums.insert(name, (enum_type, cases));
+    }
+
+    /// Clears all registered variables, fields and the instance
     pub fn clear_variables(&mut self) {
-        self.variables.clear();
+        self.variables.get_mut().clear();
+        self.addresses.clear();
+        self.slots.clear();
+        self.fields.clear();
+        self.arrays.clear();
+        self.field_arrays.clear();
+        self.records.clear();
+        self.field_records.clear();
+        self.instance = None;
     }
 
     /// Compiles an expression to LLVM IR
@@ -51,7 +345,1604 @@ impl<'ctx> ExpressionCompiler<'ctx> {
             } => self.compile_binary_operation(left, operator, right),
             Expression::Literal(value) => self.compile_literal(value),
             Expression::Variable(name) => self.compile_variable(name),
+            Expression::Field(name) => {
+                let slot = self
+                    .fields
+                    .get(name)
+                    .ok_or_else(|| CodeGenError::UndefinedVariable(format!("self.{}", name)))?;
+                self.load_slot(name, slot)
+            }
+            Expression::Call { callee, args, .. } => {
+                self.compile_call(callee, args)?.ok_or_else(|| {
+                    CodeGenError::ExpressionCompilation(format!(
+                        "Method {} does not return a value",
+                        callee
+                    ))
+                })
+            }
+            Expression::Inout(name) => self.compile_inout_argument(name),
+            Expression::Tuple(elements) => self.compile_tuple(elements),
+            Expression::Record(fields) => self.compile_record(fields),
+            Expression::ArrayLiteral(elements) => self.compile_array_literal(elements),
+            Expression::Index { base, index } => self.compile_index(base, index),
+            Expression::Atomic {
+                operation,
+                field,
+                args,
+                ordering,
+            } => self
+                .compile_atomic(*operation, field, args, *ordering)?
+                .ok_or_else(|| {
+                    CodeGenError::ExpressionCompilation(format!(
+                        "{} does not return a value",
+                        operation.name()
+                    ))
+                }),
+            // comptime 呼び出しは型検査後に定数へ畳み込まれている
+            Expression::Comptime { callee, .. } => Err(CodeGenError::Internal(format!(
+                "comptime {} was not evaluated before code generation",
+                callee
+            ))),
+            Expression::Conversion { conversion, value } => {
+                self.compile_conversion(*conversion, value)
+            }
+            Expression::Random { operation, args } => self.compile_random(*operation, args),
+            Expression::Config(key) => self.compile_config(key),
+            Expression::StructLiteral { name, fields } => self.compile_struct_literal(name, fields),
+            Expression::Member { base, name } => self.compile_member(base, name),
+            Expression::EnumCase {
+                enum_name,
+                case,
+                args,
+            } => self.compile_enum_case(enum_name, case, args),
+            Expression::Match { value, arms } => self.compile_match(value, arms),
+            // nil の型は文脈からしか決まらないので compile_expected を通る
+            Expression::Nil => Err(CodeGenError::ExpressionCompilation(
+                "nil needs an optional type from its context".to_string(),
+            )),
+            Expression::Unwrap(value) => self.compile_unwrap(value),
+            Expression::Coalesce { value, default } => self.compile_coalesce(value, default),
+            // 呼び出しは相手のメソッドが終わってから戻るので、待つのは呼び出しそのもの
+            Expression::Await(value) => self.compile_expression(value),
+        }
+    }
+
+    /// Compiles an expression whose type is known from its context: `nil`
+    /// becomes an absent optional and a plain value is wrapped as present
+    /// when an optional is expected, and a fixed-size array is copied into a
+    /// new buffer when a `[T]` is expected
+    pub fn compile_expected(
+        &self,
+        expr: &Expression,
+        expected: BasicTypeEnum<'ctx>,
+    ) -> CodeGenResult<BasicValueEnum<'ctx>> {
+        let llvm_error =
+            |e: inkwell::builder::BuilderError| CodeGenError::ExpressionCompilation(e.to_string());
+        if let BasicTypeEnum::PointerType(pointer) = expected {
+            // 空の [T] は null の記述子で表す
+            if matches!(expr, Expression::ArrayLiteral(elements) if elements.is_empty()) {
+                return Ok(pointer.const_null().into());
+            }
+            return match self.compile_expression(expr)? {
+                BasicValueEnum::ArrayValue(array) => self.compile_heap_array(array),
+                value => Ok(value),
+            };
+        }
+        let BasicTypeEnum::StructType(optional) = expected else {
+            return self.compile_expression(expr);
+        };
+        if let Expression::Nil = expr {
+            return Ok(optional.const_zero().into());
+        }
+        let value = self.compile_expression(expr)?;
+        let wraps_value = optional.count_fields() == 2
+            && optional.get_field_type_at_index(0) == Some(value.get_type())
+            && optional.get_field_type_at_index(1) == Some(self.context.bool_type().into());
+        if value.get_type() == expected || !wraps_value {
+            return Ok(value);
+        }
+        let wrapped = self
+            .builder
+            .build_insert_value(optional.get_undef(), value, 0, "value")
+            .map_err(llvm_error)?;
+        let wrapped = self
+            .builder
+            .build_insert_value(
+                wrapped,
+                self.context.bool_type().const_int(1, false),
+                1,
+                "optional",
+            )
+            .map_err(llvm_error)?;
+        Ok(wrapped.as_basic_value_enum())
+    }
+
+    /// Compiles `config(key)` to the address of a `String` descriptor: the baked
+    /// constant, or the host's value with the default substituted for null
+    fn compile_config(&self, key: &str) -> CodeGenResult<BasicValueEnum<'ctx>> {
+        let source = self.config.get(key).ok_or_else(|| {
+            CodeGenError::ExpressionCompilation(format!("config key {} is not declared", key))
+        })?;
+        let (import, key_descriptor, default) = match *source {
+            ConfigSource::Fixed(descriptor) => return Ok(descriptor.as_basic_value_enum()),
+            ConfigSource::Runtime {
+                import,
+                key,
+                default,
+            } => (import, key, default),
+        };
+        let llvm_error =
+            |e: inkwell::builder::BuilderError| CodeGenError::ExpressionCompilation(e.to_string());
+        let value = self
+            .builder
+            .build_call(import, &[key_descriptor.into()], "config")
+            .map_err(llvm_error)?
+            .try_as_basic_value()
+            .left()
+            .ok_or_else(|| {
+                CodeGenError::ExpressionCompilation(format!("{} returned no value", key))
+            })?
+            .into_pointer_value();
+        let unset = self
+            .builder
+            .build_is_null(value, "unset")
+            .map_err(llvm_error)?;
+        self.builder
+            .build_select(unset, default, value, key)
+            .map_err(llvm_error)
+    }
+
+    /// Compiles `randomInt(bound)` or `randomFloat()`: draws bits from the
+    /// registered source and maps them with the runtime's routine
+    fn compile_random(
+        &self,
+        operation: RandomOperation,
+        args: &[Expression],
+    ) -> CodeGenResult<BasicValueEnum<'ctx>> {
+        let source = self.random_source.ok_or_else(|| {
+            CodeGenError::ExpressionCompilation(format!(
+                "{} has no random source",
+                operation.name()
+            ))
+        })?;
+        let bits = self
+            .builder
+            .build_call(source, &[], "bits")
+            .map_err(|e| CodeGenError::ExpressionCompilation(e.to_string()))?
+            .try_as_basic_value()
+            .left()
+            .ok_or_else(|| {
+                CodeGenError::ExpressionCompilation("Random source returned no value".to_string())
+            })?;
+
+        let (function, args) = match (operation, args) {
+            (RandomOperation::Int, [bound]) => (
+                RuntimeFunction::RandomInt,
+                vec![bits.into(), self.compile_expression(bound)?.into()],
+            ),
+            (RandomOperation::Float, []) => (RuntimeFunction::RandomFloat, vec![bits.into()]),
+            _ => {
+                return Err(CodeGenError::ExpressionCompilation(format!(
+                    "{} expects {} arguments, found {}",
+                    operation.name(),
+                    operation.param_types().len(),
+                    args.len()
+                )))
+            }
+        };
+        self.call_runtime(function, &args)?
+            .ok_or_else(|| missing_result(function))
+    }
+
+    /// Compiles `value.toString()`, `value.toInt()` or an interpolated value.
+    /// Literal receivers of `toString()` and `toInt()` are converted here with
+    /// the runtime's own parser and formatter.
+    fn compile_conversion(
+        &self,
+        conversion: Conversion,
+        value: &Expression,
+    ) -> CodeGenResult<BasicValueEnum<'ctx>> {
+        match (conversion, value) {
+            (Conversion::IntToString, Expression::Literal(LiteralValue::Int(value))) => {
+                let mut buf = [0; string::INT_TEXT_CAPACITY];
+                let text = string::format_int(*value, &mut buf);
+                self.compile_literal(&LiteralValue::String(text.to_string()))
+            }
+            (Conversion::StringToInt, Expression::Literal(LiteralValue::String(text))) => {
+                Ok(self.const_optional_int(string::parse_int(text.as_bytes())))
+            }
+            (Conversion::IntToString, value) => {
+                let value = self.compile_expression(value)?.into_int_value();
+                let descriptor = self.alloc_string_descriptor()?;
+                self.call_runtime(
+                    RuntimeFunction::IntToString,
+                    &[value.into(), descriptor.into()],
+                )?;
+                Ok(descriptor)
+            }
+            (Conversion::Interpolate, value) => {
+                let value = self.compile_expression(value)?;
+                self.compile_interpolation(value)
+            }
+            (Conversion::StringToInt, value) => {
+                let string = self.compile_expression(value)?;
+                let slot = self
+                    .builder
+                    .build_alloca(self.context.i32_type(), "parsed")
+                    .map_err(|e| CodeGenError::ExpressionCompilation(e.to_string()))?;
+                let ok = self
+                    .call_runtime(RuntimeFunction::StringToInt, &[string.into(), slot.into()])?
+                    .ok_or_else(|| missing_result(RuntimeFunction::StringToInt))?
+                    .into_int_value();
+                let ok = self
+                    .builder
+                    .build_int_compare(IntPredicate::NE, ok, ok.get_type().const_zero(), "ok")
+                    .map_err(|e| CodeGenError::ExpressionCompilation(e.to_string()))?;
+                let parsed = self
+                    .builder
+                    .build_load(self.context.i32_type(), slot, "value")
+                    .map_err(|e| CodeGenError::ExpressionCompilation(e.to_string()))?;
+                let optional_type = self
+                    .type_converter
+                    .convert_to_llvm(&conversion.result_type())?
+                    .into_struct_type();
+                let optional = self
+                    .builder
+                    .build_insert_value(optional_type.get_undef(), parsed, 0, "optional")
+                    .map_err(|e| CodeGenError::ExpressionCompilation(e.to_string()))?;
+                let optional = self
+                    .builder
+                    .build_insert_value(optional, ok, 1, "optional")
+                    .map_err(|e| CodeGenError::ExpressionCompilation(e.to_string()))?;
+                Ok(optional.as_basic_value_enum())
+            }
+        }
+    }
+
+    /// Text of an interpolated value: a `String` as it is, and an `Int`,
+    /// `Float` or `Bool` formatted by the runtime into a new descriptor
+    fn compile_interpolation(
+        &self,
+        value: BasicValueEnum<'ctx>,
+    ) -> CodeGenResult<BasicValueEnum<'ctx>> {
+        let (function, value) = match value {
+            BasicValueEnum::PointerValue(_) => return Ok(value),
+            BasicValueEnum::FloatValue(value) => (RuntimeFunction::FloatToString, value.into()),
+            // Bool は i1 なので i32 に広げて渡す
+            BasicValueEnum::IntValue(value) if value.get_type().get_bit_width() == 1 => {
+                let value = self
+                    .builder
+                    .build_int_z_extend(value, self.context.i32_type(), "flag")
+                    .map_err(|e| CodeGenError::ExpressionCompilation(e.to_string()))?;
+                (RuntimeFunction::BoolToString, value.into())
+            }
+            BasicValueEnum::IntValue(value) => (RuntimeFunction::IntToString, value.into()),
+            value => {
+                return Err(CodeGenError::TypeConversion(format!(
+                    "Cannot interpolate a value of type {}",
+                    value.get_type()
+                )))
+            }
+        };
+        let descriptor = self.alloc_string_descriptor()?;
+        self.call_runtime(function, &[value, descriptor.into()])?;
+        Ok(descriptor)
+    }
+
+    /// Constant `Int?`
+    fn const_optional_int(&self, value: Option<i32>) -> BasicValueEnum<'ctx> {
+        let int_type = self.context.i32_type();
+        let flag = self
+            .context
+            .bool_type()
+            .const_int(value.is_some() as u64, false);
+        let value = int_type.const_int(value.unwrap_or(0) as u64, true);
+        self.context
+            .const_struct(&[value.into(), flag.into()], false)
+            .as_basic_value_enum()
+    }
+
+    /// Layout of the runtime's `ReplicaString` on wasm32
+    fn string_descriptor_type(&self) -> StructType<'ctx> {
+        let ptr = self.context.ptr_type(AddressSpace::default());
+        self.context
+            .struct_type(&[ptr.into(), self.context.i32_type().into()], false)
+    }
+
+    /// Allocates an uninitialized `{ ptr, len }` descriptor, which is what a
+    /// `String` value points to
+    fn alloc_string_descriptor(&self) -> CodeGenResult<BasicValueEnum<'ctx>> {
+        let size = self.context.i32_type().const_int(
+            self.type_converter
+                .abi_size(self.string_descriptor_type())
+                .into(),
+            false,
+        );
+        self.call_runtime(RuntimeFunction::Alloc, &[size.into()])?
+            .ok_or_else(|| missing_result(RuntimeFunction::Alloc))
+    }
+
+    /// Loads the byte pointer and length of the string `descriptor` points to
+    fn string_parts(
+        &self,
+        descriptor: PointerValue<'ctx>,
+    ) -> CodeGenResult<(BasicValueEnum<'ctx>, BasicValueEnum<'ctx>)> {
+        let llvm_error =
+            |e: inkwell::builder::BuilderError| CodeGenError::ExpressionCompilation(e.to_string());
+        let descriptor_type = self.string_descriptor_type();
+        let ptr_type = self.context.ptr_type(AddressSpace::default());
+        let ptr = self
+            .builder
+            .build_struct_gep(descriptor_type, descriptor, 0, "bytes.ptr")
+            .map_err(llvm_error)?;
+        let ptr = self
+            .builder
+            .build_load(ptr_type, ptr, "bytes")
+            .map_err(llvm_error)?;
+        let len = self.string_length(descriptor)?;
+        Ok((ptr, len))
+    }
+
+    /// Loads the length in bytes of the string `descriptor` points to
+    fn string_length(&self, descriptor: PointerValue<'ctx>) -> CodeGenResult<BasicValueEnum<'ctx>> {
+        let llvm_error =
+            |e: inkwell::builder::BuilderError| CodeGenError::ExpressionCompilation(e.to_string());
+        let len = self
+            .builder
+            .build_struct_gep(self.string_descriptor_type(), descriptor, 1, "length.ptr")
+            .map_err(llvm_error)?;
+        self.builder
+            .build_load(self.context.i32_type(), len, "length")
+            .map_err(llvm_error)
+    }
+
+    /// Compiles `+`, `==` and `!=` on two strings with the runtime's
+    /// `__replica_string_concat` and `__replica_string_eq`
+    pub fn compile_string_operation(
+        &self,
+        operator: &Operator,
+        left: PointerValue<'ctx>,
+        right: PointerValue<'ctx>,
+    ) -> CodeGenResult<BasicValueEnum<'ctx>> {
+        let (left_ptr, left_len) = self.string_parts(left)?;
+        let (right_ptr, right_len) = self.string_parts(right)?;
+        let parts = [
+            left_ptr.into(),
+            left_len.into(),
+            right_ptr.into(),
+            right_len.into(),
+        ];
+        match operator {
+            Operator::Add => {
+                let joined = self.alloc_string_descriptor()?;
+                let mut args = parts.to_vec();
+                args.push(joined.into());
+                self.call_runtime(RuntimeFunction::StringConcat, &args)?;
+                Ok(joined)
+            }
+            Operator::Equal | Operator::NotEqual => {
+                let equal = self
+                    .call_runtime(RuntimeFunction::StringEq, &parts)?
+                    .ok_or_else(|| missing_result(RuntimeFunction::StringEq))?
+                    .into_int_value();
+                // 実行時関数は等しいとき 1 を返す
+                let predicate = match operator {
+                    Operator::Equal => IntPredicate::NE,
+                    _ => IntPredicate::EQ,
+                };
+                self.build_int_comparison(predicate, equal, equal.get_type().const_zero())
+            }
+            operator => Err(CodeGenError::ExpressionCompilation(format!(
+                "{} is not defined on strings",
+                operator.symbol()
+            ))),
+        }
+    }
+
+    /// Calls a registered runtime routine, returning its result if it has one
+    fn call_runtime(
+        &self,
+        function: RuntimeFunction,
+        args: &[BasicMetadataValueEnum<'ctx>],
+    ) -> CodeGenResult<Option<BasicValueEnum<'ctx>>> {
+        let declaration = self.runtime_functions.get(&function).ok_or_else(|| {
+            CodeGenError::ExpressionCompilation(format!("{} is not declared", function.symbol()))
+        })?;
+        let call = self
+            .builder
+            .build_call(*declaration, args, function.symbol())
+            .map_err(|e| CodeGenError::ExpressionCompilation(e.to_string()))?;
+        Ok(call.try_as_basic_value().left())
+    }
+
+    /// Compiles an atomic built-in on the storage of a `Shared` Int field to the
+    /// matching LLVM atomic instruction, which becomes a WASM atomic with `+atomics`
+    pub fn compile_atomic(
+        &self,
+        operation: AtomicOperation,
+        field: &str,
+        args: &[Expression],
+        ordering: MemoryOrdering,
+    ) -> CodeGenResult<Option<BasicValueEnum<'ctx>>> {
+        let llvm_error =
+            |e: inkwell::builder::BuilderError| CodeGenError::ExpressionCompilation(e.to_string());
+        let pointer = *self.addresses.get(field).ok_or_else(|| {
+            CodeGenError::UndefinedVariable(format!("{} has no storage for atomic access", field))
+        })?;
+        let values = args
+            .iter()
+            .map(|arg| self.compile_expression(arg).map(|v| v.into_int_value()))
+            .collect::<CodeGenResult<Vec<_>>>()?;
+        let value = |index: usize| {
+            values.get(index).copied().ok_or_else(|| {
+                CodeGenError::ExpressionCompilation(format!(
+                    "{} is missing an argument",
+                    operation.name()
+                ))
+            })
+        };
+        let int_type = self.context.i32_type();
+        let atomic_ordering = Self::atomic_ordering(ordering);
+        let set_atomic = |instruction: inkwell::values::InstructionValue<'ctx>| {
+            instruction
+                .set_atomic_ordering(atomic_ordering)
+                .and_then(|_| instruction.set_alignment(4))
+                .map_err(|e| CodeGenError::ExpressionCompilation(e.to_string()))
+        };
+
+        match operation {
+            AtomicOperation::Load => {
+                let loaded = self
+                    .builder
+                    .build_load(int_type, pointer, "atomic_load")
+                    .map_err(llvm_error)?;
+                if let Some(instruction) = loaded.as_instruction_value() {
+                    set_atomic(instruction)?;
+                }
+                Ok(Some(loaded))
+            }
+            AtomicOperation::Store => {
+                let store = self
+                    .builder
+                    .build_store(pointer, value(0)?)
+                    .map_err(llvm_error)?;
+                set_atomic(store)?;
+                Ok(None)
+            }
+            AtomicOperation::Add => self
+                .builder
+                .build_atomicrmw(AtomicRMWBinOp::Add, pointer, value(0)?, atomic_ordering)
+                .map(|previous| Some(previous.as_basic_value_enum()))
+                .map_err(llvm_error),
+            AtomicOperation::CompareExchange => {
+                // 失敗時は書き込まないので release 成分を落とす
+                let failure = match atomic_ordering {
+                    AtomicOrdering::Release => AtomicOrdering::Monotonic,
+                    AtomicOrdering::AcquireRelease => AtomicOrdering::Acquire,
+                    other => other,
+                };
+                let result = self
+                    .builder
+                    .build_cmpxchg(pointer, value(0)?, value(1)?, atomic_ordering, failure)
+                    .map_err(llvm_error)?;
+                let original = self
+                    .builder
+                    .build_extract_value(result, 0, "original")
+                    .map_err(llvm_error)?;
+                let exchanged = self
+                    .builder
+                    .build_extract_value(result, 1, "exchanged")
+                    .map_err(llvm_error)?;
+
+                // (exchanged: Bool, original: Int) のレコードとして返す
+                let record_type = self
+                    .context
+                    .struct_type(&[self.context.bool_type().into(), int_type.into()], false);
+                let record = self
+                    .builder
+                    .build_insert_value(record_type.get_undef(), exchanged, 0, "record")
+                    .map_err(llvm_error)?;
+                let record = self
+                    .builder
+                    .build_insert_value(record, original, 1, "record")
+                    .map_err(llvm_error)?;
+                Ok(Some(record.as_basic_value_enum()))
+            }
+        }
+    }
+
+    fn atomic_ordering(ordering: MemoryOrdering) -> AtomicOrdering {
+        match ordering {
+            MemoryOrdering::Relaxed => AtomicOrdering::Monotonic,
+            MemoryOrdering::Acquire => AtomicOrdering::Acquire,
+            MemoryOrdering::Release => AtomicOrdering::Release,
+            MemoryOrdering::AcquireRelease => AtomicOrdering::AcquireRelease,
+            MemoryOrdering::SequentiallyConsistent => AtomicOrdering::SequentiallyConsistent,
+        }
+    }
+
+    /// Compiles an array literal to a by-value fixed-size array
+    fn compile_array_literal(
+        &self,
+        elements: &[Expression],
+    ) -> CodeGenResult<BasicValueEnum<'ctx>> {
+        let values = elements
+            .iter()
+            .map(|element| self.compile_expression(element))
+            .collect::<CodeGenResult<Vec<_>>>()?;
+        let element_type = values
+            .first()
+            .map(|value| value.get_type())
+            .ok_or_else(|| {
+                CodeGenError::ExpressionCompilation("Empty array literal".to_string())
+            })?;
+
+        let mut array = element_type.array_type(values.len() as u32).get_undef();
+        for (index, value) in values.into_iter().enumerate() {
+            array = self
+                .builder
+                .build_insert_value(array, value, index as u32, "array")
+                .map_err(|e| CodeGenError::ExpressionCompilation(e.to_string()))?
+                .into_array_value();
+        }
+        Ok(array.as_basic_value_enum())
+    }
+
+    /// Compiles `base[index]`. On a fixed-size array, constant indices were checked
+    /// by the semantic analyzer and read the element directly; dynamic indices go
+    /// through `__replica_array_element`, which traps when out of bounds.
+    fn compile_index(
+        &self,
+        base: &Expression,
+        index: &Expression,
+    ) -> CodeGenResult<BasicValueEnum<'ctx>> {
+        if let Expression::Variable(name) = base {
+            if let Some(&ConstantSource::Map {
+                find,
+                values,
+                value_type,
+            }) = self.constants.get(name).filter(|_| !self.is_local(name))
+            {
+                return self.compile_map_lookup(find, values, value_type, index);
+            }
+        }
+        let array = self.compile_expression(base)?;
+        let index = match self.compile_expression(index)? {
+            BasicValueEnum::IntValue(index) => index,
+            _ => {
+                return Err(CodeGenError::ExpressionCompilation(
+                    "Array index must be an integer".to_string(),
+                ))
+            }
+        };
+
+        let array = match array {
+            BasicValueEnum::ArrayValue(array) => array,
+            BasicValueEnum::PointerValue(array) => {
+                return self.compile_array_element(base, array, index)
+            }
+            _ => {
+                return Err(CodeGenError::ExpressionCompilation(format!(
+                    "{} is not an array",
+                    base
+                )))
+            }
+        };
+        match index.get_zero_extended_constant() {
+            Some(constant) => self
+                .builder
+                .build_extract_value(array, constant as u32, "element")
+                .map_err(|e| CodeGenError::ExpressionCompilation(e.to_string())),
+            None => self.compile_dynamic_index(array, index),
+        }
+    }
+
+    /// Compiles `base[index]` on a `[T]`. With bounds checks the element is
+    /// found by `__replica_array_get`, which traps when the index is out of
+    /// bounds; without them it is read straight from the buffer.
+    fn compile_array_element(
+        &self,
+        base: &Expression,
+        array: PointerValue<'ctx>,
+        index: IntValue<'ctx>,
+    ) -> CodeGenResult<BasicValueEnum<'ctx>> {
+        let llvm_error =
+            |e: inkwell::builder::BuilderError| CodeGenError::ExpressionCompilation(e.to_string());
+        let element_type = self.array_element_type(base)?;
+        let element = if self.bounds_checks {
+            let element_size = self.element_size(element_type)?;
+            self.call_runtime(
+                RuntimeFunction::ArrayGet,
+                &[array.into(), index.into(), element_size.into()],
+            )?
+            .ok_or_else(|| missing_result(RuntimeFunction::ArrayGet))?
+            .into_pointer_value()
+        } else {
+            let buffer = self.array_buffer(array)?;
+            // SAFETY: 範囲の検査は無効にされている
+            unsafe {
+                self.builder
+                    .build_in_bounds_gep(element_type, buffer, &[index], "element_ptr")
+                    .map_err(llvm_error)?
+            }
+        };
+        self.builder
+            .build_load(element_type, element, "element")
+            .map_err(llvm_error)
+    }
+
+    /// Compiles `table[key]` on a constant map into a call of its search
+    /// function and an optional that is nil when the key is missing
+    fn compile_map_lookup(
+        &self,
+        find: FunctionValue<'ctx>,
+        values: PointerValue<'ctx>,
+        value_type: BasicTypeEnum<'ctx>,
+        key: &Expression,
+    ) -> CodeGenResult<BasicValueEnum<'ctx>> {
+        let llvm_error =
+            |e: inkwell::builder::BuilderError| CodeGenError::ExpressionCompilation(e.to_string());
+        let key = self.compile_expression(key)?;
+        let position = self
+            .builder
+            .build_call(find, &[key.into()], "position")
+            .map_err(llvm_error)?
+            .try_as_basic_value()
+            .left()
+            .ok_or_else(|| {
+                CodeGenError::Internal(format!(
+                    "{} returns void",
+                    find.get_name().to_string_lossy()
+                ))
+            })?
+            .into_int_value();
+        let zero = position.get_type().const_zero();
+        let found = self
+            .builder
+            .build_int_compare(IntPredicate::SGE, position, zero, "found")
+            .map_err(llvm_error)?;
+        // 無いキーでも先頭の値を読み、フラグで nil にする
+        let index = self
+            .builder
+            .build_select(found, position, zero, "index")
+            .map_err(llvm_error)?
+            .into_int_value();
+        // SAFETY: 位置は検索した表の中にあり、表は空でも 1 要素ある
+        let element = unsafe {
+            self.builder
+                .build_in_bounds_gep(value_type, values, &[index], "value_ptr")
+                .map_err(llvm_error)?
+        };
+        let value = self
+            .builder
+            .build_load(value_type, element, "value")
+            .map_err(llvm_error)?;
+        let optional_type = self
+            .context
+            .struct_type(&[value_type, self.context.bool_type().into()], false);
+        let optional = self
+            .builder
+            .build_insert_value(optional_type.get_undef(), value, 0, "optional")
+            .map_err(llvm_error)?;
+        let optional = self
+            .builder
+            .build_insert_value(optional, found, 1, "optional")
+            .map_err(llvm_error)?;
+        Ok(optional.as_basic_value_enum())
+    }
+
+    /// Element type of the `[T]` that `base` evaluates to, known for locals,
+    /// parameters, fields, constants and method results
+    fn array_element_type(&self, base: &Expression) -> CodeGenResult<BasicTypeEnum<'ctx>> {
+        let element_type = match base {
+            Expression::Variable(name) if self.is_local(name) => self.arrays.get(name),
+            Expression::Variable(name) => match self.constants.get(name) {
+                Some(ConstantSource::Array { element_type, .. }) => Some(element_type),
+                _ => self.field_arrays.get(name),
+            },
+            Expression::Field(name) => self.field_arrays.get(name),
+            Expression::Call { callee, .. } => self.array_results.get(callee),
+            _ => None,
+        };
+        element_type.copied().ok_or_else(|| {
+            CodeGenError::ExpressionCompilation(format!(
+                "The element type of {} is not known; bind it to a local first",
+                base
+            ))
+        })
+    }
+
+    /// Whether `name` is a local or parameter, which hides fields and constants
+    fn is_local(&self, name: &str) -> bool {
+        self.variables.borrow().contains_key(name) || self.slots.contains_key(name)
+    }
+
+    /// Labels, in layout order, of the record that `base` evaluates to, known
+    /// for literals, locals, parameters, fields and method results
+    fn record_labels(&self, base: &Expression) -> Option<Vec<String>> {
+        let labels = match base {
+            Expression::Record(fields) => {
+                let mut labels: Vec<String> =
+                    fields.iter().map(|(label, _)| label.clone()).collect();
+                labels.sort();
+                return Some(labels);
+            }
+            Expression::Variable(name) if self.is_local(name) => self.records.get(name),
+            Expression::Variable(name) | Expression::Field(name) => self.field_records.get(name),
+            Expression::Call { callee, .. } => self.record_results.get(callee),
+            Expression::Atomic {
+                operation: AtomicOperation::CompareExchange,
+                ..
+            } => return Some(vec!["exchanged".to_string(), "original".to_string()]),
+            _ => None,
+        };
+        labels.cloned()
+    }
+
+    /// Labels of `value` when it is bound by `let pattern = value` and is a
+    /// record, to be registered with [`Self::register_record`] once bound
+    pub fn record_binding(
+        &self,
+        pattern: &Pattern,
+        value: &Expression,
+    ) -> Option<(String, Vec<String>)> {
+        let Pattern::Binding(name) = pattern else {
+            return None;
+        };
+        Some((name.clone(), self.record_labels(value)?))
+    }
+
+    /// Element type of `value` when it is bound by `let pattern = value` and
+    /// is a `[T]`, to be registered with [`Self::register_array`] once bound
+    pub fn array_binding(
+        &self,
+        pattern: &Pattern,
+        value: &Expression,
+    ) -> Option<(String, BasicTypeEnum<'ctx>)> {
+        let Pattern::Binding(name) = pattern else {
+            return None;
+        };
+        let element_type = self.array_element_type(value).ok()?;
+        Some((name.clone(), element_type))
+    }
+
+    /// Address of the buffer of a `[T]` descriptor
+    fn array_buffer(&self, array: PointerValue<'ctx>) -> CodeGenResult<PointerValue<'ctx>> {
+        let llvm_error =
+            |e: inkwell::builder::BuilderError| CodeGenError::ExpressionCompilation(e.to_string());
+        // 配列の記述子も String と同じ { ptr, len }
+        let descriptor_type = self.string_descriptor_type();
+        let slot = self
+            .builder
+            .build_struct_gep(descriptor_type, array, 0, "buffer_slot")
+            .map_err(llvm_error)?;
+        Ok(self
+            .builder
+            .build_load(
+                self.context.ptr_type(AddressSpace::default()),
+                slot,
+                "buffer",
+            )
+            .map_err(llvm_error)?
+            .into_pointer_value())
+    }
+
+    /// Copies a fixed-size array into a new `[T]`, allocated with
+    /// `__replica_array_alloc`
+    fn compile_heap_array(&self, array: ArrayValue<'ctx>) -> CodeGenResult<BasicValueEnum<'ctx>> {
+        let llvm_error =
+            |e: inkwell::builder::BuilderError| CodeGenError::ExpressionCompilation(e.to_string());
+        let array_type = array.get_type();
+        let element_type = array_type.get_element_type();
+        let length = self
+            .context
+            .i32_type()
+            .const_int(array_type.len() as u64, false);
+        let element_size = self.element_size(element_type)?;
+        let descriptor = self
+            .call_runtime(
+                RuntimeFunction::ArrayAlloc,
+                &[length.into(), element_size.into()],
+            )?
+            .ok_or_else(|| missing_result(RuntimeFunction::ArrayAlloc))?
+            .into_pointer_value();
+        let buffer = self.array_buffer(descriptor)?;
+        for index in 0..array_type.len() {
+            let value = self
+                .builder
+                .build_extract_value(array, index, "value")
+                .map_err(llvm_error)?;
+            let offset = self.context.i32_type().const_int(index as u64, false);
+            // SAFETY: バッファは配列の長さだけ確保されている
+            let element = unsafe {
+                self.builder
+                    .build_in_bounds_gep(element_type, buffer, &[offset], "element_ptr")
+                    .map_err(llvm_error)?
+            };
+            self.builder
+                .build_store(element, value)
+                .map_err(llvm_error)?;
+        }
+        Ok(descriptor.into())
+    }
+
+    /// Size in bytes of an array element, as the runtime's `usize`
+    fn element_size(&self, element_type: BasicTypeEnum<'ctx>) -> CodeGenResult<IntValue<'ctx>> {
+        Ok(self
+            .context
+            .i32_type()
+            .const_int(self.type_converter.abi_size(element_type).into(), false))
+    }
+
+    fn compile_dynamic_index(
+        &self,
+        array: ArrayValue<'ctx>,
+        index: IntValue<'ctx>,
+    ) -> CodeGenResult<BasicValueEnum<'ctx>> {
+        let llvm_error =
+            |e: inkwell::builder::BuilderError| CodeGenError::ExpressionCompilation(e.to_string());
+        let array_type = array.get_type();
+        let element_type = array_type.get_element_type();
+
+        // 添字アクセスのためにスタック上へ配置する
+        let storage = self
+            .builder
+            .build_alloca(array_type, "array")
+            .map_err(llvm_error)?;
+        self.builder
+            .build_store(storage, array)
+            .map_err(llvm_error)?;
+
+        let element = if self.bounds_checks {
+            let length = self
+                .context
+                .i32_type()
+                .const_int(array_type.len() as u64, false);
+            let element_size = self.element_size(element_type)?;
+            self.call_runtime(
+                RuntimeFunction::ArrayElement,
+                &[
+                    storage.into(),
+                    length.into(),
+                    index.into(),
+                    element_size.into(),
+                ],
+            )?
+            .ok_or_else(|| missing_result(RuntimeFunction::ArrayElement))?
+            .into_pointer_value()
+        } else {
+            let zero = self.context.i32_type().const_zero();
+            // SAFETY: 範囲の検査は無効にされている
+            unsafe {
+                self.builder
+                    .build_in_bounds_gep(array_type, storage, &[zero, index], "element_ptr")
+                    .map_err(llvm_error)?
+            }
+        };
+        self.builder
+            .build_load(element_type, element, "element")
+            .map_err(llvm_error)
+    }
+
+    /// Compiles a tuple to a by-value anonymous struct
+    fn compile_tuple(&self, elements: &[Expression]) -> CodeGenResult<BasicValueEnum<'ctx>> {
+        let values = elements
+            .iter()
+            .map(|element| self.compile_expression(element))
+            .collect::<CodeGenResult<Vec<_>>>()?;
+        self.anonymous_struct(values)
+    }
+
+    /// Compiles a record literal to the layout of its type: the values are
+    /// evaluated as written and stored in label order
+    fn compile_record(
+        &self,
+        fields: &[(String, Expression)],
+    ) -> CodeGenResult<BasicValueEnum<'ctx>> {
+        let mut values = fields
+            .iter()
+            .map(|(label, value)| Ok((label, self.compile_expression(value)?)))
+            .collect::<CodeGenResult<Vec<_>>>()?;
+        values.sort_by_key(|(label, _)| *label);
+        self.anonymous_struct(values.into_iter().map(|(_, value)| value).collect())
+    }
+
+    /// Packs `values` in order into a by-value anonymous struct
+    fn anonymous_struct(
+        &self,
+        values: Vec<BasicValueEnum<'ctx>>,
+    ) -> CodeGenResult<BasicValueEnum<'ctx>> {
+        let types: Vec<_> = values.iter().map(|value| value.get_type()).collect();
+
+        let mut tuple = self.context.struct_type(&types, false).get_undef();
+        for (index, value) in values.into_iter().enumerate() {
+            tuple = self
+                .builder
+                .build_insert_value(tuple, value, index as u32, "tuple")
+                .map_err(|e| CodeGenError::ExpressionCompilation(e.to_string()))?
+                .into_struct_value();
         }
+        Ok(tuple.as_basic_value_enum())
+    }
+
+    /// Compiles `Name(field: value, ...)` to a by-value struct, storing each value
+    /// at the position of its field in the declaration
+    fn compile_struct_literal(
+        &self,
+        name: &str,
+        fields: &[(String, Expression)],
+    ) -> CodeGenResult<BasicValueEnum<'ctx>> {
+        let (struct_type, names) = self
+            .structs
+            .get(name)
+            .ok_or_else(|| CodeGenError::TypeConversion(format!("Unknown struct {}", name)))?;
+        let mut value = struct_type.get_undef();
+        for (label, field) in fields {
+            let index = Self::struct_field_index(name, names, label)?;
+            let field = self.compile_expression(field)?;
+            value = self
+                .builder
+                .build_insert_value(value, field, index, label)
+                .map_err(|e| CodeGenError::ExpressionCompilation(e.to_string()))?
+                .into_struct_value();
+        }
+        Ok(value.as_basic_value_enum())
+    }
+
+    /// Compiles `base.name`, reading a field of a struct value, the `count`
+    /// of an array or the `length` of a string
+    fn compile_member(&self, base: &Expression, name: &str) -> CodeGenResult<BasicValueEnum<'ctx>> {
+        let value = match (self.compile_expression(base)?, name) {
+            (BasicValueEnum::StructValue(value), _) => value,
+            (BasicValueEnum::ArrayValue(array), "count") => {
+                let length = array.get_type().len() as u64;
+                return Ok(self.context.i32_type().const_int(length, false).into());
+            }
+            (BasicValueEnum::PointerValue(array), "count") => {
+                return self
+                    .call_runtime(RuntimeFunction::ArrayLen, &[array.into()])?
+                    .ok_or_else(|| missing_result(RuntimeFunction::ArrayLen));
+            }
+            (BasicValueEnum::PointerValue(string), "length") => {
+                return self.string_length(string);
+            }
+            _ => {
+                return Err(CodeGenError::ExpressionCompilation(format!(
+                    "{} is not a struct value",
+                    base
+                )))
+            }
+        };
+        // レコードはラベル順に並ぶ
+        if let Some(labels) = self.record_labels(base) {
+            let index = labels
+                .iter()
+                .position(|label| label == name)
+                .ok_or_else(|| {
+                    CodeGenError::ExpressionCompilation(format!(
+                        "Record {} has no field {}",
+                        base, name
+                    ))
+                })?;
+            return self
+                .builder
+                .build_extract_value(value, index as u32, name)
+                .map_err(|e| CodeGenError::ExpressionCompilation(e.to_string()));
+        }
+        // 名前付き型から宣言を引く
+        let (struct_name, (_, names)) = self
+            .structs
+            .iter()
+            .find(|(_, (struct_type, _))| *struct_type == value.get_type())
+            .ok_or_else(|| {
+                CodeGenError::ExpressionCompilation(format!("{} is not a struct value", base))
+            })?;
+        let index = Self::struct_field_index(struct_name, names, name)?;
+        self.builder
+            .build_extract_value(value, index, name)
+            .map_err(|e| CodeGenError::ExpressionCompilation(e.to_string()))
+    }
+
+    fn struct_field_index(struct_name: &str, names: &[String], field: &str) -> CodeGenResult<u32> {
+        names
+            .iter()
+            .position(|name| name == field)
+            .map(|index| index as u32)
+            .ok_or_else(|| {
+                CodeGenError::ExpressionCompilation(format!(
+                    "Struct {} has no field {}",
+                    struct_name, field
+                ))
+            })
+    }
+
+    /// Compiles `Name.case(values)` to a tagged union: the index of the case
+    /// as its tag and the values, laid out as a struct, at the start of the
+    /// payload
+    fn compile_enum_case(
+        &self,
+        enum_name: &str,
+        case: &str,
+        args: &[Expression],
+    ) -> CodeGenResult<BasicValueEnum<'ctx>> {
+        let llvm_error =
+            |e: inkwell::builder::BuilderError| CodeGenError::ExpressionCompilation(e.to_string());
+        let (enum_type, tag, case_type) = self.enum_case_layout(enum_name, case)?;
+        let mut values = case_type.get_undef();
+        for (index, arg) in args.iter().enumerate() {
+            let arg = self.compile_expression(arg)?;
+            values = self
+                .builder
+                .build_insert_value(values, arg, index as u32, "value")
+                .map_err(llvm_error)?
+                .into_struct_value();
+        }
+        // 未使用のペイロードも決まった値にするため、ゼロで埋めてから書く
+        let storage = self
+            .builder
+            .build_alloca(enum_type, enum_name)
+            .map_err(llvm_error)?;
+        self.builder
+            .build_store(storage, enum_type.const_zero())
+            .map_err(llvm_error)?;
+        let tag_address = self
+            .builder
+            .build_struct_gep(enum_type, storage, 0, "tag")
+            .map_err(llvm_error)?;
+        self.builder
+            .build_store(
+                tag_address,
+                self.context.i32_type().const_int(tag as u64, false),
+            )
+            .map_err(llvm_error)?;
+        let payload = self
+            .builder
+            .build_struct_gep(enum_type, storage, 1, "payload")
+            .map_err(llvm_error)?;
+        self.builder
+            .build_store(payload, values)
+            .map_err(llvm_error)?;
+        self.builder
+            .build_load(enum_type, storage, case)
+            .map_err(llvm_error)
+    }
+
+    /// The tagged union type of `enum_name`, the tag of `case` and the struct
+    /// its values are stored as in the payload
+    fn enum_case_layout(
+        &self,
+        enum_name: &str,
+        case: &str,
+    ) -> CodeGenResult<(StructType<'ctx>, usize, StructType<'ctx>)> {
+        let (enum_type, cases) = self
+            .enums
+            .get(enum_name)
+            .ok_or_else(|| CodeGenError::TypeConversion(format!("Unknown enum {}", enum_name)))?;
+        let tag = cases
+            .iter()
+            .position(|declared| declared.name == case)
+            .ok_or_else(|| {
+                CodeGenError::ExpressionCompilation(format!(
+                    "Enum {} has no case {}",
+                    enum_name, case
+                ))
+            })?;
+        let value_types = cases[tag]
+            .values
+            .iter()
+            .map(|ty| self.type_converter.convert_to_llvm(ty))
+            .collect::<CodeGenResult<Vec<_>>>()?;
+        Ok((
+            *enum_type,
+            tag,
+            self.context.struct_type(&value_types, false),
+        ))
+    }
+
+    /// Compiles `match value { pattern => result ... }` to a chain of pattern
+    /// tests, one per arm in order, joining the results of the arms in a phi
+    fn compile_match(
+        &self,
+        value: &Expression,
+        arms: &[(Pattern, Expression)],
+    ) -> CodeGenResult<BasicValueEnum<'ctx>> {
+        let llvm_error =
+            |e: inkwell::builder::BuilderError| CodeGenError::ExpressionCompilation(e.to_string());
+        let value = self.compile_expression(value)?;
+        let function = self
+            .builder
+            .get_insert_block()
+            .and_then(|block| block.get_parent())
+            .ok_or_else(|| CodeGenError::Internal("match outside of a function".into()))?;
+        let merge_block = self.context.append_basic_block(function, "match_end");
+        let mut incoming = Vec::new();
+        for (pattern, result) in arms {
+            let (matched, bindings) = self.test_pattern(pattern, value)?;
+            let arm_block = self.context.append_basic_block(function, "match_arm");
+            let next_block = self.context.append_basic_block(function, "match_next");
+            self.builder
+                .build_conditional_branch(matched, arm_block, next_block)
+                .map_err(llvm_error)?;
+
+            self.builder.position_at_end(arm_block);
+            // 腕の束縛は結果の式の間だけ有効
+            let saved = self.variables.borrow().clone();
+            self.variables.borrow_mut().extend(bindings);
+            let result = self.compile_expression(result);
+            *self.variables.borrow_mut() = saved;
+            let result = result?;
+            let end_block = self
+                .builder
+                .get_insert_block()
+                .ok_or_else(|| CodeGenError::Internal("Builder has no block".into()))?;
+            self.builder
+                .build_unconditional_branch(merge_block)
+                .map_err(llvm_error)?;
+            incoming.push((result, end_block));
+            self.builder.position_at_end(next_block);
+        }
+        // 腕は網羅的なので最後の判定の後には来ない
+        self.builder.build_unreachable().map_err(llvm_error)?;
+
+        self.builder.position_at_end(merge_block);
+        let result_type = incoming
+            .first()
+            .map(|(result, _)| result.get_type())
+            .ok_or_else(|| CodeGenError::Internal("match without arms".into()))?;
+        let phi = self
+            .builder
+            .build_phi(result_type, "match")
+            .map_err(llvm_error)?;
+        for (result, block) in &incoming {
+            phi.add_incoming(&[(result, *block)]);
+        }
+        Ok(phi.as_basic_value())
+    }
+
+    /// Tests whether `value` matches a pattern, which may be refutable.
+    /// Returns the `i1` outcome and the values the pattern binds, which are
+    /// only meaningful where the outcome is true.
+    pub fn test_pattern(
+        &self,
+        pattern: &Pattern,
+        value: BasicValueEnum<'ctx>,
+    ) -> CodeGenResult<(IntValue<'ctx>, Vec<(String, BasicValueEnum<'ctx>)>)> {
+        let mut bindings = Vec::new();
+        let matched = self.collect_tests(pattern, value, &mut bindings)?;
+        Ok((matched, bindings))
+    }
+
+    fn collect_tests(
+        &self,
+        pattern: &Pattern,
+        value: BasicValueEnum<'ctx>,
+        bindings: &mut Vec<(String, BasicValueEnum<'ctx>)>,
+    ) -> CodeGenResult<IntValue<'ctx>> {
+        let llvm_error =
+            |e: inkwell::builder::BuilderError| CodeGenError::ExpressionCompilation(e.to_string());
+        let always = self.context.bool_type().const_int(1, false);
+        match (pattern, value) {
+            (Pattern::Wildcard, _) => Ok(always),
+            (Pattern::Binding(name), _) => {
+                bindings.push((name.clone(), value));
+                Ok(always)
+            }
+            (
+                Pattern::Literal(literal @ (LiteralValue::Int(_) | LiteralValue::Bool(_))),
+                BasicValueEnum::IntValue(value),
+            ) => {
+                let expected = self.compile_literal(literal)?.into_int_value();
+                self.builder
+                    .build_int_compare(IntPredicate::EQ, value, expected, "is_literal")
+                    .map_err(llvm_error)
+            }
+            (
+                Pattern::Literal(LiteralValue::Float(expected)),
+                BasicValueEnum::FloatValue(value),
+            ) => {
+                let expected = self.context.f64_type().const_float(*expected);
+                self.builder
+                    .build_float_compare(FloatPredicate::OEQ, value, expected, "is_literal")
+                    .map_err(llvm_error)
+            }
+            (Pattern::Tuple(patterns), BasicValueEnum::StructValue(tuple)) => {
+                let mut matched = always;
+                for (index, pattern) in patterns.iter().enumerate() {
+                    let element = self
+                        .builder
+                        .build_extract_value(tuple, index as u32, "element")
+                        .map_err(llvm_error)?;
+                    let element = self.collect_tests(pattern, element, bindings)?;
+                    matched = self
+                        .builder
+                        .build_and(matched, element, "matched")
+                        .map_err(llvm_error)?;
+                }
+                Ok(matched)
+            }
+            (Pattern::Case { name, args }, BasicValueEnum::StructValue(union)) => {
+                let (tag_matches, values) = match self
+                    .enums
+                    .iter()
+                    .find(|(_, (enum_type, _))| *enum_type == union.get_type())
+                {
+                    Some((enum_name, _)) => self.read_enum_case(enum_name, name, union)?,
+                    None => self.read_optional_case(name, union)?,
+                };
+                let mut matched = tag_matches;
+                for (pattern, value) in args.iter().zip(values) {
+                    let value = self.collect_tests(pattern, value, bindings)?;
+                    matched = self
+                        .builder
+                        .build_and(matched, value, "matched")
+                        .map_err(llvm_error)?;
+                }
+                Ok(matched)
+            }
+            _ => Err(CodeGenError::ExpressionCompilation(format!(
+                "Pattern {} cannot be tested in generated code",
+                pattern
+            ))),
+        }
+    }
+
+    /// Whether a tagged union holds `case`, and the values stored for it
+    fn read_enum_case(
+        &self,
+        enum_name: &str,
+        case: &str,
+        union: StructValue<'ctx>,
+    ) -> CodeGenResult<(IntValue<'ctx>, Vec<BasicValueEnum<'ctx>>)> {
+        let llvm_error =
+            |e: inkwell::builder::BuilderError| CodeGenError::ExpressionCompilation(e.to_string());
+        let (enum_type, tag, case_type) = self.enum_case_layout(enum_name, case)?;
+        let found = self
+            .builder
+            .build_extract_value(union, 0, "tag")
+            .map_err(llvm_error)?
+            .into_int_value();
+        let matched = self
+            .builder
+            .build_int_compare(
+                IntPredicate::EQ,
+                found,
+                self.context.i32_type().const_int(tag as u64, false),
+                "is_case",
+            )
+            .map_err(llvm_error)?;
+        // ペイロードはケースごとに型が違うので、メモリを介して読み替える
+        let storage = self
+            .builder
+            .build_alloca(enum_type, enum_name)
+            .map_err(llvm_error)?;
+        self.builder
+            .build_store(storage, union)
+            .map_err(llvm_error)?;
+        let payload = self
+            .builder
+            .build_struct_gep(enum_type, storage, 1, "payload")
+            .map_err(llvm_error)?;
+        let values = self
+            .builder
+            .build_load(case_type, payload, case)
+            .map_err(llvm_error)?
+            .into_struct_value();
+        let values = (0..case_type.count_fields())
+            .map(|index| {
+                self.builder
+                    .build_extract_value(values, index, "value")
+                    .map_err(llvm_error)
+            })
+            .collect::<CodeGenResult<Vec<_>>>()?;
+        Ok((matched, values))
+    }
+
+    /// Whether an optional `{ value, present }` is `.some` or `.none`, and
+    /// the value of `.some`
+    fn read_optional_case(
+        &self,
+        case: &str,
+        optional: StructValue<'ctx>,
+    ) -> CodeGenResult<(IntValue<'ctx>, Vec<BasicValueEnum<'ctx>>)> {
+        let llvm_error =
+            |e: inkwell::builder::BuilderError| CodeGenError::ExpressionCompilation(e.to_string());
+        let present = self
+            .builder
+            .build_extract_value(optional, 1, "present")
+            .map_err(llvm_error)?
+            .into_int_value();
+        match case {
+            "some" => {
+                let value = self
+                    .builder
+                    .build_extract_value(optional, 0, "value")
+                    .map_err(llvm_error)?;
+                Ok((present, vec![value]))
+            }
+            "none" => {
+                let absent = self
+                    .builder
+                    .build_not(present, "absent")
+                    .map_err(llvm_error)?;
+                Ok((absent, Vec::new()))
+            }
+            _ => Err(CodeGenError::ExpressionCompilation(format!(
+                "Optional has no case {}",
+                case
+            ))),
+        }
+    }
+
+    /// Compiles `value!`, trapping through the runtime when the optional is `nil`
+    fn compile_unwrap(&self, value: &Expression) -> CodeGenResult<BasicValueEnum<'ctx>> {
+        let llvm_error =
+            |e: inkwell::builder::BuilderError| CodeGenError::ExpressionCompilation(e.to_string());
+        let optional = self.compile_optional(value, "!")?;
+        let (present, values) = self.read_optional_case("some", optional)?;
+        let function = self.current_function("!")?;
+        let present_block = self.context.append_basic_block(function, "unwrap_some");
+        let missing_block = self.context.append_basic_block(function, "unwrap_nil");
+        self.builder
+            .build_conditional_branch(present, present_block, missing_block)
+            .map_err(llvm_error)?;
+
+        self.builder.position_at_end(missing_block);
+        self.call_runtime(RuntimeFunction::UnwrapNil, &[])?;
+        self.builder.build_unreachable().map_err(llvm_error)?;
+
+        self.builder.position_at_end(present_block);
+        values
+            .into_iter()
+            .next()
+            .ok_or_else(|| CodeGenError::Internal("optional without a value".into()))
+    }
+
+    /// Compiles `value ?? default`. The default is only evaluated when the
+    /// optional is `nil`, and the two results are joined in a phi.
+    fn compile_coalesce(
+        &self,
+        value: &Expression,
+        default: &Expression,
+    ) -> CodeGenResult<BasicValueEnum<'ctx>> {
+        let llvm_error =
+            |e: inkwell::builder::BuilderError| CodeGenError::ExpressionCompilation(e.to_string());
+        let optional = self.compile_optional(value, "??")?;
+        let (present, values) = self.read_optional_case("some", optional)?;
+        let value = values
+            .into_iter()
+            .next()
+            .ok_or_else(|| CodeGenError::Internal("optional without a value".into()))?;
+        let function = self.current_function("??")?;
+        let present_block = self
+            .builder
+            .get_insert_block()
+            .ok_or_else(|| CodeGenError::Internal("Builder has no block".into()))?;
+        let default_block = self
+            .context
+            .append_basic_block(function, "coalesce_default");
+        let merge_block = self.context.append_basic_block(function, "coalesce_end");
+        self.builder
+            .build_conditional_branch(present, merge_block, default_block)
+            .map_err(llvm_error)?;
+
+        self.builder.position_at_end(default_block);
+        // 既定値が nil や入れ子の ?? でも型が決まるよう、値の型を期待する
+        let default = self.compile_expected(default, value.get_type())?;
+        let default_end = self
+            .builder
+            .get_insert_block()
+            .ok_or_else(|| CodeGenError::Internal("Builder has no block".into()))?;
+        self.builder
+            .build_unconditional_branch(merge_block)
+            .map_err(llvm_error)?;
+
+        self.builder.position_at_end(merge_block);
+        let phi = self
+            .builder
+            .build_phi(value.get_type(), "coalesce")
+            .map_err(llvm_error)?;
+        phi.add_incoming(&[(&value, present_block), (&default, default_end)]);
+        Ok(phi.as_basic_value())
+    }
+
+    /// Compiles the operand of `!` or `??`, which must be an optional
+    fn compile_optional(
+        &self,
+        value: &Expression,
+        operator: &str,
+    ) -> CodeGenResult<StructValue<'ctx>> {
+        match self.compile_expression(value)? {
+            BasicValueEnum::StructValue(optional) => Ok(optional),
+            _ => Err(CodeGenError::ExpressionCompilation(format!(
+                "{} unwraps an optional, found {}",
+                operator, value
+            ))),
+        }
+    }
+
+    /// The function the builder is currently emitting into
+    fn current_function(&self, construct: &str) -> CodeGenResult<FunctionValue<'ctx>> {
+        self.builder
+            .get_insert_block()
+            .and_then(|block| block.get_parent())
+            .ok_or_else(|| CodeGenError::Internal(format!("{} outside of a function", construct)))
+    }
+
+    /// Compiles `let pattern = value`, registering the bound variables
+    pub fn compile_let(&mut self, pattern: &Pattern, value: &Expression) -> CodeGenResult<()> {
+        let array = self.array_binding(pattern, value);
+        let record = self.record_binding(pattern, value);
+        let value = self.compile_expression(value)?;
+        for (name, value) in self.destructure(pattern, value)? {
+            self.register_variable(name, value);
+        }
+        if let Some((name, element_type)) = array {
+            self.register_array(name, element_type);
+        }
+        if let Some((name, labels)) = record {
+            self.register_record(name, labels);
+        }
+        Ok(())
+    }
+
+    /// Extracts the values bound by an irrefutable pattern, paired with their names
+    pub fn destructure(
+        &self,
+        pattern: &Pattern,
+        value: BasicValueEnum<'ctx>,
+    ) -> CodeGenResult<Vec<(String, BasicValueEnum<'ctx>)>> {
+        let mut bindings = Vec::new();
+        self.collect_bindings(pattern, value, &mut bindings)?;
+        Ok(bindings)
+    }
+
+    fn collect_bindings(
+        &self,
+        pattern: &Pattern,
+        value: BasicValueEnum<'ctx>,
+        bindings: &mut Vec<(String, BasicValueEnum<'ctx>)>,
+    ) -> CodeGenResult<()> {
+        match (pattern, value) {
+            (Pattern::Wildcard, _) => Ok(()),
+            (Pattern::Binding(name), _) => {
+                bindings.push((name.clone(), value));
+                Ok(())
+            }
+            (Pattern::Tuple(patterns), BasicValueEnum::StructValue(tuple)) => {
+                for (index, pattern) in patterns.iter().enumerate() {
+                    let element = self
+                        .builder
+                        .build_extract_value(tuple, index as u32, "element")
+                        .map_err(|e| CodeGenError::ExpressionCompilation(e.to_string()))?;
+                    self.collect_bindings(pattern, element, bindings)?;
+                }
+                Ok(())
+            }
+            _ => Err(CodeGenError::ExpressionCompilation(format!(
+                "Pattern {:?} is not irrefutable",
+                pattern
+            ))),
+        }
+    }
+
+    /// Compiles a method call, returning `None` for methods without a return value.
+    /// `inout` arguments are passed by pointer to the caller's storage.
+    pub fn compile_call(
+        &self,
+        callee: &str,
+        args: &[Expression],
+    ) -> CodeGenResult<Option<BasicValueEnum<'ctx>>> {
+        let function = self
+            .functions
+            .get(callee)
+            .ok_or_else(|| CodeGenError::UndefinedVariable(callee.to_string()))?;
+
+        // メソッドには呼び出し元と同じインスタンスを隠れた第一引数として渡す
+        let instance = if self.methods.contains(callee) {
+            let instance = self.instance.ok_or_else(|| {
+                CodeGenError::ExpressionCompilation(format!(
+                    "Method {} can only be called on an instance",
+                    callee
+                ))
+            })?;
+            Some(BasicMetadataValueEnum::from(instance))
+        } else {
+            None
+        };
+        let args = instance
+            .into_iter()
+            .chain(
+                self.compile_call_arguments(callee, args)?
+                    .into_iter()
+                    .map(Into::into),
+            )
+            .collect::<Vec<_>>();
+
+        let call = self
+            .builder
+            .build_call(*function, &args, "calltmp")
+            .map_err(|e| CodeGenError::ExpressionCompilation(e.to_string()))?;
+        Ok(call.try_as_basic_value().left())
+    }
+
+    /// Compiles the arguments of a call of `callee` against its parameter
+    /// types, not counting the instance a method is called on
+    pub fn compile_call_arguments(
+        &self,
+        callee: &str,
+        args: &[Expression],
+    ) -> CodeGenResult<Vec<BasicValueEnum<'ctx>>> {
+        let function = self
+            .functions
+            .get(callee)
+            .ok_or_else(|| CodeGenError::UndefinedVariable(callee.to_string()))?;
+        let param_types = function.get_type().get_param_types();
+        let param_types = &param_types[usize::from(self.methods.contains(callee))..];
+        if param_types.len() != args.len() {
+            return Err(CodeGenError::ExpressionCompilation(format!(
+                "{} takes {} arguments, found {}",
+                callee,
+                param_types.len(),
+                args.len()
+            )));
+        }
+        args.iter()
+            .zip(param_types)
+            .map(|(arg, &param_type)| {
+                let value = self.compile_expected(arg, param_type)?;
+                self.coerce_argument(value, param_type)
+            })
+            .collect()
+    }
+
+    /// Converts a function pointer passed to a `funcref` parameter. Other
+    /// arguments already have the parameter's type.
+    fn coerce_argument(
+        &self,
+        value: BasicValueEnum<'ctx>,
+        param_type: BasicTypeEnum<'ctx>,
+    ) -> CodeGenResult<BasicValueEnum<'ctx>> {
+        let (BasicValueEnum::PointerValue(pointer), BasicTypeEnum::PointerType(expected)) =
+            (value, param_type)
+        else {
+            return Ok(value);
+        };
+        if pointer.get_type() == expected {
+            return Ok(value);
+        }
+
+        let helper = self.funcref_helper.ok_or_else(|| {
+            CodeGenError::ExpressionCompilation(
+                "Function values can only be passed as funcref with reference types enabled"
+                    .to_string(),
+            )
+        })?;
+        // 関数ポインタは間接関数テーブルの添字
+        let index = self
+            .builder
+            .build_ptr_to_int(pointer, self.context.i32_type(), "table_index")
+            .map_err(|e| CodeGenError::ExpressionCompilation(e.to_string()))?;
+        self.builder
+            .build_call(helper, &[index.into()], "funcref")
+            .map_err(|e| CodeGenError::ExpressionCompilation(e.to_string()))?
+            .try_as_basic_value()
+            .left()
+            .ok_or_else(|| {
+                CodeGenError::ExpressionCompilation("funcref helper returned no value".to_string())
+            })
+    }
+
+    /// Compiles `&name` to the address of the variable's storage
+    fn compile_inout_argument(&self, name: &str) -> CodeGenResult<BasicValueEnum<'ctx>> {
+        self.addresses
+            .get(name)
+            .map(|address| address.as_basic_value_enum())
+            .ok_or_else(|| {
+                CodeGenError::OwnershipViolation(format!(
+                    "{} has no storage that can be passed as inout",
+                    name
+                ))
+            })
     }
 
     /// Compiles a binary operation
@@ -83,6 +1974,12 @@ impl<'ctx> ExpressionCompiler<'ctx> {
                         .builder
                         .build_int_signed_div(l, r, "divtmp")
                         .map_err(|e| CodeGenError::ExpressionCompilation(e.to_string()))?,
+                    comparison => {
+                        let predicate = int_predicate(comparison).ok_or_else(|| {
+                            CodeGenError::Internal(format!("{:?} is not a comparison", comparison))
+                        })?;
+                        return self.build_int_comparison(predicate, l, r);
+                    }
                 };
                 Ok(result.as_basic_value_enum())
             }
@@ -104,9 +2001,19 @@ impl<'ctx> ExpressionCompiler<'ctx> {
                         .builder
                         .build_float_div(l, r, "divtmp")
                         .map_err(|e| CodeGenError::ExpressionCompilation(e.to_string()))?,
+                    comparison => {
+                        let predicate = float_predicate(comparison).ok_or_else(|| {
+                            CodeGenError::Internal(format!("{:?} is not a comparison", comparison))
+                        })?;
+                        return self.build_float_comparison(predicate, l, r);
+                    }
                 };
                 Ok(result.as_basic_value_enum())
             }
+            // ポインタの演算数は文字列の記述子 (配列どうしの演算は意味解析で弾く)
+            (BasicValueEnum::PointerValue(l), BasicValueEnum::PointerValue(r)) => {
+                self.compile_string_operation(operator, l, r)
+            }
             _ => Err(CodeGenError::ExpressionCompilation(
                 "Incompatible types for binary operation".to_string(),
             )),
@@ -127,11 +2034,30 @@ impl<'ctx> ExpressionCompiler<'ctx> {
                 .const_float(*f)
                 .as_basic_value_enum()),
             LiteralValue::String(s) => {
-                let string_ptr = self
+                let llvm_error = |e: inkwell::builder::BuilderError| {
+                    CodeGenError::ExpressionCompilation(e.to_string())
+                };
+                // 文字列の値はバイト列を指す { ptr, len } 記述子へのポインタ
+                let bytes = self
                     .builder
                     .build_global_string_ptr(s, "str")
-                    .map_err(|e| CodeGenError::ExpressionCompilation(e.to_string()))?;
-                Ok(string_ptr.as_basic_value_enum())
+                    .map_err(llvm_error)?;
+                let descriptor = self.alloc_string_descriptor()?.into_pointer_value();
+                let descriptor_type = self.string_descriptor_type();
+                let ptr = self
+                    .builder
+                    .build_struct_gep(descriptor_type, descriptor, 0, "str.ptr")
+                    .map_err(llvm_error)?;
+                self.builder
+                    .build_store(ptr, bytes.as_pointer_value())
+                    .map_err(llvm_error)?;
+                let len = self
+                    .builder
+                    .build_struct_gep(descriptor_type, descriptor, 1, "str.len")
+                    .map_err(llvm_error)?;
+                let length = self.context.i32_type().const_int(s.len() as u64, false);
+                self.builder.build_store(len, length).map_err(llvm_error)?;
+                Ok(descriptor.as_basic_value_enum())
             }
             LiteralValue::Bool(b) => Ok(self
                 .context
@@ -141,14 +2067,42 @@ impl<'ctx> ExpressionCompiler<'ctx> {
         }
     }
 
-    /// Compiles a variable reference
+    /// Compiles a variable reference. A name that is neither a variable nor a
+    /// parameter reads the field of that name; a method name evaluates to a
+    /// pointer to the method, which the host can call back.
     fn compile_variable(&self, name: &str) -> CodeGenResult<BasicValueEnum<'ctx>> {
-        self.variables
+        if let Some(value) = self.variables.borrow().get(name) {
+            return Ok(*value);
+        }
+        if let Some(slot) = self.slots.get(name).or_else(|| self.fields.get(name)) {
+            return self.load_slot(name, slot);
+        }
+        if let Some(ConstantSource::Array { descriptor, .. }) = self.constants.get(name) {
+            return Ok(descriptor.as_basic_value_enum());
+        }
+        self.functions
             .get(name)
-            .cloned()
+            .map(|function| {
+                function
+                    .as_global_value()
+                    .as_pointer_value()
+                    .as_basic_value_enum()
+            })
             .ok_or_else(|| CodeGenError::UndefinedVariable(name.to_string()))
     }
 
+    /// Loads the current value of a field or `inout` parameter
+    fn load_slot(&self, name: &str, slot: &Slot<'ctx>) -> CodeGenResult<BasicValueEnum<'ctx>> {
+        if slot.weak {
+            return self
+                .call_runtime(RuntimeFunction::WeakLoad, &[slot.address.into()])?
+                .ok_or_else(|| missing_result(RuntimeFunction::WeakLoad));
+        }
+        self.builder
+            .build_load(slot.value_type, slot.address, name)
+            .map_err(|e| CodeGenError::ExpressionCompilation(e.to_string()))
+    }
+
     /// Compiles a comparison operation
     pub fn compile_comparison(
         &self,
@@ -161,11 +2115,7 @@ impl<'ctx> ExpressionCompiler<'ctx> {
 
         match (left_value, right_value) {
             (BasicValueEnum::IntValue(l), BasicValueEnum::IntValue(r)) => {
-                let result = self
-                    .builder
-                    .build_int_compare(predicate, l, r, "cmptmp")
-                    .map_err(|e| CodeGenError::ExpressionCompilation(e.to_string()))?;
-                Ok(result.as_basic_value_enum())
+                self.build_int_comparison(predicate, l, r)
             }
             _ => Err(CodeGenError::ExpressionCompilation(
                 "Invalid types for comparison".to_string(),
@@ -173,6 +2123,19 @@ impl<'ctx> ExpressionCompiler<'ctx> {
         }
     }
 
+    fn build_int_comparison(
+        &self,
+        predicate: IntPredicate,
+        l: IntValue<'ctx>,
+        r: IntValue<'ctx>,
+    ) -> CodeGenResult<BasicValueEnum<'ctx>> {
+        let result = self
+            .builder
+            .build_int_compare(predicate, l, r, "cmptmp")
+            .map_err(|e| CodeGenError::ExpressionCompilation(e.to_string()))?;
+        Ok(result.as_basic_value_enum())
+    }
+
     /// Compiles a floating point comparison operation
     pub fn compile_float_comparison(
         &self,
@@ -185,17 +2148,58 @@ impl<'ctx> ExpressionCompiler<'ctx> {
 
         match (left_value, right_value) {
             (BasicValueEnum::FloatValue(l), BasicValueEnum::FloatValue(r)) => {
-                let result = self
-                    .builder
-                    .build_float_compare(predicate, l, r, "cmptmp")
-                    .map_err(|e| CodeGenError::ExpressionCompilation(e.to_string()))?;
-                Ok(result.as_basic_value_enum())
+                self.build_float_comparison(predicate, l, r)
             }
             _ => Err(CodeGenError::ExpressionCompilation(
                 "Invalid types for float comparison".to_string(),
             )),
         }
     }
+
+    fn build_float_comparison(
+        &self,
+        predicate: FloatPredicate,
+        l: FloatValue<'ctx>,
+        r: FloatValue<'ctx>,
+    ) -> CodeGenResult<BasicValueEnum<'ctx>> {
+        let result = self
+            .builder
+            .build_float_compare(predicate, l, r, "cmptmp")
+            .map_err(|e| CodeGenError::ExpressionCompilation(e.to_string()))?;
+        Ok(result.as_basic_value_enum())
+    }
+}
+
+fn missing_result(function: RuntimeFunction) -> CodeGenError {
+    CodeGenError::ExpressionCompilation(format!("{} returned no value", function.symbol()))
+}
+
+/// Signed integer predicate for a comparison operator. `Bool`s are `i1`s, so
+/// their equality uses the same predicates.
+fn int_predicate(operator: &Operator) -> Option<IntPredicate> {
+    match operator {
+        Operator::Equal => Some(IntPredicate::EQ),
+        Operator::NotEqual => Some(IntPredicate::NE),
+        Operator::Less => Some(IntPredicate::SLT),
+        Operator::LessEqual => Some(IntPredicate::SLE),
+        Operator::Greater => Some(IntPredicate::SGT),
+        Operator::GreaterEqual => Some(IntPredicate::SGE),
+        Operator::Add | Operator::Subtract | Operator::Multiply | Operator::Divide => None,
+    }
+}
+
+/// Float predicate for a comparison operator: ordered, except that `!=` holds
+/// when either operand is NaN
+fn float_predicate(operator: &Operator) -> Option<FloatPredicate> {
+    match operator {
+        Operator::Equal => Some(FloatPredicate::OEQ),
+        Operator::NotEqual => Some(FloatPredicate::UNE),
+        Operator::Less => Some(FloatPredicate::OLT),
+        Operator::LessEqual => Some(FloatPredicate::OLE),
+        Operator::Greater => Some(FloatPredicate::OGT),
+        Operator::GreaterEqual => Some(FloatPredicate::OGE),
+        Operator::Add | Operator::Subtract | Operator::Multiply | Operator::Divide => None,
+    }
 }
 
 #[cfg(test)]
@@ -207,16 +2211,23 @@ mod tests {
 
     fn create_test_compiler<'ctx>(
         context: &'ctx Context,
-        builder: &'ctx Builder<'ctx>,
+        builder: &Rc<Builder<'ctx>>,
     ) -> ExpressionCompiler<'ctx> {
-        ExpressionCompiler::new(context, builder)
+        ExpressionCompiler::new(context, Rc::clone(builder))
     }
 
     #[test]
     fn test_literal_compilation() {
         let context = Context::create();
-        let builder = context.create_builder();
-        let compiler = create_test_compiler(&context, &builder);
+        let builder = Rc::new(context.create_builder());
+        let module = context.create_module("test");
+        let function = module.add_function("test", context.void_type().fn_type(&[], false), None);
+        builder.position_at_end(context.append_basic_block(function, "entry"));
+        let mut compiler = create_test_compiler(&context, &builder);
+        compiler.register_runtime_function(
+            RuntimeFunction::Alloc,
+            RuntimeFunction::Alloc.declare(&context, &module),
+        );
 
         let int_literal = LiteralValue::Int(42);
         let float_literal = LiteralValue::Float(3.14);
@@ -232,7 +2243,7 @@ mod tests {
     #[test]
     fn test_binary_operation() {
         let context = Context::create();
-        let builder = context.create_builder();
+        let builder = Rc::new(context.create_builder());
         let module = context.create_module("test");
 
         // 関数を作成してその中でテストを実行
@@ -251,10 +2262,486 @@ mod tests {
         assert!(result.is_ok());
     }
 
+    #[test]
+    fn test_call_with_inout_argument() {
+        let context = Context::create();
+        let builder = Rc::new(context.create_builder());
+        let module = context.create_module("test");
+        let ptr_type = context.ptr_type(inkwell::AddressSpace::default());
+
+        let increment = module.add_function(
+            "increment",
+            context.void_type().fn_type(&[ptr_type.into()], false),
+            None,
+        );
+        let function = module.add_function("test", context.void_type().fn_type(&[], false), None);
+        builder.position_at_end(context.append_basic_block(function, "entry"));
+
+        let mut compiler = create_test_compiler(&context, &builder);
+        let counter = builder.build_alloca(context.i32_type(), "counter").unwrap();
+        compiler.register_address("counter".to_string(), counter);
+        compiler.register_function("increment".to_string(), increment);
+
+        let args = vec![Expression::Inout("counter".to_string())];
+        assert!(compiler.compile_call("increment", &args).unwrap().is_none());
+
+        let missing = vec![Expression::Inout("missing".to_string())];
+        assert!(compiler.compile_call("increment", &missing).is_err());
+    }
+
+    #[test]
+    fn test_let_tuple_destructuring() {
+        let context = Context::create();
+        let builder = Rc::new(context.create_builder());
+        let module = context.create_module("test");
+
+        let fn_type = context.i32_type().fn_type(&[], false);
+        let function = module.add_function("test", fn_type, None);
+        builder.position_at_end(context.append_basic_block(function, "entry"));
+
+        let mut compiler = create_test_compiler(&context, &builder);
+        let point = Expression::Tuple(vec![
+            Expression::Literal(LiteralValue::Int(3)),
+            Expression::Literal(LiteralValue::Int(4)),
+        ]);
+        let pattern = Pattern::Tuple(vec![Pattern::Wildcard, Pattern::Binding("y".to_string())]);
+        compiler.compile_let(&pattern, &point).unwrap();
+
+        let y = compiler.compile_variable("y").unwrap();
+        assert_eq!(y.into_int_value().get_zero_extended_constant(), Some(4));
+        assert!(compiler.compile_variable("x").is_err());
+    }
+
+    #[test]
+    fn test_record_fields_in_label_order() {
+        let context = Context::create();
+        let builder = Rc::new(context.create_builder());
+        let module = context.create_module("test");
+
+        let fn_type = context.i32_type().fn_type(&[], false);
+        let function = module.add_function("test", fn_type, None);
+        builder.position_at_end(context.append_basic_block(function, "entry"));
+
+        let mut compiler = create_test_compiler(&context, &builder);
+        let stats = Expression::Record(vec![
+            (
+                "ok".to_string(),
+                Expression::Literal(LiteralValue::Bool(true)),
+            ),
+            (
+                "count".to_string(),
+                Expression::Literal(LiteralValue::Int(3)),
+            ),
+        ]);
+        // 書いた順によらずラベル順に並ぶ
+        let value = compiler.compile_expression(&stats).unwrap();
+        let layout = value.into_struct_value().get_type();
+        assert_eq!(
+            layout.get_field_type_at_index(0),
+            Some(context.i32_type().into())
+        );
+
+        compiler
+            .compile_let(&Pattern::Binding("s".to_string()), &stats)
+            .unwrap();
+        let member = |name: &str| Expression::Member {
+            base: Box::new(Expression::Variable("s".to_string())),
+            name: name.to_string(),
+        };
+        let count = compiler.compile_expression(&member("count")).unwrap();
+        assert_eq!(count.into_int_value().get_zero_extended_constant(), Some(3));
+        let ok = compiler.compile_expression(&member("ok")).unwrap();
+        assert_eq!(ok.into_int_value().get_zero_extended_constant(), Some(1));
+        assert!(compiler.compile_expression(&member("total")).is_err());
+    }
+
+    #[test]
+    fn test_fixed_array_indexing() {
+        let context = Context::create();
+        let builder = Rc::new(context.create_builder());
+        let module = context.create_module("test");
+
+        let fn_type = context
+            .i32_type()
+            .fn_type(&[context.i32_type().into()], false);
+        let function = module.add_function("test", fn_type, None);
+        builder.position_at_end(context.append_basic_block(function, "entry"));
+
+        let mut compiler = create_test_compiler(&context, &builder);
+        let buffer = Expression::ArrayLiteral(
+            (1..=4)
+                .map(|value| Expression::Literal(LiteralValue::Int(value)))
+                .collect(),
+        );
+        let buffer = compiler.compile_expression(&buffer).unwrap();
+        assert_eq!(buffer.into_array_value().get_type().len(), 4);
+        compiler.register_variable("buffer".to_string(), buffer);
+        compiler.register_variable(
+            "i".to_string(),
+            function.get_nth_param(0).unwrap().as_basic_value_enum(),
+        );
+
+        let element = |index: Expression| Expression::Index {
+            base: Box::new(Expression::Variable("buffer".to_string())),
+            index: Box::new(index),
+        };
+        let third = compiler
+            .compile_expression(&element(Expression::Literal(LiteralValue::Int(2))))
+            .unwrap();
+        assert_eq!(third.into_int_value().get_zero_extended_constant(), Some(3));
+
+        // 動的な添字はランタイムで境界チェックされる
+        let dynamic = element(Expression::Variable("i".to_string()));
+        assert!(compiler.compile_expression(&dynamic).is_err());
+        compiler.register_runtime_function(
+            RuntimeFunction::ArrayElement,
+            RuntimeFunction::ArrayElement.declare(&context, &module),
+        );
+        let value = compiler.compile_expression(&dynamic).unwrap();
+        builder.build_return(Some(&value)).unwrap();
+
+        let ir = module.print_to_string().to_string();
+        assert!(ir.contains("call ptr @__replica_array_element"));
+        assert!(module.verify().is_ok());
+    }
+
+    #[test]
+    fn test_int_string_conversions() {
+        let context = Context::create();
+        let builder = Rc::new(context.create_builder());
+        let module = context.create_module("test");
+
+        let ptr_type = context.ptr_type(AddressSpace::default());
+        let fn_type = context
+            .i32_type()
+            .fn_type(&[context.i32_type().into(), ptr_type.into()], false);
+        let function = module.add_function("test", fn_type, None);
+        builder.position_at_end(context.append_basic_block(function, "entry"));
+
+        let mut compiler = create_test_compiler(&context, &builder);
+        for function in [
+            RuntimeFunction::Alloc,
+            RuntimeFunction::IntToString,
+            RuntimeFunction::StringToInt,
+        ] {
+            compiler.register_runtime_function(function, function.declare(&context, &module));
+        }
+        for (index, name) in ["id", "payload"].into_iter().enumerate() {
+            compiler.register_variable(
+                name.to_string(),
+                function.get_nth_param(index as u32).unwrap(),
+            );
+        }
+        let convert = |conversion, value| Expression::Conversion {
+            conversion,
+            value: Box::new(value),
+        };
+        let variable = |name: &str| Expression::Variable(name.to_string());
+
+        let text = compiler
+            .compile_expression(&convert(Conversion::IntToString, variable("id")))
+            .unwrap();
+        assert!(text.is_pointer_value());
+        let parsed = compiler
+            .compile_expression(&convert(Conversion::StringToInt, variable("payload")))
+            .unwrap();
+        let value = builder
+            .build_extract_value(parsed.into_struct_value(), 0, "value")
+            .unwrap();
+        builder.build_return(Some(&value)).unwrap();
+
+        // リテラルはコンパイル時に変換する
+        let literal = convert(
+            Conversion::StringToInt,
+            Expression::Literal(LiteralValue::String("-12".to_string())),
+        );
+        let folded = compiler
+            .compile_expression(&literal)
+            .unwrap()
+            .into_struct_value();
+        let field = |index| {
+            folded
+                .get_field_at_index(index)
+                .unwrap()
+                .into_int_value()
+                .get_sign_extended_constant()
+        };
+        assert_eq!((field(0), field(1)), (Some(-12), Some(-1)));
+
+        let ir = module.print_to_string().to_string();
+        assert!(ir.contains("call ptr @__replica_alloc(i32 8)"));
+        assert!(ir.contains("call void @__replica_int_to_string(i32 %0, ptr"));
+        assert!(ir.contains("call i32 @__replica_string_to_int(ptr %1, ptr %parsed)"));
+        assert!(module.verify().is_ok());
+    }
+
+    #[test]
+    fn test_string_operations() {
+        let context = Context::create();
+        let builder = Rc::new(context.create_builder());
+        let module = context.create_module("test");
+        let ptr_type = context.ptr_type(AddressSpace::default());
+        let fn_type = context
+            .i32_type()
+            .fn_type(&[ptr_type.into(), ptr_type.into()], false);
+        let function = module.add_function("test", fn_type, None);
+        builder.position_at_end(context.append_basic_block(function, "entry"));
+
+        let mut compiler = create_test_compiler(&context, &builder);
+        for function in [
+            RuntimeFunction::Alloc,
+            RuntimeFunction::StringConcat,
+            RuntimeFunction::StringEq,
+        ] {
+            compiler.register_runtime_function(function, function.declare(&context, &module));
+        }
+        for (index, name) in ["first", "last"].into_iter().enumerate() {
+            compiler.register_variable(
+                name.to_string(),
+                function.get_nth_param(index as u32).unwrap(),
+            );
+        }
+        let variable = |name: &str| Box::new(Expression::Variable(name.to_string()));
+        let binary = |left, operator, right| Expression::BinaryOp {
+            left,
+            operator,
+            right,
+        };
+
+        let name = binary(
+            Box::new(binary(
+                variable("first"),
+                Operator::Add,
+                Box::new(Expression::Literal(LiteralValue::String(" ".to_string()))),
+            )),
+            Operator::Add,
+            variable("last"),
+        );
+        assert!(compiler
+            .compile_expression(&name)
+            .unwrap()
+            .is_pointer_value());
+        let same = compiler
+            .compile_expression(&binary(
+                variable("first"),
+                Operator::NotEqual,
+                variable("last"),
+            ))
+            .unwrap();
+        assert_eq!(same.into_int_value().get_type().get_bit_width(), 1);
+        let length = compiler
+            .compile_expression(&Expression::Member {
+                base: variable("first"),
+                name: "length".to_string(),
+            })
+            .unwrap();
+        assert!(compiler
+            .compile_expression(&binary(variable("first"), Operator::Less, variable("last")))
+            .is_err());
+        builder.build_return(Some(&length)).unwrap();
+
+        let ir = module.print_to_string().to_string();
+        // リテラルは定数のバイト列を指す記述子になる
+        assert!(ir.contains("c\" \\00\""));
+        assert!(ir.contains("store i32 1, ptr %str.len"));
+        assert_eq!(ir.matches("call void @__replica_string_concat(").count(), 2);
+        assert!(ir.contains("call i32 @__replica_string_eq(ptr %bytes"));
+        assert!(ir.contains("ret i32 %length"));
+        assert!(module.verify().is_ok());
+    }
+
+    #[test]
+    fn test_string_interpolation() {
+        let context = Context::create();
+        let builder = Rc::new(context.create_builder());
+        let module = context.create_module("test");
+        let fn_type = context.ptr_type(AddressSpace::default()).fn_type(
+            &[context.i32_type().into(), context.f64_type().into()],
+            false,
+        );
+        let function = module.add_function("test", fn_type, None);
+        builder.position_at_end(context.append_basic_block(function, "entry"));
+
+        let mut compiler = create_test_compiler(&context, &builder);
+        for function in [
+            RuntimeFunction::Alloc,
+            RuntimeFunction::StringConcat,
+            RuntimeFunction::FloatToString,
+            RuntimeFunction::IntToString,
+            RuntimeFunction::BoolToString,
+        ] {
+            compiler.register_runtime_function(function, function.declare(&context, &module));
+        }
+        for (index, name) in ["count", "ratio"].into_iter().enumerate() {
+            compiler.register_variable(
+                name.to_string(),
+                function.get_nth_param(index as u32).unwrap(),
+            );
+        }
+        let variable = |name: &str| Box::new(Expression::Variable(name.to_string()));
+        let interpolate = |value| {
+            Box::new(Expression::Conversion {
+                conversion: Conversion::Interpolate,
+                value,
+            })
+        };
+        let add = |left, right| {
+            Box::new(Expression::BinaryOp {
+                left,
+                operator: Operator::Add,
+                right,
+            })
+        };
+
+        // "\(count)\(ratio)\(count < 1)\("!")"
+        let empty = Box::new(Expression::BinaryOp {
+            left: variable("count"),
+            operator: Operator::Less,
+            right: Box::new(Expression::Literal(LiteralValue::Int(1))),
+        });
+        let text = add(
+            add(
+                add(
+                    interpolate(variable("count")),
+                    interpolate(variable("ratio")),
+                ),
+                interpolate(empty),
+            ),
+            interpolate(Box::new(Expression::Literal(LiteralValue::String(
+                "!".to_string(),
+            )))),
+        );
+        let text = compiler.compile_expression(&text).unwrap();
+        builder.build_return(Some(&text)).unwrap();
+
+        let ir = module.print_to_string().to_string();
+        assert!(ir.contains("call void @__replica_int_to_string(i32 %0"));
+        assert!(ir.contains("call void @__replica_float_to_string(double %1"));
+        // Bool は i32 に広げてから渡す
+        assert!(ir.contains("%flag = zext i1"));
+        assert!(ir.contains("call void @__replica_bool_to_string(i32 %flag"));
+        assert_eq!(ir.matches("call void @__replica_string_concat(").count(), 3);
+        assert!(module.verify().is_ok());
+    }
+
+    #[test]
+    fn test_atomic_builtins() {
+        let context = Context::create();
+        let builder = Rc::new(context.create_builder());
+        let module = context.create_module("test");
+        let int_type = context.i32_type();
+        let ptr_type = context.ptr_type(inkwell::AddressSpace::default());
+
+        let function =
+            module.add_function("test", int_type.fn_type(&[ptr_type.into()], false), None);
+        builder.position_at_end(context.append_basic_block(function, "entry"));
+        let mut compiler = create_test_compiler(&context, &builder);
+        let ints = |values: &[i32]| -> Vec<Expression> {
+            values
+                .iter()
+                .map(|v| Expression::Literal(LiteralValue::Int(*v)))
+                .collect()
+        };
+
+        // フィールドの格納先がなければコンパイルできない
+        assert!(compiler
+            .compile_atomic(
+                AtomicOperation::Load,
+                "hits",
+                &[],
+                MemoryOrdering::default()
+            )
+            .is_err());
+        compiler.register_address(
+            "hits".to_string(),
+            function.get_first_param().unwrap().into_pointer_value(),
+        );
+
+        let previous = compiler
+            .compile_atomic(
+                AtomicOperation::Add,
+                "hits",
+                &ints(&[1]),
+                MemoryOrdering::Relaxed,
+            )
+            .unwrap();
+        assert!(previous.is_some());
+        let stored = compiler
+            .compile_atomic(
+                AtomicOperation::Store,
+                "hits",
+                &ints(&[0]),
+                MemoryOrdering::Release,
+            )
+            .unwrap();
+        assert!(stored.is_none());
+        let exchanged = compiler
+            .compile_atomic(
+                AtomicOperation::CompareExchange,
+                "hits",
+                &ints(&[0, 1]),
+                MemoryOrdering::AcquireRelease,
+            )
+            .unwrap()
+            .unwrap();
+        assert_eq!(exchanged.into_struct_value().get_type().count_fields(), 2);
+        let loaded = compiler
+            .compile_atomic(AtomicOperation::Load, "hits", &[], MemoryOrdering::Acquire)
+            .unwrap()
+            .unwrap();
+        builder.build_return(Some(&loaded)).unwrap();
+
+        let ir = module.print_to_string().to_string();
+        assert!(ir.contains("atomicrmw add ptr %0, i32 1 monotonic"));
+        assert!(ir.contains("store atomic i32 0, ptr %0 release, align 4"));
+        assert!(ir.contains("cmpxchg ptr %0, i32 0, i32 1 acq_rel acquire"));
+        assert!(ir.contains("load atomic i32, ptr %0 acquire, align 4"));
+        assert!(module.verify().is_ok());
+    }
+
+    #[test]
+    fn test_method_reference_as_callback() {
+        let context = Context::create();
+        let builder = Rc::new(context.create_builder());
+        let module = context.create_module("test");
+        let void_type = context.void_type();
+
+        let tick = module.add_function("tick", void_type.fn_type(&[], false), None);
+        let funcref = context.ptr_type(inkwell::AddressSpace::from(20u16));
+        let on_ready = module.add_function(
+            "on_ready",
+            void_type.fn_type(&[funcref.into()], false),
+            None,
+        );
+        let function = module.add_function("test", void_type.fn_type(&[], false), None);
+        builder.position_at_end(context.append_basic_block(function, "entry"));
+
+        let mut compiler = create_test_compiler(&context, &builder);
+        compiler.register_function("tick".to_string(), tick);
+        compiler.register_function("on_ready".to_string(), on_ready);
+
+        let callback = compiler.compile_variable("tick").unwrap();
+        assert!(callback.is_pointer_value());
+
+        // funcref への変換にはヘルパーが必要
+        let args = vec![Expression::Variable("tick".to_string())];
+        assert!(compiler.compile_call("on_ready", &args).is_err());
+
+        let helper = module.add_function(
+            "replica.funcref",
+            funcref.fn_type(&[context.i32_type().into()], false),
+            None,
+        );
+        compiler.register_funcref_helper(helper);
+        assert!(compiler.compile_call("on_ready", &args).unwrap().is_none());
+        let ir = module.print_to_string().to_string();
+        assert!(ir.contains("ptrtoint ptr @tick to i32"));
+    }
+
     #[test]
     fn test_variable_compilation() {
         let context = Context::create();
-        let builder = context.create_builder();
+        let builder = Rc::new(context.create_builder());
         let mut compiler = create_test_compiler(&context, &builder);
 
         // 変数を登録
@@ -1,31 +1,618 @@
 use inkwell::{
+    attributes::AttributeLoc,
+    basic_block::BasicBlock,
     builder::Builder,
     context::Context,
-    module::Module,
-    targets::{CodeModel, FileType, InitializationConfig, RelocMode, Target, TargetTriple},
-    types::BasicType,
-    values::FunctionValue,
+    module::{Linkage, Module},
+    passes::PassBuilderOptions,
+    targets::{
+        CodeModel, FileType, InitializationConfig, RelocMode, Target, TargetMachine, TargetTriple,
+    },
+    types::{BasicMetadataTypeEnum, BasicType, BasicTypeEnum, StructType},
+    values::{
+        ArrayValue, BasicMetadataValueEnum, BasicValue, BasicValueEnum, FunctionValue,
+        InstructionOpcode, PointerValue,
+    },
+    AddressSpace, AtomicOrdering, AtomicRMWBinOp, GlobalVisibility, IntPredicate,
     OptimizationLevel,
 };
 
 use super::{
+    archive,
     error::{CodeGenError, CodeGenResult},
-    expression::ExpressionCompiler,
-    type_converter::TypeConverter,
+    expression::{ConfigSource, ConstantSource, ExpressionCompiler, Slot},
+    linker::LinkOptions,
+    runtime::{self, RuntimeFunction},
+    type_converter::{TypeConverter, TypeLayout},
+    Allocator, ConfigValue, ExportNames, OomBehavior, TopicRoute, WasmFeature,
 };
-use crate::ast::{Actor, ActorType, Method, MethodBody, Statement};
-use std::collections::HashMap;
+use crate::ast::{
+    Actor, ConstantTable, Enum, Expression, Extern, ExternFunction, LiteralValue, LogLevel, Method,
+    MethodBody, Operator, OwnershipType, Parameter, Pattern, Pool, PoolStrategy, Statement, Struct,
+    Topic, Type,
+};
+use crate::bindgen::http;
+use crate::capability;
+use crate::ice;
+use crate::proto::{self, Direction, WireField, WireKind};
+use crate::reload;
+use crate::semantic::ModuleSymbols;
+use crate::sharding;
+use crate::stack;
+use crate::versioning;
+use std::collections::{BTreeMap, HashMap};
+use std::rc::Rc;
 
 /// Main code generator for compiling Replica actors to WASM
 pub struct CodeGenerator<'ctx> {
     context: &'ctx Context,
     module: Module<'ctx>,
-    builder: Builder<'ctx>,
+    builder: Rc<Builder<'ctx>>,
     type_converter: TypeConverter<'ctx>,
     expression_compiler: ExpressionCompiler<'ctx>,
     actor_methods: HashMap<String, FunctionValue<'ctx>>,
+    /// `<Actor>.new` of every compiled actor, exported so hosts can create instances
+    instance_constructors: Vec<String>,
+    /// `<method>.post` of every async method, pool routers, `<method>.remote`
+    /// and `<method>.deliver`, exported so hosts can queue and relay calls
+    async_entry_points: Vec<String>,
+    /// `<method>.next` and `<method>.close` of every stream method, exported so
+    /// hosts can pull items
+    stream_entry_points: Vec<String>,
+    /// Name each exported symbol has in the module's export section
+    export_names: HashMap<String, String>,
+    export_naming: ExportNames,
+    /// Async methods of the actor being compiled, indexed by message tag
+    message_handlers: Vec<MessageHandler<'ctx>>,
+    /// Handlers of awaited calls and resumptions of the actor being compiled,
+    /// tagged after its async methods
+    continuations: Vec<MessageHandler<'ctx>>,
+    /// Message tag and payload struct of the awaited variant of each async
+    /// method, by name
+    awaited_tags: HashMap<String, (u32, StructType<'ctx>)>,
+    /// Whether each async method of the actor being compiled runs as a task
+    /// (see `compile_task_method`) and whether it is `@nonreentrant`, by name
+    async_methods: HashMap<String, (bool, bool)>,
     optimization_level: OptimizationLevel,
     debug_mode: bool,
+    target_triple: TargetTriple,
+    cpu: String,
+    target_features: String,
+    lto: super::LtoMode,
+    threads: bool,
+    /// Struct element index of the lock word guarding each actor's `Shared` fields
+    state_locks: HashMap<String, u32>,
+    /// Struct element index of the busy word of each actor with `@nonreentrant`
+    /// methods; the queue of deferred messages follows it
+    reentrancy_guards: HashMap<String, u32>,
+    topic_route: TopicRoute,
+    /// Struct element index of the inbox of each actor that subscribes to
+    /// topics through the runtime's routing table
+    inboxes: HashMap<String, u32>,
+    /// Types whose layout `--emit layout` reports, in declaration order
+    layouts: Vec<LayoutSource<'ctx>>,
+    /// Message tag of each async method of the actor being compiled, by name
+    /// without its package namespace
+    message_tags: HashMap<String, u32>,
+    stack_guard: bool,
+    /// Stack bytes each guarded (recursive) method must find free on entry
+    stack_guards: HashMap<String, u32>,
+    http_facade: bool,
+    loop_targets: Vec<LoopTarget<'ctx>>,
+    /// Suspension points of the stream method being compiled
+    stream: Option<StreamState<'ctx>>,
+    /// Suspension points of the task being compiled
+    task: Option<TaskState<'ctx>>,
+    log_level: LogLevel,
+    /// Name of the actor being compiled, passed to the host with every log call
+    current_actor: String,
+    /// How `emit_wasm` turns the object file into a module
+    link_options: LinkOptions,
+    /// Priority and name of every module constructor `__replica_init` runs
+    constructors: Vec<(u32, String)>,
+    /// Levels of imports below the actor being compiled
+    init_depth: u32,
+}
+
+/// A type whose layout `--emit layout` reports
+#[derive(Clone)]
+struct LayoutSource<'ctx> {
+    /// `actor`, `struct` or `enum`
+    kind: &'static str,
+    name: String,
+    struct_type: StructType<'ctx>,
+    /// What each element of the LLVM body holds; padding inserted for layout
+    /// attributes has no label
+    labels: Vec<Option<String>>,
+}
+
+/// Busy word and shared blocks of the reentrancy guard in an actor's dispatch function
+struct ReentrancyGuard<'ctx> {
+    busy: PointerValue<'ctx>,
+    /// Moves the message being dispatched to the deferred queue
+    defer: BasicBlock<'ctx>,
+    /// Dispatches deferred messages while the instance is idle
+    replay: BasicBlock<'ctx>,
+}
+
+impl<'ctx> ReentrancyGuard<'ctx> {
+    /// Sets the busy word if it is clear, returning whether it was
+    fn acquire(
+        &self,
+        codegen: &CodeGenerator<'ctx>,
+    ) -> CodeGenResult<inkwell::values::IntValue<'ctx>> {
+        let i32_type = codegen.context.i32_type();
+        let result = codegen
+            .builder
+            .build_cmpxchg(
+                self.busy,
+                i32_type.const_zero(),
+                i32_type.const_int(1, false),
+                AtomicOrdering::AcquireRelease,
+                AtomicOrdering::Acquire,
+            )
+            .map_err(|e| CodeGenError::LLVMError(e.to_string()))?;
+        codegen
+            .builder
+            .build_extract_value(result, 1, "acquired")
+            .map(|acquired| acquired.into_int_value())
+            .map_err(|e| CodeGenError::LLVMError(e.to_string()))
+    }
+
+    /// Clears the busy word
+    fn release(&self, codegen: &CodeGenerator<'ctx>) -> CodeGenResult<()> {
+        codegen
+            .builder
+            .build_atomicrmw(
+                AtomicRMWBinOp::Xchg,
+                self.busy,
+                codegen.context.i32_type().const_zero(),
+                AtomicOrdering::Release,
+            )
+            .map(|_| ())
+            .map_err(|e| CodeGenError::LLVMError(e.to_string()))
+    }
+
+    /// Whether no `@nonreentrant` method of the instance is running
+    fn is_idle(
+        &self,
+        codegen: &CodeGenerator<'ctx>,
+    ) -> CodeGenResult<inkwell::values::IntValue<'ctx>> {
+        let llvm_error = |e: inkwell::builder::BuilderError| CodeGenError::LLVMError(e.to_string());
+        let i32_type = codegen.context.i32_type();
+        let busy = codegen
+            .builder
+            .build_load(i32_type, self.busy, "is_busy")
+            .map_err(llvm_error)?;
+        if let Some(instruction) = busy.as_instruction_value() {
+            instruction
+                .set_atomic_ordering(AtomicOrdering::Acquire)
+                .and_then(|_| instruction.set_alignment(4))
+                .map_err(|e| CodeGenError::LLVMError(e.to_string()))?;
+        }
+        codegen
+            .builder
+            .build_int_compare(
+                IntPredicate::EQ,
+                busy.into_int_value(),
+                i32_type.const_zero(),
+                "idle",
+            )
+            .map_err(llvm_error)
+    }
+}
+
+/// Jump targets of an enclosing loop
+struct LoopTarget<'ctx> {
+    label: Option<String>,
+    break_block: BasicBlock<'ctx>,
+    continue_block: BasicBlock<'ctx>,
+}
+
+/// `<method>.next` of the stream method being compiled
+struct StreamState<'ctx> {
+    /// Where `yield` stores the item for the host
+    out: PointerValue<'ctx>,
+    /// Blocks that save the frame and return after each `yield`, filled in once
+    /// the whole body is compiled
+    suspends: Vec<BasicBlock<'ctx>>,
+    /// Block each `yield` resumes at, in the same order
+    resumes: Vec<BasicBlock<'ctx>>,
+}
+
+/// What an actor's dispatch function does with the messages of one tag
+#[derive(Clone, Copy)]
+enum MessageHandler<'ctx> {
+    /// Unpacks the arguments of an async method from the payload and calls
+    /// `function`: the method itself, or its `<method>.start` when it runs as a
+    /// `task`. With `reply`, the payload ends with the frame, resume tag and
+    /// result address of the task awaiting the call, which is resumed once the
+    /// method has returned.
+    Call {
+        function: FunctionValue<'ctx>,
+        payload_type: StructType<'ctx>,
+        nonreentrant: bool,
+        task: bool,
+        reply: bool,
+    },
+    /// Continues a task suspended at an `await`; the payload is its frame
+    Resume(FunctionValue<'ctx>),
+}
+
+/// `<method>.resume` of the task being compiled
+struct TaskState<'ctx> {
+    /// Slot `return` stores the result in, for methods returning a value
+    result: Option<(PointerValue<'ctx>, BasicTypeEnum<'ctx>)>,
+    /// Block that hands the result to the awaiting task and frees the frame
+    complete: BasicBlock<'ctx>,
+    /// Every `await` the task suspends at, filled in once the whole body is
+    /// compiled
+    awaits: Vec<AwaitPoint<'ctx>>,
+}
+
+/// An `await` a task suspends at
+struct AwaitPoint<'ctx> {
+    /// Block that saves the frame, queues the call and returns
+    suspend: BasicBlock<'ctx>,
+    /// Block the task resumes at once the call has returned
+    resume: BasicBlock<'ctx>,
+    /// The awaited method, called in place when its message cannot be queued
+    callee: FunctionValue<'ctx>,
+    args: Vec<BasicValueEnum<'ctx>>,
+    /// Message tag and payload of the awaited variant of the method
+    tag: u32,
+    payload_type: StructType<'ctx>,
+    /// Slot receiving the result, for methods returning a value
+    result: Option<PointerValue<'ctx>>,
+}
+
+/// Struct element index of the state of a stream's frame, which follows the
+/// pointer to the instance: 0 before the first item, `k` after the `k`-th
+/// `yield` and -1 once the body has finished
+const STREAM_STATE_INDEX: u32 = 1;
+
+/// Struct element index of the first saved slot of a stream's frame
+const STREAM_FRAME_HEADER: u32 = 2;
+
+/// Struct element index of the state of a task's frame, which follows the
+/// pointer to the instance: 0 before the body starts and `k` after the `k`-th
+/// `await`
+const TASK_STATE_INDEX: u32 = 1;
+
+/// Struct element index of the mailbox a task queues the calls it awaits on
+const TASK_MAILBOX_INDEX: u32 = 2;
+
+/// Struct element index of the frame, resume tag and result address of the
+/// task awaiting a task, null when nothing awaits it
+const TASK_REPLY_INDEX: u32 = 3;
+
+/// Struct element index of the first saved slot of a task's frame
+const TASK_FRAME_HEADER: u32 = 6;
+
+/// Stack kept free below a guarded frame for the runtime routines it calls
+const STACK_GUARD_RESERVE: u32 = 1024;
+
+/// Messages an instance of a `@nonreentrant` actor can hold back at once
+const DEFERRED_CAPACITY: u64 = 64;
+
+/// Topic values an instance can have queued before further ones are dropped
+const INBOX_CAPACITY: u64 = 64;
+
+/// Calls each instance of a pool can have queued before its router rejects more
+const POOL_MAILBOX_CAPACITY: u64 = 64;
+
+/// Priority of the module constructor setting the memory policy, which runs
+/// before anything allocates. Lower priorities run first; LLVM reserves 0-100.
+const MEMORY_POLICY_PRIORITY: u32 = 101;
+
+/// Priority of the initializer of an actor that imports no module. An
+/// importing actor's runs after those of the modules it imports, one step per
+/// level of imports below it.
+const ACTOR_INIT_PRIORITY: u32 = 1000;
+
+/// Target triples the WASM backend is known to support
+const SUPPORTED_TRIPLES: &[&str] = &[
+    "wasm32-unknown-unknown",
+    "wasm32-wasi",
+    "wasm32-wasip1",
+    "wasm64-unknown-unknown",
+];
+
+/// Method name without its package namespace (`math.length` is `length`)
+fn bare_name(name: &str) -> &str {
+    name.rsplit('.').next().unwrap_or(name)
+}
+
+/// Whether the body contains `subscribe(...)`, including in nested blocks
+fn subscribes(body: &MethodBody) -> bool {
+    body.statements.iter().any(|statement| match statement {
+        Statement::Subscribe(_) => true,
+        Statement::If {
+            then_body,
+            else_body,
+            ..
+        } => subscribes(then_body) || else_body.as_ref().is_some_and(subscribes),
+        Statement::While { body, .. } | Statement::For { body, .. } => subscribes(body),
+        Statement::Match { arms, .. } => arms.iter().any(|(_, body)| subscribes(body)),
+        _ => false,
+    })
+}
+
+/// Stack slots allocated in a function's entry block, which hold every local
+/// of a stream's `next` or a task's `resume`
+fn entry_slots<'ctx>(
+    entry: BasicBlock<'ctx>,
+) -> CodeGenResult<Vec<(PointerValue<'ctx>, BasicTypeEnum<'ctx>)>> {
+    let mut slots = Vec::new();
+    let mut instruction = entry.get_first_instruction();
+    while let Some(current) = instruction {
+        if current.get_opcode() == InstructionOpcode::Alloca {
+            let slot_type = current
+                .get_allocated_type()
+                .map_err(|e| CodeGenError::Internal(e.to_string()))?;
+            let address = PointerValue::try_from(current)
+                .map_err(|_| CodeGenError::Internal("Slot is not a pointer".into()))?;
+            slots.push((address, slot_type));
+        }
+        instruction = current.get_next_instruction();
+    }
+    Ok(slots)
+}
+
+/// Whether the async method is lowered to a task (see `compile_task_method`):
+/// its body awaits, and awaiting in place is not needed to keep other calls of
+/// a `sequential` or `@nonreentrant` method out while it runs
+fn runs_as_task(method: &Method) -> bool {
+    method.is_async
+        && !method.is_sequential
+        && !method.is_nonreentrant
+        && method.stream_item().is_none()
+        && !method
+            .params
+            .iter()
+            .any(|param| matches!(param.ownership, OwnershipType::Inout))
+        && method.body.as_ref().is_some_and(awaits)
+}
+
+/// Whether the body evaluates an `await` outside of `??` defaults and `match`
+/// arms, including in nested blocks
+fn awaits(body: &MethodBody) -> bool {
+    body.statements.iter().any(|statement| {
+        statement_expressions(statement)
+            .into_iter()
+            .any(evaluates_await)
+            || match statement {
+                Statement::If {
+                    then_body,
+                    else_body,
+                    ..
+                } => awaits(then_body) || else_body.as_ref().is_some_and(awaits),
+                Statement::While { body, .. } | Statement::For { body, .. } => awaits(body),
+                Statement::Match { arms, .. } => arms.iter().any(|(_, body)| awaits(body)),
+                _ => false,
+            }
+    })
+}
+
+/// Expressions a statement evaluates itself, not counting nested blocks
+fn statement_expressions(statement: &Statement) -> Vec<&Expression> {
+    match statement {
+        Statement::Return(value)
+        | Statement::Yield(value)
+        | Statement::Expression(value)
+        | Statement::Let { value, .. }
+        | Statement::Assign { value, .. }
+        | Statement::Publish { value, .. }
+        | Statement::Log { message: value, .. }
+        | Statement::If {
+            condition: value, ..
+        }
+        | Statement::While {
+            condition: value, ..
+        }
+        | Statement::Match { value, .. } => vec![value],
+        Statement::For { start, end, .. } => vec![start, end],
+        Statement::Break(_) | Statement::Continue(_) | Statement::Subscribe(_) => Vec::new(),
+    }
+}
+
+/// Mutable counterpart of `statement_expressions`
+fn statement_expressions_mut(statement: &mut Statement) -> Vec<&mut Expression> {
+    match statement {
+        Statement::Return(value)
+        | Statement::Yield(value)
+        | Statement::Expression(value)
+        | Statement::Let { value, .. }
+        | Statement::Assign { value, .. }
+        | Statement::Publish { value, .. }
+        | Statement::Log { message: value, .. }
+        | Statement::If {
+            condition: value, ..
+        }
+        | Statement::While {
+            condition: value, ..
+        }
+        | Statement::Match { value, .. } => vec![value],
+        Statement::For { start, end, .. } => vec![start, end],
+        Statement::Break(_) | Statement::Continue(_) | Statement::Subscribe(_) => Vec::new(),
+    }
+}
+
+/// Whether evaluating the expression always evaluates an `await`; a `??`
+/// default or `match` arm may not run, so awaits there do not count
+fn evaluates_await(expr: &Expression) -> bool {
+    match expr {
+        Expression::Await(_) => true,
+        Expression::BinaryOp { left, right, .. } => evaluates_await(left) || evaluates_await(right),
+        Expression::Index { base, index } => evaluates_await(base) || evaluates_await(index),
+        Expression::Conversion { value, .. }
+        | Expression::Member { base: value, .. }
+        | Expression::Unwrap(value)
+        | Expression::Coalesce { value, .. }
+        | Expression::Match { value, .. } => evaluates_await(value),
+        Expression::Call { args, .. }
+        | Expression::Tuple(args)
+        | Expression::ArrayLiteral(args)
+        | Expression::Atomic { args, .. }
+        | Expression::Random { args, .. }
+        | Expression::EnumCase { args, .. } => args.iter().any(evaluates_await),
+        Expression::Record(fields) | Expression::StructLiteral { fields, .. } => {
+            fields.iter().any(|(_, value)| evaluates_await(value))
+        }
+        _ => false,
+    }
+}
+
+/// Moves the awaited calls `evaluates_await` finds in the expression into
+/// `hoisted` as `Expression::Await`, innermost first, each replaced by a read
+/// of the local `await.<n>` its result is bound to. Operands evaluated before
+/// a sibling that awaits are hoisted ahead of its call, so that they still
+/// see the state from before the task suspends.
+fn hoist_awaits(expr: &mut Expression, hoisted: &mut Vec<(String, Expression)>) {
+    match expr {
+        Expression::Await(value) => {
+            hoist_awaits(value, hoisted);
+            let name = format!("await.{}", hoisted.len());
+            let call = std::mem::replace(expr, Expression::Variable(name.clone()));
+            hoisted.push((name, call));
+        }
+        Expression::BinaryOp { left, right, .. } => {
+            hoist_operands(vec![left.as_mut(), right.as_mut()], hoisted)
+        }
+        Expression::Index { base, index } => {
+            hoist_operands(vec![base.as_mut(), index.as_mut()], hoisted)
+        }
+        Expression::Conversion { value, .. }
+        | Expression::Member { base: value, .. }
+        | Expression::Unwrap(value)
+        | Expression::Coalesce { value, .. }
+        | Expression::Match { value, .. } => hoist_awaits(value, hoisted),
+        Expression::Call { args, .. }
+        | Expression::Tuple(args)
+        | Expression::ArrayLiteral(args)
+        | Expression::Atomic { args, .. }
+        | Expression::Random { args, .. }
+        | Expression::EnumCase { args, .. } => hoist_operands(args.iter_mut().collect(), hoisted),
+        Expression::Record(fields) | Expression::StructLiteral { fields, .. } => {
+            hoist_operands(fields.iter_mut().map(|(_, value)| value).collect(), hoisted)
+        }
+        _ => {}
+    }
+}
+
+/// Hoists the awaits of operands evaluated in the given order. An operand
+/// followed by one that awaits is itself hoisted as the local `operand.<n>`
+/// once its own awaits are.
+fn hoist_operands(mut operands: Vec<&mut Expression>, hoisted: &mut Vec<(String, Expression)>) {
+    for i in 0..operands.len() {
+        hoist_awaits(operands[i], hoisted);
+        // リテラルと待ち受けの結果は中断をまたいでも変わらない
+        let constant = match &*operands[i] {
+            Expression::Literal(_) | Expression::Nil => true,
+            Expression::Variable(name) => name.starts_with("await."),
+            _ => false,
+        };
+        if constant
+            || !operands[i + 1..]
+                .iter()
+                .any(|operand| evaluates_await(operand))
+        {
+            continue;
+        }
+        let name = format!("operand.{}", hoisted.len());
+        let value = std::mem::replace(operands[i], Expression::Variable(name.clone()));
+        hoisted.push((name, value));
+    }
+}
+
+/// A declaration a named type in an enum payload refers to
+enum PayloadDeclaration<'a> {
+    Struct(&'a Struct),
+    Enum(&'a Enum),
+}
+
+/// 64-bit words that are always enough to hold a value of `ty`: every scalar
+/// fits in one word and no element is aligned to more than a word, so a
+/// summed word per scalar bounds the size of any aggregate
+fn payload_words(
+    ty: &Type,
+    declarations: &HashMap<&str, PayloadDeclaration>,
+    converter: &TypeConverter,
+) -> u32 {
+    match ty {
+        Type::Tuple(elements) => elements
+            .iter()
+            .map(|element| payload_words(element, declarations, converter))
+            .sum(),
+        Type::Record(fields) => fields
+            .iter()
+            .map(|(_, field)| payload_words(field, declarations, converter))
+            .sum(),
+        Type::FixedArray(element, length) => {
+            payload_words(element, declarations, converter) * *length as u32
+        }
+        Type::Optional(inner) => payload_words(inner, declarations, converter) + 1,
+        Type::Custom(name) => match declarations.get(name.as_str()) {
+            Some(PayloadDeclaration::Struct(declaration)) => declaration
+                .fields
+                .iter()
+                .map(|field| payload_words(&field.field_type, declarations, converter))
+                .sum(),
+            // タグの 1 語と最大のケースの値
+            Some(PayloadDeclaration::Enum(declaration)) => {
+                1 + declaration
+                    .cases
+                    .iter()
+                    .map(|case| {
+                        case.values
+                            .iter()
+                            .map(|value| payload_words(value, declarations, converter))
+                            .sum::<u32>()
+                    })
+                    .max()
+                    .unwrap_or(0)
+            }
+            // インポート済みの型は LLVM 型から数える
+            None => converter
+                .convert_to_llvm(ty)
+                .map_or(1, |llvm_type| llvm_words(llvm_type)),
+        },
+        _ => 1,
+    }
+}
+
+/// Labels of a record type, in the order its fields are laid out
+fn record_labels(ty: Option<&Type>) -> Option<Vec<String>> {
+    match ty {
+        Some(Type::Record(fields)) => Some(fields.iter().map(|(label, _)| label.clone()).collect()),
+        _ => None,
+    }
+}
+
+/// Key the entries of a constant map are sorted and searched by: a `String`
+/// hashed like `__replica_string_hash` does, an `Int` sign-extended
+fn search_key(key: &LiteralValue) -> u64 {
+    match key {
+        LiteralValue::String(text) => replica_runtime::pool::fnv1a(text.as_bytes()),
+        LiteralValue::Int(value) => i64::from(*value) as u64,
+        LiteralValue::Float(value) => value.to_bits(),
+        LiteralValue::Bool(value) => u64::from(*value),
+    }
+}
+
+/// 64-bit words that are always enough to hold a value of `llvm_type`
+fn llvm_words(llvm_type: BasicTypeEnum) -> u32 {
+    match llvm_type {
+        BasicTypeEnum::StructType(struct_type) => struct_type
+            .get_field_types()
+            .into_iter()
+            .map(llvm_words)
+            .sum(),
+        BasicTypeEnum::ArrayType(array_type) => {
+            array_type.len() * llvm_words(array_type.get_element_type())
+        }
+        _ => 1,
+    }
 }
 
 impl<'ctx> CodeGenerator<'ctx> {
@@ -36,65 +623,896 @@ impl<'ctx> CodeGenerator<'ctx> {
         options: super::CodeGenOptions,
     ) -> CodeGenResult<Self> {
         let module = context.create_module(module_name);
-        let builder = context.create_builder();
+        // 式のコンパイラも同じ挿入位置に命令を置くので、ビルダーを共有する
+        let builder = Rc::new(context.create_builder());
 
         // Initialize WASM target
         Target::initialize_webassembly(&InitializationConfig::default());
 
-        let type_converter = TypeConverter::new(context);
-        let expression_compiler = ExpressionCompiler::new(context, &builder);
+        let target_triple = Self::resolve_target(&options)?;
+        Self::validate_target_features(&options.target_features)?;
+        let target_features = Self::merge_wasm_features(&options)?;
+        let threads = options.has_wasm_feature(WasmFeature::Threads);
+        module.set_triple(&target_triple);
+        runtime::record_abi_version(context, &module);
+        runtime::record_allocator(context, &module, options.allocator);
+        runtime::define_abi_handshake(context, &module, options.allocator)
+            .map_err(|e| CodeGenError::LLVMError(e.to_string()))?;
+        runtime::define_module_init(context, &module)
+            .map_err(|e| CodeGenError::LLVMError(e.to_string()))?;
+        runtime::record_shared_memory(context, &module, threads);
+
+        let mut type_converter = TypeConverter::new(context);
+        type_converter.set_reference_types(Self::has_target_feature(
+            &target_features,
+            "reference-types",
+        ));
+        let mut expression_compiler = ExpressionCompiler::new(context, Rc::clone(&builder));
+        for function in [
+            RuntimeFunction::ArrayElement,
+            RuntimeFunction::ArrayAlloc,
+            RuntimeFunction::ArrayLen,
+            RuntimeFunction::ArrayGet,
+            RuntimeFunction::Alloc,
+            RuntimeFunction::StringConcat,
+            RuntimeFunction::StringEq,
+            RuntimeFunction::FloatToString,
+            RuntimeFunction::IntToString,
+            RuntimeFunction::BoolToString,
+            RuntimeFunction::StringToInt,
+            RuntimeFunction::RandomInt,
+            RuntimeFunction::RandomFloat,
+            RuntimeFunction::UnwrapNil,
+        ] {
+            expression_compiler
+                .register_runtime_function(function, function.declare(context, &module));
+        }
+        expression_compiler.set_bounds_checks(options.bounds_checks);
+        expression_compiler.register_random_source(runtime::declare_random_source(
+            context,
+            &module,
+            options.random,
+        ));
 
-        Ok(CodeGenerator {
+        let link_options = LinkOptions::from(&options);
+        let mut generator = CodeGenerator {
             context,
             module,
             builder,
             type_converter,
             expression_compiler,
             actor_methods: HashMap::new(),
+            instance_constructors: Vec::new(),
+            async_entry_points: Vec::new(),
+            stream_entry_points: Vec::new(),
+            export_names: HashMap::new(),
+            export_naming: options.export_names,
+            message_handlers: Vec::new(),
+            continuations: Vec::new(),
+            awaited_tags: HashMap::new(),
+            async_methods: HashMap::new(),
             optimization_level: options.optimization_level,
             debug_mode: options.debug_mode,
-        })
+            target_triple,
+            cpu: options.cpu,
+            target_features,
+            lto: options.lto,
+            threads,
+            state_locks: HashMap::new(),
+            reentrancy_guards: HashMap::new(),
+            topic_route: options.topics,
+            inboxes: HashMap::new(),
+            layouts: Vec::new(),
+            message_tags: HashMap::new(),
+            stack_guard: options.stack_guard,
+            stack_guards: HashMap::new(),
+            http_facade: options.http_facade,
+            loop_targets: Vec::new(),
+            stream: None,
+            task: None,
+            log_level: options.log_level,
+            current_actor: String::new(),
+            link_options,
+            constructors: Vec::new(),
+            init_depth: 0,
+        };
+        // 型の大きさと配置は選んだターゲットのデータレイアウトで決める
+        let target_data = generator.create_target_machine()?.get_target_data();
+        let data_layout = target_data.get_data_layout();
+        generator.module.set_data_layout(&data_layout);
+        let data_layout = data_layout.as_str().to_string_lossy();
+        generator.type_converter.set_data_layout(&data_layout);
+        generator.expression_compiler.set_data_layout(&data_layout);
+        generator.define_memory_policy(options.max_memory, options.on_oom, options.allocator)?;
+        generator.register_config(&options.config)?;
+        Ok(generator)
+    }
+
+    /// Makes the keys of the manifest's `[config]` section available to
+    /// `config("KEY")`. Fixed values become constant descriptors; runtime keys
+    /// are looked up through the host's `replica_env_get`.
+    fn register_config(&mut self, config: &BTreeMap<String, ConfigValue>) -> CodeGenResult<()> {
+        for (key, value) in config {
+            let name = format!("config.{}", key);
+            let source = match value {
+                ConfigValue::Fixed(text) => {
+                    ConfigSource::Fixed(self.const_string_descriptor(text, &name)?)
+                }
+                ConfigValue::Runtime { default } => ConfigSource::Runtime {
+                    import: runtime::declare_env_import(self.context, &self.module),
+                    key: self.const_string_descriptor(key, &format!("{}.key", name))?,
+                    default: self.const_string_descriptor(default, &format!("{}.default", name))?,
+                },
+            };
+            self.expression_compiler
+                .register_config(key.clone(), source);
+        }
+        Ok(())
+    }
+
+    /// Defines a module constructor passing the memory budget and the allocator
+    /// to the runtime. Nothing is emitted for the default policy (no limit,
+    /// return null, bump allocator).
+    fn define_memory_policy(
+        &mut self,
+        max_memory: Option<u32>,
+        on_oom: OomBehavior,
+        allocator: Allocator,
+    ) -> CodeGenResult<()> {
+        const NAME: &str = "replica.memory_policy";
+        let default_budget = max_memory.is_none() && on_oom == OomBehavior::ReturnNull;
+        if default_budget && allocator == Allocator::Bump {
+            return Ok(());
+        }
+
+        let i32_type = self.context.i32_type();
+        let function = self.module.add_function(
+            NAME,
+            self.context.void_type().fn_type(&[], false),
+            Some(Linkage::Internal),
+        );
+        let basic_block = self.context.append_basic_block(function, "entry");
+        self.builder.position_at_end(basic_block);
+
+        let llvm_error = |e: inkwell::builder::BuilderError| CodeGenError::LLVMError(e.to_string());
+        // アロケータは最初の確保より前に選ぶ
+        if allocator != Allocator::Bump {
+            let null = self.context.ptr_type(AddressSpace::default()).const_null();
+            let (alloc, free) = match allocator {
+                Allocator::External => {
+                    let (alloc, free) =
+                        runtime::declare_host_allocator_imports(self.context, &self.module);
+                    (
+                        alloc.as_global_value().as_pointer_value(),
+                        free.as_global_value().as_pointer_value(),
+                    )
+                }
+                Allocator::Bump | Allocator::FreeList => (null, null),
+            };
+            let kind = i32_type.const_int(allocator.kind() as u64, false);
+            self.builder
+                .build_call(
+                    self.runtime_function(RuntimeFunction::SetAllocator),
+                    &[kind.into(), alloc.into(), free.into()],
+                    "",
+                )
+                .map_err(llvm_error)?;
+        }
+        if !default_budget {
+            let limit = i32_type.const_int(max_memory.unwrap_or(0) as u64, false);
+            let trap = i32_type.const_int((on_oom == OomBehavior::Trap) as u64, false);
+            self.builder
+                .build_call(
+                    self.runtime_function(RuntimeFunction::SetMemoryPolicy),
+                    &[limit.into(), trap.into()],
+                    "",
+                )
+                .map_err(llvm_error)?;
+        }
+        self.builder.build_return(None).map_err(llvm_error)?;
+        self.register_constructor(MEMORY_POLICY_PRIORITY, NAME)
+    }
+
+    /// Adds the function `name` to the constructors `__replica_init` runs, in
+    /// order of `priority`
+    fn register_constructor(&mut self, priority: u32, name: &str) -> CodeGenResult<()> {
+        self.constructors.push((priority, name.to_string()));
+        self.define_global_ctors()
+    }
+
+    /// Replaces `llvm.global_ctors` with an entry for every registered
+    /// constructor, including those of linked modules
+    fn define_global_ctors(&self) -> CodeGenResult<()> {
+        const NAME: &str = "llvm.global_ctors";
+        let i32_type = self.context.i32_type();
+        let ptr_type = self.context.ptr_type(AddressSpace::default());
+        let entry_type = self
+            .context
+            .struct_type(&[i32_type.into(), ptr_type.into(), ptr_type.into()], false);
+        let entries = self
+            .constructors
+            .iter()
+            .map(|(priority, name)| {
+                let function = self.module.get_function(name).ok_or_else(|| {
+                    CodeGenError::Internal(format!("Constructor '{}' is not defined", name))
+                })?;
+                Ok(entry_type.const_named_struct(&[
+                    i32_type.const_int(*priority as u64, false).into(),
+                    function.as_global_value().as_pointer_value().into(),
+                    ptr_type.const_null().into(),
+                ]))
+            })
+            .collect::<CodeGenResult<Vec<_>>>()?;
+
+        if let Some(ctors) = self.module.get_global(NAME) {
+            // SAFETY: llvm.global_ctors は LLVM 自身しか参照しない
+            unsafe { ctors.delete() };
+        }
+        let ctors = self
+            .module
+            .add_global(entry_type.array_type(entries.len() as u32), None, NAME);
+        ctors.set_linkage(Linkage::Appending);
+        ctors.set_initializer(&entry_type.const_array(&entries));
+        Ok(())
+    }
+
+    /// Checks that the requested triple names an initialized LLVM target
+    fn resolve_target(options: &super::CodeGenOptions) -> CodeGenResult<TargetTriple> {
+        let triple = TargetTriple::create(&options.target_triple);
+        let supported = SUPPORTED_TRIPLES.join(", ");
+
+        if !options.target_triple.starts_with("wasm32-")
+            && !options.target_triple.starts_with("wasm64-")
+        {
+            return Err(CodeGenError::Initialization(format!(
+                "Unsupported target triple '{}'",
+                options.target_triple
+            ))
+            .with_suggestion(format!("Supported targets: {}", supported)));
+        }
+
+        Target::from_triple(&triple).map_err(|e| {
+            CodeGenError::Initialization(format!(
+                "Unsupported target triple '{}': {}",
+                options.target_triple, e
+            ))
+            .with_suggestion(format!("Supported targets: {}", supported))
+        })?;
+
+        Ok(triple)
+    }
+
+    /// Checks that the feature string is a comma-separated list of `+feature`/`-feature`
+    fn validate_target_features(features: &str) -> CodeGenResult<()> {
+        for feature in features.split(',').filter(|f| !f.trim().is_empty()) {
+            let feature = feature.trim();
+            if !(feature.starts_with('+') || feature.starts_with('-')) || feature.len() < 2 {
+                return Err(CodeGenError::Initialization(format!(
+                    "Malformed target feature '{}'",
+                    feature
+                ))
+                .with_suggestion(format!(
+                    "Prefix features with '+' to enable or '-' to disable them (e.g. '+{}')",
+                    feature.trim_start_matches(['+', '-'])
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds the LLVM features required by the requested WASM features, rejecting
+    /// feature strings that explicitly disable one of them
+    fn merge_wasm_features(options: &super::CodeGenOptions) -> CodeGenResult<String> {
+        let mut features = options.target_features.clone();
+        for wasm_feature in &options.wasm_features {
+            for required in wasm_feature.target_features() {
+                let name = &required[1..];
+                let disabled = features
+                    .split(',')
+                    .any(|f| f.trim().strip_prefix('-') == Some(name));
+                if disabled {
+                    return Err(CodeGenError::Initialization(format!(
+                        "The {} WASM feature requires '{}', which the target features disable",
+                        wasm_feature, required
+                    ))
+                    .with_suggestion(format!("Remove '-{}' from the target features", name)));
+                }
+                if !Self::has_target_feature(&features, name) {
+                    if !features.trim().is_empty() {
+                        features.push(',');
+                    }
+                    features.push_str(required);
+                }
+            }
+        }
+        Ok(features)
+    }
+
+    /// Whether the feature string enables `feature`
+    fn has_target_feature(features: &str, feature: &str) -> bool {
+        features
+            .split(',')
+            .any(|f| f.trim().strip_prefix('+') == Some(feature))
+    }
+
+    /// Makes the types of an imported module known to the actors compiled
+    /// next: its actor as a reference to an instance, its extern types as
+    /// host handles and its structs and enums as values
+    pub fn import_module(&mut self, symbols: &ModuleSymbols) -> CodeGenResult<()> {
+        self.init_depth = self.init_depth.max(symbols.init_depth + 1);
+        self.type_converter.register_actor_reference(&symbols.actor);
+        for name in &symbols.extern_types {
+            self.type_converter.register_extern_type(name);
+        }
+        self.declare_enums(&symbols.enums, &symbols.structs)?;
+        self.declare_structs(&symbols.structs)
     }
 
     /// Compiles an actor to LLVM IR
     pub fn compile_actor(&mut self, actor: &Actor) -> CodeGenResult<()> {
         self.debug_log(&format!("Compiling actor: {}", actor.name));
+        self.current_actor = actor.name.clone();
+
+        // ホスト提供の型と関数の宣言
+        self.declare_externs(actor)?;
+
+        // フィールドや引数に使う列挙型と構造体型の作成
+        self.declare_enums(&actor.enums, &actor.structs)?;
+        self.declare_structs(&actor.structs)?;
 
         // アクター型の作成
         self.create_actor_type(actor)?;
 
+        // 再帰するメソッドにはスタックガードを挿入する
+        if self.stack_guard {
+            let report = stack::analyze(actor);
+            for method in &actor.methods {
+                if report.is_recursive(&method.name) {
+                    self.stack_guards.insert(
+                        method.name.clone(),
+                        stack::frame_size(method) + STACK_GUARD_RESERVE,
+                    );
+                }
+            }
+        }
+
         // フィールドの処理
         self.process_fields(actor)?;
 
+        // 定数表はデータセグメントに置く
+        self.define_constants(actor)?;
+
+        // 相互再帰や後方のメソッドを呼べるよう、本体より先に全シグネチャを宣言する
+        self.declare_methods(actor)?;
+
+        // キューに積むメッセージのタグは非同期メソッドの宣言順
+        self.message_tags = actor
+            .methods
+            .iter()
+            .filter(|method| method.is_async && method.stream_item().is_none())
+            .enumerate()
+            .map(|(tag, method)| (bare_name(&method.name).to_string(), tag as u32))
+            .collect();
+        self.message_handlers.clear();
+        self.continuations.clear();
+        self.awaited_tags.clear();
+        // await を含む非同期メソッドはタスクとして中断・再開できるようにする
+        self.async_methods = actor
+            .methods
+            .iter()
+            .filter(|method| method.is_async && method.stream_item().is_none())
+            .map(|method| {
+                (
+                    method.name.clone(),
+                    (runs_as_task(method), method.is_nonreentrant),
+                )
+            })
+            .collect();
+
         // メソッドのコンパイル
         for method in &actor.methods {
-            self.compile_method(method, &actor.actor_type)?;
+            let _method = ice::enter_method(&method.name);
+            self.compile_method(method, actor)?;
+        }
+
+        // キューに積まれた非同期呼び出しの振り分け
+        self.define_dispatch()?;
+        self.define_drain()?;
+
+        // 生成・破棄処理の作成
+        let init = self.compile_init(actor)?;
+        let deinit = self.compile_deinit(actor)?;
+        self.create_instance_constructor(actor, init, deinit)?;
+
+        // プールごとにルーターアクターを展開する
+        for pool in &actor.pools {
+            self.compile_pool(actor, pool)?;
+        }
+
+        // 起動時にコンストラクタを名前で登録する
+        self.define_actor_init(actor)?;
+
+        // 他のノードから呼ばれるメソッドには送信用のプロキシと受信口を生成する
+        for method in actor.methods.iter().filter(|method| method.is_remote()) {
+            self.define_remote_proxy(method)?;
+            self.define_delivery(method)?;
+        }
+        self.embed_sharding(actor)?;
+
+        // 版を持つアクターは古いクライアントのタグを今のメソッドへ振り分ける
+        if versioning::is_versioned(actor) {
+            for method in actor.methods.iter().filter(|method| {
+                method.is_async && method.stream_item().is_none() && !method.is_remote()
+            }) {
+                self.define_delivery(method)?;
+            }
+            self.define_versioned_delivery(actor)?;
+            self.embed_versions(actor)?;
+        }
+
+        // ゲートウェイ向けのルーティング表
+        if self.http_facade {
+            self.embed_routes(actor)?;
         }
 
+        // ホストが監査できるよう、インポートに必要な権限を記録する
+        self.embed_capabilities(actor)?;
+
+        // ホットリロードでホストが状態を引き継げるよう、フィールドの配置を記録する
+        self.embed_state(actor)?;
+
         // モジュールの検証
         self.verify_module()?;
 
         Ok(())
     }
 
+    /// Creates the LLVM types of `enum` declarations, skipping those already
+    /// imported. An enum is a tagged union `{ i32 tag, [N x i64] payload }`
+    /// whose payload is large enough for the values of any of its cases.
+    fn declare_enums(&mut self, enums: &[Enum], structs: &[Struct]) -> CodeGenResult<()> {
+        let mut declarations: HashMap<&str, PayloadDeclaration> = HashMap::new();
+        for declaration in structs {
+            declarations.insert(&declaration.name, PayloadDeclaration::Struct(declaration));
+        }
+        for declaration in enums {
+            declarations.insert(&declaration.name, PayloadDeclaration::Enum(declaration));
+        }
+        for declaration in enums {
+            if self.type_converter.is_plain_struct(&declaration.name) {
+                continue;
+            }
+            let words = declaration
+                .cases
+                .iter()
+                .map(|case| {
+                    case.values
+                        .iter()
+                        .map(|ty| payload_words(ty, &declarations, &self.type_converter))
+                        .sum()
+                })
+                .max()
+                .unwrap_or(0);
+            let enum_type = self.context.opaque_struct_type(&declaration.name);
+            enum_type.set_body(
+                &[
+                    self.context.i32_type().into(),
+                    self.context.i64_type().array_type(words).into(),
+                ],
+                false,
+            );
+            self.layouts.push(LayoutSource {
+                kind: "enum",
+                name: declaration.name.clone(),
+                struct_type: enum_type,
+                labels: vec![Some("tag".to_string()), Some("payload".to_string())],
+            });
+            self.type_converter
+                .register_plain_struct(&declaration.name, enum_type);
+            self.expression_compiler.register_enum(
+                declaration.name.clone(),
+                enum_type,
+                declaration.cases.clone(),
+            );
+        }
+        Ok(())
+    }
+
+    /// Creates the LLVM types of `struct` declarations, skipping those already
+    /// imported from another module. All are named before any body is set, so
+    /// fields can refer to structs declared later.
+    fn declare_structs(&mut self, structs: &[Struct]) -> CodeGenResult<()> {
+        let declared: Vec<&Struct> = structs
+            .iter()
+            .filter(|declaration| !self.type_converter.is_plain_struct(&declaration.name))
+            .collect();
+        for declaration in &declared {
+            let struct_type = self.context.opaque_struct_type(&declaration.name);
+            self.type_converter
+                .register_plain_struct(&declaration.name, struct_type);
+        }
+        for declaration in declared {
+            let struct_type = self
+                .type_converter
+                .convert_to_llvm(&Type::Custom(declaration.name.clone()))?
+                .into_struct_type();
+            let field_types = declaration
+                .fields
+                .iter()
+                .map(|field| self.type_converter.convert_to_llvm(&field.field_type))
+                .collect::<CodeGenResult<Vec<_>>>()?;
+            struct_type.set_body(&field_types, false);
+            self.layouts.push(LayoutSource {
+                kind: "struct",
+                name: declaration.name.clone(),
+                struct_type,
+                labels: declaration
+                    .fields
+                    .iter()
+                    .map(|field| Some(format!("{}: {}", field.name, field.field_type)))
+                    .collect(),
+            });
+            self.expression_compiler.register_struct(
+                declaration.name.clone(),
+                struct_type,
+                declaration
+                    .fields
+                    .iter()
+                    .map(|field| field.name.clone())
+                    .collect(),
+            );
+        }
+        Ok(())
+    }
+
     /// Creates actor type structure
     fn create_actor_type(&mut self, actor: &Actor) -> CodeGenResult<()> {
         let struct_type = self.context.opaque_struct_type(&actor.name);
 
-        // フィールドの型を収集
-        let field_types = actor
-            .fields
-            .iter()
-            .map(|field| self.type_converter.convert_to_llvm(&field.field_type))
-            .collect::<Result<Vec<_>, _>>()?;
+        // フィールドの型を収集 (レイアウト属性があればパディングを挿入)
+        let mut body = self
+            .type_converter
+            .convert_actor_fields(&actor.fields, &actor.layout)?;
+
+        // スレッド有効時は Shared フィールドをロックワードで保護する
+        if self.threads
+            && actor
+                .fields
+                .iter()
+                .any(|field| matches!(field.ownership, OwnershipType::Shared))
+        {
+            let lock_index = self.type_converter.append_lock_word(&mut body);
+            self.state_locks.insert(actor.name.clone(), lock_index);
+        }
+
+        // @nonreentrant のメソッドがあれば実行中フラグと保留キューを持たせる
+        if actor.methods.iter().any(|method| method.is_nonreentrant) {
+            let busy_index = self.type_converter.append_reentrancy_guard(&mut body);
+            self.reentrancy_guards
+                .insert(actor.name.clone(), busy_index);
+        }
 
-        struct_type.set_body(&field_types, false);
+        // ランタイムの経路表で購読するなら配送先のメールボックスを持たせる
+        if self.topic_route == TopicRoute::Runtime
+            && actor
+                .methods
+                .iter()
+                .filter_map(|method| method.body.as_ref())
+                .any(subscribes)
+        {
+            let inbox_index = self.type_converter.append_inbox(&mut body);
+            self.inboxes.insert(actor.name.clone(), inbox_index);
+        }
+
+        struct_type.set_body(&body.types, body.packed);
+        let mut labels = vec![None; body.types.len()];
+        for (field, &index) in actor.fields.iter().zip(&body.field_indices) {
+            labels[index as usize] = Some(format!("{}: {}", field.name, field.field_type));
+        }
+        // ランタイムが使う語も状態構造体の一部として示す
+        let words = [
+            (self.state_locks.get(&actor.name), 0, "lock word"),
+            (self.reentrancy_guards.get(&actor.name), 0, "busy word"),
+            (
+                self.reentrancy_guards.get(&actor.name),
+                1,
+                "deferred messages",
+            ),
+            (self.inboxes.get(&actor.name), 0, "inbox"),
+        ];
+        for (index, offset, label) in words {
+            if let Some(&index) = index {
+                labels[(index + offset) as usize] = Some(label.to_string());
+            }
+        }
+        self.layouts.push(LayoutSource {
+            kind: "actor",
+            name: actor.name.clone(),
+            struct_type,
+            labels,
+        });
         self.type_converter
             .register_struct_type(&actor.name, struct_type);
+        self.type_converter
+            .register_field_indices(&actor.name, body.field_indices);
+
+        Ok(())
+    }
+
+    /// Registers extern types and declares extern functions as imports, except
+    /// `@proto` codecs, which are defined in the module
+    fn declare_externs(&mut self, actor: &Actor) -> CodeGenResult<()> {
+        for item in &actor.externs {
+            if let Extern::Type(name) = item {
+                self.type_converter.register_extern_type(name);
+            }
+        }
 
+        let takes_callbacks = actor.externs.iter().any(|item| {
+            matches!(item, Extern::Function(function)
+                if function.params.iter().any(|p| matches!(p.param_type, Type::Function { .. })))
+        });
+        if takes_callbacks && self.type_converter.uses_reference_types() {
+            let helper = self.define_funcref_helper()?;
+            self.expression_compiler.register_funcref_helper(helper);
+        }
+
+        for item in &actor.externs {
+            if let Extern::Function(function) = item {
+                let function_type =
+                    self.create_function_type(&function.params, &function.return_type)?;
+                let declaration = match self.module.get_function(&function.name) {
+                    Some(declaration) => declaration,
+                    None => match proto::codec(function).map_err(CodeGenError::Internal)? {
+                        Some(codec) => self.define_proto_codec(function, codec)?,
+                        None => self.module.add_function(
+                            &function.name,
+                            function_type,
+                            Some(Linkage::External),
+                        ),
+                    },
+                };
+                self.expression_compiler
+                    .register_function(function.name.clone(), declaration);
+            }
+        }
         Ok(())
     }
 
+    /// Defines an `@proto` extern as a call into the runtime's protobuf codec,
+    /// passing the record through a stack slot described by a constant descriptor
+    fn define_proto_codec(
+        &mut self,
+        function: &ExternFunction,
+        codec: proto::Codec,
+    ) -> CodeGenResult<FunctionValue<'ctx>> {
+        let function_type = self.create_function_type(&function.params, &function.return_type)?;
+        let codec_function =
+            self.module
+                .add_function(&function.name, function_type, Some(Linkage::Internal));
+        let descriptor = self.proto_descriptor(
+            &format!("{}.proto", function.name),
+            codec.record,
+            &codec.fields,
+        )?;
+        let record_type = self.type_converter.convert_to_llvm(codec.record)?;
+
+        let basic_block = self.context.append_basic_block(codec_function, "entry");
+        self.builder.position_at_end(basic_block);
+
+        let llvm_error = |e: inkwell::builder::BuilderError| CodeGenError::LLVMError(e.to_string());
+        let argument = codec_function.get_first_param().ok_or_else(|| {
+            CodeGenError::Internal(format!("codec {} has no parameter", function.name))
+        })?;
+        let record = self
+            .builder
+            .build_alloca(record_type, "record")
+            .map_err(llvm_error)?;
+
+        match codec.direction {
+            Direction::Encode => {
+                self.builder
+                    .build_store(record, argument)
+                    .map_err(llvm_error)?;
+                let bytes = self
+                    .builder
+                    .build_call(
+                        self.runtime_function(RuntimeFunction::ProtoEncode),
+                        &[descriptor.into(), record.into()],
+                        "bytes",
+                    )
+                    .map_err(llvm_error)?
+                    .try_as_basic_value()
+                    .left()
+                    .ok_or_else(|| {
+                        CodeGenError::Internal("__replica_proto_encode returns void".into())
+                    })?;
+                self.builder
+                    .build_return(Some(&bytes))
+                    .map_err(llvm_error)?;
+            }
+            Direction::Decode => {
+                let status = self
+                    .builder
+                    .build_call(
+                        self.runtime_function(RuntimeFunction::ProtoDecode),
+                        &[descriptor.into(), argument.into(), record.into()],
+                        "status",
+                    )
+                    .map_err(llvm_error)?
+                    .try_as_basic_value()
+                    .left()
+                    .ok_or_else(|| {
+                        CodeGenError::Internal("__replica_proto_decode returns void".into())
+                    })?;
+                let ok = self
+                    .builder
+                    .build_int_compare(
+                        IntPredicate::NE,
+                        status.into_int_value(),
+                        self.context.i32_type().const_zero(),
+                        "ok",
+                    )
+                    .map_err(llvm_error)?;
+                let value = self
+                    .builder
+                    .build_load(record_type, record, "value")
+                    .map_err(llvm_error)?;
+
+                // 失敗時も値は埋めておき、フラグだけで nil を表す
+                let optional_type = function_type
+                    .get_return_type()
+                    .ok_or_else(|| CodeGenError::Internal("decoder returns void".into()))?
+                    .into_struct_type();
+                let optional = self
+                    .builder
+                    .build_insert_value(optional_type.get_undef(), value, 0, "some")
+                    .map_err(llvm_error)?;
+                let optional = self
+                    .builder
+                    .build_insert_value(optional, ok, 1, "optional")
+                    .map_err(llvm_error)?
+                    .into_struct_value();
+                self.builder
+                    .build_return(Some(&optional))
+                    .map_err(llvm_error)?;
+            }
+        }
+        Ok(codec_function)
+    }
+
+    /// Emits the runtime descriptor of a message record as constant data,
+    /// recursing into embedded messages
+    fn proto_descriptor(
+        &self,
+        name: &str,
+        record: &Type,
+        fields: &[WireField],
+    ) -> CodeGenResult<PointerValue<'ctx>> {
+        let i32_type = self.context.i32_type();
+        let ptr_type = self.context.ptr_type(AddressSpace::default());
+        let constant = |value: u32| i32_type.const_int(value as u64, false);
+
+        let types = proto::record_fields(record)
+            .ok_or_else(|| CodeGenError::Internal(format!("{} is not a record", name)))?;
+        // フィールドの位置は生成コードと同じターゲットのレイアウトで求める
+        let record_type = self.type_converter.convert_to_llvm(record)?;
+        if !record_type.is_struct_type() {
+            return Err(CodeGenError::Internal(format!("{} is not a record", name)));
+        }
+        let record_layout = self
+            .type_converter
+            .target_layout(record_type.into_struct_type());
+
+        let field_type = self.context.struct_type(
+            &[
+                i32_type.into(),
+                i32_type.into(),
+                i32_type.into(),
+                ptr_type.into(),
+            ],
+            false,
+        );
+        let mut descriptors = Vec::new();
+        for (index, (field, ty)) in fields.iter().zip(&types).enumerate() {
+            let message = match (&field.kind, ty) {
+                (WireKind::Message(nested), Type::Array(element)) if field.repeated => {
+                    self.proto_descriptor(&format!("{}.{}", name, index), element, nested)?
+                }
+                (WireKind::Message(nested), ty) => {
+                    self.proto_descriptor(&format!("{}.{}", name, index), ty, nested)?
+                }
+                _ => ptr_type.const_null(),
+            };
+            descriptors.push(field_type.const_named_struct(&[
+                constant(field.number).into(),
+                constant(runtime::proto_kind(field)).into(),
+                constant(record_layout.elements[index].offset).into(),
+                message.into(),
+            ]));
+        }
+
+        let field_array = field_type.const_array(&descriptors);
+        let field_table =
+            self.module
+                .add_global(field_array.get_type(), None, &format!("{}.fields", name));
+        field_table.set_initializer(&field_array);
+        field_table.set_constant(true);
+        field_table.set_linkage(Linkage::Private);
+
+        let message_type = self
+            .context
+            .struct_type(&[i32_type.into(), i32_type.into(), ptr_type.into()], false);
+        let descriptor = self.module.add_global(message_type, None, name);
+        descriptor.set_initializer(&message_type.const_named_struct(&[
+            constant(record_layout.size).into(),
+            constant(fields.len() as u32).into(),
+            field_table.as_pointer_value().into(),
+        ]));
+        descriptor.set_constant(true);
+        descriptor.set_linkage(Linkage::Private);
+        Ok(descriptor.as_pointer_value())
+    }
+
+    /// Defines `replica.funcref(index)`, which loads the `funcref` of a function
+    /// pointer from `__indirect_function_table`
+    fn define_funcref_helper(&mut self) -> CodeGenResult<FunctionValue<'ctx>> {
+        const NAME: &str = "replica.funcref";
+        if let Some(helper) = self.module.get_function(NAME) {
+            return Ok(helper);
+        }
+
+        let funcref_type = self.type_converter.function_reference_type();
+        let table = self
+            .module
+            .get_global("__indirect_function_table")
+            .unwrap_or_else(|| {
+                self.module.add_global(
+                    funcref_type.array_type(0),
+                    Some(AddressSpace::from(1u16)),
+                    "__indirect_function_table",
+                )
+            });
+        let table_get = inkwell::intrinsics::Intrinsic::find("llvm.wasm.table.get.funcref")
+            .and_then(|intrinsic| intrinsic.get_declaration(&self.module, &[]))
+            .ok_or_else(|| {
+                CodeGenError::Initialization(
+                    "LLVM does not provide llvm.wasm.table.get.funcref".to_string(),
+                )
+                .with_suggestion("Disable the reference-types target feature".to_string())
+            })?;
+
+        let i32_type = self.context.i32_type();
+        let helper = self.module.add_function(
+            NAME,
+            funcref_type.fn_type(&[i32_type.into()], false),
+            Some(Linkage::Internal),
+        );
+        let basic_block = self.context.append_basic_block(helper, "entry");
+        self.builder.position_at_end(basic_block);
+
+        let llvm_error = |e: inkwell::builder::BuilderError| CodeGenError::LLVMError(e.to_string());
+        let index = helper
+            .get_first_param()
+            .ok_or_else(|| CodeGenError::Internal("funcref helper has no index".into()))?;
+        let funcref = self
+            .builder
+            .build_call(
+                table_get,
+                &[table.as_pointer_value().into(), index.into()],
+                "funcref",
+            )
+            .map_err(llvm_error)?
+            .try_as_basic_value()
+            .left()
+            .ok_or_else(|| CodeGenError::Internal("table.get returned no value".into()))?;
+        self.builder
+            .build_return(Some(&funcref))
+            .map_err(llvm_error)?;
+
+        Ok(helper)
+    }
+
     /// Processes actor fields
     fn process_fields(&mut self, actor: &Actor) -> CodeGenResult<()> {
         for field in &actor.fields {
@@ -106,20 +1524,55 @@ impl<'ctx> CodeGenerator<'ctx> {
         Ok(())
     }
 
-    /// Compiles a method to LLVM IR
-    fn compile_method(&mut self, method: &Method, actor_type: &ActorType) -> CodeGenResult<()> {
+    /// Declares every method of `actor` and registers it in `actor_methods`, so
+    /// bodies can call methods defined after them
+    fn declare_methods(&mut self, actor: &Actor) -> CodeGenResult<()> {
+        for method in &actor.methods {
+            let function_type = self.create_method_type(method)?;
+            let symbol = self.member_symbol(&method.name);
+            let function = self.module.add_function(&symbol, function_type, None);
+            self.expression_compiler
+                .register_method(method.name.clone(), function);
+            if let Some(element_type) = self.array_element_type(method.return_type.as_ref())? {
+                self.expression_compiler
+                    .register_array_result(method.name.clone(), element_type);
+            }
+            if let Some(labels) = record_labels(method.return_type.as_ref()) {
+                self.expression_compiler
+                    .register_record_result(method.name.clone(), labels);
+            }
+            self.name_export(&symbol, &[bare_name(&method.name)]);
+            self.actor_methods.insert(symbol, function);
+        }
+        Ok(())
+    }
+
+    /// Compiles a method to LLVM IR. The method must have been declared by
+    /// `declare_methods`.
+    fn compile_method(&mut self, method: &Method, actor: &Actor) -> CodeGenResult<()> {
         self.debug_log(&format!("Compiling method: {}", method.name));
 
-        // メソッドの型を作成
-        let function_type = self.create_method_type(method)?;
-        let function = self.module.add_function(&method.name, function_type, None);
+        let function = *self
+            .actor_methods
+            .get(&self.member_symbol(&method.name))
+            .ok_or_else(|| {
+                CodeGenError::Internal(format!("Method {} was not declared", method.name))
+            })?;
+
+        if method.stream_item().is_some() {
+            return self.compile_stream_method(method, actor, function);
+        }
 
         // エントリーブロックの作成
         let basic_block = self.context.append_basic_block(function, "entry");
         self.builder.position_at_end(basic_block);
 
+        if let Some(&reserve) = self.stack_guards.get(&method.name) {
+            self.build_stack_guard(function, reserve)?;
+        }
+
         // パラメータの処理
-        self.process_method_parameters(method, function)?;
+        self.process_method_parameters(method, function, actor)?;
 
         // メソッドボディのコンパイル
         if let Some(body) = &method.body {
@@ -131,133 +1584,7592 @@ impl<'ctx> CodeGenerator<'ctx> {
 
         // 非同期処理の場合の追加コード
         if method.is_async {
+            if runs_as_task(method) {
+                self.compile_task_method(method, actor, function)?;
+            }
             self.generate_async_wrapper(function, method)?;
         }
 
-        self.actor_methods.insert(method.name.clone(), function);
         Ok(())
     }
 
-    /// Generates WASM output
-    pub fn emit_wasm(&self) -> CodeGenResult<Vec<u8>> {
-        let triple = TargetTriple::create("wasm32-unknown-unknown");
-        self.module.set_triple(&triple);
+    /// Compiles a method returning `AsyncStream<T>` into a pull-based stream.
+    /// `<method>(self, params...)` runs none of the body: it allocates the
+    /// stream's frame with `__replica_alloc` and returns it, or null when memory
+    /// is exhausted. `<method>.next(stream, out) -> i32` runs the body up to the
+    /// next `yield`, stores the item at `out` and returns 1, or returns 0 once
+    /// the body has finished. `<method>.close(stream)` frees the frame. The body
+    /// only runs while the host asks for items, so a slow consumer holds the
+    /// producer back.
+    ///
+    /// Every local of `next` lives in a stack slot of its entry block. The slots
+    /// are saved to the frame at each `yield` and restored when `next` is
+    /// called again, which then continues after the `yield` it stopped at.
+    fn compile_stream_method(
+        &mut self,
+        method: &Method,
+        actor: &Actor,
+        open: FunctionValue<'ctx>,
+    ) -> CodeGenResult<()> {
+        let llvm_error = |e: inkwell::builder::BuilderError| CodeGenError::LLVMError(e.to_string());
+        let ptr_type = self.context.ptr_type(AddressSpace::default());
+        let i32_type = self.context.i32_type();
 
-        let target = Target::from_triple(&triple)
-            .map_err(|e| CodeGenError::WasmGen(format!("Failed to create target: {}", e)))?;
+        let next_name = format!("{}.next", self.member_symbol(&method.name));
+        let next = self.module.add_function(
+            &next_name,
+            i32_type.fn_type(&[ptr_type.into(), ptr_type.into()], false),
+            None,
+        );
+        self.name_export(&next_name, &[bare_name(&method.name), "next"]);
+        self.stream_entry_points.push(next_name);
 
-        let target_machine = target
-            .create_target_machine(
-                &triple,
-                "generic",
+        let entry = self.context.append_basic_block(next, "entry");
+        let resume = self.context.append_basic_block(next, "resume");
+        let start = self.context.append_basic_block(next, "start");
+        let finished = self.context.append_basic_block(next, "finished");
+        self.builder.position_at_end(entry);
+        let (stream, out) = match (next.get_nth_param(0), next.get_nth_param(1)) {
+            (Some(stream), Some(out)) => (stream.into_pointer_value(), out.into_pointer_value()),
+            _ => {
+                return Err(CodeGenError::Internal(format!(
+                    "{}.next has no stream parameter",
+                    method.name
+                )))
+            }
+        };
+        stream.set_name("stream");
+        out.set_name("out");
+
+        // フレームの先頭はインスタンスへのポインタ
+        let this = self
+            .builder
+            .build_load(ptr_type, stream, "self")
+            .map_err(llvm_error)?
+            .into_pointer_value();
+        self.bind_instance(actor, this)?;
+        // 引数も yield をまたいで保存するスロットに置く
+        let mut param_slots = Vec::new();
+        for param in &method.params {
+            let value_type = self
+                .type_converter
+                .convert_with_ownership(&param.param_type, &param.ownership)?;
+            let address = self.entry_alloca(value_type, &param.name)?;
+            self.expression_compiler.register_slot(
+                param.name.clone(),
+                Slot {
+                    address,
+                    value_type,
+                    weak: false,
+                },
+            );
+            self.register_array_param(param)?;
+            param_slots.push(address);
+        }
+        self.builder
+            .build_unconditional_branch(resume)
+            .map_err(llvm_error)?;
+
+        self.builder.position_at_end(start);
+        self.stream = Some(StreamState {
+            out,
+            suspends: Vec::new(),
+            resumes: Vec::new(),
+        });
+        let result = method
+            .body
+            .as_ref()
+            .map_or(Ok(()), |body| self.compile_body(body));
+        let state = self
+            .stream
+            .take()
+            .ok_or_else(|| CodeGenError::Internal("Stream state lost".into()))?;
+        result?;
+        self.builder
+            .build_unconditional_branch(finished)
+            .map_err(llvm_error)?;
+
+        // フレーム: インスタンス、状態、エントリーブロックの全スロット
+        let slots = entry_slots(entry)?;
+        let frame_type = self
+            .context
+            .opaque_struct_type(&format!("{}.frame", self.member_symbol(&method.name)));
+        let field_types: Vec<BasicTypeEnum> = [ptr_type.into(), i32_type.into()]
+            .into_iter()
+            .chain(slots.iter().map(|&(_, slot_type)| slot_type))
+            .collect();
+        frame_type.set_body(&field_types, false);
+        let state_field = |builder: &Builder<'ctx>, frame: PointerValue<'ctx>| {
+            builder
+                .build_struct_gep(frame_type, frame, STREAM_STATE_INDEX, "state")
+                .map_err(llvm_error)
+        };
+
+        // 保存したスロットを戻し、前回止まった yield の直後から再開する
+        self.builder.position_at_end(resume);
+        self.copy_frame_slots(frame_type, stream, STREAM_FRAME_HEADER, &slots, false)?;
+        let resumed_at = self
+            .builder
+            .build_load(i32_type, state_field(&self.builder, stream)?, "resumed_at")
+            .map_err(llvm_error)?
+            .into_int_value();
+        let cases: Vec<_> = std::iter::once(start)
+            .chain(state.resumes.iter().copied())
+            .enumerate()
+            .map(|(index, block)| (i32_type.const_int(index as u64, false), block))
+            .collect();
+        self.builder
+            .build_switch(resumed_at, finished, &cases)
+            .map_err(llvm_error)?;
+
+        for (index, suspend) in state.suspends.iter().enumerate() {
+            self.builder.position_at_end(*suspend);
+            self.copy_frame_slots(frame_type, stream, STREAM_FRAME_HEADER, &slots, true)?;
+            self.builder
+                .build_store(
+                    state_field(&self.builder, stream)?,
+                    i32_type.const_int(index as u64 + 1, false),
+                )
+                .map_err(llvm_error)?;
+            self.builder
+                .build_return(Some(&i32_type.const_int(1, false)))
+                .map_err(llvm_error)?;
+        }
+
+        // 終わったストリームの next は何もせずに 0 を返し続ける
+        self.builder.position_at_end(finished);
+        self.builder
+            .build_store(
+                state_field(&self.builder, stream)?,
+                i32_type.const_all_ones(),
+            )
+            .map_err(llvm_error)?;
+        self.builder
+            .build_return(Some(&i32_type.const_zero()))
+            .map_err(llvm_error)?;
+
+        // 本体は実行せず、フレームの確保と引数の保存だけを行う
+        let entry = self.context.append_basic_block(open, "entry");
+        let init = self.context.append_basic_block(open, "init");
+        let done = self.context.append_basic_block(open, "done");
+        self.builder.position_at_end(entry);
+        let this = open
+            .get_first_param()
+            .ok_or_else(|| {
+                CodeGenError::Internal(format!("Method {} has no self parameter", method.name))
+            })?
+            .into_pointer_value();
+        this.set_name("self");
+        let size = self.alloc_size(frame_type);
+        let alloc = self.runtime_function(RuntimeFunction::Alloc);
+        let frame = self
+            .builder
+            .build_call(alloc, &[size.into()], "frame")
+            .map_err(llvm_error)?
+            .try_as_basic_value()
+            .left()
+            .ok_or_else(|| CodeGenError::Internal("__replica_alloc returns void".into()))?
+            .into_pointer_value();
+        let exhausted = self
+            .builder
+            .build_is_null(frame, "exhausted")
+            .map_err(llvm_error)?;
+        self.builder
+            .build_conditional_branch(exhausted, done, init)
+            .map_err(llvm_error)?;
+
+        self.builder.position_at_end(init);
+        self.builder.build_store(frame, this).map_err(llvm_error)?;
+        self.builder
+            .build_store(state_field(&self.builder, frame)?, i32_type.const_zero())
+            .map_err(llvm_error)?;
+        for ((param, value), param_slot) in method
+            .params
+            .iter()
+            .zip(open.get_param_iter().skip(1))
+            .zip(&param_slots)
+        {
+            value.set_name(&param.name);
+            let index = slots
+                .iter()
+                .position(|(address, _)| address == param_slot)
+                .ok_or_else(|| {
+                    CodeGenError::Internal(format!("Parameter {} has no slot", param.name))
+                })?;
+            let field = self
+                .builder
+                .build_struct_gep(
+                    frame_type,
+                    frame,
+                    STREAM_FRAME_HEADER + index as u32,
+                    &param.name,
+                )
+                .map_err(llvm_error)?;
+            self.builder.build_store(field, value).map_err(llvm_error)?;
+        }
+        self.builder
+            .build_unconditional_branch(done)
+            .map_err(llvm_error)?;
+        self.builder.position_at_end(done);
+        self.builder
+            .build_return(Some(&frame))
+            .map_err(llvm_error)?;
+
+        let close_name = format!("{}.close", self.member_symbol(&method.name));
+        let close = self.module.add_function(
+            &close_name,
+            self.context.void_type().fn_type(&[ptr_type.into()], false),
+            None,
+        );
+        self.name_export(&close_name, &[bare_name(&method.name), "close"]);
+        self.stream_entry_points.push(close_name);
+        let entry = self.context.append_basic_block(close, "entry");
+        self.builder.position_at_end(entry);
+        let stream = close
+            .get_first_param()
+            .ok_or_else(|| {
+                CodeGenError::Internal(format!("{}.close has no stream parameter", method.name))
+            })?
+            .into_pointer_value();
+        stream.set_name("stream");
+        let free = self.runtime_function(RuntimeFunction::Free);
+        self.builder
+            .build_call(free, &[stream.into()], "")
+            .map_err(llvm_error)?;
+        self.builder.build_return(None).map_err(llvm_error)?;
+
+        Ok(())
+    }
+
+    /// Lowers an async method whose body awaits to a task, a state machine
+    /// driven by the actor's dispatch function, so that an `await` suspends
+    /// the method instead of running the awaited call in place.
+    /// `<method>.start(self, mailbox, reply_frame, reply_tag, reply_result,
+    /// params...)` allocates the task's frame with `__replica_alloc` and runs
+    /// `<method>.resume(frame)` up to the first `await`, which queues the
+    /// awaited call on `mailbox` and returns. The call's message carries the
+    /// frame: once the call has run, its result is stored in the frame and the
+    /// frame is queued under the task's resume tag, whose message runs
+    /// `resume` again from after the `await`. When the body returns, the result
+    /// is handed to the task awaiting this one, if any, in the same way and
+    /// the frame is freed.
+    ///
+    /// Like a stream's `next`, `resume` keeps every local in a stack slot of
+    /// its entry block, saved to the frame at each `await` and restored when
+    /// the task resumes. When the frame, a payload or a place in the mailbox
+    /// cannot be had, the method or the awaited call runs in place, as in a
+    /// direct call of the method.
+    fn compile_task_method(
+        &mut self,
+        method: &Method,
+        actor: &Actor,
+        direct: FunctionValue<'ctx>,
+    ) -> CodeGenResult<()> {
+        let llvm_error = |e: inkwell::builder::BuilderError| CodeGenError::LLVMError(e.to_string());
+        let ptr_type = self.context.ptr_type(AddressSpace::default());
+        let i32_type = self.context.i32_type();
+
+        let start_task = self.task_start_function(&method.name, direct);
+        let resume_task = self.module.add_function(
+            &format!("{}.resume", self.member_symbol(&method.name)),
+            self.context.void_type().fn_type(&[ptr_type.into()], false),
+            None,
+        );
+        let resume_tag = (self.message_tags.len() + self.continuations.len()) as u32;
+        self.continuations.push(MessageHandler::Resume(resume_task));
+
+        let entry = self.context.append_basic_block(resume_task, "entry");
+        let restore = self.context.append_basic_block(resume_task, "restore");
+        let start = self.context.append_basic_block(resume_task, "start");
+        let complete = self.context.append_basic_block(resume_task, "complete");
+        let invalid = self.context.append_basic_block(resume_task, "invalid");
+        self.builder.position_at_end(entry);
+        let frame = resume_task
+            .get_first_param()
+            .ok_or_else(|| {
+                CodeGenError::Internal(format!("{}.resume has no frame parameter", method.name))
+            })?
+            .into_pointer_value();
+        frame.set_name("frame");
+
+        // フレームの先頭はインスタンスへのポインタ
+        let this = self
+            .builder
+            .build_load(ptr_type, frame, "self")
+            .map_err(llvm_error)?
+            .into_pointer_value();
+        self.bind_instance(actor, this)?;
+        // 引数も await をまたいで保存するスロットに置く
+        let mut param_slots = Vec::new();
+        for param in &method.params {
+            let value_type = self
+                .type_converter
+                .convert_with_ownership(&param.param_type, &param.ownership)?;
+            let address = self.entry_alloca(value_type, &param.name)?;
+            self.expression_compiler.register_slot(
+                param.name.clone(),
+                Slot {
+                    address,
+                    value_type,
+                    weak: false,
+                },
+            );
+            self.register_array_param(param)?;
+            param_slots.push(address);
+        }
+        let result = match direct.get_type().get_return_type() {
+            Some(result_type) => Some((self.entry_alloca(result_type, "result")?, result_type)),
+            None => None,
+        };
+        self.builder
+            .build_unconditional_branch(restore)
+            .map_err(llvm_error)?;
+
+        // 本体の終わりに達したら既定値を返す
+        self.builder.position_at_end(start);
+        if let Some((slot, result_type)) = result {
+            self.builder
+                .build_store(slot, result_type.const_zero())
+                .map_err(llvm_error)?;
+        }
+        self.task = Some(TaskState {
+            result,
+            complete,
+            awaits: Vec::new(),
+        });
+        let compiled = method
+            .body
+            .as_ref()
+            .map_or(Ok(()), |body| self.compile_body(body));
+        let state = self
+            .task
+            .take()
+            .ok_or_else(|| CodeGenError::Internal("Task state lost".into()))?;
+        compiled?;
+        self.builder
+            .build_unconditional_branch(complete)
+            .map_err(llvm_error)?;
+
+        // フレーム: インスタンス、状態、メールボックス、待ち手への返信先、エントリーブロックの全スロット
+        let slots = entry_slots(entry)?;
+        let frame_type = self
+            .context
+            .opaque_struct_type(&format!("{}.frame", self.member_symbol(&method.name)));
+        let field_types: Vec<BasicTypeEnum> = [
+            ptr_type.into(),
+            i32_type.into(),
+            ptr_type.into(),
+            ptr_type.into(),
+            i32_type.into(),
+            ptr_type.into(),
+        ]
+        .into_iter()
+        .chain(slots.iter().map(|&(_, slot_type)| slot_type))
+        .collect();
+        frame_type.set_body(&field_types, false);
+        let field = |builder: &Builder<'ctx>, frame: PointerValue<'ctx>, index: u32, name: &str| {
+            builder
+                .build_struct_gep(frame_type, frame, index, name)
+                .map_err(llvm_error)
+        };
+
+        // 保存したスロットを戻し、前回止まった await の直後から再開する
+        self.builder.position_at_end(restore);
+        self.copy_frame_slots(frame_type, frame, TASK_FRAME_HEADER, &slots, false)?;
+        let resumed_at = self
+            .builder
+            .build_load(
+                i32_type,
+                field(&self.builder, frame, TASK_STATE_INDEX, "state")?,
+                "resumed_at",
+            )
+            .map_err(llvm_error)?
+            .into_int_value();
+        let cases: Vec<_> = std::iter::once(start)
+            .chain(state.awaits.iter().map(|point| point.resume))
+            .enumerate()
+            .map(|(index, block)| (i32_type.const_int(index as u64, false), block))
+            .collect();
+        self.builder
+            .build_switch(resumed_at, invalid, &cases)
+            .map_err(llvm_error)?;
+        self.builder.position_at_end(invalid);
+        self.builder.build_unreachable().map_err(llvm_error)?;
+
+        let alloc = self.runtime_function(RuntimeFunction::Alloc);
+        let free = self.runtime_function(RuntimeFunction::Free);
+        let mailbox_push = self.runtime_function(RuntimeFunction::MailboxPush);
+        for (index, point) in state.awaits.iter().enumerate() {
+            let queue = self.context.append_basic_block(resume_task, "await.queue");
+            let queued = self.context.append_basic_block(resume_task, "await.queued");
+            let rejected = self
+                .context
+                .append_basic_block(resume_task, "await.rejected");
+            let in_place = self
+                .context
+                .append_basic_block(resume_task, "await.in_place");
+
+            self.builder.position_at_end(point.suspend);
+            self.copy_frame_slots(frame_type, frame, TASK_FRAME_HEADER, &slots, true)?;
+            self.builder
+                .build_store(
+                    field(&self.builder, frame, TASK_STATE_INDEX, "state")?,
+                    i32_type.const_int(index as u64 + 1, false),
+                )
+                .map_err(llvm_error)?;
+            let mailbox = self
+                .builder
+                .build_load(
+                    ptr_type,
+                    field(&self.builder, frame, TASK_MAILBOX_INDEX, "mailbox")?,
+                    "mailbox",
+                )
+                .map_err(llvm_error)?;
+            let size = self.alloc_size(point.payload_type);
+            let payload = self
+                .builder
+                .build_call(alloc, &[size.into()], "payload")
+                .map_err(llvm_error)?
+                .try_as_basic_value()
+                .left()
+                .ok_or_else(|| CodeGenError::Internal("__replica_alloc returns void".into()))?
+                .into_pointer_value();
+            let exhausted = self
+                .builder
+                .build_is_null(payload, "exhausted")
+                .map_err(llvm_error)?;
+            self.builder
+                .build_conditional_branch(exhausted, in_place, queue)
+                .map_err(llvm_error)?;
+
+            // 引数の後に、結果を受け取るフレームと再開のタグを付ける
+            self.builder.position_at_end(queue);
+            let result_field = match point.result {
+                Some(slot) => {
+                    let position = slots
+                        .iter()
+                        .position(|&(address, _)| address == slot)
+                        .ok_or_else(|| {
+                            CodeGenError::Internal("Awaited result has no slot".into())
+                        })?;
+                    field(
+                        &self.builder,
+                        frame,
+                        TASK_FRAME_HEADER + position as u32,
+                        "result_field",
+                    )?
+                }
+                None => ptr_type.const_null(),
+            };
+            let reply: [BasicValueEnum; 3] = [
+                frame.into(),
+                i32_type.const_int(resume_tag as u64, false).into(),
+                result_field.into(),
+            ];
+            for (position, value) in point.args.iter().chain(&reply).enumerate() {
+                let slot = self
+                    .builder
+                    .build_struct_gep(point.payload_type, payload, position as u32, "arg.slot")
+                    .map_err(llvm_error)?;
+                self.builder.build_store(slot, *value).map_err(llvm_error)?;
+            }
+            let pushed = self
+                .builder
+                .build_call(
+                    mailbox_push,
+                    &[
+                        mailbox.into(),
+                        i32_type.const_int(point.tag as u64, false).into(),
+                        payload.into(),
+                        size.into(),
+                    ],
+                    "pushed",
+                )
+                .map_err(llvm_error)?
+                .try_as_basic_value()
+                .left()
+                .ok_or_else(|| {
+                    CodeGenError::Internal("__replica_mailbox_push returns void".into())
+                })?
+                .into_int_value();
+            let is_full = self
+                .builder
+                .build_int_compare(IntPredicate::NE, pushed, i32_type.const_zero(), "is_full")
+                .map_err(llvm_error)?;
+            self.builder
+                .build_conditional_branch(is_full, rejected, queued)
+                .map_err(llvm_error)?;
+            self.builder.position_at_end(queued);
+            self.builder.build_return(None).map_err(llvm_error)?;
+            self.builder.position_at_end(rejected);
+            self.builder
+                .build_call(free, &[payload.into()], "")
+                .map_err(llvm_error)?;
+            self.builder
+                .build_unconditional_branch(in_place)
+                .map_err(llvm_error)?;
+
+            // 積めなければ待つ呼び出しをその場で実行する
+            self.builder.position_at_end(in_place);
+            let args: Vec<BasicMetadataValueEnum> = std::iter::once(this.into())
+                .chain(point.args.iter().map(|&arg| arg.into()))
+                .collect();
+            let value = self
+                .builder
+                .build_call(point.callee, &args, "awaited")
+                .map_err(llvm_error)?
+                .try_as_basic_value()
+                .left();
+            if let (Some(slot), Some(value)) = (point.result, value) {
+                self.builder.build_store(slot, value).map_err(llvm_error)?;
+            }
+            self.builder
+                .build_unconditional_branch(point.resume)
+                .map_err(llvm_error)?;
+        }
+
+        // 結果を待ち手へ渡してフレームを解放する
+        self.builder.position_at_end(complete);
+        let value = result
+            .map(|(slot, result_type)| self.builder.build_load(result_type, slot, "result"))
+            .transpose()
+            .map_err(llvm_error)?;
+        let mailbox = self
+            .builder
+            .build_load(
+                ptr_type,
+                field(&self.builder, frame, TASK_MAILBOX_INDEX, "mailbox")?,
+                "mailbox",
+            )
+            .map_err(llvm_error)?
+            .into_pointer_value();
+        let mut reply = Vec::with_capacity(3);
+        for (offset, (value_type, name)) in [ptr_type.into(), i32_type.into(), ptr_type.into()]
+            .into_iter()
+            .zip(["reply.frame", "reply.tag", "reply.result"])
+            .enumerate()
+        {
+            let address = field(&self.builder, frame, TASK_REPLY_INDEX + offset as u32, name)?;
+            let value: BasicValueEnum = self
+                .builder
+                .build_load::<BasicTypeEnum>(value_type, address, name)
+                .map_err(llvm_error)?;
+            reply.push(value);
+        }
+        self.build_reply(this, mailbox, [reply[0], reply[1], reply[2]], value)?;
+        self.builder
+            .build_call(free, &[frame.into()], "")
+            .map_err(llvm_error)?;
+        self.builder.build_return(None).map_err(llvm_error)?;
+
+        // フレームを確保して引数と返信先を保存し、最初の await まで進める
+        let entry = self.context.append_basic_block(start_task, "entry");
+        let init = self.context.append_basic_block(start_task, "init");
+        let exhausted = self.context.append_basic_block(start_task, "exhausted");
+        self.builder.position_at_end(entry);
+        let params = start_task.get_params();
+        let [this, mailbox, reply_frame, reply_tag, reply_result, args @ ..] = params.as_slice()
+        else {
+            return Err(CodeGenError::Internal(format!(
+                "{}.start takes self, mailbox and reply",
+                method.name
+            )));
+        };
+        for (param, name) in params.iter().zip(
+            [
+                "self",
+                "mailbox",
+                "reply.frame",
+                "reply.tag",
+                "reply.result",
+            ]
+            .into_iter()
+            .chain(method.params.iter().map(|param| param.name.as_str())),
+        ) {
+            param.set_name(name);
+        }
+        let size = self.alloc_size(frame_type);
+        let frame = self
+            .builder
+            .build_call(alloc, &[size.into()], "frame")
+            .map_err(llvm_error)?
+            .try_as_basic_value()
+            .left()
+            .ok_or_else(|| CodeGenError::Internal("__replica_alloc returns void".into()))?
+            .into_pointer_value();
+        let is_null = self
+            .builder
+            .build_is_null(frame, "is_null")
+            .map_err(llvm_error)?;
+        self.builder
+            .build_conditional_branch(is_null, exhausted, init)
+            .map_err(llvm_error)?;
+
+        self.builder.position_at_end(init);
+        let header = [
+            (0, *this),
+            (TASK_STATE_INDEX, i32_type.const_zero().into()),
+            (TASK_MAILBOX_INDEX, *mailbox),
+            (TASK_REPLY_INDEX, *reply_frame),
+            (TASK_REPLY_INDEX + 1, *reply_tag),
+            (TASK_REPLY_INDEX + 2, *reply_result),
+        ];
+        for (index, value) in header {
+            let address = field(&self.builder, frame, index, "header")?;
+            self.builder
+                .build_store(address, value)
+                .map_err(llvm_error)?;
+        }
+        for ((param, value), param_slot) in method.params.iter().zip(args).zip(&param_slots) {
+            let position = slots
+                .iter()
+                .position(|(address, _)| address == param_slot)
+                .ok_or_else(|| {
+                    CodeGenError::Internal(format!("Parameter {} has no slot", param.name))
+                })?;
+            let address = field(
+                &self.builder,
+                frame,
+                TASK_FRAME_HEADER + position as u32,
+                &param.name,
+            )?;
+            self.builder
+                .build_store(address, *value)
+                .map_err(llvm_error)?;
+        }
+        self.builder
+            .build_call(resume_task, &[frame.into()], "")
+            .map_err(llvm_error)?;
+        self.builder.build_return(None).map_err(llvm_error)?;
+
+        // フレームを確保できなければメソッドをその場で実行する
+        self.builder.position_at_end(exhausted);
+        let direct_args: Vec<BasicMetadataValueEnum> = std::iter::once(*this)
+            .chain(args.iter().copied())
+            .map(Into::into)
+            .collect();
+        let value = self
+            .builder
+            .build_call(direct, &direct_args, "result")
+            .map_err(llvm_error)?
+            .try_as_basic_value()
+            .left();
+        self.build_reply(
+            this.into_pointer_value(),
+            mailbox.into_pointer_value(),
+            [*reply_frame, *reply_tag, *reply_result],
+            value,
+        )?;
+        self.builder.build_return(None).map_err(llvm_error)?;
+
+        Ok(())
+    }
+
+    /// `<method>.start` of a task, declared on first use (see
+    /// `compile_task_method`)
+    fn task_start_function(&self, name: &str, direct: FunctionValue<'ctx>) -> FunctionValue<'ctx> {
+        let start_name = format!("{}.start", self.member_symbol(name));
+        self.module.get_function(&start_name).unwrap_or_else(|| {
+            let ptr_type = self.context.ptr_type(AddressSpace::default());
+            let params: Vec<BasicMetadataTypeEnum> = [
+                ptr_type.into(),
+                ptr_type.into(),
+                ptr_type.into(),
+                self.context.i32_type().into(),
+                ptr_type.into(),
+            ]
+            .into_iter()
+            .chain(
+                direct
+                    .get_type()
+                    .get_param_types()
+                    .into_iter()
+                    .skip(1)
+                    .map(Into::into),
+            )
+            .collect();
+            self.module.add_function(
+                &start_name,
+                self.context.void_type().fn_type(&params, false),
+                None,
+            )
+        })
+    }
+
+    /// Hands the result of an awaited call to the task awaiting it, if
+    /// `frame` is not null: `value` is stored at `result` and the frame is
+    /// queued on `mailbox` under `tag`. When the mailbox is full the task is
+    /// resumed in place through the dispatch function. The builder continues
+    /// in a block after the reply.
+    fn build_reply(
+        &self,
+        actor: PointerValue<'ctx>,
+        mailbox: PointerValue<'ctx>,
+        [frame, tag, result]: [BasicValueEnum<'ctx>; 3],
+        value: Option<BasicValueEnum<'ctx>>,
+    ) -> CodeGenResult<()> {
+        let llvm_error = |e: inkwell::builder::BuilderError| CodeGenError::LLVMError(e.to_string());
+        let i32_type = self.context.i32_type();
+        let function = self.current_function()?;
+        let reply = self.context.append_basic_block(function, "reply");
+        let in_place = self.context.append_basic_block(function, "reply.in_place");
+        let done = self.context.append_basic_block(function, "reply.done");
+
+        let awaited = self
+            .builder
+            .build_is_not_null(frame.into_pointer_value(), "awaited")
+            .map_err(llvm_error)?;
+        self.builder
+            .build_conditional_branch(awaited, reply, done)
+            .map_err(llvm_error)?;
+
+        self.builder.position_at_end(reply);
+        if let Some(value) = value {
+            self.builder
+                .build_store(result.into_pointer_value(), value)
+                .map_err(llvm_error)?;
+        }
+        let pushed = self
+            .builder
+            .build_call(
+                self.runtime_function(RuntimeFunction::MailboxPush),
+                &[
+                    mailbox.into(),
+                    tag.into(),
+                    frame.into(),
+                    i32_type.const_zero().into(),
+                ],
+                "reply.pushed",
+            )
+            .map_err(llvm_error)?
+            .try_as_basic_value()
+            .left()
+            .ok_or_else(|| CodeGenError::Internal("__replica_mailbox_push returns void".into()))?
+            .into_int_value();
+        let is_full = self
+            .builder
+            .build_int_compare(IntPredicate::NE, pushed, i32_type.const_zero(), "is_full")
+            .map_err(llvm_error)?;
+        self.builder
+            .build_conditional_branch(is_full, in_place, done)
+            .map_err(llvm_error)?;
+
+        // 満杯なら待っていたタスクをその場で再開する
+        self.builder.position_at_end(in_place);
+        self.builder
+            .build_call(
+                self.dispatch_function(),
+                &[
+                    actor.into(),
+                    tag.into(),
+                    frame.into(),
+                    i32_type.const_zero().into(),
+                    mailbox.into(),
+                ],
+                "",
+            )
+            .map_err(llvm_error)?;
+        self.builder
+            .build_unconditional_branch(done)
+            .map_err(llvm_error)?;
+
+        self.builder.position_at_end(done);
+        Ok(())
+    }
+
+    /// Whether the body being compiled can suspend, so its locals must live in
+    /// slots that are saved across suspensions
+    fn suspendable(&self) -> bool {
+        self.stream.is_some() || self.task.is_some()
+    }
+
+    /// Copies the slots of a stream's `next` or a task's `resume` into the
+    /// frame fields from `header` on (`save`), or back
+    fn copy_frame_slots(
+        &self,
+        frame_type: StructType<'ctx>,
+        frame: PointerValue<'ctx>,
+        header: u32,
+        slots: &[(PointerValue<'ctx>, BasicTypeEnum<'ctx>)],
+        save: bool,
+    ) -> CodeGenResult<()> {
+        let llvm_error = |e: inkwell::builder::BuilderError| CodeGenError::LLVMError(e.to_string());
+        for (index, &(slot, slot_type)) in slots.iter().enumerate() {
+            let field = self
+                .builder
+                .build_struct_gep(frame_type, frame, header + index as u32, "saved_slot")
+                .map_err(llvm_error)?;
+            let (from, to) = if save { (slot, field) } else { (field, slot) };
+            let value = self
+                .builder
+                .build_load(slot_type, from, "slot")
+                .map_err(llvm_error)?;
+            self.builder.build_store(to, value).map_err(llvm_error)?;
+        }
+        Ok(())
+    }
+
+    /// Traps through `__replica_stack_overflow` when fewer than `reserve` bytes of
+    /// stack are left below the current frame. The bottom of the stack is
+    /// `__stack_low`, which `wasm-ld` defines. Leaves the builder in the block
+    /// that continues the function.
+    fn build_stack_guard(&self, function: FunctionValue<'ctx>, reserve: u32) -> CodeGenResult<()> {
+        let ptr_type = self.context.ptr_type(AddressSpace::default());
+        let i8_type = self.context.i8_type();
+        let llvm_error = |e: inkwell::builder::BuilderError| CodeGenError::LLVMError(e.to_string());
+
+        let frame_address = inkwell::intrinsics::Intrinsic::find("llvm.frameaddress")
+            .and_then(|intrinsic| intrinsic.get_declaration(&self.module, &[ptr_type.into()]))
+            .ok_or_else(|| {
+                CodeGenError::Initialization("LLVM does not provide llvm.frameaddress".to_string())
+            })?;
+        let stack_low = self
+            .module
+            .get_global("__stack_low")
+            .unwrap_or_else(|| self.module.add_global(i8_type, None, "__stack_low"));
+
+        let frame = self
+            .builder
+            .build_call(
+                frame_address,
+                &[self.context.i32_type().const_zero().into()],
+                "frame",
+            )
+            .map_err(llvm_error)?
+            .try_as_basic_value()
+            .left()
+            .ok_or_else(|| CodeGenError::Internal("llvm.frameaddress returns void".into()))?
+            .into_pointer_value();
+        // SAFETY: the offset only feeds a comparison and is never dereferenced
+        let limit = unsafe {
+            self.builder.build_gep(
+                i8_type,
+                stack_low.as_pointer_value(),
+                &[self.context.i32_type().const_int(reserve as u64, false)],
+                "stack_limit",
+            )
+        }
+        .map_err(llvm_error)?;
+        let overflow = self
+            .builder
+            .build_int_compare(inkwell::IntPredicate::ULT, frame, limit, "stack_overflow")
+            .map_err(llvm_error)?;
+
+        let overflow_block = self.context.append_basic_block(function, "stack_overflow");
+        let body_block = self.context.append_basic_block(function, "body");
+        self.builder
+            .build_conditional_branch(overflow, overflow_block, body_block)
+            .map_err(llvm_error)?;
+
+        self.builder.position_at_end(overflow_block);
+        self.builder
+            .build_call(
+                self.runtime_function(RuntimeFunction::StackOverflow),
+                &[],
+                "",
+            )
+            .map_err(llvm_error)?;
+        self.builder.build_unreachable().map_err(llvm_error)?;
+
+        self.builder.position_at_end(body_block);
+        Ok(())
+    }
+
+    /// Compiles the actor's `init` block into `<Actor>.init(self, params...)`,
+    /// which `<Actor>.new` calls on the zeroed instance before returning it
+    fn compile_init(&mut self, actor: &Actor) -> CodeGenResult<Option<FunctionValue<'ctx>>> {
+        let Some(init) = &actor.init else {
+            return Ok(None);
+        };
+        self.debug_log(&format!("Compiling init of {}", actor.name));
+
+        let ptr_type = self.context.ptr_type(AddressSpace::default());
+        let param_types: Vec<BasicMetadataTypeEnum> = std::iter::once(Ok(ptr_type.into()))
+            .chain(init.params.iter().map(|param| {
+                self.type_converter
+                    .convert_with_ownership(&param.param_type, &param.ownership)
+                    .map(Into::into)
+            }))
+            .collect::<CodeGenResult<_>>()?;
+        let function = self.module.add_function(
+            &format!("{}.init", actor.name),
+            self.context.void_type().fn_type(&param_types, false),
+            Some(Linkage::Internal),
+        );
+
+        let basic_block = self.context.append_basic_block(function, "entry");
+        self.builder.position_at_end(basic_block);
+
+        let this = function
+            .get_first_param()
+            .ok_or_else(|| CodeGenError::Internal("init has no self parameter".into()))?
+            .into_pointer_value();
+        this.set_name("self");
+        self.bind_instance(actor, this)?;
+        // inout 引数は意味解析で拒否済み
+        for (param, value) in init.params.iter().zip(function.get_param_iter().skip(1)) {
+            value.set_name(&param.name);
+            self.expression_compiler
+                .register_variable(param.name.clone(), value);
+            self.register_array_param(param)?;
+        }
+
+        // return は意味解析で拒否済み
+        self.compile_body(&init.body)?;
+        let terminated = self
+            .builder
+            .get_insert_block()
+            .and_then(|block| block.get_terminator())
+            .is_some();
+        if !terminated {
+            self.builder
+                .build_return(None)
+                .map_err(|e| CodeGenError::LLVMError(e.to_string()))?;
+        }
+
+        Ok(Some(function))
+    }
+
+    /// Compiles the actor's `deinit` block into `<Actor>.deinit(self)`.
+    /// Weak fields are released afterwards so the runtime can free their targets,
+    /// and so is the queue of messages a `@nonreentrant` method deferred. The
+    /// inbox of a subscriber leaves the routing table before it is freed.
+    fn compile_deinit(&mut self, actor: &Actor) -> CodeGenResult<Option<FunctionValue<'ctx>>> {
+        let weak_fields: Vec<u32> = actor
+            .fields
+            .iter()
+            .enumerate()
+            .filter(|(_, field)| matches!(field.ownership, OwnershipType::Weak))
+            .map(|(index, _)| self.type_converter.field_index(&actor.name, index))
+            .collect();
+        let guard = self.reentrancy_guards.get(&actor.name).copied();
+        let inbox = self.inboxes.get(&actor.name).copied();
+        if actor.deinit.is_none() && weak_fields.is_empty() && guard.is_none() && inbox.is_none() {
+            return Ok(None);
+        }
+        self.debug_log(&format!("Compiling deinit of {}", actor.name));
+
+        let ptr_type = self.context.ptr_type(AddressSpace::default());
+        let function_type = self.context.void_type().fn_type(&[ptr_type.into()], false);
+        let function = self.module.add_function(
+            &format!("{}.deinit", actor.name),
+            function_type,
+            Some(Linkage::Internal),
+        );
+
+        let basic_block = self.context.append_basic_block(function, "entry");
+        self.builder.position_at_end(basic_block);
+
+        let actor_type = self
+            .type_converter
+            .convert_to_llvm(&Type::Custom(actor.name.clone()))?
+            .into_struct_type();
+        let this = function
+            .get_first_param()
+            .ok_or_else(|| CodeGenError::Internal("deinit has no self parameter".into()))?
+            .into_pointer_value();
+        self.bind_instance(actor, this)?;
+
+        // return は意味解析で拒否済み
+        for statement in actor.deinit.iter().flat_map(|d| &d.body.statements) {
+            self.compile_statement(statement)?;
+        }
+
+        // weak 参照の解放
+        let weak_release = self.runtime_function(RuntimeFunction::WeakRelease);
+        for index in weak_fields {
+            let slot = self
+                .builder
+                .build_struct_gep(actor_type, this, index, "weak_slot")
+                .map_err(|e| CodeGenError::LLVMError(e.to_string()))?;
+            self.builder
+                .build_call(weak_release, &[slot.into()], "")
+                .map_err(|e| CodeGenError::LLVMError(e.to_string()))?;
+        }
+
+        // 保留中のメッセージは配送されずに捨てられる
+        if let Some(busy_index) = guard {
+            let slot = self
+                .builder
+                .build_struct_gep(actor_type, this, busy_index + 1, "deferred.slot")
+                .map_err(|e| CodeGenError::LLVMError(e.to_string()))?;
+            let queue = self
+                .builder
+                .build_load(ptr_type, slot, "deferred")
+                .map_err(|e| CodeGenError::LLVMError(e.to_string()))?;
+            self.builder
+                .build_call(
+                    self.runtime_function(RuntimeFunction::Free),
+                    &[queue.into()],
+                    "",
+                )
+                .map_err(|e| CodeGenError::LLVMError(e.to_string()))?;
+        }
+
+        // 購読を外してから受信箱を解放する
+        if let Some(inbox_index) = inbox {
+            let slot = self
+                .builder
+                .build_struct_gep(actor_type, this, inbox_index, "inbox.slot")
+                .map_err(|e| CodeGenError::LLVMError(e.to_string()))?;
+            let inbox = self
+                .builder
+                .build_load(ptr_type, slot, "inbox")
+                .map_err(|e| CodeGenError::LLVMError(e.to_string()))?;
+            for function in [RuntimeFunction::Unsubscribe, RuntimeFunction::Free] {
+                self.builder
+                    .build_call(self.runtime_function(function), &[inbox.into()], "")
+                    .map_err(|e| CodeGenError::LLVMError(e.to_string()))?;
+            }
+        }
+
+        self.builder
+            .build_return(None)
+            .map_err(|e| CodeGenError::LLVMError(e.to_string()))?;
+
+        Ok(Some(function))
+    }
+
+    /// Makes the fields of the instance `this` points to readable by the
+    /// expressions compiled next, and passes `this` on to method calls
+    fn bind_instance(&mut self, actor: &Actor, this: PointerValue<'ctx>) -> CodeGenResult<()> {
+        let actor_type = self
+            .type_converter
+            .convert_to_llvm(&Type::Custom(actor.name.clone()))?
+            .into_struct_type();
+
+        self.expression_compiler.clear_variables();
+        self.expression_compiler.set_instance(this);
+        for (position, field) in actor.fields.iter().enumerate() {
+            let index = self.type_converter.field_index(&actor.name, position);
+            let address = self
+                .builder
+                .build_struct_gep(actor_type, this, index, &field.name)
+                .map_err(|e| CodeGenError::LLVMError(e.to_string()))?;
+            let value_type = self
+                .type_converter
+                .convert_with_ownership(&field.field_type, &field.ownership)?;
+            self.expression_compiler.register_field(
+                field.name.clone(),
+                Slot {
+                    address,
+                    value_type,
+                    weak: matches!(field.ownership, OwnershipType::Weak),
+                },
+            );
+            if let Some(element_type) = self.array_element_type(Some(&field.field_type))? {
+                self.expression_compiler
+                    .register_field_array(field.name.clone(), element_type);
+            }
+            if let Some(labels) = record_labels(Some(&field.field_type)) {
+                self.expression_compiler
+                    .register_field_record(field.name.clone(), labels);
+            }
+            // Shared フィールドはアトミック組み込み関数から参照できる
+            if matches!(field.ownership, OwnershipType::Shared) {
+                self.expression_compiler
+                    .register_address(field.name.clone(), address);
+            }
+        }
+        Ok(())
+    }
+
+    /// Compiles `match value { pattern => body ... }` to a chain of pattern
+    /// tests, one per arm in order. Semantic analysis proved the arms
+    /// exhaustive, so falling past the last test is unreachable.
+    fn compile_match(
+        &mut self,
+        value: &Expression,
+        arms: &[(Pattern, MethodBody)],
+    ) -> CodeGenResult<()> {
+        let llvm_error = |e: inkwell::builder::BuilderError| CodeGenError::LLVMError(e.to_string());
+        let value = self.expression_compiler.compile_expression(value)?;
+        let function = self.current_function()?;
+        let merge_block = self.context.append_basic_block(function, "match_end");
+        for (pattern, body) in arms {
+            let (matched, bindings) = self.expression_compiler.test_pattern(pattern, value)?;
+            let arm_block = self.context.append_basic_block(function, "match_arm");
+            let next_block = self.context.append_basic_block(function, "match_next");
+            self.builder
+                .build_conditional_branch(matched, arm_block, next_block)
+                .map_err(llvm_error)?;
+
+            self.builder.position_at_end(arm_block);
+            // 腕の束縛は腕の後で同名の外側の変数を隠さない
+            let scope = self.expression_compiler.save_scope();
+            for (name, value) in bindings {
+                // 中断できる本体では再開後も読めるようスロットに置く
+                if self.suspendable() {
+                    self.bind_slot(name, value)?;
+                } else {
+                    self.expression_compiler.register_variable(name, value);
+                }
+            }
+            self.compile_body(body)?;
+            self.expression_compiler.restore_scope(scope);
+            self.builder
+                .build_unconditional_branch(merge_block)
+                .map_err(llvm_error)?;
+            self.builder.position_at_end(next_block);
+        }
+        self.builder.build_unreachable().map_err(llvm_error)?;
+        self.builder.position_at_end(merge_block);
+        Ok(())
+    }
+
+    /// Compiles a statement at the builder's current position
+    fn compile_statement(&mut self, statement: &Statement) -> CodeGenResult<()> {
+        let llvm_error = |e: inkwell::builder::BuilderError| CodeGenError::LLVMError(e.to_string());
+        // タスクでは文の await を先に済ませ、その結果を読む文に書き換える
+        if self.task.is_some()
+            && statement_expressions(statement)
+                .into_iter()
+                .any(evaluates_await)
+        {
+            return self.compile_awaiting_statement(statement);
+        }
+        match statement {
+            Statement::Expression(Expression::Call { callee, args, .. }) => {
+                self.expression_compiler.compile_call(callee, args)?;
+            }
+            Statement::Expression(Expression::Await(value)) => match value.as_ref() {
+                Expression::Call { callee, args, .. } => {
+                    self.expression_compiler.compile_call(callee, args)?;
+                }
+                value => {
+                    self.expression_compiler.compile_expression(value)?;
+                }
+            },
+            Statement::Expression(Expression::Atomic {
+                operation,
+                field,
+                args,
+                ordering,
+            }) => {
+                self.expression_compiler
+                    .compile_atomic(*operation, field, args, *ordering)?;
+            }
+            Statement::Expression(expr) => {
+                self.expression_compiler.compile_expression(expr)?;
+            }
+            Statement::Let {
+                pattern,
+                is_mutable: true,
+                value,
+            } => self.compile_var(pattern, value)?,
+            // 中断できる本体では再開後も読めるよう let もスロットに置く
+            Statement::Let { pattern, value, .. } if self.suspendable() => {
+                self.compile_var(pattern, value)?
+            }
+            Statement::Let { pattern, value, .. } => {
+                self.expression_compiler.compile_let(pattern, value)?;
+            }
+            Statement::Return(expr) if self.task.is_some() => {
+                let (result, complete) = self
+                    .task
+                    .as_ref()
+                    .map(|task| (task.result, task.complete))
+                    .ok_or_else(|| CodeGenError::Internal("Task state lost".into()))?;
+                match result {
+                    Some((slot, result_type)) => {
+                        let value = self
+                            .expression_compiler
+                            .compile_expected(expr, result_type)?;
+                        self.builder.build_store(slot, value).map_err(llvm_error)?;
+                    }
+                    None => {
+                        self.expression_compiler.compile_expression(expr)?;
+                    }
+                }
+                self.builder
+                    .build_unconditional_branch(complete)
+                    .map_err(llvm_error)?;
+                self.continue_in_unreachable_block("after_return")?;
+            }
+            Statement::Return(expr) => {
+                let value = match self.current_function()?.get_type().get_return_type() {
+                    Some(return_type) => self
+                        .expression_compiler
+                        .compile_expected(expr, return_type)?,
+                    None => self.expression_compiler.compile_expression(expr)?,
+                };
+                self.builder
+                    .build_return(Some(&value))
+                    .map_err(llvm_error)?;
+                self.continue_in_unreachable_block("after_return")?;
+            }
+            Statement::Yield(expr) => {
+                let value = self.expression_compiler.compile_expression(expr)?;
+                let function = self.current_function()?;
+                let suspend = self.context.append_basic_block(function, "suspend");
+                let resume = self.context.append_basic_block(function, "after_yield");
+                let stream = self.stream.as_mut().ok_or_else(|| {
+                    CodeGenError::InvalidOperation("yield outside of a stream method".into())
+                })?;
+                self.builder
+                    .build_store(stream.out, value)
+                    .map_err(llvm_error)?;
+                stream.suspends.push(suspend);
+                stream.resumes.push(resume);
+                self.builder
+                    .build_unconditional_branch(suspend)
+                    .map_err(llvm_error)?;
+                self.builder.position_at_end(resume);
+            }
+            Statement::Break(label) | Statement::Continue(label) => {
+                let is_break = matches!(statement, Statement::Break(_));
+                let target = self
+                    .loop_targets
+                    .iter()
+                    .rev()
+                    .find(|target| label.is_none() || target.label == *label)
+                    .ok_or_else(|| {
+                        CodeGenError::InvalidOperation(format!(
+                            "No enclosing loop for {}",
+                            if is_break { "break" } else { "continue" }
+                        ))
+                    })?;
+                let block = if is_break {
+                    target.break_block
+                } else {
+                    target.continue_block
+                };
+                self.builder
+                    .build_unconditional_branch(block)
+                    .map_err(llvm_error)?;
+                self.continue_in_unreachable_block("after_jump")?;
+            }
+            Statement::If {
+                condition,
+                then_body,
+                else_body,
+            } => {
+                let condition = self
+                    .expression_compiler
+                    .compile_expression(condition)?
+                    .into_int_value();
+                let function = self.current_function()?;
+                let then_block = self.context.append_basic_block(function, "then");
+                let else_block = else_body
+                    .as_ref()
+                    .map(|_| self.context.append_basic_block(function, "else"));
+                let merge_block = self.context.append_basic_block(function, "if_end");
+
+                self.builder
+                    .build_conditional_branch(
+                        condition,
+                        then_block,
+                        else_block.unwrap_or(merge_block),
+                    )
+                    .map_err(llvm_error)?;
+
+                // 各分岐の末尾 (return 後の到達不能ブロックを含む) から合流ブロックへ
+                let branches = std::iter::once((then_block, then_body))
+                    .chain(else_block.zip(else_body.as_ref()));
+                for (block, body) in branches {
+                    self.builder.position_at_end(block);
+                    self.compile_body(body)?;
+                    self.builder
+                        .build_unconditional_branch(merge_block)
+                        .map_err(llvm_error)?;
+                }
+                self.builder.position_at_end(merge_block);
+            }
+            Statement::Match { value, arms } => self.compile_match(value, arms)?,
+            Statement::While {
+                label,
+                condition,
+                body,
+            } => {
+                let function = self.current_function()?;
+                let condition_block = self.context.append_basic_block(function, "while_cond");
+                let body_block = self.context.append_basic_block(function, "while_body");
+                let exit_block = self.context.append_basic_block(function, "while_end");
+
+                self.builder
+                    .build_unconditional_branch(condition_block)
+                    .map_err(llvm_error)?;
+                self.builder.position_at_end(condition_block);
+                let condition = self
+                    .expression_compiler
+                    .compile_expression(condition)?
+                    .into_int_value();
+                self.builder
+                    .build_conditional_branch(condition, body_block, exit_block)
+                    .map_err(llvm_error)?;
+
+                self.builder.position_at_end(body_block);
+                self.compile_loop_body(label, body, exit_block, condition_block)?;
+                self.builder
+                    .build_unconditional_branch(condition_block)
+                    .map_err(llvm_error)?;
+                self.builder.position_at_end(exit_block);
+            }
+            Statement::For {
+                label,
+                variable,
+                start,
+                end,
+                body,
+            } => {
+                // 範囲の両端はループに入る前に一度だけ評価する
+                let start = self
+                    .expression_compiler
+                    .compile_expression(start)?
+                    .into_int_value();
+                let end = self
+                    .expression_compiler
+                    .compile_expression(end)?
+                    .into_int_value();
+                let i32_type = self.context.i32_type();
+                // 中断できる本体では終端も中断をまたいで保存する
+                let end_slot = if self.suspendable() {
+                    let slot = self.entry_alloca(i32_type.into(), "end")?;
+                    self.builder.build_store(slot, end).map_err(llvm_error)?;
+                    Some(slot)
+                } else {
+                    None
+                };
+                let counter = self.entry_alloca(i32_type.into(), variable)?;
+                self.builder
+                    .build_store(counter, start)
+                    .map_err(llvm_error)?;
+
+                let function = self.current_function()?;
+                let condition_block = self.context.append_basic_block(function, "for_cond");
+                let body_block = self.context.append_basic_block(function, "for_body");
+                let step_block = self.context.append_basic_block(function, "for_step");
+                let exit_block = self.context.append_basic_block(function, "for_end");
+
+                self.builder
+                    .build_unconditional_branch(condition_block)
+                    .map_err(llvm_error)?;
+                self.builder.position_at_end(condition_block);
+                let index = self
+                    .builder
+                    .build_load(i32_type, counter, variable)
+                    .map_err(llvm_error)?
+                    .into_int_value();
+                let end = match end_slot {
+                    Some(slot) => self
+                        .builder
+                        .build_load(i32_type, slot, "end")
+                        .map_err(llvm_error)?
+                        .into_int_value(),
+                    None => end,
+                };
+                let in_range = self
+                    .builder
+                    .build_int_compare(IntPredicate::SLT, index, end, "in_range")
+                    .map_err(llvm_error)?;
+                self.builder
+                    .build_conditional_branch(in_range, body_block, exit_block)
+                    .map_err(llvm_error)?;
+
+                self.builder.position_at_end(body_block);
+                let index = self
+                    .builder
+                    .build_load(i32_type, counter, variable)
+                    .map_err(llvm_error)?;
+                // ループ変数はループの後で同名の外側の変数を隠さない
+                let scope = self.expression_compiler.save_scope();
+                if self.suspendable() {
+                    self.expression_compiler.register_slot(
+                        variable.clone(),
+                        Slot {
+                            address: counter,
+                            value_type: i32_type.into(),
+                            weak: false,
+                        },
+                    );
+                } else {
+                    self.expression_compiler
+                        .register_variable(variable.clone(), index);
+                }
+                self.compile_loop_body(label, body, exit_block, step_block)?;
+                self.expression_compiler.restore_scope(scope);
+                self.builder
+                    .build_unconditional_branch(step_block)
+                    .map_err(llvm_error)?;
+
+                self.builder.position_at_end(step_block);
+                let index = self
+                    .builder
+                    .build_load(i32_type, counter, variable)
+                    .map_err(llvm_error)?
+                    .into_int_value();
+                let next = self
+                    .builder
+                    .build_int_add(index, i32_type.const_int(1, false), "next")
+                    .map_err(llvm_error)?;
+                self.builder
+                    .build_store(counter, next)
+                    .map_err(llvm_error)?;
+                self.builder
+                    .build_unconditional_branch(condition_block)
+                    .map_err(llvm_error)?;
+                self.builder.position_at_end(exit_block);
+            }
+            Statement::Log {
+                level,
+                message,
+                line,
+            } => self.compile_log(*level, message, *line)?,
+            Statement::Publish { topic, value } => self.compile_publish(topic, value)?,
+            Statement::Subscribe(topic) => self.compile_subscribe(topic)?,
+            Statement::Assign {
+                target,
+                operator,
+                value,
+            } => self.compile_assign(target, operator, value)?,
+        }
+        Ok(())
+    }
+
+    /// Compiles a statement of a task that evaluates `await`. A `while` whose
+    /// condition awaits checks the condition at the top of its body. Otherwise
+    /// the awaited calls run first, innermost first, after the operands
+    /// evaluated before them, and the statement then reads their results.
+    fn compile_awaiting_statement(&mut self, statement: &Statement) -> CodeGenResult<()> {
+        if let Statement::While {
+            label,
+            condition,
+            body,
+        } = statement
+        {
+            // 条件の await は反復ごとに待つ
+            let check = Statement::If {
+                condition: condition.clone(),
+                then_body: MethodBody {
+                    statements: Vec::new(),
+                },
+                else_body: Some(MethodBody {
+                    statements: vec![Statement::Break(None)],
+                }),
+            };
+            return self.compile_statement(&Statement::While {
+                label: label.clone(),
+                condition: Expression::Literal(LiteralValue::Bool(true)),
+                body: MethodBody {
+                    statements: std::iter::once(check)
+                        .chain(body.statements.iter().cloned())
+                        .collect(),
+                },
+            });
+        }
+
+        let mut statement = statement.clone();
+        // 複合代入は代入先を待つ前に読む
+        if let Statement::Assign {
+            target: target @ (Expression::Variable(_) | Expression::Field(_)),
+            operator,
+            value,
+        } = &mut statement
+        {
+            if let Some(operator) = operator.take() {
+                *value = Expression::BinaryOp {
+                    left: Box::new(target.clone()),
+                    operator,
+                    right: Box::new(std::mem::replace(value, Expression::Nil)),
+                };
+            }
+        }
+        let mut hoisted = Vec::new();
+        hoist_operands(statement_expressions_mut(&mut statement), &mut hoisted);
+        for (name, value) in hoisted {
+            match value {
+                Expression::Await(call) => self.compile_await(name, &call)?,
+                value => self.compile_var(&Pattern::Binding(name), &value)?,
+            }
+        }
+        match &statement {
+            // 結果を捨てる await は待つだけで済む
+            Statement::Expression(Expression::Variable(name)) if name.starts_with("await.") => {
+                Ok(())
+            }
+            statement => self.compile_statement(statement),
+        }
+    }
+
+    /// Compiles `await call` in a task: the arguments are evaluated, the task
+    /// suspends until the call has run, and the result is bound to the local
+    /// `name`. Calls of methods the dispatch function cannot run are made in
+    /// place.
+    fn compile_await(&mut self, name: String, call: &Expression) -> CodeGenResult<()> {
+        let llvm_error = |e: inkwell::builder::BuilderError| CodeGenError::LLVMError(e.to_string());
+        let Expression::Call { callee, args, .. } = call else {
+            return Err(CodeGenError::InvalidOperation(format!(
+                "Only calls of async methods can be awaited, found {}",
+                call
+            )));
+        };
+        let Some((tag, payload_type)) = self.awaited_handler(callee)? else {
+            if let Some(value) = self.expression_compiler.compile_call(callee, args)? {
+                self.bind_slot(name, value)?;
+            }
+            return Ok(());
+        };
+        let function = *self
+            .actor_methods
+            .get(&self.member_symbol(callee))
+            .ok_or_else(|| CodeGenError::Internal(format!("Method {} was not declared", callee)))?;
+
+        let array = self
+            .expression_compiler
+            .array_binding(&Pattern::Binding(name.clone()), call);
+        let record = self
+            .expression_compiler
+            .record_binding(&Pattern::Binding(name.clone()), call);
+        let args = self
+            .expression_compiler
+            .compile_call_arguments(callee, args)?;
+        let result = match function.get_type().get_return_type() {
+            Some(value_type) => {
+                let address = self.entry_alloca(value_type, &name)?;
+                self.expression_compiler.register_slot(
+                    name,
+                    Slot {
+                        address,
+                        value_type,
+                        weak: false,
+                    },
+                );
+                Some(address)
+            }
+            None => None,
+        };
+        if let Some((name, element_type)) = array {
+            self.expression_compiler.register_array(name, element_type);
+        }
+        if let Some((name, labels)) = record {
+            self.expression_compiler.register_record(name, labels);
+        }
+
+        let current = self.current_function()?;
+        let suspend = self.context.append_basic_block(current, "suspend");
+        let resume = self.context.append_basic_block(current, "after_await");
+        self.builder
+            .build_unconditional_branch(suspend)
+            .map_err(llvm_error)?;
+        self.builder.position_at_end(resume);
+        let task = self
+            .task
+            .as_mut()
+            .ok_or_else(|| CodeGenError::InvalidOperation("await outside of a task".into()))?;
+        task.awaits.push(AwaitPoint {
+            suspend,
+            resume,
+            callee: function,
+            args,
+            tag,
+            payload_type,
+            result,
+        });
+        Ok(())
+    }
+
+    /// Message tag and payload struct of the awaited variant of `callee`,
+    /// registered as a handler of the dispatch function on first use. Its
+    /// payload holds the arguments followed by the frame, resume tag and
+    /// result address of the awaiting task. Returns `None` for methods that
+    /// are not async methods of the actor being compiled.
+    fn awaited_handler(&mut self, callee: &str) -> CodeGenResult<Option<(u32, StructType<'ctx>)>> {
+        if let Some(&handler) = self.awaited_tags.get(callee) {
+            return Ok(Some(handler));
+        }
+        let Some(&(task, nonreentrant)) = self.async_methods.get(callee) else {
+            return Ok(None);
+        };
+        let direct = *self
+            .actor_methods
+            .get(&self.member_symbol(callee))
+            .ok_or_else(|| CodeGenError::Internal(format!("Method {} was not declared", callee)))?;
+        let ptr_type = self.context.ptr_type(AddressSpace::default());
+        let field_types: Vec<BasicTypeEnum> = direct
+            .get_type()
+            .get_param_types()
+            .into_iter()
+            .skip(1)
+            .chain([
+                ptr_type.into(),
+                self.context.i32_type().into(),
+                ptr_type.into(),
+            ])
+            .collect();
+        let payload_type = self.context.struct_type(&field_types, false);
+        let function = if task {
+            self.task_start_function(callee, direct)
+        } else {
+            direct
+        };
+        let tag = (self.message_tags.len() + self.continuations.len()) as u32;
+        self.continuations.push(MessageHandler::Call {
+            function,
+            payload_type,
+            nonreentrant,
+            task,
+            reply: true,
+        });
+        self.awaited_tags
+            .insert(callee.to_string(), (tag, payload_type));
+        Ok(Some((tag, payload_type)))
+    }
+
+    /// Compiles `var pattern = value`, giving each bound variable a stack slot
+    /// that assignments store into
+    fn compile_var(&mut self, pattern: &Pattern, value: &Expression) -> CodeGenResult<()> {
+        let array = self.expression_compiler.array_binding(pattern, value);
+        let record = self.expression_compiler.record_binding(pattern, value);
+        let value = self.expression_compiler.compile_expression(value)?;
+        for (name, value) in self.expression_compiler.destructure(pattern, value)? {
+            self.bind_slot(name, value)?;
+        }
+        if let Some((name, element_type)) = array {
+            self.expression_compiler.register_array(name, element_type);
+        }
+        if let Some((name, labels)) = record {
+            self.expression_compiler.register_record(name, labels);
+        }
+        Ok(())
+    }
+
+    /// Stores `value` in a new slot of the entry block and registers it as
+    /// the local `name`
+    fn bind_slot(&mut self, name: String, value: BasicValueEnum<'ctx>) -> CodeGenResult<()> {
+        let value_type = value.get_type();
+        let address = self.entry_alloca(value_type, &name)?;
+        self.builder
+            .build_store(address, value)
+            .map_err(|e| CodeGenError::LLVMError(e.to_string()))?;
+        self.expression_compiler.register_slot(
+            name,
+            Slot {
+                address,
+                value_type,
+                weak: false,
+            },
+        );
+        Ok(())
+    }
+
+    /// Compiles `target = value` or `target op= value`. Locals are stored into
+    /// their slot; fields of the current instance are assigned through the
+    /// field's setter, which takes locks and manages references as needed.
+    fn compile_assign(
+        &mut self,
+        target: &Expression,
+        operator: &Option<Operator>,
+        value: &Expression,
+    ) -> CodeGenResult<()> {
+        // 代入先の型が分かれば、nil や包む前の値をその型で生成できる
+        let setter_type = |field: &str| {
+            self.module
+                .get_function(&format!("{}.set_{}", self.current_actor, field))
+                .and_then(|setter| setter.get_type().get_param_types().get(1).copied())
+        };
+        let expected = match target {
+            Expression::Variable(name) => match self.expression_compiler.slot(name) {
+                Some(slot) => Some(slot.value_type),
+                // 局所変数でなければフィールドへの代入
+                None => setter_type(name),
+            },
+            Expression::Field(name) => setter_type(name),
+            _ => None,
+        };
+        // 複合代入は `target op value` の結果を格納する
+        let value = match operator {
+            Some(operator) => {
+                self.expression_compiler
+                    .compile_expression(&Expression::BinaryOp {
+                        left: Box::new(target.clone()),
+                        operator: operator.clone(),
+                        right: Box::new(value.clone()),
+                    })?
+            }
+            None => match expected {
+                Some(expected) => self.expression_compiler.compile_expected(value, expected)?,
+                None => self.expression_compiler.compile_expression(value)?,
+            },
+        };
+        let field = match target {
+            Expression::Variable(name) => {
+                if let Some(slot) = self.expression_compiler.slot(name) {
+                    self.builder
+                        .build_store(slot.address, value)
+                        .map_err(|e| CodeGenError::LLVMError(e.to_string()))?;
+                    return Ok(());
+                }
+                if self.expression_compiler.has_variable(name) {
+                    return Err(CodeGenError::InvalidOperation(format!(
+                        "Cannot assign to immutable binding {}",
+                        name
+                    )));
+                }
+                name
+            }
+            Expression::Field(name) => name,
+            other => {
+                return Err(CodeGenError::InvalidOperation(format!(
+                    "Cannot assign to {:?}",
+                    other
+                )))
+            }
+        };
+        let setter = self
+            .module
+            .get_function(&format!("{}.set_{}", self.current_actor, field))
+            .ok_or_else(|| {
+                CodeGenError::InvalidOperation(format!(
+                    "{} has no mutable field {}",
+                    self.current_actor, field
+                ))
+            })?;
+        let this = self.expression_compiler.instance().ok_or_else(|| {
+            CodeGenError::Internal(format!("Assignment to {} outside of a method", field))
+        })?;
+        self.builder
+            .build_call(setter, &[this.into(), value.into()], "")
+            .map_err(|e| CodeGenError::LLVMError(e.to_string()))?;
+        Ok(())
+    }
+
+    /// Compiles `log.<level>(message)` to a call of the host's `replica_log`.
+    /// Calls below the configured level are dropped without evaluating the message.
+    fn compile_log(
+        &mut self,
+        level: LogLevel,
+        message: &Expression,
+        line: usize,
+    ) -> CodeGenResult<()> {
+        if level < self.log_level {
+            return Ok(());
+        }
+
+        let message = match message {
+            Expression::Literal(LiteralValue::String(text)) => {
+                self.const_string_descriptor(text, "log.message")?.into()
+            }
+            message => self.expression_compiler.compile_expression(message)?,
+        };
+        let actor = self.const_string_descriptor(&self.current_actor, "log.actor")?;
+        let i32_type = self.context.i32_type();
+        self.builder
+            .build_call(
+                runtime::declare_log_import(self.context, &self.module),
+                &[
+                    i32_type.const_int(level as u64, false).into(),
+                    message.into(),
+                    actor.into(),
+                    i32_type.const_int(line as u64, false).into(),
+                ],
+                "",
+            )
+            .map_err(|e| CodeGenError::LLVMError(e.to_string()))?;
+        Ok(())
+    }
+
+    /// Compiles `publish(topic, value)`: the value is stored in a one-field
+    /// struct, the payload `on<Topic>` unpacks, and handed to the runtime's
+    /// `__replica_publish` or the host's `replica_topic_publish` with its size
+    fn compile_publish(&mut self, topic: &str, value: &Expression) -> CodeGenResult<()> {
+        let llvm_error = |e: inkwell::builder::BuilderError| CodeGenError::LLVMError(e.to_string());
+        let value = self.expression_compiler.compile_expression(value)?;
+        let payload_type = self.context.struct_type(&[value.get_type()], false);
+        let payload = self.entry_alloca(payload_type.into(), "topic.value")?;
+        let slot = self
+            .builder
+            .build_struct_gep(payload_type, payload, 0, "topic.slot")
+            .map_err(llvm_error)?;
+        self.builder.build_store(slot, value).map_err(llvm_error)?;
+        let size = self.alloc_size(payload_type);
+
+        let name = self.topic_name(topic)?;
+        let publish = match self.topic_route {
+            TopicRoute::Runtime => self.runtime_function(RuntimeFunction::Publish),
+            TopicRoute::Transport => runtime::declare_topic_imports(self.context, &self.module).0,
+        };
+        self.builder
+            .build_call(publish, &[name.into(), payload.into(), size.into()], "")
+            .map_err(llvm_error)?;
+        Ok(())
+    }
+
+    /// Compiles `subscribe(topic)`. Through the runtime, the instance's inbox is
+    /// registered with the dispatch function and the message tag of
+    /// `on<Topic>`; through a transport, the host is told the instance and
+    /// delivers values by calling `on<Topic>` itself.
+    fn compile_subscribe(&mut self, topic: &str) -> CodeGenResult<()> {
+        let llvm_error = |e: inkwell::builder::BuilderError| CodeGenError::LLVMError(e.to_string());
+        let this = self.expression_compiler.instance().ok_or_else(|| {
+            CodeGenError::Internal(format!("subscribe({}) outside of a method", topic))
+        })?;
+        let name = self.topic_name(topic)?;
+        if self.topic_route == TopicRoute::Transport {
+            let (_, subscribe) = runtime::declare_topic_imports(self.context, &self.module);
+            self.builder
+                .build_call(subscribe, &[name.into(), this.into()], "")
+                .map_err(llvm_error)?;
+            return Ok(());
+        }
+
+        let handler = Topic::handler_name(topic);
+        let tag = *self.message_tags.get(&handler).ok_or_else(|| {
+            CodeGenError::InvalidOperation(format!(
+                "{} has no method {} to deliver {} to",
+                self.current_actor, handler, topic
+            ))
+        })?;
+        let inbox_index = *self.inboxes.get(&self.current_actor).ok_or_else(|| {
+            CodeGenError::Internal(format!("{} has no inbox", self.current_actor))
+        })?;
+        let actor_type = self
+            .type_converter
+            .convert_to_llvm(&Type::Custom(self.current_actor.clone()))?
+            .into_struct_type();
+        let slot = self
+            .builder
+            .build_struct_gep(actor_type, this, inbox_index, "inbox.slot")
+            .map_err(llvm_error)?;
+        let inbox = self
+            .builder
+            .build_load(
+                self.context.ptr_type(AddressSpace::default()),
+                slot,
+                "inbox",
+            )
+            .map_err(llvm_error)?;
+        let dispatch = self.dispatch_function();
+        self.builder
+            .build_call(
+                self.runtime_function(RuntimeFunction::Subscribe),
+                &[
+                    name.into(),
+                    inbox.into(),
+                    dispatch.as_global_value().as_pointer_value().into(),
+                    self.context.i32_type().const_int(tag as u64, false).into(),
+                ],
                 "",
-                self.optimization_level,
-                RelocMode::Default,
-                CodeModel::Default,
             )
-            .ok_or_else(|| CodeGenError::WasmGen("Failed to create target machine".to_string()))?;
+            .map_err(llvm_error)?;
+        Ok(())
+    }
+
+    /// Address of the `String` descriptor naming `topic`, emitted once per module
+    fn topic_name(&self, topic: &str) -> CodeGenResult<PointerValue<'ctx>> {
+        let name = format!("topic.{}", topic);
+        match self.module.get_global(&name) {
+            Some(global) => Ok(global.as_pointer_value()),
+            None => self.const_string_descriptor(topic, &name),
+        }
+    }
+
+    /// Emits a constant `String` descriptor for `text` and returns its address
+    fn const_string_descriptor(&self, text: &str, name: &str) -> CodeGenResult<PointerValue<'ctx>> {
+        let bytes = self.context.const_string(text.as_bytes(), false);
+        let buffer = self
+            .module
+            .add_global(bytes.get_type(), None, &format!("{}.text", name));
+        buffer.set_initializer(&bytes);
+        buffer.set_constant(true);
+        buffer.set_linkage(Linkage::Private);
+
+        Ok(self.const_descriptor(buffer.as_pointer_value(), text.len(), name))
+    }
+
+    /// Constant `{ ptr, len }` descriptor of the `len` elements at `buffer`,
+    /// which is what both `String` and `[T]` values point to
+    fn const_descriptor(
+        &self,
+        buffer: PointerValue<'ctx>,
+        len: usize,
+        name: &str,
+    ) -> PointerValue<'ctx> {
+        let ptr_type = self.context.ptr_type(AddressSpace::default());
+        let i32_type = self.context.i32_type();
+        let descriptor_type = self
+            .context
+            .struct_type(&[ptr_type.into(), i32_type.into()], false);
+        let descriptor = self.module.add_global(descriptor_type, None, name);
+        descriptor.set_initializer(
+            &descriptor_type
+                .const_named_struct(&[buffer.into(), i32_type.const_int(len as u64, false).into()]),
+        );
+        descriptor.set_constant(true);
+        descriptor.set_linkage(Linkage::Private);
+        descriptor.as_pointer_value()
+    }
+
+    /// Lays out the `const` tables of `actor` in the data segment and makes
+    /// them known to expressions. The entries of a map are sorted by their
+    /// search key for the binary search of `<Actor>.<name>.find`.
+    fn define_constants(&mut self, actor: &Actor) -> CodeGenResult<()> {
+        for constant in &actor.constants {
+            let name = format!("{}.{}", actor.name, constant.name);
+            let source = match &constant.table {
+                ConstantTable::Array {
+                    element_type,
+                    values,
+                } => {
+                    let element_type = self.type_converter.convert_to_llvm(element_type)?;
+                    let buffer = self.const_table(element_type, values, &name)?;
+                    ConstantSource::Array {
+                        descriptor: self.const_descriptor(buffer, values.len(), &name),
+                        element_type,
+                    }
+                }
+                ConstantTable::Map {
+                    key_type,
+                    value_type,
+                    entries,
+                } => {
+                    let mut entries = entries.clone();
+                    entries.sort_by_key(|(key, _)| search_key(key));
+                    let (keys, values): (Vec<_>, Vec<_>) = entries.into_iter().unzip();
+                    let value_type = self.type_converter.convert_to_llvm(value_type)?;
+                    let values_name = format!("{}.values", name);
+                    let values = if values.is_empty() {
+                        // 見つからないときも値を読むので、空の表にも 0 を 1 つ置く
+                        self.private_constant(value_type.array_type(1).const_zero(), &values_name)
+                    } else {
+                        self.const_table(value_type, &values, &values_name)?
+                    };
+                    ConstantSource::Map {
+                        find: self.define_map_search(key_type, &keys, &name)?,
+                        values,
+                        value_type,
+                    }
+                }
+            };
+            self.expression_compiler
+                .register_constant(constant.name.clone(), source);
+        }
+        Ok(())
+    }
+
+    /// Private constant global holding `values` as an array of `element_type`.
+    /// Strings are pointers to constant descriptors of their own.
+    fn const_table(
+        &self,
+        element_type: BasicTypeEnum<'ctx>,
+        values: &[LiteralValue],
+        name: &str,
+    ) -> CodeGenResult<PointerValue<'ctx>> {
+        let mismatch = || {
+            CodeGenError::TypeConversion(format!(
+                "{} holds literals of another type than {}",
+                name, element_type
+            ))
+        };
+        let array = match element_type {
+            BasicTypeEnum::IntType(int_type) => {
+                let elements = values
+                    .iter()
+                    .map(|value| match value {
+                        LiteralValue::Int(value) => Ok(int_type.const_int(*value as u64, true)),
+                        _ => Err(mismatch()),
+                    })
+                    .collect::<CodeGenResult<Vec<_>>>()?;
+                int_type.const_array(&elements)
+            }
+            BasicTypeEnum::FloatType(float_type) => {
+                let elements = values
+                    .iter()
+                    .map(|value| match value {
+                        LiteralValue::Float(value) => Ok(float_type.const_float(*value)),
+                        _ => Err(mismatch()),
+                    })
+                    .collect::<CodeGenResult<Vec<_>>>()?;
+                float_type.const_array(&elements)
+            }
+            BasicTypeEnum::PointerType(pointer_type) => {
+                let elements = values
+                    .iter()
+                    .enumerate()
+                    .map(|(index, value)| match value {
+                        LiteralValue::String(text) => {
+                            self.const_string_descriptor(text, &format!("{}.{}", name, index))
+                        }
+                        _ => Err(mismatch()),
+                    })
+                    .collect::<CodeGenResult<Vec<_>>>()?;
+                pointer_type.const_array(&elements)
+            }
+            _ => return Err(mismatch()),
+        };
+        Ok(self.private_constant(array, &format!("{}.data", name)))
+    }
+
+    /// Defines `<name>.find`, which gives the position of a key among the
+    /// sorted `keys` of a constant map, or -1. A binary search finds the first
+    /// key whose search key is not below the one looked up; keys from there on
+    /// with the same search key are compared until one matches, which takes
+    /// more than one step only for strings whose hashes collide.
+    fn define_map_search(
+        &self,
+        key_type: &Type,
+        keys: &[LiteralValue],
+        name: &str,
+    ) -> CodeGenResult<FunctionValue<'ctx>> {
+        let llvm_error = |e: inkwell::builder::BuilderError| CodeGenError::LLVMError(e.to_string());
+        let i32_type = self.context.i32_type();
+        let i64_type = self.context.i64_type();
+        let key_llvm_type = self.type_converter.convert_to_llvm(key_type)?;
+        let function = self.module.add_function(
+            &format!("{}.find", name),
+            i32_type.fn_type(&[key_llvm_type.into()], false),
+            Some(Linkage::Internal),
+        );
+        let search_keys: Vec<_> = keys
+            .iter()
+            .map(|key| i64_type.const_int(search_key(key), false))
+            .collect();
+        let search_keys = self.private_constant(
+            i64_type.const_array(&search_keys),
+            &format!("{}.search_keys", name),
+        );
+        let is_string = matches!(key_type, Type::String);
+        let strings = if is_string {
+            Some(self.const_table(key_llvm_type, keys, &format!("{}.keys", name))?)
+        } else {
+            None
+        };
+
+        let entry = self.context.append_basic_block(function, "entry");
+        let search = self.context.append_basic_block(function, "search");
+        let step = self.context.append_basic_block(function, "step");
+        let scan = self.context.append_basic_block(function, "scan");
+        let compare = self.context.append_basic_block(function, "compare");
+        let found = self.context.append_basic_block(function, "found");
+        let missing = self.context.append_basic_block(function, "missing");
+        let load_search_key = |index: inkwell::values::IntValue<'ctx>| {
+            // SAFETY: 呼び出し側で index < 要素数を確かめている
+            let address = unsafe {
+                self.builder
+                    .build_in_bounds_gep(i64_type, search_keys, &[index], "search_key_ptr")
+                    .map_err(llvm_error)?
+            };
+            self.builder
+                .build_load(i64_type, address, "search_key")
+                .map(|value| value.into_int_value())
+                .map_err(llvm_error)
+        };
+
+        // 探すキーを 64 ビットにする: String はハッシュ、Int は符号拡張
+        self.builder.position_at_end(entry);
+        let key = function
+            .get_nth_param(0)
+            .ok_or_else(|| CodeGenError::Internal(format!("{}.find has no key", name)))?;
+        let target = if is_string {
+            self.builder
+                .build_call(
+                    self.runtime_function(RuntimeFunction::StringHash),
+                    &[key.into()],
+                    "target",
+                )
+                .map_err(llvm_error)?
+                .try_as_basic_value()
+                .left()
+                .ok_or_else(|| CodeGenError::Internal("__replica_string_hash returns void".into()))?
+                .into_int_value()
+        } else {
+            self.builder
+                .build_int_s_extend(key.into_int_value(), i64_type, "target")
+                .map_err(llvm_error)?
+        };
+        let len = i32_type.const_int(keys.len() as u64, false);
+        self.builder
+            .build_unconditional_branch(search)
+            .map_err(llvm_error)?;
+
+        // [low, high) を半分ずつ狭め、target 以上の最初の位置を low に残す
+        self.builder.position_at_end(search);
+        let low = self
+            .builder
+            .build_phi(i32_type, "low")
+            .map_err(llvm_error)?;
+        let high = self
+            .builder
+            .build_phi(i32_type, "high")
+            .map_err(llvm_error)?;
+        let (low_value, high_value) = (
+            low.as_basic_value().into_int_value(),
+            high.as_basic_value().into_int_value(),
+        );
+        let narrowing = self
+            .builder
+            .build_int_compare(IntPredicate::ULT, low_value, high_value, "narrowing")
+            .map_err(llvm_error)?;
+        self.builder
+            .build_conditional_branch(narrowing, step, scan)
+            .map_err(llvm_error)?;
+
+        self.builder.position_at_end(step);
+        let sum = self
+            .builder
+            .build_int_add(low_value, high_value, "sum")
+            .map_err(llvm_error)?;
+        let middle = self
+            .builder
+            .build_right_shift(sum, i32_type.const_int(1, false), false, "middle")
+            .map_err(llvm_error)?;
+        let below = self
+            .builder
+            .build_int_compare(IntPredicate::ULT, load_search_key(middle)?, target, "below")
+            .map_err(llvm_error)?;
+        let after_middle = self
+            .builder
+            .build_int_add(middle, i32_type.const_int(1, false), "after_middle")
+            .map_err(llvm_error)?;
+        let next_low = self
+            .builder
+            .build_select(below, after_middle, low_value, "next_low")
+            .map_err(llvm_error)?;
+        let next_high = self
+            .builder
+            .build_select(below, high_value, middle, "next_high")
+            .map_err(llvm_error)?;
+        self.builder
+            .build_unconditional_branch(search)
+            .map_err(llvm_error)?;
+        low.add_incoming(&[(&i32_type.const_zero(), entry), (&next_low, step)]);
+        high.add_incoming(&[(&len, entry), (&next_high, step)]);
+
+        // 同じ検索キーの間、キーそのものを比べる
+        self.builder.position_at_end(scan);
+        let index = self
+            .builder
+            .build_phi(i32_type, "index")
+            .map_err(llvm_error)?;
+        let index_value = index.as_basic_value().into_int_value();
+        index.add_incoming(&[(&low_value, search)]);
+        let in_table = self
+            .builder
+            .build_int_compare(IntPredicate::ULT, index_value, len, "in_table")
+            .map_err(llvm_error)?;
+        self.builder
+            .build_conditional_branch(in_table, compare, missing)
+            .map_err(llvm_error)?;
+
+        self.builder.position_at_end(compare);
+        let same_search_key = self
+            .builder
+            .build_int_compare(
+                IntPredicate::EQ,
+                load_search_key(index_value)?,
+                target,
+                "same_search_key",
+            )
+            .map_err(llvm_error)?;
+        match strings {
+            // Int の検索キーはキーそのもの
+            None => {
+                self.builder
+                    .build_conditional_branch(same_search_key, found, missing)
+                    .map_err(llvm_error)?;
+            }
+            Some(strings) => {
+                let check = self.context.append_basic_block(function, "check");
+                let next = self.context.append_basic_block(function, "next");
+                self.builder
+                    .build_conditional_branch(same_search_key, check, missing)
+                    .map_err(llvm_error)?;
+
+                self.builder.position_at_end(check);
+                let ptr_type = self.context.ptr_type(AddressSpace::default());
+                // SAFETY: index < 要素数は scan で確かめている
+                let address = unsafe {
+                    self.builder
+                        .build_in_bounds_gep(ptr_type, strings, &[index_value], "candidate_ptr")
+                        .map_err(llvm_error)?
+                };
+                let candidate = self
+                    .builder
+                    .build_load(ptr_type, address, "candidate")
+                    .map_err(llvm_error)?
+                    .into_pointer_value();
+                let equal = self
+                    .expression_compiler
+                    .compile_string_operation(
+                        &Operator::Equal,
+                        candidate,
+                        key.into_pointer_value(),
+                    )?
+                    .into_int_value();
+                self.builder
+                    .build_conditional_branch(equal, found, next)
+                    .map_err(llvm_error)?;
+
+                self.builder.position_at_end(next);
+                let after_index = self
+                    .builder
+                    .build_int_add(index_value, i32_type.const_int(1, false), "after_index")
+                    .map_err(llvm_error)?;
+                self.builder
+                    .build_unconditional_branch(scan)
+                    .map_err(llvm_error)?;
+                index.add_incoming(&[(&after_index, next)]);
+            }
+        }
+
+        self.builder.position_at_end(found);
+        self.builder
+            .build_return(Some(&index_value))
+            .map_err(llvm_error)?;
+        self.builder.position_at_end(missing);
+        self.builder
+            .build_return(Some(&i32_type.const_all_ones()))
+            .map_err(llvm_error)?;
+        Ok(function)
+    }
+
+    /// Private constant global initialized with `value`
+    fn private_constant(&self, value: ArrayValue<'ctx>, name: &str) -> PointerValue<'ctx> {
+        let global = self.module.add_global(value.get_type(), None, name);
+        global.set_initializer(&value);
+        global.set_constant(true);
+        global.set_linkage(Linkage::Private);
+        global.as_pointer_value()
+    }
+
+    /// Compiles the statements of a block at the builder's current position. The
+    /// block's bindings go out of scope at its end.
+    fn compile_body(&mut self, body: &MethodBody) -> CodeGenResult<()> {
+        let scope = self.expression_compiler.save_scope();
+        let result = body
+            .statements
+            .iter()
+            .try_for_each(|statement| self.compile_statement(statement));
+        self.expression_compiler.restore_scope(scope);
+        result
+    }
+
+    /// Compiles a loop body in which `break` jumps to `exit_block` and `continue`
+    /// to `continue_block`
+    fn compile_loop_body(
+        &mut self,
+        label: &Option<String>,
+        body: &MethodBody,
+        exit_block: BasicBlock<'ctx>,
+        continue_block: BasicBlock<'ctx>,
+    ) -> CodeGenResult<()> {
+        self.push_loop(label.clone(), exit_block, continue_block);
+        let result = self.compile_body(body);
+        self.pop_loop();
+        result
+    }
+
+    /// Function the builder is currently inserting into
+    fn current_function(&self) -> CodeGenResult<FunctionValue<'ctx>> {
+        self.builder
+            .get_insert_block()
+            .and_then(|block| block.get_parent())
+            .ok_or_else(|| CodeGenError::Internal("Builder is not inside a function".into()))
+    }
+
+    /// Allocates a stack slot in the entry block of the current function, so that
+    /// slots for loops are allocated once rather than on every iteration
+    fn entry_alloca(
+        &self,
+        slot_type: BasicTypeEnum<'ctx>,
+        name: &str,
+    ) -> CodeGenResult<PointerValue<'ctx>> {
+        let entry = self
+            .current_function()?
+            .get_first_basic_block()
+            .ok_or_else(|| CodeGenError::Internal("Function has no entry block".into()))?;
+        let builder = self.context.create_builder();
+        match entry.get_first_instruction() {
+            Some(instruction) => builder.position_before(&instruction),
+            None => builder.position_at_end(entry),
+        }
+        builder
+            .build_alloca(slot_type, name)
+            .map_err(|e| CodeGenError::LLVMError(e.to_string()))
+    }
+
+    /// Moves the builder to a fresh block after a terminator, so that any
+    /// (unreachable) statements that follow still have somewhere to go
+    fn continue_in_unreachable_block(&self, name: &str) -> CodeGenResult<()> {
+        let function = self.current_function()?;
+        let block = self.context.append_basic_block(function, name);
+        self.builder.position_at_end(block);
+        Ok(())
+    }
+
+    /// Makes `break`/`continue` inside a loop body jump to the given blocks
+    pub fn push_loop(
+        &mut self,
+        label: Option<String>,
+        break_block: BasicBlock<'ctx>,
+        continue_block: BasicBlock<'ctx>,
+    ) {
+        self.loop_targets.push(LoopTarget {
+            label,
+            break_block,
+            continue_block,
+        });
+    }
+
+    /// Leaves the innermost loop body
+    pub fn pop_loop(&mut self) {
+        self.loop_targets.pop();
+    }
+
+    /// Creates `<Actor>.new(params...)`, which allocates a reference-counted
+    /// instance and runs the actor's `init` on it with the parameters. Returns
+    /// null without running `init` when memory is exhausted. The deinitializer
+    /// is registered with the runtime and runs from `__replica_release`.
+    fn create_instance_constructor(
+        &mut self,
+        actor: &Actor,
+        init: Option<FunctionValue<'ctx>>,
+        deinit: Option<FunctionValue<'ctx>>,
+    ) -> CodeGenResult<()> {
+        let ptr_type = self.context.ptr_type(AddressSpace::default());
+        let name = format!("{}.new", actor.name);
+        let param_types: Vec<BasicMetadataTypeEnum> = init
+            .iter()
+            .flat_map(|init| init.get_type().get_param_types().into_iter().skip(1))
+            .map(BasicMetadataTypeEnum::from)
+            .collect();
+        let function = self
+            .module
+            .add_function(&name, ptr_type.fn_type(&param_types, false), None);
+        self.name_export(&name, &["new"]);
+        self.instance_constructors.push(name);
+
+        let basic_block = self.context.append_basic_block(function, "entry");
+        self.builder.position_at_end(basic_block);
+
+        let size = self.alloc_size(
+            self.type_converter
+                .convert_to_llvm(&Type::Custom(actor.name.clone()))?,
+        );
+        let deinit = deinit
+            .map(|f| f.as_global_value().as_pointer_value())
+            .unwrap_or_else(|| ptr_type.const_null());
+
+        let object_new = self.runtime_function(RuntimeFunction::ObjectNew);
+        let object = self
+            .builder
+            .build_call(object_new, &[size.into(), deinit.into()], "object")
+            .map_err(|e| CodeGenError::LLVMError(e.to_string()))?
+            .try_as_basic_value()
+            .left()
+            .ok_or_else(|| CodeGenError::Internal("__replica_object_new returns void".into()))?;
+        if let Some(&busy_index) = self.reentrancy_guards.get(&actor.name) {
+            self.init_reentrancy_guard(function, object.into_pointer_value(), busy_index)?;
+        }
+        if let Some(&inbox_index) = self.inboxes.get(&actor.name) {
+            self.init_inbox(function, object.into_pointer_value(), inbox_index)?;
+        }
+        if let Some(init) = init {
+            self.run_init(function, init, object.into_pointer_value())?;
+        }
+        self.builder
+            .build_return(Some(&object))
+            .map_err(|e| CodeGenError::LLVMError(e.to_string()))?;
+
+        Ok(())
+    }
+
+    /// Calls `<Actor>.init` with the constructor's parameters once the
+    /// allocation succeeded. Leaves the builder in the block that returns the
+    /// instance, or null.
+    fn run_init(
+        &self,
+        constructor: FunctionValue<'ctx>,
+        init: FunctionValue<'ctx>,
+        object: PointerValue<'ctx>,
+    ) -> CodeGenResult<()> {
+        let llvm_error = |e: inkwell::builder::BuilderError| CodeGenError::LLVMError(e.to_string());
+        let allocated = self.context.append_basic_block(constructor, "init");
+        let done = self.context.append_basic_block(constructor, "initialized");
+        let failed = self
+            .builder
+            .build_is_null(object, "alloc_failed")
+            .map_err(llvm_error)?;
+        self.builder
+            .build_conditional_branch(failed, done, allocated)
+            .map_err(llvm_error)?;
+
+        self.builder.position_at_end(allocated);
+        let args: Vec<BasicMetadataValueEnum> = std::iter::once(object.into())
+            .chain(constructor.get_param_iter().map(Into::into))
+            .collect();
+        self.builder
+            .build_call(init, &args, "")
+            .map_err(llvm_error)?;
+        self.builder
+            .build_unconditional_branch(done)
+            .map_err(llvm_error)?;
+
+        self.builder.position_at_end(done);
+        Ok(())
+    }
+
+    /// Defines `<Actor>.register`, which registers the constructors of the actor
+    /// and its pools with the runtime's registry under their names, and adds
+    /// it to the module constructors after those of the imported modules. The
+    /// registry creates instances without arguments, so an actor whose `init`
+    /// takes parameters is only created through its exported `<Actor>.new`.
+    fn define_actor_init(&mut self, actor: &Actor) -> CodeGenResult<()> {
+        let llvm_error = |e: inkwell::builder::BuilderError| CodeGenError::LLVMError(e.to_string());
+        let name = format!("{}.register", actor.name);
+        let function = self.module.add_function(
+            &name,
+            self.context.void_type().fn_type(&[], false),
+            Some(Linkage::Internal),
+        );
+        let basic_block = self.context.append_basic_block(function, "entry");
+        self.builder.position_at_end(basic_block);
+
+        let register = self.runtime_function(RuntimeFunction::RegisterActor);
+        let names = std::iter::once(actor.name.as_str())
+            .chain(actor.pools.iter().map(|pool| pool.name.as_str()));
+        for name in names {
+            let constructor = self
+                .module
+                .get_function(&format!("{}.new", name))
+                .ok_or_else(|| {
+                    CodeGenError::Internal(format!("Constructor of {} is not defined", name))
+                })?;
+            if constructor.count_params() > 0 {
+                continue;
+            }
+            let text = self.const_string_descriptor(name, &format!("{}.registry_name", name))?;
+            self.builder
+                .build_call(
+                    register,
+                    &[
+                        text.into(),
+                        constructor.as_global_value().as_pointer_value().into(),
+                    ],
+                    "",
+                )
+                .map_err(llvm_error)?;
+        }
+        self.builder.build_return(None).map_err(llvm_error)?;
+
+        self.register_constructor(ACTOR_INIT_PRIORITY + self.init_depth, &name)
+    }
+
+    /// Clears the busy word of a new instance and allocates the mailbox the
+    /// dispatch function defers messages to while one of its `@nonreentrant`
+    /// methods runs. A failed allocation leaves the queue null, so the first
+    /// deferral traps. Leaves the builder in the block that continues the
+    /// constructor.
+    fn init_reentrancy_guard(
+        &self,
+        constructor: FunctionValue<'ctx>,
+        object: PointerValue<'ctx>,
+        busy_index: u32,
+    ) -> CodeGenResult<()> {
+        let llvm_error = |e: inkwell::builder::BuilderError| CodeGenError::LLVMError(e.to_string());
+        let actor_type = self
+            .type_converter
+            .convert_to_llvm(&Type::Custom(self.current_actor.clone()))?
+            .into_struct_type();
+        let allocated = self.context.append_basic_block(constructor, "allocated");
+        let ready = self.context.append_basic_block(constructor, "ready");
+
+        let is_null = self
+            .builder
+            .build_is_null(object, "is_null")
+            .map_err(llvm_error)?;
+        self.builder
+            .build_conditional_branch(is_null, ready, allocated)
+            .map_err(llvm_error)?;
+
+        self.builder.position_at_end(allocated);
+        // 解放されたばかりの領域を再利用することがあるので明示的に初期化する
+        let busy = self
+            .builder
+            .build_struct_gep(actor_type, object, busy_index, "busy")
+            .map_err(llvm_error)?;
+        self.builder
+            .build_store(busy, self.context.i32_type().const_zero())
+            .map_err(llvm_error)?;
+        let capacity = self.context.i32_type().const_int(DEFERRED_CAPACITY, false);
+        let queue = self
+            .builder
+            .build_call(
+                self.runtime_function(RuntimeFunction::MailboxNew),
+                &[object.into(), capacity.into()],
+                "deferred",
+            )
+            .map_err(llvm_error)?
+            .try_as_basic_value()
+            .left()
+            .ok_or_else(|| CodeGenError::Internal("__replica_mailbox_new returns void".into()))?;
+        let slot = self
+            .builder
+            .build_struct_gep(actor_type, object, busy_index + 1, "deferred.slot")
+            .map_err(llvm_error)?;
+        self.builder.build_store(slot, queue).map_err(llvm_error)?;
+        self.builder
+            .build_unconditional_branch(ready)
+            .map_err(llvm_error)?;
+
+        self.builder.position_at_end(ready);
+        Ok(())
+    }
+
+    /// Expands a `pool` into a router actor: `<Pool>.new()` creates the
+    /// instances of the pooled actor with a mailbox each, and `<Pool>.<method>`
+    /// forwards a call of every async method to the `.post` entry point of the
+    /// instance the pool's strategy picks. The router's state is
+    /// `{ [n x ptr] instances, [n x ptr] mailboxes, i32 next }`.
+    fn compile_pool(&mut self, actor: &Actor, pool: &Pool) -> CodeGenResult<()> {
+        let ptr_type = self.context.ptr_type(AddressSpace::default());
+        let i32_type = self.context.i32_type();
+        let members = ptr_type.array_type(pool.size);
+        let router_type = self.context.opaque_struct_type(&pool.name);
+        router_type.set_body(&[members.into(), members.into(), i32_type.into()], false);
+
+        let deinit = self.define_pool_deinit(pool, router_type)?;
+        self.define_pool_constructor(actor, pool, router_type, deinit)?;
+        for method in actor
+            .methods
+            .iter()
+            .filter(|method| method.is_async && method.stream_item().is_none())
+        {
+            self.define_pool_route(pool, router_type, method)?;
+        }
+        Ok(())
+    }
+
+    /// Creates `<Pool>.deinit`, which frees the mailboxes and releases the
+    /// instances. Messages still queued for them are never delivered.
+    fn define_pool_deinit(
+        &self,
+        pool: &Pool,
+        router_type: StructType<'ctx>,
+    ) -> CodeGenResult<FunctionValue<'ctx>> {
+        let llvm_error = |e: inkwell::builder::BuilderError| CodeGenError::LLVMError(e.to_string());
+        let ptr_type = self.context.ptr_type(AddressSpace::default());
+        let function = self.module.add_function(
+            &format!("{}.deinit", pool.name),
+            self.context.void_type().fn_type(&[ptr_type.into()], false),
+            Some(Linkage::Internal),
+        );
+        let entry = self.context.append_basic_block(function, "entry");
+        self.builder.position_at_end(entry);
+        let router = function
+            .get_nth_param(0)
+            .ok_or_else(|| CodeGenError::Internal("deinit has no instance parameter".into()))?
+            .into_pointer_value();
+
+        // 解放は生成時と逆の順序で、インスタンスより先にメールボックスを捨てる
+        for index in 0..pool.size {
+            let slot_index = self.context.i32_type().const_int(index as u64, false);
+            for (field, function, name) in [
+                (1, RuntimeFunction::Free, "mailbox"),
+                (0, RuntimeFunction::Release, "instance"),
+            ] {
+                let slot = self.pool_member(router_type, router, field, slot_index)?;
+                let member = self
+                    .builder
+                    .build_load(ptr_type, slot, name)
+                    .map_err(llvm_error)?;
+                self.builder
+                    .build_call(self.runtime_function(function), &[member.into()], "")
+                    .map_err(llvm_error)?;
+            }
+        }
+        self.builder.build_return(None).map_err(llvm_error)?;
+        Ok(function)
+    }
+
+    /// Creates `<Pool>.new()`. It returns null, releasing whatever it had
+    /// created, when the router, an instance or a mailbox cannot be allocated.
+    fn define_pool_constructor(
+        &mut self,
+        actor: &Actor,
+        pool: &Pool,
+        router_type: StructType<'ctx>,
+        deinit: FunctionValue<'ctx>,
+    ) -> CodeGenResult<()> {
+        let llvm_error = |e: inkwell::builder::BuilderError| CodeGenError::LLVMError(e.to_string());
+        let ptr_type = self.context.ptr_type(AddressSpace::default());
+        let i32_type = self.context.i32_type();
+        let name = format!("{}.new", pool.name);
+        let function = self
+            .module
+            .add_function(&name, ptr_type.fn_type(&[], false), None);
+        let export_name = self.export_naming.export_name(&name, &pool.name, &["new"]);
+        self.export_names.insert(name.clone(), export_name);
+        self.instance_constructors.push(name);
+
+        let entry = self.context.append_basic_block(function, "entry");
+        let allocated = self.context.append_basic_block(function, "allocated");
+        let failed = self.context.append_basic_block(function, "failed");
+        let ready = self.context.append_basic_block(function, "ready");
+        self.builder.position_at_end(entry);
+
+        let size = self.alloc_size(router_type);
+        let router = self
+            .builder
+            .build_call(
+                self.runtime_function(RuntimeFunction::ObjectNew),
+                &[
+                    size.into(),
+                    deinit.as_global_value().as_pointer_value().into(),
+                ],
+                "router",
+            )
+            .map_err(llvm_error)?
+            .try_as_basic_value()
+            .left()
+            .ok_or_else(|| CodeGenError::Internal("__replica_object_new returns void".into()))?
+            .into_pointer_value();
+        let is_null = self
+            .builder
+            .build_is_null(router, "is_null")
+            .map_err(llvm_error)?;
+        self.builder
+            .build_conditional_branch(is_null, ready, allocated)
+            .map_err(llvm_error)?;
+
+        self.builder.position_at_end(allocated);
+        let instance_new = self
+            .module
+            .get_function(&format!("{}.new", actor.name))
+            .ok_or_else(|| CodeGenError::Internal(format!("{}.new is not defined", actor.name)))?;
+        let capacity = i32_type.const_int(POOL_MAILBOX_CAPACITY, false);
+        let mut missing = self.context.bool_type().const_zero();
+        for index in 0..pool.size {
+            let instance = self
+                .builder
+                .build_call(instance_new, &[], "instance")
+                .map_err(llvm_error)?
+                .try_as_basic_value()
+                .left()
+                .ok_or_else(|| CodeGenError::Internal(format!("{}.new returns void", actor.name)))?
+                .into_pointer_value();
+            let mailbox = self
+                .builder
+                .build_call(
+                    self.runtime_function(RuntimeFunction::MailboxNew),
+                    &[instance.into(), capacity.into()],
+                    "mailbox",
+                )
+                .map_err(llvm_error)?
+                .try_as_basic_value()
+                .left()
+                .ok_or_else(|| CodeGenError::Internal("__replica_mailbox_new returns void".into()))?
+                .into_pointer_value();
+            let slot_index = i32_type.const_int(index as u64, false);
+            for (field, member) in [(0, instance), (1, mailbox)] {
+                let slot = self.pool_member(router_type, router, field, slot_index)?;
+                self.builder.build_store(slot, member).map_err(llvm_error)?;
+                let is_null = self
+                    .builder
+                    .build_is_null(member, "is_null")
+                    .map_err(llvm_error)?;
+                missing = self
+                    .builder
+                    .build_or(missing, is_null, "missing")
+                    .map_err(llvm_error)?;
+            }
+        }
+        let next = self
+            .builder
+            .build_struct_gep(router_type, router, 2, "next")
+            .map_err(llvm_error)?;
+        self.builder
+            .build_store(next, i32_type.const_zero())
+            .map_err(llvm_error)?;
+        self.builder
+            .build_conditional_branch(missing, failed, ready)
+            .map_err(llvm_error)?;
+
+        // 作れた分は router の deinit が解放する
+        self.builder.position_at_end(failed);
+        self.builder
+            .build_call(
+                self.runtime_function(RuntimeFunction::Release),
+                &[router.into()],
+                "",
+            )
+            .map_err(llvm_error)?;
+        self.builder
+            .build_unconditional_branch(ready)
+            .map_err(llvm_error)?;
+
+        self.builder.position_at_end(ready);
+        let result = self
+            .builder
+            .build_phi(ptr_type, "result")
+            .map_err(llvm_error)?;
+        result.add_incoming(&[
+            (&ptr_type.const_null(), entry),
+            (&router, allocated),
+            (&ptr_type.const_null(), failed),
+        ]);
+        self.builder
+            .build_return(Some(&result.as_basic_value()))
+            .map_err(llvm_error)?;
+        Ok(())
+    }
+
+    /// Creates `<Pool>.<method>(router, params...) -> i32`, which queues the
+    /// call on the instance the pool's strategy picks and returns the result of
+    /// its `<method>.post`
+    fn define_pool_route(
+        &mut self,
+        pool: &Pool,
+        router_type: StructType<'ctx>,
+        method: &Method,
+    ) -> CodeGenResult<()> {
+        let llvm_error = |e: inkwell::builder::BuilderError| CodeGenError::LLVMError(e.to_string());
+        let i32_type = self.context.i32_type();
+        let post = self
+            .module
+            .get_function(&format!("{}.post", self.member_symbol(&method.name)))
+            .ok_or_else(|| {
+                CodeGenError::Internal(format!("{}.post is not defined", method.name))
+            })?;
+        let member = bare_name(&method.name);
+        let name = format!("{}.{}", pool.name, member);
+        // ルーターはメールボックスの代わりに自身を受け取るだけで、.post と同じ型
+        let function = self.module.add_function(&name, post.get_type(), None);
+        let export_name = self.export_naming.export_name(&name, &pool.name, &[member]);
+        self.export_names.insert(name.clone(), export_name);
+        self.async_entry_points.push(name);
+
+        let entry = self.context.append_basic_block(function, "entry");
+        self.builder.position_at_end(entry);
+        let params = function.get_params();
+        let router = params[0].into_pointer_value();
+        router.set_name("router");
+        let buckets = i32_type.const_int(pool.size as u64, false);
+
+        let index = match &pool.strategy {
+            PoolStrategy::RoundRobin => {
+                let next = self
+                    .builder
+                    .build_struct_gep(router_type, router, 2, "next")
+                    .map_err(llvm_error)?;
+                let turn = self
+                    .builder
+                    .build_atomicrmw(
+                        AtomicRMWBinOp::Add,
+                        next,
+                        i32_type.const_int(1, false),
+                        AtomicOrdering::Monotonic,
+                    )
+                    .map_err(llvm_error)?;
+                self.builder
+                    .build_int_unsigned_rem(turn, buckets, "index")
+                    .map_err(llvm_error)?
+            }
+            PoolStrategy::ConsistentHash(key) => {
+                let position = method
+                    .params
+                    .iter()
+                    .position(|param| param.name == *key)
+                    .ok_or_else(|| {
+                        CodeGenError::InvalidOperation(format!(
+                            "Pool {} routes by {}, but {} has no parameter {}",
+                            pool.name, key, method.name, key
+                        ))
+                    })?;
+                self.jump_hash(
+                    &method.params[position].param_type,
+                    params[position + 1],
+                    buckets,
+                )?
+            }
+        };
+
+        // どちらの戦略でも添字はプールの大きさ未満
+        let slot = self.pool_member(router_type, router, 1, index)?;
+        let mailbox = self
+            .builder
+            .build_load(
+                self.context.ptr_type(AddressSpace::default()),
+                slot,
+                "mailbox",
+            )
+            .map_err(llvm_error)?;
+        let args: Vec<_> = std::iter::once(mailbox.into())
+            .chain(params[1..].iter().map(|&param| param.into()))
+            .collect();
+        let queued = self
+            .builder
+            .build_call(post, &args, "queued")
+            .map_err(llvm_error)?
+            .try_as_basic_value()
+            .left()
+            .ok_or_else(|| CodeGenError::Internal(format!("{}.post returns void", method.name)))?;
+        self.builder
+            .build_return(Some(&queued))
+            .map_err(llvm_error)?;
+        Ok(())
+    }
+
+    /// Address of slot `index` of the router's instances (`field` 0) or
+    /// mailboxes (`field` 1). `index` must be below the pool's size.
+    fn pool_member(
+        &self,
+        router_type: StructType<'ctx>,
+        router: PointerValue<'ctx>,
+        field: u32,
+        index: inkwell::values::IntValue<'ctx>,
+    ) -> CodeGenResult<PointerValue<'ctx>> {
+        let i32_type = self.context.i32_type();
+        let members_type = router_type
+            .get_field_type_at_index(field)
+            .ok_or_else(|| CodeGenError::Internal(format!("Router has no field {}", field)))?;
+        let members = self
+            .builder
+            .build_struct_gep(router_type, router, field, "members")
+            .map_err(|e| CodeGenError::LLVMError(e.to_string()))?;
+        // SAFETY: the caller keeps `index` below the array length
+        unsafe {
+            self.builder.build_in_bounds_gep(
+                members_type,
+                members,
+                &[i32_type.const_zero(), index],
+                "member",
+            )
+        }
+        .map_err(|e| CodeGenError::LLVMError(e.to_string()))
+    }
+
+    /// Allocates the mailbox topic values are queued on for a new instance. A
+    /// failed allocation leaves it null, so subscribing traps. Leaves the
+    /// builder in the block that continues the constructor.
+    fn init_inbox(
+        &self,
+        constructor: FunctionValue<'ctx>,
+        object: PointerValue<'ctx>,
+        inbox_index: u32,
+    ) -> CodeGenResult<()> {
+        let llvm_error = |e: inkwell::builder::BuilderError| CodeGenError::LLVMError(e.to_string());
+        let actor_type = self
+            .type_converter
+            .convert_to_llvm(&Type::Custom(self.current_actor.clone()))?
+            .into_struct_type();
+        let allocated = self
+            .context
+            .append_basic_block(constructor, "inbox.allocate");
+        let ready = self.context.append_basic_block(constructor, "inbox.ready");
+
+        let is_null = self
+            .builder
+            .build_is_null(object, "is_null")
+            .map_err(llvm_error)?;
+        self.builder
+            .build_conditional_branch(is_null, ready, allocated)
+            .map_err(llvm_error)?;
+
+        self.builder.position_at_end(allocated);
+        let capacity = self.context.i32_type().const_int(INBOX_CAPACITY, false);
+        let inbox = self
+            .builder
+            .build_call(
+                self.runtime_function(RuntimeFunction::MailboxNew),
+                &[object.into(), capacity.into()],
+                "inbox",
+            )
+            .map_err(llvm_error)?
+            .try_as_basic_value()
+            .left()
+            .ok_or_else(|| CodeGenError::Internal("__replica_mailbox_new returns void".into()))?;
+        let slot = self
+            .builder
+            .build_struct_gep(actor_type, object, inbox_index, "inbox.slot")
+            .map_err(llvm_error)?;
+        self.builder.build_store(slot, inbox).map_err(llvm_error)?;
+        self.builder
+            .build_unconditional_branch(ready)
+            .map_err(llvm_error)?;
+
+        self.builder.position_at_end(ready);
+        Ok(())
+    }
+
+    /// Picks one of `buckets` for the value of a pool or shard key with
+    /// `__replica_jump_hash`, after reducing it to 64 bits: a `String` with
+    /// `__replica_string_hash`, an `Int` by sign extension
+    fn jump_hash(
+        &self,
+        key_type: &Type,
+        value: inkwell::values::BasicValueEnum<'ctx>,
+        buckets: inkwell::values::IntValue<'ctx>,
+    ) -> CodeGenResult<inkwell::values::IntValue<'ctx>> {
+        let llvm_error = |e: inkwell::builder::BuilderError| CodeGenError::LLVMError(e.to_string());
+        let bits = match key_type {
+            Type::String => self
+                .builder
+                .build_call(
+                    self.runtime_function(RuntimeFunction::StringHash),
+                    &[value.into()],
+                    "key",
+                )
+                .map_err(llvm_error)?
+                .try_as_basic_value()
+                .left()
+                .ok_or_else(|| CodeGenError::Internal("__replica_string_hash returns void".into()))?
+                .into_int_value(),
+            _ => self
+                .builder
+                .build_int_s_extend(value.into_int_value(), self.context.i64_type(), "key")
+                .map_err(llvm_error)?,
+        };
+        Ok(self
+            .builder
+            .build_call(
+                self.runtime_function(RuntimeFunction::JumpHash),
+                &[bits.into(), buckets.into()],
+                "index",
+            )
+            .map_err(llvm_error)?
+            .try_as_basic_value()
+            .left()
+            .ok_or_else(|| CodeGenError::Internal("__replica_jump_hash returns void".into()))?
+            .into_int_value())
+    }
+
+    /// Creates `<method>.remote(params...) -> i32`, the proxy that sends a call
+    /// to another node through the host, with the arguments packed as
+    /// `<method>.post` packs them. A shard key picks one of the nodes
+    /// `replica_shard_nodes()` reports as a consistent-hash pool picks an
+    /// instance; without one the node is -1, left to the host. Plain sharded
+    /// calls go to `replica_shard_send`. Calls of `@idempotent` or `@retry`
+    /// methods go to `replica_remote_send` with a key from
+    /// `replica_idempotency_key()` (0 when not idempotent), and a `@retry`
+    /// policy sends again after `replica_retry_wait` while the host reports a
+    /// failure, doubling the wait each time. The payload lives on the stack, so
+    /// the host copies it before returning. Returns what the last send returned.
+    fn define_remote_proxy(&mut self, method: &Method) -> CodeGenResult<()> {
+        let llvm_error = |e: inkwell::builder::BuilderError| CodeGenError::LLVMError(e.to_string());
+        let i32_type = self.context.i32_type();
+        let i64_type = self.context.i64_type();
+        let (tag, payload_type, post) = self.remote_message(method)?;
+
+        // メールボックスを除けば .post と同じ引数
+        let params: Vec<BasicMetadataTypeEnum> = post
+            .get_type()
+            .get_param_types()
+            .into_iter()
+            .skip(1)
+            .map(|ty| ty.into())
+            .collect();
+        let name = format!("{}.remote", self.member_symbol(&method.name));
+        let function = self
+            .module
+            .add_function(&name, i32_type.fn_type(&params, false), None);
+        self.name_export(&name, &[bare_name(&method.name), "remote"]);
+        self.async_entry_points.push(name);
+
+        let entry = self.context.append_basic_block(function, "entry");
+        self.builder.position_at_end(entry);
+        let args = function.get_params();
+        for (param, value) in method.params.iter().zip(&args) {
+            value.set_name(&param.name);
+        }
+
+        let node = match &method.shard_key {
+            Some(key) => {
+                let position = method
+                    .params
+                    .iter()
+                    .position(|param| param.name == *key)
+                    .ok_or_else(|| {
+                        CodeGenError::InvalidOperation(format!(
+                            "Method {} is sharded by {}, but has no parameter {}",
+                            method.name, key, key
+                        ))
+                    })?;
+                let (nodes, _) = runtime::declare_shard_imports(self.context, &self.module);
+                let count = self
+                    .builder
+                    .build_call(nodes, &[], "nodes")
+                    .map_err(llvm_error)?
+                    .try_as_basic_value()
+                    .left()
+                    .ok_or_else(|| {
+                        CodeGenError::Internal("replica_shard_nodes returns void".into())
+                    })?
+                    .into_int_value();
+                self.jump_hash(&method.params[position].param_type, args[position], count)?
+            }
+            // シャードキーがなければノードはホストが選ぶ
+            None => i32_type.const_all_ones(),
+        };
+
+        let (payload, len) = if args.is_empty() {
+            (
+                self.context.ptr_type(AddressSpace::default()).const_null(),
+                i32_type.const_zero(),
+            )
+        } else {
+            let payload = self
+                .builder
+                .build_alloca(payload_type, "payload")
+                .map_err(llvm_error)?;
+            for (index, (param, value)) in method.params.iter().zip(&args).enumerate() {
+                let slot = self
+                    .builder
+                    .build_struct_gep(payload_type, payload, index as u32, &param.name)
+                    .map_err(llvm_error)?;
+                self.builder.build_store(slot, *value).map_err(llvm_error)?;
+            }
+            (payload, self.alloc_size(payload_type))
+        };
+        let tag = i32_type.const_int(tag as u64, false);
+
+        if method.retry.is_none() && !method.is_idempotent {
+            let (_, send) = runtime::declare_shard_imports(self.context, &self.module);
+            let sent = self
+                .builder
+                .build_call(
+                    send,
+                    &[node.into(), tag.into(), payload.into(), len.into()],
+                    "sent",
+                )
+                .map_err(llvm_error)?
+                .try_as_basic_value()
+                .left()
+                .ok_or_else(|| CodeGenError::Internal("replica_shard_send returns void".into()))?;
+            self.builder.build_return(Some(&sent)).map_err(llvm_error)?;
+            return Ok(());
+        }
+
+        // 再試行しても同じ鍵を送るので、受信側は重複を捨てられる
+        let key = if method.is_idempotent {
+            let next_key = runtime::declare_remote_import(
+                self.context,
+                &self.module,
+                runtime::IDEMPOTENCY_KEY_IMPORT,
+            );
+            self.builder
+                .build_call(next_key, &[], "key")
+                .map_err(llvm_error)?
+                .try_as_basic_value()
+                .left()
+                .ok_or_else(|| {
+                    CodeGenError::Internal("replica_idempotency_key returns void".into())
+                })?
+                .into_int_value()
+        } else {
+            i64_type.const_zero()
+        };
+        let send =
+            runtime::declare_remote_import(self.context, &self.module, runtime::REMOTE_SEND_IMPORT);
+        let send_args: [BasicMetadataValueEnum; 5] = [
+            node.into(),
+            tag.into(),
+            key.into(),
+            payload.into(),
+            len.into(),
+        ];
+        let call_send = |builder: &Builder<'ctx>| {
+            builder
+                .build_call(send, &send_args, "sent")
+                .map_err(llvm_error)?
+                .try_as_basic_value()
+                .left()
+                .map(|sent| sent.into_int_value())
+                .ok_or_else(|| CodeGenError::Internal("replica_remote_send returns void".into()))
+        };
+
+        let Some(retry) = method.retry else {
+            let sent = call_send(&self.builder)?;
+            self.builder.build_return(Some(&sent)).map_err(llvm_error)?;
+            return Ok(());
+        };
+        let attempt_block = self.context.append_basic_block(function, "attempt");
+        let retry_block = self.context.append_basic_block(function, "retry");
+        let done_block = self.context.append_basic_block(function, "done");
+        self.builder
+            .build_unconditional_branch(attempt_block)
+            .map_err(llvm_error)?;
+
+        self.builder.position_at_end(attempt_block);
+        let attempt = self
+            .builder
+            .build_phi(i32_type, "attempt")
+            .map_err(llvm_error)?;
+        let delay = self
+            .builder
+            .build_phi(i32_type, "delay")
+            .map_err(llvm_error)?;
+        let sent = call_send(&self.builder)?;
+        let failed = self
+            .builder
+            .build_int_compare(IntPredicate::NE, sent, i32_type.const_zero(), "failed")
+            .map_err(llvm_error)?;
+        let retries_left = self
+            .builder
+            .build_int_compare(
+                IntPredicate::ULT,
+                attempt.as_basic_value().into_int_value(),
+                i32_type.const_int(retry.times as u64, false),
+                "retries_left",
+            )
+            .map_err(llvm_error)?;
+        let again = self
+            .builder
+            .build_and(failed, retries_left, "again")
+            .map_err(llvm_error)?;
+        self.builder
+            .build_conditional_branch(again, retry_block, done_block)
+            .map_err(llvm_error)?;
+
+        self.builder.position_at_end(retry_block);
+        let wait =
+            runtime::declare_remote_import(self.context, &self.module, runtime::RETRY_WAIT_IMPORT);
+        self.builder
+            .build_call(wait, &[delay.as_basic_value().into()], "")
+            .map_err(llvm_error)?;
+        let next_attempt = self
+            .builder
+            .build_int_add(
+                attempt.as_basic_value().into_int_value(),
+                i32_type.const_int(1, false),
+                "next_attempt",
+            )
+            .map_err(llvm_error)?;
+        let next_delay = self
+            .builder
+            .build_int_mul(
+                delay.as_basic_value().into_int_value(),
+                i32_type.const_int(2, false),
+                "next_delay",
+            )
+            .map_err(llvm_error)?;
+        self.builder
+            .build_unconditional_branch(attempt_block)
+            .map_err(llvm_error)?;
+
+        let zero = i32_type.const_zero();
+        let backoff = i32_type.const_int(retry.backoff_ms as u64, false);
+        attempt.add_incoming(&[(&zero, entry), (&next_attempt, retry_block)]);
+        delay.add_incoming(&[(&backoff, entry), (&next_delay, retry_block)]);
+
+        self.builder.position_at_end(done_block);
+        self.builder.build_return(Some(&sent)).map_err(llvm_error)?;
+        Ok(())
+    }
+
+    /// Creates `<method>.deliver(mailbox: ptr, key: i64, payload: ptr, len:
+    /// i32) -> i32`, which queues a call another node sent through
+    /// `<method>.remote` by unpacking the payload into `<method>.post`. It
+    /// returns -2 without queueing when `len` is not the size of the payload.
+    /// For an `@idempotent` method it returns 1 without queueing when a call
+    /// with `key` was already delivered, and records `key` once the call is
+    /// queued; otherwise `key` is ignored. Returns what `<method>.post` returned.
+    fn define_delivery(&mut self, method: &Method) -> CodeGenResult<()> {
+        let llvm_error = |e: inkwell::builder::BuilderError| CodeGenError::LLVMError(e.to_string());
+        let i32_type = self.context.i32_type();
+        let ptr_type = self.context.ptr_type(AddressSpace::default());
+        let (_, payload_type, post) = self.remote_message(method)?;
+
+        let name = format!("{}.deliver", self.member_symbol(&method.name));
+        let function = self.module.add_function(
+            &name,
+            i32_type.fn_type(
+                &[
+                    ptr_type.into(),
+                    self.context.i64_type().into(),
+                    ptr_type.into(),
+                    i32_type.into(),
+                ],
+                false,
+            ),
+            None,
+        );
+        self.name_export(&name, &[bare_name(&method.name), "deliver"]);
+        self.async_entry_points.push(name);
+        let [mailbox, key, payload, len] = [0, 1, 2, 3].map(|index| {
+            function
+                .get_nth_param(index)
+                .expect("deliver takes four parameters")
+        });
+        mailbox.set_name("mailbox");
+        key.set_name("key");
+        payload.set_name("payload");
+        len.set_name("len");
+
+        let entry = self.context.append_basic_block(function, "entry");
+        let malformed = self.context.append_basic_block(function, "malformed");
+        let unpack = self.context.append_basic_block(function, "unpack");
+        self.builder.position_at_end(entry);
+        let size = if method.params.is_empty() {
+            i32_type.const_zero()
+        } else {
+            self.alloc_size(payload_type)
+        };
+        let fits = self
+            .builder
+            .build_int_compare(IntPredicate::EQ, len.into_int_value(), size, "fits")
+            .map_err(llvm_error)?;
+        self.builder
+            .build_conditional_branch(fits, unpack, malformed)
+            .map_err(llvm_error)?;
+        self.builder.position_at_end(malformed);
+        self.builder
+            .build_return(Some(&i32_type.const_int(-2i64 as u64, true)))
+            .map_err(llvm_error)?;
+
+        self.builder.position_at_end(unpack);
+        if method.is_idempotent {
+            let duplicate = self.context.append_basic_block(function, "duplicate");
+            let fresh = self.context.append_basic_block(function, "fresh");
+            let seen = self
+                .builder
+                .build_call(
+                    self.runtime_function(RuntimeFunction::IdempotencySeen),
+                    &[key.into()],
+                    "seen",
+                )
+                .map_err(llvm_error)?
+                .try_as_basic_value()
+                .left()
+                .ok_or_else(|| {
+                    CodeGenError::Internal("__replica_idempotency_seen returns void".into())
+                })?
+                .into_int_value();
+            let is_duplicate = self
+                .builder
+                .build_int_compare(
+                    IntPredicate::NE,
+                    seen,
+                    i32_type.const_zero(),
+                    "is_duplicate",
+                )
+                .map_err(llvm_error)?;
+            self.builder
+                .build_conditional_branch(is_duplicate, duplicate, fresh)
+                .map_err(llvm_error)?;
+            self.builder.position_at_end(duplicate);
+            self.builder
+                .build_return(Some(&i32_type.const_int(1, false)))
+                .map_err(llvm_error)?;
+            self.builder.position_at_end(fresh);
+        }
+
+        let mut args: Vec<BasicMetadataValueEnum> = vec![mailbox.into()];
+        for (index, param) in method.params.iter().enumerate() {
+            let slot = self
+                .builder
+                .build_struct_gep(
+                    payload_type,
+                    payload.into_pointer_value(),
+                    index as u32,
+                    &param.name,
+                )
+                .map_err(llvm_error)?;
+            let field_type = payload_type
+                .get_field_type_at_index(index as u32)
+                .ok_or_else(|| {
+                    CodeGenError::Internal(format!("Payload of {} is too short", method.name))
+                })?;
+            let value = self
+                .builder
+                .build_load(field_type, slot, &param.name)
+                .map_err(llvm_error)?;
+            args.push(value.into());
+        }
+        let status = self
+            .builder
+            .build_call(post, &args, "status")
+            .map_err(llvm_error)?
+            .try_as_basic_value()
+            .left()
+            .ok_or_else(|| CodeGenError::Internal(format!("{}.post returns void", method.name)))?
+            .into_int_value();
+        if method.is_idempotent {
+            // 積めなかった呼び出しは再送を受け付けるよう記録しない
+            let record = self.context.append_basic_block(function, "record");
+            let done = self.context.append_basic_block(function, "done");
+            let queued = self
+                .builder
+                .build_int_compare(IntPredicate::EQ, status, i32_type.const_zero(), "queued")
+                .map_err(llvm_error)?;
+            self.builder
+                .build_conditional_branch(queued, record, done)
+                .map_err(llvm_error)?;
+            self.builder.position_at_end(record);
+            self.builder
+                .build_call(
+                    self.runtime_function(RuntimeFunction::IdempotencyRecord),
+                    &[key.into()],
+                    "",
+                )
+                .map_err(llvm_error)?;
+            self.builder
+                .build_unconditional_branch(done)
+                .map_err(llvm_error)?;
+            self.builder.position_at_end(done);
+        }
+        self.builder
+            .build_return(Some(&status))
+            .map_err(llvm_error)?;
+        Ok(())
+    }
+
+    /// Creates `<Actor>.deliver(mailbox: ptr, version: i32, tag: i32, key:
+    /// i64, payload: ptr, len: i32) -> i32` for a versioned actor, which hands
+    /// a call from a client of API `version` to the `<method>.deliver` that
+    /// `tag` named in that version and returns what it returned. It returns -3
+    /// for a version the actor never had and -4 for a tag naming no method
+    /// clients of the version can call.
+    fn define_versioned_delivery(&mut self, actor: &Actor) -> CodeGenResult<()> {
+        let llvm_error = |e: inkwell::builder::BuilderError| CodeGenError::LLVMError(e.to_string());
+        let i32_type = self.context.i32_type();
+        let ptr_type = self.context.ptr_type(AddressSpace::default());
+
+        let name = format!("{}.deliver", actor.name);
+        let function = self.module.add_function(
+            &name,
+            i32_type.fn_type(
+                &[
+                    ptr_type.into(),
+                    i32_type.into(),
+                    i32_type.into(),
+                    self.context.i64_type().into(),
+                    ptr_type.into(),
+                    i32_type.into(),
+                ],
+                false,
+            ),
+            None,
+        );
+        self.name_export(&name, &["deliver"]);
+        self.async_entry_points.push(name);
+        let [mailbox, version, tag, key, payload, len] = [0, 1, 2, 3, 4, 5].map(|index| {
+            function
+                .get_nth_param(index)
+                .expect("versioned deliver takes six parameters")
+        });
+        for (value, name) in [
+            (mailbox, "mailbox"),
+            (version, "version"),
+            (tag, "tag"),
+            (key, "key"),
+            (payload, "payload"),
+            (len, "len"),
+        ] {
+            value.set_name(name);
+        }
+
+        let entry = self.context.append_basic_block(function, "entry");
+        let unknown_version = self.context.append_basic_block(function, "unknown_version");
+        let unsupported = self.context.append_basic_block(function, "unsupported");
+        let versions = versioning::api_versions(actor);
+        let version_blocks: Vec<_> = versions
+            .iter()
+            .map(|api| {
+                (
+                    i32_type.const_int(api.version as u64, false),
+                    self.context
+                        .append_basic_block(function, &format!("version.{}", api.version)),
+                )
+            })
+            .collect();
+        self.builder.position_at_end(entry);
+        self.builder
+            .build_switch(version.into_int_value(), unknown_version, &version_blocks)
+            .map_err(llvm_error)?;
+
+        for (api, (_, block)) in versions.iter().zip(&version_blocks) {
+            self.builder.position_at_end(*block);
+            // その版のクライアントが送るタグで、今のメソッドの受信口を選ぶ
+            let method_blocks: Vec<_> = api
+                .methods
+                .iter()
+                .map(|(method, tag)| {
+                    (
+                        i32_type.const_int(*tag as u64, false),
+                        self.context.append_basic_block(
+                            function,
+                            &format!("version.{}.{}", api.version, bare_name(method)),
+                        ),
+                    )
+                })
+                .collect();
+            self.builder
+                .build_switch(tag.into_int_value(), unsupported, &method_blocks)
+                .map_err(llvm_error)?;
+
+            for ((method, _), (_, call)) in api.methods.iter().zip(&method_blocks) {
+                self.builder.position_at_end(*call);
+                let deliver = self
+                    .module
+                    .get_function(&format!("{}.deliver", self.member_symbol(method)))
+                    .ok_or_else(|| {
+                        CodeGenError::Internal(format!("{}.deliver is not defined", method))
+                    })?;
+                let status = self
+                    .builder
+                    .build_call(
+                        deliver,
+                        &[mailbox.into(), key.into(), payload.into(), len.into()],
+                        "status",
+                    )
+                    .map_err(llvm_error)?
+                    .try_as_basic_value()
+                    .left()
+                    .ok_or_else(|| {
+                        CodeGenError::Internal(format!("{}.deliver returns void", method))
+                    })?;
+                self.builder
+                    .build_return(Some(&status))
+                    .map_err(llvm_error)?;
+            }
+        }
+
+        self.builder.position_at_end(unknown_version);
+        self.builder
+            .build_return(Some(&i32_type.const_int(-3i64 as u64, true)))
+            .map_err(llvm_error)?;
+        self.builder.position_at_end(unsupported);
+        self.builder
+            .build_return(Some(&i32_type.const_int(-4i64 as u64, true)))
+            .map_err(llvm_error)?;
+        Ok(())
+    }
+
+    /// Message tag, payload struct and `<method>.post` of a method called
+    /// through `<method>.remote`
+    fn remote_message(
+        &self,
+        method: &Method,
+    ) -> CodeGenResult<(u32, StructType<'ctx>, FunctionValue<'ctx>)> {
+        let tag = *self
+            .message_tags
+            .get(bare_name(&method.name))
+            .ok_or_else(|| {
+                CodeGenError::InvalidOperation(format!(
+                    "Method {} is called remotely, but is not queued as a message",
+                    method.name
+                ))
+            })?;
+        let MessageHandler::Call { payload_type, .. } = self.message_handlers[tag as usize] else {
+            return Err(CodeGenError::Internal(format!(
+                "{} is not queued as a call",
+                method.name
+            )));
+        };
+        let post = self
+            .module
+            .get_function(&format!("{}.post", self.member_symbol(&method.name)))
+            .ok_or_else(|| {
+                CodeGenError::Internal(format!("{}.post is not defined", method.name))
+            })?;
+        Ok((tag, payload_type, post))
+    }
+
+    /// Bytes `__replica_alloc` is asked for to hold a value of `llvm_type`,
+    /// as an `i32` sized with the target's data layout
+    fn alloc_size(&self, llvm_type: impl BasicType<'ctx>) -> inkwell::values::IntValue<'ctx> {
+        self.context
+            .i32_type()
+            .const_int(self.type_converter.abi_size(llvm_type).into(), false)
+    }
+
+    /// Appends the sharded methods of the actor to the `replica.sharding`
+    /// custom section read by cluster tooling
+    fn embed_sharding(&self, actor: &Actor) -> CodeGenResult<()> {
+        let methods = sharding::sharded_methods(actor);
+        if methods.is_empty() {
+            return Ok(());
+        }
+
+        let section = self.context.metadata_node(&[
+            self.context
+                .metadata_string(sharding::SHARDING_SECTION)
+                .into(),
+            self.context
+                .metadata_string(&sharding::section(&methods))
+                .into(),
+        ]);
+        self.module
+            .add_global_metadata("wasm.custom_sections", &section)
+            .map_err(|e| CodeGenError::LLVMError(e.to_string()))
+    }
+
+    /// Appends the API versions of the actor to the `replica.versions` custom
+    /// section read by hosts translating calls from older clients
+    fn embed_versions(&self, actor: &Actor) -> CodeGenResult<()> {
+        let section = self.context.metadata_node(&[
+            self.context
+                .metadata_string(versioning::VERSIONS_SECTION)
+                .into(),
+            self.context
+                .metadata_string(&versioning::section(&versioning::api_versions(actor)))
+                .into(),
+        ]);
+        self.module
+            .add_global_metadata("wasm.custom_sections", &section)
+            .map_err(|e| CodeGenError::LLVMError(e.to_string()))
+    }
+
+    /// Appends the actor's HTTP routes to the `replica.routes` custom section.
+    /// Sections with the same name are concatenated when modules are linked.
+    fn embed_routes(&self, actor: &Actor) -> CodeGenResult<()> {
+        let (routes, _) = http::routes(actor);
+        if routes.is_empty() {
+            return Ok(());
+        }
+
+        let section = self.context.metadata_node(&[
+            self.context.metadata_string(http::ROUTES_SECTION).into(),
+            self.context
+                .metadata_string(&http::routing_table(&routes))
+                .into(),
+        ]);
+        self.module
+            .add_global_metadata("wasm.custom_sections", &section)
+            .map_err(|e| CodeGenError::LLVMError(e.to_string()))
+    }
+
+    /// Appends the capabilities the actor's imports require to the
+    /// `replica.capabilities` custom section
+    fn embed_capabilities(&self, actor: &Actor) -> CodeGenResult<()> {
+        let requirements = capability::requirements(actor);
+        if requirements.is_empty() {
+            return Ok(());
+        }
+
+        let section = self.context.metadata_node(&[
+            self.context
+                .metadata_string(capability::CAPABILITIES_SECTION)
+                .into(),
+            self.context
+                .metadata_string(&capability::section(&requirements))
+                .into(),
+        ]);
+        self.module
+            .add_global_metadata("wasm.custom_sections", &section)
+            .map_err(|e| CodeGenError::LLVMError(e.to_string()))
+    }
+
+    /// Appends where the fields of the actor's instance live to the
+    /// `replica.state` custom section read by hosts reloading the module
+    fn embed_state(&self, actor: &Actor) -> CodeGenResult<()> {
+        let struct_type = self
+            .type_converter
+            .convert_to_llvm(&Type::Custom(actor.name.clone()))?
+            .into_struct_type();
+        let target_layout = self.type_converter.target_layout(struct_type);
+        let fields: Vec<(u32, u32)> = (0..actor.fields.len())
+            .map(|field| {
+                let element = &target_layout.elements
+                    [self.type_converter.field_index(&actor.name, field) as usize];
+                (element.offset, element.size)
+            })
+            .collect();
+
+        let section = self.context.metadata_node(&[
+            self.context.metadata_string(reload::STATE_SECTION).into(),
+            self.context
+                .metadata_string(&reload::section(&[reload::state_schema(actor, &fields)]))
+                .into(),
+        ]);
+        self.module
+            .add_global_metadata("wasm.custom_sections", &section)
+            .map_err(|e| CodeGenError::LLVMError(e.to_string()))
+    }
+
+    /// Generates a WebAssembly module by linking the object file, and the
+    /// runtime library if one is configured, with the external linker
+    pub fn emit_wasm(&self) -> CodeGenResult<Vec<u8>> {
+        let object = self.emit_object()?;
+        self.link_options.link(&object, &self.exported_symbols())
+    }
+
+    /// Prints the linked WebAssembly module in the text format
+    pub fn emit_wat(&self) -> CodeGenResult<Vec<u8>> {
+        Ok(super::wat::print(&self.emit_wasm()?)?.into_bytes())
+    }
+
+    /// Prints the module as textual LLVM IR
+    pub fn emit_ir_text(&self) -> CodeGenResult<Vec<u8>> {
+        self.apply_symbol_visibility();
+        Ok(self.module.print_to_string().to_bytes().to_vec())
+    }
+
+    /// Generates the artifact selected by `kind`
+    pub fn emit(&self, kind: super::EmitKind) -> CodeGenResult<Vec<u8>> {
+        match kind {
+            super::EmitKind::Wasm => self.emit_wasm(),
+            super::EmitKind::Wat => self.emit_wat(),
+            super::EmitKind::Object => self.emit_object(),
+            super::EmitKind::LlvmIr => self.emit_ir_text(),
+            super::EmitKind::LlvmBitcode => self.emit_bitcode(),
+            super::EmitKind::StaticLib => self.emit_static_lib(),
+            super::EmitKind::Layout => self.emit_layout(),
+            // バインディングは型付き AST から生成し、LLVM モジュールを経由しない
+            super::EmitKind::PyBindings | super::EmitKind::OpenApi => Err(CodeGenError::Internal(
+                format!("{} output is generated from the typed AST", kind),
+            )),
+            super::EmitKind::Ast | super::EmitKind::Tokens => Err(CodeGenError::Internal(format!(
+                "{} output is generated by the front end",
+                kind
+            ))),
+        }
+    }
+
+    /// Prints the layout the target gives the state struct of each actor and
+    /// each declared struct and enum: the offset, size and alignment of every
+    /// field, and the padding between them
+    pub fn emit_layout(&self) -> CodeGenResult<Vec<u8>> {
+        let mut text = String::new();
+        let padding = |text: &mut String, offset: u32, size: u32| {
+            text.push_str(&format!("  {:>6}  {:>4}         (padding)\n", offset, size));
+        };
+        for source in &self.layouts {
+            let layout = self.type_converter.target_layout(source.struct_type);
+            if !text.is_empty() {
+                text.push('\n');
+            }
+            text.push_str(&format!(
+                "{} {}: size {}, align {}\n  offset  size  align  field\n",
+                source.kind, source.name, layout.size, layout.align
+            ));
+            let mut end = 0;
+            for (element, label) in layout.elements.iter().zip(&source.labels) {
+                if element.offset > end {
+                    padding(&mut text, end, element.offset - end);
+                }
+                match label {
+                    Some(label) => text.push_str(&format!(
+                        "  {:>6}  {:>4}  {:>5}  {}\n",
+                        element.offset, element.size, element.align, label
+                    )),
+                    None => padding(&mut text, element.offset, element.size),
+                }
+                end = element.offset + element.size;
+            }
+            if layout.size > end {
+                padding(&mut text, end, layout.size - end);
+            }
+        }
+        Ok(text.into_bytes())
+    }
+
+    /// Generates LLVM bitcode for the module
+    pub fn emit_bitcode(&self) -> CodeGenResult<Vec<u8>> {
+        self.apply_symbol_visibility();
+        Ok(self.module.write_bitcode_to_memory().as_slice().to_vec())
+    }
+
+    /// Generates an `ar` archive holding the module's object file and a symbol index
+    pub fn emit_static_lib(&self) -> CodeGenResult<Vec<u8>> {
+        let object = self.emit_object()?;
+        let name = format!("{}.o", self.module.get_name().to_string_lossy());
+
+        Ok(archive::write_archive(&[archive::ArchiveMember {
+            name: &name,
+            data: &object,
+            symbols: self.exported_symbols(),
+        }]))
+    }
+
+    /// Links another generator's module into this one, consuming it.
+    /// Both generators must share the same context.
+    pub fn link(&mut self, other: CodeGenerator<'ctx>) -> CodeGenResult<()> {
+        let linked_methods: Vec<String> = other.actor_methods.keys().cloned().collect();
+        self.instance_constructors
+            .extend(other.instance_constructors.iter().cloned());
+        self.async_entry_points
+            .extend(other.async_entry_points.iter().cloned());
+        self.layouts.extend(other.layouts.iter().cloned());
+        self.stream_entry_points
+            .extend(other.stream_entry_points.iter().cloned());
+        self.export_names.extend(other.export_names.clone());
+        for constructor in &other.constructors {
+            // 両方が定義するメモリ方針のコンストラクタは一度だけ呼ぶ
+            if !self.constructors.contains(constructor) {
+                self.constructors.push(constructor.clone());
+            }
+        }
+
+        self.module
+            .link_in_module(other.module)
+            .map_err(|e| CodeGenError::LLVMError(format!("Failed to link modules: {}", e)))?;
+        self.define_global_ctors()?;
+
+        // Function handles of the consumed module are re-resolved in the linked one
+        for name in linked_methods {
+            let function = self.module.get_function(&name).ok_or_else(|| {
+                CodeGenError::Internal(format!("Method '{}' lost while linking", name))
+            })?;
+            self.actor_methods.insert(name, function);
+        }
+
+        Ok(())
+    }
+
+    /// Runs the link-time optimization pipeline selected in the options.
+    /// Non-exported functions are internalized first so unused ones are removed.
+    pub fn run_lto(&self) -> CodeGenResult<()> {
+        let level = match self.optimization_level {
+            OptimizationLevel::None | OptimizationLevel::Less => "O1",
+            OptimizationLevel::Default => "O2",
+            OptimizationLevel::Aggressive => "O3",
+        };
+        let pipeline = match self.lto {
+            super::LtoMode::Off => return Ok(()),
+            super::LtoMode::Thin => format!("thinlto<{}>", level),
+            super::LtoMode::Full => format!("lto<{}>", level),
+        };
+
+        self.apply_symbol_visibility();
+        let target_machine = self.create_target_machine()?;
+        self.module
+            .run_passes(&pipeline, &target_machine, PassBuilderOptions::create())
+            .map_err(|e| CodeGenError::LLVMError(format!("LTO pipeline failed: {}", e)))
+    }
+
+    fn create_target_machine(&self) -> CodeGenResult<TargetMachine> {
+        let target = Target::from_triple(&self.target_triple)
+            .map_err(|e| CodeGenError::WasmGen(format!("Failed to create target: {}", e)))?;
+
+        target
+            .create_target_machine(
+                &self.target_triple,
+                &self.cpu,
+                &self.target_features,
+                self.optimization_level,
+                RelocMode::Default,
+                CodeModel::Default,
+            )
+            .ok_or_else(|| CodeGenError::WasmGen("Failed to create target machine".to_string()))
+    }
+
+    /// Generates a relocatable object file for the target
+    pub fn emit_object(&self) -> CodeGenResult<Vec<u8>> {
+        self.apply_symbol_visibility();
+        let target_machine = self.create_target_machine()?;
+
+        // WASSMバイトコードの生成
+        target_machine
+            .write_to_memory_buffer(&self.module, FileType::Object)
+            .map(|buffer| buffer.as_slice().to_vec())
+            .map_err(|e| CodeGenError::WasmGen(format!("Failed to emit WASM: {}", e)))
+    }
+
+    /// Size and alignment the selected target gives values of `ty`. Structs,
+    /// enums and actors are known once the actor declaring them is compiled.
+    pub fn type_layout(&self, ty: &Type) -> CodeGenResult<TypeLayout> {
+        self.type_converter.type_layout(ty)
+    }
+
+    /// Declares a runtime routine for use by generated code
+    pub fn runtime_function(&self, function: RuntimeFunction) -> FunctionValue<'ctx> {
+        function.declare(self.context, &self.module)
+    }
+
+    /// Names of the symbols this module exports to external linkers
+    pub fn exported_symbols(&self) -> Vec<String> {
+        let mut symbols: Vec<String> = self
+            .actor_methods
+            .keys()
+            .chain(&self.instance_constructors)
+            .chain(&self.async_entry_points)
+            .chain(&self.stream_entry_points)
+            .cloned()
+            .collect();
+        symbols.sort();
+        symbols
+    }
+
+    /// Gives actor methods, instance constructors and async entry points external
+    /// linkage and default visibility, and hides every other function defined in the module (see `docs/ABI.md`)
+    fn apply_symbol_visibility(&self) {
+        let mut function = self.module.get_first_function();
+        while let Some(f) = function {
+            let is_definition = f.count_basic_blocks() > 0;
+            let name = f.get_name().to_string_lossy();
+            let global = f.as_global_value();
+
+            if self.actor_methods.contains_key(name.as_ref())
+                || self.instance_constructors.iter().any(|c| *c == name)
+                || self.async_entry_points.iter().any(|p| *p == name)
+                || self.stream_entry_points.iter().any(|p| *p == name)
+            {
+                f.set_linkage(Linkage::External);
+                global.set_visibility(GlobalVisibility::Default);
+                // --export を渡さずにリンクしてもエクスポート節に載る
+                if let Some(export_name) = self.export_names.get(name.as_ref()) {
+                    f.add_attribute(
+                        AttributeLoc::Function,
+                        self.context
+                            .create_string_attribute("wasm-export-name", export_name),
+                    );
+                }
+            } else if is_definition
+                && name != runtime::ABI_HANDSHAKE_EXPORT
+                && name != runtime::INIT_EXPORT
+            {
+                // ハンドシェイクと初期化の関数はリンクされる全オブジェクトで共有するので内部化しない
+                f.set_linkage(Linkage::Internal);
+            }
+
+            function = f.get_next_function();
+        }
+    }
+
+    /// Records the name `symbol`, the member `parts` of the actor being
+    /// compiled, is exported under
+    fn name_export(&mut self, symbol: &str, parts: &[&str]) {
+        let export_name = self
+            .export_naming
+            .export_name(symbol, &self.current_actor, parts);
+        self.export_names.insert(symbol.to_string(), export_name);
+    }
+
+    /// Symbol of the member `name` (a method, or a namespaced one like
+    /// `math.length`) of the actor being compiled, e.g. `Counter.add`. Entry
+    /// points append to it, so actors linked into one module never clash.
+    fn member_symbol(&self, name: &str) -> String {
+        format!("{}.{}", self.current_actor, bare_name(name))
+    }
+
+    /// Verifies the generated module
+    fn verify_module(&self) -> CodeGenResult<()> {
+        self.module
+            .verify()
+            .map_err(|e| CodeGenError::Validation(format!("Module verification failed: {}", e)))
+    }
+
+    // Helper methods for debugging
+    fn debug_log(&self, message: &str) {
+        if self.debug_mode {
+            eprintln!("[CodeGen Debug] {}", message);
+        }
+    }
+
+    // Private helper methods for method compilation
+    /// Function type of a method: the instance it runs on, then its parameters
+    fn create_method_type(
+        &self,
+        method: &Method,
+    ) -> CodeGenResult<inkwell::types::FunctionType<'ctx>> {
+        let function_type = self.create_function_type(&method.params, &method.return_type)?;
+        let ptr_type = self.context.ptr_type(AddressSpace::default());
+        let param_types: Vec<_> = std::iter::once(ptr_type.into())
+            .chain(
+                function_type
+                    .get_param_types()
+                    .into_iter()
+                    .map(BasicMetadataTypeEnum::from),
+            )
+            .collect();
+        Ok(match function_type.get_return_type() {
+            Some(return_type) => return_type.fn_type(&param_types, false),
+            None => self.context.void_type().fn_type(&param_types, false),
+        })
+    }
+
+    fn create_function_type(
+        &self,
+        params: &[Parameter],
+        return_type: &Option<Type>,
+    ) -> CodeGenResult<inkwell::types::FunctionType<'ctx>> {
+        let param_types = params
+            .iter()
+            .map(|param| {
+                self.type_converter
+                    .convert_with_ownership(&param.param_type, &param.ownership)
+                    .map(Into::into)
+            })
+            .collect::<CodeGenResult<Vec<_>>>()?;
+
+        match return_type {
+            Some(return_type) => Ok(self
+                .type_converter
+                .convert_to_llvm(return_type)?
+                .fn_type(&param_types, false)),
+            None => Ok(self.context.void_type().fn_type(&param_types, false)),
+        }
+    }
+
+    /// Binds the instance and the parameters of `function` for its body. `inout`
+    /// parameters are read through the pointer at every use.
+    fn process_method_parameters(
+        &mut self,
+        method: &Method,
+        function: FunctionValue<'ctx>,
+        actor: &Actor,
+    ) -> CodeGenResult<()> {
+        let this = function
+            .get_first_param()
+            .ok_or_else(|| {
+                CodeGenError::Internal(format!("Method {} has no self parameter", method.name))
+            })?
+            .into_pointer_value();
+        this.set_name("self");
+        self.bind_instance(actor, this)?;
+
+        for (param, value) in method.params.iter().zip(function.get_param_iter().skip(1)) {
+            value.set_name(&param.name);
+            if matches!(param.ownership, OwnershipType::Inout) {
+                let value_type = self.type_converter.convert_to_llvm(&param.param_type)?;
+                self.expression_compiler.register_slot(
+                    param.name.clone(),
+                    Slot {
+                        address: value.into_pointer_value(),
+                        value_type,
+                        weak: false,
+                    },
+                );
+            } else {
+                self.expression_compiler
+                    .register_variable(param.name.clone(), value);
+            }
+            self.register_array_param(param)?;
+        }
+        Ok(())
+    }
+
+    /// Registers the element type of a `[T]` parameter, or the labels of a
+    /// record one, once it is bound
+    fn register_array_param(&mut self, param: &Parameter) -> CodeGenResult<()> {
+        if let Some(element_type) = self.array_element_type(Some(&param.param_type))? {
+            self.expression_compiler
+                .register_array(param.name.clone(), element_type);
+        }
+        if let Some(labels) = record_labels(Some(&param.param_type)) {
+            self.expression_compiler
+                .register_record(param.name.clone(), labels);
+        }
+        Ok(())
+    }
+
+    /// LLVM type of the elements of a `[T]`, which is only a pointer in IR
+    fn array_element_type(&self, ty: Option<&Type>) -> CodeGenResult<Option<BasicTypeEnum<'ctx>>> {
+        match ty {
+            Some(Type::Array(element)) => self.type_converter.convert_to_llvm(element).map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    /// Compiles the statements of a method, returning the default value if
+    /// control reaches the end of the body
+    fn compile_method_body(&mut self, body: &MethodBody, method: &Method) -> CodeGenResult<()> {
+        self.compile_body(body)?;
+        let terminated = self
+            .builder
+            .get_insert_block()
+            .and_then(|block| block.get_terminator())
+            .is_some();
+        if !terminated {
+            self.generate_default_return(method)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the zero value of the method's return type, or nothing
+    fn generate_default_return(&self, method: &Method) -> CodeGenResult<()> {
+        let value = method
+            .return_type
+            .as_ref()
+            .map(|return_type| self.type_converter.convert_to_llvm(return_type))
+            .transpose()?
+            .map(|return_type| return_type.const_zero());
+        self.builder
+            .build_return(value.as_ref().map(|value| value as &dyn BasicValue))
+            .map_err(|e| CodeGenError::LLVMError(e.to_string()))?;
+        Ok(())
+    }
+
+    /// Creates `<method>.post(mailbox, params...) -> i32`, which queues a call of
+    /// the async method on the mailbox's actor and schedules the mailbox. The
+    /// arguments are packed into a struct allocated with `__replica_alloc`, and
+    /// the message tag is the method's position among the actor's async methods.
+    /// Returns 0, or -1 when the mailbox or run queue is full; arguments of a
+    /// rejected call stay with the caller.
+    fn generate_async_wrapper(
+        &mut self,
+        function: FunctionValue<'ctx>,
+        method: &Method,
+    ) -> CodeGenResult<()> {
+        let llvm_error = |e: inkwell::builder::BuilderError| CodeGenError::LLVMError(e.to_string());
+        // 呼び出し元の記憶域はメッセージの処理まで生存しない
+        if let Some(param) = method
+            .params
+            .iter()
+            .find(|param| matches!(param.ownership, OwnershipType::Inout))
+        {
+            return Err(CodeGenError::InvalidOperation(format!(
+                "Async method {} cannot take inout parameter {}",
+                method.name, param.name
+            )));
+        }
+
+        let ptr_type = self.context.ptr_type(AddressSpace::default());
+        let i32_type = self.context.i32_type();
+        let arg_types: Vec<BasicTypeEnum> = function
+            .get_type()
+            .get_param_types()
+            .into_iter()
+            .skip(1)
+            .collect();
+        let payload_type = self.context.struct_type(&arg_types, false);
+        let post_params: Vec<BasicMetadataTypeEnum> = std::iter::once(ptr_type.into())
+            .chain(arg_types.iter().map(|&ty| ty.into()))
+            .collect();
+
+        // タスクとして展開したメソッドはフレームを確保してから始める
+        let task = runs_as_task(method);
+        let handler = if task {
+            self.task_start_function(&method.name, function)
+        } else {
+            function
+        };
+        let tag = i32_type.const_int(self.message_handlers.len() as u64, false);
+        self.message_handlers.push(MessageHandler::Call {
+            function: handler,
+            payload_type,
+            nonreentrant: method.is_nonreentrant,
+            task,
+            reply: false,
+        });
+        let dispatch = self.dispatch_function();
+        let name = format!("{}.post", self.member_symbol(&method.name));
+        let post = self
+            .module
+            .add_function(&name, i32_type.fn_type(&post_params, false), None);
+        self.name_export(&name, &[bare_name(&method.name), "post"]);
+        self.async_entry_points.push(name);
+
+        let entry = self.context.append_basic_block(post, "entry");
+        let queued = self.context.append_basic_block(post, "queued");
+        let rejected = self.context.append_basic_block(post, "rejected");
+        self.builder.position_at_end(entry);
+
+        let mailbox = post
+            .get_first_param()
+            .ok_or_else(|| CodeGenError::Internal(format!("{}.post has no mailbox", method.name)))?
+            .into_pointer_value();
+        mailbox.set_name("mailbox");
+
+        // 引数のないメソッドはペイロードを確保しない
+        let (payload, len) = if arg_types.is_empty() {
+            (ptr_type.const_null(), i32_type.const_zero())
+        } else {
+            let size = self.alloc_size(payload_type);
+            let alloc = self.runtime_function(RuntimeFunction::Alloc);
+            let payload = self
+                .builder
+                .build_call(alloc, &[size.into()], "payload")
+                .map_err(llvm_error)?
+                .try_as_basic_value()
+                .left()
+                .ok_or_else(|| CodeGenError::Internal("__replica_alloc returns void".into()))?
+                .into_pointer_value();
+            for (index, (param, value)) in method
+                .params
+                .iter()
+                .zip(post.get_param_iter().skip(1))
+                .enumerate()
+            {
+                value.set_name(&param.name);
+                let slot = self
+                    .builder
+                    .build_struct_gep(payload_type, payload, index as u32, &param.name)
+                    .map_err(llvm_error)?;
+                self.builder.build_store(slot, value).map_err(llvm_error)?;
+            }
+            (payload, size)
+        };
+
+        let mailbox_push = self.runtime_function(RuntimeFunction::MailboxPush);
+        let pushed = self
+            .builder
+            .build_call(
+                mailbox_push,
+                &[mailbox.into(), tag.into(), payload.into(), len.into()],
+                "pushed",
+            )
+            .map_err(llvm_error)?
+            .try_as_basic_value()
+            .left()
+            .ok_or_else(|| CodeGenError::Internal("__replica_mailbox_push returns void".into()))?
+            .into_int_value();
+        let is_full = self
+            .builder
+            .build_int_compare(IntPredicate::NE, pushed, i32_type.const_zero(), "is_full")
+            .map_err(llvm_error)?;
+        self.builder
+            .build_conditional_branch(is_full, rejected, queued)
+            .map_err(llvm_error)?;
+
+        // 満杯ならペイロードだけを解放し、引数の所有権は呼び出し元に残す
+        self.builder.position_at_end(rejected);
+        if !arg_types.is_empty() {
+            let free = self.runtime_function(RuntimeFunction::Free);
+            self.builder
+                .build_call(free, &[payload.into()], "")
+                .map_err(llvm_error)?;
+        }
+        self.builder
+            .build_return(Some(&pushed))
+            .map_err(llvm_error)?;
+
+        self.builder.position_at_end(queued);
+        let schedule = self.runtime_function(RuntimeFunction::Schedule);
+        let scheduled = self
+            .builder
+            .build_call(
+                schedule,
+                &[
+                    mailbox.into(),
+                    dispatch.as_global_value().as_pointer_value().into(),
+                ],
+                "scheduled",
+            )
+            .map_err(llvm_error)?
+            .try_as_basic_value()
+            .left()
+            .ok_or_else(|| CodeGenError::Internal("__replica_schedule returns void".into()))?;
+        self.builder
+            .build_return(Some(&scheduled))
+            .map_err(llvm_error)?;
+
+        Ok(())
+    }
+
+    /// `<Actor>.dispatch(actor, tag, payload, len, mailbox)` of the actor being
+    /// compiled, declared on first use
+    fn dispatch_function(&self) -> FunctionValue<'ctx> {
+        let name = format!("{}.dispatch", self.current_actor);
+        self.module.get_function(&name).unwrap_or_else(|| {
+            let ptr_type = self.context.ptr_type(AddressSpace::default());
+            let i32_type = self.context.i32_type();
+            let function_type = self.context.void_type().fn_type(
+                &[
+                    ptr_type.into(),
+                    i32_type.into(),
+                    ptr_type.into(),
+                    i32_type.into(),
+                    ptr_type.into(),
+                ],
+                false,
+            );
+            self.module.add_function(&name, function_type, None)
+        })
+    }
+
+    /// Creates `<Actor>.drain(mailbox: ptr, budget: i32) -> i32`, which runs up
+    /// to `budget` messages of one mailbox through the actor's dispatch function
+    /// with `__replica_drain`, oldest first, and returns the number still
+    /// queued. It lets a host drive a single instance without `__replica_run`.
+    fn define_drain(&mut self) -> CodeGenResult<()> {
+        if self.message_handlers.is_empty() {
+            return Ok(());
+        }
+        let llvm_error = |e: inkwell::builder::BuilderError| CodeGenError::LLVMError(e.to_string());
+        let ptr_type = self.context.ptr_type(AddressSpace::default());
+        let i32_type = self.context.i32_type();
+
+        let name = format!("{}.drain", self.current_actor);
+        let function = self.module.add_function(
+            &name,
+            i32_type.fn_type(&[ptr_type.into(), i32_type.into()], false),
+            None,
+        );
+        self.name_export(&name, &["drain"]);
+        self.async_entry_points.push(name);
+        let [mailbox, budget] = [0, 1].map(|index| {
+            function
+                .get_nth_param(index)
+                .expect("drain takes a mailbox and a budget")
+        });
+        mailbox.set_name("mailbox");
+        budget.set_name("budget");
+
+        let entry = self.context.append_basic_block(function, "entry");
+        self.builder.position_at_end(entry);
+        let dispatch = self.dispatch_function();
+        let remaining = self
+            .builder
+            .build_call(
+                self.runtime_function(RuntimeFunction::Drain),
+                &[
+                    mailbox.into(),
+                    dispatch.as_global_value().as_pointer_value().into(),
+                    budget.into(),
+                ],
+                "remaining",
+            )
+            .map_err(llvm_error)?
+            .try_as_basic_value()
+            .left()
+            .ok_or_else(|| CodeGenError::Internal("__replica_drain returns void".into()))?;
+        self.builder
+            .build_return(Some(&remaining))
+            .map_err(llvm_error)?;
+        Ok(())
+    }
+
+    /// Defines the dispatch function `__replica_run` calls for every queued
+    /// message of the actor: it unpacks the arguments of the async method selected
+    /// by the tag, frees the payload and calls the method, dropping its result.
+    /// Tasks are started with the mailbox the message came from (see
+    /// `compile_task_method`). Messages of awaited calls also carry the task
+    /// awaiting the call, which the result is handed to, and resumption
+    /// messages continue a suspended task. Unknown tags are ignored.
+    ///
+    /// Actors with `@nonreentrant` methods get a guard around the calls: such a
+    /// method sets the instance's busy word while it runs, and any message that
+    /// arrives in the meantime is moved to the instance's queue of deferred
+    /// messages with its payload intact. Once the busy word is clear again the
+    /// queue is drained through the dispatch function, oldest message first.
+    /// Deferring into a missing or full queue traps.
+    fn define_dispatch(&mut self) -> CodeGenResult<()> {
+        let handlers: Vec<MessageHandler> = self
+            .message_handlers
+            .iter()
+            .chain(&self.continuations)
+            .copied()
+            .collect();
+        if handlers.is_empty() {
+            return Ok(());
+        }
+        let llvm_error = |e: inkwell::builder::BuilderError| CodeGenError::LLVMError(e.to_string());
+        let ptr_type = self.context.ptr_type(AddressSpace::default());
+        let i32_type = self.context.i32_type();
+
+        let dispatch = self.dispatch_function();
+        let entry = self.context.append_basic_block(dispatch, "entry");
+        let done = self.context.append_basic_block(dispatch, "done");
+        let params = dispatch.get_params();
+        let [actor, tag, payload, len, mailbox] = params.as_slice() else {
+            return Err(CodeGenError::Internal(
+                "dispatch takes actor, tag, payload, len and mailbox".into(),
+            ));
+        };
+        for (param, name) in params
+            .iter()
+            .zip(["actor", "tag", "payload", "len", "mailbox"])
+        {
+            param.set_name(name);
+        }
+        let actor = actor.into_pointer_value();
+        let payload = payload.into_pointer_value();
+        let mailbox = mailbox.into_pointer_value();
+
+        self.builder.position_at_end(entry);
+        let guard = match self.reentrancy_guards.get(&self.current_actor) {
+            Some(&busy_index) => Some(self.build_reentrancy_guard(
+                dispatch,
+                actor,
+                busy_index,
+                [*tag, payload.into(), *len, mailbox.into()],
+                done,
+            )?),
+            None => None,
+        };
+        // 保護があれば、呼び出しの後に保留中のメッセージを流す
+        let finished = guard.as_ref().map_or(done, |guard| guard.replay);
+
+        let mut cases = Vec::with_capacity(handlers.len());
+        for (index, handler) in handlers.into_iter().enumerate() {
+            let (function, nonreentrant) = match handler {
+                MessageHandler::Call {
+                    function,
+                    nonreentrant,
+                    ..
+                } => (function, nonreentrant),
+                MessageHandler::Resume(function) => (function, false),
+            };
+            let name = function.get_name().to_string_lossy().into_owned();
+            let block = self
+                .context
+                .append_basic_block(dispatch, &format!("message.{}", name));
+            cases.push((i32_type.const_int(index as u64, false), block));
+            self.builder.position_at_end(block);
+
+            if let Some(guard) = &guard {
+                let call = self
+                    .context
+                    .append_basic_block(dispatch, &format!("call.{}", name));
+                let admitted = if nonreentrant {
+                    guard.acquire(self)?
+                } else {
+                    guard.is_idle(self)?
+                };
+                self.builder
+                    .build_conditional_branch(admitted, call, guard.defer)
+                    .map_err(llvm_error)?;
+                self.builder.position_at_end(call);
+            }
+
+            let MessageHandler::Call {
+                payload_type,
+                task,
+                reply,
+                ..
+            } = handler
+            else {
+                // 再開のメッセージはタスクのフレームそのもの
+                self.builder
+                    .build_call(function, &[payload.into()], "")
+                    .map_err(llvm_error)?;
+                self.builder
+                    .build_unconditional_branch(finished)
+                    .map_err(llvm_error)?;
+                continue;
+            };
+
+            let mut fields = Vec::with_capacity(payload_type.count_fields() as usize);
+            for (field, field_type) in payload_type.get_field_types().into_iter().enumerate() {
+                let slot = self
+                    .builder
+                    .build_struct_gep(payload_type, payload, field as u32, "arg.slot")
+                    .map_err(llvm_error)?;
+                let value = self
+                    .builder
+                    .build_load(field_type, slot, "arg")
+                    .map_err(llvm_error)?;
+                fields.push(value);
+            }
+            if payload_type.count_fields() > 0 {
+                let free = self.runtime_function(RuntimeFunction::Free);
+                self.builder
+                    .build_call(free, &[payload.into()], "")
+                    .map_err(llvm_error)?;
+            }
+            // 待たれている呼び出しのペイロードは引数の後に待ち手への返信先を持つ
+            let (args, awaiting) = if reply {
+                let (args, awaiting) = fields.split_at(fields.len() - 3);
+                (args.to_vec(), [awaiting[0], awaiting[1], awaiting[2]])
+            } else {
+                let none = [
+                    ptr_type.const_null().into(),
+                    i32_type.const_zero().into(),
+                    ptr_type.const_null().into(),
+                ];
+                (fields, none)
+            };
+            let mut call_args: Vec<BasicMetadataValueEnum> = vec![actor.into()];
+            if task {
+                call_args.push(mailbox.into());
+                call_args.extend(
+                    awaiting
+                        .iter()
+                        .map(|&value| BasicMetadataValueEnum::from(value)),
+                );
+            }
+            call_args.extend(
+                args.iter()
+                    .map(|&value| BasicMetadataValueEnum::from(value)),
+            );
+            let result = self
+                .builder
+                .build_call(function, &call_args, "")
+                .map_err(llvm_error)?
+                .try_as_basic_value()
+                .left();
+            if let (Some(guard), true) = (&guard, nonreentrant) {
+                guard.release(self)?;
+            }
+            if reply && !task {
+                self.build_reply(actor, mailbox, awaiting, result)?;
+            }
+            self.builder
+                .build_unconditional_branch(finished)
+                .map_err(llvm_error)?;
+        }
+
+        self.builder.position_at_end(done);
+        self.builder.build_return(None).map_err(llvm_error)?;
+
+        self.builder.position_at_end(entry);
+        self.builder
+            .build_switch(tag.into_int_value(), done, &cases)
+            .map_err(llvm_error)?;
+
+        Ok(())
+    }
+
+    /// Emits the shared blocks of the reentrancy guard in `dispatch`: `defer`
+    /// moves the current message to the deferred queue, and `replay` dispatches
+    /// deferred messages until the queue is empty or the instance is busy again,
+    /// then continues at `done`. The builder stays in the entry block.
+    fn build_reentrancy_guard(
+        &self,
+        dispatch: FunctionValue<'ctx>,
+        actor: PointerValue<'ctx>,
+        busy_index: u32,
+        [tag, payload, len, mailbox]: [inkwell::values::BasicValueEnum<'ctx>; 4],
+        done: BasicBlock<'ctx>,
+    ) -> CodeGenResult<ReentrancyGuard<'ctx>> {
+        let llvm_error = |e: inkwell::builder::BuilderError| CodeGenError::LLVMError(e.to_string());
+        let ptr_type = self.context.ptr_type(AddressSpace::default());
+        let i32_type = self.context.i32_type();
+        let actor_type = self
+            .type_converter
+            .convert_to_llvm(&Type::Custom(self.current_actor.clone()))?
+            .into_struct_type();
+        let entry = self
+            .builder
+            .get_insert_block()
+            .ok_or_else(|| CodeGenError::Internal("dispatch has no entry block".into()))?;
+
+        let busy = self
+            .builder
+            .build_struct_gep(actor_type, actor, busy_index, "busy")
+            .map_err(llvm_error)?;
+        let deferred = self
+            .builder
+            .build_struct_gep(actor_type, actor, busy_index + 1, "deferred")
+            .map_err(llvm_error)?;
+        // __replica_mailbox_pop が書き込む Message { tag, payload, len }
+        let message_type = self
+            .context
+            .struct_type(&[i32_type.into(), ptr_type.into(), i32_type.into()], false);
+        let message = self
+            .builder
+            .build_alloca(message_type, "message")
+            .map_err(llvm_error)?;
+        let guard = ReentrancyGuard {
+            busy,
+            defer: self.context.append_basic_block(dispatch, "defer"),
+            replay: self.context.append_basic_block(dispatch, "replay"),
+        };
+        let overflow = self.context.append_basic_block(dispatch, "defer.overflow");
+        let push = self.context.append_basic_block(dispatch, "defer.push");
+        let pop = self.context.append_basic_block(dispatch, "replay.pop");
+        let next = self.context.append_basic_block(dispatch, "replay.next");
+
+        // 保留キューへ移す。ペイロードは再実行まで解放しない
+        self.builder.position_at_end(guard.defer);
+        let queue = self
+            .builder
+            .build_load(ptr_type, deferred, "queue")
+            .map_err(llvm_error)?
+            .into_pointer_value();
+        let missing = self
+            .builder
+            .build_is_null(queue, "queue.missing")
+            .map_err(llvm_error)?;
+        self.builder
+            .build_conditional_branch(missing, overflow, push)
+            .map_err(llvm_error)?;
+        self.builder.position_at_end(push);
+        let pushed = self
+            .builder
+            .build_call(
+                self.runtime_function(RuntimeFunction::MailboxPush),
+                &[queue.into(), tag.into(), payload.into(), len.into()],
+                "deferred.pushed",
+            )
+            .map_err(llvm_error)?
+            .try_as_basic_value()
+            .left()
+            .ok_or_else(|| CodeGenError::Internal("__replica_mailbox_push returns void".into()))?
+            .into_int_value();
+        let is_full = self
+            .builder
+            .build_int_compare(IntPredicate::NE, pushed, i32_type.const_zero(), "is_full")
+            .map_err(llvm_error)?;
+        // 他のワーカーがすでに解放していれば自分で流す
+        self.builder
+            .build_conditional_branch(is_full, overflow, guard.replay)
+            .map_err(llvm_error)?;
+        self.builder.position_at_end(overflow);
+        self.builder.build_unreachable().map_err(llvm_error)?;
+
+        self.builder.position_at_end(guard.replay);
+        let idle = guard.is_idle(self)?;
+        self.builder
+            .build_conditional_branch(idle, pop, done)
+            .map_err(llvm_error)?;
+        self.builder.position_at_end(pop);
+        let queue = self
+            .builder
+            .build_load(ptr_type, deferred, "queue")
+            .map_err(llvm_error)?
+            .into_pointer_value();
+        let missing = self
+            .builder
+            .build_is_null(queue, "queue.missing")
+            .map_err(llvm_error)?;
+        let popped = self.context.append_basic_block(dispatch, "replay.popped");
+        self.builder
+            .build_conditional_branch(missing, done, popped)
+            .map_err(llvm_error)?;
+        self.builder.position_at_end(popped);
+        let status = self
+            .builder
+            .build_call(
+                self.runtime_function(RuntimeFunction::MailboxPop),
+                &[queue.into(), message.into()],
+                "popped",
+            )
+            .map_err(llvm_error)?
+            .try_as_basic_value()
+            .left()
+            .ok_or_else(|| CodeGenError::Internal("__replica_mailbox_pop returns void".into()))?
+            .into_int_value();
+        let is_empty = self
+            .builder
+            .build_int_compare(IntPredicate::NE, status, i32_type.const_zero(), "is_empty")
+            .map_err(llvm_error)?;
+        self.builder
+            .build_conditional_branch(is_empty, done, next)
+            .map_err(llvm_error)?;
+        self.builder.position_at_end(next);
+        let mut fields = Vec::with_capacity(3);
+        for (index, (field_type, name)) in message_type
+            .get_field_types()
+            .into_iter()
+            .zip(["deferred.tag", "deferred.payload", "deferred.len"])
+            .enumerate()
+        {
+            let slot = self
+                .builder
+                .build_struct_gep(message_type, message, index as u32, name)
+                .map_err(llvm_error)?;
+            let value = self
+                .builder
+                .build_load(field_type, slot, name)
+                .map_err(llvm_error)?;
+            fields.push(value.into());
+        }
+        self.builder
+            .build_call(
+                dispatch,
+                &[
+                    actor.into(),
+                    fields[0],
+                    fields[1],
+                    fields[2],
+                    mailbox.into(),
+                ],
+                "",
+            )
+            .map_err(llvm_error)?;
+        self.builder
+            .build_unconditional_branch(guard.replay)
+            .map_err(llvm_error)?;
+
+        self.builder.position_at_end(entry);
+        Ok(guard)
+    }
+
+    /// Creates the setter `<Actor>.set_<field>(self, value)` for a mutable field.
+    /// Assigning a collection shares its buffer; weak fields go through the runtime.
+    fn create_field_accessor(
+        &mut self,
+        actor: &Actor,
+        field: &crate::ast::Field,
+    ) -> CodeGenResult<()> {
+        let actor_type = self
+            .type_converter
+            .convert_to_llvm(&Type::Custom(actor.name.clone()))?
+            .into_struct_type();
+        let position = actor
+            .fields
+            .iter()
+            .position(|f| f.name == field.name)
+            .ok_or_else(|| CodeGenError::Internal(format!("Unknown field {}", field.name)))?;
+        let index = self.type_converter.field_index(&actor.name, position);
+        let value_type = self
+            .type_converter
+            .convert_with_ownership(&field.field_type, &field.ownership)?;
+
+        let ptr_type = self.context.ptr_type(AddressSpace::default());
+        let function_type = self
+            .context
+            .void_type()
+            .fn_type(&[ptr_type.into(), value_type.into()], false);
+        let function = self.module.add_function(
+            &format!("{}.set_{}", actor.name, field.name),
+            function_type,
+            Some(Linkage::Internal),
+        );
+        let basic_block = self.context.append_basic_block(function, "entry");
+        self.builder.position_at_end(basic_block);
+
+        let llvm_error = |e: inkwell::builder::BuilderError| CodeGenError::LLVMError(e.to_string());
+        let this = function
+            .get_nth_param(0)
+            .ok_or_else(|| CodeGenError::Internal("setter has no self parameter".into()))?
+            .into_pointer_value();
+        let value = function
+            .get_nth_param(1)
+            .ok_or_else(|| CodeGenError::Internal("setter has no value parameter".into()))?;
+        let slot = self
+            .builder
+            .build_struct_gep(actor_type, this, index, "slot")
+            .map_err(llvm_error)?;
+
+        // 他のワーカーと共有される状態はロックを保持して書き込む
+        let lock = match self.state_locks.get(&actor.name) {
+            Some(&lock_index) if matches!(field.ownership, OwnershipType::Shared) => Some(
+                self.builder
+                    .build_struct_gep(actor_type, this, lock_index, "lock")
+                    .map_err(llvm_error)?,
+            ),
+            _ => None,
+        };
+        if let Some(lock) = lock {
+            let acquire = self.runtime_function(RuntimeFunction::LockAcquire);
+            self.builder
+                .build_call(acquire, &[lock.into()], "")
+                .map_err(llvm_error)?;
+        }
+
+        if matches!(field.ownership, OwnershipType::Weak) {
+            let weak_store = self.runtime_function(RuntimeFunction::WeakStore);
+            self.builder
+                .build_call(weak_store, &[slot.into(), value.into()], "")
+                .map_err(llvm_error)?;
+        } else if self.type_converter.is_copy_on_write(&field.field_type) {
+            // 論理的なコピー: バッファを共有し、古い値を解放する
+            let retain = self.runtime_function(RuntimeFunction::Retain);
+            let release = self.runtime_function(RuntimeFunction::Release);
+            self.builder
+                .build_call(retain, &[value.into()], "")
+                .map_err(llvm_error)?;
+            let old = self
+                .builder
+                .build_load(ptr_type, slot, "old")
+                .map_err(llvm_error)?;
+            self.builder.build_store(slot, value).map_err(llvm_error)?;
+            self.builder
+                .build_call(release, &[old.into()], "")
+                .map_err(llvm_error)?;
+        } else {
+            self.builder.build_store(slot, value).map_err(llvm_error)?;
+        }
+
+        if let Some(lock) = lock {
+            let release = self.runtime_function(RuntimeFunction::LockRelease);
+            self.builder
+                .build_call(release, &[lock.into()], "")
+                .map_err(llvm_error)?;
+        }
+
+        self.builder.build_return(None).map_err(llvm_error)?;
+        Ok(())
+    }
+
+    /// Emits the uniqueness check performed before a collection buffer is mutated
+    /// in place. Returns the buffer to write to, which is a private copy when the
+    /// original was shared.
+    pub fn build_make_unique(
+        &self,
+        buffer: inkwell::values::PointerValue<'ctx>,
+        size: inkwell::values::IntValue<'ctx>,
+    ) -> CodeGenResult<inkwell::values::PointerValue<'ctx>> {
+        let make_unique = self.runtime_function(RuntimeFunction::MakeUnique);
+        self.builder
+            .build_call(make_unique, &[buffer.into(), size.into()], "unique")
+            .map_err(|e| CodeGenError::LLVMError(e.to_string()))?
+            .try_as_basic_value()
+            .left()
+            .map(|value| value.into_pointer_value())
+            .ok_or_else(|| CodeGenError::Internal("__replica_make_unique returns void".into()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::{ActorType, EnumCase, Field, LiteralValue, Operator, RandomOperation, Type};
+    use crate::codegen::RandomSource;
+
+    fn create_test_context() -> Context {
+        Context::create()
+    }
+
+    #[test]
+    fn test_basic_actor_compilation() {
+        let context = create_test_context();
+        let options = super::super::CodeGenOptions::default();
+        let mut codegen = CodeGenerator::new(&context, "test", options).unwrap();
+
+        let actor = Actor {
+            name: "TestActor".to_string(),
+            actor_type: ActorType::Single,
+            methods: vec![],
+            fields: vec![],
+            init: None,
+            deinit: None,
+            layout: Default::default(),
+            externs: vec![],
+            imports: vec![],
+            topics: vec![],
+            pools: vec![],
+            structs: vec![],
+            enums: vec![],
+            constants: vec![],
+            protocol: None,
+            doc: None,
+        };
+
+        assert!(codegen.compile_actor(&actor).is_ok());
+    }
+
+    #[test]
+    fn test_wasm_emission() {
+        let context = create_test_context();
+        let options = super::super::CodeGenOptions::default();
+        let codegen = CodeGenerator::new(&context, "test", options).unwrap();
+
+        let wasm = codegen.emit_wasm();
+        assert!(wasm.is_ok());
+    }
+
+    #[test]
+    fn test_wasm_emission_with_cpu_and_features() {
+        let context = create_test_context();
+        let options = super::super::CodeGenOptions {
+            cpu: "bleeding-edge".to_string(),
+            target_features: "+simd128, -sign-ext".to_string(),
+            ..Default::default()
+        };
+        let codegen = CodeGenerator::new(&context, "test", options).unwrap();
+
+        assert!(codegen.emit_wasm().is_ok());
+    }
+
+    #[test]
+    fn test_bitcode_and_static_lib_emission() {
+        let context = create_test_context();
+        let options = super::super::CodeGenOptions::default();
+        let codegen = CodeGenerator::new(&context, "test", options).unwrap();
+
+        let bitcode = codegen.emit(super::super::EmitKind::LlvmBitcode).unwrap();
+        assert!(bitcode.starts_with(b"BC"));
+
+        let archive = codegen.emit(super::super::EmitKind::StaticLib).unwrap();
+        assert!(archive.starts_with(b"!<arch>\n"));
+
+        let object = codegen.emit(super::super::EmitKind::Object).unwrap();
+        assert!(object.starts_with(b"\0asm"));
+
+        let ir = codegen.emit(super::super::EmitKind::LlvmIr).unwrap();
+        let ir = String::from_utf8(ir).unwrap();
+        assert!(ir.contains("target triple = \"wasm32-unknown-unknown\""));
+    }
+
+    #[test]
+    fn test_link_and_lto() {
+        let context = create_test_context();
+        let options = super::super::CodeGenOptions {
+            lto: super::super::LtoMode::Full,
+            ..Default::default()
+        };
+        let mut first = CodeGenerator::new(&context, "first", options.clone()).unwrap();
+        let second = CodeGenerator::new(&context, "second", options).unwrap();
+
+        let actor = |name: &str| Actor {
+            name: name.to_string(),
+            actor_type: ActorType::Single,
+            methods: vec![],
+            fields: vec![],
+            init: None,
+            deinit: None,
+            layout: Default::default(),
+            externs: vec![],
+            imports: vec![],
+            topics: vec![],
+            pools: vec![],
+            structs: vec![],
+            enums: vec![],
+            constants: vec![],
+            protocol: None,
+            doc: None,
+        };
+        first.compile_actor(&actor("First")).unwrap();
+
+        assert!(first.link(second).is_ok());
+        assert!(first.run_lto().is_ok());
+        assert!(first.emit_wasm().is_ok());
+    }
+
+    #[test]
+    fn test_malformed_target_features() {
+        assert!(CodeGenerator::validate_target_features("").is_ok());
+        assert!(CodeGenerator::validate_target_features("+simd128,-sign-ext").is_ok());
+        assert!(CodeGenerator::validate_target_features("simd128").is_err());
+        assert!(CodeGenerator::validate_target_features("+").is_err());
+    }
+
+    #[test]
+    fn test_deinit_registered_with_runtime() {
+        let context = create_test_context();
+        let options = super::super::CodeGenOptions::default();
+        let mut codegen = CodeGenerator::new(&context, "test", options).unwrap();
+
+        let actor = Actor {
+            name: "Socket".to_string(),
+            actor_type: ActorType::Single,
+            methods: vec![],
+            fields: vec![],
+            init: None,
+            deinit: Some(crate::ast::Deinit {
+                is_async: false,
+                body: MethodBody { statements: vec![] },
+            }),
+            layout: Default::default(),
+            externs: vec![],
+            imports: vec![],
+            topics: vec![],
+            pools: vec![],
+            structs: vec![],
+            enums: vec![],
+            constants: vec![],
+            protocol: None,
+            doc: None,
+        };
+        codegen.compile_actor(&actor).unwrap();
+
+        assert!(codegen.module.get_function("Socket.deinit").is_some());
+        let ir = codegen.module.print_to_string().to_string();
+        assert!(ir.contains("call ptr @__replica_object_new(i32 0, ptr @Socket.deinit)"));
+    }
+
+    #[test]
+    fn test_object_size_follows_target_layout() {
+        let field = |name: &str, field_type| Field {
+            name: name.to_string(),
+            field_type,
+            is_mutable: true,
+            ownership: OwnershipType::Owned,
+            offset: None,
+            doc: None,
+        };
+        // actor Gauge { var value: Float  var label: String  var flag: Bool  deinit {} }
+        let actor = Actor {
+            name: "Gauge".to_string(),
+            actor_type: ActorType::Single,
+            methods: vec![],
+            fields: vec![
+                field("value", Type::Float),
+                field("label", Type::String),
+                field("flag", Type::Bool),
+            ],
+            init: None,
+            deinit: Some(crate::ast::Deinit {
+                is_async: false,
+                body: MethodBody { statements: vec![] },
+            }),
+            layout: Default::default(),
+            externs: vec![],
+            imports: vec![],
+            topics: vec![],
+            pools: vec![],
+            structs: vec![],
+            enums: vec![],
+            constants: vec![],
+            protocol: None,
+            doc: None,
+        };
+        let compile = |triple: &str| {
+            let context = create_test_context();
+            let options = super::super::CodeGenOptions {
+                target_triple: triple.to_string(),
+                ..Default::default()
+            };
+            let mut codegen = CodeGenerator::new(&context, "test", options).unwrap();
+            codegen.compile_actor(&actor).unwrap();
+            codegen.module.print_to_string().to_string()
+        };
+
+        // wasm32: 0 value, 8 label, 12 flag。Float の境界まで末尾を埋めて 16
+        assert!(compile("wasm32-unknown-unknown")
+            .contains("call ptr @__replica_object_new(i32 16, ptr @Gauge.deinit)"));
+        // wasm64 ではポインタが 8 バイト: 0 value, 8 label, 16 flag で 24
+        assert!(compile("wasm64-unknown-unknown")
+            .contains("call ptr @__replica_object_new(i32 24, ptr @Gauge.deinit)"));
+    }
+
+    #[test]
+    fn test_init_runs_from_constructor() {
+        let context = create_test_context();
+        let options = super::super::CodeGenOptions::default();
+        let mut codegen = CodeGenerator::new(&context, "test", options).unwrap();
+
+        // actor Counter { let count: Int  init(start: Int) { count = start } }
+        let actor = Actor {
+            name: "Counter".to_string(),
+            actor_type: ActorType::Distributed,
+            methods: vec![],
+            fields: vec![Field {
+                name: "count".to_string(),
+                field_type: Type::Int,
+                is_mutable: false,
+                ownership: OwnershipType::Owned,
+                offset: None,
+                doc: None,
+            }],
+            init: Some(crate::ast::Init {
+                is_immediate: false,
+                params: vec![Parameter {
+                    name: "start".to_string(),
+                    param_type: Type::Int,
+                    ownership: OwnershipType::Owned,
+                }],
+                body: MethodBody {
+                    statements: vec![Statement::Assign {
+                        target: Expression::Variable("count".to_string()),
+                        operator: None,
+                        value: Expression::Variable("start".to_string()),
+                    }],
+                },
+            }),
+            deinit: None,
+            layout: Default::default(),
+            externs: vec![],
+            imports: vec![],
+            topics: vec![],
+            pools: vec![],
+            structs: vec![],
+            enums: vec![],
+            constants: vec![],
+            protocol: None,
+            doc: None,
+        };
+        codegen.compile_actor(&actor).unwrap();
+
+        let init = codegen.module.get_function("Counter.init").unwrap();
+        assert_eq!(init.get_linkage(), Linkage::Internal);
+        let constructor = codegen.module.get_function("Counter.new").unwrap();
+        assert_eq!(constructor.count_params(), 1);
+        let ir = codegen.module.print_to_string().to_string();
+        assert!(ir.contains("call void @Counter.init(ptr %object"));
+        // 引数を取るコンストラクタはレジストリに載らない
+        assert!(!ir.contains("Counter.registry_name"));
+    }
+
+    #[test]
+    fn test_http_facade_routes_section() {
+        let context = create_test_context();
+        let options = super::super::CodeGenOptions {
+            http_facade: true,
+            ..Default::default()
+        };
+        let codegen = CodeGenerator::new(&context, "test", options).unwrap();
+
+        let actor = Actor {
+            name: "Counter".to_string(),
+            actor_type: ActorType::Distributed,
+            methods: vec![Method {
+                name: "add".to_string(),
+                is_async: false,
+                is_sequential: false,
+                is_immediate: false,
+                is_pure: false,
+                is_nonreentrant: false,
+                shard_key: None,
+                retry: None,
+                is_idempotent: false,
+                since: None,
+                removed: None,
+                params: vec![Parameter {
+                    name: "amount".to_string(),
+                    param_type: Type::Int,
+                    ownership: OwnershipType::Owned,
+                }],
+                return_type: None,
+                body: None,
+                doc: None,
+            }],
+            fields: vec![],
+            init: None,
+            deinit: None,
+            layout: Default::default(),
+            externs: vec![],
+            imports: vec![],
+            topics: vec![],
+            pools: vec![],
+            structs: vec![],
+            enums: vec![],
+            constants: vec![],
+            protocol: None,
+            doc: None,
+        };
+        codegen.embed_routes(&actor).unwrap();
+
+        let ir = codegen.module.print_to_string().to_string();
+        assert!(ir.contains("!wasm.custom_sections = !{"));
+        assert!(ir.contains("!\"replica.routes\""));
+        assert!(ir.contains("\\22path\\22:\\22/Counter/add\\22"));
+    }
+
+    #[test]
+    fn test_weak_fields_released_on_deinit() {
+        let context = create_test_context();
+        let options = super::super::CodeGenOptions::default();
+        let mut codegen = CodeGenerator::new(&context, "test", options).unwrap();
+
+        let actor = Actor {
+            name: "Node".to_string(),
+            actor_type: ActorType::Single,
+            methods: vec![],
+            fields: vec![crate::ast::Field {
+                name: "parent".to_string(),
+                field_type: Type::Optional(Box::new(Type::Custom("Node".to_string()))),
+                is_mutable: true,
+                ownership: OwnershipType::Weak,
+                offset: None,
+                doc: None,
+            }],
+            init: None,
+            deinit: None,
+            layout: Default::default(),
+            externs: vec![],
+            imports: vec![],
+            topics: vec![],
+            pools: vec![],
+            structs: vec![],
+            enums: vec![],
+            constants: vec![],
+            protocol: None,
+            doc: None,
+        };
+        codegen.compile_actor(&actor).unwrap();
+
+        let ir = codegen.module.print_to_string().to_string();
+        assert!(ir.contains("%Node = type { ptr }"));
+        assert!(ir.contains("call void @__replica_weak_release(ptr %weak_slot)"));
+    }
+
+    #[test]
+    fn test_collection_setter_shares_buffer() {
+        let context = create_test_context();
+        let options = super::super::CodeGenOptions::default();
+        let mut codegen = CodeGenerator::new(&context, "test", options).unwrap();
+
+        let actor = Actor {
+            name: "Inbox".to_string(),
+            actor_type: ActorType::Single,
+            methods: vec![],
+            fields: vec![crate::ast::Field {
+                name: "subject".to_string(),
+                field_type: Type::String,
+                is_mutable: true,
+                ownership: OwnershipType::Owned,
+                offset: None,
+                doc: None,
+            }],
+            init: None,
+            deinit: None,
+            layout: Default::default(),
+            externs: vec![],
+            imports: vec![],
+            topics: vec![],
+            pools: vec![],
+            structs: vec![],
+            enums: vec![],
+            constants: vec![],
+            protocol: None,
+            doc: None,
+        };
+        codegen.compile_actor(&actor).unwrap();
+
+        let setter = codegen.module.get_function("Inbox.set_subject").unwrap();
+        assert_eq!(setter.count_params(), 2);
+        let ir = codegen.module.print_to_string().to_string();
+        assert!(ir.contains("call void @__replica_retain(ptr %1)"));
+        assert!(ir.contains("call i32 @__replica_release(ptr %old)"));
+
+        // ホットリロード用の状態セクションにフィールドの配置が記録される
+        assert!(ir.contains("!\"replica.state\""));
+        assert!(ir.contains(
+            "{\\22carry\\22:\\22string\\22,\\22name\\22:\\22subject\\22,\\22offset\\22:0,\\22size\\22:4,\\22type\\22:\\22String\\22}"
+        ));
+    }
+
+    #[test]
+    fn test_shared_fields_are_locked_with_threads() {
+        let context = create_test_context();
+        let options = super::super::CodeGenOptions {
+            target_features: String::from("+simd128"),
+            wasm_features: vec![WasmFeature::Threads],
+            ..super::super::CodeGenOptions::default()
+        };
+        let mut codegen = CodeGenerator::new(&context, "test", options).unwrap();
+        assert_eq!(codegen.target_features, "+simd128,+atomics,+bulk-memory");
+
+        let field = |name: &str, ownership| crate::ast::Field {
+            name: name.to_string(),
+            field_type: Type::Int,
+            is_mutable: true,
+            ownership,
+            offset: None,
+            doc: None,
+        };
+        let actor = Actor {
+            name: "Counter".to_string(),
+            actor_type: ActorType::Single,
+            methods: vec![],
+            fields: vec![
+                field("hits", OwnershipType::Shared),
+                field("local", OwnershipType::Owned),
+            ],
+            init: None,
+            deinit: None,
+            layout: Default::default(),
+            externs: vec![],
+            imports: vec![],
+            topics: vec![],
+            pools: vec![],
+            structs: vec![],
+            enums: vec![],
+            constants: vec![],
+            protocol: None,
+            doc: None,
+        };
+        codegen.compile_actor(&actor).unwrap();
+
+        let ir = codegen.module.print_to_string().to_string();
+        assert!(ir.contains("%Counter = type { i32, i32, i32 }"));
+        assert!(ir.contains(&format!("!\"{}\", i32 1", runtime::SHARED_MEMORY_FLAG)));
+        let setter = |name: &str| {
+            let start = ir.find(&format!("@Counter.set_{}(", name)).unwrap();
+            ir[start..].split("\n}").next().unwrap().to_string()
+        };
+        assert!(setter("hits").contains("@__replica_lock_acquire"));
+        assert!(setter("hits").contains("@__replica_lock_release"));
+        assert!(!setter("local").contains("@__replica_lock_acquire"));
+    }
+
+    #[test]
+    fn test_memory_policy_constructor() {
+        let context = create_test_context();
+        let codegen = CodeGenerator::new(&context, "test", Default::default()).unwrap();
+        assert!(codegen.module.get_global("llvm.global_ctors").is_none());
+
+        let options = super::super::CodeGenOptions {
+            max_memory: Some(32 * super::super::WASM_PAGE_SIZE),
+            on_oom: OomBehavior::Trap,
+            ..super::super::CodeGenOptions::default()
+        };
+        let codegen = CodeGenerator::new(&context, "test", options).unwrap();
+        assert!(codegen.verify_module().is_ok());
+
+        let ir = codegen.module.print_to_string().to_string();
+        assert!(ir.contains("@llvm.global_ctors = appending global"));
+        assert!(ir.contains("call void @__replica_set_memory_policy(i32 2097152, i32 1)"));
+        assert!(!ir.contains("__replica_set_allocator"));
+    }
+
+    #[test]
+    fn test_allocator_constructor() {
+        let context = create_test_context();
+        let options = |allocator| super::super::CodeGenOptions {
+            allocator,
+            ..super::super::CodeGenOptions::default()
+        };
+        let codegen = CodeGenerator::new(&context, "test", options(Allocator::FreeList)).unwrap();
+        assert!(codegen.verify_module().is_ok());
+        let ir = codegen.module.print_to_string().to_string();
+        assert!(ir.contains("call void @__replica_set_allocator(i32 1, ptr null, ptr null)"));
+        assert!(!ir.contains("__replica_set_memory_policy"));
+
+        // external ではホストの関数を渡す
+        let codegen = CodeGenerator::new(&context, "test", options(Allocator::External)).unwrap();
+        assert!(codegen.verify_module().is_ok());
+        let ir = codegen.module.print_to_string().to_string();
+        assert!(ir.contains("declare ptr @replica_host_alloc(i32)"));
+        assert!(ir.contains("declare void @replica_host_free(ptr)"));
+        assert!(ir.contains(
+            "call void @__replica_set_allocator(i32 2, ptr @replica_host_alloc, ptr @replica_host_free)"
+        ));
+    }
+
+    #[test]
+    fn test_actors_register_after_their_imports() {
+        let parse = |source: &str| {
+            let tokens = crate::lexer::tokenize(source).unwrap();
+            crate::parser::Parser::new(tokens).parse_actor().unwrap()
+        };
+        let mut symbols = crate::semantic::SymbolTable::new();
+        symbols.declare("Peer", &parse("actor Peer {}"));
+        let actor = parse(
+            "import Peer\npool Workers of Node(size: 2)\nactor Node {\n    func ping(peer: Peer) {}\n}",
+        );
+
+        let context = create_test_context();
+        let options = super::super::CodeGenOptions {
+            on_oom: OomBehavior::Trap,
+            ..super::super::CodeGenOptions::default()
+        };
+        let mut codegen = CodeGenerator::new(&context, "test", options).unwrap();
+        codegen
+            .import_module(symbols.module("Peer").unwrap())
+            .unwrap();
+        codegen.compile_actor(&actor).unwrap();
+
+        let ir = codegen.module.print_to_string().to_string();
+        assert!(ir.contains("define internal void @Node.register()"));
+        assert!(ir.contains(
+            "call void @__replica_register_actor(ptr @Node.registry_name, ptr @Node.new)"
+        ));
+        assert!(ir.contains(
+            "call void @__replica_register_actor(ptr @Workers.registry_name, ptr @Workers.new)"
+        ));
+        // メモリ方針が先で、アクターはインポート先より一段後
+        assert!(ir.contains("{ i32 101, ptr @replica.memory_policy, ptr null }"));
+        assert!(ir.contains("{ i32 1001, ptr @Node.register, ptr null }"));
+        assert!(ir.contains("define linkonce_odr void @__replica_init()"));
+    }
+
+    #[test]
+    fn test_stack_guard() {
+        let context = create_test_context();
+        let codegen = CodeGenerator::new(&context, "test", Default::default()).unwrap();
+        let function =
+            codegen
+                .module
+                .add_function("recurse", context.void_type().fn_type(&[], false), None);
+        let entry = context.append_basic_block(function, "entry");
+        codegen.builder.position_at_end(entry);
+        codegen.build_stack_guard(function, 1040).unwrap();
+        codegen.builder.build_return(None).unwrap();
+        assert!(codegen.verify_module().is_ok());
+
+        let ir = codegen.module.print_to_string().to_string();
+        assert!(ir.contains("@__stack_low = external global i8"));
+        assert!(ir.contains("call ptr @llvm.frameaddress.p0(i32 0)"));
+        assert!(ir.contains("ptr @__stack_low, i32 1040"));
+        assert!(ir.contains("call void @__replica_stack_overflow()"));
+    }
+
+    #[test]
+    fn test_threads_conflicting_with_disabled_atomics() {
+        let context = create_test_context();
+        let options = super::super::CodeGenOptions {
+            target_features: String::from("-atomics"),
+            wasm_features: vec![WasmFeature::Threads],
+            ..super::super::CodeGenOptions::default()
+        };
+        assert!(CodeGenerator::new(&context, "test", options).is_err());
+    }
+
+    #[test]
+    fn test_inout_parameters_are_pointers() {
+        let context = create_test_context();
+        let options = super::super::CodeGenOptions::default();
+        let codegen = CodeGenerator::new(&context, "test", options).unwrap();
+
+        let method = Method {
+            name: "increment".to_string(),
+            is_async: false,
+            is_sequential: false,
+            is_immediate: false,
+            is_pure: false,
+            is_nonreentrant: false,
+            shard_key: None,
+            retry: None,
+            is_idempotent: false,
+            since: None,
+            removed: None,
+            params: vec![crate::ast::Parameter {
+                name: "value".to_string(),
+                param_type: Type::Int,
+                ownership: OwnershipType::Inout,
+            }],
+            return_type: None,
+            body: None,
+            doc: None,
+        };
+        let function_type = codegen.create_method_type(&method).unwrap();
+
+        // 先頭はインスタンスへのポインタ
+        assert!(function_type.get_return_type().is_none());
+        assert_eq!(function_type.count_param_types(), 2);
+        assert!(function_type.get_param_types()[0].is_pointer_type());
+        assert!(function_type.get_param_types()[1].is_pointer_type());
+    }
+
+    #[test]
+    fn test_extern_functions_are_imports() {
+        let context = create_test_context();
+        let handle = || Type::Custom("FileHandle".to_string());
+        let actor = Actor {
+            name: "Reader".to_string(),
+            actor_type: ActorType::Single,
+            methods: vec![],
+            fields: vec![],
+            init: None,
+            deinit: None,
+            layout: Default::default(),
+            externs: vec![
+                Extern::Type("FileHandle".to_string()),
+                Extern::Function(crate::ast::ExternFunction {
+                    name: "close".to_string(),
+                    params: vec![Parameter {
+                        name: "file".to_string(),
+                        param_type: handle(),
+                        ownership: OwnershipType::Owned,
+                    }],
+                    return_type: None,
+                    proto: None,
+                    requires: vec!["fs".to_string()],
+                }),
+            ],
+            imports: vec![],
+            topics: vec![],
+            pools: vec![],
+            structs: vec![],
+            enums: vec![],
+            constants: vec![],
+            protocol: None,
+            doc: None,
+        };
+
+        let options = super::super::CodeGenOptions::default();
+        let mut codegen = CodeGenerator::new(&context, "test", options).unwrap();
+        codegen.compile_actor(&actor).unwrap();
+        let close = codegen.module.get_function("close").unwrap();
+        assert_eq!(close.count_basic_blocks(), 0);
+        assert!(close.get_type().get_param_types()[0].is_int_type());
+        // 必要な権限はカスタムセクションに記録される
+        let ir = codegen.module.print_to_string().to_string();
+        assert!(ir.contains("!\"replica.capabilities\""));
+        assert!(ir.contains("\\22capability\\22:\\22fs\\22"));
+
+        // reference-types が有効なら externref で受け渡す
+        let options = super::super::CodeGenOptions {
+            target_features: String::from("+reference-types"),
+            ..super::super::CodeGenOptions::default()
+        };
+        let mut codegen = CodeGenerator::new(&context, "test", options).unwrap();
+        codegen.compile_actor(&actor).unwrap();
+        let close = codegen.module.get_function("close").unwrap();
+        assert!(close.get_type().get_param_types()[0].is_pointer_type());
+        assert!(codegen
+            .module
+            .print_to_string()
+            .to_string()
+            .contains("ptr addrspace(10)"));
+    }
+
+    #[test]
+    fn test_callbacks_use_funcref_with_reference_types() {
+        let context = create_test_context();
+        let actor = Actor {
+            name: "Timer".to_string(),
+            actor_type: ActorType::Single,
+            methods: vec![],
+            fields: vec![],
+            init: None,
+            deinit: None,
+            layout: Default::default(),
+            externs: vec![Extern::Function(crate::ast::ExternFunction {
+                name: "on_tick".to_string(),
+                params: vec![Parameter {
+                    name: "callback".to_string(),
+                    param_type: Type::Function {
+                        params: vec![],
+                        return_type: None,
+                    },
+                    ownership: OwnershipType::Owned,
+                }],
+                return_type: None,
+                proto: None,
+                requires: vec![],
+            })],
+            imports: vec![],
+            topics: vec![],
+            pools: vec![],
+            structs: vec![],
+            enums: vec![],
+            constants: vec![],
+            protocol: None,
+            doc: None,
+        };
+
+        let options = super::super::CodeGenOptions::default();
+        let mut codegen = CodeGenerator::new(&context, "test", options).unwrap();
+        codegen.compile_actor(&actor).unwrap();
+        assert!(codegen.module.get_function("replica.funcref").is_none());
+
+        let options = super::super::CodeGenOptions {
+            target_features: String::from("+reference-types"),
+            ..super::super::CodeGenOptions::default()
+        };
+        let mut codegen = CodeGenerator::new(&context, "test", options).unwrap();
+        codegen.compile_actor(&actor).unwrap();
+        assert!(codegen.module.get_function("replica.funcref").is_some());
+        let ir = codegen.module.print_to_string().to_string();
+        assert!(ir.contains("ptr addrspace(20)"));
+        assert!(ir.contains("@__indirect_function_table"));
+    }
+
+    #[test]
+    fn test_proto_codecs_are_defined() {
+        let context = create_test_context();
+        let point = Type::Record(vec![
+            ("x".to_string(), Type::Int),
+            ("label".to_string(), Type::String),
+        ]);
+        let bytes = || Type::Array(Box::new(Type::Int));
+        let codec = |name: &str, param_type, return_type| {
+            Extern::Function(crate::ast::ExternFunction {
+                name: name.to_string(),
+                params: vec![Parameter {
+                    name: "value".to_string(),
+                    param_type,
+                    ownership: OwnershipType::Owned,
+                }],
+                return_type: Some(return_type),
+                proto: Some("1:sint32, 2:string".to_string()),
+                requires: vec![],
+            })
+        };
+        let actor = Actor {
+            name: "PointProto".to_string(),
+            actor_type: ActorType::Single,
+            methods: vec![],
+            fields: vec![],
+            init: None,
+            deinit: None,
+            layout: Default::default(),
+            externs: vec![
+                codec("encodePoint", point.clone(), bytes()),
+                codec("decodePoint", bytes(), Type::Optional(Box::new(point))),
+            ],
+            imports: vec![],
+            topics: vec![],
+            pools: vec![],
+            structs: vec![],
+            enums: vec![],
+            constants: vec![],
+            protocol: None,
+            doc: None,
+        };
+
+        let options = super::super::CodeGenOptions::default();
+        let mut codegen = CodeGenerator::new(&context, "test", options).unwrap();
+        codegen.compile_actor(&actor).unwrap();
+
+        // インポートではなくモジュール内で定義される
+        let encode = codegen.module.get_function("encodePoint").unwrap();
+        assert_eq!(encode.count_basic_blocks(), 1);
+        assert_eq!(encode.get_linkage(), Linkage::Internal);
+        let ir = codegen.module.print_to_string().to_string();
+        assert!(ir.contains("call ptr @__replica_proto_encode"));
+        assert!(ir.contains("call i32 @__replica_proto_decode"));
+        // x はフィールド 1 (sint32) でオフセット 0、label はフィールド 2 (string) でオフセット 4
+        assert!(ir.contains("{ i32 1, i32 1, i32 0, ptr null }, { i32 2, i32 8, i32 4, ptr null }"));
+    }
+
+    #[test]
+    fn test_labeled_break_and_continue() {
+        let context = create_test_context();
+        let options = super::super::CodeGenOptions::default();
+        let mut codegen = CodeGenerator::new(&context, "test", options).unwrap();
+
+        let function =
+            codegen
+                .module
+                .add_function("loops", context.void_type().fn_type(&[], false), None);
+        let entry = context.append_basic_block(function, "entry");
+        let outer_exit = context.append_basic_block(function, "outer_exit");
+        let inner_exit = context.append_basic_block(function, "inner_exit");
+        let inner_next = context.append_basic_block(function, "inner_next");
+        codegen.builder.position_at_end(entry);
+
+        assert!(codegen.compile_statement(&Statement::Break(None)).is_err());
+
+        codegen.push_loop(Some("outer".to_string()), outer_exit, outer_exit);
+        codegen.push_loop(None, inner_exit, inner_next);
+        codegen
+            .compile_statement(&Statement::Break(Some("outer".to_string())))
+            .unwrap();
+        codegen
+            .compile_statement(&Statement::Continue(None))
+            .unwrap();
+        codegen.pop_loop();
+        codegen.pop_loop();
+
+        let ir = codegen.module.print_to_string().to_string();
+        assert!(ir.contains("br label %outer_exit"));
+        assert!(ir.contains("br label %inner_next"));
+    }
+
+    #[test]
+    fn test_if_else_branches_merge() {
+        let context = create_test_context();
+        let options = super::super::CodeGenOptions::default();
+        let mut codegen = CodeGenerator::new(&context, "test", options).unwrap();
+
+        let function =
+            codegen
+                .module
+                .add_function("choose", context.i32_type().fn_type(&[], false), None);
+        let entry = context.append_basic_block(function, "entry");
+        codegen.builder.position_at_end(entry);
+
+        let int = |value| Expression::Literal(LiteralValue::Int(value));
+        let flag = |value| Expression::Literal(LiteralValue::Bool(value));
+        // if true { return 1 } else if false { return 2 } else { return 3 }
+        let statement = Statement::If {
+            condition: flag(true),
+            then_body: MethodBody {
+                statements: vec![Statement::Return(int(1))],
+            },
+            else_body: Some(MethodBody {
+                statements: vec![Statement::If {
+                    condition: flag(false),
+                    then_body: MethodBody {
+                        statements: vec![Statement::Return(int(2))],
+                    },
+                    else_body: Some(MethodBody {
+                        statements: vec![Statement::Return(int(3))],
+                    }),
+                }],
+            }),
+        };
+        codegen.compile_statement(&statement).unwrap();
+        codegen
+            .compile_statement(&Statement::Return(int(4)))
+            .unwrap();
+
+        assert!(function.verify(true));
+        let ir = codegen.module.print_to_string().to_string();
+        assert!(ir.contains("br i1 true, label %then, label %else"));
+        assert_eq!(ir.matches("br i1 false").count(), 1);
+        assert!(ir.contains("br label %if_end"));
+        assert!(ir.contains("ret i32 4"));
+    }
+
+    #[test]
+    fn test_loops_with_break_and_continue() {
+        let context = create_test_context();
+        let options = super::super::CodeGenOptions::default();
+        let mut codegen = CodeGenerator::new(&context, "test", options).unwrap();
+
+        let function =
+            codegen
+                .module
+                .add_function("count", context.i32_type().fn_type(&[], false), None);
+        let entry = context.append_basic_block(function, "entry");
+        codegen.builder.position_at_end(entry);
+
+        let int = |value| Expression::Literal(LiteralValue::Int(value));
+        // outer: for i in 0..10 { while true { continue outer } }
+        let statement = Statement::For {
+            label: Some("outer".to_string()),
+            variable: "i".to_string(),
+            start: int(0),
+            end: int(10),
+            body: MethodBody {
+                statements: vec![Statement::While {
+                    label: None,
+                    condition: Expression::Literal(LiteralValue::Bool(true)),
+                    body: MethodBody {
+                        statements: vec![Statement::Continue(Some("outer".to_string()))],
+                    },
+                }],
+            },
+        };
+        codegen.compile_statement(&statement).unwrap();
+        codegen
+            .compile_statement(&Statement::Return(int(0)))
+            .unwrap();
+
+        assert!(function.verify(true));
+        assert!(codegen.loop_targets.is_empty());
+        let ir = codegen.module.print_to_string().to_string();
+        // カウンタは入口ブロックで一度だけ確保する
+        let entry_ir = &ir[ir.find("entry:").unwrap()..ir.find("for_cond:").unwrap()];
+        assert!(entry_ir.contains("%i = alloca i32"));
+        assert!(ir.contains("icmp slt i32"));
+        assert!(ir.contains("br i1 true, label %while_body, label %while_end"));
+        // continue outer は外側ループの増分ブロックへ飛ぶ
+        assert!(ir.contains("br label %for_step"));
+        assert!(ir.contains("ret i32 0"));
+    }
+
+    #[test]
+    fn test_comparisons_use_signed_and_ordered_predicates() {
+        let context = create_test_context();
+        let options = super::super::CodeGenOptions::default();
+        let mut codegen = CodeGenerator::new(&context, "test", options).unwrap();
+
+        let fn_type = context.bool_type().fn_type(
+            &[context.i32_type().into(), context.f64_type().into()],
+            false,
+        );
+        let function = codegen.module.add_function("check", fn_type, None);
+        let entry = context.append_basic_block(function, "entry");
+        codegen.builder.position_at_end(entry);
+        for (name, param) in ["n", "x"].into_iter().zip(function.get_param_iter()) {
+            codegen
+                .expression_compiler
+                .register_variable(name.to_string(), param);
+        }
+
+        let compare = |left: &str, operator, right: &str| Expression::BinaryOp {
+            left: Box::new(Expression::Variable(left.to_string())),
+            operator,
+            right: Box::new(Expression::Variable(right.to_string())),
+        };
+        // if n <= n { return x != x } return x < x
+        let statement = Statement::If {
+            condition: compare("n", Operator::LessEqual, "n"),
+            then_body: MethodBody {
+                statements: vec![Statement::Return(compare("x", Operator::NotEqual, "x"))],
+            },
+            else_body: None,
+        };
+        codegen.compile_statement(&statement).unwrap();
+        codegen
+            .compile_statement(&Statement::Return(compare("x", Operator::Less, "x")))
+            .unwrap();
+
+        assert!(function.verify(true));
+        let ir = codegen.module.print_to_string().to_string();
+        assert!(ir.contains("icmp sle i32"));
+        assert!(ir.contains("fcmp une double"));
+        assert!(ir.contains("fcmp olt double"));
+    }
+
+    #[test]
+    fn test_methods_declared_before_bodies() {
+        let context = create_test_context();
+        let options = super::super::CodeGenOptions::default();
+        let mut codegen = CodeGenerator::new(&context, "test", options).unwrap();
+
+        let int = Parameter {
+            name: "n".to_string(),
+            param_type: Type::Int,
+            ownership: OwnershipType::Owned,
+        };
+        let method = |name: &str| Method {
+            name: name.to_string(),
+            is_async: false,
+            is_sequential: false,
+            is_immediate: false,
+            is_pure: false,
+            is_nonreentrant: false,
+            shard_key: None,
+            retry: None,
+            is_idempotent: false,
+            since: None,
+            removed: None,
+            params: vec![int.clone()],
+            return_type: Some(Type::Int),
+            body: None,
+            doc: None,
+        };
+        let actor = Actor {
+            name: "Parity".to_string(),
+            actor_type: ActorType::Single,
+            methods: vec![method("isEven"), method("isOdd")],
+            fields: vec![],
+            init: None,
+            deinit: None,
+            layout: Default::default(),
+            externs: vec![],
+            imports: vec![],
+            topics: vec![],
+            pools: vec![],
+            structs: vec![],
+            enums: vec![],
+            constants: vec![],
+            protocol: None,
+            doc: None,
+        };
+        codegen.current_actor = actor.name.clone();
+        codegen.declare_methods(&actor).unwrap();
+
+        // isEven から後方で宣言された isOdd を、isOdd から isEven を呼ぶ
+        let call = |callee: &str| Expression::Call {
+            callee: callee.to_string(),
+            type_args: Vec::new(),
+            args: vec![Expression::Literal(LiteralValue::Int(1))],
+        };
+        for (caller, callee) in [("isEven", "isOdd"), ("isOdd", "isEven")] {
+            let function = codegen.actor_methods[&format!("Parity.{}", caller)];
+            let entry = context.append_basic_block(function, "entry");
+            codegen.builder.position_at_end(entry);
+            let this = function.get_first_param().unwrap().into_pointer_value();
+            codegen.expression_compiler.set_instance(this);
+            codegen
+                .compile_statement(&Statement::Return(call(callee)))
+                .unwrap();
+            assert!(function.verify(true));
+        }
+
+        let ir = codegen.module.print_to_string().to_string();
+        assert!(ir.contains("call i32 @Parity.isOdd(ptr %0, i32 1)"));
+        assert!(ir.contains("call i32 @Parity.isEven(ptr %0, i32 1)"));
+
+        let extra = Expression::Call {
+            callee: "isOdd".to_string(),
+            type_args: Vec::new(),
+            args: vec![],
+        };
+        assert!(codegen
+            .compile_statement(&Statement::Return(extra))
+            .is_err());
+    }
+
+    #[test]
+    fn test_methods_read_and_assign_fields_through_self() {
+        let context = create_test_context();
+        let options = super::super::CodeGenOptions::default();
+        let mut codegen = CodeGenerator::new(&context, "test", options).unwrap();
+
+        let field = |name: &str, is_mutable| crate::ast::Field {
+            name: name.to_string(),
+            field_type: Type::Int,
+            is_mutable,
+            ownership: OwnershipType::Owned,
+            offset: None,
+            doc: None,
+        };
+        let method = |name: &str, params: Vec<Parameter>, statements| Method {
+            name: name.to_string(),
+            is_async: false,
+            is_sequential: false,
+            is_immediate: false,
+            is_pure: false,
+            is_nonreentrant: false,
+            shard_key: None,
+            retry: None,
+            is_idempotent: false,
+            since: None,
+            removed: None,
+            params,
+            return_type: Some(Type::Int),
+            body: Some(MethodBody { statements }),
+            doc: None,
+        };
+        let add = |left: Expression, right: &str| Expression::BinaryOp {
+            left: Box::new(left),
+            operator: Operator::Add,
+            right: Box::new(Expression::Variable(right.to_string())),
+        };
+        // increment(by) { self.count = self.count + by; return count }
+        let increment = method(
+            "increment",
+            vec![Parameter {
+                name: "by".to_string(),
+                param_type: Type::Int,
+                ownership: OwnershipType::Owned,
+            }],
+            vec![
+                Statement::Assign {
+                    target: Expression::Field("count".to_string()),
+                    operator: None,
+                    value: add(Expression::Field("count".to_string()), "by"),
+                },
+                Statement::Return(Expression::Variable("count".to_string())),
+            ],
+        );
+        // tick() { return self.increment(step) }
+        let tick = method(
+            "tick",
+            vec![],
+            vec![Statement::Return(Expression::Call {
+                callee: "increment".to_string(),
+                type_args: Vec::new(),
+                args: vec![Expression::Variable("step".to_string())],
+            })],
+        );
+        let actor = Actor {
+            name: "Counter".to_string(),
+            actor_type: ActorType::Single,
+            methods: vec![increment, tick],
+            fields: vec![field("count", true), field("step", false)],
+            init: None,
+            deinit: None,
+            layout: Default::default(),
+            externs: vec![],
+            imports: vec![],
+            topics: vec![],
+            pools: vec![],
+            structs: vec![],
+            enums: vec![],
+            constants: vec![],
+            protocol: None,
+            doc: None,
+        };
+        codegen.compile_actor(&actor).unwrap();
+
+        let ir = codegen.module.print_to_string().to_string();
+        assert!(ir.contains("define i32 @Counter.increment(ptr %self, i32 %by)"));
+        assert!(ir.contains("getelementptr inbounds %Counter, ptr %self, i32 0, i32 0"));
+        assert!(ir.contains("call void @Counter.set_count(ptr %self, i32 %addtmp)"));
+        assert!(ir.contains("load i32, ptr %step"));
+        assert!(ir.contains("call i32 @Counter.increment(ptr %self, i32 %step"));
+        // ホストがインスタンスを作れるよう、コンストラクタも公開する
+        assert_eq!(
+            codegen.exported_symbols(),
+            vec!["Counter.new", "increment", "tick"]
+        );
+    }
+
+    #[test]
+    fn test_var_bindings_are_assigned_through_stack_slots() {
+        let context = create_test_context();
+        let options = super::super::CodeGenOptions::default();
+        let mut codegen = CodeGenerator::new(&context, "test", options).unwrap();
+
+        let variable = |name: &str| Expression::Variable(name.to_string());
+        let binding = |name: &str, is_mutable| Statement::Let {
+            pattern: Pattern::Binding(name.to_string()),
+            is_mutable,
+            value: Expression::Literal(LiteralValue::Int(0)),
+        };
+        let add_assign = |target, name: &str| Statement::Assign {
+            target,
+            operator: Some(Operator::Add),
+            value: variable(name),
+        };
+        // sum(n) { var total = 0; for i in 0..n { total += i }; count += total; return total }
+        let sum = Method {
+            name: "sum".to_string(),
+            is_async: false,
+            is_sequential: false,
+            is_immediate: false,
+            is_pure: false,
+            is_nonreentrant: false,
+            shard_key: None,
+            retry: None,
+            is_idempotent: false,
+            since: None,
+            removed: None,
+            params: vec![Parameter {
+                name: "n".to_string(),
+                param_type: Type::Int,
+                ownership: OwnershipType::Owned,
+            }],
+            return_type: Some(Type::Int),
+            body: Some(MethodBody {
+                statements: vec![
+                    binding("total", true),
+                    Statement::For {
+                        label: None,
+                        variable: "i".to_string(),
+                        start: Expression::Literal(LiteralValue::Int(0)),
+                        end: variable("n"),
+                        body: MethodBody {
+                            statements: vec![add_assign(variable("total"), "i")],
+                        },
+                    },
+                    add_assign(variable("count"), "total"),
+                    Statement::Return(variable("total")),
+                ],
+            }),
+            doc: None,
+        };
+        let actor = Actor {
+            name: "Counter".to_string(),
+            actor_type: ActorType::Single,
+            methods: vec![sum],
+            fields: vec![crate::ast::Field {
+                name: "count".to_string(),
+                field_type: Type::Int,
+                is_mutable: true,
+                ownership: OwnershipType::Owned,
+                offset: None,
+                doc: None,
+            }],
+            init: None,
+            deinit: None,
+            layout: Default::default(),
+            externs: vec![],
+            imports: vec![],
+            topics: vec![],
+            pools: vec![],
+            structs: vec![],
+            enums: vec![],
+            constants: vec![],
+            protocol: None,
+            doc: None,
+        };
+        codegen.compile_actor(&actor).unwrap();
+        assert!(codegen.actor_methods["Counter.sum"].verify(true));
+
+        let ir = codegen.module.print_to_string().to_string();
+        assert!(ir.contains("%total = alloca i32"));
+        assert!(ir.contains("store i32 %addtmp, ptr %total"));
+        assert!(ir.contains("call void @Counter.set_count(ptr %self, i32 %addtmp"));
+
+        // let の束縛には代入できない
+        let function =
+            codegen
+                .module
+                .add_function("fixed", context.void_type().fn_type(&[], false), None);
+        codegen
+            .builder
+            .position_at_end(context.append_basic_block(function, "entry"));
+        codegen.compile_statement(&binding("fixed", false)).unwrap();
+        assert!(codegen
+            .compile_statement(&add_assign(variable("fixed"), "fixed"))
+            .is_err());
+    }
+
+    #[test]
+    fn test_random_builtins_use_selected_source() {
+        let context = create_test_context();
+        for (source, bits) in [
+            (RandomSource::Module, "call i64 @__replica_random_next()"),
+            (RandomSource::Host, "call i64 @replica_random_bits()"),
+        ] {
+            let options = super::super::CodeGenOptions {
+                random: source,
+                ..Default::default()
+            };
+            let mut codegen = CodeGenerator::new(&context, "test", options).unwrap();
+
+            let function =
+                codegen
+                    .module
+                    .add_function("roll", context.i32_type().fn_type(&[], false), None);
+            let entry = context.append_basic_block(function, "entry");
+            codegen.builder.position_at_end(entry);
+            let roll = Expression::Random {
+                operation: RandomOperation::Int,
+                args: vec![Expression::Literal(LiteralValue::Int(6))],
+            };
+            codegen.compile_statement(&Statement::Return(roll)).unwrap();
+            assert!(function.verify(true));
+
+            let ir = codegen.module.print_to_string().to_string();
+            assert!(ir.contains(bits));
+            assert!(ir.contains("call i32 @__replica_random_int(i64 %bits, i32 6)"));
+        }
+    }
+
+    #[test]
+    fn test_config_values_are_baked_or_read_from_host() {
+        let context = create_test_context();
+        let options = super::super::CodeGenOptions {
+            config: BTreeMap::from([
+                (
+                    "GREETING".to_string(),
+                    ConfigValue::Fixed("hello".to_string()),
+                ),
+                (
+                    "API_URL".to_string(),
+                    ConfigValue::Runtime {
+                        default: "http://localhost".to_string(),
+                    },
+                ),
+            ]),
+            ..Default::default()
+        };
+        let mut codegen = CodeGenerator::new(&context, "test", options).unwrap();
 
-        // WASSMバイトコードの生成
-        target_machine
-            .write_to_memory_buffer(&self.module, FileType::Object)
-            .map(|buffer| buffer.as_slice().to_vec())
-            .map_err(|e| CodeGenError::WasmGen(format!("Failed to emit WASM: {}", e)))
+        let ptr_type = context.ptr_type(AddressSpace::default());
+        for key in ["GREETING", "API_URL"] {
+            let function = codegen.module.add_function(
+                &format!("read_{}", key),
+                ptr_type.fn_type(&[], false),
+                None,
+            );
+            codegen
+                .builder
+                .position_at_end(context.append_basic_block(function, "entry"));
+            let config = Expression::Config(key.to_string());
+            codegen
+                .compile_statement(&Statement::Return(config))
+                .unwrap();
+            assert!(function.verify(true));
+        }
+
+        let ir = codegen.module.print_to_string().to_string();
+        assert!(ir.contains("@config.GREETING.text = private constant [5 x i8] c\"hello\""));
+        assert!(ir.contains("ret ptr @config.GREETING"));
+        assert!(ir.contains("%config = call ptr @replica_env_get(ptr @config.API_URL.key)"));
+        assert!(ir.contains("select i1 %unset, ptr @config.API_URL.default, ptr %config"));
+
+        let unknown = Expression::Config("PORT".to_string());
+        assert!(codegen
+            .compile_statement(&Statement::Return(unknown))
+            .is_err());
     }
 
-    /// Verifies the generated module
-    fn verify_module(&self) -> CodeGenResult<()> {
-        self.module
-            .verify()
-            .map_err(|e| CodeGenError::Validation(format!("Module verification failed: {}", e)))
+    #[test]
+    fn test_log_levels_below_threshold_are_removed() {
+        let context = create_test_context();
+        let options = super::super::CodeGenOptions {
+            log_level: LogLevel::Info,
+            ..Default::default()
+        };
+        let mut codegen = CodeGenerator::new(&context, "test", options).unwrap();
+        codegen.current_actor = "Counter".to_string();
+
+        let function =
+            codegen
+                .module
+                .add_function("tick", context.void_type().fn_type(&[], false), None);
+        let entry = context.append_basic_block(function, "entry");
+        codegen.builder.position_at_end(entry);
+        let log = |level, text: &str, line| Statement::Log {
+            level,
+            message: Expression::Literal(LiteralValue::String(text.to_string())),
+            line,
+        };
+        codegen
+            .compile_statement(&log(LogLevel::Debug, "verbose", 3))
+            .unwrap();
+        codegen
+            .compile_statement(&log(LogLevel::Warn, "low", 4))
+            .unwrap();
+        codegen.builder.build_return(None).unwrap();
+        assert!(function.verify(true));
+
+        let ir = codegen.module.print_to_string().to_string();
+        // debug の呼び出しはメッセージごと出力から消える
+        assert!(!ir.contains("verbose"));
+        assert_eq!(ir.matches("call void @replica_log").count(), 1);
+        assert!(
+            ir.contains("call void @replica_log(i32 2, ptr @log.message, ptr @log.actor, i32 4)")
+        );
+        assert!(ir.contains("c\"Counter\""));
+        assert!(ir.contains("{ ptr @log.message.text, i32 3 }"));
     }
 
-    // Helper methods for debugging
-    fn debug_log(&self, message: &str) {
-        if self.debug_mode {
-            eprintln!("[CodeGen Debug] {}", message);
+    #[test]
+    fn test_make_unique_before_mutation() {
+        let context = create_test_context();
+        let options = super::super::CodeGenOptions::default();
+        let codegen = CodeGenerator::new(&context, "test", options).unwrap();
+
+        let ptr_type = context.ptr_type(AddressSpace::default());
+        let function = codegen.module.add_function(
+            "mutate",
+            ptr_type.fn_type(&[ptr_type.into()], false),
+            None,
+        );
+        codegen
+            .builder
+            .position_at_end(context.append_basic_block(function, "entry"));
+
+        let buffer = function.get_first_param().unwrap().into_pointer_value();
+        let size = context.i32_type().const_int(16, false);
+        let unique = codegen.build_make_unique(buffer, size).unwrap();
+        codegen.builder.build_return(Some(&unique)).unwrap();
+
+        assert!(function.verify(false));
+    }
+
+    #[test]
+    fn test_async_methods_are_queued_through_mailboxes() {
+        let context = create_test_context();
+        let options = super::super::CodeGenOptions::default();
+        let mut codegen = CodeGenerator::new(&context, "test", options).unwrap();
+
+        let method = |name: &str, params| Method {
+            name: name.to_string(),
+            is_async: true,
+            is_sequential: false,
+            is_immediate: false,
+            is_pure: false,
+            is_nonreentrant: false,
+            shard_key: None,
+            retry: None,
+            is_idempotent: false,
+            since: None,
+            removed: None,
+            params,
+            return_type: None,
+            body: Some(MethodBody { statements: vec![] }),
+            doc: None,
+        };
+        let amount = Parameter {
+            name: "amount".to_string(),
+            param_type: Type::Int,
+            ownership: OwnershipType::Owned,
+        };
+        let actor = Actor {
+            name: "Counter".to_string(),
+            actor_type: ActorType::Distributed,
+            methods: vec![method("add", vec![amount.clone()]), method("reset", vec![])],
+            fields: vec![],
+            init: None,
+            deinit: None,
+            layout: Default::default(),
+            externs: vec![],
+            imports: vec![],
+            topics: vec![],
+            pools: vec![],
+            structs: vec![],
+            enums: vec![],
+            constants: vec![],
+            protocol: None,
+            doc: None,
+        };
+        codegen.compile_actor(&actor).unwrap();
+
+        let ir = codegen.module.print_to_string().to_string();
+        // 引数は確保した構造体に詰めてタグ付きで積む
+        assert!(ir.contains("define i32 @Counter.add.post(ptr %mailbox, i32 %amount)"));
+        assert!(ir.contains("call i32 @__replica_mailbox_push(ptr %mailbox, i32 0, ptr %payload,"));
+        assert!(
+            ir.contains("call i32 @__replica_mailbox_push(ptr %mailbox, i32 1, ptr null, i32 0)")
+        );
+        assert!(ir.contains("call i32 @__replica_schedule(ptr %mailbox, ptr @Counter.dispatch)"));
+        // スケジューラはタグでメソッドを選び、引数を取り出して呼ぶ
+        assert!(ir.contains("switch i32 %tag, label %done"));
+        assert!(ir.contains("call void @Counter.add(ptr %actor, i32 %arg)"));
+        assert!(ir.contains("call void @Counter.reset(ptr %actor)"));
+        // 一つのメールボックスだけを順に処理する入口も公開する
+        assert!(ir.contains("define i32 @Counter.drain(ptr %mailbox, i32 %budget)"));
+        assert!(ir.contains(
+            "call i32 @__replica_drain(ptr %mailbox, ptr @Counter.dispatch, i32 %budget)"
+        ));
+        assert_eq!(
+            codegen.exported_symbols(),
+            vec![
+                "Counter.add",
+                "Counter.add.post",
+                "Counter.drain",
+                "Counter.new",
+                "Counter.reset",
+                "Counter.reset.post"
+            ]
+        );
+
+        // inout 引数の参照先はメッセージの処理まで生存しない
+        let mut inout = amount;
+        inout.ownership = OwnershipType::Inout;
+        let actor = Actor {
+            name: "Borrower".to_string(),
+            methods: vec![method("adjust", vec![inout])],
+            ..actor
+        };
+        assert!(matches!(
+            codegen.compile_actor(&actor),
+            Err(CodeGenError::InvalidOperation(_))
+        ));
+    }
+
+    #[test]
+    fn test_nonreentrant_methods_defer_messages() {
+        let context = create_test_context();
+        let options = super::super::CodeGenOptions::default();
+        let mut codegen = CodeGenerator::new(&context, "test", options).unwrap();
+
+        let method = |name: &str, is_nonreentrant| Method {
+            name: name.to_string(),
+            is_async: true,
+            is_sequential: false,
+            is_immediate: false,
+            is_pure: false,
+            is_nonreentrant,
+            shard_key: None,
+            retry: None,
+            is_idempotent: false,
+            since: None,
+            removed: None,
+            params: vec![],
+            return_type: None,
+            body: Some(MethodBody { statements: vec![] }),
+            doc: None,
+        };
+        let actor = Actor {
+            name: "Cache".to_string(),
+            actor_type: ActorType::Distributed,
+            methods: vec![method("refresh", true), method("get", false)],
+            fields: vec![],
+            init: None,
+            deinit: None,
+            layout: Default::default(),
+            externs: vec![],
+            imports: vec![],
+            topics: vec![],
+            pools: vec![],
+            structs: vec![],
+            enums: vec![],
+            constants: vec![],
+            protocol: None,
+            doc: None,
+        };
+        codegen.compile_actor(&actor).unwrap();
+
+        let ir = codegen.module.print_to_string().to_string();
+        // 状態の末尾に実行中フラグと保留キューが付く
+        assert!(ir.contains("%Cache = type { i32, ptr }"));
+        assert!(ir.contains("call ptr @__replica_mailbox_new(ptr %object, i32 64)"));
+        // @nonreentrant のメソッドはフラグを取り、他のメッセージはフラグを確かめる
+        assert!(ir.contains("cmpxchg ptr %busy, i32 0, i32 1 acq_rel acquire"));
+        assert!(ir.contains("atomicrmw xchg ptr %busy, i32 0 release"));
+        assert!(ir.contains("load atomic i32, ptr %busy acquire, align 4"));
+        // 実行中に届いたメッセージはペイロードごと保留し、後で振り分け直す
+        assert!(ir.contains(
+            "call i32 @__replica_mailbox_push(ptr %queue, i32 %tag, ptr %payload, i32 %len)"
+        ));
+        assert!(ir.contains("call i32 @__replica_mailbox_pop("));
+        assert!(ir.contains("call void @Cache.dispatch(ptr %actor, i32 %deferred.tag, ptr %deferred.payload, i32 %deferred.len, ptr %mailbox)"));
+        // 破棄時にキューを解放する
+        assert!(ir.contains("call void @__replica_free(ptr %deferred)"));
+        assert!(codegen.verify_module().is_ok());
+    }
+
+    #[test]
+    fn test_topics_route_through_runtime_or_transport() {
+        let ticker = || {
+            let method = |name: &str, params, statements| Method {
+                name: name.to_string(),
+                is_async: true,
+                is_sequential: false,
+                is_immediate: false,
+                is_pure: false,
+                is_nonreentrant: false,
+                shard_key: None,
+                retry: None,
+                is_idempotent: false,
+                since: None,
+                removed: None,
+                params,
+                return_type: None,
+                body: Some(MethodBody { statements }),
+                doc: None,
+            };
+            Actor {
+                name: "Ticker".to_string(),
+                actor_type: ActorType::Distributed,
+                methods: vec![
+                    method(
+                        "start",
+                        vec![],
+                        vec![Statement::Subscribe("Prices".to_string())],
+                    ),
+                    method(
+                        "tick",
+                        vec![],
+                        vec![Statement::Publish {
+                            topic: "Prices".to_string(),
+                            value: Expression::Literal(LiteralValue::Int(42)),
+                        }],
+                    ),
+                    method(
+                        "onPrices",
+                        vec![Parameter {
+                            name: "value".to_string(),
+                            param_type: Type::Int,
+                            ownership: OwnershipType::Owned,
+                        }],
+                        vec![],
+                    ),
+                ],
+                fields: vec![],
+                init: None,
+                deinit: None,
+                layout: Default::default(),
+                externs: vec![],
+                imports: vec![],
+                topics: vec![Topic {
+                    name: "Prices".to_string(),
+                    value_type: Type::Int,
+                }],
+                pools: vec![],
+                structs: vec![],
+                enums: vec![],
+                constants: vec![],
+                protocol: None,
+                doc: None,
+            }
+        };
+
+        let context = create_test_context();
+        let options = super::super::CodeGenOptions::default();
+        let mut codegen = CodeGenerator::new(&context, "test", options).unwrap();
+        codegen.compile_actor(&ticker()).unwrap();
+        let ir = codegen.module.print_to_string().to_string();
+        // 購読するアクターは受信箱を持ち、破棄時に購読を外す
+        assert!(ir.contains("%Ticker = type { ptr }"));
+        assert!(ir.contains("call void @__replica_subscribe(ptr @topic.Prices, ptr %inbox, ptr @Ticker.dispatch, i32 2)"));
+        assert!(
+            ir.contains("call i32 @__replica_publish(ptr @topic.Prices, ptr %topic.value, i32 ")
+        );
+        assert!(ir.contains("call void @__replica_unsubscribe(ptr %inbox)"));
+        assert!(codegen.verify_module().is_ok());
+
+        let options = super::super::CodeGenOptions {
+            topics: TopicRoute::Transport,
+            ..Default::default()
+        };
+        let mut codegen = CodeGenerator::new(&context, "transport", options).unwrap();
+        codegen.compile_actor(&ticker()).unwrap();
+        let ir = codegen.module.print_to_string().to_string();
+        // ホストが配送するので受信箱は要らない
+        assert!(!ir.contains("@__replica_subscribe"));
+        assert!(ir.contains("call void @replica_topic_subscribe(ptr @topic.Prices, ptr %self)"));
+        assert!(ir.contains(
+            "call void @replica_topic_publish(ptr @topic.Prices, ptr %topic.value, i32 "
+        ));
+        assert!(codegen.verify_module().is_ok());
+    }
+
+    #[test]
+    fn test_pools_expand_into_routers() {
+        let context = create_test_context();
+        let options = super::super::CodeGenOptions::default();
+        let mut codegen = CodeGenerator::new(&context, "test", options).unwrap();
+
+        let actor = Actor {
+            name: "Worker".to_string(),
+            actor_type: ActorType::Distributed,
+            methods: vec![Method {
+                name: "handle".to_string(),
+                is_async: true,
+                is_sequential: false,
+                is_immediate: false,
+                is_pure: false,
+                is_nonreentrant: false,
+                shard_key: None,
+                retry: None,
+                is_idempotent: false,
+                since: None,
+                removed: None,
+                params: vec![Parameter {
+                    name: "user".to_string(),
+                    param_type: Type::String,
+                    ownership: OwnershipType::Owned,
+                }],
+                return_type: None,
+                body: Some(MethodBody { statements: vec![] }),
+                doc: None,
+            }],
+            fields: vec![],
+            init: None,
+            deinit: None,
+            layout: Default::default(),
+            externs: vec![],
+            imports: vec![],
+            topics: vec![],
+            pools: vec![
+                Pool {
+                    name: "Workers".to_string(),
+                    actor: "Worker".to_string(),
+                    size: 4,
+                    strategy: PoolStrategy::RoundRobin,
+                },
+                Pool {
+                    name: "Sessions".to_string(),
+                    actor: "Worker".to_string(),
+                    size: 2,
+                    strategy: PoolStrategy::ConsistentHash("user".to_string()),
+                },
+            ],
+            structs: vec![],
+            enums: vec![],
+            constants: vec![],
+            protocol: None,
+            doc: None,
+        };
+        codegen.compile_actor(&actor).unwrap();
+
+        let ir = codegen.module.print_to_string().to_string();
+        // ルーターはインスタンスとそのメールボックスを持つ
+        assert!(ir.contains("%Workers = type { [4 x ptr], [4 x ptr], i32 }"));
+        assert!(ir.contains("call ptr @Worker.new()"));
+        assert!(ir.contains("call ptr @__replica_mailbox_new(ptr %instance, i32 64)"));
+        // 順番に配るか、キーのハッシュで配る
+        assert!(ir.contains("atomicrmw add ptr %next, i32 1 monotonic"));
+        assert!(ir.contains("call i64 @__replica_string_hash(ptr"));
+        assert!(ir.contains("call i32 @__replica_jump_hash(i64 %key, i32 2)"));
+        assert!(ir.contains("call i32 @Worker.handle.post(ptr %mailbox, ptr"));
+        for symbol in [
+            "Workers.new",
+            "Workers.handle",
+            "Sessions.new",
+            "Sessions.handle",
+        ] {
+            assert!(
+                codegen.exported_symbols().iter().any(|s| s == symbol),
+                "{}",
+                symbol
+            );
         }
+        assert!(codegen.verify_module().is_ok());
     }
 
-    // Private helper methods for method compilation
-    fn create_method_type(
-        &self,
-        method: &Method,
-    ) -> CodeGenResult<inkwell::types::FunctionType<'ctx>> {
-        // 実装
-        todo!()
+    #[test]
+    fn test_sharded_methods_get_remote_proxies() {
+        let context = create_test_context();
+        let options = super::super::CodeGenOptions::default();
+        let mut codegen = CodeGenerator::new(&context, "test", options).unwrap();
+
+        let param = |name: &str, param_type: Type| Parameter {
+            name: name.to_string(),
+            param_type,
+            ownership: OwnershipType::Owned,
+        };
+        let actor = Actor {
+            name: "Accounts".to_string(),
+            actor_type: ActorType::Distributed,
+            methods: vec![Method {
+                name: "deposit".to_string(),
+                is_async: true,
+                is_sequential: false,
+                is_immediate: false,
+                is_pure: false,
+                is_nonreentrant: false,
+                shard_key: Some("account".to_string()),
+                retry: None,
+                is_idempotent: false,
+                since: None,
+                removed: None,
+                params: vec![param("account", Type::String), param("amount", Type::Int)],
+                return_type: None,
+                body: Some(MethodBody { statements: vec![] }),
+                doc: None,
+            }],
+            fields: vec![],
+            init: None,
+            deinit: None,
+            layout: Default::default(),
+            externs: vec![],
+            imports: vec![],
+            topics: vec![],
+            pools: vec![],
+            structs: vec![],
+            enums: vec![],
+            constants: vec![],
+            protocol: None,
+            doc: None,
+        };
+        codegen.compile_actor(&actor).unwrap();
+
+        let ir = codegen.module.print_to_string().to_string();
+        // キーのハッシュでノードを選び、.post と同じ形の引数をホストへ渡す
+        assert!(
+            ir.contains("define i32 @Accounts.deposit.remote(ptr %account, i32 %amount)"),
+            "{}",
+            ir
+        );
+        assert!(ir.contains("call i32 @replica_shard_nodes()"), "{}", ir);
+        assert!(
+            ir.contains("call i64 @__replica_string_hash(ptr %account)"),
+            "{}",
+            ir
+        );
+        assert!(
+            ir.contains("call i32 @__replica_jump_hash(i64 %key, i32 %nodes)"),
+            "{}",
+            ir
+        );
+        assert!(
+            ir.contains("call i32 @replica_shard_send(i32 %index, i32 0, ptr %payload"),
+            "{}",
+            ir
+        );
+        assert!(ir.contains("replica.sharding"), "{}", ir);
+        assert!(codegen
+            .exported_symbols()
+            .iter()
+            .any(|symbol| symbol == "Accounts.deposit.remote"));
+        assert!(codegen.verify_module().is_ok());
     }
 
-    fn process_method_parameters(
-        &mut self,
-        method: &Method,
-        function: FunctionValue<'ctx>,
-    ) -> CodeGenResult<()> {
-        // パラメータの処理ロジック
-        todo!()
+    #[test]
+    fn test_retried_idempotent_methods_get_remote_proxies() {
+        let context = create_test_context();
+        let options = super::super::CodeGenOptions::default();
+        let mut codegen = CodeGenerator::new(&context, "test", options).unwrap();
+
+        let actor = Actor {
+            name: "Payments".to_string(),
+            actor_type: ActorType::Distributed,
+            methods: vec![Method {
+                name: "charge".to_string(),
+                is_async: true,
+                is_sequential: false,
+                is_immediate: false,
+                is_pure: false,
+                is_nonreentrant: false,
+                shard_key: None,
+                retry: Some(crate::ast::RetryPolicy {
+                    times: 3,
+                    backoff_ms: 100,
+                }),
+                is_idempotent: true,
+                since: None,
+                removed: None,
+                params: vec![Parameter {
+                    name: "amount".to_string(),
+                    param_type: Type::Int,
+                    ownership: OwnershipType::Owned,
+                }],
+                return_type: None,
+                body: Some(MethodBody { statements: vec![] }),
+                doc: None,
+            }],
+            fields: vec![],
+            init: None,
+            deinit: None,
+            layout: Default::default(),
+            externs: vec![],
+            imports: vec![],
+            topics: vec![],
+            pools: vec![],
+            structs: vec![],
+            enums: vec![],
+            constants: vec![],
+            protocol: None,
+            doc: None,
+        };
+        codegen.compile_actor(&actor).unwrap();
+
+        let ir = codegen.module.print_to_string().to_string();
+        // 鍵は一度だけ取り、失敗するたびに待ってから同じ鍵で送り直す
+        assert!(
+            ir.contains("define i32 @Payments.charge.remote(i32 %amount)"),
+            "{}",
+            ir
+        );
+        assert!(ir.contains("call i64 @replica_idempotency_key()"), "{}", ir);
+        assert!(
+            ir.contains("call i32 @replica_remote_send(i32 -1, i32 0, i64 %key"),
+            "{}",
+            ir
+        );
+        assert!(
+            ir.contains("call void @replica_retry_wait(i32 %delay)"),
+            "{}",
+            ir
+        );
+        assert!(ir.contains("icmp ult i32 %attempt, 3"), "{}", ir);
+        assert!(!ir.contains("replica_shard_send"), "{}", ir);
+
+        // 受信口は既に届いた鍵の呼び出しを積まない
+        assert!(
+            ir.contains(
+                "define i32 @Payments.charge.deliver(ptr %mailbox, i64 %key, ptr %payload, i32 %len)"
+            ),
+            "{}",
+            ir
+        );
+        assert!(
+            ir.contains("call i32 @__replica_idempotency_seen(i64 %key)"),
+            "{}",
+            ir
+        );
+        assert!(
+            ir.contains("call void @__replica_idempotency_record(i64 %key)"),
+            "{}",
+            ir
+        );
+        for symbol in ["Payments.charge.remote", "Payments.charge.deliver"] {
+            assert!(
+                codegen.exported_symbols().iter().any(|s| s == symbol),
+                "{}",
+                symbol
+            );
+        }
+        assert!(codegen.verify_module().is_ok());
     }
 
-    fn compile_method_body(&mut self, body: &MethodBody, method: &Method) -> CodeGenResult<()> {
-        // メソッドボディのコンパイルロジック
-        todo!()
+    #[test]
+    fn test_versioned_actors_translate_old_tags() {
+        let context = create_test_context();
+        let options = super::super::CodeGenOptions::default();
+        let mut codegen = CodeGenerator::new(&context, "test", options).unwrap();
+
+        let method = |name: &str, since, removed| Method {
+            name: name.to_string(),
+            is_async: true,
+            is_sequential: false,
+            is_immediate: false,
+            is_pure: false,
+            is_nonreentrant: false,
+            shard_key: None,
+            retry: None,
+            is_idempotent: false,
+            since,
+            removed,
+            params: vec![Parameter {
+                name: "amount".to_string(),
+                param_type: Type::Int,
+                ownership: OwnershipType::Owned,
+            }],
+            return_type: None,
+            body: Some(MethodBody { statements: vec![] }),
+            doc: None,
+        };
+        let actor = Actor {
+            name: "Bank".to_string(),
+            actor_type: ActorType::Distributed,
+            methods: vec![
+                method("open", None, Some(3)),
+                method("deposit", Some(2), None),
+                method("audit", None, None),
+            ],
+            fields: vec![],
+            init: None,
+            deinit: None,
+            layout: Default::default(),
+            externs: vec![],
+            imports: vec![],
+            topics: vec![],
+            pools: vec![],
+            structs: vec![],
+            enums: vec![],
+            constants: vec![],
+            protocol: None,
+            doc: None,
+        };
+        codegen.compile_actor(&actor).unwrap();
+
+        let ir = codegen.module.print_to_string().to_string();
+        assert!(
+            ir.contains(
+                "define i32 @Bank.deliver(ptr %mailbox, i32 %version, i32 %tag, i64 %key, ptr %payload, i32 %len)"
+            ),
+            "{}",
+            ir
+        );
+        // 版 1 のクライアントは audit をタグ 1 で送り、版 3 では open を呼べない
+        assert!(
+            ir.contains("switch i32 %version, label %unknown_version ["),
+            "{}",
+            ir
+        );
+        assert!(ir.contains("i32 1, label %version.1.audit"), "{}", ir);
+        assert!(ir.contains("i32 2, label %version.2.audit"), "{}", ir);
+        assert!(!ir.contains("version.3.open"), "{}", ir);
+        assert!(ir.contains("ret i32 -3"), "{}", ir);
+        assert!(ir.contains("ret i32 -4"), "{}", ir);
+        assert!(
+            ir.contains(
+                "call i32 @Bank.audit.deliver(ptr %mailbox, i64 %key, ptr %payload, i32 %len)"
+            ),
+            "{}",
+            ir
+        );
+        assert!(ir.contains("replica.versions"), "{}", ir);
+        assert!(codegen.module.get_function("Bank.audit.remote").is_none());
+        for symbol in ["Bank.deliver", "Bank.open.deliver", "Bank.deposit.deliver"] {
+            assert!(
+                codegen.exported_symbols().iter().any(|s| s == symbol),
+                "{}",
+                symbol
+            );
+        }
+        assert!(codegen.verify_module().is_ok());
     }
 
-    fn generate_default_return(&self, method: &Method) -> CodeGenResult<()> {
-        // デフォルト値の生成ロジック
-        todo!()
+    #[test]
+    fn test_structs_lower_to_named_types() {
+        let context = create_test_context();
+        let options = super::super::CodeGenOptions::default();
+        let mut codegen = CodeGenerator::new(&context, "test", options).unwrap();
+
+        let field = |name: &str| Field {
+            name: name.to_string(),
+            field_type: Type::Int,
+            is_mutable: true,
+            ownership: OwnershipType::Owned,
+            offset: None,
+            doc: None,
+        };
+        let point = Type::Custom("Point".to_string());
+        let coordinate = |name: &str| Expression::Member {
+            base: Box::new(Expression::Variable("point".to_string())),
+            name: name.to_string(),
+        };
+        let actor = Actor {
+            name: "Board".to_string(),
+            actor_type: ActorType::Distributed,
+            methods: vec![Method {
+                name: "flip".to_string(),
+                is_async: false,
+                is_sequential: false,
+                is_immediate: false,
+                is_pure: false,
+                is_nonreentrant: false,
+                shard_key: None,
+                retry: None,
+                is_idempotent: false,
+                since: None,
+                removed: None,
+                params: vec![Parameter {
+                    name: "point".to_string(),
+                    param_type: point.clone(),
+                    ownership: OwnershipType::Owned,
+                }],
+                return_type: Some(point.clone()),
+                body: Some(MethodBody {
+                    statements: vec![Statement::Return(Expression::StructLiteral {
+                        name: "Point".to_string(),
+                        fields: vec![
+                            ("y".to_string(), coordinate("x")),
+                            ("x".to_string(), coordinate("y")),
+                        ],
+                    })],
+                }),
+                doc: None,
+            }],
+            fields: vec![Field {
+                field_type: point,
+                ..field("cursor")
+            }],
+            init: None,
+            deinit: None,
+            layout: Default::default(),
+            externs: vec![],
+            imports: vec![],
+            topics: vec![],
+            pools: vec![],
+            structs: vec![Struct {
+                name: "Point".to_string(),
+                fields: vec![field("x"), field("y")],
+                doc: None,
+            }],
+            enums: vec![],
+            constants: vec![],
+            protocol: None,
+            doc: None,
+        };
+        codegen.compile_actor(&actor).unwrap();
+
+        let ir = codegen.module.print_to_string().to_string();
+        // 構造体は値として状態構造体や引数に埋め込まれる
+        assert!(ir.contains("%Point = type { i32, i32 }"), "{}", ir);
+        assert!(ir.contains("%Board = type { %Point"), "{}", ir);
+        assert!(
+            ir.contains("define %Point @Board.flip(ptr %self, %Point %point)"),
+            "{}",
+            ir
+        );
+        assert!(ir.contains("extractvalue %Point"), "{}", ir);
+        assert!(ir.contains("insertvalue %Point"), "{}", ir);
+        assert!(codegen.verify_module().is_ok());
     }
 
-    fn generate_async_wrapper(
-        &mut self,
-        function: FunctionValue<'ctx>,
-        method: &Method,
-    ) -> CodeGenResult<()> {
-        // 非同期ラッパーの生成ロジック
-        todo!()
+    #[test]
+    fn test_enums_lower_to_tagged_unions() {
+        let context = create_test_context();
+        let options = super::super::CodeGenOptions::default();
+        let mut codegen = CodeGenerator::new(&context, "test", options).unwrap();
+
+        let shape = Type::Custom("Shape".to_string());
+        let float = |value: f64| Expression::Literal(LiteralValue::Float(value));
+        let int = |value: i32| Expression::Literal(LiteralValue::Int(value));
+        let case = |name: &str, args: Vec<Pattern>| Pattern::Case {
+            name: name.to_string(),
+            args,
+        };
+        let binding = |name: &str| Pattern::Binding(name.to_string());
+        let method = |name: &str, return_type: Type, statements: Vec<Statement>| Method {
+            name: name.to_string(),
+            is_async: false,
+            is_sequential: false,
+            is_immediate: false,
+            is_pure: false,
+            is_nonreentrant: false,
+            shard_key: None,
+            retry: None,
+            is_idempotent: false,
+            since: None,
+            removed: None,
+            params: vec![Parameter {
+                name: "shape".to_string(),
+                param_type: shape.clone(),
+                ownership: OwnershipType::Owned,
+            }],
+            return_type: Some(return_type),
+            body: Some(MethodBody { statements }),
+            doc: None,
+        };
+        let returns = |value: Expression| MethodBody {
+            statements: vec![Statement::Return(value)],
+        };
+        // match shape { .circle(let r) => ..., .rect(let w, let h) => ..., .empty => ... }
+        let area = method(
+            "area",
+            Type::Float,
+            vec![Statement::Match {
+                value: Expression::Variable("shape".to_string()),
+                arms: vec![
+                    (
+                        case("circle", vec![binding("r")]),
+                        returns(Expression::Variable("r".to_string())),
+                    ),
+                    (
+                        case("rect", vec![binding("w"), binding("h")]),
+                        returns(Expression::BinaryOp {
+                            left: Box::new(Expression::Variable("w".to_string())),
+                            operator: Operator::Multiply,
+                            right: Box::new(Expression::Variable("h".to_string())),
+                        }),
+                    ),
+                    (case("empty", vec![]), returns(float(0.0))),
+                ],
+            }],
+        );
+        // return match shape { .rect(_, _) => 4, _ => 0 }
+        let sides = method(
+            "sides",
+            Type::Int,
+            vec![Statement::Return(Expression::Match {
+                value: Box::new(Expression::Variable("shape".to_string())),
+                arms: vec![
+                    (
+                        case("rect", vec![Pattern::Wildcard, Pattern::Wildcard]),
+                        int(4),
+                    ),
+                    (Pattern::Wildcard, int(0)),
+                ],
+            })],
+        );
+        let widen = method(
+            "widen",
+            shape.clone(),
+            vec![Statement::Return(Expression::EnumCase {
+                enum_name: "Shape".to_string(),
+                case: "rect".to_string(),
+                args: vec![float(2.0), float(1.0)],
+            })],
+        );
+        let actor = Actor {
+            name: "Canvas".to_string(),
+            actor_type: ActorType::Distributed,
+            methods: vec![area, sides, widen],
+            fields: vec![],
+            init: None,
+            deinit: None,
+            layout: Default::default(),
+            externs: vec![],
+            imports: vec![],
+            topics: vec![],
+            pools: vec![],
+            structs: vec![],
+            enums: vec![Enum {
+                name: "Shape".to_string(),
+                cases: vec![
+                    EnumCase {
+                        name: "circle".to_string(),
+                        values: vec![Type::Float],
+                    },
+                    EnumCase {
+                        name: "rect".to_string(),
+                        values: vec![Type::Float, Type::Float],
+                    },
+                    EnumCase {
+                        name: "empty".to_string(),
+                        values: vec![],
+                    },
+                ],
+                doc: None,
+            }],
+            constants: vec![],
+            protocol: None,
+            doc: None,
+        };
+        codegen.compile_actor(&actor).unwrap();
+
+        let ir = codegen.module.print_to_string().to_string();
+        // タグと、最大のケース (2 つの Float) が入るペイロード
+        assert!(ir.contains("%Shape = type { i32, [2 x i64] }"), "{}", ir);
+        assert!(
+            ir.contains("define double @Canvas.area(ptr %self, %Shape %shape)"),
+            "{}",
+            ir
+        );
+        assert!(ir.contains("icmp eq i32 %tag, 1"), "{}", ir);
+        assert!(ir.contains("load { double, double }"), "{}", ir);
+        assert!(ir.contains("phi i32"), "{}", ir);
+        assert!(ir.contains("store { double, double }"), "{}", ir);
+        assert!(codegen.verify_module().is_ok());
     }
 
-    fn create_field_accessor(
-        &mut self,
-        actor: &Actor,
-        field: &crate::ast::Field,
-    ) -> CodeGenResult<()> {
-        // フィールドアクセサの生成ロジック
-        todo!()
+    #[test]
+    fn test_optionals_unwrap_and_coalesce() {
+        let context = create_test_context();
+        let options = super::super::CodeGenOptions::default();
+        let mut codegen = CodeGenerator::new(&context, "test", options).unwrap();
+
+        let optional_int = Type::Optional(Box::new(Type::Int));
+        let key = || Box::new(Expression::Variable("key".to_string()));
+        let method = |name: &str, params: Vec<Parameter>, return_type: Type, value| Method {
+            name: name.to_string(),
+            is_async: false,
+            is_sequential: false,
+            is_immediate: false,
+            is_pure: false,
+            is_nonreentrant: false,
+            shard_key: None,
+            retry: None,
+            is_idempotent: false,
+            since: None,
+            removed: None,
+            params,
+            return_type: Some(return_type),
+            body: Some(MethodBody {
+                statements: vec![Statement::Return(value)],
+            }),
+            doc: None,
+        };
+        let param = |name: &str, param_type: &Type| {
+            vec![Parameter {
+                name: name.to_string(),
+                param_type: param_type.clone(),
+                ownership: OwnershipType::Owned,
+            }]
+        };
+        let actor = Actor {
+            name: "Cache".to_string(),
+            actor_type: ActorType::Distributed,
+            methods: vec![
+                // return key ?? 0
+                method(
+                    "lookup",
+                    param("key", &optional_int),
+                    Type::Int,
+                    Expression::Coalesce {
+                        value: key(),
+                        default: Box::new(Expression::Literal(LiteralValue::Int(0))),
+                    },
+                ),
+                // return key!
+                method(
+                    "force",
+                    param("key", &optional_int),
+                    Type::Int,
+                    Expression::Unwrap(key()),
+                ),
+                method("clear", vec![], optional_int.clone(), Expression::Nil),
+                method(
+                    "wrap",
+                    param("n", &Type::Int),
+                    optional_int.clone(),
+                    Expression::Variable("n".to_string()),
+                ),
+            ],
+            fields: vec![],
+            init: None,
+            deinit: None,
+            layout: Default::default(),
+            externs: vec![],
+            imports: vec![],
+            topics: vec![],
+            pools: vec![],
+            structs: vec![],
+            enums: vec![],
+            constants: vec![],
+            protocol: None,
+            doc: None,
+        };
+        codegen.compile_actor(&actor).unwrap();
+
+        let ir = codegen.module.print_to_string().to_string();
+        // 既定値は nil のときだけ評価され、結果は phi で合流する
+        assert!(ir.contains("coalesce_default:"), "{}", ir);
+        assert!(ir.contains("phi i32"), "{}", ir);
+        assert!(ir.contains("call void @__replica_unwrap_nil()"), "{}", ir);
+        assert!(ir.contains("unreachable"), "{}", ir);
+        assert!(ir.contains("ret { i32, i1 } zeroinitializer"), "{}", ir);
+        assert!(ir.contains("insertvalue { i32, i1 }"), "{}", ir);
+        assert!(codegen.verify_module().is_ok());
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::ast::{ActorType, Type};
+    #[test]
+    fn test_dynamic_arrays() {
+        let ints = Type::Array(Box::new(Type::Int));
+        let int = |value: i32| Expression::Literal(LiteralValue::Int(value));
+        let values = || Box::new(Expression::Variable("values".to_string()));
+        let method = |name: &str, params: Vec<Parameter>, value| Method {
+            name: name.to_string(),
+            is_async: false,
+            is_sequential: false,
+            is_immediate: false,
+            is_pure: false,
+            is_nonreentrant: false,
+            shard_key: None,
+            retry: None,
+            is_idempotent: false,
+            since: None,
+            removed: None,
+            params,
+            return_type: Some(if name == "latest" {
+                Type::Int
+            } else {
+                ints.clone()
+            }),
+            body: Some(MethodBody {
+                statements: vec![Statement::Return(value)],
+            }),
+            doc: None,
+        };
+        let actor = Actor {
+            name: "Samples".to_string(),
+            actor_type: ActorType::Distributed,
+            methods: vec![
+                // return values[i] + values.count
+                method(
+                    "latest",
+                    vec![
+                        Parameter {
+                            name: "values".to_string(),
+                            param_type: ints.clone(),
+                            ownership: OwnershipType::Owned,
+                        },
+                        Parameter {
+                            name: "i".to_string(),
+                            param_type: Type::Int,
+                            ownership: OwnershipType::Owned,
+                        },
+                    ],
+                    Expression::BinaryOp {
+                        left: Box::new(Expression::Index {
+                            base: values(),
+                            index: Box::new(Expression::Variable("i".to_string())),
+                        }),
+                        operator: Operator::Add,
+                        right: Box::new(Expression::Member {
+                            base: values(),
+                            name: "count".to_string(),
+                        }),
+                    },
+                ),
+                method(
+                    "make",
+                    vec![],
+                    Expression::ArrayLiteral(vec![int(1), int(2), int(3)]),
+                ),
+                method("empty", vec![], Expression::ArrayLiteral(vec![])),
+            ],
+            fields: vec![],
+            init: None,
+            deinit: None,
+            layout: Default::default(),
+            externs: vec![],
+            imports: vec![],
+            topics: vec![],
+            pools: vec![],
+            structs: vec![],
+            enums: vec![],
+            constants: vec![],
+            protocol: None,
+            doc: None,
+        };
 
-    fn create_test_context() -> Context {
-        Context::create()
+        let context = create_test_context();
+        let options = super::super::CodeGenOptions::default();
+        let mut codegen = CodeGenerator::new(&context, "test", options).unwrap();
+        codegen.compile_actor(&actor).unwrap();
+        let ir = codegen.module.print_to_string().to_string();
+        assert!(
+            ir.contains("call ptr @__replica_array_get(ptr %values"),
+            "{}",
+            ir
+        );
+        assert!(
+            ir.contains("call i32 @__replica_array_len(ptr %values)"),
+            "{}",
+            ir
+        );
+        // リテラルは新しいバッファへ要素を順に格納する
+        assert!(
+            ir.contains("call ptr @__replica_array_alloc(i32 3"),
+            "{}",
+            ir
+        );
+        assert_eq!(ir.matches("store i32").count(), 3, "{}", ir);
+        assert!(ir.contains("ret ptr null"), "{}", ir);
+        assert!(codegen.verify_module().is_ok());
+
+        // 範囲の検査を外すとバッファを直接読む
+        let context = create_test_context();
+        let options = super::super::CodeGenOptions {
+            bounds_checks: false,
+            ..Default::default()
+        };
+        let mut codegen = CodeGenerator::new(&context, "test", options).unwrap();
+        codegen.compile_actor(&actor).unwrap();
+        let ir = codegen.module.print_to_string().to_string();
+        assert!(!ir.contains("call ptr @__replica_array_get"), "{}", ir);
+        assert!(
+            ir.contains("getelementptr inbounds i32, ptr %buffer"),
+            "{}",
+            ir
+        );
+        assert!(codegen.verify_module().is_ok());
     }
 
     #[test]
-    fn test_basic_actor_compilation() {
+    fn test_constant_tables_live_in_the_data_segment() {
+        let source = "actor Router {
+            const primes: [Int] = [2, 3, 5, 7]
+            const ports: [String: Int] = [\"http\": 80, \"https\": 443]
+            const codes: [Int: Float] = [5: 0.5, -2: 2.0, 1: 1.5]
+
+            func largest() -> Int {
+                return primes[primes.count - 1]
+            }
+
+            func port(scheme: String) -> Int {
+                return ports[scheme] ?? 0
+            }
+
+            func code(n: Int) -> Float? {
+                return codes[n]
+            }
+        }";
+        let tokens = crate::lexer::tokenize(source).unwrap();
+        let actor = crate::parser::Parser::new(tokens).parse_actor().unwrap();
+
+        let context = create_test_context();
+        let options = super::super::CodeGenOptions::default();
+        let mut codegen = CodeGenerator::new(&context, "test", options).unwrap();
+        codegen.compile_actor(&actor).unwrap();
+        let ir = codegen.module.print_to_string().to_string();
+        // 配列は定数の記述子で、実行時には組み立てない
+        assert!(
+            ir.contains(
+                "@Router.primes.data = private constant [4 x i32] [i32 2, i32 3, i32 5, i32 7]"
+            ),
+            "{}",
+            ir
+        );
+        assert!(
+            ir.contains(
+                "@Router.primes = private constant { ptr, i32 } { ptr @Router.primes.data, i32 4 }"
+            ),
+            "{}",
+            ir
+        );
+        assert!(!ir.contains("call ptr @__replica_array_alloc"), "{}", ir);
+        // マップは検索キーの順に並べ、生成した二分探索で引く
+        assert!(
+            ir.contains(
+                "@Router.codes.search_keys = private constant [3 x i64] [i64 1, i64 5, i64 -2]"
+            ),
+            "{}",
+            ir
+        );
+        assert!(
+            ir.contains("@Router.codes.values.data = private constant [3 x double] [double 1.500000e+00, double 5.000000e-01, double 2.000000e+00]"),
+            "{}",
+            ir
+        );
+        assert!(
+            ir.contains("define internal i32 @Router.ports.find(ptr"),
+            "{}",
+            ir
+        );
+        assert!(ir.contains("call i64 @__replica_string_hash"), "{}", ir);
+        assert!(ir.contains("call i32 @Router.codes.find(i32 %n)"), "{}", ir);
+        assert!(codegen.verify_module().is_ok());
+    }
+
+    #[test]
+    fn test_stream_methods_are_pulled_through_next() {
         let context = create_test_context();
         let options = super::super::CodeGenOptions::default();
         let mut codegen = CodeGenerator::new(&context, "test", options).unwrap();
 
+        // func ticks(count: Int) -> AsyncStream<Int> {
+        //     let step = 2
+        //     for i in 0..count { yield i * step }
+        // }
+        let variable = |name: &str| Expression::Variable(name.to_string());
+        let ticks = Method {
+            name: "ticks".to_string(),
+            is_async: true,
+            is_sequential: false,
+            is_immediate: false,
+            is_pure: false,
+            is_nonreentrant: false,
+            shard_key: None,
+            retry: None,
+            is_idempotent: false,
+            since: None,
+            removed: None,
+            params: vec![Parameter {
+                name: "count".to_string(),
+                param_type: Type::Int,
+                ownership: OwnershipType::Owned,
+            }],
+            return_type: Some(Type::Generic {
+                name: crate::ast::ASYNC_STREAM.to_string(),
+                args: vec![Type::Int],
+            }),
+            body: Some(MethodBody {
+                statements: vec![
+                    Statement::Let {
+                        pattern: Pattern::Binding("step".to_string()),
+                        is_mutable: false,
+                        value: Expression::Literal(LiteralValue::Int(2)),
+                    },
+                    Statement::For {
+                        label: None,
+                        variable: "i".to_string(),
+                        start: Expression::Literal(LiteralValue::Int(0)),
+                        end: variable("count"),
+                        body: MethodBody {
+                            statements: vec![Statement::Yield(Expression::BinaryOp {
+                                left: Box::new(variable("i")),
+                                operator: Operator::Multiply,
+                                right: Box::new(variable("step")),
+                            })],
+                        },
+                    },
+                ],
+            }),
+            doc: None,
+        };
         let actor = Actor {
-            name: "TestActor".to_string(),
+            name: "Feed".to_string(),
             actor_type: ActorType::Single,
-            methods: vec![],
+            methods: vec![ticks],
             fields: vec![],
+            init: None,
+            deinit: None,
+            layout: Default::default(),
+            externs: vec![],
+            imports: vec![],
+            topics: vec![],
+            pools: vec![],
+            structs: vec![],
+            enums: vec![],
+            constants: vec![],
+            protocol: None,
+            doc: None,
         };
+        codegen.compile_actor(&actor).unwrap();
 
-        assert!(codegen.compile_actor(&actor).is_ok());
+        let ir = codegen.module.print_to_string().to_string();
+        // 呼び出しはフレームを確保するだけで、本体は next で少しずつ実行する
+        assert!(
+            ir.contains("define ptr @Feed.ticks(ptr %self, i32 %count)"),
+            "{}",
+            ir
+        );
+        assert!(ir.contains("define i32 @Feed.ticks.next(ptr %stream, ptr %out)"));
+        assert!(ir.contains("define void @Feed.ticks.close(ptr %stream)"));
+        // ローカル変数 (step, i, 終端, count) はフレームに保存される
+        assert!(ir.contains("%Feed.ticks.frame = type { ptr, i32, i32, i32, i32, i32 }"));
+        assert!(ir.contains("switch i32 %resumed_at, label %finished ["));
+        assert!(ir.contains("store i32 1, ptr %state"));
+        assert!(ir.contains("store i32 -1, ptr %state"));
+        assert!(ir.contains("call void @__replica_free(ptr %stream)"));
+        // ストリームはメールボックス経由では呼べない
+        assert!(!ir.contains("@Feed.ticks.post"));
+        let symbols = codegen.exported_symbols();
+        assert!(symbols.contains(&"Feed.ticks.next".to_string()));
+        assert!(symbols.contains(&"Feed.ticks.close".to_string()));
+        assert!(codegen.verify_module().is_ok());
     }
 
     #[test]
-    fn test_wasm_emission() {
+    fn test_awaiting_methods_run_as_tasks() {
         let context = create_test_context();
         let options = super::super::CodeGenOptions::default();
-        let codegen = CodeGenerator::new(&context, "test", options).unwrap();
+        let mut codegen = CodeGenerator::new(&context, "test", options).unwrap();
 
-        let wasm = codegen.emit_wasm();
-        assert!(wasm.is_ok());
+        let variable = |name: &str| Expression::Variable(name.to_string());
+        let await_fee = |amount| {
+            Expression::Await(Box::new(Expression::Call {
+                callee: "fee".to_string(),
+                type_args: vec![],
+                args: vec![amount],
+            }))
+        };
+        let method = |name: &str, return_type, statements| Method {
+            name: name.to_string(),
+            is_async: true,
+            is_sequential: false,
+            is_immediate: false,
+            is_pure: false,
+            is_nonreentrant: false,
+            shard_key: None,
+            retry: None,
+            is_idempotent: false,
+            since: None,
+            removed: None,
+            params: vec![Parameter {
+                name: "amount".to_string(),
+                param_type: Type::Int,
+                ownership: OwnershipType::Owned,
+            }],
+            return_type,
+            body: Some(MethodBody { statements }),
+            doc: None,
+        };
+        // async func fee(amount: Int) -> Int { return amount }
+        let fee = method(
+            "fee",
+            Some(Type::Int),
+            vec![Statement::Return(variable("amount"))],
+        );
+        // async func charge(amount: Int) -> Int {
+        //     let total = await fee(amount) + amount
+        //     return total
+        // }
+        let charge = method(
+            "charge",
+            Some(Type::Int),
+            vec![
+                Statement::Let {
+                    pattern: Pattern::Binding("total".to_string()),
+                    is_mutable: false,
+                    value: Expression::BinaryOp {
+                        left: Box::new(await_fee(variable("amount"))),
+                        operator: Operator::Add,
+                        right: Box::new(variable("amount")),
+                    },
+                },
+                Statement::Return(variable("total")),
+            ],
+        );
+        // sequential async func audit(amount: Int) { await fee(amount) }
+        let audit = Method {
+            is_sequential: true,
+            ..method(
+                "audit",
+                None,
+                vec![Statement::Expression(await_fee(variable("amount")))],
+            )
+        };
+        let actor = Actor {
+            name: "Bank".to_string(),
+            actor_type: ActorType::Single,
+            methods: vec![fee, charge, audit],
+            fields: vec![],
+            init: None,
+            deinit: None,
+            layout: Default::default(),
+            externs: vec![],
+            imports: vec![],
+            topics: vec![],
+            pools: vec![],
+            structs: vec![],
+            enums: vec![],
+            constants: vec![],
+            protocol: None,
+            doc: None,
+        };
+        codegen.compile_actor(&actor).unwrap();
+
+        let ir = codegen.module.print_to_string().to_string();
+        // await を含むメソッドはフレームを確保して状態機械として進む
+        assert!(
+            ir.contains("define void @Bank.charge.start(ptr %self, ptr %mailbox, ptr %reply.frame, i32 %reply.tag, ptr %reply.result, i32 %amount)"),
+            "{}",
+            ir
+        );
+        assert!(ir.contains("define void @Bank.charge.resume(ptr %frame)"));
+        assert!(ir.contains("%Bank.charge.frame = type { ptr, i32, ptr, ptr, i32, ptr, i32"));
+        assert!(ir.contains("switch i32 %resumed_at, label %invalid ["));
+        assert!(ir.contains("store i32 1, ptr %state"));
+        // 待つ呼び出しはタグ 4 で積み、再開はタグ 3 で届く
+        assert!(ir.contains("call i32 @__replica_mailbox_push(ptr %mailbox, i32 4, ptr %payload,"));
+        assert!(ir.contains("store i32 3, ptr %arg.slot"));
+        assert!(ir.contains("%awaited = call i32 @Bank.fee(ptr %self"));
+        // 結果は待ち手のフレームに書き込み、フレームを再開のメッセージとして積む
+        assert!(ir.contains("i32 %reply.tag, ptr %reply.frame, i32 0)"));
+        assert!(ir.contains("call void @__replica_free(ptr %frame)"));
+        // dispatch はタスクを始め、待たれた呼び出しを実行し、中断したタスクを再開する
+        assert!(ir.contains(
+            "call void @Bank.charge.start(ptr %actor, ptr %mailbox, ptr null, i32 0, ptr null, i32 %arg"
+        ));
+        assert!(ir.contains("call void @Bank.charge.resume(ptr %payload)"));
+        // sequential のメソッドは await をその場で実行する
+        assert!(!ir.contains("@Bank.audit.start"));
+        assert!(ir.contains("call i32 @Bank.fee(ptr %self, i32 %amount)"));
+        assert!(!codegen
+            .exported_symbols()
+            .contains(&"Bank.charge.start".to_string()));
+        assert!(codegen.verify_module().is_ok());
+    }
+
+    #[test]
+    fn test_operands_before_an_await_are_evaluated_first() {
+        let call = |callee: &str, args| Expression::Call {
+            callee: callee.to_string(),
+            type_args: vec![],
+            args,
+        };
+        let awaited = |callee: &str, args| Expression::Await(Box::new(call(callee, args)));
+        let total = || Expression::Variable("total".to_string());
+        let add = |left, right| Expression::BinaryOp {
+            left: Box::new(left),
+            operator: Operator::Add,
+            right: Box::new(right),
+        };
+        let hoist = |mut expr: Expression| {
+            let mut hoisted = Vec::new();
+            hoist_awaits(&mut expr, &mut hoisted);
+            let hoisted: Vec<String> = hoisted
+                .iter()
+                .map(|(name, value)| format!("{} = {}", name, value))
+                .collect();
+            (hoisted, expr.to_string())
+        };
+
+        // f() + await g(): f は待つ前に呼ぶ
+        let (hoisted, expr) = hoist(add(call("f", vec![]), awaited("g", vec![])));
+        assert_eq!(hoisted, ["operand.0 = f()", "await.1 = await g()"]);
+        assert_eq!(expr, "operand.0 + await.1");
+
+        // total + await add(total, await g()): 中断前の total を読む
+        let (hoisted, expr) = hoist(add(
+            total(),
+            awaited("add", vec![total(), awaited("g", vec![])]),
+        ));
+        assert_eq!(
+            hoisted,
+            [
+                "operand.0 = total",
+                "operand.1 = total",
+                "await.2 = await g()",
+                "await.3 = await add(operand.1, await.2)",
+            ]
+        );
+        assert_eq!(expr, "operand.0 + await.3");
+
+        // 後ろの被演算子やリテラルはそのまま
+        let (hoisted, expr) = hoist(Expression::ArrayLiteral(vec![
+            Expression::Literal(LiteralValue::Int(1)),
+            awaited("g", vec![]),
+            total(),
+        ]));
+        assert_eq!(hoisted, ["await.0 = await g()"]);
+        assert_eq!(expr, "[1, await.0, total]");
+    }
+
+    #[test]
+    fn test_layout_report() {
+        let context = create_test_context();
+        let options = super::super::CodeGenOptions::default();
+        let mut codegen = CodeGenerator::new(&context, "test", options).unwrap();
+
+        let field = |name: &str, field_type| Field {
+            name: name.to_string(),
+            field_type,
+            is_mutable: true,
+            ownership: OwnershipType::Owned,
+            offset: None,
+            doc: None,
+        };
+        let actor = Actor {
+            name: "Gauge".to_string(),
+            actor_type: ActorType::Distributed,
+            methods: vec![],
+            fields: vec![
+                field("flag", Type::Bool),
+                field("value", Type::Float),
+                field("count", Type::Int),
+            ],
+            init: None,
+            deinit: None,
+            layout: Default::default(),
+            externs: vec![],
+            imports: vec![],
+            topics: vec![],
+            pools: vec![],
+            structs: vec![Struct {
+                name: "Point".to_string(),
+                fields: vec![field("x", Type::Int), field("y", Type::Int)],
+                doc: None,
+            }],
+            enums: vec![],
+            constants: vec![],
+            protocol: None,
+            doc: None,
+        };
+        codegen.compile_actor(&actor).unwrap();
+
+        let report =
+            String::from_utf8(codegen.emit(super::super::EmitKind::Layout).unwrap()).unwrap();
+        assert_eq!(
+            report,
+            "struct Point: size 8, align 4\n  offset  size  align  field\n       0     4      4  x: Int\n       4     4      4  y: Int\n\nactor Gauge: size 24, align 8\n  offset  size  align  field\n       0     1      1  flag: Bool\n       1     7         (padding)\n       8     8      8  value: Float\n      16     4      4  count: Int\n      20     4         (padding)\n"
+        );
+    }
+
+    #[test]
+    fn test_entry_points_carry_export_names() {
+        let actor = Actor {
+            name: "Counter".to_string(),
+            actor_type: ActorType::Distributed,
+            methods: vec![Method {
+                name: "add".to_string(),
+                is_async: true,
+                is_sequential: false,
+                is_immediate: false,
+                is_pure: false,
+                is_nonreentrant: false,
+                shard_key: None,
+                retry: None,
+                is_idempotent: false,
+                since: None,
+                removed: None,
+                params: vec![],
+                return_type: None,
+                body: Some(MethodBody { statements: vec![] }),
+                doc: None,
+            }],
+            fields: vec![],
+            init: None,
+            deinit: None,
+            layout: Default::default(),
+            externs: vec![],
+            imports: vec![],
+            topics: vec![],
+            pools: vec![],
+            structs: vec![],
+            enums: vec![],
+            constants: vec![],
+            protocol: None,
+            doc: None,
+        };
+        let has_export_name =
+            |ir: &str, name: &str| ir.contains(&format!("\"wasm-export-name\"=\"{}\"", name));
+        let context = create_test_context();
+        let compile = |export_names| {
+            let options = super::super::CodeGenOptions {
+                export_names,
+                ..Default::default()
+            };
+            let mut codegen = CodeGenerator::new(&context, "test", options).unwrap();
+            codegen.compile_actor(&actor).unwrap();
+            codegen.apply_symbol_visibility();
+            codegen
+        };
+
+        // 既定ではアクター名で修飾されたシンボル名のまま公開し、内部関数には付けない
+        let ir = compile(ExportNames::Symbol)
+            .module
+            .print_to_string()
+            .to_string();
+        for symbol in ["Counter.add", "Counter.add.post", "Counter.new"] {
+            assert!(has_export_name(&ir, symbol), "{} is not exported", symbol);
+        }
+        assert!(!has_export_name(&ir, "Counter.dispatch"));
+
+        let codegen = compile(ExportNames::Qualified);
+        let ir = codegen.module.print_to_string().to_string();
+        for export_name in [
+            "Counter_add",
+            "Counter_add_post",
+            "Counter_new",
+            "Counter_drain",
+        ] {
+            assert!(
+                has_export_name(&ir, export_name),
+                "{} is not exported",
+                export_name
+            );
+        }
+        // シンボル名自体は変わらないので、アーカイブの索引やリンクは影響を受けない
+        assert_eq!(
+            codegen.exported_symbols(),
+            vec![
+                "Counter.add",
+                "Counter.add.post",
+                "Counter.drain",
+                "Counter.new"
+            ]
+        );
     }
 
     // Add more tests for specific compilation scenarios
@@ -0,0 +1,266 @@
+//! Linking of the generated object file into a WebAssembly module.
+//!
+//! LLVM only emits relocatable objects. `wasm-ld` turns one into a module a
+//! host can instantiate: it lays out linear memory and the shadow stack,
+//! resolves the `__replica_*` routines against the runtime library and keeps
+//! the actor entry points as exports. Functions it cannot resolve become
+//! imports from `env`, which is how extern functions reach the host.
+
+use super::error::{CodeGenError, CodeGenResult};
+use super::{runtime, CodeGenOptions, WasmFeature};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Runtime exports hosts call directly; kept when the runtime library is linked
+const RUNTIME_EXPORTS: &[&str] = &[
+    "__replica_alloc",
+    "__replica_release",
+    "__replica_mailbox_new",
+    "__replica_run",
+    "replica_random_seed",
+    "replica_metrics",
+    "replica_mailbox_metrics",
+    "replica_actor_new",
+];
+
+/// Magic number and version every WebAssembly binary module starts with
+const WASM_HEADER: [u8; 8] = *b"\0asm\x01\0\0\0";
+
+/// Settings of the link step, taken from [`CodeGenOptions`]
+#[derive(Debug, Clone)]
+pub struct LinkOptions {
+    /// Linker command, possibly with leading arguments (`rust-lld -flavor wasm`)
+    pub linker: Vec<String>,
+    /// Runtime library linked into the module
+    pub runtime_library: Option<PathBuf>,
+    pub stack_size: u32,
+    pub max_memory: Option<u32>,
+    pub shared_memory: bool,
+    pub wasm64: bool,
+}
+
+impl From<&CodeGenOptions> for LinkOptions {
+    fn from(options: &CodeGenOptions) -> Self {
+        LinkOptions {
+            linker: options
+                .linker
+                .split_whitespace()
+                .map(str::to_string)
+                .collect(),
+            runtime_library: options.runtime_library.clone(),
+            stack_size: options.stack_size,
+            max_memory: options.max_memory,
+            shared_memory: options.has_wasm_feature(WasmFeature::Threads),
+            wasm64: options.target_triple.starts_with("wasm64"),
+        }
+    }
+}
+
+impl LinkOptions {
+    /// Arguments that link `object` into the module `output`, exporting `exports`
+    pub fn args(&self, exports: &[String], object: &Path, output: &Path) -> Vec<String> {
+        let mut args = vec![
+            "--no-entry".to_string(),
+            // extern 関数とホスト提供のルーチンは env からのインポートになる
+            "--allow-undefined".to_string(),
+            "-z".to_string(),
+            format!("stack-size={}", self.stack_size),
+        ];
+        if self.wasm64 {
+            args.push("-mwasm64".to_string());
+        }
+        if let Some(max_memory) = self.max_memory {
+            args.push(format!("--max-memory={}", max_memory));
+        }
+        if self.shared_memory {
+            args.push("--shared-memory".to_string());
+        }
+        args.extend(exports.iter().map(|name| format!("--export={}", name)));
+        args.push(format!("--export={}", runtime::ABI_HANDSHAKE_EXPORT));
+        args.push(format!("--export={}", runtime::INIT_EXPORT));
+        // コールバックは関数テーブルの添字で渡るので、ホストが引けるようにする
+        args.push("--export-table".to_string());
+        if self.runtime_library.is_some() {
+            args.extend(
+                RUNTIME_EXPORTS
+                    .iter()
+                    .map(|name| format!("--export-if-defined={}", name)),
+            );
+        }
+        args.push(object.display().to_string());
+        if let Some(library) = &self.runtime_library {
+            args.push(library.display().to_string());
+        }
+        args.push("-o".to_string());
+        args.push(output.display().to_string());
+        args
+    }
+
+    /// Links `object` into a module exporting `exports` and returns its bytes
+    pub fn link(&self, object: &[u8], exports: &[String]) -> CodeGenResult<Vec<u8>> {
+        let (program, leading_args) = self
+            .linker
+            .split_first()
+            .ok_or_else(|| CodeGenError::WasmGen("--linker must name a program".to_string()))?;
+        if self.shared_memory && self.max_memory.is_none() {
+            return Err(CodeGenError::WasmGen(
+                "Shared memory needs an upper bound; set --max-memory when linking with the threads feature".to_string(),
+            ));
+        }
+        if let Some(library) = &self.runtime_library {
+            if !library.is_file() {
+                return Err(CodeGenError::WasmGen(format!(
+                    "Runtime library {} does not exist; build it with `cargo build -p replica-runtime --release --target <triple>`",
+                    library.display()
+                )));
+            }
+        }
+
+        let dir = ScratchDir::create()?;
+        let object_path = dir.path.join("module.o");
+        let output_path = dir.path.join("module.wasm");
+        fs::write(&object_path, object).map_err(|e| {
+            CodeGenError::WasmGen(format!("Failed to write {}: {}", object_path.display(), e))
+        })?;
+
+        let output = Command::new(program)
+            .args(leading_args)
+            .args(self.args(exports, &object_path, &output_path))
+            .output()
+            .map_err(|e| {
+                CodeGenError::WasmGen(format!(
+                    "Failed to run linker {}: {}; install LLVM's wasm-ld or choose another with --linker",
+                    program, e
+                ))
+            })?;
+        if !output.status.success() {
+            return Err(CodeGenError::WasmGen(format!(
+                "{} failed ({}): {}",
+                program,
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+
+        let module = fs::read(&output_path)
+            .map_err(|e| CodeGenError::WasmGen(format!("{} produced no module: {}", program, e)))?;
+        if !module.starts_with(&WASM_HEADER) {
+            return Err(CodeGenError::WasmGen(format!(
+                "{} did not produce a WebAssembly module",
+                program
+            )));
+        }
+        Ok(module)
+    }
+}
+
+/// Temporary directory for the linker's input and output, removed on drop
+struct ScratchDir {
+    path: PathBuf,
+}
+
+impl ScratchDir {
+    fn create() -> CodeGenResult<Self> {
+        // 同じプロセス内の並行リンクとも衝突しないよう連番を付ける
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "replica-link-{}-{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::create_dir_all(&path).map_err(|e| {
+            CodeGenError::WasmGen(format!("Failed to create {}: {}", path.display(), e))
+        })?;
+        Ok(ScratchDir { path })
+    }
+}
+
+impl Drop for ScratchDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options() -> CodeGenOptions {
+        CodeGenOptions {
+            linker: "rust-lld -flavor wasm".to_string(),
+            stack_size: 2 * super::super::WASM_PAGE_SIZE,
+            ..CodeGenOptions::default()
+        }
+    }
+
+    #[test]
+    fn test_link_args() {
+        let link = LinkOptions::from(&options());
+        assert_eq!(link.linker, ["rust-lld", "-flavor", "wasm"]);
+
+        let exports = vec!["Counter.new".to_string(), "add".to_string()];
+        let args = link.args(&exports, Path::new("a.o"), Path::new("a.wasm"));
+        assert_eq!(
+            args,
+            [
+                "--no-entry",
+                "--allow-undefined",
+                "-z",
+                "stack-size=131072",
+                "--export=Counter.new",
+                "--export=add",
+                "--export=__replica_abi_handshake",
+                "--export=__replica_init",
+                "--export-table",
+                "a.o",
+                "-o",
+                "a.wasm",
+            ]
+        );
+
+        // ランタイムをリンクする場合はホスト向けのエクスポートも残す
+        let link = LinkOptions::from(&CodeGenOptions {
+            runtime_library: Some(PathBuf::from("libreplica_runtime.a")),
+            max_memory: Some(16 * super::super::WASM_PAGE_SIZE),
+            wasm_features: vec![WasmFeature::Threads],
+            target_triple: "wasm64-unknown-unknown".to_string(),
+            ..options()
+        });
+        let args = link.args(&[], Path::new("a.o"), Path::new("a.wasm"));
+        for expected in [
+            "-mwasm64",
+            "--max-memory=1048576",
+            "--shared-memory",
+            "--export-if-defined=__replica_run",
+        ] {
+            assert!(
+                args.iter().any(|arg| arg == expected),
+                "missing {}",
+                expected
+            );
+        }
+        assert_eq!(
+            args[args.len() - 4..],
+            ["a.o", "libreplica_runtime.a", "-o", "a.wasm"]
+        );
+    }
+
+    #[test]
+    fn test_link_errors() {
+        let missing = LinkOptions::from(&CodeGenOptions {
+            linker: "replica-no-such-linker".to_string(),
+            ..CodeGenOptions::default()
+        });
+        let error = missing.link(b"", &[]).unwrap_err().to_string();
+        assert!(error.contains("Failed to run linker replica-no-such-linker"));
+
+        let unbounded = LinkOptions {
+            shared_memory: true,
+            ..missing
+        };
+        let error = unbounded.link(b"", &[]).unwrap_err().to_string();
+        assert!(error.contains("set --max-memory"));
+    }
+}
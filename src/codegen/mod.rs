@@ -1,25 +1,30 @@
 //! Code generation module for compiling Replica actors to WASM.
 //! This module handles the transformation of AST to LLVM IR and final WASM output.
 
+mod archive;
 mod error;
 mod expression;
 mod generator;
+mod linker;
+pub mod runtime;
 mod type_converter;
+mod wat;
 
+use crate::ast::LogLevel;
 use inkwell::context::Context;
 use inkwell::module::Module;
 use inkwell::OptimizationLevel;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
 
-pub use error::{CodeGenError, CodeGenResult};
+pub use error::{CodeGenError, CodeGenResult, ErrorContext, SourceLocation};
 pub use generator::CodeGenerator;
 
 // Re-export only the necessary types and traits
 pub use expression::ExpressionCompiler;
-pub use type_converter::TypeConverter;
-
-pub struct ContextWrapper {
-    context: Context,
-}
+pub use type_converter::{TypeConverter, TypeLayout};
 
 /// Configuration options for code generation
 #[derive(Debug, Clone)]
@@ -30,6 +35,52 @@ pub struct CodeGenOptions {
     pub debug_mode: bool,
     /// Target triple for WASM compilation
     pub target_triple: String,
+    /// Target CPU name passed to LLVM (e.g. `generic`, `mvp`, `bleeding-edge`)
+    pub cpu: String,
+    /// Comma-separated LLVM target features (e.g. `+simd128,+bulk-memory`)
+    pub target_features: String,
+    /// Link-time optimization applied when modules are linked together
+    pub lto: LtoMode,
+    /// Experimental WebAssembly proposals the output may rely on
+    pub wasm_features: Vec<WasmFeature>,
+    /// Upper bound on linear memory in bytes, a multiple of the 64 KiB page size
+    pub max_memory: Option<u32>,
+    /// What the allocator does when memory is exhausted
+    pub on_oom: OomBehavior,
+    /// Allocator behind `__replica_alloc` and `__replica_free`
+    pub allocator: Allocator,
+    /// Actor state structs larger than this many bytes produce a warning
+    pub state_size_warning: Option<u32>,
+    /// Stack size the module is linked with; deeper static call chains produce a warning
+    pub stack_size: u32,
+    /// Whether recursive methods check for stack overflow on entry
+    pub stack_guard: bool,
+    /// Whether dynamic array indices are checked, trapping when out of bounds
+    pub bounds_checks: bool,
+    /// Whether distributed actors' HTTP routes are embedded in a custom section
+    pub http_facade: bool,
+    /// Where `randomInt` and `randomFloat` draw their random bits from
+    pub random: RandomSource,
+    /// Where `publish` sends topic values and `subscribe` registers instances
+    pub topics: TopicRoute,
+    /// Least severe `log.<level>` call kept in the output; lower levels are removed
+    pub log_level: LogLevel,
+    /// Values `config("KEY")` resolves to, keyed by `KEY`
+    pub config: BTreeMap<String, ConfigValue>,
+    /// Command that links `--emit wasm` output into a module, e.g. `wasm-ld`
+    pub linker: String,
+    /// Runtime library linked into `--emit wasm` output; without it the
+    /// `__replica_*` routines are left as imports for the host to provide
+    pub runtime_library: Option<PathBuf>,
+    /// Names the actor entry points are exported under
+    pub export_names: ExportNames,
+}
+
+impl CodeGenOptions {
+    /// Whether `feature` was requested
+    pub fn has_wasm_feature(&self, feature: WasmFeature) -> bool {
+        self.wasm_features.contains(&feature)
+    }
 }
 
 impl Default for CodeGenOptions {
@@ -38,6 +89,537 @@ impl Default for CodeGenOptions {
             optimization_level: OptimizationLevel::Default,
             debug_mode: false,
             target_triple: String::from("wasm32-unknown-unknown"),
+            cpu: String::from("generic"),
+            target_features: String::new(),
+            lto: LtoMode::Off,
+            wasm_features: Vec::new(),
+            max_memory: None,
+            on_oom: OomBehavior::ReturnNull,
+            allocator: Allocator::Bump,
+            state_size_warning: Some(WASM_PAGE_SIZE),
+            stack_size: WASM_PAGE_SIZE,
+            stack_guard: false,
+            bounds_checks: true,
+            http_facade: false,
+            random: RandomSource::Module,
+            topics: TopicRoute::Runtime,
+            log_level: LogLevel::Debug,
+            config: BTreeMap::new(),
+            linker: String::from("wasm-ld"),
+            runtime_library: None,
+            export_names: ExportNames::Symbol,
+        }
+    }
+}
+
+/// Size of a WebAssembly memory page
+pub const WASM_PAGE_SIZE: u32 = 65536;
+
+/// Parses a byte size such as `65536`, `64KiB` or `16MiB`
+pub fn parse_size(s: &str) -> Result<u32, String> {
+    let s = s.trim();
+    let digits = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (number, unit) = s.split_at(digits);
+    let multiplier: u64 = match unit.trim() {
+        "" | "B" => 1,
+        "K" | "KiB" => 1 << 10,
+        "M" | "MiB" => 1 << 20,
+        "G" | "GiB" => 1 << 30,
+        _ => {
+            return Err(format!(
+                "Invalid size '{}' (expected e.g. 65536, 64KiB or 16MiB)",
+                s
+            ))
+        }
+    };
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .and_then(|bytes| u32::try_from(bytes).ok())
+        .ok_or_else(|| format!("Invalid size '{}' (must be below 4GiB)", s))
+}
+
+/// Parses a memory limit, which must be a whole number of WASM pages
+pub fn parse_memory_limit(s: &str) -> Result<u32, String> {
+    let bytes = parse_size(s)?;
+    if bytes == 0 || bytes % WASM_PAGE_SIZE != 0 {
+        return Err(format!(
+            "Maximum memory '{}' must be a non-zero multiple of the {}-byte page size",
+            s, WASM_PAGE_SIZE
+        ));
+    }
+    Ok(bytes)
+}
+
+/// Parses a warning threshold: a size, or `off` to disable the warning
+pub fn parse_size_threshold(s: &str) -> Result<Option<u32>, String> {
+    match s.trim() {
+        "off" => Ok(None),
+        size => parse_size(size).map(Some),
+    }
+}
+
+/// Parses an optimization level, `0` to `3` as with other compilers
+pub fn parse_opt_level(s: &str) -> Result<OptimizationLevel, String> {
+    match s.trim() {
+        "0" => Ok(OptimizationLevel::None),
+        "1" => Ok(OptimizationLevel::Less),
+        "2" => Ok(OptimizationLevel::Default),
+        "3" => Ok(OptimizationLevel::Aggressive),
+        _ => Err(format!(
+            "Invalid optimization level '{}' (expected 0, 1, 2 or 3)",
+            s
+        )),
+    }
+}
+
+/// Allocator behavior when linear memory is exhausted
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum OomBehavior {
+    /// Abort execution with a trap
+    Trap,
+    /// Return null so the failure surfaces as a runtime error, e.g. from `<Actor>.new`
+    #[default]
+    ReturnNull,
+}
+
+impl OomBehavior {
+    /// Names accepted on the command line and in `replica.toml`
+    pub const NAMES: &'static [&'static str] = &["trap", "null"];
+}
+
+impl FromStr for OomBehavior {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "trap" => Ok(OomBehavior::Trap),
+            "null" => Ok(OomBehavior::ReturnNull),
+            _ => Err(format!(
+                "Unknown out-of-memory behavior '{}' (expected one of: {})",
+                s,
+                Self::NAMES.join(", ")
+            )),
+        }
+    }
+}
+
+impl fmt::Display for OomBehavior {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OomBehavior::Trap => write!(f, "trap"),
+            OomBehavior::ReturnNull => write!(f, "null"),
+        }
+    }
+}
+
+/// Allocator the runtime serves `__replica_alloc` and `__replica_free` with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Allocator {
+    /// Never reuses memory except the most recent allocation once it is freed
+    #[default]
+    Bump,
+    /// Reuses freed blocks for later allocations that fit
+    FreeList,
+    /// The host imports `replica_host_alloc` and `replica_host_free`, for hosts
+    /// that already manage the module's memory
+    External,
+}
+
+impl Allocator {
+    /// Names accepted on the command line and in `replica.toml`
+    pub const NAMES: &'static [&'static str] = &["bump", "freelist", "external"];
+
+    /// Number the runtime and the ABI handshake identify the allocator by
+    pub fn kind(self) -> replica_runtime::alloc::AllocatorKind {
+        use replica_runtime::alloc::AllocatorKind;
+        match self {
+            Allocator::Bump => AllocatorKind::Bump,
+            Allocator::FreeList => AllocatorKind::FreeList,
+            Allocator::External => AllocatorKind::External,
+        }
+    }
+}
+
+impl FromStr for Allocator {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bump" => Ok(Allocator::Bump),
+            "freelist" => Ok(Allocator::FreeList),
+            "external" => Ok(Allocator::External),
+            _ => Err(format!(
+                "Unknown allocator '{}' (expected one of: {})",
+                s,
+                Self::NAMES.join(", ")
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Allocator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Allocator::Bump => write!(f, "bump"),
+            Allocator::FreeList => write!(f, "freelist"),
+            Allocator::External => write!(f, "external"),
+        }
+    }
+}
+
+/// Source of the bits behind the random number built-ins
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum RandomSource {
+    /// The runtime's seedable generator, so a seeded run is reproducible
+    #[default]
+    Module,
+    /// The host import `replica_random_bits() -> i64`
+    Host,
+}
+
+impl RandomSource {
+    /// Names accepted on the command line and in `replica.toml`
+    pub const NAMES: &'static [&'static str] = &["module", "host"];
+}
+
+impl FromStr for RandomSource {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "module" => Ok(RandomSource::Module),
+            "host" => Ok(RandomSource::Host),
+            _ => Err(format!(
+                "Unknown random source '{}' (expected one of: {})",
+                s,
+                Self::NAMES.join(", ")
+            )),
+        }
+    }
+}
+
+impl fmt::Display for RandomSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RandomSource::Module => write!(f, "module"),
+            RandomSource::Host => write!(f, "host"),
+        }
+    }
+}
+
+/// Where the values of topics are routed between publishers and subscribers
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum TopicRoute {
+    /// The runtime's routing table, which queues values on the subscribers'
+    /// mailboxes in the same module
+    #[default]
+    Runtime,
+    /// The host imports `replica_topic_publish` and `replica_topic_subscribe`,
+    /// so a transport can carry values between modules
+    Transport,
+}
+
+impl TopicRoute {
+    /// Names accepted on the command line and in `replica.toml`
+    pub const NAMES: &'static [&'static str] = &["runtime", "transport"];
+}
+
+impl FromStr for TopicRoute {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "runtime" => Ok(TopicRoute::Runtime),
+            "transport" => Ok(TopicRoute::Transport),
+            _ => Err(format!(
+                "Unknown topic route '{}' (expected one of: {})",
+                s,
+                Self::NAMES.join(", ")
+            )),
+        }
+    }
+}
+
+impl fmt::Display for TopicRoute {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TopicRoute::Runtime => write!(f, "runtime"),
+            TopicRoute::Transport => write!(f, "transport"),
+        }
+    }
+}
+
+/// Names actor methods, `<Actor>.new` and their entry points are exported
+/// under in the WebAssembly module. Symbols are qualified with the actor name
+/// either way, so the choice only changes the separator.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum ExportNames {
+    /// The symbol names, e.g. `Counter.add`, `Counter.new` and
+    /// `Counter.add.post` (see `docs/ABI.md`)
+    #[default]
+    Symbol,
+    /// Prefixed with the actor name and joined with `_`, e.g. `Counter_add`,
+    /// `Counter_new` and `Counter_add_post`
+    Qualified,
+}
+
+impl ExportNames {
+    /// Names accepted on the command line and in `replica.toml`
+    pub const NAMES: &'static [&'static str] = &["symbol", "qualified"];
+
+    /// Export name of `symbol`, the member `parts` of `actor` (`["add"]`,
+    /// `["new"]` or `["add", "post"]` of `Counter`, for `Counter.add` and so on). Namespaced actors keep their package
+    /// prefix, so `math.Vector`'s `length` becomes `math_Vector_length`.
+    pub fn export_name(self, symbol: &str, actor: &str, parts: &[&str]) -> String {
+        match self {
+            ExportNames::Symbol => symbol.to_string(),
+            ExportNames::Qualified => std::iter::once(actor)
+                .chain(parts.iter().copied())
+                .collect::<Vec<_>>()
+                .join("_")
+                .replace('.', "_"),
+        }
+    }
+}
+
+impl FromStr for ExportNames {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "symbol" => Ok(ExportNames::Symbol),
+            "qualified" => Ok(ExportNames::Qualified),
+            _ => Err(format!(
+                "Unknown export naming '{}' (expected one of: {})",
+                s,
+                Self::NAMES.join(", ")
+            )),
+        }
+    }
+}
+
+impl fmt::Display for ExportNames {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExportNames::Symbol => write!(f, "symbol"),
+            ExportNames::Qualified => write!(f, "qualified"),
+        }
+    }
+}
+
+/// How a `config("KEY")` built-in obtains its value
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigValue {
+    /// Baked into the module's data at compile time
+    Fixed(String),
+    /// Asked from the host import `replica_env_get` each time it is evaluated,
+    /// falling back to `default` when the host has no value for the key
+    Runtime { default: String },
+}
+
+/// Experimental WebAssembly features that change what the compiler generates,
+/// not just which instructions LLVM may select
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum WasmFeature {
+    /// Shared linear memory and atomics, so actors can run on a pool of workers
+    Threads,
+}
+
+impl WasmFeature {
+    /// Names accepted by `--wasm-features` and in `replica.toml`
+    pub const NAMES: &'static [&'static str] = &["threads"];
+
+    /// LLVM target features the generated code needs
+    pub fn target_features(&self) -> &'static [&'static str] {
+        match self {
+            WasmFeature::Threads => &["+atomics", "+bulk-memory"],
+        }
+    }
+
+    /// Parses a comma-separated list such as `threads`
+    pub fn parse_list(list: &str) -> Result<Vec<Self>, String> {
+        let mut features = Vec::new();
+        for name in list.split(',').map(str::trim).filter(|n| !n.is_empty()) {
+            let feature = name.parse()?;
+            if !features.contains(&feature) {
+                features.push(feature);
+            }
+        }
+        Ok(features)
+    }
+}
+
+impl FromStr for WasmFeature {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "threads" => Ok(WasmFeature::Threads),
+            _ => Err(format!(
+                "Unknown WASM feature '{}' (expected one of: {})",
+                s,
+                Self::NAMES.join(", ")
+            )),
+        }
+    }
+}
+
+impl fmt::Display for WasmFeature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WasmFeature::Threads => write!(f, "threads"),
+        }
+    }
+}
+
+/// Link-time optimization modes for multi-module builds
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum LtoMode {
+    /// Modules are linked but only optimized individually
+    #[default]
+    Off,
+    /// Linked module is optimized with LLVM's ThinLTO pipeline
+    Thin,
+    /// Linked module is optimized with LLVM's full LTO pipeline
+    Full,
+}
+
+impl LtoMode {
+    /// Names accepted on the command line and in `replica.toml`
+    pub const NAMES: &'static [&'static str] = &["off", "thin", "full"];
+}
+
+impl FromStr for LtoMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(LtoMode::Off),
+            "thin" => Ok(LtoMode::Thin),
+            "full" => Ok(LtoMode::Full),
+            _ => Err(format!(
+                "Unknown LTO mode '{}' (expected one of: {})",
+                s,
+                Self::NAMES.join(", ")
+            )),
+        }
+    }
+}
+
+impl fmt::Display for LtoMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LtoMode::Off => write!(f, "off"),
+            LtoMode::Thin => write!(f, "thin"),
+            LtoMode::Full => write!(f, "full"),
+        }
+    }
+}
+
+/// Output artifacts the code generator can produce
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum EmitKind {
+    /// WebAssembly module linked with the external linker
+    #[default]
+    Wasm,
+    /// The linked module in the WebAssembly text format
+    Wat,
+    /// Relocatable object file, before linking
+    Object,
+    /// Textual LLVM IR of the module
+    LlvmIr,
+    /// LLVM bitcode for linking with other LLVM-based toolchains
+    LlvmBitcode,
+    /// `ar` archive containing the object file, for external linkers
+    StaticLib,
+    /// Python module wrapping the actors' imports and exports for wasmtime-py
+    PyBindings,
+    /// OpenAPI document describing distributed actors' methods as HTTP endpoints
+    OpenApi,
+    /// Syntax tree of each input, as parsed
+    Ast,
+    /// Token stream of each input, one token per line with its line number
+    Tokens,
+    /// Field offsets, sizes, alignments and padding of each actor's state
+    /// struct and of the declared structs and enums, as laid out for the target
+    Layout,
+}
+
+impl EmitKind {
+    /// Names accepted on the command line
+    pub const NAMES: &'static [&'static str] = &[
+        "wasm",
+        "wat",
+        "obj",
+        "llvm-ir",
+        "llvm-bc",
+        "staticlib",
+        "py-bindings",
+        "openapi",
+        "ast",
+        "tokens",
+        "layout",
+    ];
+
+    /// Conventional file extension for the artifact
+    pub fn extension(&self) -> &'static str {
+        match self {
+            EmitKind::Wasm => "wasm",
+            EmitKind::Wat => "wat",
+            EmitKind::Object => "o",
+            EmitKind::LlvmIr => "ll",
+            EmitKind::LlvmBitcode => "bc",
+            EmitKind::StaticLib => "a",
+            EmitKind::PyBindings => "py",
+            EmitKind::OpenApi => "json",
+            EmitKind::Ast => "ast",
+            EmitKind::Tokens => "tokens",
+            EmitKind::Layout => "layout",
+        }
+    }
+}
+
+impl FromStr for EmitKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "wasm" => Ok(EmitKind::Wasm),
+            "wat" => Ok(EmitKind::Wat),
+            "obj" => Ok(EmitKind::Object),
+            "llvm-ir" => Ok(EmitKind::LlvmIr),
+            "llvm-bc" => Ok(EmitKind::LlvmBitcode),
+            "staticlib" => Ok(EmitKind::StaticLib),
+            "py-bindings" => Ok(EmitKind::PyBindings),
+            "openapi" => Ok(EmitKind::OpenApi),
+            "ast" => Ok(EmitKind::Ast),
+            "tokens" => Ok(EmitKind::Tokens),
+            "layout" => Ok(EmitKind::Layout),
+            _ => Err(format!(
+                "Unknown emit kind '{}' (expected one of: {})",
+                s,
+                Self::NAMES.join(", ")
+            )),
+        }
+    }
+}
+
+impl fmt::Display for EmitKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EmitKind::Wasm => write!(f, "wasm"),
+            EmitKind::Wat => write!(f, "wat"),
+            EmitKind::Object => write!(f, "obj"),
+            EmitKind::LlvmIr => write!(f, "llvm-ir"),
+            EmitKind::LlvmBitcode => write!(f, "llvm-bc"),
+            EmitKind::StaticLib => write!(f, "staticlib"),
+            EmitKind::PyBindings => write!(f, "py-bindings"),
+            EmitKind::OpenApi => write!(f, "openapi"),
+            EmitKind::Ast => write!(f, "ast"),
+            EmitKind::Tokens => write!(f, "tokens"),
+            EmitKind::Layout => write!(f, "layout"),
         }
     }
 }
@@ -51,18 +633,6 @@ pub fn create_generator<'ctx>(
     CodeGenerator::new(context, module_name, options.unwrap_or_default())
 }
 
-/// Utility function to create a new context and code generator in one step
-pub fn create_generator_with_context(
-    module_name: &str,
-    options: Option<CodeGenOptions>,
-) -> CodeGenResult<(ContextWrapper, CodeGenerator)> {
-    let context_wrapper = ContextWrapper {
-        context: Context::create(),
-    };
-    let generator = create_generator(&context_wrapper.context, module_name, options)?;
-    Ok((context_wrapper, generator))
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -75,12 +645,6 @@ mod tests {
         assert!(result.is_ok());
     }
 
-    #[test]
-    fn test_create_generator_with_context() {
-        let result = create_generator_with_context("test_module", None);
-        assert!(result.is_ok());
-    }
-
     #[test]
     fn test_create_generator_with_options() {
         let context = Context::create();
@@ -88,22 +652,199 @@ mod tests {
             optimization_level: OptimizationLevel::Aggressive,
             debug_mode: true,
             target_triple: String::from("wasm32-unknown-unknown"),
+            cpu: String::from("mvp"),
+            target_features: String::from("+bulk-memory"),
+            lto: LtoMode::Full,
+            wasm_features: vec![WasmFeature::Threads],
+            max_memory: Some(16 * WASM_PAGE_SIZE),
+            on_oom: OomBehavior::Trap,
+            allocator: Allocator::FreeList,
+            state_size_warning: None,
+            stack_size: 2 * WASM_PAGE_SIZE,
+            stack_guard: true,
+            bounds_checks: false,
+            http_facade: true,
+            random: RandomSource::Host,
+            topics: TopicRoute::Transport,
+            log_level: LogLevel::Warn,
+            config: BTreeMap::from([
+                (
+                    "GREETING".to_string(),
+                    ConfigValue::Fixed("hello".to_string()),
+                ),
+                (
+                    "API_URL".to_string(),
+                    ConfigValue::Runtime {
+                        default: String::new(),
+                    },
+                ),
+            ]),
+            linker: String::from("rust-lld -flavor wasm"),
+            runtime_library: Some(PathBuf::from("libreplica_runtime.a")),
+            export_names: ExportNames::Qualified,
         };
 
         let result = create_generator(&context, "test_module", Some(options));
         assert!(result.is_ok());
     }
 
+    #[test]
+    fn test_create_generator_with_unsupported_triple() {
+        let context = Context::create();
+        let options = CodeGenOptions {
+            target_triple: String::from("x86_64-unknown-linux-gnu"),
+            ..CodeGenOptions::default()
+        };
+
+        let error = create_generator(&context, "test_module", Some(options))
+            .err()
+            .expect("non-WASM triples should be rejected");
+        assert!(error.to_string().contains("x86_64-unknown-linux-gnu"));
+        assert!(error.to_string().contains("wasm32-unknown-unknown"));
+    }
+
+    #[test]
+    fn test_create_generator_with_malformed_features() {
+        let context = Context::create();
+        let options = CodeGenOptions {
+            target_features: String::from("simd128"),
+            ..CodeGenOptions::default()
+        };
+
+        let result = create_generator(&context, "test_module", Some(options));
+        assert!(matches!(
+            result.err().map(|e| e.category()),
+            Some(error::ErrorCategory::Initialization)
+        ));
+    }
+
+    #[test]
+    fn test_emit_kind_parsing() {
+        for name in EmitKind::NAMES {
+            let kind: EmitKind = name.parse().unwrap();
+            assert_eq!(kind.to_string(), *name);
+        }
+        assert!("exe".parse::<EmitKind>().is_err());
+        assert_eq!(EmitKind::default(), EmitKind::Wasm);
+    }
+
+    #[test]
+    fn test_lto_mode_parsing() {
+        for name in LtoMode::NAMES {
+            let mode: LtoMode = name.parse().unwrap();
+            assert_eq!(mode.to_string(), *name);
+        }
+        assert!("fat".parse::<LtoMode>().is_err());
+    }
+
+    #[test]
+    fn test_wasm_feature_parsing() {
+        for name in WasmFeature::NAMES {
+            let feature: WasmFeature = name.parse().unwrap();
+            assert_eq!(feature.to_string(), *name);
+        }
+        assert_eq!(
+            WasmFeature::parse_list("threads, threads").unwrap(),
+            vec![WasmFeature::Threads]
+        );
+        assert!(WasmFeature::parse_list("threads,gc").is_err());
+    }
+
+    #[test]
+    fn test_memory_option_parsing() {
+        assert_eq!(parse_size("4096"), Ok(4096));
+        assert_eq!(parse_size("64KiB"), Ok(WASM_PAGE_SIZE));
+        assert_eq!(parse_size("16M"), Ok(16 << 20));
+        assert!(parse_size("4GiB").is_err());
+        assert!(parse_size("12 pages").is_err());
+
+        assert_eq!(parse_memory_limit("1MiB"), Ok(16 * WASM_PAGE_SIZE));
+        assert!(parse_memory_limit("1000").is_err());
+        assert!(parse_memory_limit("0").is_err());
+        assert_eq!(parse_size_threshold("off"), Ok(None));
+        assert_eq!(parse_size_threshold("8KiB"), Ok(Some(8192)));
+
+        for name in OomBehavior::NAMES {
+            let behavior: OomBehavior = name.parse().unwrap();
+            assert_eq!(behavior.to_string(), *name);
+        }
+        assert!("abort".parse::<OomBehavior>().is_err());
+
+        for name in Allocator::NAMES {
+            let allocator: Allocator = name.parse().unwrap();
+            assert_eq!(allocator.to_string(), *name);
+        }
+        assert_eq!(Allocator::External.kind() as u32, 2);
+        assert!("malloc".parse::<Allocator>().is_err());
+
+        for name in RandomSource::NAMES {
+            let source: RandomSource = name.parse().unwrap();
+            assert_eq!(source.to_string(), *name);
+        }
+        assert!("os".parse::<RandomSource>().is_err());
+
+        for name in TopicRoute::NAMES {
+            let route: TopicRoute = name.parse().unwrap();
+            assert_eq!(route.to_string(), *name);
+        }
+        assert!("mqtt".parse::<TopicRoute>().is_err());
+    }
+
+    #[test]
+    fn test_export_names() {
+        for name in ExportNames::NAMES {
+            let names: ExportNames = name.parse().unwrap();
+            assert_eq!(names.to_string(), *name);
+        }
+        assert!("mangled".parse::<ExportNames>().is_err());
+
+        let qualified = ExportNames::Qualified;
+        assert_eq!(
+            qualified.export_name("Counter.add", "Counter", &["add"]),
+            "Counter_add"
+        );
+        assert_eq!(
+            qualified.export_name("Counter.add.post", "Counter", &["add", "post"]),
+            "Counter_add_post"
+        );
+        assert_eq!(
+            qualified.export_name("math.Vector.new", "math.Vector", &["new"]),
+            "math_Vector_new"
+        );
+        assert_eq!(
+            ExportNames::Symbol.export_name("Counter.new", "Counter", &["new"]),
+            "Counter.new"
+        );
+        // 既定の名前もアクター名で修飾されている
+        assert_eq!(
+            ExportNames::default().export_name("Counter.add.post", "Counter", &["add", "post"]),
+            "Counter.add.post"
+        );
+    }
+
     #[test]
     fn test_generator_compilation() {
-        let (context, mut generator) =
-            create_generator_with_context("test_module", None).expect("Failed to create generator");
+        let context = Context::create();
+        let mut generator =
+            create_generator(&context, "test_module", None).expect("Failed to create generator");
 
         let test_actor = Actor {
             name: String::from("TestActor"),
             actor_type: ActorType::Single,
             methods: vec![],
             fields: vec![],
+            init: None,
+            deinit: None,
+            layout: Default::default(),
+            externs: vec![],
+            imports: vec![],
+            topics: vec![],
+            pools: vec![],
+            structs: vec![],
+            enums: vec![],
+            constants: vec![],
+            protocol: None,
+            doc: None,
         };
 
         let result = generator.compile_actor(&test_actor);
@@ -0,0 +1,797 @@
+//! Declarations of the routines provided by the prebuilt `replica-runtime` library.
+//! Generated code only declares these symbols; the definitions come from
+//! `libreplica_runtime.a`, which is linked into the final module.
+
+use inkwell::{
+    attributes::AttributeLoc,
+    builder::BuilderError,
+    context::Context,
+    module::{FlagBehavior, Linkage, Module},
+    types::{BasicMetadataTypeEnum, FunctionType},
+    values::FunctionValue,
+    AddressSpace, IntPredicate,
+};
+
+use super::{Allocator, RandomSource};
+use crate::proto::{WireField, WireKind};
+use replica_runtime::proto;
+pub use replica_runtime::{ABI_HANDSHAKE_EXPORT, INIT_EXPORT, RUNTIME_ABI_VERSION};
+
+/// Module flag carrying the runtime ABI version a module was compiled against
+pub const ABI_VERSION_FLAG: &str = "replica.runtime_abi_version";
+
+/// Module flag carrying the number of the allocator a module is compiled with
+pub const ALLOCATOR_FLAG: &str = "replica.allocator";
+
+/// Module flag set to 1 when the module expects shared memory (`threads`)
+pub const SHARED_MEMORY_FLAG: &str = "replica.shared_memory";
+
+/// Function wasm-ld synthesizes to call the module's `llvm.global_ctors`
+pub const CALL_CTORS: &str = "__wasm_call_ctors";
+
+/// Host import supplying random bits when compiled with `--random host`
+pub const HOST_RANDOM_IMPORT: &str = "replica_random_bits";
+
+/// Host import allocating memory when compiled with `--allocator external`
+pub const HOST_ALLOC_IMPORT: &str = "replica_host_alloc";
+
+/// Host import releasing memory when compiled with `--allocator external`
+pub const HOST_FREE_IMPORT: &str = "replica_host_free";
+
+/// Host import receiving every `log.<level>(message)` call
+pub const LOG_IMPORT: &str = "replica_log";
+
+/// Host import looking up `config("KEY")` values resolved at run time
+pub const ENV_IMPORT: &str = "replica_env_get";
+
+/// Host import receiving `publish(Topic, value)` when compiled with `--topics transport`
+pub const TOPIC_PUBLISH_IMPORT: &str = "replica_topic_publish";
+
+/// Host import receiving `subscribe(Topic)` when compiled with `--topics transport`
+pub const TOPIC_SUBSCRIBE_IMPORT: &str = "replica_topic_subscribe";
+
+/// Host import returning the number of nodes calls of sharded methods are spread over
+pub const SHARD_NODES_IMPORT: &str = "replica_shard_nodes";
+
+/// Host import sending a message of a sharded method to a node
+pub const SHARD_SEND_IMPORT: &str = "replica_shard_send";
+
+/// Host import sending a message of an `@idempotent` or `@retry` method with
+/// its idempotency key
+pub const REMOTE_SEND_IMPORT: &str = "replica_remote_send";
+
+/// Host import returning a fresh idempotency key for a remote call
+pub const IDEMPOTENCY_KEY_IMPORT: &str = "replica_idempotency_key";
+
+/// Host import waiting before a failed remote call is sent again
+pub const RETRY_WAIT_IMPORT: &str = "replica_retry_wait";
+
+/// Routines exported by the runtime library
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RuntimeFunction {
+    AbiVersion,
+    Alloc,
+    Free,
+    ObjectNew,
+    Retain,
+    Release,
+    WeakStore,
+    WeakLoad,
+    WeakRelease,
+    IsUnique,
+    MakeUnique,
+    StringConcat,
+    StringEq,
+    FloatToString,
+    IntToString,
+    BoolToString,
+    StringToInt,
+    RandomNext,
+    RandomInt,
+    RandomFloat,
+    ArrayNew,
+    ArrayElement,
+    ArrayAlloc,
+    ArrayLen,
+    ArrayGet,
+    MailboxNew,
+    MailboxPush,
+    MailboxPop,
+    MailboxLen,
+    Schedule,
+    Run,
+    Drain,
+    Subscribe,
+    Unsubscribe,
+    Publish,
+    StringHash,
+    JumpHash,
+    IdempotencySeen,
+    IdempotencyRecord,
+    LockAcquire,
+    LockRelease,
+    SetMemoryPolicy,
+    SetAllocator,
+    StackOverflow,
+    UnwrapNil,
+    ProtoEncode,
+    ProtoDecode,
+    RegisterActor,
+}
+
+impl RuntimeFunction {
+    /// Every runtime routine
+    pub const ALL: &'static [RuntimeFunction] = &[
+        RuntimeFunction::AbiVersion,
+        RuntimeFunction::Alloc,
+        RuntimeFunction::Free,
+        RuntimeFunction::ObjectNew,
+        RuntimeFunction::Retain,
+        RuntimeFunction::Release,
+        RuntimeFunction::WeakStore,
+        RuntimeFunction::WeakLoad,
+        RuntimeFunction::WeakRelease,
+        RuntimeFunction::IsUnique,
+        RuntimeFunction::MakeUnique,
+        RuntimeFunction::StringConcat,
+        RuntimeFunction::StringEq,
+        RuntimeFunction::FloatToString,
+        RuntimeFunction::IntToString,
+        RuntimeFunction::BoolToString,
+        RuntimeFunction::StringToInt,
+        RuntimeFunction::RandomNext,
+        RuntimeFunction::RandomInt,
+        RuntimeFunction::RandomFloat,
+        RuntimeFunction::ArrayNew,
+        RuntimeFunction::ArrayElement,
+        RuntimeFunction::ArrayAlloc,
+        RuntimeFunction::ArrayLen,
+        RuntimeFunction::ArrayGet,
+        RuntimeFunction::MailboxNew,
+        RuntimeFunction::MailboxPush,
+        RuntimeFunction::MailboxPop,
+        RuntimeFunction::MailboxLen,
+        RuntimeFunction::Schedule,
+        RuntimeFunction::Run,
+        RuntimeFunction::Drain,
+        RuntimeFunction::Subscribe,
+        RuntimeFunction::Unsubscribe,
+        RuntimeFunction::Publish,
+        RuntimeFunction::StringHash,
+        RuntimeFunction::JumpHash,
+        RuntimeFunction::IdempotencySeen,
+        RuntimeFunction::IdempotencyRecord,
+        RuntimeFunction::LockAcquire,
+        RuntimeFunction::LockRelease,
+        RuntimeFunction::SetMemoryPolicy,
+        RuntimeFunction::SetAllocator,
+        RuntimeFunction::StackOverflow,
+        RuntimeFunction::UnwrapNil,
+        RuntimeFunction::ProtoEncode,
+        RuntimeFunction::ProtoDecode,
+        RuntimeFunction::RegisterActor,
+    ];
+
+    /// Linker symbol of the routine
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            RuntimeFunction::AbiVersion => "__replica_runtime_abi_version",
+            RuntimeFunction::Alloc => "__replica_alloc",
+            RuntimeFunction::Free => "__replica_free",
+            RuntimeFunction::ObjectNew => "__replica_object_new",
+            RuntimeFunction::Retain => "__replica_retain",
+            RuntimeFunction::Release => "__replica_release",
+            RuntimeFunction::WeakStore => "__replica_weak_store",
+            RuntimeFunction::WeakLoad => "__replica_weak_load",
+            RuntimeFunction::WeakRelease => "__replica_weak_release",
+            RuntimeFunction::IsUnique => "__replica_is_unique",
+            RuntimeFunction::MakeUnique => "__replica_make_unique",
+            RuntimeFunction::StringConcat => "__replica_string_concat",
+            RuntimeFunction::StringEq => "__replica_string_eq",
+            RuntimeFunction::FloatToString => "__replica_float_to_string",
+            RuntimeFunction::IntToString => "__replica_int_to_string",
+            RuntimeFunction::BoolToString => "__replica_bool_to_string",
+            RuntimeFunction::StringToInt => "__replica_string_to_int",
+            RuntimeFunction::RandomNext => "__replica_random_next",
+            RuntimeFunction::RandomInt => "__replica_random_int",
+            RuntimeFunction::RandomFloat => "__replica_random_float",
+            RuntimeFunction::ArrayNew => "__replica_array_new",
+            RuntimeFunction::ArrayElement => "__replica_array_element",
+            RuntimeFunction::ArrayAlloc => "__replica_array_alloc",
+            RuntimeFunction::ArrayLen => "__replica_array_len",
+            RuntimeFunction::ArrayGet => "__replica_array_get",
+            RuntimeFunction::MailboxNew => "__replica_mailbox_new",
+            RuntimeFunction::MailboxPush => "__replica_mailbox_push",
+            RuntimeFunction::MailboxPop => "__replica_mailbox_pop",
+            RuntimeFunction::MailboxLen => "__replica_mailbox_len",
+            RuntimeFunction::Schedule => "__replica_schedule",
+            RuntimeFunction::Run => "__replica_run",
+            RuntimeFunction::Drain => "__replica_drain",
+            RuntimeFunction::Subscribe => "__replica_subscribe",
+            RuntimeFunction::Unsubscribe => "__replica_unsubscribe",
+            RuntimeFunction::Publish => "__replica_publish",
+            RuntimeFunction::StringHash => "__replica_string_hash",
+            RuntimeFunction::JumpHash => "__replica_jump_hash",
+            RuntimeFunction::IdempotencySeen => "__replica_idempotency_seen",
+            RuntimeFunction::IdempotencyRecord => "__replica_idempotency_record",
+            RuntimeFunction::LockAcquire => "__replica_lock_acquire",
+            RuntimeFunction::LockRelease => "__replica_lock_release",
+            RuntimeFunction::SetMemoryPolicy => "__replica_set_memory_policy",
+            RuntimeFunction::SetAllocator => "__replica_set_allocator",
+            RuntimeFunction::StackOverflow => "__replica_stack_overflow",
+            RuntimeFunction::UnwrapNil => "__replica_unwrap_nil",
+            RuntimeFunction::ProtoEncode => "__replica_proto_encode",
+            RuntimeFunction::ProtoDecode => "__replica_proto_decode",
+            RuntimeFunction::RegisterActor => "__replica_register_actor",
+        }
+    }
+
+    /// LLVM signature matching the runtime's `extern "C"` definition on wasm32
+    fn signature<'ctx>(&self, context: &'ctx Context) -> FunctionType<'ctx> {
+        let ptr = context.ptr_type(AddressSpace::default());
+        let i32_type = context.i32_type();
+        let size = context.i32_type();
+        let void = context.void_type();
+        let params = |types: &[BasicMetadataTypeEnum<'ctx>]| types.to_vec();
+
+        match self {
+            RuntimeFunction::AbiVersion => i32_type.fn_type(&[], false),
+            RuntimeFunction::Alloc => ptr.fn_type(&params(&[size.into()]), false),
+            RuntimeFunction::Free => void.fn_type(&params(&[ptr.into()]), false),
+            RuntimeFunction::ObjectNew => ptr.fn_type(&params(&[size.into(), ptr.into()]), false),
+            RuntimeFunction::Retain => void.fn_type(&params(&[ptr.into()]), false),
+            RuntimeFunction::Release => i32_type.fn_type(&params(&[ptr.into()]), false),
+            RuntimeFunction::WeakStore => void.fn_type(&params(&[ptr.into(), ptr.into()]), false),
+            RuntimeFunction::WeakLoad => ptr.fn_type(&params(&[ptr.into()]), false),
+            RuntimeFunction::WeakRelease => void.fn_type(&params(&[ptr.into()]), false),
+            RuntimeFunction::IsUnique => i32_type.fn_type(&params(&[ptr.into()]), false),
+            RuntimeFunction::MakeUnique => ptr.fn_type(&params(&[ptr.into(), size.into()]), false),
+            RuntimeFunction::StringConcat => void.fn_type(
+                &params(&[ptr.into(), size.into(), ptr.into(), size.into(), ptr.into()]),
+                false,
+            ),
+            RuntimeFunction::StringEq => i32_type.fn_type(
+                &params(&[ptr.into(), size.into(), ptr.into(), size.into()]),
+                false,
+            ),
+            RuntimeFunction::FloatToString => {
+                void.fn_type(&params(&[context.f64_type().into(), ptr.into()]), false)
+            }
+            RuntimeFunction::IntToString | RuntimeFunction::BoolToString => {
+                void.fn_type(&params(&[i32_type.into(), ptr.into()]), false)
+            }
+            RuntimeFunction::StringToInt => {
+                i32_type.fn_type(&params(&[ptr.into(), ptr.into()]), false)
+            }
+            RuntimeFunction::RandomNext => context.i64_type().fn_type(&[], false),
+            RuntimeFunction::RandomInt => i32_type.fn_type(
+                &params(&[context.i64_type().into(), i32_type.into()]),
+                false,
+            ),
+            RuntimeFunction::RandomFloat => context
+                .f64_type()
+                .fn_type(&params(&[context.i64_type().into()]), false),
+            RuntimeFunction::ArrayNew => ptr.fn_type(&params(&[size.into(), size.into()]), false),
+            RuntimeFunction::ArrayElement => ptr.fn_type(
+                &params(&[ptr.into(), size.into(), size.into(), size.into()]),
+                false,
+            ),
+            RuntimeFunction::ArrayAlloc => ptr.fn_type(&params(&[size.into(), size.into()]), false),
+            RuntimeFunction::ArrayLen => size.fn_type(&params(&[ptr.into()]), false),
+            RuntimeFunction::ArrayGet => {
+                ptr.fn_type(&params(&[ptr.into(), size.into(), size.into()]), false)
+            }
+            RuntimeFunction::MailboxNew => ptr.fn_type(&params(&[ptr.into(), size.into()]), false),
+            RuntimeFunction::MailboxPush => i32_type.fn_type(
+                &params(&[ptr.into(), i32_type.into(), ptr.into(), size.into()]),
+                false,
+            ),
+            RuntimeFunction::MailboxPop => {
+                i32_type.fn_type(&params(&[ptr.into(), ptr.into()]), false)
+            }
+            RuntimeFunction::MailboxLen => size.fn_type(&params(&[ptr.into()]), false),
+            RuntimeFunction::Schedule => {
+                i32_type.fn_type(&params(&[ptr.into(), ptr.into()]), false)
+            }
+            RuntimeFunction::Run => i32_type.fn_type(&params(&[i32_type.into()]), false),
+            RuntimeFunction::Drain => {
+                i32_type.fn_type(&params(&[ptr.into(), ptr.into(), i32_type.into()]), false)
+            }
+            RuntimeFunction::Subscribe => void.fn_type(
+                &params(&[ptr.into(), ptr.into(), ptr.into(), i32_type.into()]),
+                false,
+            ),
+            RuntimeFunction::Unsubscribe => void.fn_type(&params(&[ptr.into()]), false),
+            RuntimeFunction::Publish => {
+                i32_type.fn_type(&params(&[ptr.into(), ptr.into(), size.into()]), false)
+            }
+            RuntimeFunction::StringHash => {
+                context.i64_type().fn_type(&params(&[ptr.into()]), false)
+            }
+            RuntimeFunction::JumpHash => i32_type.fn_type(
+                &params(&[context.i64_type().into(), i32_type.into()]),
+                false,
+            ),
+            RuntimeFunction::IdempotencySeen => {
+                i32_type.fn_type(&params(&[context.i64_type().into()]), false)
+            }
+            RuntimeFunction::IdempotencyRecord => {
+                void.fn_type(&params(&[context.i64_type().into()]), false)
+            }
+            RuntimeFunction::LockAcquire | RuntimeFunction::LockRelease => {
+                void.fn_type(&params(&[ptr.into()]), false)
+            }
+            RuntimeFunction::SetMemoryPolicy => {
+                void.fn_type(&params(&[size.into(), i32_type.into()]), false)
+            }
+            RuntimeFunction::SetAllocator => {
+                void.fn_type(&params(&[i32_type.into(), ptr.into(), ptr.into()]), false)
+            }
+            RuntimeFunction::StackOverflow | RuntimeFunction::UnwrapNil => void.fn_type(&[], false),
+            RuntimeFunction::ProtoEncode => ptr.fn_type(&params(&[ptr.into(), ptr.into()]), false),
+            RuntimeFunction::ProtoDecode => {
+                i32_type.fn_type(&params(&[ptr.into(), ptr.into(), ptr.into()]), false)
+            }
+            RuntimeFunction::RegisterActor => {
+                void.fn_type(&params(&[ptr.into(), ptr.into()]), false)
+            }
+        }
+    }
+
+    /// Returns the declaration of the routine in `module`, adding it on first use
+    pub fn declare<'ctx>(
+        &self,
+        context: &'ctx Context,
+        module: &Module<'ctx>,
+    ) -> FunctionValue<'ctx> {
+        module.get_function(self.symbol()).unwrap_or_else(|| {
+            module.add_function(
+                self.symbol(),
+                self.signature(context),
+                Some(Linkage::External),
+            )
+        })
+    }
+}
+
+/// Returns the function the random built-ins take their bits from: the runtime's
+/// generator, or an import the host must provide
+pub fn declare_random_source<'ctx>(
+    context: &'ctx Context,
+    module: &Module<'ctx>,
+    source: RandomSource,
+) -> FunctionValue<'ctx> {
+    match source {
+        RandomSource::Module => RuntimeFunction::RandomNext.declare(context, module),
+        RandomSource::Host => module.get_function(HOST_RANDOM_IMPORT).unwrap_or_else(|| {
+            module.add_function(
+                HOST_RANDOM_IMPORT,
+                context.i64_type().fn_type(&[], false),
+                Some(Linkage::External),
+            )
+        }),
+    }
+}
+
+/// Returns the declarations of the host's `replica_host_alloc(size: i32) -> ptr`
+/// and `replica_host_free(ptr)`, adding them on first use
+pub fn declare_host_allocator_imports<'ctx>(
+    context: &'ctx Context,
+    module: &Module<'ctx>,
+) -> (FunctionValue<'ctx>, FunctionValue<'ctx>) {
+    let ptr = context.ptr_type(AddressSpace::default());
+    let declare = |name: &str, function_type| {
+        module
+            .get_function(name)
+            .unwrap_or_else(|| module.add_function(name, function_type, Some(Linkage::External)))
+    };
+    let alloc = declare(
+        HOST_ALLOC_IMPORT,
+        ptr.fn_type(&[context.i32_type().into()], false),
+    );
+    let free = declare(
+        HOST_FREE_IMPORT,
+        context.void_type().fn_type(&[ptr.into()], false),
+    );
+    (alloc, free)
+}
+
+/// Returns the declaration of the host's `replica_log(level: i32, message: ptr,
+/// actor: ptr, line: i32)`, adding it on first use. Both strings are `String`
+/// descriptors.
+pub fn declare_log_import<'ctx>(
+    context: &'ctx Context,
+    module: &Module<'ctx>,
+) -> FunctionValue<'ctx> {
+    module.get_function(LOG_IMPORT).unwrap_or_else(|| {
+        let ptr = context.ptr_type(AddressSpace::default());
+        let i32_type = context.i32_type();
+        module.add_function(
+            LOG_IMPORT,
+            context.void_type().fn_type(
+                &[i32_type.into(), ptr.into(), ptr.into(), i32_type.into()],
+                false,
+            ),
+            Some(Linkage::External),
+        )
+    })
+}
+
+/// Returns the declaration of the host's `replica_env_get(key: ptr) -> ptr`,
+/// adding it on first use. Both are `String` descriptors; the host returns null
+/// when it has no value for the key.
+pub fn declare_env_import<'ctx>(
+    context: &'ctx Context,
+    module: &Module<'ctx>,
+) -> FunctionValue<'ctx> {
+    module.get_function(ENV_IMPORT).unwrap_or_else(|| {
+        let ptr = context.ptr_type(AddressSpace::default());
+        module.add_function(
+            ENV_IMPORT,
+            ptr.fn_type(&[ptr.into()], false),
+            Some(Linkage::External),
+        )
+    })
+}
+
+/// Returns the declarations of the host's `replica_topic_publish(topic: ptr,
+/// value: ptr, len: i32)` and `replica_topic_subscribe(topic: ptr, instance:
+/// ptr)`, adding them on first use. `topic` is a `String` descriptor.
+pub fn declare_topic_imports<'ctx>(
+    context: &'ctx Context,
+    module: &Module<'ctx>,
+) -> (FunctionValue<'ctx>, FunctionValue<'ctx>) {
+    let ptr = context.ptr_type(AddressSpace::default());
+    let void = context.void_type();
+    let declare = |name: &str, function_type| {
+        module
+            .get_function(name)
+            .unwrap_or_else(|| module.add_function(name, function_type, Some(Linkage::External)))
+    };
+    let publish = declare(
+        TOPIC_PUBLISH_IMPORT,
+        void.fn_type(&[ptr.into(), ptr.into(), context.i32_type().into()], false),
+    );
+    let subscribe = declare(
+        TOPIC_SUBSCRIBE_IMPORT,
+        void.fn_type(&[ptr.into(), ptr.into()], false),
+    );
+    (publish, subscribe)
+}
+
+/// Declares the host imports of `<method>.remote` proxies:
+/// `replica_shard_nodes() -> i32` and
+/// `replica_shard_send(node: i32, tag: i32, payload: ptr, len: i32) -> i32`
+pub fn declare_shard_imports<'ctx>(
+    context: &'ctx Context,
+    module: &Module<'ctx>,
+) -> (FunctionValue<'ctx>, FunctionValue<'ctx>) {
+    let i32_type = context.i32_type();
+    let declare = |name: &str, function_type| {
+        module
+            .get_function(name)
+            .unwrap_or_else(|| module.add_function(name, function_type, Some(Linkage::External)))
+    };
+    let nodes = declare(SHARD_NODES_IMPORT, i32_type.fn_type(&[], false));
+    let send = declare(
+        SHARD_SEND_IMPORT,
+        i32_type.fn_type(
+            &[
+                i32_type.into(),
+                i32_type.into(),
+                context.ptr_type(AddressSpace::default()).into(),
+                i32_type.into(),
+            ],
+            false,
+        ),
+    );
+    (nodes, send)
+}
+
+/// Returns the declaration of a host import of `<method>.remote` proxies with
+/// a reliability policy, adding it on first use:
+/// `replica_remote_send(node: i32, tag: i32, key: i64, payload: ptr, len: i32)
+/// -> i32`, `replica_idempotency_key() -> i64` or `replica_retry_wait(delay_ms:
+/// i32)`
+pub fn declare_remote_import<'ctx>(
+    context: &'ctx Context,
+    module: &Module<'ctx>,
+    name: &str,
+) -> FunctionValue<'ctx> {
+    let i32_type = context.i32_type();
+    let i64_type = context.i64_type();
+    module.get_function(name).unwrap_or_else(|| {
+        let function_type = match name {
+            REMOTE_SEND_IMPORT => i32_type.fn_type(
+                &[
+                    i32_type.into(),
+                    i32_type.into(),
+                    i64_type.into(),
+                    context.ptr_type(AddressSpace::default()).into(),
+                    i32_type.into(),
+                ],
+                false,
+            ),
+            IDEMPOTENCY_KEY_IMPORT => i64_type.fn_type(&[], false),
+            RETRY_WAIT_IMPORT => context.void_type().fn_type(&[i32_type.into()], false),
+            other => unreachable!("{} is not an import of remote proxies", other),
+        };
+        module.add_function(name, function_type, Some(Linkage::External))
+    })
+}
+
+/// Field kind the runtime's protobuf codec expects in a field descriptor
+pub fn proto_kind(field: &WireField) -> u32 {
+    let kind = match field.kind {
+        WireKind::Int32 => proto::KIND_INT32,
+        WireKind::Sint32 => proto::KIND_SINT32,
+        WireKind::Uint32 => proto::KIND_UINT32,
+        WireKind::Fixed32 => proto::KIND_FIXED32,
+        WireKind::Sfixed32 => proto::KIND_SFIXED32,
+        WireKind::Bool => proto::KIND_BOOL,
+        WireKind::Float => proto::KIND_FLOAT,
+        WireKind::Double => proto::KIND_DOUBLE,
+        WireKind::String => proto::KIND_STRING,
+        WireKind::Bytes => proto::KIND_BYTES,
+        WireKind::Message(_) => proto::KIND_MESSAGE,
+    };
+    if field.repeated {
+        kind | proto::REPEATED
+    } else {
+        kind
+    }
+}
+
+/// Records the runtime ABI version the module is compiled against.
+/// Linking modules built for different versions fails with an LLVM error.
+pub fn record_abi_version<'ctx>(context: &'ctx Context, module: &Module<'ctx>) {
+    if module.get_flag(ABI_VERSION_FLAG).is_none() {
+        module.add_basic_value_flag(
+            ABI_VERSION_FLAG,
+            FlagBehavior::Error,
+            context
+                .i32_type()
+                .const_int(RUNTIME_ABI_VERSION as u64, false),
+        );
+    }
+}
+
+/// Records the allocator the module is compiled with. Linking modules built
+/// with different allocators fails, as blocks would be freed by the wrong one.
+pub fn record_allocator<'ctx>(context: &'ctx Context, module: &Module<'ctx>, allocator: Allocator) {
+    if module.get_flag(ALLOCATOR_FLAG).is_none() {
+        module.add_basic_value_flag(
+            ALLOCATOR_FLAG,
+            FlagBehavior::Error,
+            context.i32_type().const_int(allocator.kind() as u64, false),
+        );
+    }
+}
+
+/// Defines the exported `__replica_abi_handshake`, which combines the module's
+/// allocator and the ABI version it is compiled against with the one the linked
+/// runtime reports. Linked objects define the same body, so the copies are merged.
+pub fn define_abi_handshake<'ctx>(
+    context: &'ctx Context,
+    module: &Module<'ctx>,
+    allocator: Allocator,
+) -> Result<FunctionValue<'ctx>, BuilderError> {
+    if let Some(function) = module.get_function(ABI_HANDSHAKE_EXPORT) {
+        return Ok(function);
+    }
+
+    let i32_type = context.i32_type();
+    let function = module.add_function(
+        ABI_HANDSHAKE_EXPORT,
+        i32_type.fn_type(&[], false),
+        Some(Linkage::LinkOnceODR),
+    );
+    // --export-dynamic なしでリンクしてもエクスポートされる
+    function.add_attribute(
+        AttributeLoc::Function,
+        context.create_string_attribute("wasm-export-name", ABI_HANDSHAKE_EXPORT),
+    );
+
+    let builder = context.create_builder();
+    builder.position_at_end(context.append_basic_block(function, "entry"));
+    let runtime_version = builder
+        .build_call(
+            RuntimeFunction::AbiVersion.declare(context, module),
+            &[],
+            "runtime",
+        )?
+        .try_as_basic_value()
+        .left()
+        .expect("__replica_runtime_abi_version returns i32")
+        .into_int_value();
+    let runtime_version = builder.build_and(
+        runtime_version,
+        i32_type.const_int(0xffff, false),
+        "runtime",
+    )?;
+    let module_version = i32_type.const_int(
+        (allocator.kind() as u64) << 24 | (RUNTIME_ABI_VERSION as u64) << 16,
+        false,
+    );
+    let handshake = builder.build_or(module_version, runtime_version, "handshake")?;
+    builder.build_return(Some(&handshake))?;
+    Ok(function)
+}
+
+/// Defines the exported `__replica_init`, which runs the module constructors
+/// the first time it is called. wasm-ld only calls them from `_start`, which a
+/// module linked with `--no-entry` does not have, and a start section would run
+/// them before the host can read memory for the imports they call. Linked
+/// objects define the same body, so the copies are merged.
+pub fn define_module_init<'ctx>(
+    context: &'ctx Context,
+    module: &Module<'ctx>,
+) -> Result<FunctionValue<'ctx>, BuilderError> {
+    if let Some(function) = module.get_function(INIT_EXPORT) {
+        return Ok(function);
+    }
+
+    let void = context.void_type();
+    let i32_type = context.i32_type();
+    let function = module.add_function(
+        INIT_EXPORT,
+        void.fn_type(&[], false),
+        Some(Linkage::LinkOnceODR),
+    );
+    function.add_attribute(
+        AttributeLoc::Function,
+        context.create_string_attribute("wasm-export-name", INIT_EXPORT),
+    );
+    let call_ctors = module.get_function(CALL_CTORS).unwrap_or_else(|| {
+        module.add_function(
+            CALL_CTORS,
+            void.fn_type(&[], false),
+            Some(Linkage::External),
+        )
+    });
+    let done = module.add_global(i32_type, None, "replica.initialized");
+    done.set_linkage(Linkage::LinkOnceODR);
+    done.set_initializer(&i32_type.const_zero());
+
+    let builder = context.create_builder();
+    let entry = context.append_basic_block(function, "entry");
+    let run = context.append_basic_block(function, "run");
+    let exit = context.append_basic_block(function, "exit");
+    builder.position_at_end(entry);
+    // 二度目以降の呼び出しは何もしない
+    let initialized = builder
+        .build_load(i32_type, done.as_pointer_value(), "initialized")?
+        .into_int_value();
+    let initialized = builder.build_int_compare(
+        IntPredicate::NE,
+        initialized,
+        i32_type.const_zero(),
+        "is_initialized",
+    )?;
+    builder.build_conditional_branch(initialized, exit, run)?;
+
+    builder.position_at_end(run);
+    builder.build_store(done.as_pointer_value(), i32_type.const_int(1, false))?;
+    builder.build_call(call_ctors, &[], "")?;
+    builder.build_unconditional_branch(exit)?;
+
+    builder.position_at_end(exit);
+    builder.build_return(None)?;
+    Ok(function)
+}
+
+/// Records whether the module expects shared memory. Threaded modules lay out
+/// actor state differently, so linking them with unthreaded ones fails.
+pub fn record_shared_memory<'ctx>(context: &'ctx Context, module: &Module<'ctx>, shared: bool) {
+    if module.get_flag(SHARED_MEMORY_FLAG).is_none() {
+        module.add_basic_value_flag(
+            SHARED_MEMORY_FLAG,
+            FlagBehavior::Error,
+            context.i32_type().const_int(shared as u64, false),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_declare_is_idempotent() {
+        let context = Context::create();
+        let module = context.create_module("test");
+
+        let first = RuntimeFunction::Alloc.declare(&context, &module);
+        let second = RuntimeFunction::Alloc.declare(&context, &module);
+        assert_eq!(first, second);
+        assert_eq!(first.count_basic_blocks(), 0);
+    }
+
+    #[test]
+    fn test_all_runtime_functions_declare() {
+        let context = Context::create();
+        let module = context.create_module("test");
+
+        for function in RuntimeFunction::ALL {
+            function.declare(&context, &module);
+        }
+        assert!(module.verify().is_ok());
+    }
+
+    #[test]
+    fn test_random_source() {
+        let context = Context::create();
+        let module = context.create_module("test");
+
+        let generator = declare_random_source(&context, &module, RandomSource::Module);
+        assert_eq!(generator.get_name().to_str(), Ok("__replica_random_next"));
+        let host = declare_random_source(&context, &module, RandomSource::Host);
+        assert_eq!(host.get_name().to_str(), Ok(HOST_RANDOM_IMPORT));
+        assert_eq!(host.get_type(), generator.get_type());
+        assert_eq!(host.count_basic_blocks(), 0);
+    }
+
+    #[test]
+    fn test_abi_version_flag() {
+        let context = Context::create();
+        let module = context.create_module("test");
+        record_abi_version(&context, &module);
+        record_abi_version(&context, &module);
+
+        let ir = module.print_to_string().to_string();
+        assert!(ir.contains(&format!(
+            "!\"{}\", i32 {}",
+            ABI_VERSION_FLAG, RUNTIME_ABI_VERSION
+        )));
+        assert!(module.get_flag(ABI_VERSION_FLAG).is_some());
+    }
+
+    #[test]
+    fn test_abi_handshake() {
+        let context = Context::create();
+        let module = context.create_module("test");
+        let first = define_abi_handshake(&context, &module, Allocator::Bump).unwrap();
+        let second = define_abi_handshake(&context, &module, Allocator::Bump).unwrap();
+        assert_eq!(first, second);
+        assert!(module.verify().is_ok());
+
+        let ir = module.print_to_string().to_string();
+        assert!(ir.contains("define linkonce_odr i32 @__replica_abi_handshake()"));
+        assert!(ir.contains("call i32 @__replica_runtime_abi_version()"));
+        assert!(ir.contains(&format!("or i32 {}", RUNTIME_ABI_VERSION << 16)));
+        assert!(ir.contains("\"wasm-export-name\"=\"__replica_abi_handshake\""));
+
+        // アロケータは最上位バイトに入る
+        let module = context.create_module("external");
+        define_abi_handshake(&context, &module, Allocator::External).unwrap();
+        let ir = module.print_to_string().to_string();
+        assert!(ir.contains(&format!("or i32 {}", 2 << 24 | RUNTIME_ABI_VERSION << 16)));
+    }
+
+    #[test]
+    fn test_allocator_flag() {
+        let context = Context::create();
+        let module = context.create_module("test");
+        record_allocator(&context, &module, Allocator::FreeList);
+        record_allocator(&context, &module, Allocator::FreeList);
+
+        let ir = module.print_to_string().to_string();
+        assert!(ir.contains(&format!("!\"{}\", i32 1", ALLOCATOR_FLAG)));
+    }
+
+    #[test]
+    fn test_module_init() {
+        let context = Context::create();
+        let module = context.create_module("test");
+        let first = define_module_init(&context, &module).unwrap();
+        let second = define_module_init(&context, &module).unwrap();
+        assert_eq!(first, second);
+        assert!(module.verify().is_ok());
+
+        let ir = module.print_to_string().to_string();
+        assert!(ir.contains("define linkonce_odr void @__replica_init()"));
+        assert!(ir.contains("@replica.initialized = linkonce_odr global i32 0"));
+        assert!(ir.contains("call void @__wasm_call_ctors()"));
+        assert!(ir.contains("\"wasm-export-name\"=\"__replica_init\""));
+    }
+}
@@ -1,18 +1,71 @@
 use super::error::{CodeGenError, CodeGenResult};
-use crate::ast::{OwnershipType, Type};
+use crate::ast::{Field, Layout, OwnershipType, Type};
+use crate::layout;
 use inkwell::{
     context::Context,
+    targets::TargetData,
     types::{AnyTypeEnum, BasicMetadataTypeEnum, BasicType, BasicTypeEnum, StructType},
     values::{BasicValue, BasicValueEnum},
     AddressSpace,
 };
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+
+/// Data layout of the default `wasm32` target, used until the generator sets
+/// the one of the selected triple
+pub const WASM32_DATA_LAYOUT: &str = "e-m:e-p:32:32-p10:8:8-p20:8:8-i64:64-n32:64-S128-ni:1:10:20";
 
 /// Handles type conversions between Replica's type system and LLVM types
 pub struct TypeConverter<'ctx> {
     context: &'ctx Context,
     struct_types: HashMap<String, StructType<'ctx>>,
     cached_types: HashMap<String, BasicTypeEnum<'ctx>>,
+    field_indices: HashMap<String, Vec<u32>>,
+    extern_types: HashSet<String>,
+    /// Actors of imported modules, defined by other files
+    actor_references: HashSet<String>,
+    /// `struct` declarations, whose values are stored inline and copied
+    plain_structs: HashSet<String>,
+    reference_types: bool,
+    /// Sizes and alignments of the target, which allocations and message
+    /// payloads are sized with
+    target_data: TargetData,
+}
+
+/// LLVM body of an actor's state struct
+pub struct StructBody<'ctx> {
+    pub types: Vec<BasicTypeEnum<'ctx>>,
+    pub packed: bool,
+    /// Struct element index of each field, which differs from the declaration
+    /// order when padding is inserted
+    pub field_indices: Vec<u32>,
+    /// Byte size of a packed body; LLVM computes the size of natural ones
+    pub packed_size: Option<u32>,
+}
+
+/// Where the target places one element of an LLVM struct body
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ElementLayout {
+    pub offset: u32,
+    pub size: u32,
+    pub align: u32,
+}
+
+/// Size and alignment the target gives values of a type
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TypeLayout {
+    /// Bytes a value occupies in memory, including tail padding
+    pub size: u32,
+    pub align: u32,
+}
+
+/// Layout the target gives an LLVM struct body
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TargetLayout {
+    /// Placement of each element, in struct element order
+    pub elements: Vec<ElementLayout>,
+    /// Total size including tail padding
+    pub size: u32,
+    pub align: u32,
 }
 
 impl<'ctx> TypeConverter<'ctx> {
@@ -22,14 +75,251 @@ impl<'ctx> TypeConverter<'ctx> {
             context,
             struct_types: HashMap::new(),
             cached_types: HashMap::new(),
+            field_indices: HashMap::new(),
+            extern_types: HashSet::new(),
+            actor_references: HashSet::new(),
+            plain_structs: HashSet::new(),
+            reference_types: false,
+            target_data: TargetData::create(WASM32_DATA_LAYOUT),
         }
     }
 
+    /// Sizes types with `data_layout`, the layout string of the target
+    pub fn set_data_layout(&mut self, data_layout: &str) {
+        self.target_data = TargetData::create(data_layout);
+    }
+
     /// Registers a custom struct type
     pub fn register_struct_type(&mut self, name: &str, struct_type: StructType<'ctx>) {
         self.struct_types.insert(name.to_string(), struct_type);
     }
 
+    /// Registers the LLVM type of a `struct` declaration. Unlike actor state
+    /// structs, its values are passed and copied by value.
+    pub fn register_plain_struct(&mut self, name: &str, struct_type: StructType<'ctx>) {
+        self.register_struct_type(name, struct_type);
+        self.plain_structs.insert(name.to_string());
+    }
+
+    /// Whether `name` is a registered `struct` declaration
+    pub fn is_plain_struct(&self, name: &str) -> bool {
+        self.plain_structs.contains(name)
+    }
+
+    /// Registers an `extern type`, whose values are opaque host handles
+    pub fn register_extern_type(&mut self, name: &str) {
+        self.extern_types.insert(name.to_string());
+    }
+
+    /// Registers the actor of an imported module. Its state struct belongs to
+    /// another file, so values of the type are pointers to an instance.
+    pub fn register_actor_reference(&mut self, name: &str) {
+        self.actor_references.insert(name.to_string());
+    }
+
+    /// Selects the representation of extern handles: `externref` values when the
+    /// target has the reference-types feature, `i32` indices into a host-side table
+    /// otherwise
+    pub fn set_reference_types(&mut self, enabled: bool) {
+        self.reference_types = enabled;
+    }
+
+    /// Whether extern handles and function values are `externref`/`funcref`
+    pub fn uses_reference_types(&self) -> bool {
+        self.reference_types
+    }
+
+    /// Whether `ty` is or contains a value that becomes a WASM reference
+    /// (`externref` or `funcref`) when reference types are enabled
+    pub fn contains_reference_type(&self, ty: &Type) -> bool {
+        match ty {
+            Type::Custom(name) => self.extern_types.contains(name),
+            Type::Function { .. } => true,
+            Type::Array(inner) | Type::FixedArray(inner, _) | Type::Optional(inner) => {
+                self.contains_reference_type(inner)
+            }
+            Type::Tuple(elements) => elements.iter().any(|e| self.contains_reference_type(e)),
+            Type::Record(fields) => fields.iter().any(|(_, f)| self.contains_reference_type(f)),
+            _ => false,
+        }
+    }
+
+    fn handle_type(&self) -> BasicTypeEnum<'ctx> {
+        if self.reference_types {
+            // externref はアドレス空間 10 の不透明ポインタ
+            self.context
+                .ptr_type(AddressSpace::from(10u16))
+                .as_basic_type_enum()
+        } else {
+            self.context.i32_type().as_basic_type_enum()
+        }
+    }
+
+    /// Type of function values: a `funcref` with reference types, otherwise a
+    /// function pointer, i.e. an index into the indirect function table
+    pub fn function_reference_type(&self) -> BasicTypeEnum<'ctx> {
+        let address_space = if self.reference_types { 20u16 } else { 0 };
+        self.context
+            .ptr_type(AddressSpace::from(address_space))
+            .as_basic_type_enum()
+    }
+
+    /// Registers where the fields of a struct type live in its LLVM body
+    pub fn register_field_indices(&mut self, name: &str, field_indices: Vec<u32>) {
+        self.field_indices.insert(name.to_string(), field_indices);
+    }
+
+    /// Returns the struct element index of the `field`-th field of `name`
+    pub fn field_index(&self, name: &str, field: usize) -> u32 {
+        self.field_indices
+            .get(name)
+            .and_then(|indices| indices.get(field).copied())
+            .unwrap_or(field as u32)
+    }
+
+    /// Converts the fields of an actor to the body of its state struct. With layout
+    /// attributes the body is packed and padded with byte arrays so each field sits
+    /// at the offset computed by [`layout::compute`].
+    pub fn convert_actor_fields(
+        &self,
+        fields: &[Field],
+        actor_layout: &Layout,
+    ) -> CodeGenResult<StructBody<'ctx>> {
+        if self.reference_types {
+            if let Some(field) = fields
+                .iter()
+                .find(|field| self.contains_reference_type(&field.field_type))
+            {
+                return Err(CodeGenError::TypeConversion(format!(
+                    "Field {} holds an externref or funcref, which cannot be stored in linear memory",
+                    field.name
+                )));
+            }
+        }
+
+        let field_types = fields
+            .iter()
+            .map(|field| self.convert_with_ownership(&field.field_type, &field.ownership))
+            .collect::<CodeGenResult<Vec<_>>>()?;
+        if !layout::is_explicit(fields, actor_layout) {
+            return Ok(StructBody {
+                field_indices: (0..field_types.len() as u32).collect(),
+                types: field_types,
+                packed: false,
+                packed_size: None,
+            });
+        }
+
+        let resolved =
+            layout::compute(fields, actor_layout).map_err(CodeGenError::TypeConversion)?;
+        let padding = |bytes: u32| {
+            self.context
+                .i8_type()
+                .array_type(bytes)
+                .as_basic_type_enum()
+        };
+
+        let mut types = Vec::new();
+        let mut field_indices = Vec::with_capacity(fields.len());
+        let mut end = 0;
+        for ((field_type, offset), size) in field_types
+            .into_iter()
+            .zip(&resolved.offsets)
+            .zip(&resolved.sizes)
+        {
+            if *offset > end {
+                types.push(padding(offset - end));
+            }
+            field_indices.push(types.len() as u32);
+            types.push(field_type);
+            end = offset + size;
+        }
+        if resolved.size > end {
+            types.push(padding(resolved.size - end));
+        }
+
+        Ok(StructBody {
+            types,
+            packed: true,
+            field_indices,
+            packed_size: Some(resolved.size),
+        })
+    }
+
+    /// Appends a 4-byte aligned lock word after the fields of `body` and
+    /// returns its struct element index
+    pub fn append_lock_word(&self, body: &mut StructBody<'ctx>) -> u32 {
+        if let Some(size) = body.packed_size {
+            // アトミック命令は自然なアラインメントを要求する
+            let padding = size.next_multiple_of(4) - size;
+            if padding > 0 {
+                body.types
+                    .push(self.context.i8_type().array_type(padding).into());
+            }
+            body.packed_size = Some(size + padding + 4);
+        }
+        body.types.push(self.context.i32_type().into());
+        body.types.len() as u32 - 1
+    }
+
+    /// Appends the reentrancy guard of an actor with `@nonreentrant` methods after
+    /// the fields of `body`: a busy word followed by the pointer to the mailbox
+    /// of deferred messages. Returns the struct element index of the busy word.
+    pub fn append_reentrancy_guard(&self, body: &mut StructBody<'ctx>) -> u32 {
+        let busy_index = self.append_lock_word(body);
+        body.types
+            .push(self.context.ptr_type(AddressSpace::default()).into());
+        if let Some(size) = body.packed_size.as_mut() {
+            *size += layout::POINTER_SIZE;
+        }
+        busy_index
+    }
+
+    /// Appends the pointer to the mailbox topic values are delivered to after
+    /// the fields of `body` and returns its struct element index
+    pub fn append_inbox(&self, body: &mut StructBody<'ctx>) -> u32 {
+        if let Some(size) = body.packed_size {
+            let padding = size.next_multiple_of(layout::POINTER_SIZE) - size;
+            if padding > 0 {
+                body.types
+                    .push(self.context.i8_type().array_type(padding).into());
+            }
+            body.packed_size = Some(size + padding + layout::POINTER_SIZE);
+        }
+        body.types
+            .push(self.context.ptr_type(AddressSpace::default()).into());
+        body.types.len() as u32 - 1
+    }
+
+    /// Lays out the body of `struct_type` with the target's data layout, which
+    /// is where the generated code reads and writes its elements. Elements of
+    /// a packed body are byte-aligned.
+    pub fn target_layout(&self, struct_type: StructType<'ctx>) -> TargetLayout {
+        let target_data = &self.target_data;
+        let packed = struct_type.is_packed();
+        let elements = struct_type
+            .get_field_types()
+            .iter()
+            .enumerate()
+            .map(|(index, element)| ElementLayout {
+                offset: target_data
+                    .offset_of_element(&struct_type, index as u32)
+                    .unwrap_or(0) as u32,
+                size: target_data.get_abi_size(element) as u32,
+                align: if packed {
+                    1
+                } else {
+                    target_data.get_abi_alignment(element)
+                },
+            })
+            .collect();
+        TargetLayout {
+            elements,
+            size: target_data.get_abi_size(&struct_type) as u32,
+            align: target_data.get_abi_alignment(&struct_type),
+        }
+    }
+
     /// Converts a Replica type to an LLVM basic type
     pub fn convert_to_llvm(&self, ty: &Type) -> CodeGenResult<BasicTypeEnum<'ctx>> {
         match ty {
@@ -43,17 +333,71 @@ impl<'ctx> TypeConverter<'ctx> {
                     .as_basic_type_enum())
             }
             Type::Bool => Ok(self.context.bool_type().as_basic_type_enum()),
+            Type::Custom(name) if self.extern_types.contains(name) => Ok(self.handle_type()),
+            Type::Custom(name) if self.actor_references.contains(name) => Ok(self
+                .context
+                .ptr_type(AddressSpace::default())
+                .as_basic_type_enum()),
             Type::Custom(name) => self.get_custom_type(name),
+            // ストリームはホストが next/close に渡すフレームへのポインタ
+            Type::Generic { .. } if ty.stream_item().is_some() => Ok(self
+                .context
+                .ptr_type(AddressSpace::default())
+                .as_basic_type_enum()),
+            Type::Generic { name, .. } => Err(CodeGenError::TypeConversion(format!(
+                "Generic type {} is not supported",
+                name
+            ))),
             Type::Array(element_type) => {
                 // 配列は要素型へのポインタとして実装
                 let elem_type = self.convert_to_llvm(element_type)?;
                 let pointer_type = self.context.ptr_type(AddressSpace::default());
                 Ok(pointer_type.as_basic_type_enum())
             }
+            Type::FixedArray(element_type, length) => {
+                // 固定長配列はヒープを使わず構造体の中に直接配置する
+                let elem_type = self.convert_to_llvm(element_type)?;
+                Ok(elem_type.array_type(*length as u32).as_basic_type_enum())
+            }
             Type::Optional(inner_type) => {
                 // Optional型は内部型とbooleanフラグの構造体として実装
                 self.create_optional_type(inner_type)
             }
+            // タプルとレコードは要素を順に並べた無名構造体として実装
+            Type::Tuple(elements) => self.convert_struct(elements.iter()),
+            Type::Record(fields) => self.convert_struct(fields.iter().map(|(_, field)| field)),
+            Type::Function { .. } => Ok(self.function_reference_type()),
+        }
+    }
+
+    /// Lays out `element_types` in order as a by-value anonymous struct
+    fn convert_struct<'a>(
+        &self,
+        element_types: impl Iterator<Item = &'a Type>,
+    ) -> CodeGenResult<BasicTypeEnum<'ctx>> {
+        let fields = element_types
+            .map(|element| self.convert_to_llvm(element))
+            .collect::<CodeGenResult<Vec<_>>>()?;
+        Ok(self
+            .context
+            .struct_type(&fields, false)
+            .as_basic_type_enum())
+    }
+
+    /// Converts the type of a field or parameter, taking its ownership into account.
+    /// Weak and unowned references are stored as plain pointers to the target, and
+    /// `inout` parameters are passed as a pointer to the caller's storage.
+    pub fn convert_with_ownership(
+        &self,
+        ty: &Type,
+        ownership: &OwnershipType,
+    ) -> CodeGenResult<BasicTypeEnum<'ctx>> {
+        match ownership {
+            OwnershipType::Weak | OwnershipType::Unowned | OwnershipType::Inout => Ok(self
+                .context
+                .ptr_type(AddressSpace::default())
+                .as_basic_type_enum()),
+            _ => self.convert_to_llvm(ty),
         }
     }
 
@@ -76,7 +420,16 @@ impl<'ctx> TypeConverter<'ctx> {
                     .const_null()
                     .as_basic_value_enum())
             }
+            Type::Custom(name) if self.extern_types.contains(name) => {
+                Ok(self.handle_type().const_zero())
+            }
+            Type::Custom(name) if self.actor_references.contains(name) => Ok(self
+                .context
+                .ptr_type(AddressSpace::default())
+                .const_null()
+                .as_basic_value_enum()),
             Type::Custom(name) => self.create_default_custom_value(name),
+            Type::Generic { .. } => self.convert_to_llvm(ty).map(|ty| ty.const_zero()),
             Type::Array(_) => {
                 // null ポインタを返す
                 Ok(self
@@ -89,31 +442,66 @@ impl<'ctx> TypeConverter<'ctx> {
                 // None値を表す0を返す
                 Ok(self.context.i32_type().const_zero().as_basic_value_enum())
             }
+            Type::FixedArray(..) | Type::Tuple(_) | Type::Record(_) | Type::Function { .. } => {
+                Ok(self.convert_to_llvm(ty)?.const_zero())
+            }
         }
     }
 
-    /// Gets the size of a type in bytes
-    pub fn get_type_size(&self, ty: &Type) -> CodeGenResult<u32> {
+    /// Size and alignment the target gives values of a type
+    pub fn type_layout(&self, ty: &Type) -> CodeGenResult<TypeLayout> {
         let llvm_type = self.convert_to_llvm(ty)?;
-        Ok(llvm_type
-            .size_of()
-            .unwrap_or_else(|| self.context.i32_type().size_of())
-            .const_bit_cast(self.context.i32_type())
-            .get_zero_extended_constant()
-            .unwrap_or(4) as u32)
+        Ok(TypeLayout {
+            size: self.abi_size(llvm_type),
+            align: self.target_data.get_abi_alignment(&llvm_type),
+        })
+    }
+
+    /// Gets the size of a type in bytes, including the padding that keeps
+    /// consecutive values aligned
+    pub fn get_type_size(&self, ty: &Type) -> CodeGenResult<u32> {
+        Ok(self.type_layout(ty)?.size)
+    }
+
+    /// Gets the alignment of a type in bytes
+    pub fn get_type_alignment(&self, ty: &Type) -> CodeGenResult<u32> {
+        Ok(self.type_layout(ty)?.align)
+    }
+
+    /// Bytes the target allocates for a value of an LLVM type, which is what
+    /// `__replica_alloc` is asked for
+    pub fn abi_size(&self, llvm_type: impl BasicType<'ctx>) -> u32 {
+        self.target_data
+            .get_abi_size(&llvm_type.as_basic_type_enum())
+            .try_into()
+            .unwrap_or(u32::MAX)
     }
 
     /// Checks if a type is copyable
     pub fn is_copyable(&self, ty: &Type) -> bool {
         match ty {
             Type::Int | Type::Float | Type::Bool => true,
-            Type::String => false,    // 文字列は所有権を持つ
-            Type::Custom(_) => false, // カスタム型はデフォルトでコピー不可
-            Type::Array(_) => false,  // 配列は所有権を持つ
+            Type::String => true, // コピーはバッファを共有する (copy-on-write)
+            // ハンドルと平坦な構造体のみコピー可能
+            Type::Custom(name) => {
+                self.extern_types.contains(name) || self.plain_structs.contains(name)
+            }
+            Type::Generic { .. } => false,
+            Type::Array(_) => true, // コピーはバッファを共有する (copy-on-write)
+            Type::FixedArray(element, _) => self.is_copyable(element),
             Type::Optional(inner) => self.is_copyable(inner),
+            Type::Tuple(elements) => elements.iter().all(|element| self.is_copyable(element)),
+            Type::Record(fields) => fields.iter().all(|(_, field)| self.is_copyable(field)),
+            Type::Function { .. } => true,
         }
     }
 
+    /// Checks if values of a type are reference-counted buffers that are shared on
+    /// copy and cloned by the runtime before the first mutation
+    pub fn is_copy_on_write(&self, ty: &Type) -> bool {
+        matches!(ty, Type::String | Type::Array(_))
+    }
+
     // Private helper methods
     fn get_custom_type(&self, name: &str) -> CodeGenResult<BasicTypeEnum<'ctx>> {
         self.struct_types
@@ -139,6 +527,10 @@ impl<'ctx> TypeConverter<'ctx> {
         self.struct_types
             .get(name)
             .map(|st| {
+                // 構造体の値はゼロで埋める
+                if self.plain_structs.contains(name) {
+                    return st.const_zero().as_basic_value_enum();
+                }
                 st.ptr_type(AddressSpace::default())
                     .const_null()
                     .as_basic_value_enum()
@@ -195,6 +587,244 @@ mod tests {
         assert!(result.is_ok());
     }
 
+    #[test]
+    fn test_collections_are_copy_on_write() {
+        let context = create_test_context();
+        let converter = TypeConverter::new(&context);
+
+        let array_type = Type::Array(Box::new(Type::Int));
+        assert!(converter.is_copyable(&array_type));
+        assert!(converter.is_copy_on_write(&array_type));
+        assert!(converter.is_copy_on_write(&Type::String));
+        assert!(!converter.is_copy_on_write(&Type::Int));
+    }
+
+    #[test]
+    fn test_weak_reference_conversion() {
+        let context = create_test_context();
+        let converter = TypeConverter::new(&context);
+
+        // 未登録の型でも weak 参照はポインタになる
+        let weak_type = Type::Optional(Box::new(Type::Custom("Peer".to_string())));
+        let result = converter.convert_with_ownership(&weak_type, &OwnershipType::Weak);
+        assert!(matches!(result.unwrap(), BasicTypeEnum::PointerType(_)));
+    }
+
+    #[test]
+    fn test_fixed_array_is_inline() {
+        let context = create_test_context();
+        let converter = TypeConverter::new(&context);
+
+        let buffer_type = Type::FixedArray(Box::new(Type::Int), 4);
+        let result = converter.convert_to_llvm(&buffer_type).unwrap();
+        assert!(result.is_array_type());
+        assert_eq!(result.into_array_type().len(), 4);
+        assert!(converter.is_copyable(&buffer_type));
+        assert!(!converter.is_copy_on_write(&buffer_type));
+    }
+
+    #[test]
+    fn test_explicit_layout_inserts_padding() {
+        let context = create_test_context();
+        let converter = TypeConverter::new(&context);
+        let field = |name: &str, field_type, offset| Field {
+            name: name.to_string(),
+            field_type,
+            is_mutable: true,
+            ownership: OwnershipType::Owned,
+            offset,
+            doc: None,
+        };
+        let fields = vec![
+            field("tag", Type::Bool, None),
+            field("length", Type::Int, Some(4)),
+        ];
+
+        let natural = converter
+            .convert_actor_fields(&fields[..1], &Layout::default())
+            .unwrap();
+        assert!(!natural.packed);
+        assert_eq!(natural.field_indices, vec![0]);
+
+        let layout = Layout {
+            packed: false,
+            align: Some(8),
+        };
+        let body = converter.convert_actor_fields(&fields, &layout).unwrap();
+        assert!(body.packed);
+        // tag, [3 x i8], length
+        assert_eq!(body.field_indices, vec![0, 2]);
+        assert_eq!(body.types.len(), 3);
+        assert_eq!(body.types[1].into_array_type().len(), 3);
+
+        let misaligned = vec![field("length", Type::Int, Some(2))];
+        assert!(converter
+            .convert_actor_fields(&misaligned, &Layout::default())
+            .is_err());
+    }
+
+    #[test]
+    fn test_target_layout() {
+        let context = create_test_context();
+        let converter = TypeConverter::new(&context);
+        let element = |offset, size, align| ElementLayout {
+            offset,
+            size,
+            align,
+        };
+
+        // flag, value, count
+        let natural = context.struct_type(
+            &[
+                context.bool_type().into(),
+                context.f64_type().into(),
+                context.i32_type().into(),
+            ],
+            false,
+        );
+        let layout = converter.target_layout(natural);
+        assert_eq!(
+            layout.elements,
+            vec![element(0, 1, 1), element(8, 8, 8), element(16, 4, 4)]
+        );
+        assert_eq!((layout.size, layout.align), (24, 8));
+
+        // tag, [3 x i8], length
+        let packed = context.struct_type(
+            &[
+                context.bool_type().into(),
+                context.i8_type().array_type(3).into(),
+                context.i32_type().into(),
+            ],
+            true,
+        );
+        let layout = converter.target_layout(packed);
+        assert_eq!(
+            layout.elements,
+            vec![element(0, 1, 1), element(1, 3, 1), element(4, 4, 1)]
+        );
+        assert_eq!((layout.size, layout.align), (8, 1));
+    }
+
+    #[test]
+    fn test_type_layout() {
+        let context = create_test_context();
+        let mut converter = TypeConverter::new(&context);
+        let layout = |converter: &TypeConverter, ty: Type| {
+            let layout = converter.type_layout(&ty).unwrap();
+            (layout.size, layout.align)
+        };
+
+        assert_eq!(layout(&converter, Type::Int), (4, 4));
+        assert_eq!(layout(&converter, Type::Float), (8, 8));
+        assert_eq!(layout(&converter, Type::Bool), (1, 1));
+        assert_eq!(layout(&converter, Type::String), (4, 4));
+        // 値と有無のフラグの後ろは Float の境界まで埋める
+        assert_eq!(
+            layout(&converter, Type::Optional(Box::new(Type::Float))),
+            (16, 8)
+        );
+        assert_eq!(converter.get_type_size(&Type::Bool).unwrap(), 1);
+        assert_eq!(converter.get_type_alignment(&Type::Float).unwrap(), 8);
+
+        // wasm64 ではポインタが 8 バイトになる
+        converter.set_data_layout("e-m:e-p:64:64-p10:8:8-p20:8:8-i64:64-n32:64-S128-ni:1:10:20");
+        assert_eq!(layout(&converter, Type::String), (8, 8));
+        assert!(converter
+            .type_layout(&Type::Custom("Missing".to_string()))
+            .is_err());
+    }
+
+    #[test]
+    fn test_lock_word_is_aligned() {
+        let context = create_test_context();
+        let converter = TypeConverter::new(&context);
+        let fields = vec![Field {
+            name: "ready".to_string(),
+            field_type: Type::Bool,
+            is_mutable: true,
+            ownership: OwnershipType::Shared,
+            offset: None,
+            doc: None,
+        }];
+
+        let mut natural = converter
+            .convert_actor_fields(&fields, &Layout::default())
+            .unwrap();
+        assert_eq!(converter.append_lock_word(&mut natural), 1);
+
+        let layout = Layout {
+            packed: true,
+            align: None,
+        };
+        let mut packed = converter.convert_actor_fields(&fields, &layout).unwrap();
+        // ready, [3 x i8], lock
+        assert_eq!(converter.append_lock_word(&mut packed), 2);
+        assert_eq!(packed.types[1].into_array_type().len(), 3);
+        assert_eq!(packed.packed_size, Some(8));
+    }
+
+    #[test]
+    fn test_extern_handle_representation() {
+        let context = create_test_context();
+        let mut converter = TypeConverter::new(&context);
+        converter.register_extern_type("FileHandle");
+        let handle = Type::Custom("FileHandle".to_string());
+
+        let result = converter.convert_to_llvm(&handle).unwrap();
+        assert_eq!(result, context.i32_type().as_basic_type_enum());
+        assert!(converter.is_copyable(&handle));
+        assert!(converter
+            .convert_to_llvm(&Type::Function {
+                params: vec![],
+                return_type: None,
+            })
+            .unwrap()
+            .is_pointer_type());
+
+        converter.set_reference_types(true);
+        let result = converter.convert_to_llvm(&handle).unwrap();
+        assert_eq!(
+            result.into_pointer_type().get_address_space(),
+            AddressSpace::from(10u16)
+        );
+
+        let callback = Type::Function {
+            params: vec![Type::Int],
+            return_type: None,
+        };
+        let result = converter.convert_to_llvm(&callback).unwrap();
+        assert_eq!(
+            result.into_pointer_type().get_address_space(),
+            AddressSpace::from(20u16)
+        );
+
+        let field = Field {
+            name: "file".to_string(),
+            field_type: handle,
+            is_mutable: true,
+            ownership: OwnershipType::Owned,
+            offset: None,
+            doc: None,
+        };
+        assert!(converter
+            .convert_actor_fields(&[field], &Layout::default())
+            .is_err());
+    }
+
+    #[test]
+    fn test_record_type_conversion() {
+        let context = create_test_context();
+        let converter = TypeConverter::new(&context);
+
+        let record_type = Type::Record(vec![
+            ("count".to_string(), Type::Int),
+            ("ok".to_string(), Type::Bool),
+        ]);
+        let result = converter.convert_to_llvm(&record_type).unwrap();
+        assert_eq!(result.into_struct_type().count_fields(), 2);
+    }
+
     #[test]
     fn test_custom_type_handling() {
         let context = create_test_context();
@@ -208,4 +838,21 @@ mod tests {
         let result = converter.convert_to_llvm(&Type::Custom("MyStruct".to_string()));
         assert!(result.is_ok());
     }
+
+    #[test]
+    fn test_imported_actors_are_pointers() {
+        let context = create_test_context();
+        let mut converter = TypeConverter::new(&context);
+        let peer = Type::Custom("Peer".to_string());
+        assert!(converter.convert_to_llvm(&peer).is_err());
+
+        // 状態構造体は別のファイルにあり、値はインスタンスへのポインタ
+        converter.register_actor_reference("Peer");
+        assert!(converter.convert_to_llvm(&peer).unwrap().is_pointer_type());
+        assert!(converter
+            .create_default_value(&peer)
+            .unwrap()
+            .into_pointer_value()
+            .is_null());
+    }
 }
@@ -0,0 +1,1046 @@
+//! WebAssembly text format printer used by `--emit wat`.
+//!
+//! Decodes the binary module produced by the linker and prints it in the
+//! linear instruction syntax, one instruction per line, with function names
+//! from the `name` custom section. Covers the MVP instruction set and the
+//! sign-extension, saturating conversion, bulk memory, reference types and
+//! threads proposals. SIMD bodies are not decoded; the printer notes them and
+//! moves on to the next function.
+
+use super::error::{CodeGenError, CodeGenResult};
+use std::collections::HashMap;
+use std::fmt::Write;
+
+/// Section ids of the binary format
+const CUSTOM: u8 = 0;
+const TYPE: u8 = 1;
+const IMPORT: u8 = 2;
+const FUNCTION: u8 = 3;
+const TABLE: u8 = 4;
+const MEMORY: u8 = 5;
+const GLOBAL: u8 = 6;
+const EXPORT: u8 = 7;
+const START: u8 = 8;
+const ELEMENT: u8 = 9;
+const CODE: u8 = 10;
+const DATA: u8 = 11;
+const DATA_COUNT: u8 = 12;
+
+/// Instructions without immediates from `i32.eqz` (0x45) to `i64.extend32_s` (0xC4)
+const NUMERIC: [&str; 128] = [
+    "i32.eqz",
+    "i32.eq",
+    "i32.ne",
+    "i32.lt_s",
+    "i32.lt_u",
+    "i32.gt_s",
+    "i32.gt_u",
+    "i32.le_s",
+    "i32.le_u",
+    "i32.ge_s",
+    "i32.ge_u",
+    "i64.eqz",
+    "i64.eq",
+    "i64.ne",
+    "i64.lt_s",
+    "i64.lt_u",
+    "i64.gt_s",
+    "i64.gt_u",
+    "i64.le_s",
+    "i64.le_u",
+    "i64.ge_s",
+    "i64.ge_u",
+    "f32.eq",
+    "f32.ne",
+    "f32.lt",
+    "f32.gt",
+    "f32.le",
+    "f32.ge",
+    "f64.eq",
+    "f64.ne",
+    "f64.lt",
+    "f64.gt",
+    "f64.le",
+    "f64.ge",
+    "i32.clz",
+    "i32.ctz",
+    "i32.popcnt",
+    "i32.add",
+    "i32.sub",
+    "i32.mul",
+    "i32.div_s",
+    "i32.div_u",
+    "i32.rem_s",
+    "i32.rem_u",
+    "i32.and",
+    "i32.or",
+    "i32.xor",
+    "i32.shl",
+    "i32.shr_s",
+    "i32.shr_u",
+    "i32.rotl",
+    "i32.rotr",
+    "i64.clz",
+    "i64.ctz",
+    "i64.popcnt",
+    "i64.add",
+    "i64.sub",
+    "i64.mul",
+    "i64.div_s",
+    "i64.div_u",
+    "i64.rem_s",
+    "i64.rem_u",
+    "i64.and",
+    "i64.or",
+    "i64.xor",
+    "i64.shl",
+    "i64.shr_s",
+    "i64.shr_u",
+    "i64.rotl",
+    "i64.rotr",
+    "f32.abs",
+    "f32.neg",
+    "f32.ceil",
+    "f32.floor",
+    "f32.trunc",
+    "f32.nearest",
+    "f32.sqrt",
+    "f32.add",
+    "f32.sub",
+    "f32.mul",
+    "f32.div",
+    "f32.min",
+    "f32.max",
+    "f32.copysign",
+    "f64.abs",
+    "f64.neg",
+    "f64.ceil",
+    "f64.floor",
+    "f64.trunc",
+    "f64.nearest",
+    "f64.sqrt",
+    "f64.add",
+    "f64.sub",
+    "f64.mul",
+    "f64.div",
+    "f64.min",
+    "f64.max",
+    "f64.copysign",
+    "i32.wrap_i64",
+    "i32.trunc_f32_s",
+    "i32.trunc_f32_u",
+    "i32.trunc_f64_s",
+    "i32.trunc_f64_u",
+    "i64.extend_i32_s",
+    "i64.extend_i32_u",
+    "i64.trunc_f32_s",
+    "i64.trunc_f32_u",
+    "i64.trunc_f64_s",
+    "i64.trunc_f64_u",
+    "f32.convert_i32_s",
+    "f32.convert_i32_u",
+    "f32.convert_i64_s",
+    "f32.convert_i64_u",
+    "f32.demote_f64",
+    "f64.convert_i32_s",
+    "f64.convert_i32_u",
+    "f64.convert_i64_s",
+    "f64.convert_i64_u",
+    "f64.promote_f32",
+    "i32.reinterpret_f32",
+    "i64.reinterpret_f64",
+    "f32.reinterpret_i32",
+    "f64.reinterpret_i64",
+    "i32.extend8_s",
+    "i32.extend16_s",
+    "i64.extend8_s",
+    "i64.extend16_s",
+    "i64.extend32_s",
+];
+
+/// Loads and stores from `i32.load` (0x28) to `i64.store32` (0x3E), with the
+/// log2 of their natural alignment
+const MEMORY_ACCESS: [(&str, u32); 23] = [
+    ("i32.load", 2),
+    ("i64.load", 3),
+    ("f32.load", 2),
+    ("f64.load", 3),
+    ("i32.load8_s", 0),
+    ("i32.load8_u", 0),
+    ("i32.load16_s", 1),
+    ("i32.load16_u", 1),
+    ("i64.load8_s", 0),
+    ("i64.load8_u", 0),
+    ("i64.load16_s", 1),
+    ("i64.load16_u", 1),
+    ("i64.load32_s", 2),
+    ("i64.load32_u", 2),
+    ("i32.store", 2),
+    ("i64.store", 3),
+    ("f32.store", 2),
+    ("f64.store", 3),
+    ("i32.store8", 0),
+    ("i32.store16", 1),
+    ("i64.store8", 0),
+    ("i64.store16", 1),
+    ("i64.store32", 2),
+];
+
+/// Saturating conversions, `0xFC 0` to `0xFC 7`
+const TRUNC_SAT: [&str; 8] = [
+    "i32.trunc_sat_f32_s",
+    "i32.trunc_sat_f32_u",
+    "i32.trunc_sat_f64_s",
+    "i32.trunc_sat_f64_u",
+    "i64.trunc_sat_f32_s",
+    "i64.trunc_sat_f32_u",
+    "i64.trunc_sat_f64_s",
+    "i64.trunc_sat_f64_u",
+];
+
+/// Atomic loads and stores, `0xFE 0x10` to `0xFE 0x1D`
+const ATOMIC_ACCESS: [(&str, u32); 14] = [
+    ("i32.atomic.load", 2),
+    ("i64.atomic.load", 3),
+    ("i32.atomic.load8_u", 0),
+    ("i32.atomic.load16_u", 1),
+    ("i64.atomic.load8_u", 0),
+    ("i64.atomic.load16_u", 1),
+    ("i64.atomic.load32_u", 2),
+    ("i32.atomic.store", 2),
+    ("i64.atomic.store", 3),
+    ("i32.atomic.store8", 0),
+    ("i32.atomic.store16", 1),
+    ("i64.atomic.store8", 0),
+    ("i64.atomic.store16", 1),
+    ("i64.atomic.store32", 2),
+];
+
+/// Read-modify-write operations, seven widths each from `0xFE 0x1E` on
+const ATOMIC_RMW: [&str; 7] = ["add", "sub", "and", "or", "xor", "xchg", "cmpxchg"];
+
+/// Prints the binary module `module` in the WebAssembly text format
+pub fn print(module: &[u8]) -> CodeGenResult<String> {
+    let mut reader = Reader::new(module);
+    if reader.take(8)? != b"\0asm\x01\0\0\0" {
+        return Err(malformed("not a version 1 WebAssembly module"));
+    }
+    let mut sections = Vec::new();
+    while !reader.is_empty() {
+        let id = reader.byte()?;
+        let size = reader.u32()? as usize;
+        sections.push((id, reader.take(size)?));
+    }
+
+    let mut printer = Printer::default();
+    for (id, payload) in &sections {
+        if *id == CUSTOM {
+            let mut section = Reader::new(payload);
+            if section.name()? == "name" {
+                printer.read_function_names(&mut section)?;
+            }
+        }
+    }
+
+    printer.out.push_str("(module\n");
+    for (id, payload) in sections {
+        printer.section(id, &mut Reader::new(payload))?;
+    }
+    printer.out.push_str(")\n");
+    Ok(printer.out)
+}
+
+fn malformed(what: &str) -> CodeGenError {
+    CodeGenError::WasmGen(format!("Malformed WebAssembly module: {}", what))
+}
+
+/// Cursor over the bytes of a module or section
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Reader { bytes, pos: 0 }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pos >= self.bytes.len()
+    }
+
+    fn take(&mut self, len: usize) -> CodeGenResult<&'a [u8]> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|&end| end <= self.bytes.len())
+            .ok_or_else(|| malformed("unexpected end of data"))?;
+        let bytes = &self.bytes[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> CodeGenResult<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u64(&mut self) -> CodeGenResult<u64> {
+        let mut result = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            result |= u64::from(byte & 0x7F) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(result);
+            }
+        }
+        Err(malformed("integer too long"))
+    }
+
+    fn u32(&mut self) -> CodeGenResult<u32> {
+        u32::try_from(self.u64()?).map_err(|_| malformed("integer too large"))
+    }
+
+    fn signed(&mut self) -> CodeGenResult<i64> {
+        let mut result = 0i64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            result |= i64::from(byte & 0x7F) << shift;
+            if byte & 0x80 == 0 {
+                // 符号ビットを上位に広げる
+                if shift + 7 < 64 && byte & 0x40 != 0 {
+                    result |= -1 << (shift + 7);
+                }
+                return Ok(result);
+            }
+        }
+        Err(malformed("integer too long"))
+    }
+
+    fn name(&mut self) -> CodeGenResult<String> {
+        let len = self.u32()? as usize;
+        Ok(String::from_utf8_lossy(self.take(len)?).into_owned())
+    }
+}
+
+/// Accumulates the text of the module and the index spaces printed so far
+#[derive(Default)]
+struct Printer {
+    out: String,
+    /// Function names from the `name` custom section, by function index
+    function_names: HashMap<u32, String>,
+    /// Parameter and result types of each entry of the type section
+    types: Vec<(Vec<&'static str>, Vec<&'static str>)>,
+    /// Type index of every function, imported ones first
+    function_types: Vec<u32>,
+    imported_functions: u32,
+    tables: u32,
+    memories: u32,
+    globals: u32,
+}
+
+impl Printer {
+    fn section(&mut self, id: u8, section: &mut Reader) -> CodeGenResult<()> {
+        match id {
+            CUSTOM => {
+                let name = section.name()?;
+                let _ = writeln!(
+                    self.out,
+                    "  ;; custom section {}, {} bytes",
+                    quote(name.as_bytes()),
+                    section.bytes.len() - section.pos
+                );
+            }
+            TYPE => self.types(section)?,
+            IMPORT => self.imports(section)?,
+            FUNCTION => {
+                for _ in 0..section.u32()? {
+                    self.function_types.push(section.u32()?);
+                }
+            }
+            TABLE => {
+                for _ in 0..section.u32()? {
+                    let table = self.table_type(section)?;
+                    let _ = writeln!(self.out, "  (table (;{};) {})", self.tables, table);
+                    self.tables += 1;
+                }
+            }
+            MEMORY => {
+                for _ in 0..section.u32()? {
+                    let memory = limits(section)?;
+                    let _ = writeln!(self.out, "  (memory (;{};) {})", self.memories, memory);
+                    self.memories += 1;
+                }
+            }
+            GLOBAL => {
+                for _ in 0..section.u32()? {
+                    let global = global_type(section)?;
+                    let init = self.const_expr(section)?.join(" ");
+                    let _ = writeln!(
+                        self.out,
+                        "  (global (;{};) {} {})",
+                        self.globals, global, init
+                    );
+                    self.globals += 1;
+                }
+            }
+            EXPORT => {
+                for _ in 0..section.u32()? {
+                    let name = section.name()?;
+                    let kind = section.byte()?;
+                    let index = section.u32()?;
+                    let target = match kind {
+                        0 => format!("func {}", self.function(index)),
+                        1 => format!("table {}", index),
+                        2 => format!("memory {}", index),
+                        3 => format!("global {}", index),
+                        _ => return Err(malformed("unknown export kind")),
+                    };
+                    let _ = writeln!(
+                        self.out,
+                        "  (export {} ({}))",
+                        quote(name.as_bytes()),
+                        target
+                    );
+                }
+            }
+            START => {
+                let function = section.u32()?;
+                let _ = writeln!(self.out, "  (start {})", self.function(function));
+            }
+            ELEMENT => self.elements(section)?,
+            CODE => self.code(section)?,
+            DATA => self.data(section)?,
+            DATA_COUNT => {}
+            _ => return Err(malformed(&format!("unknown section id {}", id))),
+        }
+        Ok(())
+    }
+
+    /// Reads the function names subsection of the `name` section
+    fn read_function_names(&mut self, section: &mut Reader) -> CodeGenResult<()> {
+        while !section.is_empty() {
+            let id = section.byte()?;
+            let size = section.u32()? as usize;
+            let mut subsection = Reader::new(section.take(size)?);
+            if id == 1 {
+                for _ in 0..subsection.u32()? {
+                    let index = subsection.u32()?;
+                    let name = subsection.name()?;
+                    self.function_names.insert(index, name);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// `$name` of function `index`, or the index when it has no usable name
+    fn function(&self, index: u32) -> String {
+        match self.function_names.get(&index) {
+            Some(name) if !name.is_empty() && name.bytes().all(is_id_char) => {
+                format!("${}", name)
+            }
+            _ => index.to_string(),
+        }
+    }
+
+    /// `$name ` introducing the definition of function `index`, if it is named
+    fn label(&self, index: u32) -> String {
+        match self.function(index) {
+            name if name.starts_with('$') => format!("{} ", name),
+            _ => String::new(),
+        }
+    }
+
+    fn types(&mut self, section: &mut Reader) -> CodeGenResult<()> {
+        for index in 0..section.u32()? {
+            if section.byte()? != 0x60 {
+                return Err(malformed("type is not a function type"));
+            }
+            let params = value_types(section)?;
+            let results = value_types(section)?;
+            let _ = writeln!(
+                self.out,
+                "  (type (;{};) (func{}))",
+                index,
+                signature(&params, &results)
+            );
+            self.types.push((params, results));
+        }
+        Ok(())
+    }
+
+    fn imports(&mut self, section: &mut Reader) -> CodeGenResult<()> {
+        for _ in 0..section.u32()? {
+            let module = section.name()?;
+            let name = section.name()?;
+            let description = match section.byte()? {
+                0 => {
+                    let type_index = section.u32()?;
+                    let index = self.imported_functions;
+                    self.imported_functions += 1;
+                    self.function_types.push(type_index);
+                    format!(
+                        "func {}(;{};) (type {})",
+                        self.label(index),
+                        index,
+                        type_index
+                    )
+                }
+                1 => {
+                    self.tables += 1;
+                    format!(
+                        "table (;{};) {}",
+                        self.tables - 1,
+                        self.table_type(section)?
+                    )
+                }
+                2 => {
+                    self.memories += 1;
+                    format!("memory (;{};) {}", self.memories - 1, limits(section)?)
+                }
+                3 => {
+                    self.globals += 1;
+                    format!("global (;{};) {}", self.globals - 1, global_type(section)?)
+                }
+                _ => return Err(malformed("unknown import kind")),
+            };
+            let _ = writeln!(
+                self.out,
+                "  (import {} {} ({}))",
+                quote(module.as_bytes()),
+                quote(name.as_bytes()),
+                description
+            );
+        }
+        Ok(())
+    }
+
+    fn table_type(&self, section: &mut Reader) -> CodeGenResult<String> {
+        let element = value_type(section.byte()?)?;
+        Ok(format!("{} {}", limits(section)?, element))
+    }
+
+    fn elements(&mut self, section: &mut Reader) -> CodeGenResult<()> {
+        for index in 0..section.u32()? {
+            let flags = section.u32()?;
+            if flags > 7 {
+                return Err(malformed("unknown element segment kind"));
+            }
+            let mut header = String::new();
+            if flags & 0b011 == 0b010 {
+                let _ = write!(header, " (table {})", section.u32()?);
+            }
+            if flags & 0b001 == 0 {
+                header.push(' ');
+                header.push_str(&offset(self.const_expr(section)?));
+            } else if flags & 0b010 != 0 {
+                header.push_str(" declare");
+            }
+
+            let items = if flags & 0b100 == 0 {
+                // 0 と 4 以外は要素の種類 (funcref のみ) を持つ
+                if flags & 0b011 != 0 && section.byte()? != 0x00 {
+                    return Err(malformed("unknown element kind"));
+                }
+                let functions: Vec<String> = (0..section.u32()?)
+                    .map(|_| section.u32().map(|f| self.function(f)))
+                    .collect::<CodeGenResult<_>>()?;
+                format!("func {}", functions.join(" "))
+            } else {
+                let element = if flags & 0b011 != 0 {
+                    value_type(section.byte()?)?
+                } else {
+                    "funcref"
+                };
+                let mut items = element.to_string();
+                for _ in 0..section.u32()? {
+                    let _ = write!(items, " (item {})", self.const_expr(section)?.join(" "));
+                }
+                items
+            };
+            let _ = writeln!(self.out, "  (elem (;{};){} {})", index, header, items);
+        }
+        Ok(())
+    }
+
+    fn data(&mut self, section: &mut Reader) -> CodeGenResult<()> {
+        for index in 0..section.u32()? {
+            let mut header = String::new();
+            match section.u32()? {
+                0 => {}
+                1 => {
+                    let len = section.u32()? as usize;
+                    let bytes = section.take(len)?;
+                    let _ = writeln!(self.out, "  (data (;{};) {})", index, quote(bytes));
+                    continue;
+                }
+                2 => {
+                    let _ = write!(header, " (memory {})", section.u32()?);
+                }
+                _ => return Err(malformed("unknown data segment kind")),
+            }
+            let offset = offset(self.const_expr(section)?);
+            let len = section.u32()? as usize;
+            let bytes = section.take(len)?;
+            let _ = writeln!(
+                self.out,
+                "  (data (;{};){} {} {})",
+                index,
+                header,
+                offset,
+                quote(bytes)
+            );
+        }
+        Ok(())
+    }
+
+    fn code(&mut self, section: &mut Reader) -> CodeGenResult<()> {
+        for defined in 0..section.u32()? {
+            let size = section.u32()? as usize;
+            let mut body = Reader::new(section.take(size)?);
+            let index = self.imported_functions + defined;
+            let type_index = *self
+                .function_types
+                .get(index as usize)
+                .ok_or_else(|| malformed("function body without a declaration"))?;
+            let (params, results) = self
+                .types
+                .get(type_index as usize)
+                .ok_or_else(|| malformed("function type out of range"))?;
+            let _ = writeln!(
+                self.out,
+                "  (func {}(;{};) (type {}){}",
+                self.label(index),
+                index,
+                type_index,
+                signature(params, results)
+            );
+
+            let mut locals = Vec::new();
+            for _ in 0..body.u32()? {
+                let count = body.u32()?;
+                let local = value_type(body.byte()?)?;
+                locals.extend(std::iter::repeat_n(local, count as usize));
+            }
+            if !locals.is_empty() {
+                let _ = writeln!(self.out, "    (local {})", locals.join(" "));
+            }
+            self.body(&mut body)?;
+            self.out.push_str("  )\n");
+        }
+        Ok(())
+    }
+
+    /// Prints the instructions of a function body, indented by block depth
+    fn body(&mut self, body: &mut Reader) -> CodeGenResult<()> {
+        let mut depth = 0usize;
+        loop {
+            let opcode = body.byte()?;
+            let indent = match opcode {
+                // 関数本体を閉じる end は出力しない
+                0x0B if depth == 0 => return Ok(()),
+                0x0B => {
+                    depth -= 1;
+                    depth
+                }
+                0x05 => depth.saturating_sub(1),
+                _ => depth,
+            };
+            let Some(text) = self.instruction(opcode, body)? else {
+                let _ = writeln!(
+                    self.out,
+                    "    {};; unsupported instruction 0x{:02x}, rest of the body skipped",
+                    "  ".repeat(indent),
+                    opcode
+                );
+                return Ok(());
+            };
+            let _ = writeln!(self.out, "    {}{}", "  ".repeat(indent), text);
+            if matches!(opcode, 0x02..=0x04) {
+                depth += 1;
+            }
+        }
+    }
+
+    /// Instructions of a constant expression, without the closing `end`
+    fn const_expr(&self, section: &mut Reader) -> CodeGenResult<Vec<String>> {
+        let mut instructions = Vec::new();
+        loop {
+            match section.byte()? {
+                0x0B => return Ok(instructions),
+                opcode => instructions.push(
+                    self.instruction(opcode, section)?
+                        .ok_or_else(|| malformed("unsupported constant expression"))?,
+                ),
+            }
+        }
+    }
+
+    /// Text of the instruction starting with `opcode`, or `None` if the
+    /// printer cannot decode it
+    fn instruction(&self, opcode: u8, reader: &mut Reader) -> CodeGenResult<Option<String>> {
+        let text = match opcode {
+            0x00 => "unreachable".to_string(),
+            0x01 => "nop".to_string(),
+            0x02 => format!("block{}", self.block_type(reader)?),
+            0x03 => format!("loop{}", self.block_type(reader)?),
+            0x04 => format!("if{}", self.block_type(reader)?),
+            0x05 => "else".to_string(),
+            0x0B => "end".to_string(),
+            0x0C => format!("br {}", reader.u32()?),
+            0x0D => format!("br_if {}", reader.u32()?),
+            0x0E => {
+                let mut labels = Vec::new();
+                // 既定のラベルを含めて数 + 1 個
+                for _ in 0..=reader.u32()? {
+                    labels.push(reader.u32()?.to_string());
+                }
+                format!("br_table {}", labels.join(" "))
+            }
+            0x0F => "return".to_string(),
+            0x10 => format!("call {}", self.function(reader.u32()?)),
+            0x11 => {
+                let type_index = reader.u32()?;
+                match reader.u32()? {
+                    0 => format!("call_indirect (type {})", type_index),
+                    table => format!("call_indirect {} (type {})", table, type_index),
+                }
+            }
+            0x1A => "drop".to_string(),
+            0x1B => "select".to_string(),
+            0x1C => {
+                let types = value_types(reader)?;
+                format!("select (result {})", types.join(" "))
+            }
+            0x20 => format!("local.get {}", reader.u32()?),
+            0x21 => format!("local.set {}", reader.u32()?),
+            0x22 => format!("local.tee {}", reader.u32()?),
+            0x23 => format!("global.get {}", reader.u32()?),
+            0x24 => format!("global.set {}", reader.u32()?),
+            0x25 => format!("table.get {}", reader.u32()?),
+            0x26 => format!("table.set {}", reader.u32()?),
+            0x28..=0x3E => {
+                let (name, natural) = MEMORY_ACCESS[usize::from(opcode - 0x28)];
+                memory_access(name, natural, reader)?
+            }
+            0x3F => {
+                reader.u32()?;
+                "memory.size".to_string()
+            }
+            0x40 => {
+                reader.u32()?;
+                "memory.grow".to_string()
+            }
+            0x41 => format!("i32.const {}", reader.signed()? as i32),
+            0x42 => format!("i64.const {}", reader.signed()?),
+            0x43 => {
+                let bits = u32::from_le_bytes(reader.take(4)?.try_into().unwrap());
+                format!("f32.const {}", float(f64::from(f32::from_bits(bits))))
+            }
+            0x44 => {
+                let bits = u64::from_le_bytes(reader.take(8)?.try_into().unwrap());
+                format!("f64.const {}", float(f64::from_bits(bits)))
+            }
+            0x45..=0xC4 => NUMERIC[usize::from(opcode - 0x45)].to_string(),
+            0xD0 => format!("ref.null {}", heap_type(reader.byte()?)?),
+            0xD1 => "ref.is_null".to_string(),
+            0xD2 => format!("ref.func {}", self.function(reader.u32()?)),
+            0xFC => match reader.u32()? {
+                sub @ 0..=7 => TRUNC_SAT[sub as usize].to_string(),
+                8 => {
+                    let data = reader.u32()?;
+                    reader.u32()?;
+                    format!("memory.init {}", data)
+                }
+                9 => format!("data.drop {}", reader.u32()?),
+                10 => {
+                    reader.u32()?;
+                    reader.u32()?;
+                    "memory.copy".to_string()
+                }
+                11 => {
+                    reader.u32()?;
+                    "memory.fill".to_string()
+                }
+                12 => {
+                    let element = reader.u32()?;
+                    format!("table.init {} {}", reader.u32()?, element)
+                }
+                13 => format!("elem.drop {}", reader.u32()?),
+                14 => format!("table.copy {} {}", reader.u32()?, reader.u32()?),
+                15 => format!("table.grow {}", reader.u32()?),
+                16 => format!("table.size {}", reader.u32()?),
+                17 => format!("table.fill {}", reader.u32()?),
+                _ => return Ok(None),
+            },
+            0xFE => match reader.u32()? {
+                0x00 => memory_access("memory.atomic.notify", 2, reader)?,
+                0x01 => memory_access("memory.atomic.wait32", 2, reader)?,
+                0x02 => memory_access("memory.atomic.wait64", 3, reader)?,
+                0x03 => {
+                    reader.byte()?;
+                    "atomic.fence".to_string()
+                }
+                sub @ 0x10..=0x1D => {
+                    let (name, natural) = ATOMIC_ACCESS[sub as usize - 0x10];
+                    memory_access(name, natural, reader)?
+                }
+                sub @ 0x1E..=0x4E => {
+                    let operation = ATOMIC_RMW[(sub as usize - 0x1E) / 7];
+                    let (name, natural) = match (sub - 0x1E) % 7 {
+                        0 => (format!("i32.atomic.rmw.{}", operation), 2),
+                        1 => (format!("i64.atomic.rmw.{}", operation), 3),
+                        2 => (format!("i32.atomic.rmw8.{}_u", operation), 0),
+                        3 => (format!("i32.atomic.rmw16.{}_u", operation), 1),
+                        4 => (format!("i64.atomic.rmw8.{}_u", operation), 0),
+                        5 => (format!("i64.atomic.rmw16.{}_u", operation), 1),
+                        _ => (format!("i64.atomic.rmw32.{}_u", operation), 2),
+                    };
+                    memory_access(&name, natural, reader)?
+                }
+                _ => return Ok(None),
+            },
+            _ => return Ok(None),
+        };
+        Ok(Some(text))
+    }
+
+    fn block_type(&self, reader: &mut Reader) -> CodeGenResult<String> {
+        match reader.bytes.get(reader.pos) {
+            Some(0x40) => {
+                reader.pos += 1;
+                Ok(String::new())
+            }
+            Some(&byte) if value_type(byte).is_ok() => {
+                reader.pos += 1;
+                Ok(format!(" (result {})", value_type(byte)?))
+            }
+            _ => Ok(format!(" (type {})", reader.signed()?)),
+        }
+    }
+}
+
+/// `name offset=N align=N`, leaving out the defaults
+fn memory_access(name: &str, natural: u32, reader: &mut Reader) -> CodeGenResult<String> {
+    let flags = reader.u32()?;
+    // ビット 6 は複数メモリのインデックスが続くことを示す
+    let memory = if flags & 0x40 != 0 { reader.u32()? } else { 0 };
+    let offset = reader.u64()?;
+    let mut text = name.to_string();
+    if memory != 0 {
+        let _ = write!(text, " {}", memory);
+    }
+    if offset != 0 {
+        let _ = write!(text, " offset={}", offset);
+    }
+    let align = flags & !0x40;
+    if align != natural {
+        let _ = write!(text, " align={}", 1u64 << align.min(63));
+    }
+    Ok(text)
+}
+
+fn value_type(byte: u8) -> CodeGenResult<&'static str> {
+    match byte {
+        0x7F => Ok("i32"),
+        0x7E => Ok("i64"),
+        0x7D => Ok("f32"),
+        0x7C => Ok("f64"),
+        0x7B => Ok("v128"),
+        0x70 => Ok("funcref"),
+        0x6F => Ok("externref"),
+        _ => Err(malformed(&format!("unknown value type 0x{:02x}", byte))),
+    }
+}
+
+fn heap_type(byte: u8) -> CodeGenResult<&'static str> {
+    match byte {
+        0x70 => Ok("func"),
+        0x6F => Ok("extern"),
+        _ => Err(malformed(&format!("unknown heap type 0x{:02x}", byte))),
+    }
+}
+
+fn value_types(reader: &mut Reader) -> CodeGenResult<Vec<&'static str>> {
+    (0..reader.u32()?)
+        .map(|_| value_type(reader.byte()?))
+        .collect()
+}
+
+fn global_type(reader: &mut Reader) -> CodeGenResult<String> {
+    let value = value_type(reader.byte()?)?;
+    match reader.byte()? {
+        0 => Ok(value.to_string()),
+        1 => Ok(format!("(mut {})", value)),
+        _ => Err(malformed("unknown global mutability")),
+    }
+}
+
+/// Limits of a table or memory: `[i64] min [max] [shared]`
+fn limits(reader: &mut Reader) -> CodeGenResult<String> {
+    let flags = reader.byte()?;
+    if flags > 7 {
+        return Err(malformed("unknown limits flags"));
+    }
+    let mut text = String::new();
+    if flags & 0b100 != 0 {
+        text.push_str("i64 ");
+    }
+    let _ = write!(text, "{}", reader.u64()?);
+    if flags & 0b001 != 0 {
+        let _ = write!(text, " {}", reader.u64()?);
+    }
+    if flags & 0b010 != 0 {
+        text.push_str(" shared");
+    }
+    Ok(text)
+}
+
+fn signature(params: &[&str], results: &[&str]) -> String {
+    let mut text = String::new();
+    if !params.is_empty() {
+        let _ = write!(text, " (param {})", params.join(" "));
+    }
+    if !results.is_empty() {
+        let _ = write!(text, " (result {})", results.join(" "));
+    }
+    text
+}
+
+/// Offset of an active segment: the abbreviated `(instr)` form when it is a
+/// single instruction
+fn offset(instructions: Vec<String>) -> String {
+    match &instructions[..] {
+        [instruction] => format!("({})", instruction),
+        _ => format!("(offset {})", instructions.join(" ")),
+    }
+}
+
+/// Float literal in a form the text format accepts
+fn float(value: f64) -> String {
+    if value.is_nan() {
+        "nan".to_string()
+    } else if value == f64::INFINITY {
+        "inf".to_string()
+    } else if value == f64::NEG_INFINITY {
+        "-inf".to_string()
+    } else {
+        format!("{:?}", value)
+    }
+}
+
+/// String literal with everything but printable ASCII escaped as `\hh`
+fn quote(bytes: &[u8]) -> String {
+    let mut text = String::from("\"");
+    for &byte in bytes {
+        match byte {
+            b'"' | b'\\' => {
+                text.push('\\');
+                text.push(byte as char);
+            }
+            0x20..=0x7E => text.push(byte as char),
+            _ => {
+                let _ = write!(text, "\\{:02x}", byte);
+            }
+        }
+    }
+    text.push('"');
+    text
+}
+
+/// Whether `byte` may appear in a `$name` identifier
+fn is_id_char(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || b"!#$%&'*+-./:<=>?@\\^_`|~".contains(&byte)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn section(id: u8, payload: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![id, payload.len() as u8];
+        bytes.extend_from_slice(payload);
+        bytes
+    }
+
+    /// Module importing `env.log`, exporting `add` and storing "hi\n" at 1024
+    fn module() -> Vec<u8> {
+        let mut bytes = b"\0asm\x01\0\0\0".to_vec();
+        // (i32, i32) -> i32 と (i32) -> ()
+        bytes.extend(section(
+            TYPE,
+            &[2, 0x60, 2, 0x7F, 0x7F, 1, 0x7F, 0x60, 1, 0x7F, 0],
+        ));
+        bytes.extend(section(
+            IMPORT,
+            &[1, 3, b'e', b'n', b'v', 3, b'l', b'o', b'g', 0, 1],
+        ));
+        bytes.extend(section(FUNCTION, &[1, 0]));
+        bytes.extend(section(MEMORY, &[1, 1, 2, 16]));
+        bytes.extend(section(GLOBAL, &[1, 0x7F, 1, 0x41, 0x80, 0x80, 0x04, 0x0B]));
+        bytes.extend(section(EXPORT, &[1, 3, b'a', b'd', b'd', 0, 1]));
+        let body = [
+            1, 1, 0x7F, // local i32
+            0x20, 0, 0x20, 1, 0x6A, 0x22, 2, // local.get 0, local.get 1, i32.add, local.tee 2
+            0x41, 0x7F, 0x48, // i32.const -1, i32.lt_s
+            0x04, 0x40, 0x20, 2, 0x10, 0, 0x0B, // if, local.get 2, call 0, end
+            0x20, 2, 0x28, 0x02, 0x08, // local.get 2, i32.load offset=8
+            0x0B,
+        ];
+        let mut code = vec![1, body.len() as u8];
+        code.extend_from_slice(&body);
+        bytes.extend(section(CODE, &code));
+        bytes.extend(section(
+            DATA,
+            &[1, 0, 0x41, 0x80, 0x08, 0x0B, 3, b'h', b'i', b'\n'],
+        ));
+        // name セクションの関数名
+        let names = [1, 11, 2, 0, 3, b'l', b'o', b'g', 1, 3, b'a', b'd', b'd'];
+        let mut custom = vec![4, b'n', b'a', b'm', b'e'];
+        custom.extend_from_slice(&names);
+        bytes.extend(section(CUSTOM, &custom));
+        bytes
+    }
+
+    #[test]
+    fn test_print_module() {
+        assert_eq!(
+            print(&module()).unwrap(),
+            "(module\n\
+             \x20 (type (;0;) (func (param i32 i32) (result i32)))\n\
+             \x20 (type (;1;) (func (param i32)))\n\
+             \x20 (import \"env\" \"log\" (func $log (;0;) (type 1)))\n\
+             \x20 (memory (;0;) 2 16)\n\
+             \x20 (global (;0;) (mut i32) i32.const 65536)\n\
+             \x20 (export \"add\" (func $add))\n\
+             \x20 (func $add (;1;) (type 0) (param i32 i32) (result i32)\n\
+             \x20   (local i32)\n\
+             \x20   local.get 0\n\
+             \x20   local.get 1\n\
+             \x20   i32.add\n\
+             \x20   local.tee 2\n\
+             \x20   i32.const -1\n\
+             \x20   i32.lt_s\n\
+             \x20   if\n\
+             \x20     local.get 2\n\
+             \x20     call $log\n\
+             \x20   end\n\
+             \x20   local.get 2\n\
+             \x20   i32.load offset=8\n\
+             \x20 )\n\
+             \x20 (data (;0;) (i32.const 1024) \"hi\\0a\")\n\
+             \x20 ;; custom section \"name\", 13 bytes\n\
+             )\n"
+        );
+    }
+
+    #[test]
+    fn test_malformed_modules_are_rejected() {
+        assert!(print(b"\0asm\x02\0\0\0").is_err());
+
+        let mut truncated = module();
+        truncated.truncate(truncated.len() - 4);
+        let error = print(&truncated).unwrap_err().to_string();
+        assert!(error.contains("unexpected end of data"), "{}", error);
+    }
+}
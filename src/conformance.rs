@@ -0,0 +1,394 @@
+//! Grammar conformance corpus.
+//!
+//! `tests/conformance/valid` holds programs that must lex, parse and pass
+//! semantic analysis. Each `name.replica` is checked against its token stream
+//! in `name.tokens` and its AST in `name.ast`. `tests/conformance/invalid`
+//! holds programs that must be rejected, with the expected diagnostic in
+//! `name.diagnostic`: its code (`lex`, `parse/<variant>` or
+//! `semantic/<variant>`) on the first line and the message on the next ones;
+//! lexical errors are all reported, one per line.
+//!
+//! Any grammar change that alters these fails the tests, so new keywords and
+//! precedence changes are never accidental. When a change is intended, rerun
+//! the tests with `REPLICA_BLESS=1` to rewrite the expectations and review
+//! the diff.
+//!
+//! The corpus also holds `grammar.ebnf` to the parser: the grammar must
+//! derive every valid program and none of the programs the parser rejects.
+
+use crate::ast::Actor;
+use crate::grammar::Grammar;
+use crate::lexer::{self, LexError, Token};
+use crate::parser::{ParseError, Parser};
+use crate::semantic::{SemanticAnalyzer, SemanticError};
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Environment variable that makes the tests rewrite the expectations
+const BLESS: &str = "REPLICA_BLESS";
+
+/// Every token kind, in declaration order
+const TOKEN_KINDS: [&str; 81] = [
+    "Actor",
+    "Single",
+    "Var",
+    "Let",
+    "Func",
+    "Async",
+    "Sequential",
+    "Await",
+    "Immediate",
+    "Pure",
+    "Comptime",
+    "Move",
+    "Copy",
+    "Shared",
+    "Weak",
+    "Unowned",
+    "Inout",
+    "Init",
+    "Deinit",
+    "Extern",
+    "Const",
+    "Import",
+    "Topic",
+    "Pool",
+    "Struct",
+    "Enum",
+    "Arrow",
+    "FatArrow",
+    "Identifier",
+    "StringLiteral",
+    "StringHead",
+    "StringMiddle",
+    "StringTail",
+    "NumberLiteral",
+    "FloatLiteral",
+    "LBrace",
+    "RBrace",
+    "LParen",
+    "RParen",
+    "LBracket",
+    "RBracket",
+    "Semicolon",
+    "Colon",
+    "Comma",
+    "Equals",
+    "EqualEqual",
+    "NotEqual",
+    "Less",
+    "LessEqual",
+    "Greater",
+    "GreaterEqual",
+    "Plus",
+    "Minus",
+    "Multiply",
+    "Divide",
+    "PlusEquals",
+    "MinusEquals",
+    "MultiplyEquals",
+    "DivideEquals",
+    "Question",
+    "QuestionQuestion",
+    "Exclamation",
+    "Ampersand",
+    "Dot",
+    "At",
+    "Return",
+    "Break",
+    "Continue",
+    "Yield",
+    "If",
+    "Else",
+    "While",
+    "For",
+    "In",
+    "Match",
+    "Case",
+    "Nil",
+    "True",
+    "False",
+    "DotDot",
+    "Error",
+];
+
+/// Name of the kind of `token`. The match is exhaustive, so a new token does
+/// not compile until it is added here and to [`TOKEN_KINDS`], and the
+/// coverage test then asks for a corpus program using it.
+fn token_kind(token: &Token) -> &'static str {
+    match token {
+        Token::Actor => "Actor",
+        Token::Single => "Single",
+        Token::Var => "Var",
+        Token::Let => "Let",
+        Token::Func => "Func",
+        Token::Async => "Async",
+        Token::Sequential => "Sequential",
+        Token::Await => "Await",
+        Token::Immediate => "Immediate",
+        Token::Pure => "Pure",
+        Token::Comptime => "Comptime",
+        Token::Move => "Move",
+        Token::Copy => "Copy",
+        Token::Shared => "Shared",
+        Token::Weak => "Weak",
+        Token::Unowned => "Unowned",
+        Token::Inout => "Inout",
+        Token::Init => "Init",
+        Token::Deinit => "Deinit",
+        Token::Extern => "Extern",
+        Token::Const => "Const",
+        Token::Import => "Import",
+        Token::Topic => "Topic",
+        Token::Pool => "Pool",
+        Token::Struct => "Struct",
+        Token::Enum => "Enum",
+        Token::Arrow => "Arrow",
+        Token::FatArrow => "FatArrow",
+        Token::Identifier(_) => "Identifier",
+        Token::StringLiteral(_) => "StringLiteral",
+        Token::StringHead(_) => "StringHead",
+        Token::StringMiddle(_) => "StringMiddle",
+        Token::StringTail(_) => "StringTail",
+        Token::NumberLiteral(_) => "NumberLiteral",
+        Token::FloatLiteral(_) => "FloatLiteral",
+        Token::LBrace => "LBrace",
+        Token::RBrace => "RBrace",
+        Token::LParen => "LParen",
+        Token::RParen => "RParen",
+        Token::LBracket => "LBracket",
+        Token::RBracket => "RBracket",
+        Token::Semicolon => "Semicolon",
+        Token::Colon => "Colon",
+        Token::Comma => "Comma",
+        Token::Equals => "Equals",
+        Token::EqualEqual => "EqualEqual",
+        Token::NotEqual => "NotEqual",
+        Token::Less => "Less",
+        Token::LessEqual => "LessEqual",
+        Token::Greater => "Greater",
+        Token::GreaterEqual => "GreaterEqual",
+        Token::Plus => "Plus",
+        Token::Minus => "Minus",
+        Token::Multiply => "Multiply",
+        Token::Divide => "Divide",
+        Token::PlusEquals => "PlusEquals",
+        Token::MinusEquals => "MinusEquals",
+        Token::MultiplyEquals => "MultiplyEquals",
+        Token::DivideEquals => "DivideEquals",
+        Token::Question => "Question",
+        Token::QuestionQuestion => "QuestionQuestion",
+        Token::Exclamation => "Exclamation",
+        Token::Ampersand => "Ampersand",
+        Token::Dot => "Dot",
+        Token::At => "At",
+        Token::Return => "Return",
+        Token::Break => "Break",
+        Token::Continue => "Continue",
+        Token::Yield => "Yield",
+        Token::If => "If",
+        Token::Else => "Else",
+        Token::While => "While",
+        Token::For => "For",
+        Token::In => "In",
+        Token::Match => "Match",
+        Token::Case => "Case",
+        Token::Nil => "Nil",
+        Token::True => "True",
+        Token::False => "False",
+        Token::DotDot => "DotDot",
+        Token::Error(_) => "Error",
+    }
+}
+
+/// First error a program hits in the front end
+enum Diagnostic {
+    Lex(Vec<LexError>),
+    Parse(ParseError),
+    Semantic(SemanticError),
+}
+
+impl Diagnostic {
+    /// Stable identifier of the kind of error, independent of its message
+    fn code(&self) -> String {
+        match self {
+            Diagnostic::Lex(_) => "lex".to_string(),
+            Diagnostic::Parse(error) => format!("parse/{}", error.code()),
+            Diagnostic::Semantic(error) => format!("semantic/{}", error.code()),
+        }
+    }
+
+    fn message(&self) -> String {
+        match self {
+            Diagnostic::Lex(errors) => errors
+                .iter()
+                .map(LexError::to_string)
+                .collect::<Vec<_>>()
+                .join("\n"),
+            Diagnostic::Parse(error) => error.to_string(),
+            Diagnostic::Semantic(error) => error.to_string(),
+        }
+    }
+}
+
+/// Lexes, parses and analyzes `source` the way `query::Database::typed_ast`
+/// does, without compile-time evaluation or plugins
+fn front_end(source: &str) -> Result<(Vec<(Token, usize)>, Actor), Diagnostic> {
+    let (tokens, errors) = lexer::tokenize_recovering(source);
+    if !errors.is_empty() {
+        return Err(Diagnostic::Lex(errors));
+    }
+    let tokens = lexer::token_lines(source, tokens);
+    let actor = Parser::with_lines(tokens.clone())
+        .with_docs(lexer::doc_comments(source))
+        .parse_actor()
+        .map_err(Diagnostic::Parse)?;
+    SemanticAnalyzer::new()
+        .analyze_actor(&actor)
+        .map_err(Diagnostic::Semantic)?;
+    Ok((tokens, actor))
+}
+
+/// `.replica` programs under `tests/conformance/<kind>`, sorted by name
+fn corpus(kind: &str) -> Vec<PathBuf> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/conformance")
+        .join(kind);
+    let mut programs: Vec<PathBuf> = fs::read_dir(&dir)
+        .unwrap_or_else(|e| panic!("cannot read {}: {}", dir.display(), e))
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "replica"))
+        .collect();
+    programs.sort();
+    assert!(!programs.is_empty(), "no programs in {}", dir.display());
+    programs
+}
+
+fn read(program: &Path) -> String {
+    fs::read_to_string(program)
+        .unwrap_or_else(|e| panic!("cannot read {}: {}", program.display(), e))
+}
+
+/// Compares `actual` with the expectation stored next to `program` under
+/// `extension`, or rewrites the expectation when blessing
+fn expect(program: &Path, extension: &str, actual: &str) -> Result<(), String> {
+    let path = program.with_extension(extension);
+    if std::env::var_os(BLESS).is_some() {
+        return fs::write(&path, actual).map_err(|e| format!("{}: {}", path.display(), e));
+    }
+    match fs::read_to_string(&path) {
+        Ok(expected) if expected == actual => Ok(()),
+        Ok(expected) => Err(format!(
+            "{} does not match\n--- expected\n{}--- actual\n{}",
+            path.display(),
+            expected,
+            actual
+        )),
+        Err(_) => Err(format!(
+            "{} is missing; rerun with {}=1 to create it",
+            path.display(),
+            BLESS
+        )),
+    }
+}
+
+/// Fails with every mismatch at once, so one run shows the whole effect of a
+/// grammar change
+fn assert_all(failures: Vec<String>) {
+    assert!(
+        failures.is_empty(),
+        "{} conformance failure(s):\n\n{}",
+        failures.len(),
+        failures.join("\n\n")
+    );
+}
+
+#[test]
+fn test_valid_programs() {
+    let mut failures = Vec::new();
+    for program in corpus("valid") {
+        let (tokens, actor) = match front_end(&read(&program)) {
+            Ok(result) => result,
+            Err(diagnostic) => {
+                failures.push(format!(
+                    "{} was rejected: {}",
+                    program.display(),
+                    diagnostic.message()
+                ));
+                continue;
+            }
+        };
+        let tokens: String = tokens
+            .iter()
+            .map(|(token, line)| format!("{}\t{:?}\n", line, token))
+            .collect();
+        failures.extend(expect(&program, "tokens", &tokens).err());
+        failures.extend(expect(&program, "ast", &format!("{:#?}\n", actor)).err());
+    }
+    assert_all(failures);
+}
+
+#[test]
+fn test_invalid_programs() {
+    let mut failures = Vec::new();
+    for program in corpus("invalid") {
+        let Err(diagnostic) = front_end(&read(&program)) else {
+            failures.push(format!("{} was accepted", program.display()));
+            continue;
+        };
+        let actual = format!("{}\n{}\n", diagnostic.code(), diagnostic.message());
+        failures.extend(expect(&program, "diagnostic", &actual).err());
+    }
+    assert_all(failures);
+}
+
+#[test]
+fn test_corpus_uses_every_token() {
+    let used: BTreeSet<&str> = corpus("valid")
+        .into_iter()
+        .chain(corpus("invalid"))
+        .flat_map(|program| lexer::tokenize_recovering(&read(&program)).0)
+        .map(|(token, _)| token_kind(&token))
+        .collect();
+    let unused: Vec<&str> = TOKEN_KINDS
+        .into_iter()
+        .filter(|kind| !used.contains(kind))
+        .collect();
+    assert!(
+        unused.is_empty(),
+        "no conformance program uses {:?}",
+        unused
+    );
+}
+
+#[test]
+fn test_grammar_matches_parser() {
+    let grammar = Grammar::replica();
+    let mut failures = Vec::new();
+    for program in corpus("valid") {
+        let tokens = lexer::tokenize(&read(&program)).unwrap();
+        if let Err(at) = grammar.accepts(&tokens) {
+            failures.push(format!(
+                "grammar.ebnf rejects {} at token {} ({:?})",
+                program.display(),
+                at,
+                tokens.get(at)
+            ));
+        }
+    }
+    for program in corpus("invalid") {
+        // 字句や意味の誤りは文法では判定しない
+        let source = read(&program);
+        if !matches!(front_end(&source), Err(Diagnostic::Parse(_))) {
+            continue;
+        }
+        if grammar.accepts(&lexer::tokenize(&source).unwrap()).is_ok() {
+            failures.push(format!(
+                "grammar.ebnf accepts {}, which the parser rejects",
+                program.display()
+            ));
+        }
+    }
+    assert_all(failures);
+}
@@ -0,0 +1,1275 @@
+//! Compile-time evaluation of `pure` methods.
+//! `comptime f(args)` calls are interpreted over the typed AST and replaced by
+//! literals of their results before code generation, so lookup tables and
+//! configuration are baked into the module instead of computed at run time.
+
+use crate::ast::{
+    Actor, Conversion, Expression, LiteralValue, Method, MethodBody, Operator, Pattern, Statement,
+};
+use replica_runtime::string;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+
+/// Deepest chain of nested calls the evaluator follows
+const MAX_CALL_DEPTH: usize = 256;
+
+/// Expressions evaluated per `comptime` call before giving up
+const MAX_STEPS: usize = 1_000_000;
+
+/// A value computed at compile time
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Int(i32),
+    Float(f64),
+    Bool(bool),
+    String(String),
+    Tuple(Vec<Value>),
+    Record(Vec<(String, Value)>),
+    /// Fixed-size array
+    Array(Vec<Value>),
+}
+
+impl Value {
+    /// Expression that produces the value at run time
+    pub fn to_expression(&self) -> Expression {
+        match self {
+            Value::Int(value) => Expression::Literal(LiteralValue::Int(*value)),
+            Value::Float(value) => Expression::Literal(LiteralValue::Float(*value)),
+            Value::Bool(value) => Expression::Literal(LiteralValue::Bool(*value)),
+            Value::String(value) => Expression::Literal(LiteralValue::String(value.clone())),
+            Value::Tuple(elements) => {
+                Expression::Tuple(elements.iter().map(Value::to_expression).collect())
+            }
+            Value::Record(fields) => Expression::Record(
+                fields
+                    .iter()
+                    .map(|(label, value)| (label.clone(), value.to_expression()))
+                    .collect(),
+            ),
+            Value::Array(elements) => {
+                Expression::ArrayLiteral(elements.iter().map(Value::to_expression).collect())
+            }
+        }
+    }
+}
+
+/// How the statements of a block finished
+enum Flow {
+    Normal,
+    Return(Value),
+    /// `break`, with its label if any
+    Break(Option<String>),
+    /// `continue`, with its label if any
+    Continue(Option<String>),
+}
+
+impl Flow {
+    /// Whether a jump to `target` applies to the loop labeled `label`; unlabeled
+    /// jumps apply to the innermost loop
+    fn targets(target: &Option<String>, label: &Option<String>) -> bool {
+        target.is_none() || target == label
+    }
+}
+
+/// Interpreter for the pure methods of one actor
+pub struct ConstEvaluator<'a> {
+    methods: HashMap<&'a str, &'a Method>,
+    depth: usize,
+    steps: usize,
+}
+
+impl<'a> ConstEvaluator<'a> {
+    pub fn new(actor: &'a Actor) -> Self {
+        ConstEvaluator {
+            methods: actor
+                .methods
+                .iter()
+                .map(|method| (method.name.as_str(), method))
+                .collect(),
+            depth: 0,
+            steps: 0,
+        }
+    }
+
+    /// Evaluates `callee(args)`, which semantic analysis checked to be a call to a
+    /// pure method with constant arguments
+    pub fn evaluate_call(&mut self, callee: &str, args: &[Expression]) -> Result<Value, String> {
+        self.steps = 0;
+        let args = args
+            .iter()
+            .map(|arg| self.evaluate(arg, &HashMap::new()))
+            .collect::<Result<Vec<_>, _>>()?;
+        self.call(callee, args)
+    }
+
+    fn call(&mut self, callee: &str, args: Vec<Value>) -> Result<Value, String> {
+        let method = *self
+            .methods
+            .get(callee)
+            .ok_or_else(|| format!("{} is not a method of this actor", callee))?;
+        let body = match (&method.body, method.is_pure) {
+            (Some(body), true) => body,
+            _ => return Err(format!("{} is not a pure method", callee)),
+        };
+        if self.depth == MAX_CALL_DEPTH {
+            return Err(format!(
+                "Calls nested deeper than {} levels while evaluating {}",
+                MAX_CALL_DEPTH, callee
+            ));
+        }
+
+        let mut locals: HashMap<String, Value> = method
+            .params
+            .iter()
+            .map(|param| param.name.clone())
+            .zip(args)
+            .collect();
+
+        self.depth += 1;
+        // 本体の束縛は呼び出しの終わりで捨てるので、ブロックとして扱わない
+        let result = self.run_statements(body, &mut locals, &HashSet::new(), &mut HashMap::new());
+        self.depth -= 1;
+        match result? {
+            Flow::Return(value) => Ok(value),
+            _ => Err(format!("{} did not return a value", callee)),
+        }
+    }
+
+    /// Runs `body` as a block: its bindings go out of scope when it ends, while its
+    /// assignments to variables declared outside it stay
+    fn run(
+        &mut self,
+        body: &MethodBody,
+        locals: &mut HashMap<String, Value>,
+    ) -> Result<Flow, String> {
+        let outer: HashSet<String> = locals.keys().cloned().collect();
+        // ブロック内の束縛に隠された外側の変数の値
+        let mut shadowed = HashMap::new();
+        let flow = self.run_statements(body, locals, &outer, &mut shadowed);
+        locals.retain(|name, _| outer.contains(name));
+        locals.extend(shadowed);
+        flow
+    }
+
+    fn run_statements(
+        &mut self,
+        body: &MethodBody,
+        locals: &mut HashMap<String, Value>,
+        outer: &HashSet<String>,
+        shadowed: &mut HashMap<String, Value>,
+    ) -> Result<Flow, String> {
+        for statement in &body.statements {
+            match statement {
+                Statement::Return(expr) => return self.evaluate(expr, locals).map(Flow::Return),
+                Statement::Expression(expr) => {
+                    self.evaluate(expr, locals)?;
+                }
+                Statement::Log { level, .. } => {
+                    return Err(format!(
+                        "log.{} cannot be evaluated at compile time",
+                        level.name()
+                    ))
+                }
+                Statement::Yield(_) => {
+                    return Err("yield cannot be evaluated at compile time".to_string())
+                }
+                Statement::Publish { topic, .. } | Statement::Subscribe(topic) => {
+                    return Err(format!("topic {} cannot be used at compile time", topic))
+                }
+                Statement::Assign {
+                    target: Expression::Variable(name),
+                    operator,
+                    value,
+                } if locals.contains_key(name) => {
+                    let value = self.evaluate(value, locals)?;
+                    let value = match operator {
+                        Some(operator) => {
+                            Self::binary_operation(operator, locals[name].clone(), value)?
+                        }
+                        None => value,
+                    };
+                    locals.insert(name.clone(), value);
+                }
+                Statement::Assign { .. } => {
+                    return Err("field assignments cannot be evaluated at compile time".to_string())
+                }
+                Statement::Let { pattern, value, .. } => {
+                    let value = self.evaluate(value, locals)?;
+                    let mut names = Vec::new();
+                    Self::pattern_names(pattern, &mut names);
+                    for name in names {
+                        if outer.contains(name) && !shadowed.contains_key(name) {
+                            shadowed.insert(name.to_string(), locals[name].clone());
+                        }
+                    }
+                    Self::bind(pattern, value, locals)?;
+                }
+                Statement::If {
+                    condition,
+                    then_body,
+                    else_body,
+                } => {
+                    let branch = match self.evaluate(condition, locals)? {
+                        Value::Bool(true) => Some(then_body),
+                        Value::Bool(false) => else_body.as_ref(),
+                        other => return Err(format!("if condition {:?} is not a Bool", other)),
+                    };
+                    // 分岐内の束縛は分岐の外から見えない
+                    if let Some(branch) = branch {
+                        match self.run(branch, locals)? {
+                            Flow::Normal => {}
+                            flow => return Ok(flow),
+                        }
+                    }
+                }
+                Statement::While {
+                    label,
+                    condition,
+                    body,
+                } => loop {
+                    match self.evaluate(condition, locals)? {
+                        Value::Bool(true) => {}
+                        Value::Bool(false) => break,
+                        other => return Err(format!("while condition {:?} is not a Bool", other)),
+                    }
+                    match self.run(body, locals)? {
+                        Flow::Break(target) if Flow::targets(&target, label) => break,
+                        Flow::Continue(target) if Flow::targets(&target, label) => {}
+                        Flow::Normal => {}
+                        flow => return Ok(flow),
+                    }
+                },
+                Statement::For { .. } | Statement::Match { .. } => {
+                    match self.run_nested(statement, locals)? {
+                        Flow::Normal => {}
+                        flow => return Ok(flow),
+                    }
+                }
+                Statement::Break(label) => return Ok(Flow::Break(label.clone())),
+                Statement::Continue(label) => return Ok(Flow::Continue(label.clone())),
+            }
+        }
+        Ok(Flow::Normal)
+    }
+
+    /// Runs a `for` or `match` statement. Kept out of `run_statements`, whose
+    /// frame every nested call of a method pays for.
+    fn run_nested(
+        &mut self,
+        statement: &Statement,
+        locals: &mut HashMap<String, Value>,
+    ) -> Result<Flow, String> {
+        match statement {
+            Statement::For {
+                label,
+                variable,
+                start,
+                end,
+                body,
+            } => self.run_for(label, variable, start, end, body, locals),
+            Statement::Match { value, arms } => self.run_match(value, arms, locals),
+            _ => Err(format!("{:?} does not contain a block", statement)),
+        }
+    }
+
+    /// Runs `for variable in start..end { body }`, returning how the loop left the
+    /// enclosing block
+    fn run_for(
+        &mut self,
+        label: &Option<String>,
+        variable: &str,
+        start: &Expression,
+        end: &Expression,
+        body: &MethodBody,
+        locals: &mut HashMap<String, Value>,
+    ) -> Result<Flow, String> {
+        let (start, end) = match (self.evaluate(start, locals)?, self.evaluate(end, locals)?) {
+            (Value::Int(start), Value::Int(end)) => (start, end),
+            (start, end) => {
+                return Err(format!(
+                    "for range {:?}..{:?} is not a range of Ints",
+                    start, end
+                ))
+            }
+        };
+        // ループ変数は同名の外側の変数を一時的に隠す
+        let hidden = locals.remove(variable);
+        let mut flow = Flow::Normal;
+        for index in start..end {
+            // 本体が空でも反復回数は評価量に数える
+            self.step()?;
+            locals.insert(variable.to_string(), Value::Int(index));
+            match self.run(body, locals)? {
+                Flow::Break(target) if Flow::targets(&target, label) => break,
+                Flow::Continue(target) if Flow::targets(&target, label) => {}
+                Flow::Normal => {}
+                other => {
+                    flow = other;
+                    break;
+                }
+            }
+        }
+        locals.remove(variable);
+        locals.extend(hidden.map(|value| (variable.to_string(), value)));
+        Ok(flow)
+    }
+
+    /// Counts one step of evaluation, failing once the budget is spent
+    fn step(&mut self) -> Result<(), String> {
+        self.steps += 1;
+        if self.steps > MAX_STEPS {
+            return Err(format!(
+                "Evaluation did not finish within {} steps",
+                MAX_STEPS
+            ));
+        }
+        Ok(())
+    }
+
+    /// Names bound by `pattern`
+    fn pattern_names<'p>(pattern: &'p Pattern, names: &mut Vec<&'p str>) {
+        match pattern {
+            Pattern::Binding(name) => names.push(name),
+            Pattern::Tuple(patterns) | Pattern::Case { args: patterns, .. } => {
+                for pattern in patterns {
+                    Self::pattern_names(pattern, names);
+                }
+            }
+            Pattern::Wildcard | Pattern::Literal(_) => {}
+        }
+    }
+
+    /// Runs the body of the first arm of a `match` statement whose pattern
+    /// matches
+    fn run_match(
+        &mut self,
+        value: &Expression,
+        arms: &[(Pattern, MethodBody)],
+        locals: &mut HashMap<String, Value>,
+    ) -> Result<Flow, String> {
+        let value = self.evaluate(value, locals)?;
+        let (pattern, body) = self.select_arm(arms, &value)?;
+        // 腕の束縛は同名の外側の変数を一時的に隠す
+        let mut names = Vec::new();
+        Self::pattern_names(pattern, &mut names);
+        let hidden: Vec<_> = names
+            .iter()
+            .filter_map(|name| Some((name.to_string(), locals.get(*name)?.clone())))
+            .collect();
+        Self::bind(pattern, value, locals)?;
+        let flow = self.run(body, locals)?;
+        for name in names {
+            locals.remove(name);
+        }
+        locals.extend(hidden);
+        Ok(flow)
+    }
+
+    /// Whether `pattern` matches `value`. Enum values do not exist at compile
+    /// time, so case patterns cannot be decided.
+    fn matches(pattern: &Pattern, value: &Value) -> Result<bool, String> {
+        match (pattern, value) {
+            (Pattern::Wildcard | Pattern::Binding(_), _) => Ok(true),
+            (Pattern::Literal(literal), value) => Ok(match (literal, value) {
+                (LiteralValue::Int(literal), Value::Int(value)) => literal == value,
+                (LiteralValue::Float(literal), Value::Float(value)) => literal == value,
+                (LiteralValue::Bool(literal), Value::Bool(value)) => literal == value,
+                (LiteralValue::String(literal), Value::String(value)) => literal == value,
+                _ => false,
+            }),
+            (Pattern::Tuple(patterns), Value::Tuple(values)) if patterns.len() == values.len() => {
+                for (pattern, value) in patterns.iter().zip(values) {
+                    if !Self::matches(pattern, value)? {
+                        return Ok(false);
+                    }
+                }
+                Ok(true)
+            }
+            (Pattern::Case { .. }, _) => Err(format!(
+                "Pattern {} cannot be matched at compile time",
+                pattern
+            )),
+            _ => Ok(false),
+        }
+    }
+
+    /// Evaluates the result of the first arm of a `match` expression whose
+    /// pattern matches. Kept out of `evaluate` so that its frame stays small
+    /// for deeply nested calls.
+    fn evaluate_match(
+        &mut self,
+        value: &Expression,
+        arms: &[(Pattern, Expression)],
+        locals: &HashMap<String, Value>,
+    ) -> Result<Value, String> {
+        let value = self.evaluate(value, locals)?;
+        let (pattern, result) = self.select_arm(arms, &value)?;
+        let mut locals = locals.clone();
+        Self::bind(pattern, value, &mut locals)?;
+        self.evaluate(result, &locals)
+    }
+
+    /// First arm of a `match` whose pattern matches `value`
+    fn select_arm<'m, T>(
+        &self,
+        arms: &'m [(Pattern, T)],
+        value: &Value,
+    ) -> Result<&'m (Pattern, T), String> {
+        for arm in arms {
+            if Self::matches(&arm.0, value)? {
+                return Ok(arm);
+            }
+        }
+        Err(format!("No arm of the match matches {:?}", value))
+    }
+
+    fn bind(
+        pattern: &Pattern,
+        value: Value,
+        locals: &mut HashMap<String, Value>,
+    ) -> Result<(), String> {
+        match (pattern, value) {
+            (Pattern::Wildcard, _) => Ok(()),
+            (Pattern::Binding(name), value) => {
+                locals.insert(name.clone(), value);
+                Ok(())
+            }
+            (Pattern::Tuple(patterns), Value::Tuple(values)) if patterns.len() == values.len() => {
+                patterns
+                    .iter()
+                    .zip(values)
+                    .try_for_each(|(pattern, value)| Self::bind(pattern, value, locals))
+            }
+            (pattern, value) => Err(format!(
+                "Pattern {:?} does not match {:?} at compile time",
+                pattern, value
+            )),
+        }
+    }
+
+    fn evaluate(
+        &mut self,
+        expr: &Expression,
+        locals: &HashMap<String, Value>,
+    ) -> Result<Value, String> {
+        self.step()?;
+        match expr {
+            Expression::Literal(literal) => Ok(match literal {
+                LiteralValue::Int(value) => Value::Int(*value),
+                LiteralValue::Float(value) => Value::Float(*value),
+                LiteralValue::Bool(value) => Value::Bool(*value),
+                LiteralValue::String(value) => Value::String(value.clone()),
+            }),
+            Expression::Variable(name) => locals
+                .get(name)
+                .cloned()
+                .ok_or_else(|| format!("{} is not known at compile time", name)),
+            Expression::Field(name) => Err(format!("self.{} is not known at compile time", name)),
+            Expression::BinaryOp {
+                left,
+                operator,
+                right,
+            } => {
+                let left = self.evaluate(left, locals)?;
+                let right = self.evaluate(right, locals)?;
+                Self::binary_operation(operator, left, right)
+            }
+            Expression::Call { callee, args, .. } | Expression::Comptime { callee, args } => {
+                let args = args
+                    .iter()
+                    .map(|arg| self.evaluate(arg, locals))
+                    .collect::<Result<Vec<_>, _>>()?;
+                self.call(callee, args)
+            }
+            Expression::Tuple(elements) => elements
+                .iter()
+                .map(|element| self.evaluate(element, locals))
+                .collect::<Result<_, _>>()
+                .map(Value::Tuple),
+            Expression::Record(fields) => fields
+                .iter()
+                .map(|(label, value)| Ok((label.clone(), self.evaluate(value, locals)?)))
+                .collect::<Result<_, String>>()
+                .map(Value::Record),
+            Expression::ArrayLiteral(elements) => elements
+                .iter()
+                .map(|element| self.evaluate(element, locals))
+                .collect::<Result<_, _>>()
+                .map(Value::Array),
+            Expression::Index { base, index } => {
+                let base = self.evaluate(base, locals)?;
+                let index = self.evaluate(index, locals)?;
+                match (base, index) {
+                    (Value::Array(elements), Value::Int(index)) => usize::try_from(index)
+                        .ok()
+                        .and_then(|i| elements.get(i).cloned())
+                        .ok_or_else(|| {
+                            format!(
+                                "Index {} is out of bounds for an array of length {}",
+                                index,
+                                elements.len()
+                            )
+                        }),
+                    (base, _) => Err(format!("Cannot index into {:?} at compile time", base)),
+                }
+            }
+            Expression::Inout(name) => {
+                Err(format!("&{} cannot be evaluated at compile time", name))
+            }
+            Expression::Atomic { operation, .. } => Err(format!(
+                "{} cannot be evaluated at compile time",
+                operation.name()
+            )),
+            Expression::Random { operation, .. } => Err(format!(
+                "{} cannot be evaluated at compile time",
+                operation.name()
+            )),
+            Expression::Config(key) => Err(format!(
+                "config(\"{}\") cannot be evaluated at compile time",
+                key
+            )),
+            Expression::Member { base, name } => self.evaluate_member(base, name, locals),
+            // 構造体や列挙型の値はまだコンパイル時に表せない
+            Expression::StructLiteral { .. } | Expression::EnumCase { .. } => {
+                Err(format!("{} cannot be evaluated at compile time", expr))
+            }
+            Expression::Match { value, arms } => self.evaluate_match(value, arms, locals),
+            // Optional の値はまだコンパイル時に表せない
+            Expression::Nil | Expression::Unwrap(_) | Expression::Coalesce { .. } => {
+                Err(format!("{} cannot be evaluated at compile time", expr))
+            }
+            // 非同期メソッドはコンパイル時には走らない
+            Expression::Await(_) => Err(format!("{} cannot be evaluated at compile time", expr)),
+            Expression::Conversion { conversion, value } => self
+                .evaluate(value, locals)
+                .and_then(|value| Self::convert(*conversion, value)),
+        }
+    }
+
+    /// Applies a conversion, formatting values as the runtime does
+    fn convert(conversion: Conversion, value: Value) -> Result<Value, String> {
+        match (conversion, value) {
+            (Conversion::IntToString | Conversion::Interpolate, Value::Int(value)) => {
+                Ok(Value::String(value.to_string()))
+            }
+            (Conversion::Interpolate, Value::Float(value)) => {
+                let mut buf = [0; string::FLOAT_TEXT_CAPACITY];
+                Ok(Value::String(
+                    string::format_float(value, &mut buf).to_string(),
+                ))
+            }
+            (Conversion::Interpolate, Value::Bool(value)) => Ok(Value::String(value.to_string())),
+            (Conversion::Interpolate, Value::String(value)) => Ok(Value::String(value)),
+            // Optional の値はまだコンパイル時に表せない
+            (conversion, value) => Err(format!(
+                "{:?}.{}() cannot be evaluated at compile time",
+                value,
+                conversion.name()
+            )),
+        }
+    }
+
+    /// Evaluates `base.name`. Only the `length` of a string, in UTF-8 bytes as
+    /// the generated code counts it, is known at compile time.
+    fn evaluate_member(
+        &mut self,
+        base: &Expression,
+        name: &str,
+        locals: &HashMap<String, Value>,
+    ) -> Result<Value, String> {
+        // 構造体の値はまだコンパイル時に表せない
+        if name != "length" {
+            return Err(format!(
+                "{}.{} cannot be evaluated at compile time",
+                base, name
+            ));
+        }
+        match self.evaluate(base, locals)? {
+            Value::String(text) => Ok(Value::Int(text.len() as i32)),
+            value => Err(format!("{:?} has no length at compile time", value)),
+        }
+    }
+
+    /// Applies a binary operator with the same semantics as the generated
+    /// code: 32-bit wrapping integers and IEEE doubles
+    fn binary_operation(operator: &Operator, left: Value, right: Value) -> Result<Value, String> {
+        match (left, right) {
+            (Value::Int(left), Value::Int(right)) => match operator {
+                Operator::Add => Ok(Value::Int(left.wrapping_add(right))),
+                Operator::Subtract => Ok(Value::Int(left.wrapping_sub(right))),
+                Operator::Multiply => Ok(Value::Int(left.wrapping_mul(right))),
+                Operator::Divide => left
+                    .checked_div(right)
+                    .map(Value::Int)
+                    .ok_or_else(|| format!("Integer division {} / {} is undefined", left, right)),
+                comparison => Ok(Value::Bool(Self::compare(
+                    comparison,
+                    left.partial_cmp(&right),
+                ))),
+            },
+            (Value::Float(left), Value::Float(right)) => Ok(match operator {
+                Operator::Add => Value::Float(left + right),
+                Operator::Subtract => Value::Float(left - right),
+                Operator::Multiply => Value::Float(left * right),
+                Operator::Divide => Value::Float(left / right),
+                comparison => Value::Bool(Self::compare(comparison, left.partial_cmp(&right))),
+            }),
+            (Value::Bool(left), Value::Bool(right))
+                if matches!(operator, Operator::Equal | Operator::NotEqual) =>
+            {
+                Ok(Value::Bool(Self::compare(
+                    operator,
+                    left.partial_cmp(&right),
+                )))
+            }
+            (Value::String(left), Value::String(right)) if *operator == Operator::Add => {
+                Ok(Value::String(left + &right))
+            }
+            // 生成コードと同じくバイト列として比べる
+            (Value::String(left), Value::String(right))
+                if matches!(operator, Operator::Equal | Operator::NotEqual) =>
+            {
+                Ok(Value::Bool(Self::compare(
+                    operator,
+                    left.as_bytes().partial_cmp(right.as_bytes()),
+                )))
+            }
+            (left, right) => Err(format!(
+                "Invalid operands {:?} and {:?} at compile time",
+                left, right
+            )),
+        }
+    }
+
+    /// Whether `comparison` holds for operands ordered as `ordering`. Unordered
+    /// operands (NaN) are only unequal, as with LLVM's `une` and ordered predicates.
+    fn compare(comparison: &Operator, ordering: Option<Ordering>) -> bool {
+        match comparison {
+            Operator::Equal => ordering == Some(Ordering::Equal),
+            Operator::NotEqual => ordering != Some(Ordering::Equal),
+            Operator::Less => ordering == Some(Ordering::Less),
+            Operator::LessEqual => matches!(ordering, Some(Ordering::Less | Ordering::Equal)),
+            Operator::Greater => ordering == Some(Ordering::Greater),
+            Operator::GreaterEqual => {
+                matches!(ordering, Some(Ordering::Greater | Ordering::Equal))
+            }
+            Operator::Add | Operator::Subtract | Operator::Multiply | Operator::Divide => false,
+        }
+    }
+}
+
+/// Returns a copy of `actor` in which every `comptime` call is replaced by its result
+pub fn fold_actor(actor: &Actor) -> Result<Actor, String> {
+    let mut evaluator = ConstEvaluator::new(actor);
+    let mut folded = actor.clone();
+
+    for method in &mut folded.methods {
+        if let Some(body) = &mut method.body {
+            fold_body(&mut evaluator, body)
+                .map_err(|e| format!("in method {}: {}", method.name, e))?;
+        }
+    }
+    if let Some(init) = &mut folded.init {
+        fold_body(&mut evaluator, &mut init.body).map_err(|e| format!("in init: {}", e))?;
+    }
+    if let Some(deinit) = &mut folded.deinit {
+        fold_body(&mut evaluator, &mut deinit.body).map_err(|e| format!("in deinit: {}", e))?;
+    }
+    Ok(folded)
+}
+
+fn fold_body(evaluator: &mut ConstEvaluator, body: &mut MethodBody) -> Result<(), String> {
+    for statement in &mut body.statements {
+        match statement {
+            Statement::Return(expr)
+            | Statement::Expression(expr)
+            | Statement::Yield(expr)
+            | Statement::Let { value: expr, .. }
+            | Statement::Log { message: expr, .. }
+            | Statement::Publish { value: expr, .. }
+            | Statement::Assign { value: expr, .. } => fold_expression(evaluator, expr)?,
+            Statement::If {
+                condition,
+                then_body,
+                else_body,
+            } => {
+                fold_expression(evaluator, condition)?;
+                fold_body(evaluator, then_body)?;
+                if let Some(else_body) = else_body {
+                    fold_body(evaluator, else_body)?;
+                }
+            }
+            Statement::While {
+                condition, body, ..
+            } => {
+                fold_expression(evaluator, condition)?;
+                fold_body(evaluator, body)?;
+            }
+            Statement::For {
+                start, end, body, ..
+            } => {
+                fold_expression(evaluator, start)?;
+                fold_expression(evaluator, end)?;
+                fold_body(evaluator, body)?;
+            }
+            Statement::Match { value, arms } => {
+                fold_expression(evaluator, value)?;
+                for (_, body) in arms {
+                    fold_body(evaluator, body)?;
+                }
+            }
+            Statement::Break(_) | Statement::Continue(_) | Statement::Subscribe(_) => {}
+        }
+    }
+    Ok(())
+}
+
+fn fold_expression(evaluator: &mut ConstEvaluator, expr: &mut Expression) -> Result<(), String> {
+    match expr {
+        Expression::Comptime { callee, args } => {
+            let value = evaluator
+                .evaluate_call(callee, args)
+                .map_err(|e| format!("comptime {}: {}", callee, e))?;
+            *expr = value.to_expression();
+            Ok(())
+        }
+        Expression::BinaryOp { left, right, .. }
+        | Expression::Index {
+            base: left,
+            index: right,
+        }
+        | Expression::Coalesce {
+            value: left,
+            default: right,
+        } => {
+            fold_expression(evaluator, left)?;
+            fold_expression(evaluator, right)
+        }
+        Expression::Call { args, .. }
+        | Expression::Atomic { args, .. }
+        | Expression::Random { args, .. }
+        | Expression::EnumCase { args, .. }
+        | Expression::Tuple(args)
+        | Expression::ArrayLiteral(args) => args
+            .iter_mut()
+            .try_for_each(|arg| fold_expression(evaluator, arg)),
+        Expression::Match { value, arms } => {
+            fold_expression(evaluator, value)?;
+            arms.iter_mut()
+                .try_for_each(|(_, result)| fold_expression(evaluator, result))
+        }
+        Expression::Record(fields) | Expression::StructLiteral { fields, .. } => fields
+            .iter_mut()
+            .try_for_each(|(_, value)| fold_expression(evaluator, value)),
+        Expression::Conversion { value, .. }
+        | Expression::Member { base: value, .. }
+        | Expression::Unwrap(value)
+        | Expression::Await(value) => fold_expression(evaluator, value),
+        Expression::Literal(_)
+        | Expression::Variable(_)
+        | Expression::Field(_)
+        | Expression::Inout(_)
+        | Expression::Config(_)
+        | Expression::Nil => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::{ActorType, OwnershipType, Parameter, Type};
+
+    fn int(value: i32) -> Expression {
+        Expression::Literal(LiteralValue::Int(value))
+    }
+
+    fn variable(name: &str) -> Expression {
+        Expression::Variable(name.to_string())
+    }
+
+    fn binary(left: Expression, operator: Operator, right: Expression) -> Expression {
+        Expression::BinaryOp {
+            left: Box::new(left),
+            operator,
+            right: Box::new(right),
+        }
+    }
+
+    fn method(name: &str, is_pure: bool, params: &[&str], statements: Vec<Statement>) -> Method {
+        Method {
+            name: name.to_string(),
+            is_async: false,
+            is_sequential: false,
+            is_immediate: false,
+            is_pure,
+            is_nonreentrant: false,
+            shard_key: None,
+            retry: None,
+            is_idempotent: false,
+            since: None,
+            removed: None,
+            params: params
+                .iter()
+                .map(|param| Parameter {
+                    name: param.to_string(),
+                    param_type: Type::Int,
+                    ownership: OwnershipType::Owned,
+                })
+                .collect(),
+            return_type: Some(Type::Int),
+            body: Some(MethodBody { statements }),
+            doc: None,
+        }
+    }
+
+    fn actor(methods: Vec<Method>) -> Actor {
+        Actor {
+            name: "Config".to_string(),
+            actor_type: ActorType::Single,
+            methods,
+            fields: vec![],
+            init: None,
+            deinit: None,
+            layout: Default::default(),
+            externs: vec![],
+            imports: vec![],
+            topics: vec![],
+            pools: vec![],
+            structs: vec![],
+            enums: vec![],
+            constants: vec![],
+            protocol: None,
+            doc: None,
+        }
+    }
+
+    fn comptime(callee: &str, args: Vec<Expression>) -> Expression {
+        Expression::Comptime {
+            callee: callee.to_string(),
+            args,
+        }
+    }
+
+    #[test]
+    fn test_fold_lookup_table() {
+        // pure func square(n) { return n * n }
+        // pure func table(base) { let (a, b) = (square(base), square(base + 1)); return [a, b, base] }
+        let square = method(
+            "square",
+            true,
+            &["n"],
+            vec![Statement::Return(binary(
+                variable("n"),
+                Operator::Multiply,
+                variable("n"),
+            ))],
+        );
+        let call = |arg| Expression::Call {
+            callee: "square".to_string(),
+            type_args: Vec::new(),
+            args: vec![arg],
+        };
+        let table = method(
+            "table",
+            true,
+            &["base"],
+            vec![
+                Statement::Let {
+                    pattern: Pattern::Tuple(vec![
+                        Pattern::Binding("a".to_string()),
+                        Pattern::Binding("b".to_string()),
+                    ]),
+                    is_mutable: false,
+                    value: Expression::Tuple(vec![
+                        call(variable("base")),
+                        call(binary(variable("base"), Operator::Add, int(1))),
+                    ]),
+                },
+                Statement::Return(Expression::ArrayLiteral(vec![
+                    variable("a"),
+                    variable("b"),
+                    variable("base"),
+                ])),
+            ],
+        );
+        let lookup = method(
+            "lookup",
+            false,
+            &[],
+            vec![Statement::Return(Expression::Index {
+                base: Box::new(comptime("table", vec![int(3)])),
+                index: Box::new(int(1)),
+            })],
+        );
+
+        let folded = fold_actor(&actor(vec![square, table, lookup])).unwrap();
+        let body = folded.methods[2].body.as_ref().unwrap();
+        match &body.statements[0] {
+            Statement::Return(Expression::Index { base, .. }) => match base.as_ref() {
+                Expression::ArrayLiteral(elements) => assert!(matches!(
+                    elements.as_slice(),
+                    [
+                        Expression::Literal(LiteralValue::Int(9)),
+                        Expression::Literal(LiteralValue::Int(16)),
+                        Expression::Literal(LiteralValue::Int(3)),
+                    ]
+                )),
+                other => panic!("comptime call was not folded: {:?}", other),
+            },
+            other => panic!("unexpected statement: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_evaluate_if_branches() {
+        // pure func pick(flag) { if flag { return 1 } else { let two = 2; return two } }
+        let pick = method(
+            "pick",
+            true,
+            &["flag"],
+            vec![Statement::If {
+                condition: variable("flag"),
+                then_body: MethodBody {
+                    statements: vec![Statement::Return(int(1))],
+                },
+                else_body: Some(MethodBody {
+                    statements: vec![
+                        Statement::Let {
+                            pattern: Pattern::Binding("two".to_string()),
+                            is_mutable: false,
+                            value: int(2),
+                        },
+                        Statement::Return(variable("two")),
+                    ],
+                }),
+            }],
+        );
+        let actor = actor(vec![pick]);
+        let mut evaluator = ConstEvaluator::new(&actor);
+        let flag = |value| Expression::Literal(LiteralValue::Bool(value));
+
+        assert_eq!(
+            evaluator.evaluate_call("pick", &[flag(true)]),
+            Ok(Value::Int(1))
+        );
+        assert_eq!(
+            evaluator.evaluate_call("pick", &[flag(false)]),
+            Ok(Value::Int(2))
+        );
+        assert!(evaluator
+            .evaluate_call("pick", &[int(1)])
+            .unwrap_err()
+            .contains("is not a Bool"));
+    }
+
+    #[test]
+    fn test_evaluate_loops() {
+        let body = |statements| MethodBody { statements };
+        // pure func jumps(n) {
+        //     outer: for i in 2..n {
+        //         for j in 0..i { continue outer }
+        //         return i * i
+        //     }
+        //     for k in 0..n { break }
+        //     return n
+        // }
+        let jumps = method(
+            "jumps",
+            true,
+            &["n"],
+            vec![
+                Statement::For {
+                    label: Some("outer".to_string()),
+                    variable: "i".to_string(),
+                    start: int(2),
+                    end: variable("n"),
+                    body: body(vec![
+                        Statement::For {
+                            label: None,
+                            variable: "j".to_string(),
+                            start: int(0),
+                            end: variable("i"),
+                            body: body(vec![Statement::Continue(Some("outer".to_string()))]),
+                        },
+                        Statement::Return(binary(variable("i"), Operator::Multiply, variable("i"))),
+                    ]),
+                },
+                Statement::For {
+                    label: None,
+                    variable: "k".to_string(),
+                    start: int(0),
+                    end: variable("n"),
+                    body: body(vec![Statement::Break(None)]),
+                },
+                Statement::Return(variable("n")),
+            ],
+        );
+        // pure func spin(flag) { while flag { } return 0 }
+        let spin = method(
+            "spin",
+            true,
+            &["flag"],
+            vec![
+                Statement::While {
+                    label: None,
+                    condition: variable("flag"),
+                    body: body(vec![]),
+                },
+                Statement::Return(int(0)),
+            ],
+        );
+        let actor = actor(vec![jumps, spin]);
+        let mut evaluator = ConstEvaluator::new(&actor);
+        let flag = |value| Expression::Literal(LiteralValue::Bool(value));
+
+        // continue outer により内側のループは外側の反復を飛ばす
+        assert_eq!(
+            evaluator.evaluate_call("jumps", &[int(5)]),
+            Ok(Value::Int(5))
+        );
+        assert_eq!(
+            evaluator.evaluate_call("spin", &[flag(false)]),
+            Ok(Value::Int(0))
+        );
+        assert!(evaluator
+            .evaluate_call("spin", &[flag(true)])
+            .unwrap_err()
+            .contains("did not finish"));
+        assert!(evaluator
+            .evaluate_call("spin", &[int(1)])
+            .unwrap_err()
+            .contains("is not a Bool"));
+    }
+
+    #[test]
+    fn test_evaluate_assignments() {
+        let body = |statements| MethodBody { statements };
+        let binding = |name: &str, is_mutable, value| Statement::Let {
+            pattern: Pattern::Binding(name.to_string()),
+            is_mutable,
+            value,
+        };
+        let assign = |name: &str, operator, value| Statement::Assign {
+            target: variable(name),
+            operator,
+            value,
+        };
+        // pure func sum(n) {
+        //     var total = 0
+        //     for i in 0..n { let total = i; total += 100 }
+        //     for i in 0..n { if i > 1 { total += i } else { total -= 1 } }
+        //     total *= 2
+        //     return total
+        // }
+        let sum = method(
+            "sum",
+            true,
+            &["n"],
+            vec![
+                binding("total", true, int(0)),
+                Statement::For {
+                    label: None,
+                    variable: "i".to_string(),
+                    start: int(0),
+                    end: variable("n"),
+                    body: body(vec![
+                        binding("total", true, variable("i")),
+                        assign("total", Some(Operator::Add), int(100)),
+                    ]),
+                },
+                Statement::For {
+                    label: None,
+                    variable: "i".to_string(),
+                    start: int(0),
+                    end: variable("n"),
+                    body: body(vec![Statement::If {
+                        condition: binary(variable("i"), Operator::Greater, int(1)),
+                        then_body: body(vec![assign("total", Some(Operator::Add), variable("i"))]),
+                        else_body: Some(body(vec![assign(
+                            "total",
+                            Some(Operator::Subtract),
+                            int(1),
+                        )])),
+                    }]),
+                },
+                assign("total", Some(Operator::Multiply), int(2)),
+                Statement::Return(variable("total")),
+            ],
+        );
+        let actor = actor(vec![sum]);
+        let mut evaluator = ConstEvaluator::new(&actor);
+
+        // 内側の let total は外側の total を変えず、ループ内の代入は残る
+        // (-1 - 1 + 2 + 3) * 2
+        assert_eq!(evaluator.evaluate_call("sum", &[int(4)]), Ok(Value::Int(6)));
+    }
+
+    #[test]
+    fn test_evaluate_comparisons() {
+        // pure func clamp(n) { if n > 10 { return 10 } else if n <= 0 { return 0 } return n }
+        let clamp = method(
+            "clamp",
+            true,
+            &["n"],
+            vec![
+                Statement::If {
+                    condition: binary(variable("n"), Operator::Greater, int(10)),
+                    then_body: MethodBody {
+                        statements: vec![Statement::Return(int(10))],
+                    },
+                    else_body: Some(MethodBody {
+                        statements: vec![Statement::If {
+                            condition: binary(variable("n"), Operator::LessEqual, int(0)),
+                            then_body: MethodBody {
+                                statements: vec![Statement::Return(int(0))],
+                            },
+                            else_body: None,
+                        }],
+                    }),
+                },
+                Statement::Return(variable("n")),
+            ],
+        );
+        let actor = actor(vec![clamp]);
+        let mut evaluator = ConstEvaluator::new(&actor);
+        for (n, clamped) in [(42, 10), (-3, 0), (0, 0), (7, 7)] {
+            assert_eq!(
+                evaluator.evaluate_call("clamp", &[int(n)]),
+                Ok(Value::Int(clamped))
+            );
+        }
+
+        let nan = Value::Float(f64::NAN);
+        let compare = |operator| {
+            ConstEvaluator::binary_operation(&operator, nan.clone(), nan.clone()).unwrap()
+        };
+        assert_eq!(compare(Operator::Equal), Value::Bool(false));
+        assert_eq!(compare(Operator::NotEqual), Value::Bool(true));
+        assert_eq!(compare(Operator::GreaterEqual), Value::Bool(false));
+    }
+
+    #[test]
+    fn test_evaluate_conversions() {
+        let convert = |conversion, value| Expression::Conversion {
+            conversion,
+            value: Box::new(value),
+        };
+        // pure func label(n) { return (n * 2).toString() }
+        let label = method(
+            "label",
+            true,
+            &["n"],
+            vec![Statement::Return(convert(
+                Conversion::IntToString,
+                binary(variable("n"), Operator::Multiply, int(2)),
+            ))],
+        );
+        let actor = actor(vec![label]);
+        let mut evaluator = ConstEvaluator::new(&actor);
+        assert_eq!(
+            evaluator.evaluate_call("label", &[int(-21)]),
+            Ok(Value::String("-42".to_string()))
+        );
+
+        let parsed = convert(
+            Conversion::StringToInt,
+            Expression::Literal(LiteralValue::String("1".to_string())),
+        );
+        assert!(evaluator
+            .evaluate(&parsed, &HashMap::new())
+            .unwrap_err()
+            .contains("toInt() cannot be evaluated at compile time"));
+
+        // 補間はランタイムと同じ書式で文字列にする
+        for (value, text) in [
+            (int(-7), "-7"),
+            (
+                Expression::Literal(LiteralValue::Float(0.1 + 0.2)),
+                "0.30000000000000004",
+            ),
+            (binary(int(1), Operator::Less, int(2)), "true"),
+            (
+                Expression::Literal(LiteralValue::String("é".to_string())),
+                "é",
+            ),
+        ] {
+            assert_eq!(
+                evaluator.evaluate(&convert(Conversion::Interpolate, value), &HashMap::new()),
+                Ok(Value::String(text.to_string()))
+            );
+        }
+    }
+
+    #[test]
+    fn test_evaluate_strings() {
+        let text = |value: &str| Expression::Literal(LiteralValue::String(value.to_string()));
+        let actor = actor(vec![]);
+        let mut evaluator = ConstEvaluator::new(&actor);
+        let mut evaluate = |expr| evaluator.evaluate(&expr, &HashMap::new());
+
+        let greeting = binary(text("héllo, "), Operator::Add, text("world"));
+        assert_eq!(
+            evaluate(greeting.clone()),
+            Ok(Value::String("héllo, world".to_string()))
+        );
+        // length はバイト数
+        let length = Expression::Member {
+            base: Box::new(greeting),
+            name: "length".to_string(),
+        };
+        assert_eq!(evaluate(length), Ok(Value::Int(13)));
+        assert_eq!(
+            evaluate(binary(text("a"), Operator::Equal, text("a"))),
+            Ok(Value::Bool(true))
+        );
+        assert_eq!(
+            evaluate(binary(text("a"), Operator::NotEqual, text("a"))),
+            Ok(Value::Bool(false))
+        );
+        assert!(evaluate(binary(text("a"), Operator::Less, text("b"))).is_err());
+    }
+
+    #[test]
+    fn test_evaluation_errors() {
+        let divide = method(
+            "divide",
+            true,
+            &["n"],
+            vec![Statement::Return(binary(
+                int(10),
+                Operator::Divide,
+                variable("n"),
+            ))],
+        );
+        let forever = method(
+            "forever",
+            true,
+            &["n"],
+            vec![Statement::Return(Expression::Call {
+                callee: "forever".to_string(),
+                type_args: Vec::new(),
+                args: vec![variable("n")],
+            })],
+        );
+        let impure = method("impure", false, &[], vec![Statement::Return(int(1))]);
+        let actor = actor(vec![divide, forever, impure]);
+        let mut evaluator = ConstEvaluator::new(&actor);
+
+        assert_eq!(
+            evaluator.evaluate_call("divide", &[int(2)]),
+            Ok(Value::Int(5))
+        );
+        assert!(evaluator
+            .evaluate_call("divide", &[int(0)])
+            .unwrap_err()
+            .contains("is undefined"));
+        assert!(evaluator
+            .evaluate_call("forever", &[int(1)])
+            .unwrap_err()
+            .contains("nested deeper"));
+        assert!(evaluator
+            .evaluate_call("impure", &[])
+            .unwrap_err()
+            .contains("not a pure method"));
+    }
+}
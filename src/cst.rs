@@ -0,0 +1,346 @@
+//! Lossless concrete syntax tree (CST).
+//!
+//! The AST drops whitespace, comments and positions. For the formatter,
+//! refactorings and diagnostics that point at exact source ranges, [`parse`]
+//! also builds a tree whose leaves are every token *and* every run of
+//! whitespace or comment between them, so the text of the tree is the source
+//! byte for byte.
+//!
+//! The tree is produced by the same parser as the AST: when asked to, the
+//! parser records where each node starts and ends as token indices, and the
+//! tree is assembled afterwards from those events and the token spans. The
+//! plain compile path records nothing. Nodes of a kind appear in the same
+//! order as the AST items they were parsed into, e.g. the `Method` nodes of an
+//! `Actor` match `Actor::methods`.
+
+use crate::ast::Actor;
+use crate::lexer::{self, LexError};
+use crate::parser::{ParseError, Parser};
+use std::ops::Range;
+use thiserror::Error;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SyntaxKind {
+    /// The whole file, including anything after the actor
+    Root,
+    /// `import Module` line before the actor
+    Import,
+    /// `topic Name: Type` line before the actor
+    Topic,
+    /// `pool Name of Actor(...)` line before the actor
+    Pool,
+    /// `struct Name { ... }` declaration before the actor
+    Struct,
+    /// `enum Name { case ... }` declaration before the actor
+    Enum,
+    Actor,
+    /// `@name` or `@name(argument)`
+    Attribute,
+    /// Field declaration with its attributes
+    Field,
+    Method,
+    Parameter,
+    Type,
+    /// `init(...) { ... }` with its parameters
+    Init,
+    Deinit,
+    /// `extern type` or `extern func` with its attributes
+    Extern,
+    /// `const NAME: [T] = [...]` table of literals
+    Constant,
+    /// `{ ... }` body of a method, init, deinit, branch or loop
+    Block,
+    Statement,
+    Expression,
+    /// Spaces and line breaks
+    Whitespace,
+    /// `// ...` up to, but excluding, the line break
+    Comment,
+    /// Any token the parser sees
+    Token,
+}
+
+impl SyntaxKind {
+    /// Whether tokens of this kind are invisible to the parser
+    pub fn is_trivia(self) -> bool {
+        matches!(self, SyntaxKind::Whitespace | SyntaxKind::Comment)
+    }
+}
+
+/// Node boundary recorded by the parser. `at` is the index of the first token
+/// after the boundary.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    Start { kind: SyntaxKind, at: usize },
+    Finish { at: usize },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SyntaxToken {
+    pub kind: SyntaxKind,
+    /// Byte range in the source
+    pub range: Range<usize>,
+    pub text: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SyntaxElement {
+    Node(SyntaxNode),
+    Token(SyntaxToken),
+}
+
+/// Node of the tree. Its range spans its first to last token; whitespace and
+/// comments before its first token belong to the parent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SyntaxNode {
+    pub kind: SyntaxKind,
+    pub range: Range<usize>,
+    pub children: Vec<SyntaxElement>,
+}
+
+impl SyntaxNode {
+    /// Source text covered by the node, trivia inside it included
+    pub fn text(&self) -> String {
+        let mut text = String::with_capacity(self.range.len());
+        self.write_text(&mut text);
+        text
+    }
+
+    fn write_text(&self, text: &mut String) {
+        for child in &self.children {
+            match child {
+                SyntaxElement::Node(node) => node.write_text(text),
+                SyntaxElement::Token(token) => text.push_str(&token.text),
+            }
+        }
+    }
+
+    /// Child nodes, without tokens
+    pub fn child_nodes(&self) -> impl Iterator<Item = &SyntaxNode> {
+        self.children.iter().filter_map(|child| match child {
+            SyntaxElement::Node(node) => Some(node),
+            SyntaxElement::Token(_) => None,
+        })
+    }
+
+    /// Tokens directly under this node, trivia included
+    pub fn tokens(&self) -> impl Iterator<Item = &SyntaxToken> {
+        self.children.iter().filter_map(|child| match child {
+            SyntaxElement::Node(_) => None,
+            SyntaxElement::Token(token) => Some(token),
+        })
+    }
+
+    /// Nodes of `kind` at or below this one, in source order
+    pub fn descendants(&self, kind: SyntaxKind) -> Vec<&SyntaxNode> {
+        let mut nodes = Vec::new();
+        self.collect(kind, &mut nodes);
+        nodes
+    }
+
+    fn collect<'a>(&'a self, kind: SyntaxKind, nodes: &mut Vec<&'a SyntaxNode>) {
+        if self.kind == kind {
+            nodes.push(self);
+        }
+        for node in self.child_nodes() {
+            node.collect(kind, nodes);
+        }
+    }
+
+    /// Innermost node whose range contains `offset`, or this node if none does
+    pub fn covering_node(&self, offset: usize) -> &SyntaxNode {
+        self.child_nodes()
+            .find(|node| node.range.contains(&offset))
+            .map_or(self, |node| node.covering_node(offset))
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum SyntaxError {
+    #[error("Lexer error at {0}")]
+    Lex(#[from] LexError),
+    #[error("Parser error: {0}")]
+    Parse(#[from] ParseError),
+}
+
+/// Parses `source` into its actor and the lossless tree of the whole file
+pub fn parse(source: &str) -> Result<(Actor, SyntaxNode), SyntaxError> {
+    let tokens = lexer::tokenize_with_spans(source)?;
+    let spans: Vec<Range<usize>> = tokens.iter().map(|(_, span)| span.clone()).collect();
+
+    let mut parser = Parser::with_lines(lexer::token_lines(source, tokens))
+        .with_docs(lexer::doc_comments(source))
+        .record_events();
+    let actor = parser.parse_actor()?;
+
+    let mut builder = TreeBuilder {
+        source,
+        spans: &spans,
+        next: 0,
+        offset: 0,
+        stack: vec![SyntaxNode {
+            kind: SyntaxKind::Root,
+            range: 0..source.len(),
+            children: Vec::new(),
+        }],
+    };
+    for event in parser.take_events() {
+        builder.apply(event);
+    }
+    Ok((actor, builder.finish()))
+}
+
+/// Replays parser events over the token spans, filling in the trivia between
+struct TreeBuilder<'a> {
+    source: &'a str,
+    spans: &'a [Range<usize>],
+    /// Index of the next token to place
+    next: usize,
+    /// End of the source text placed so far
+    offset: usize,
+    /// Open nodes, the root first
+    stack: Vec<SyntaxNode>,
+}
+
+impl TreeBuilder<'_> {
+    fn apply(&mut self, event: Event) {
+        match event {
+            Event::Start { kind, at } => {
+                // 先頭トークンの前の空白やコメントは親に置く
+                self.tokens_until(at);
+                self.trivia_until(self.spans.get(at).map_or(self.source.len(), |s| s.start));
+                self.stack.push(SyntaxNode {
+                    kind,
+                    range: self.offset..self.offset,
+                    children: Vec::new(),
+                });
+            }
+            Event::Finish { at } => {
+                self.tokens_until(at);
+                let mut node = self.stack.pop().expect("parser events are balanced");
+                node.range.end = self.offset;
+                self.push(SyntaxElement::Node(node));
+            }
+        }
+    }
+
+    fn finish(mut self) -> SyntaxNode {
+        self.tokens_until(self.spans.len());
+        self.trivia_until(self.source.len());
+        let root = self.stack.remove(0);
+        debug_assert!(self.stack.is_empty(), "parser events are balanced");
+        root
+    }
+
+    fn push(&mut self, element: SyntaxElement) {
+        if let Some(parent) = self.stack.last_mut() {
+            parent.children.push(element);
+        }
+    }
+
+    fn push_token(&mut self, kind: SyntaxKind, range: Range<usize>) {
+        let text = self.source[range.clone()].to_string();
+        self.offset = range.end;
+        self.push(SyntaxElement::Token(SyntaxToken { kind, range, text }));
+    }
+
+    /// Places the tokens before index `end`, each after its leading trivia
+    fn tokens_until(&mut self, end: usize) {
+        while self.next < end.min(self.spans.len()) {
+            let span = self.spans[self.next].clone();
+            self.trivia_until(span.start);
+            self.push_token(SyntaxKind::Token, span);
+            self.next += 1;
+        }
+    }
+
+    /// Splits the text between the last placed token and `end` into runs of
+    /// whitespace and comments
+    fn trivia_until(&mut self, end: usize) {
+        while self.offset < end {
+            let rest = &self.source[self.offset..end];
+            let (kind, len) = match lexer::line_comment(rest) {
+                Ok((after, _)) => (SyntaxKind::Comment, rest.len() - after.len()),
+                Err(_) => (
+                    SyntaxKind::Whitespace,
+                    rest.find("//").unwrap_or(rest.len()),
+                ),
+            };
+            self.push_token(kind, self.offset..self.offset + len);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOURCE: &str = "// counter.replica\n\
+        @packed actor Counter {\n\
+        \x20   @offset(0) var count: Int  // running total\n\
+        \n\
+        \x20   func add(amount: Int, scale: Int?) -> Int {\n\
+        \x20       count += amount // adds\n\
+        \x20       return count\n\
+        \x20   }\n\
+        }\n";
+
+    #[test]
+    fn test_tree_keeps_every_byte() {
+        let (actor, tree) = parse(SOURCE).unwrap();
+        assert_eq!(tree.text(), SOURCE);
+        assert_eq!(tree.range, 0..SOURCE.len());
+
+        // ファイル先頭のコメントはアクターではなくルートに属する
+        let comment = tree.tokens().next().unwrap();
+        assert_eq!(comment.kind, SyntaxKind::Comment);
+        assert_eq!(comment.text, "// counter.replica");
+        let [actor_node] = tree.descendants(SyntaxKind::Actor)[..] else {
+            panic!("expected one actor node");
+        };
+        assert!(actor_node.text().starts_with("@packed actor Counter {"));
+        assert!(actor_node.text().ends_with('}'));
+
+        // ノードは AST の要素と同じ順序で並ぶ
+        let fields = tree.descendants(SyntaxKind::Field);
+        assert_eq!(fields.len(), actor.fields.len());
+        assert_eq!(fields[0].text(), "@offset(0) var count: Int");
+        let methods = tree.descendants(SyntaxKind::Method);
+        assert_eq!(methods.len(), actor.methods.len());
+        let parameters: Vec<String> = methods[0]
+            .descendants(SyntaxKind::Parameter)
+            .iter()
+            .map(|node| node.text())
+            .collect();
+        assert_eq!(parameters, ["amount: Int", "scale: Int?"]);
+        let statements = methods[0].descendants(SyntaxKind::Statement);
+        assert_eq!(statements.len(), 2);
+        assert_eq!(statements[0].text(), "count += amount");
+
+        for node in tree.descendants(SyntaxKind::Type) {
+            assert_eq!(node.text(), &SOURCE[node.range.clone()]);
+        }
+    }
+
+    #[test]
+    fn test_covering_node_for_diagnostics() {
+        let (_, tree) = parse(SOURCE).unwrap();
+        let offset = SOURCE.find("amount //").unwrap();
+        let node = tree.covering_node(offset);
+        assert_eq!(node.kind, SyntaxKind::Expression);
+        assert_eq!(node.text(), "amount");
+
+        // コメントは文の外側、ブロックの中
+        let offset = SOURCE.find("// adds").unwrap();
+        assert_eq!(tree.covering_node(offset).kind, SyntaxKind::Block);
+    }
+
+    #[test]
+    fn test_errors_are_reported() {
+        assert!(matches!(parse("actor A { % }"), Err(SyntaxError::Lex(_))));
+        assert!(matches!(
+            parse("actor A { func ( }"),
+            Err(SyntaxError::Parse(_))
+        ));
+    }
+}
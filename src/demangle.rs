@@ -0,0 +1,156 @@
+//! Translates qualified export names back to source names.
+//! Symbols are named `<Actor>.<method>`, keeping the entry point after it
+//! (`Counter.add.post`), but qualified export names join everything with `_`
+//! (`Counter_add_post`), so stack traces and `wasm-objdump` output of modules
+//! built with them cannot be split back into actor, method and entry point. A
+//! `Demangler` learns the names the given actors compile to and rewrites them
+//! as symbols. `replicac demangle` filters text through one, like `c++filt`.
+
+use crate::ast::Actor;
+use crate::codegen::ExportNames;
+use std::collections::HashMap;
+
+/// Entry points a method may get besides itself (see `docs/ABI.md`)
+const METHOD_ENTRY_POINTS: &[&str] = &["post", "next", "close", "remote", "deliver"];
+
+/// Entry points an actor may get, named after it
+const ACTOR_ENTRY_POINTS: &[&str] = &["new", "deliver", "drain"];
+
+/// Source names by the symbol and export names of compiled actors
+#[derive(Debug, Default)]
+pub struct Demangler {
+    names: HashMap<String, String>,
+}
+
+impl Demangler {
+    /// Learns the names of `actors`, namespaced as they were compiled (see
+    /// `package::namespace_actor`)
+    pub fn new<'a>(actors: impl IntoIterator<Item = &'a Actor>) -> Self {
+        let actors: Vec<&Actor> = actors.into_iter().collect();
+        let mut demangler = Demangler::default();
+        for actor in &actors {
+            for method in &actor.methods {
+                // 名前空間つきのメソッドは `math.length` なので、最後の部分が名前
+                let name = method.name.rsplit('.').next().unwrap_or(&method.name);
+                let symbol = format!("{}.{}", actor.name, name);
+                demangler.add(&symbol, &actor.name, &[name]);
+                for entry in METHOD_ENTRY_POINTS {
+                    let symbol = format!("{}.{}", symbol, entry);
+                    demangler.add(&symbol, &actor.name, &[name, entry]);
+                }
+            }
+            for entry in ACTOR_ENTRY_POINTS {
+                let symbol = format!("{}.{}", actor.name, entry);
+                demangler.add(&symbol, &actor.name, &[entry]);
+            }
+            // プールのルーターは、プールされるアクターのメソッドごとにある
+            for pool in &actor.pools {
+                let symbol = format!("{}.new", pool.name);
+                demangler.add(&symbol, &pool.name, &["new"]);
+                let pooled = actors.iter().find(|pooled| pooled.name == pool.actor);
+                for method in pooled.iter().flat_map(|pooled| &pooled.methods) {
+                    let name = method.name.rsplit('.').next().unwrap_or(&method.name);
+                    let symbol = format!("{}.{}", pool.name, name);
+                    demangler.add(&symbol, &pool.name, &[name]);
+                }
+            }
+        }
+        demangler
+    }
+
+    /// Maps the qualified export name of `symbol`, the member `parts` of
+    /// `actor`, back to `symbol`
+    fn add(&mut self, symbol: &str, actor: &str, parts: &[&str]) {
+        let export = ExportNames::Qualified.export_name(symbol, actor, parts);
+        self.names.insert(export, symbol.to_string());
+    }
+
+    /// Source name of a qualified export name, or `None` if it names nothing
+    /// the demangler learned or is already in source form
+    pub fn demangle(&self, name: &str) -> Option<&str> {
+        self.names.get(name).map(String::as_str)
+    }
+
+    /// Rewrites every name in `text` that [`Demangler::demangle`] knows,
+    /// leaving the rest as it is
+    pub fn demangle_text(&self, text: &str) -> String {
+        let is_name = |c: char| c.is_alphanumeric() || c == '_' || c == '.';
+        let mut demangled = String::with_capacity(text.len());
+        let mut rest = text;
+        while let Some(start) = rest.find(is_name) {
+            demangled.push_str(&rest[..start]);
+            rest = &rest[start..];
+            let end = rest.find(|c| !is_name(c)).unwrap_or(rest.len());
+            // 文末の `.` は名前に含めない
+            let run = &rest[..end];
+            let name = run.trim_end_matches('.');
+            demangled.push_str(self.demangle(name).unwrap_or(name));
+            demangled.push_str(&run[name.len()..]);
+            rest = &rest[end..];
+        }
+        demangled.push_str(rest);
+        demangled
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer;
+    use crate::package;
+    use crate::parser::Parser;
+
+    fn parse(source: &str) -> Actor {
+        let tokens = lexer::tokenize_with_lines(source).unwrap();
+        Parser::with_lines(tokens).parse_actor().unwrap()
+    }
+
+    #[test]
+    fn test_demangle() {
+        let counter = parse(
+            "pool Workers of Counter(size: 4)
+            actor Counter {
+                var value: Int
+                func add_one(amount: Int) -> Int { return amount }
+            }",
+        );
+        let vector = package::namespace_actor(
+            &parse("actor Vector { func length() -> Int { return 0 } }"),
+            "math",
+        );
+        let demangler = Demangler::new([&counter, &vector]);
+
+        // シンボルはすでにソースの名前になっている
+        assert_eq!(demangler.demangle("Counter.add_one"), None);
+        assert_eq!(
+            demangler.demangle("Counter_add_one"),
+            Some("Counter.add_one")
+        );
+        // 名前の `_` と区切りの `_` は学んだ名前から見分ける
+        assert_eq!(
+            demangler.demangle("Counter_add_one_post"),
+            Some("Counter.add_one.post")
+        );
+        assert_eq!(demangler.demangle("Counter_new"), Some("Counter.new"));
+        assert_eq!(demangler.demangle("Counter_drain"), Some("Counter.drain"));
+        assert_eq!(demangler.demangle("Counter.new"), None);
+        assert_eq!(
+            demangler.demangle("Workers_add_one"),
+            Some("Workers.add_one")
+        );
+        assert_eq!(demangler.demangle("math.Vector.length"), None);
+        assert_eq!(
+            demangler.demangle("math_Vector_length"),
+            Some("math.Vector.length")
+        );
+        assert_eq!(demangler.demangle("__replica_alloc"), None);
+
+        let trace = "at Counter_add_one_post (wasm://wasm/1a2b:wasm-function[7]:0x1f3)\n\
+                     func[9] <math_Vector_length>: calls __replica_alloc and Counter_add_one.";
+        assert_eq!(
+            demangler.demangle_text(trace),
+            "at Counter.add_one.post (wasm://wasm/1a2b:wasm-function[7]:0x1f3)\n\
+             func[9] <math.Vector.length>: calls __replica_alloc and Counter.add_one."
+        );
+    }
+}
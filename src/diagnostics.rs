@@ -0,0 +1,432 @@
+//! Rendering of compiler errors.
+//! Errors from lexing, parsing and semantic analysis become [`Report`]s: a
+//! stable code, the message, and an [`ErrorContext`] locating the offending
+//! source with an optional suggestion. They are rendered like rustc errors,
+//! with the source line and a caret underline, optionally in color.
+
+use crate::codegen::{ErrorContext, SourceLocation};
+use crate::lexer::{LexError, Token};
+use crate::parser::ParseError;
+use crate::semantic::SemanticError;
+use codespan_reporting::diagnostic::{Diagnostic, Label};
+use codespan_reporting::files::SimpleFile;
+use codespan_reporting::term::{self, termcolor::Buffer};
+use std::fmt;
+use std::io::IsTerminal;
+use std::ops::Range;
+use std::str::FromStr;
+
+/// When diagnostics are colored
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ColorChoice {
+    /// Only when stderr is a terminal and `NO_COLOR` is not set
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    /// Names accepted by `--color`
+    pub const NAMES: &'static [&'static str] = &["auto", "always", "never"];
+
+    /// Whether diagnostics written to stderr should be colored
+    pub fn enabled(self) -> bool {
+        match self {
+            ColorChoice::Auto => {
+                std::io::stderr().is_terminal() && std::env::var_os("NO_COLOR").is_none()
+            }
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+        }
+    }
+}
+
+impl FromStr for ColorChoice {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(ColorChoice::Auto),
+            "always" => Ok(ColorChoice::Always),
+            "never" => Ok(ColorChoice::Never),
+            _ => Err(format!(
+                "Unknown color choice '{}' (expected one of: {})",
+                s,
+                Self::NAMES.join(", ")
+            )),
+        }
+    }
+}
+
+impl fmt::Display for ColorChoice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ColorChoice::Auto => "auto",
+            ColorChoice::Always => "always",
+            ColorChoice::Never => "never",
+        })
+    }
+}
+
+/// A compiler error ready to be rendered against its source
+#[derive(Debug, Clone)]
+pub struct Report {
+    /// Stable error code: `lex`, `parse/<variant>`, `semantic/<variant>`,
+    /// `consteval`, `plugin` or `codegen`
+    pub code: String,
+    pub message: String,
+    /// Where the error is, with the label shown under the caret as `context`
+    pub context: ErrorContext,
+    /// Length in bytes of the underlined source
+    pub length: usize,
+}
+
+impl Report {
+    /// An error without a source location
+    pub fn new(code: impl Into<String>, message: impl Into<String>) -> Self {
+        Report {
+            code: code.into(),
+            message: message.into(),
+            context: ErrorContext::default(),
+            length: 0,
+        }
+    }
+
+    /// A lexical error, underlining the `length` bytes that could not be lexed
+    pub fn lex(file: &str, error: &LexError, length: usize) -> Self {
+        let suggestion = error
+            .message
+            .starts_with("Unterminated string")
+            .then(|| "add the closing `\"`".to_string());
+        Report {
+            code: "lex".to_string(),
+            message: error.message.clone(),
+            context: ErrorContext {
+                location: Some(SourceLocation {
+                    file: file.to_string(),
+                    line: error.line,
+                    column: error.column,
+                }),
+                context: "not a valid token".to_string(),
+                suggestion,
+            },
+            length,
+        }
+    }
+
+    /// A syntax error found at the token with index `position` in `tokens`;
+    /// unexpected ends of input point past the last token
+    pub fn parse(
+        file: &str,
+        source: &str,
+        tokens: &[(Token, Range<usize>)],
+        error: &ParseError,
+        position: Option<usize>,
+    ) -> Self {
+        let mut report = Report::new(format!("parse/{}", error.code()), error.to_string());
+        match (error, position.and_then(|index| tokens.get(index))) {
+            (ParseError::UnexpectedEOF, _) => {
+                let end = tokens.last().map_or(0, |(_, span)| span.end);
+                report.locate(file, source, end..end, "input ends here");
+                report.context.suggestion = Some("check for a missing `}` or `)`".to_string());
+            }
+            (ParseError::TooDeeplyNested(_), Some((_, span))) => {
+                report.locate(file, source, span.clone(), "nested too deeply");
+                report.context.suggestion =
+                    Some("move inner parts into `let` bindings or methods".to_string());
+            }
+            (_, Some((_, span))) => report.locate(file, source, span.clone(), "unexpected token"),
+            (_, None) => {}
+        }
+        report
+    }
+
+    /// A semantic error, located in the body of `method` (see
+    /// `SemanticAnalyzer::current_method`); undefined names and calls that
+    /// break the actor's protocol are underlined where they are used, with a
+    /// similarly named identifier as suggestion for undefined names. Unresolved
+    /// imports are underlined in their `import` line.
+    pub fn semantic(
+        file: &str,
+        source: &str,
+        tokens: &[(Token, Range<usize>)],
+        error: &SemanticError,
+        method: Option<&str>,
+    ) -> Self {
+        let mut report = Report::new(format!("semantic/{}", error.code()), error.to_string());
+        if let SemanticError::UnresolvedImport(module) = error {
+            let import = tokens.windows(2).find(|pair| {
+                pair[0].0 == Token::Import && pair[1].0 == Token::Identifier(module.clone())
+            });
+            if let Some(pair) = import {
+                report.locate(file, source, pair[1].1.clone(), "module not found");
+            }
+            return report;
+        }
+        let Some(method) = method else {
+            return report;
+        };
+        // メソッド名（init と deinit はキーワード）から本体を探す
+        let start = if method == "init" {
+            tokens.iter().position(|(token, _)| *token == Token::Init)
+        } else if method == "deinit" {
+            tokens.iter().position(|(token, _)| *token == Token::Deinit)
+        } else {
+            tokens
+                .windows(2)
+                .position(|pair| {
+                    pair[0].0 == Token::Func && pair[1].0 == Token::Identifier(method.to_string())
+                })
+                .map(|index| index + 1)
+        };
+        let Some(start) = start else {
+            return report;
+        };
+
+        if let SemanticError::UndefinedVariable(name) = error {
+            let name = name.strip_prefix("self.").unwrap_or(name);
+            let used = tokens[start + 1..]
+                .iter()
+                .find(|(token, _)| *token == Token::Identifier(name.to_string()));
+            if let Some((_, span)) = used {
+                report.locate(file, source, span.clone(), "not found in this scope");
+                report.context.suggestion = similar_identifier(tokens, name)
+                    .map(|similar| format!("did you mean `{}`?", similar));
+                return report;
+            }
+        }
+
+        if let SemanticError::ProtocolError {
+            call: Some((callee, occurrence)),
+            ..
+        } = error
+        {
+            // 宣言 `func callee(` を除いた呼び出しのうち occurrence 番目
+            let call = (start + 1..tokens.len().saturating_sub(1))
+                .filter(|&i| {
+                    tokens[i].0 == Token::Identifier(callee.clone())
+                        && tokens[i + 1].0 == Token::LParen
+                        && tokens[i - 1].0 != Token::Func
+                })
+                .nth(*occurrence);
+            if let Some(call) = call {
+                report.locate(file, source, tokens[call].1.clone(), "call out of order");
+                return report;
+            }
+        }
+
+        let label = if method == "deinit" {
+            "in this deinitializer"
+        } else {
+            "in this method"
+        };
+        report.locate(file, source, tokens[start].1.clone(), label);
+        report
+    }
+
+    /// Points the report at the bytes `span` of `source`
+    fn locate(&mut self, file: &str, source: &str, span: Range<usize>, label: &str) {
+        let before = &source[..span.start];
+        self.context.location = Some(SourceLocation {
+            file: file.to_string(),
+            line: before.matches('\n').count() + 1,
+            column: before.rsplit('\n').next().unwrap_or("").chars().count() + 1,
+        });
+        self.context.context = label.to_string();
+        self.length = span.len();
+    }
+
+    /// Renders the report with the source line it points at, in color if
+    /// `color` is set
+    pub fn render(&self, source: &str, color: bool) -> String {
+        let mut diagnostic = Diagnostic::error()
+            .with_message(&self.message)
+            .with_code(&self.code);
+        let name = match &self.context.location {
+            Some(location) => {
+                let start = offset(source, location.line, location.column);
+                let end = (start + self.length).min(source.len());
+                diagnostic = diagnostic.with_labels(vec![
+                    Label::primary((), start..end).with_message(&self.context.context)
+                ]);
+                location.file.clone()
+            }
+            None => String::new(),
+        };
+        if let Some(suggestion) = &self.context.suggestion {
+            diagnostic = diagnostic.with_notes(vec![format!("help: {}", suggestion)]);
+        }
+
+        let file = SimpleFile::new(name, source);
+        let mut buffer = if color {
+            Buffer::ansi()
+        } else {
+            Buffer::no_color()
+        };
+        // バッファへの書き込みは失敗しない
+        let _ = term::emit(&mut buffer, &term::Config::default(), &file, &diagnostic);
+        String::from_utf8_lossy(buffer.as_slice()).into_owned()
+    }
+}
+
+/// Byte offset of a 1-based line and character column in `source`
+fn offset(source: &str, line: usize, column: usize) -> usize {
+    let line_start: usize = source
+        .split_inclusive('\n')
+        .take(line.saturating_sub(1))
+        .map(str::len)
+        .sum();
+    source[line_start..]
+        .char_indices()
+        .nth(column.saturating_sub(1))
+        .map_or(source.len(), |(index, _)| line_start + index)
+}
+
+/// The identifier in `tokens` closest in spelling to `name`, if any is within
+/// two edits and shorter than the name itself
+fn similar_identifier<'a>(tokens: &'a [(Token, Range<usize>)], name: &str) -> Option<&'a str> {
+    tokens
+        .iter()
+        .filter_map(|(token, _)| match token {
+            Token::Identifier(identifier) if identifier != name => Some(identifier.as_str()),
+            _ => None,
+        })
+        .map(|identifier| (edit_distance(identifier, name), identifier))
+        .filter(|&(distance, _)| distance <= 2 && distance < name.chars().count())
+        .min_by_key(|&(distance, _)| distance)
+        .map(|(_, identifier)| identifier)
+}
+
+/// Levenshtein distance between `a` and `b`, in characters
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, &cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != cb);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer;
+    use crate::parser::Parser;
+    use crate::semantic::SemanticAnalyzer;
+
+    /// Report for the first error in `source`, rendered without color
+    fn render(source: &str) -> String {
+        let (tokens, errors) = lexer::tokenize_recovering(source);
+        let report = if let Some(error) = errors.first() {
+            let length = tokens
+                .iter()
+                .find(|(token, _)| matches!(token, Token::Error(_)))
+                .map_or(1, |(_, span)| span.len());
+            Report::lex("test.replica", error, length)
+        } else {
+            let mut parser = Parser::with_lines(lexer::token_lines(source, tokens.clone()));
+            match parser.parse_actor() {
+                Err(error) => {
+                    let position = parser.error_position(&error);
+                    Report::parse("test.replica", source, &tokens, &error, position)
+                }
+                Ok(actor) => {
+                    let mut analyzer = SemanticAnalyzer::new();
+                    let error = analyzer.analyze_actor(&actor).unwrap_err();
+                    let method = analyzer.current_method();
+                    Report::semantic("test.replica", source, &tokens, &error, method)
+                }
+            }
+        };
+        report.render(source, false)
+    }
+
+    #[test]
+    fn test_render_errors() {
+        let rendered = render("actor A {\n    var count: Int #\n}\n");
+        assert!(
+            rendered.contains("error[lex]: Unexpected character '#'"),
+            "{}",
+            rendered
+        );
+        assert!(rendered.contains("┌─ test.replica:2:20"), "{}", rendered);
+        assert!(rendered.contains("var count: Int #"), "{}", rendered);
+        assert!(rendered.contains("^ not a valid token"), "{}", rendered);
+
+        let rendered = render(
+            "actor A {\n    var count: Int\n    func get() -> Int {\n        return coutn\n    }\n}\n",
+        );
+        assert!(
+            rendered.contains("error[semantic/UndefinedVariable]"),
+            "{}",
+            rendered
+        );
+        assert!(rendered.contains("test.replica:4:16"), "{}", rendered);
+        assert!(
+            rendered.contains("^^^^^ not found in this scope"),
+            "{}",
+            rendered
+        );
+        assert!(
+            rendered.contains("= help: did you mean `count`?"),
+            "{}",
+            rendered
+        );
+
+        let rendered = render(
+            "@protocol(\"open close\")\nactor A {\n    func open() {}\n    func close() {}\n    func run() {\n        close()\n        open()\n        close()\n    }\n}\n",
+        );
+        assert!(
+            rendered.contains("error[semantic/ProtocolError]"),
+            "{}",
+            rendered
+        );
+        assert!(rendered.contains("test.replica:6:9"), "{}", rendered);
+        assert!(rendered.contains("^^^^^ call out of order"), "{}", rendered);
+
+        let rendered = render("actor A {\n    var : Int\n}\n");
+        assert!(
+            rendered.contains("error[parse/UnexpectedToken]"),
+            "{}",
+            rendered
+        );
+        assert!(rendered.contains("test.replica:2:9"), "{}", rendered);
+        assert!(rendered.contains("^ unexpected token"), "{}", rendered);
+
+        let rendered = render("actor A {\n    func get() -> Int {\n");
+        assert!(
+            rendered.contains("error[parse/UnexpectedEOF]"),
+            "{}",
+            rendered
+        );
+        assert!(rendered.contains("input ends here"), "{}", rendered);
+        assert!(
+            rendered.contains("help: check for a missing `}`"),
+            "{}",
+            rendered
+        );
+    }
+
+    #[test]
+    fn test_render_color() {
+        let report = Report::new("plugin", "rejected");
+        assert_eq!(report.render("", false), "error[plugin]: rejected\n\n");
+        assert!(report.render("", true).contains("\u{1b}["));
+        assert_eq!("never".parse(), Ok(ColorChoice::Never));
+        assert!(!ColorChoice::Never.enabled());
+        assert!("rainbow".parse::<ColorChoice>().is_err());
+    }
+
+    #[test]
+    fn test_edit_distance() {
+        assert_eq!(edit_distance("count", "coutn"), 2);
+        assert_eq!(edit_distance("", "abc"), 3);
+        assert_eq!(edit_distance("total", "total"), 0);
+    }
+}
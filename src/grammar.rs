@@ -0,0 +1,550 @@
+//! Formal grammar of Replica source files.
+//! `grammar.ebnf` describes the syntax `parser.rs` accepts as EBNF rules over
+//! the tokens of the lexer. This module reads it, recognizes token streams
+//! with it so the conformance tests can check the grammar against the parser,
+//! and converts it to a tree-sitter grammar for editors.
+
+use crate::lexer::{self, Token};
+use serde_json::{json, Value};
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::str::FromStr;
+
+/// The grammar, in EBNF
+pub const SOURCE: &str = include_str!("grammar.ebnf");
+
+/// Token class of comments, which tree-sitter skips between tokens
+const COMMENT: &str = "COMMENT";
+
+/// How the grammar is written
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum GrammarFormat {
+    #[default]
+    Ebnf,
+    /// `grammar.json` of a tree-sitter grammar, as `tree-sitter generate` reads it
+    TreeSitter,
+}
+
+impl GrammarFormat {
+    pub const NAMES: &'static [&'static str] = &["ebnf", "tree-sitter"];
+}
+
+impl FromStr for GrammarFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ebnf" => Ok(GrammarFormat::Ebnf),
+            "tree-sitter" => Ok(GrammarFormat::TreeSitter),
+            _ => Err(format!(
+                "Unknown grammar format '{}' (expected one of: {})",
+                s,
+                Self::NAMES.join(", ")
+            )),
+        }
+    }
+}
+
+impl fmt::Display for GrammarFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            GrammarFormat::Ebnf => "ebnf",
+            GrammarFormat::TreeSitter => "tree-sitter",
+        })
+    }
+}
+
+/// Right-hand side of a grammar rule
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    /// A token spelled exactly as the text
+    Literal(String),
+    /// Regular expression of a token class
+    Pattern(String),
+    /// Reference to another rule
+    Rule(String),
+    Sequence(Vec<Expr>),
+    Choice(Vec<Expr>),
+    /// Zero or more repetitions
+    Repeat(Box<Expr>),
+    Optional(Box<Expr>),
+}
+
+/// Rules of a grammar, in source order; the first one is the start rule
+#[derive(Debug, Clone, PartialEq)]
+pub struct Grammar {
+    pub rules: Vec<(String, Expr)>,
+}
+
+/// Whether `name` is a token class, such as `IDENTIFIER`, rather than a rule
+/// over tokens
+pub fn is_token_class(name: &str) -> bool {
+    !name.chars().any(|c| c.is_ascii_lowercase())
+}
+
+/// Whether `token` belongs to the token class `name`; `None` for a class the
+/// lexer has no tokens of
+fn token_class(name: &str, token: &Token) -> Option<bool> {
+    match name {
+        "IDENTIFIER" => Some(matches!(token, Token::Identifier(_))),
+        "NUMBER" => Some(matches!(token, Token::NumberLiteral(_))),
+        "FLOAT" => Some(matches!(token, Token::FloatLiteral(_))),
+        "STRING" => Some(matches!(token, Token::StringLiteral(_))),
+        "STRING_HEAD" => Some(matches!(token, Token::StringHead(_))),
+        "STRING_MIDDLE" => Some(matches!(token, Token::StringMiddle(_))),
+        "STRING_TAIL" => Some(matches!(token, Token::StringTail(_))),
+        _ => None,
+    }
+}
+
+impl Grammar {
+    /// The grammar of Replica, from [`SOURCE`]
+    pub fn replica() -> Grammar {
+        Grammar::parse(SOURCE).expect("grammar.ebnf is well-formed")
+    }
+
+    /// Reads a grammar in the notation of `grammar.ebnf`, checking that every
+    /// referenced rule is defined and every literal is one token
+    pub fn parse(source: &str) -> Result<Grammar, String> {
+        let tokens = ebnf_tokens(source)?;
+        let mut reader = Reader { tokens, current: 0 };
+        let mut rules = Vec::new();
+        while reader.current < reader.tokens.len() {
+            let name = match reader.next() {
+                Some(EbnfToken::Name(name)) => name,
+                other => return Err(format!("expected a rule name, found {:?}", other)),
+            };
+            reader.expect(EbnfToken::Symbol('='))?;
+            let expr = reader.choice()?;
+            reader.expect(EbnfToken::Symbol(';'))?;
+            rules.push((name, expr));
+        }
+
+        let grammar = Grammar { rules };
+        for (name, expr) in &grammar.rules {
+            // トークンの種類は正規表現だけで定義する
+            if is_token_class(name) != matches!(expr, Expr::Pattern(_)) {
+                return Err(format!(
+                    "{} must be a token class named in capitals with a /pattern/ as its whole body",
+                    name
+                ));
+            }
+            let mut references = Vec::new();
+            collect_rules(expr, &mut references);
+            if let Some(missing) = references.iter().find(|name| grammar.rule(name).is_none()) {
+                return Err(format!("{} refers to undefined rule {}", name, missing));
+            }
+        }
+        for literal in grammar.literals() {
+            match lexer::tokenize(literal).as_deref() {
+                Ok([_]) => {}
+                _ => return Err(format!("\"{}\" is not a single token", literal)),
+            }
+        }
+        Ok(grammar)
+    }
+
+    /// Name of the start rule
+    pub fn start(&self) -> &str {
+        self.rules.first().map_or("", |(name, _)| name)
+    }
+
+    pub fn rule(&self, name: &str) -> Option<&Expr> {
+        self.rules
+            .iter()
+            .find(|(rule, _)| rule == name)
+            .map(|(_, expr)| expr)
+    }
+
+    /// Every quoted literal of the grammar
+    pub fn literals(&self) -> BTreeSet<&str> {
+        fn collect<'a>(expr: &'a Expr, literals: &mut BTreeSet<&'a str>) {
+            match expr {
+                Expr::Literal(text) => {
+                    literals.insert(text);
+                }
+                Expr::Sequence(items) | Expr::Choice(items) => {
+                    items.iter().for_each(|item| collect(item, literals))
+                }
+                Expr::Repeat(inner) | Expr::Optional(inner) => collect(inner, literals),
+                Expr::Pattern(_) | Expr::Rule(_) => {}
+            }
+        }
+        let mut literals = BTreeSet::new();
+        for (_, expr) in &self.rules {
+            collect(expr, &mut literals);
+        }
+        literals
+    }
+
+    /// Whether the grammar derives `tokens` from its start rule. On rejection
+    /// returns the index of the furthest token any derivation reached.
+    pub fn accepts(&self, tokens: &[Token]) -> Result<(), usize> {
+        let literals = self
+            .literals()
+            .into_iter()
+            .filter_map(|text| {
+                let token = lexer::tokenize(text).ok()?.pop()?;
+                Some((text, token))
+            })
+            .collect();
+        let mut recognizer = Recognizer {
+            grammar: self,
+            tokens,
+            literals,
+            memo: HashMap::new(),
+            furthest: 0,
+        };
+        let ends = recognizer.ends(&Expr::Rule(self.start().to_string()), 0);
+        if ends.contains(&tokens.len()) {
+            Ok(())
+        } else {
+            Err(recognizer.furthest)
+        }
+    }
+
+    /// The grammar as the `grammar.json` of a tree-sitter grammar. Rule names
+    /// are lowercased, `identifier` is the word rule so keywords are not read
+    /// as identifiers, and whitespace and comments may appear between tokens.
+    pub fn to_tree_sitter(&self) -> String {
+        let rules: Vec<String> = self
+            .rules
+            .iter()
+            .map(|(name, expr)| {
+                format!(
+                    "    {}: {}",
+                    Value::from(name.to_lowercase()),
+                    tree_sitter_rule(expr)
+                )
+            })
+            .collect();
+        let extras = json!([
+            { "type": "PATTERN", "value": "\\s" },
+            { "type": "SYMBOL", "name": COMMENT.to_lowercase() },
+        ]);
+        // tree-sitter は最初の規則から構文解析を始めるので、規則の順序を保って書く
+        format!(
+            "{{\n  \"name\": \"replica\",\n  \"word\": \"identifier\",\n  \"extras\": {},\n  \"rules\": {{\n{}\n  }}\n}}\n",
+            extras,
+            rules.join(",\n")
+        )
+    }
+}
+
+/// Rules referenced from `expr`
+fn collect_rules<'a>(expr: &'a Expr, rules: &mut Vec<&'a str>) {
+    match expr {
+        Expr::Rule(name) => rules.push(name),
+        Expr::Sequence(items) | Expr::Choice(items) => {
+            items.iter().for_each(|item| collect_rules(item, rules))
+        }
+        Expr::Repeat(inner) | Expr::Optional(inner) => collect_rules(inner, rules),
+        Expr::Literal(_) | Expr::Pattern(_) => {}
+    }
+}
+
+fn tree_sitter_rule(expr: &Expr) -> Value {
+    match expr {
+        Expr::Literal(text) => json!({ "type": "STRING", "value": text }),
+        Expr::Pattern(pattern) => json!({ "type": "PATTERN", "value": pattern }),
+        Expr::Rule(name) => json!({ "type": "SYMBOL", "name": name.to_lowercase() }),
+        Expr::Sequence(items) => json!({
+            "type": "SEQ",
+            "members": items.iter().map(tree_sitter_rule).collect::<Vec<_>>(),
+        }),
+        Expr::Choice(items) => json!({
+            "type": "CHOICE",
+            "members": items.iter().map(tree_sitter_rule).collect::<Vec<_>>(),
+        }),
+        Expr::Repeat(inner) => json!({ "type": "REPEAT", "content": tree_sitter_rule(inner) }),
+        Expr::Optional(inner) => json!({
+            "type": "CHOICE",
+            "members": [tree_sitter_rule(inner), { "type": "BLANK" }],
+        }),
+    }
+}
+
+/// Token of the EBNF notation itself
+#[derive(Debug, Clone, PartialEq)]
+enum EbnfToken {
+    Name(String),
+    Literal(String),
+    Pattern(String),
+    /// One of `= ; | ( ) [ ] { }`
+    Symbol(char),
+}
+
+fn ebnf_tokens(source: &str) -> Result<Vec<EbnfToken>, String> {
+    let mut tokens = Vec::new();
+    let mut rest = source;
+    loop {
+        rest = rest.trim_start();
+        let Some(c) = rest.chars().next() else {
+            return Ok(tokens);
+        };
+        if let Some(comment) = rest.strip_prefix("(*") {
+            let end = comment.find("*)").ok_or("unterminated (* comment")?;
+            rest = &comment[end + 2..];
+        } else if c == '"' {
+            let end = rest[1..].find('"').ok_or("unterminated literal")?;
+            tokens.push(EbnfToken::Literal(rest[1..end + 1].to_string()));
+            rest = &rest[end + 2..];
+        } else if c == '/' {
+            // `\/` は正規表現の中の `/`
+            let mut escaped = false;
+            let end = rest[1..]
+                .find(|c| {
+                    let close = c == '/' && !escaped;
+                    escaped = c == '\\' && !escaped;
+                    close
+                })
+                .ok_or("unterminated pattern")?;
+            tokens.push(EbnfToken::Pattern(rest[1..end + 1].replace("\\/", "/")));
+            rest = &rest[end + 2..];
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let end = rest
+                .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                .unwrap_or(rest.len());
+            tokens.push(EbnfToken::Name(rest[..end].to_string()));
+            rest = &rest[end..];
+        } else if "=;|()[]{}".contains(c) {
+            tokens.push(EbnfToken::Symbol(c));
+            rest = &rest[1..];
+        } else {
+            return Err(format!("unexpected character '{}' in grammar", c));
+        }
+    }
+}
+
+struct Reader {
+    tokens: Vec<EbnfToken>,
+    current: usize,
+}
+
+impl Reader {
+    fn next(&mut self) -> Option<EbnfToken> {
+        let token = self.tokens.get(self.current).cloned();
+        self.current += 1;
+        token
+    }
+
+    fn expect(&mut self, expected: EbnfToken) -> Result<(), String> {
+        match self.next() {
+            Some(token) if token == expected => Ok(()),
+            other => Err(format!("expected {:?}, found {:?}", expected, other)),
+        }
+    }
+
+    /// Parses `a | b | ...`
+    fn choice(&mut self) -> Result<Expr, String> {
+        let mut alternatives = vec![self.sequence()?];
+        while self.tokens.get(self.current) == Some(&EbnfToken::Symbol('|')) {
+            self.current += 1;
+            alternatives.push(self.sequence()?);
+        }
+        Ok(match alternatives.len() {
+            1 => alternatives.remove(0),
+            _ => Expr::Choice(alternatives),
+        })
+    }
+
+    /// Parses the items of one alternative, up to `|` or a closing symbol
+    fn sequence(&mut self) -> Result<Expr, String> {
+        let mut items = Vec::new();
+        loop {
+            let item = match self.tokens.get(self.current) {
+                Some(EbnfToken::Symbol('|' | ';' | ')' | ']' | '}')) | None => break,
+                Some(EbnfToken::Name(name)) => Expr::Rule(name.clone()),
+                Some(EbnfToken::Literal(text)) => Expr::Literal(text.clone()),
+                Some(EbnfToken::Pattern(pattern)) => Expr::Pattern(pattern.clone()),
+                Some(EbnfToken::Symbol(open @ ('(' | '[' | '{'))) => {
+                    let open = *open;
+                    self.current += 1;
+                    let inner = self.choice()?;
+                    let (close, expr) = match open {
+                        '(' => (')', inner),
+                        '[' => (']', Expr::Optional(Box::new(inner))),
+                        _ => ('}', Expr::Repeat(Box::new(inner))),
+                    };
+                    self.expect(EbnfToken::Symbol(close))?;
+                    items.push(expr);
+                    continue;
+                }
+                Some(token) => return Err(format!("unexpected {:?} in rule", token)),
+            };
+            self.current += 1;
+            items.push(item);
+        }
+        Ok(match items.len() {
+            0 => return Err("empty alternative".to_string()),
+            1 => items.remove(0),
+            _ => Expr::Sequence(items),
+        })
+    }
+}
+
+/// Finds every way a grammar derives prefixes of a token stream. Statements
+/// have no separators, so a program may have several derivations; each rule
+/// is tried once per position and yields every position it can end at.
+struct Recognizer<'a> {
+    grammar: &'a Grammar,
+    tokens: &'a [Token],
+    literals: HashMap<&'a str, Token>,
+    memo: HashMap<(String, usize), BTreeSet<usize>>,
+    furthest: usize,
+}
+
+impl Recognizer<'_> {
+    /// Positions a derivation of `expr` starting at `from` can end at
+    fn ends(&mut self, expr: &Expr, from: usize) -> BTreeSet<usize> {
+        match expr {
+            Expr::Literal(text) => {
+                let token = self.tokens.get(from);
+                self.matched(
+                    from,
+                    token.is_some() && token == self.literals.get(text.as_str()),
+                )
+            }
+            Expr::Rule(name) if is_token_class(name) => {
+                let token = self.tokens.get(from);
+                let matched = token.is_some_and(|token| token_class(name, token) == Some(true));
+                self.matched(from, matched)
+            }
+            Expr::Rule(name) => {
+                let key = (name.clone(), from);
+                if let Some(ends) = self.memo.get(&key) {
+                    return ends.clone();
+                }
+                // 左再帰は導出を持たないものとして扱い、無限に再帰しない
+                self.memo.insert(key.clone(), BTreeSet::new());
+                let grammar = self.grammar;
+                let ends = grammar
+                    .rule(name)
+                    .map(|expr| self.ends(expr, from))
+                    .unwrap_or_default();
+                self.memo.insert(key, ends.clone());
+                ends
+            }
+            Expr::Sequence(items) => items.iter().fold(BTreeSet::from([from]), |starts, item| {
+                starts
+                    .into_iter()
+                    .flat_map(|start| self.ends(item, start))
+                    .collect()
+            }),
+            Expr::Choice(items) => items
+                .iter()
+                .flat_map(|item| self.ends(item, from))
+                .collect(),
+            Expr::Optional(inner) => {
+                let mut ends = self.ends(inner, from);
+                ends.insert(from);
+                ends
+            }
+            Expr::Repeat(inner) => {
+                let mut ends = BTreeSet::from([from]);
+                let mut frontier = vec![from];
+                while let Some(start) = frontier.pop() {
+                    for end in self.ends(inner, start) {
+                        if ends.insert(end) {
+                            frontier.push(end);
+                        }
+                    }
+                }
+                ends
+            }
+            Expr::Pattern(_) => BTreeSet::new(),
+        }
+    }
+
+    /// Ends of a one-token match at `from`, recording how far matching got
+    fn matched(&mut self, from: usize, matched: bool) -> BTreeSet<usize> {
+        self.furthest = self.furthest.max(from);
+        if matched {
+            BTreeSet::from([from + 1])
+        } else {
+            BTreeSet::new()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn accepts(source: &str) -> bool {
+        Grammar::replica()
+            .accepts(&lexer::tokenize(source).unwrap())
+            .is_ok()
+    }
+
+    #[test]
+    fn test_parse_notation() {
+        let grammar = Grammar::parse(
+            "(* comment *) list = \"[\" [ item { \",\" item } ] \"]\" ;
+             item = NAME | list ;
+             NAME = /[a-z]+\\/?/ ;",
+        )
+        .unwrap();
+        assert_eq!(grammar.start(), "list");
+        assert_eq!(
+            grammar.rule("item"),
+            Some(&Expr::Choice(vec![
+                Expr::Rule("NAME".to_string()),
+                Expr::Rule("list".to_string())
+            ]))
+        );
+        assert_eq!(
+            grammar.rule("NAME"),
+            Some(&Expr::Pattern("[a-z]+/?".to_string()))
+        );
+
+        assert!(Grammar::parse("a = b ;")
+            .unwrap_err()
+            .contains("undefined rule b"));
+        assert!(Grammar::parse("a = \"a b\" ;")
+            .unwrap_err()
+            .contains("single token"));
+        assert!(Grammar::parse("a = /x/ ;").is_err());
+        assert!(Grammar::parse("a = ( \"x\" ;").is_err());
+    }
+
+    #[test]
+    fn test_replica_grammar() {
+        let grammar = Grammar::replica();
+        assert_eq!(grammar.start(), "source_file");
+        // 文の区切りがないので、曖昧な並びもどれかの導出で受理する
+        assert!(accepts(
+            "actor Counter {
+    var count: Int?
+
+    @since(2)
+    func add(amount: Int) -> Int {
+        count += amount
+        return count ?? 0 + 1
+    }
+}"
+        ));
+        assert!(!accepts("actor Counter {\n    var count: Int move move\n}"));
+        assert!(!accepts(
+            "actor Counter {\n    func f() -> Bool {\n        return 1 < 2 < 3\n    }\n}"
+        ));
+
+        let tokens = lexer::tokenize("actor A {\n    var x: Int = 1\n}").unwrap();
+        // `=` で止まる
+        assert_eq!(grammar.accepts(&tokens), Err(7));
+    }
+
+    #[test]
+    fn test_tree_sitter() {
+        let json: Value = serde_json::from_str(&Grammar::replica().to_tree_sitter()).unwrap();
+        assert_eq!(json["word"], "identifier");
+        assert_eq!(json["rules"]["identifier"]["type"], "PATTERN");
+        assert_eq!(
+            json["rules"]["field_attribute"]["members"][0],
+            json!({ "type": "STRING", "value": "@" })
+        );
+        // 最初の規則が開始規則
+        let text = Grammar::replica().to_tree_sitter();
+        assert!(text.contains("\"rules\": {\n    \"source_file\": "));
+    }
+}
@@ -0,0 +1,299 @@
+//! Reports of internal compiler errors. The query database records the phase,
+//! file, actor and method it is working on, so that a panic can say where in
+//! the input it happened. [`install`] replaces the raw panic message and
+//! backtrace with such a report and writes a reproduction bundle: the source
+//! file being compiled, the options and the command line.
+
+use std::backtrace::Backtrace;
+use std::cell::RefCell;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::panic::{self, PanicHookInfo};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Arc;
+
+/// Stage of the compiler a file is in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    Lexing,
+    Parsing,
+    SemanticAnalysis,
+    CodeGeneration,
+    Linking,
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Phase::Lexing => "lexing",
+            Phase::Parsing => "parsing",
+            Phase::SemanticAnalysis => "semantic analysis",
+            Phase::CodeGeneration => "code generation",
+            Phase::Linking => "linking",
+        };
+        f.write_str(name)
+    }
+}
+
+/// What the compiler is working on
+#[derive(Debug, Clone, Default)]
+pub struct Location {
+    pub phase: Option<Phase>,
+    /// Path and text of the source file
+    pub file: Option<(PathBuf, Arc<str>)>,
+    pub actor: Option<String>,
+    pub method: Option<String>,
+}
+
+thread_local! {
+    static LOCATION: RefCell<Location> = RefCell::default();
+    /// Code generation options of the current database, as `{:#?}` text
+    static OPTIONS: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// Restores the location that was current before it when dropped
+#[must_use = "the location is restored as soon as the scope is dropped"]
+pub struct Scope {
+    previous: Option<Location>,
+}
+
+impl Drop for Scope {
+    fn drop(&mut self) {
+        if let Some(previous) = self.previous.take() {
+            LOCATION.with(|location| *location.borrow_mut() = previous);
+        }
+    }
+}
+
+/// Changes the current location with `change` until the scope is dropped
+fn enter(change: impl FnOnce(&mut Location)) -> Scope {
+    LOCATION.with(|location| {
+        let mut location = location.borrow_mut();
+        let previous = location.clone();
+        change(&mut location);
+        Scope {
+            previous: Some(previous),
+        }
+    })
+}
+
+/// Marks the start of `phase` on the file at `path`
+pub fn enter_phase(phase: Phase, path: &Path, text: &Arc<str>) -> Scope {
+    enter(|location| {
+        *location = Location {
+            phase: Some(phase),
+            file: Some((path.to_path_buf(), text.clone())),
+            actor: None,
+            method: None,
+        }
+    })
+}
+
+pub fn enter_actor(name: &str) -> Scope {
+    enter(|location| {
+        location.actor = Some(name.to_string());
+        location.method = None;
+    })
+}
+
+pub fn enter_method(name: &str) -> Scope {
+    enter(|location| location.method = Some(name.to_string()))
+}
+
+pub fn current() -> Location {
+    LOCATION.with(|location| location.borrow().clone())
+}
+
+/// Records the options to put in reproduction bundles
+pub fn set_options(options: &impl fmt::Debug) {
+    let text = format!("{:#?}", options);
+    OPTIONS.with(|options| *options.borrow_mut() = Some(text));
+}
+
+/// An internal compiler error with where it happened
+#[derive(Debug, Clone)]
+pub struct CrashReport {
+    pub message: String,
+    /// Position in the compiler's own source of the panic
+    pub panicked_at: Option<String>,
+    pub location: Location,
+    pub options: Option<String>,
+    pub version: &'static str,
+    pub backtrace: String,
+}
+
+impl CrashReport {
+    /// Reports `message` at the current location
+    pub fn new(message: impl Into<String>) -> Self {
+        CrashReport {
+            message: message.into(),
+            panicked_at: None,
+            location: current(),
+            options: OPTIONS.with(|options| options.borrow().clone()),
+            version: env!("CARGO_PKG_VERSION"),
+            backtrace: String::new(),
+        }
+    }
+
+    fn from_panic(info: &PanicHookInfo) -> Self {
+        let payload = info.payload();
+        let message = payload
+            .downcast_ref::<&str>()
+            .map(|message| message.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "Box<dyn Any>".to_string());
+        CrashReport {
+            panicked_at: info.location().map(ToString::to_string),
+            backtrace: Backtrace::force_capture().to_string(),
+            ..CrashReport::new(message)
+        }
+    }
+
+    /// Report for stderr, without the backtrace
+    pub fn render(&self) -> String {
+        let mut text = format!("error: internal compiler error: {}\n", self.message);
+        let mut line = |label: &str, value: &str| {
+            text.push_str(&format!("  {:<10}{}\n", format!("{}:", label), value));
+        };
+        if let Some(phase) = self.location.phase {
+            line("phase", &phase.to_string());
+        }
+        if let Some((path, _)) = &self.location.file {
+            line("file", &path.display().to_string());
+        }
+        if let Some(actor) = &self.location.actor {
+            line("actor", actor);
+        }
+        if let Some(method) = &self.location.method {
+            line("method", method);
+        }
+        if let Some(panicked_at) = &self.panicked_at {
+            line("at", panicked_at);
+        }
+        line("compiler", &format!("replicac {}", self.version));
+        text
+    }
+
+    /// Writes the report, the source file, the options and `args` to a new
+    /// directory in `dir`, returning its path
+    pub fn write_bundle(&self, dir: &Path, args: &[String]) -> io::Result<PathBuf> {
+        let bundle = (0..)
+            .map(|n| dir.join(format!("replica-ice-{}-{}", process::id(), n)))
+            .find(|path| !path.exists())
+            .unwrap_or_default();
+        fs::create_dir_all(&bundle)?;
+
+        let mut report = self.render();
+        if !self.backtrace.is_empty() {
+            report.push_str(&format!("\nbacktrace:\n{}", self.backtrace));
+        }
+        fs::write(bundle.join("report.txt"), report)?;
+
+        // 入力はクラッシュしたファイルだけに絞り、コマンドもそれを指すよう書き換える
+        let mut args = args.to_vec();
+        if let Some((path, text)) = &self.location.file {
+            let name = path.file_name().unwrap_or(path.as_os_str());
+            fs::write(bundle.join(name), &**text)?;
+            for arg in &mut args {
+                if Path::new(arg.as_str()) == path {
+                    *arg = name.to_string_lossy().into_owned();
+                }
+            }
+        }
+        fs::write(bundle.join("command.txt"), args.join(" ") + "\n")?;
+        if let Some(options) = &self.options {
+            fs::write(bundle.join("options.txt"), format!("{}\n", options))?;
+        }
+        Ok(bundle)
+    }
+}
+
+/// Reports panics as internal compiler errors, writing a reproduction bundle
+/// of the command line `args` to the temporary directory
+pub fn install(args: Vec<String>) {
+    panic::set_hook(Box::new(move |info| {
+        let report = CrashReport::from_panic(info);
+        eprint!("{}", report.render());
+        match report.write_bundle(&std::env::temp_dir(), &args) {
+            Ok(bundle) => eprintln!(
+                "note: this is a bug in the compiler; please report it with the \
+                 reproduction bundle written to {}",
+                bundle.display()
+            ),
+            Err(e) => eprintln!("note: failed to write a reproduction bundle: {}", e),
+        }
+    }));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scopes_restore_the_location() {
+        let text: Arc<str> = "actor Counter {}".into();
+        let path = Path::new("src/counter.replica");
+        {
+            let _phase = enter_phase(Phase::CodeGeneration, path, &text);
+            let _actor = enter_actor("Counter");
+            {
+                let _method = enter_method("add");
+                assert_eq!(current().method.as_deref(), Some("add"));
+            }
+            let location = current();
+            assert_eq!(location.phase, Some(Phase::CodeGeneration));
+            assert_eq!(location.actor.as_deref(), Some("Counter"));
+            assert_eq!(location.method, None);
+        }
+        assert!(current().phase.is_none());
+    }
+
+    #[test]
+    fn test_crash_report_bundle() {
+        let text: Arc<str> = "actor Counter {}".into();
+        let path = Path::new("src/counter.replica");
+        let _phase = enter_phase(Phase::CodeGeneration, path, &text);
+        let _actor = enter_actor("Counter");
+        let _method = enter_method("add");
+        set_options(&("wasm32-unknown-unknown", 2));
+        let report = CrashReport::new("index out of bounds");
+        assert_eq!(
+            report.render(),
+            format!(
+                "error: internal compiler error: index out of bounds\n  \
+                 phase:    code generation\n  \
+                 file:     src/counter.replica\n  \
+                 actor:    Counter\n  \
+                 method:   add\n  \
+                 compiler: replicac {}\n",
+                env!("CARGO_PKG_VERSION")
+            )
+        );
+
+        let dir = std::env::temp_dir().join(format!("replica-ice-test-{}", process::id()));
+        let args: Vec<String> = [
+            "replicac",
+            "build",
+            "--opt-level",
+            "2",
+            "src/counter.replica",
+        ]
+        .iter()
+        .map(|arg| arg.to_string())
+        .collect();
+        let bundle = report.write_bundle(&dir, &args).unwrap();
+        let read = |name: &str| fs::read_to_string(bundle.join(name)).unwrap();
+        assert_eq!(read("counter.replica"), "actor Counter {}");
+        assert_eq!(
+            read("command.txt"),
+            "replicac build --opt-level 2 counter.replica\n"
+        );
+        assert!(read("options.txt").contains("\"wasm32-unknown-unknown\""));
+        assert!(read("report.txt").starts_with("error: internal compiler error"));
+        // 同じプロセスの 2 つ目のバンドルは別のディレクトリになる
+        assert_ne!(report.write_bundle(&dir, &args).unwrap(), bundle);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,291 @@
+//! Incremental re-lexing and re-parsing of a source file being edited.
+//! A `Document` keeps the tokens of its text and the methods last parsed from
+//! them. An edit re-lexes only the tokens around it, and the next parse takes
+//! every method whose tokens the edit left alone instead of parsing it again.
+
+use crate::lexer::{self, LexError, Token};
+use crate::parser::{Parser, ReusableMethod};
+use std::ops::Range;
+use std::sync::Arc;
+
+pub struct Document {
+    text: Arc<str>,
+    tokens: Vec<(Token, Range<usize>)>,
+    /// Source line of each token
+    lines: Vec<usize>,
+    /// Errors of the [`Token::Error`]s among the tokens, in order
+    errors: Vec<LexError>,
+    /// Methods of the last parse, by the range of their tokens
+    methods: Vec<ReusableMethod>,
+}
+
+impl Document {
+    /// Lexes the whole of `text`
+    pub fn new(text: impl Into<Arc<str>>) -> Self {
+        let text = text.into();
+        let (tokens, errors) = lexer::tokenize_recovering(&text);
+        let lines = lexer::token_lines(&text, tokens.clone())
+            .into_iter()
+            .map(|(_, line)| line)
+            .collect();
+        Document {
+            text,
+            tokens,
+            lines,
+            errors,
+            methods: Vec::new(),
+        }
+    }
+
+    pub fn text(&self) -> &Arc<str> {
+        &self.text
+    }
+
+    /// Tokens with their byte ranges, as from `lexer::tokenize_recovering`
+    pub fn tokens(&self) -> &[(Token, Range<usize>)] {
+        &self.tokens
+    }
+
+    /// Tokens with their lines, as from `lexer::token_lines`
+    pub fn token_lines(&self) -> Vec<(Token, usize)> {
+        self.tokens
+            .iter()
+            .map(|(token, _)| token.clone())
+            .zip(self.lines.iter().copied())
+            .collect()
+    }
+
+    pub fn lex_errors(&self) -> &[LexError] {
+        &self.errors
+    }
+
+    /// Replaces the bytes at `range` with `replacement`, re-lexing only the
+    /// tokens the edit can have changed
+    pub fn edit(&mut self, range: Range<usize>, replacement: &str) {
+        let text: Arc<str> = [
+            &self.text[..range.start],
+            replacement,
+            &self.text[range.end..],
+        ]
+        .concat()
+        .into();
+        let relexed = lexer::relex(&self.tokens, &text, range.clone(), replacement.len());
+        let newlines = |text: &str| text.matches('\n').count() as isize;
+        let moved_lines = newlines(replacement) - newlines(&self.text[range.clone()]);
+        let moved_tokens = relexed.tokens.len() as isize - (relexed.end - relexed.start) as isize;
+
+        // 読み直したトークンの行は、その手前の変わっていないトークンから数える
+        let (mut line, mut counted) = match self.tokens.get(relexed.start) {
+            Some((_, span)) if relexed.start > 0 => (self.lines[relexed.start], span.start),
+            _ => (1, 0),
+        };
+        let lines: Vec<usize> = relexed
+            .tokens
+            .iter()
+            .map(|(_, span)| {
+                line += text[counted..span.start].matches('\n').count();
+                counted = span.start;
+                line
+            })
+            .collect();
+        self.lines.splice(relexed.start..relexed.end, lines);
+        for line in &mut self.lines[relexed.start + relexed.tokens.len()..] {
+            *line = line.saturating_add_signed(moved_lines);
+        }
+
+        // 後ろのエラーは行も列も変わりうるので、位置を求め直す
+        let is_error = |(token, _): &(Token, Range<usize>)| matches!(token, Token::Error(_));
+        let before = self.tokens[..relexed.start]
+            .iter()
+            .filter(|t| is_error(t))
+            .count();
+        let replaced = self.tokens[relexed.start..relexed.end]
+            .iter()
+            .filter(|t| is_error(t))
+            .count();
+        let mut errors = self.errors.split_off(before);
+        let after = errors.split_off(replaced);
+        self.errors.extend(relexed.errors.iter().cloned());
+        let moved = |span: &Range<usize>| {
+            span.start + replacement.len() - range.len()..span.end + replacement.len() - range.len()
+        };
+        let after_spans = self.tokens[relexed.end..]
+            .iter()
+            .filter(|t| is_error(t))
+            .map(|(_, span)| moved(span));
+        for (mut error, span) in after.into_iter().zip(after_spans) {
+            let line_start = text[..span.start].rfind('\n').map_or(0, |index| index + 1);
+            error.line = error.line.saturating_add_signed(moved_lines);
+            error.column = text[line_start..span.start].chars().count() + 1;
+            self.errors.push(error);
+        }
+
+        let suffix = self.tokens.split_off(relexed.end);
+        self.tokens.truncate(relexed.start);
+        self.tokens.extend(relexed.tokens.iter().cloned());
+        self.tokens.extend(
+            suffix
+                .into_iter()
+                .map(|(token, span)| (token, moved(&span))),
+        );
+
+        // 編集に触れたメソッドは捨て、後ろのメソッドはトークンの位置と行をずらす
+        self.methods.retain_mut(|method| {
+            if method.tokens.end <= relexed.start {
+                true
+            } else if method.tokens.start >= relexed.end {
+                let shift = |index: usize| index.saturating_add_signed(moved_tokens);
+                method.tokens = shift(method.tokens.start)..shift(method.tokens.end);
+                if let Some(body) = &mut method.method.body {
+                    body.move_lines(moved_lines);
+                }
+                true
+            } else {
+                false
+            }
+        });
+        self.text = text;
+    }
+
+    /// Parser over the tokens that takes the methods of the last parse where
+    /// they are still valid; hand it back to [`Document::keep_parsed_methods`]
+    pub fn parser(&mut self) -> Parser {
+        Parser::with_lines(self.token_lines())
+            .with_docs(lexer::doc_comments(&self.text))
+            .reuse_methods(std::mem::take(&mut self.methods))
+    }
+
+    /// Keeps the methods `parser` parsed or reused for the next parse
+    pub fn keep_parsed_methods(&mut self, parser: &mut Parser) {
+        self.methods = parser.take_parsed_methods();
+    }
+
+    /// Drops the methods kept from the last parse, so that the next one
+    /// parses every method again
+    pub fn forget_parsed_methods(&mut self) {
+        self.methods.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::Actor;
+
+    const SOURCE: &str = r#"actor Ledger {
+    var total: Int
+
+    /// Adds to the total.
+    func add(amount: Int) -> Int {
+        log("adding \(amount)")
+        total = total + amount
+        return total
+    }
+
+    func reset() {
+        log("reset")
+        total = 0
+    }
+}
+"#;
+
+    fn parse(document: &mut Document) -> (Actor, usize) {
+        let reusable = document.methods.len();
+        let mut parser = document.parser().recover_errors();
+        let actor = parser.parse_actor().unwrap();
+        document.keep_parsed_methods(&mut parser);
+        (actor, reusable)
+    }
+
+    /// Applies the edit to `document` and checks it matches a fresh document
+    /// of the edited text, both before and after parsing
+    fn edit(document: &mut Document, from: &str, to: &str) -> Actor {
+        let start = document.text.find(from).unwrap();
+        document.edit(start..start + from.len(), to);
+        let mut fresh = Document::new(document.text.clone());
+        assert_eq!(document.tokens, fresh.tokens);
+        assert_eq!(document.lines, fresh.lines);
+        assert_eq!(document.errors, fresh.errors);
+        let (actor, _) = parse(document);
+        assert_eq!(actor, parse(&mut fresh).0);
+        actor
+    }
+
+    #[test]
+    fn test_edits_match_a_full_parse() {
+        let mut document = Document::new(SOURCE);
+        let (actor, reusable) = parse(&mut document);
+        assert_eq!((actor.methods.len(), reusable), (2, 0));
+
+        // 上の行を増やすと、使い回した後ろのメソッドの行もずれる
+        let actor = edit(
+            &mut document,
+            "var total: Int\n",
+            "var total: Int\n    var count: Int\n\n",
+        );
+        assert_eq!(actor.fields.len(), 2);
+        edit(&mut document, "return total", "return total + count");
+        edit(
+            &mut document,
+            "adding \\(amount)",
+            "adding \\(amount + count) now",
+        );
+        edit(&mut document, "\"reset\"", "\"reset \\(total)\"");
+        edit(
+            &mut document,
+            "/// Adds to the total.",
+            "/// Adds an amount.",
+        );
+        edit(
+            &mut document,
+            "\n    func reset",
+            "\n    var broken = \"\n    func reset",
+        );
+        edit(&mut document, "var broken = \"\n", "");
+        edit(&mut document, "actor Ledger", "// A ledger.\nactor Ledger");
+    }
+
+    #[test]
+    fn test_quote_closes_an_earlier_unterminated_string() {
+        let mut document =
+            Document::new("actor A\" {\n  func f() { log(\"x\") }\n  func g() { y }\n}\n");
+        assert!(document
+            .tokens
+            .iter()
+            .any(|(token, _)| *token == Token::Error("\") }".to_string())));
+        // 行末で終わっていた文字列が、後ろの `"` で閉じる
+        let start = document.text.find('y').unwrap();
+        document.edit(start..start, "\"");
+        let fresh = Document::new(document.text.clone());
+        assert_eq!(document.tokens, fresh.tokens);
+        assert_eq!(document.lines, fresh.lines);
+        assert_eq!(document.errors, fresh.errors);
+        assert!(document
+            .tokens
+            .iter()
+            .any(|(token, _)| *token == Token::StringLiteral(") }\n  func g() { ".to_string())));
+    }
+
+    #[test]
+    fn test_untouched_methods_are_reused() {
+        let mut document = Document::new(SOURCE);
+        parse(&mut document);
+        let start = SOURCE.find("total = 0").unwrap();
+        document.edit(start..start + 9, "total = 1");
+        // 編集した reset は捨て、add だけを使い回す
+        assert_eq!(document.methods.len(), 1);
+        assert_eq!(document.methods[0].method.name, "add");
+        document.methods[0].method.name = "cached".to_string();
+        let (actor, reusable) = parse(&mut document);
+        assert_eq!(reusable, 1);
+        assert_eq!(actor.methods[0].name, "cached");
+        assert_eq!(document.methods.len(), 2);
+
+        // 上に行を足すと、使い回すメソッドのトークンの位置と行がずれる
+        document.edit(0..0, "/// A ledger.\n");
+        document.methods[0].method.name = "add".to_string();
+        let (actor, reusable) = parse(&mut document);
+        assert_eq!(reusable, 2);
+        assert_eq!(actor, parse(&mut Document::new(document.text.clone())).0);
+    }
+}
@@ -0,0 +1,222 @@
+//! Byte layout of actor state structs on wasm32.
+//! The semantic analyzer uses it to validate layout attributes and the code
+//! generator to place fields at the offsets it computes, so both agree on where
+//! each field lives when the struct is shared with the host.
+
+use crate::ast::{Field, Layout, OwnershipType, Type};
+
+/// Largest alignment `@align` may request; heap objects are 8-byte aligned
+pub const MAX_ALIGN: u32 = 8;
+
+/// Size of pointers and handles on wasm32
+pub const POINTER_SIZE: u32 = 4;
+
+/// Resolved layout of a struct
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StructLayout {
+    /// Byte offset of each field, in declaration order
+    pub offsets: Vec<u32>,
+    /// Size of each field
+    pub sizes: Vec<u32>,
+    /// Total size including tail padding
+    pub size: u32,
+    pub align: u32,
+}
+
+/// Returns the size and natural alignment of a value of `ty`
+pub fn size_and_align(ty: &Type) -> (u32, u32) {
+    match ty {
+        Type::Int => (4, 4),
+        Type::Float => (8, 8),
+        Type::Bool => (1, 1),
+        Type::String
+        | Type::Array(_)
+        | Type::Custom(_)
+        | Type::Generic { .. }
+        | Type::Function { .. } => (POINTER_SIZE, POINTER_SIZE),
+        Type::FixedArray(element, length) => {
+            let (size, align) = size_and_align(element);
+            (size * *length as u32, align)
+        }
+        // Optional は { T, i1 } の構造体として配置される
+        Type::Optional(inner) => natural_layout([inner.as_ref(), &Type::Bool].into_iter()),
+        Type::Tuple(elements) => natural_layout(elements.iter()),
+        Type::Record(fields) => natural_layout(fields.iter().map(|(_, field)| field)),
+    }
+}
+
+/// Returns the size and alignment of a field, which is a pointer for non-owning references
+fn field_size_and_align(field: &Field) -> (u32, u32) {
+    match field.ownership {
+        OwnershipType::Weak | OwnershipType::Unowned | OwnershipType::Inout => {
+            (POINTER_SIZE, POINTER_SIZE)
+        }
+        _ => size_and_align(&field.field_type),
+    }
+}
+
+fn natural_layout<'a>(types: impl Iterator<Item = &'a Type>) -> (u32, u32) {
+    let layout = natural(types);
+    (layout.size, layout.align)
+}
+
+/// Lays out the elements of a tuple or record in order at their natural alignment
+pub fn natural<'a>(types: impl IntoIterator<Item = &'a Type>) -> StructLayout {
+    let mut offsets = Vec::new();
+    let mut sizes = Vec::new();
+    let mut size = 0;
+    let mut align = 1;
+    for ty in types {
+        let (field_size, field_align) = size_and_align(ty);
+        offsets.push(round_up(size, field_align));
+        sizes.push(field_size);
+        size = round_up(size, field_align) + field_size;
+        align = align.max(field_align);
+    }
+    StructLayout {
+        offsets,
+        sizes,
+        size: round_up(size, align),
+        align,
+    }
+}
+
+fn round_up(value: u32, align: u32) -> u32 {
+    value.div_ceil(align) * align
+}
+
+/// Whether the struct needs the explicit layout instead of LLVM's natural one
+pub fn is_explicit(fields: &[Field], layout: &Layout) -> bool {
+    layout.packed || layout.align.is_some() || fields.iter().any(|f| f.offset.is_some())
+}
+
+/// Computes the layout of `fields` under the given attributes, rejecting
+/// invalid alignments and explicit offsets that overlap or are misaligned
+pub fn compute(fields: &[Field], layout: &Layout) -> Result<StructLayout, String> {
+    if let Some(align) = layout.align {
+        if !align.is_power_of_two() {
+            return Err(format!("@align({}) is not a power of two", align));
+        }
+        if align > MAX_ALIGN {
+            return Err(format!(
+                "@align({}) exceeds the {}-byte alignment of heap objects",
+                align, MAX_ALIGN
+            ));
+        }
+    }
+
+    let mut offsets = Vec::with_capacity(fields.len());
+    let mut sizes = Vec::with_capacity(fields.len());
+    let mut end = 0;
+    let mut align = layout.align.unwrap_or(1);
+    for field in fields {
+        let (size, natural_align) = field_size_and_align(field);
+        let field_align = if layout.packed { 1 } else { natural_align };
+
+        let offset = match field.offset {
+            Some(offset) if offset < end => {
+                return Err(format!(
+                    "Field {} at offset {} overlaps the previous field, which ends at {}",
+                    field.name, offset, end
+                ))
+            }
+            Some(offset) if offset % field_align != 0 => {
+                return Err(format!(
+                    "Field {} at offset {} is not aligned to {} bytes; mark the actor @packed",
+                    field.name, offset, field_align
+                ))
+            }
+            Some(offset) => offset,
+            None => round_up(end, field_align),
+        };
+
+        offsets.push(offset);
+        sizes.push(size);
+        end = offset + size;
+        align = align.max(field_align);
+    }
+
+    Ok(StructLayout {
+        offsets,
+        sizes,
+        size: round_up(end, align),
+        align,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn field(name: &str, field_type: Type, offset: Option<u32>) -> Field {
+        Field {
+            name: name.to_string(),
+            field_type,
+            is_mutable: true,
+            ownership: OwnershipType::Owned,
+            offset,
+            doc: None,
+        }
+    }
+
+    #[test]
+    fn test_natural_and_packed_layout() {
+        let fields = vec![
+            field("flag", Type::Bool, None),
+            field("value", Type::Float, None),
+            field("count", Type::Int, None),
+        ];
+
+        let natural = compute(&fields, &Layout::default()).unwrap();
+        assert_eq!(natural.offsets, vec![0, 8, 16]);
+        assert_eq!((natural.size, natural.align), (24, 8));
+
+        let packed = Layout {
+            packed: true,
+            align: None,
+        };
+        let packed = compute(&fields, &packed).unwrap();
+        assert_eq!(packed.offsets, vec![0, 1, 9]);
+        assert_eq!((packed.size, packed.align), (13, 1));
+    }
+
+    #[test]
+    fn test_explicit_offsets() {
+        let layout = Layout {
+            packed: false,
+            align: Some(8),
+        };
+        let fields = vec![
+            field("tag", Type::Int, Some(4)),
+            field("buffer", Type::FixedArray(Box::new(Type::Int), 2), None),
+        ];
+        let resolved = compute(&fields, &layout).unwrap();
+        assert_eq!(resolved.offsets, vec![4, 8]);
+        assert_eq!((resolved.size, resolved.align), (16, 8));
+
+        let overlapping = vec![
+            field("tag", Type::Int, Some(4)),
+            field("count", Type::Int, Some(6)),
+        ];
+        assert!(compute(&overlapping, &Layout::default()).is_err());
+
+        let misaligned = vec![field("count", Type::Int, Some(2))];
+        assert!(compute(&misaligned, &Layout::default()).is_err());
+        let packed = Layout {
+            packed: true,
+            align: None,
+        };
+        assert!(compute(&misaligned, &packed).is_ok());
+    }
+
+    #[test]
+    fn test_invalid_alignment() {
+        let layout = |align| Layout {
+            packed: false,
+            align: Some(align),
+        };
+        assert!(compute(&[], &layout(3)).is_err());
+        assert!(compute(&[], &layout(16)).is_err());
+        assert!(compute(&[], &layout(8)).is_ok());
+    }
+}
@@ -1,115 +1,1203 @@
+//! Lexer for Replica source. Every byte of the input ends up in the range of
+//! a token or in the whitespace and comments between tokens: input that forms
+//! no token becomes a [`Token::Error`] with a positioned [`LexError`], and
+//! lexing carries on after it.
+
 use nom::{
     branch::alt,
-    bytes::complete::{tag, take_while1},
-    character::complete::{alpha1, alphanumeric1, char, multispace0},
-    combinator::{map, recognize},
+    bytes::complete::{tag, take_while},
+    character::complete::{
+        alpha1, alphanumeric1, char, digit1, multispace1, not_line_ending, one_of,
+    },
+    combinator::{map, opt, recognize},
+    error::{Error, ErrorKind},
     multi::many0,
-    sequence::{pair, preceded, terminated},
+    sequence::{pair, preceded, tuple},
     IResult,
 };
+use std::collections::HashMap;
+use std::ops::Range;
+use thiserror::Error;
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum Token {
     Actor,
-    SingleActor,
+    Single,
     Var,
     Let,
     Func,
     Async,
     Sequential,
+    Await,
     Immediate,
+    Pure,
+    Comptime,
     Move,
     Copy,
     Shared,
+    Weak,
+    Unowned,
+    Inout,
     Init,
+    Deinit,
+    Extern,
+    Const,
+    Import,
+    Topic,
+    Pool,
+    Struct,
+    Enum,
     Arrow,
+    /// `=>` between the pattern and the body of a `match` arm
+    FatArrow,
     Identifier(String),
     StringLiteral(String),
+    /// `"text\(`, the text of an interpolated string before its first
+    /// interpolation; the tokens of the interpolated expression follow
+    StringHead(String),
+    /// `)text\(`, the text between two interpolations
+    StringMiddle(String),
+    /// `)text"`, the text after the last interpolation
+    StringTail(String),
+    /// Integer literal such as `42`, `0xFF`, `0b1010` or `1_000`, as its
+    /// decimal digits
     NumberLiteral(String),
+    /// Float literal such as `1.5` or `6.02e23`, already rounded to the nearest `f64`
+    FloatLiteral(f64),
     LBrace,
     RBrace,
     LParen,
     RParen,
+    LBracket,
+    RBracket,
+    Semicolon,
     Colon,
     Comma,
     Equals,
+    EqualEqual,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
     Plus,
     Minus,
     Multiply,
     Divide,
+    /// `+=`, `-=`, `*=` and `/=` in compound assignments
+    PlusEquals,
+    MinusEquals,
+    MultiplyEquals,
+    DivideEquals,
+    Question,
+    /// `??` between an optional and the value used when it is `nil`
+    QuestionQuestion,
+    /// `!` after an optional, unwrapping it
+    Exclamation,
+    Ampersand,
+    Dot,
+    At,
     Return,
+    Break,
+    Continue,
+    Yield,
+    If,
+    Else,
+    While,
+    For,
+    In,
+    Match,
+    Case,
+    Nil,
+    True,
+    False,
+    /// `..` in `start..end` ranges
+    DotDot,
+    /// Input that does not form a token, such as a stray character or an
+    /// unterminated string; the lexer reports it and carries on after it
+    Error(String),
 }
 
-fn keyword(input: &str) -> IResult<&str, Token> {
-    alt((
-        map(tag("actor"), |_| Token::Actor),
-        map(tag("single actor"), |_| Token::SingleActor),
-        map(tag("var"), |_| Token::Var),
-        map(tag("let"), |_| Token::Let),
-        map(tag("func"), |_| Token::Func),
-        map(tag("async"), |_| Token::Async),
-        map(tag("sequential"), |_| Token::Sequential),
-        map(tag("immediate"), |_| Token::Immediate),
-        map(tag("move"), |_| Token::Move),
-        map(tag("copy"), |_| Token::Copy),
-        map(tag("shared"), |_| Token::Shared),
-        map(tag("init"), |_| Token::Init),
-        map(tag("return"), |_| Token::Return),
+/// Keyword spelled `word`, or `None` if `word` is an identifier
+pub fn keyword(word: &str) -> Option<Token> {
+    let token = match word {
+        "actor" => Token::Actor,
+        "single" => Token::Single,
+        "var" => Token::Var,
+        "let" => Token::Let,
+        "func" => Token::Func,
+        "async" => Token::Async,
+        "sequential" => Token::Sequential,
+        "await" => Token::Await,
+        "immediate" => Token::Immediate,
+        "pure" => Token::Pure,
+        "move" => Token::Move,
+        "copy" => Token::Copy,
+        "shared" => Token::Shared,
+        "weak" => Token::Weak,
+        "unowned" => Token::Unowned,
+        "inout" => Token::Inout,
+        "init" => Token::Init,
+        "deinit" => Token::Deinit,
+        "extern" => Token::Extern,
+        "const" => Token::Const,
+        // 最初の宣言の前に来るもの
+        "import" => Token::Import,
+        "topic" => Token::Topic,
+        "pool" => Token::Pool,
+        "struct" => Token::Struct,
+        "enum" => Token::Enum,
+        // 文と式
+        "comptime" => Token::Comptime,
+        "return" => Token::Return,
+        "break" => Token::Break,
+        "continue" => Token::Continue,
+        "yield" => Token::Yield,
+        "if" => Token::If,
+        "else" => Token::Else,
+        "while" => Token::While,
+        "for" => Token::For,
+        "in" => Token::In,
+        "match" => Token::Match,
+        "case" => Token::Case,
+        "nil" => Token::Nil,
+        "true" => Token::True,
+        "false" => Token::False,
+        _ => return None,
+    };
+    Some(token)
+}
+
+/// A letter or `_` followed by letters, digits and `_`
+fn word_text(input: &str) -> IResult<&str, &str> {
+    recognize(pair(
+        alt((alpha1, tag("_"))),
+        many0(alt((alphanumeric1, tag("_")))),
     ))(input)
 }
 
+/// Keyword or identifier. The whole word is lexed before it is classified, so
+/// a word that starts with a keyword (`variable`, `index`) stays one
+/// identifier.
+fn word(input: &str) -> IResult<&str, Token> {
+    let (rest, word) = word_text(input)?;
+    let token = keyword(word).unwrap_or_else(|| Token::Identifier(word.to_string()));
+    Ok((rest, token))
+}
+
 fn operator(input: &str) -> IResult<&str, Token> {
+    alt((
+        punctuation,
+        comparison_operator,
+        compound_assignment_operator,
+        single_char_operator,
+    ))(input)
+}
+
+fn punctuation(input: &str) -> IResult<&str, Token> {
     alt((
         map(tag("->"), |_| Token::Arrow),
+        map(tag("=>"), |_| Token::FatArrow),
         map(char('{'), |_| Token::LBrace),
         map(char('}'), |_| Token::RBrace),
         map(char('('), |_| Token::LParen),
         map(char(')'), |_| Token::RParen),
+        map(char('['), |_| Token::LBracket),
+        map(char(']'), |_| Token::RBracket),
+        map(char(';'), |_| Token::Semicolon),
         map(char(':'), |_| Token::Colon),
         map(char(','), |_| Token::Comma),
+        map(tag("??"), |_| Token::QuestionQuestion),
+        map(char('?'), |_| Token::Question),
+        map(char('&'), |_| Token::Ampersand),
+        map(tag(".."), |_| Token::DotDot),
+        map(char('.'), |_| Token::Dot),
+        map(char('@'), |_| Token::At),
+    ))(input)
+}
+
+fn comparison_operator(input: &str) -> IResult<&str, Token> {
+    // 2 文字の演算子を先に試す (`<=` は `<` + `=` ではない)
+    alt((
+        map(tag("=="), |_| Token::EqualEqual),
+        map(tag("!="), |_| Token::NotEqual),
+        map(tag("<="), |_| Token::LessEqual),
+        map(tag(">="), |_| Token::GreaterEqual),
+        map(char('<'), |_| Token::Less),
+        map(char('>'), |_| Token::Greater),
+    ))(input)
+}
+
+fn compound_assignment_operator(input: &str) -> IResult<&str, Token> {
+    // `+=` は `+` + `=` ではない
+    alt((
+        map(tag("+="), |_| Token::PlusEquals),
+        map(tag("-="), |_| Token::MinusEquals),
+        map(tag("*="), |_| Token::MultiplyEquals),
+        map(tag("/="), |_| Token::DivideEquals),
+    ))(input)
+}
+
+fn single_char_operator(input: &str) -> IResult<&str, Token> {
+    // `==`、`=>`、`->` と `!=` を先に試した後で照合する
+    alt((
         map(char('='), |_| Token::Equals),
         map(char('+'), |_| Token::Plus),
         map(char('-'), |_| Token::Minus),
         map(char('*'), |_| Token::Multiply),
         map(char('/'), |_| Token::Divide),
+        map(char('!'), |_| Token::Exclamation),
     ))(input)
 }
 
-fn identifier(input: &str) -> IResult<&str, Token> {
-    map(
+/// `"text"`, or the head of an interpolated string up to its first `\(`
+fn string_literal(input: &str) -> IResult<&str, Token> {
+    let (rest, text) = preceded(char('"'), string_text)(input)?;
+    match rest.strip_prefix('"') {
+        Some(rest) => Ok((rest, Token::StringLiteral(text))),
+        None => map(tag("\\("), |_| Token::StringHead(text.clone()))(rest),
+    }
+}
+
+/// The rest of an interpolated string after the `)` that closes an
+/// interpolation, up to the next `\(` or the closing `"`
+fn string_continuation(input: &str) -> IResult<&str, Token> {
+    let (rest, text) = preceded(char(')'), string_text)(input)?;
+    match rest.strip_prefix('"') {
+        Some(rest) => Ok((rest, Token::StringTail(text))),
+        None => map(tag("\\("), |_| Token::StringMiddle(text.clone()))(rest),
+    }
+}
+
+/// Characters of a string up to its closing `"` or the `\(` starting an
+/// interpolation, with escape sequences decoded
+fn string_text(input: &str) -> IResult<&str, String> {
+    let mut text = String::new();
+    let mut rest = input;
+    loop {
+        let mut chars = rest.chars();
+        match chars.next() {
+            None | Some('"') => return Ok((rest, text)),
+            Some('\\') if chars.as_str().starts_with('(') => return Ok((rest, text)),
+            Some('\\') => {
+                let (next, c) = escape(rest)?;
+                text.push(c);
+                rest = next;
+            }
+            Some(c) => {
+                text.push(c);
+                rest = chars.as_str();
+            }
+        }
+    }
+}
+
+/// `\n`, `\t`, `\r`, `\0`, `\"`, `\'`, `\\` or a unicode escape such as
+/// `\u{1F600}`, as the character it stands for
+fn escape(input: &str) -> IResult<&str, char> {
+    let invalid = || nom::Err::Failure(Error::new(input, ErrorKind::Escaped));
+    let mut chars = input[1..].chars();
+    let c = match chars.next().ok_or_else(invalid)? {
+        'n' => '\n',
+        't' => '\t',
+        'r' => '\r',
+        '0' => '\0',
+        c @ ('"' | '\'' | '\\') => c,
+        'u' => {
+            // 1 から 6 桁の 16 進数で、サロゲートは文字にならない
+            let digits = chars.as_str().strip_prefix('{').ok_or_else(invalid)?;
+            let end = digits.find('}').filter(|&end| (1..=6).contains(&end));
+            let end = end.ok_or_else(invalid)?;
+            let c = u32::from_str_radix(&digits[..end], 16)
+                .ok()
+                .and_then(char::from_u32)
+                .ok_or_else(invalid)?;
+            return Ok((&digits[end + 1..], c));
+        }
+        _ => return Err(invalid()),
+    };
+    Ok((chars.as_str(), c))
+}
+
+/// The escape sequence at the start of `input`, for error messages
+fn escape_text(input: &str) -> &str {
+    let len = match input.strip_prefix("\\u{") {
+        Some(digits) => digits
+            .find('}')
+            .filter(|&end| !digits[..end].contains(['"', '\n']))
+            .map_or(2, |end| end + 4),
+        None => 1 + input[1..].chars().next().map_or(0, char::len_utf8),
+    };
+    &input[..len]
+}
+
+/// Length of the string starting at `input`, with its `"` or the `)` of an
+/// interpolation before it, up to the closing `"` or the end of the line
+fn string_len(input: &str) -> usize {
+    let mut chars = input.char_indices().skip(1);
+    while let Some((index, c)) = chars.next() {
+        match c {
+            '"' => return index + 1,
+            '\n' => return index,
+            '\\' => {
+                chars.next();
+            }
+            _ => {}
+        }
+    }
+    input.len()
+}
+
+/// Lexer error with the 1-based position where it occurred
+#[derive(Error, Debug, Clone, PartialEq)]
+#[error("{line}:{column}: {message}")]
+pub struct LexError {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+/// Text of a number literal: `0x` or `0b` and the word after it, or decimal
+/// digits with an optional fraction and exponent, any of them with `_`
+/// separators
+fn number_text(input: &str) -> IResult<&str, &str> {
+    let digits = || pair(digit1, take_while(|c: char| c.is_ascii_digit() || c == '_'));
+    alt((
+        // 範囲外の数字も取り込んで、まとめてエラーにする
         recognize(pair(
-            alt((alpha1, tag("_"))),
-            many0(alt((alphanumeric1, tag("_")))),
+            alt((tag("0x"), tag("0b"))),
+            take_while(|c: char| c.is_ascii_alphanumeric() || c == '_'),
         )),
-        |s: &str| Token::Identifier(s.to_string()),
-    )(input)
+        // `0..n` の `..` を小数点として取り込まない
+        recognize(tuple((
+            digits(),
+            opt(pair(char('.'), digits())),
+            opt(tuple((one_of("eE"), opt(one_of("+-")), digits()))),
+        ))),
+    ))(input)
 }
 
-fn string_literal(input: &str) -> IResult<&str, Token> {
-    map(
-        preceded(char('"'), terminated(take_while1(|c| c != '"'), char('"'))),
-        |s: &str| Token::StringLiteral(s.to_string()),
-    )(input)
+fn number_literal(input: &str) -> IResult<&str, Token> {
+    let (rest, text) = number_text(input)?;
+    match number_token(text) {
+        Ok(token) => Ok((rest, token)),
+        // 位置を残すため、リテラルの先頭で失敗させる
+        Err(_) => Err(nom::Err::Failure(Error::new(input, ErrorKind::Digit))),
+    }
 }
 
-fn number_literal(input: &str) -> IResult<&str, Token> {
-    map(
-        recognize(many0(alt((alphanumeric1, tag("."))))),
-        |s: &str| Token::NumberLiteral(s.to_string()),
-    )(input)
+/// The token of the number literal `text`, or why it is not a valid one.
+/// `Int` literals may be one past `i32::MAX`, which only `-` can make valid.
+fn number_token(text: &str) -> Result<Token, String> {
+    let (radix, name) = match text.get(..2) {
+        Some("0x") => (16, "hexadecimal"),
+        Some("0b") => (2, "binary"),
+        _ => (10, "decimal"),
+    };
+    // 10 進数は数字、小数点と指数だけを取り込んでいる
+    let digits = if radix == 10 { text } else { &text[2..] };
+    if radix != 10 {
+        if let Some(c) = digits.chars().find(|&c| c != '_' && !c.is_digit(radix)) {
+            return Err(format!(
+                "Invalid digit '{}' in {} literal {}",
+                c, name, text
+            ));
+        }
+        if !digits.contains(|c: char| c != '_') {
+            return Err(format!("Missing digits in {} literal {}", name, text));
+        }
+    }
+    // `_` の両側は数字でなければならない
+    let is_digit = |c: Option<char>| c.is_some_and(|c| c.is_digit(radix));
+    let separated = text.match_indices('_').all(|(index, _)| {
+        is_digit(text[..index].trim_end_matches('_').chars().last())
+            && is_digit(text[index..].trim_start_matches('_').chars().next())
+    });
+    if !separated {
+        return Err(format!(
+            "Digit separators must be between digits in {}",
+            text
+        ));
+    }
+
+    let digits = digits.replace('_', "");
+    if radix == 10 && text.contains(['.', 'e', 'E']) {
+        // 標準ライブラリの変換は最近接丸めで正確
+        let value: f64 = digits
+            .parse()
+            .map_err(|_| format!("Invalid float literal {}", text))?;
+        return match float_literal_error(text, value) {
+            Some(error) => Err(error),
+            None => Ok(Token::FloatLiteral(value)),
+        };
+    }
+    match u64::from_str_radix(&digits, radix) {
+        Ok(value) if value <= u64::from(i32::MIN.unsigned_abs()) => {
+            Ok(Token::NumberLiteral(value.to_string()))
+        }
+        _ => Err(format!("Integer literal {} is too large for an Int", text)),
+    }
+}
+
+/// Why `text` cannot be a `Float` literal: literals must be finite, and a non-zero
+/// literal must not round to zero
+fn float_literal_error(text: &str, value: f64) -> Option<String> {
+    let mantissa = text.split(['e', 'E']).next().unwrap_or(text);
+    if value.is_infinite() {
+        Some(format!("Float literal {} is too large for a Float", text))
+    } else if value == 0.0 && mantissa.contains(|c: char| ('1'..='9').contains(&c)) {
+        Some(format!("Float literal {} is too small for a Float", text))
+    } else {
+        None
+    }
 }
 
 fn token(input: &str) -> IResult<&str, Token> {
-    println!("Tokenizing input: \n{}", input); // Debugging output
-    alt((
-        keyword,
-        operator,
-        identifier,
-        string_literal,
-        number_literal,
-    ))(input)
+    alt((word, operator, string_literal, number_literal))(input)
+}
+
+/// `// comment` up to the end of the line
+pub fn line_comment(input: &str) -> IResult<&str, &str> {
+    recognize(pair(tag("//"), not_line_ending))(input)
+}
+
+/// Whitespace and comments between tokens, which the parser never sees
+fn trivia(input: &str) -> IResult<&str, &str> {
+    recognize(many0(alt((multispace1, line_comment))))(input)
+}
+
+/// Skips the trivia at the start of `input`
+fn skip_trivia(input: &str) -> &str {
+    trivia(input).map_or(input, |(rest, _)| rest)
+}
+
+/// Lexes the whole of `input`, reporting where lexing stopped if any input is left
+pub fn tokenize(input: &str) -> Result<Vec<Token>, LexError> {
+    let tokens = tokenize_with_lines(input)?;
+    Ok(tokens.into_iter().map(|(token, _)| token).collect())
+}
+
+/// Lexes the whole of `input` like [`tokenize`], pairing each token with the
+/// 1-based line it starts on
+pub fn tokenize_with_lines(input: &str) -> Result<Vec<(Token, usize)>, LexError> {
+    tokenize_with_spans(input).map(|tokens| token_lines(input, tokens))
 }
 
-pub fn lex(input: &str) -> IResult<&str, Vec<Token>> {
-    many0(terminated(token, multispace0))(input)
+/// Lexes the whole of `input` like [`tokenize`], pairing each token with the
+/// byte range it covers. Whitespace and comments lie between the ranges.
+pub fn tokenize_with_spans(input: &str) -> Result<Vec<(Token, Range<usize>)>, LexError> {
+    let (tokens, mut errors) = tokenize_recovering(input);
+    if errors.is_empty() {
+        Ok(tokens)
+    } else {
+        Err(errors.remove(0))
+    }
+}
+
+/// Lexes the whole of `input` without stopping at invalid input: each bad
+/// span becomes a [`Token::Error`] and an error, in source order, and lexing
+/// resumes after it. Ranges are as in [`tokenize_with_spans`].
+pub fn tokenize_recovering(input: &str) -> (Vec<(Token, Range<usize>)>, Vec<LexError>) {
+    let mut tokens = Vec::new();
+    let mut errors = Vec::new();
+    for (token, error) in Lexer::new(input, 0) {
+        tokens.push(token);
+        errors.extend(error);
+    }
+    (tokens, errors)
+}
+
+/// Recovering lexer over `input`, yielding each token with its byte range
+/// and, for a [`Token::Error`], its error
+struct Lexer<'a> {
+    input: &'a str,
+    rest: &'a str,
+    /// Parentheses open inside each enclosing string interpolation
+    interpolations: Vec<usize>,
+}
+
+impl<'a> Lexer<'a> {
+    /// Starts lexing at the byte `offset`, which must lie between tokens and
+    /// outside any interpolated string
+    fn new(input: &'a str, offset: usize) -> Self {
+        Lexer {
+            input,
+            rest: skip_trivia(&input[offset..]),
+            interpolations: Vec::new(),
+        }
+    }
+}
+
+impl Iterator for Lexer<'_> {
+    type Item = ((Token, Range<usize>), Option<LexError>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let rest = self.rest;
+        if rest.is_empty() {
+            return None;
+        }
+        // 補間の中で括弧が閉じきったときの `)` は文字列の続きになる
+        let lexed = match self.interpolations.last() {
+            Some(0) if rest.starts_with(')') => string_continuation(rest),
+            _ => token(rest),
+        };
+        let start = self.input.len() - rest.len();
+        let (token, span, error) = match lexed {
+            Ok((next, token)) => (token, start..self.input.len() - next.len(), None),
+            Err(error) => {
+                let (error, span) = lex_error(self.input, rest, error);
+                (
+                    Token::Error(self.input[span.clone()].to_string()),
+                    span,
+                    Some(error),
+                )
+            }
+        };
+        track_interpolations(&mut self.interpolations, &token);
+        self.rest = skip_trivia(&self.input[span.end..]);
+        Some(((token, span), error))
+    }
+}
+
+/// Updates the open parentheses of each enclosing interpolation after `token`
+fn track_interpolations(interpolations: &mut Vec<usize>, token: &Token) {
+    match (token, interpolations.last_mut()) {
+        (Token::StringHead(_), _) => interpolations.push(0),
+        // 続きを読めなかった `)` も補間を閉じる
+        (Token::StringTail(_), _) => {
+            interpolations.pop();
+        }
+        (Token::Error(text), Some(_)) if text.starts_with(')') => {
+            interpolations.pop();
+        }
+        (Token::LParen, Some(open)) => *open += 1,
+        (Token::RParen, Some(open)) => *open -= 1,
+        _ => {}
+    }
+}
+
+/// Tokens re-lexed after an edit, standing in for `start..end` of the tokens
+/// lexed before it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Relexed {
+    pub start: usize,
+    pub end: usize,
+    pub tokens: Vec<(Token, Range<usize>)>,
+    /// Errors of the [`Token::Error`]s among `tokens`, in order
+    pub errors: Vec<LexError>,
+}
+
+/// Re-lexes `input` after the bytes `edit` of the text `old` was lexed from
+/// were replaced by `inserted` bytes. Lexing restarts a token before the
+/// edit, or at the first unterminated string before it, which a `"` typed
+/// later can close, outside any interpolated string. It stops at the first
+/// token past the edit where it is back in step with `old`; the old tokens
+/// from there on stay valid once moved by the change in length.
+pub fn relex(
+    old: &[(Token, Range<usize>)],
+    input: &str,
+    edit: Range<usize>,
+    inserted: usize,
+) -> Relexed {
+    // 編集に触れるトークンの 1 つ前から。補間の途中なら一番外側の文字列の頭まで戻る
+    let touched = old.partition_point(|(_, span)| span.end < edit.start);
+    // 閉じていない文字列は後ろで `"` を打つと閉じるので、最初のものから読み直す
+    let unterminated = old[..touched]
+        .iter()
+        .position(|(token, _)| matches!(token, Token::Error(text) if text.starts_with(['"', ')'])));
+    let before = touched
+        .saturating_sub(1)
+        .min(unterminated.unwrap_or(usize::MAX));
+    let mut interpolations = Vec::new();
+    let mut outermost = None;
+    for (index, (token, _)) in old[..before].iter().enumerate() {
+        if interpolations.is_empty() {
+            outermost = Some(index);
+        }
+        track_interpolations(&mut interpolations, token);
+    }
+    let start = if interpolations.is_empty() {
+        before
+    } else {
+        outermost.unwrap_or(before)
+    };
+
+    let moved =
+        |span: &Range<usize>| span.start + inserted - edit.len()..span.end + inserted - edit.len();
+    // 先頭のトークンより前の空白やコメントが変わることもあるので、そのときは最初から読む
+    let offset = match start {
+        0 => 0,
+        _ => old[start].1.start,
+    };
+    let mut lexer = Lexer::new(input, offset);
+    let mut tokens = Vec::new();
+    let mut errors = Vec::new();
+    // 古いトークンの位置と、その直前の補間の状態
+    let mut end = start;
+    let mut old_interpolations = Vec::new();
+    loop {
+        let state = lexer.interpolations.clone();
+        let Some(((token, span), error)) = lexer.next() else {
+            end = old.len();
+            break;
+        };
+        if span.start >= edit.start + inserted {
+            // 同じ位置の古いトークンまで進め、同じ状態から同じトークンを読んだら以降は一致する
+            while end < old.len() && old[end].1.start + inserted < span.start + edit.len() {
+                track_interpolations(&mut old_interpolations, &old[end].0);
+                end += 1;
+            }
+            if let Some((old_token, old_span)) = old.get(end) {
+                if old_span.start >= edit.end
+                    && *old_token == token
+                    && moved(old_span) == span
+                    && old_interpolations == state
+                {
+                    break;
+                }
+            }
+        }
+        tokens.push((token, span));
+        errors.extend(error);
+    }
+    Relexed {
+        start,
+        end,
+        tokens,
+        errors,
+    }
+}
+
+/// Replaces the byte ranges of tokens lexed from `input` with the 1-based
+/// lines they start on
+pub fn token_lines(input: &str, tokens: Vec<(Token, Range<usize>)>) -> Vec<(Token, usize)> {
+    let (mut line, mut counted) = (1, 0);
+    tokens
+        .into_iter()
+        .map(|(token, span)| {
+            line += input[counted..span.start].matches('\n').count();
+            counted = span.start;
+            (token, line)
+        })
+        .collect()
+}
+
+/// Text of the `///` comments in `input`, keyed by the 1-based line right
+/// after each run of them, where the declaration they document starts. One
+/// space after `///` is dropped and the lines of a run are joined with `\n`.
+pub fn doc_comments(input: &str) -> HashMap<usize, String> {
+    let mut docs = HashMap::new();
+    let mut run: Vec<&str> = Vec::new();
+    let mut in_string = false;
+    for (index, line) in input.lines().enumerate() {
+        let doc = match line.trim_start().strip_prefix("///") {
+            Some(text) if !in_string => Some(text.strip_prefix(' ').unwrap_or(text)),
+            _ => None,
+        };
+        match doc {
+            Some(text) => run.push(text),
+            None if !run.is_empty() => {
+                docs.insert(index + 1, run.join("\n"));
+                run.clear();
+            }
+            None => {}
+        }
+
+        // 文字列は改行を含めるので、行をまたいで状態を持ち越す
+        let mut rest = line;
+        while let Some(c) = rest.chars().next() {
+            if !in_string && rest.starts_with("//") {
+                break;
+            }
+            in_string ^= c == '"';
+            // 文字列の中の `\"` では閉じない
+            let len = match c {
+                '\\' if in_string => rest[1..].chars().next().map_or(0, char::len_utf8),
+                _ => 0,
+            };
+            rest = &rest[c.len_utf8() + len..];
+        }
+    }
+    docs
+}
+
+/// Describes why no token could be lexed at `rest`, along with the byte range
+/// of the invalid input to skip
+fn lex_error(input: &str, rest: &str, error: nom::Err<Error<&str>>) -> (LexError, Range<usize>) {
+    let (rest, len, message) = match error {
+        // 閉じていない文字列は行末までを 1 つのエラーにする。`)` で失敗するのは補間の後の続きだけ
+        nom::Err::Error(_) if rest.starts_with(['"', ')']) => (
+            rest,
+            rest.find('\n').unwrap_or(rest.len()),
+            "Unterminated string literal".to_string(),
+        ),
+        nom::Err::Error(_) => {
+            let found = rest.chars().next().unwrap_or_default();
+            (
+                rest,
+                found.len_utf8(),
+                format!("Unexpected character '{}'", found),
+            )
+        }
+        // 文字列は閉じるところまでを 1 つのエラーにして、続きをコードとして読まない
+        nom::Err::Failure(error) if error.code == ErrorKind::Escaped => (
+            rest,
+            string_len(rest),
+            format!(
+                "Invalid escape sequence {} in string literal",
+                escape_text(error.input)
+            ),
+        ),
+        nom::Err::Failure(error) if error.code == ErrorKind::Digit => {
+            let text = number_text(error.input).map_or(error.input, |(_, text)| text);
+            let message = number_token(text)
+                .err()
+                .unwrap_or_else(|| format!("Invalid number literal {}", text));
+            (error.input, text.len(), message)
+        }
+        nom::Err::Failure(error) => {
+            let len = error.input.chars().next().map_or(0, char::len_utf8);
+            (
+                error.input,
+                len,
+                format!("Unexpected input ({:?})", error.code),
+            )
+        }
+        nom::Err::Incomplete(_) => (rest, rest.len(), "Unexpected end of input".to_string()),
+    };
+
+    // 残りの入力の長さから行と列を求める
+    let start = input.len() - rest.len();
+    let consumed = &input[..start];
+    let line = consumed.matches('\n').count() + 1;
+    let column = consumed.rsplit('\n').next().unwrap_or("").chars().count() + 1;
+    let error = LexError {
+        line,
+        column,
+        message,
+    };
+    // 何も読み飛ばさないと先に進めない
+    (error, start..start + len.max(1).min(rest.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn identifier(name: &str) -> Token {
+        Token::Identifier(name.to_string())
+    }
+
+    #[test]
+    fn test_keywords_need_a_word_boundary() {
+        let tokens = tokenize("index in format").unwrap();
+        assert_eq!(
+            tokens,
+            vec![identifier("index"), Token::In, identifier("format")]
+        );
+
+        // キーワードで始まる識別子は 1 つの識別子のまま
+        let words = "variable letter functions actors initial inout_count deinitialize \
+                     importance topics pooled structure enumerate returned breaker \
+                     continues ifs elsewhere whiled nilable matches cases yielded \
+                     let_ var2 _var singled single_actor constants";
+        let tokens = tokenize(words).unwrap();
+        let expected: Vec<Token> = words.split_whitespace().map(identifier).collect();
+        assert_eq!(tokens, expected);
+
+        // 区切りが記号でもキーワードになる
+        assert_eq!(
+            tokenize("var(x)let{nil}").unwrap(),
+            vec![
+                Token::Var,
+                Token::LParen,
+                identifier("x"),
+                Token::RParen,
+                Token::Let,
+                Token::LBrace,
+                Token::Nil,
+                Token::RBrace,
+            ]
+        );
+        // `single actor` は 2 つのトークンで、組み合わせるのは構文解析
+        assert_eq!(
+            tokenize("single\n  actor").unwrap(),
+            vec![Token::Single, Token::Actor]
+        );
+        assert_eq!(
+            tokenize("single actors").unwrap(),
+            vec![Token::Single, identifier("actors")]
+        );
+        assert_eq!(keyword("inout"), Some(Token::Inout));
+        assert_eq!(keyword("Inout"), None);
+    }
+
+    #[test]
+    fn test_comparison_operators() {
+        let tokens = tokenize("a == b != c <= d >= e < f > g = h").unwrap();
+        let operators: Vec<Token> = tokens
+            .into_iter()
+            .filter(|token| !matches!(token, Token::Identifier(_)))
+            .collect();
+        assert_eq!(
+            operators,
+            vec![
+                Token::EqualEqual,
+                Token::NotEqual,
+                Token::LessEqual,
+                Token::GreaterEqual,
+                Token::Less,
+                Token::Greater,
+                Token::Equals,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_compound_assignment_operators() {
+        let tokens = tokenize("a += 1; b -= c; d *= -2; e /= f").unwrap();
+        let operators: Vec<Token> = tokens
+            .into_iter()
+            .filter(|token| {
+                !matches!(
+                    token,
+                    Token::Identifier(_) | Token::NumberLiteral(_) | Token::Semicolon
+                )
+            })
+            .collect();
+        assert_eq!(
+            operators,
+            vec![
+                Token::PlusEquals,
+                Token::MinusEquals,
+                Token::MultiplyEquals,
+                Token::Minus,
+                Token::DivideEquals,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_range_after_number() {
+        let tokens = tokenize("for i in 0..n { }").unwrap();
+        assert_eq!(
+            tokens,
+            vec![
+                Token::For,
+                identifier("i"),
+                Token::In,
+                Token::NumberLiteral("0".to_string()),
+                Token::DotDot,
+                identifier("n"),
+                Token::LBrace,
+                Token::RBrace,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_float_literals() {
+        let tokens = tokenize("1.5 6.02e23 1E-3 2e+2 0.1e1 7").unwrap();
+        assert_eq!(
+            tokens,
+            vec![
+                Token::FloatLiteral(1.5),
+                Token::FloatLiteral(6.02e23),
+                Token::FloatLiteral(1e-3),
+                Token::FloatLiteral(200.0),
+                Token::FloatLiteral(1.0),
+                Token::NumberLiteral("7".to_string()),
+            ]
+        );
+
+        // ランタイムが出力した浮動小数点数はそのまま同じ値のリテラルとして読める
+        let mut buf = [0; replica_runtime::string::FLOAT_TEXT_CAPACITY];
+        for value in [0.1 + 0.2, 1.0 / 3.0, 1e16, 5e-324, f64::MAX] {
+            let text = replica_runtime::string::format_float(value, &mut buf);
+            assert_eq!(tokenize(text).unwrap(), vec![Token::FloatLiteral(value)]);
+        }
+    }
+
+    #[test]
+    fn test_integer_literals() {
+        let tokens =
+            tokenize("0xFF 0x7f_ff 0b1010 1_000_000 1_000.5 1e1_0 007 2147483648").unwrap();
+        let number = |text: &str| Token::NumberLiteral(text.to_string());
+        assert_eq!(
+            tokens,
+            vec![
+                number("255"),
+                number("32767"),
+                number("10"),
+                number("1000000"),
+                Token::FloatLiteral(1000.5),
+                Token::FloatLiteral(1e10),
+                number("7"),
+                number("2147483648"),
+            ]
+        );
+        // 単位は数値の直後に続く
+        assert_eq!(
+            tokenize("100ms").unwrap(),
+            vec![number("100"), identifier("ms")]
+        );
+
+        let (_, errors) =
+            tokenize_recovering("0b102 0x 0xG 1_ 1__0 2_.5 0x_1 2147483649 0x8000_0001");
+        let messages: Vec<String> = errors.iter().map(LexError::to_string).collect();
+        assert_eq!(
+            messages,
+            [
+                "1:1: Invalid digit '2' in binary literal 0b102",
+                "1:7: Missing digits in hexadecimal literal 0x",
+                "1:10: Invalid digit 'G' in hexadecimal literal 0xG",
+                "1:14: Digit separators must be between digits in 1_",
+                "1:22: Digit separators must be between digits in 2_.5",
+                "1:27: Digit separators must be between digits in 0x_1",
+                "1:32: Integer literal 2147483649 is too large for an Int",
+                "1:43: Integer literal 0x8000_0001 is too large for an Int",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_lex_errors_have_positions() {
+        let error = tokenize("let x = 1.0\nlet y = 1e400").unwrap_err();
+        assert_eq!((error.line, error.column), (2, 9));
+        assert!(error.message.contains("1e400 is too large"), "{}", error);
+
+        let error = tokenize("  let tiny = 1e-400").unwrap_err();
+        assert_eq!((error.line, error.column), (1, 14));
+        assert!(error.message.contains("too small"));
+        // 0 は丸めではない
+        assert!(tokenize("0.0e-400").is_ok());
+
+        let error = tokenize("a % b").unwrap_err();
+        assert_eq!(error.to_string(), "1:3: Unexpected character '%'");
+    }
+
+    #[test]
+    fn test_lexing_recovers_after_errors() {
+        let source = "let a = 1 % 2\nlet b = \"open\nlet c = 1e400 # d";
+        let (tokens, errors) = tokenize_recovering(source);
+        let messages: Vec<String> = errors.iter().map(LexError::to_string).collect();
+        assert_eq!(
+            messages,
+            [
+                "1:11: Unexpected character '%'",
+                "2:9: Unterminated string literal",
+                "3:9: Float literal 1e400 is too large for a Float",
+                "3:15: Unexpected character '#'",
+            ]
+        );
+
+        // 不正な入力はエラートークンになり、その後も字句解析を続ける
+        let bad: Vec<(&Token, &str)> = tokens
+            .iter()
+            .filter(|(token, _)| matches!(token, Token::Error(_)))
+            .map(|(token, span)| (token, &source[span.clone()]))
+            .collect();
+        assert_eq!(bad.len(), 4);
+        assert_eq!(bad[1], (&Token::Error("\"open".to_string()), "\"open"));
+        assert_eq!(bad[2].1, "1e400");
+        assert_eq!(tokens[4].0, Token::Error("%".to_string()));
+        assert_eq!(tokens[5].0, Token::NumberLiteral("2".to_string()));
+        assert_eq!(tokens.last().unwrap().0, identifier("d"));
+
+        // 最初のエラーは厳密な字句解析と同じ
+        assert_eq!(tokenize(source).unwrap_err(), errors[0]);
+    }
+
+    #[test]
+    fn test_no_input_is_dropped() {
+        let source = "actor A { // note\n\tlet ü = \"s \\(x) t\" € 1e400\r\n  ~ 0x 'q'\u{a0}}\n";
+        let (tokens, errors) = tokenize_recovering(source);
+        // トークンの間には空白とコメントしかない
+        let mut end = 0;
+        for (_, span) in &tokens {
+            assert!(span.start >= end && span.end > span.start);
+            assert_eq!(skip_trivia(&source[end..span.start]), "");
+            end = span.end;
+        }
+        assert_eq!(skip_trivia(&source[end..]), "");
+
+        let bad: Vec<&str> = tokens
+            .iter()
+            .filter(|(token, _)| matches!(token, Token::Error(_)))
+            .map(|(_, span)| &source[span.clone()])
+            .collect();
+        assert_eq!(bad, ["ü", "€", "1e400", "~", "0x", "'", "'", "\u{a0}"]);
+        let positions: Vec<(usize, usize)> = errors.iter().map(|e| (e.line, e.column)).collect();
+        assert_eq!(
+            positions,
+            [
+                (2, 6),
+                (2, 21),
+                (2, 23),
+                (3, 3),
+                (3, 5),
+                (3, 8),
+                (3, 10),
+                (3, 11)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_comments_are_skipped() {
+        let source = "// hello.replica\nactor A { // trailing\n  let a: Int // 1 / 2\n}//";
+        let tokens = tokenize_with_lines(source).unwrap();
+        let lines: Vec<usize> = tokens.iter().map(|(_, line)| *line).collect();
+        assert_eq!(lines, vec![2, 2, 2, 3, 3, 3, 3, 4]);
+        assert_eq!(tokens[3].0, Token::Let);
+
+        // 範囲の間には空白とコメントだけが残る
+        let spans = tokenize_with_spans(source).unwrap();
+        assert_eq!(&source[spans[0].1.clone()], "actor");
+        assert_eq!(
+            &source[spans[2].1.end..spans[3].1.start],
+            " // trailing\n  "
+        );
+        assert_eq!(tokenize("a / b").unwrap()[1], Token::Divide);
+    }
+
+    #[test]
+    fn test_token_lines() {
+        let tokens = tokenize_with_lines("\n  let a =\n\n  \"x\ny\" b").unwrap();
+        let lines: Vec<usize> = tokens.iter().map(|(_, line)| *line).collect();
+        // 文字列中の改行も後続のトークンの行に数える
+        assert_eq!(lines, vec![2, 2, 2, 4, 5]);
+        assert_eq!(tokens[3].0, Token::StringLiteral("x\ny".to_string()));
+    }
+
+    #[test]
+    fn test_string_escapes() {
+        let string = |text: &str| Token::StringLiteral(text.to_string());
+        assert_eq!(tokenize(r#""""#).unwrap(), [string("")]);
+        assert_eq!(
+            tokenize(r#""a\n\t\r\0\"\'\\b" "\u{1F600}\u{e9}""#).unwrap(),
+            [string("a\n\t\r\0\"'\\b"), string("\u{1F600}\u{e9}")]
+        );
+        // `\\(` は補間ではなく、バックスラッシュと括弧になる
+        assert_eq!(tokenize(r#""\\(x)""#).unwrap(), [string("\\(x)")]);
+        assert_eq!(
+            tokenize(r#""\"\(a)\n""#).unwrap(),
+            [
+                Token::StringHead("\"".to_string()),
+                identifier("a"),
+                Token::StringTail("\n".to_string()),
+            ]
+        );
+
+        // 不正なエスケープは文字列全体を 1 つのエラーにして、その後も読み続ける
+        let source = r#"let a = "x\qy" + "\u{D800}" + "\u{1234567}" + "\u12" b"#;
+        let (tokens, errors) = tokenize_recovering(source);
+        let messages: Vec<String> = errors.iter().map(LexError::to_string).collect();
+        assert_eq!(
+            messages,
+            [
+                "1:9: Invalid escape sequence \\q in string literal",
+                "1:18: Invalid escape sequence \\u{D800} in string literal",
+                "1:31: Invalid escape sequence \\u{1234567} in string literal",
+                "1:47: Invalid escape sequence \\u in string literal",
+            ]
+        );
+        assert_eq!(&source[tokens[3].1.clone()], r#""x\qy""#);
+        assert_eq!(tokens.last().unwrap().0, identifier("b"));
+        let (tokens, errors) = tokenize_recovering(r#""\(a)\q" + b"#);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(tokens.last().unwrap().0, identifier("b"));
+    }
+
+    #[test]
+    fn test_string_interpolation() {
+        let source = r#""sum \(f(a, (b)) + 1) of \("x\(y)")!" z"#;
+        let spans = tokenize_with_spans(source).unwrap();
+        let tokens: Vec<&Token> = spans.iter().map(|(token, _)| token).collect();
+        let text = |text: &str| text.to_string();
+        assert_eq!(
+            tokens,
+            [
+                &Token::StringHead(text("sum ")),
+                &identifier("f"),
+                &Token::LParen,
+                &identifier("a"),
+                &Token::Comma,
+                &Token::LParen,
+                &identifier("b"),
+                &Token::RParen,
+                &Token::RParen,
+                &Token::Plus,
+                &Token::NumberLiteral(text("1")),
+                &Token::StringMiddle(text(" of ")),
+                // 補間の中の文字列もまた補間できる
+                &Token::StringHead(text("x")),
+                &identifier("y"),
+                &Token::StringTail(text("")),
+                &Token::StringTail(text("!")),
+                &identifier("z"),
+            ]
+        );
+        assert_eq!(&source[spans[11].1.clone()], ") of \\(");
+        assert_eq!(&source[spans[15].1.clone()], ")!\"");
+
+        let (tokens, errors) = tokenize_recovering("\"a \\(b) c\nd");
+        assert_eq!(errors[0].to_string(), "1:7: Unterminated string literal");
+        assert_eq!(tokens.last().unwrap().0, identifier("d"));
+    }
+
+    #[test]
+    fn test_relex() {
+        // 読み直した結果を古いトークンに継ぎ合わせると、全体を読み直したものと一致する
+        let relex_edit = |source: &str, edit: Range<usize>, text: &str| {
+            let (old, _) = tokenize_recovering(source);
+            let edited = format!("{}{}{}", &source[..edit.start], text, &source[edit.end..]);
+            let relexed = relex(&old, &edited, edit.clone(), text.len());
+            let mut tokens = old[..relexed.start].to_vec();
+            tokens.extend(relexed.tokens.iter().cloned());
+            tokens.extend(old[relexed.end..].iter().map(|(token, span)| {
+                let moved =
+                    span.start + text.len() - edit.len()..span.end + text.len() - edit.len();
+                (token.clone(), moved)
+            }));
+            let (expected, errors) = tokenize_recovering(&edited);
+            assert_eq!(tokens, expected, "{:?}", edited);
+            let error_tokens = relexed
+                .tokens
+                .iter()
+                .filter(|(token, _)| matches!(token, Token::Error(_)))
+                .count();
+            assert_eq!(relexed.errors.len(), error_tokens);
+            assert!(relexed.errors.iter().all(|error| errors.contains(error)));
+            relexed
+        };
+
+        let source = "let a = 1\nlet bc = a + 2\nlet d = 3";
+        let relexed = relex_edit(source, 15..15, "x");
+        assert_eq!((relexed.start, relexed.end), (4, 6));
+        assert_eq!(relexed.tokens[1].0, identifier("bxc"));
+        // 字句の境目をまたぐ編集
+        relex_edit(source, 8..10, "");
+        relex_edit(source, 0..0, "// note\n");
+        relex_edit(source, 20..21, "+ \"open");
+        relex_edit(source, 0..source.len(), "");
+
+        // 補間の中の編集は文字列の頭から読み直す
+        let source = r#"let s = "a \(f(x)) b \(y)" + z"#;
+        let relexed = relex_edit(source, 15..16, "(w)");
+        assert_eq!(relexed.tokens[0].0, Token::StringHead("a ".to_string()));
+        relex_edit(source, 17..18, "");
+        relex_edit(source, 25..26, "");
+        relex_edit(source, 8..9, "");
+        relex_edit(source, 30..30, "\"\\(");
+    }
+
+    #[test]
+    fn test_doc_comments() {
+        let source = "/// A counter.\n///\n///   Indented.\nactor A {\n    // not a doc\n    let s: String = \"\n/// inside a string\n\"\n    ///Adds.\n    func add() { }\n}";
+        let docs = doc_comments(source);
+        assert_eq!(docs.len(), 2, "{:?}", docs);
+        assert_eq!(docs[&4], "A counter.\n\n  Indented.");
+        assert_eq!(docs[&10], "Adds.");
+    }
 }
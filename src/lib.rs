@@ -0,0 +1,187 @@
+//! Compiler for the Replica programming language, as a library.
+//!
+//! [`compile_source`] turns the source of one actor into a WASM module (or
+//! any other [`EmitKind`]) and reports failures as rendered [`Diagnostics`].
+//! The stages are available on their own as [`lexer`], [`parser`],
+//! [`semantic`] and [`codegen`], and [`query::Database`] drives them
+//! incrementally over several files. `replicac` is a command-line front end
+//! to this crate.
+
+pub mod ast;
+pub mod bindgen;
+pub mod capability;
+pub mod codegen;
+#[cfg(test)]
+mod conformance;
+pub mod consteval;
+pub mod cst;
+pub mod demangle;
+pub mod diagnostics;
+pub mod grammar;
+pub mod ice;
+pub mod incremental;
+pub mod layout;
+pub mod lexer;
+pub mod lsp;
+pub mod manifest;
+pub mod model;
+pub mod ownership;
+pub mod package;
+pub mod parser;
+pub mod plugin;
+pub mod proto;
+pub mod query;
+pub mod reduce;
+pub mod reload;
+pub mod scaffold;
+pub mod semantic;
+pub mod sharding;
+pub mod stack;
+pub mod testing;
+pub mod typestate;
+pub mod versioning;
+
+pub use crate::codegen::{CodeGenError, CodeGenOptions, EmitKind};
+pub use crate::diagnostics::Report;
+
+use crate::plugin::Hook;
+use crate::query::Database;
+use std::fmt;
+
+impl From<CodeGenError> for String {
+    fn from(error: CodeGenError) -> String {
+        error.to_string()
+    }
+}
+
+/// Settings for [`compile_source`]
+#[derive(Debug, Clone)]
+pub struct CompileOptions {
+    /// Target, optimization and feature settings of the code generator
+    pub codegen: CodeGenOptions,
+    /// What to produce; a WASM module by default
+    pub emit: EmitKind,
+    /// Name the source is reported under in diagnostics
+    pub file_name: String,
+}
+
+impl Default for CompileOptions {
+    fn default() -> Self {
+        CompileOptions {
+            codegen: CodeGenOptions::default(),
+            emit: EmitKind::default(),
+            file_name: "input.replica".to_string(),
+        }
+    }
+}
+
+/// Artifact produced by [`compile_source`]
+#[derive(Debug, Clone)]
+pub struct CompiledModule {
+    pub kind: EmitKind,
+    pub bytes: Vec<u8>,
+    /// Warnings of the lints, analyses and plugins that ran, in pass order
+    pub warnings: Vec<String>,
+}
+
+/// Errors that made [`compile_source`] fail, with the source they point into
+#[derive(Debug, Clone)]
+pub struct Diagnostics {
+    source: String,
+    reports: Vec<Report>,
+}
+
+impl Diagnostics {
+    /// Every error found, in source order within each stage
+    pub fn reports(&self) -> &[Report] {
+        &self.reports
+    }
+
+    /// Renders every report with the source lines it points at
+    pub fn render(&self, color: bool) -> String {
+        self.reports
+            .iter()
+            .map(|report| report.render(&self.source, color))
+            .collect::<String>()
+            .trim_end()
+            .to_string()
+    }
+}
+
+impl fmt::Display for Diagnostics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.render(false))
+    }
+}
+
+impl std::error::Error for Diagnostics {}
+
+/// Compiles the source of one actor into the artifact `options.emit` selects
+pub fn compile_source(
+    source: &str,
+    options: CompileOptions,
+) -> Result<CompiledModule, Diagnostics> {
+    let mut db = Database::with_options(options.codegen);
+    let file = db.set_source_text(&options.file_name, source);
+
+    match db.emit(file, options.emit) {
+        Ok(bytes) => {
+            let warnings = [Hook::PostParse, Hook::PostTypecheck, Hook::PreCodegen]
+                .into_iter()
+                .flat_map(|hook| db.warnings(file, hook).as_ref().clone())
+                .collect();
+            Ok(CompiledModule {
+                kind: options.emit,
+                bytes: bytes.as_ref().clone(),
+                warnings,
+            })
+        }
+        Err(message) => {
+            // コード生成の失敗には位置付きのレポートがない
+            let mut reports = db.error_reports(file).as_ref().clone();
+            if reports.is_empty() {
+                reports.push(Report::new("codegen", message));
+            }
+            Err(Diagnostics {
+                source: source.to_string(),
+                reports,
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compile_source() {
+        let source = "actor Counter {\n    var value: Int\n\n    func get() -> Int {\n        return value\n    }\n}\n";
+        let module = compile_source(source, CompileOptions::default()).unwrap();
+        assert_eq!(module.kind, EmitKind::Wasm);
+        assert!(module.bytes.starts_with(b"\0asm"));
+
+        let options = CompileOptions {
+            emit: EmitKind::Tokens,
+            ..CompileOptions::default()
+        };
+        let tokens = compile_source(source, options).unwrap();
+        assert!(String::from_utf8(tokens.bytes)
+            .unwrap()
+            .contains("Identifier(\"Counter\")"));
+    }
+
+    #[test]
+    fn test_compile_source_diagnostics() {
+        let source = "actor A {\n    func f() -> Int {\n        return a\n    }\n    func g() -> Int {\n        return b\n    }\n}\n";
+        let options = CompileOptions {
+            file_name: "a.replica".to_string(),
+            ..CompileOptions::default()
+        };
+        let diagnostics = compile_source(source, options).unwrap_err();
+        assert_eq!(diagnostics.reports().len(), 2);
+        let rendered = diagnostics.to_string();
+        assert!(rendered.contains("a.replica:3:16"), "{}", rendered);
+        assert!(rendered.contains("a.replica:6:16"), "{}", rendered);
+    }
+}
@@ -0,0 +1,545 @@
+//! Language server.
+//! `replicac lsp` speaks the Language Server Protocol over stdin and stdout.
+//! Open documents are kept in a `query::Database`, so a hover only reparses a
+//! file after it changed, and only the methods an edit touched. Hovering a method, field or actor name shows its
+//! signature, ownership annotations, how it runs and its `///` comment.
+
+use crate::ast::{Actor, ActorType, Field, Method, OwnershipType};
+use crate::lexer::{self, Token};
+use crate::query::{Database, FileId};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io::{BufRead, Write};
+
+/// JSON-RPC error code for a request the server does not implement
+const METHOD_NOT_FOUND: i64 = -32601;
+
+/// Declaration a hovered name refers to
+enum Symbol<'a> {
+    Actor(&'a Actor),
+    Method(&'a Method),
+    Field(&'a Field),
+}
+
+/// Markdown shown when hovering the byte `offset` of `source`, whose syntax
+/// tree is `actor`, or `None` when it is not on a name the actor declares
+pub fn hover(source: &str, actor: &Actor, offset: usize) -> Option<String> {
+    let (tokens, _) = lexer::tokenize_recovering(source);
+    // カーソルが名前の直後にある場合もその名前を指しているとみなす
+    let index = tokens.iter().position(|(token, span)| {
+        matches!(token, Token::Identifier(_)) && span.start <= offset && offset <= span.end
+    })?;
+    let Token::Identifier(name) = &tokens[index].0 else {
+        return None;
+    };
+    let next = tokens.get(index + 1).map(|(token, _)| token);
+    let method = || actor.methods.iter().find(|method| &method.name == name);
+    let field = || actor.fields.iter().find(|field| &field.name == name);
+
+    let symbol = if next == Some(&Token::LParen) {
+        method().map(Symbol::Method)
+    } else {
+        field()
+            .map(Symbol::Field)
+            .or_else(|| method().map(Symbol::Method))
+    };
+    let symbol = symbol.or_else(|| (&actor.name == name).then_some(Symbol::Actor(actor)))?;
+    Some(render(&symbol, &actor.name))
+}
+
+/// Signature in a code block, followed by how it runs and its doc comment;
+/// `actor` names the actor of methods in their symbols
+fn render(symbol: &Symbol, actor: &str) -> String {
+    let (signature, note, doc) = match symbol {
+        Symbol::Actor(actor) => (actor_signature(actor), actor_note(actor), &actor.doc),
+        Symbol::Method(method) => (
+            method_signature(method),
+            method_note(method, actor),
+            &method.doc,
+        ),
+        Symbol::Field(field) => (field_signature(field), field_note(field), &field.doc),
+    };
+    let mut text = format!("```replica\n{}\n```\n\n{}", signature, note);
+    if let Some(doc) = doc {
+        text.push_str("\n\n---\n\n");
+        text.push_str(doc);
+    }
+    text
+}
+
+fn actor_signature(actor: &Actor) -> String {
+    let mut attributes = String::new();
+    if actor.layout.packed {
+        attributes.push_str("@packed ");
+    }
+    if let Some(align) = actor.layout.align {
+        attributes.push_str(&format!("@align({}) ", align));
+    }
+    let keyword = match actor.actor_type {
+        ActorType::Distributed => "actor",
+        ActorType::Single => "single actor",
+    };
+    format!("{}{} {}", attributes, keyword, actor.name)
+}
+
+fn actor_note(actor: &Actor) -> String {
+    match actor.actor_type {
+        ActorType::Distributed => "Distributed actor: methods are called asynchronously",
+        ActorType::Single => "Single actor: optimized for use without distribution",
+    }
+    .to_string()
+}
+
+fn method_signature(method: &Method) -> String {
+    let mut signature = String::new();
+    if method.is_nonreentrant {
+        signature.push_str("@nonreentrant ");
+    }
+    if method.is_idempotent {
+        signature.push_str("@idempotent ");
+    }
+    if let Some(retry) = method.retry {
+        signature.push_str(&format!(
+            "@retry(times: {}, backoff: {}ms) ",
+            retry.times, retry.backoff_ms
+        ));
+    }
+    if let Some(since) = method.since {
+        signature.push_str(&format!("@since({}) ", since));
+    }
+    if let Some(removed) = method.removed {
+        signature.push_str(&format!("@removed({}) ", removed));
+    }
+    if method.is_sequential {
+        signature.push_str("sequential ");
+    } else if method.is_async {
+        signature.push_str("async ");
+    }
+    if method.is_immediate {
+        signature.push_str("immediate ");
+    }
+    if method.is_pure {
+        signature.push_str("pure ");
+    }
+    let params: Vec<String> = method
+        .params
+        .iter()
+        .map(|param| {
+            let marker = if method.shard_key.as_ref() == Some(&param.name) {
+                "@shardKey "
+            } else {
+                ""
+            };
+            match param.ownership {
+                OwnershipType::Inout => {
+                    format!("{}{}: inout {}", marker, param.name, param.param_type)
+                }
+                _ => format!("{}{}: {}", marker, param.name, param.param_type),
+            }
+        })
+        .collect();
+    signature.push_str(&format!("func {}({})", method.name, params.join(", ")));
+    if let Some(return_type) = &method.return_type {
+        signature.push_str(&format!(" -> {}", return_type));
+    }
+    signature
+}
+
+fn method_note(method: &Method, actor: &str) -> String {
+    let mut note = if method.is_immediate {
+        "Immediate: runs synchronously while the instance is created".to_string()
+    } else if method.is_sequential {
+        "Async, sequential: queued calls run one at a time in order".to_string()
+    } else if method.is_async {
+        format!(
+            "Async: callers await the result, or queue the call with `{}.{}.post`",
+            actor, method.name
+        )
+    } else {
+        "Synchronous".to_string()
+    };
+    if method.is_pure {
+        note.push_str("; pure, so `comptime` calls run during compilation");
+    }
+    if method.is_nonreentrant {
+        note.push_str("; nonreentrant, so other messages wait until it returns");
+    }
+    note
+}
+
+fn field_signature(field: &Field) -> String {
+    let mut signature = String::new();
+    if let Some(offset) = field.offset {
+        signature.push_str(&format!("@offset({}) ", offset));
+    }
+    let keyword = if field.is_mutable { "var" } else { "let" };
+    signature.push_str(&format!("{} {}: {}", keyword, field.name, field.field_type));
+    if let Some(ownership) = ownership_keyword(&field.ownership) {
+        signature.push_str(&format!(" {}", ownership));
+    }
+    signature
+}
+
+fn field_note(field: &Field) -> String {
+    match field.ownership {
+        OwnershipType::Owned => "Owned by the instance",
+        OwnershipType::Moved => "Moved: ownership passes to whoever reads it",
+        OwnershipType::Shared => "Shared between instances; updated with atomic built-ins",
+        OwnershipType::Copied => "Copied on every read",
+        OwnershipType::Weak => "Weak: zeroed when the target is destroyed",
+        OwnershipType::Unowned => "Unowned: must not outlive the target",
+        OwnershipType::Inout => "Borrowed for the duration of a call",
+    }
+    .to_string()
+}
+
+/// Keyword written after a field's type, if its ownership is not the default
+fn ownership_keyword(ownership: &OwnershipType) -> Option<&'static str> {
+    match ownership {
+        OwnershipType::Owned => None,
+        OwnershipType::Moved => Some("move"),
+        OwnershipType::Shared => Some("shared"),
+        OwnershipType::Copied => Some("copy"),
+        OwnershipType::Weak => Some("weak"),
+        OwnershipType::Unowned => Some("unowned"),
+        OwnershipType::Inout => Some("inout"),
+    }
+}
+
+/// Byte offset of an LSP position, whose `character` counts UTF-16 code
+/// units. Positions past the end of a line clamp to its end.
+fn offset(source: &str, line: usize, character: usize) -> Option<usize> {
+    if line > source.matches('\n').count() {
+        return None;
+    }
+    let start: usize = source.split_inclusive('\n').take(line).map(str::len).sum();
+    let text = source[start..].split('\n').next().unwrap_or_default();
+    let mut units = 0;
+    for (index, c) in text.char_indices() {
+        if units >= character {
+            return Some(start + index);
+        }
+        units += c.len_utf16();
+    }
+    Some(start + text.len())
+}
+
+/// Reads one message framed with a `Content-Length` header, or `None` at the
+/// end of the input
+fn read_message(input: &mut impl BufRead) -> Result<Option<Value>, String> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header).map_err(|e| e.to_string())? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some(value) = header.strip_prefix("Content-Length:") {
+            length = Some(
+                value
+                    .trim()
+                    .parse::<usize>()
+                    .map_err(|_| format!("Invalid Content-Length '{}'", value.trim()))?,
+            );
+        }
+    }
+    let length = length.ok_or("Message without Content-Length")?;
+    let mut body = vec![0; length];
+    input.read_exact(&mut body).map_err(|e| e.to_string())?;
+    serde_json::from_slice(&body)
+        .map(Some)
+        .map_err(|e| format!("Invalid message: {}", e))
+}
+
+fn write_message(output: &mut impl Write, message: &Value) -> Result<(), String> {
+    let body = message.to_string();
+    write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body)
+        .and_then(|_| output.flush())
+        .map_err(|e| e.to_string())
+}
+
+/// State of a running server: the open documents by URI
+#[derive(Default)]
+struct Server {
+    db: Database,
+    documents: HashMap<String, FileId>,
+}
+
+impl Server {
+    fn open(&mut self, uri: &str, text: &str) {
+        // file:// 以外の URI はそのままパスとして扱う
+        let path = uri.strip_prefix("file://").unwrap_or(uri);
+        let file = self.db.set_source_text(path, text);
+        self.documents.insert(uri.to_string(), file);
+    }
+
+    /// Applies one entry of `contentChanges`: new text for a range, or for
+    /// the whole document when it has no range
+    fn change(&mut self, uri: &str, change: &Value) {
+        let Some(text) = change["text"].as_str() else {
+            return;
+        };
+        let range = &change["range"];
+        let Some(&file) = self.documents.get(uri).filter(|_| !range.is_null()) else {
+            self.open(uri, text);
+            return;
+        };
+        let source = self.db.source_text(file);
+        let position = |position: &Value| {
+            let line = position["line"].as_u64()? as usize;
+            offset(&source, line, position["character"].as_u64()? as usize)
+        };
+        if let (Some(start), Some(end)) = (position(&range["start"]), position(&range["end"])) {
+            self.db.edit_source_text(file, start..end.max(start), text);
+        }
+    }
+
+    fn hover(&mut self, params: &Value) -> Value {
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or_default();
+        let Some(&file) = self.documents.get(uri) else {
+            return Value::Null;
+        };
+        let source = self.db.source_text(file);
+        let position = &params["position"];
+        let line = position["line"].as_u64().unwrap_or_default() as usize;
+        let character = position["character"].as_u64().unwrap_or_default() as usize;
+        let Some(offset) = offset(&source, line, character) else {
+            return Value::Null;
+        };
+        // 意味解析に失敗していても、構文木があれば宣言は引ける
+        let Ok(actor) = self.db.typed_ast(file).or_else(|_| self.db.parse(file)) else {
+            return Value::Null;
+        };
+        match hover(&source, &actor, offset) {
+            Some(value) => json!({
+                "contents": { "kind": "markdown", "value": value },
+            }),
+            None => Value::Null,
+        }
+    }
+
+    /// Result of a request, or the code and message of its error
+    fn request(&mut self, method: &str, params: &Value) -> Result<Value, (i64, String)> {
+        match method {
+            "initialize" => Ok(json!({
+                "capabilities": {
+                    "hoverProvider": true,
+                    // 変更された範囲だけを受け取る
+                    "textDocumentSync": 2,
+                },
+                "serverInfo": { "name": "replicac", "version": env!("CARGO_PKG_VERSION") },
+            })),
+            "textDocument/hover" => Ok(self.hover(params)),
+            "shutdown" => Ok(Value::Null),
+            _ => Err((METHOD_NOT_FOUND, format!("Unsupported method '{}'", method))),
+        }
+    }
+
+    fn notification(&mut self, method: &str, params: &Value) {
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or_default();
+        match method {
+            "textDocument/didOpen" => {
+                let text = params["textDocument"]["text"].as_str().unwrap_or_default();
+                self.open(uri, text);
+            }
+            "textDocument/didChange" => {
+                // 変更は送られた順に積み重なる
+                for change in params["contentChanges"].as_array().into_iter().flatten() {
+                    self.change(uri, change);
+                }
+            }
+            "textDocument/didClose" => {
+                self.documents.remove(uri);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Serves requests from `input` until the client sends `exit` or closes it
+pub fn run(mut input: impl BufRead, mut output: impl Write) -> Result<(), String> {
+    let mut server = Server::default();
+    while let Some(message) = read_message(&mut input)? {
+        let method = message["method"].as_str().unwrap_or_default();
+        if method == "exit" {
+            break;
+        }
+        let params = &message["params"];
+        let Some(id) = message.get("id") else {
+            server.notification(method, params);
+            continue;
+        };
+        let response = match server.request(method, params) {
+            Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+            Err((code, message)) => json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "error": { "code": code, "message": message },
+            }),
+        };
+        write_message(&mut output, &response)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+
+    const SOURCE: &str = "/// Keeps a running total
+actor Ledger {
+    /// Sum of every amount
+    var total: Int
+    var last: Ledger? weak
+
+    /// Adds `amount`.
+    /// Returns the new total.
+    async func add(amount: Int, log: inout [String]) -> Int {
+        self.total = self.total + amount
+        return self.total
+    }
+
+    pure func twice(value: Int) -> Int {
+        return add(value, &log) + value
+    }
+}
+";
+
+    fn parse(source: &str) -> Actor {
+        let (tokens, _) = lexer::tokenize_recovering(source);
+        Parser::with_lines(lexer::token_lines(source, tokens))
+            .with_docs(lexer::doc_comments(source))
+            .parse_actor()
+            .unwrap()
+    }
+
+    /// Hover on the last character of the first occurrence of `needle`
+    fn hover_at(needle: &str) -> Option<String> {
+        let index = SOURCE.find(needle).unwrap() + needle.len() - 1;
+        hover(SOURCE, &parse(SOURCE), index)
+    }
+
+    #[test]
+    fn test_hover() {
+        assert_eq!(
+            hover_at("return add").unwrap(),
+            "```replica\nasync func add(amount: Int, log: inout [String]) -> Int\n```\n\n\
+             Async: callers await the result, or queue the call with `Ledger.add.post`\n\n---\n\n\
+             Adds `amount`.\nReturns the new total."
+        );
+        // 宣言の上でも呼び出しの上でも同じ内容になる
+        assert_eq!(hover_at("func add"), hover_at("return add"));
+        assert_eq!(
+            hover_at("self.total").unwrap(),
+            "```replica\nvar total: Int\n```\n\nOwned by the instance\n\n---\n\nSum of every amount"
+        );
+        assert_eq!(
+            hover_at("var last").unwrap(),
+            "```replica\nvar last: Ledger? weak\n```\n\nWeak: zeroed when the target is destroyed"
+        );
+        assert!(hover_at("func twice").unwrap().contains(
+            "pure func twice(value: Int) -> Int\n```\n\n\
+             Synchronous; pure"
+        ));
+        let actor = hover_at("actor Ledger").unwrap();
+        assert!(actor.starts_with("```replica\nactor Ledger\n```"));
+        assert!(actor.ends_with("Keeps a running total"));
+        // 型名・引数・キーワード・コメントには何も出さない
+        assert_eq!(hover_at("Int"), None);
+        assert_eq!(hover_at("(amount"), None);
+        assert_eq!(hover_at("func"), None);
+        assert_eq!(hover_at("Adds `amount"), None);
+    }
+
+    #[test]
+    fn test_incremental_changes() {
+        let uri = "file:///ledger.replica";
+        let mut server = Server::default();
+        server.open(uri, SOURCE);
+        let range = |line, start, end| json!({ "start": { "line": line, "character": start }, "end": { "line": line, "character": end } });
+        // 範囲つきの変更は送られた順に当てる
+        server.notification(
+            "textDocument/didChange",
+            &json!({
+                "textDocument": { "uri": uri },
+                "contentChanges": [
+                    { "range": range(2, 8, 11), "text": "Total" },
+                    { "range": range(0, 0, 0), "text": "\n" },
+                ],
+            }),
+        );
+        let file = server.documents[uri];
+        assert_eq!(
+            server.db.source_text(file).as_ref(),
+            format!("\n{}", SOURCE.replacen("Sum", "Total", 1))
+        );
+        let hover = server.hover(&json!({
+            "textDocument": { "uri": uri },
+            "position": { "line": 4, "character": 9 },
+        }));
+        assert!(hover["contents"]["value"]
+            .as_str()
+            .unwrap()
+            .ends_with("Total of every amount"));
+    }
+
+    #[test]
+    fn test_offset() {
+        let source = "ab\n\u{1F600}x\n";
+        assert_eq!(offset(source, 0, 1), Some(1));
+        assert_eq!(offset(source, 0, 9), Some(2));
+        // サロゲートペアは 2 単位と数える
+        assert_eq!(offset(source, 1, 2), Some(7));
+        assert_eq!(offset(source, 2, 0), Some(9));
+        assert_eq!(offset(source, 3, 0), None);
+    }
+
+    #[test]
+    fn test_run() {
+        let messages = [
+            json!({ "jsonrpc": "2.0", "id": 1, "method": "initialize", "params": {} }),
+            json!({ "jsonrpc": "2.0", "method": "textDocument/didOpen", "params": {
+                "textDocument": { "uri": "file:///ledger.replica", "text": "actor A {}" },
+            }}),
+            json!({ "jsonrpc": "2.0", "method": "textDocument/didChange", "params": {
+                "textDocument": { "uri": "file:///ledger.replica" },
+                "contentChanges": [{ "text": SOURCE }],
+            }}),
+            json!({ "jsonrpc": "2.0", "id": 2, "method": "textDocument/hover", "params": {
+                "textDocument": { "uri": "file:///ledger.replica" },
+                "position": { "line": 3, "character": 9 },
+            }}),
+            json!({ "jsonrpc": "2.0", "id": 3, "method": "textDocument/definition", "params": {} }),
+            json!({ "jsonrpc": "2.0", "id": 4, "method": "shutdown" }),
+            json!({ "jsonrpc": "2.0", "method": "exit" }),
+        ];
+        let mut input = Vec::new();
+        for message in &messages {
+            write_message(&mut input, message).unwrap();
+        }
+        let mut output = Vec::new();
+        run(input.as_slice(), &mut output).unwrap();
+
+        let mut output = output.as_slice();
+        let mut responses = Vec::new();
+        while let Some(response) = read_message(&mut output).unwrap() {
+            responses.push(response);
+        }
+        assert_eq!(responses.len(), 4);
+        assert_eq!(
+            responses[0]["result"]["capabilities"]["hoverProvider"],
+            true
+        );
+        assert_eq!(
+            responses[1]["result"]["contents"]["value"],
+            "```replica\nvar total: Int\n```\n\nOwned by the instance\n\n---\n\nSum of every amount"
+        );
+        assert_eq!(responses[2]["error"]["code"], METHOD_NOT_FOUND);
+        assert_eq!(responses[3]["id"], 4);
+        assert_eq!(responses[3]["result"], Value::Null);
+    }
+}
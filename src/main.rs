@@ -1,101 +1,1042 @@
-use crate::codegen::CodeGenError;
-use inkwell::context::Context;
+use clap::builder::{PossibleValuesParser, TypedValueParser};
+use clap::{ArgGroup, Args, Parser, Subcommand};
+use inkwell::OptimizationLevel;
+use replica_compiler::ast::{Actor, LogLevel};
+use replica_compiler::codegen::{
+    Allocator, CodeGenOptions, EmitKind, ExportNames, LtoMode, OomBehavior, RandomSource,
+    TopicRoute, WasmFeature,
+};
+use replica_compiler::demangle::Demangler;
+use replica_compiler::diagnostics::ColorChoice;
+use replica_compiler::grammar::{Grammar, GrammarFormat};
+use replica_compiler::manifest::Manifest;
+use replica_compiler::model::ModelFormat;
+use replica_compiler::package::ResolvedPackage;
+use replica_compiler::plugin::Hook;
+use replica_compiler::query::{Database, FileId};
+use replica_compiler::testing::{Outcome, Report, TestTarget};
+use replica_compiler::{
+    bindgen, capability, codegen, grammar, ice, lsp, model, package, proto, reduce, reload,
+    scaffold, testing,
+};
 use std::fs;
-use std::path::Path;
+use std::io::{self, BufRead, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
 use std::process;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
 
-mod ast;
-mod codegen;
-mod lexer;
-mod ownership;
-mod parser;
-mod semantic;
+/// Registers the sources of `source_paths`, of every dependency and of the
+/// modules they import in `db`, returning the files in that order
+fn load_files(
+    db: &mut Database,
+    source_paths: &[PathBuf],
+    packages: &[ResolvedPackage],
+) -> Result<Vec<FileId>, String> {
+    let mut files = Vec::with_capacity(source_paths.len());
+    for source_path in source_paths {
+        // Read source file
+        let source = fs::read_to_string(source_path).map_err(|e| {
+            format!(
+                "Failed to read source file {}: {}",
+                source_path.display(),
+                e
+            )
+        })?;
+        files.push(db.set_source_text(source_path, source));
+    }
 
-use crate::semantic::SemanticAnalyzer;
+    // Dependencies are linked into the same artifact under their package namespace
+    for package in packages {
+        for source_path in &package.files {
+            let source = fs::read_to_string(source_path).map_err(|e| {
+                format!(
+                    "Failed to read source file {} of dependency `{}`: {}",
+                    source_path.display(),
+                    package.name,
+                    e
+                )
+            })?;
+            let file = db.set_source_text(source_path, source);
+            db.set_namespace(file, package.name.as_str());
+            files.push(file);
+        }
+    }
+
+    // Modules named by `import` are read from next to the importing file and
+    // linked in too, under the importer's namespace
+    let mut next = 0;
+    while let Some(&file) = files.get(next) {
+        next += 1;
+        for import_path in db.missing_imports(file) {
+            let source = match fs::read_to_string(&import_path) {
+                Ok(source) => source,
+                // 見つからないモジュールは意味解析が未解決の import として報告する
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => {
+                    return Err(format!(
+                        "Failed to read imported file {}: {}",
+                        import_path.display(),
+                        e
+                    ))
+                }
+            };
+            let imported = db.set_source_text(&import_path, source);
+            if files.contains(&imported) {
+                continue;
+            }
+            if let Some(namespace) = db.namespace(file).map(str::to_string) {
+                db.set_namespace(imported, namespace);
+            }
+            files.push(imported);
+        }
+    }
+    Ok(files)
+}
 
-impl From<CodeGenError> for String {
-    fn from(error: CodeGenError) -> String {
-        error.to_string()
+/// Prints the plugin warnings reported for `files` on stderr
+fn print_warnings(db: &Database, files: &[FileId]) {
+    for &file in files {
+        for hook in [Hook::PostParse, Hook::PostTypecheck, Hook::PreCodegen] {
+            for warning in db.warnings(file, hook).iter() {
+                eprintln!("warning: {}: {}", db.path(file).display(), warning);
+            }
+        }
     }
 }
 
-fn compile_file(source_path: &Path) -> Result<Vec<u8>, String> {
-    // Read source file
-    let source = fs::read_to_string(source_path)
-        .map_err(|e| format!("Failed to read source file: {}", e))?;
+/// Renders the reports of every file that failed before code generation with
+/// their source lines, or `None` when the failure left no report
+fn render_errors(db: &Database, files: &[FileId], color: bool) -> Option<String> {
+    let rendered: String = files
+        .iter()
+        .flat_map(|&file| {
+            let source = db.source_text(file);
+            db.error_reports(file)
+                .iter()
+                .map(|report| report.render(&source, color))
+                .collect::<Vec<_>>()
+        })
+        .collect();
+    (!rendered.is_empty()).then(|| rendered.trim_end().to_string())
+}
 
-    // Lexical analysis
-    let (_, tokens) = lexer::lex(&source).map_err(|e| format!("Lexer error: {}", e))?;
+fn compile_files(
+    source_paths: &[PathBuf],
+    packages: &[ResolvedPackage],
+    options: CodeGenOptions,
+    emit: EmitKind,
+    color: bool,
+) -> Result<Vec<u8>, String> {
+    // Lexing, parsing, semantic analysis and code generation are driven on demand
+    let mut db = Database::with_options(options);
+    let files = load_files(&mut db, source_paths, packages)?;
 
-    // Parsing
-    let mut parser = parser::Parser::new(tokens);
-    let ast = parser
-        .parse_actor()
-        .map_err(|e| format!("Parser error: {}", e))?;
+    // Multiple files are linked into one artifact, with optional LTO
+    let artifact = match files.as_slice() {
+        [file] => db.emit(*file, emit),
+        files => db.emit_linked(files, emit),
+    }
+    .map_err(|e| {
+        render_errors(&db, &files, color).unwrap_or_else(|| format!("Compilation error: {}", e))
+    })?;
 
-    // Semantic analysis
-    let mut analyzer = SemanticAnalyzer::new();
-    analyzer
-        .analyze_actor(&ast)
-        .map_err(|e| format!("Semantic analysis error: {}", e))?;
+    // Warnings do not stop compilation, but are reported on stderr
+    print_warnings(&db, &files);
+    Ok(artifact.as_ref().clone())
+}
+
+/// Runs every check up to code generation on the inputs and their
+/// dependencies, reporting all files that fail rather than only the first
+fn check_files(
+    source_paths: &[PathBuf],
+    packages: &[ResolvedPackage],
+    options: CodeGenOptions,
+    color: bool,
+) -> Result<(), String> {
+    let mut db = Database::with_options(options);
+    let files = load_files(&mut db, source_paths, packages)?;
 
-    // Code generation
-    let context = Context::create();
-    let module_name = source_path
+    let errors: Vec<String> = files
+        .iter()
+        .filter_map(|&file| {
+            db.typed_ast(file).err().map(|e| {
+                render_errors(&db, &[file], color)
+                    .unwrap_or_else(|| format!("{}: {}", db.path(file).display(), e))
+            })
+        })
+        .collect();
+    print_warnings(&db, &files);
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors.join("\n"))
+    }
+}
+
+/// Writes Rust host bindings for the actor in `input` to `output`, or stdout
+fn generate_bindings(input: &Path, output: Option<&Path>) -> Result<(), String> {
+    let mut db = Database::new();
+    let files = load_files(&mut db, &[input.to_path_buf()], &[])?;
+    let actor = db.typed_ast(files[0])?;
+
+    let source_name = input
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let bindings = bindgen::rust::generate(&actor, &source_name)?;
+    for skipped in &bindings.skipped {
+        eprintln!("warning: {}: {}", input.display(), skipped.message);
+    }
+
+    match output {
+        Some(path) => fs::write(path, bindings.source)
+            .map_err(|e| format!("Failed to write {}: {}", path.display(), e)),
+        None => {
+            print!("{}", bindings.source);
+            Ok(())
+        }
+    }
+}
+
+/// Generates Replica source declaring the messages of a proto3 schema
+fn import_proto(input: &Path, output: Option<&Path>) -> Result<(), String> {
+    let schema = fs::read_to_string(input)
+        .map_err(|e| format!("Failed to read schema {}: {}", input.display(), e))?;
+    let stem = input
         .file_stem()
-        .and_then(|s| s.to_str())
-        .unwrap_or("module");
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let source = proto::generate_source(&schema, &stem)
+        .map_err(|e| format!("{}: {}", input.display(), e))?;
 
-    let mut code_gen =
-        codegen::CodeGenerator::new(&context, module_name, codegen::CodeGenOptions::default())?;
+    match output {
+        Some(path) => fs::write(path, source)
+            .map_err(|e| format!("Failed to write {}: {}", path.display(), e)),
+        None => {
+            print!("{}", source);
+            Ok(())
+        }
+    }
+}
 
-    code_gen
-        .compile_actor(&ast)
-        .map_err(|e| format!("Code generation error: {}", e))?;
+/// Writes the state-transition model of every actor in `inputs` to `output`,
+/// or stdout
+fn write_model(
+    format: ModelFormat,
+    inputs: &[PathBuf],
+    output: Option<&Path>,
+) -> Result<(), String> {
+    let mut db = Database::new();
+    let files = load_files(&mut db, inputs, &[])?;
+    let mut models = Vec::with_capacity(inputs.len());
+    // 取り込んだモジュールは入力の検査に使うだけで、モデルには含めない
+    for &file in &files[..inputs.len()] {
+        models.push(model::extract(&*db.typed_ast(file)?));
+    }
 
-    // Emit WASM
-    code_gen
-        .emit_wasm()
-        .map_err(|e| format!("WASM emission error: {}", e))
+    let text = match format {
+        ModelFormat::Json => {
+            serde_json::to_string_pretty(&model::to_json(&models)).unwrap_or_default() + "\n"
+        }
+        ModelFormat::Dot => model::to_dot(&models),
+    };
+    match output {
+        Some(path) => {
+            fs::write(path, text).map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+        }
+        None => {
+            print!("{}", text);
+            Ok(())
+        }
+    }
 }
 
-fn main() {
-    let args: Vec<String> = std::env::args().collect();
-    if args.len() != 3 {
-        eprintln!("Usage: {} <input_file> <output_file>", args[0]);
-        process::exit(1);
+/// Copies stdin to stdout, rewriting the qualified export names of the
+/// actors in `inputs` to their source names
+fn demangle(inputs: &[PathBuf]) -> Result<(), String> {
+    let mut db = Database::new();
+    let files = load_files(&mut db, inputs, &[])?;
+    let mut actors = Vec::with_capacity(files.len());
+    for &file in &files {
+        let actor = db.parse(file)?;
+        actors.push(match db.namespace(file) {
+            Some(namespace) => package::namespace_actor(&actor, namespace),
+            None => actor.as_ref().clone(),
+        });
+    }
+    let demangler = Demangler::new(&actors);
+
+    let mut stdout = io::stdout().lock();
+    for line in io::stdin().lock().lines() {
+        let line = line.map_err(|e| format!("Failed to read stdin: {}", e))?;
+        writeln!(stdout, "{}", demangler.demangle_text(&line))
+            .map_err(|e| format!("Failed to write stdout: {}", e))?;
+    }
+    Ok(())
+}
+
+/// What makes an input worth keeping while `replicac reduce` shrinks it
+#[derive(Debug, Clone, PartialEq)]
+enum ReducePredicate {
+    /// Compiling it panics
+    Panics,
+    /// Checking it reports an error with this code, e.g. `parse/UnexpectedEOF`
+    Diagnostic(String),
+}
+
+/// Reduces `input` to a smaller source that still satisfies `predicate`,
+/// writing it to `output`, or stdout
+fn reduce_file(
+    predicate: &ReducePredicate,
+    input: &Path,
+    output: Option<&Path>,
+) -> Result<(), String> {
+    let mut db = Database::new();
+    let files = load_files(&mut db, &[input.to_path_buf()], &[])?;
+    let source = db.source_text(files[0]);
+
+    // 試す入力ごとのパニックは報告しない
+    let hook = panic::take_hook();
+    panic::set_hook(Box::new(|_| {}));
+    let reduced = reduce::reduce(&source, |text| {
+        let file = db.set_source_text(input, text);
+        match predicate {
+            ReducePredicate::Panics => {
+                panic::catch_unwind(AssertUnwindSafe(|| db.emit(file, EmitKind::Wasm))).is_err()
+            }
+            ReducePredicate::Diagnostic(code) => {
+                let _ = db.typed_ast(file);
+                db.error_reports(file)
+                    .iter()
+                    .any(|report| &report.code == code)
+            }
+        }
+    });
+    panic::set_hook(hook);
+
+    let reduced = reduced.ok_or_else(|| match predicate {
+        ReducePredicate::Panics => format!("Compiling {} does not panic", input.display()),
+        ReducePredicate::Diagnostic(code) => {
+            format!("{} reports no {} error", input.display(), code)
+        }
+    })?;
+    eprintln!(
+        "Reduced {} from {} to {} lines",
+        input.display(),
+        source.lines().count(),
+        reduced.lines().count()
+    );
+    match output {
+        Some(path) => fs::write(path, reduced)
+            .map_err(|e| format!("Failed to write {}: {}", path.display(), e)),
+        None => {
+            print!("{}", reduced);
+            Ok(())
+        }
     }
+}
 
-    let input_path = Path::new(&args[1]);
-    let output_path = Path::new(&args[2]);
+/// Writes the grammar of the language to `output`, or stdout
+fn write_grammar(format: GrammarFormat, output: Option<&Path>) -> Result<(), String> {
+    let text = match format {
+        GrammarFormat::Ebnf => grammar::SOURCE.to_string(),
+        GrammarFormat::TreeSitter => Grammar::replica().to_tree_sitter(),
+    };
+    match output {
+        Some(path) => {
+            fs::write(path, text).map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+        }
+        None => {
+            print!("{}", text);
+            Ok(())
+        }
+    }
+}
 
+/// Runs the test methods of `inputs` on every target and prints the report,
+/// returning whether every test passed
+fn run_tests(targets: &[TestTarget], inputs: &[PathBuf]) -> Result<bool, String> {
+    let mut db = Database::new();
+    let files = load_files(&mut db, inputs, &[])?;
+    let mut actors = Vec::with_capacity(inputs.len());
+    for &file in &files[..inputs.len()] {
+        actors.push(db.typed_ast(file)?);
+    }
+    let actors: Vec<&Actor> = actors.iter().map(AsRef::as_ref).collect();
+
+    let tests = testing::test_cases(&actors);
+    if tests.is_empty() {
+        return Err("No test methods found (expected `func test...() -> Bool`)".to_string());
+    }
+    let mut report = Report::new(tests);
+    for &target in targets {
+        let Some(triple) = target.triple() else {
+            let outcomes = testing::run_interp(&actors, report.tests());
+            report.add(target, outcomes);
+            continue;
+        };
+        let options = CodeGenOptions {
+            target_triple: triple.to_string(),
+            ..CodeGenOptions::default()
+        };
+        // ターゲットごとに別のファイルへ書き、実行後に消す
+        let module =
+            std::env::temp_dir().join(format!("replica-test-{}-{}.wasm", process::id(), target));
+        let outcomes = match compile_files(inputs, &[], options, EmitKind::Wasm, false)
+            .and_then(|bytes| fs::write(&module, bytes).map_err(|e| e.to_string()))
+        {
+            Ok(()) => testing::run_module(target, &module, report.tests()),
+            Err(e) => {
+                let error = Outcome::Error(format!("build failed: {}", e));
+                vec![error; report.tests().len()]
+            }
+        };
+        let _ = fs::remove_file(&module);
+        report.add(target, outcomes);
+    }
+
+    print!("{}", report);
+    Ok(report.passed())
+}
+
+/// Checks the capabilities `module` requires against those granted with
+/// `--allow`, then runs it with the engine, returning the engine's exit code
+fn run_module(run: &RunArgs) -> Result<i32, String> {
+    let wasm = fs::read(&run.module)
+        .map_err(|e| format!("Failed to read module {}: {}", run.module.display(), e))?;
+    let requirements = capability::read_requirements(&wasm)
+        .map_err(|e| format!("{}: {}", run.module.display(), e))?;
+    capability::check(&requirements, &run.allow)
+        .map_err(|e| format!("Refusing to run {}: {}", run.module.display(), e))?;
+
+    let engine: Vec<&str> = run.engine.split_whitespace().collect();
+    let (program, engine_args) = engine
+        .split_first()
+        .ok_or_else(|| "--engine must name a program".to_string())?;
+    // 付与した権限と制限はホストが参照できるよう環境変数でも渡す
+    let mut command = process::Command::new(program);
+    command.args(engine_args);
+    if is_wasmtime(program) {
+        command.args(run.wasmtime_limits());
+    }
+    command
+        .arg(&run.module)
+        .args(&run.args)
+        .env("REPLICA_ALLOW", run.allow.join(","));
+    if let Some(max_memory) = run.max_memory {
+        command.env("REPLICA_MAX_MEMORY", max_memory.to_string());
+    }
+    if let Some(max_fuel) = run.max_fuel {
+        command.env("REPLICA_MAX_FUEL", max_fuel.to_string());
+    }
+    if let Some(timeout) = run.timeout {
+        command.env("REPLICA_TIMEOUT_MS", timeout.as_millis().to_string());
+    }
+
+    let mut child = command
+        .spawn()
+        .map_err(|e| format!("Failed to start {}: {}", program, e))?;
+    let status = match run.timeout {
+        Some(timeout) => {
+            // エンジン自身の割り込みを優先し、応答しない場合だけ強制終了する
+            let deadline = Instant::now() + timeout + TIMEOUT_GRACE;
+            loop {
+                if let Some(status) = child
+                    .try_wait()
+                    .map_err(|e| format!("Failed to wait for {}: {}", program, e))?
+                {
+                    break status;
+                }
+                if Instant::now() >= deadline {
+                    let _ = child.kill();
+                    let _ = child.wait();
+                    return Err(format!(
+                        "{} timed out after {:?}",
+                        run.module.display(),
+                        timeout
+                    ));
+                }
+                std::thread::sleep(Duration::from_millis(10));
+            }
+        }
+        None => child
+            .wait()
+            .map_err(|e| format!("Failed to wait for {}: {}", program, e))?,
+    };
+    Ok(status.code().unwrap_or(1))
+}
+
+/// Time an engine gets past `--timeout` to stop the module itself before it is killed
+const TIMEOUT_GRACE: Duration = Duration::from_secs(1);
+
+/// Whether `program` is the wasmtime CLI, which enforces limits given as `-W` options
+fn is_wasmtime(program: &str) -> bool {
+    Path::new(program)
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        == Some("wasmtime")
+}
+
+/// Parses a duration such as `500ms`, `10s` or `2m`; plain numbers are seconds
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    let digits = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (number, unit) = s.split_at(digits);
+    let invalid = || format!("Invalid duration '{}' (expected e.g. 500ms, 10s or 2m)", s);
+    let number: u64 = number.parse().map_err(|_| invalid())?;
+    let duration = match unit.trim() {
+        "ms" => Duration::from_millis(number),
+        "" | "s" => Duration::from_secs(number),
+        "m" => Duration::from_secs(number.checked_mul(60).ok_or_else(invalid)?),
+        _ => return Err(invalid()),
+    };
+    if duration.is_zero() {
+        return Err(format!("Duration '{}' must be positive", s));
+    }
+    Ok(duration)
+}
+
+/// Value parser for a type parsed with `FromStr` from one of `names`, which
+/// clap lists in `--help` and in its errors
+fn named<T>(names: &'static [&'static str]) -> impl TypedValueParser<Value = T>
+where
+    T: FromStr<Err = String> + Clone + Send + Sync + 'static,
+{
+    PossibleValuesParser::new(names).try_map(|name| name.parse::<T>())
+}
+
+/// Parses a capability granted with `--allow`
+fn capability_name(name: &str) -> Result<String, String> {
+    let name = name.trim();
+    if !capability::is_valid_name(name) {
+        return Err(format!("Invalid capability: {:?}", name));
+    }
+    Ok(name.to_string())
+}
+
+/// Backends `replicac test` runs on, parsed from one comma-separated value
+type TestTargets = Vec<TestTarget>;
+
+/// WASM proposals enabled with `--wasm-features`, parsed from one
+/// comma-separated value
+type WasmFeatures = Vec<WasmFeature>;
+
+/// Threshold of `--state-size-warning`, `None` when it is `off`
+type SizeThreshold = Option<u32>;
+
+/// Command-line interface of `replicac`
+#[derive(Debug, Parser)]
+#[command(
+    name = "replicac",
+    version,
+    about = "Compiler for the Replica programming language"
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+/// What `replicac` does
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Compiles the inputs and writes the artifact selected by --emit
+    Build {
+        #[command(flatten)]
+        compile: CompileArgs,
+        /// Artifact to write
+        #[arg(long, default_value = "wasm", value_parser = named::<EmitKind>(EmitKind::NAMES))]
+        emit: EmitKind,
+        /// Rebuilds whenever an input or the manifest changes, until interrupted
+        #[arg(long)]
+        watch: bool,
+    },
+    /// Reports errors and warnings without generating code
+    Check(CompileArgs),
+    /// Prints the syntax tree of the inputs
+    Ast(CompileArgs),
+    /// Prints the tokens of the inputs
+    Tokens(CompileArgs),
+    /// Runs the test methods of the inputs on several backends
+    Test {
+        /// Comma-separated backends to run on: interp, wasi, browser-sim
+        #[arg(long, default_value = "interp,wasi,browser-sim", value_parser = TestTarget::parse_list)]
+        target: TestTargets,
+        /// Source files with test methods
+        #[arg(required = true)]
+        inputs: Vec<PathBuf>,
+    },
+    /// Creates a project with an actor, a host harness and tests
+    New {
+        /// Directory the project is created in
+        path: PathBuf,
+        /// Target triple the project builds for
+        #[arg(long, default_value_t = CodeGenOptions::default().target_triple)]
+        target: String,
+    },
+    /// Runs a compiled module with the capabilities it is granted
+    Run(RunArgs),
+    /// Writes which methods change which fields of every actor
+    Model {
+        /// Format of the model
+        #[arg(long, default_value = "json", value_parser = named::<ModelFormat>(ModelFormat::NAMES))]
+        format: ModelFormat,
+        /// Where the model is written instead of stdout
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        /// Source files of the actors
+        #[arg(required = true)]
+        inputs: Vec<PathBuf>,
+    },
+    /// Replaces qualified export names read from stdin with source names
+    Demangle {
+        /// Source files the symbols were compiled from
+        #[arg(required = true)]
+        inputs: Vec<PathBuf>,
+    },
+    /// Shrinks an input while the compiler still fails on it the same way
+    Reduce(ReduceArgs),
+    /// Writes the grammar of the language
+    Grammar {
+        /// Format of the grammar
+        #[arg(long, default_value = "ebnf", value_parser = named::<GrammarFormat>(GrammarFormat::NAMES))]
+        format: GrammarFormat,
+        /// Where the grammar is written instead of stdout
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Speaks the Language Server Protocol over stdin and stdout
+    Lsp,
+    /// Generates Rust bindings for the actors of a file
+    Bindgen {
+        /// Source file of the actors
+        input: PathBuf,
+        /// Where the bindings are written instead of stdout
+        output: Option<PathBuf>,
+    },
+    /// Generates Replica records from a proto3 schema
+    ImportProto {
+        /// proto3 schema
+        input: PathBuf,
+        /// Where the source is written instead of stdout
+        output: Option<PathBuf>,
+    },
+}
+
+/// Inputs and code generation flags shared by `build`, `check`, `ast` and
+/// `tokens`. Flags override `replica.toml`, which overrides the defaults.
+#[derive(Debug, Args)]
+struct CompileArgs {
+    /// Source files, compiled together into one module
+    #[arg(required = true)]
+    inputs: Vec<PathBuf>,
+    /// Where the artifact is written; `build` defaults to the first input
+    /// with the artifact's extension, the others to stdout
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+    /// LLVM optimization level, 0 to 3
+    #[arg(long, value_parser = codegen::parse_opt_level)]
+    opt_level: Option<OptimizationLevel>,
+    /// Logs each code generation step
+    #[arg(long)]
+    debug: bool,
+    /// Target triple
+    #[arg(long = "target", value_name = "TRIPLE")]
+    target_triple: Option<String>,
+    /// LLVM target CPU such as generic or bleeding-edge
+    #[arg(long)]
+    cpu: Option<String>,
+    /// LLVM target features such as +simd128
+    #[arg(long)]
+    target_features: Option<String>,
+    /// Link-time optimization across the inputs
+    #[arg(long, value_parser = named::<LtoMode>(LtoMode::NAMES))]
+    lto: Option<LtoMode>,
+    /// Comma-separated WASM proposals to enable
+    #[arg(long, value_parser = WasmFeature::parse_list)]
+    wasm_features: Option<WasmFeatures>,
+    /// Largest linear memory, a multiple of the 64KiB page size
+    #[arg(long, value_parser = codegen::parse_memory_limit)]
+    max_memory: Option<u32>,
+    /// What allocation does once linear memory is exhausted
+    #[arg(long, value_parser = named::<OomBehavior>(OomBehavior::NAMES))]
+    on_oom: Option<OomBehavior>,
+    /// Allocator the runtime serves allocations with
+    #[arg(long, value_parser = named::<Allocator>(Allocator::NAMES))]
+    allocator: Option<Allocator>,
+    /// Actor state size to warn above, or off
+    #[arg(long, value_parser = codegen::parse_size_threshold)]
+    state_size_warning: Option<SizeThreshold>,
+    /// Stack size the module is linked with
+    #[arg(long, value_parser = codegen::parse_size)]
+    stack_size: Option<u32>,
+    /// Traps when the stack overflows into the data below it
+    #[arg(long)]
+    stack_guard: bool,
+    /// Leaves out the bounds checks of array accesses
+    #[arg(long)]
+    no_bounds_checks: bool,
+    /// Exports an HTTP facade over the actor's methods
+    #[arg(long)]
+    http_facade: bool,
+    /// Source of the bits behind the random number built-ins
+    #[arg(long, value_parser = named::<RandomSource>(RandomSource::NAMES))]
+    random: Option<RandomSource>,
+    /// Where topic values are routed between publishers and subscribers
+    #[arg(long, value_parser = named::<TopicRoute>(TopicRoute::NAMES))]
+    topics: Option<TopicRoute>,
+    /// Least severe log statement compiled in
+    #[arg(long, value_parser = named::<LogLevel>(LogLevel::NAMES))]
+    log_level: Option<LogLevel>,
+    /// Command that links the module, such as "rust-lld -flavor wasm"
+    #[arg(long)]
+    linker: Option<String>,
+    /// Runtime library linked into the module
+    #[arg(long = "runtime-lib")]
+    runtime_library: Option<PathBuf>,
+    /// Names the methods are exported under
+    #[arg(long, value_parser = named::<ExportNames>(ExportNames::NAMES))]
+    export_names: Option<ExportNames>,
+    /// When diagnostics are colored
+    #[arg(long, default_value = "auto", value_parser = named::<ColorChoice>(ColorChoice::NAMES))]
+    color: ColorChoice,
+}
+
+impl CompileArgs {
+    /// Manifest of the project the first input belongs to, if any
+    fn manifest_path(&self) -> Option<PathBuf> {
+        Manifest::find(self.inputs[0].parent().unwrap_or(Path::new(".")))
+    }
+
+    /// Builds code generation options: defaults, then `replica.toml`, then CLI flags
+    fn codegen_options(&self) -> Result<CodeGenOptions, String> {
+        let mut options = CodeGenOptions::default();
+
+        if let Some(manifest_path) = self.manifest_path() {
+            Manifest::load(&manifest_path)?.apply(&mut options)?;
+        }
+
+        if let Some(triple) = &self.target_triple {
+            options.target_triple = triple.clone();
+        }
+        if let Some(opt_level) = self.opt_level {
+            options.optimization_level = opt_level;
+        }
+        if self.debug {
+            options.debug_mode = true;
+        }
+        if let Some(cpu) = &self.cpu {
+            options.cpu = cpu.clone();
+        }
+        if let Some(features) = &self.target_features {
+            options.target_features = features.clone();
+        }
+        if let Some(lto) = self.lto {
+            options.lto = lto;
+        }
+        if let Some(features) = &self.wasm_features {
+            options.wasm_features = features.clone();
+        }
+        if let Some(max_memory) = self.max_memory {
+            options.max_memory = Some(max_memory);
+        }
+        if let Some(on_oom) = self.on_oom {
+            options.on_oom = on_oom;
+        }
+        if let Some(allocator) = self.allocator {
+            options.allocator = allocator;
+        }
+        if let Some(threshold) = self.state_size_warning {
+            options.state_size_warning = threshold;
+        }
+        if let Some(stack_size) = self.stack_size {
+            options.stack_size = stack_size;
+        }
+        if self.stack_guard {
+            options.stack_guard = true;
+        }
+        if self.no_bounds_checks {
+            options.bounds_checks = false;
+        }
+        if self.http_facade {
+            options.http_facade = true;
+        }
+        if let Some(random) = self.random {
+            options.random = random;
+        }
+        if let Some(topics) = self.topics {
+            options.topics = topics;
+        }
+        if let Some(log_level) = self.log_level {
+            options.log_level = log_level;
+        }
+        if let Some(linker) = &self.linker {
+            options.linker = linker.clone();
+        }
+        if let Some(runtime_library) = &self.runtime_library {
+            options.runtime_library = Some(runtime_library.clone());
+        }
+        if let Some(export_names) = self.export_names {
+            options.export_names = export_names;
+        }
+
+        Ok(options)
+    }
+
+    /// Code generation options and resolved dependencies of the inputs
+    fn prepare(&self) -> Result<(CodeGenOptions, Vec<ResolvedPackage>), String> {
+        let options = self
+            .codegen_options()
+            .map_err(|e| format!("Configuration error: {}", e))?;
+        let packages = match self.manifest_path() {
+            Some(path) => {
+                package::resolve(&path).map_err(|e| format!("Dependency error: {}", e))?
+            }
+            None => Vec::new(),
+        };
+        // 成果物を標準出力に書くこともあるので、経過は標準エラーに出す
+        for package in &packages {
+            eprintln!("Using dependency {} ({})", package.name, package.source);
+        }
+        Ok((options, packages))
+    }
+
+    /// The inputs as a comma-separated list for messages
+    fn input_list(&self) -> String {
+        let inputs: Vec<String> = self
+            .inputs
+            .iter()
+            .map(|input| input.display().to_string())
+            .collect();
+        inputs.join(", ")
+    }
+}
+
+/// Arguments of `replicac run`
+#[derive(Debug, Args)]
+struct RunArgs {
+    /// Comma-separated capabilities granted to the module
+    #[arg(long, value_delimiter = ',', value_parser = capability_name)]
+    allow: Vec<String>,
+    /// Program and leading arguments the module is passed to
+    #[arg(long, default_value = "wasmtime run")]
+    engine: String,
+    /// Largest linear memory the module may grow to, in bytes
+    #[arg(long, value_parser = codegen::parse_size)]
+    max_memory: Option<u32>,
+    /// Fuel the module may consume, roughly one unit per instruction
+    #[arg(long, visible_alias = "max-instructions", value_parser = clap::value_parser!(u64).range(1..))]
+    max_fuel: Option<u64>,
+    /// Wall-clock time the module may run for, such as 500ms, 10s or 2m
+    #[arg(long, value_parser = parse_duration)]
+    timeout: Option<Duration>,
+    /// Module to run
+    module: PathBuf,
+    /// Arguments passed through to the module
+    #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+    args: Vec<String>,
+}
+
+impl RunArgs {
+    /// wasmtime options enforcing the limits: memory through its resource
+    /// limiter, fuel metering, and epoch interruption for the timeout
+    fn wasmtime_limits(&self) -> Vec<String> {
+        let mut options = Vec::new();
+        if let Some(max_memory) = self.max_memory {
+            options.push(format!("max-memory-size={}", max_memory));
+        }
+        if let Some(max_fuel) = self.max_fuel {
+            options.push(format!("fuel={}", max_fuel));
+        }
+        if let Some(timeout) = self.timeout {
+            options.push(format!("timeout={}ms", timeout.as_millis()));
+        }
+        options
+            .into_iter()
+            .flat_map(|option| ["-W".to_string(), option])
+            .collect()
+    }
+}
+
+/// Arguments of `replicac reduce`
+#[derive(Debug, Args)]
+#[command(group(ArgGroup::new("predicate").required(true).args(["panics", "diagnostic"])))]
+struct ReduceArgs {
+    /// Keeps reductions on which the compiler panics
+    #[arg(long)]
+    panics: bool,
+    /// Keeps reductions that report an error with this code
+    #[arg(long, value_name = "CODE")]
+    diagnostic: Option<String>,
+    /// Where the reduced source is written instead of stdout
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+    /// Source file to shrink
+    input: PathBuf,
+}
+
+impl ReduceArgs {
+    fn predicate(&self) -> ReducePredicate {
+        match &self.diagnostic {
+            Some(code) => ReducePredicate::Diagnostic(code.clone()),
+            None => ReducePredicate::Panics,
+        }
+    }
+}
+
+/// Reports the errors of the inputs without writing anything
+fn check(args: &CompileArgs) -> Result<(), String> {
+    let (options, packages) = args.prepare()?;
+    check_files(&args.inputs, &packages, options, args.color.enabled())?;
+    println!("No errors in {}", args.input_list());
+    Ok(())
+}
+
+/// Compiles the inputs to `emit`, written to `output`, or stdout
+fn build(args: &CompileArgs, emit: EmitKind, output: Option<&Path>) -> Result<(), String> {
+    let (options, packages) = args.prepare()?;
+    // 標準出力に書く場合は進捗を表示しない
+    let Some(output) = output else {
+        let bytes = compile_files(&args.inputs, &packages, options, emit, args.color.enabled())?;
+        return io::stdout()
+            .write_all(&bytes)
+            .map_err(|e| format!("Failed to write output: {}", e));
+    };
     println!(
-        "Compiling {} to {}",
-        input_path.display(),
-        output_path.display()
+        "Compiling {} to {} ({})",
+        args.input_list(),
+        output.display(),
+        options.target_triple
     );
+    let bytes = compile_files(&args.inputs, &packages, options, emit, args.color.enabled())?;
+    fs::write(output, bytes).map_err(|e| format!("Failed to write output file: {}", e))?;
+    println!("Successfully compiled to {}", emit);
+    Ok(())
+}
+
+/// How often `build --watch` looks for changed files
+const WATCH_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Rebuilds `output` whenever an input or the manifest changes. A failed build
+/// is reported and the previous output kept, so a host watching it goes on
+/// running the last module that compiled.
+fn watch_build(args: &CompileArgs, emit: EmitKind, output: &Path) -> Result<(), String> {
+    let mut built = None;
+    loop {
+        let stamps = modification_times(args);
+        if built.as_ref() != Some(&stamps) {
+            let previous = fs::read(output).ok();
+            match build(args, emit, Some(output)) {
+                Ok(()) if emit == EmitKind::Wasm => {
+                    if let Some(previous) = previous {
+                        report_reload(&previous, output);
+                    }
+                }
+                Ok(()) => {}
+                Err(e) => eprintln!("{}", e),
+            }
+            built = Some(stamps);
+            println!("Watching {} for changes", args.input_list());
+        }
+        std::thread::sleep(WATCH_INTERVAL);
+    }
+}
+
+/// Modification times of the inputs and the manifest; a file that cannot be
+/// read counts as changed once it can
+fn modification_times(args: &CompileArgs) -> Vec<Option<std::time::SystemTime>> {
+    args.inputs
+        .iter()
+        .cloned()
+        .chain(args.manifest_path())
+        .map(|path| fs::metadata(path).and_then(|meta| meta.modified()).ok())
+        .collect()
+}
+
+/// Warns about the state a host could not carry from the module built before,
+/// `previous`, into the one just written to `output`, since hosts refuse to
+/// reload such a module
+fn report_reload(previous: &[u8], output: &Path) {
+    let Ok(current) = fs::read(output) else {
+        return;
+    };
+    let (Ok(old), Ok(new)) = (
+        reload::read_state_schemas(previous),
+        reload::read_state_schemas(&current),
+    ) else {
+        return;
+    };
+    for problem in reload::reload_problems(&old, &new) {
+        eprintln!(
+            "warning: hosts cannot reload {} keeping its state: {}",
+            output.display(),
+            problem
+        );
+    }
+}
 
-    // Compile the source file
-    match compile_file(input_path) {
-        Ok(wasm_bytes) => {
-            // Write the output file
-            if let Err(e) = fs::write(output_path, wasm_bytes) {
-                eprintln!("Failed to write output file: {}", e);
-                process::exit(1);
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    // 内部エラーは生のバックトレースではなく、位置と再現用の入力を報告する
+    ice::install(args);
+
+    let result = match Cli::parse().command {
+        Command::Build {
+            compile,
+            emit,
+            watch,
+        } => {
+            let output = compile
+                .output
+                .clone()
+                .unwrap_or_else(|| compile.inputs[0].with_extension(emit.extension()));
+            if watch {
+                watch_build(&compile, emit, &output)
+            } else {
+                build(&compile, emit, Some(&output))
             }
-            println!("Successfully compiled to WASM");
         }
-        Err(e) => {
-            eprintln!("Compilation error: {}", e);
-            process::exit(1);
+        Command::Check(compile) => check(&compile),
+        Command::Ast(compile) => build(&compile, EmitKind::Ast, compile.output.as_deref()),
+        Command::Tokens(compile) => build(&compile, EmitKind::Tokens, compile.output.as_deref()),
+        Command::Test { target, inputs } => match run_tests(&target, &inputs) {
+            Ok(true) => Ok(()),
+            Ok(false) => process::exit(1),
+            Err(e) => Err(e),
+        },
+        Command::New { path, target } => scaffold::create_project(&path, &target).map(|_| {
+            println!("Created project {}", path.display());
+            println!(
+                "See {} to build and run it",
+                path.join("README.md").display()
+            );
+        }),
+        Command::Run(run) => run_module(&run).map(|code| process::exit(code)),
+        Command::Model {
+            format,
+            output,
+            inputs,
+        } => write_model(format, &inputs, output.as_deref()),
+        Command::Demangle { inputs } => demangle(&inputs),
+        Command::Reduce(reduce) => {
+            reduce_file(&reduce.predicate(), &reduce.input, reduce.output.as_deref())
         }
+        Command::Grammar { format, output } => write_grammar(format, output.as_deref()),
+        Command::Lsp => lsp::run(io::stdin().lock(), io::stdout().lock())
+            .map_err(|e| format!("Language server error: {}", e)),
+        Command::ImportProto { input, output } => import_proto(&input, output.as_deref()),
+        Command::Bindgen { input, output } => generate_bindings(&input, output.as_deref()),
+    };
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        process::exit(1);
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::path::PathBuf;
 
     #[test]
     fn test_basic_compilation() {
@@ -112,9 +1053,519 @@ mod tests {
         let test_path = PathBuf::from("test.replica");
         fs::write(&test_path, test_source).unwrap();
 
-        let result = compile_file(&test_path);
+        let result = compile_files(
+            std::slice::from_ref(&test_path),
+            &[],
+            CodeGenOptions::default(),
+            EmitKind::Wasm,
+            false,
+        );
         fs::remove_file(&test_path).unwrap();
 
         assert!(result.is_ok(), "Compilation failed: {:?}", result.err());
     }
+
+    /// Parses `replicac <args>`
+    fn parse(args: &[&str]) -> Result<Command, clap::Error> {
+        Cli::try_parse_from(std::iter::once("replicac").chain(args.iter().copied()))
+            .map(|cli| cli.command)
+    }
+
+    /// Parses `replicac build <args>`, returning the compile arguments and `--emit`
+    fn parse_build(args: &[&str]) -> Result<(CompileArgs, EmitKind), clap::Error> {
+        let args: Vec<&str> = std::iter::once("build")
+            .chain(args.iter().copied())
+            .collect();
+        match parse(&args)? {
+            Command::Build { compile, emit, .. } => Ok((compile, emit)),
+            command => panic!("parsed as {:?}", command),
+        }
+    }
+
+    /// Code generation options of `replicac build <args>`
+    fn build_options(args: &[&str]) -> Result<CodeGenOptions, String> {
+        parse_build(args)
+            .map_err(|e| e.to_string())?
+            .0
+            .codegen_options()
+    }
+
+    #[test]
+    fn test_cli_definition() {
+        use clap::CommandFactory;
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn test_cli_target_flags() {
+        let (cli, emit) = parse_build(&[
+            "--target",
+            "wasm32-wasi",
+            "in.replica",
+            "--target-features",
+            "+simd128",
+            "-o",
+            "out.wasm",
+        ])
+        .unwrap();
+        assert_eq!(cli.inputs, vec![PathBuf::from("in.replica")]);
+        assert_eq!(cli.output, Some(PathBuf::from("out.wasm")));
+
+        let options = cli.codegen_options().unwrap();
+        assert_eq!(options.target_triple, "wasm32-wasi");
+        assert_eq!(options.cpu, "generic");
+        assert_eq!(options.target_features, "+simd128");
+        assert_eq!(emit, EmitKind::Wasm);
+    }
+
+    #[test]
+    fn test_cli_emit_kind() {
+        let (_, emit) = parse_build(&["--emit", "staticlib", "in.replica"]).unwrap();
+        assert_eq!(emit, EmitKind::StaticLib);
+        let (_, emit) = parse_build(&["--emit=llvm-ir", "in.replica"]).unwrap();
+        assert_eq!(emit, EmitKind::LlvmIr);
+        let (_, emit) = parse_build(&["--emit", "layout", "in.replica"]).unwrap();
+        assert_eq!(emit, EmitKind::Layout);
+        assert_eq!(emit.extension(), "layout");
+
+        for bad in [["--emit", "exe"], ["--stack-guard=yes", "--emit=wat"]] {
+            assert!(
+                parse_build(&[bad[0], bad[1], "in.replica"]).is_err(),
+                "{:?} was accepted",
+                bad
+            );
+        }
+    }
+
+    #[test]
+    fn test_cli_watch() {
+        match parse(&["build", "--watch", "in.replica"]).unwrap() {
+            Command::Build { watch, .. } => assert!(watch),
+            command => panic!("parsed as {:?}", command),
+        }
+        match parse(&["build", "in.replica"]).unwrap() {
+            Command::Build { watch, .. } => assert!(!watch),
+            command => panic!("parsed as {:?}", command),
+        }
+        // 監視は build だけが受け付ける
+        assert!(parse(&["check", "--watch", "in.replica"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_multiple_inputs_with_lto() {
+        let (cli, _) =
+            parse_build(&["a.replica", "b.replica", "--lto", "thin", "-o", "out.wasm"]).unwrap();
+        assert_eq!(
+            cli.inputs,
+            vec![PathBuf::from("a.replica"), PathBuf::from("b.replica")]
+        );
+        assert_eq!(cli.output, Some(PathBuf::from("out.wasm")));
+        assert_eq!(cli.codegen_options().unwrap().lto, LtoMode::Thin);
+    }
+
+    #[test]
+    fn test_cli_subcommands() {
+        let (cli, emit) = parse_build(&[
+            "-o",
+            "out.wasm",
+            "--opt-level",
+            "3",
+            "--debug",
+            "--target",
+            "wasm32-wasi",
+            "a.replica",
+            "b.replica",
+        ])
+        .unwrap();
+        assert_eq!(emit, EmitKind::Wasm);
+        assert_eq!(
+            cli.inputs,
+            vec![PathBuf::from("a.replica"), PathBuf::from("b.replica")]
+        );
+        assert_eq!(cli.output, Some(PathBuf::from("out.wasm")));
+        let options = cli.codegen_options().unwrap();
+        assert_eq!(options.optimization_level, OptimizationLevel::Aggressive);
+        assert!(options.debug_mode);
+        assert_eq!(options.target_triple, "wasm32-wasi");
+
+        let Command::Check(cli) = parse(&["check", "a.replica"]).unwrap() else {
+            panic!("check was not parsed");
+        };
+        assert_eq!(cli.output, None);
+
+        let Command::Ast(cli) = parse(&["ast", "a.replica"]).unwrap() else {
+            panic!("ast was not parsed");
+        };
+        assert_eq!(cli.output, None);
+
+        let Command::Tokens(cli) = parse(&["tokens", "a.replica", "-o", "a.tokens"]).unwrap()
+        else {
+            panic!("tokens was not parsed");
+        };
+        assert_eq!(cli.output, Some(PathBuf::from("a.tokens")));
+
+        let (cli, _) = parse_build(&["--opt-level=0", "a.replica"]).unwrap();
+        assert_eq!(
+            cli.codegen_options().unwrap().optimization_level,
+            OptimizationLevel::None
+        );
+
+        let Command::Test { target, inputs } = parse(&["test", "a.replica"]).unwrap() else {
+            panic!("test was not parsed");
+        };
+        assert_eq!(target, TestTarget::ALL);
+        assert_eq!(inputs, vec![PathBuf::from("a.replica")]);
+        let Command::Test { target, .. } =
+            parse(&["test", "--target", "wasi,interp,wasi", "a.replica"]).unwrap()
+        else {
+            panic!("test was not parsed");
+        };
+        assert_eq!(target, [TestTarget::Wasi, TestTarget::Interp]);
+
+        for bad in [
+            &["ast", "--emit", "wat", "a.replica"][..],
+            &["check"],
+            &["build", "--opt-level", "4", "a.replica"],
+            &["build", "--debug=yes", "a.replica"],
+            &["test", "--target", "jvm", "a.replica"],
+            &["test"],
+            // サブコマンドなしの形は受け付けない
+            &["a.replica", "a.wasm"],
+        ] {
+            assert!(parse(bad).is_err(), "{:?} was accepted", bad);
+        }
+    }
+
+    #[test]
+    fn test_cli_missing_flag_value() {
+        assert!(parse_build(&["in.replica", "--cpu"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_memory_options() {
+        let options = build_options(&[
+            "--max-memory",
+            "16MiB",
+            "--on-oom",
+            "trap",
+            "--allocator",
+            "external",
+            "--state-size-warning",
+            "off",
+            "in.replica",
+        ])
+        .unwrap();
+        assert_eq!(options.max_memory, Some(16 << 20));
+        assert_eq!(options.on_oom, OomBehavior::Trap);
+        assert_eq!(options.allocator, Allocator::External);
+        assert_eq!(options.state_size_warning, None);
+        assert!(!options.stack_guard);
+        assert!(options.bounds_checks);
+
+        let options = build_options(&["--state-size-warning", "4KiB", "in.replica"]).unwrap();
+        assert_eq!(options.state_size_warning, Some(4096));
+        assert!(build_options(&["--max-memory", "1000", "in.replica"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_stack_options() {
+        let (cli, _) = parse_build(&[
+            "--stack-guard",
+            "--no-bounds-checks",
+            "--stack-size",
+            "1MiB",
+            "in.replica",
+        ])
+        .unwrap();
+        assert_eq!(cli.inputs, vec![PathBuf::from("in.replica")]);
+        let options = cli.codegen_options().unwrap();
+        assert_eq!(options.stack_size, 1 << 20);
+        assert!(options.stack_guard);
+        assert!(!options.bounds_checks);
+        assert!(!options.http_facade);
+
+        assert!(
+            build_options(&["--http-facade", "in.replica"])
+                .unwrap()
+                .http_facade
+        );
+    }
+
+    #[test]
+    fn test_cli_random_source() {
+        let options = build_options(&["--random", "host", "in.replica"]).unwrap();
+        assert_eq!(options.random, RandomSource::Host);
+        assert!(build_options(&["--random", "os", "in.replica"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_topic_route() {
+        let options = build_options(&["--topics", "transport", "in.replica"]).unwrap();
+        assert_eq!(options.topics, TopicRoute::Transport);
+        assert!(build_options(&["--topics", "kafka", "in.replica"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_log_level() {
+        let options = build_options(&["--log-level", "warn", "in.replica"]).unwrap();
+        assert_eq!(options.log_level, LogLevel::Warn);
+        assert!(build_options(&["--log-level", "trace", "in.replica"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_color() {
+        let (cli, _) = parse_build(&["--color=never", "in.replica"]).unwrap();
+        assert_eq!(cli.color, ColorChoice::Never);
+        let (cli, _) = parse_build(&["in.replica"]).unwrap();
+        assert_eq!(cli.color, ColorChoice::Auto);
+        assert!(parse_build(&["--color", "sometimes", "in.replica"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_linker() {
+        let options = build_options(&[
+            "--linker",
+            "rust-lld -flavor wasm",
+            "--runtime-lib",
+            "lib/libreplica_runtime.a",
+            "--export-names",
+            "qualified",
+            "in.replica",
+        ])
+        .unwrap();
+        assert_eq!(options.linker, "rust-lld -flavor wasm");
+        assert_eq!(
+            options.runtime_library,
+            Some(PathBuf::from("lib/libreplica_runtime.a"))
+        );
+        assert_eq!(options.export_names, ExportNames::Qualified);
+        assert_eq!(CodeGenOptions::default().linker, "wasm-ld");
+    }
+
+    #[test]
+    fn test_cli_new_project() {
+        let Command::New { path, target } =
+            parse(&["new", "counter", "--target", "wasm32-wasi"]).unwrap()
+        else {
+            panic!("new was not parsed");
+        };
+        assert_eq!(path, PathBuf::from("counter"));
+        assert_eq!(target, "wasm32-wasi");
+
+        let Command::New { target, .. } = parse(&["new", "counter"]).unwrap() else {
+            panic!("new was not parsed");
+        };
+        assert_eq!(target, CodeGenOptions::default().target_triple);
+        assert!(parse(&["new"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_bindgen() {
+        let Command::Bindgen { input, output } = parse(&["bindgen", "files.replica"]).unwrap()
+        else {
+            panic!("bindgen was not parsed");
+        };
+        assert_eq!(input, PathBuf::from("files.replica"));
+        assert_eq!(output, None);
+
+        let Command::Bindgen { output, .. } =
+            parse(&["bindgen", "files.replica", "bindings.rs"]).unwrap()
+        else {
+            panic!("bindgen was not parsed");
+        };
+        assert_eq!(output, Some(PathBuf::from("bindings.rs")));
+
+        assert!(parse(&["bindgen"]).is_err());
+        assert!(parse(&["bindgen", "a.replica", "a.rs", "b.rs"]).is_err());
+
+        let Command::ImportProto { input, .. } = parse(&["import-proto", "people.proto"]).unwrap()
+        else {
+            panic!("import-proto was not parsed");
+        };
+        assert_eq!(input, PathBuf::from("people.proto"));
+    }
+
+    #[test]
+    fn test_cli_model() {
+        let Command::Model {
+            format,
+            output,
+            inputs,
+        } = parse(&["model", "door.replica"]).unwrap()
+        else {
+            panic!("model was not parsed");
+        };
+        assert_eq!(format, ModelFormat::Json);
+        assert_eq!(inputs, vec![PathBuf::from("door.replica")]);
+        assert_eq!(output, None);
+
+        let Command::Model {
+            format,
+            output,
+            inputs,
+        } = parse(&[
+            "model",
+            "--format",
+            "dot",
+            "a.replica",
+            "b.replica",
+            "-o",
+            "model.dot",
+        ])
+        .unwrap()
+        else {
+            panic!("model was not parsed");
+        };
+        assert_eq!(format, ModelFormat::Dot);
+        assert_eq!(inputs.len(), 2);
+        assert_eq!(output, Some(PathBuf::from("model.dot")));
+
+        assert!(parse(&["model"]).is_err());
+        assert!(parse(&["model", "--format", "svg", "a.replica"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_demangle() {
+        let Command::Demangle { inputs } = parse(&["demangle", "a.replica", "b.replica"]).unwrap()
+        else {
+            panic!("demangle was not parsed");
+        };
+        assert_eq!(inputs.len(), 2);
+
+        assert!(parse(&["demangle"]).is_err());
+        assert!(parse(&["demangle", "--format", "a.replica"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_reduce() {
+        let reduce = |args: &[&str]| -> Result<ReduceArgs, clap::Error> {
+            match parse(args)? {
+                Command::Reduce(reduce) => Ok(reduce),
+                command => panic!("parsed as {:?}", command),
+            }
+        };
+        let args = reduce(&["reduce", "--panics", "crash.replica"]).unwrap();
+        assert_eq!(args.predicate(), ReducePredicate::Panics);
+        assert_eq!(args.input, PathBuf::from("crash.replica"));
+        assert_eq!(args.output, None);
+
+        let args = reduce(&[
+            "reduce",
+            "--diagnostic",
+            "semantic/UndefinedVariable",
+            "-o",
+            "small.replica",
+            "crash.replica",
+        ])
+        .unwrap();
+        assert_eq!(
+            args.predicate(),
+            ReducePredicate::Diagnostic("semantic/UndefinedVariable".to_string())
+        );
+        assert_eq!(args.output, Some(PathBuf::from("small.replica")));
+
+        for bad in [
+            &["reduce", "crash.replica"][..],
+            &["reduce", "--panics"],
+            &["reduce", "--panics", "a.replica", "b.replica"],
+            &["reduce", "--panics", "--diagnostic", "x", "a.replica"],
+        ] {
+            assert!(reduce(bad).is_err(), "{:?} was accepted", bad);
+        }
+    }
+
+    #[test]
+    fn test_cli_grammar() {
+        let Command::Grammar { format, output } = parse(&["grammar"]).unwrap() else {
+            panic!("grammar was not parsed");
+        };
+        assert_eq!(format, GrammarFormat::Ebnf);
+        assert_eq!(output, None);
+
+        let Command::Grammar { format, output } =
+            parse(&["grammar", "--format", "tree-sitter", "-o", "grammar.json"]).unwrap()
+        else {
+            panic!("grammar was not parsed");
+        };
+        assert_eq!(format, GrammarFormat::TreeSitter);
+        assert_eq!(output, Some(PathBuf::from("grammar.json")));
+
+        assert!(parse(&["grammar", "--format", "yacc"]).is_err());
+        assert!(parse(&["grammar", "a.replica"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_run() {
+        let run = |args: &[&str]| -> Result<RunArgs, clap::Error> {
+            match parse(args)? {
+                Command::Run(run) => Ok(run),
+                command => panic!("parsed as {:?}", command),
+            }
+        };
+        let args = run(&[
+            "run",
+            "--allow",
+            "net,fs",
+            "--allow",
+            "clock",
+            "app.wasm",
+            "--verbose",
+        ])
+        .unwrap();
+        assert_eq!(args.module, PathBuf::from("app.wasm"));
+        assert_eq!(args.allow, ["net", "fs", "clock"]);
+        assert_eq!(args.engine, "wasmtime run");
+        // モジュール以降の引数はそのまま渡す
+        assert_eq!(args.args, ["--verbose"]);
+
+        let args = run(&["run", "--engine", "node host/run.mjs", "app.wasm"]).unwrap();
+        assert_eq!(args.engine, "node host/run.mjs");
+        assert!(args.allow.is_empty());
+
+        assert!(args.wasmtime_limits().is_empty());
+
+        let args = run(&[
+            "run",
+            "--max-memory",
+            "16MiB",
+            "--max-instructions",
+            "1000000",
+            "--timeout",
+            "500ms",
+            "app.wasm",
+        ])
+        .unwrap();
+        assert_eq!(args.max_memory, Some(16 << 20));
+        assert_eq!(args.max_fuel, Some(1_000_000));
+        assert_eq!(args.timeout, Some(Duration::from_millis(500)));
+        assert_eq!(
+            args.wasmtime_limits(),
+            [
+                "-W",
+                "max-memory-size=16777216",
+                "-W",
+                "fuel=1000000",
+                "-W",
+                "timeout=500ms"
+            ]
+        );
+        assert!(is_wasmtime("/usr/local/bin/wasmtime"));
+        assert!(!is_wasmtime("node"));
+
+        assert_eq!(parse_duration("2m"), Ok(Duration::from_secs(120)));
+        assert_eq!(parse_duration("10"), Ok(Duration::from_secs(10)));
+        assert!(parse_duration("0s").is_err());
+        assert!(parse_duration("1h").is_err());
+        assert!(run(&["run", "--max-fuel", "0", "a.wasm"]).is_err());
+
+        assert!(run(&["run"]).is_err());
+        assert!(run(&["run", "--allow", "Net", "a.wasm"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_wasm_features() {
+        let options = build_options(&["--wasm-features", "threads", "in.replica"]).unwrap();
+        assert!(options.has_wasm_feature(WasmFeature::Threads));
+        assert!(build_options(&["--wasm-features", "gc", "in.replica"]).is_err());
+    }
 }
@@ -0,0 +1,410 @@
+//! Project manifest (`replica.toml`) handling.
+
+use crate::ast::LogLevel;
+use crate::codegen::{
+    self, Allocator, CodeGenOptions, ConfigValue, ExportNames, LtoMode, OomBehavior, RandomSource,
+    TopicRoute, WasmFeature,
+};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// File name of the project manifest
+pub const MANIFEST_FILE: &str = "replica.toml";
+
+/// Parsed contents of `replica.toml`
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Manifest {
+    #[serde(default)]
+    pub package: Option<Package>,
+    #[serde(default)]
+    pub target: TargetConfig,
+    #[serde(default)]
+    pub build: BuildConfig,
+    #[serde(default)]
+    pub memory: MemoryConfig,
+    /// Packages this one depends on, keyed by the name they are imported under
+    #[serde(default)]
+    pub dependencies: BTreeMap<String, Dependency>,
+    /// Values actors read with `config("KEY")`, keyed by `KEY`
+    #[serde(default)]
+    pub config: BTreeMap<String, ConfigEntry>,
+}
+
+/// `[package]` section
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Package {
+    pub name: String,
+    #[serde(default)]
+    pub version: Option<String>,
+}
+
+/// `[target]` section selecting the code generation target
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TargetConfig {
+    /// LLVM target triple, e.g. `wasm32-wasi`
+    pub triple: Option<String>,
+    /// LLVM CPU name, e.g. `mvp`
+    pub cpu: Option<String>,
+    /// Target features, e.g. `["+simd128", "-sign-ext"]`
+    pub features: Option<Vec<String>>,
+    /// Experimental WebAssembly features, e.g. `["threads"]`
+    #[serde(rename = "wasm-features")]
+    pub wasm_features: Option<Vec<String>>,
+}
+
+/// `[build]` section controlling how modules are combined
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BuildConfig {
+    /// Link-time optimization mode: `off`, `thin` or `full`
+    pub lto: Option<String>,
+    /// Whether distributed actors' HTTP routes are embedded in the module
+    #[serde(rename = "http-facade")]
+    pub http_facade: Option<bool>,
+    /// Source of random bits: `module` or `host`
+    pub random: Option<String>,
+    /// How topic values reach subscribers: `runtime` or `transport`
+    pub topics: Option<String>,
+    /// Least severe log level kept: `debug`, `info`, `warn` or `error`
+    #[serde(rename = "log-level")]
+    pub log_level: Option<String>,
+    /// Command that links `wasm` output, e.g. `rust-lld -flavor wasm`
+    pub linker: Option<String>,
+    /// Names entry points are exported under: `symbol` or `qualified`
+    #[serde(rename = "export-names")]
+    pub export_names: Option<String>,
+}
+
+/// `[memory]` section budgeting linear memory
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MemoryConfig {
+    /// Maximum linear memory, e.g. `16MiB`
+    pub max: Option<String>,
+    /// Allocator behavior on exhaustion: `trap` or `null`
+    #[serde(rename = "on-oom")]
+    pub on_oom: Option<String>,
+    /// Allocator: `bump`, `freelist` or `external`
+    pub allocator: Option<String>,
+    /// Actor state size above which a warning is reported, or `off`
+    #[serde(rename = "state-size-warning")]
+    pub state_size_warning: Option<String>,
+    /// Stack size the module is linked with, e.g. `1MiB`
+    #[serde(rename = "stack-size")]
+    pub stack_size: Option<String>,
+    /// Whether recursive methods check for stack overflow
+    #[serde(rename = "stack-guard")]
+    pub stack_guard: Option<bool>,
+    /// Whether dynamic array indices are checked
+    #[serde(rename = "bounds-checks")]
+    pub bounds_checks: Option<bool>,
+}
+
+/// Entry of the `[config]` section: `KEY = "value"` bakes the value into the
+/// module, `KEY = { runtime = true, default = "value" }` asks the host for it
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum ConfigEntry {
+    Value(String),
+    Runtime(RuntimeConfig),
+}
+
+/// Table form of a `[config]` entry
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RuntimeConfig {
+    /// Must be `true`; a value fixed at compile time is written as a plain string
+    pub runtime: bool,
+    /// Value used when the host has none for the key; empty if unset
+    #[serde(default)]
+    pub default: Option<String>,
+}
+
+/// Entry of the `[dependencies]` section, e.g. `math = { git = "...", rev = "v1.0" }`
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Dependency {
+    /// Directory of the package, relative to the manifest declaring it
+    pub path: Option<String>,
+    /// URL of a git repository holding the package
+    pub git: Option<String>,
+    /// Branch, tag or commit of a git dependency; the remote's default branch if unset
+    pub rev: Option<String>,
+}
+
+impl Manifest {
+    /// Parses a manifest from TOML text
+    pub fn parse(text: &str) -> Result<Self, String> {
+        toml::from_str(text).map_err(|e| format!("Invalid {}: {}", MANIFEST_FILE, e))
+    }
+
+    /// Reads and parses the manifest at `path`
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        Self::parse(&text)
+    }
+
+    /// Searches `start` and its ancestors for a manifest file
+    pub fn find(start: &Path) -> Option<PathBuf> {
+        start
+            .ancestors()
+            .map(|dir| dir.join(MANIFEST_FILE))
+            .find(|candidate| candidate.is_file())
+    }
+
+    /// Applies the manifest's target, build and memory settings on top of `options`
+    pub fn apply(&self, options: &mut CodeGenOptions) -> Result<(), String> {
+        if let Some(triple) = &self.target.triple {
+            options.target_triple = triple.clone();
+        }
+        if let Some(cpu) = &self.target.cpu {
+            options.cpu = cpu.clone();
+        }
+        if let Some(features) = &self.target.features {
+            options.target_features = features.join(",");
+        }
+        if let Some(features) = &self.target.wasm_features {
+            options.wasm_features = WasmFeature::parse_list(&features.join(","))
+                .map_err(|e| format!("Invalid {}: {}", MANIFEST_FILE, e))?;
+        }
+        if let Some(lto) = &self.build.lto {
+            options.lto = lto
+                .parse::<LtoMode>()
+                .map_err(|e| format!("Invalid {}: {}", MANIFEST_FILE, e))?;
+        }
+        if let Some(http_facade) = self.build.http_facade {
+            options.http_facade = http_facade;
+        }
+        if let Some(random) = &self.build.random {
+            options.random = random
+                .parse::<RandomSource>()
+                .map_err(|e| format!("Invalid {}: {}", MANIFEST_FILE, e))?;
+        }
+        if let Some(topics) = &self.build.topics {
+            options.topics = topics
+                .parse::<TopicRoute>()
+                .map_err(|e| format!("Invalid {}: {}", MANIFEST_FILE, e))?;
+        }
+        if let Some(log_level) = &self.build.log_level {
+            options.log_level = log_level
+                .parse::<LogLevel>()
+                .map_err(|e| format!("Invalid {}: {}", MANIFEST_FILE, e))?;
+        }
+        if let Some(linker) = &self.build.linker {
+            options.linker = linker.clone();
+        }
+        if let Some(export_names) = &self.build.export_names {
+            options.export_names = export_names
+                .parse::<ExportNames>()
+                .map_err(|e| format!("Invalid {}: {}", MANIFEST_FILE, e))?;
+        }
+        for (key, entry) in &self.config {
+            let value = match entry {
+                ConfigEntry::Value(value) => ConfigValue::Fixed(value.clone()),
+                ConfigEntry::Runtime(RuntimeConfig { runtime: true, default }) => {
+                    ConfigValue::Runtime {
+                        default: default.clone().unwrap_or_default(),
+                    }
+                }
+                ConfigEntry::Runtime(_) => {
+                    return Err(format!(
+                        "Invalid {}: config key {} sets runtime = false; write a value fixed at compile time as a string",
+                        MANIFEST_FILE, key
+                    ))
+                }
+            };
+            options.config.insert(key.clone(), value);
+        }
+        self.memory
+            .apply(options)
+            .map_err(|e| format!("Invalid {}: {}", MANIFEST_FILE, e))
+    }
+}
+
+impl MemoryConfig {
+    fn apply(&self, options: &mut CodeGenOptions) -> Result<(), String> {
+        if let Some(max) = &self.max {
+            options.max_memory = Some(codegen::parse_memory_limit(max)?);
+        }
+        if let Some(on_oom) = &self.on_oom {
+            options.on_oom = on_oom.parse::<OomBehavior>()?;
+        }
+        if let Some(allocator) = &self.allocator {
+            options.allocator = allocator.parse::<Allocator>()?;
+        }
+        if let Some(threshold) = &self.state_size_warning {
+            options.state_size_warning = codegen::parse_size_threshold(threshold)?;
+        }
+        if let Some(stack_size) = &self.stack_size {
+            options.stack_size = codegen::parse_size(stack_size)?;
+        }
+        if let Some(stack_guard) = self.stack_guard {
+            options.stack_guard = stack_guard;
+        }
+        if let Some(bounds_checks) = self.bounds_checks {
+            options.bounds_checks = bounds_checks;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_target_section() {
+        let manifest = Manifest::parse(
+            r#"
+            [package]
+            name = "counter"
+
+            [target]
+            triple = "wasm32-wasi"
+            cpu = "mvp"
+            features = ["+simd128", "-sign-ext"]
+            wasm-features = ["threads"]
+
+            [build]
+            lto = "full"
+            http-facade = true
+            random = "host"
+            topics = "transport"
+            log-level = "info"
+            linker = "rust-lld -flavor wasm"
+            export-names = "qualified"
+            "#,
+        )
+        .unwrap();
+
+        let mut options = CodeGenOptions::default();
+        manifest.apply(&mut options).unwrap();
+        assert_eq!(options.target_triple, "wasm32-wasi");
+        assert_eq!(options.cpu, "mvp");
+        assert_eq!(options.target_features, "+simd128,-sign-ext");
+        assert_eq!(options.lto, LtoMode::Full);
+        assert_eq!(options.wasm_features, vec![WasmFeature::Threads]);
+        assert!(options.http_facade);
+        assert_eq!(options.random, RandomSource::Host);
+        assert_eq!(options.topics, TopicRoute::Transport);
+        assert_eq!(options.log_level, LogLevel::Info);
+        assert_eq!(options.linker, "rust-lld -flavor wasm");
+        assert_eq!(options.export_names, ExportNames::Qualified);
+    }
+
+    #[test]
+    fn test_memory_section() {
+        let manifest = Manifest::parse(
+            r#"
+            [memory]
+            max = "32MiB"
+            on-oom = "trap"
+            allocator = "freelist"
+            state-size-warning = "4KiB"
+            stack-size = "256KiB"
+            stack-guard = true
+            bounds-checks = false
+            "#,
+        )
+        .unwrap();
+
+        let mut options = CodeGenOptions::default();
+        manifest.apply(&mut options).unwrap();
+        assert_eq!(options.max_memory, Some(32 << 20));
+        assert_eq!(options.on_oom, OomBehavior::Trap);
+        assert_eq!(options.allocator, Allocator::FreeList);
+        assert_eq!(options.state_size_warning, Some(4096));
+        assert_eq!(options.stack_size, 256 << 10);
+        assert!(options.stack_guard);
+        assert!(!options.bounds_checks);
+
+        let manifest = Manifest::parse("[memory]\nmax = \"100\"\n").unwrap();
+        assert!(manifest.apply(&mut options).is_err());
+    }
+
+    #[test]
+    fn test_dependencies_section() {
+        let manifest = Manifest::parse(
+            r#"
+            [dependencies]
+            math = { git = "https://example.com/math.git", rev = "v1.0" }
+            util = { path = "../util" }
+            "#,
+        )
+        .unwrap();
+
+        let math = &manifest.dependencies["math"];
+        assert_eq!(math.git.as_deref(), Some("https://example.com/math.git"));
+        assert_eq!(math.rev.as_deref(), Some("v1.0"));
+        assert_eq!(
+            manifest.dependencies["util"].path.as_deref(),
+            Some("../util")
+        );
+
+        assert!(Manifest::parse("[dependencies]\nmath = { version = \"1\" }\n").is_err());
+    }
+
+    #[test]
+    fn test_config_section() {
+        let manifest = Manifest::parse(
+            r#"
+            [config]
+            GREETING = "hello"
+            API_URL = { runtime = true, default = "http://localhost" }
+            TOKEN = { runtime = true }
+            "#,
+        )
+        .unwrap();
+
+        let mut options = CodeGenOptions::default();
+        manifest.apply(&mut options).unwrap();
+        assert_eq!(
+            options.config["GREETING"],
+            ConfigValue::Fixed("hello".to_string())
+        );
+        assert_eq!(
+            options.config["API_URL"],
+            ConfigValue::Runtime {
+                default: "http://localhost".to_string()
+            }
+        );
+        assert_eq!(
+            options.config["TOKEN"],
+            ConfigValue::Runtime {
+                default: String::new()
+            }
+        );
+
+        let manifest = Manifest::parse("[config]\nTOKEN = { runtime = false }\n").unwrap();
+        assert!(manifest.apply(&mut options).is_err());
+        assert!(Manifest::parse("[config]\nPORT = 8080\n").is_err());
+    }
+
+    #[test]
+    fn test_invalid_lto_mode() {
+        let manifest = Manifest::parse("[build]\nlto = \"fat\"\n").unwrap();
+        let mut options = CodeGenOptions::default();
+        assert!(manifest.apply(&mut options).is_err());
+    }
+
+    #[test]
+    fn test_empty_manifest_keeps_defaults() {
+        let manifest = Manifest::parse("").unwrap();
+        let mut options = CodeGenOptions::default();
+        manifest.apply(&mut options).unwrap();
+        assert_eq!(options.target_triple, "wasm32-unknown-unknown");
+        assert_eq!(options.cpu, "generic");
+    }
+
+    #[test]
+    fn test_unknown_key_is_rejected() {
+        let result = Manifest::parse("[target]\narch = \"wasm32\"\n");
+        assert!(result.unwrap_err().contains(MANIFEST_FILE));
+    }
+}
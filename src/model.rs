@@ -0,0 +1,477 @@
+//! State-machine model of actors.
+//! `replicac model` lists, for every actor, which methods change which fields
+//! and under which conditions, as JSON or as a Graphviz graph, so the behavior
+//! of an actor can be reviewed without reading every method body.
+
+use crate::ast::{Actor, AtomicOperation, Expression, MethodBody, Pattern, Statement};
+use serde_json::{json, Value};
+use std::collections::HashSet;
+use std::fmt;
+use std::str::FromStr;
+
+/// A method changing a field
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Transition {
+    pub method: String,
+    pub field: String,
+    /// Conditions enclosing the change, outermost first: `if` conditions,
+    /// negated for `else` branches, and the loops it runs in
+    pub guards: Vec<String>,
+}
+
+/// State-transition summary of one actor
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Model {
+    pub actor: String,
+    /// Fields of the actor: the state
+    pub fields: Vec<String>,
+    /// Methods of the actor, followed by `init` and `deinit` if it has them
+    pub methods: Vec<String>,
+    pub transitions: Vec<Transition>,
+}
+
+/// How a model is written
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ModelFormat {
+    #[default]
+    Json,
+    /// Graphviz DOT, one cluster per actor
+    Dot,
+}
+
+impl ModelFormat {
+    pub const NAMES: &'static [&'static str] = &["json", "dot"];
+}
+
+impl FromStr for ModelFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(ModelFormat::Json),
+            "dot" => Ok(ModelFormat::Dot),
+            _ => Err(format!(
+                "Unknown model format '{}' (expected one of: {})",
+                s,
+                Self::NAMES.join(", ")
+            )),
+        }
+    }
+}
+
+impl fmt::Display for ModelFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ModelFormat::Json => "json",
+            ModelFormat::Dot => "dot",
+        })
+    }
+}
+
+/// Walks one method body, recording the fields it changes
+struct Extractor<'a> {
+    fields: &'a HashSet<&'a str>,
+    method: &'a str,
+    guards: Vec<String>,
+    transitions: &'a mut Vec<Transition>,
+}
+
+impl Extractor<'_> {
+    fn record(&mut self, field: &str) {
+        let transition = Transition {
+            method: self.method.to_string(),
+            field: field.to_string(),
+            guards: self.guards.clone(),
+        };
+        if !self.transitions.contains(&transition) {
+            self.transitions.push(transition);
+        }
+    }
+
+    /// Field `name` refers to, unless a local binding hides it
+    fn field<'n>(&self, name: &'n str, locals: &HashSet<String>) -> Option<&'n str> {
+        (self.fields.contains(name) && !locals.contains(name)).then_some(name)
+    }
+
+    /// Runs `body` as a block: its bindings go out of scope when it ends
+    fn block(&mut self, body: &MethodBody, locals: &HashSet<String>) {
+        let mut locals = locals.clone();
+        for statement in &body.statements {
+            self.statement(statement, &mut locals);
+        }
+    }
+
+    /// Runs `body` with `guard` added to the enclosing conditions
+    fn guarded(&mut self, guard: String, body: &MethodBody, locals: &HashSet<String>) {
+        self.guards.push(guard);
+        self.block(body, locals);
+        self.guards.pop();
+    }
+
+    fn statement(&mut self, statement: &Statement, locals: &mut HashSet<String>) {
+        match statement {
+            Statement::Return(expr) | Statement::Yield(expr) | Statement::Expression(expr) => {
+                self.expression(expr, locals)
+            }
+            Statement::Log { message, .. } | Statement::Publish { value: message, .. } => {
+                self.expression(message, locals)
+            }
+            Statement::Break(_) | Statement::Continue(_) | Statement::Subscribe(_) => {}
+            Statement::Let { pattern, value, .. } => {
+                self.expression(value, locals);
+                bind(pattern, locals);
+            }
+            Statement::Assign { target, value, .. } => {
+                self.expression(value, locals);
+                match target {
+                    Expression::Field(name) => self.record(name),
+                    Expression::Variable(name) => {
+                        if let Some(field) = self.field(name, locals) {
+                            self.record(field);
+                        }
+                    }
+                    target => self.expression(target, locals),
+                }
+            }
+            Statement::If {
+                condition,
+                then_body,
+                else_body,
+            } => {
+                self.expression(condition, locals);
+                self.guarded(condition.to_string(), then_body, locals);
+                if let Some(else_body) = else_body {
+                    let negated = match condition {
+                        Expression::BinaryOp { .. } => format!("!({})", condition),
+                        _ => format!("!{}", condition),
+                    };
+                    self.guarded(negated, else_body, locals);
+                }
+            }
+            Statement::While {
+                condition, body, ..
+            } => {
+                self.expression(condition, locals);
+                self.guarded(format!("while {}", condition), body, locals);
+            }
+            Statement::For {
+                variable,
+                start,
+                end,
+                body,
+                ..
+            } => {
+                self.expression(start, locals);
+                self.expression(end, locals);
+                let mut inner = locals.clone();
+                inner.insert(variable.clone());
+                self.guarded(
+                    format!("for {} in {}..{}", variable, start, end),
+                    body,
+                    &inner,
+                );
+            }
+            Statement::Match { value, arms } => {
+                self.expression(value, locals);
+                for (pattern, body) in arms {
+                    let mut inner = locals.clone();
+                    bind(pattern, &mut inner);
+                    self.guarded(format!("{} matches {}", value, pattern), body, &inner);
+                }
+            }
+        }
+    }
+
+    /// Records the fields changed inside an expression: through atomic
+    /// built-ins other than loads, and `&field` arguments
+    fn expression(&mut self, expr: &Expression, locals: &HashSet<String>) {
+        match expr {
+            Expression::Atomic {
+                operation, field, ..
+            } if *operation != AtomicOperation::Load => self.record(field),
+            Expression::Inout(name) => {
+                if let Some(field) = self.field(name, locals) {
+                    self.record(field);
+                }
+            }
+            _ => {}
+        }
+        for child in children(expr) {
+            self.expression(child, locals);
+        }
+    }
+}
+
+/// Adds the names bound by `pattern` to `locals`
+fn bind(pattern: &Pattern, locals: &mut HashSet<String>) {
+    match pattern {
+        Pattern::Binding(name) => {
+            locals.insert(name.clone());
+        }
+        Pattern::Tuple(patterns) | Pattern::Case { args: patterns, .. } => {
+            for pattern in patterns {
+                bind(pattern, locals);
+            }
+        }
+        Pattern::Wildcard | Pattern::Literal(_) => {}
+    }
+}
+
+/// Subexpressions of `expr`
+fn children(expr: &Expression) -> Vec<&Expression> {
+    match expr {
+        Expression::BinaryOp { left, right, .. } => vec![left, right],
+        Expression::Call { args, .. }
+        | Expression::Comptime { args, .. }
+        | Expression::Atomic { args, .. }
+        | Expression::Random { args, .. }
+        | Expression::EnumCase { args, .. }
+        | Expression::Tuple(args)
+        | Expression::ArrayLiteral(args) => args.iter().collect(),
+        Expression::Match { value, arms } => std::iter::once(value.as_ref())
+            .chain(arms.iter().map(|(_, result)| result))
+            .collect(),
+        Expression::Record(fields) | Expression::StructLiteral { fields, .. } => {
+            fields.iter().map(|(_, value)| value).collect()
+        }
+        Expression::Index { base, index } => vec![base, index],
+        Expression::Coalesce { value, default } => vec![value, default],
+        Expression::Conversion { value, .. }
+        | Expression::Member { base: value, .. }
+        | Expression::Unwrap(value)
+        | Expression::Await(value) => vec![value],
+        Expression::Literal(_)
+        | Expression::Variable(_)
+        | Expression::Field(_)
+        | Expression::Inout(_)
+        | Expression::Config(_)
+        | Expression::Nil => Vec::new(),
+    }
+}
+
+/// Extracts the model of `actor`
+pub fn extract(actor: &Actor) -> Model {
+    let fields: HashSet<&str> = actor
+        .fields
+        .iter()
+        .map(|field| field.name.as_str())
+        .collect();
+    let bodies = actor.methods.iter().filter_map(|method| {
+        let params = method.params.iter().map(|param| param.name.clone());
+        Some((
+            method.name.as_str(),
+            method.body.as_ref()?,
+            params.collect(),
+        ))
+    });
+    let init = actor.init.as_ref().map(|init| {
+        let params = init.params.iter().map(|param| param.name.clone());
+        ("init", &init.body, params.collect())
+    });
+    let deinit = actor
+        .deinit
+        .as_ref()
+        .map(|deinit| ("deinit", &deinit.body, HashSet::new()));
+
+    let mut methods = Vec::new();
+    let mut transitions = Vec::new();
+    for (method, body, params) in bodies.chain(init).chain(deinit) {
+        methods.push(method.to_string());
+        Extractor {
+            fields: &fields,
+            method,
+            guards: Vec::new(),
+            transitions: &mut transitions,
+        }
+        .block(body, &params);
+    }
+
+    Model {
+        actor: actor.name.clone(),
+        fields: actor
+            .fields
+            .iter()
+            .map(|field| field.name.clone())
+            .collect(),
+        methods,
+        transitions,
+    }
+}
+
+/// `{"actors": [...]}` with one entry per model
+pub fn to_json(models: &[Model]) -> Value {
+    let actors: Vec<Value> = models
+        .iter()
+        .map(|model| {
+            let transitions: Vec<Value> = model
+                .transitions
+                .iter()
+                .map(|transition| {
+                    json!({
+                        "method": transition.method,
+                        "field": transition.field,
+                        "guards": transition.guards,
+                    })
+                })
+                .collect();
+            json!({
+                "actor": model.actor,
+                "fields": model.fields,
+                "methods": model.methods,
+                "transitions": transitions,
+            })
+        })
+        .collect();
+    json!({ "actors": actors })
+}
+
+/// Graphviz graph with an edge from each method to each field it changes,
+/// labeled with the guards
+pub fn to_dot(models: &[Model]) -> String {
+    fn quote(text: &str) -> String {
+        format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\""))
+    }
+
+    let mut dot = String::from("digraph model {\n    rankdir=LR;\n");
+    for model in models {
+        // ノード名はアクターごとに分け、同名のメソッドやフィールドを区別する
+        let node = |name: &str| quote(&format!("{}.{}", model.actor, name));
+        dot.push_str(&format!(
+            "    subgraph {} {{\n        label={};\n",
+            quote(&format!("cluster_{}", model.actor)),
+            quote(&model.actor)
+        ));
+        for field in &model.fields {
+            dot.push_str(&format!(
+                "        {} [label={}, shape=ellipse];\n",
+                node(field),
+                quote(field)
+            ));
+        }
+        for method in &model.methods {
+            dot.push_str(&format!(
+                "        {} [label={}, shape=box];\n",
+                node(method),
+                quote(method)
+            ));
+        }
+        for transition in &model.transitions {
+            dot.push_str(&format!(
+                "        {} -> {}",
+                node(&transition.method),
+                node(&transition.field)
+            ));
+            if !transition.guards.is_empty() {
+                dot.push_str(&format!(
+                    " [label={}]",
+                    quote(&transition.guards.join(" && "))
+                ));
+            }
+            dot.push_str(";\n");
+        }
+        dot.push_str("    }\n");
+    }
+    dot.push_str("}\n");
+    dot
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer;
+    use crate::parser::Parser;
+
+    fn parse(source: &str) -> Actor {
+        let (tokens, _) = lexer::tokenize_recovering(source);
+        Parser::with_lines(lexer::token_lines(source, tokens))
+            .parse_actor()
+            .unwrap()
+    }
+
+    fn transition(method: &str, field: &str, guards: &[&str]) -> Transition {
+        Transition {
+            method: method.to_string(),
+            field: field.to_string(),
+            guards: guards.iter().map(|guard| guard.to_string()).collect(),
+        }
+    }
+
+    const DOOR: &str = "actor Door {
+        var open: Int
+        var opened: Int
+        var hits: Int shared
+
+        func toggle(force: Int) {
+            if self.open == 0 {
+                self.open = 1
+                opened += 1
+            } else if force > 0 {
+                open = 0
+            }
+        }
+
+        func knock(times: Int) {
+            var open = 0
+            for i in 0..times {
+                open = i
+                atomicAdd(&hits, 1, ordering: relaxed)
+            }
+            let seen = atomicLoad(&hits)
+        }
+
+        func state() -> Int {
+            return self.open
+        }
+
+        deinit {
+            opened = 0
+        }
+    }";
+
+    #[test]
+    fn test_extract() {
+        let model = extract(&parse(DOOR));
+        assert_eq!(model.actor, "Door");
+        assert_eq!(model.fields, ["open", "opened", "hits"]);
+        assert_eq!(model.methods, ["toggle", "knock", "state", "deinit"]);
+        // ローカル変数への代入と atomicLoad は状態を変えない
+        assert_eq!(
+            model.transitions,
+            vec![
+                transition("toggle", "open", &["self.open == 0"]),
+                transition("toggle", "opened", &["self.open == 0"]),
+                transition("toggle", "open", &["!(self.open == 0)", "force > 0"]),
+                transition("knock", "hits", &["for i in 0..times"]),
+                transition("deinit", "opened", &[]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_output_formats() {
+        let models = [extract(&parse(DOOR))];
+        let json = to_json(&models);
+        assert_eq!(json["actors"][0]["actor"], "Door");
+        assert_eq!(
+            json["actors"][0]["transitions"][2],
+            json!({
+                "method": "toggle",
+                "field": "open",
+                "guards": ["!(self.open == 0)", "force > 0"],
+            })
+        );
+
+        let dot = to_dot(&models);
+        assert!(dot.starts_with("digraph model {\n"));
+        assert!(dot.contains("    subgraph \"cluster_Door\" {\n        label=\"Door\";\n"));
+        assert!(dot.contains("        \"Door.hits\" [label=\"hits\", shape=ellipse];\n"));
+        assert!(dot.contains("        \"Door.deinit\" [label=\"deinit\", shape=box];\n"));
+        assert!(dot.contains(
+            "        \"Door.toggle\" -> \"Door.open\" [label=\"!(self.open == 0) && force > 0\"];\n"
+        ));
+        assert!(dot.contains("        \"Door.deinit\" -> \"Door.opened\";\n"));
+
+        assert_eq!("dot".parse(), Ok(ModelFormat::Dot));
+        assert!("svg".parse::<ModelFormat>().is_err());
+    }
+}
@@ -1,5 +1,6 @@
-use crate::ast::OwnershipInfo;
+use crate::ast::{Expression, MethodBody, OwnershipInfo, OwnershipType, Statement};
 use std::collections::HashMap;
+use std::fmt;
 
 pub struct OwnershipChecker {
     symbol_table: HashMap<String, OwnershipInfo>,
@@ -21,4 +22,321 @@ impl OwnershipChecker {
         // TODO: Implement copy validation
         todo!()
     }
+
+    /// Registers a variable or field with its ownership
+    pub fn declare(&mut self, name: &str, info: OwnershipInfo) {
+        self.symbol_table.insert(name.to_string(), info);
+    }
+
+    /// Checks that accesses overlapping for the duration of a call do not conflict:
+    /// no location may be passed to more than one `inout` parameter, and a `Shared`
+    /// field may not be read while it is being modified.
+    pub fn check_exclusive_access(&self, body: &MethodBody) -> Result<(), String> {
+        self.check_body_access(body, None)
+    }
+
+    /// Checks the statements of `body`, numbering them within `parent` if nested
+    fn check_body_access(&self, body: &MethodBody, parent: Option<&str>) -> Result<(), String> {
+        for (index, statement) in body.statements.iter().enumerate() {
+            let location = match parent {
+                Some(parent) => format!("{}, statement {}", parent, index + 1),
+                None => format!("statement {}", index + 1),
+            };
+            match statement {
+                Statement::Return(expr)
+                | Statement::Expression(expr)
+                | Statement::Yield(expr)
+                | Statement::Let { value: expr, .. }
+                | Statement::Log { message: expr, .. }
+                | Statement::Publish { value: expr, .. }
+                | Statement::Assign { value: expr, .. } => {
+                    self.check_expression_access(expr, &location)?
+                }
+                Statement::If {
+                    condition,
+                    then_body,
+                    else_body,
+                } => {
+                    self.check_expression_access(condition, &location)?;
+                    self.check_body_access(then_body, Some(&format!("{}, then", location)))?;
+                    if let Some(else_body) = else_body {
+                        self.check_body_access(else_body, Some(&format!("{}, else", location)))?;
+                    }
+                }
+                Statement::While {
+                    condition, body, ..
+                } => {
+                    self.check_expression_access(condition, &location)?;
+                    self.check_body_access(body, Some(&format!("{}, loop body", location)))?;
+                }
+                Statement::For {
+                    start, end, body, ..
+                } => {
+                    self.check_expression_access(start, &location)?;
+                    self.check_expression_access(end, &location)?;
+                    self.check_body_access(body, Some(&format!("{}, loop body", location)))?;
+                }
+                Statement::Match { value, arms } => {
+                    self.check_expression_access(value, &location)?;
+                    for (index, (_, body)) in arms.iter().enumerate() {
+                        let arm = format!("{}, arm {}", location, index + 1);
+                        self.check_body_access(body, Some(&arm))?;
+                    }
+                }
+                Statement::Break(_) | Statement::Continue(_) | Statement::Subscribe(_) => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn check_expression_access(&self, expr: &Expression, location: &str) -> Result<(), String> {
+        match expr {
+            Expression::Call { callee, args, .. } => {
+                let mut accesses = Vec::new();
+                for (index, arg) in args.iter().enumerate() {
+                    let arg_location =
+                        format!("{}, argument {} of {}", location, index + 1, callee);
+                    self.check_expression_access(arg, &arg_location)?;
+                    Self::collect_accesses(arg, &arg_location, &mut accesses);
+                }
+
+                for (i, first) in accesses.iter().enumerate() {
+                    for second in &accesses[i + 1..] {
+                        if self.conflicts(first, second) {
+                            return Err(format!(
+                                "Overlapping accesses to '{}': {} at {} conflicts with {} at {}",
+                                first.name,
+                                first.kind,
+                                first.location,
+                                second.kind,
+                                second.location
+                            ));
+                        }
+                    }
+                }
+                Ok(())
+            }
+            Expression::BinaryOp { left, right, .. } => {
+                self.check_expression_access(left, location)?;
+                self.check_expression_access(right, location)
+            }
+            Expression::Tuple(elements) => elements
+                .iter()
+                .try_for_each(|element| self.check_expression_access(element, location)),
+            Expression::Record(fields) | Expression::StructLiteral { fields, .. } => fields
+                .iter()
+                .try_for_each(|(_, value)| self.check_expression_access(value, location)),
+            Expression::ArrayLiteral(elements) => elements
+                .iter()
+                .try_for_each(|element| self.check_expression_access(element, location)),
+            Expression::Index { base, index }
+            | Expression::Coalesce {
+                value: base,
+                default: index,
+            } => {
+                self.check_expression_access(base, location)?;
+                self.check_expression_access(index, location)
+            }
+            Expression::Atomic { args, .. }
+            | Expression::Comptime { args, .. }
+            | Expression::Random { args, .. }
+            | Expression::EnumCase { args, .. } => args
+                .iter()
+                .try_for_each(|arg| self.check_expression_access(arg, location)),
+            Expression::Match { value, arms } => {
+                self.check_expression_access(value, location)?;
+                arms.iter()
+                    .try_for_each(|(_, result)| self.check_expression_access(result, location))
+            }
+            Expression::Conversion { value, .. }
+            | Expression::Member { base: value, .. }
+            | Expression::Unwrap(value)
+            | Expression::Await(value) => self.check_expression_access(value, location),
+            Expression::Literal(_)
+            | Expression::Variable(_)
+            | Expression::Field(_)
+            | Expression::Inout(_)
+            | Expression::Config(_)
+            | Expression::Nil => Ok(()),
+        }
+    }
+
+    /// Collects every access made while evaluating `expr`
+    fn collect_accesses(expr: &Expression, location: &str, accesses: &mut Vec<Access>) {
+        match expr {
+            Expression::Variable(name) | Expression::Field(name) => accesses.push(Access {
+                name: name.clone(),
+                kind: AccessKind::Read,
+                location: location.to_string(),
+            }),
+            Expression::Inout(name) => accesses.push(Access {
+                name: name.clone(),
+                kind: AccessKind::Modify,
+                location: location.to_string(),
+            }),
+            Expression::BinaryOp { left, right, .. }
+            | Expression::Index {
+                base: left,
+                index: right,
+            }
+            | Expression::Coalesce {
+                value: left,
+                default: right,
+            } => {
+                Self::collect_accesses(left, location, accesses);
+                Self::collect_accesses(right, location, accesses);
+            }
+            Expression::Call { callee, args, .. } => {
+                for (index, arg) in args.iter().enumerate() {
+                    let arg_location =
+                        format!("{}, argument {} of {}", location, index + 1, callee);
+                    Self::collect_accesses(arg, &arg_location, accesses);
+                }
+            }
+            Expression::Tuple(elements) | Expression::ArrayLiteral(elements) => {
+                for element in elements {
+                    Self::collect_accesses(element, location, accesses);
+                }
+            }
+            Expression::Record(fields) | Expression::StructLiteral { fields, .. } => {
+                for (_, value) in fields {
+                    Self::collect_accesses(value, location, accesses);
+                }
+            }
+            // アトミック操作は不可分なので読み取りとして扱う
+            Expression::Atomic { field, args, .. } => {
+                accesses.push(Access {
+                    name: field.clone(),
+                    kind: AccessKind::Read,
+                    location: location.to_string(),
+                });
+                for arg in args {
+                    Self::collect_accesses(arg, location, accesses);
+                }
+            }
+            Expression::Conversion { value, .. }
+            | Expression::Member { base: value, .. }
+            | Expression::Unwrap(value)
+            | Expression::Await(value) => Self::collect_accesses(value, location, accesses),
+            Expression::Random { args, .. } | Expression::EnumCase { args, .. } => {
+                for arg in args {
+                    Self::collect_accesses(arg, location, accesses);
+                }
+            }
+            Expression::Match { value, arms } => {
+                Self::collect_accesses(value, location, accesses);
+                for (_, result) in arms {
+                    Self::collect_accesses(result, location, accesses);
+                }
+            }
+            // comptime の引数は定数なので実行時のアクセスは生じない
+            Expression::Literal(_)
+            | Expression::Comptime { .. }
+            | Expression::Config(_)
+            | Expression::Nil => {}
+        }
+    }
+
+    fn conflicts(&self, first: &Access, second: &Access) -> bool {
+        if first.name != second.name {
+            return false;
+        }
+        match (first.kind, second.kind) {
+            (AccessKind::Modify, AccessKind::Modify) => true,
+            (AccessKind::Read, AccessKind::Read) => false,
+            _ => matches!(
+                self.symbol_table.get(&first.name),
+                Some(OwnershipInfo {
+                    ownership_type: OwnershipType::Shared,
+                    ..
+                })
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AccessKind {
+    Read,
+    Modify,
+}
+
+impl fmt::Display for AccessKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AccessKind::Read => write!(f, "read"),
+            AccessKind::Modify => write!(f, "modification"),
+        }
+    }
+}
+
+/// A single access to a named location
+struct Access {
+    name: String,
+    kind: AccessKind,
+    location: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn call(args: Vec<Expression>) -> MethodBody {
+        MethodBody {
+            statements: vec![Statement::Expression(Expression::Call {
+                callee: "swap".to_string(),
+                type_args: Vec::new(),
+                args,
+            })],
+        }
+    }
+
+    #[test]
+    fn test_overlapping_inout_access() {
+        let checker = OwnershipChecker::new();
+        let inout = |name: &str| Expression::Inout(name.to_string());
+
+        assert!(checker
+            .check_exclusive_access(&call(vec![inout("a"), inout("b")]))
+            .is_ok());
+
+        let error = checker
+            .check_exclusive_access(&call(vec![inout("a"), inout("a")]))
+            .unwrap_err();
+        assert!(error.contains("'a'"));
+        assert!(error.contains("argument 1 of swap"));
+        assert!(error.contains("argument 2 of swap"));
+    }
+
+    #[test]
+    fn test_shared_field_read_during_modification() {
+        let mut checker = OwnershipChecker::new();
+        checker.declare(
+            "total",
+            OwnershipInfo {
+                ownership_type: OwnershipType::Shared,
+                is_mutable: true,
+            },
+        );
+        let args = || {
+            vec![
+                Expression::Inout("total".to_string()),
+                Expression::Variable("total".to_string()),
+            ]
+        };
+
+        let error = checker.check_exclusive_access(&call(args())).unwrap_err();
+        assert!(error.contains("modification at statement 1, argument 1 of swap"));
+        assert!(error.contains("read at statement 1, argument 2 of swap"));
+
+        // 共有されていないローカル変数は読み取りと競合しない
+        checker.declare(
+            "total",
+            OwnershipInfo {
+                ownership_type: OwnershipType::Owned,
+                is_mutable: true,
+            },
+        );
+        assert!(checker.check_exclusive_access(&call(args())).is_ok());
+    }
 }
@@ -0,0 +1,979 @@
+//! Package dependencies declared in the `[dependencies]` section of `replica.toml`.
+//! Resolves path and git packages transitively, pins git revisions in
+//! `replica.lock` so builds are reproducible, and namespaces the actors of each
+//! dependency so several packages can be linked into one module without their
+//! symbols clashing.
+
+use crate::ast::{Actor, Expression, Init, Method, MethodBody, Pattern, Statement, Type};
+use crate::manifest::{Dependency, Manifest, MANIFEST_FILE};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// File name of the lockfile written next to the root manifest
+pub const LOCK_FILE: &str = "replica.lock";
+
+/// Directory, relative to the root package, that git dependencies are checked out into
+const GIT_CHECKOUT_DIR: &str = ".replica/git";
+
+/// Directory of a package holding its Replica sources
+const SOURCE_DIR: &str = "src";
+
+/// Extension of Replica source files
+pub const SOURCE_EXTENSION: &str = "replica";
+
+/// Where a resolved package came from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Source {
+    /// A directory on disk, read in place
+    Path(PathBuf),
+    /// A git repository checked out at `commit`
+    Git {
+        url: String,
+        rev: Option<String>,
+        commit: String,
+    },
+}
+
+impl std::fmt::Display for Source {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Source::Path(path) => write!(f, "{}", path.display()),
+            Source::Git { url, commit, .. } => write!(f, "{}#{}", url, commit),
+        }
+    }
+}
+
+/// A dependency ready to be compiled
+#[derive(Debug, Clone)]
+pub struct ResolvedPackage {
+    /// Name the package is declared under, used as its namespace
+    pub name: String,
+    pub source: Source,
+    /// Directory containing the package's manifest
+    pub root: PathBuf,
+    /// Source files of the package, in a stable order
+    pub files: Vec<PathBuf>,
+}
+
+/// Contents of `replica.lock`
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Lockfile {
+    #[serde(default, rename = "package")]
+    pub packages: Vec<LockedPackage>,
+}
+
+/// Pinned revision of a git dependency; path dependencies are read in place and not locked
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LockedPackage {
+    pub name: String,
+    pub git: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rev: Option<String>,
+    pub commit: String,
+}
+
+impl Lockfile {
+    /// Parses a lockfile from TOML text
+    pub fn parse(text: &str) -> Result<Self, String> {
+        toml::from_str(text).map_err(|e| format!("Invalid {}: {}", LOCK_FILE, e))
+    }
+
+    /// Reads the lockfile at `path`; a missing lockfile is empty
+    pub fn load(path: &Path) -> Result<Self, String> {
+        if !path.exists() {
+            return Ok(Lockfile::default());
+        }
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        Self::parse(&text)
+    }
+
+    /// Renders the lockfile as TOML
+    pub fn to_toml(&self) -> Result<String, String> {
+        let body =
+            toml::to_string(self).map_err(|e| format!("Failed to write {}: {}", LOCK_FILE, e))?;
+        Ok(format!(
+            "# Generated by replicac from {}; do not edit by hand.\n\n{}",
+            MANIFEST_FILE, body
+        ))
+    }
+
+    /// Commit pinned for a git dependency, if the manifest still asks for the same repository and revision
+    fn locked_commit(&self, name: &str, url: &str, rev: Option<&str>) -> Option<&str> {
+        self.packages
+            .iter()
+            .find(|locked| locked.name == name && locked.git == url && locked.rev.as_deref() == rev)
+            .map(|locked| locked.commit.as_str())
+    }
+}
+
+/// Resolves the dependencies of the package whose manifest is at `manifest_path`,
+/// fetching git packages as needed and updating `replica.lock` next to it.
+/// Packages are returned so that every package comes after its own dependencies.
+pub fn resolve(manifest_path: &Path) -> Result<Vec<ResolvedPackage>, String> {
+    let root = manifest_path.parent().unwrap_or(Path::new("."));
+    let manifest = Manifest::load(manifest_path)?;
+    let lock_path = root.join(LOCK_FILE);
+    let lockfile = Lockfile::load(&lock_path)?;
+
+    let mut resolver = Resolver {
+        root: root.to_path_buf(),
+        lockfile,
+        packages: Vec::new(),
+        resolving: Vec::new(),
+    };
+    resolver.resolve_dependencies(root, &manifest.dependencies)?;
+
+    let mut locked: Vec<LockedPackage> = resolver
+        .packages
+        .iter()
+        .filter_map(|package| match &package.source {
+            Source::Git { url, rev, commit } => Some(LockedPackage {
+                name: package.name.clone(),
+                git: url.clone(),
+                rev: rev.clone(),
+                commit: commit.clone(),
+            }),
+            Source::Path(_) => None,
+        })
+        .collect();
+    locked.sort_by(|a, b| a.name.cmp(&b.name));
+    let lockfile = Lockfile { packages: locked };
+
+    // 依存関係が変わったときだけロックファイルを書き換える
+    if lockfile != resolver.lockfile {
+        fs::write(&lock_path, lockfile.to_toml()?)
+            .map_err(|e| format!("Failed to write {}: {}", lock_path.display(), e))?;
+    }
+    Ok(resolver.packages)
+}
+
+struct Resolver {
+    /// Directory of the root package
+    root: PathBuf,
+    lockfile: Lockfile,
+    packages: Vec<ResolvedPackage>,
+    /// Packages whose dependencies are being resolved, outermost first
+    resolving: Vec<String>,
+}
+
+impl Resolver {
+    fn resolve_dependencies(
+        &mut self,
+        dir: &Path,
+        dependencies: &BTreeMap<String, Dependency>,
+    ) -> Result<(), String> {
+        for (name, dependency) in dependencies {
+            self.resolve_dependency(dir, name, dependency)?;
+        }
+        Ok(())
+    }
+
+    fn resolve_dependency(
+        &mut self,
+        dir: &Path,
+        name: &str,
+        dependency: &Dependency,
+    ) -> Result<(), String> {
+        validate_name(name)?;
+        if let Some(start) = self.resolving.iter().position(|pending| pending == name) {
+            let mut cycle = self.resolving[start..].to_vec();
+            cycle.push(name.to_string());
+            return Err(format!("Dependency cycle: {}", cycle.join(" -> ")));
+        }
+
+        let (root, source) = match (&dependency.path, &dependency.git) {
+            (Some(path), None) => {
+                if dependency.rev.is_some() {
+                    return Err(format!("Dependency `{}`: `rev` requires `git`", name));
+                }
+                let root = dir
+                    .join(path)
+                    .canonicalize()
+                    .map_err(|e| format!("Dependency `{}`: cannot open {}: {}", name, path, e))?;
+                (root.clone(), Source::Path(root))
+            }
+            (None, Some(url)) => self.fetch_git(name, url, dependency.rev.as_deref())?,
+            _ => {
+                return Err(format!(
+                    "Dependency `{}` must have exactly one of `path` or `git`",
+                    name
+                ))
+            }
+        };
+
+        // 同じ名前の依存は同じ取得元を指す限り一度だけ解決する
+        if let Some(existing) = self.packages.iter().find(|package| package.name == name) {
+            if existing.source == source {
+                return Ok(());
+            }
+            return Err(format!(
+                "Dependency `{}` is required from two sources: {} and {}",
+                name, existing.source, source
+            ));
+        }
+
+        let manifest = Manifest::load(&root.join(MANIFEST_FILE))?;
+        self.resolving.push(name.to_string());
+        self.resolve_dependencies(&root, &manifest.dependencies)?;
+        self.resolving.pop();
+
+        let files = source_files(&root)?;
+        if files.is_empty() {
+            return Err(format!(
+                "Dependency `{}` has no .{} files in {}",
+                name,
+                SOURCE_EXTENSION,
+                root.join(SOURCE_DIR).display()
+            ));
+        }
+        self.packages.push(ResolvedPackage {
+            name: name.to_string(),
+            source,
+            root,
+            files,
+        });
+        Ok(())
+    }
+
+    /// Checks out a git dependency at its locked commit, or at `rev` (the remote's
+    /// default branch if unset) when it is not locked yet
+    fn fetch_git(
+        &self,
+        name: &str,
+        url: &str,
+        rev: Option<&str>,
+    ) -> Result<(PathBuf, Source), String> {
+        reject_option(name, "url", url)?;
+        if let Some(rev) = rev {
+            reject_option(name, "rev", rev)?;
+        }
+        let checkout = self.root.join(GIT_CHECKOUT_DIR).join(name);
+        if !checkout.join(".git").exists() {
+            if let Some(parent) = checkout.parent() {
+                fs::create_dir_all(parent)
+                    .map_err(|e| format!("Failed to create {}: {}", parent.display(), e))?;
+            }
+            git(
+                None,
+                &["clone", "--quiet", "--", url, &checkout.to_string_lossy()],
+            )?;
+        }
+
+        let locked = self.lockfile.locked_commit(name, url, rev);
+        if let Some(commit) = locked {
+            reject_option(name, "locked commit", commit)?;
+        }
+        let target = match locked {
+            // ロック済みのコミットが手元にあればネットワークにアクセスしない
+            Some(commit)
+                if git(
+                    Some(&checkout),
+                    &["cat-file", "-e", &format!("{}^{{commit}}", commit)],
+                )
+                .is_ok() =>
+            {
+                commit.to_string()
+            }
+            _ => {
+                git(Some(&checkout), &["remote", "set-url", "--", "origin", url])?;
+                git(Some(&checkout), &["fetch", "--quiet", "--tags", "origin"])?;
+                match (locked, rev) {
+                    (Some(commit), _) => commit.to_string(),
+                    (None, Some(rev)) => resolve_rev(&checkout, rev).ok_or_else(|| {
+                        format!(
+                            "Dependency `{}`: unknown revision `{}` in {}",
+                            name, rev, url
+                        )
+                    })?,
+                    (None, None) => git(Some(&checkout), &["rev-parse", "origin/HEAD"])?,
+                }
+            }
+        };
+
+        git(
+            Some(&checkout),
+            &["checkout", "--quiet", "--detach", &target, "--"],
+        )?;
+        let commit = git(Some(&checkout), &["rev-parse", "HEAD"])?;
+        Ok((
+            checkout,
+            Source::Git {
+                url: url.to_string(),
+                rev: rev.map(str::to_string),
+                commit,
+            },
+        ))
+    }
+}
+
+/// Rejects a url or revision git would read as an option. Manifests of fetched
+/// packages name their own dependencies, so these values are not trusted.
+fn reject_option(name: &str, what: &str, value: &str) -> Result<(), String> {
+    if value.starts_with('-') {
+        return Err(format!(
+            "Dependency `{}`: {} `{}` must not start with '-'",
+            name, what, value
+        ));
+    }
+    Ok(())
+}
+
+/// Resolves a branch, tag or commit of a fetched repository to a commit hash
+fn resolve_rev(checkout: &Path, rev: &str) -> Option<String> {
+    [format!("origin/{}", rev), rev.to_string()]
+        .iter()
+        .find_map(|candidate| {
+            git(
+                Some(checkout),
+                &[
+                    "rev-parse",
+                    "--verify",
+                    "--quiet",
+                    &format!("{}^{{commit}}", candidate),
+                ],
+            )
+            .ok()
+        })
+}
+
+/// Runs git, returning its trimmed standard output
+fn git(dir: Option<&Path>, args: &[&str]) -> Result<String, String> {
+    let mut command = Command::new("git");
+    if let Some(dir) = dir {
+        command.arg("-C").arg(dir);
+    }
+    let output = command
+        .args(args)
+        .output()
+        .map_err(|e| format!("Failed to run git: {}", e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "git {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// Dependency names become symbol prefixes, so they are restricted like package names
+fn validate_name(name: &str) -> Result<(), String> {
+    let valid = name.starts_with(|c: char| c.is_ascii_alphabetic())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    if valid {
+        Ok(())
+    } else {
+        Err(format!(
+            "Invalid dependency name '{}': use letters, digits, '_' and '-', starting with a letter",
+            name
+        ))
+    }
+}
+
+/// `.replica` files under the package's `src` directory, sorted by path
+fn source_files(root: &Path) -> Result<Vec<PathBuf>, String> {
+    let mut files = Vec::new();
+    let mut pending = vec![root.join(SOURCE_DIR)];
+    while let Some(dir) = pending.pop() {
+        if !dir.is_dir() {
+            continue;
+        }
+        let entries =
+            fs::read_dir(&dir).map_err(|e| format!("Failed to read {}: {}", dir.display(), e))?;
+        for entry in entries {
+            let path = entry
+                .map_err(|e| format!("Failed to read {}: {}", dir.display(), e))?
+                .path();
+            if path.is_dir() {
+                pending.push(path);
+            } else if path.extension().is_some_and(|ext| ext == SOURCE_EXTENSION) {
+                files.push(path);
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Prefixes the actor and its methods with `namespace`, e.g. `math.Vector` and
+/// `math.length`, rewriting calls and references between its own methods.
+/// Externs keep their names, since they are imports provided by the host.
+pub fn namespace_actor(actor: &Actor, namespace: &str) -> Actor {
+    let methods: HashSet<&str> = actor.methods.iter().map(|m| m.name.as_str()).collect();
+    let renamer = Renamer {
+        namespace,
+        actor: &actor.name,
+        methods: &methods,
+    };
+
+    let mut namespaced = actor.clone();
+    namespaced.name = renamer.qualify(&actor.name);
+    for field in &mut namespaced.fields {
+        renamer.rename_type(&mut field.field_type);
+    }
+    for method in &mut namespaced.methods {
+        renamer.rename_method(method);
+    }
+    if let Some(init) = &mut namespaced.init {
+        renamer.rename_init(init);
+    }
+    if let Some(deinit) = &mut namespaced.deinit {
+        renamer.rename_body(&mut deinit.body, &HashSet::new());
+    }
+    // ルーターもアクターと同じ名前空間に置く
+    for pool in &mut namespaced.pools {
+        pool.name = renamer.qualify(&pool.name);
+        pool.actor = namespaced.name.clone();
+    }
+    namespaced
+}
+
+struct Renamer<'a> {
+    namespace: &'a str,
+    actor: &'a str,
+    methods: &'a HashSet<&'a str>,
+}
+
+impl Renamer<'_> {
+    fn qualify(&self, name: &str) -> String {
+        format!("{}.{}", self.namespace, name)
+    }
+
+    fn rename_method(&self, method: &mut Method) {
+        method.name = self.qualify(&method.name);
+        for param in &mut method.params {
+            self.rename_type(&mut param.param_type);
+        }
+        if let Some(return_type) = &mut method.return_type {
+            self.rename_type(return_type);
+        }
+
+        // 引数やローカル変数と同名のメソッド参照は書き換えない
+        let mut locals: HashSet<String> = method.params.iter().map(|p| p.name.clone()).collect();
+        if let Some(body) = &method.body {
+            collect_body_bindings(body, &mut locals);
+        }
+        if let Some(body) = &mut method.body {
+            self.rename_body(body, &locals);
+        }
+    }
+
+    fn rename_init(&self, init: &mut Init) {
+        for param in &mut init.params {
+            self.rename_type(&mut param.param_type);
+        }
+        let mut locals: HashSet<String> = init.params.iter().map(|p| p.name.clone()).collect();
+        collect_body_bindings(&init.body, &mut locals);
+        self.rename_body(&mut init.body, &locals);
+    }
+
+    fn rename_body(&self, body: &mut MethodBody, locals: &HashSet<String>) {
+        for statement in &mut body.statements {
+            match statement {
+                Statement::Return(expr)
+                | Statement::Expression(expr)
+                | Statement::Yield(expr)
+                | Statement::Let { value: expr, .. }
+                | Statement::Log { message: expr, .. }
+                | Statement::Publish { value: expr, .. }
+                | Statement::Assign { value: expr, .. } => self.rename_expression(expr, locals),
+                Statement::If {
+                    condition,
+                    then_body,
+                    else_body,
+                } => {
+                    self.rename_expression(condition, locals);
+                    self.rename_body(then_body, locals);
+                    if let Some(else_body) = else_body {
+                        self.rename_body(else_body, locals);
+                    }
+                }
+                Statement::While {
+                    condition, body, ..
+                } => {
+                    self.rename_expression(condition, locals);
+                    self.rename_body(body, locals);
+                }
+                Statement::For {
+                    start, end, body, ..
+                } => {
+                    self.rename_expression(start, locals);
+                    self.rename_expression(end, locals);
+                    self.rename_body(body, locals);
+                }
+                Statement::Match { value, arms } => {
+                    self.rename_expression(value, locals);
+                    for (_, body) in arms {
+                        self.rename_body(body, locals);
+                    }
+                }
+                Statement::Break(_) | Statement::Continue(_) | Statement::Subscribe(_) => {}
+            }
+        }
+    }
+
+    fn rename_expression(&self, expr: &mut Expression, locals: &HashSet<String>) {
+        match expr {
+            Expression::Call {
+                callee,
+                type_args,
+                args,
+            } => {
+                if self.methods.contains(callee.as_str()) && !locals.contains(callee.as_str()) {
+                    *callee = self.qualify(callee);
+                }
+                for ty in type_args {
+                    self.rename_type(ty);
+                }
+                for arg in args {
+                    self.rename_expression(arg, locals);
+                }
+            }
+            Expression::Comptime { callee, args } => {
+                if self.methods.contains(callee.as_str()) && !locals.contains(callee.as_str()) {
+                    *callee = self.qualify(callee);
+                }
+                for arg in args {
+                    self.rename_expression(arg, locals);
+                }
+            }
+            Expression::Variable(name) => {
+                if self.methods.contains(name.as_str()) && !locals.contains(name.as_str()) {
+                    *name = self.qualify(name);
+                }
+            }
+            Expression::BinaryOp { left, right, .. }
+            | Expression::Index {
+                base: left,
+                index: right,
+            }
+            | Expression::Coalesce {
+                value: left,
+                default: right,
+            } => {
+                self.rename_expression(left, locals);
+                self.rename_expression(right, locals);
+            }
+            Expression::Tuple(elements) | Expression::ArrayLiteral(elements) => {
+                for element in elements {
+                    self.rename_expression(element, locals);
+                }
+            }
+            Expression::Record(fields) => {
+                for (_, value) in fields {
+                    self.rename_expression(value, locals);
+                }
+            }
+            Expression::StructLiteral { fields, .. } => {
+                for (_, value) in fields {
+                    self.rename_expression(value, locals);
+                }
+            }
+            Expression::Member { base, .. } => self.rename_expression(base, locals),
+            Expression::Match { value, arms } => {
+                self.rename_expression(value, locals);
+                for (pattern, result) in arms {
+                    let mut locals = locals.clone();
+                    collect_bindings(pattern, &mut locals);
+                    self.rename_expression(result, &locals);
+                }
+            }
+            Expression::Atomic { args, .. }
+            | Expression::Random { args, .. }
+            | Expression::EnumCase { args, .. } => {
+                for arg in args {
+                    self.rename_expression(arg, locals);
+                }
+            }
+            Expression::Conversion { value, .. }
+            | Expression::Unwrap(value)
+            | Expression::Await(value) => self.rename_expression(value, locals),
+            Expression::Literal(_)
+            | Expression::Field(_)
+            | Expression::Inout(_)
+            | Expression::Config(_)
+            | Expression::Nil => {}
+        }
+    }
+
+    fn rename_type(&self, ty: &mut Type) {
+        match ty {
+            Type::Custom(name) if name == self.actor => *name = self.qualify(name),
+            Type::Generic { name, args } => {
+                if *name == self.actor {
+                    *name = self.qualify(name);
+                }
+                for arg in args {
+                    self.rename_type(arg);
+                }
+            }
+            Type::Array(element) | Type::FixedArray(element, _) | Type::Optional(element) => {
+                self.rename_type(element)
+            }
+            Type::Tuple(elements) => {
+                for element in elements {
+                    self.rename_type(element);
+                }
+            }
+            Type::Record(fields) => {
+                for (_, field_type) in fields {
+                    self.rename_type(field_type);
+                }
+            }
+            Type::Function {
+                params,
+                return_type,
+            } => {
+                for param in params {
+                    self.rename_type(param);
+                }
+                if let Some(return_type) = return_type {
+                    self.rename_type(return_type);
+                }
+            }
+            Type::Int | Type::Float | Type::String | Type::Bool | Type::Custom(_) => {}
+        }
+    }
+}
+
+/// Names bound by `let`/`var` anywhere in `body`
+fn collect_body_bindings(body: &MethodBody, names: &mut HashSet<String>) {
+    for statement in &body.statements {
+        match statement {
+            Statement::Let { pattern, .. } => collect_bindings(pattern, names),
+            Statement::If {
+                then_body,
+                else_body,
+                ..
+            } => {
+                collect_body_bindings(then_body, names);
+                if let Some(else_body) = else_body {
+                    collect_body_bindings(else_body, names);
+                }
+            }
+            Statement::While { body, .. } => collect_body_bindings(body, names),
+            Statement::Match { arms, .. } => {
+                for (pattern, body) in arms {
+                    collect_bindings(pattern, names);
+                    collect_body_bindings(body, names);
+                }
+            }
+            Statement::For { variable, body, .. } => {
+                names.insert(variable.clone());
+                collect_body_bindings(body, names);
+            }
+            _ => {}
+        }
+    }
+}
+
+fn collect_bindings(pattern: &Pattern, names: &mut HashSet<String>) {
+    match pattern {
+        Pattern::Binding(name) => {
+            names.insert(name.clone());
+        }
+        Pattern::Tuple(patterns) | Pattern::Case { args: patterns, .. } => {
+            for pattern in patterns {
+                collect_bindings(pattern, names);
+            }
+        }
+        Pattern::Wildcard | Pattern::Literal(_) => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::{ActorType, Field, OwnershipType, Parameter, Pool, PoolStrategy};
+
+    /// Temporary directory removed when dropped
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let path =
+                std::env::temp_dir().join(format!("replica-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(&path).unwrap();
+            TempDir(path)
+        }
+
+        fn write(&self, relative: &str, contents: &str) {
+            let path = self.0.join(relative);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn test_resolve_path_dependencies() {
+        let dir = TempDir::new("deps-path");
+        dir.write(
+            "app/replica.toml",
+            "[package]\nname = \"app\"\n\n[dependencies]\nmath = { path = \"../math\" }\nutil = { path = \"../util\" }\n",
+        );
+        dir.write(
+            "math/replica.toml",
+            "[package]\nname = \"math\"\n\n[dependencies]\nutil = { path = \"../util\" }\n",
+        );
+        dir.write("math/src/vector.replica", "actor Vector { var x: Int }");
+        dir.write(
+            "math/src/nested/matrix.replica",
+            "actor Matrix { var n: Int }",
+        );
+        dir.write("util/replica.toml", "[package]\nname = \"util\"\n");
+        dir.write("util/src/log.replica", "actor Log { var level: Int }");
+
+        let packages = resolve(&dir.0.join("app/replica.toml")).unwrap();
+        let names: Vec<&str> = packages.iter().map(|p| p.name.as_str()).collect();
+        // 依存先が先に並び、共有された util は一度だけ現れる
+        assert_eq!(names, ["util", "math"]);
+        assert_eq!(packages[1].files.len(), 2);
+        assert!(matches!(packages[0].source, Source::Path(_)));
+        // パス依存はロックしない
+        assert!(!dir.0.join("app").join(LOCK_FILE).exists());
+    }
+
+    #[test]
+    fn test_resolve_errors() {
+        let dir = TempDir::new("deps-errors");
+        dir.write(
+            "a/replica.toml",
+            "[dependencies]\nb = { path = \"../b\" }\n",
+        );
+        dir.write("a/src/a.replica", "actor A { var x: Int }");
+        dir.write(
+            "b/replica.toml",
+            "[dependencies]\na = { path = \"../a\" }\n",
+        );
+        dir.write("b/src/b.replica", "actor B { var x: Int }");
+        let error = resolve(&dir.0.join("a/replica.toml")).unwrap_err();
+        assert!(error.contains("b -> a -> b"), "{}", error);
+
+        dir.write(
+            "c/replica.toml",
+            "[dependencies]\nx = { path = \"../a\", git = \"https://example.com/x.git\" }\n",
+        );
+        assert!(resolve(&dir.0.join("c/replica.toml"))
+            .unwrap_err()
+            .contains("exactly one"));
+
+        dir.write(
+            "d/replica.toml",
+            "[dependencies]\nempty = { path = \"../e\" }\n",
+        );
+        dir.write("e/replica.toml", "");
+        assert!(resolve(&dir.0.join("d/replica.toml"))
+            .unwrap_err()
+            .contains("no .replica files"));
+
+        // git のオプションとして読まれる取得元は実行前に拒否する
+        dir.write(
+            "f/replica.toml",
+            "[dependencies]\nevil = { git = \"--upload-pack=touch pwned\" }\n",
+        );
+        assert!(resolve(&dir.0.join("f/replica.toml"))
+            .unwrap_err()
+            .contains("url `--upload-pack=touch pwned` must not start with '-'"));
+        assert!(!dir.0.join("f").join(GIT_CHECKOUT_DIR).exists());
+        dir.write(
+            "g/replica.toml",
+            "[dependencies]\nevil = { git = \"https://example.com/x.git\", rev = \"--output=x\" }\n",
+        );
+        assert!(resolve(&dir.0.join("g/replica.toml"))
+            .unwrap_err()
+            .contains("rev `--output=x`"));
+    }
+
+    #[test]
+    fn test_git_dependency_is_locked() {
+        let dir = TempDir::new("deps-git");
+        dir.write("remote/replica.toml", "[package]\nname = \"math\"\n");
+        dir.write("remote/src/math.replica", "actor Math { var x: Int }");
+        let remote = dir.0.join("remote");
+        let commit = |message: &str| {
+            git(Some(&remote), &["add", "-A"]).unwrap();
+            git(
+                Some(&remote),
+                &[
+                    "-c",
+                    "user.name=test",
+                    "-c",
+                    "user.email=test@example.com",
+                    "commit",
+                    "--quiet",
+                    "-m",
+                    message,
+                ],
+            )
+            .unwrap();
+            git(Some(&remote), &["rev-parse", "HEAD"]).unwrap()
+        };
+        git(Some(&remote), &["init", "--quiet"]).unwrap();
+        let first = commit("first");
+
+        dir.write(
+            "app/replica.toml",
+            &format!(
+                "[dependencies]\nmath = {{ git = \"{}\" }}\n",
+                remote.display()
+            ),
+        );
+        let manifest = dir.0.join("app/replica.toml");
+        let packages = resolve(&manifest).unwrap();
+        assert!(matches!(&packages[0].source, Source::Git { commit, .. } if *commit == first));
+        assert!(packages[0].files[0].ends_with("src/math.replica"));
+
+        let lockfile = Lockfile::load(&dir.0.join("app").join(LOCK_FILE)).unwrap();
+        assert_eq!(lockfile.packages.len(), 1);
+        assert_eq!(lockfile.packages[0].commit, first);
+
+        // 新しいコミットがあってもロックされたコミットを使い続ける
+        dir.write("remote/src/extra.replica", "actor Extra { var y: Int }");
+        commit("second");
+        let packages = resolve(&manifest).unwrap();
+        assert!(matches!(&packages[0].source, Source::Git { commit, .. } if *commit == first));
+        assert_eq!(packages[0].files.len(), 1);
+    }
+
+    #[test]
+    fn test_lockfile_round_trip() {
+        let lockfile = Lockfile {
+            packages: vec![LockedPackage {
+                name: "math".to_string(),
+                git: "https://example.com/math.git".to_string(),
+                rev: Some("v1.0".to_string()),
+                commit: "0123abcd".to_string(),
+            }],
+        };
+        let text = lockfile.to_toml().unwrap();
+        assert!(text.contains("[[package]]"));
+        assert_eq!(Lockfile::parse(&text).unwrap(), lockfile);
+        assert_eq!(
+            lockfile.locked_commit("math", "https://example.com/math.git", Some("v1.0")),
+            Some("0123abcd")
+        );
+        // 要求するリビジョンが変わったらロックは使わない
+        assert_eq!(
+            lockfile.locked_commit("math", "https://example.com/math.git", None),
+            None
+        );
+    }
+
+    #[test]
+    fn test_namespace_actor() {
+        let method = |name: &str, statements: Vec<Statement>, params: Vec<Parameter>| Method {
+            name: name.to_string(),
+            is_async: false,
+            is_sequential: false,
+            is_immediate: false,
+            is_pure: false,
+            is_nonreentrant: false,
+            shard_key: None,
+            retry: None,
+            is_idempotent: false,
+            since: None,
+            removed: None,
+            params,
+            return_type: Some(Type::Int),
+            body: Some(MethodBody { statements }),
+            doc: None,
+        };
+        let actor = Actor {
+            name: "Vector".to_string(),
+            actor_type: ActorType::Single,
+            methods: vec![
+                method(
+                    "length",
+                    vec![Statement::Return(Expression::Call {
+                        callee: "square".to_string(),
+                        type_args: Vec::new(),
+                        args: vec![Expression::Variable("x".to_string())],
+                    })],
+                    vec![],
+                ),
+                method(
+                    "square",
+                    vec![Statement::Expression(Expression::Call {
+                        callee: "log".to_string(),
+                        type_args: Vec::new(),
+                        args: vec![Expression::Variable("length".to_string())],
+                    })],
+                    vec![Parameter {
+                        name: "length".to_string(),
+                        param_type: Type::Optional(Box::new(Type::Custom("Vector".to_string()))),
+                        ownership: OwnershipType::Owned,
+                    }],
+                ),
+            ],
+            fields: vec![Field {
+                name: "x".to_string(),
+                field_type: Type::Int,
+                is_mutable: true,
+                ownership: OwnershipType::Owned,
+                offset: None,
+                doc: None,
+            }],
+            init: None,
+            deinit: None,
+            layout: Default::default(),
+            externs: vec![],
+            imports: vec![],
+            topics: vec![],
+            pools: vec![Pool {
+                name: "Vectors".to_string(),
+                actor: "Vector".to_string(),
+                size: 2,
+                strategy: PoolStrategy::RoundRobin,
+            }],
+            structs: vec![],
+            enums: vec![],
+            constants: vec![],
+            protocol: None,
+            doc: None,
+        };
+
+        let namespaced = namespace_actor(&actor, "math");
+        assert_eq!(namespaced.name, "math.Vector");
+        assert_eq!(
+            (
+                namespaced.pools[0].name.as_str(),
+                namespaced.pools[0].actor.as_str()
+            ),
+            ("math.Vectors", "math.Vector")
+        );
+        assert_eq!(namespaced.methods[0].name, "math.length");
+        assert!(matches!(
+            &namespaced.methods[0].body.as_ref().unwrap().statements[0],
+            Statement::Return(Expression::Call { callee, .. }) if callee == "math.square"
+        ));
+
+        let square = &namespaced.methods[1];
+        assert!(matches!(
+            &square.params[0].param_type,
+            Type::Optional(inner) if matches!(inner.as_ref(), Type::Custom(name) if name == "math.Vector")
+        ));
+        // ホスト関数と引数は名前空間に入らない
+        match &square.body.as_ref().unwrap().statements[0] {
+            Statement::Expression(Expression::Call { callee, args, .. }) => {
+                assert_eq!(callee, "log");
+                assert!(matches!(&args[0], Expression::Variable(name) if name == "length"));
+            }
+            other => panic!("unexpected statement {:?}", other),
+        }
+    }
+}
@@ -1,5 +1,8 @@
 use crate::ast::*;
+use crate::cst::{Event, SyntaxKind};
 use crate::lexer::Token;
+use std::collections::HashMap;
+use std::ops::Range;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -11,16 +14,386 @@ pub enum ParseError {
     },
     #[error("Unexpected end of input")]
     UnexpectedEOF,
+    #[error("Nesting is deeper than the limit of {0} levels")]
+    TooDeeplyNested(usize),
+    #[error("Input has more than the limit of {0} tokens")]
+    TooManyTokens(usize),
+    #[error("Too many errors; stopped after {0}")]
+    TooManyErrors(usize),
+}
+
+impl ParseError {
+    /// Name of the variant, used in diagnostic codes
+    pub fn code(&self) -> &'static str {
+        match self {
+            ParseError::UnexpectedToken { .. } => "UnexpectedToken",
+            ParseError::UnexpectedEOF => "UnexpectedEOF",
+            ParseError::TooDeeplyNested(_) => "TooDeeplyNested",
+            ParseError::TooManyTokens(_) => "TooManyTokens",
+            ParseError::TooManyErrors(_) => "TooManyErrors",
+        }
+    }
+}
+
+/// Bounds on the input a parser accepts, so that pathological input ends in
+/// an error rather than overflowing the stack or running on unbounded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParseLimits {
+    /// Deepest nesting of expressions, blocks, types and patterns
+    pub max_depth: usize,
+    pub max_tokens: usize,
+    /// Errors recovered from before parsing gives up
+    pub max_errors: usize,
+}
+
+impl Default for ParseLimits {
+    fn default() -> Self {
+        ParseLimits {
+            max_depth: 128,
+            max_tokens: 1_000_000,
+            max_errors: 100,
+        }
+    }
+}
+
+/// Argument of an attribute, e.g. `@align(8)`, `@proto("1:string")` or the
+/// `100ms` of `@retry(times: 3, backoff: 100ms)`
+enum AttributeArgument {
+    Number(u32),
+    String(String),
+    /// A number followed by `ms` or `s`, in milliseconds
+    Duration(u32),
+}
+
+/// `@name`, optionally with arguments, which may be labeled as in
+/// `@requires(capability: "net")`
+struct Attribute {
+    name: String,
+    arguments: Vec<(Option<String>, AttributeArgument)>,
+}
+
+/// Where a node can be opened once the tokens after it show what they form
+#[derive(Clone, Copy)]
+struct Checkpoint {
+    event: usize,
+    token: usize,
+}
+
+/// Method parsed from the tokens at `tokens`, which a later parse of the same
+/// tokens can take as it is instead of parsing them again
+#[derive(Debug, Clone)]
+pub struct ReusableMethod {
+    pub tokens: Range<usize>,
+    pub method: Method,
 }
 
 pub struct Parser {
     tokens: Vec<Token>,
+    /// Source line of each token; empty when the tokens carry no positions
+    lines: Vec<usize>,
     current: usize,
+    /// Syntax node boundaries for the CST; `None` on the plain compile path
+    events: Option<Vec<Event>>,
+    /// Doc comments by the line of the declaration they document
+    docs: HashMap<usize, String>,
+    /// Errors recovered from so far, with the index of the token each was
+    /// reported at; `None` when the first error ends parsing
+    errors: Option<Vec<(ParseError, Option<usize>)>>,
+    /// Methods from an earlier parse by the index of their first token
+    reusable: HashMap<usize, ReusableMethod>,
+    /// Methods parsed or reused so far that a later parse can reuse
+    parsed: Vec<ReusableMethod>,
+    limits: ParseLimits,
+    /// Nodes open around the next token
+    depth: usize,
 }
 
 impl Parser {
     pub fn new(tokens: Vec<Token>) -> Self {
-        Parser { tokens, current: 0 }
+        Parser {
+            tokens,
+            lines: Vec::new(),
+            current: 0,
+            events: None,
+            docs: HashMap::new(),
+            errors: None,
+            reusable: HashMap::new(),
+            parsed: Vec::new(),
+            limits: ParseLimits::default(),
+            depth: 0,
+        }
+    }
+
+    /// Creates a parser over tokens paired with their source lines, as returned
+    /// by `lexer::tokenize_with_lines`
+    pub fn with_lines(tokens: Vec<(Token, usize)>) -> Self {
+        let (tokens, lines) = tokens.into_iter().unzip();
+        Parser {
+            tokens,
+            lines,
+            current: 0,
+            events: None,
+            docs: HashMap::new(),
+            errors: None,
+            reusable: HashMap::new(),
+            parsed: Vec::new(),
+            limits: ParseLimits::default(),
+            depth: 0,
+        }
+    }
+
+    /// Makes the parser record where syntax nodes start and end, for `cst::parse`
+    pub fn record_events(mut self) -> Self {
+        self.events = Some(Vec::new());
+        self
+    }
+
+    /// Attaches doc comments, as returned by `lexer::doc_comments`, to the
+    /// declarations starting on the lines they precede. Needs token lines.
+    pub fn with_docs(mut self, docs: HashMap<usize, String>) -> Self {
+        self.docs = docs;
+        self
+    }
+
+    /// Replaces the default [`ParseLimits`]
+    pub fn with_limits(mut self, limits: ParseLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Makes the parser record errors in declarations and statements and carry
+    /// on with the next one, so that one run reports all of them. The actor
+    /// then lacks the declarations that failed; see [`Parser::take_errors`].
+    pub fn recover_errors(mut self) -> Self {
+        self.errors = Some(Vec::new());
+        self
+    }
+
+    /// Lets the parser take `methods` from an earlier parse instead of parsing
+    /// their tokens again, wherever a method starts at the same token index
+    /// with the same doc comment. The caller guarantees the tokens in each
+    /// range are unchanged; see [`Parser::take_parsed_methods`].
+    pub fn reuse_methods(mut self, methods: Vec<ReusableMethod>) -> Self {
+        self.reusable = methods
+            .into_iter()
+            .map(|method| (method.tokens.start, method))
+            .collect();
+        self
+    }
+
+    /// Methods parsed without errors so far, or reused, with their token
+    /// ranges, for [`Parser::reuse_methods`] on the next parse
+    pub fn take_parsed_methods(&mut self) -> Vec<ReusableMethod> {
+        std::mem::take(&mut self.parsed)
+    }
+
+    /// Errors recovered from so far, with the index of the token each was
+    /// reported at as given by [`Parser::error_position`]
+    pub fn take_errors(&mut self) -> Vec<(ParseError, Option<usize>)> {
+        self.errors.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+
+    /// Records `error` when recovering from errors, or else returns it
+    fn recover(&mut self, error: ParseError) -> Result<(), ParseError> {
+        let position = self.error_position(&error);
+        match &mut self.errors {
+            Some(errors) if errors.len() < self.limits.max_errors => {
+                errors.push((error, position));
+                Ok(())
+            }
+            // 上限に達したら、そこで解析をやめる
+            Some(_) => Err(ParseError::TooManyErrors(self.limits.max_errors)),
+            None => Err(error),
+        }
+    }
+
+    /// Number of `{` left open among the tokens consumed since `start`. When
+    /// one of them closed a brace opened before `start`, backs up to it, so
+    /// that the block it ends is not skipped as well.
+    fn open_braces(&mut self, start: usize) -> isize {
+        let mut depth = 0;
+        for index in start..self.current {
+            match self.tokens[index] {
+                Token::LBrace => depth += 1,
+                Token::RBrace if depth == 0 => {
+                    self.current = index;
+                    return 0;
+                }
+                Token::RBrace => depth -= 1,
+                _ => {}
+            }
+        }
+        depth
+    }
+
+    /// Skips the rest of a declaration that failed to parse from `start`, up
+    /// to the next declaration or the `}` closing the actor body
+    fn synchronize_member(&mut self, start: usize) {
+        // 少なくとも 1 トークンは読み飛ばす
+        self.current = self.current.max(start + 1).min(self.tokens.len());
+        let mut depth = self.open_braces(start);
+        while let Some(token) = self.peek() {
+            match token {
+                Token::RBrace if depth <= 0 => return,
+                Token::Var
+                | Token::Let
+                | Token::At
+                | Token::Func
+                | Token::Immediate
+                | Token::Pure
+                | Token::Extern
+                | Token::Const
+                | Token::Init
+                | Token::Deinit
+                | Token::Async
+                | Token::Sequential
+                    if depth <= 0 =>
+                {
+                    return
+                }
+                Token::LBrace => depth += 1,
+                Token::RBrace => depth -= 1,
+                _ => {}
+            }
+            self.current += 1;
+        }
+    }
+
+    /// Skips the rest of a statement that failed to parse from `start`: up to
+    /// the first token on a later line outside the braces opened since, or
+    /// the `}` closing the block
+    fn synchronize_statement(&mut self, start: usize) {
+        self.current = self.current.max(start + 1).min(self.tokens.len());
+        let line = self.lines.get(self.current - 1).copied().unwrap_or(0);
+        let mut depth = self.open_braces(start);
+        while let Some(token) = self.peek() {
+            match token {
+                Token::RBrace if depth <= 0 => return,
+                Token::LBrace => depth += 1,
+                Token::RBrace => depth -= 1,
+                _ if depth <= 0 && self.line() > line => return,
+                _ => {}
+            }
+            self.current += 1;
+        }
+    }
+
+    /// Node boundaries recorded so far
+    pub fn take_events(&mut self) -> Vec<Event> {
+        self.events.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+
+    /// Opens a node of `kind` before the next token
+    fn start_node(&mut self, kind: SyntaxKind) {
+        let at = self.current;
+        if let Some(events) = &mut self.events {
+            events.push(Event::Start { kind, at });
+        }
+    }
+
+    /// Opens a node of `kind` at `checkpoint`, around the nodes recorded since
+    fn start_node_at(&mut self, checkpoint: Checkpoint, kind: SyntaxKind) {
+        if let Some(events) = &mut self.events {
+            let start = Event::Start {
+                kind,
+                at: checkpoint.token,
+            };
+            events.insert(checkpoint.event, start);
+        }
+    }
+
+    /// Closes the innermost open node after the last consumed token
+    fn finish_node(&mut self) {
+        let at = self.current;
+        if let Some(events) = &mut self.events {
+            events.push(Event::Finish { at });
+        }
+    }
+
+    fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            event: self.events.as_ref().map_or(0, Vec::len),
+            token: self.current,
+        }
+    }
+
+    /// Backtracks to `checkpoint`, dropping the tokens and nodes parsed since
+    fn rewind(&mut self, checkpoint: Checkpoint) {
+        self.current = checkpoint.token;
+        if let Some(events) = &mut self.events {
+            events.truncate(checkpoint.event);
+        }
+    }
+
+    /// Runs `parse` inside a node of `kind`
+    fn node<T>(
+        &mut self,
+        kind: SyntaxKind,
+        parse: impl FnOnce(&mut Self) -> Result<T, ParseError>,
+    ) -> Result<T, ParseError> {
+        self.start_node(kind);
+        let result = self.nested(parse);
+        self.finish_node();
+        result
+    }
+
+    /// Runs `parse` one level deeper, failing once the nesting limit is hit
+    fn nested<T>(
+        &mut self,
+        parse: impl FnOnce(&mut Self) -> Result<T, ParseError>,
+    ) -> Result<T, ParseError> {
+        if self.depth >= self.limits.max_depth {
+            return Err(ParseError::TooDeeplyNested(self.limits.max_depth));
+        }
+        self.depth += 1;
+        let result = parse(self);
+        self.depth -= 1;
+        result
+    }
+
+    /// Source line of the next token, or 0 if unknown
+    fn line(&self) -> usize {
+        self.lines.get(self.current).copied().unwrap_or(0)
+    }
+
+    /// Takes the method an earlier parse made of the tokens from here, unless
+    /// its doc comment changed, and moves past them
+    fn reuse_method(&mut self) -> Option<Method> {
+        let reused = self.reusable.remove(&self.current)?;
+        if reused.method.doc != self.doc() || self.events.is_some() {
+            return None;
+        }
+        self.current = reused.tokens.end;
+        let method = reused.method.clone();
+        self.parsed.push(reused);
+        Some(method)
+    }
+
+    /// Number of errors recovered from so far
+    fn recovered(&self) -> usize {
+        self.errors.as_ref().map_or(0, Vec::len)
+    }
+
+    /// Doc comment of the declaration starting at the next token
+    fn doc(&self) -> Option<String> {
+        self.docs.get(&self.line()).cloned()
+    }
+
+    /// Index of the token `error` was reported at: the one just consumed, or
+    /// the next one for errors raised before consuming it
+    pub fn error_position(&self, error: &ParseError) -> Option<usize> {
+        let found = match error {
+            ParseError::UnexpectedToken { found, .. } => found,
+            // 深すぎる入れ子はそこで止まるので、次のトークンを指す
+            ParseError::TooDeeplyNested(_) => {
+                return Some(self.current).filter(|_| self.peek().is_some())
+            }
+            _ => return None,
+        };
+        [self.current.checked_sub(1), Some(self.current)]
+            .into_iter()
+            .flatten()
+            .find(|&index| self.tokens.get(index) == Some(found))
     }
 
     fn peek(&self) -> Option<&Token> {
@@ -45,14 +418,72 @@ impl Parser {
     }
 
     pub fn parse_actor(&mut self) -> Result<Actor, ParseError> {
+        if self.tokens.len() > self.limits.max_tokens {
+            return Err(ParseError::TooManyTokens(self.limits.max_tokens));
+        }
+        self.node(SyntaxKind::Actor, Self::parse_actor_declaration)
+    }
+
+    fn parse_actor_declaration(&mut self) -> Result<Actor, ParseError> {
+        let mut imports = Vec::new();
+        let mut topics = Vec::new();
+        let mut pools = Vec::new();
+        let mut structs = Vec::new();
+        let mut enums = Vec::new();
+        loop {
+            match self.peek() {
+                Some(Token::Import) => {
+                    imports.push(self.node(SyntaxKind::Import, Self::parse_import)?)
+                }
+                Some(Token::Topic) => topics.push(self.node(SyntaxKind::Topic, Self::parse_topic)?),
+                Some(Token::Pool) => pools.push(self.node(SyntaxKind::Pool, Self::parse_pool)?),
+                Some(Token::Struct) => {
+                    structs.push(self.node(SyntaxKind::Struct, Self::parse_struct)?)
+                }
+                Some(Token::Enum) => enums.push(self.node(SyntaxKind::Enum, Self::parse_enum)?),
+                _ => break,
+            }
+        }
+
+        let doc = self.doc();
+        let mut layout = Layout::default();
+        let mut protocol = None;
+        let mut nonreentrant = false;
+        for attribute in self.parse_attributes()? {
+            match (attribute.name.as_str(), attribute.arguments.as_slice()) {
+                ("packed", []) => layout.packed = true,
+                ("nonreentrant", []) => nonreentrant = true,
+                ("align", [(None, AttributeArgument::Number(align))]) => {
+                    layout.align = Some(*align)
+                }
+                ("protocol", [(None, AttributeArgument::String(steps))]) => {
+                    protocol = Some(steps.parse().map_err(|_| ParseError::UnexpectedToken {
+                        expected: "method names followed by ?, * or +",
+                        found: Token::StringLiteral(steps.clone()),
+                    })?)
+                }
+                _ => return Err(Self::unknown_attribute(attribute.name)),
+            }
+        }
+
         let actor_type = match self.peek() {
             Some(Token::Actor) => {
                 self.advance();
                 ActorType::Distributed
             }
-            Some(Token::SingleActor) => {
+            // `single` と `actor` の間には改行があってもよい
+            Some(Token::Single) => {
                 self.advance();
-                ActorType::Single
+                match self.advance() {
+                    Some(Token::Actor) => ActorType::Single,
+                    Some(token) => {
+                        return Err(ParseError::UnexpectedToken {
+                            expected: "actor after single",
+                            found: token.clone(),
+                        })
+                    }
+                    None => return Err(ParseError::UnexpectedEOF),
+                }
             }
             Some(token) => {
                 return Err(ParseError::UnexpectedToken {
@@ -78,25 +509,48 @@ impl Parser {
 
         let mut methods = Vec::new();
         let mut fields = Vec::new();
+        let mut constants = Vec::new();
+        let mut init = None;
+        let mut deinit = None;
+        let mut externs = Vec::new();
 
         while let Some(token) = self.peek() {
-            match token {
-                Token::RBrace => {
-                    self.advance();
-                    break;
-                }
-                Token::Var | Token::Let => {
-                    fields.push(self.parse_field()?);
-                }
-                Token::Func | Token::Immediate => {
-                    methods.push(self.parse_method()?);
-                }
-                _ => {
-                    return Err(ParseError::UnexpectedToken {
-                        expected: "field or method declaration",
-                        found: token.clone(),
+            if token == &Token::RBrace {
+                self.advance();
+                break;
+            }
+            if let Some(method) = self.reuse_method() {
+                methods.push(method);
+                continue;
+            }
+            let start = self.current;
+            let (parsed, recovered) = (methods.len(), self.recovered());
+            match self.parse_member(
+                &mut methods,
+                &mut fields,
+                &mut constants,
+                &mut init,
+                &mut deinit,
+                &mut externs,
+            ) {
+                // 途中でエラーから回復したメソッドは使い回さない
+                Ok(()) if methods.len() > parsed && self.recovered() == recovered => {
+                    self.parsed.push(ReusableMethod {
+                        tokens: start..self.current,
+                        method: methods[parsed].clone(),
                     })
                 }
+                Ok(()) => {}
+                Err(error) => {
+                    self.recover(error)?;
+                    self.synchronize_member(start);
+                }
+            }
+        }
+        // アクター全体の指定はすべてのメソッドに適用する
+        if nonreentrant {
+            for method in &mut methods {
+                method.is_nonreentrant = true;
             }
         }
 
@@ -105,189 +559,1838 @@ impl Parser {
             actor_type,
             methods,
             fields,
+            init,
+            deinit,
+            layout,
+            externs,
+            imports,
+            topics,
+            pools,
+            structs,
+            enums,
+            constants,
+            protocol,
+            doc,
         })
     }
 
-    fn parse_method(&mut self) -> Result<Method, ParseError> {
-        let is_immediate = if let Some(Token::Immediate) = self.peek() {
-            self.advance();
-            true
-        } else {
-            false
-        };
+    /// Parses `import Name`
+    fn parse_import(&mut self) -> Result<String, ParseError> {
+        self.expect(Token::Import)?;
+        match self.advance() {
+            Some(Token::Identifier(name)) => Ok(name.clone()),
+            Some(token) => Err(ParseError::UnexpectedToken {
+                expected: "module name",
+                found: token.clone(),
+            }),
+            None => Err(ParseError::UnexpectedEOF),
+        }
+    }
 
-        self.expect(Token::Func)?;
+    /// Parses `topic Name: Type`
+    fn parse_topic(&mut self) -> Result<Topic, ParseError> {
+        self.expect(Token::Topic)?;
+        let name = self.parse_topic_name()?;
+        self.expect(Token::Colon)?;
+        let value_type = self.parse_type()?;
+        Ok(Topic { name, value_type })
+    }
 
-        let name = match self.advance() {
-            Some(Token::Identifier(name)) => name.clone(),
+    fn parse_topic_name(&mut self) -> Result<String, ParseError> {
+        match self.advance() {
+            Some(Token::Identifier(name)) => Ok(name.clone()),
+            Some(token) => Err(ParseError::UnexpectedToken {
+                expected: "topic name",
+                found: token.clone(),
+            }),
+            None => Err(ParseError::UnexpectedEOF),
+        }
+    }
+
+    /// Parses `pool Name of Actor(size: n)`, optionally followed by
+    /// `, strategy: roundRobin` or `, strategy: consistentHash(key)`
+    fn parse_pool(&mut self) -> Result<Pool, ParseError> {
+        self.expect(Token::Pool)?;
+        let name = self.parse_pool_word("pool name")?;
+        self.expect_word("of")?;
+        let actor = self.parse_pool_word("pooled actor")?;
+        self.expect(Token::LParen)?;
+
+        self.expect_word("size")?;
+        self.expect(Token::Colon)?;
+        let size = match self.advance() {
+            Some(Token::NumberLiteral(value)) => {
+                value.parse().map_err(|_| ParseError::UnexpectedToken {
+                    expected: "pool size",
+                    found: Token::NumberLiteral(value.clone()),
+                })?
+            }
             Some(token) => {
                 return Err(ParseError::UnexpectedToken {
-                    expected: "identifier",
+                    expected: "pool size",
                     found: token.clone(),
                 })
             }
             None => return Err(ParseError::UnexpectedEOF),
         };
 
-        self.expect(Token::LParen)?;
-        let params = self.parse_parameters()?;
-        self.expect(Token::RParen)?;
-
-        let return_type = if let Some(Token::Arrow) = self.peek() {
+        let mut strategy = PoolStrategy::default();
+        if let Some(Token::Comma) = self.peek() {
             self.advance();
-            Some(self.parse_type()?)
-        } else {
-            None
-        };
-
-        // Add method body parsing
-        self.expect(Token::LBrace)?;
-        let body = self.parse_method_body()?;
-        self.expect(Token::RBrace)?;
-
-        Ok(Method {
-            name,
-            is_async: true,
-            is_sequential: false,
-            is_immediate,
-            params,
-            return_type,
-            body: Some(body),
-        })
-    }
-
-    fn parse_method_body(&mut self) -> Result<MethodBody, ParseError> {
-        let mut statements = Vec::new();
-
-        while let Some(token) = self.peek() {
-            match token {
-                Token::RBrace => break,
-                Token::Return => {
-                    self.advance();
-                    let expr = self.parse_expression()?;
-                    statements.push(Statement::Return(expr));
+            self.expect_word("strategy")?;
+            self.expect(Token::Colon)?;
+            strategy = match self.parse_pool_word("pool strategy")?.as_str() {
+                "roundRobin" => PoolStrategy::RoundRobin,
+                "consistentHash" => {
+                    self.expect(Token::LParen)?;
+                    let key = self.parse_pool_word("key parameter")?;
+                    self.expect(Token::RParen)?;
+                    PoolStrategy::ConsistentHash(key)
                 }
-                _ => {
-                    let expr = self.parse_expression()?;
-                    statements.push(Statement::Expression(expr));
+                other => {
+                    return Err(ParseError::UnexpectedToken {
+                        expected: "roundRobin or consistentHash",
+                        found: Token::Identifier(other.to_string()),
+                    })
                 }
-            }
+            };
         }
+        self.expect(Token::RParen)?;
 
-        Ok(MethodBody { statements })
-    }
-
-    fn parse_expression(&mut self) -> Result<Expression, ParseError> {
-        self.parse_binary_expression()
+        Ok(Pool {
+            name,
+            actor,
+            size,
+            strategy,
+        })
     }
 
-    fn parse_binary_expression(&mut self) -> Result<Expression, ParseError> {
-        let mut left = self.parse_primary()?;
-
-        while let Some(token) = self.peek() {
-            let operator = match token {
-                Token::Plus => Operator::Add,
-                Token::Minus => Operator::Subtract,
-                Token::Multiply => Operator::Multiply,
-                Token::Divide => Operator::Divide,
-                _ => break,
-            };
-            self.advance();
-
-            let right = self.parse_primary()?;
-            left = Expression::BinaryOp {
-                left: Box::new(left),
-                operator,
-                right: Box::new(right),
-            };
+    fn parse_pool_word(&mut self, expected: &'static str) -> Result<String, ParseError> {
+        match self.advance() {
+            Some(Token::Identifier(word)) => Ok(word.clone()),
+            Some(token) => Err(ParseError::UnexpectedToken {
+                expected,
+                found: token.clone(),
+            }),
+            None => Err(ParseError::UnexpectedEOF),
         }
-
-        Ok(left)
     }
 
-    fn parse_primary(&mut self) -> Result<Expression, ParseError> {
+    /// Consumes the identifier `word`, which is not reserved outside of a pool
+    fn expect_word(&mut self, word: &'static str) -> Result<(), ParseError> {
         match self.advance() {
-            Some(Token::Identifier(name)) => Ok(Expression::Variable(name.clone())),
-            Some(Token::NumberLiteral(value)) => {
-                if value.contains('.') {
-                    Ok(Expression::Literal(LiteralValue::Float(
-                        value.parse().map_err(|_| ParseError::UnexpectedToken {
-                            expected: "float number",
-                            found: Token::NumberLiteral(value.clone()),
-                        })?,
-                    )))
-                } else {
-                    Ok(Expression::Literal(LiteralValue::Int(
-                        value.parse().map_err(|_| ParseError::UnexpectedToken {
-                            expected: "integer number",
-                            found: Token::NumberLiteral(value.clone()),
-                        })?,
-                    )))
-                }
-            }
-            Some(Token::LParen) => {
-                let expr = self.parse_expression()?;
-                self.expect(Token::RParen)?;
-                Ok(expr)
-            }
+            Some(Token::Identifier(found)) if found == word => Ok(()),
             Some(token) => Err(ParseError::UnexpectedToken {
-                expected: "expression",
+                expected: word,
                 found: token.clone(),
             }),
             None => Err(ParseError::UnexpectedEOF),
         }
     }
 
-    fn parse_field(&mut self) -> Result<Field, ParseError> {
-        let is_mutable = match self.advance() {
-            Some(Token::Var) => true,
-            Some(Token::Let) => false,
+    /// Parses `struct Name { var field: Type ... }`
+    fn parse_struct(&mut self) -> Result<Struct, ParseError> {
+        let doc = self.doc();
+        self.expect(Token::Struct)?;
+        let name = match self.advance() {
+            Some(Token::Identifier(name)) => name.clone(),
             Some(token) => {
                 return Err(ParseError::UnexpectedToken {
-                    expected: "var or let",
+                    expected: "struct name",
                     found: token.clone(),
                 })
             }
             None => return Err(ParseError::UnexpectedEOF),
         };
+        self.expect(Token::LBrace)?;
+        let mut fields = Vec::new();
+        while !matches!(self.peek(), Some(Token::RBrace)) {
+            let doc = self.doc();
+            let field = self.node(SyntaxKind::Field, |p| p.parse_field(Vec::new()))?;
+            fields.push(Field { doc, ..field });
+        }
+        self.expect(Token::RBrace)?;
+        Ok(Struct { name, fields, doc })
+    }
 
+    /// Parses `enum Name { case first(Type, ...) case second ... }`
+    fn parse_enum(&mut self) -> Result<Enum, ParseError> {
+        let doc = self.doc();
+        self.expect(Token::Enum)?;
         let name = match self.advance() {
             Some(Token::Identifier(name)) => name.clone(),
             Some(token) => {
                 return Err(ParseError::UnexpectedToken {
-                    expected: "identifier",
+                    expected: "enum name",
                     found: token.clone(),
                 })
             }
             None => return Err(ParseError::UnexpectedEOF),
         };
-
-        self.expect(Token::Colon)?;
-
-        let field_type = self.parse_type()?;
-        let mut ownership = OwnershipType::Owned;
-
-        if let Some(Token::Move) = self.peek() {
-            self.advance();
-            ownership = OwnershipType::Moved;
-        }
-
-        Ok(Field {
-            name,
-            field_type,
+        self.expect(Token::LBrace)?;
+        let mut cases = Vec::new();
+        while !matches!(self.peek(), Some(Token::RBrace)) {
+            match self.advance() {
+                Some(Token::Case) => {}
+                Some(token) => {
+                    return Err(ParseError::UnexpectedToken {
+                        expected: "case",
+                        found: token.clone(),
+                    })
+                }
+                None => return Err(ParseError::UnexpectedEOF),
+            }
+            let case = match self.advance() {
+                Some(Token::Identifier(case)) => case.clone(),
+                Some(token) => {
+                    return Err(ParseError::UnexpectedToken {
+                        expected: "case name",
+                        found: token.clone(),
+                    })
+                }
+                None => return Err(ParseError::UnexpectedEOF),
+            };
+            let mut values = Vec::new();
+            if let Some(Token::LParen) = self.peek() {
+                self.advance();
+                while !matches!(self.peek(), Some(Token::RParen)) {
+                    if !values.is_empty() {
+                        self.expect(Token::Comma)?;
+                    }
+                    values.push(self.parse_type()?);
+                }
+                self.expect(Token::RParen)?;
+            }
+            cases.push(EnumCase { name: case, values });
+        }
+        self.expect(Token::RBrace)?;
+        Ok(Enum { name, cases, doc })
+    }
+
+    /// Parses one field, method, extern, init or deinit declaration of an actor body
+    fn parse_member(
+        &mut self,
+        methods: &mut Vec<Method>,
+        fields: &mut Vec<Field>,
+        constants: &mut Vec<Constant>,
+        init: &mut Option<Init>,
+        deinit: &mut Option<Deinit>,
+        externs: &mut Vec<Extern>,
+    ) -> Result<(), ParseError> {
+        let doc = self.doc();
+        let Some(token) = self.peek() else {
+            return Err(ParseError::UnexpectedEOF);
+        };
+        match token {
+            Token::Var | Token::Let => {
+                let field = self.node(SyntaxKind::Field, |p| p.parse_field(Vec::new()))?;
+                fields.push(Field { doc, ..field });
+            }
+            Token::At => {
+                // 属性の後ろが extern なら外部宣言、func ならメソッド、そうでなければフィールド
+                let checkpoint = self.checkpoint();
+                let attributes = self.parse_attributes()?;
+                if let Some(Token::Extern) = self.peek() {
+                    self.start_node_at(checkpoint, SyntaxKind::Extern);
+                    externs.push(self.parse_extern(attributes)?);
+                } else if let Some(
+                    Token::Func | Token::Async | Token::Sequential | Token::Immediate | Token::Pure,
+                ) = self.peek()
+                {
+                    self.start_node_at(checkpoint, SyntaxKind::Method);
+                    let mut method = self.parse_method()?;
+                    for attribute in attributes {
+                        match (attribute.name.as_str(), attribute.arguments.as_slice()) {
+                            ("nonreentrant", []) => method.is_nonreentrant = true,
+                            ("idempotent", []) => method.is_idempotent = true,
+                            ("since", [(None, AttributeArgument::Number(version))]) => {
+                                method.since = Some(*version)
+                            }
+                            ("removed", [(None, AttributeArgument::Number(version))]) => {
+                                method.removed = Some(*version)
+                            }
+                            ("retry", arguments) => {
+                                method.retry = Some(Self::retry_policy(arguments)?)
+                            }
+                            _ => return Err(Self::unknown_attribute(attribute.name)),
+                        }
+                    }
+                    methods.push(Method { doc, ..method });
+                } else {
+                    self.start_node_at(checkpoint, SyntaxKind::Field);
+                    let field = self.parse_field(attributes)?;
+                    fields.push(Field { doc, ..field });
+                }
+                self.finish_node();
+            }
+            Token::Extern => {
+                externs.push(self.node(SyntaxKind::Extern, |p| p.parse_extern(Vec::new()))?);
+            }
+            Token::Const => {
+                let constant = self.node(SyntaxKind::Constant, Self::parse_constant)?;
+                constants.push(Constant { doc, ..constant });
+            }
+            Token::Init | Token::Immediate if self.at_init() => {
+                if init.is_some() {
+                    return Err(ParseError::UnexpectedToken {
+                        expected: "at most one init block",
+                        found: Token::Init,
+                    });
+                }
+                *init = Some(self.node(SyntaxKind::Init, Self::parse_init)?);
+            }
+            Token::Deinit | Token::Async if self.at_deinit() => {
+                if deinit.is_some() {
+                    return Err(ParseError::UnexpectedToken {
+                        expected: "at most one deinit block",
+                        found: Token::Deinit,
+                    });
+                }
+                *deinit = Some(self.node(SyntaxKind::Deinit, Self::parse_deinit)?);
+            }
+            Token::Func | Token::Async | Token::Sequential | Token::Immediate | Token::Pure => {
+                let method = self.node(SyntaxKind::Method, Self::parse_method)?;
+                methods.push(Method { doc, ..method });
+            }
+            _ => {
+                return Err(ParseError::UnexpectedToken {
+                    expected: "field or method declaration",
+                    found: token.clone(),
+                })
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Parses `const NAME: [T] = [value, ...]` or
+    /// `const NAME: [K: V] = [key: value, ...]`, where `[:]` is the empty map
+    fn parse_constant(&mut self) -> Result<Constant, ParseError> {
+        self.expect(Token::Const)?;
+        let name = match self.advance() {
+            Some(Token::Identifier(name)) => name.clone(),
+            Some(token) => {
+                return Err(ParseError::UnexpectedToken {
+                    expected: "constant name",
+                    found: token.clone(),
+                })
+            }
+            None => return Err(ParseError::UnexpectedEOF),
+        };
+        self.expect(Token::Colon)?;
+
+        // `[K: V]` はマップ、`[T]` は配列
+        self.expect(Token::LBracket)?;
+        let element_type = self.parse_type()?;
+        let value_type = match self.peek() {
+            Some(Token::Colon) => {
+                self.advance();
+                Some(self.parse_type()?)
+            }
+            _ => None,
+        };
+        self.expect(Token::RBracket)?;
+        self.expect(Token::Equals)?;
+
+        self.expect(Token::LBracket)?;
+        let table = match value_type {
+            None => {
+                let mut values = Vec::new();
+                while let Some(token) = self.peek() {
+                    if token == &Token::RBracket {
+                        break;
+                    }
+                    if !values.is_empty() {
+                        self.expect(Token::Comma)?;
+                    }
+                    values.push(self.parse_literal("constant value")?);
+                }
+                ConstantTable::Array {
+                    element_type,
+                    values,
+                }
+            }
+            Some(value_type) => {
+                let mut entries = Vec::new();
+                if let Some(Token::Colon) = self.peek() {
+                    self.advance();
+                } else {
+                    while let Some(token) = self.peek() {
+                        if token == &Token::RBracket {
+                            break;
+                        }
+                        if !entries.is_empty() {
+                            self.expect(Token::Comma)?;
+                        }
+                        let key = self.parse_literal("constant key")?;
+                        self.expect(Token::Colon)?;
+                        entries.push((key, self.parse_literal("constant value")?));
+                    }
+                }
+                ConstantTable::Map {
+                    key_type: element_type,
+                    value_type,
+                    entries,
+                }
+            }
+        };
+        self.expect(Token::RBracket)?;
+
+        Ok(Constant {
+            name,
+            table,
+            doc: None,
+        })
+    }
+
+    /// Parses `extern type Name` or a body-less `extern func`, which may be
+    /// preceded by `@proto("...")` and `@requires(capability: "...")`
+    fn parse_extern(&mut self, attributes: Vec<Attribute>) -> Result<Extern, ParseError> {
+        let mut proto = None;
+        let mut requires = Vec::new();
+        for attribute in attributes {
+            match (attribute.name.as_str(), attribute.arguments.as_slice()) {
+                ("proto", [(None, AttributeArgument::String(spec))]) => proto = Some(spec.clone()),
+                ("requires", [(Some(label), AttributeArgument::String(capability))])
+                    if label == "capability" =>
+                {
+                    requires.push(capability.clone())
+                }
+                _ => return Err(Self::unknown_attribute(attribute.name)),
+            }
+        }
+        self.expect(Token::Extern)?;
+
+        match self.advance() {
+            // 属性を付けられるのは関数だけ
+            Some(Token::Identifier(keyword)) if keyword == "type" && proto.is_some() => {
+                Err(Self::unknown_attribute("proto".to_string()))
+            }
+            Some(Token::Identifier(keyword)) if keyword == "type" && !requires.is_empty() => {
+                Err(Self::unknown_attribute("requires".to_string()))
+            }
+            Some(Token::Identifier(keyword)) if keyword == "type" => match self.advance() {
+                Some(Token::Identifier(name)) => Ok(Extern::Type(name.clone())),
+                Some(token) => Err(ParseError::UnexpectedToken {
+                    expected: "type name",
+                    found: token.clone(),
+                }),
+                None => Err(ParseError::UnexpectedEOF),
+            },
+            Some(Token::Func) => {
+                let name = match self.advance() {
+                    Some(Token::Identifier(name)) => name.clone(),
+                    Some(token) => {
+                        return Err(ParseError::UnexpectedToken {
+                            expected: "identifier",
+                            found: token.clone(),
+                        })
+                    }
+                    None => return Err(ParseError::UnexpectedEOF),
+                };
+
+                self.expect(Token::LParen)?;
+                let params = self.parse_parameters()?;
+                self.expect(Token::RParen)?;
+
+                let return_type = if let Some(Token::Arrow) = self.peek() {
+                    self.advance();
+                    Some(self.parse_type()?)
+                } else {
+                    None
+                };
+
+                Ok(Extern::Function(ExternFunction {
+                    name,
+                    params,
+                    return_type,
+                    proto,
+                    requires,
+                }))
+            }
+            Some(token) => Err(ParseError::UnexpectedToken {
+                expected: "type or func after extern",
+                found: token.clone(),
+            }),
+            None => Err(ParseError::UnexpectedEOF),
+        }
+    }
+
+    /// Parses `@name`, `@name(n)`, `@name("text")` and `@name(label: ...)` attributes
+    fn parse_attributes(&mut self) -> Result<Vec<Attribute>, ParseError> {
+        let mut attributes = Vec::new();
+
+        while let Some(Token::At) = self.peek() {
+            self.start_node(SyntaxKind::Attribute);
+            self.advance();
+            let name = match self.advance() {
+                Some(Token::Identifier(name)) => name.clone(),
+                Some(token) => {
+                    return Err(ParseError::UnexpectedToken {
+                        expected: "attribute name",
+                        found: token.clone(),
+                    })
+                }
+                None => return Err(ParseError::UnexpectedEOF),
+            };
+
+            let mut arguments = Vec::new();
+            if let Some(Token::LParen) = self.peek() {
+                self.advance();
+                loop {
+                    let mut label = None;
+                    if let [Token::Identifier(name), Token::Colon] =
+                        &self.tokens[self.current..(self.current + 2).min(self.tokens.len())]
+                    {
+                        label = Some(name.clone());
+                        self.current += 2;
+                    }
+                    arguments.push((label, self.parse_attribute_argument()?));
+                    if self.peek() != Some(&Token::Comma) {
+                        break;
+                    }
+                    self.advance();
+                }
+                self.expect(Token::RParen)?;
+            }
+
+            self.finish_node();
+            attributes.push(Attribute { name, arguments });
+        }
+
+        Ok(attributes)
+    }
+
+    /// Parses a number, a duration such as `100ms` or `2s`, or a string
+    fn parse_attribute_argument(&mut self) -> Result<AttributeArgument, ParseError> {
+        match self.advance() {
+            Some(Token::NumberLiteral(value)) => {
+                let value = value.clone();
+                let invalid = || ParseError::UnexpectedToken {
+                    expected: "attribute argument",
+                    found: Token::NumberLiteral(value.clone()),
+                };
+                let number: u32 = value.parse().map_err(|_| invalid())?;
+                // 単位が続けば期間 (ミリ秒)
+                let scale = match self.peek() {
+                    Some(Token::Identifier(unit)) if unit == "ms" => 1,
+                    Some(Token::Identifier(unit)) if unit == "s" => 1000,
+                    _ => return Ok(AttributeArgument::Number(number)),
+                };
+                self.advance();
+                number
+                    .checked_mul(scale)
+                    .map(AttributeArgument::Duration)
+                    .ok_or_else(invalid)
+            }
+            Some(Token::StringLiteral(value)) => Ok(AttributeArgument::String(value.clone())),
+            Some(token) => Err(ParseError::UnexpectedToken {
+                expected: "attribute argument",
+                found: token.clone(),
+            }),
+            None => Err(ParseError::UnexpectedEOF),
+        }
+    }
+
+    /// Reads `times: n, backoff: d` of `@retry`, in either order
+    fn retry_policy(
+        arguments: &[(Option<String>, AttributeArgument)],
+    ) -> Result<RetryPolicy, ParseError> {
+        let malformed = || ParseError::UnexpectedToken {
+            expected: "@retry(times: n, backoff: duration)",
+            found: Token::Identifier("retry".to_string()),
+        };
+        let mut times = None;
+        let mut backoff = None;
+        for (label, argument) in arguments {
+            match (label.as_deref(), argument) {
+                (Some("times"), AttributeArgument::Number(value)) if times.is_none() => {
+                    times = Some(*value)
+                }
+                (Some("backoff"), AttributeArgument::Duration(value)) if backoff.is_none() => {
+                    backoff = Some(*value)
+                }
+                _ => return Err(malformed()),
+            }
+        }
+        match (times, backoff) {
+            (Some(times), Some(backoff_ms)) => Ok(RetryPolicy { times, backoff_ms }),
+            _ => Err(malformed()),
+        }
+    }
+
+    fn unknown_attribute(name: String) -> ParseError {
+        ParseError::UnexpectedToken {
+            expected: "known attribute",
+            found: Token::Identifier(name),
+        }
+    }
+
+    /// Whether the next tokens start an `init` block, optionally marked `immediate`
+    fn at_init(&self) -> bool {
+        match self.peek() {
+            Some(Token::Init) => true,
+            Some(Token::Immediate) => self.tokens.get(self.current + 1) == Some(&Token::Init),
+            _ => false,
+        }
+    }
+
+    fn parse_init(&mut self) -> Result<Init, ParseError> {
+        let is_immediate = if let Some(Token::Immediate) = self.peek() {
+            self.advance();
+            true
+        } else {
+            false
+        };
+
+        self.expect(Token::Init)?;
+        self.expect(Token::LParen)?;
+        let params = self.parse_parameters()?;
+        self.expect(Token::RParen)?;
+        let body = self.parse_block()?;
+
+        Ok(Init {
+            is_immediate,
+            params,
+            body,
+        })
+    }
+
+    /// Whether the next tokens start a `deinit` block, optionally marked `async`
+    fn at_deinit(&self) -> bool {
+        match self.peek() {
+            Some(Token::Deinit) => true,
+            Some(Token::Async) => self.tokens.get(self.current + 1) == Some(&Token::Deinit),
+            _ => false,
+        }
+    }
+
+    fn parse_deinit(&mut self) -> Result<Deinit, ParseError> {
+        let is_async = if let Some(Token::Async) = self.peek() {
+            self.advance();
+            true
+        } else {
+            false
+        };
+
+        self.expect(Token::Deinit)?;
+        let body = self.parse_block()?;
+
+        Ok(Deinit { is_async, body })
+    }
+
+    /// Parses a method. `async func` handles messages and `sequential func`
+    /// handles them one call at a time; a plain `func` is synchronous.
+    fn parse_method(&mut self) -> Result<Method, ParseError> {
+        let (is_async, is_sequential) = match self.peek() {
+            Some(Token::Async) => {
+                self.advance();
+                (true, false)
+            }
+            Some(Token::Sequential) => {
+                self.advance();
+                (true, true)
+            }
+            _ => (false, false),
+        };
+        let is_immediate = if let Some(Token::Immediate) = self.peek() {
+            self.advance();
+            true
+        } else {
+            false
+        };
+        let is_pure = if let Some(Token::Pure) = self.peek() {
+            self.advance();
+            true
+        } else {
+            false
+        };
+
+        self.expect(Token::Func)?;
+
+        let name = match self.advance() {
+            Some(Token::Identifier(name)) => name.clone(),
+            Some(token) => {
+                return Err(ParseError::UnexpectedToken {
+                    expected: "identifier",
+                    found: token.clone(),
+                })
+            }
+            None => return Err(ParseError::UnexpectedEOF),
+        };
+
+        self.expect(Token::LParen)?;
+        let (params, shard_key) = self.parse_method_parameters()?;
+        self.expect(Token::RParen)?;
+
+        let return_type = if let Some(Token::Arrow) = self.peek() {
+            self.advance();
+            Some(self.parse_type()?)
+        } else {
+            None
+        };
+
+        let body = self.parse_block()?;
+
+        Ok(Method {
+            name,
+            is_async,
+            is_sequential,
+            is_immediate,
+            is_pure,
+            is_nonreentrant: false,
+            shard_key,
+            retry: None,
+            is_idempotent: false,
+            since: None,
+            removed: None,
+            params,
+            return_type,
+            body: Some(body),
+            doc: None,
+        })
+    }
+
+    fn parse_method_body(&mut self) -> Result<MethodBody, ParseError> {
+        let mut statements = Vec::new();
+
+        while let Some(token) = self.peek() {
+            if token == &Token::RBrace {
+                break;
+            }
+            let start = self.current;
+            match self.node(SyntaxKind::Statement, Self::parse_statement) {
+                Ok(statement) => statements.push(statement),
+                Err(error) => {
+                    self.recover(error)?;
+                    self.synchronize_statement(start);
+                }
+            }
+        }
+
+        Ok(MethodBody { statements })
+    }
+
+    fn parse_statement(&mut self) -> Result<Statement, ParseError> {
+        match self.peek().ok_or(ParseError::UnexpectedEOF)? {
+            Token::Return => {
+                self.advance();
+                Ok(Statement::Return(self.parse_expression()?))
+            }
+            Token::Yield => {
+                self.advance();
+                Ok(Statement::Yield(self.parse_expression()?))
+            }
+            Token::Let | Token::Var => self.parse_let_statement(),
+            Token::Break => {
+                self.advance();
+                Ok(Statement::Break(self.parse_loop_label()))
+            }
+            Token::Continue => {
+                self.advance();
+                Ok(Statement::Continue(self.parse_loop_label()))
+            }
+            Token::If => self.parse_if(),
+            Token::Match => self.parse_match_statement(),
+            Token::While | Token::For => self.parse_loop(None),
+            Token::Identifier(_) if self.at_log() => self.parse_log(),
+            Token::Identifier(_) if self.at_topic_builtin() => self.parse_topic_builtin(),
+            Token::Identifier(label) if self.at_loop_label() => {
+                let label = label.clone();
+                self.advance();
+                self.expect(Token::Colon)?;
+                self.parse_loop(Some(label))
+            }
+            _ => {
+                let expr = self.parse_expression()?;
+                if self
+                    .peek()
+                    .is_some_and(|token| assignment_operator(token).is_some())
+                {
+                    self.parse_assignment(expr)
+                } else {
+                    Ok(Statement::Expression(expr))
+                }
+            }
+        }
+    }
+
+    /// Parses the `= value` or `op= value` of an assignment to `target`, which must
+    /// name a field or a variable
+    fn parse_assignment(&mut self, target: Expression) -> Result<Statement, ParseError> {
+        let token = self.advance().cloned().ok_or(ParseError::UnexpectedEOF)?;
+        if !matches!(target, Expression::Field(_) | Expression::Variable(_)) {
+            return Err(ParseError::UnexpectedToken {
+                expected: "end of statement after an expression that is not a field or variable",
+                found: token,
+            });
+        }
+        let operator = assignment_operator(&token).flatten();
+        let value = self.parse_expression()?;
+        Ok(Statement::Assign {
+            target,
+            operator,
+            value,
+        })
+    }
+
+    /// Parses `if condition { ... }` with optional `else if` and `else` branches.
+    /// `if let pattern = value { ... } else { ... }` unwraps an optional and is
+    /// parsed as `match value { .some(pattern) => { ... }, .none => { ... } }`.
+    fn parse_if(&mut self) -> Result<Statement, ParseError> {
+        self.expect(Token::If)?;
+        let unwrapped = if let Some(Token::Let) = self.peek() {
+            self.advance();
+            let pattern = self.parse_pattern()?;
+            self.expect(Token::Equals)?;
+            Some(pattern)
+        } else {
+            None
+        };
+        let condition = self.parse_expression()?;
+        let then_body = self.parse_block()?;
+
+        let else_body = if let Some(Token::Else) = self.peek() {
+            self.advance();
+            if let Some(Token::If) = self.peek() {
+                // else if は else 節に入れ子の if を 1 つ置いたものとして表す
+                Some(MethodBody {
+                    statements: vec![self.parse_if()?],
+                })
+            } else {
+                Some(self.parse_block()?)
+            }
+        } else {
+            None
+        };
+
+        if let Some(pattern) = unwrapped {
+            let some = Pattern::Case {
+                name: "some".to_string(),
+                args: vec![pattern],
+            };
+            let none = Pattern::Case {
+                name: "none".to_string(),
+                args: Vec::new(),
+            };
+            return Ok(Statement::Match {
+                value: condition,
+                arms: vec![
+                    (some, then_body),
+                    (
+                        none,
+                        else_body.unwrap_or(MethodBody {
+                            statements: Vec::new(),
+                        }),
+                    ),
+                ],
+            });
+        }
+        Ok(Statement::If {
+            condition,
+            then_body,
+            else_body,
+        })
+    }
+
+    /// Parses `while condition { ... }` or `for variable in start..end { ... }`
+    fn parse_loop(&mut self, label: Option<String>) -> Result<Statement, ParseError> {
+        match self.advance() {
+            Some(Token::While) => {
+                let condition = self.parse_expression()?;
+                let body = self.parse_block()?;
+                Ok(Statement::While {
+                    label,
+                    condition,
+                    body,
+                })
+            }
+            Some(Token::For) => {
+                let variable = match self.advance() {
+                    Some(Token::Identifier(name)) => name.clone(),
+                    Some(token) => {
+                        return Err(ParseError::UnexpectedToken {
+                            expected: "loop variable",
+                            found: token.clone(),
+                        })
+                    }
+                    None => return Err(ParseError::UnexpectedEOF),
+                };
+                self.expect(Token::In)?;
+                let start = self.parse_expression()?;
+                self.expect(Token::DotDot)?;
+                let end = self.parse_expression()?;
+                let body = self.parse_block()?;
+                Ok(Statement::For {
+                    label,
+                    variable,
+                    start,
+                    end,
+                    body,
+                })
+            }
+            Some(token) => Err(ParseError::UnexpectedToken {
+                expected: "while or for",
+                found: token.clone(),
+            }),
+            None => Err(ParseError::UnexpectedEOF),
+        }
+    }
+
+    /// Whether the next tokens start a logging built-in, `log.<level>(`
+    fn at_log(&self) -> bool {
+        matches!(
+            self.tokens.get(self.current..self.current + 4),
+            Some([
+                Token::Identifier(log),
+                Token::Dot,
+                Token::Identifier(_),
+                Token::LParen
+            ]) if log == "log"
+        )
+    }
+
+    /// Parses `log.<level>(message)`
+    fn parse_log(&mut self) -> Result<Statement, ParseError> {
+        let line = self.line();
+        self.advance();
+        self.expect(Token::Dot)?;
+        let level = match self.advance() {
+            Some(Token::Identifier(name)) => match LogLevel::from_name(name) {
+                Some(level) => level,
+                None => {
+                    return Err(ParseError::UnexpectedToken {
+                        expected: "debug, info, warn or error",
+                        found: Token::Identifier(name.clone()),
+                    })
+                }
+            },
+            Some(token) => {
+                return Err(ParseError::UnexpectedToken {
+                    expected: "log level",
+                    found: token.clone(),
+                })
+            }
+            None => return Err(ParseError::UnexpectedEOF),
+        };
+        self.expect(Token::LParen)?;
+        let message = self.parse_expression()?;
+        self.expect(Token::RParen)?;
+        Ok(Statement::Log {
+            level,
+            message,
+            line,
+        })
+    }
+
+    /// Whether the next tokens start `publish(` or `subscribe(`
+    fn at_topic_builtin(&self) -> bool {
+        matches!(
+            self.tokens.get(self.current..self.current + 2),
+            Some([Token::Identifier(name), Token::LParen])
+                if name == "publish" || name == "subscribe"
+        )
+    }
+
+    /// Parses `publish(Topic, value)` or `subscribe(Topic)`
+    fn parse_topic_builtin(&mut self) -> Result<Statement, ParseError> {
+        let publish = matches!(self.advance(), Some(Token::Identifier(name)) if name == "publish");
+        self.expect(Token::LParen)?;
+        let topic = self.parse_topic_name()?;
+        let statement = if publish {
+            self.expect(Token::Comma)?;
+            let value = self.parse_expression()?;
+            Statement::Publish { topic, value }
+        } else {
+            Statement::Subscribe(topic)
+        };
+        self.expect(Token::RParen)?;
+        Ok(statement)
+    }
+
+    /// Whether the next tokens are `label: while` or `label: for`
+    fn at_loop_label(&self) -> bool {
+        matches!(
+            self.tokens.get(self.current..self.current + 3),
+            Some([
+                Token::Identifier(_),
+                Token::Colon,
+                Token::While | Token::For
+            ])
+        )
+    }
+
+    /// Parses `{ statements }`
+    fn parse_block(&mut self) -> Result<MethodBody, ParseError> {
+        self.node(SyntaxKind::Block, |parser| {
+            parser.expect(Token::LBrace)?;
+            let body = parser.parse_method_body()?;
+            parser.expect(Token::RBrace)?;
+            Ok(body)
+        })
+    }
+
+    /// Parses the optional label after `break`/`continue`. An identifier directly
+    /// following the keyword is always a label, since code after a jump is unreachable.
+    fn parse_loop_label(&mut self) -> Option<String> {
+        match self.peek() {
+            Some(Token::Identifier(label)) => {
+                let label = label.clone();
+                self.advance();
+                Some(label)
+            }
+            _ => None,
+        }
+    }
+
+    /// Parses `match value { pattern => { ... } ... }`, whose arms are blocks
+    fn parse_match_statement(&mut self) -> Result<Statement, ParseError> {
+        self.expect(Token::Match)?;
+        let value = self.parse_expression()?;
+        let arms = self.parse_match_arms(Self::parse_block)?;
+        Ok(Statement::Match { value, arms })
+    }
+
+    /// Parses the `{ pattern => arm, ... }` of a `match`; commas between arms
+    /// are optional
+    fn parse_match_arms<T>(
+        &mut self,
+        mut parse_arm: impl FnMut(&mut Self) -> Result<T, ParseError>,
+    ) -> Result<Vec<(Pattern, T)>, ParseError> {
+        self.expect(Token::LBrace)?;
+        let mut arms = Vec::new();
+        while !matches!(self.peek(), Some(Token::RBrace)) {
+            let pattern = self.parse_pattern()?;
+            self.expect(Token::FatArrow)?;
+            arms.push((pattern, parse_arm(self)?));
+            if let Some(Token::Comma) = self.peek() {
+                self.advance();
+            }
+        }
+        self.expect(Token::RBrace)?;
+        Ok(arms)
+    }
+
+    fn parse_let_statement(&mut self) -> Result<Statement, ParseError> {
+        let is_mutable = matches!(self.advance(), Some(Token::Var));
+        let pattern = self.parse_pattern()?;
+        self.expect(Token::Equals)?;
+        let value = self.parse_expression()?;
+
+        Ok(Statement::Let {
+            pattern,
+            is_mutable,
+            value,
+        })
+    }
+
+    /// Whether the next token starts a number, string or boolean literal
+    fn at_literal(&self) -> bool {
+        matches!(
+            self.peek(),
+            Some(
+                Token::NumberLiteral(_)
+                    | Token::FloatLiteral(_)
+                    | Token::StringLiteral(_)
+                    | Token::True
+                    | Token::False
+                    | Token::Minus
+            )
+        )
+    }
+
+    /// Parses a number, possibly negative, a string without interpolations
+    /// or a boolean
+    fn parse_literal(&mut self, expected: &'static str) -> Result<LiteralValue, ParseError> {
+        if !self.at_literal() {
+            return match self.advance() {
+                Some(token) => Err(ParseError::UnexpectedToken {
+                    expected,
+                    found: token.clone(),
+                }),
+                None => Err(ParseError::UnexpectedEOF),
+            };
+        }
+        match self.parse_primary()? {
+            Expression::Literal(value) => Ok(value),
+            _ => Err(ParseError::UnexpectedToken {
+                expected,
+                found: self.tokens[self.current - 1].clone(),
+            }),
+        }
+    }
+
+    fn parse_pattern(&mut self) -> Result<Pattern, ParseError> {
+        if self.at_literal() {
+            return self.parse_literal("literal pattern").map(Pattern::Literal);
+        }
+
+        match self.advance() {
+            Some(Token::Identifier(name)) if name == "_" => Ok(Pattern::Wildcard),
+            Some(Token::Identifier(name)) => Ok(Pattern::Binding(name.clone())),
+            Some(Token::Let) => match self.advance() {
+                Some(Token::Identifier(name)) => Ok(Pattern::Binding(name.clone())),
+                Some(token) => Err(ParseError::UnexpectedToken {
+                    expected: "binding name",
+                    found: token.clone(),
+                }),
+                None => Err(ParseError::UnexpectedEOF),
+            },
+            Some(Token::LParen) => {
+                let mut patterns = self.parse_pattern_list()?;
+                self.expect(Token::RParen)?;
+                if patterns.len() == 1 {
+                    Ok(patterns.remove(0))
+                } else {
+                    Ok(Pattern::Tuple(patterns))
+                }
+            }
+            Some(Token::Dot) => {
+                let name = match self.advance() {
+                    Some(Token::Identifier(name)) => name.clone(),
+                    Some(token) => {
+                        return Err(ParseError::UnexpectedToken {
+                            expected: "case name",
+                            found: token.clone(),
+                        })
+                    }
+                    None => return Err(ParseError::UnexpectedEOF),
+                };
+                let mut args = Vec::new();
+                if let Some(Token::LParen) = self.peek() {
+                    self.advance();
+                    args = self.parse_pattern_list()?;
+                    self.expect(Token::RParen)?;
+                }
+                Ok(Pattern::Case { name, args })
+            }
+            Some(token) => Err(ParseError::UnexpectedToken {
+                expected: "pattern",
+                found: token.clone(),
+            }),
+            None => Err(ParseError::UnexpectedEOF),
+        }
+    }
+
+    fn parse_pattern_list(&mut self) -> Result<Vec<Pattern>, ParseError> {
+        let mut patterns = Vec::new();
+
+        while let Some(token) = self.peek() {
+            if token == &Token::RParen {
+                break;
+            }
+
+            if !patterns.is_empty() {
+                self.expect(Token::Comma)?;
+            }
+            patterns.push(self.nested(Self::parse_pattern)?);
+        }
+
+        Ok(patterns)
+    }
+
+    fn parse_expression(&mut self) -> Result<Expression, ParseError> {
+        self.node(SyntaxKind::Expression, Self::parse_binary_expression)
+    }
+
+    fn parse_binary_expression(&mut self) -> Result<Expression, ParseError> {
+        self.parse_binary_operand(0)
+    }
+
+    /// Precedence climbing: parses operands joined by operators binding at
+    /// least as tightly as `min_precedence`, folding equal levels to the left
+    fn parse_binary_operand(&mut self, min_precedence: u8) -> Result<Expression, ParseError> {
+        let mut left = self.parse_postfix()?;
+
+        loop {
+            if self.peek() == Some(&Token::QuestionQuestion)
+                && COALESCE_PRECEDENCE >= min_precedence
+            {
+                self.advance();
+                // `??` は右結合 (`a ?? b ?? c` は `a ?? (b ?? c)`)
+                let default =
+                    self.nested(|parser| parser.parse_binary_operand(COALESCE_PRECEDENCE))?;
+                left = Expression::Coalesce {
+                    value: Box::new(left),
+                    default: Box::new(default),
+                };
+                continue;
+            }
+            let Some((operator, precedence)) = self.peek().and_then(binary_operator) else {
+                break;
+            };
+            if precedence < min_precedence {
+                break;
+            }
+            self.advance();
+
+            // 右辺はより強く結合する演算子だけを取り込む
+            let right = self.parse_binary_operand(precedence + 1)?;
+            let is_comparison = operator.is_comparison();
+            left = Expression::BinaryOp {
+                left: Box::new(left),
+                operator,
+                right: Box::new(right),
+            };
+
+            // 比較は連鎖できない (`a < b < c` は括弧が必要)
+            if is_comparison {
+                if let Some(token) = self.peek().filter(|token| {
+                    binary_operator(token).is_some_and(|(op, _)| op.is_comparison())
+                }) {
+                    return Err(ParseError::UnexpectedToken {
+                        expected: "end of comparison (comparisons do not chain)",
+                        found: token.clone(),
+                    });
+                }
+            }
+        }
+
+        Ok(left)
+    }
+
+    fn parse_postfix(&mut self) -> Result<Expression, ParseError> {
+        let mut expr = self.parse_primary()?;
+
+        loop {
+            match self.peek() {
+                Some(Token::Exclamation) => {
+                    self.advance();
+                    expr = Expression::Unwrap(Box::new(expr));
+                }
+                Some(Token::LBracket) => {
+                    self.advance();
+                    let index = self.parse_expression()?;
+                    self.expect(Token::RBracket)?;
+                    expr = Expression::Index {
+                        base: Box::new(expr),
+                        index: Box::new(index),
+                    };
+                }
+                // `.name(` は変換、括弧のない `.name` は構造体のフィールド
+                Some(Token::Dot) if self.at_member() => {
+                    self.advance();
+                    if let Some(Token::Identifier(name)) = self.advance() {
+                        expr = Expression::Member {
+                            base: Box::new(expr),
+                            name: name.clone(),
+                        };
+                    }
+                }
+                Some(Token::Dot) => {
+                    self.advance();
+                    let conversion = self.parse_conversion()?;
+                    expr = Expression::Conversion {
+                        conversion,
+                        value: Box::new(expr),
+                    };
+                }
+                _ => return Ok(expr),
+            }
+        }
+    }
+
+    /// Whether the next tokens are `.name` not followed by `(`
+    fn at_member(&self) -> bool {
+        matches!(
+            self.tokens.get(self.current..self.current + 2),
+            Some([Token::Dot, Token::Identifier(_)])
+        ) && self.tokens.get(self.current + 2) != Some(&Token::LParen)
+    }
+
+    /// Parses the `toString()` of `value.toString()`
+    fn parse_conversion(&mut self) -> Result<Conversion, ParseError> {
+        let conversion = match self.advance() {
+            Some(Token::Identifier(name)) => match Conversion::from_name(name) {
+                Some(conversion) => conversion,
+                None => {
+                    return Err(ParseError::UnexpectedToken {
+                        expected: "toString or toInt",
+                        found: Token::Identifier(name.clone()),
+                    })
+                }
+            },
+            Some(token) => {
+                return Err(ParseError::UnexpectedToken {
+                    expected: "method name",
+                    found: token.clone(),
+                })
+            }
+            None => return Err(ParseError::UnexpectedEOF),
+        };
+        self.expect(Token::LParen)?;
+        self.expect(Token::RParen)?;
+        Ok(conversion)
+    }
+
+    /// Parses the rest of an interpolated string after its head, desugaring
+    /// `"a \(x) b"` into `"a " + "\(x)" + " b"` where `"\(x)"` converts `x` to
+    /// text. Empty pieces of text are left out.
+    fn parse_interpolation(&mut self, head: String) -> Result<Expression, ParseError> {
+        let text = |text: String| Expression::Literal(LiteralValue::String(text));
+        let mut parts = vec![text(head)];
+        loop {
+            let value = self.parse_expression()?;
+            parts.push(Expression::Conversion {
+                conversion: Conversion::Interpolate,
+                value: Box::new(value),
+            });
+            match self.advance() {
+                Some(Token::StringMiddle(middle)) => parts.push(text(middle.clone())),
+                Some(Token::StringTail(tail)) => {
+                    parts.push(text(tail.clone()));
+                    break;
+                }
+                Some(token) => {
+                    return Err(ParseError::UnexpectedToken {
+                        expected: ") closing the interpolation",
+                        found: token.clone(),
+                    })
+                }
+                None => return Err(ParseError::UnexpectedEOF),
+            }
+        }
+
+        // 補間が 1 つはあるので、空の文字列片を除いても何か残る
+        let mut parts = parts.into_iter().filter(|part| {
+            !matches!(part, Expression::Literal(LiteralValue::String(text)) if text.is_empty())
+        });
+        let first = parts.next().ok_or(ParseError::UnexpectedEOF)?;
+        Ok(parts.fold(first, |left, right| Expression::BinaryOp {
+            left: Box::new(left),
+            operator: Operator::Add,
+            right: Box::new(right),
+        }))
+    }
+
+    /// Parses the `"KEY")` of `config("KEY")`; the key must be a string literal
+    fn parse_config(&mut self) -> Result<Expression, ParseError> {
+        let key = match self.advance() {
+            Some(Token::StringLiteral(key)) => key.clone(),
+            Some(token) => {
+                return Err(ParseError::UnexpectedToken {
+                    expected: "config key as a string literal",
+                    found: token.clone(),
+                })
+            }
+            None => return Err(ParseError::UnexpectedEOF),
+        };
+        self.expect(Token::RParen)?;
+        Ok(Expression::Config(key))
+    }
+
+    fn parse_primary(&mut self) -> Result<Expression, ParseError> {
+        if self.at_record() {
+            let fields = self.parse_record_fields(Self::parse_expression)?;
+            return Ok(Expression::Record(fields));
+        }
+        if self.at_self_member() {
+            return self.parse_self_member();
+        }
+        if self.at_enum_case() {
+            return self.parse_enum_case();
+        }
+
+        match self.advance() {
+            Some(Token::Identifier(name)) => {
+                let name = name.clone();
+                let type_args = self.parse_call_type_arguments();
+                // 引数がすべてラベル付きなら構造体の値
+                if type_args.is_empty() && self.at_record() {
+                    let fields = self.parse_record_fields(Self::parse_expression)?;
+                    return Ok(Expression::StructLiteral { name, fields });
+                }
+                if let Some(Token::LParen) = self.peek() {
+                    self.advance();
+                    if !type_args.is_empty() {
+                        let args = self.parse_arguments()?;
+                        self.expect(Token::RParen)?;
+                        return Ok(Expression::Call {
+                            callee: name,
+                            type_args,
+                            args,
+                        });
+                    }
+                    if let Some(operation) = AtomicOperation::from_name(&name) {
+                        return self.parse_atomic(operation);
+                    }
+                    if name == "config" {
+                        return self.parse_config();
+                    }
+                    let args = self.parse_arguments()?;
+                    self.expect(Token::RParen)?;
+                    if let Some(operation) = RandomOperation::from_name(&name) {
+                        return Ok(Expression::Random { operation, args });
+                    }
+                    return Ok(Expression::Call {
+                        callee: name,
+                        type_args,
+                        args,
+                    });
+                }
+                Ok(Expression::Variable(name))
+            }
+            Some(Token::Match) => {
+                let value = self.parse_expression()?;
+                let arms = self.parse_match_arms(Self::parse_expression)?;
+                Ok(Expression::Match {
+                    value: Box::new(value),
+                    arms,
+                })
+            }
+            // `await` は後置演算子まで含めた式全体にかかる
+            Some(Token::Await) => {
+                let value = self.nested(Self::parse_postfix)?;
+                Ok(Expression::Await(Box::new(value)))
+            }
+            Some(Token::Comptime) => {
+                let callee = match self.advance() {
+                    Some(Token::Identifier(name)) => name.clone(),
+                    Some(token) => {
+                        return Err(ParseError::UnexpectedToken {
+                            expected: "method call after comptime",
+                            found: token.clone(),
+                        })
+                    }
+                    None => return Err(ParseError::UnexpectedEOF),
+                };
+                self.expect(Token::LParen)?;
+                let args = self.parse_arguments()?;
+                self.expect(Token::RParen)?;
+                Ok(Expression::Comptime { callee, args })
+            }
+            Some(Token::Ampersand) => match self.advance() {
+                Some(Token::Identifier(name)) => Ok(Expression::Inout(name.clone())),
+                Some(token) => Err(ParseError::UnexpectedToken {
+                    expected: "variable after &",
+                    found: token.clone(),
+                }),
+                None => Err(ParseError::UnexpectedEOF),
+            },
+            Some(Token::StringLiteral(value)) => {
+                Ok(Expression::Literal(LiteralValue::String(value.clone())))
+            }
+            Some(Token::StringHead(head)) => {
+                let head = head.clone();
+                self.parse_interpolation(head)
+            }
+            Some(Token::NumberLiteral(value)) => int_literal(value, false),
+            Some(Token::FloatLiteral(value)) => {
+                Ok(Expression::Literal(LiteralValue::Float(*value)))
+            }
+            // `-` の直後の数値は負のリテラル。`i32::MIN` もこれで書ける
+            Some(Token::Minus) => match self.advance() {
+                Some(Token::NumberLiteral(value)) => int_literal(value, true),
+                Some(Token::FloatLiteral(value)) => {
+                    Ok(Expression::Literal(LiteralValue::Float(-value)))
+                }
+                Some(token) => Err(ParseError::UnexpectedToken {
+                    expected: "number after -",
+                    found: token.clone(),
+                }),
+                None => Err(ParseError::UnexpectedEOF),
+            },
+            Some(Token::Nil) => Ok(Expression::Nil),
+            Some(Token::True) => Ok(Expression::Literal(LiteralValue::Bool(true))),
+            Some(Token::False) => Ok(Expression::Literal(LiteralValue::Bool(false))),
+            Some(Token::LParen) => {
+                let expr = self.parse_expression()?;
+                if let Some(Token::Comma) = self.peek() {
+                    let mut elements = vec![expr];
+                    while let Some(Token::Comma) = self.peek() {
+                        self.advance();
+                        elements.push(self.parse_expression()?);
+                    }
+                    self.expect(Token::RParen)?;
+                    return Ok(Expression::Tuple(elements));
+                }
+                self.expect(Token::RParen)?;
+                Ok(expr)
+            }
+            Some(Token::LBracket) => {
+                let mut elements = Vec::new();
+                while let Some(token) = self.peek() {
+                    if token == &Token::RBracket {
+                        break;
+                    }
+                    if !elements.is_empty() {
+                        self.expect(Token::Comma)?;
+                    }
+                    elements.push(self.parse_expression()?);
+                }
+                self.expect(Token::RBracket)?;
+                Ok(Expression::ArrayLiteral(elements))
+            }
+            Some(token) => Err(ParseError::UnexpectedToken {
+                expected: "expression",
+                found: token.clone(),
+            }),
+            None => Err(ParseError::UnexpectedEOF),
+        }
+    }
+
+    /// Whether the next tokens are `Name.case`, a case of an enum. Type names
+    /// start with an uppercase letter, which tells them from values; a
+    /// conversion such as `Name.toString()` is left to the postfix parser.
+    fn at_enum_case(&self) -> bool {
+        match self.tokens.get(self.current..self.current + 3) {
+            Some([Token::Identifier(name), Token::Dot, Token::Identifier(case)]) => {
+                name.starts_with(|c: char| c.is_ascii_uppercase())
+                    && !(Conversion::from_name(case).is_some()
+                        && self.tokens.get(self.current + 3) == Some(&Token::LParen))
+            }
+            _ => false,
+        }
+    }
+
+    /// Parses `Name.case` or `Name.case(values)`
+    fn parse_enum_case(&mut self) -> Result<Expression, ParseError> {
+        let (Some(Token::Identifier(enum_name)), Some(Token::Dot), Some(Token::Identifier(case))) = (
+            self.advance().cloned(),
+            self.advance().cloned(),
+            self.advance().cloned(),
+        ) else {
+            return Err(ParseError::UnexpectedEOF);
+        };
+        let mut args = Vec::new();
+        if let Some(Token::LParen) = self.peek() {
+            self.advance();
+            args = self.parse_arguments()?;
+            self.expect(Token::RParen)?;
+        }
+        Ok(Expression::EnumCase {
+            enum_name,
+            case,
+            args,
+        })
+    }
+
+    /// Whether the next tokens are `self.`, starting a field access or method call
+    fn at_self_member(&self) -> bool {
+        matches!(
+            self.tokens.get(self.current..self.current + 2),
+            Some([Token::Identifier(name), Token::Dot]) if name == "self"
+        )
+    }
+
+    /// Parses `self.name` as a field, or `self.name(args)` as a call of the method
+    fn parse_self_member(&mut self) -> Result<Expression, ParseError> {
+        self.advance();
+        self.expect(Token::Dot)?;
+        let name = match self.advance() {
+            Some(Token::Identifier(name)) => name.clone(),
+            Some(token) => {
+                return Err(ParseError::UnexpectedToken {
+                    expected: "field or method name after self.",
+                    found: token.clone(),
+                })
+            }
+            None => return Err(ParseError::UnexpectedEOF),
+        };
+        let type_args = self.parse_call_type_arguments();
+        if let Some(Token::LParen) = self.peek() {
+            self.advance();
+            let args = self.parse_arguments()?;
+            self.expect(Token::RParen)?;
+            return Ok(Expression::Call {
+                callee: name,
+                type_args,
+                args,
+            });
+        }
+        Ok(Expression::Field(name))
+    }
+
+    /// Parses the `<T, ...>` of a call such as `map<Int>(values)`.
+    ///
+    /// `<` after a name is only taken as a type argument list when it parses as
+    /// one and is directly followed by `(`; otherwise nothing is consumed and
+    /// `<` stays a comparison. As in C#, `a < b, c > (d)` therefore reads as a
+    /// generic call, so such comparisons need parentheses.
+    fn parse_call_type_arguments(&mut self) -> Vec<Type> {
+        if self.peek() != Some(&Token::Less) {
+            return Vec::new();
+        }
+        let checkpoint = self.checkpoint();
+        match self.parse_type_arguments() {
+            Ok(type_args) if self.peek() == Some(&Token::LParen) => type_args,
+            _ => {
+                self.rewind(checkpoint);
+                Vec::new()
+            }
+        }
+    }
+
+    /// Parses `<T, ...>` with at least one type
+    fn parse_type_arguments(&mut self) -> Result<Vec<Type>, ParseError> {
+        self.expect(Token::Less)?;
+        let mut type_args = vec![self.parse_type()?];
+        while let Some(Token::Comma) = self.peek() {
+            self.advance();
+            type_args.push(self.parse_type()?);
+        }
+        self.expect(Token::Greater)?;
+        Ok(type_args)
+    }
+
+    /// Whether the next tokens are `(label:`, starting a record literal or type
+    fn at_record(&self) -> bool {
+        matches!(
+            self.tokens.get(self.current..self.current + 3),
+            Some([Token::LParen, Token::Identifier(_), Token::Colon])
+        )
+    }
+
+    /// Parses `(label: item, ...)`, using `parse_item` for each field
+    fn parse_record_fields<T>(
+        &mut self,
+        parse_item: fn(&mut Self) -> Result<T, ParseError>,
+    ) -> Result<Vec<(String, T)>, ParseError> {
+        self.expect(Token::LParen)?;
+        let mut fields = Vec::new();
+        while let Some(Token::Identifier(label)) = self.peek() {
+            let label = label.clone();
+            self.advance();
+            self.expect(Token::Colon)?;
+            fields.push((label, parse_item(self)?));
+            match self.peek() {
+                Some(Token::Comma) => {
+                    self.advance();
+                }
+                _ => break,
+            }
+        }
+        self.expect(Token::RParen)?;
+        Ok(fields)
+    }
+
+    /// Parses the arguments of an atomic built-in after its `(`:
+    /// `&field`, the value arguments and an optional trailing `ordering: name`
+    fn parse_atomic(&mut self, operation: AtomicOperation) -> Result<Expression, ParseError> {
+        self.expect(Token::Ampersand)?;
+        let field = match self.advance() {
+            Some(Token::Identifier(name)) => name.clone(),
+            Some(token) => {
+                return Err(ParseError::UnexpectedToken {
+                    expected: "shared field after &",
+                    found: token.clone(),
+                })
+            }
+            None => return Err(ParseError::UnexpectedEOF),
+        };
+
+        let mut args = Vec::new();
+        let mut ordering = MemoryOrdering::default();
+        while let Some(Token::Comma) = self.peek() {
+            self.advance();
+            let labeled = matches!(
+                (self.peek(), self.tokens.get(self.current + 1)),
+                (Some(Token::Identifier(label)), Some(Token::Colon)) if label == "ordering"
+            );
+            if !labeled {
+                args.push(self.parse_expression()?);
+                continue;
+            }
+
+            // ordering: は最後の引数
+            self.current += 2;
+            ordering = match self.advance() {
+                Some(Token::Identifier(name)) => {
+                    MemoryOrdering::from_name(name).ok_or_else(|| ParseError::UnexpectedToken {
+                        expected: "memory ordering",
+                        found: Token::Identifier(name.clone()),
+                    })?
+                }
+                Some(token) => {
+                    return Err(ParseError::UnexpectedToken {
+                        expected: "memory ordering",
+                        found: token.clone(),
+                    })
+                }
+                None => return Err(ParseError::UnexpectedEOF),
+            };
+            break;
+        }
+        self.expect(Token::RParen)?;
+
+        Ok(Expression::Atomic {
+            operation,
+            field,
+            args,
+            ordering,
+        })
+    }
+
+    fn parse_arguments(&mut self) -> Result<Vec<Expression>, ParseError> {
+        let mut args = Vec::new();
+
+        while let Some(token) = self.peek() {
+            if token == &Token::RParen {
+                break;
+            }
+
+            if !args.is_empty() {
+                self.expect(Token::Comma)?;
+            }
+            args.push(self.parse_expression()?);
+        }
+
+        Ok(args)
+    }
+
+    fn parse_field(&mut self, attributes: Vec<Attribute>) -> Result<Field, ParseError> {
+        let mut offset = None;
+        for attribute in attributes {
+            match (attribute.name.as_str(), attribute.arguments.as_slice()) {
+                ("offset", [(None, AttributeArgument::Number(value))]) => offset = Some(*value),
+                _ => return Err(Self::unknown_attribute(attribute.name)),
+            }
+        }
+
+        let is_mutable = match self.advance() {
+            Some(Token::Var) => true,
+            Some(Token::Let) => false,
+            Some(token) => {
+                return Err(ParseError::UnexpectedToken {
+                    expected: "var or let",
+                    found: token.clone(),
+                })
+            }
+            None => return Err(ParseError::UnexpectedEOF),
+        };
+
+        let name = match self.advance() {
+            Some(Token::Identifier(name)) => name.clone(),
+            Some(token) => {
+                return Err(ParseError::UnexpectedToken {
+                    expected: "identifier",
+                    found: token.clone(),
+                })
+            }
+            None => return Err(ParseError::UnexpectedEOF),
+        };
+
+        self.expect(Token::Colon)?;
+
+        let field_type = self.parse_type()?;
+        let ownership = match self.peek() {
+            Some(Token::Move) => OwnershipType::Moved,
+            Some(Token::Shared) => OwnershipType::Shared,
+            Some(Token::Weak) => OwnershipType::Weak,
+            Some(Token::Unowned) => OwnershipType::Unowned,
+            _ => OwnershipType::Owned,
+        };
+        if !matches!(ownership, OwnershipType::Owned) {
+            self.advance();
+        }
+
+        Ok(Field {
+            name,
+            field_type,
             is_mutable,
             ownership,
+            offset,
+            doc: None,
         })
     }
 
     fn parse_type(&mut self) -> Result<Type, ParseError> {
+        self.node(SyntaxKind::Type, |parser| {
+            let base_type = parser.parse_base_type()?;
+
+            if let Some(Token::Question) = parser.peek() {
+                parser.advance();
+                return Ok(Type::Optional(Box::new(base_type)));
+            }
+            Ok(base_type)
+        })
+    }
+
+    fn parse_base_type(&mut self) -> Result<Type, ParseError> {
+        if self.at_record() {
+            let fields = self.parse_record_fields(Self::parse_type)?;
+            return Ok(Type::record(fields));
+        }
+
         match self.advance() {
+            Some(Token::LParen) => {
+                let mut elements = Vec::new();
+                while let Some(token) = self.peek() {
+                    if token == &Token::RParen {
+                        break;
+                    }
+                    if !elements.is_empty() {
+                        self.expect(Token::Comma)?;
+                    }
+                    elements.push(self.parse_type()?);
+                }
+                self.expect(Token::RParen)?;
+                Ok(Type::Tuple(elements))
+            }
+            Some(Token::LBracket) => {
+                let element_type = self.parse_type()?;
+                if let Some(Token::RBracket) = self.peek() {
+                    self.advance();
+                    return Ok(Type::Array(Box::new(element_type)));
+                }
+
+                self.expect(Token::Semicolon)?;
+                let length = match self.advance() {
+                    Some(Token::NumberLiteral(value)) => {
+                        value.parse().map_err(|_| ParseError::UnexpectedToken {
+                            expected: "array length",
+                            found: Token::NumberLiteral(value.clone()),
+                        })?
+                    }
+                    Some(token) => {
+                        return Err(ParseError::UnexpectedToken {
+                            expected: "array length",
+                            found: token.clone(),
+                        })
+                    }
+                    None => return Err(ParseError::UnexpectedEOF),
+                };
+                self.expect(Token::RBracket)?;
+                Ok(Type::FixedArray(Box::new(element_type), length))
+            }
+            Some(Token::Func) => {
+                self.expect(Token::LParen)?;
+                let mut params = Vec::new();
+                while let Some(token) = self.peek() {
+                    if token == &Token::RParen {
+                        break;
+                    }
+                    if !params.is_empty() {
+                        self.expect(Token::Comma)?;
+                    }
+                    params.push(self.parse_type()?);
+                }
+                self.expect(Token::RParen)?;
+
+                let return_type = if let Some(Token::Arrow) = self.peek() {
+                    self.advance();
+                    Some(Box::new(self.parse_type()?))
+                } else {
+                    None
+                };
+                Ok(Type::Function {
+                    params,
+                    return_type,
+                })
+            }
             Some(Token::Identifier(type_name)) => match type_name.as_str() {
                 "Int" => Ok(Type::Int),
                 "Float" => Ok(Type::Float),
                 "String" => Ok(Type::String),
                 "Bool" => Ok(Type::Bool),
-                _ => Ok(Type::Custom(type_name.clone())),
+                _ => {
+                    let name = type_name.clone();
+                    if let Some(Token::Less) = self.peek() {
+                        let args = self.parse_type_arguments()?;
+                        return Ok(Type::Generic { name, args });
+                    }
+                    Ok(Type::Custom(name))
+                }
             },
             Some(token) => Err(ParseError::UnexpectedToken {
                 expected: "type",
@@ -308,28 +2411,1556 @@ impl Parser {
             if !params.is_empty() {
                 self.expect(Token::Comma)?;
             }
+            params.push(self.node(SyntaxKind::Parameter, Self::parse_parameter)?);
+        }
 
-            let name = match self.advance() {
-                Some(Token::Identifier(name)) => name.clone(),
-                Some(token) => {
-                    return Err(ParseError::UnexpectedToken {
-                        expected: "parameter name",
-                        found: token.clone(),
-                    })
+        Ok(params)
+    }
+
+    /// Parses the parameters of a method, one of which may be marked
+    /// `@shardKey`; returns them with the name of the marked one
+    fn parse_method_parameters(&mut self) -> Result<(Vec<Parameter>, Option<String>), ParseError> {
+        let mut params = Vec::new();
+        let mut shard_key = None;
+
+        while let Some(token) = self.peek() {
+            if token == &Token::RParen {
+                break;
+            }
+
+            if !params.is_empty() {
+                self.expect(Token::Comma)?;
+            }
+            let (attributes, param) = self.node(SyntaxKind::Parameter, |p| {
+                Ok((p.parse_attributes()?, p.parse_parameter()?))
+            })?;
+            for attribute in attributes {
+                match (attribute.name.as_str(), attribute.arguments.as_slice()) {
+                    ("shardKey", []) if shard_key.is_none() => shard_key = Some(param.name.clone()),
+                    ("shardKey", []) => {
+                        return Err(ParseError::UnexpectedToken {
+                            expected: "at most one @shardKey parameter",
+                            found: Token::Identifier(param.name),
+                        })
+                    }
+                    _ => return Err(Self::unknown_attribute(attribute.name)),
                 }
-                None => return Err(ParseError::UnexpectedEOF),
-            };
+            }
+            params.push(param);
+        }
 
-            self.expect(Token::Colon)?;
-            let param_type = self.parse_type()?;
+        Ok((params, shard_key))
+    }
 
-            params.push(Parameter {
-                name,
-                param_type,
-                ownership: OwnershipType::Owned,
-            });
+    fn parse_parameter(&mut self) -> Result<Parameter, ParseError> {
+        let name = match self.advance() {
+            Some(Token::Identifier(name)) => name.clone(),
+            Some(token) => {
+                return Err(ParseError::UnexpectedToken {
+                    expected: "parameter name",
+                    found: token.clone(),
+                })
+            }
+            None => return Err(ParseError::UnexpectedEOF),
+        };
+
+        self.expect(Token::Colon)?;
+        let ownership = if let Some(Token::Inout) = self.peek() {
+            self.advance();
+            OwnershipType::Inout
+        } else {
+            OwnershipType::Owned
+        };
+        let param_type = self.parse_type()?;
+
+        Ok(Parameter {
+            name,
+            param_type,
+            ownership,
+        })
+    }
+}
+
+/// Precedence of `??`: looser than arithmetic, tighter than comparisons, so
+/// `count ?? 0 + 1 == total` is `(count ?? (0 + 1)) == total`
+const COALESCE_PRECEDENCE: u8 = 2;
+
+/// Binary operator for `token` with its precedence; higher binds tighter
+fn binary_operator(token: &Token) -> Option<(Operator, u8)> {
+    match token {
+        Token::EqualEqual => Some((Operator::Equal, 1)),
+        Token::NotEqual => Some((Operator::NotEqual, 1)),
+        Token::Less => Some((Operator::Less, 1)),
+        Token::LessEqual => Some((Operator::LessEqual, 1)),
+        Token::Greater => Some((Operator::Greater, 1)),
+        Token::GreaterEqual => Some((Operator::GreaterEqual, 1)),
+        Token::Plus => Some((Operator::Add, 3)),
+        Token::Minus => Some((Operator::Subtract, 3)),
+        Token::Multiply => Some((Operator::Multiply, 4)),
+        Token::Divide => Some((Operator::Divide, 4)),
+        _ => None,
+    }
+}
+
+/// Operator an assignment `token` applies before storing: `Some(None)` for `=`,
+/// `Some(Some(op))` for `op=`, and `None` if `token` does not assign
+fn assignment_operator(token: &Token) -> Option<Option<Operator>> {
+    match token {
+        Token::Equals => Some(None),
+        Token::PlusEquals => Some(Some(Operator::Add)),
+        Token::MinusEquals => Some(Some(Operator::Subtract)),
+        Token::MultiplyEquals => Some(Some(Operator::Multiply)),
+        Token::DivideEquals => Some(Some(Operator::Divide)),
+        _ => None,
+    }
+}
+
+/// `Int` literal of the digits `value`, negated if `negative`
+fn int_literal(value: &str, negative: bool) -> Result<Expression, ParseError> {
+    let text = if negative {
+        format!("-{}", value)
+    } else {
+        value.to_string()
+    };
+    match text.parse() {
+        Ok(value) => Ok(Expression::Literal(LiteralValue::Int(value))),
+        Err(_) => Err(ParseError::UnexpectedToken {
+            expected: "integer number",
+            found: Token::NumberLiteral(value.to_string()),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn number(value: &str) -> Token {
+        Token::NumberLiteral(value.to_string())
+    }
+
+    /// Parses `tokens` as an expression, rendered with explicit parentheses
+    fn parse(tokens: Vec<Token>) -> String {
+        let mut parser = Parser::new(tokens);
+        let expression = parser.parse_expression().unwrap();
+        assert!(parser.peek().is_none(), "unparsed tokens");
+        render(&expression)
+    }
+
+    fn render(expression: &Expression) -> String {
+        match expression {
+            Expression::BinaryOp {
+                left,
+                operator,
+                right,
+            } => {
+                let symbol = match operator {
+                    Operator::Add => "+",
+                    Operator::Subtract => "-",
+                    Operator::Multiply => "*",
+                    Operator::Divide => "/",
+                    Operator::Equal => "==",
+                    Operator::NotEqual => "!=",
+                    Operator::Less => "<",
+                    Operator::LessEqual => "<=",
+                    Operator::Greater => ">",
+                    Operator::GreaterEqual => ">=",
+                };
+                format!("({} {} {})", render(left), symbol, render(right))
+            }
+            Expression::Literal(LiteralValue::Int(value)) => value.to_string(),
+            Expression::Variable(name) => name.clone(),
+            Expression::Field(name) => format!("self.{}", name),
+            other => panic!("unexpected expression {:?}", other),
         }
+    }
 
-        Ok(params)
+    #[test]
+    fn test_multiplication_binds_tighter_than_addition() {
+        // 1 + 2 * 3
+        let tokens = vec![
+            number("1"),
+            Token::Plus,
+            number("2"),
+            Token::Multiply,
+            number("3"),
+        ];
+        assert_eq!(parse(tokens), "(1 + (2 * 3))");
+
+        // a * b - c / d
+        let tokens = vec![
+            Token::Identifier("a".to_string()),
+            Token::Multiply,
+            Token::Identifier("b".to_string()),
+            Token::Minus,
+            Token::Identifier("c".to_string()),
+            Token::Divide,
+            Token::Identifier("d".to_string()),
+        ];
+        assert_eq!(parse(tokens), "((a * b) - (c / d))");
+    }
+
+    #[test]
+    fn test_same_precedence_is_left_associative() {
+        // 10 - 4 - 3
+        let tokens = vec![
+            number("10"),
+            Token::Minus,
+            number("4"),
+            Token::Minus,
+            number("3"),
+        ];
+        assert_eq!(parse(tokens), "((10 - 4) - 3)");
+
+        // 8 / 4 * 2 + 1 - 6 / 3
+        let tokens = vec![
+            number("8"),
+            Token::Divide,
+            number("4"),
+            Token::Multiply,
+            number("2"),
+            Token::Plus,
+            number("1"),
+            Token::Minus,
+            number("6"),
+            Token::Divide,
+            number("3"),
+        ];
+        assert_eq!(parse(tokens), "((((8 / 4) * 2) + 1) - (6 / 3))");
+    }
+
+    #[test]
+    fn test_comparisons_bind_looser_than_arithmetic() {
+        // a + 1 < b * 2
+        let tokens = vec![
+            Token::Identifier("a".to_string()),
+            Token::Plus,
+            number("1"),
+            Token::Less,
+            Token::Identifier("b".to_string()),
+            Token::Multiply,
+            number("2"),
+        ];
+        assert_eq!(parse(tokens), "((a + 1) < (b * 2))");
+
+        // a == b != c は括弧なしでは連鎖できない
+        let tokens = vec![
+            Token::Identifier("a".to_string()),
+            Token::EqualEqual,
+            Token::Identifier("b".to_string()),
+            Token::NotEqual,
+            Token::Identifier("c".to_string()),
+        ];
+        assert!(matches!(
+            Parser::new(tokens).parse_expression(),
+            Err(ParseError::UnexpectedToken {
+                found: Token::NotEqual,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_conversions_are_postfix() {
+        // values[0].toString()
+        let tokens = vec![
+            Token::Identifier("values".to_string()),
+            Token::LBracket,
+            number("0"),
+            Token::RBracket,
+            Token::Dot,
+            Token::Identifier("toString".to_string()),
+            Token::LParen,
+            Token::RParen,
+        ];
+        let expression = Parser::new(tokens).parse_expression().unwrap();
+        let Expression::Conversion {
+            conversion: Conversion::IntToString,
+            value,
+        } = expression
+        else {
+            panic!("expected a conversion, found {:?}", expression);
+        };
+        assert!(matches!(*value, Expression::Index { .. }));
+
+        let tokens = vec![
+            Token::StringLiteral("7".to_string()),
+            Token::Dot,
+            Token::Identifier("toFloat".to_string()),
+            Token::LParen,
+            Token::RParen,
+        ];
+        assert!(matches!(
+            Parser::new(tokens).parse_expression(),
+            Err(ParseError::UnexpectedToken {
+                expected: "toString or toInt",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_string_interpolation() {
+        let parse = |source: &str| {
+            let tokens = crate::lexer::tokenize(source).unwrap();
+            Parser::new(tokens).parse_expression()
+        };
+        let expression = parse(r#""total \(a + 1) of \(b).""#).unwrap();
+        assert_eq!(
+            expression.to_string(),
+            r#"((("total " + "\(a + 1)") + " of ") + "\(b)") + ".""#
+        );
+        // 空の文字列片は連結しない
+        let expression = parse(r#""\(count)""#).unwrap();
+        assert!(matches!(
+            expression,
+            Expression::Conversion {
+                conversion: Conversion::Interpolate,
+                ..
+            }
+        ));
+        assert_eq!(
+            parse(r#""\(a)\(b)!""#).unwrap().to_string(),
+            r#"("\(a)" + "\(b)") + "!""#
+        );
+        // 文字列はエスケープし直して書き戻す
+        let source = r#""say \"hi\"\n\\ \u{7}\(a)""#;
+        assert_eq!(
+            parse(source).unwrap().to_string(),
+            r#""say \"hi\"\n\\ \u{7}" + "\(a)""#
+        );
+
+        assert!(matches!(
+            parse(r#""\(a b)""#),
+            Err(ParseError::UnexpectedToken {
+                expected: ") closing the interpolation",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_random_builtins() {
+        // randomInt(6) + 1
+        let tokens = vec![
+            Token::Identifier("randomInt".to_string()),
+            Token::LParen,
+            number("6"),
+            Token::RParen,
+            Token::Plus,
+            number("1"),
+        ];
+        let expression = Parser::new(tokens).parse_expression().unwrap();
+        let Expression::BinaryOp { left, .. } = expression else {
+            panic!("expected an addition, found {:?}", expression);
+        };
+        assert!(matches!(
+            *left,
+            Expression::Random {
+                operation: RandomOperation::Int,
+                ref args,
+            } if args.len() == 1
+        ));
+
+        let tokens = vec![
+            Token::Identifier("randomFloat".to_string()),
+            Token::LParen,
+            Token::RParen,
+        ];
+        assert!(matches!(
+            Parser::new(tokens).parse_expression().unwrap(),
+            Expression::Random {
+                operation: RandomOperation::Float,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_config_builtin() {
+        let tokens = crate::lexer::tokenize("config(\"API_URL\")").unwrap();
+        assert!(matches!(
+            Parser::new(tokens).parse_expression().unwrap(),
+            Expression::Config(ref key) if key == "API_URL"
+        ));
+
+        // キーは文字列リテラルに限る
+        let tokens = crate::lexer::tokenize("config(name)").unwrap();
+        assert!(matches!(
+            Parser::new(tokens).parse_expression(),
+            Err(ParseError::UnexpectedToken {
+                expected: "config key as a string literal",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_log_statements_record_lines() {
+        let source = "log.info(\"ready\")\n\nlog.warn(name)";
+        let tokens = crate::lexer::tokenize_with_lines(source).unwrap();
+        let body = Parser::with_lines(tokens).parse_method_body().unwrap();
+        let [Statement::Log {
+            level: LogLevel::Info,
+            message: Expression::Literal(LiteralValue::String(text)),
+            line: 1,
+        }, Statement::Log {
+            level: LogLevel::Warn,
+            message,
+            line: 3,
+        }] = body.statements.as_slice()
+        else {
+            panic!("expected two log statements, found {:?}", body.statements);
+        };
+        assert_eq!(text, "ready");
+        assert_eq!(render(message), "name");
+
+        let tokens = crate::lexer::tokenize("log.trace(\"x\")").unwrap();
+        assert!(matches!(
+            Parser::new(tokens).parse_method_body(),
+            Err(ParseError::UnexpectedToken {
+                expected: "debug, info, warn or error",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_actors_compare_structurally() {
+        let source = "actor Counter {\n    var count: Int\n\n    func tick() {\n        if count > 1.5 {\n            log.info(\"tick\")\n        }\n    }\n}";
+        let parse = |shift: usize| {
+            let tokens = crate::lexer::tokenize_with_lines(source).unwrap();
+            let tokens = tokens.into_iter().map(|(t, line)| (t, line + shift));
+            Parser::with_lines(tokens.collect()).parse_actor().unwrap()
+        };
+        let (actor, moved) = (parse(0), parse(10));
+        assert_eq!(actor, actor.clone());
+        assert_ne!(actor, moved);
+        assert!(actor.structurally_eq(&moved));
+
+        let mut renamed = moved.clone();
+        renamed.methods[0].name = "tock".to_string();
+        assert!(!actor.structurally_eq(&renamed));
+
+        let actors: std::collections::HashSet<Actor> =
+            [actor.without_spans(), moved.without_spans()].into();
+        assert_eq!(actors.len(), 1);
+        assert_eq!(LiteralValue::Float(f64::NAN), LiteralValue::Float(f64::NAN));
+        assert_ne!(LiteralValue::Float(0.0), LiteralValue::Float(-0.0));
+    }
+
+    #[test]
+    fn test_init_block() {
+        let tokens = crate::lexer::tokenize(
+            "single actor Point {
+    let x: Int
+    var y: Int
+
+    immediate init(x: Int, y: Int) {
+        self.x = x
+        self.y = y
+    }
+}",
+        )
+        .unwrap();
+        let actor = Parser::new(tokens).parse_actor().unwrap();
+        let init = actor.init.unwrap();
+        assert!(init.is_immediate);
+        let params: Vec<_> = init.params.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(params, ["x", "y"]);
+        assert_eq!(init.body.statements.len(), 2);
+        assert!(actor.methods.is_empty());
+
+        // init は一つだけ
+        let tokens = crate::lexer::tokenize("actor Twice { init() {} init(x: Int) {} }").unwrap();
+        assert!(Parser::new(tokens).parse_actor().is_err());
+    }
+
+    #[test]
+    fn test_self_fields_and_assignment() {
+        let source = "self.count = self.count + step\ntotal = 0\nself.reset()";
+        let tokens = crate::lexer::tokenize(source).unwrap();
+        let body = Parser::new(tokens).parse_method_body().unwrap();
+        let [Statement::Assign {
+            target: first_target,
+            operator: None,
+            value: first_value,
+        }, Statement::Assign {
+            target: Expression::Variable(second_target),
+            ..
+        }, Statement::Expression(Expression::Call { callee, args, .. })] =
+            body.statements.as_slice()
+        else {
+            panic!(
+                "expected two assignments and a call, found {:?}",
+                body.statements
+            );
+        };
+        assert_eq!(render(first_target), "self.count");
+        assert_eq!(render(first_value), "(self.count + step)");
+        assert_eq!(second_target, "total");
+        assert_eq!(callee, "reset");
+        assert!(args.is_empty());
+
+        let tokens = crate::lexer::tokenize("count + 1 = 2").unwrap();
+        assert!(matches!(
+            Parser::new(tokens).parse_method_body(),
+            Err(ParseError::UnexpectedToken {
+                found: Token::Equals,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_compound_assignment() {
+        let source = "total += i * 2\nself.count -= 1\nscale *= 3\nscale /= 2";
+        let tokens = crate::lexer::tokenize(source).unwrap();
+        let body = Parser::new(tokens).parse_method_body().unwrap();
+        let assignments: Vec<(String, Option<Operator>, String)> = body
+            .statements
+            .iter()
+            .map(|statement| match statement {
+                Statement::Assign {
+                    target,
+                    operator,
+                    value,
+                } => (render(target), operator.clone(), render(value)),
+                other => panic!("expected an assignment, found {:?}", other),
+            })
+            .collect();
+        assert_eq!(
+            assignments,
+            vec![
+                (
+                    "total".to_string(),
+                    Some(Operator::Add),
+                    "(i * 2)".to_string()
+                ),
+                (
+                    "self.count".to_string(),
+                    Some(Operator::Subtract),
+                    "1".to_string()
+                ),
+                (
+                    "scale".to_string(),
+                    Some(Operator::Multiply),
+                    "3".to_string()
+                ),
+                ("scale".to_string(), Some(Operator::Divide), "2".to_string()),
+            ]
+        );
+
+        let tokens = crate::lexer::tokenize("count() += 1").unwrap();
+        assert!(matches!(
+            Parser::new(tokens).parse_method_body(),
+            Err(ParseError::UnexpectedToken {
+                found: Token::PlusEquals,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_labeled_loops() {
+        let identifier = |name: &str| Token::Identifier(name.to_string());
+        // outer: for i in 0..n { while ready { break outer } }
+        let tokens = vec![
+            identifier("outer"),
+            Token::Colon,
+            Token::For,
+            identifier("i"),
+            Token::In,
+            number("0"),
+            Token::DotDot,
+            identifier("n"),
+            Token::LBrace,
+            Token::While,
+            identifier("ready"),
+            Token::LBrace,
+            Token::Break,
+            identifier("outer"),
+            Token::RBrace,
+            Token::RBrace,
+        ];
+        let body = Parser::new(tokens).parse_method_body().unwrap();
+        let [Statement::For {
+            label,
+            variable,
+            start,
+            end,
+            body,
+        }] = body.statements.as_slice()
+        else {
+            panic!("expected a for loop, found {:?}", body.statements);
+        };
+        assert_eq!(label.as_deref(), Some("outer"));
+        assert_eq!(variable, "i");
+        assert_eq!((render(start), render(end)), ("0".into(), "n".into()));
+        let [Statement::While {
+            label: None,
+            condition,
+            body,
+        }] = body.statements.as_slice()
+        else {
+            panic!("expected a while loop, found {:?}", body.statements);
+        };
+        assert_eq!(render(condition), "ready");
+        assert!(matches!(
+            body.statements.as_slice(),
+            [Statement::Break(Some(label))] if label == "outer"
+        ));
+    }
+
+    #[test]
+    fn test_if_else_if_else() {
+        let identifier = |name: &str| Token::Identifier(name.to_string());
+        // if ready { return 1 } else if done { return 2 } else { return 3 }
+        let tokens = vec![
+            Token::If,
+            identifier("ready"),
+            Token::LBrace,
+            Token::Return,
+            number("1"),
+            Token::RBrace,
+            Token::Else,
+            Token::If,
+            identifier("done"),
+            Token::LBrace,
+            Token::Return,
+            number("2"),
+            Token::RBrace,
+            Token::Else,
+            Token::LBrace,
+            Token::Return,
+            number("3"),
+            Token::RBrace,
+        ];
+        let body = Parser::new(tokens).parse_method_body().unwrap();
+        assert_eq!(body.statements.len(), 1);
+
+        let Statement::If {
+            condition,
+            then_body,
+            else_body: Some(else_body),
+        } = &body.statements[0]
+        else {
+            panic!("expected if/else, found {:?}", body.statements[0]);
+        };
+        assert_eq!(render(condition), "ready");
+        assert_eq!(then_body.statements.len(), 1);
+        assert!(matches!(
+            else_body.statements.as_slice(),
+            [Statement::If {
+                else_body: Some(_),
+                ..
+            }]
+        ));
+
+        // else のない if の後に文が続く
+        let tokens = vec![
+            Token::If,
+            identifier("ready"),
+            Token::LBrace,
+            Token::RBrace,
+            Token::Return,
+            number("0"),
+        ];
+        let body = Parser::new(tokens).parse_method_body().unwrap();
+        assert!(matches!(
+            body.statements.as_slice(),
+            [
+                Statement::If {
+                    else_body: None,
+                    ..
+                },
+                Statement::Return(_)
+            ]
+        ));
+    }
+
+    #[test]
+    fn test_bool_literal_conditions() {
+        // if true { } else if false { }
+        let tokens = vec![
+            Token::If,
+            Token::True,
+            Token::LBrace,
+            Token::RBrace,
+            Token::Else,
+            Token::If,
+            Token::False,
+            Token::LBrace,
+            Token::RBrace,
+        ];
+        let body = Parser::new(tokens).parse_method_body().unwrap();
+        let [Statement::If {
+            condition,
+            else_body: Some(else_body),
+            ..
+        }] = body.statements.as_slice()
+        else {
+            panic!("expected if/else, found {:?}", body.statements);
+        };
+        assert!(matches!(
+            condition,
+            Expression::Literal(LiteralValue::Bool(true))
+        ));
+        assert!(matches!(
+            else_body.statements.as_slice(),
+            [Statement::If {
+                condition: Expression::Literal(LiteralValue::Bool(false)),
+                else_body: None,
+                ..
+            }]
+        ));
+    }
+
+    #[test]
+    fn test_parentheses_override_precedence() {
+        // (1 + 2) * 3
+        let tokens = vec![
+            Token::LParen,
+            number("1"),
+            Token::Plus,
+            number("2"),
+            Token::RParen,
+            Token::Multiply,
+            number("3"),
+        ];
+        assert_eq!(parse(tokens), "((1 + 2) * 3)");
+    }
+
+    #[test]
+    fn test_negative_literals() {
+        // 1 - -2 * 3
+        let tokens = vec![
+            number("1"),
+            Token::Minus,
+            Token::Minus,
+            number("2"),
+            Token::Multiply,
+            number("3"),
+        ];
+        assert_eq!(parse(tokens), "(1 - (-2 * 3))");
+        assert_eq!(
+            parse(vec![Token::Minus, number("2147483648")]),
+            i32::MIN.to_string()
+        );
+
+        // 負にしなければ Int に収まらない
+        let mut parser = Parser::new(vec![number("2147483648")]);
+        assert!(parser.parse_expression().is_err());
+        let mut parser = Parser::new(vec![Token::Minus, Token::Identifier("a".to_string())]);
+        assert!(matches!(
+            parser.parse_expression(),
+            Err(ParseError::UnexpectedToken {
+                expected: "number after -",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_constants() {
+        let source = "actor Router {
+            /// Ports by service
+            const ports: [String: Int] = [\"http\": 80, \"https\": 443]
+            const steps: [Float] = [0.5, -1.5]
+            const none: [Int: String] = [:]
+        }";
+        let tokens = crate::lexer::tokenize_with_lines(source).unwrap();
+        let actor = Parser::with_lines(tokens)
+            .with_docs(crate::lexer::doc_comments(source))
+            .parse_actor()
+            .unwrap();
+        let string = |text: &str| LiteralValue::String(text.to_string());
+        assert_eq!(
+            actor.constants[0],
+            Constant {
+                name: "ports".to_string(),
+                table: ConstantTable::Map {
+                    key_type: Type::String,
+                    value_type: Type::Int,
+                    entries: vec![
+                        (string("http"), LiteralValue::Int(80)),
+                        (string("https"), LiteralValue::Int(443)),
+                    ],
+                },
+                doc: Some("Ports by service".to_string()),
+            }
+        );
+        assert_eq!(
+            actor.constants[1].table,
+            ConstantTable::Array {
+                element_type: Type::Float,
+                values: vec![LiteralValue::Float(0.5), LiteralValue::Float(-1.5)],
+            }
+        );
+        assert!(matches!(
+            &actor.constants[2].table,
+            ConstantTable::Map { entries, .. } if entries.is_empty()
+        ));
+
+        // 値はリテラルに限る
+        let tokens = crate::lexer::tokenize("const limits: [Int] = [1, limit]").unwrap();
+        assert!(matches!(
+            Parser::new(tokens).parse_constant(),
+            Err(ParseError::UnexpectedToken {
+                expected: "constant value",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_generic_arguments() {
+        let tokens = crate::lexer::tokenize("Cache<String, [Map<Int, Bool>]>?").unwrap();
+        let map = Type::Generic {
+            name: "Map".to_string(),
+            args: vec![Type::Int, Type::Bool],
+        };
+        assert_eq!(
+            Parser::new(tokens).parse_type().unwrap(),
+            Type::Optional(Box::new(Type::Generic {
+                name: "Cache".to_string(),
+                args: vec![Type::String, Type::Array(Box::new(map))],
+            }))
+        );
+
+        let tokens = crate::lexer::tokenize("map<Int>(values) + self.get<String>()").unwrap();
+        let mut parser = Parser::new(tokens);
+        let Expression::BinaryOp { left, right, .. } = parser.parse_expression().unwrap() else {
+            panic!("expected a sum of two calls");
+        };
+        assert!(matches!(
+            (*left, *right),
+            (
+                Expression::Call { callee: map, type_args: map_args, args },
+                Expression::Call { callee: get, type_args: get_args, .. },
+            ) if map == "map" && map_args == [Type::Int] && args.len() == 1
+                && get == "get" && get_args == [Type::String]
+        ));
+
+        // ( が続かない < は比較のまま
+        let comparisons = [
+            ("a < b", "(a < b)"),
+            ("a < (b)", "(a < b)"),
+            ("a < b * c", "(a < (b * c))"),
+        ];
+        for (source, expected) in comparisons {
+            assert_eq!(parse(crate::lexer::tokenize(source).unwrap()), expected);
+        }
+    }
+
+    #[test]
+    fn test_recover_errors() {
+        let source = "actor A {
+            var : Int
+            func f() -> Int {
+                let x = = 1
+                return x +
+            }
+            func g() -> Int {
+                return 2
+            }
+            var ok: Int
+        }";
+        let tokens = crate::lexer::tokenize_with_lines(source).unwrap();
+        let mut parser = Parser::with_lines(tokens).recover_errors();
+        let actor = parser.parse_actor().unwrap();
+        let errors = parser.take_errors();
+        assert_eq!(errors.len(), 3, "{:?}", errors);
+        assert!(matches!(
+            &errors[0].0,
+            ParseError::UnexpectedToken {
+                found: Token::Colon,
+                ..
+            }
+        ));
+
+        // 失敗した文とフィールドだけが抜ける
+        let names: Vec<&str> = actor.methods.iter().map(|m| m.name.as_str()).collect();
+        assert_eq!(names, ["f", "g"]);
+        assert!(actor.methods[0]
+            .body
+            .as_ref()
+            .unwrap()
+            .statements
+            .is_empty());
+        assert_eq!(actor.fields.len(), 1);
+        assert_eq!(actor.fields[0].name, "ok");
+
+        let tokens = crate::lexer::tokenize_with_lines(source).unwrap();
+        assert!(Parser::with_lines(tokens).parse_actor().is_err());
+    }
+
+    #[test]
+    fn test_parse_limits() {
+        // `actor A { func f() -> Int { <body> } }` のトークン
+        let actor = |body: Vec<Token>| {
+            let mut tokens = vec![
+                Token::Actor,
+                Token::Identifier("A".to_string()),
+                Token::LBrace,
+                Token::Func,
+                Token::Identifier("f".to_string()),
+                Token::LParen,
+                Token::RParen,
+                Token::Arrow,
+                Token::Identifier("Int".to_string()),
+                Token::LBrace,
+            ];
+            tokens.extend(body);
+            tokens.extend([Token::RBrace, Token::RBrace]);
+            tokens
+        };
+        let nested = |depth: usize| {
+            let mut body = vec![Token::Return];
+            body.extend(std::iter::repeat_n(Token::LParen, depth));
+            body.push(number("1"));
+            body.extend(std::iter::repeat_n(Token::RParen, depth));
+            body
+        };
+
+        // 上限までは解析でき、それを超えるとスタックを使い切る前にエラーになる
+        assert!(Parser::new(actor(nested(100))).parse_actor().is_ok());
+        let error = Parser::new(actor(nested(100_000)))
+            .parse_actor()
+            .unwrap_err();
+        assert!(matches!(error, ParseError::TooDeeplyNested(128)));
+        let limits = ParseLimits {
+            max_depth: 8,
+            ..ParseLimits::default()
+        };
+        let mut parser = Parser::new(actor(nested(8))).with_limits(limits);
+        let error = parser.parse_actor().unwrap_err();
+        assert_eq!(parser.error_position(&error), Some(15));
+        assert_eq!(
+            error.to_string(),
+            "Nesting is deeper than the limit of 8 levels"
+        );
+
+        let limits = ParseLimits {
+            max_tokens: 12,
+            ..ParseLimits::default()
+        };
+        let error = Parser::new(actor(nested(1)))
+            .with_limits(limits)
+            .parse_actor()
+            .unwrap_err();
+        assert!(matches!(error, ParseError::TooManyTokens(12)));
+
+        // 回復したエラーが上限に達したら打ち切る
+        let broken = vec![vec![Token::Let, Token::Equals, Token::Equals]; 10].concat();
+        let limits = ParseLimits {
+            max_errors: 3,
+            ..ParseLimits::default()
+        };
+        let lines = actor(broken).into_iter().enumerate();
+        let mut parser = Parser::with_lines(lines.map(|(line, token)| (token, line)).collect())
+            .with_limits(limits)
+            .recover_errors();
+        let error = parser.parse_actor().unwrap_err();
+        assert!(matches!(error, ParseError::TooManyErrors(3)));
+        assert_eq!(parser.take_errors().len(), 3);
+    }
+
+    #[test]
+    fn test_long_chains_within_the_depth_limit() {
+        let parse = |expression: String| {
+            let source = format!("actor A {{ func f() -> Int {{ return {} }} }}", expression);
+            Parser::new(crate::lexer::tokenize(&source).unwrap()).parse_actor()
+        };
+
+        // 左に畳み込む連鎖や長い補間はループで読むので、深さに数えない
+        for long in [
+            vec!["1"; 300].join(" + "),
+            vec!["1"; 300].join(" * 2 - "),
+            format!("\"{}\"", "\\(x) ".repeat(300)),
+            format!("x{}", "!".repeat(300)),
+            format!("a{}", "[0]".repeat(300)),
+        ] {
+            assert!(parse(long).is_ok());
+        }
+
+        // 括弧と右結合の `??` は再帰するので数える
+        for deep in [
+            format!("{}1{}", "(".repeat(200), ")".repeat(200)),
+            vec!["1"; 200].join(" ?? "),
+        ] {
+            let error = parse(deep).unwrap_err();
+            assert!(
+                matches!(error, ParseError::TooDeeplyNested(128)),
+                "{}",
+                error
+            );
+        }
+    }
+
+    #[test]
+    fn test_nonreentrant_attribute() {
+        let parse = |source: &str| {
+            let tokens = crate::lexer::tokenize(source).unwrap();
+            Parser::new(tokens).parse_actor()
+        };
+        let actor = parse(
+            "actor Cache {\n    @nonreentrant\n    func refresh() {}\n\n    func get() -> Int {\n        return 1\n    }\n}",
+        )
+        .unwrap();
+        assert!(actor.methods[0].is_nonreentrant);
+        assert!(!actor.methods[1].is_nonreentrant);
+
+        // アクターに付けるとすべてのメソッドが対象になる
+        let actor =
+            parse("@nonreentrant\nactor Cache {\n    func refresh() {}\n    func clear() {}\n}")
+                .unwrap();
+        assert!(actor.methods.iter().all(|method| method.is_nonreentrant));
+
+        assert!(matches!(
+            parse("actor Cache {\n    @packed\n    func refresh() {}\n}"),
+            Err(ParseError::UnexpectedToken {
+                expected: "known attribute",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_single_actor_keyword_pair() {
+        let parse = |source: &str| {
+            let tokens = crate::lexer::tokenize(source).unwrap();
+            Parser::new(tokens).parse_actor()
+        };
+        for source in [
+            "single actor Counter {}",
+            "single\nactor Counter {}",
+            "single   // 単一インスタンス\n    actor Counter {}",
+        ] {
+            let actor = parse(source).unwrap();
+            assert_eq!(actor.actor_type, ActorType::Single);
+            assert_eq!(actor.name, "Counter");
+        }
+        assert_eq!(
+            parse("actor Counter {}").unwrap().actor_type,
+            ActorType::Distributed
+        );
+
+        assert!(matches!(
+            parse("single Counter {}"),
+            Err(ParseError::UnexpectedToken {
+                expected: "actor after single",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_async_and_sequential_methods() {
+        let tokens = crate::lexer::tokenize(
+            "actor Queue {
+    func size() -> Int {
+        return 0
+    }
+
+    async func push(value: Int) {}
+
+    @nonreentrant
+    sequential func drain() -> Int {
+        await push(1)
+        return await fetch(size())! + 1
+    }
+
+    async deinit {}
+}",
+        )
+        .unwrap();
+        let actor = Parser::new(tokens).parse_actor().unwrap();
+        let flags: Vec<_> = actor
+            .methods
+            .iter()
+            .map(|m| (m.name.as_str(), m.is_async, m.is_sequential))
+            .collect();
+        assert_eq!(
+            flags,
+            [
+                ("size", false, false),
+                ("push", true, false),
+                ("drain", true, true)
+            ]
+        );
+        assert!(actor.methods[2].is_nonreentrant);
+        assert!(actor.deinit.unwrap().is_async);
+
+        // await は後置の ! まで含めた式にかかり、+ より強く結合する
+        let body = &actor.methods[2].body.as_ref().unwrap().statements;
+        assert_eq!(
+            body[0],
+            Statement::Expression(Expression::Await(Box::new(Expression::Call {
+                callee: "push".to_string(),
+                type_args: vec![],
+                args: vec![Expression::Literal(LiteralValue::Int(1))],
+            })))
+        );
+        let Statement::Return(value) = &body[1] else {
+            panic!("expected a return, found {:?}", body[1]);
+        };
+        assert_eq!(value.to_string(), "await fetch(size())! + 1");
+        assert!(matches!(
+            value,
+            Expression::BinaryOp { left, .. } if matches!(left.as_ref(), Expression::Await(_))
+        ));
+    }
+
+    #[test]
+    fn test_yield_statement() {
+        let tokens = crate::lexer::tokenize(
+            "actor Feed {\n    func ticks() -> AsyncStream<Int> {\n        yield 1\n    }\n}",
+        )
+        .unwrap();
+        let actor = Parser::new(tokens).parse_actor().unwrap();
+        let method = &actor.methods[0];
+        assert_eq!(method.stream_item(), Some(&Type::Int));
+        assert!(matches!(
+            method.body.as_ref().unwrap().statements.as_slice(),
+            [Statement::Yield(Expression::Literal(LiteralValue::Int(1)))]
+        ));
+    }
+
+    #[test]
+    fn test_imports() {
+        let tokens = crate::lexer::tokenize(
+            "import Peer\nimport Storage\n\n@packed\nactor Node {\n    var peer: Peer\n}",
+        )
+        .unwrap();
+        let actor = Parser::new(tokens).parse_actor().unwrap();
+        assert_eq!(actor.imports, ["Peer", "Storage"]);
+        assert!(actor.layout.packed);
+        assert_eq!(actor.fields[0].field_type, Type::Custom("Peer".to_string()));
+
+        let tokens = crate::lexer::tokenize("import \"Peer\"\nactor Node {}").unwrap();
+        assert!(matches!(
+            Parser::new(tokens).parse_actor(),
+            Err(ParseError::UnexpectedToken {
+                expected: "module name",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_topics() {
+        let tokens = crate::lexer::tokenize(
+            "import Feed\ntopic Prices: Int\n\nactor Ticker {\n    func start() {\n        subscribe(Prices)\n        publish(Prices, 1 + 2)\n    }\n}",
+        )
+        .unwrap();
+        let actor = Parser::new(tokens).parse_actor().unwrap();
+        assert_eq!(actor.imports, ["Feed"]);
+        assert_eq!(
+            actor.topics,
+            [Topic {
+                name: "Prices".to_string(),
+                value_type: Type::Int,
+            }]
+        );
+        let body = actor.methods[0].body.as_ref().unwrap();
+        assert!(matches!(
+            body.statements.as_slice(),
+            [Statement::Subscribe(topic), Statement::Publish { topic: published, value: Expression::BinaryOp { .. } }]
+                if topic == "Prices" && published == "Prices"
+        ));
+
+        let tokens =
+            crate::lexer::tokenize("actor A {\n    func f() {\n        publish(Prices)\n    }\n}")
+                .unwrap();
+        assert!(Parser::new(tokens).parse_actor().is_err());
+    }
+
+    #[test]
+    fn test_pools() {
+        let parse = |source: &str| {
+            let tokens = crate::lexer::tokenize(source).unwrap();
+            Parser::new(tokens).parse_actor()
+        };
+        let actor = parse(
+            "pool Workers of Worker(size: 4)
+pool Shards of Worker(size: 8, strategy: consistentHash(user))
+
+actor Worker {
+    func run(user: Int) {}
+}",
+        )
+        .unwrap();
+        assert_eq!(
+            actor.pools,
+            [
+                Pool {
+                    name: "Workers".to_string(),
+                    actor: "Worker".to_string(),
+                    size: 4,
+                    strategy: PoolStrategy::RoundRobin,
+                },
+                Pool {
+                    name: "Shards".to_string(),
+                    actor: "Worker".to_string(),
+                    size: 8,
+                    strategy: PoolStrategy::ConsistentHash("user".to_string()),
+                },
+            ]
+        );
+
+        assert!(matches!(
+            parse(
+                "pool Workers of Worker(size: 4, strategy: random)
+actor Worker {}"
+            ),
+            Err(ParseError::UnexpectedToken {
+                expected: "roundRobin or consistentHash",
+                ..
+            })
+        ));
+        assert!(matches!(
+            parse(
+                "pool Workers Worker(size: 4)
+actor Worker {}"
+            ),
+            Err(ParseError::UnexpectedToken { expected: "of", .. })
+        ));
+    }
+
+    #[test]
+    fn test_shard_keys() {
+        let parse = |source: &str| {
+            let tokens = crate::lexer::tokenize(source).unwrap();
+            Parser::new(tokens).parse_actor()
+        };
+        let actor = parse(
+            "actor Accounts {
+    func deposit(@shardKey account: String, amount: Int) {}
+    func total() {}
+}",
+        )
+        .unwrap();
+        assert_eq!(actor.methods[0].shard_key.as_deref(), Some("account"));
+        assert_eq!(actor.methods[0].params[0].name, "account");
+        assert_eq!(actor.methods[1].shard_key, None);
+
+        assert!(matches!(
+            parse(
+                "actor Accounts {
+    func transfer(@shardKey source: String, @shardKey target: String) {}
+}"
+            ),
+            Err(ParseError::UnexpectedToken {
+                expected: "at most one @shardKey parameter",
+                ..
+            })
+        ));
+        assert!(matches!(
+            parse(
+                "actor Accounts {
+    func deposit(@key account: String) {}
+}"
+            ),
+            Err(ParseError::UnexpectedToken {
+                expected: "known attribute",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_retry_and_idempotent_attributes() {
+        let parse = |source: &str| {
+            let tokens = crate::lexer::tokenize(source).unwrap();
+            Parser::new(tokens).parse_actor()
+        };
+        let actor = parse(
+            "actor Payments {
+    @idempotent
+    @retry(times: 3, backoff: 100ms)
+    func charge(id: String, amount: Int) {}
+
+    @retry(backoff: 2s, times: 1)
+    func refund(id: String) {}
+
+    func audit() {}
+}",
+        )
+        .unwrap();
+        assert!(actor.methods[0].is_idempotent);
+        assert_eq!(
+            actor.methods[0].retry,
+            Some(RetryPolicy {
+                times: 3,
+                backoff_ms: 100
+            })
+        );
+        assert!(!actor.methods[1].is_idempotent);
+        assert_eq!(
+            actor.methods[1].retry,
+            Some(RetryPolicy {
+                times: 1,
+                backoff_ms: 2000
+            })
+        );
+        assert!(!actor.methods[2].is_remote());
+
+        for source in [
+            "actor Payments {\n    @retry(times: 3)\n    func charge() {}\n}",
+            "actor Payments {\n    @retry(times: 3, backoff: 100)\n    func charge() {}\n}",
+            "actor Payments {\n    @retry(times: 3, times: 4, backoff: 1s)\n    func charge() {}\n}",
+        ] {
+            assert!(
+                matches!(
+                    parse(source),
+                    Err(ParseError::UnexpectedToken {
+                        expected: "@retry(times: n, backoff: duration)",
+                        ..
+                    })
+                ),
+                "{}",
+                source
+            );
+        }
+        assert!(matches!(
+            parse("actor Payments {\n    @idempotent(true)\n    func charge() {}\n}"),
+            Err(ParseError::UnexpectedToken { .. })
+        ));
+    }
+
+    #[test]
+    fn test_api_version_attributes() {
+        let parse = |source: &str| {
+            let tokens = crate::lexer::tokenize(source).unwrap();
+            Parser::new(tokens).parse_actor()
+        };
+        let actor = parse(
+            "actor Bank {
+    @removed(3)
+    func open(name: String) {}
+
+    @since(2)
+    func deposit(amount: Int) {}
+
+    func audit() {}
+}",
+        )
+        .unwrap();
+        assert_eq!(
+            (actor.methods[0].since, actor.methods[0].removed),
+            (None, Some(3))
+        );
+        assert_eq!(
+            (actor.methods[1].since, actor.methods[1].removed),
+            (Some(2), None)
+        );
+        assert!(actor.methods[0].is_available_in(2));
+        assert!(!actor.methods[0].is_available_in(3));
+        assert!(!actor.methods[1].is_available_in(1));
+        assert!(actor.methods[2].is_available_in(1));
+
+        for source in [
+            "actor Bank {\n    @since\n    func audit() {}\n}",
+            "actor Bank {\n    @since(\"2\")\n    func audit() {}\n}",
+            "actor Bank {\n    @removed(version: 2)\n    func audit() {}\n}",
+        ] {
+            assert!(
+                matches!(parse(source), Err(ParseError::UnexpectedToken { .. })),
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn test_structs() {
+        let tokens = crate::lexer::tokenize(
+            "struct Point {
+    var x: Int
+    let y: Int
+}
+
+actor Board {
+    func shift(point: Point) -> String {
+        let moved = Point(x: point.x + 1, y: point.y)
+        return moved.x.toString()
+    }
+}",
+        )
+        .unwrap();
+        let actor = Parser::new(tokens).parse_actor().unwrap();
+        assert_eq!(actor.structs.len(), 1);
+        let point = &actor.structs[0];
+        assert_eq!(point.name, "Point");
+        assert_eq!(
+            point
+                .fields
+                .iter()
+                .map(|field| (field.name.as_str(), field.is_mutable))
+                .collect::<Vec<_>>(),
+            [("x", true), ("y", false)]
+        );
+
+        let body = actor.methods[0].body.as_ref().unwrap();
+        let Statement::Let { value, .. } = &body.statements[0] else {
+            panic!("expected let, found {:?}", body.statements[0]);
+        };
+        assert_eq!(value.to_string(), "Point(x: point.x + 1, y: point.y)");
+        let Expression::StructLiteral { name, fields } = value else {
+            panic!("expected a struct value, found {:?}", value);
+        };
+        assert_eq!(name, "Point");
+        assert!(matches!(
+            &fields[1].1,
+            Expression::Member { base, name } if **base == Expression::Variable("point".to_string()) && name == "y"
+        ));
+        // 括弧が続くドットは変換のまま
+        assert!(matches!(
+            &body.statements[1],
+            Statement::Return(Expression::Conversion { value, .. }) if value.to_string() == "moved.x"
+        ));
+    }
+
+    #[test]
+    fn test_enums() {
+        let tokens = crate::lexer::tokenize(
+            "enum Shape {
+    case circle(Float)
+    case rect(Float, Float)
+    case empty
+}
+
+actor Canvas {
+    func area(shape: Shape) -> Float {
+        match shape {
+            .circle(let r) => {
+                return r * r * 3.0
+            }
+            .rect(let w, let h) => {
+                return w * h
+            }
+            .empty => {
+                return 0.0
+            }
+        }
+    }
+
+    func unit() -> Shape {
+        return Shape.rect(1.0, 1.0)
+    }
+
+    func sides(shape: Shape) -> Int {
+        return match shape { .rect(_, _) => 4, _ => 0 }
+    }
+}",
+        )
+        .unwrap();
+        let actor = Parser::new(tokens).parse_actor().unwrap();
+        assert_eq!(actor.enums.len(), 1);
+        let shape = &actor.enums[0];
+        assert_eq!(shape.name, "Shape");
+        assert_eq!(
+            shape
+                .cases
+                .iter()
+                .map(|case| (case.name.as_str(), case.values.len()))
+                .collect::<Vec<_>>(),
+            [("circle", 1), ("rect", 2), ("empty", 0)]
+        );
+        assert_eq!(shape.case_index("empty"), Some(2));
+
+        let body = actor.methods[0].body.as_ref().unwrap();
+        let Statement::Match { value, arms } = &body.statements[0] else {
+            panic!("expected match, found {:?}", body.statements[0]);
+        };
+        assert_eq!(*value, Expression::Variable("shape".to_string()));
+        assert_eq!(
+            arms.iter()
+                .map(|(pattern, _)| pattern.to_string())
+                .collect::<Vec<_>>(),
+            [".circle(let r)", ".rect(let w, let h)", ".empty"]
+        );
+
+        let body = actor.methods[1].body.as_ref().unwrap();
+        assert!(matches!(
+            &body.statements[0],
+            Statement::Return(Expression::EnumCase { enum_name, case, args })
+                if enum_name == "Shape" && case == "rect" && args.len() == 2
+        ));
+
+        let body = actor.methods[2].body.as_ref().unwrap();
+        let Statement::Return(value) = &body.statements[0] else {
+            panic!("expected return, found {:?}", body.statements[0]);
+        };
+        assert_eq!(
+            value.to_string(),
+            "match shape { .rect(_, _) => 4, _ => 0 }"
+        );
+    }
+
+    #[test]
+    fn test_optionals() {
+        let parse = |source: &str| {
+            let tokens = crate::lexer::tokenize(source).unwrap();
+            Parser::new(tokens).parse_expression().unwrap()
+        };
+        // ?? は比較より強く算術より弱く、右結合
+        assert_eq!(parse("a ?? b + 1").to_string(), "a ?? (b + 1)");
+        assert!(matches!(
+            parse("a ?? b ?? 0"),
+            Expression::Coalesce { default, .. } if matches!(*default, Expression::Coalesce { .. })
+        ));
+        assert!(matches!(
+            parse("a ?? 0 == 1"),
+            Expression::BinaryOp { left, operator: Operator::Equal, .. }
+                if matches!(*left, Expression::Coalesce { .. })
+        ));
+        assert_eq!(parse("(a ?? 0) * 2").to_string(), "(a ?? 0) * 2");
+        assert!(matches!(
+            parse("a! + 1"),
+            Expression::BinaryOp { left, .. } if matches!(*left, Expression::Unwrap(_))
+        ));
+        assert_eq!(parse("nil"), Expression::Nil);
+
+        let tokens = crate::lexer::tokenize(
+            "actor Cache {
+    var hits: Int?
+
+    func lookup(key: Int?) -> Int {
+        if let k = key {
+            return k
+        }
+        return hits ?? 0
+    }
+
+    func clear() {
+        hits = nil
+    }
+}",
+        )
+        .unwrap();
+        let actor = Parser::new(tokens).parse_actor().unwrap();
+        let body = actor.methods[0].body.as_ref().unwrap();
+        // if let は .some と .none の match として表す
+        let Statement::Match { value, arms } = &body.statements[0] else {
+            panic!("expected match, found {:?}", body.statements[0]);
+        };
+        assert_eq!(*value, Expression::Variable("key".to_string()));
+        assert_eq!(
+            arms.iter()
+                .map(|(pattern, body)| (pattern.to_string(), body.statements.len()))
+                .collect::<Vec<_>>(),
+            [(".some(let k)".to_string(), 1), (".none".to_string(), 0)]
+        );
+
+        let body = actor.methods[1].body.as_ref().unwrap();
+        assert!(matches!(
+            &body.statements[0],
+            Statement::Assign {
+                value: Expression::Nil,
+                ..
+            }
+        ));
     }
 }
@@ -0,0 +1,215 @@
+//! Compiler plugin API.
+//! Plugins register custom passes that run at fixed hook points of the pipeline:
+//! after parsing, after semantic analysis, and right before code generation.
+
+mod naming;
+
+use crate::ast::Actor;
+use std::path::{Path, PathBuf};
+
+pub use naming::NamingConventionLint;
+
+/// Result type for plugin passes; an error aborts compilation of the file
+pub type PassResult = Result<(), String>;
+
+/// Pipeline stages at which plugin passes run
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Hook {
+    /// After parsing; passes may rewrite the AST before it is analyzed
+    PostParse,
+    /// After semantic analysis succeeded; the AST is read-only
+    PostTypecheck,
+    /// Right before code generation; passes may inject code
+    PreCodegen,
+}
+
+/// State shared with a pass while it runs
+pub struct PassContext {
+    hook: Hook,
+    file: PathBuf,
+    warnings: Vec<String>,
+}
+
+impl PassContext {
+    /// Creates a context for running passes on `file` at the given hook
+    pub fn new(hook: Hook, file: &Path) -> Self {
+        PassContext {
+            hook,
+            file: file.to_path_buf(),
+            warnings: Vec::new(),
+        }
+    }
+
+    /// Hook point currently running
+    pub fn hook(&self) -> Hook {
+        self.hook
+    }
+
+    /// Source file being compiled
+    pub fn file(&self) -> &Path {
+        &self.file
+    }
+
+    /// Reports a non-fatal diagnostic
+    pub fn warn(&mut self, message: impl Into<String>) {
+        self.warnings.push(message.into());
+    }
+
+    /// Consumes the context and returns the reported warnings
+    pub fn into_warnings(self) -> Vec<String> {
+        self.warnings
+    }
+}
+
+/// A custom compiler pass. All hooks default to doing nothing.
+pub trait CompilerPlugin {
+    /// Name used when reporting diagnostics from this plugin
+    fn name(&self) -> &str;
+
+    /// Runs after parsing
+    fn post_parse(&self, _actor: &mut Actor, _ctx: &mut PassContext) -> PassResult {
+        Ok(())
+    }
+
+    /// Runs after semantic analysis
+    fn post_typecheck(&self, _actor: &Actor, _ctx: &mut PassContext) -> PassResult {
+        Ok(())
+    }
+
+    /// Runs before code generation
+    fn pre_codegen(&self, _actor: &mut Actor, _ctx: &mut PassContext) -> PassResult {
+        Ok(())
+    }
+}
+
+/// Ordered collection of registered plugins
+#[derive(Default)]
+pub struct PluginRegistry {
+    plugins: Vec<Box<dyn CompilerPlugin>>,
+}
+
+impl PluginRegistry {
+    /// Creates an empty registry
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers a plugin; plugins run in registration order
+    pub fn register(&mut self, plugin: Box<dyn CompilerPlugin>) {
+        self.plugins.push(plugin);
+    }
+
+    /// Returns true if no plugins are registered
+    pub fn is_empty(&self) -> bool {
+        self.plugins.is_empty()
+    }
+
+    /// Runs every plugin's post-parse pass
+    pub fn run_post_parse(&self, actor: &mut Actor, ctx: &mut PassContext) -> PassResult {
+        for plugin in &self.plugins {
+            plugin
+                .post_parse(actor, ctx)
+                .map_err(|e| format!("Plugin '{}' failed: {}", plugin.name(), e))?;
+        }
+        Ok(())
+    }
+
+    /// Runs every plugin's post-typecheck pass
+    pub fn run_post_typecheck(&self, actor: &Actor, ctx: &mut PassContext) -> PassResult {
+        for plugin in &self.plugins {
+            plugin
+                .post_typecheck(actor, ctx)
+                .map_err(|e| format!("Plugin '{}' failed: {}", plugin.name(), e))?;
+        }
+        Ok(())
+    }
+
+    /// Runs every plugin's pre-codegen pass
+    pub fn run_pre_codegen(&self, actor: &mut Actor, ctx: &mut PassContext) -> PassResult {
+        for plugin in &self.plugins {
+            plugin
+                .pre_codegen(actor, ctx)
+                .map_err(|e| format!("Plugin '{}' failed: {}", plugin.name(), e))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::ActorType;
+
+    struct RenamePlugin;
+
+    impl CompilerPlugin for RenamePlugin {
+        fn name(&self) -> &str {
+            "rename"
+        }
+
+        fn post_parse(&self, actor: &mut Actor, ctx: &mut PassContext) -> PassResult {
+            actor.name = format!("{}Impl", actor.name);
+            ctx.warn("renamed actor");
+            Ok(())
+        }
+    }
+
+    struct RejectPlugin;
+
+    impl CompilerPlugin for RejectPlugin {
+        fn name(&self) -> &str {
+            "reject"
+        }
+
+        fn post_typecheck(&self, _actor: &Actor, _ctx: &mut PassContext) -> PassResult {
+            Err("not allowed".to_string())
+        }
+    }
+
+    fn test_actor() -> Actor {
+        Actor {
+            name: "Counter".to_string(),
+            actor_type: ActorType::Distributed,
+            methods: vec![],
+            fields: vec![],
+            init: None,
+            deinit: None,
+            layout: Default::default(),
+            externs: vec![],
+            imports: vec![],
+            topics: vec![],
+            pools: vec![],
+            structs: vec![],
+            enums: vec![],
+            constants: vec![],
+            protocol: None,
+            doc: None,
+        }
+    }
+
+    #[test]
+    fn test_post_parse_rewrites_ast() {
+        let mut registry = PluginRegistry::new();
+        registry.register(Box::new(RenamePlugin));
+
+        let mut actor = test_actor();
+        let mut ctx = PassContext::new(Hook::PostParse, Path::new("counter.replica"));
+        assert!(registry.run_post_parse(&mut actor, &mut ctx).is_ok());
+        assert_eq!(actor.name, "CounterImpl");
+        assert_eq!(ctx.into_warnings(), vec!["renamed actor".to_string()]);
+    }
+
+    #[test]
+    fn test_plugin_error_names_plugin() {
+        let mut registry = PluginRegistry::new();
+        registry.register(Box::new(RejectPlugin));
+
+        let actor = test_actor();
+        let mut ctx = PassContext::new(Hook::PostTypecheck, Path::new("counter.replica"));
+        let result = registry.run_post_typecheck(&actor, &mut ctx);
+        assert_eq!(
+            result,
+            Err("Plugin 'reject' failed: not allowed".to_string())
+        );
+    }
+}
@@ -0,0 +1,100 @@
+//! Example plugin: lints actor, method and field names against the usual
+//! Replica naming conventions.
+
+use super::{CompilerPlugin, PassContext, PassResult};
+use crate::ast::Actor;
+
+/// Warns about actors that are not UpperCamelCase and members that are not lowerCamelCase
+pub struct NamingConventionLint;
+
+impl NamingConventionLint {
+    fn is_upper_camel_case(name: &str) -> bool {
+        name.chars().next().is_some_and(|c| c.is_ascii_uppercase()) && !name.contains('_')
+    }
+
+    fn is_lower_camel_case(name: &str) -> bool {
+        name.chars().next().is_some_and(|c| c.is_ascii_lowercase()) && !name.contains('_')
+    }
+}
+
+impl CompilerPlugin for NamingConventionLint {
+    fn name(&self) -> &str {
+        "naming-convention"
+    }
+
+    fn post_parse(&self, actor: &mut Actor, ctx: &mut PassContext) -> PassResult {
+        if !Self::is_upper_camel_case(&actor.name) {
+            ctx.warn(format!(
+                "actor `{}` should have an UpperCamelCase name",
+                actor.name
+            ));
+        }
+
+        for field in &actor.fields {
+            if !Self::is_lower_camel_case(&field.name) {
+                ctx.warn(format!(
+                    "field `{}` should have a lowerCamelCase name",
+                    field.name
+                ));
+            }
+        }
+
+        for method in &actor.methods {
+            if !Self::is_lower_camel_case(&method.name) {
+                ctx.warn(format!(
+                    "method `{}` should have a lowerCamelCase name",
+                    method.name
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::{ActorType, Field, OwnershipType, Type};
+    use crate::plugin::Hook;
+    use std::path::Path;
+
+    #[test]
+    fn test_naming_warnings() {
+        let mut actor = Actor {
+            name: "bad_actor".to_string(),
+            actor_type: ActorType::Distributed,
+            methods: vec![],
+            fields: vec![Field {
+                name: "Value".to_string(),
+                field_type: Type::Int,
+                is_mutable: true,
+                ownership: OwnershipType::Owned,
+                offset: None,
+                doc: None,
+            }],
+            init: None,
+            deinit: None,
+            layout: Default::default(),
+            externs: vec![],
+            imports: vec![],
+            topics: vec![],
+            pools: vec![],
+            structs: vec![],
+            enums: vec![],
+            constants: vec![],
+            protocol: None,
+            doc: None,
+        };
+
+        let mut ctx = PassContext::new(Hook::PostParse, Path::new("test.replica"));
+        NamingConventionLint
+            .post_parse(&mut actor, &mut ctx)
+            .unwrap();
+
+        let warnings = ctx.into_warnings();
+        assert_eq!(warnings.len(), 2);
+        assert!(warnings[0].contains("bad_actor"));
+        assert!(warnings[1].contains("Value"));
+    }
+}
@@ -0,0 +1,989 @@
+//! Import of Protocol Buffers schemas.
+//! `replicac import-proto` reads a proto3 schema and generates Replica source in
+//! which messages are records and enum values are `Int` constants. Each message
+//! gets an `encode<Message>`/`decode<Message>` pair of `@proto` externs; instead
+//! of importing them, the code generator defines them on top of the runtime's
+//! wire-format codec, so the bytes are compatible with any protobuf library.
+
+use crate::ast::{ExternFunction, Type};
+use crate::lexer;
+
+/// How a field is represented on the wire, as written in `@proto` specs
+#[derive(Debug, Clone, PartialEq)]
+pub enum WireKind {
+    Int32,
+    Sint32,
+    Uint32,
+    Fixed32,
+    Sfixed32,
+    Bool,
+    Float,
+    Double,
+    String,
+    Bytes,
+    /// Embedded message, stored inline as a record
+    Message(Vec<WireField>),
+}
+
+/// One field of a message in a `@proto` spec, e.g. `3:repeated string`
+#[derive(Debug, Clone, PartialEq)]
+pub struct WireField {
+    pub number: u32,
+    pub repeated: bool,
+    pub kind: WireKind,
+}
+
+impl WireKind {
+    fn scalar(name: &str) -> Option<WireKind> {
+        Some(match name {
+            // 列挙型はワイヤ上では int32 と同じ
+            "int32" | "enum" => WireKind::Int32,
+            "sint32" => WireKind::Sint32,
+            "uint32" => WireKind::Uint32,
+            "fixed32" => WireKind::Fixed32,
+            "sfixed32" => WireKind::Sfixed32,
+            "bool" => WireKind::Bool,
+            "float" => WireKind::Float,
+            "double" => WireKind::Double,
+            "string" => WireKind::String,
+            "bytes" => WireKind::Bytes,
+            _ => return None,
+        })
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            WireKind::Int32 => "int32",
+            WireKind::Sint32 => "sint32",
+            WireKind::Uint32 => "uint32",
+            WireKind::Fixed32 => "fixed32",
+            WireKind::Sfixed32 => "sfixed32",
+            WireKind::Bool => "bool",
+            WireKind::Float => "float",
+            WireKind::Double => "double",
+            WireKind::String => "string",
+            WireKind::Bytes => "bytes",
+            WireKind::Message(_) => "message",
+        }
+    }
+
+    /// Replica spelling of a value of this kind
+    fn replica_type(&self, record: impl FnOnce() -> String) -> String {
+        match self {
+            WireKind::Bool => "Bool".to_string(),
+            WireKind::Float | WireKind::Double => "Float".to_string(),
+            WireKind::String => "String".to_string(),
+            WireKind::Bytes => "[Int]".to_string(),
+            WireKind::Message(_) => record(),
+            _ => "Int".to_string(),
+        }
+    }
+}
+
+/// Parses a `@proto` spec such as `1:string, 2:repeated int32, 3:{1:bool}`
+pub fn parse_spec(spec: &str) -> Result<Vec<WireField>, String> {
+    let mut rest = spec;
+    let fields = parse_spec_fields(&mut rest)?;
+    if !rest.trim().is_empty() {
+        return Err(format!("unexpected `{}` in @proto spec", rest.trim()));
+    }
+    Ok(fields)
+}
+
+fn parse_spec_fields(rest: &mut &str) -> Result<Vec<WireField>, String> {
+    let mut fields = Vec::new();
+    *rest = rest.trim_start();
+    if rest.is_empty() || rest.starts_with('}') {
+        return Ok(fields);
+    }
+
+    loop {
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let number = rest[..digits]
+            .parse()
+            .map_err(|_| format!("expected a field number in @proto spec at `{}`", rest))?;
+        *rest = rest[digits..]
+            .strip_prefix(':')
+            .ok_or_else(|| format!("expected `:` after field number {}", number))?
+            .trim_start();
+        let repeated = match rest.strip_prefix("repeated ") {
+            Some(after) => {
+                *rest = after.trim_start();
+                true
+            }
+            None => false,
+        };
+
+        let kind = if let Some(after) = rest.strip_prefix('{') {
+            *rest = after;
+            let fields = parse_spec_fields(rest)?;
+            *rest = rest
+                .strip_prefix('}')
+                .ok_or_else(|| format!("unclosed `{{` in field {} of @proto spec", number))?;
+            WireKind::Message(fields)
+        } else {
+            let end = rest
+                .find(|c: char| !c.is_ascii_alphanumeric())
+                .unwrap_or(rest.len());
+            let kind = WireKind::scalar(&rest[..end])
+                .ok_or_else(|| format!("unknown wire type `{}` in @proto spec", &rest[..end]))?;
+            *rest = &rest[end..];
+            kind
+        };
+        fields.push(WireField {
+            number,
+            repeated,
+            kind,
+        });
+
+        *rest = rest.trim_start();
+        match rest.strip_prefix(',') {
+            Some(after) => *rest = after.trim_start(),
+            None => return Ok(fields),
+        }
+    }
+}
+
+/// Formats fields as a `@proto` spec
+pub fn format_spec(fields: &[WireField]) -> String {
+    fields
+        .iter()
+        .map(|field| {
+            let kind = match &field.kind {
+                WireKind::Message(fields) => format!("{{{}}}", format_spec(fields)),
+                kind => kind.name().to_string(),
+            };
+            let repeated = if field.repeated { "repeated " } else { "" };
+            format!("{}:{}{}", field.number, repeated, kind)
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Direction of a `@proto` codec
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// `(value: <record>) -> [Int]`
+    Encode,
+    /// `(bytes: [Int]) -> <record>?`, returning nil for malformed input
+    Decode,
+}
+
+/// Codec an `@proto` extern asks the code generator to define
+#[derive(Debug)]
+pub struct Codec<'a> {
+    pub direction: Direction,
+    pub fields: Vec<WireField>,
+    /// Record type of the message
+    pub record: &'a Type,
+}
+
+/// Returns the codec `function` declares, or `None` for plain host imports.
+/// The record's fields must match the spec in order.
+pub fn codec(function: &ExternFunction) -> Result<Option<Codec<'_>>, String> {
+    let Some(spec) = &function.proto else {
+        return Ok(None);
+    };
+    let fields = parse_spec(spec)?;
+
+    let is_bytes = |ty: &Type| matches!(ty, Type::Array(inner) if matches!(**inner, Type::Int));
+    let (direction, record) = match (function.params.as_slice(), &function.return_type) {
+        ([param], Some(result)) if is_bytes(result) => (Direction::Encode, &param.param_type),
+        ([param], Some(Type::Optional(record))) if is_bytes(&param.param_type) => {
+            (Direction::Decode, record.as_ref())
+        }
+        _ => return Err(
+            "a @proto codec must be `(value: <record>) -> [Int]` or `(bytes: [Int]) -> <record>?`"
+                .to_string(),
+        ),
+    };
+
+    if !message_matches(record, &fields) {
+        return Err(format!(
+            "the record type does not match the @proto fields `{}`",
+            spec
+        ));
+    }
+    Ok(Some(Codec {
+        direction,
+        fields,
+        record,
+    }))
+}
+
+/// Field types of a record; `()` is the record of an empty message
+pub fn record_fields(ty: &Type) -> Option<Vec<&Type>> {
+    match ty {
+        Type::Record(fields) => Some(fields.iter().map(|(_, field)| field).collect()),
+        Type::Tuple(elements) if elements.is_empty() => Some(Vec::new()),
+        _ => None,
+    }
+}
+
+fn message_matches(ty: &Type, fields: &[WireField]) -> bool {
+    match record_fields(ty) {
+        Some(types) => {
+            types.len() == fields.len()
+                && types.iter().zip(fields).all(|(ty, field)| match ty {
+                    Type::Array(element) if field.repeated => kind_matches(element, &field.kind),
+                    _ => !field.repeated && kind_matches(ty, &field.kind),
+                })
+        }
+        None => false,
+    }
+}
+
+fn kind_matches(ty: &Type, kind: &WireKind) -> bool {
+    match (kind, ty) {
+        (WireKind::Bool, Type::Bool) => true,
+        (WireKind::Float | WireKind::Double, Type::Float) => true,
+        (WireKind::String, Type::String) => true,
+        (WireKind::Bytes, Type::Array(element)) => matches!(**element, Type::Int),
+        (WireKind::Message(fields), _) => message_matches(ty, fields),
+        (
+            WireKind::Int32
+            | WireKind::Sint32
+            | WireKind::Uint32
+            | WireKind::Fixed32
+            | WireKind::Sfixed32,
+            Type::Int,
+        ) => true,
+        _ => false,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Number(i64),
+    Str(String),
+    Symbol(char),
+}
+
+/// Splits a schema into tokens paired with their line numbers
+fn tokenize(source: &str) -> Result<Vec<(Token, usize)>, String> {
+    let mut tokens = Vec::new();
+    let mut line = 1;
+    let mut chars = source.char_indices().peekable();
+
+    while let Some((start, c)) = chars.next() {
+        match c {
+            '\n' => line += 1,
+            c if c.is_whitespace() => {}
+            '/' if chars.peek().map(|&(_, c)| c) == Some('/') => {
+                while chars.next_if(|&(_, c)| c != '\n').is_some() {}
+            }
+            '/' if chars.peek().map(|&(_, c)| c) == Some('*') => {
+                chars.next();
+                let mut previous = ' ';
+                loop {
+                    match chars.next() {
+                        Some((_, '/')) if previous == '*' => break,
+                        Some((_, c)) => {
+                            line += (c == '\n') as usize;
+                            previous = c;
+                        }
+                        None => return Err(format!("line {}: unterminated comment", line)),
+                    }
+                }
+            }
+            '"' | '\'' => {
+                let mut value = String::new();
+                loop {
+                    match chars.next() {
+                        Some((_, quote)) if quote == c => break,
+                        Some((_, '\\')) => value.extend(chars.next().map(|(_, c)| c)),
+                        Some((_, '\n')) | None => {
+                            return Err(format!("line {}: unterminated string", line))
+                        }
+                        Some((_, c)) => value.push(c),
+                    }
+                }
+                tokens.push((Token::Str(value), line));
+            }
+            c if c.is_ascii_digit() || c == '-' => {
+                let mut end = start + c.len_utf8();
+                while let Some((i, c)) =
+                    chars.next_if(|&(_, c)| c.is_ascii_alphanumeric() || c == '.')
+                {
+                    end = i + c.len_utf8();
+                }
+                let text = &source[start..end];
+                let (negative, digits) = match text.strip_prefix('-') {
+                    Some(digits) => (true, digits),
+                    None => (false, text),
+                };
+                let value = match digits
+                    .strip_prefix("0x")
+                    .or_else(|| digits.strip_prefix("0X"))
+                {
+                    Some(hex) => i64::from_str_radix(hex, 16),
+                    None if digits.len() > 1 && digits.starts_with('0') => {
+                        i64::from_str_radix(&digits[1..], 8)
+                    }
+                    None => digits.parse(),
+                };
+                match value {
+                    Ok(value) => {
+                        tokens.push((Token::Number(if negative { -value } else { value }), line))
+                    }
+                    // 小数はオプションの値にしか現れないので読み飛ばす
+                    Err(_) => tokens.push((Token::Number(0), line)),
+                }
+            }
+            c if c.is_ascii_alphabetic() || c == '_' || c == '.' => {
+                let mut end = start + 1;
+                while let Some((i, _)) =
+                    chars.next_if(|&(_, c)| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+                {
+                    end = i + 1;
+                }
+                tokens.push((Token::Ident(source[start..end].to_string()), line));
+            }
+            c => tokens.push((Token::Symbol(c), line)),
+        }
+    }
+    Ok(tokens)
+}
+
+#[derive(Debug)]
+struct Message {
+    /// Names of the enclosing messages and of the message itself
+    path: Vec<String>,
+    fields: Vec<Field>,
+}
+
+#[derive(Debug)]
+struct Field {
+    name: String,
+    number: u32,
+    repeated: bool,
+    type_name: String,
+    line: usize,
+}
+
+#[derive(Debug)]
+struct Enum {
+    path: Vec<String>,
+    values: Vec<(String, i64)>,
+}
+
+/// Messages and enums of a schema, nested declarations included
+#[derive(Debug, Default)]
+struct Schema {
+    package: Vec<String>,
+    messages: Vec<Message>,
+    enums: Vec<Enum>,
+}
+
+struct SchemaParser {
+    tokens: Vec<(Token, usize)>,
+    current: usize,
+    schema: Schema,
+}
+
+impl SchemaParser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.current).map(|(token, _)| token)
+    }
+
+    fn line(&self) -> usize {
+        self.tokens
+            .get(self.current.min(self.tokens.len().saturating_sub(1)))
+            .map_or(1, |&(_, line)| line)
+    }
+
+    fn error<T>(&self, message: impl std::fmt::Display) -> Result<T, String> {
+        Err(format!("line {}: {}", self.line(), message))
+    }
+
+    fn advance(&mut self) -> Option<Token> {
+        let token = self.peek().cloned();
+        self.current += 1;
+        token
+    }
+
+    fn expect_symbol(&mut self, symbol: char) -> Result<(), String> {
+        match self.peek() {
+            Some(Token::Symbol(c)) if *c == symbol => {
+                self.current += 1;
+                Ok(())
+            }
+            _ => self.error(format!("expected `{}`", symbol)),
+        }
+    }
+
+    fn ident(&mut self, what: &str) -> Result<String, String> {
+        match self.peek() {
+            Some(Token::Ident(name)) => {
+                let name = name.clone();
+                self.current += 1;
+                Ok(name)
+            }
+            _ => self.error(format!("expected {}", what)),
+        }
+    }
+
+    fn number(&mut self, what: &str) -> Result<i64, String> {
+        match self.peek() {
+            Some(Token::Number(value)) => {
+                let value = *value;
+                self.current += 1;
+                Ok(value)
+            }
+            _ => self.error(format!("expected {}", what)),
+        }
+    }
+
+    /// Skips to the end of a statement such as `option java_package = "x";`,
+    /// including any block it opens
+    fn skip_statement(&mut self) -> Result<(), String> {
+        let mut depth = 0;
+        loop {
+            match self.advance() {
+                Some(Token::Symbol('{')) => depth += 1,
+                Some(Token::Symbol('}')) if depth > 1 => depth -= 1,
+                Some(Token::Symbol('}')) if depth == 1 => return Ok(()),
+                Some(Token::Symbol(';')) if depth == 0 => return Ok(()),
+                Some(_) => {}
+                None => return self.error("unexpected end of file"),
+            }
+        }
+    }
+
+    /// Skips `[deprecated = true]`-style field options
+    fn skip_field_options(&mut self) -> Result<(), String> {
+        if self.peek() == Some(&Token::Symbol('[')) {
+            while self.advance() != Some(Token::Symbol(']')) {
+                if self.peek().is_none() {
+                    return self.error("unclosed field options");
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn parse(mut self) -> Result<Schema, String> {
+        let mut proto3 = false;
+        while let Some(token) = self.advance() {
+            match token {
+                Token::Ident(keyword) if keyword == "syntax" => {
+                    self.expect_symbol('=')?;
+                    match self.advance() {
+                        Some(Token::Str(syntax)) if syntax == "proto3" => proto3 = true,
+                        Some(Token::Str(syntax)) => {
+                            return self.error(format!(
+                                "only proto3 schemas are supported, found syntax \"{}\"",
+                                syntax
+                            ))
+                        }
+                        _ => return self.error("expected a syntax string"),
+                    }
+                    self.expect_symbol(';')?;
+                }
+                Token::Ident(keyword) if keyword == "package" => {
+                    let package = self.ident("a package name")?;
+                    self.schema.package = package.split('.').map(str::to_string).collect();
+                    self.expect_symbol(';')?;
+                }
+                Token::Ident(keyword) if keyword == "import" => {
+                    return self.error("imports are not supported; merge the schemas into one file")
+                }
+                Token::Ident(keyword) if keyword == "edition" => {
+                    return self.error("editions are not supported; use syntax = \"proto3\"")
+                }
+                Token::Ident(keyword) if keyword == "message" => self.parse_message(&[])?,
+                Token::Ident(keyword) if keyword == "enum" => self.parse_enum(&[])?,
+                // サービスは取り込まず、メッセージだけを使う
+                Token::Ident(keyword) if keyword == "option" || keyword == "service" => {
+                    self.skip_statement()?
+                }
+                Token::Symbol(';') => {}
+                _ => {
+                    self.current -= 1;
+                    return self.error("expected a message, enum or option");
+                }
+            }
+        }
+
+        if !proto3 {
+            return Err(
+                "only proto3 schemas are supported; add `syntax = \"proto3\";`".to_string(),
+            );
+        }
+        Ok(self.schema)
+    }
+
+    fn parse_message(&mut self, scope: &[String]) -> Result<(), String> {
+        let mut path = scope.to_vec();
+        path.push(self.ident("a message name")?);
+        self.expect_symbol('{')?;
+
+        let mut fields = Vec::new();
+        loop {
+            match self.peek().cloned() {
+                Some(Token::Symbol('}')) => {
+                    self.current += 1;
+                    break;
+                }
+                Some(Token::Symbol(';')) => self.current += 1,
+                Some(Token::Ident(keyword)) => match keyword.as_str() {
+                    "message" => {
+                        self.current += 1;
+                        self.parse_message(&path)?;
+                    }
+                    "enum" => {
+                        self.current += 1;
+                        self.parse_enum(&path)?;
+                    }
+                    "option" | "reserved" | "extensions" => self.skip_statement()?,
+                    "oneof" => return self.error("oneof is not supported"),
+                    "optional" => {
+                        return self.error(
+                            "optional fields are not supported; without `optional` the field \
+                             has the same encoding but no presence tracking",
+                        )
+                    }
+                    "required" | "group" | "extend" => {
+                        return self.error(format!("`{}` is not part of proto3", keyword))
+                    }
+                    "map"
+                        if self.tokens.get(self.current + 1).map(|(t, _)| t)
+                            == Some(&Token::Symbol('<')) =>
+                    {
+                        return self.error("map fields are not supported")
+                    }
+                    _ => fields.push(self.parse_field()?),
+                },
+                Some(_) => return self.error("expected a field declaration"),
+                None => return self.error("unexpected end of file in message"),
+            }
+        }
+
+        self.schema.messages.push(Message { path, fields });
+        Ok(())
+    }
+
+    fn parse_field(&mut self) -> Result<Field, String> {
+        let repeated = self.peek() == Some(&Token::Ident("repeated".to_string()));
+        if repeated {
+            self.current += 1;
+        }
+        let line = self.line();
+        let type_name = self.ident("a field type")?;
+        let name = self.ident("a field name")?;
+        self.expect_symbol('=')?;
+        let number = self.number("a field number")?;
+        let number = match u32::try_from(number) {
+            Ok(number @ 1..=0x1fff_ffff) => number,
+            _ => return self.error(format!("invalid field number {}", number)),
+        };
+        self.skip_field_options()?;
+        self.expect_symbol(';')?;
+
+        Ok(Field {
+            name,
+            number,
+            repeated,
+            type_name,
+            line,
+        })
+    }
+
+    fn parse_enum(&mut self, scope: &[String]) -> Result<(), String> {
+        let mut path = scope.to_vec();
+        path.push(self.ident("an enum name")?);
+        self.expect_symbol('{')?;
+
+        let mut values = Vec::new();
+        loop {
+            match self.peek().cloned() {
+                Some(Token::Symbol('}')) => {
+                    self.current += 1;
+                    break;
+                }
+                Some(Token::Symbol(';')) => self.current += 1,
+                Some(Token::Ident(keyword)) if keyword == "option" || keyword == "reserved" => {
+                    self.skip_statement()?
+                }
+                Some(Token::Ident(name)) => {
+                    self.current += 1;
+                    self.expect_symbol('=')?;
+                    let value = self.number("an enum value")?;
+                    if i32::try_from(value).is_err() {
+                        return self.error(format!("enum value {} does not fit in int32", value));
+                    }
+                    self.skip_field_options()?;
+                    self.expect_symbol(';')?;
+                    values.push((name, value));
+                }
+                _ => return self.error("expected an enum value"),
+            }
+        }
+
+        self.schema.enums.push(Enum { path, values });
+        Ok(())
+    }
+}
+
+/// What a field's type name refers to
+enum Resolved<'a> {
+    Kind(WireKind),
+    Message(&'a Message),
+}
+
+impl Schema {
+    /// Resolves `type_name` the way protoc does: relative names are looked up
+    /// in the enclosing scopes from the innermost outwards
+    fn resolve(&self, scope: &[String], type_name: &str) -> Option<Resolved<'_>> {
+        if let Some(kind) = WireKind::scalar(type_name).filter(|_| type_name != "enum") {
+            return Some(Resolved::Kind(kind));
+        }
+
+        let (absolute, name) = match type_name.strip_prefix('.') {
+            Some(name) => (true, name),
+            None => (false, type_name),
+        };
+        let parts: Vec<&str> = name.split('.').collect();
+        let find = |candidate: &[&str]| {
+            let matches = |path: &[String]| {
+                path.len() == candidate.len() && path.iter().zip(candidate).all(|(a, b)| a == b)
+            };
+            if let Some(message) = self.messages.iter().find(|m| matches(&m.path)) {
+                return Some(Resolved::Message(message));
+            }
+            self.enums
+                .iter()
+                .find(|e| matches(&e.path))
+                .map(|_| Resolved::Kind(WireKind::Int32))
+        };
+
+        // パッケージ名は宣言のパスに含めていないので取り除く
+        let package: Vec<&str> = self.package.iter().map(String::as_str).collect();
+        let unqualified = parts.strip_prefix(package.as_slice()).unwrap_or(&parts);
+        if absolute {
+            return find(unqualified);
+        }
+        (0..=scope.len())
+            .rev()
+            .find_map(|depth| {
+                let mut candidate: Vec<&str> = scope[..depth].iter().map(String::as_str).collect();
+                candidate.extend(&parts);
+                find(&candidate)
+            })
+            .or_else(|| find(unqualified))
+    }
+
+    /// Wire fields and Replica record type of `message`
+    fn expand(
+        &self,
+        message: &Message,
+        stack: &mut Vec<String>,
+    ) -> Result<(Vec<WireField>, String), String> {
+        let name = message.path.concat();
+        if stack.contains(&name) {
+            return Err(format!(
+                "message {} contains itself through {}; recursive messages are not supported because records are stored inline",
+                name,
+                stack.join(" -> ")
+            ));
+        }
+        stack.push(name);
+
+        let mut fields = Vec::new();
+        let mut labels = Vec::new();
+        for field in &message.fields {
+            let (kind, record) = match self.resolve(&message.path, &field.type_name) {
+                Some(Resolved::Kind(kind)) => (kind, None),
+                Some(Resolved::Message(nested)) => {
+                    let (fields, record) = self.expand(nested, stack)?;
+                    (WireKind::Message(fields), Some(record))
+                }
+                None if is_64_bit(&field.type_name) => {
+                    return Err(format!(
+                        "line {}: field `{}` has type {}; 64-bit integers are not supported because Replica's Int is 32 bits",
+                        field.line, field.name, field.type_name
+                    ))
+                }
+                None => {
+                    return Err(format!(
+                        "line {}: unknown type `{}` of field `{}`",
+                        field.line, field.type_name, field.name
+                    ))
+                }
+            };
+
+            let value_type = kind.replica_type(|| record.unwrap_or_default());
+            let field_type = if field.repeated {
+                format!("[{}]", value_type)
+            } else {
+                value_type
+            };
+            labels.push(format!(
+                "{}: {}",
+                identifier(&lower_camel(&field.name)),
+                field_type
+            ));
+            fields.push(WireField {
+                number: field.number,
+                repeated: field.repeated,
+                kind,
+            });
+        }
+
+        stack.pop();
+        Ok((fields, format!("({})", labels.join(", "))))
+    }
+}
+
+fn is_64_bit(type_name: &str) -> bool {
+    matches!(
+        type_name,
+        "int64" | "uint64" | "sint64" | "fixed64" | "sfixed64"
+    )
+}
+
+/// `user_id` -> `userId`
+fn lower_camel(name: &str) -> String {
+    let pascal = pascal(name);
+    let mut chars = pascal.chars();
+    match chars.next() {
+        Some(first) if name.contains('_') || name.starts_with(char::is_uppercase) => {
+            first.to_ascii_lowercase().to_string() + chars.as_str()
+        }
+        _ => name.to_string(),
+    }
+}
+
+/// `STATUS_ACTIVE` or `status_active` -> `StatusActive`; camel case is kept
+fn pascal(name: &str) -> String {
+    let shouting = !name.chars().any(|c| c.is_ascii_lowercase());
+    name.split('_')
+        .filter(|part| !part.is_empty())
+        .map(|part| {
+            let part = if shouting {
+                part.to_ascii_lowercase()
+            } else {
+                part.to_string()
+            };
+            let mut chars = part.chars();
+            chars
+                .next()
+                .map(|first| first.to_ascii_uppercase().to_string() + chars.as_str())
+                .unwrap_or_default()
+        })
+        .collect()
+}
+
+/// `PersonKind` -> `PERSON_KIND_`, the prefix protobuf style puts on enum values
+fn screaming_snake(name: &str) -> String {
+    let mut prefix = String::new();
+    for (index, c) in name.chars().enumerate() {
+        if index > 0 && c.is_ascii_uppercase() {
+            prefix.push('_');
+        }
+        prefix.push(c.to_ascii_uppercase());
+    }
+    prefix + "_"
+}
+
+/// Keeps generated identifiers from being read as keywords
+fn identifier(name: &str) -> String {
+    if lexer::keyword(name).is_some() {
+        format!("_{}", name)
+    } else {
+        name.to_string()
+    }
+}
+
+/// Generates the Replica source for a proto3 schema: a single actor named after
+/// `stem` holding one constant function per enum value and the codecs of every
+/// message
+pub fn generate_source(schema: &str, stem: &str) -> Result<String, String> {
+    let tokens = tokenize(schema)?;
+    let schema = SchemaParser {
+        tokens,
+        current: 0,
+        schema: Schema::default(),
+    }
+    .parse()?;
+
+    let stem = stem.replace(|c: char| !c.is_ascii_alphanumeric(), "_");
+    let mut source = format!("single actor {}Proto {{\n", pascal(&stem));
+    for enumeration in &schema.enums {
+        let name = enumeration.path.concat();
+        // 値の名前に付いた列挙型名の接頭辞 (STATUS_ など) は省く
+        let prefixes = [
+            screaming_snake(&name),
+            screaming_snake(&enumeration.path[enumeration.path.len() - 1]),
+        ];
+        for (value_name, value) in &enumeration.values {
+            let short = prefixes
+                .iter()
+                .find_map(|prefix| value_name.strip_prefix(prefix.as_str()))
+                .filter(|short| !short.is_empty())
+                .unwrap_or(value_name);
+            let value = if *value < 0 {
+                format!("0 - {}", -value)
+            } else {
+                value.to_string()
+            };
+            source.push_str(&format!(
+                "    pure func {}() -> Int {{\n        return {}\n    }}\n",
+                identifier(&(lower_camel(&name) + &pascal(short))),
+                value
+            ));
+        }
+    }
+
+    for message in &schema.messages {
+        let name = message.path.concat();
+        let (fields, record) = schema.expand(message, &mut Vec::new())?;
+        let spec = format_spec(&fields);
+        source.push_str(&format!(
+            "    @proto(\"{spec}\")\n    extern func encode{name}(value: {record}) -> [Int]\n"
+        ));
+        source.push_str(&format!(
+            "    @proto(\"{spec}\")\n    extern func decode{name}(bytes: [Int]) -> {record}?\n"
+        ));
+    }
+    source.push_str("}\n");
+    Ok(source)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::{OwnershipType, Parameter};
+
+    const SCHEMA: &str = r#"
+        syntax = "proto3";
+        package example.people;
+
+        /* 住所録 */
+        message Person {
+            string name = 1;
+            sint32 id = 2 [deprecated = true];
+            repeated string emails = 3;
+            Status status = 4;
+            Address home = 5;
+            repeated Address previous_homes = 6;
+
+            message Address {
+                string street = 1;
+                bytes postcode = 2;
+            }
+            reserved 7 to 9;
+        }
+
+        enum Status {
+            STATUS_UNKNOWN = 0;
+            STATUS_ACTIVE = 1;
+            RETIRED = -2; // 接頭辞なし
+        }
+    "#;
+
+    #[test]
+    fn test_generate_source() {
+        let source = generate_source(SCHEMA, "people").unwrap();
+        assert!(source.starts_with("single actor PeopleProto {\n"));
+        assert!(source.contains("pure func statusActive() -> Int {\n        return 1\n"));
+        assert!(source.contains("pure func statusRetired() -> Int {\n        return 0 - 2\n"));
+
+        let address = "(street: String, postcode: [Int])";
+        let person = format!(
+            "(name: String, id: Int, emails: [String], status: Int, home: {0}, previousHomes: [{0}])",
+            address
+        );
+        assert!(source.contains(&format!(
+            "    @proto(\"1:string, 2:sint32, 3:repeated string, 4:int32, 5:{{1:string, 2:bytes}}, 6:repeated {{1:string, 2:bytes}}\")\n    extern func encodePerson(value: {}) -> [Int]\n",
+            person
+        )));
+        assert!(source.contains(&format!(
+            "extern func decodePerson(bytes: [Int]) -> {}?\n",
+            person
+        )));
+        assert!(source.contains(&format!(
+            "extern func encodePersonAddress(value: {}) -> [Int]",
+            address
+        )));
+    }
+
+    #[test]
+    fn test_unsupported_schemas() {
+        let error = |schema: &str| generate_source(schema, "x").unwrap_err();
+
+        assert!(error("message A { string a = 1; }").contains("add `syntax = \"proto3\";`"));
+        assert!(error("syntax = \"proto2\";").contains("found syntax \"proto2\""));
+        let error64 = error("syntax = \"proto3\";\nmessage A {\n  int64 a = 1;\n}");
+        assert!(
+            error64.starts_with("line 3:") && error64.contains("64-bit"),
+            "{}",
+            error64
+        );
+        assert!(
+            error("syntax = \"proto3\"; message A { map<string, int32> m = 1; }")
+                .contains("map fields")
+        );
+        assert!(
+            error("syntax = \"proto3\"; message A { B b = 1; } message B { A a = 1; }")
+                .contains("recursive messages")
+        );
+        assert!(error("syntax = \"proto3\"; message A { Missing m = 1; }")
+            .contains("unknown type `Missing`"));
+    }
+
+    #[test]
+    fn test_spec_round_trip() {
+        let spec = "1:string, 2:repeated sint32, 3:{1:bool, 2:repeated {}}, 4:bytes";
+        let fields = parse_spec(spec).unwrap();
+        assert_eq!(fields.len(), 4);
+        assert!(fields[1].repeated);
+        assert_eq!(format_spec(&fields), spec);
+        assert!(parse_spec("1:int64").is_err());
+        assert!(parse_spec("1:{2:bool").is_err());
+    }
+
+    #[test]
+    fn test_codec_signature() {
+        let param = |name: &str, param_type| Parameter {
+            name: name.to_string(),
+            param_type,
+            ownership: OwnershipType::Owned,
+        };
+        let bytes = || Type::Array(Box::new(Type::Int));
+        let record = Type::Record(vec![
+            ("name".to_string(), Type::String),
+            ("scores".to_string(), Type::Array(Box::new(Type::Int))),
+        ]);
+        let function = |params, return_type| ExternFunction {
+            name: "codec".to_string(),
+            params,
+            return_type: Some(return_type),
+            proto: Some("1:string, 2:repeated int32".to_string()),
+            requires: vec![],
+        };
+
+        let encode = function(vec![param("value", record.clone())], bytes());
+        assert_eq!(
+            codec(&encode).unwrap().unwrap().direction,
+            Direction::Encode
+        );
+        let decode = function(
+            vec![param("bytes", bytes())],
+            Type::Optional(Box::new(record.clone())),
+        );
+        assert_eq!(
+            codec(&decode).unwrap().unwrap().direction,
+            Direction::Decode
+        );
+
+        // フィールドの型が食い違う
+        let mismatched = function(vec![param("value", Type::Record(vec![]))], bytes());
+        assert!(codec(&mismatched).unwrap_err().contains("does not match"));
+        let not_optional = function(vec![param("bytes", bytes())], record);
+        assert!(codec(&not_optional).unwrap_err().contains("must be"));
+    }
+}
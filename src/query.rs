@@ -0,0 +1,1103 @@
+//! Demand-driven compilation queries.
+//! Source files are inputs to a `Database`; every derived artifact (tokens, AST,
+//! diagnostics, WASM) is computed on request, memoized, and recomputed only after
+//! the file it depends on has changed.
+
+use crate::ast::Actor;
+use crate::bindgen;
+use crate::codegen::{self, CodeGenOptions, EmitKind, WasmFeature};
+use crate::consteval;
+use crate::diagnostics::Report;
+use crate::ice::{self, Phase};
+use crate::incremental::Document;
+use crate::layout;
+use crate::lexer::{self, Token};
+use crate::package;
+use crate::parser::ParseLimits;
+use crate::plugin::{CompilerPlugin, Hook, PassContext, PluginRegistry};
+use crate::semantic::{SemanticAnalyzer, SymbolTable};
+use crate::stack;
+use inkwell::context::Context;
+use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Identifies a source file registered in a `Database`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FileId(u32);
+
+/// Result type shared by all queries; errors are pre-rendered messages
+pub type QueryResult<T> = Result<Arc<T>, String>;
+
+struct SourceFile {
+    path: PathBuf,
+    text: Arc<str>,
+    /// Package namespace the file's actor is compiled under, for dependencies
+    namespace: Option<String>,
+    /// Revision at which the text or namespace last changed
+    changed_at: u64,
+}
+
+/// A memoized query value together with the input revision it was computed from
+struct Memo<T> {
+    verified_at: u64,
+    value: T,
+}
+
+/// Memoization table for a single query, keyed by file (plus any extra query arguments)
+struct QueryCache<T, K = FileId> {
+    entries: HashMap<K, Memo<T>>,
+}
+
+impl<T: Clone, K: Eq + Hash> QueryCache<T, K> {
+    fn new() -> Self {
+        QueryCache {
+            entries: HashMap::new(),
+        }
+    }
+
+    fn get(&self, key: K, changed_at: u64) -> Option<T> {
+        self.entries
+            .get(&key)
+            .filter(|memo| memo.verified_at == changed_at)
+            .map(|memo| memo.value.clone())
+    }
+
+    fn insert(&mut self, key: K, changed_at: u64, value: T) -> T {
+        self.entries.insert(
+            key,
+            Memo {
+                verified_at: changed_at,
+                value: value.clone(),
+            },
+        );
+        value
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
+
+/// Query database holding compiler inputs and memoized results
+pub struct Database {
+    revision: u64,
+    files: Vec<SourceFile>,
+    file_ids: HashMap<PathBuf, FileId>,
+    options: CodeGenOptions,
+    parse_limits: ParseLimits,
+    plugins: PluginRegistry,
+    /// Tokens and parsed methods of each file, kept across edits so that an
+    /// edit re-lexes and re-parses only what it touched
+    documents: HashMap<FileId, Document>,
+    tokens: QueryCache<QueryResult<Vec<(Token, usize)>>>,
+    parsed: QueryCache<QueryResult<Actor>>,
+    typed: QueryCache<QueryResult<Actor>>,
+    artifacts: QueryCache<QueryResult<Vec<u8>>, (FileId, EmitKind)>,
+    linked: QueryCache<QueryResult<Vec<u8>>, (Vec<FileId>, EmitKind)>,
+    warnings: QueryCache<Arc<Vec<String>>, (FileId, Hook)>,
+    errors: QueryCache<Arc<Vec<Report>>>,
+}
+
+impl Database {
+    /// Creates an empty database using default code generation options
+    pub fn new() -> Self {
+        Self::with_options(CodeGenOptions::default())
+    }
+
+    /// Creates an empty database using the given code generation options
+    pub fn with_options(options: CodeGenOptions) -> Self {
+        ice::set_options(&options);
+        Database {
+            revision: 0,
+            files: Vec::new(),
+            file_ids: HashMap::new(),
+            options,
+            parse_limits: ParseLimits::default(),
+            plugins: PluginRegistry::new(),
+            documents: HashMap::new(),
+            tokens: QueryCache::new(),
+            parsed: QueryCache::new(),
+            typed: QueryCache::new(),
+            artifacts: QueryCache::new(),
+            linked: QueryCache::new(),
+            warnings: QueryCache::new(),
+            errors: QueryCache::new(),
+        }
+    }
+
+    /// Current input revision; bumped whenever any input changes
+    pub fn revision(&self) -> u64 {
+        self.revision
+    }
+
+    /// Sets the text of a source file, registering it if needed.
+    /// Setting identical text keeps all memoized results valid.
+    pub fn set_source_text(&mut self, path: impl AsRef<Path>, text: impl Into<Arc<str>>) -> FileId {
+        let path = path.as_ref();
+        let text = text.into();
+
+        if let Some(&file) = self.file_ids.get(path) {
+            let source = &mut self.files[file.0 as usize];
+            if source.text != text {
+                self.revision += 1;
+                source.text = text;
+                source.changed_at = self.revision;
+            }
+            return file;
+        }
+
+        self.revision += 1;
+        let file = FileId(self.files.len() as u32);
+        self.files.push(SourceFile {
+            path: path.to_path_buf(),
+            text,
+            namespace: None,
+            changed_at: self.revision,
+        });
+        self.file_ids.insert(path.to_path_buf(), file);
+        file
+    }
+
+    /// Replaces the bytes at `range` in the text of a registered file with
+    /// `replacement`. Unlike [`Database::set_source_text`], the next parse
+    /// re-lexes only the tokens around the edit and reuses the methods it
+    /// did not touch.
+    pub fn edit_source_text(&mut self, file: FileId, range: Range<usize>, replacement: &str) {
+        let source = &mut self.files[file.0 as usize];
+        let text = match self.documents.get_mut(&file) {
+            Some(document) if Arc::ptr_eq(document.text(), &source.text) => {
+                document.edit(range, replacement);
+                document.text().clone()
+            }
+            _ => [
+                &source.text[..range.start],
+                replacement,
+                &source.text[range.end..],
+            ]
+            .concat()
+            .into(),
+        };
+        self.revision += 1;
+        source.text = text;
+        source.changed_at = self.revision;
+    }
+
+    /// Compiles a file's actor under a package namespace, so that its symbols do not
+    /// clash with those of other packages linked into the same module
+    pub fn set_namespace(&mut self, file: FileId, namespace: impl Into<String>) {
+        let namespace = Some(namespace.into());
+        let source = &mut self.files[file.0 as usize];
+        if source.namespace != namespace {
+            self.revision += 1;
+            source.namespace = namespace;
+            source.changed_at = self.revision;
+        }
+    }
+
+    /// Package namespace the file's actor is compiled under, if any
+    pub fn namespace(&self, file: FileId) -> Option<&str> {
+        self.files[file.0 as usize].namespace.as_deref()
+    }
+
+    /// Replaces the code generation options, invalidating generated artifacts and
+    /// the semantic results that depend on the enabled WASM features
+    pub fn set_options(&mut self, options: CodeGenOptions) {
+        ice::set_options(&options);
+        self.revision += 1;
+        self.options = options;
+        self.typed.clear();
+        self.artifacts.clear();
+        self.linked.clear();
+    }
+
+    /// Replaces the limits the parser enforces on every file, e.g. tighter
+    /// ones for untrusted input, invalidating every result past lexing
+    pub fn set_parse_limits(&mut self, limits: ParseLimits) {
+        self.revision += 1;
+        self.parse_limits = limits;
+        // 残したメソッドは前の制限で解析したものなので、読み直させる
+        for document in self.documents.values_mut() {
+            document.forget_parsed_methods();
+        }
+        self.parsed.clear();
+        self.typed.clear();
+        self.artifacts.clear();
+        self.linked.clear();
+        self.warnings.clear();
+    }
+
+    /// Registers a compiler plugin, invalidating every result that plugins can affect
+    pub fn register_plugin(&mut self, plugin: Box<dyn CompilerPlugin>) {
+        self.revision += 1;
+        self.plugins.register(plugin);
+        self.parsed.clear();
+        self.typed.clear();
+        self.artifacts.clear();
+        self.linked.clear();
+        self.warnings.clear();
+    }
+
+    /// Looks up a previously registered file by path
+    pub fn file_id(&self, path: impl AsRef<Path>) -> Option<FileId> {
+        self.file_ids.get(path.as_ref()).copied()
+    }
+
+    /// Returns the path of a registered file
+    pub fn path(&self, file: FileId) -> &Path {
+        &self.files[file.0 as usize].path
+    }
+
+    /// Returns the source text of a registered file
+    pub fn source_text(&self, file: FileId) -> Arc<str> {
+        self.files[file.0 as usize].text.clone()
+    }
+
+    /// Query: token stream of a file, with the line of each token
+    pub fn tokens(&mut self, file: FileId) -> QueryResult<Vec<(Token, usize)>> {
+        let changed_at = self.changed_at(file);
+        if let Some(result) = self.tokens.get(file, changed_at) {
+            return result;
+        }
+
+        let _phase = self.enter_phase(Phase::Lexing, file);
+        // 字句エラーは最初の 1 つで止めず、まとめて報告する
+        let path = self.path(file).display().to_string();
+        let document = self.document(file);
+        let result = if document.lex_errors().is_empty() {
+            Ok(Arc::new(document.token_lines()))
+        } else {
+            // 回復した字句エラーは Error トークンとして同じ順に残っている
+            let errors = document.lex_errors().to_vec();
+            let reports = errors
+                .iter()
+                .zip(
+                    document
+                        .tokens()
+                        .iter()
+                        .filter(|(token, _)| matches!(token, Token::Error(_))),
+                )
+                .map(|(error, (_, span))| Report::lex(&path, error, span.len()))
+                .collect();
+            self.record_errors(file, reports);
+            Err(errors
+                .iter()
+                .map(|e| format!("Lexer error at {}", e))
+                .collect::<Vec<_>>()
+                .join("\n"))
+        };
+        self.tokens.insert(file, changed_at, result)
+    }
+
+    /// Query: syntax tree of a file
+    pub fn parse(&mut self, file: FileId) -> QueryResult<Actor> {
+        let changed_at = self.changed_at(file);
+        if let Some(result) = self.parsed.get(file, changed_at) {
+            return result;
+        }
+
+        let result = self.tokens(file).and_then(|_| {
+            let _phase = self.enter_phase(Phase::Parsing, file);
+            self.record_errors(file, Vec::new());
+            let text = self.source_text(file);
+            let limits = self.parse_limits;
+            let document = self.document(file);
+            let mut parser = document.parser().with_limits(limits).recover_errors();
+            let parsed = parser.parse_actor();
+            document.keep_parsed_methods(&mut parser);
+            let mut errors = parser.take_errors();
+            let mut actor = match parsed {
+                Ok(actor) if errors.is_empty() => actor,
+                parsed => {
+                    if let Err(e) = parsed {
+                        let position = parser.error_position(&e);
+                        errors.push((e, position));
+                    }
+                    // 位置を求めるため、トークンのバイト範囲を使う
+                    let spans = self.document(file).tokens().to_vec();
+                    let path = self.path(file).display().to_string();
+                    let reports = errors
+                        .iter()
+                        .map(|(e, position)| Report::parse(&path, &text, &spans, e, *position))
+                        .collect();
+                    self.record_errors(file, reports);
+                    return Err(errors
+                        .iter()
+                        .map(|(e, _)| format!("Parser error: {}", e))
+                        .collect::<Vec<_>>()
+                        .join("\n"));
+                }
+            };
+
+            let mut ctx = PassContext::new(Hook::PostParse, self.path(file));
+            if let Err(e) = self.plugins.run_post_parse(&mut actor, &mut ctx) {
+                self.record_errors(file, vec![Report::new("plugin", e.as_str())]);
+                return Err(e);
+            }
+            self.record_warnings(file, ctx);
+            Ok(Arc::new(actor))
+        });
+        self.parsed.insert(file, changed_at, result)
+    }
+
+    /// Path of the file defining `module` for an `import` in `file`: the
+    /// `<module>.replica` next to it
+    pub fn import_path(&self, file: FileId, module: &str) -> PathBuf {
+        self.path(file)
+            .with_file_name(format!("{}.{}", module, package::SOURCE_EXTENSION))
+    }
+
+    /// Paths of the modules `file` imports that are not registered yet, so
+    /// that a driver can load them. Empty when the file does not parse.
+    pub fn missing_imports(&mut self, file: FileId) -> Vec<PathBuf> {
+        self.imports(file)
+            .into_iter()
+            .filter(|(_, imported)| imported.is_none())
+            .map(|(module, _)| self.import_path(file, &module))
+            .collect()
+    }
+
+    /// Modules imported by `file`, with the file defining each if registered
+    fn imports(&mut self, file: FileId) -> Vec<(String, Option<FileId>)> {
+        let Ok(actor) = self.parse(file) else {
+            return Vec::new();
+        };
+        actor
+            .imports
+            .iter()
+            .map(|module| (module.clone(), self.file_id(self.import_path(file, module))))
+            .collect()
+    }
+
+    /// Declarations of the modules `file` imports, directly or through other
+    /// modules, that parse. The others are left out, for semantic analysis to
+    /// report.
+    fn symbols(&mut self, file: FileId) -> SymbolTable {
+        let mut symbols = SymbolTable::new();
+        let mut visiting = HashSet::from([file]);
+        self.declare_imports(file, &mut symbols, &mut visiting);
+        symbols
+    }
+
+    /// Declares the modules `file` imports after the modules they import. A
+    /// module reached again while its imports are being declared is declared
+    /// as it is, for semantic analysis to report the cycle.
+    fn declare_imports(
+        &mut self,
+        file: FileId,
+        symbols: &mut SymbolTable,
+        visiting: &mut HashSet<FileId>,
+    ) {
+        for (module, imported) in self.imports(file) {
+            let Some(imported) = imported else {
+                continue;
+            };
+            if symbols.module(&module).is_some() {
+                continue;
+            }
+            let Ok(actor) = self.parse(imported) else {
+                continue;
+            };
+            if visiting.insert(imported) {
+                self.declare_imports(imported, symbols, visiting);
+                visiting.remove(&imported);
+            }
+            symbols.declare(module, &actor);
+        }
+    }
+
+    /// Latest revision at which `file` or a module it imports, directly or
+    /// through other modules, changed. An import that is not registered yet
+    /// may be added at any revision.
+    fn changed_with_imports(&mut self, file: FileId) -> u64 {
+        let mut changed_at = self.changed_at(file);
+        let mut pending = vec![file];
+        let mut seen = HashSet::from([file]);
+        while let Some(file) = pending.pop() {
+            for (_, imported) in self.imports(file) {
+                match imported {
+                    Some(imported) => {
+                        if seen.insert(imported) {
+                            changed_at = changed_at.max(self.changed_at(imported));
+                            pending.push(imported);
+                        }
+                    }
+                    None => return self.revision,
+                }
+            }
+        }
+        changed_at
+    }
+
+    /// Query: syntax tree of a file that passed semantic analysis, with
+    /// `comptime` calls replaced by their results
+    pub fn typed_ast(&mut self, file: FileId) -> QueryResult<Actor> {
+        let changed_at = self.changed_with_imports(file);
+        if let Some(result) = self.typed.get(file, changed_at) {
+            return result;
+        }
+
+        let result = self.parse(file).and_then(|actor| {
+            let _phase = self.enter_phase(Phase::SemanticAnalysis, file);
+            let _actor = ice::enter_actor(&actor.name);
+            self.record_errors(file, Vec::new());
+            let mut analyzer = SemanticAnalyzer::new();
+            analyzer.set_symbols(self.symbols(file));
+            analyzer.set_threads(self.options.has_wasm_feature(WasmFeature::Threads));
+            analyzer.set_config_keys(self.options.config.keys().cloned());
+            let errors = analyzer.analyze_actor_all(&actor);
+            if !errors.is_empty() {
+                let text = self.source_text(file);
+                let (spans, _) = lexer::tokenize_recovering(&text);
+                let path = self.path(file).display().to_string();
+                let reports = errors
+                    .iter()
+                    .map(|(e, method)| Report::semantic(&path, &text, &spans, e, method.as_deref()))
+                    .collect();
+                self.record_errors(file, reports);
+                return Err(errors
+                    .iter()
+                    .map(|(e, _)| format!("Semantic analysis error: {}", e))
+                    .collect::<Vec<_>>()
+                    .join("\n"));
+            }
+            let actor = consteval::fold_actor(&actor).map_err(|e| {
+                self.record_errors(file, vec![Report::new("consteval", e.to_string())]);
+                format!("Compile-time evaluation error: {}", e)
+            })?;
+
+            let mut ctx = PassContext::new(Hook::PostTypecheck, self.path(file));
+            self.check_state_size(&actor, &mut ctx);
+            for warning in stack::analyze(&actor).warnings(self.options.stack_size) {
+                ctx.warn(warning);
+            }
+            if let Err(e) = self.plugins.run_post_typecheck(&actor, &mut ctx) {
+                self.record_errors(file, vec![Report::new("plugin", e.as_str())]);
+                return Err(e);
+            }
+            self.record_warnings(file, ctx);
+            Ok(Arc::new(actor))
+        });
+        self.typed.insert(file, changed_at, result)
+    }
+
+    /// Query: all diagnostics reported for a file, errors first
+    pub fn diagnostics(&mut self, file: FileId) -> Vec<String> {
+        let mut diagnostics = match self.typed_ast(file) {
+            Ok(_) => Vec::new(),
+            Err(message) => vec![message],
+        };
+
+        for hook in [Hook::PostParse, Hook::PostTypecheck] {
+            diagnostics.extend(
+                self.warnings(file, hook)
+                    .iter()
+                    .map(|warning| format!("warning: {}", warning)),
+            );
+        }
+
+        diagnostics
+    }
+
+    /// Warnings reported by plugins for a file at the given hook
+    pub fn warnings(&self, file: FileId, hook: Hook) -> Arc<Vec<String>> {
+        self.warnings
+            .get((file, hook), self.changed_at(file))
+            .unwrap_or_default()
+    }
+
+    /// Located reports of the lexical, syntax, semantic and plugin errors that
+    /// made `typed_ast` fail for a file, empty until it has been run
+    pub fn error_reports(&self, file: FileId) -> Arc<Vec<Report>> {
+        self.errors
+            .get(file, self.changed_at(file))
+            .unwrap_or_default()
+    }
+
+    /// Query: WASM output for a file
+    pub fn wasm(&mut self, file: FileId) -> QueryResult<Vec<u8>> {
+        self.emit(file, EmitKind::Wasm)
+    }
+
+    /// Query: output artifact of the given kind for a file
+    pub fn emit(&mut self, file: FileId, kind: EmitKind) -> QueryResult<Vec<u8>> {
+        let changed_at = self.changed_with_imports(file);
+        if let Some(result) = self.artifacts.get((file, kind), changed_at) {
+            return result;
+        }
+
+        let result = match kind {
+            EmitKind::PyBindings | EmitKind::OpenApi => self.host_bindings(&[file], kind),
+            EmitKind::Ast | EmitKind::Tokens => self.front_end_output(&[file], kind),
+            _ => self
+                .typed_ast(file)
+                .and_then(|actor| self.generate(file, &actor, kind)),
+        };
+        self.artifacts.insert((file, kind), changed_at, result)
+    }
+
+    /// Query: a single artifact built from several files linked together,
+    /// with link-time optimization applied according to the options
+    pub fn emit_linked(&mut self, files: &[FileId], kind: EmitKind) -> QueryResult<Vec<u8>> {
+        let changed_at = files
+            .iter()
+            .map(|&file| self.changed_with_imports(file))
+            .max()
+            .unwrap_or(0);
+        let key = (files.to_vec(), kind);
+        if let Some(result) = self.linked.get(key.clone(), changed_at) {
+            return result;
+        }
+
+        let result = match kind {
+            EmitKind::PyBindings | EmitKind::OpenApi => self.host_bindings(files, kind),
+            EmitKind::Ast | EmitKind::Tokens => self.front_end_output(files, kind),
+            _ => self.generate_linked(files, kind),
+        };
+        self.linked.insert(key, changed_at, result)
+    }
+
+    /// Python module or OpenAPI document for the actors of `files`, linked into
+    /// one WASM module. Methods left out are reported as pre-codegen warnings.
+    fn host_bindings(&mut self, files: &[FileId], kind: EmitKind) -> QueryResult<Vec<u8>> {
+        let mut actors = Vec::with_capacity(files.len());
+        for &file in files {
+            let actor = self.typed_ast(file)?;
+            actors.push(match &self.files[file.0 as usize].namespace {
+                Some(namespace) => Arc::new(package::namespace_actor(&actor, namespace)),
+                None => actor,
+            });
+        }
+
+        let names: Vec<String> = files
+            .iter()
+            .map(|&file| {
+                self.path(file)
+                    .file_name()
+                    .map(|name| name.to_string_lossy().into_owned())
+                    .unwrap_or_default()
+            })
+            .collect();
+        let actor_refs: Vec<&Actor> = actors.iter().map(AsRef::as_ref).collect();
+        let bindings = match kind {
+            EmitKind::OpenApi => bindgen::http::openapi(&actor_refs, &names.join(", ")),
+            _ => {
+                let wasi = self.options.target_triple.contains("-wasi");
+                bindgen::python::generate(&actor_refs, &names.join(", "), wasi)?
+            }
+        };
+
+        // 省略したメソッドはアクターを定義したファイルの警告として報告する
+        for (&file, actor) in files.iter().zip(&actors) {
+            let mut ctx = PassContext::new(Hook::PreCodegen, self.path(file));
+            for skipped in bindings.skipped.iter().filter(|s| s.actor == actor.name) {
+                ctx.warn(skipped.message.clone());
+            }
+            self.record_warnings(file, ctx);
+        }
+        Ok(Arc::new(bindings.source.into_bytes()))
+    }
+
+    /// Token streams or syntax trees of `files`, each headed by its path when
+    /// there are several
+    fn front_end_output(&mut self, files: &[FileId], kind: EmitKind) -> QueryResult<Vec<u8>> {
+        let mut text = String::new();
+        for &file in files {
+            if files.len() > 1 {
+                text.push_str(&format!("// {}\n", self.path(file).display()));
+            }
+            if kind == EmitKind::Tokens {
+                for (token, line) in self.tokens(file)?.iter() {
+                    text.push_str(&format!("{}\t{:?}\n", line, token));
+                }
+            } else {
+                text.push_str(&format!("{:#?}\n", self.parse(file)?));
+            }
+        }
+        Ok(Arc::new(text.into_bytes()))
+    }
+
+    fn generate(&mut self, file: FileId, actor: &Actor, kind: EmitKind) -> QueryResult<Vec<u8>> {
+        let context = Context::create();
+        let code_gen = self.build_generator(&context, file, actor)?;
+
+        code_gen
+            .emit(kind)
+            .map(Arc::new)
+            .map_err(|e| format!("{} emission error: {}", kind, e))
+    }
+
+    fn generate_linked(&mut self, files: &[FileId], kind: EmitKind) -> QueryResult<Vec<u8>> {
+        let (&first, rest) = files
+            .split_first()
+            .ok_or_else(|| "No input files to link".to_string())?;
+
+        let context = Context::create();
+        let actor = self.typed_ast(first)?;
+        let mut linked = self.build_generator(&context, first, &actor)?;
+
+        for &file in rest {
+            let actor = self.typed_ast(file)?;
+            let code_gen = self.build_generator(&context, file, &actor)?;
+            linked
+                .link(code_gen)
+                .map_err(|e| format!("Link error: {}", e))?;
+        }
+
+        let _phase = self.enter_phase(Phase::Linking, first);
+        linked
+            .run_lto()
+            .map_err(|e| format!("Link-time optimization error: {}", e))?;
+
+        linked
+            .emit(kind)
+            .map(Arc::new)
+            .map_err(|e| format!("{} emission error: {}", kind, e))
+    }
+
+    /// Runs pre-codegen passes and lowers one file's actor into a fresh generator
+    fn build_generator<'ctx>(
+        &mut self,
+        context: &'ctx Context,
+        file: FileId,
+        actor: &Actor,
+    ) -> Result<codegen::CodeGenerator<'ctx>, String> {
+        let _phase = self.enter_phase(Phase::CodeGeneration, file);
+        let _actor = ice::enter_actor(&actor.name);
+        let mut actor = actor.clone();
+        let mut ctx = PassContext::new(Hook::PreCodegen, self.path(file));
+        self.plugins.run_pre_codegen(&mut actor, &mut ctx)?;
+        if self.options.http_facade {
+            for skipped in bindgen::http::routes(&actor).1 {
+                ctx.warn(skipped.message);
+            }
+        }
+        self.record_warnings(file, ctx);
+
+        if let Some(namespace) = &self.files[file.0 as usize].namespace {
+            actor = package::namespace_actor(&actor, namespace);
+        }
+        let symbols = self.symbols(file);
+
+        let module_name = self
+            .path(file)
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .unwrap_or("module");
+
+        let mut code_gen =
+            codegen::create_generator(context, module_name, Some(self.options.clone()))?;
+        for module in &actor.imports {
+            if let Some(symbols) = symbols.module(module) {
+                code_gen
+                    .import_module(symbols)
+                    .map_err(|e| format!("Code generation error: {}", e))?;
+            }
+        }
+
+        code_gen
+            .compile_actor(&actor)
+            .map_err(|e| format!("Code generation error: {}", e))?;
+
+        Ok(code_gen)
+    }
+
+    /// Warns when an actor's state struct exceeds the configured size threshold
+    fn check_state_size(&self, actor: &Actor, ctx: &mut PassContext) {
+        let Some(threshold) = self.options.state_size_warning else {
+            return;
+        };
+        if let Ok(resolved) = layout::compute(&actor.fields, &actor.layout) {
+            if resolved.size > threshold {
+                ctx.warn(format!(
+                    "actor `{}` has {} bytes of state, more than the {}-byte threshold",
+                    actor.name, resolved.size, threshold
+                ));
+            }
+        }
+    }
+
+    /// Marks the start of `phase` on `file` for crash reports
+    fn enter_phase(&self, phase: Phase, file: FileId) -> ice::Scope {
+        ice::enter_phase(phase, self.path(file), &self.source_text(file))
+    }
+
+    fn record_warnings(&mut self, file: FileId, ctx: PassContext) {
+        let hook = ctx.hook();
+        let changed_at = self.changed_at(file);
+        self.warnings
+            .insert((file, hook), changed_at, Arc::new(ctx.into_warnings()));
+    }
+
+    fn record_errors(&mut self, file: FileId, reports: Vec<Report>) {
+        let changed_at = self.changed_at(file);
+        self.errors.insert(file, changed_at, Arc::new(reports));
+    }
+
+    /// Incremental lexing state of a file, lexed afresh when its text was
+    /// replaced rather than edited
+    fn document(&mut self, file: FileId) -> &mut Document {
+        let text = &self.files[file.0 as usize].text;
+        let document = self
+            .documents
+            .entry(file)
+            .or_insert_with(|| Document::new(text.clone()));
+        if !Arc::ptr_eq(document.text(), text) {
+            *document = Document::new(text.clone());
+        }
+        document
+    }
+
+    fn changed_at(&self, file: FileId) -> u64 {
+        self.files[file.0 as usize].changed_at
+    }
+}
+
+impl Default for Database {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOURCE: &str = "actor Counter { var value: Int }";
+
+    #[test]
+    fn test_parse_is_memoized() {
+        let mut db = Database::new();
+        let file = db.set_source_text("counter.replica", SOURCE);
+
+        let first = db.parse(file).unwrap();
+        let second = db.parse(file).unwrap();
+        assert!(Arc::ptr_eq(&first, &second));
+    }
+
+    #[test]
+    fn test_lex_errors_are_reported_together() {
+        let mut db = Database::new();
+        let file = db.set_source_text("bad.replica", "actor A { var a: Int %\n var b: Int # }");
+        let error = db.parse(file).unwrap_err();
+        assert_eq!(
+            error,
+            "Lexer error at 1:22: Unexpected character '%'\nLexer error at 2:13: Unexpected character '#'"
+        );
+    }
+
+    #[test]
+    fn test_identical_text_keeps_memo() {
+        let mut db = Database::new();
+        let file = db.set_source_text("counter.replica", SOURCE);
+        let first = db.parse(file).unwrap();
+        let revision = db.revision();
+
+        assert_eq!(db.set_source_text("counter.replica", SOURCE), file);
+        assert_eq!(db.revision(), revision);
+        assert!(Arc::ptr_eq(&first, &db.parse(file).unwrap()));
+    }
+
+    #[test]
+    fn test_changed_text_invalidates() {
+        let mut db = Database::new();
+        let file = db.set_source_text("counter.replica", SOURCE);
+        let first = db.parse(file).unwrap();
+
+        db.set_source_text("counter.replica", "actor Renamed { var value: Int }");
+        let second = db.parse(file).unwrap();
+        assert!(!Arc::ptr_eq(&first, &second));
+        assert_eq!(second.name, "Renamed");
+    }
+
+    #[test]
+    fn test_edited_text_invalidates() {
+        let mut db = Database::new();
+        let file = db.set_source_text("counter.replica", SOURCE);
+        db.parse(file).unwrap();
+
+        // 編集を重ねても、全文を置き換えたときと同じ結果になる
+        db.edit_source_text(file, 6..13, "Renamed");
+        db.edit_source_text(file, 30..30, " func get() -> Int { return value }");
+        assert_eq!(
+            &*db.source_text(file),
+            "actor Renamed { var value: Int func get() -> Int { return value } }"
+        );
+        let edited = db.parse(file).unwrap();
+        assert_eq!(edited.name, "Renamed");
+        assert_eq!(edited.methods[0].name, "get");
+
+        db.edit_source_text(file, 0..0, "%");
+        assert_eq!(
+            db.parse(file).unwrap_err(),
+            "Lexer error at 1:1: Unexpected character '%'"
+        );
+        let mut fresh = Database::new();
+        let renamed = fresh.set_source_text("counter.replica", db.source_text(file));
+        assert_eq!(fresh.parse(renamed), db.parse(file));
+    }
+
+    #[test]
+    fn test_parse_limits() {
+        let mut db = Database::new();
+        let file = db.set_source_text(
+            "deep.replica",
+            "actor A { func f() -> Int { return ((((1)))) } }",
+        );
+        assert!(db.parse(file).is_ok());
+
+        db.set_parse_limits(ParseLimits {
+            max_depth: 6,
+            ..ParseLimits::default()
+        });
+        assert_eq!(
+            db.parse(file).unwrap_err(),
+            "Parser error: Nesting is deeper than the limit of 6 levels"
+        );
+        let reports = db.error_reports(file);
+        assert_eq!(reports[0].code, "parse/TooDeeplyNested");
+        assert_eq!(reports[0].context.context, "nested too deeply");
+    }
+
+    #[test]
+    fn test_files_are_independent() {
+        let mut db = Database::new();
+        let a = db.set_source_text("a.replica", SOURCE);
+        let b = db.set_source_text("b.replica", "actor Other { let id: Int }");
+        let parsed_a = db.parse(a).unwrap();
+
+        db.set_source_text("b.replica", "actor Changed { let id: Int }");
+        assert_eq!(db.parse(b).unwrap().name, "Changed");
+        assert!(Arc::ptr_eq(&parsed_a, &db.parse(a).unwrap()));
+    }
+
+    #[test]
+    fn test_plugin_warnings_in_diagnostics() {
+        let mut db = Database::new();
+        db.register_plugin(Box::new(crate::plugin::NamingConventionLint));
+        let file = db.set_source_text("lint.replica", "actor counter { var value: Int }");
+
+        let diagnostics = db.diagnostics(file);
+        assert_eq!(diagnostics.len(), 1);
+        assert!(diagnostics[0].starts_with("warning: actor `counter`"));
+    }
+
+    #[test]
+    fn test_state_size_warning() {
+        let source = "actor Buffer { var data: [Int; 32] }";
+        let mut db = Database::with_options(CodeGenOptions {
+            state_size_warning: Some(64),
+            ..Default::default()
+        });
+        let file = db.set_source_text("buffer.replica", source);
+        let diagnostics = db.diagnostics(file);
+        assert_eq!(diagnostics.len(), 1);
+        assert!(diagnostics[0].contains("actor `Buffer` has 128 bytes of state"));
+
+        db.set_options(CodeGenOptions {
+            state_size_warning: None,
+            ..Default::default()
+        });
+        assert!(db.diagnostics(file).is_empty());
+    }
+
+    #[test]
+    fn test_recursion_warning() {
+        let source = "actor Math { func fact(n: Int) -> Int { return fact(n) } }";
+        let mut db = Database::new();
+        let file = db.set_source_text("math.replica", source);
+        let diagnostics = db.diagnostics(file);
+        assert_eq!(diagnostics.len(), 1, "{:?}", diagnostics);
+        assert!(diagnostics[0].contains("method `fact` is recursive (fact -> fact)"));
+    }
+
+    #[test]
+    fn test_namespaced_dependency() {
+        let mut db = Database::new();
+        let app = db.set_source_text("app/counter.replica", SOURCE);
+        let dependency = db.set_source_text("math/counter.replica", SOURCE);
+        db.set_namespace(dependency, "math");
+        let revision = db.revision();
+        db.set_namespace(dependency, "math");
+        assert_eq!(db.revision(), revision);
+
+        // 名前空間が異なれば同名のアクターも一つのモジュールにリンクできる
+        let linked = db.emit_linked(&[app, dependency], EmitKind::LlvmBitcode);
+        assert!(linked.is_ok(), "{:?}", linked.err());
+    }
+
+    #[test]
+    fn test_linked_actors_share_method_names() {
+        let mut db = Database::new();
+        let store = db.set_source_text(
+            "store.replica",
+            "actor Store { var value: Int\n func get() -> Int { return value } }",
+        );
+        let cache = db.set_source_text(
+            "cache.replica",
+            "actor Cache { var hits: Int\n func get() -> Int { return hits } }",
+        );
+
+        // メソッドのシンボルはアクター名で修飾されるので、同名でも衝突しない
+        let linked = db.emit_linked(&[store, cache], EmitKind::LlvmIr);
+        let ir = String::from_utf8(linked.unwrap().as_ref().clone()).unwrap();
+        assert!(ir.contains("@Store.get("), "{}", ir);
+        assert!(ir.contains("@Cache.get("), "{}", ir);
+    }
+
+    #[test]
+    fn test_python_bindings() {
+        let source = "actor Shapes { func area(w: Int, h: Int) -> Int { return w * h } func pair() -> (Int, Int) { return (1, 2) } }";
+        let mut db = Database::new();
+        let file = db.set_source_text("shapes.replica", source);
+
+        let module = db.emit(file, EmitKind::PyBindings).unwrap();
+        let module = String::from_utf8(module.as_ref().clone()).unwrap();
+        assert!(module.contains("async def area(self, w: int, h: int) -> int:"));
+        assert!(!module.contains("def pair"));
+
+        // 境界を越えられないメソッドはコード生成前の警告になる
+        let warnings = db.warnings(file, Hook::PreCodegen);
+        assert_eq!(warnings.len(), 1, "{:?}", warnings);
+        assert!(warnings[0].contains("method `pair`"));
+    }
+
+    #[test]
+    fn test_openapi() {
+        let source = "actor Counter { func add(amount: Int) -> Int { return amount + 1 } }";
+        let mut db = Database::new();
+        let file = db.set_source_text("counter.replica", source);
+
+        let document = db.emit(file, EmitKind::OpenApi).unwrap();
+        let document: serde_json::Value = serde_json::from_slice(&document).unwrap();
+        assert_eq!(
+            document["paths"]["/Counter/add"]["post"]["operationId"],
+            "Counter_add"
+        );
+    }
+
+    #[test]
+    fn test_front_end_output() {
+        let mut db = Database::new();
+        let file = db.set_source_text("point.replica", "actor Point {\n    let x: Int\n}");
+
+        let tokens = db.emit(file, EmitKind::Tokens).unwrap();
+        let tokens = String::from_utf8(tokens.as_ref().clone()).unwrap();
+        assert!(tokens.starts_with("1\tActor\n1\tIdentifier(\"Point\")\n"));
+        assert!(tokens.contains("2\tLet\n"));
+
+        let other = db.set_source_text("origin.replica", "actor Origin { }");
+        let ast = db.emit_linked(&[file, other], EmitKind::Ast).unwrap();
+        let ast = String::from_utf8(ast.as_ref().clone()).unwrap();
+        assert!(ast.starts_with("// point.replica\nActor {\n    name: \"Point\","));
+        assert!(ast.contains("// origin.replica\n"));
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        let mut db = Database::new();
+        let file = db.set_source_text("bad.replica", "actor { }");
+        let diagnostics = db.diagnostics(file);
+        assert_eq!(diagnostics.len(), 1);
+        assert!(diagnostics[0].starts_with("Parser error"));
+    }
+
+    #[test]
+    fn test_error_reports() {
+        let mut db = Database::new();
+        let file = db.set_source_text("bad.replica", "actor A { var a: Int %\n var b: Int # }");
+        assert!(db.typed_ast(file).is_err());
+        let reports = db.error_reports(file);
+        assert_eq!(reports.len(), 2);
+        assert_eq!(reports[1].code, "lex");
+        let location = reports[1].context.location.as_ref().unwrap();
+        assert_eq!((location.line, location.column), (2, 13));
+
+        // 修正すると古いレポートは残らない
+        db.set_source_text("bad.replica", "actor A { func f() -> Int { return b } }");
+        assert!(db.typed_ast(file).is_err());
+        let reports = db.error_reports(file);
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].code, "semantic/UndefinedVariable");
+
+        db.set_source_text("bad.replica", SOURCE);
+        assert!(db.typed_ast(file).is_ok());
+        assert!(db.error_reports(file).is_empty());
+    }
+
+    #[test]
+    fn test_all_errors_are_reported() {
+        let mut db = Database::new();
+        let file = db.set_source_text(
+            "bad.replica",
+            "actor A {\n func f() -> Int { let = 1 }\n var : Int\n}",
+        );
+        let error = db.typed_ast(file).unwrap_err();
+        assert_eq!(error.lines().count(), 2, "{}", error);
+        assert_eq!(db.error_reports(file).len(), 2);
+
+        db.set_source_text(
+            "bad.replica",
+            "actor A {\n func f() -> Int { return a }\n func g() -> Int { return b }\n}",
+        );
+        let error = db.typed_ast(file).unwrap_err();
+        assert_eq!(
+            error,
+            "Semantic analysis error: Undefined variable: a\nSemantic analysis error: Undefined variable: b"
+        );
+        let lines: Vec<usize> = db
+            .error_reports(file)
+            .iter()
+            .filter_map(|report| Some(report.context.location.as_ref()?.line))
+            .collect();
+        assert_eq!(lines, [2, 3]);
+    }
+
+    #[test]
+    fn test_imports_resolve_next_to_the_file() {
+        let mut db = Database::new();
+        let node = db.set_source_text(
+            "src/node.replica",
+            "import Peer\nactor Node {\n    func next(peer: Peer) -> Peer {\n        return peer\n    }\n}",
+        );
+        assert_eq!(
+            db.missing_imports(node),
+            [PathBuf::from("src/Peer.replica")]
+        );
+        assert!(db.typed_ast(node).is_err());
+        let report = db.error_reports(node)[0].clone();
+        assert_eq!(report.code, "semantic/UnresolvedImport");
+        let location = report.context.location.unwrap();
+        assert_eq!((location.line, location.column), (1, 8));
+
+        // 取り込むファイルが登録されると結果は作り直される
+        let peer = db.set_source_text("src/Peer.replica", "actor Peer { var id: Int }");
+        assert!(db.missing_imports(node).is_empty());
+        assert!(db.typed_ast(node).is_ok());
+
+        db.set_source_text("src/Peer.replica", "actor Renamed { var id: Int }");
+        let error = db.typed_ast(node).unwrap_err();
+        assert!(error.contains("Unknown type Peer"), "{}", error);
+        assert!(db.typed_ast(peer).is_ok());
+    }
+
+    #[test]
+    fn test_import_cycles_through_other_modules() {
+        let mut db = Database::new();
+        let node = db.set_source_text("src/Node.replica", "import Peer\nactor Node {}");
+        db.set_source_text("src/Peer.replica", "import Storage\nactor Peer {}");
+        db.set_source_text("src/Storage.replica", "actor Storage {}");
+        assert!(db.typed_ast(node).is_ok());
+
+        // 間接的にインポートしたファイルの変更でも作り直される
+        db.set_source_text("src/Storage.replica", "import Node\nactor Storage {}");
+        let error = db.typed_ast(node).unwrap_err();
+        assert!(
+            error.contains("Import cycle Node -> Peer -> Storage -> Node"),
+            "{}",
+            error
+        );
+    }
+}
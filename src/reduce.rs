@@ -0,0 +1,177 @@
+//! Test-case reduction for bug reports.
+//! [`reduce`] deletes declarations, statements and then lines from a source
+//! file for as long as it stays interesting, e.g. still makes the compiler
+//! panic or still reports some diagnostic, leaving a small file that shows the
+//! same problem. `replicac reduce` drives it with the compiler as the test.
+
+use crate::cst::{self, SyntaxKind};
+use std::ops::Range;
+
+/// Nodes tried for deletion together, larger ones first so that a method
+/// goes in one step rather than statement by statement
+const PASSES: &[&[SyntaxKind]] = &[
+    &[
+        SyntaxKind::Method,
+        SyntaxKind::Init,
+        SyntaxKind::Deinit,
+        SyntaxKind::Struct,
+        SyntaxKind::Enum,
+        SyntaxKind::Extern,
+        SyntaxKind::Field,
+        SyntaxKind::Constant,
+        SyntaxKind::Import,
+        SyntaxKind::Topic,
+        SyntaxKind::Pool,
+    ],
+    &[SyntaxKind::Statement],
+    &[SyntaxKind::Attribute],
+];
+
+/// Smallest text reachable from `source` by deletions that keep
+/// `interesting` true, or `None` if `source` itself is not interesting
+pub fn reduce(source: &str, mut interesting: impl FnMut(&str) -> bool) -> Option<String> {
+    if !interesting(source) {
+        return None;
+    }
+    let mut text = source.to_string();
+    loop {
+        let before = text.len();
+        for kinds in PASSES {
+            text = remove_nodes(text, kinds, &mut interesting);
+        }
+        text = remove_lines(text, &mut interesting);
+        if text.len() == before {
+            return Some(text);
+        }
+    }
+}
+
+/// Deletes every node of `kinds` whose deletion keeps the text interesting.
+/// Text that does not parse is left to [`remove_lines`].
+fn remove_nodes(
+    text: String,
+    kinds: &[SyntaxKind],
+    interesting: &mut impl FnMut(&str) -> bool,
+) -> String {
+    let Ok((_, tree)) = cst::parse(&text) else {
+        return text;
+    };
+    let mut ranges: Vec<Range<usize>> = kinds
+        .iter()
+        .flat_map(|&kind| tree.descendants(kind))
+        .map(|node| whole_lines(&text, node.range.clone()))
+        .collect();
+    // 後ろから消せば、手前の範囲の位置は変わらない
+    ranges.sort_by_key(|range| std::cmp::Reverse(range.start));
+
+    let mut text = text;
+    let mut removed: Option<Range<usize>> = None;
+    for range in ranges {
+        // 消した範囲の中のノードは一緒に消えている
+        if removed
+            .as_ref()
+            .is_some_and(|removed| range.end > removed.start)
+        {
+            continue;
+        }
+        let candidate = [&text[..range.start], &text[range.end..]].concat();
+        if interesting(&candidate) {
+            text = candidate;
+            removed = Some(range);
+        }
+    }
+    text
+}
+
+/// `range` grown to the whole lines it covers when nothing else is on them
+fn whole_lines(text: &str, range: Range<usize>) -> Range<usize> {
+    let start = text[..range.start].rfind('\n').map_or(0, |index| index + 1);
+    let end = text[range.end..]
+        .find('\n')
+        .map_or(text.len(), |index| range.end + index + 1);
+    if text[start..range.start].trim().is_empty() && text[range.end..end].trim().is_empty() {
+        start..end
+    } else {
+        range
+    }
+}
+
+/// Deletes runs of lines, halving the run length down to single lines, while
+/// the text stays interesting
+fn remove_lines(text: String, interesting: &mut impl FnMut(&str) -> bool) -> String {
+    let mut lines: Vec<&str> = text.split_inclusive('\n').collect();
+    let mut size = lines.len() / 2;
+    while size > 0 {
+        let mut end = lines.len();
+        while end > 0 {
+            let start = end.saturating_sub(size);
+            let candidate = [&lines[..start], &lines[end..]].concat().concat();
+            if interesting(&candidate) {
+                lines.drain(start..end);
+            }
+            end = start;
+        }
+        size /= 2;
+    }
+    lines.concat()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOURCE: &str = "import Math
+
+struct Point {
+    var x: Int
+}
+
+actor Counter {
+    var count: Int
+    var total: Int
+
+    @nonreentrant
+    func add(amount: Int) -> Int {
+        count += amount
+        total += amount
+        return crash(count)
+    }
+
+    func reset() {
+        count = 0
+    }
+}
+";
+
+    #[test]
+    fn test_reduce() {
+        // 構文が正しく、括弧の閉じたまま `crash(` を含む最小の入力
+        let mut tests = 0;
+        let reduced = reduce(SOURCE, |text| {
+            tests += 1;
+            text.contains("crash(")
+                && text.matches('{').count() == text.matches('}').count()
+                && cst::parse(text).is_ok()
+        })
+        .unwrap();
+        assert_eq!(
+            reduced,
+            "actor Counter {\n    func add(amount: Int) -> Int {\n        return crash(count)\n    }\n}\n"
+        );
+        assert!(tests < 100, "{} tests", tests);
+
+        // 構文を問わなければ行単位で 1 行まで減る
+        let reduced = reduce(SOURCE, |text| text.contains("crash(")).unwrap();
+        assert_eq!(reduced, "        return crash(count)\n");
+
+        assert_eq!(reduce(SOURCE, |text| text.contains("panic(")), None);
+    }
+
+    #[test]
+    fn test_whole_lines() {
+        let text = "a\n    let x = 1\nb c\n";
+        assert_eq!(whole_lines(text, 6..15), 2..16);
+        // 同じ行に他のものがあれば範囲のまま
+        assert_eq!(whole_lines(text, 16..17), 16..17);
+    }
+}
@@ -0,0 +1,364 @@
+//! State carry-over for hot reload.
+//! The module's `replica.state` custom section records where each field of an
+//! actor's instance lives, relative to the pointer `<Actor>.new` returns, and
+//! how a host can copy it. A host reloading a module creates every live
+//! instance again in the new module and copies the fields both versions
+//! declare with the same type; fields the new version adds keep the values
+//! `init` gave them. `replicac build --watch` compares the sections of
+//! successive builds so that a change a host would refuse is reported when it
+//! is made.
+
+use crate::ast::{Actor, Type};
+use crate::capability::custom_sections;
+use serde_json::{json, Value};
+
+/// Custom section describing the state of each actor, one JSON object per line
+pub const STATE_SECTION: &str = "replica.state";
+
+/// How a host copies a field into the reloaded module
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Carry {
+    /// Plain bytes holding no pointer, copied as they are
+    Bytes,
+    /// Pointer to a `String` descriptor; the host copies the text into a new
+    /// descriptor allocated in the new module
+    String,
+    /// Pointers into the old module (arrays, actors, enums) or host handles,
+    /// which mean nothing in the new one
+    Never,
+}
+
+impl Carry {
+    /// Name of the carry in the section
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Carry::Bytes => "bytes",
+            Carry::String => "string",
+            Carry::Never => "never",
+        }
+    }
+
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "bytes" => Some(Carry::Bytes),
+            "string" => Some(Carry::String),
+            "never" => Some(Carry::Never),
+            _ => None,
+        }
+    }
+}
+
+/// How a field of type `ty` is carried over
+pub fn carry(ty: &Type) -> Carry {
+    let plain = |ty: &Type| carry(ty) == Carry::Bytes;
+    match ty {
+        Type::Int | Type::Float | Type::Bool => Carry::Bytes,
+        Type::String => Carry::String,
+        Type::FixedArray(element, _) => {
+            if plain(element) {
+                Carry::Bytes
+            } else {
+                Carry::Never
+            }
+        }
+        Type::Optional(inner) => {
+            if plain(inner) {
+                Carry::Bytes
+            } else {
+                Carry::Never
+            }
+        }
+        Type::Tuple(elements) => {
+            if elements.iter().all(plain) {
+                Carry::Bytes
+            } else {
+                Carry::Never
+            }
+        }
+        Type::Record(fields) => {
+            if fields.iter().all(|(_, field)| plain(field)) {
+                Carry::Bytes
+            } else {
+                Carry::Never
+            }
+        }
+        Type::Custom(_) | Type::Generic { .. } | Type::Array(_) | Type::Function { .. } => {
+            Carry::Never
+        }
+    }
+}
+
+/// A field of an actor's instance
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StateField {
+    pub name: String,
+    /// Declared type, as written in the source
+    pub ty: String,
+    /// Byte offset from the instance pointer
+    pub offset: u32,
+    pub size: u32,
+    pub carry: Carry,
+}
+
+/// Fields of one actor's instance
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StateSchema {
+    pub actor: String,
+    pub fields: Vec<StateField>,
+}
+
+/// Schema of `actor`, given the offset and size of each of its fields in
+/// declaration order, as the code generator lays them out
+pub fn state_schema(actor: &Actor, layout: &[(u32, u32)]) -> StateSchema {
+    StateSchema {
+        actor: actor.name.clone(),
+        fields: actor
+            .fields
+            .iter()
+            .zip(layout)
+            .map(|(field, &(offset, size))| StateField {
+                name: field.name.clone(),
+                ty: field.field_type.to_string(),
+                offset,
+                size,
+                carry: carry(&field.field_type),
+            })
+            .collect(),
+    }
+}
+
+/// Contents of the `replica.state` section. Lines are self-contained, so the
+/// sections of linked modules concatenate.
+pub fn section(schemas: &[StateSchema]) -> String {
+    schemas
+        .iter()
+        .map(|schema| {
+            let fields: Vec<Value> = schema
+                .fields
+                .iter()
+                .map(|field| {
+                    json!({
+                        "name": field.name,
+                        "type": field.ty,
+                        "offset": field.offset,
+                        "size": field.size,
+                        "carry": field.carry.as_str(),
+                    })
+                })
+                .collect();
+            json!({ "actor": schema.actor, "fields": fields }).to_string() + "\n"
+        })
+        .collect()
+}
+
+/// Reads the state schemas recorded in a WebAssembly binary
+pub fn read_state_schemas(wasm: &[u8]) -> Result<Vec<StateSchema>, String> {
+    let mut schemas = Vec::new();
+    for payload in custom_sections(wasm, STATE_SECTION)? {
+        let text = std::str::from_utf8(payload)
+            .map_err(|_| format!("{} section is not UTF-8", STATE_SECTION))?;
+        for line in text.lines().filter(|line| !line.trim().is_empty()) {
+            let malformed = || format!("malformed {} entry: {}", STATE_SECTION, line);
+            let entry: Value = serde_json::from_str(line).map_err(|_| malformed())?;
+            let number = |value: &Value| {
+                value
+                    .as_u64()
+                    .and_then(|number| u32::try_from(number).ok())
+                    .ok_or_else(malformed)
+            };
+            let text = |value: &Value| value.as_str().map(str::to_string).ok_or_else(malformed);
+            let fields = entry["fields"]
+                .as_array()
+                .ok_or_else(malformed)?
+                .iter()
+                .map(|field| {
+                    Ok(StateField {
+                        name: text(&field["name"])?,
+                        ty: text(&field["type"])?,
+                        offset: number(&field["offset"])?,
+                        size: number(&field["size"])?,
+                        carry: field["carry"]
+                            .as_str()
+                            .and_then(Carry::parse)
+                            .ok_or_else(malformed)?,
+                    })
+                })
+                .collect::<Result<_, String>>()?;
+            schemas.push(StateSchema {
+                actor: text(&entry["actor"])?,
+                fields,
+            });
+        }
+    }
+    Ok(schemas)
+}
+
+/// Reasons a host would refuse to carry the state of `old` into `new`: an
+/// actor that is gone, a field whose type changed, or one kept by both that
+/// cannot be carried. Fields only one version declares are not a problem.
+pub fn reload_problems(old: &[StateSchema], new: &[StateSchema]) -> Vec<String> {
+    let mut problems = Vec::new();
+    for before in old {
+        let Some(after) = new.iter().find(|schema| schema.actor == before.actor) else {
+            problems.push(format!("actor {} was removed", before.actor));
+            continue;
+        };
+        for field in &before.fields {
+            let Some(kept) = after.fields.iter().find(|kept| kept.name == field.name) else {
+                continue;
+            };
+            if kept.ty != field.ty {
+                problems.push(format!(
+                    "{}.{} changed type from {} to {}",
+                    before.actor, field.name, field.ty, kept.ty
+                ));
+            } else if kept.carry == Carry::Never {
+                problems.push(format!(
+                    "{}.{} has type {}, whose values cannot be carried over",
+                    before.actor, field.name, field.ty
+                ));
+            }
+        }
+    }
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn actor(source: &str) -> Actor {
+        let tokens = crate::lexer::tokenize(source).unwrap();
+        crate::parser::Parser::new(tokens).parse_actor().unwrap()
+    }
+
+    #[test]
+    fn test_carry() {
+        let array = |element: Type| Type::Array(Box::new(element));
+        assert_eq!(carry(&Type::Float), Carry::Bytes);
+        assert_eq!(carry(&Type::String), Carry::String);
+        assert_eq!(carry(&array(Type::Int)), Carry::Never);
+        assert_eq!(
+            carry(&Type::FixedArray(Box::new(Type::Int), 4)),
+            Carry::Bytes
+        );
+        // 文字列を含む値は記述子ごとに作り直せないので引き継がない
+        assert_eq!(
+            carry(&Type::Tuple(vec![Type::Int, Type::String])),
+            Carry::Never
+        );
+        assert_eq!(
+            carry(&Type::Record(vec![
+                ("count".to_string(), Type::Int),
+                ("ok".to_string(), Type::Bool),
+            ])),
+            Carry::Bytes
+        );
+        assert_eq!(carry(&Type::Custom("Logger".to_string())), Carry::Never);
+    }
+
+    #[test]
+    fn test_section_lines() {
+        let field = |name: &str, ty: &str, offset, carry| StateField {
+            name: name.to_string(),
+            ty: ty.to_string(),
+            offset,
+            size: 4,
+            carry,
+        };
+        let schemas = [
+            StateSchema {
+                actor: "Counter".to_string(),
+                fields: vec![
+                    field("count", "Int", 0, Carry::Bytes),
+                    field("label", "String", 4, Carry::String),
+                ],
+            },
+            StateSchema {
+                actor: "Meter".to_string(),
+                fields: vec![],
+            },
+        ];
+        assert_eq!(
+            section(&schemas),
+            "{\"actor\":\"Counter\",\"fields\":[{\"carry\":\"bytes\",\"name\":\"count\",\"offset\":0,\"size\":4,\"type\":\"Int\"},{\"carry\":\"string\",\"name\":\"label\",\"offset\":4,\"size\":4,\"type\":\"String\"}]}\n\
+             {\"actor\":\"Meter\",\"fields\":[]}\n"
+        );
+    }
+
+    #[test]
+    fn test_section_round_trip() {
+        let counter = actor(
+            "actor Counter {
+    var count: Int
+    var label: String
+    var history: [Int]
+    var window: (Int, Bool)
+}",
+        );
+        let schema = state_schema(&counter, &[(0, 4), (4, 4), (8, 4), (12, 8)]);
+        let carries: Vec<Carry> = schema.fields.iter().map(|field| field.carry).collect();
+        assert_eq!(
+            carries,
+            [Carry::Bytes, Carry::String, Carry::Never, Carry::Bytes]
+        );
+
+        let text = section(std::slice::from_ref(&schema));
+        assert_eq!(text.lines().count(), 1);
+        assert!(text.contains("\"name\":\"window\""));
+        assert!(text.contains("\"type\":\"(Int, Bool)\""));
+
+        let mut body = vec![STATE_SECTION.len() as u8];
+        body.extend_from_slice(STATE_SECTION.as_bytes());
+        body.extend_from_slice(text.as_bytes());
+        let mut wasm = b"\0asm\x01\0\0\0".to_vec();
+        assert!((128..1 << 14).contains(&body.len()));
+        wasm.extend([0, body.len() as u8 | 0x80, (body.len() >> 7) as u8]);
+        wasm.extend(body);
+        assert_eq!(read_state_schemas(&wasm).unwrap(), vec![schema]);
+    }
+
+    #[test]
+    fn test_reload_problems() {
+        let schema = |source: &str| {
+            let actor = actor(source);
+            let layout: Vec<(u32, u32)> =
+                (0..actor.fields.len() as u32).map(|i| (i * 8, 8)).collect();
+            state_schema(&actor, &layout)
+        };
+        let old = [schema(
+            "actor Counter {
+    var count: Int
+    var history: [Int]
+    var label: String
+}",
+        )];
+
+        // 追加と削除は問題にならない
+        let new = [schema(
+            "actor Counter {
+    var label: String
+    var count: Int
+    var step: Int
+}",
+        )];
+        assert!(reload_problems(&old, &new).is_empty());
+
+        let new = [schema(
+            "actor Counter {
+    var count: Float
+    var history: [Int]
+}",
+        )];
+        assert_eq!(
+            reload_problems(&old, &new),
+            [
+                "Counter.count changed type from Int to Float",
+                "Counter.history has type [Int], whose values cannot be carried over",
+            ]
+        );
+
+        let new = [schema("actor Meter {\n    var count: Int\n}")];
+        assert_eq!(reload_problems(&old, &new), ["actor Counter was removed"]);
+    }
+}
@@ -0,0 +1,373 @@
+//! Project scaffolding for `replicac new`.
+//! Generates a manifest, a sample actor, a Node.js host harness for the chosen
+//! target and a test that runs the actor, so a new project builds and runs
+//! without reading the compiler source.
+
+use crate::manifest::MANIFEST_FILE;
+use replica_runtime::{ABI_HANDSHAKE_EXPORT, INIT_EXPORT, RUNTIME_ABI_VERSION};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// How the generated harness instantiates the module
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Host {
+    /// Plain `WebAssembly.instantiate` with only the actor's `env` imports
+    JavaScript,
+    /// Node's `node:wasi` implementation of WASI preview 1
+    Wasi,
+}
+
+impl Host {
+    fn for_triple(triple: &str) -> Self {
+        if triple.contains("-wasi") {
+            Host::Wasi
+        } else {
+            Host::JavaScript
+        }
+    }
+
+    /// Rust target the runtime library is built for
+    fn runtime_target(&self, triple: &str) -> String {
+        match self {
+            Host::Wasi => "wasm32-wasip1".to_string(),
+            Host::JavaScript => triple.to_string(),
+        }
+    }
+}
+
+/// Creates a project named after the last component of `root`, returning the
+/// files written. `root` must not exist or be an empty directory.
+pub fn create_project(root: &Path, triple: &str) -> Result<Vec<PathBuf>, String> {
+    let name = root
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| format!("Cannot derive a project name from {}", root.display()))?;
+    let files = project_files(name, triple)?;
+
+    if root.exists()
+        && fs::read_dir(root)
+            .map_err(|e| format!("Failed to read {}: {}", root.display(), e))?
+            .next()
+            .is_some()
+    {
+        return Err(format!(
+            "{} already exists and is not empty",
+            root.display()
+        ));
+    }
+
+    let mut written = Vec::with_capacity(files.len());
+    for (relative, contents) in files {
+        let path = root.join(relative);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .map_err(|e| format!("Failed to create {}: {}", parent.display(), e))?;
+        }
+        fs::write(&path, contents)
+            .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+        written.push(path);
+    }
+    Ok(written)
+}
+
+/// Relative paths and contents of the files making up a new project
+fn project_files(name: &str, triple: &str) -> Result<Vec<(PathBuf, String)>, String> {
+    let valid = name.starts_with(|c: char| c.is_ascii_alphabetic())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    if !valid {
+        return Err(format!(
+            "Invalid project name '{}': use letters, digits, '_' and '-', starting with a letter",
+            name
+        ));
+    }
+
+    let host = Host::for_triple(triple);
+    let actor = actor_name(name);
+    let source = format!("src/{}.replica", name);
+    let module = format!("build/{}.wasm", name);
+
+    Ok(vec![
+        (
+            PathBuf::from(MANIFEST_FILE),
+            format!(
+                "[package]\nname = \"{}\"\nversion = \"0.1.0\"\n\n[target]\ntriple = \"{}\"\n",
+                name, triple
+            ),
+        ),
+        (
+            PathBuf::from(&source),
+            format!(
+                "actor {} {{\n    var count: Int\n\n    func add(amount: Int) -> Int {{\n        return amount + 1\n    }}\n}}\n",
+                actor
+            ),
+        ),
+        (PathBuf::from("host/load.mjs"), loader(host, &module, &actor)),
+        (
+            PathBuf::from("host/run.mjs"),
+            "import { load } from \"./load.mjs\";\n\nconst actor = await load();\nconsole.log(`add(41) = ${actor.add(41)}`);\n".to_string(),
+        ),
+        (
+            PathBuf::from(format!("tests/{}.test.mjs", name)),
+            "import { test } from \"node:test\";\nimport assert from \"node:assert/strict\";\nimport { load } from \"../host/load.mjs\";\n\ntest(\"add increments its argument\", async () => {\n    const actor = await load();\n    assert.equal(actor.add(41), 42);\n});\n".to_string(),
+        ),
+        (
+            PathBuf::from("README.md"),
+            readme(name, triple, host, &source, &module),
+        ),
+        (PathBuf::from(".gitignore"), "/build\n/.replica\n".to_string()),
+    ])
+}
+
+/// `counter-service` becomes `CounterService`
+fn actor_name(name: &str) -> String {
+    name.split(['-', '_'])
+        .filter(|part| !part.is_empty())
+        .map(|part| {
+            let mut chars = part.chars();
+            chars
+                .next()
+                .map(|first| first.to_ascii_uppercase().to_string() + chars.as_str())
+                .unwrap_or_default()
+        })
+        .collect()
+}
+
+/// Module that instantiates the compiled actor, creates an instance of `actor`
+/// and returns its methods
+fn loader(host: Host, module: &str, actor: &str) -> String {
+    let (imports, instantiate, initialize) = match host {
+        Host::JavaScript => (
+            "",
+            "    const { instance } = await WebAssembly.instantiate(bytes, { env: imports(host) });\n",
+            "",
+        ),
+        Host::Wasi => (
+            "import { WASI } from \"node:wasi\";\n",
+            "    const wasi = new WASI({ version: \"preview1\", args: [], env: {} });\n    const { instance } = await WebAssembly.instantiate(bytes, {\n        ...wasi.getImportObject(),\n        env: imports(host),\n    });\n",
+            "    wasi.initialize(instance);\n",
+        ),
+    };
+    format!(
+        "import {{ readFile }} from \"node:fs/promises\";\n{}\n// Runtime ABI version this loader was generated for\nconst ABI_VERSION = {};\n\n// Whether the module is built with `+reference-types` in the [target] features\n// of {}: extern values then cross as externref and callbacks as funcref\n// instead of as handles and function table indices\nconst REFERENCE_TYPES = false;\n\n// Instantiates the compiled actor, creates an instance of {} and returns its\n// methods bound to it, with the Host converting extern values and callbacks as\n// `host`. `imports(host)` returns the functions implementing its `extern func`s\nexport async function load(imports = () => ({{}})) {{\n    const bytes = await readFile(new URL(\"../{}\", import.meta.url));\n    const host = new Host(REFERENCE_TYPES);\n{}    checkAbi(instance.exports);\n{}    instance.exports.{}();\n    return host.attach(instance.exports, \"{}\");\n}}\n{}{}",
+        imports,
+        RUNTIME_ABI_VERSION,
+        MANIFEST_FILE,
+        actor,
+        module,
+        instantiate,
+        initialize,
+        INIT_EXPORT,
+        actor,
+        HOST,
+        ABI_CHECK.replace("{handshake}", ABI_HANDSHAKE_EXPORT)
+    )
+}
+
+/// Conversions of the values the ABI passes as handles or table indices unless
+/// the module is built with reference types
+const HOST: &str = r#"
+// Converts extern values and callbacks between JavaScript and the module
+export class Host {
+    constructor(referenceTypes) {
+        this.referenceTypes = referenceTypes;
+        // Objects behind the i32 handles of extern values; handle 0 is never
+        // given out
+        this.objects = [undefined];
+        this.free = [];
+        this.exports = null;
+        this.self = 0;
+    }
+
+    // Extern value passed to the module for `object`
+    ref(object) {
+        if (this.referenceTypes) {
+            return object;
+        }
+        const handle = this.free.length > 0 ? this.free.pop() : this.objects.length;
+        this.objects[handle] = object;
+        return handle;
+    }
+
+    // Object behind an extern value received from the module
+    deref(value) {
+        return this.referenceTypes ? value : this.objects[value];
+    }
+
+    // Frees the handle of an extern value the module no longer uses
+    release(value) {
+        if (!this.referenceTypes && value !== 0 && this.objects[value] !== undefined) {
+            this.objects[value] = undefined;
+            this.free.push(value);
+        }
+    }
+
+    // Function calling the method received as a callback; methods take the
+    // instance first, so it is passed like the module's own calls do
+    callback(value) {
+        const method = this.referenceTypes
+            ? value
+            : this.exports.__indirect_function_table.get(value);
+        if (typeof method !== "function") {
+            throw new Error(`callback ${value} does not refer to a function`);
+        }
+        return (...args) => method(this.self, ...args);
+    }
+
+    // Creates an instance of `actor` and returns the module's methods bound to
+    // it. Methods are exported as `<Actor>.<method>`; entry points such as
+    // `.post`, the actor's own `new`, `drain` and `deliver` and runtime
+    // exports are left out
+    attach(exports, actor) {
+        this.exports = exports;
+        this.self = exports[`${actor}.new`]();
+        if (!this.self) {
+            throw new Error(`${actor}.new could not allocate the instance`);
+        }
+        const prefix = `${actor}.`;
+        const methods = { host: this };
+        for (const [name, value] of Object.entries(exports)) {
+            const method = name.slice(prefix.length);
+            if (
+                typeof value === "function" &&
+                name.startsWith(prefix) &&
+                !method.includes(".") &&
+                !["new", "drain", "deliver"].includes(method)
+            ) {
+                methods[method] = (...args) => value(this.self, ...args);
+            }
+        }
+        return methods;
+    }
+}
+"#;
+
+/// Handshake run before the module is initialized, so that a module or runtime
+/// built for another ABI fails to load instead of corrupting memory
+const ABI_CHECK: &str = r#"
+// Allocators a module may be compiled with, by their number in the handshake
+const ALLOCATORS = ["bump", "freelist", "external"];
+
+// Fails unless the module and the runtime linked into it both use ABI_VERSION
+// and the module's allocator is known
+function checkAbi(exports) {
+    const handshake = exports.{handshake};
+    if (typeof handshake !== "function") {
+        throw new Error("module does not export `{handshake}`; it was built by an older replicac, recompile it");
+    }
+    const versions = handshake() >>> 0;
+    const allocator = versions >>> 24;
+    const module = (versions >>> 16) & 0xff;
+    const runtime = versions & 0xffff;
+    if (module !== ABI_VERSION) {
+        throw new Error(`module was compiled for runtime ABI v${module}, but this loader expects v${ABI_VERSION}; regenerate it with the replicac that built the module`);
+    }
+    if (runtime !== module) {
+        throw new Error(`module was compiled for runtime ABI v${module}, but is linked with a runtime implementing v${runtime}; rebuild libreplica_runtime.a from the same release as replicac`);
+    }
+    if (allocator >= ALLOCATORS.length) {
+        throw new Error(`module was compiled with allocator ${allocator}, which this loader does not know; regenerate it with the replicac that built the module`);
+    }
+}
+"#;
+
+fn readme(name: &str, triple: &str, host: Host, source: &str, module: &str) -> String {
+    let runtime_target = host.runtime_target(triple);
+    format!(
+        "# {name}\n\n\
+         A Replica project targeting `{triple}`.\n\n\
+         ## Build\n\n\
+         Build the runtime library for the same target, then compile the actor; \
+         `wasm-ld` links the two into the module:\n\n\
+         ```sh\n\
+         mkdir -p build\n\
+         cargo build -p replica-runtime --release --target {runtime_target}\n\
+         replicac build --runtime-lib path/to/target/{runtime_target}/release/libreplica_runtime.a \\\n    \
+         -o {module} {source}\n\
+         ```\n\n\
+         ## Run\n\n\
+         ```sh\n\
+         node host/run.mjs\n\
+         node --test tests/\n\
+         ```\n\n\
+         ## Host functions\n\n\
+         `load` takes a function receiving the `Host` and returning the \
+         implementations of the actor's `extern func`s. Convert extern values \
+         with `host.ref` and `host.deref`, and wrap function values received as \
+         callbacks with `host.callback`. Set `REFERENCE_TYPES` in \
+         `host/load.mjs` when building with `+reference-types`.\n"
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codegen::CodeGenOptions;
+    use crate::manifest::Manifest;
+
+    fn file<'a>(files: &'a [(PathBuf, String)], path: &str) -> &'a str {
+        files
+            .iter()
+            .find(|(relative, _)| relative == Path::new(path))
+            .map(|(_, contents)| contents.as_str())
+            .unwrap_or_else(|| panic!("{} was not generated", path))
+    }
+
+    #[test]
+    fn test_project_files() {
+        let files = project_files("counter-service", "wasm32-wasi").unwrap();
+
+        let mut options = CodeGenOptions::default();
+        Manifest::parse(file(&files, MANIFEST_FILE))
+            .unwrap()
+            .apply(&mut options)
+            .unwrap();
+        assert_eq!(options.target_triple, "wasm32-wasi");
+
+        assert!(file(&files, "src/counter-service.replica").starts_with("actor CounterService {"));
+        let load = file(&files, "host/load.mjs");
+        assert!(load.contains("node:wasi"));
+        assert!(load.contains(&format!("const ABI_VERSION = {};", RUNTIME_ABI_VERSION)));
+        // 初期化より前にハンドシェイクを確認する
+        assert!(load.find("checkAbi(instance.exports)") < load.find("wasi.initialize(instance)"));
+        assert!(
+            load.find("wasi.initialize(instance)")
+                < load.find("instance.exports.__replica_init();")
+        );
+        assert!(load.contains("exports.__replica_abi_handshake;"));
+        assert!(load.contains("const allocator = versions >>> 24;"));
+        // メソッドは先頭に self を取るので、生成したインスタンスに束縛して返す
+        assert!(load.contains("return host.attach(instance.exports, \"CounterService\");"));
+        assert!(load.contains("this.self = exports[`${actor}.new`]();"));
+        assert!(load.contains("methods[method] = (...args) => value(this.self, ...args);"));
+        // 参照型なしではハンドルと関数テーブルの添字でやり取りする
+        assert!(load.contains("const REFERENCE_TYPES = false;"));
+        assert!(load.contains("this.exports.__indirect_function_table.get(value)"));
+        assert!(load.contains("...wasi.getImportObject(),\n        env: imports(host),"));
+        assert!(file(&files, "tests/counter-service.test.mjs").contains("actor.add(41)"));
+        assert!(file(&files, "README.md").contains("--target wasm32-wasip1"));
+
+        let files = project_files("hello", "wasm32-unknown-unknown").unwrap();
+        let load = file(&files, "host/load.mjs");
+        assert!(!load.contains("node:wasi"));
+        assert!(load.contains("WebAssembly.instantiate(bytes, { env: imports(host) })"));
+
+        assert!(project_files("1st", "wasm32-unknown-unknown").is_err());
+        assert!(project_files("my app", "wasm32-unknown-unknown").is_err());
+    }
+
+    #[test]
+    fn test_create_project() {
+        let root = std::env::temp_dir().join(format!("replica-new-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+
+        let written = create_project(&root, "wasm32-unknown-unknown").unwrap();
+        assert!(written.iter().all(|path| path.is_file()));
+        assert!(root.join("host/run.mjs").is_file());
+
+        // 既存のプロジェクトは上書きしない
+        assert!(create_project(&root, "wasm32-unknown-unknown").is_err());
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -1,7 +1,27 @@
 use crate::ast::*;
-use std::collections::HashMap;
+use crate::capability;
+use crate::ice;
+use crate::layout;
+use crate::ownership::OwnershipChecker;
+use crate::proto;
+use crate::typestate;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
 use thiserror::Error;
 
+/// Members the code generator defines next to the methods as `<Actor>.<member>`
+/// symbols; a method or constant of the same name would share its symbol
+const GENERATED_MEMBERS: &[&str] = &[
+    "new",
+    "init",
+    "deinit",
+    "register",
+    "registry_name",
+    "dispatch",
+    "drain",
+    "deliver",
+];
+
 #[derive(Error, Debug)]
 pub enum SemanticError {
     #[error("Type error: {0}")]
@@ -16,12 +36,166 @@ pub enum SemanticError {
     UndefinedVariable(String),
     #[error("Invalid operation: {0}")]
     InvalidOperation(String),
+    #[error("Layout error: {0}")]
+    LayoutError(String),
+    /// Calls breaking the actor's `@protocol`; `call` is the offending call,
+    /// as the callee and its index among the calls to it in the method
+    #[error("Protocol error: {message}")]
+    ProtocolError {
+        message: String,
+        call: Option<(String, usize)>,
+    },
+    /// `import` of a module that was not compiled with the file
+    #[error("Unresolved import: no module {0} was found; it must be the actor of {0}.replica next to this file")]
+    UnresolvedImport(String),
+}
+
+impl SemanticError {
+    /// Name of the variant, used in diagnostic codes
+    pub fn code(&self) -> &'static str {
+        match self {
+            SemanticError::TypeError(_) => "TypeError",
+            SemanticError::OwnershipError(_) => "OwnershipError",
+            SemanticError::InvalidActorOperation(_) => "InvalidActorOperation",
+            SemanticError::AsyncError(_) => "AsyncError",
+            SemanticError::UndefinedVariable(_) => "UndefinedVariable",
+            SemanticError::InvalidOperation(_) => "InvalidOperation",
+            SemanticError::LayoutError(_) => "LayoutError",
+            SemanticError::ProtocolError { .. } => "ProtocolError",
+            SemanticError::UnresolvedImport(_) => "UnresolvedImport",
+        }
+    }
+}
+
+/// How many times the paths through `init` reaching a statement have assigned
+/// a `let` field: at least `min` and at most `max`
+#[derive(Debug, Clone, Copy, Default)]
+struct Assignments {
+    min: u32,
+    max: u32,
+}
+
+/// Declarations files share through `import`, by module name
+#[derive(Debug, Clone, Default)]
+pub struct SymbolTable {
+    modules: HashMap<String, ModuleSymbols>,
+}
+
+/// What a module makes visible to the files importing it: its actor, which
+/// they can hold references to, its extern types, structs, enums and topics
+#[derive(Debug, Clone, PartialEq)]
+pub struct ModuleSymbols {
+    pub actor: String,
+    pub extern_types: Vec<String>,
+    pub structs: Vec<Struct>,
+    pub enums: Vec<Enum>,
+    pub topics: Vec<Topic>,
+    /// Modules the module imports in turn
+    pub imports: Vec<String>,
+    /// Levels of imports below the module, which is initialized after all of
+    /// them: 0 when it imports nothing
+    pub init_depth: u32,
+}
+
+impl SymbolTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records the declarations of `actor` under the module name `module`. The
+    /// modules it imports are declared first, so that its place in the
+    /// initialization order is known.
+    pub fn declare(&mut self, module: impl Into<String>, actor: &Actor) {
+        let extern_types = actor
+            .externs
+            .iter()
+            .filter_map(|item| match item {
+                Extern::Type(name) => Some(name.clone()),
+                Extern::Function(_) => None,
+            })
+            .collect();
+        let init_depth = actor
+            .imports
+            .iter()
+            .filter_map(|import| self.modules.get(import))
+            .map(|symbols| symbols.init_depth + 1)
+            .max()
+            .unwrap_or(0);
+        self.modules.insert(
+            module.into(),
+            ModuleSymbols {
+                actor: actor.name.clone(),
+                extern_types,
+                structs: actor.structs.clone(),
+                enums: actor.enums.clone(),
+                topics: actor.topics.clone(),
+                imports: actor.imports.clone(),
+                init_depth,
+            },
+        );
+    }
+
+    /// Chain of modules from `module` through their imports back to the one
+    /// defining `actor`, e.g. `[Peer, Node]`, or `None` if there is none
+    pub fn import_cycle(&self, module: &str, actor: &str) -> Option<Vec<String>> {
+        let mut path = vec![module.to_string()];
+        let mut visited = HashSet::new();
+        self.find_importer(&mut path, &mut visited, actor)
+            .then_some(path)
+    }
+
+    fn find_importer(
+        &self,
+        path: &mut Vec<String>,
+        visited: &mut HashSet<String>,
+        actor: &str,
+    ) -> bool {
+        let module = path.last().cloned().unwrap_or_default();
+        if !visited.insert(module.clone()) {
+            return false;
+        }
+        let Some(symbols) = self.modules.get(&module) else {
+            return false;
+        };
+        if symbols.actor == actor {
+            return true;
+        }
+        for import in &symbols.imports {
+            path.push(import.clone());
+            if self.find_importer(path, visited, actor) {
+                return true;
+            }
+            path.pop();
+        }
+        false
+    }
+
+    pub fn module(&self, name: &str) -> Option<&ModuleSymbols> {
+        self.modules.get(name)
+    }
 }
 
 pub struct SemanticAnalyzer {
     type_environment: HashMap<String, Type>,
     ownership_tracker: HashMap<String, OwnershipType>,
-    current_scope: Vec<HashMap<String, Type>>, // スコープスタック
+    // スコープスタック。match 式の腕は式の解析中に束縛を積む
+    current_scope: RefCell<Vec<HashMap<String, Type>>>,
+    mutable_bindings: RefCell<Vec<HashSet<String>>>, // 各スコープで代入できる束縛
+    methods: HashMap<String, Method>,
+    fields: HashMap<String, Field>,
+    constants: HashMap<String, ConstantTable>,
+    loop_labels: Vec<Option<String>>, // 囲んでいるループのラベル
+    extern_types: HashSet<String>,
+    extern_functions: HashSet<String>,
+    threads: bool, // アクターがワーカー間で実行されるか
+    config_keys: HashSet<String>,
+    symbols: SymbolTable,
+    topics: HashMap<String, Type>, // 宣言済みとインポートしたトピックの値の型
+    structs: HashMap<String, Struct>, // 宣言済みとインポートした構造体
+    enums: HashMap<String, Enum>,  // 宣言済みとインポートした列挙型
+    current_method: Option<String>, // analyze_actor が失敗したメソッド（deinit は "deinit"）
+    in_async: bool,                // 解析中のメソッドが await できるか
+    in_init: bool,                 // init の中では let フィールドにも代入できる
 }
 
 impl SemanticAnalyzer {
@@ -29,30 +203,256 @@ impl SemanticAnalyzer {
         SemanticAnalyzer {
             type_environment: HashMap::new(),
             ownership_tracker: HashMap::new(),
-            current_scope: vec![HashMap::new()],
+            current_scope: RefCell::new(vec![HashMap::new()]),
+            mutable_bindings: RefCell::new(vec![HashSet::new()]),
+            methods: HashMap::new(),
+            fields: HashMap::new(),
+            constants: HashMap::new(),
+            loop_labels: Vec::new(),
+            extern_types: HashSet::new(),
+            extern_functions: HashSet::new(),
+            threads: false,
+            config_keys: HashSet::new(),
+            symbols: SymbolTable::new(),
+            topics: HashMap::new(),
+            structs: HashMap::new(),
+            enums: HashMap::new(),
+            current_method: None,
+            in_async: false,
+            in_init: false,
         }
     }
 
+    /// Enables the rules for modules built with the `threads` WASM feature,
+    /// where `Shared` fields are the only state visible to several workers
+    pub fn set_threads(&mut self, enabled: bool) {
+        self.threads = enabled;
+    }
+
+    /// Declares the keys of the manifest's `[config]` section, the only keys
+    /// `config("KEY")` can read
+    pub fn set_config_keys(&mut self, keys: impl IntoIterator<Item = String>) {
+        self.config_keys = keys.into_iter().collect();
+    }
+
+    /// Provides the modules the analyzed actor can `import`
+    pub fn set_symbols(&mut self, symbols: SymbolTable) {
+        self.symbols = symbols;
+    }
+
+    /// Method whose body `analyze_actor` failed in, or `"deinit"`; `None`
+    /// for errors outside method bodies
+    pub fn current_method(&self) -> Option<&str> {
+        self.current_method.as_deref()
+    }
+
     pub fn analyze_actor(&mut self, actor: &Actor) -> Result<(), SemanticError> {
+        match self.analyze_actor_all(actor).into_iter().next() {
+            Some((error, method)) => {
+                self.current_method = method;
+                Err(error)
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Analyzes the actor like [`SemanticAnalyzer::analyze_actor`], but keeps
+    /// checking the other methods after one of them fails. Returns every
+    /// error with the method it was found in, as for `current_method`; an
+    /// error in the declarations stops the analysis.
+    pub fn analyze_actor_all(&mut self, actor: &Actor) -> Vec<(SemanticError, Option<String>)> {
+        if let Err(error) = self.analyze_declarations(actor) {
+            return vec![(error, None)];
+        }
+
+        let mut errors = Vec::new();
+
+        // メソッドの解析（失敗しても残りのメソッドを検査する）
+        for method in &actor.methods {
+            let _method = ice::enter_method(&method.name);
+            if let Err(error) =
+                self.isolated(|analyzer| analyzer.analyze_method(method, &actor.actor_type))
+            {
+                errors.push((error, Some(method.name.clone())));
+            }
+        }
+
+        // イニシャライザの解析
+        if let Some(init) = &actor.init {
+            if let Err(error) = self.isolated(|analyzer| analyzer.analyze_init(init, actor)) {
+                errors.push((error, Some("init".to_string())));
+            }
+        }
+
+        // デイニシャライザの解析
+        if let Some(deinit) = &actor.deinit {
+            if let Err(error) = self.isolated(|analyzer| analyzer.analyze_deinit(deinit)) {
+                errors.push((error, Some("deinit".to_string())));
+            }
+        }
+
+        // 呼び出し順序のプロトコルを検査
+        if let Some(protocol) = &actor.protocol {
+            errors.extend(self.check_protocol(actor, protocol));
+        }
+
+        self.current_method = None;
+        errors
+    }
+
+    /// Checks and registers everything the method bodies rely on: actor
+    /// rules, externs, fields, layout and method signatures
+    fn analyze_declarations(&mut self, actor: &Actor) -> Result<(), SemanticError> {
+        // 型引数は構文だけが先に導入されている
+        Self::check_no_generic_types(actor)?;
+
         // アクター固有のルールをチェック
         match actor.actor_type {
             ActorType::Single => self.check_single_actor_constraints(actor)?,
             ActorType::Distributed => self.check_distributed_actor_constraints(actor)?,
         }
 
+        // ホスト提供の型と関数を登録
+        self.declare_externs(&actor.externs)?;
+
+        // 他のファイルのアクターと extern 型を取り込む
+        self.declare_imports(actor)?;
+
+        // トピックの値やフィールドに使えるよう、構造体と列挙型を先に登録する
+        self.declare_structs(actor)?;
+        self.check_enums(actor)?;
+
+        // このファイルとインポートしたモジュールのトピックを登録
+        self.declare_topics(actor)?;
+
+        // プールのルーターが転送できるメソッドを持つか
+        Self::check_pools(actor)?;
+
+        // シャードキーでノードを選べるメソッドか
+        Self::check_shard_keys(actor)?;
+
+        // 再試行と冪等性の指定がリモート呼び出しに使えるか
+        Self::check_remote_policies(actor)?;
+
+        // API の版の指定が矛盾していないか
+        Self::check_api_versions(actor)?;
+
         // フィールドの解析
         for field in &actor.fields {
             self.analyze_field(field)?;
         }
 
-        // メソッドの解析
+        // レイアウト属性の検証
+        layout::compute(&actor.fields, &actor.layout).map_err(SemanticError::LayoutError)?;
+
+        // 定数表の解析
+        for constant in &actor.constants {
+            self.declare_constant(constant, actor)?;
+        }
+
+        // メソッドと定数は `<Actor>.<名前>` のシンボルになる
+        let constants: HashSet<&str> = actor
+            .constants
+            .iter()
+            .map(|constant| constant.name.as_str())
+            .collect();
+        for method in &actor.methods {
+            let member = method.name.rsplit('.').next().unwrap_or(&method.name);
+            if constants.contains(member) {
+                return Err(SemanticError::InvalidOperation(format!(
+                    "Method {} conflicts with the constant of the same name",
+                    member
+                )));
+            }
+        }
+        let members = actor
+            .methods
+            .iter()
+            .map(|method| method.name.rsplit('.').next().unwrap_or(&method.name))
+            .chain(constants);
+        for member in members {
+            if GENERATED_MEMBERS.contains(&member) {
+                return Err(SemanticError::InvalidOperation(format!(
+                    "{} conflicts with the generated symbol {}.{}",
+                    member, actor.name, member
+                )));
+            }
+        }
+
+        // メソッドのシグネチャを登録
         for method in &actor.methods {
-            self.analyze_method(method, &actor.actor_type)?;
+            if self.extern_functions.contains(&method.name) {
+                return Err(SemanticError::InvalidOperation(format!(
+                    "Method {} conflicts with an extern function of the same name",
+                    method.name
+                )));
+            }
+            if self
+                .methods
+                .insert(method.name.clone(), method.clone())
+                .is_some()
+            {
+                return Err(SemanticError::InvalidOperation(format!(
+                    "Method {} is declared more than once",
+                    method.name
+                )));
+            }
         }
 
         Ok(())
     }
 
+    /// Runs `analyze`, dropping any scopes and loop labels it left behind by
+    /// failing halfway through a body, and leaving the method's async context
+    fn isolated(
+        &mut self,
+        analyze: impl FnOnce(&mut Self) -> Result<(), SemanticError>,
+    ) -> Result<(), SemanticError> {
+        let (scopes, labels) = (self.current_scope.get_mut().len(), self.loop_labels.len());
+        let result = analyze(self);
+        self.current_scope.get_mut().truncate(scopes);
+        self.mutable_bindings.get_mut().truncate(scopes);
+        self.loop_labels.truncate(labels);
+        self.in_async = false;
+        self.in_init = false;
+        result
+    }
+
+    /// Checks that the protocol names methods of the actor, and that every
+    /// method body and the deinit call them in the order it allows
+    fn check_protocol(
+        &self,
+        actor: &Actor,
+        protocol: &Protocol,
+    ) -> Vec<(SemanticError, Option<String>)> {
+        if let Some((name, _)) = protocol
+            .steps
+            .iter()
+            .find(|(name, _)| !self.methods.contains_key(name))
+        {
+            let error = SemanticError::ProtocolError {
+                message: format!("Protocol names unknown method {}", name),
+                call: None,
+            };
+            return vec![(error, None)];
+        }
+
+        let bodies = actor
+            .methods
+            .iter()
+            .filter_map(|method| Some((method.name.as_str(), method.body.as_ref()?)));
+        let init = actor.init.as_ref().map(|init| ("init", &init.body));
+        let deinit = actor.deinit.as_ref().map(|deinit| ("deinit", &deinit.body));
+        bodies
+            .chain(init)
+            .chain(deinit)
+            .filter_map(|(method, body)| {
+                let error = typestate::check(protocol, method, body).err()?;
+                Some((error, Some(method.to_string())))
+            })
+            .collect()
+    }
+
     fn check_single_actor_constraints(&self, actor: &Actor) -> Result<(), SemanticError> {
         // 分散機能を使用していないことを確認
         for method in &actor.methods {
@@ -77,195 +477,2915 @@ impl SemanticAnalyzer {
         Ok(())
     }
 
-    fn analyze_field(&mut self, field: &Field) -> Result<(), SemanticError> {
-        // フィールドの型を登録
-        self.type_environment
-            .insert(field.name.clone(), field.field_type.clone());
-
-        // 所有権ルールのチェック
-        match field.ownership {
-            OwnershipType::Moved => {
-                if field.is_mutable {
-                    return Err(SemanticError::OwnershipError(
-                        "Moved fields cannot be mutable".to_string(),
-                    ));
-                }
+    /// Makes the actors, extern types, structs and enums of the imported modules
+    /// known as types. An extern type declared by several modules is the same
+    /// host type; a struct or enum declared by several modules must have the
+    /// same fields or cases.
+    fn declare_imports(&mut self, actor: &Actor) -> Result<(), SemanticError> {
+        for (index, module) in actor.imports.iter().enumerate() {
+            if actor.imports[..index].contains(module) {
+                return Err(SemanticError::InvalidOperation(format!(
+                    "Module {} is imported more than once",
+                    module
+                )));
             }
-            OwnershipType::Shared => {
-                if !field.is_mutable {
-                    return Err(SemanticError::OwnershipError(
-                        "Shared fields must be mutable".to_string(),
-                    ));
-                }
+            let symbols = self
+                .symbols
+                .module(module)
+                .cloned()
+                .ok_or_else(|| SemanticError::UnresolvedImport(module.clone()))?;
+            if symbols.actor == actor.name {
+                return Err(SemanticError::InvalidOperation(format!(
+                    "Actor {} cannot import the module {} defining itself",
+                    actor.name, module
+                )));
             }
-            _ => {}
-        }
-
-        Ok(())
-    }
-
-    fn analyze_expression(&self, expr: &Expression) -> Result<Type, SemanticError> {
-        match expr {
-            Expression::BinaryOp {
-                left,
-                operator,
-                right,
-            } => {
-                let left_type = self.analyze_expression(left)?;
-                let right_type = self.analyze_expression(right)?;
-
-                match operator {
-                    Operator::Add | Operator::Subtract | Operator::Multiply | Operator::Divide => {
-                        // 数値演算の型チェック
-                        match (&left_type, &right_type) {
-                            (Type::Int, Type::Int) => Ok(Type::Int),
-                            (Type::Float, Type::Float) => Ok(Type::Float),
-                            _ => Err(SemanticError::TypeError(format!(
-                                "Invalid operand types for arithmetic operation: {:?} and {:?}",
-                                left_type, right_type
-                            ))),
-                        }
-                    }
-                }
+            // モジュールはインポート先の後に初期化するので循環は順序が決まらない
+            if let Some(cycle) = self.symbols.import_cycle(module, &actor.name) {
+                return Err(SemanticError::InvalidOperation(format!(
+                    "Import cycle {} -> {}: a module is initialized after the modules it imports",
+                    actor.name,
+                    cycle.join(" -> ")
+                )));
             }
-            Expression::Literal(value) => match value {
-                LiteralValue::Int(_) => Ok(Type::Int),
-                LiteralValue::Float(_) => Ok(Type::Float),
-                LiteralValue::String(_) => Ok(Type::String),
-                LiteralValue::Bool(_) => Ok(Type::Bool),
-            },
-            Expression::Variable(name) => {
-                // 変数の型を現在のスコープから探す
-                for scope in self.current_scope.iter().rev() {
-                    if let Some(var_type) = scope.get(name) {
-                        return Ok(var_type.clone());
+            // インポートしたアクターの値はインスタンスへの参照
+            self.type_environment
+                .insert(symbols.actor.clone(), Type::Custom(symbols.actor));
+            for name in symbols.extern_types {
+                self.type_environment
+                    .insert(name.clone(), Type::Custom(name.clone()));
+                self.extern_types.insert(name);
+            }
+            for declaration in symbols.structs {
+                match self.structs.get(&declaration.name) {
+                    Some(known) if known.fields != declaration.fields => {
+                        return Err(SemanticError::TypeError(format!(
+                            "Struct {} is imported with different fields from several modules",
+                            declaration.name
+                        )))
+                    }
+                    Some(_) => {}
+                    None => {
+                        self.type_environment.insert(
+                            declaration.name.clone(),
+                            Type::Custom(declaration.name.clone()),
+                        );
+                        self.structs.insert(declaration.name.clone(), declaration);
                     }
                 }
-                Err(SemanticError::UndefinedVariable(name.clone()))
             }
-        }
-    }
-
-    fn analyze_statement(
-        &mut self,
-        stmt: &Statement,
-        expected_return_type: &Option<Type>,
-    ) -> Result<(), SemanticError> {
-        match stmt {
-            Statement::Return(expr) => {
-                let expr_type = self.analyze_expression(expr)?;
-                if let Some(expected) = expected_return_type {
-                    if !self.check_type_compatibility(expected, &expr_type) {
+            for declaration in symbols.enums {
+                match self.enums.get(&declaration.name) {
+                    Some(known) if known.cases != declaration.cases => {
                         return Err(SemanticError::TypeError(format!(
-                            "Return type mismatch: expected {:?}, found {:?}",
-                            expected, expr_type
-                        )));
+                            "Enum {} is imported with different cases from several modules",
+                            declaration.name
+                        )))
+                    }
+                    Some(_) => {}
+                    None => {
+                        self.type_environment.insert(
+                            declaration.name.clone(),
+                            Type::Custom(declaration.name.clone()),
+                        );
+                        self.enums.insert(declaration.name.clone(), declaration);
                     }
                 }
-                Ok(())
-            }
-            Statement::Expression(expr) => {
-                self.analyze_expression(expr)?;
-                Ok(())
             }
         }
+        Ok(())
     }
 
-    fn analyze_method(
-        &mut self,
-        method: &Method,
-        actor_type: &ActorType,
-    ) -> Result<(), SemanticError> {
-        // 新しいスコープを作成
-        self.current_scope.push(HashMap::new());
-
-        // パラメータをスコープに追加
-        for param in &method.params {
-            self.current_scope
-                .last_mut()
-                .unwrap()
-                .insert(param.name.clone(), param.param_type.clone());
+    /// Registers the `struct` and `enum` declarations of the file as types and
+    /// checks the structs. Their fields hold plain data, structs or enums,
+    /// without ownership modifiers, so values can be copied byte for byte, e.g.
+    /// into messages; a struct cannot contain itself.
+    fn declare_structs(&mut self, actor: &Actor) -> Result<(), SemanticError> {
+        let names = actor
+            .structs
+            .iter()
+            .map(|declaration| &declaration.name)
+            .chain(actor.enums.iter().map(|declaration| &declaration.name));
+        for name in names {
+            if *name == actor.name || self.type_environment.contains_key(name) {
+                return Err(SemanticError::InvalidOperation(format!(
+                    "{} is declared more than once",
+                    name
+                )));
+            }
+            self.type_environment
+                .insert(name.clone(), Type::Custom(name.clone()));
         }
-
-        // async/sequentialのチェック
-        if method.is_sequential && !method.is_async {
-            return Err(SemanticError::AsyncError(
-                "Sequential methods must be async".to_string(),
-            ));
+        for declaration in &actor.structs {
+            self.structs
+                .insert(declaration.name.clone(), declaration.clone());
+        }
+        for declaration in &actor.enums {
+            self.enums
+                .insert(declaration.name.clone(), declaration.clone());
         }
 
-        // immediateイニシャライザのチェック
-        if method.is_immediate {
-            if method.name != "init" {
-                return Err(SemanticError::AsyncError(
-                    "Only init method can be immediate".to_string(),
-                ));
+        for declaration in &actor.structs {
+            if declaration.fields.is_empty() {
+                return Err(SemanticError::TypeError(format!(
+                    "Struct {} has no fields",
+                    declaration.name
+                )));
             }
-
-            if matches!(actor_type, ActorType::Distributed) {
-                return Err(SemanticError::AsyncError(
-                    "Distributed actors cannot have immediate init".to_string(),
-                ));
+            for (index, field) in declaration.fields.iter().enumerate() {
+                if declaration.fields[..index]
+                    .iter()
+                    .any(|other| other.name == field.name)
+                {
+                    return Err(SemanticError::TypeError(format!(
+                        "Struct {} declares the field {} more than once",
+                        declaration.name, field.name
+                    )));
+                }
+                if !matches!(field.ownership, OwnershipType::Owned) {
+                    return Err(SemanticError::OwnershipError(format!(
+                        "Field {} of struct {} cannot have an ownership modifier",
+                        field.name, declaration.name
+                    )));
+                }
+                if !self.is_thread_safe(&field.field_type) {
+                    return Err(SemanticError::TypeError(format!(
+                        "Field {} of struct {} must hold plain data (Int, Float, Bool, structs, enums or fixed-size aggregates of them), found {:?}",
+                        field.name, declaration.name, field.field_type
+                    )));
+                }
             }
+            self.check_not_recursive(&declaration.name)?;
         }
+        Ok(())
+    }
 
-        // メソッドボディの解析
-        if let Some(body) = &method.body {
-            for statement in &body.statements {
-                self.analyze_statement(statement, &method.return_type)?;
+    /// Checks the `enum` declarations of the file, which `declare_structs`
+    /// registered. Like struct fields, associated values hold plain data, so a
+    /// value is a discriminant and the bytes of its case's values; an enum
+    /// cannot contain itself.
+    fn check_enums(&self, actor: &Actor) -> Result<(), SemanticError> {
+        for declaration in &actor.enums {
+            if declaration.cases.is_empty() {
+                return Err(SemanticError::TypeError(format!(
+                    "Enum {} has no cases",
+                    declaration.name
+                )));
             }
+            for (index, case) in declaration.cases.iter().enumerate() {
+                if declaration.cases[..index]
+                    .iter()
+                    .any(|other| other.name == case.name)
+                {
+                    return Err(SemanticError::TypeError(format!(
+                        "Enum {} declares the case {} more than once",
+                        declaration.name, case.name
+                    )));
+                }
+                if let Some(value) = case.values.iter().find(|value| !self.is_thread_safe(value)) {
+                    return Err(SemanticError::TypeError(format!(
+                        "Case {} of enum {} must hold plain data (Int, Float, Bool, structs, enums or fixed-size aggregates of them), found {:?}",
+                        case.name, declaration.name, value
+                    )));
+                }
+            }
+            self.check_not_recursive(&declaration.name)?;
         }
+        Ok(())
+    }
 
-        // スコープを削除
-        self.current_scope.pop();
-
-        // パラメータと戻り値の型の検証
-        for param in &method.params {
-            self.verify_parameter_type(param)?;
-        }
-
-        if let Some(return_type) = &method.return_type {
-            self.verify_return_type(return_type)?;
+    /// Rejects a struct or enum `name` through which a type contains itself
+    fn check_not_recursive(&self, name: &str) -> Result<(), SemanticError> {
+        let own_type = Type::Custom(name.to_string());
+        match self.recursive_struct(&own_type, &mut Vec::new()) {
+            Some(name) => {
+                let kind = if self.enums.contains_key(&name) {
+                    "Enum"
+                } else {
+                    "Struct"
+                };
+                Err(SemanticError::TypeError(format!(
+                    "{} {} contains itself",
+                    kind, name
+                )))
+            }
+            None => Ok(()),
         }
-
-        Ok(())
     }
 
-    fn verify_parameter_type(&self, param: &Parameter) -> Result<(), SemanticError> {
-        // パラメータの型が有効かチェック
-        match &param.param_type {
+    /// Returns a struct or enum that `ty` reaches again through its own fields
+    /// or associated values, which would make its size infinite. `path` holds
+    /// the types being expanded.
+    fn recursive_struct<'a>(&'a self, ty: &'a Type, path: &mut Vec<&'a str>) -> Option<String> {
+        match ty {
             Type::Custom(name) => {
-                if !self.type_environment.contains_key(name) {
-                    return Err(SemanticError::TypeError(format!(
-                        "Unknown type {} for parameter {}",
-                        name, param.name
-                    )));
+                let members: Vec<&Type> = match (self.structs.get(name), self.enums.get(name)) {
+                    (Some(declaration), _) => declaration
+                        .fields
+                        .iter()
+                        .map(|field| &field.field_type)
+                        .collect(),
+                    (None, Some(declaration)) => declaration
+                        .cases
+                        .iter()
+                        .flat_map(|case| &case.values)
+                        .collect(),
+                    (None, None) => return None,
+                };
+                if path.contains(&name.as_str()) {
+                    return Some(name.clone());
                 }
+                path.push(name);
+                let found = members
+                    .into_iter()
+                    .find_map(|member| self.recursive_struct(member, path));
+                path.pop();
+                found
             }
-            _ => {}
+            Type::FixedArray(inner, _) | Type::Optional(inner) => {
+                self.recursive_struct(inner, path)
+            }
+            Type::Tuple(elements) => elements
+                .iter()
+                .find_map(|element| self.recursive_struct(element, path)),
+            Type::Record(fields) => fields
+                .iter()
+                .find_map(|(_, field)| self.recursive_struct(field, path)),
+            _ => None,
         }
-        Ok(())
     }
 
-    fn verify_return_type(&self, return_type: &Type) -> Result<(), SemanticError> {
-        // 戻り値の型が有効かチェック
-        match return_type {
-            Type::Custom(name) => {
-                if !self.type_environment.contains_key(name) {
+    /// Registers the topics of the file and of the modules it imports. A topic
+    /// declared by several modules is the same topic and must carry the same
+    /// type everywhere.
+    fn declare_topics(&mut self, actor: &Actor) -> Result<(), SemanticError> {
+        for topic in &actor.topics {
+            if self.topics.contains_key(&topic.name) {
+                return Err(SemanticError::InvalidOperation(format!(
+                    "Topic {} is declared more than once",
+                    topic.name
+                )));
+            }
+            // 値は購読者ごとにバイト列として複製される
+            if !self.is_thread_safe(&topic.value_type) {
+                return Err(SemanticError::TypeError(format!(
+                    "Topic {} must carry plain data (Int, Float, Bool, structs, enums or fixed-size aggregates of them), found {:?}",
+                    topic.name, topic.value_type
+                )));
+            }
+            self.topics
+                .insert(topic.name.clone(), topic.value_type.clone());
+        }
+
+        let imported: Vec<Topic> = actor
+            .imports
+            .iter()
+            .filter_map(|module| self.symbols.module(module))
+            .flat_map(|symbols| symbols.topics.clone())
+            .collect();
+        for topic in imported {
+            match self.topics.get(&topic.name) {
+                Some(value_type) if *value_type != topic.value_type => {
                     return Err(SemanticError::TypeError(format!(
-                        "Unknown return type {}",
-                        name
-                    )));
+                        "Topic {} is declared with type {:?} and with type {:?}",
+                        topic.name, value_type, topic.value_type
+                    )))
+                }
+                Some(_) => {}
+                None => {
+                    self.topics.insert(topic.name, topic.value_type);
                 }
             }
-            _ => {}
         }
         Ok(())
     }
 
-    fn verify_shared_field_constraints(&self, field: &Field) -> Result<(), SemanticError> {
-        // 共有フィールドの制約をチェック
-        match &field.field_type {
+    /// Type of the values published on `topic`
+    fn topic_type(&self, topic: &str) -> Result<&Type, SemanticError> {
+        self.topics.get(topic).ok_or_else(|| {
+            SemanticError::InvalidOperation(format!(
+                "Unknown topic {}; declare it with `topic {}: Type` before the actor",
+                topic, topic
+            ))
+        })
+    }
+
+    /// Checks the `pool` lines of the file. A pool routes the async methods of
+    /// the actor declared in the same file; with `consistentHash(key)` every one
+    /// of them must take an `Int` or `String` parameter named `key`.
+    fn check_pools(actor: &Actor) -> Result<(), SemanticError> {
+        for (index, pool) in actor.pools.iter().enumerate() {
+            // ルーターは引数なしでインスタンスを生成する
+            if actor
+                .init
+                .as_ref()
+                .is_some_and(|init| !init.params.is_empty())
+            {
+                return Err(SemanticError::InvalidOperation(format!(
+                    "Pool {} cannot create instances of {}, whose init takes parameters",
+                    pool.name, actor.name
+                )));
+            }
+            if pool.name == actor.name || actor.pools[..index].iter().any(|p| p.name == pool.name) {
+                return Err(SemanticError::InvalidOperation(format!(
+                    "{} is declared more than once",
+                    pool.name
+                )));
+            }
+            // ルーターはメソッドのシグネチャが分かるアクターしか生成できない
+            if pool.actor != actor.name {
+                return Err(SemanticError::InvalidOperation(format!(
+                    "Pool {} must pool {}, the actor declared in this file, found {}",
+                    pool.name, actor.name, pool.actor
+                )));
+            }
+            if actor.actor_type != ActorType::Distributed {
+                return Err(SemanticError::InvalidActorOperation(format!(
+                    "Pool {} needs a distributed actor; single actor {} has no messages to route",
+                    pool.name, actor.name
+                )));
+            }
+            if !(1..=Pool::MAX_SIZE).contains(&pool.size) {
+                return Err(SemanticError::InvalidOperation(format!(
+                    "Pool {} must have between 1 and {} instances, found {}",
+                    pool.name,
+                    Pool::MAX_SIZE,
+                    pool.size
+                )));
+            }
+            let PoolStrategy::ConsistentHash(key) = &pool.strategy else {
+                continue;
+            };
+            let routed = actor
+                .methods
+                .iter()
+                .filter(|method| method.is_async && method.stream_item().is_none());
+            for method in routed {
+                match method.params.iter().find(|param| param.name == *key) {
+                    Some(param) if matches!(param.param_type, Type::Int | Type::String) => {}
+                    Some(param) => {
+                        return Err(SemanticError::TypeError(format!(
+                            "Pool {} hashes {}, which must be an Int or a String, found {:?} in {}",
+                            pool.name, key, param.param_type, method.name
+                        )))
+                    }
+                    None => {
+                        return Err(SemanticError::InvalidOperation(format!(
+                            "Pool {} routes by {}, but {} has no parameter {}",
+                            pool.name, key, method.name, key
+                        )))
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks the `@shardKey` parameters: calls sharded by key are messages to
+    /// an instance on another node, so the method must be queueable (async and
+    /// not a stream) on a distributed actor, and the key an `Int` or `String`
+    /// as for a consistent-hash pool.
+    fn check_shard_keys(actor: &Actor) -> Result<(), SemanticError> {
+        for method in &actor.methods {
+            let Some(key) = &method.shard_key else {
+                continue;
+            };
+            if actor.actor_type != ActorType::Distributed {
+                return Err(SemanticError::InvalidActorOperation(format!(
+                    "Method {} of single actor {} cannot be sharded; only distributed actors run on several nodes",
+                    method.name, actor.name
+                )));
+            }
+            if !method.is_async || method.stream_item().is_some() {
+                return Err(SemanticError::InvalidOperation(format!(
+                    "Method {} is sharded by {}, but only async methods that are not streams can be sent to another node",
+                    method.name, key
+                )));
+            }
+            let param = method
+                .params
+                .iter()
+                .find(|param| param.name == *key)
+                .ok_or_else(|| {
+                    SemanticError::InvalidOperation(format!(
+                        "Method {} is sharded by {}, but has no parameter {}",
+                        method.name, key, key
+                    ))
+                })?;
+            if !matches!(param.param_type, Type::Int | Type::String) {
+                return Err(SemanticError::TypeError(format!(
+                    "Shard key {} of {} must be an Int or a String, found {:?}",
+                    key, method.name, param.param_type
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks `@retry` and `@idempotent`, which shape how `<method>.remote`
+    /// sends calls to another node. A retried send may already have been
+    /// delivered, so only idempotent methods can be retried.
+    fn check_remote_policies(actor: &Actor) -> Result<(), SemanticError> {
+        for method in &actor.methods {
+            let attribute = match (&method.retry, method.is_idempotent) {
+                (None, false) => continue,
+                (Some(_), _) => "@retry",
+                (None, true) => "@idempotent",
+            };
+            if actor.actor_type != ActorType::Distributed {
+                return Err(SemanticError::InvalidActorOperation(format!(
+                    "Method {} of single actor {} cannot be {}; only distributed actors are called remotely",
+                    method.name, actor.name, attribute
+                )));
+            }
+            if !method.is_async || method.stream_item().is_some() {
+                return Err(SemanticError::InvalidOperation(format!(
+                    "Method {} is {}, but only async methods that are not streams can be sent to another node",
+                    method.name, attribute
+                )));
+            }
+            let Some(retry) = method.retry else {
+                continue;
+            };
+            if !method.is_idempotent {
+                return Err(SemanticError::InvalidOperation(format!(
+                    "Method {} is retried, so it must be @idempotent: a call that seems to have failed may still have been delivered",
+                    method.name
+                )));
+            }
+            if !(1..=RetryPolicy::MAX_TIMES).contains(&retry.times) {
+                return Err(SemanticError::InvalidOperation(format!(
+                    "Method {} must be retried between 1 and {} times, found {}",
+                    method.name,
+                    RetryPolicy::MAX_TIMES,
+                    retry.times
+                )));
+            }
+            if retry.backoff_ms > RetryPolicy::MAX_BACKOFF_MS {
+                return Err(SemanticError::InvalidOperation(format!(
+                    "Backoff of {} must be at most {}ms, found {}ms",
+                    method.name,
+                    RetryPolicy::MAX_BACKOFF_MS,
+                    retry.backoff_ms
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks `@since` and `@removed`, which give the API versions a method
+    /// can be called in. Versions count from 1, a method is removed after it
+    /// is added, and a removed method has no `<method>.remote` proxy, since
+    /// only clients of older versions still call it.
+    fn check_api_versions(actor: &Actor) -> Result<(), SemanticError> {
+        for method in &actor.methods {
+            let attribute = match (method.since, method.removed) {
+                (None, None) => continue,
+                (Some(_), _) => "@since",
+                (None, Some(_)) => "@removed",
+            };
+            if actor.actor_type != ActorType::Distributed {
+                return Err(SemanticError::InvalidActorOperation(format!(
+                    "Method {} of single actor {} cannot be {}; only distributed actors have versioned clients",
+                    method.name, actor.name, attribute
+                )));
+            }
+            if !method.is_async || method.stream_item().is_some() {
+                return Err(SemanticError::InvalidOperation(format!(
+                    "Method {} is {}, but only async methods that are not streams are versioned messages",
+                    method.name, attribute
+                )));
+            }
+            if method.since == Some(0) || method.removed == Some(0) {
+                return Err(SemanticError::InvalidOperation(format!(
+                    "API versions of {} count from 1, found 0",
+                    method.name
+                )));
+            }
+            let Some(removed) = method.removed else {
+                continue;
+            };
+            let since = method.since.unwrap_or(1);
+            if removed <= since {
+                return Err(SemanticError::InvalidOperation(format!(
+                    "Method {} is removed in version {}, but was added in version {}",
+                    method.name, removed, since
+                )));
+            }
+            if method.is_remote() {
+                return Err(SemanticError::InvalidOperation(format!(
+                    "Method {} is removed in version {}, so current clients cannot call it remotely",
+                    method.name, removed
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that the actor has the method `subscribe(topic)` delivers to:
+    /// `async func on<Topic>(value: T)` for a topic carrying `T`
+    fn check_topic_handler(&self, topic: &str) -> Result<(), SemanticError> {
+        let value_type = self.topic_type(topic)?;
+        let handler = Topic::handler_name(topic);
+        let accepts = self.methods.get(&handler).is_some_and(|method| {
+            method.is_async
+                && method.return_type.is_none()
+                && matches!(method.params.as_slice(), [param] if param.param_type == *value_type)
+        });
+        if !accepts {
+            return Err(SemanticError::InvalidOperation(format!(
+                "Subscribing to {} needs a handler `async func {}(value: {:?})`",
+                topic, handler, value_type
+            )));
+        }
+        Ok(())
+    }
+
+    /// Registers extern types as opaque handle types and extern functions as
+    /// body-less methods that calls can resolve to
+    fn declare_externs(&mut self, externs: &[Extern]) -> Result<(), SemanticError> {
+        for item in externs {
+            match item {
+                Extern::Type(name) => {
+                    if !self.extern_types.insert(name.clone()) {
+                        return Err(SemanticError::TypeError(format!(
+                            "Extern type {} is declared twice",
+                            name
+                        )));
+                    }
+                    self.type_environment
+                        .insert(name.clone(), Type::Custom(name.clone()));
+                }
+                Extern::Function(function) => {
+                    if let Some(param) = function
+                        .params
+                        .iter()
+                        .find(|param| matches!(param.ownership, OwnershipType::Inout))
+                    {
+                        return Err(SemanticError::OwnershipError(format!(
+                            "Parameter {} of extern function {} cannot be inout",
+                            param.name, function.name
+                        )));
+                    }
+                    if let Some(name) = function
+                        .requires
+                        .iter()
+                        .find(|name| !capability::is_valid_name(name))
+                    {
+                        return Err(SemanticError::InvalidOperation(format!(
+                            "Extern function {} requires invalid capability \"{}\"; capability names are lowercase, such as \"net\"",
+                            function.name, name
+                        )));
+                    }
+                    if function.proto.is_some() && !function.requires.is_empty() {
+                        return Err(SemanticError::InvalidOperation(format!(
+                            "Extern function {} is a protobuf codec defined in the module, so it cannot require capabilities",
+                            function.name
+                        )));
+                    }
+                    proto::codec(function).map_err(|e| {
+                        SemanticError::TypeError(format!(
+                            "Extern function {} is not a valid protobuf codec: {}",
+                            function.name, e
+                        ))
+                    })?;
+                    if !self.extern_functions.insert(function.name.clone()) {
+                        return Err(SemanticError::InvalidOperation(format!(
+                            "Extern function {} is declared twice",
+                            function.name
+                        )));
+                    }
+                    self.methods.insert(
+                        function.name.clone(),
+                        Method {
+                            name: function.name.clone(),
+                            is_async: false,
+                            is_sequential: false,
+                            is_immediate: false,
+                            is_pure: false,
+                            is_nonreentrant: false,
+                            shard_key: None,
+                            retry: None,
+                            is_idempotent: false,
+                            since: None,
+                            removed: None,
+                            params: function.params.clone(),
+                            return_type: function.return_type.clone(),
+                            body: None,
+                            doc: None,
+                        },
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Rejects generic types such as `Cache<String>` in declarations; they are
+    /// parsed, but generics are not implemented yet
+    fn check_no_generic_types(actor: &Actor) -> Result<(), SemanticError> {
+        let fields = actor.fields.iter().map(|field| &field.field_type);
+        let externs = actor.externs.iter().filter_map(|item| match item {
+            Extern::Function(function) => Some((&function.params, &function.return_type)),
+            Extern::Type(_) => None,
+        });
+        // メソッドの戻り値の AsyncStream<T> は T だけを調べる
+        let signatures = actor
+            .methods
+            .iter()
+            .map(|method| {
+                let return_type = method.return_type.as_ref();
+                (&method.params, method.stream_item().or(return_type))
+            })
+            .chain(externs.map(|(params, return_type)| (params, return_type.as_ref())))
+            .flat_map(|(params, return_type)| {
+                params
+                    .iter()
+                    .map(|param| &param.param_type)
+                    .chain(return_type)
+            });
+        match fields.chain(signatures).find_map(Self::generic_type_in) {
+            Some(name) => Err(SemanticError::TypeError(format!(
+                "Generic type {}<...> is not supported yet",
+                name
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns the name of the generic type that `ty` is or contains, if any
+    fn generic_type_in(ty: &Type) -> Option<&str> {
+        match ty {
+            Type::Generic { name, .. } => Some(name),
+            Type::Array(inner) | Type::FixedArray(inner, _) | Type::Optional(inner) => {
+                Self::generic_type_in(inner)
+            }
+            Type::Tuple(elements) => elements.iter().find_map(Self::generic_type_in),
+            Type::Record(fields) => fields
+                .iter()
+                .find_map(|(_, field)| Self::generic_type_in(field)),
+            Type::Function {
+                params,
+                return_type,
+            } => params
+                .iter()
+                .chain(return_type.as_deref())
+                .find_map(Self::generic_type_in),
+            Type::Int | Type::Float | Type::String | Type::Bool | Type::Custom(_) => None,
+        }
+    }
+
+    /// Rejects explicit type arguments such as `map<Int>(values)`
+    fn check_no_type_arguments(callee: &str, type_args: &[Type]) -> Result<(), SemanticError> {
+        if type_args.is_empty() {
+            return Ok(());
+        }
+        Err(SemanticError::TypeError(format!(
+            "Call of {} has type arguments, but generic methods are not supported yet",
+            callee
+        )))
+    }
+
+    /// Returns the extern type that `ty` is or contains, if any
+    fn opaque_type_in<'a>(&self, ty: &'a Type) -> Option<&'a str> {
+        match ty {
+            Type::Custom(name) if self.extern_types.contains(name) => Some(name),
+            Type::Array(inner) | Type::FixedArray(inner, _) | Type::Optional(inner) => {
+                self.opaque_type_in(inner)
+            }
+            Type::Tuple(elements) => elements
+                .iter()
+                .find_map(|element| self.opaque_type_in(element)),
+            Type::Record(fields) => fields
+                .iter()
+                .find_map(|(_, field)| self.opaque_type_in(field)),
+            _ => None,
+        }
+    }
+
+    /// Whether values of `ty` can be read and written by several workers: plain
+    /// data stored inline, without reference counts or host handles. Structs
+    /// qualify, as `declare_structs` only accepts such fields.
+    fn is_thread_safe(&self, ty: &Type) -> bool {
+        match ty {
+            Type::Int | Type::Float | Type::Bool => true,
+            Type::Custom(name) => self.structs.contains_key(name) || self.enums.contains_key(name),
+            Type::FixedArray(inner, _) | Type::Optional(inner) => self.is_thread_safe(inner),
+            Type::Tuple(elements) => elements.iter().all(|element| self.is_thread_safe(element)),
+            Type::Record(fields) => fields.iter().all(|(_, field)| self.is_thread_safe(field)),
+            _ => false,
+        }
+    }
+
+    /// Whether the custom type `name` is an actor rather than an extern type, a
+    /// struct or an enum, so that values of it are references to an instance
+    fn is_actor_type(&self, name: &str) -> bool {
+        !self.extern_types.contains(name)
+            && !self.structs.contains_key(name)
+            && !self.enums.contains_key(name)
+    }
+
+    /// Rejects operations that would look inside an opaque extern handle
+    fn check_not_opaque(&self, ty: &Type) -> Result<(), SemanticError> {
+        match ty {
+            Type::Custom(name) if self.extern_types.contains(name) => {
+                Err(SemanticError::InvalidOperation(format!(
+                    "Cannot dereference a value of opaque extern type {}",
+                    name
+                )))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks that a constant table holds literals of its declared types, with
+    /// distinct keys if it is a map, and registers it
+    fn declare_constant(
+        &mut self,
+        constant: &Constant,
+        actor: &Actor,
+    ) -> Result<(), SemanticError> {
+        let name = &constant.name;
+        if self.fields.contains_key(name)
+            || self.constants.contains_key(name)
+            || actor.methods.iter().any(|method| &method.name == name)
+        {
+            return Err(SemanticError::InvalidOperation(format!(
+                "Constant {} conflicts with another member of the same name",
+                name
+            )));
+        }
+        match &constant.table {
+            ConstantTable::Array {
+                element_type,
+                values,
+            } => {
+                Self::check_constant_type(name, element_type)?;
+                for value in values {
+                    self.check_constant_value(name, element_type, value)?;
+                }
+            }
+            ConstantTable::Map {
+                key_type,
+                value_type,
+                entries,
+            } => {
+                // 文字列のキーはハッシュ値で、Int のキーは値そのもので二分探索する
+                if !matches!(key_type, Type::Int | Type::String) {
+                    return Err(SemanticError::TypeError(format!(
+                        "Keys of constant map {} must be Int or String, found {}",
+                        name, key_type
+                    )));
+                }
+                Self::check_constant_type(name, value_type)?;
+                let mut keys = HashSet::new();
+                for (key, value) in entries {
+                    self.check_constant_value(name, key_type, key)?;
+                    self.check_constant_value(name, value_type, value)?;
+                    if !keys.insert(key) {
+                        return Err(SemanticError::InvalidOperation(format!(
+                            "Constant map {} has the key {} more than once",
+                            name,
+                            Expression::Literal(key.clone())
+                        )));
+                    }
+                }
+            }
+        }
+        self.constants.insert(name.clone(), constant.table.clone());
+        Ok(())
+    }
+
+    /// Checks that the values of the constant table `name` have a type that
+    /// can be laid out in the data segment
+    fn check_constant_type(name: &str, value_type: &Type) -> Result<(), SemanticError> {
+        match value_type {
+            Type::Int | Type::Float | Type::String => Ok(()),
+            _ => Err(SemanticError::TypeError(format!(
+                "Constant {} must hold Int, Float or String values, found {}",
+                name, value_type
+            ))),
+        }
+    }
+
+    /// Checks a literal of the constant table `name` against its declared type
+    fn check_constant_value(
+        &self,
+        name: &str,
+        expected: &Type,
+        value: &LiteralValue,
+    ) -> Result<(), SemanticError> {
+        let value = Expression::Literal(value.clone());
+        let found = self.analyze_expression(&value)?;
+        if !self.check_type_compatibility(expected, &found) {
+            return Err(SemanticError::TypeError(format!(
+                "Constant {} holds {} values, found {} of type {}",
+                name, expected, value, found
+            )));
+        }
+        Ok(())
+    }
+
+    /// The constant table an expression names, unless a local hides it
+    fn constant_table(&self, expr: &Expression) -> Option<(&str, &ConstantTable)> {
+        match expr {
+            Expression::Variable(name) if self.lookup_variable(name).is_err() => self
+                .constants
+                .get_key_value(name)
+                .map(|(name, table)| (name.as_str(), table)),
+            _ => None,
+        }
+    }
+
+    fn analyze_field(&mut self, field: &Field) -> Result<(), SemanticError> {
+        // フィールドの型を登録
+        self.type_environment
+            .insert(field.name.clone(), field.field_type.clone());
+        self.fields.insert(field.name.clone(), field.clone());
+
+        // 所有権ルールのチェック
+        match field.ownership {
+            OwnershipType::Moved => {
+                if field.is_mutable {
+                    return Err(SemanticError::OwnershipError(
+                        "Moved fields cannot be mutable".to_string(),
+                    ));
+                }
+            }
+            OwnershipType::Shared => {
+                if !field.is_mutable {
+                    return Err(SemanticError::OwnershipError(
+                        "Shared fields must be mutable".to_string(),
+                    ));
+                }
+                // 参照カウントやハンドルはワーカー間で安全に共有できない
+                if self.threads && !self.is_thread_safe(&field.field_type) {
+                    return Err(SemanticError::OwnershipError(format!(
+                        "Shared field {} must hold plain data (Int, Float, Bool or fixed-size aggregates of them) to be shared between threads",
+                        field.name
+                    )));
+                }
+            }
+            OwnershipType::Weak => {
+                // 弱参照は対象の破棄時に nil になるため Optional かつ可変でなければならない
+                if !matches!(&field.field_type, Type::Optional(inner) if matches!(&**inner, Type::Custom(name) if self.is_actor_type(name)))
+                {
+                    return Err(SemanticError::OwnershipError(format!(
+                        "Weak field {} must have an optional actor type",
+                        field.name
+                    )));
+                }
+                if !field.is_mutable {
+                    return Err(SemanticError::OwnershipError(
+                        "Weak fields must be mutable".to_string(),
+                    ));
+                }
+            }
+            OwnershipType::Unowned if !matches!(&field.field_type, Type::Custom(name) if self.is_actor_type(name)) =>
+            {
+                return Err(SemanticError::OwnershipError(format!(
+                    "Unowned field {} must have a non-optional actor type",
+                    field.name
+                )));
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    fn analyze_expression(&self, expr: &Expression) -> Result<Type, SemanticError> {
+        match expr {
+            Expression::BinaryOp {
+                left,
+                operator,
+                right,
+            } => {
+                let left_type = self.analyze_expression(left)?;
+                let right_type = self.analyze_expression(right)?;
+                self.check_not_opaque(&left_type)?;
+                self.check_not_opaque(&right_type)?;
+
+                match operator {
+                    Operator::Add | Operator::Subtract | Operator::Multiply | Operator::Divide => {
+                        // 数値演算の型チェック
+                        match (&left_type, &right_type) {
+                            (Type::Int, Type::Int) => Ok(Type::Int),
+                            (Type::Float, Type::Float) => Ok(Type::Float),
+                            // 文字列は + で連結する
+                            (Type::String, Type::String) if *operator == Operator::Add => {
+                                Ok(Type::String)
+                            }
+                            _ => Err(SemanticError::TypeError(format!(
+                                "Invalid operand types for arithmetic operation: {:?} and {:?}",
+                                left_type, right_type
+                            ))),
+                        }
+                    }
+                    Operator::Equal | Operator::NotEqual => {
+                        // 等値比較は同じ型のスカラー値どうしに限る
+                        match (&left_type, &right_type) {
+                            (Type::Int, Type::Int)
+                            | (Type::Float, Type::Float)
+                            | (Type::Bool, Type::Bool)
+                            | (Type::String, Type::String) => Ok(Type::Bool),
+                            _ => Err(SemanticError::TypeError(format!(
+                                "Invalid operand types for equality comparison: {:?} and {:?}",
+                                left_type, right_type
+                            ))),
+                        }
+                    }
+                    Operator::Less
+                    | Operator::LessEqual
+                    | Operator::Greater
+                    | Operator::GreaterEqual => match (&left_type, &right_type) {
+                        (Type::Int, Type::Int) | (Type::Float, Type::Float) => Ok(Type::Bool),
+                        _ => Err(SemanticError::TypeError(format!(
+                            "Invalid operand types for ordering comparison: {:?} and {:?}",
+                            left_type, right_type
+                        ))),
+                    },
+                }
+            }
+            Expression::Literal(value) => match value {
+                LiteralValue::Int(_) => Ok(Type::Int),
+                LiteralValue::Float(_) => Ok(Type::Float),
+                LiteralValue::String(_) => Ok(Type::String),
+                LiteralValue::Bool(_) => Ok(Type::Bool),
+            },
+            Expression::Variable(name) => {
+                if let Some((name, table)) = self.constant_table(expr) {
+                    return table.value_type().ok_or_else(|| {
+                        SemanticError::TypeError(format!(
+                            "Constant map {} can only be indexed, as in {}[key]",
+                            name, name
+                        ))
+                    });
+                }
+                self.lookup_variable(name).or_else(|error| {
+                    match (self.fields.get(name), self.methods.get(name)) {
+                        // ローカル変数に隠されていない名前はフィールドを指す
+                        (Some(field), _) => Ok(field.field_type.clone()),
+                        // メソッド名は関数参照として扱う
+                        (None, Some(method)) => self.method_reference_type(method),
+                        (None, None) => Err(error),
+                    }
+                })
+            }
+            Expression::Field(name) => self
+                .fields
+                .get(name)
+                .map(|field| field.field_type.clone())
+                .ok_or_else(|| SemanticError::UndefinedVariable(format!("self.{}", name))),
+            Expression::Call {
+                callee,
+                type_args,
+                args,
+            } => {
+                Self::check_no_type_arguments(callee, type_args)?;
+                self.analyze_call(callee, args)?.ok_or_else(|| {
+                    SemanticError::TypeError(format!("Method {} does not return a value", callee))
+                })
+            }
+            Expression::Inout(name) => Err(SemanticError::InvalidOperation(format!(
+                "&{} can only be passed to an inout parameter",
+                name
+            ))),
+            Expression::Tuple(elements) => Ok(Type::Tuple(
+                elements
+                    .iter()
+                    .map(|element| self.analyze_expression(element))
+                    .collect::<Result<_, _>>()?,
+            )),
+            Expression::Record(fields) => {
+                let mut labels = HashSet::new();
+                let mut field_types = Vec::with_capacity(fields.len());
+                for (label, value) in fields {
+                    if !labels.insert(label) {
+                        return Err(SemanticError::TypeError(format!(
+                            "Duplicate record field {}",
+                            label
+                        )));
+                    }
+                    field_types.push((label.clone(), self.analyze_expression(value)?));
+                }
+                Ok(Type::record(field_types))
+            }
+            Expression::StructLiteral { name, fields } => self.analyze_struct_literal(name, fields),
+            Expression::EnumCase {
+                enum_name,
+                case,
+                args,
+            } => self.analyze_enum_case(enum_name, case, args),
+            Expression::Match { value, arms } => {
+                let value_type = self.analyze_expression(value)?;
+                self.check_match_arms(&value_type, arms.iter().map(|(pattern, _)| pattern))?;
+                let mut result_type: Option<Type> = None;
+                for (pattern, result) in arms {
+                    // 腕の束縛はその腕の式からだけ見える
+                    self.push_scope(HashMap::new());
+                    let found = self
+                        .bind_pattern(pattern, &value_type, false)
+                        .and_then(|()| self.analyze_expression(result));
+                    self.pop_scope();
+                    let found = found?;
+                    match &result_type {
+                        Some(expected) if !self.check_type_compatibility(expected, &found) => {
+                            return Err(SemanticError::TypeError(format!(
+                                "Arms of a match must have the same type: expected {:?}, found {:?}",
+                                expected, found
+                            )))
+                        }
+                        Some(_) => {}
+                        None => result_type = Some(found),
+                    }
+                }
+                result_type.ok_or_else(|| {
+                    SemanticError::TypeError(
+                        "A match expression needs at least one arm".to_string(),
+                    )
+                })
+            }
+            Expression::Member { base, name } => {
+                let base_type = self.analyze_expression(base)?;
+                // 配列の要素数
+                if name == "count" && matches!(base_type, Type::Array(_) | Type::FixedArray(..))
+                {
+                    return Ok(Type::Int);
+                }
+                // 文字列のバイト数
+                if name == "length" && base_type == Type::String {
+                    return Ok(Type::Int);
+                }
+                let field_type = match &base_type {
+                    Type::Custom(struct_name) => {
+                        self.structs.get(struct_name).and_then(|declaration| {
+                            declaration
+                                .field_index(name)
+                                .map(|i| &declaration.fields[i].field_type)
+                        })
+                    }
+                    Type::Record(fields) => fields
+                        .iter()
+                        .find(|(label, _)| label == name)
+                        .map(|(_, field_type)| field_type),
+                    _ => None,
+                };
+                field_type.cloned().ok_or_else(|| {
+                    SemanticError::TypeError(format!("{} has no field {}", base_type, name))
+                })
+            }
+            Expression::ArrayLiteral(elements) => {
+                let (first, rest) = elements.split_first().ok_or_else(|| {
+                    SemanticError::TypeError(
+                        "Cannot infer the element type of an empty array literal".to_string(),
+                    )
+                })?;
+                let element_type = self.analyze_expression(first)?;
+                for element in rest {
+                    let found = self.analyze_expression(element)?;
+                    if !self.check_type_compatibility(&element_type, &found) {
+                        return Err(SemanticError::TypeError(format!(
+                            "Array elements must have the same type: expected {:?}, found {:?}",
+                            element_type, found
+                        )));
+                    }
+                }
+                Ok(Type::FixedArray(Box::new(element_type), elements.len()))
+            }
+            Expression::Index { base, index } => self.analyze_index(base, index),
+            Expression::Atomic {
+                operation,
+                field,
+                args,
+                ordering,
+            } => self
+                .analyze_atomic(*operation, field, args, *ordering)?
+                .ok_or_else(|| {
+                    SemanticError::TypeError(format!(
+                        "{} does not return a value",
+                        operation.name()
+                    ))
+                }),
+            Expression::Comptime { callee, args } => self.analyze_comptime(callee, args),
+            Expression::Conversion { conversion, value } => {
+                self.analyze_conversion(*conversion, value)
+            }
+            Expression::Random { operation, args } => self.analyze_random(*operation, args),
+            Expression::Config(key) => {
+                if !self.config_keys.contains(key) {
+                    return Err(SemanticError::InvalidOperation(format!(
+                        "Unknown config key {}; declare it under [config] in replica.toml",
+                        key
+                    )));
+                }
+                Ok(Type::String)
+            }
+            Expression::Nil => Err(SemanticError::TypeError(
+                "nil needs an optional type from its context: return it, assign it, pass it as an argument or use it after ??".to_string(),
+            )),
+            Expression::Unwrap(value) => self.optional_value_type(value, "!"),
+            Expression::Await(value) => self.analyze_await(value)?.ok_or_else(|| {
+                SemanticError::TypeError(format!("{} does not return a value", value))
+            }),
+            Expression::Coalesce { value, default } => {
+                let value_type = self.optional_value_type(value, "??")?;
+                let default_type = self.analyze_expected(default, &value_type)?;
+                if !self.check_type_compatibility(&value_type, &default_type) {
+                    return Err(SemanticError::TypeError(format!(
+                        "Default of ?? must be {:?}, found {:?}",
+                        value_type, default_type
+                    )));
+                }
+                Ok(value_type)
+            }
+        }
+    }
+
+    /// Type of `expr` where a value of `expected` is wanted: `nil` takes the
+    /// type of an expected optional and `[]` that of an expected `[T]`,
+    /// anything else its own type
+    fn analyze_expected(&self, expr: &Expression, expected: &Type) -> Result<Type, SemanticError> {
+        match (expr, expected) {
+            (Expression::Nil, Type::Optional(_)) => Ok(expected.clone()),
+            (Expression::ArrayLiteral(elements), Type::Array(_)) if elements.is_empty() => {
+                Ok(expected.clone())
+            }
+            _ => self.analyze_expression(expr),
+        }
+    }
+
+    /// Type of the value inside the optional `expr`, which `operator` unwraps
+    fn optional_value_type(
+        &self,
+        expr: &Expression,
+        operator: &str,
+    ) -> Result<Type, SemanticError> {
+        match self.analyze_expression(expr)? {
+            Type::Optional(inner) => Ok(*inner),
+            found => Err(SemanticError::TypeError(format!(
+                "{} unwraps an optional, found {:?}",
+                operator, found
+            ))),
+        }
+    }
+
+    /// Checks the receiver of a conversion and returns its result type
+    fn analyze_conversion(
+        &self,
+        conversion: Conversion,
+        value: &Expression,
+    ) -> Result<Type, SemanticError> {
+        let value_type = self.analyze_expression(value)?;
+        let receiver_types = conversion.receiver_types();
+        if receiver_types
+            .iter()
+            .any(|receiver| self.check_type_compatibility(receiver, &value_type))
+        {
+            return Ok(conversion.result_type());
+        }
+        Err(SemanticError::TypeError(match conversion {
+            Conversion::Interpolate => format!(
+                "Cannot interpolate {:?} into a string; interpolated values must be Int, Float, Bool or String",
+                value_type
+            ),
+            _ => format!(
+                "{}() is defined on {:?}, not {:?}",
+                conversion.name(),
+                receiver_types[0],
+                value_type
+            ),
+        }))
+    }
+
+    /// Checks the arguments of a random number built-in and returns its result type
+    fn analyze_random(
+        &self,
+        operation: RandomOperation,
+        args: &[Expression],
+    ) -> Result<Type, SemanticError> {
+        let param_types = operation.param_types();
+        if param_types.len() != args.len() {
+            return Err(SemanticError::TypeError(format!(
+                "{} expects {} arguments, found {}",
+                operation.name(),
+                param_types.len(),
+                args.len()
+            )));
+        }
+        for (expected, arg) in param_types.iter().zip(args) {
+            let found = self.analyze_expression(arg)?;
+            if !self.check_type_compatibility(expected, &found) {
+                return Err(SemanticError::TypeError(format!(
+                    "{} expects {:?}, found {:?}",
+                    operation.name(),
+                    expected,
+                    found
+                )));
+            }
+        }
+        Ok(operation.result_type())
+    }
+
+    /// Checks `comptime callee(args)`: the callee must be a pure method and every
+    /// argument a constant expression
+    fn analyze_comptime(&self, callee: &str, args: &[Expression]) -> Result<Type, SemanticError> {
+        match self.methods.get(callee) {
+            Some(method) if method.is_pure => {}
+            Some(_) => {
+                return Err(SemanticError::InvalidOperation(format!(
+                    "comptime can only call pure methods, and {} is not pure",
+                    callee
+                )))
+            }
+            None => return Err(SemanticError::UndefinedVariable(callee.to_string())),
+        }
+        if let Some(arg) = args.iter().find(|arg| !Self::is_constant(arg)) {
+            return Err(SemanticError::InvalidOperation(format!(
+                "Argument {:?} of comptime {} is not a constant expression",
+                arg, callee
+            )));
+        }
+
+        self.analyze_call(callee, args)?.ok_or_else(|| {
+            SemanticError::TypeError(format!("Method {} does not return a value", callee))
+        })
+    }
+
+    /// Whether `expr` can be evaluated without any run-time state
+    fn is_constant(expr: &Expression) -> bool {
+        match expr {
+            Expression::Literal(_) | Expression::Comptime { .. } => true,
+            Expression::BinaryOp { left, right, .. }
+            | Expression::Index {
+                base: left,
+                index: right,
+            } => Self::is_constant(left) && Self::is_constant(right),
+            Expression::Tuple(elements) | Expression::ArrayLiteral(elements) => {
+                elements.iter().all(Self::is_constant)
+            }
+            Expression::Record(fields) => fields.iter().all(|(_, value)| Self::is_constant(value)),
+            // Optional はコンパイル時に表せないので toInt() は定数にならない
+            Expression::Conversion { conversion, value } => {
+                *conversion != Conversion::StringToInt && Self::is_constant(value)
+            }
+            Expression::Variable(_)
+            | Expression::Field(_)
+            | Expression::Call { .. }
+            | Expression::Inout(_)
+            | Expression::Atomic { .. }
+            | Expression::Random { .. }
+            | Expression::Config(_)
+            | Expression::StructLiteral { .. }
+            | Expression::Member { .. }
+            | Expression::EnumCase { .. }
+            | Expression::Match { .. }
+            | Expression::Nil
+            | Expression::Unwrap(_)
+            | Expression::Coalesce { .. }
+            | Expression::Await(_) => false,
+        }
+    }
+
+    /// Whether values of `ty` can be computed at compile time and emitted as literals
+    fn is_constant_type(ty: &Type) -> bool {
+        match ty {
+            Type::Int | Type::Float | Type::Bool | Type::String => true,
+            Type::FixedArray(element, _) => Self::is_constant_type(element),
+            Type::Tuple(elements) => elements.iter().all(Self::is_constant_type),
+            Type::Record(fields) => fields.iter().all(|(_, ty)| Self::is_constant_type(ty)),
+            Type::Custom(_)
+            | Type::Generic { .. }
+            | Type::Array(_)
+            | Type::Optional(_)
+            | Type::Function { .. } => false,
+        }
+    }
+
+    /// Checks that a pure method depends only on its arguments: it takes and
+    /// returns constant types, reads no fields and calls only pure methods
+    fn check_pure_method(&self, method: &Method) -> Result<(), SemanticError> {
+        let return_type = method.return_type.as_ref().ok_or_else(|| {
+            SemanticError::InvalidOperation(format!(
+                "Pure method {} must return a value",
+                method.name
+            ))
+        })?;
+        for param in &method.params {
+            if matches!(param.ownership, OwnershipType::Inout)
+                || !Self::is_constant_type(&param.param_type)
+            {
+                return Err(SemanticError::InvalidOperation(format!(
+                    "Parameter {} of pure method {} must be a plain value (Int, Float, Bool, String or fixed-size aggregates of them)",
+                    param.name, method.name
+                )));
+            }
+        }
+        if !Self::is_constant_type(return_type) {
+            return Err(SemanticError::InvalidOperation(format!(
+                "Pure method {} must return a plain value, found {:?}",
+                method.name, return_type
+            )));
+        }
+
+        let locals: HashSet<&str> = method.params.iter().map(|p| p.name.as_str()).collect();
+        if let Some(body) = &method.body {
+            self.check_pure_body(method, body, locals)?;
+        }
+        Ok(())
+    }
+
+    fn check_pure_body<'a>(
+        &self,
+        method: &Method,
+        body: &'a MethodBody,
+        mut locals: HashSet<&'a str>,
+    ) -> Result<(), SemanticError> {
+        for statement in &body.statements {
+            match statement {
+                Statement::Return(expr) | Statement::Yield(expr) | Statement::Expression(expr) => {
+                    self.check_pure_expression(method, expr, &locals)?
+                }
+                Statement::Let { pattern, value, .. } => {
+                    self.check_pure_expression(method, value, &locals)?;
+                    Self::pattern_bindings(pattern, &mut locals);
+                }
+                Statement::If {
+                    condition,
+                    then_body,
+                    else_body,
+                } => {
+                    self.check_pure_expression(method, condition, &locals)?;
+                    // 分岐内の束縛は分岐の外から見えない
+                    for branch in std::iter::once(then_body).chain(else_body) {
+                        self.check_pure_body(method, branch, locals.clone())?;
+                    }
+                }
+                Statement::While {
+                    condition, body, ..
+                } => {
+                    self.check_pure_expression(method, condition, &locals)?;
+                    self.check_pure_body(method, body, locals.clone())?;
+                }
+                Statement::Match { value, arms } => {
+                    self.check_pure_expression(method, value, &locals)?;
+                    for (pattern, body) in arms {
+                        let mut arm_locals = locals.clone();
+                        Self::pattern_bindings(pattern, &mut arm_locals);
+                        self.check_pure_body(method, body, arm_locals)?;
+                    }
+                }
+                Statement::For {
+                    variable,
+                    start,
+                    end,
+                    body,
+                    ..
+                } => {
+                    self.check_pure_expression(method, start, &locals)?;
+                    self.check_pure_expression(method, end, &locals)?;
+                    let mut body_locals = locals.clone();
+                    body_locals.insert(variable.as_str());
+                    self.check_pure_body(method, body, body_locals)?;
+                }
+                Statement::Log { level, .. } => {
+                    return Err(SemanticError::InvalidOperation(format!(
+                        "Pure method {} cannot call log.{}",
+                        method.name,
+                        level.name()
+                    )))
+                }
+                Statement::Publish { topic, .. } | Statement::Subscribe(topic) => {
+                    return Err(SemanticError::InvalidOperation(format!(
+                        "Pure method {} cannot use topic {}",
+                        method.name, topic
+                    )))
+                }
+                // ローカル変数への代入は純粋
+                Statement::Assign {
+                    target: Expression::Variable(name),
+                    value,
+                    ..
+                } if locals.contains(name.as_str()) => {
+                    self.check_pure_expression(method, value, &locals)?
+                }
+                Statement::Assign { .. } => {
+                    return Err(SemanticError::InvalidOperation(format!(
+                        "Pure method {} cannot assign fields",
+                        method.name
+                    )))
+                }
+                Statement::Break(_) | Statement::Continue(_) => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn check_pure_expression(
+        &self,
+        method: &Method,
+        expr: &Expression,
+        locals: &HashSet<&str>,
+    ) -> Result<(), SemanticError> {
+        let impure = |what: String| {
+            Err(SemanticError::InvalidOperation(format!(
+                "Pure method {} {}",
+                method.name, what
+            )))
+        };
+        match expr {
+            Expression::Variable(name)
+                if !locals.contains(name.as_str()) && self.fields.contains_key(name) =>
+            {
+                impure(format!("cannot read field {}", name))
+            }
+            Expression::Field(name) => impure(format!("cannot read field {}", name)),
+            Expression::Call { callee, .. }
+                if !self.methods.get(callee).is_some_and(|m| m.is_pure) =>
+            {
+                impure(format!("cannot call {}, which is not pure", callee))
+            }
+            Expression::Atomic { operation, .. } => {
+                impure(format!("cannot use {}", operation.name()))
+            }
+            Expression::Random { operation, .. } => {
+                impure(format!("cannot call {}", operation.name()))
+            }
+            Expression::Config(key) => impure(format!("cannot read config(\"{}\")", key)),
+            Expression::Inout(name) => impure(format!("cannot pass &{}", name)),
+            Expression::Await(value) => impure(format!("cannot await {}", value)),
+            Expression::BinaryOp { left, right, .. }
+            | Expression::Index {
+                base: left,
+                index: right,
+            }
+            | Expression::Coalesce {
+                value: left,
+                default: right,
+            } => {
+                self.check_pure_expression(method, left, locals)?;
+                self.check_pure_expression(method, right, locals)
+            }
+            Expression::Call { args, .. }
+            | Expression::Comptime { args, .. }
+            | Expression::EnumCase { args, .. }
+            | Expression::Tuple(args)
+            | Expression::ArrayLiteral(args) => args
+                .iter()
+                .try_for_each(|arg| self.check_pure_expression(method, arg, locals)),
+            Expression::Match { value, arms } => {
+                self.check_pure_expression(method, value, locals)?;
+                for (pattern, result) in arms {
+                    let mut arm_locals = locals.clone();
+                    Self::pattern_bindings(pattern, &mut arm_locals);
+                    self.check_pure_expression(method, result, &arm_locals)?;
+                }
+                Ok(())
+            }
+            Expression::Record(fields) | Expression::StructLiteral { fields, .. } => fields
+                .iter()
+                .try_for_each(|(_, value)| self.check_pure_expression(method, value, locals)),
+            Expression::Conversion { value, .. }
+            | Expression::Member { base: value, .. }
+            | Expression::Unwrap(value) => self.check_pure_expression(method, value, locals),
+            Expression::Literal(_) | Expression::Variable(_) | Expression::Nil => Ok(()),
+        }
+    }
+
+    /// Adds the names bound by `pattern` to `names`
+    fn pattern_bindings<'a>(pattern: &'a Pattern, names: &mut HashSet<&'a str>) {
+        match pattern {
+            Pattern::Binding(name) => {
+                names.insert(name);
+            }
+            Pattern::Tuple(patterns) | Pattern::Case { args: patterns, .. } => {
+                for pattern in patterns {
+                    Self::pattern_bindings(pattern, names);
+                }
+            }
+            Pattern::Wildcard | Pattern::Literal(_) => {}
+        }
+    }
+
+    /// Checks `Name(field: value, ...)`, which must give every field of the struct
+    /// exactly once
+    fn analyze_struct_literal(
+        &self,
+        name: &str,
+        fields: &[(String, Expression)],
+    ) -> Result<Type, SemanticError> {
+        let declaration = self.structs.get(name).ok_or_else(|| {
+            SemanticError::TypeError(format!(
+                "Unknown struct {}; only struct values take labeled arguments",
+                name
+            ))
+        })?;
+        for (index, (label, value)) in fields.iter().enumerate() {
+            let field = declaration
+                .field_index(label)
+                .map(|i| &declaration.fields[i])
+                .ok_or_else(|| {
+                    SemanticError::TypeError(format!("Struct {} has no field {}", name, label))
+                })?;
+            if fields[..index].iter().any(|(other, _)| other == label) {
+                return Err(SemanticError::TypeError(format!(
+                    "Field {} of {} is given more than once",
+                    label, name
+                )));
+            }
+            let found = self.analyze_expression(value)?;
+            if !self.check_type_compatibility(&field.field_type, &found) {
+                return Err(SemanticError::TypeError(format!(
+                    "Field {} of {} has type {:?}, found {:?}",
+                    label, name, field.field_type, found
+                )));
+            }
+        }
+        if let Some(missing) = declaration
+            .fields
+            .iter()
+            .find(|field| !fields.iter().any(|(label, _)| *label == field.name))
+        {
+            return Err(SemanticError::TypeError(format!(
+                "Value of {} is missing the field {}",
+                name, missing.name
+            )));
+        }
+        Ok(Type::Custom(name.to_string()))
+    }
+
+    /// Checks `Name.case(values)`, which must give every associated value of
+    /// the case
+    fn analyze_enum_case(
+        &self,
+        enum_name: &str,
+        case: &str,
+        args: &[Expression],
+    ) -> Result<Type, SemanticError> {
+        let declaration = self
+            .enums
+            .get(enum_name)
+            .ok_or_else(|| SemanticError::TypeError(format!("Unknown enum {}", enum_name)))?;
+        let values = declaration
+            .case_index(case)
+            .map(|i| &declaration.cases[i].values)
+            .ok_or_else(|| {
+                SemanticError::TypeError(format!("Enum {} has no case {}", enum_name, case))
+            })?;
+        if args.len() != values.len() {
+            return Err(SemanticError::TypeError(format!(
+                "Case {}.{} takes {} values, found {}",
+                enum_name,
+                case,
+                values.len(),
+                args.len()
+            )));
+        }
+        for (index, (arg, expected)) in args.iter().zip(values).enumerate() {
+            let found = self.analyze_expression(arg)?;
+            if !self.check_type_compatibility(expected, &found) {
+                return Err(SemanticError::TypeError(format!(
+                    "Value {} of {}.{} has type {:?}, found {:?}",
+                    index + 1,
+                    enum_name,
+                    case,
+                    expected,
+                    found
+                )));
+            }
+        }
+        Ok(Type::Custom(enum_name.to_string()))
+    }
+
+    /// Checks that the arms of a `match` on a value of `value_type` cover
+    /// every value, and that no arm follows one matching everything
+    fn check_match_arms<'p>(
+        &self,
+        value_type: &Type,
+        patterns: impl IntoIterator<Item = &'p Pattern>,
+    ) -> Result<(), SemanticError> {
+        let patterns: Vec<&Pattern> = patterns.into_iter().collect();
+        if let Some(index) = patterns.iter().position(|p| Self::is_irrefutable(p)) {
+            if let Some(unreachable) = patterns.get(index + 1) {
+                return Err(SemanticError::InvalidOperation(format!(
+                    "Arm {} of the match is unreachable after {}, which matches every value",
+                    unreachable, patterns[index]
+                )));
+            }
+        }
+        match self.uncovered(value_type, &patterns) {
+            Some(missing) => Err(SemanticError::InvalidOperation(format!(
+                "Match on {} is not exhaustive: {} is not covered",
+                value_type, missing
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    /// A pattern for values of `ty` that none of `patterns` matches, if any.
+    /// Enums and optionals are covered case by case, looking into the value of
+    /// cases that carry one, and `Bool` by `true` and `false`; any other type
+    /// needs a pattern matching everything.
+    fn uncovered(&self, ty: &Type, patterns: &[&Pattern]) -> Option<String> {
+        if patterns.iter().any(|pattern| Self::is_irrefutable(pattern)) {
+            return None;
+        }
+        if *ty == Type::Bool {
+            return [true, false]
+                .into_iter()
+                .find(|value| {
+                    !patterns.iter().any(|pattern| {
+                        matches!(pattern, Pattern::Literal(LiteralValue::Bool(b)) if b == value)
+                    })
+                })
+                .map(|value| value.to_string());
+        }
+        let cases: Vec<(&str, Vec<&Type>)> = match ty {
+            Type::Optional(inner) => vec![("some", vec![inner.as_ref()]), ("none", Vec::new())],
+            Type::Custom(name) if self.enums.contains_key(name) => self.enums[name]
+                .cases
+                .iter()
+                .map(|case| (case.name.as_str(), case.values.iter().collect()))
+                .collect(),
+            _ => return Some("_".to_string()),
+        };
+        for (case, values) in cases {
+            let rows: Vec<&[Pattern]> = patterns
+                .iter()
+                .filter_map(|pattern| match pattern {
+                    Pattern::Case { name, args } if name == case => Some(args.as_slice()),
+                    _ => None,
+                })
+                .collect();
+            if rows.iter().any(|row| row.iter().all(Self::is_irrefutable)) {
+                continue;
+            }
+            // 値を一つだけ運ぶケースは、その値の網羅性を調べる
+            if let [value] = values.as_slice() {
+                let inner: Vec<&Pattern> = rows.iter().filter_map(|row| row.first()).collect();
+                match self.uncovered(value, &inner) {
+                    Some(missing) => return Some(format!(".{}({})", case, missing)),
+                    None => continue,
+                }
+            }
+            return Some(match values.len() {
+                0 => format!(".{}", case),
+                count => format!(".{}({})", case, vec!["_"; count].join(", ")),
+            });
+        }
+        None
+    }
+
+    /// Checks `base[index]`. Constant indices into fixed-size arrays are checked
+    /// here; dynamic ones are checked at run time.
+    fn analyze_index(&self, base: &Expression, index: &Expression) -> Result<Type, SemanticError> {
+        let index_type = self.analyze_expression(index)?;
+        // 定数のマップはキーで引き、無いキーには nil を返す
+        if let Some((
+            name,
+            ConstantTable::Map {
+                key_type,
+                value_type,
+                ..
+            },
+        )) = self.constant_table(base)
+        {
+            if !self.check_type_compatibility(key_type, &index_type) {
+                return Err(SemanticError::TypeError(format!(
+                    "Keys of constant map {} are {}, found {:?}",
+                    name, key_type, index_type
+                )));
+            }
+            return Ok(Type::Optional(Box::new(value_type.clone())));
+        }
+        if !matches!(index_type, Type::Int) {
+            return Err(SemanticError::TypeError(format!(
+                "Array index must be Int, found {:?}",
+                index_type
+            )));
+        }
+
+        let base_type = self.analyze_expression(base)?;
+        self.check_not_opaque(&base_type)?;
+        match base_type {
+            Type::Array(element_type) => Ok(*element_type),
+            Type::FixedArray(element_type, length) => {
+                if let Expression::Literal(LiteralValue::Int(value)) = index {
+                    if *value < 0 || *value as usize >= length {
+                        return Err(SemanticError::InvalidOperation(format!(
+                            "Index {} is out of bounds for an array of length {}",
+                            value, length
+                        )));
+                    }
+                }
+                Ok(*element_type)
+            }
+            base_type => Err(SemanticError::TypeError(format!(
+                "Cannot index into a value of type {:?}",
+                base_type
+            ))),
+        }
+    }
+
+    /// Whether a pattern matches every value of its type
+    pub fn is_irrefutable(pattern: &Pattern) -> bool {
+        match pattern {
+            Pattern::Wildcard | Pattern::Binding(_) => true,
+            Pattern::Tuple(patterns) => patterns.iter().all(Self::is_irrefutable),
+            Pattern::Literal(_) | Pattern::Case { .. } => false,
+        }
+    }
+
+    /// Checks a pattern against the type of the matched value and declares its
+    /// bindings, which can be assigned if `is_mutable`
+    fn bind_pattern(
+        &self,
+        pattern: &Pattern,
+        value_type: &Type,
+        is_mutable: bool,
+    ) -> Result<(), SemanticError> {
+        match (pattern, value_type) {
+            (Pattern::Wildcard, _) => Ok(()),
+            (Pattern::Binding(name), _) => {
+                self.declare_variable(name, value_type.clone(), is_mutable);
+                Ok(())
+            }
+            (Pattern::Literal(value), _) => {
+                let literal_type = self.analyze_expression(&Expression::Literal(value.clone()))?;
+                if self.check_type_compatibility(value_type, &literal_type) {
+                    Ok(())
+                } else {
+                    Err(SemanticError::TypeError(format!(
+                        "Pattern {:?} cannot match a value of type {:?}",
+                        value, value_type
+                    )))
+                }
+            }
+            (Pattern::Tuple(patterns), Type::Tuple(types)) if patterns.len() == types.len() => {
+                for (pattern, element_type) in patterns.iter().zip(types) {
+                    self.bind_pattern(pattern, element_type, is_mutable)?;
+                }
+                Ok(())
+            }
+            // レコードはラベル順に並ぶため、位置で分解すると書いた順と食い違う
+            (Pattern::Tuple(_), Type::Record(_)) => Err(SemanticError::TypeError(format!(
+                "Cannot destructure record {} by position; read its fields by label",
+                value_type
+            ))),
+            (Pattern::Case { name, args }, Type::Custom(enum_name))
+                if self.enums.contains_key(enum_name) =>
+            {
+                let declaration = &self.enums[enum_name];
+                let values = declaration
+                    .case_index(name)
+                    .map(|i| &declaration.cases[i].values)
+                    .ok_or_else(|| {
+                        SemanticError::TypeError(format!("Enum {} has no case {}", enum_name, name))
+                    })?;
+                if args.len() != values.len() {
+                    return Err(SemanticError::TypeError(format!(
+                        "Case .{} of {} has {} values, the pattern has {}",
+                        name,
+                        enum_name,
+                        values.len(),
+                        args.len()
+                    )));
+                }
+                for (pattern, value_type) in args.iter().zip(values) {
+                    self.bind_pattern(pattern, value_type, is_mutable)?;
+                }
+                Ok(())
+            }
+            (Pattern::Case { name, args }, Type::Optional(inner)) => {
+                match (name.as_str(), args.as_slice()) {
+                    ("some", [payload]) => self.bind_pattern(payload, inner, is_mutable),
+                    ("none", []) => Ok(()),
+                    _ => Err(SemanticError::TypeError(format!(
+                        "Optional has no case .{} with {} values",
+                        name,
+                        args.len()
+                    ))),
+                }
+            }
+            _ => Err(SemanticError::TypeError(format!(
+                "Pattern {:?} cannot match a value of type {:?}",
+                pattern, value_type
+            ))),
+        }
+    }
+
+    /// Declares a local variable in the innermost scope, hiding any outer binding
+    fn declare_variable(&self, name: &str, var_type: Type, is_mutable: bool) {
+        self.current_scope
+            .borrow_mut()
+            .last_mut()
+            .unwrap()
+            .insert(name.to_string(), var_type);
+        let mut bindings = self.mutable_bindings.borrow_mut();
+        let mutable = bindings.last_mut().unwrap();
+        if is_mutable {
+            mutable.insert(name.to_string());
+        } else {
+            mutable.remove(name);
+        }
+    }
+
+    fn push_scope(&self, scope: HashMap<String, Type>) {
+        self.current_scope.borrow_mut().push(scope);
+        self.mutable_bindings.borrow_mut().push(HashSet::new());
+    }
+
+    fn pop_scope(&self) {
+        self.current_scope.borrow_mut().pop();
+        self.mutable_bindings.borrow_mut().pop();
+    }
+
+    /// Whether the innermost local variable called `name` is a `var` binding or an
+    /// inout parameter
+    fn is_mutable_variable(&self, name: &str) -> bool {
+        self.current_scope
+            .borrow()
+            .iter()
+            .zip(self.mutable_bindings.borrow().iter())
+            .rev()
+            .find(|(scope, _)| scope.contains_key(name))
+            .is_some_and(|(_, mutable)| mutable.contains(name))
+    }
+
+    fn lookup_variable(&self, name: &str) -> Result<Type, SemanticError> {
+        // 変数の型を現在のスコープから探す
+        for scope in self.current_scope.borrow().iter().rev() {
+            if let Some(var_type) = scope.get(name) {
+                return Ok(var_type.clone());
+            }
+        }
+        Err(SemanticError::UndefinedVariable(name.to_string()))
+    }
+
+    /// Returns the function type of a method referenced by name, e.g. to pass it to
+    /// the host as a callback
+    fn method_reference_type(&self, method: &Method) -> Result<Type, SemanticError> {
+        if method
+            .params
+            .iter()
+            .any(|param| matches!(param.ownership, OwnershipType::Inout))
+        {
+            return Err(SemanticError::OwnershipError(format!(
+                "Method {} has inout parameters and cannot be used as a function value",
+                method.name
+            )));
+        }
+        Ok(Type::Function {
+            params: method
+                .params
+                .iter()
+                .map(|param| param.param_type.clone())
+                .collect(),
+            return_type: method.return_type.clone().map(Box::new),
+        })
+    }
+
+    /// Checks a method call and returns the method's return type
+    /// Checks `await value` in the method being analyzed: only async methods
+    /// can await, and only calls of async methods. Returns the type of the
+    /// call, or `None` when the method returns nothing.
+    fn analyze_await(&self, value: &Expression) -> Result<Option<Type>, SemanticError> {
+        if !self.in_async {
+            return Err(SemanticError::AsyncError(format!(
+                "await {} can only be used in an async method",
+                value
+            )));
+        }
+        match value {
+            Expression::Call {
+                callee,
+                type_args,
+                args,
+            } if self
+                .methods
+                .get(callee)
+                .is_some_and(|method| method.is_async) =>
+            {
+                Self::check_no_type_arguments(callee, type_args)?;
+                self.analyze_call(callee, args)
+            }
+            _ => Err(SemanticError::AsyncError(format!(
+                "Only calls of async methods can be awaited, found {}",
+                value
+            ))),
+        }
+    }
+
+    fn analyze_call(
+        &self,
+        callee: &str,
+        args: &[Expression],
+    ) -> Result<Option<Type>, SemanticError> {
+        let method = self
+            .methods
+            .get(callee)
+            .ok_or_else(|| SemanticError::UndefinedVariable(callee.to_string()))?;
+        // ストリームはホストが next で引き出すもので、アクター内からは消費できない
+        if method.stream_item().is_some() {
+            return Err(SemanticError::InvalidOperation(format!(
+                "{} returns an AsyncStream, which only the host can consume",
+                callee
+            )));
+        }
+
+        if method.params.len() != args.len() {
+            return Err(SemanticError::TypeError(format!(
+                "Method {} expects {} arguments, found {}",
+                callee,
+                method.params.len(),
+                args.len()
+            )));
+        }
+
+        for (param, arg) in method.params.iter().zip(args) {
+            let is_inout = matches!(param.ownership, OwnershipType::Inout);
+            let compatible = match arg {
+                Expression::Inout(name) if is_inout => {
+                    // inout は型が完全に一致する必要がある
+                    let arg_type = self.analyze_inout_argument(name)?;
+                    self.check_type_compatibility(&param.param_type, &arg_type)
+                        && self.check_type_compatibility(&arg_type, &param.param_type)
+                }
+                Expression::Inout(name) => {
+                    return Err(SemanticError::OwnershipError(format!(
+                        "&{} passed to parameter {} which is not inout",
+                        name, param.name
+                    )))
+                }
+                _ if is_inout => {
+                    return Err(SemanticError::OwnershipError(format!(
+                        "Argument for inout parameter {} must be passed with &",
+                        param.name
+                    )))
+                }
+                _ => {
+                    let arg_type = self.analyze_expected(arg, &param.param_type)?;
+                    self.check_type_compatibility(&param.param_type, &arg_type)
+                }
+            };
+
+            if !compatible {
+                return Err(SemanticError::TypeError(format!(
+                    "Argument type mismatch for parameter {} of {}",
+                    param.name, callee
+                )));
+            }
+        }
+
+        Ok(method.return_type.clone())
+    }
+
+    /// Resolves the type of a `&name` argument, which must refer to mutable storage
+    /// Checks an atomic built-in and returns its result type. The target must be
+    /// a `Shared` Int field and the ordering must be valid for the operation.
+    fn analyze_atomic(
+        &self,
+        operation: AtomicOperation,
+        field: &str,
+        args: &[Expression],
+        ordering: MemoryOrdering,
+    ) -> Result<Option<Type>, SemanticError> {
+        let name = operation.name();
+        let target = match self.fields.get(field) {
+            Some(target) if self.lookup_variable(field).is_err() => target,
+            _ => {
+                return Err(SemanticError::InvalidOperation(format!(
+                    "{} can only be applied to Shared fields, and {} is not a field",
+                    name, field
+                )))
+            }
+        };
+        if !matches!(target.ownership, OwnershipType::Shared) {
+            return Err(SemanticError::OwnershipError(format!(
+                "{} requires a Shared field, but {} has {:?} ownership",
+                name, field, target.ownership
+            )));
+        }
+        if !matches!(target.field_type, Type::Int) {
+            return Err(SemanticError::TypeError(format!(
+                "{} operates on Int fields, but {} has type {:?}",
+                name, field, target.field_type
+            )));
+        }
+
+        if args.len() != operation.arity() {
+            return Err(SemanticError::TypeError(format!(
+                "{} expects {} arguments after &{}, found {}",
+                name,
+                operation.arity(),
+                field,
+                args.len()
+            )));
+        }
+        for arg in args {
+            let arg_type = self.analyze_expression(arg)?;
+            if !self.check_type_compatibility(&Type::Int, &arg_type) {
+                return Err(SemanticError::TypeError(format!(
+                    "{} expects Int arguments, found {:?}",
+                    name, arg_type
+                )));
+            }
+        }
+
+        // 読み取りに release、書き込みに acquire は指定できない
+        let valid_ordering = match operation {
+            AtomicOperation::Load => !matches!(
+                ordering,
+                MemoryOrdering::Release | MemoryOrdering::AcquireRelease
+            ),
+            AtomicOperation::Store => !matches!(
+                ordering,
+                MemoryOrdering::Acquire | MemoryOrdering::AcquireRelease
+            ),
+            AtomicOperation::Add | AtomicOperation::CompareExchange => true,
+        };
+        if !valid_ordering {
+            return Err(SemanticError::InvalidOperation(format!(
+                "{} cannot use the {} ordering",
+                name,
+                ordering.name()
+            )));
+        }
+
+        Ok(match operation {
+            AtomicOperation::Load | AtomicOperation::Add => Some(Type::Int),
+            AtomicOperation::Store => None,
+            AtomicOperation::CompareExchange => Some(Type::Record(vec![
+                ("exchanged".to_string(), Type::Bool),
+                ("original".to_string(), Type::Int),
+            ])),
+        })
+    }
+
+    fn analyze_inout_argument(&self, name: &str) -> Result<Type, SemanticError> {
+        if let Ok(var_type) = self.lookup_variable(name) {
+            if !self.is_mutable_variable(name) {
+                return Err(SemanticError::OwnershipError(format!(
+                    "Cannot pass immutable binding {} as inout",
+                    name
+                )));
+            }
+            return Ok(var_type);
+        }
+
+        match self.fields.get(name) {
+            Some(field) if field.is_mutable => Ok(field.field_type.clone()),
+            Some(_) => Err(SemanticError::OwnershipError(format!(
+                "Cannot pass let field {} as inout",
+                name
+            ))),
+            None => Err(SemanticError::UndefinedVariable(name.to_string())),
+        }
+    }
+
+    fn analyze_statement(
+        &mut self,
+        stmt: &Statement,
+        expected_return_type: &Option<Type>,
+    ) -> Result<(), SemanticError> {
+        match stmt {
+            Statement::Return(expr) => {
+                let expr_type = match expected_return_type {
+                    Some(expected) => self.analyze_expected(expr, expected)?,
+                    None => self.analyze_expression(expr)?,
+                };
+                if expected_return_type
+                    .as_ref()
+                    .is_some_and(|expected| expected.stream_item().is_some())
+                {
+                    return Err(SemanticError::InvalidOperation(
+                        "A method returning AsyncStream cannot return a value; the stream ends at the end of its body".to_string(),
+                    ));
+                }
+                if let Some(expected) = expected_return_type {
+                    if !self.check_type_compatibility(expected, &expr_type) {
+                        return Err(SemanticError::TypeError(format!(
+                            "Return type mismatch: expected {:?}, found {:?}",
+                            expected, expr_type
+                        )));
+                    }
+                }
+                Ok(())
+            }
+            Statement::Expression(Expression::Call {
+                callee,
+                type_args,
+                args,
+            }) => {
+                // 戻り値のないメソッド呼び出しも文としては有効
+                Self::check_no_type_arguments(callee, type_args)?;
+                self.analyze_call(callee, args)?;
+                Ok(())
+            }
+            Statement::Expression(Expression::Await(value)) => {
+                self.analyze_await(value)?;
+                Ok(())
+            }
+            Statement::Expression(Expression::Atomic {
+                operation,
+                field,
+                args,
+                ordering,
+            }) => {
+                self.analyze_atomic(*operation, field, args, *ordering)?;
+                Ok(())
+            }
+            Statement::Expression(expr) => {
+                self.analyze_expression(expr)?;
+                Ok(())
+            }
+            Statement::Yield(expr) => {
+                let item = expected_return_type
+                    .as_ref()
+                    .and_then(Type::stream_item)
+                    .ok_or_else(|| {
+                        SemanticError::InvalidOperation(
+                            "yield can only be used in a method returning AsyncStream".to_string(),
+                        )
+                    })?;
+                let expr_type = self.analyze_expression(expr)?;
+                if !self.check_type_compatibility(item, &expr_type) {
+                    return Err(SemanticError::TypeError(format!(
+                        "Yielded type mismatch: expected {:?}, found {:?}",
+                        item, expr_type
+                    )));
+                }
+                Ok(())
+            }
+            Statement::Break(label) => self.check_loop_jump("break", label),
+            Statement::Continue(label) => self.check_loop_jump("continue", label),
+            Statement::Let {
+                pattern,
+                is_mutable,
+                value,
+            } => {
+                let value_type = self.analyze_expression(value)?;
+                if !Self::is_irrefutable(pattern) {
+                    return Err(SemanticError::InvalidOperation(format!(
+                        "Refutable pattern {:?} in let binding",
+                        pattern
+                    )));
+                }
+                self.bind_pattern(pattern, &value_type, *is_mutable)
+            }
+            Statement::If {
+                condition,
+                then_body,
+                else_body,
+            } => {
+                let condition_type = self.analyze_expression(condition)?;
+                if !self.check_type_compatibility(&Type::Bool, &condition_type) {
+                    return Err(SemanticError::TypeError(format!(
+                        "if condition must be Bool, found {:?}",
+                        condition_type
+                    )));
+                }
+                for branch in std::iter::once(then_body).chain(else_body) {
+                    self.analyze_block(branch, HashMap::new(), expected_return_type)?;
+                }
+                Ok(())
+            }
+            Statement::While {
+                label,
+                condition,
+                body,
+            } => {
+                let condition_type = self.analyze_expression(condition)?;
+                if !self.check_type_compatibility(&Type::Bool, &condition_type) {
+                    return Err(SemanticError::TypeError(format!(
+                        "while condition must be Bool, found {:?}",
+                        condition_type
+                    )));
+                }
+                self.analyze_loop_body(label, body, HashMap::new(), expected_return_type)
+            }
+            Statement::For {
+                label,
+                variable,
+                start,
+                end,
+                body,
+            } => {
+                for bound in [start, end] {
+                    let bound_type = self.analyze_expression(bound)?;
+                    if !self.check_type_compatibility(&Type::Int, &bound_type) {
+                        return Err(SemanticError::TypeError(format!(
+                            "for range bounds must be Int, found {:?}",
+                            bound_type
+                        )));
+                    }
+                }
+                let scope = HashMap::from([(variable.clone(), Type::Int)]);
+                self.analyze_loop_body(label, body, scope, expected_return_type)
+            }
+            Statement::Match { value, arms } => {
+                let value_type = self.analyze_expression(value)?;
+                self.check_match_arms(&value_type, arms.iter().map(|(pattern, _)| pattern))?;
+                for (pattern, body) in arms {
+                    // 腕の束縛はその腕の本体からだけ見える
+                    self.push_scope(HashMap::new());
+                    let result = self
+                        .bind_pattern(pattern, &value_type, false)
+                        .and_then(|()| {
+                            self.analyze_block(body, HashMap::new(), expected_return_type)
+                        });
+                    self.pop_scope();
+                    result?;
+                }
+                Ok(())
+            }
+            Statement::Publish { topic, value } => {
+                let topic_type = self.topic_type(topic)?.clone();
+                let value_type = self.analyze_expression(value)?;
+                if !self.check_type_compatibility(&topic_type, &value_type) {
+                    return Err(SemanticError::TypeError(format!(
+                        "Topic {} carries {:?}, found {:?}",
+                        topic, topic_type, value_type
+                    )));
+                }
+                Ok(())
+            }
+            Statement::Subscribe(topic) => self.check_topic_handler(topic),
+            Statement::Log { level, message, .. } => {
+                let message_type = self.analyze_expression(message)?;
+                if !self.check_type_compatibility(&Type::String, &message_type) {
+                    return Err(SemanticError::TypeError(format!(
+                        "log.{} message must be String, found {:?}",
+                        level.name(),
+                        message_type
+                    )));
+                }
+                Ok(())
+            }
+            Statement::Assign {
+                target,
+                operator,
+                value,
+            } => {
+                let (name, target_type) = self.assignment_target(target)?;
+                // 複合代入は `target op value` の結果を格納する
+                let value_type = match operator {
+                    Some(operator) => self.analyze_expression(&Expression::BinaryOp {
+                        left: Box::new(target.clone()),
+                        operator: operator.clone(),
+                        right: Box::new(value.clone()),
+                    })?,
+                    None => self.analyze_expected(value, &target_type)?,
+                };
+                if !self.check_type_compatibility(&target_type, &value_type) {
+                    return Err(SemanticError::TypeError(format!(
+                        "Cannot assign {:?} to {} of type {:?}",
+                        value_type, name, target_type
+                    )));
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Resolves the name and type of what an assignment writes: a `var` local or
+    /// inout parameter, or a mutable field that no local hides
+    fn assignment_target<'a>(
+        &self,
+        target: &'a Expression,
+    ) -> Result<(&'a str, Type), SemanticError> {
+        let name = match target {
+            Expression::Field(name) => name,
+            Expression::Variable(name) => match self.lookup_variable(name) {
+                Ok(var_type) if self.is_mutable_variable(name) => return Ok((name, var_type)),
+                Ok(_) => {
+                    return Err(SemanticError::OwnershipError(format!(
+                        "Cannot assign to immutable binding {}; declare it with var",
+                        name
+                    )))
+                }
+                Err(_) => name,
+            },
+            other => {
+                return Err(SemanticError::InvalidOperation(format!(
+                    "Cannot assign to {:?}",
+                    other
+                )))
+            }
+        };
+        if self.constants.contains_key(name) {
+            return Err(SemanticError::OwnershipError(format!(
+                "Cannot assign to constant {}",
+                name
+            )));
+        }
+        match self.fields.get(name) {
+            Some(field) if field.is_mutable || self.in_init => Ok((name, field.field_type.clone())),
+            Some(_) => Err(SemanticError::OwnershipError(format!(
+                "Cannot assign to let field {}; only init can set it",
+                name
+            ))),
+            None => Err(SemanticError::UndefinedVariable(name.clone())),
+        }
+    }
+
+    /// Analyzes `body` in a new scope starting with the bindings in `scope`
+    fn analyze_block(
+        &mut self,
+        body: &MethodBody,
+        scope: HashMap<String, Type>,
+        expected_return_type: &Option<Type>,
+    ) -> Result<(), SemanticError> {
+        self.push_scope(scope);
+        let result = body
+            .statements
+            .iter()
+            .try_for_each(|statement| self.analyze_statement(statement, expected_return_type));
+        self.pop_scope();
+        result
+    }
+
+    /// Analyzes a loop body, where `break`/`continue` may target the loop
+    fn analyze_loop_body(
+        &mut self,
+        label: &Option<String>,
+        body: &MethodBody,
+        scope: HashMap<String, Type>,
+        expected_return_type: &Option<Type>,
+    ) -> Result<(), SemanticError> {
+        if let Some(label) = label {
+            if self.loop_labels.iter().any(|l| l.as_ref() == Some(label)) {
+                return Err(SemanticError::InvalidOperation(format!(
+                    "Loop label '{}' is already used by an enclosing loop",
+                    label
+                )));
+            }
+        }
+        self.loop_labels.push(label.clone());
+        let result = self.analyze_block(body, scope, expected_return_type);
+        self.loop_labels.pop();
+        result
+    }
+
+    /// Checks that `break`/`continue` occur inside a loop carrying the given label
+    fn check_loop_jump(&self, keyword: &str, label: &Option<String>) -> Result<(), SemanticError> {
+        if self.loop_labels.is_empty() {
+            return Err(SemanticError::InvalidOperation(format!(
+                "{} outside of a loop",
+                keyword
+            )));
+        }
+        match label {
+            Some(label) if !self.loop_labels.iter().any(|l| l.as_ref() == Some(label)) => {
+                Err(SemanticError::InvalidOperation(format!(
+                    "{} refers to unknown loop label '{}'",
+                    keyword, label
+                )))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn analyze_method(
+        &mut self,
+        method: &Method,
+        actor_type: &ActorType,
+    ) -> Result<(), SemanticError> {
+        // 不透明なハンドルは extern 関数との間でのみ受け渡しできる
+        let signature_types = method.params.iter().map(|param| &param.param_type);
+        for ty in signature_types.chain(&method.return_type) {
+            if let Some(name) = self.opaque_type_in(ty) {
+                return Err(SemanticError::InvalidOperation(format!(
+                    "Method {} cannot take or return opaque type {}; it can only be received from and passed back to extern functions",
+                    method.name, name
+                )));
+            }
+        }
+
+        // 新しいスコープを作成
+        self.push_scope(HashMap::new());
+
+        // パラメータをスコープに追加 (inout 引数は代入できる)
+        for param in &method.params {
+            let is_inout = matches!(param.ownership, OwnershipType::Inout);
+            self.declare_variable(&param.name, param.param_type.clone(), is_inout);
+        }
+
+        // async/sequentialのチェック
+        if method.is_sequential && !method.is_async {
+            return Err(SemanticError::AsyncError(
+                "Sequential methods must be async".to_string(),
+            ));
+        }
+        self.in_async = method.is_async;
+
+        // immediateイニシャライザのチェック
+        if method.is_immediate {
+            if method.name != "init" {
+                return Err(SemanticError::AsyncError(
+                    "Only init method can be immediate".to_string(),
+                ));
+            }
+
+            if matches!(actor_type, ActorType::Distributed) {
+                return Err(SemanticError::AsyncError(
+                    "Distributed actors cannot have immediate init".to_string(),
+                ));
+            }
+        }
+
+        if method.is_pure {
+            self.check_pure_method(method)?;
+        }
+
+        // ストリームの状態は呼び出し元より長く生存する
+        if method.stream_item().is_some() {
+            if method.is_pure {
+                return Err(SemanticError::InvalidOperation(format!(
+                    "Pure method {} cannot return AsyncStream",
+                    method.name
+                )));
+            }
+            if let Some(param) = method
+                .params
+                .iter()
+                .find(|param| matches!(param.ownership, OwnershipType::Inout))
+            {
+                return Err(SemanticError::OwnershipError(format!(
+                    "Method {} returns AsyncStream, so it cannot take inout parameter {}",
+                    method.name, param.name
+                )));
+            }
+        }
+
+        // メソッドボディの解析
+        if let Some(body) = &method.body {
+            for statement in &body.statements {
+                self.analyze_statement(statement, &method.return_type)?;
+            }
+
+            // 値を返すメソッドは末尾まで到達してはならない (ストリームは末尾で終わる)
+            if let Some(return_type) = method
+                .return_type
+                .as_ref()
+                .filter(|_| method.stream_item().is_none())
+            {
+                if !Self::always_returns(body) {
+                    return Err(SemanticError::TypeError(format!(
+                        "Method {} must return {:?} on every path; add a return at the end of the body",
+                        method.name, return_type
+                    )));
+                }
+            }
+
+            // inout 引数と共有フィールドの排他性チェック
+            let mut checker = OwnershipChecker::new();
+            for field in self.fields.values() {
+                checker.declare(
+                    &field.name,
+                    OwnershipInfo {
+                        ownership_type: field.ownership.clone(),
+                        is_mutable: field.is_mutable,
+                    },
+                );
+            }
+            checker
+                .check_exclusive_access(body)
+                .map_err(SemanticError::OwnershipError)?;
+        }
+
+        // スコープを削除
+        self.pop_scope();
+
+        // パラメータと戻り値の型の検証
+        for param in &method.params {
+            self.verify_parameter_type(param)?;
+        }
+
+        if let Some(return_type) = &method.return_type {
+            self.verify_return_type(return_type)?;
+        }
+
+        Ok(())
+    }
+
+    /// Checks the `init` block `<Actor>.new` runs, where the parameters are in
+    /// scope and each `let` field is assigned exactly once
+    fn analyze_init(&mut self, init: &Init, actor: &Actor) -> Result<(), SemanticError> {
+        if init.is_immediate && matches!(actor.actor_type, ActorType::Distributed) {
+            return Err(SemanticError::AsyncError(
+                "Distributed actors cannot have immediate init".to_string(),
+            ));
+        }
+        for param in &init.params {
+            if let Some(name) = self.opaque_type_in(&param.param_type) {
+                return Err(SemanticError::InvalidOperation(format!(
+                    "init cannot take opaque type {}; it can only be received from and passed back to extern functions",
+                    name
+                )));
+            }
+            // インスタンスの生成後に呼び出し元の変数は残らない
+            if matches!(param.ownership, OwnershipType::Inout) {
+                return Err(SemanticError::OwnershipError(format!(
+                    "init cannot take inout parameter {}",
+                    param.name
+                )));
+            }
+            self.verify_parameter_type(param)?;
+        }
+        if Self::has_return(&init.body) {
+            return Err(SemanticError::InvalidOperation(
+                "init cannot use return; new returns the instance once init reaches the end of its body"
+                    .to_string(),
+            ));
+        }
+
+        self.push_scope(HashMap::new());
+        for param in &init.params {
+            self.declare_variable(&param.name, param.param_type.clone(), false);
+        }
+        self.in_init = true;
+        for statement in &init.body.statements {
+            self.analyze_statement(statement, &None)?;
+        }
+        self.in_init = false;
+        self.pop_scope();
+
+        // let フィールドはどの経路でもちょうど 1 回代入する
+        let mut assigned: Vec<(&str, Assignments)> = actor
+            .fields
+            .iter()
+            .filter(|field| !field.is_mutable)
+            .map(|field| (field.name.as_str(), Assignments::default()))
+            .collect();
+        let params = init
+            .params
+            .iter()
+            .map(|param| param.name.as_str())
+            .collect();
+        Self::count_let_assignments(&init.body, &mut assigned, &mut vec![params], false)?;
+        for (name, count) in assigned {
+            match count {
+                Assignments { max: 0, .. } => {
+                    return Err(SemanticError::OwnershipError(format!(
+                        "init never assigns let field {}",
+                        name
+                    )))
+                }
+                Assignments { min: 0, .. } => {
+                    return Err(SemanticError::OwnershipError(format!(
+                        "init does not assign let field {} on every path",
+                        name
+                    )))
+                }
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Adds the assignments of `let` fields in `body` to `assigned`. A field is
+    /// written either as `self.name` or as a bare name no parameter or local in
+    /// `locals` hides. Assigning a field a path may already have assigned, or
+    /// assigning one in a loop, is an error.
+    fn count_let_assignments<'a>(
+        body: &'a MethodBody,
+        assigned: &mut Vec<(&str, Assignments)>,
+        locals: &mut Vec<HashSet<&'a str>>,
+        in_loop: bool,
+    ) -> Result<(), SemanticError> {
+        locals.push(HashSet::new());
+        let result = body.statements.iter().try_for_each(|statement| {
+            match statement {
+                Statement::Assign { target, .. } => {
+                    let name = match target {
+                        Expression::Field(name) => Some(name),
+                        Expression::Variable(name)
+                            if !locals.iter().any(|scope| scope.contains(name.as_str())) =>
+                        {
+                            Some(name)
+                        }
+                        _ => None,
+                    };
+                    let Some((name, count)) = assigned
+                        .iter_mut()
+                        .find(|(field, _)| Some(*field) == name.map(String::as_str))
+                    else {
+                        return Ok(());
+                    };
+                    if in_loop {
+                        return Err(SemanticError::OwnershipError(format!(
+                            "Let field {} is assigned in a loop in init; assign it exactly once",
+                            name
+                        )));
+                    }
+                    if count.max > 0 {
+                        return Err(SemanticError::OwnershipError(format!(
+                            "Let field {} is assigned more than once in init",
+                            name
+                        )));
+                    }
+                    count.min += 1;
+                    count.max += 1;
+                }
+                Statement::Let { pattern, .. } => {
+                    if let Some(scope) = locals.last_mut() {
+                        Self::pattern_bindings(pattern, scope);
+                    }
+                }
+                Statement::If {
+                    then_body,
+                    else_body,
+                    ..
+                } => {
+                    let branches = [(None, Some(then_body)), (None, else_body.as_ref())];
+                    Self::join_branches(branches.into_iter(), assigned, locals)?;
+                }
+                // 網羅性は検査済みなので、どれかの腕が必ず実行される
+                Statement::Match { arms, .. } if !arms.is_empty() => {
+                    let branches = arms
+                        .iter()
+                        .map(|(pattern, body)| (Some(pattern), Some(body)));
+                    Self::join_branches(branches, assigned, locals)?;
+                }
+                Statement::While { body, .. } => {
+                    Self::count_let_assignments(body, &mut assigned.clone(), locals, true)?;
+                }
+                Statement::For { variable, body, .. } => {
+                    locals.push(HashSet::from([variable.as_str()]));
+                    let result =
+                        Self::count_let_assignments(body, &mut assigned.clone(), locals, true);
+                    locals.pop();
+                    result?;
+                }
+                _ => {}
+            }
+            Ok(())
+        });
+        locals.pop();
+        result
+    }
+
+    /// Counts the assignments along each branch from the same start, keeping
+    /// the fewest and most any of them makes. A branch runs its body, if any,
+    /// with the names its pattern binds in scope; a missing `else` assigns
+    /// nothing.
+    fn join_branches<'a>(
+        branches: impl Iterator<Item = (Option<&'a Pattern>, Option<&'a MethodBody>)>,
+        assigned: &mut Vec<(&str, Assignments)>,
+        locals: &mut Vec<HashSet<&'a str>>,
+    ) -> Result<(), SemanticError> {
+        let mut joined: Option<Vec<Assignments>> = None;
+        for (pattern, body) in branches {
+            let mut counts = assigned.clone();
+            if let Some(body) = body {
+                let mut bound = HashSet::new();
+                if let Some(pattern) = pattern {
+                    Self::pattern_bindings(pattern, &mut bound);
+                }
+                locals.push(bound);
+                let result = Self::count_let_assignments(body, &mut counts, locals, false);
+                locals.pop();
+                result?;
+            }
+            joined = Some(match joined {
+                None => counts.iter().map(|(_, count)| *count).collect(),
+                Some(joined) => joined
+                    .iter()
+                    .zip(&counts)
+                    .map(|(a, (_, b))| Assignments {
+                        min: a.min.min(b.min),
+                        max: a.max.max(b.max),
+                    })
+                    .collect(),
+            });
+        }
+        for ((_, count), joined) in assigned.iter_mut().zip(joined.unwrap_or_default()) {
+            *count = joined;
+        }
+        Ok(())
+    }
+
+    /// Whether `body` contains a `return`, including in nested blocks
+    fn has_return(body: &MethodBody) -> bool {
+        body.statements.iter().any(|statement| match statement {
+            Statement::Return(_) => true,
+            Statement::If {
+                then_body,
+                else_body,
+                ..
+            } => std::iter::once(then_body)
+                .chain(else_body)
+                .any(Self::has_return),
+            Statement::Match { arms, .. } => arms.iter().any(|(_, body)| Self::has_return(body)),
+            Statement::While { body, .. } | Statement::For { body, .. } => Self::has_return(body),
+            _ => false,
+        })
+    }
+
+    fn analyze_deinit(&mut self, deinit: &Deinit) -> Result<(), SemanticError> {
+        // インスタンス破棄中に中断することはできない
+        if deinit.is_async {
+            return Err(SemanticError::AsyncError(
+                "deinit cannot be async".to_string(),
+            ));
+        }
+
+        Self::check_deinit_body(&deinit.body)?;
+
+        self.push_scope(HashMap::new());
+        for statement in &deinit.body.statements {
+            self.analyze_statement(statement, &None)?;
+        }
+        self.pop_scope();
+
+        Ok(())
+    }
+
+    /// Whether every path through `body` ends in a `return` or an endless loop
+    fn always_returns(body: &MethodBody) -> bool {
+        body.statements.iter().any(|statement| match statement {
+            Statement::Return(_) => true,
+            Statement::If {
+                then_body,
+                else_body: Some(else_body),
+                ..
+            } => Self::always_returns(then_body) && Self::always_returns(else_body),
+            // match は網羅的なので、すべての腕が返せば必ず返る
+            Statement::Match { arms, .. } => {
+                !arms.is_empty() && arms.iter().all(|(_, body)| Self::always_returns(body))
+            }
+            Statement::While {
+                label,
+                condition: Expression::Literal(LiteralValue::Bool(true)),
+                body,
+            } => !Self::breaks_out(body, label, true),
+            _ => false,
+        })
+    }
+
+    /// Whether `body` contains a `break` leaving the loop labelled `label`.
+    /// An unlabelled `break` counts only outside nested loops (`innermost`).
+    fn breaks_out(body: &MethodBody, label: &Option<String>, innermost: bool) -> bool {
+        body.statements.iter().any(|statement| match statement {
+            Statement::Break(None) => innermost,
+            Statement::Break(target) => label.is_some() && target == label,
+            Statement::If {
+                then_body,
+                else_body,
+                ..
+            } => std::iter::once(then_body)
+                .chain(else_body)
+                .any(|branch| Self::breaks_out(branch, label, innermost)),
+            Statement::Match { arms, .. } => arms
+                .iter()
+                .any(|(_, body)| Self::breaks_out(body, label, innermost)),
+            Statement::While { body, .. } | Statement::For { body, .. } => {
+                Self::breaks_out(body, label, false)
+            }
+            _ => false,
+        })
+    }
+
+    /// Rejects `return` and uses of `self` that could let it escape from a deinit
+    fn check_deinit_body(body: &MethodBody) -> Result<(), SemanticError> {
+        for statement in &body.statements {
+            // self を外部に逃がすことは禁止
+            let expr = match statement {
+                Statement::Return(_) => {
+                    return Err(SemanticError::InvalidOperation(
+                        "deinit cannot return a value".to_string(),
+                    ))
+                }
+                Statement::Subscribe(topic) => {
+                    return Err(SemanticError::InvalidOperation(format!(
+                        "deinit cannot subscribe to topic {}",
+                        topic
+                    )))
+                }
+                Statement::Expression(expr)
+                | Statement::Yield(expr)
+                | Statement::Let { value: expr, .. }
+                | Statement::Log { message: expr, .. }
+                | Statement::Publish { value: expr, .. }
+                | Statement::Assign { value: expr, .. } => expr,
+                Statement::If {
+                    condition,
+                    then_body,
+                    else_body,
+                } => {
+                    for branch in std::iter::once(then_body).chain(else_body) {
+                        Self::check_deinit_body(branch)?;
+                    }
+                    condition
+                }
+                Statement::While {
+                    condition, body, ..
+                } => {
+                    Self::check_deinit_body(body)?;
+                    condition
+                }
+                Statement::Match { value, arms } => {
+                    for (_, body) in arms {
+                        Self::check_deinit_body(body)?;
+                    }
+                    value
+                }
+                Statement::For {
+                    start, end, body, ..
+                } => {
+                    Self::check_deinit_body(body)?;
+                    if Self::references_self(start) {
+                        return Err(SemanticError::OwnershipError(
+                            "self cannot escape from deinit".to_string(),
+                        ));
+                    }
+                    end
+                }
+                Statement::Break(_) | Statement::Continue(_) => continue,
+            };
+            if Self::references_self(expr) {
+                return Err(SemanticError::OwnershipError(
+                    "self cannot escape from deinit".to_string(),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    fn references_self(expr: &Expression) -> bool {
+        match expr {
+            Expression::BinaryOp { left, right, .. } => {
+                Self::references_self(left) || Self::references_self(right)
+            }
+            Expression::Variable(name) | Expression::Inout(name) => name == "self",
+            Expression::Call { args, .. }
+            | Expression::Comptime { args, .. }
+            | Expression::Atomic { args, .. }
+            | Expression::Random { args, .. }
+            | Expression::EnumCase { args, .. }
+            | Expression::Tuple(args)
+            | Expression::ArrayLiteral(args) => args.iter().any(Self::references_self),
+            Expression::Match { value, arms } => {
+                Self::references_self(value)
+                    || arms.iter().any(|(_, result)| Self::references_self(result))
+            }
+            Expression::Index { base, index }
+            | Expression::Coalesce {
+                value: base,
+                default: index,
+            } => Self::references_self(base) || Self::references_self(index),
+            Expression::Record(fields) | Expression::StructLiteral { fields, .. } => {
+                fields.iter().any(|(_, value)| Self::references_self(value))
+            }
+            Expression::Conversion { value, .. }
+            | Expression::Member { base: value, .. }
+            | Expression::Unwrap(value)
+            | Expression::Await(value) => Self::references_self(value),
+            // フィールドの読み取りは self を逃がさない
+            Expression::Literal(_)
+            | Expression::Field(_)
+            | Expression::Config(_)
+            | Expression::Nil => false,
+        }
+    }
+
+    fn verify_parameter_type(&self, param: &Parameter) -> Result<(), SemanticError> {
+        // パラメータの型が有効かチェック
+        match &param.param_type {
+            Type::Custom(name) => {
+                if !self.type_environment.contains_key(name) {
+                    return Err(SemanticError::TypeError(format!(
+                        "Unknown type {} for parameter {}",
+                        name, param.name
+                    )));
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn verify_return_type(&self, return_type: &Type) -> Result<(), SemanticError> {
+        // 戻り値の型が有効かチェック
+        match return_type {
+            Type::Custom(name) => {
+                if !self.type_environment.contains_key(name) {
+                    return Err(SemanticError::TypeError(format!(
+                        "Unknown return type {}",
+                        name
+                    )));
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn verify_shared_field_constraints(&self, field: &Field) -> Result<(), SemanticError> {
+        // 共有フィールドの制約をチェック
+        match &field.field_type {
             Type::Custom(_) => {
                 // カスタム型の共有フィールドに対する追加チェック
                 if !field.is_mutable {
@@ -279,46 +3399,1410 @@ impl SemanticAnalyzer {
         Ok(())
     }
 
-    fn check_type_compatibility(&self, expected: &Type, found: &Type) -> bool {
-        match (expected, found) {
-            (Type::Int, Type::Int) => true,
-            (Type::Float, Type::Float) => true,
-            (Type::String, Type::String) => true,
-            (Type::Bool, Type::Bool) => true,
-            (Type::Custom(e), Type::Custom(f)) => e == f,
-            (Type::Array(e), Type::Array(f)) => self.check_type_compatibility(e, f),
-            (Type::Optional(e), Type::Optional(f)) => self.check_type_compatibility(e, f),
-            (Type::Optional(e), f) => self.check_type_compatibility(e, f),
-            _ => false,
+    fn check_type_compatibility(&self, expected: &Type, found: &Type) -> bool {
+        match (expected, found) {
+            (Type::Int, Type::Int) => true,
+            (Type::Float, Type::Float) => true,
+            (Type::String, Type::String) => true,
+            (Type::Bool, Type::Bool) => true,
+            (Type::Custom(e), Type::Custom(f)) => e == f,
+            (
+                Type::Function {
+                    params: e,
+                    return_type: e_return,
+                },
+                Type::Function {
+                    params: f,
+                    return_type: f_return,
+                },
+            ) => {
+                e.len() == f.len()
+                    && e.iter()
+                        .zip(f)
+                        .all(|(e, f)| self.check_type_compatibility(e, f))
+                    && match (e_return, f_return) {
+                        (Some(e), Some(f)) => self.check_type_compatibility(e, f),
+                        (None, None) => true,
+                        _ => false,
+                    }
+            }
+            (Type::Array(e), Type::Array(f)) => self.check_type_compatibility(e, f),
+            // 固定長配列は新しいバッファへ複写して [T] として渡す
+            (Type::Array(e), Type::FixedArray(f, _)) => self.check_type_compatibility(e, f),
+            (Type::FixedArray(e, n), Type::FixedArray(f, m)) => {
+                n == m && self.check_type_compatibility(e, f)
+            }
+            (Type::Optional(e), Type::Optional(f)) => self.check_type_compatibility(e, f),
+            (Type::Optional(e), f) => self.check_type_compatibility(e, f),
+            (Type::Tuple(e), Type::Tuple(f)) => {
+                e.len() == f.len()
+                    && e.iter()
+                        .zip(f)
+                        .all(|(e, f)| self.check_type_compatibility(e, f))
+            }
+            // 構造的型付け: ラベルの集合が同じで、同じラベルのフィールドの型が互換。
+            // 並び順は問わない
+            (Type::Record(e), Type::Record(f)) => {
+                e.len() == f.len()
+                    && e.iter().all(|(label, e)| {
+                        f.iter()
+                            .find(|(f_label, _)| f_label == label)
+                            .is_some_and(|(_, f)| self.check_type_compatibility(e, f))
+                    })
+            }
+            _ => false,
+        }
+    }
+}
+
+// テストモジュール
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 基本的な型チェックのテスト
+    #[test]
+    fn test_basic_type_checking() {
+        let analyzer = SemanticAnalyzer::new();
+        assert!(analyzer.check_type_compatibility(&Type::Int, &Type::Int));
+        assert!(!analyzer.check_type_compatibility(&Type::Int, &Type::Float));
+    }
+
+    // 配列型の互換性テスト
+    #[test]
+    fn test_array_type_compatibility() {
+        let analyzer = SemanticAnalyzer::new();
+        assert!(analyzer.check_type_compatibility(
+            &Type::Array(Box::new(Type::Int)),
+            &Type::Array(Box::new(Type::Int))
+        ));
+        assert!(!analyzer.check_type_compatibility(
+            &Type::Array(Box::new(Type::Int)),
+            &Type::Array(Box::new(Type::Float))
+        ));
+    }
+
+    fn actor_with_deinit(is_async: bool, statements: Vec<Statement>) -> Actor {
+        Actor {
+            name: "Socket".to_string(),
+            actor_type: ActorType::Distributed,
+            methods: vec![],
+            fields: vec![],
+            init: None,
+            deinit: Some(Deinit {
+                is_async,
+                body: MethodBody { statements },
+            }),
+            layout: Default::default(),
+            externs: vec![],
+            imports: vec![],
+            topics: vec![],
+            pools: vec![],
+            structs: vec![],
+            enums: vec![],
+            constants: vec![],
+            protocol: None,
+            doc: None,
+        }
+    }
+
+    // デイニシャライザのテスト
+    #[test]
+    fn test_deinit_rules() {
+        let mut analyzer = SemanticAnalyzer::new();
+        let literal = Expression::Literal(LiteralValue::Int(0));
+        assert!(analyzer
+            .analyze_actor(&actor_with_deinit(
+                false,
+                vec![Statement::Expression(literal.clone())]
+            ))
+            .is_ok());
+
+        assert!(matches!(
+            analyzer.analyze_actor(&actor_with_deinit(true, vec![])),
+            Err(SemanticError::AsyncError(_))
+        ));
+        assert!(matches!(
+            analyzer.analyze_actor(&actor_with_deinit(false, vec![Statement::Return(literal)])),
+            Err(SemanticError::InvalidOperation(_))
+        ));
+
+        let escaping = Expression::BinaryOp {
+            left: Box::new(Expression::Variable("self".to_string())),
+            operator: Operator::Add,
+            right: Box::new(Expression::Literal(LiteralValue::Int(1))),
+        };
+        assert!(matches!(
+            analyzer.analyze_actor(&actor_with_deinit(
+                false,
+                vec![Statement::Expression(escaping)]
+            )),
+            Err(SemanticError::OwnershipError(_))
+        ));
+    }
+
+    fn field(field_type: Type, is_mutable: bool, ownership: OwnershipType) -> Field {
+        Field {
+            name: "peer".to_string(),
+            field_type,
+            is_mutable,
+            ownership,
+            offset: None,
+            doc: None,
+        }
+    }
+
+    // weak / unowned 参照のテスト
+    #[test]
+    fn test_weak_and_unowned_fields() {
+        let mut analyzer = SemanticAnalyzer::new();
+        let peer = || Type::Custom("Peer".to_string());
+        let optional_peer = || Type::Optional(Box::new(peer()));
+
+        assert!(analyzer
+            .analyze_field(&field(optional_peer(), true, OwnershipType::Weak))
+            .is_ok());
+        assert!(analyzer
+            .analyze_field(&field(peer(), true, OwnershipType::Weak))
+            .is_err());
+        assert!(analyzer
+            .analyze_field(&field(optional_peer(), false, OwnershipType::Weak))
+            .is_err());
+
+        assert!(analyzer
+            .analyze_field(&field(peer(), false, OwnershipType::Unowned))
+            .is_ok());
+        assert!(analyzer
+            .analyze_field(&field(optional_peer(), false, OwnershipType::Unowned))
+            .is_err());
+        assert!(analyzer
+            .analyze_field(&field(Type::Int, false, OwnershipType::Unowned))
+            .is_err());
+    }
+
+    // レイアウト属性のテスト
+    #[test]
+    fn test_layout_attributes() {
+        let mut actor = actor_with_deinit(false, vec![]);
+        actor.fields = vec![Field {
+            offset: Some(2),
+            ..field(Type::Int, true, OwnershipType::Owned)
+        }];
+        assert!(matches!(
+            SemanticAnalyzer::new().analyze_actor(&actor),
+            Err(SemanticError::LayoutError(_))
+        ));
+
+        actor.layout.packed = true;
+        assert!(SemanticAnalyzer::new().analyze_actor(&actor).is_ok());
+
+        actor.layout.align = Some(6);
+        assert!(matches!(
+            SemanticAnalyzer::new().analyze_actor(&actor),
+            Err(SemanticError::LayoutError(_))
+        ));
+    }
+
+    fn method(name: &str, params: Vec<Parameter>, statements: Vec<Statement>) -> Method {
+        Method {
+            name: name.to_string(),
+            is_async: false,
+            is_sequential: false,
+            is_immediate: false,
+            is_pure: false,
+            is_nonreentrant: false,
+            shard_key: None,
+            retry: None,
+            is_idempotent: false,
+            since: None,
+            removed: None,
+            params,
+            return_type: None,
+            body: Some(MethodBody { statements }),
+            doc: None,
+        }
+    }
+
+    fn actor_with_methods(fields: Vec<Field>, methods: Vec<Method>) -> Actor {
+        Actor {
+            name: "Counter".to_string(),
+            actor_type: ActorType::Distributed,
+            methods,
+            fields,
+            init: None,
+            deinit: None,
+            layout: Default::default(),
+            externs: vec![],
+            imports: vec![],
+            topics: vec![],
+            pools: vec![],
+            structs: vec![],
+            enums: vec![],
+            constants: vec![],
+            protocol: None,
+            doc: None,
+        }
+    }
+
+    // extern 型のテスト
+    #[test]
+    fn test_extern_types_are_opaque() {
+        let handle = || Type::Custom("FileHandle".to_string());
+        let param = |name: &str, param_type| Parameter {
+            name: name.to_string(),
+            param_type,
+            ownership: OwnershipType::Owned,
+        };
+        let with_methods = |methods| {
+            let mut actor = actor_with_methods(vec![], methods);
+            actor.externs = vec![
+                Extern::Type("FileHandle".to_string()),
+                Extern::Function(ExternFunction {
+                    name: "open".to_string(),
+                    params: vec![],
+                    return_type: Some(handle()),
+                    proto: None,
+                    requires: vec![],
+                }),
+                Extern::Function(ExternFunction {
+                    name: "close".to_string(),
+                    params: vec![param("file", handle())],
+                    return_type: None,
+                    proto: None,
+                    requires: vec![],
+                }),
+            ];
+            actor
+        };
+        let open = || Expression::Call {
+            callee: "open".to_string(),
+            type_args: Vec::new(),
+            args: vec![],
+        };
+
+        // extern 関数から受け取り、extern 関数へ返すことはできる
+        let round_trip = method(
+            "reopen",
+            vec![],
+            vec![Statement::Expression(Expression::Call {
+                callee: "close".to_string(),
+                type_args: Vec::new(),
+                args: vec![open()],
+            })],
+        );
+        assert!(SemanticAnalyzer::new()
+            .analyze_actor(&with_methods(vec![round_trip]))
+            .is_ok());
+
+        let dereference = method(
+            "size",
+            vec![],
+            vec![Statement::Expression(Expression::BinaryOp {
+                left: Box::new(open()),
+                operator: Operator::Add,
+                right: Box::new(Expression::Literal(LiteralValue::Int(1))),
+            })],
+        );
+        assert!(matches!(
+            SemanticAnalyzer::new().analyze_actor(&with_methods(vec![dereference])),
+            Err(SemanticError::InvalidOperation(_))
+        ));
+
+        let leaking = method("leak", vec![param("file", handle())], vec![]);
+        assert!(matches!(
+            SemanticAnalyzer::new().analyze_actor(&with_methods(vec![leaking])),
+            Err(SemanticError::InvalidOperation(_))
+        ));
+    }
+
+    // プロトコルバッファのコーデックのテスト
+    #[test]
+    fn test_proto_codec_signatures() {
+        let with_codec = |param_type, return_type| {
+            let mut actor = actor_with_methods(vec![], vec![]);
+            actor.externs = vec![Extern::Function(ExternFunction {
+                name: "encodePoint".to_string(),
+                params: vec![Parameter {
+                    name: "value".to_string(),
+                    param_type,
+                    ownership: OwnershipType::Owned,
+                }],
+                return_type: Some(return_type),
+                proto: Some("1:sint32, 2:sint32".to_string()),
+                requires: vec![],
+            })];
+            actor
+        };
+        let point = |y| Type::Record(vec![("x".to_string(), Type::Int), ("y".to_string(), y)]);
+        let bytes = || Type::Array(Box::new(Type::Int));
+
+        let ok = with_codec(point(Type::Int), bytes());
+        assert!(SemanticAnalyzer::new().analyze_actor(&ok).is_ok());
+
+        let mismatched = with_codec(point(Type::Float), bytes());
+        assert!(matches!(
+            SemanticAnalyzer::new().analyze_actor(&mismatched),
+            Err(SemanticError::TypeError(_))
+        ));
+    }
+
+    // コールバックのテスト
+    #[test]
+    fn test_method_reference_as_callback() {
+        let callback_param = Parameter {
+            name: "callback".to_string(),
+            param_type: Type::Function {
+                params: vec![Type::Int],
+                return_type: None,
+            },
+            ownership: OwnershipType::Owned,
+        };
+        let register = |callee: &str| {
+            method(
+                "start",
+                vec![],
+                vec![Statement::Expression(Expression::Call {
+                    callee: "on_ready".to_string(),
+                    type_args: Vec::new(),
+                    args: vec![Expression::Variable(callee.to_string())],
+                })],
+            )
+        };
+        let handler = |name: &str, param_type| {
+            method(
+                name,
+                vec![Parameter {
+                    name: "status".to_string(),
+                    param_type,
+                    ownership: OwnershipType::Owned,
+                }],
+                vec![],
+            )
+        };
+        let with_methods = |methods| {
+            let mut actor = actor_with_methods(vec![], methods);
+            actor.externs = vec![Extern::Function(ExternFunction {
+                name: "on_ready".to_string(),
+                params: vec![callback_param.clone()],
+                return_type: None,
+                proto: None,
+                requires: vec![],
+            })];
+            actor
+        };
+
+        let ok = with_methods(vec![handler("ready", Type::Int), register("ready")]);
+        assert!(SemanticAnalyzer::new().analyze_actor(&ok).is_ok());
+
+        let mismatched = with_methods(vec![handler("ready", Type::Float), register("ready")]);
+        assert!(matches!(
+            SemanticAnalyzer::new().analyze_actor(&mismatched),
+            Err(SemanticError::TypeError(_))
+        ));
+    }
+
+    // メソッド呼び出しのテスト
+    #[test]
+    fn test_calls_between_methods() {
+        let param = Parameter {
+            name: "n".to_string(),
+            param_type: Type::Int,
+            ownership: OwnershipType::Owned,
+        };
+        let caller = |callee: &str, args| {
+            let mut caller = method(
+                "caller",
+                vec![],
+                vec![Statement::Return(Expression::Call {
+                    callee: callee.to_string(),
+                    type_args: Vec::new(),
+                    args,
+                })],
+            );
+            caller.return_type = Some(Type::Int);
+            caller
+        };
+        let callee = || {
+            let mut callee = method("callee", vec![param.clone()], vec![]);
+            callee.return_type = Some(Type::Int);
+            callee.body = Some(MethodBody {
+                statements: vec![Statement::Return(Expression::Variable("n".to_string()))],
+            });
+            callee
+        };
+        let int = |value| Expression::Literal(LiteralValue::Int(value));
+
+        // 後で宣言されたメソッドも呼べる
+        let forward = actor_with_methods(vec![], vec![caller("callee", vec![int(1)]), callee()]);
+        assert!(SemanticAnalyzer::new().analyze_actor(&forward).is_ok());
+
+        let arity = actor_with_methods(vec![], vec![caller("callee", vec![]), callee()]);
+        assert!(SemanticAnalyzer::new().analyze_actor(&arity).is_err());
+
+        let mistyped = actor_with_methods(
+            vec![],
+            vec![
+                caller(
+                    "callee",
+                    vec![Expression::Literal(LiteralValue::Bool(true))],
+                ),
+                callee(),
+            ],
+        );
+        assert!(SemanticAnalyzer::new().analyze_actor(&mistyped).is_err());
+
+        let duplicate = actor_with_methods(vec![], vec![callee(), callee()]);
+        let error = SemanticAnalyzer::new()
+            .analyze_actor(&duplicate)
+            .unwrap_err();
+        assert!(error.to_string().contains("declared more than once"));
+    }
+
+    // inout パラメータのテスト
+    #[test]
+    fn test_inout_arguments() {
+        let increment = method(
+            "increment",
+            vec![Parameter {
+                name: "value".to_string(),
+                param_type: Type::Int,
+                ownership: OwnershipType::Inout,
+            }],
+            vec![],
+        );
+        let call = |arg: Expression| {
+            method(
+                "tick",
+                vec![],
+                vec![Statement::Expression(Expression::Call {
+                    callee: "increment".to_string(),
+                    type_args: Vec::new(),
+                    args: vec![arg],
+                })],
+            )
+        };
+        let count = |is_mutable| field(Type::Int, is_mutable, OwnershipType::Owned);
+
+        let ok = actor_with_methods(
+            vec![count(true)],
+            vec![
+                increment.clone(),
+                call(Expression::Inout("peer".to_string())),
+            ],
+        );
+        assert!(SemanticAnalyzer::new().analyze_actor(&ok).is_ok());
+
+        let by_value = actor_with_methods(
+            vec![count(true)],
+            vec![
+                increment.clone(),
+                call(Expression::Variable("peer".to_string())),
+            ],
+        );
+        assert!(matches!(
+            SemanticAnalyzer::new().analyze_actor(&by_value),
+            Err(SemanticError::OwnershipError(_))
+        ));
+
+        let immutable = actor_with_methods(
+            vec![count(false)],
+            vec![increment, call(Expression::Inout("peer".to_string()))],
+        );
+        assert!(matches!(
+            SemanticAnalyzer::new().analyze_actor(&immutable),
+            Err(SemanticError::OwnershipError(_))
+        ));
+    }
+
+    // パターン束縛のテスト
+    #[test]
+    fn test_let_pattern_bindings() {
+        let mut analyzer = SemanticAnalyzer::new();
+        let point = Expression::Tuple(vec![
+            Expression::Literal(LiteralValue::Int(1)),
+            Expression::Literal(LiteralValue::Float(2.0)),
+        ]);
+        let binding = |pattern| Statement::Let {
+            pattern,
+            is_mutable: false,
+            value: point.clone(),
+        };
+
+        let destructure = Pattern::Tuple(vec![
+            Pattern::Binding("x".to_string()),
+            Pattern::Binding("y".to_string()),
+        ]);
+        assert!(analyzer
+            .analyze_statement(&binding(destructure), &None)
+            .is_ok());
+        assert!(matches!(
+            analyzer.analyze_expression(&Expression::Variable("y".to_string())),
+            Ok(Type::Float)
+        ));
+
+        let wrong_arity = Pattern::Tuple(vec![Pattern::Wildcard]);
+        assert!(analyzer
+            .analyze_statement(&binding(wrong_arity), &None)
+            .is_err());
+
+        let refutable = Pattern::Tuple(vec![
+            Pattern::Literal(LiteralValue::Int(1)),
+            Pattern::Wildcard,
+        ]);
+        assert!(matches!(
+            analyzer.analyze_statement(&binding(refutable), &None),
+            Err(SemanticError::InvalidOperation(_))
+        ));
+    }
+
+    #[test]
+    fn test_optional_case_pattern() {
+        let analyzer = SemanticAnalyzer::new();
+        let optional_int = Type::Optional(Box::new(Type::Int));
+        let some = Pattern::Case {
+            name: "some".to_string(),
+            args: vec![Pattern::Binding("v".to_string())],
+        };
+
+        assert!(!SemanticAnalyzer::is_irrefutable(&some));
+        assert!(analyzer.bind_pattern(&some, &optional_int, false).is_ok());
+        assert!(matches!(
+            analyzer.analyze_expression(&Expression::Variable("v".to_string())),
+            Ok(Type::Int)
+        ));
+
+        let unknown = Pattern::Case {
+            name: "ok".to_string(),
+            args: vec![],
+        };
+        assert!(analyzer
+            .bind_pattern(&unknown, &optional_int, false)
+            .is_err());
+    }
+
+    #[test]
+    fn test_bool_patterns_cover_true_and_false() {
+        let analyzer = SemanticAnalyzer::new();
+        let literal = |value| Pattern::Literal(LiteralValue::Bool(value));
+        let (yes, no) = (literal(true), literal(false));
+
+        assert_eq!(analyzer.uncovered(&Type::Bool, &[&yes, &no]), None);
+        assert_eq!(
+            analyzer.uncovered(&Type::Bool, &[&yes]),
+            Some("false".to_string())
+        );
+        assert_eq!(
+            analyzer.uncovered(&Type::Bool, &[&no]),
+            Some("true".to_string())
+        );
+        assert_eq!(analyzer.uncovered(&Type::Bool, &[&Pattern::Wildcard]), None);
+    }
+
+    // break / continue のテスト
+    #[test]
+    fn test_loop_jumps() {
+        let mut analyzer = SemanticAnalyzer::new();
+        assert!(analyzer
+            .analyze_statement(&Statement::Break(None), &None)
+            .is_err());
+
+        analyzer.loop_labels.push(Some("outer".to_string()));
+        analyzer.loop_labels.push(None);
+        assert!(analyzer
+            .analyze_statement(&Statement::Continue(None), &None)
+            .is_ok());
+        assert!(analyzer
+            .analyze_statement(&Statement::Break(Some("outer".to_string())), &None)
+            .is_ok());
+        let error = analyzer
+            .analyze_statement(&Statement::Break(Some("inner".to_string())), &None)
+            .unwrap_err();
+        assert!(error.to_string().contains("'inner'"));
+    }
+
+    #[test]
+    fn test_conversion_types() {
+        let analyzer = SemanticAnalyzer::new();
+        let convert = |conversion, value| Expression::Conversion {
+            conversion,
+            value: Box::new(Expression::Literal(value)),
+        };
+
+        let text = convert(Conversion::IntToString, LiteralValue::Int(7));
+        assert!(matches!(
+            analyzer.analyze_expression(&text),
+            Ok(Type::String)
+        ));
+        let parsed = convert(Conversion::StringToInt, LiteralValue::String("7".into()));
+        assert!(matches!(
+            analyzer.analyze_expression(&parsed),
+            Ok(Type::Optional(inner)) if matches!(*inner, Type::Int)
+        ));
+
+        let error = analyzer
+            .analyze_expression(&convert(Conversion::StringToInt, LiteralValue::Int(7)))
+            .unwrap_err();
+        assert!(error.to_string().contains("toInt() is defined on String"));
+    }
+
+    #[test]
+    fn test_comparison_types() {
+        let analyzer = SemanticAnalyzer::new();
+        let compare = |left, operator, right| Expression::BinaryOp {
+            left: Box::new(Expression::Literal(left)),
+            operator,
+            right: Box::new(Expression::Literal(right)),
+        };
+
+        let ordered = compare(
+            LiteralValue::Float(1.0),
+            Operator::Less,
+            LiteralValue::Float(2.0),
+        );
+        assert!(matches!(
+            analyzer.analyze_expression(&ordered),
+            Ok(Type::Bool)
+        ));
+        let equal = compare(
+            LiteralValue::Bool(true),
+            Operator::NotEqual,
+            LiteralValue::Bool(false),
+        );
+        assert!(matches!(
+            analyzer.analyze_expression(&equal),
+            Ok(Type::Bool)
+        ));
+
+        // Bool には順序がなく、異なる型どうしは比較できない
+        let ordered = compare(
+            LiteralValue::Bool(true),
+            Operator::Greater,
+            LiteralValue::Bool(false),
+        );
+        assert!(matches!(
+            analyzer.analyze_expression(&ordered),
+            Err(SemanticError::TypeError(_))
+        ));
+        let mixed = compare(
+            LiteralValue::Int(1),
+            Operator::Equal,
+            LiteralValue::Float(1.0),
+        );
+        assert!(matches!(
+            analyzer.analyze_expression(&mixed),
+            Err(SemanticError::TypeError(_))
+        ));
+    }
+
+    #[test]
+    fn test_loop_statements() {
+        let mut analyzer = SemanticAnalyzer::new();
+        let int = |value| Expression::Literal(LiteralValue::Int(value));
+        let body = |statements| MethodBody { statements };
+        let for_loop = |label: Option<&str>, end, statements| Statement::For {
+            label: label.map(str::to_string),
+            variable: "i".to_string(),
+            start: int(0),
+            end,
+            body: body(statements),
+        };
+
+        // ループ変数は Int で、本体の中でだけ見える
+        let statement = for_loop(
+            Some("outer"),
+            int(3),
+            vec![
+                Statement::Expression(Expression::Variable("i".to_string())),
+                Statement::Continue(Some("outer".to_string())),
+            ],
+        );
+        assert!(analyzer.analyze_statement(&statement, &None).is_ok());
+        assert!(analyzer
+            .analyze_expression(&Expression::Variable("i".to_string()))
+            .is_err());
+        // ループを抜けた後の break は無効
+        assert!(analyzer
+            .analyze_statement(&Statement::Break(None), &None)
+            .is_err());
+
+        let statement = for_loop(None, Expression::Literal(LiteralValue::Bool(true)), vec![]);
+        assert!(analyzer.analyze_statement(&statement, &None).is_err());
+
+        let statement = Statement::While {
+            label: None,
+            condition: int(1),
+            body: body(vec![Statement::Break(None)]),
+        };
+        let error = analyzer.analyze_statement(&statement, &None).unwrap_err();
+        assert!(error.to_string().contains("must be Bool"));
+
+        // 内側のループが外側のラベルを使い回すことはできない
+        let statement = for_loop(
+            Some("outer"),
+            int(3),
+            vec![Statement::While {
+                label: Some("outer".to_string()),
+                condition: Expression::Literal(LiteralValue::Bool(true)),
+                body: body(vec![]),
+            }],
+        );
+        let error = analyzer.analyze_statement(&statement, &None).unwrap_err();
+        assert!(error.to_string().contains("already used"));
+    }
+
+    #[test]
+    fn test_if_statements() {
+        let mut analyzer = SemanticAnalyzer::new();
+        let int = |value| Expression::Literal(LiteralValue::Int(value));
+        let branch = |statements| MethodBody { statements };
+        let if_statement = |condition, else_body| Statement::If {
+            condition,
+            then_body: branch(vec![Statement::Let {
+                pattern: Pattern::Binding("inner".to_string()),
+                is_mutable: false,
+                value: int(1),
+            }]),
+            else_body,
+        };
+
+        let returns_int = Some(Type::Int);
+        let statement = if_statement(
+            Expression::Literal(LiteralValue::Bool(true)),
+            Some(branch(vec![Statement::Return(int(2))])),
+        );
+        assert!(analyzer.analyze_statement(&statement, &returns_int).is_ok());
+        // 分岐内の束縛は外から参照できない
+        assert!(analyzer
+            .analyze_expression(&Expression::Variable("inner".to_string()))
+            .is_err());
+
+        let error = analyzer
+            .analyze_statement(&if_statement(int(1), None), &returns_int)
+            .unwrap_err();
+        assert!(error.to_string().contains("must be Bool"));
+
+        // 分岐内の return も戻り値型と照合する
+        let statement = if_statement(
+            Expression::Literal(LiteralValue::Bool(false)),
+            Some(branch(vec![Statement::Return(Expression::Literal(
+                LiteralValue::String("no".to_string()),
+            ))])),
+        );
+        assert!(matches!(
+            analyzer.analyze_statement(&statement, &returns_int),
+            Err(SemanticError::TypeError(_))
+        ));
+    }
+
+    // レコード型のテスト
+    #[test]
+    fn test_record_return_type() {
+        let mut analyzer = SemanticAnalyzer::new();
+        let record_type = |labels: [&str; 2]| {
+            Type::Record(vec![
+                (labels[0].to_string(), Type::Int),
+                (labels[1].to_string(), Type::Optional(Box::new(Type::Int))),
+            ])
+        };
+        let literal = Expression::Record(vec![
+            (
+                "count".to_string(),
+                Expression::Literal(LiteralValue::Int(3)),
+            ),
+            (
+                "limit".to_string(),
+                Expression::Literal(LiteralValue::Int(8)),
+            ),
+        ]);
+
+        let statement = Statement::Return(literal.clone());
+        assert!(analyzer
+            .analyze_statement(&statement, &Some(record_type(["count", "limit"])))
+            .is_ok());
+        // ラベルの順序は問わないが、ラベルの集合は一致しなければならない
+        assert!(analyzer
+            .analyze_statement(&statement, &Some(record_type(["limit", "count"])))
+            .is_ok());
+        assert!(analyzer
+            .analyze_statement(&statement, &Some(record_type(["count", "total"])))
+            .is_err());
+
+        let duplicate = Expression::Record(vec![
+            (
+                "count".to_string(),
+                Expression::Literal(LiteralValue::Int(3)),
+            ),
+            (
+                "count".to_string(),
+                Expression::Literal(LiteralValue::Int(4)),
+            ),
+        ]);
+        assert!(analyzer.analyze_expression(&duplicate).is_err());
+    }
+
+    #[test]
+    fn test_records_from_source() {
+        let analyze = |stats: &str, body: &str| {
+            let source = format!(
+                "actor Tally {{\n    func stats() -> (count: Int, ok: Bool) {{\n        {}\n    }}\n\n    func total() -> Int {{\n        {}\n    }}\n}}",
+                stats, body
+            );
+            let tokens = crate::lexer::tokenize(&source).unwrap();
+            let actor = crate::parser::Parser::new(tokens).parse_actor().unwrap();
+            SemanticAnalyzer::new().analyze_actor(&actor)
+        };
+        let stats = "return (count: 3, ok: true)";
+
+        analyze(stats, "let s = stats()\n        return s.count").unwrap();
+        analyze(stats, "return stats().count").unwrap();
+        // ラベルの順序が宣言と違っても同じ型
+        analyze("return (ok: true, count: 3)", "return 0").unwrap();
+        analyze(
+            stats,
+            "var s = (ok: false, count: 0)\n        s = stats()\n        return s.count",
+        )
+        .unwrap();
+
+        let errors = [
+            (
+                stats,
+                "let s = stats()\n        return s.total",
+                "(count: Int, ok: Bool) has no field total",
+            ),
+            (
+                "return (count: 3, done: true)",
+                "return 0",
+                "Return type mismatch",
+            ),
+            (stats, "return stats().ok", "Return type mismatch"),
+            // 位置での分解はラベルと取り違えやすいので受け付けない
+            (
+                stats,
+                "let (ok, count) = stats()\n        return count",
+                "Cannot destructure record (count: Int, ok: Bool) by position",
+            ),
+        ];
+        for (stats, body, expected) in errors {
+            let error = analyze(stats, body).unwrap_err().to_string();
+            assert!(error.contains(expected), "{}: {}", body, error);
         }
     }
-}
 
-// テストモジュール
-#[cfg(test)]
-mod tests {
-    use super::*;
+    // 固定長配列のテスト
+    #[test]
+    fn test_fixed_array_indexing() {
+        let mut analyzer = SemanticAnalyzer::new();
+        analyzer.current_scope.get_mut()[0].insert(
+            "buffer".to_string(),
+            Type::FixedArray(Box::new(Type::Int), 4),
+        );
+        let element = |index: LiteralValue| Expression::Index {
+            base: Box::new(Expression::Variable("buffer".to_string())),
+            index: Box::new(Expression::Literal(index)),
+        };
 
-    // 基本的な型チェックのテスト
+        assert!(matches!(
+            analyzer.analyze_expression(&element(LiteralValue::Int(3))),
+            Ok(Type::Int)
+        ));
+        assert!(analyzer
+            .analyze_expression(&element(LiteralValue::Int(4)))
+            .is_err());
+        assert!(analyzer
+            .analyze_expression(&element(LiteralValue::Float(1.0)))
+            .is_err());
+
+        let literal = Expression::ArrayLiteral(vec![
+            Expression::Literal(LiteralValue::Int(1)),
+            Expression::Literal(LiteralValue::Int(2)),
+        ]);
+        assert!(matches!(
+            analyzer.analyze_expression(&literal),
+            Ok(Type::FixedArray(_, 2))
+        ));
+        let mixed = Expression::ArrayLiteral(vec![
+            Expression::Literal(LiteralValue::Int(1)),
+            Expression::Literal(LiteralValue::Float(2.0)),
+        ]);
+        assert!(analyzer.analyze_expression(&mixed).is_err());
+        assert!(analyzer
+            .analyze_expression(&Expression::ArrayLiteral(vec![]))
+            .is_err());
+    }
+
+    // スレッド有効時の共有フィールドのテスト
     #[test]
-    fn test_basic_type_checking() {
-        let analyzer = SemanticAnalyzer::new();
-        assert!(analyzer.check_type_compatibility(&Type::Int, &Type::Int));
-        assert!(!analyzer.check_type_compatibility(&Type::Int, &Type::Float));
+    fn test_shared_fields_with_threads() {
+        let shared = |field_type| Field {
+            name: "state".to_string(),
+            field_type,
+            is_mutable: true,
+            ownership: OwnershipType::Shared,
+            offset: None,
+            doc: None,
+        };
+        let counters = Type::Record(vec![
+            ("hits".to_string(), Type::Int),
+            ("ratio".to_string(), Type::Float),
+        ]);
+
+        let mut analyzer = SemanticAnalyzer::new();
+        assert!(analyzer.analyze_field(&shared(Type::String)).is_ok());
+
+        analyzer.set_threads(true);
+        assert!(analyzer.analyze_field(&shared(counters)).is_ok());
+        assert!(analyzer
+            .analyze_field(&shared(Type::FixedArray(Box::new(Type::Int), 4)))
+            .is_ok());
+        assert!(matches!(
+            analyzer.analyze_field(&shared(Type::String)),
+            Err(SemanticError::OwnershipError(_))
+        ));
+        assert!(analyzer
+            .analyze_field(&shared(Type::Custom("Peer".to_string())))
+            .is_err());
     }
 
-    // 配列型の互換性テスト
+    // アトミック組み込み関数のテスト
     #[test]
-    fn test_array_type_compatibility() {
+    fn test_atomic_builtins() {
+        let mut analyzer = SemanticAnalyzer::new();
+        let field = |name: &str, ownership| Field {
+            name: name.to_string(),
+            field_type: Type::Int,
+            is_mutable: true,
+            ownership,
+            offset: None,
+            doc: None,
+        };
+        analyzer
+            .analyze_field(&field("hits", OwnershipType::Shared))
+            .unwrap();
+        analyzer
+            .analyze_field(&field("local", OwnershipType::Owned))
+            .unwrap();
+
+        let atomic = |operation, field: &str, args, ordering| Expression::Atomic {
+            operation,
+            field: field.to_string(),
+            args,
+            ordering,
+        };
+        let one = || vec![Expression::Literal(LiteralValue::Int(1))];
+
+        assert!(matches!(
+            analyzer.analyze_expression(&atomic(
+                AtomicOperation::Add,
+                "hits",
+                one(),
+                MemoryOrdering::Relaxed
+            )),
+            Ok(Type::Int)
+        ));
+        assert!(matches!(
+            analyzer.analyze_expression(&atomic(
+                AtomicOperation::CompareExchange,
+                "hits",
+                vec![
+                    Expression::Literal(LiteralValue::Int(0)),
+                    Expression::Literal(LiteralValue::Int(1)),
+                ],
+                MemoryOrdering::default()
+            )),
+            Ok(Type::Record(_))
+        ));
+        let store = Statement::Expression(atomic(
+            AtomicOperation::Store,
+            "hits",
+            one(),
+            MemoryOrdering::Release,
+        ));
+        assert!(analyzer.analyze_statement(&store, &None).is_ok());
+
+        // 共有されていない状態には使えない
+        assert!(matches!(
+            analyzer.analyze_expression(&atomic(
+                AtomicOperation::Load,
+                "local",
+                vec![],
+                MemoryOrdering::default()
+            )),
+            Err(SemanticError::OwnershipError(_))
+        ));
+        assert!(analyzer
+            .analyze_expression(&atomic(
+                AtomicOperation::Load,
+                "hits",
+                vec![],
+                MemoryOrdering::Release
+            ))
+            .is_err());
+        assert!(analyzer
+            .analyze_expression(&atomic(
+                AtomicOperation::Add,
+                "hits",
+                vec![],
+                MemoryOrdering::default()
+            ))
+            .is_err());
+    }
+
+    // pure メソッドと comptime 呼び出しのテスト
+    #[test]
+    fn test_pure_methods_and_comptime() {
+        let int = |value| Expression::Literal(LiteralValue::Int(value));
+        let pure = |name: &str, is_pure, body: Expression| Method {
+            name: name.to_string(),
+            is_async: false,
+            is_sequential: false,
+            is_immediate: false,
+            is_pure,
+            is_nonreentrant: false,
+            shard_key: None,
+            retry: None,
+            is_idempotent: false,
+            since: None,
+            removed: None,
+            params: vec![Parameter {
+                name: "n".to_string(),
+                param_type: Type::Int,
+                ownership: OwnershipType::Owned,
+            }],
+            return_type: Some(Type::Int),
+            body: Some(MethodBody {
+                statements: vec![Statement::Return(body)],
+            }),
+            doc: None,
+        };
+        let comptime = |callee: &str, arg| Expression::Comptime {
+            callee: callee.to_string(),
+            args: vec![arg],
+        };
+        let actor = |methods| Actor {
+            name: "Config".to_string(),
+            actor_type: ActorType::Distributed,
+            methods,
+            fields: vec![Field {
+                name: "limit".to_string(),
+                field_type: Type::Int,
+                is_mutable: true,
+                ownership: OwnershipType::Owned,
+                offset: None,
+                doc: None,
+            }],
+            init: None,
+            deinit: None,
+            layout: Default::default(),
+            externs: vec![],
+            imports: vec![],
+            topics: vec![],
+            pools: vec![],
+            structs: vec![],
+            enums: vec![],
+            constants: vec![],
+            protocol: None,
+            doc: None,
+        };
+        let double = || {
+            pure(
+                "double",
+                true,
+                Expression::BinaryOp {
+                    left: Box::new(Expression::Variable("n".to_string())),
+                    operator: Operator::Multiply,
+                    right: Box::new(int(2)),
+                },
+            )
+        };
+
+        let valid = actor(vec![
+            double(),
+            pure("size", false, comptime("double", int(4))),
+        ]);
+        assert!(SemanticAnalyzer::new().analyze_actor(&valid).is_ok());
+
+        // 引数は定数でなければならない
+        let dynamic = actor(vec![
+            double(),
+            pure(
+                "size",
+                false,
+                comptime("double", Expression::Variable("n".to_string())),
+            ),
+        ]);
+        assert!(SemanticAnalyzer::new().analyze_actor(&dynamic).is_err());
+
+        // pure でないメソッドは comptime で呼べない
+        let impure_callee = actor(vec![
+            pure("triple", false, int(3)),
+            pure("size", false, comptime("triple", int(1))),
+        ]);
+        assert!(SemanticAnalyzer::new()
+            .analyze_actor(&impure_callee)
+            .is_err());
+
+        // pure メソッドはフィールドを読めない
+        let reads_field = actor(vec![pure(
+            "bounded",
+            true,
+            Expression::Variable("limit".to_string()),
+        )]);
+        let error = SemanticAnalyzer::new()
+            .analyze_actor(&reads_field)
+            .unwrap_err();
+        assert!(error.to_string().contains("cannot read field limit"));
+    }
+
+    // ログ組み込み関数のテスト
+    #[test]
+    fn test_log_statements() {
+        let log = |message| Statement::Log {
+            level: LogLevel::Info,
+            message,
+            line: 1,
+        };
+        let text = || Expression::Literal(LiteralValue::String("started".to_string()));
+
+        let mut analyzer = SemanticAnalyzer::new();
+        assert!(analyzer.analyze_statement(&log(text()), &None).is_ok());
+        let error = analyzer
+            .analyze_statement(&log(Expression::Literal(LiteralValue::Int(1))), &None)
+            .unwrap_err();
+        assert!(error.to_string().contains("message must be String"));
+
+        // pure メソッドからはログを出せない
+        let mut start = method(
+            "start",
+            vec![],
+            vec![
+                log(text()),
+                Statement::Return(Expression::Literal(LiteralValue::Int(0))),
+            ],
+        );
+        start.is_pure = true;
+        start.return_type = Some(Type::Int);
+        let error = SemanticAnalyzer::new()
+            .analyze_actor(&actor_with_methods(vec![], vec![start]))
+            .unwrap_err();
+        assert!(error.to_string().contains("cannot call log.info"));
+    }
+
+    // フィールドの読み書きのテスト
+    #[test]
+    fn test_field_access_and_assignment() {
+        let field = |name: &str, is_mutable| Field {
+            name: name.to_string(),
+            field_type: Type::Int,
+            is_mutable,
+            ownership: OwnershipType::Owned,
+            offset: None,
+            doc: None,
+        };
+        let assign = |target, value| Statement::Assign {
+            target,
+            operator: None,
+            value: Expression::Literal(value),
+        };
+        let analyze = |statements| {
+            SemanticAnalyzer::new().analyze_actor(&actor_with_methods(
+                vec![field("count", true), field("limit", false)],
+                vec![method(
+                    "reset",
+                    vec![Parameter {
+                        name: "start".to_string(),
+                        param_type: Type::Int,
+                        ownership: OwnershipType::Owned,
+                    }],
+                    statements,
+                )],
+            ))
+        };
+        let self_field = |name: &str| Expression::Field(name.to_string());
+        let bare = |name: &str| Expression::Variable(name.to_string());
+
+        // self.count = limit; count = self.limit
+        assert!(analyze(vec![
+            Statement::Assign {
+                target: self_field("count"),
+                operator: None,
+                value: bare("limit"),
+            },
+            Statement::Assign {
+                target: bare("count"),
+                operator: None,
+                value: self_field("limit"),
+            },
+        ])
+        .is_ok());
+
+        let error = analyze(vec![assign(self_field("limit"), LiteralValue::Int(0))]).unwrap_err();
+        assert!(matches!(error, SemanticError::OwnershipError(_)));
+        assert!(error.to_string().contains("let field limit"));
+
+        let error = analyze(vec![assign(bare("count"), LiteralValue::Bool(true))]).unwrap_err();
+        assert!(matches!(error, SemanticError::TypeError(_)));
+
+        // inout でない引数は代入できず、未知のフィールドはエラー
+        let error = analyze(vec![assign(bare("start"), LiteralValue::Int(0))]).unwrap_err();
+        assert!(matches!(error, SemanticError::OwnershipError(_)));
+        assert!(error.to_string().contains("immutable binding start"));
+        assert!(matches!(
+            analyze(vec![assign(self_field("total"), LiteralValue::Int(0))]),
+            Err(SemanticError::UndefinedVariable(_))
+        ));
+        assert!(matches!(
+            analyze(vec![Statement::Expression(self_field("start"))]),
+            Err(SemanticError::UndefinedVariable(_))
+        ));
+    }
+
+    // ローカル変数への代入と複合代入のテスト
+    #[test]
+    fn test_local_assignment() {
+        let int = |value| Expression::Literal(LiteralValue::Int(value));
+        let bare = |name: &str| Expression::Variable(name.to_string());
+        let binding = |name: &str, is_mutable, value| Statement::Let {
+            pattern: Pattern::Binding(name.to_string()),
+            is_mutable,
+            value,
+        };
+        let assign = |name: &str, operator, value| Statement::Assign {
+            target: bare(name),
+            operator,
+            value,
+        };
+        let analyze = |statements: Vec<Statement>| {
+            let mut analyzer = SemanticAnalyzer::new();
+            analyzer.push_scope(HashMap::new());
+            statements
+                .iter()
+                .try_for_each(|statement| analyzer.analyze_statement(statement, &None))
+        };
+
+        // var total = 0; total += 2; total = total * 3
+        assert!(analyze(vec![
+            binding("total", true, int(0)),
+            assign("total", Some(Operator::Add), int(2)),
+            assign("total", None, bare("total")),
+        ])
+        .is_ok());
+
+        // let は代入できない
+        let error = analyze(vec![
+            binding("total", false, int(0)),
+            assign("total", Some(Operator::Add), int(2)),
+        ])
+        .unwrap_err();
+        assert!(matches!(error, SemanticError::OwnershipError(_)));
+
+        // 複合代入の型は二項演算の結果で決まる
+        let error = analyze(vec![
+            binding("ready", true, Expression::Literal(LiteralValue::Bool(true))),
+            assign("ready", Some(Operator::Add), int(1)),
+        ])
+        .unwrap_err();
+        assert!(matches!(error, SemanticError::TypeError(_)));
+
+        // 内側の let は外側の var を隠す
+        let shadowed = Statement::If {
+            condition: Expression::Literal(LiteralValue::Bool(true)),
+            then_body: MethodBody {
+                statements: vec![
+                    binding("total", false, int(1)),
+                    assign("total", None, int(2)),
+                ],
+            },
+            else_body: None,
+        };
+        assert!(matches!(
+            analyze(vec![binding("total", true, int(0)), shadowed]),
+            Err(SemanticError::OwnershipError(_))
+        ));
+
+        // 外側の var はブロック内から代入できる
+        let nested = Statement::While {
+            label: None,
+            condition: Expression::Literal(LiteralValue::Bool(true)),
+            body: MethodBody {
+                statements: vec![assign("total", Some(Operator::Subtract), int(1))],
+            },
+        };
+        assert!(analyze(vec![binding("total", true, int(0)), nested]).is_ok());
+    }
+
+    // 乱数組み込み関数のテスト
+    #[test]
+    fn test_random_builtins() {
         let analyzer = SemanticAnalyzer::new();
-        assert!(analyzer.check_type_compatibility(
-            &Type::Array(Box::new(Type::Int)),
-            &Type::Array(Box::new(Type::Int))
+        let random = |operation, args| Expression::Random { operation, args };
+        let int = |value| Expression::Literal(LiteralValue::Int(value));
+
+        assert!(matches!(
+            analyzer.analyze_expression(&random(RandomOperation::Int, vec![int(6)])),
+            Ok(Type::Int)
         ));
-        assert!(!analyzer.check_type_compatibility(
-            &Type::Array(Box::new(Type::Int)),
-            &Type::Array(Box::new(Type::Float))
+        assert!(matches!(
+            analyzer.analyze_expression(&random(RandomOperation::Float, vec![])),
+            Ok(Type::Float)
+        ));
+        assert!(analyzer
+            .analyze_expression(&random(RandomOperation::Int, vec![]))
+            .is_err());
+        assert!(analyzer
+            .analyze_expression(&random(
+                RandomOperation::Int,
+                vec![Expression::Literal(LiteralValue::Float(6.0))]
+            ))
+            .is_err());
+
+        // pure メソッドからは呼べない
+        let mut roll = method("roll", vec![], vec![]);
+        roll.is_pure = true;
+        roll.return_type = Some(Type::Int);
+        roll.body = Some(MethodBody {
+            statements: vec![Statement::Return(random(
+                RandomOperation::Int,
+                vec![int(6)],
+            ))],
+        });
+        let error = SemanticAnalyzer::new()
+            .analyze_actor(&actor_with_methods(vec![], vec![roll.clone()]))
+            .unwrap_err();
+        assert!(error.to_string().contains("cannot call randomInt"));
+
+        roll.is_pure = false;
+        assert!(SemanticAnalyzer::new()
+            .analyze_actor(&actor_with_methods(vec![], vec![roll]))
+            .is_ok());
+    }
+
+    // 設定値の組み込み関数のテスト
+    #[test]
+    fn test_config_builtin() {
+        let config = |key: &str| Expression::Config(key.to_string());
+
+        let mut analyzer = SemanticAnalyzer::new();
+        assert!(analyzer
+            .analyze_expression(&config("API_URL"))
+            .unwrap_err()
+            .to_string()
+            .contains("Unknown config key API_URL"));
+        analyzer.set_config_keys(["API_URL".to_string()]);
+        assert!(matches!(
+            analyzer.analyze_expression(&config("API_URL")),
+            Ok(Type::String)
         ));
+
+        // pure メソッドからは読めない
+        let mut url = method("url", vec![], vec![Statement::Return(config("API_URL"))]);
+        url.is_pure = true;
+        url.return_type = Some(Type::String);
+        let mut analyzer = SemanticAnalyzer::new();
+        analyzer.set_config_keys(["API_URL".to_string()]);
+        let error = analyzer
+            .analyze_actor(&actor_with_methods(vec![], vec![url]))
+            .unwrap_err();
+        assert!(error.to_string().contains("cannot read config"));
     }
 
     // オプショナル型のテスト
@@ -331,4 +4815,1036 @@ mod tests {
             &Type::Optional(Box::new(Type::Int))
         ));
     }
+
+    #[test]
+    fn test_generics_are_rejected() {
+        let cache = || Type::Generic {
+            name: "Cache".to_string(),
+            args: vec![Type::String],
+        };
+        let mut lookup = method("lookup", vec![], vec![]);
+        lookup.return_type = Some(Type::Optional(Box::new(cache())));
+        let error = SemanticAnalyzer::new()
+            .analyze_actor(&actor_with_methods(vec![], vec![lookup]))
+            .unwrap_err();
+        assert!(error.to_string().contains("Generic type Cache<...>"));
+        let error = SemanticAnalyzer::new()
+            .analyze_actor(&actor_with_methods(
+                vec![field(cache(), true, OwnershipType::Owned)],
+                vec![],
+            ))
+            .unwrap_err();
+        assert!(error.to_string().contains("Generic type Cache<...>"));
+
+        let call = Statement::Expression(Expression::Call {
+            callee: "reset".to_string(),
+            type_args: vec![Type::Int],
+            args: vec![],
+        });
+        let actor = actor_with_methods(
+            vec![],
+            vec![
+                method("reset", vec![], vec![]),
+                method("run", vec![], vec![call]),
+            ],
+        );
+        let error = SemanticAnalyzer::new().analyze_actor(&actor).unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("Call of reset has type arguments"));
+    }
+
+    #[test]
+    fn test_every_path_must_return() {
+        let int = |value| Expression::Literal(LiteralValue::Int(value));
+        let flag = |value| Expression::Literal(LiteralValue::Bool(value));
+        let body = |statements| MethodBody { statements };
+        let analyze = |statements: Vec<Statement>| {
+            let mut getter = method("value", vec![], statements);
+            getter.return_type = Some(Type::Int);
+            SemanticAnalyzer::new().analyze_actor(&actor_with_methods(vec![], vec![getter]))
+        };
+        let branch = |else_body| Statement::If {
+            condition: flag(true),
+            then_body: body(vec![Statement::Return(int(1))]),
+            else_body,
+        };
+        let forever = |label: Option<&str>, statements| Statement::While {
+            label: label.map(str::to_string),
+            condition: flag(true),
+            body: body(statements),
+        };
+
+        assert!(analyze(vec![Statement::Return(int(1))]).is_ok());
+        assert!(analyze(vec![branch(Some(body(vec![Statement::Return(int(2))])))]).is_ok());
+        assert!(analyze(vec![forever(None, vec![Statement::Return(int(1))])]).is_ok());
+
+        // else のない if や break で抜けるループは末尾に到達する
+        let missing = [
+            vec![],
+            vec![Statement::Expression(int(1))],
+            vec![branch(None)],
+            vec![forever(None, vec![Statement::Break(None)])],
+            vec![forever(
+                Some("outer"),
+                vec![forever(
+                    None,
+                    vec![Statement::Break(Some("outer".to_string()))],
+                )],
+            )],
+        ];
+        for statements in missing {
+            let error = analyze(statements).unwrap_err();
+            assert!(matches!(&error, SemanticError::TypeError(message)
+                if message.starts_with("Method value must return Int on every path")));
+        }
+
+        // 内側のループの break は外側の無限ループを抜けない
+        assert!(analyze(vec![forever(
+            None,
+            vec![forever(None, vec![Statement::Break(None)])]
+        )])
+        .is_ok());
+    }
+
+    #[test]
+    fn test_while_true_needs_no_return_from_source() {
+        let analyze = |loop_source: &str| {
+            let source = format!(
+                "actor Search {{\n    func firstSquareAbove(limit: Int) -> Int {{\n        var n = 0\n        {} {{\n            n += 1\n            if n * n > limit {{\n                return n\n            }}\n        }}\n    }}\n}}",
+                loop_source
+            );
+            let tokens = crate::lexer::tokenize(&source).unwrap();
+            let actor = crate::parser::Parser::new(tokens).parse_actor().unwrap();
+            SemanticAnalyzer::new().analyze_actor(&actor)
+        };
+
+        analyze("while true").unwrap();
+        // 条件が false や変数なら末尾に到達しうる
+        for condition in ["while false", "while limit > 0"] {
+            let error = analyze(condition).unwrap_err().to_string();
+            assert!(
+                error.contains("must return Int on every path"),
+                "{}: {}",
+                condition,
+                error
+            );
+        }
+    }
+
+    #[test]
+    fn test_analyze_actor_all() {
+        let source = "actor A {
+            func f() -> Int {
+                if 1 {
+                    return missing
+                }
+                return 0
+            }
+            func g() -> Int {
+                return 1
+            }
+            func h() -> Int {
+                return other
+            }
+        }";
+        let tokens = crate::lexer::tokenize_with_lines(source).unwrap();
+        let actor = crate::parser::Parser::with_lines(tokens)
+            .parse_actor()
+            .unwrap();
+        let errors = SemanticAnalyzer::new().analyze_actor_all(&actor);
+        let methods: Vec<Option<&str>> = errors.iter().map(|(_, m)| m.as_deref()).collect();
+        assert_eq!(methods, [Some("f"), Some("h")], "{:?}", errors);
+        assert!(matches!(&errors[1].0, SemanticError::UndefinedVariable(name) if name == "other"));
+
+        let mut analyzer = SemanticAnalyzer::new();
+        assert!(analyzer.analyze_actor(&actor).is_err());
+        assert_eq!(analyzer.current_method(), Some("f"));
+    }
+
+    #[test]
+    fn test_async_streams() {
+        let analyze = |members: &str| {
+            let source = format!("actor Feed {{\n    var last: Int\n{}\n}}", members);
+            let tokens = crate::lexer::tokenize(&source).unwrap();
+            let actor = crate::parser::Parser::new(tokens).parse_actor().unwrap();
+            SemanticAnalyzer::new().analyze_actor(&actor)
+        };
+        analyze(
+            "    func ticks(count: Int) -> AsyncStream<Int> {\n        for i in 0..count {\n            yield i + last\n        }\n    }",
+        )
+        .unwrap();
+
+        let errors = [
+            (
+                "    func ticks() -> AsyncStream<Int> {\n        yield \"tick\"\n    }",
+                "Yielded type mismatch",
+            ),
+            (
+                "    func tick() -> Int {\n        yield 1\n        return 1\n    }",
+                "yield can only be used",
+            ),
+            (
+                "    func ticks() -> AsyncStream<Int> {\n        return 1\n    }",
+                "cannot return a value",
+            ),
+            (
+                "    func ticks() -> AsyncStream<Int> {\n        yield 1\n    }\n    func first() {\n        ticks()\n    }",
+                "only the host can consume",
+            ),
+            (
+                "    func ticks(count: inout Int) -> AsyncStream<Int> {\n        yield count\n    }",
+                "cannot take inout parameter count",
+            ),
+            (
+                "    func ticks() -> AsyncStream<AsyncStream<Int>> {\n    }",
+                "Generic type AsyncStream",
+            ),
+        ];
+        for (members, expected) in errors {
+            let error = analyze(members).unwrap_err().to_string();
+            assert!(error.contains(expected), "{}: {}", members, error);
+        }
+    }
+
+    #[test]
+    fn test_await() {
+        let analyze = |members: &str| {
+            let source = format!(
+                "actor Queue {{\n    var size: Int\n\n    async func count() -> Int {{\n        return size\n    }}\n\n    sequential func push(value: Int) {{\n        size += value\n    }}\n\n    func peek() -> Int {{\n        return size\n    }}\n{}\n}}",
+                members
+            );
+            let tokens = crate::lexer::tokenize(&source).unwrap();
+            let actor = crate::parser::Parser::new(tokens).parse_actor().unwrap();
+            SemanticAnalyzer::new().analyze_actor(&actor)
+        };
+        // 戻り値のないメソッドは文として await できる
+        analyze(
+            "    async func settle() -> Int {\n        await push(1)\n        return await count() + peek()\n    }",
+        )
+        .unwrap();
+
+        let errors = [
+            (
+                "    func settle() -> Int {\n        return await count()\n    }",
+                "await count() can only be used in an async method",
+            ),
+            (
+                "    async func settle() -> Int {\n        return await peek()\n    }",
+                "Only calls of async methods can be awaited, found peek()",
+            ),
+            (
+                "    async func settle() -> Int {\n        return await size\n    }",
+                "Only calls of async methods can be awaited, found size",
+            ),
+            (
+                "    async func settle() -> Int {\n        return await push(1)\n    }",
+                "push(1) does not return a value",
+            ),
+            (
+                "    async func settle() {\n        await count(1)\n    }",
+                "Method count expects 0 arguments",
+            ),
+            (
+                "    pure func settle() -> Int {\n        return await count()\n    }",
+                "Pure method settle cannot await count()",
+            ),
+        ];
+        for (members, expected) in errors {
+            let error = analyze(members).unwrap_err().to_string();
+            assert!(error.contains(expected), "{}: {}", members, error);
+        }
+    }
+
+    #[test]
+    fn test_imports_share_declarations() {
+        let parse = |source: &str| {
+            let tokens = crate::lexer::tokenize(source).unwrap();
+            crate::parser::Parser::new(tokens).parse_actor().unwrap()
+        };
+        let peer = parse("actor Peer {\n    extern type Socket\n    var id: Int\n}");
+        let mut symbols = SymbolTable::new();
+        symbols.declare("Peer", &peer);
+        let analyze = |source: &str| {
+            let mut analyzer = SemanticAnalyzer::new();
+            analyzer.set_symbols(symbols.clone());
+            analyzer.analyze_actor(&parse(source))
+        };
+
+        // インポートしたアクターと extern 型はシグネチャに使える
+        analyze(
+            "import Peer\nactor Node {\n    extern func close(socket: Socket)\n    var peer: Peer\n\n    func connect(next: Peer) -> Peer {\n        return next\n    }\n}",
+        )
+        .unwrap();
+
+        let error = analyze("actor Node {\n    func connect(next: Peer) {}\n}").unwrap_err();
+        assert!(error.to_string().contains("Unknown type Peer"), "{}", error);
+        let error = analyze("import Storage\nactor Node {}").unwrap_err();
+        assert!(matches!(&error, SemanticError::UnresolvedImport(module) if module == "Storage"));
+        let error = analyze("import Peer\nimport Peer\nactor Node {}").unwrap_err();
+        assert!(
+            error.to_string().contains("imported more than once"),
+            "{}",
+            error
+        );
+        let error = analyze("import Peer\nactor Peer {}").unwrap_err();
+        assert!(error.to_string().contains("defining itself"), "{}", error);
+    }
+
+    #[test]
+    fn test_imports_are_initialized_first() {
+        let parse = |source: &str| {
+            let tokens = crate::lexer::tokenize(source).unwrap();
+            crate::parser::Parser::new(tokens).parse_actor().unwrap()
+        };
+        let mut symbols = SymbolTable::new();
+        symbols.declare("Storage", &parse("actor Storage {}"));
+        symbols.declare("Peer", &parse("import Storage\nactor Peer {}"));
+        symbols.declare("Gateway", &parse("import Peer\nactor Gateway {}"));
+        assert_eq!(symbols.module("Storage").unwrap().init_depth, 0);
+        assert_eq!(symbols.module("Gateway").unwrap().init_depth, 2);
+
+        let analyze = |symbols: &SymbolTable, source: &str| {
+            let mut analyzer = SemanticAnalyzer::new();
+            analyzer.set_symbols(symbols.clone());
+            analyzer.analyze_actor(&parse(source))
+        };
+        analyze(&symbols, "import Gateway\nimport Storage\nactor Node {}").unwrap();
+
+        // Node を経由して自分に戻るインポートは初期化の順序が決まらない
+        symbols.declare("Node", &parse("import Gateway\nactor Node {}"));
+        symbols.declare("Storage", &parse("import Node\nactor Storage {}"));
+        let error = analyze(&symbols, "import Gateway\nactor Node {}").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Invalid operation: Import cycle Node -> Gateway -> Peer -> Storage -> Node: a module is initialized after the modules it imports"
+        );
+    }
+
+    #[test]
+    fn test_init_sets_let_fields() {
+        let analyze = |source: &str| {
+            let tokens = crate::lexer::tokenize(source).unwrap();
+            let actor = crate::parser::Parser::new(tokens).parse_actor().unwrap();
+            SemanticAnalyzer::new().analyze_actor(&actor)
+        };
+        analyze(
+            "actor User {
+                let id: String
+                var name: String
+                init(id: String, name: String) {
+                    self.id = id
+                    self.name = name
+                }
+            }",
+        )
+        .unwrap();
+
+        // let フィールドに代入できるのは init だけ
+        let error = analyze(
+            "actor User {
+                let id: String
+                func rename(id: String) { self.id = id }
+            }",
+        )
+        .unwrap_err();
+        assert!(
+            error.to_string().contains("only init can set it"),
+            "{}",
+            error
+        );
+        let error = analyze("actor User { init(id: String) { id = \"\" } }").unwrap_err();
+        assert!(
+            error.to_string().contains("immutable binding id"),
+            "{}",
+            error
+        );
+
+        for (source, message) in [
+            (
+                "actor User { var id: Int  init(id: Int) { return id } }",
+                "init cannot use return",
+            ),
+            (
+                "actor User { async func fetch() -> Int { return 1 }  init() { let id = await fetch() } }",
+                "await",
+            ),
+            (
+                "actor User { immediate init() {} }",
+                "Distributed actors cannot have immediate init",
+            ),
+            (
+                "pool Users of User(size: 2)\nactor User { async func ping() {}  init(id: Int) {} }",
+                "whose init takes parameters",
+            ),
+        ] {
+            let error = analyze(source).unwrap_err();
+            assert!(error.to_string().contains(message), "{}", error);
+        }
+        analyze("single actor User { immediate init() {} }").unwrap();
+    }
+
+    #[test]
+    fn test_init_assigns_each_let_field_once() {
+        let analyze = |body: &str| {
+            let source = format!(
+                "actor User {{ let id: Int  var name: String  init(id: Int, flag: Bool) {{ {} }} }}",
+                body
+            );
+            let tokens = crate::lexer::tokenize(&source).unwrap();
+            let actor = crate::parser::Parser::new(tokens).parse_actor().unwrap();
+            SemanticAnalyzer::new().analyze_actor(&actor)
+        };
+        for body in [
+            "self.id = id",
+            "if flag { self.id = id } else { self.id = 0 }",
+            "match id { 0 => { self.id = 1 } _ => { self.id = id } }",
+            "let total = id + 1\n self.id = total",
+            // 代入されない var フィールドはそのまま既定値を持つ
+            "self.id = id\n while flag { self.name = \"\" }",
+        ] {
+            analyze(body).unwrap_or_else(|error| panic!("{}: {}", body, error));
+        }
+
+        for (body, message) in [
+            ("self.name = \"\"", "init never assigns let field id"),
+            (
+                "if flag { self.id = id }",
+                "init does not assign let field id on every path",
+            ),
+            (
+                "match id { 0 => { self.id = 1 } _ => {} }",
+                "init does not assign let field id on every path",
+            ),
+            (
+                "self.id = id\n self.id = 0",
+                "Let field id is assigned more than once in init",
+            ),
+            (
+                "if flag { self.id = id }\n self.id = 0",
+                "Let field id is assigned more than once in init",
+            ),
+            (
+                "self.id = 0\n while flag { self.id = id }",
+                "Let field id is assigned in a loop in init",
+            ),
+            (
+                "self.id = 0\n for i in 0..2 { self.id = i }",
+                "Let field id is assigned in a loop in init",
+            ),
+        ] {
+            let error = analyze(body).unwrap_err();
+            assert!(error.to_string().contains(message), "{}: {}", body, error);
+        }
+    }
+
+    #[test]
+    fn test_topics() {
+        let parse = |source: &str| {
+            let tokens = crate::lexer::tokenize(source).unwrap();
+            crate::parser::Parser::new(tokens).parse_actor().unwrap()
+        };
+        let analyze = |source: &str| SemanticAnalyzer::new().analyze_actor(&parse(source));
+
+        analyze(
+            "topic Prices: Int\nactor Ticker {\n    var last: Int\n\n    func start() {\n        subscribe(Prices)\n        publish(Prices, last + 1)\n    }\n\n    async func onPrices(value: Int) {\n        last = value\n    }\n}",
+        )
+        .unwrap();
+
+        for (source, expected) in [
+            (
+                "actor A {\n    func f() {\n        publish(Prices, 1)\n    }\n}",
+                "Unknown topic Prices",
+            ),
+            (
+                "topic Prices: Int\nactor A {\n    func f() {\n        publish(Prices, \"1\")\n    }\n}",
+                "Topic Prices carries Int",
+            ),
+            (
+                "topic Prices: Int\nactor A {\n    func f() {\n        subscribe(Prices)\n    }\n}",
+                "needs a handler `async func onPrices(value: Int)`",
+            ),
+            (
+                "topic Prices: Int\nactor A {\n    func f() {\n        subscribe(Prices)\n    }\n\n    func onPrices(value: Int) {}\n}",
+                "needs a handler",
+            ),
+            (
+                "topic Prices: Int\nactor A {\n    func f() {\n        subscribe(Prices)\n    }\n\n    async func onPrices(value: Float) {}\n}",
+                "needs a handler",
+            ),
+            ("topic Names: String\nactor A {}", "must carry plain data"),
+            ("topic Prices: Int\ntopic Prices: Int\nactor A {}", "declared more than once"),
+            (
+                "topic Prices: Int\nactor A {\n    pure func f() -> Int {\n        publish(Prices, 1)\n        return 1\n    }\n}",
+                "cannot use topic Prices",
+            ),
+        ] {
+            let error = analyze(source).unwrap_err().to_string();
+            assert!(error.contains(expected), "{}: {}", source, error);
+        }
+
+        // インポートしたモジュールのトピックも使える
+        let mut symbols = SymbolTable::new();
+        symbols.declare("Feed", &parse("topic Prices: Int\nactor Feed {}"));
+        let analyze_importing = |source: &str| {
+            let mut analyzer = SemanticAnalyzer::new();
+            analyzer.set_symbols(symbols.clone());
+            analyzer.analyze_actor(&parse(source))
+        };
+        analyze_importing(
+            "import Feed\nactor A {\n    func f() {\n        publish(Prices, 1)\n    }\n}",
+        )
+        .unwrap();
+        let error = analyze_importing("import Feed\ntopic Prices: Float\nactor A {}").unwrap_err();
+        assert!(
+            error.to_string().contains("declared with type"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn test_pools() {
+        let analyze = |source: &str| {
+            let tokens = crate::lexer::tokenize(source).unwrap();
+            let actor = crate::parser::Parser::new(tokens).parse_actor().unwrap();
+            SemanticAnalyzer::new().analyze_actor(&actor)
+        };
+
+        analyze(
+            "pool Workers of Worker(size: 4)
+pool Shards of Worker(size: 2, strategy: consistentHash(user))
+actor Worker {
+    async func run(user: String, step: Int) {}
+}",
+        )
+        .unwrap();
+
+        for (source, expected) in [
+            (
+                "pool Workers of Other(size: 4)
+actor Worker {}",
+                "must pool Worker, the actor declared in this file",
+            ),
+            (
+                "pool Workers of Worker(size: 0)
+actor Worker {}",
+                "between 1 and 64 instances",
+            ),
+            (
+                "pool Worker of Worker(size: 2)
+actor Worker {}",
+                "declared more than once",
+            ),
+            (
+                "pool Workers of Worker(size: 2)
+single actor Worker {}",
+                "needs a distributed actor",
+            ),
+            (
+                "pool Shards of Worker(size: 2, strategy: consistentHash(user))
+actor Worker {
+    async func run(step: Int) {}
+}",
+                "run has no parameter user",
+            ),
+            (
+                "pool Shards of Worker(size: 2, strategy: consistentHash(user))
+actor Worker {
+    async func run(user: Float) {}
+}",
+                "must be an Int or a String",
+            ),
+        ] {
+            let error = analyze(source).unwrap_err().to_string();
+            assert!(error.contains(expected), "{}: {}", source, error);
+        }
+    }
+
+    #[test]
+    fn test_shard_keys() {
+        let analyze = |source: &str| {
+            let tokens = crate::lexer::tokenize(source).unwrap();
+            let actor = crate::parser::Parser::new(tokens).parse_actor().unwrap();
+            SemanticAnalyzer::new().analyze_actor(&actor)
+        };
+
+        analyze(
+            "actor Accounts {
+    var total: Int
+
+    async func deposit(@shardKey account: String, amount: Int) {
+        total = total + amount
+    }
+
+    async func reset(@shardKey id: Int) {
+        total = 0
+    }
+}",
+        )
+        .unwrap();
+
+        for (source, expected) in [
+            (
+                "actor Accounts {
+    func balances(@shardKey account: String) -> AsyncStream<Int> {
+        yield 0
+    }
+}",
+                "only async methods that are not streams",
+            ),
+            (
+                "actor Accounts {
+    func deposit(@shardKey account: String) {}
+}",
+                "only async methods that are not streams",
+            ),
+            (
+                "actor Accounts {
+    async func deposit(@shardKey amount: Float) {}
+}",
+                "Shard key amount of deposit must be an Int or a String",
+            ),
+        ] {
+            let error = analyze(source).unwrap_err().to_string();
+            assert!(error.contains(expected), "{}: {}", source, error);
+        }
+    }
+
+    #[test]
+    fn test_remote_policies() {
+        let analyze = |source: &str| {
+            let tokens = crate::lexer::tokenize(source).unwrap();
+            let actor = crate::parser::Parser::new(tokens).parse_actor().unwrap();
+            SemanticAnalyzer::new().analyze_actor(&actor)
+        };
+
+        analyze(
+            "actor Payments {
+    var total: Int
+
+    @idempotent
+    @retry(times: 3, backoff: 100ms)
+    async func charge(@shardKey id: String, amount: Int) {
+        total = total + amount
+    }
+
+    @idempotent
+    async func reset() {
+        total = 0
+    }
+}",
+        )
+        .unwrap();
+
+        for (source, expected) in [
+            (
+                "single actor Payments {\n    @idempotent\n    immediate func reset() {}\n}",
+                "cannot be @idempotent",
+            ),
+            (
+                "actor Payments {\n    @retry(times: 3, backoff: 100ms)\n    async func charge() {}\n}",
+                "must be @idempotent",
+            ),
+            (
+                "actor Payments {\n    @idempotent\n    func totals() -> AsyncStream<Int> {\n        yield 0\n    }\n}",
+                "only async methods that are not streams",
+            ),
+            (
+                "actor Payments {\n    @idempotent\n    @retry(times: 0, backoff: 100ms)\n    async func charge() {}\n}",
+                "between 1 and 10 times, found 0",
+            ),
+            (
+                "actor Payments {\n    @idempotent\n    @retry(times: 3, backoff: 6s)\n    async func charge() {}\n}",
+                "must be at most 5000ms, found 6000ms",
+            ),
+        ] {
+            let error = analyze(source).unwrap_err().to_string();
+            assert!(error.contains(expected), "{}: {}", source, error);
+        }
+    }
+
+    #[test]
+    fn test_api_versions() {
+        let analyze = |source: &str| {
+            let tokens = crate::lexer::tokenize(source).unwrap();
+            let actor = crate::parser::Parser::new(tokens).parse_actor().unwrap();
+            SemanticAnalyzer::new().analyze_actor(&actor)
+        };
+
+        analyze(
+            "actor Bank {
+    @removed(3)
+    async func open(name: String) {}
+
+    @since(2)
+    @idempotent
+    async func deposit(amount: Int) {}
+
+    @since(2)
+    @removed(4)
+    async func audit() {}
+}",
+        )
+        .unwrap();
+
+        for (source, expected) in [
+            (
+                "single actor Bank {\n    @since(2)\n    immediate func audit() {}\n}",
+                "cannot be @since",
+            ),
+            (
+                "actor Bank {\n    @removed(2)\n    func audits() -> AsyncStream<Int> {\n        yield 0\n    }\n}",
+                "only async methods that are not streams",
+            ),
+            (
+                "actor Bank {\n    @since(0)\n    async func audit() {}\n}",
+                "count from 1, found 0",
+            ),
+            (
+                "actor Bank {\n    @since(3)\n    @removed(3)\n    async func audit() {}\n}",
+                "removed in version 3, but was added in version 3",
+            ),
+            (
+                "actor Bank {\n    @removed(2)\n    @idempotent\n    async func audit() {}\n}",
+                "cannot call it remotely",
+            ),
+        ] {
+            let error = analyze(source).unwrap_err().to_string();
+            assert!(error.contains(expected), "{}: {}", source, error);
+        }
+    }
+
+    #[test]
+    fn test_structs() {
+        let parse = |source: &str| {
+            let tokens = crate::lexer::tokenize(source).unwrap();
+            crate::parser::Parser::new(tokens).parse_actor().unwrap()
+        };
+        let analyze = |source: &str| SemanticAnalyzer::new().analyze_actor(&parse(source));
+
+        // 後で宣言した構造体もフィールドに使え、トピックの値にもなる
+        analyze(
+            "struct Segment {\n    var from: Point\n    var to: Point\n}\nstruct Point {\n    var x: Int\n    var y: Int\n}\ntopic Moves: Segment\nactor Board {\n    var last: Point\n\n    func onMoves(value: Segment) {\n        last = value.to\n    }\n\n    func length(segment: Segment) -> Int {\n        return segment.to.x - segment.from.x\n    }\n\n    func origin() -> Point {\n        return Point(y: 0, x: 0)\n    }\n}",
+        )
+        .unwrap();
+
+        let point = "struct Point {\n    var x: Int\n    var y: Int\n}\n";
+        for (source, expected) in [
+            (
+                "struct Board {\n    var x: Int\n}\nactor Board {}".to_string(),
+                "Board is declared more than once",
+            ),
+            (
+                "struct Empty {\n}\nactor A {}".to_string(),
+                "has no fields",
+            ),
+            (
+                "struct Name {\n    var text: String\n}\nactor A {}".to_string(),
+                "must hold plain data",
+            ),
+            (
+                "struct Node {\n    var next: Node?\n}\nactor A {}".to_string(),
+                "Struct Node contains itself",
+            ),
+            (
+                "struct P {\n    var x: Int\n    var x: Int\n}\nactor A {}".to_string(),
+                "declares the field x more than once",
+            ),
+            (
+                "struct P {\n    var x: Int shared\n}\nactor A {}".to_string(),
+                "cannot have an ownership modifier",
+            ),
+            (
+                format!("{}actor A {{\n    func f() -> Point {{\n        return Point(x: 1)\n    }}\n}}", point),
+                "missing the field y",
+            ),
+            (
+                format!("{}actor A {{\n    func f() -> Point {{\n        return Point(x: 1, y: 2, x: 3)\n    }}\n}}", point),
+                "given more than once",
+            ),
+            (
+                format!("{}actor A {{\n    func f() -> Point {{\n        return Point(x: 1, y: 1.5)\n    }}\n}}", point),
+                "Field y of Point has type Int, found Float",
+            ),
+            (
+                format!("{}actor A {{\n    func f(p: Point) -> Int {{\n        return p.z\n    }}\n}}", point),
+                "has no field z",
+            ),
+            (
+                "actor A {\n    func f() -> Int {\n        return g(x: 1)\n    }\n}".to_string(),
+                "Unknown struct g",
+            ),
+        ] {
+            let error = analyze(&source).unwrap_err().to_string();
+            assert!(error.contains(expected), "{}: {}", source, error);
+        }
+
+        // インポートしたモジュールの構造体も使える
+        let mut symbols = SymbolTable::new();
+        symbols.declare("Geometry", &parse(&format!("{}actor Geometry {{}}", point)));
+        let mut analyzer = SemanticAnalyzer::new();
+        analyzer.set_symbols(symbols);
+        analyzer
+            .analyze_actor(&parse(
+                "import Geometry\nactor A {\n    func f(p: Point) -> Int {\n        return p.x\n    }\n}",
+            ))
+            .unwrap();
+    }
+
+    #[test]
+    fn test_enums() {
+        let analyze = |source: &str| {
+            let tokens = crate::lexer::tokenize(source).unwrap();
+            let actor = crate::parser::Parser::new(tokens).parse_actor().unwrap();
+            SemanticAnalyzer::new().analyze_actor(&actor)
+        };
+
+        let shape = "enum Shape {\n    case circle(Float)\n    case rect(Float, Float)\n    case empty\n}\n";
+        // すべての腕が返す match は必ず返り、入れ子の Optional も網羅できる
+        analyze(&format!(
+            "{}actor Canvas {{\n    var last: Shape\n\n    func area(shape: Shape) -> Float {{\n        match shape {{\n            .circle(let r) => {{\n                return r * r * 3.0\n            }}\n            .rect(let w, let h) => {{\n                return w * h\n            }}\n            .empty => {{\n                return 0.0\n            }}\n        }}\n    }}\n\n    func save(width: Float) {{\n        last = Shape.rect(width, 1.0)\n    }}\n\n    func count(size: Int?) -> Int {{\n        return match size {{ .some(0) => 0, .some(let n) => n, .none => 0 }}\n    }}\n}}",
+            shape
+        ))
+        .unwrap();
+
+        for (source, expected) in [
+            (
+                "enum Empty {\n}\nactor A {}".to_string(),
+                "Enum Empty has no cases",
+            ),
+            (
+                "enum E {\n    case a\n    case a\n}\nactor A {}".to_string(),
+                "declares the case a more than once",
+            ),
+            (
+                "enum E {\n    case name(String)\n}\nactor A {}".to_string(),
+                "must hold plain data",
+            ),
+            (
+                "enum List {\n    case node(Int, List)\n    case end\n}\nactor A {}".to_string(),
+                "Enum List contains itself",
+            ),
+            (
+                format!("{}actor A {{\n    func f() -> Shape {{\n        return Shape.square(1.0)\n    }}\n}}", shape),
+                "Enum Shape has no case square",
+            ),
+            (
+                format!("{}actor A {{\n    func f() -> Shape {{\n        return Shape.rect(1.0)\n    }}\n}}", shape),
+                "Case Shape.rect takes 2 values, found 1",
+            ),
+            (
+                format!("{}actor A {{\n    func f() -> Shape {{\n        return Shape.circle(1)\n    }}\n}}", shape),
+                "Value 1 of Shape.circle has type Float, found Int",
+            ),
+            (
+                format!("{}actor A {{\n    func f(s: Shape) -> Int {{\n        return match s {{ .circle(_) => 1, .empty => 0 }}\n    }}\n}}", shape),
+                "is not exhaustive: .rect(_, _) is not covered",
+            ),
+            (
+                "actor A {\n    func f(n: Int?) -> Int {\n        return match n { .some(1) => 1, .none => 0 }\n    }\n}".to_string(),
+                ".some(_) is not covered",
+            ),
+            (
+                "actor A {\n    func f(n: Int) -> Int {\n        return match n { _ => 1, 2 => 2 }\n    }\n}".to_string(),
+                "Arm 2 of the match is unreachable",
+            ),
+            (
+                format!("{}actor A {{\n    func f(s: Shape) -> Int {{\n        return match s {{ .rect(let w) => 1, _ => 0 }}\n    }}\n}}", shape),
+                "Case .rect of Shape has 2 values, the pattern has 1",
+            ),
+            (
+                format!("{}actor A {{\n    func f(s: Shape) -> Int {{\n        return match s {{ .empty => 1, _ => 1.5 }}\n    }}\n}}", shape),
+                "Arms of a match must have the same type",
+            ),
+        ] {
+            let error = analyze(&source).unwrap_err().to_string();
+            assert!(error.contains(expected), "{}: {}", source, error);
+        }
+    }
+
+    #[test]
+    fn test_optionals() {
+        let analyze = |source: &str| {
+            let tokens = crate::lexer::tokenize(source).unwrap();
+            let actor = crate::parser::Parser::new(tokens).parse_actor().unwrap();
+            SemanticAnalyzer::new().analyze_actor(&actor)
+        };
+
+        // nil は戻り値・代入先・引数・?? の右辺から型が決まる
+        analyze(
+            "actor Cache {\n    var hits: Int?\n\n    func lookup(key: Int?) -> Int {\n        if let k = key {\n            return k\n        }\n        return key ?? hits ?? 0\n    }\n\n    func reset() -> Int? {\n        hits = nil\n        return nil\n    }\n\n    func force(key: Int?) -> Int {\n        return key! + lookup(nil)\n    }\n}",
+        )
+        .unwrap();
+
+        for (source, expected) in [
+            (
+                "actor A {\n    func f() -> Int {\n        let x = nil\n        return 0\n    }\n}",
+                "nil needs an optional type from its context",
+            ),
+            (
+                "actor A {\n    func f(n: Int) -> Int {\n        return n!\n    }\n}",
+                "! unwraps an optional, found Int",
+            ),
+            (
+                "actor A {\n    func f(n: Int) -> Int {\n        return n ?? 0\n    }\n}",
+                "?? unwraps an optional, found Int",
+            ),
+            (
+                "actor A {\n    func f(n: Int?) -> Int {\n        return n ?? 1.5\n    }\n}",
+                "Default of ?? must be Int, found Float",
+            ),
+        ] {
+            let error = analyze(source).unwrap_err().to_string();
+            assert!(error.contains(expected), "{}: {}", source, error);
+        }
+    }
+
+    #[test]
+    fn test_dynamic_arrays() {
+        let analyze = |source: &str| {
+            let tokens = crate::lexer::tokenize(source).unwrap();
+            let actor = crate::parser::Parser::new(tokens).parse_actor().unwrap();
+            SemanticAnalyzer::new().analyze_actor(&actor)
+        };
+
+        // 配列リテラルと [] は [T] が期待される場所で [T] になる
+        analyze(
+            "actor Samples {\n    var readings: [Int]\n\n    func reset() -> [Int] {\n        readings = [1, 2]\n        return []\n    }\n\n    func latest(values: [Int]) -> Int {\n        return values[values.count - 1] + [1, 2].count\n    }\n\n    func window() -> Int {\n        return latest([3, 4])\n    }\n}",
+        )
+        .unwrap();
+
+        for (source, expected) in [
+            (
+                "actor A {\n    func f(n: Int) -> Int {\n        return n.count\n    }\n}",
+                "Int has no field count",
+            ),
+            (
+                "actor A {\n    func f() -> [Float] {\n        return [1, 2]\n    }\n}",
+                "expected Array(Float), found FixedArray(Int, 2)",
+            ),
+            (
+                "actor A {\n    func f(values: [Int]) -> Int {\n        return values[1.5]\n    }\n}",
+                "Array index must be Int, found Float",
+            ),
+            (
+                "actor A {\n    func f() -> Int {\n        let empty = []\n        return 0\n    }\n}",
+                "Cannot infer the element type of an empty array literal",
+            ),
+        ] {
+            let error = analyze(source).unwrap_err().to_string();
+            assert!(error.contains(expected), "{}: {}", source, error);
+        }
+    }
+
+    #[test]
+    fn test_string_operations() {
+        let analyze = |source: &str| {
+            let tokens = crate::lexer::tokenize(source).unwrap();
+            let actor = crate::parser::Parser::new(tokens).parse_actor().unwrap();
+            SemanticAnalyzer::new().analyze_actor(&actor)
+        };
+
+        analyze(
+            "actor Greeter {\n    var greeting: String\n\n    func greet(name: String) -> String {\n        greeting = \"Hello, \" + name\n        return greeting + \"!\"\n    }\n\n    func isEmpty(text: String) -> Bool {\n        return text == \"\"\n    }\n}",
+        )
+        .unwrap();
+        analyze(
+            "actor Greeter {\n    func width(name: String) -> Int {\n        return (name + \"!\").length\n    }\n\n    func differs(a: String, b: String) -> Bool {\n        return a != b\n    }\n}",
+        )
+        .unwrap();
+
+        for (source, expected) in [
+            (
+                "actor A {\n    func f(a: String) -> String {\n        return a - \"b\"\n    }\n}",
+                "Invalid operand types for arithmetic operation: String and String",
+            ),
+            (
+                "actor A {\n    func f(a: String) -> String {\n        return a + 1\n    }\n}",
+                "Invalid operand types for arithmetic operation: String and Int",
+            ),
+            (
+                "actor A {\n    func f(a: String) -> Bool {\n        return a < \"b\"\n    }\n}",
+                "Invalid operand types for ordering comparison",
+            ),
+            (
+                "actor A {\n    func f(n: Int) -> Int {\n        return n.length\n    }\n}",
+                "Int has no field length",
+            ),
+        ] {
+            let error = analyze(source).unwrap_err().to_string();
+            assert!(error.contains(expected), "{}: {}", source, error);
+        }
+    }
+
+    #[test]
+    fn test_string_interpolation() {
+        let analyze = |source: &str| {
+            let tokens = crate::lexer::tokenize(source).unwrap();
+            let actor = crate::parser::Parser::new(tokens).parse_actor().unwrap();
+            SemanticAnalyzer::new().analyze_actor(&actor)
+        };
+
+        analyze(
+            "actor Meter {\n    func show(n: Int, x: Float, name: String) -> String {\n        return \"\\(name): \\(n * 2) / \\(x) (\\(n < 3))\"\n    }\n}",
+        )
+        .unwrap();
+
+        let error = analyze(
+            "actor Meter {\n    func show(n: Int?) -> String {\n        return \"n = \\(n)\"\n    }\n}",
+        )
+        .unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .contains("Cannot interpolate Optional(Int)"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn test_constant_tables() {
+        let analyze = |source: &str| {
+            let tokens = crate::lexer::tokenize(source).unwrap();
+            let actor = crate::parser::Parser::new(tokens).parse_actor().unwrap();
+            SemanticAnalyzer::new().analyze_actor(&actor)
+        };
+
+        analyze(
+            "actor Router {\n    const ports: [String: Int] = [\"http\": 80, \"https\": 443]\n    const primes: [Int] = [2, 3, 5, 7]\n\n    func port(scheme: String) -> Int {\n        return ports[scheme] ?? primes[primes.count - 1]\n    }\n\n    func shadow(primes: Int) -> Int {\n        return primes\n    }\n}",
+        )
+        .unwrap();
+
+        let errors = [
+            (
+                "const codes: [Bool] = []",
+                "must hold Int, Float or String values",
+            ),
+            ("const SCALES: [Float: Int] = [:]", "must be Int or String"),
+            ("const codes: [Int] = [1, \"2\"]", "holds Int values"),
+            ("const codes: [Int: Int] = [1: 2, 1: 3]", "key 1 more than once"),
+            (
+                "const codes: [Int] = [1]\n    var codes: Int",
+                "conflicts with another member",
+            ),
+            (
+                "const codes: [Int: Int] = [1: 2]\n    func all() -> Int {\n        return codes.count\n    }",
+                "can only be indexed",
+            ),
+            (
+                "const codes: [Int: Int] = [1: 2]\n    func code() -> Int? {\n        return codes[\"1\"]\n    }",
+                "Keys of constant map codes are Int",
+            ),
+            (
+                "const codes: [Int] = [1]\n    func reset() {\n        codes = [2]\n    }",
+                "Cannot assign to constant codes",
+            ),
+        ];
+        for (members, expected) in errors {
+            let source = format!("actor Router {{\n    {}\n}}", members);
+            let error = analyze(&source).unwrap_err().to_string();
+            assert!(error.contains(expected), "{}: {}", members, error);
+        }
+    }
 }
@@ -0,0 +1,146 @@
+//! Sharding keys of distributed actors.
+//! `func deposit(@shardKey account: String, amount: Int)` marks the parameter
+//! that decides which node of a cluster handles a call. The generated
+//! `<method>.remote` proxy hashes the key like a consistent-hash pool (FNV-1a
+//! for a `String`, then jump consistent hash over the node count the host
+//! reports) and hands the message to the host transport. The module's
+//! `replica.sharding` custom section records the scheme of every sharded
+//! method, so cluster tooling can place instances and route calls the same way.
+
+use crate::ast::{Actor, Type};
+use crate::capability::custom_sections;
+use serde_json::{json, Value};
+
+/// Custom section describing the sharded methods, one JSON object per line
+pub const SHARDING_SECTION: &str = "replica.sharding";
+
+/// Hash scheme of every shard key: 64-bit FNV-1a for strings, sign-extended
+/// integers otherwise, reduced to a node with jump consistent hash
+pub const SCHEME: &str = "fnv1a-jump";
+
+/// A method whose calls are sent to the node owning their key
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShardedMethod {
+    pub actor: String,
+    pub method: String,
+    pub key: String,
+    /// `Int` or `String`
+    pub key_type: String,
+    /// Message tag of the method, as queued by `<method>.post`
+    pub tag: u32,
+}
+
+/// Sharded methods of `actor`, with the message tags the code generator gives
+/// them: positions among the async methods that are not streams
+pub fn sharded_methods(actor: &Actor) -> Vec<ShardedMethod> {
+    actor
+        .methods
+        .iter()
+        .filter(|method| method.is_async && method.stream_item().is_none())
+        .enumerate()
+        .filter_map(|(tag, method)| {
+            let key = method.shard_key.as_ref()?;
+            let param = method.params.iter().find(|param| param.name == *key)?;
+            let key_type = match param.param_type {
+                Type::String => "String",
+                _ => "Int",
+            };
+            Some(ShardedMethod {
+                actor: actor.name.clone(),
+                method: method.name.clone(),
+                key: key.clone(),
+                key_type: key_type.to_string(),
+                tag: tag as u32,
+            })
+        })
+        .collect()
+}
+
+/// Contents of the `replica.sharding` section. Lines are self-contained, so
+/// the sections of linked modules concatenate.
+pub fn section(methods: &[ShardedMethod]) -> String {
+    methods
+        .iter()
+        .map(|method| {
+            let entry = json!({
+                "actor": method.actor,
+                "method": method.method,
+                "key": method.key,
+                "keyType": method.key_type,
+                "scheme": SCHEME,
+                "tag": method.tag,
+            });
+            entry.to_string() + "\n"
+        })
+        .collect()
+}
+
+/// Reads the sharded methods recorded in a WebAssembly binary
+pub fn read_sharded_methods(wasm: &[u8]) -> Result<Vec<ShardedMethod>, String> {
+    let mut methods = Vec::new();
+    for payload in custom_sections(wasm, SHARDING_SECTION)? {
+        let text = std::str::from_utf8(payload)
+            .map_err(|_| format!("{} section is not UTF-8", SHARDING_SECTION))?;
+        for line in text.lines().filter(|line| !line.trim().is_empty()) {
+            let malformed = || format!("malformed {} entry: {}", SHARDING_SECTION, line);
+            let entry: Value = serde_json::from_str(line).map_err(|_| malformed())?;
+            let text = |name: &str| {
+                entry[name]
+                    .as_str()
+                    .map(str::to_string)
+                    .ok_or_else(malformed)
+            };
+            methods.push(ShardedMethod {
+                actor: text("actor")?,
+                method: text("method")?,
+                key: text("key")?,
+                key_type: text("keyType")?,
+                tag: entry["tag"]
+                    .as_u64()
+                    .and_then(|tag| u32::try_from(tag).ok())
+                    .ok_or_else(malformed)?,
+            });
+        }
+    }
+    Ok(methods)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_section_round_trip() {
+        let tokens = crate::lexer::tokenize(
+            "actor Bank {
+    async func open(@shardKey account: String) {}
+
+    async func audit() {}
+
+    async func deposit(amount: Int, @shardKey account: Int) {}
+}",
+        )
+        .unwrap();
+        let actor = crate::parser::Parser::new(tokens).parse_actor().unwrap();
+        let methods = sharded_methods(&actor);
+        assert_eq!(
+            methods
+                .iter()
+                .map(|m| (m.method.as_str(), m.key_type.as_str(), m.tag))
+                .collect::<Vec<_>>(),
+            [("open", "String", 0), ("deposit", "Int", 2)]
+        );
+
+        let payload = section(&methods);
+        let mut body = vec![SHARDING_SECTION.len() as u8];
+        body.extend_from_slice(SHARDING_SECTION.as_bytes());
+        body.extend_from_slice(payload.as_bytes());
+        let mut wasm = b"\0asm\x01\0\0\0".to_vec();
+        // 本体は 127 バイトを超えるので、大きさは 2 バイトの LEB128 になる
+        assert!((128..1 << 14).contains(&body.len()));
+        wasm.extend([0, body.len() as u8 | 0x80, (body.len() >> 7) as u8]);
+        wasm.extend(body);
+        assert_eq!(read_sharded_methods(&wasm).unwrap(), methods);
+        assert!(payload.contains("\"scheme\":\"fnv1a-jump\""));
+    }
+}
@@ -0,0 +1,471 @@
+//! Static stack-depth analysis.
+//! Builds the call graph between an actor's methods and computes, for every
+//! exported method, the deepest chain of calls it can make and an estimate of
+//! the stack that chain needs. Recursive methods have no static bound; they are
+//! reported so the code generator can guard them at runtime, since running out
+//! of WASM stack otherwise surfaces as an opaque trap.
+
+use crate::ast::{Actor, Expression, Method, MethodBody, OwnershipType, Pattern, Statement};
+use crate::layout;
+use std::collections::{HashMap, HashSet, VecDeque};
+
+/// Bytes every frame needs besides its locals (saved stack pointer, return slot)
+const FRAME_OVERHEAD: u32 = 16;
+
+/// Estimated size of a local introduced by `let`/`var`, whose type is not in the AST
+const LOCAL_SLOT_SIZE: u32 = 8;
+
+/// Worst-case stack use of a call chain
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StackBound {
+    /// Number of nested frames, including the entry point's own
+    pub frames: u32,
+    /// Estimated bytes of stack those frames need
+    pub bytes: u32,
+}
+
+/// Stack use of one exported method; `None` when it can recurse without bound
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EntryDepth {
+    pub name: String,
+    pub bound: Option<StackBound>,
+}
+
+/// Result of analyzing an actor
+#[derive(Debug, Clone, Default)]
+pub struct StackReport {
+    /// One entry per method, in declaration order
+    pub entries: Vec<EntryDepth>,
+    /// Call cycles, each listed from the method that starts it, e.g. `[even, odd]`
+    pub cycles: Vec<Vec<String>>,
+}
+
+impl StackReport {
+    /// Whether `method` is part of a call cycle
+    pub fn is_recursive(&self, method: &str) -> bool {
+        self.cycles
+            .iter()
+            .any(|cycle| cycle.iter().any(|name| name == method))
+    }
+
+    /// Warnings for unbounded recursion and for entry points whose estimate
+    /// exceeds `stack_size` bytes
+    pub fn warnings(&self, stack_size: u32) -> Vec<String> {
+        let mut warnings: Vec<String> = self
+            .cycles
+            .iter()
+            .map(|cycle| {
+                format!(
+                    "method `{}` is recursive ({} -> {}); its stack depth cannot be bounded statically",
+                    cycle[0],
+                    cycle.join(" -> "),
+                    cycle[0]
+                )
+            })
+            .collect();
+
+        for entry in &self.entries {
+            if let Some(bound) = entry.bound.filter(|bound| bound.bytes > stack_size) {
+                warnings.push(format!(
+                    "method `{}` may need {} bytes of stack through {} nested calls, more than the {}-byte stack",
+                    entry.name, bound.bytes, bound.frames, stack_size
+                ));
+            }
+        }
+        warnings
+    }
+}
+
+/// Computes the call depth of every method of `actor` and finds its call cycles
+pub fn analyze(actor: &Actor) -> StackReport {
+    let graph = CallGraph::build(actor);
+
+    let mut memo = HashMap::new();
+    let mut visiting = HashSet::new();
+    let entries = actor
+        .methods
+        .iter()
+        .map(|method| EntryDepth {
+            name: method.name.clone(),
+            bound: graph.bound(&method.name, &mut memo, &mut visiting),
+        })
+        .collect();
+
+    // 宣言順に各メソッドを起点とする最短の循環を探し、既出のメソッドは起点にしない
+    let mut cycles: Vec<Vec<String>> = Vec::new();
+    for method in &actor.methods {
+        let reported = cycles.iter().any(|cycle| cycle.contains(&method.name));
+        if !reported {
+            if let Some(cycle) = graph.cycle_through(&method.name) {
+                cycles.push(cycle);
+            }
+        }
+    }
+
+    StackReport { entries, cycles }
+}
+
+/// Estimated frame size of a method: overhead, parameters and named locals
+pub fn frame_size(method: &Method) -> u32 {
+    let params: u32 = method
+        .params
+        .iter()
+        .map(|param| match param.ownership {
+            OwnershipType::Weak | OwnershipType::Unowned | OwnershipType::Inout => {
+                layout::POINTER_SIZE
+            }
+            _ => layout::size_and_align(&param.param_type).0,
+        })
+        .sum();
+
+    let locals: u32 = method.body.iter().map(count_locals).sum();
+
+    FRAME_OVERHEAD + params + locals
+}
+
+/// Bytes of the local slots bound in `body`, including those inside branches
+fn count_locals(body: &MethodBody) -> u32 {
+    body.statements
+        .iter()
+        .map(|statement| match statement {
+            Statement::Let { pattern, .. } => count_bindings(pattern) * LOCAL_SLOT_SIZE,
+            Statement::If {
+                then_body,
+                else_body,
+                ..
+            } => count_locals(then_body) + else_body.as_ref().map_or(0, count_locals),
+            Statement::While { body, .. } => count_locals(body),
+            Statement::For { body, .. } => LOCAL_SLOT_SIZE + count_locals(body),
+            Statement::Match { arms, .. } => arms
+                .iter()
+                .map(|(pattern, body)| {
+                    count_bindings(pattern) * LOCAL_SLOT_SIZE + count_locals(body)
+                })
+                .sum(),
+            _ => 0,
+        })
+        .sum()
+}
+
+fn count_bindings(pattern: &Pattern) -> u32 {
+    match pattern {
+        Pattern::Binding(_) => 1,
+        Pattern::Tuple(patterns) | Pattern::Case { args: patterns, .. } => {
+            patterns.iter().map(count_bindings).sum()
+        }
+        Pattern::Wildcard | Pattern::Literal(_) => 0,
+    }
+}
+
+/// Calls between an actor's methods; calls to host functions are not edges
+struct CallGraph<'a> {
+    callees: HashMap<&'a str, Vec<&'a str>>,
+    frames: HashMap<&'a str, u32>,
+}
+
+impl<'a> CallGraph<'a> {
+    fn build(actor: &'a Actor) -> Self {
+        let methods: HashSet<&str> = actor.methods.iter().map(|m| m.name.as_str()).collect();
+
+        let mut callees = HashMap::new();
+        let mut frames = HashMap::new();
+        for method in &actor.methods {
+            let mut calls = Vec::new();
+            if let Some(body) = &method.body {
+                collect_body_calls(body, &mut calls);
+            }
+            calls.retain(|callee| methods.contains(callee));
+
+            callees.insert(method.name.as_str(), calls);
+            frames.insert(method.name.as_str(), frame_size(method));
+        }
+        CallGraph { callees, frames }
+    }
+
+    /// Deepest call chain starting at `method`, or `None` if it reaches a cycle
+    fn bound(
+        &self,
+        method: &'a str,
+        memo: &mut HashMap<&'a str, Option<StackBound>>,
+        visiting: &mut HashSet<&'a str>,
+    ) -> Option<StackBound> {
+        if let Some(bound) = memo.get(method) {
+            return *bound;
+        }
+        // 探索中のメソッドに戻った場合は再帰しているので上限がない
+        if !visiting.insert(method) {
+            return None;
+        }
+
+        let mut deepest = StackBound {
+            frames: 0,
+            bytes: 0,
+        };
+        let mut bounded = true;
+        for &callee in &self.callees[method] {
+            match self.bound(callee, memo, visiting) {
+                Some(bound) => {
+                    deepest.frames = deepest.frames.max(bound.frames);
+                    deepest.bytes = deepest.bytes.max(bound.bytes);
+                }
+                None => bounded = false,
+            }
+        }
+        visiting.remove(method);
+
+        let bound = bounded.then(|| StackBound {
+            frames: deepest.frames + 1,
+            bytes: deepest.bytes + self.frames[method],
+        });
+        memo.insert(method, bound);
+        bound
+    }
+
+    /// Shortest call path from `method` back to itself
+    fn cycle_through(&self, method: &'a str) -> Option<Vec<String>> {
+        let mut previous: HashMap<&str, &str> = HashMap::new();
+        let mut queue = VecDeque::from([method]);
+        while let Some(current) = queue.pop_front() {
+            for &callee in &self.callees[current] {
+                if callee == method {
+                    let mut path = vec![current.to_string()];
+                    let mut node = current;
+                    while node != method {
+                        node = previous[node];
+                        path.push(node.to_string());
+                    }
+                    path.reverse();
+                    return Some(path);
+                }
+                if !previous.contains_key(callee) {
+                    previous.insert(callee, current);
+                    queue.push_back(callee);
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Collects the callees of every call in `body`, including those inside branches
+fn collect_body_calls<'a>(body: &'a MethodBody, calls: &mut Vec<&'a str>) {
+    for statement in &body.statements {
+        match statement {
+            Statement::Return(expr)
+            | Statement::Expression(expr)
+            | Statement::Yield(expr)
+            | Statement::Let { value: expr, .. }
+            | Statement::Log { message: expr, .. }
+            | Statement::Publish { value: expr, .. }
+            | Statement::Assign { value: expr, .. } => collect_calls(expr, calls),
+            Statement::If {
+                condition,
+                then_body,
+                else_body,
+            } => {
+                collect_calls(condition, calls);
+                collect_body_calls(then_body, calls);
+                if let Some(else_body) = else_body {
+                    collect_body_calls(else_body, calls);
+                }
+            }
+            Statement::While {
+                condition, body, ..
+            } => {
+                collect_calls(condition, calls);
+                collect_body_calls(body, calls);
+            }
+            Statement::For {
+                start, end, body, ..
+            } => {
+                collect_calls(start, calls);
+                collect_calls(end, calls);
+                collect_body_calls(body, calls);
+            }
+            Statement::Match { value, arms } => {
+                collect_calls(value, calls);
+                for (_, body) in arms {
+                    collect_body_calls(body, calls);
+                }
+            }
+            Statement::Break(_) | Statement::Continue(_) | Statement::Subscribe(_) => {}
+        }
+    }
+}
+
+/// Collects the callees of every call in `expr`, in evaluation order
+fn collect_calls<'a>(expr: &'a Expression, calls: &mut Vec<&'a str>) {
+    match expr {
+        Expression::Call { callee, args, .. } => {
+            for arg in args {
+                collect_calls(arg, calls);
+            }
+            if !calls.contains(&callee.as_str()) {
+                calls.push(callee);
+            }
+        }
+        Expression::BinaryOp { left, right, .. }
+        | Expression::Index {
+            base: left,
+            index: right,
+        }
+        | Expression::Coalesce {
+            value: left,
+            default: right,
+        } => {
+            collect_calls(left, calls);
+            collect_calls(right, calls);
+        }
+        Expression::Tuple(elements) | Expression::ArrayLiteral(elements) => {
+            for element in elements {
+                collect_calls(element, calls);
+            }
+        }
+        Expression::Record(fields) | Expression::StructLiteral { fields, .. } => {
+            for (_, value) in fields {
+                collect_calls(value, calls);
+            }
+        }
+        Expression::Atomic { args, .. }
+        | Expression::Random { args, .. }
+        | Expression::EnumCase { args, .. } => {
+            for arg in args {
+                collect_calls(arg, calls);
+            }
+        }
+        Expression::Match { value, arms } => {
+            collect_calls(value, calls);
+            for (_, result) in arms {
+                collect_calls(result, calls);
+            }
+        }
+        Expression::Conversion { value, .. }
+        | Expression::Member { base: value, .. }
+        | Expression::Unwrap(value)
+        | Expression::Await(value) => collect_calls(value, calls),
+        // comptime 呼び出しはコンパイル時に評価されスタックを使わない
+        Expression::Literal(_)
+        | Expression::Variable(_)
+        | Expression::Field(_)
+        | Expression::Inout(_)
+        | Expression::Comptime { .. }
+        | Expression::Config(_)
+        | Expression::Nil => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::{ActorType, MethodBody, Parameter, Type};
+
+    fn method(name: &str, calls: &[&str]) -> Method {
+        let statements = calls
+            .iter()
+            .map(|callee| {
+                Statement::Expression(Expression::Call {
+                    callee: callee.to_string(),
+                    type_args: Vec::new(),
+                    args: vec![],
+                })
+            })
+            .collect();
+        Method {
+            name: name.to_string(),
+            is_async: false,
+            is_sequential: false,
+            is_immediate: false,
+            is_pure: false,
+            is_nonreentrant: false,
+            shard_key: None,
+            retry: None,
+            is_idempotent: false,
+            since: None,
+            removed: None,
+            params: vec![],
+            return_type: None,
+            body: Some(MethodBody { statements }),
+            doc: None,
+        }
+    }
+
+    fn actor(methods: Vec<Method>) -> Actor {
+        Actor {
+            name: "Worker".to_string(),
+            actor_type: ActorType::Single,
+            methods,
+            fields: vec![],
+            init: None,
+            deinit: None,
+            layout: Default::default(),
+            externs: vec![],
+            imports: vec![],
+            topics: vec![],
+            pools: vec![],
+            structs: vec![],
+            enums: vec![],
+            constants: vec![],
+            protocol: None,
+            doc: None,
+        }
+    }
+
+    #[test]
+    fn test_call_depth() {
+        let mut leaf = method("leaf", &["log"]);
+        leaf.params.push(Parameter {
+            name: "value".to_string(),
+            param_type: Type::Float,
+            ownership: OwnershipType::Owned,
+        });
+        let report = analyze(&actor(vec![
+            method("run", &["step", "leaf"]),
+            method("step", &["leaf"]),
+            leaf,
+        ]));
+
+        assert!(report.cycles.is_empty());
+        let bound = |index: usize| report.entries[index].bound.unwrap();
+        assert_eq!(
+            bound(2),
+            StackBound {
+                frames: 1,
+                bytes: FRAME_OVERHEAD + 8
+            }
+        );
+        assert_eq!(bound(1).frames, 2);
+        assert_eq!(bound(0).frames, 3);
+        assert_eq!(bound(0).bytes, 3 * FRAME_OVERHEAD + 8);
+        assert!(report.warnings(65536).is_empty());
+        assert_eq!(report.warnings(48).len(), 1);
+    }
+
+    #[test]
+    fn test_recursion_is_unbounded() {
+        let report = analyze(&actor(vec![
+            method("main", &["even"]),
+            method("even", &["odd"]),
+            method("odd", &["even"]),
+            method("fact", &["fact"]),
+            method("idle", &[]),
+        ]));
+
+        assert_eq!(
+            report.cycles,
+            vec![
+                vec!["even".to_string(), "odd".to_string()],
+                vec!["fact".to_string()]
+            ]
+        );
+        assert!(report.is_recursive("odd"));
+        assert!(!report.is_recursive("main"));
+        assert!(report.entries[..4]
+            .iter()
+            .all(|entry| entry.bound.is_none()));
+        assert!(report.entries[4].bound.is_some());
+
+        let warnings = report.warnings(65536);
+        assert_eq!(warnings.len(), 2);
+        assert!(warnings[0].contains("(even -> odd -> even)"));
+    }
+}
@@ -0,0 +1,533 @@
+//! Cross-target test runner.
+//! `replicac test` runs the test methods of a program on several backends and
+//! reports each result per target, so a backend that computes something
+//! different from the others is caught by the same suite. A test method is
+//! named `test…`, takes no parameters and returns `Bool`; it passes when it
+//! returns true.
+
+use crate::ast::{Actor, Method, Type};
+use crate::consteval::{ConstEvaluator, Value};
+use std::fmt;
+use std::path::Path;
+use std::process::Command;
+use std::str::FromStr;
+
+/// Backend a test suite runs on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TestTarget {
+    /// The compile-time evaluator, which runs `pure` tests over the typed AST
+    Interp,
+    /// The `wasm32-wasi` build, invoked with wasmtime
+    Wasi,
+    /// The `wasm32-unknown-unknown` build, instantiated by Node.js without WASI
+    /// the way a browser would
+    BrowserSim,
+}
+
+impl TestTarget {
+    /// Accepted spellings, in the order targets run by default
+    pub const NAMES: [&'static str; 3] = ["interp", "wasi", "browser-sim"];
+
+    pub const ALL: [TestTarget; 3] = [TestTarget::Interp, TestTarget::Wasi, TestTarget::BrowserSim];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "interp" => Some(TestTarget::Interp),
+            "wasi" => Some(TestTarget::Wasi),
+            "browser-sim" => Some(TestTarget::BrowserSim),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            TestTarget::Interp => "interp",
+            TestTarget::Wasi => "wasi",
+            TestTarget::BrowserSim => "browser-sim",
+        }
+    }
+
+    /// Triple the tests are compiled for, or `None` for the interpreter
+    pub fn triple(self) -> Option<&'static str> {
+        match self {
+            TestTarget::Interp => None,
+            TestTarget::Wasi => Some("wasm32-wasi"),
+            TestTarget::BrowserSim => Some("wasm32-unknown-unknown"),
+        }
+    }
+
+    /// Parses a comma-separated list such as `wasi,interp`, dropping repeats
+    pub fn parse_list(s: &str) -> Result<Vec<Self>, String> {
+        let mut targets = Vec::new();
+        for name in s.split(',').map(str::trim) {
+            let target = name.parse()?;
+            if !targets.contains(&target) {
+                targets.push(target);
+            }
+        }
+        Ok(targets)
+    }
+}
+
+impl FromStr for TestTarget {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::from_name(s).ok_or_else(|| {
+            format!(
+                "Unknown test target '{}' (expected one of: {})",
+                s,
+                Self::NAMES.join(", ")
+            )
+        })
+    }
+}
+
+impl fmt::Display for TestTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Test method of an actor
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TestCase {
+    pub actor: String,
+    pub method: String,
+}
+
+impl TestCase {
+    /// Export of the compiled test, `<Actor>.<method>`
+    pub fn symbol(&self) -> String {
+        format!("{}.{}", self.actor, self.method)
+    }
+}
+
+/// Result of one test on one target
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    Passed,
+    Failed,
+    /// The test trapped, or could not be built or started
+    Error(String),
+    /// The target cannot run the test, e.g. a method that is not `pure` in
+    /// the interpreter
+    Skipped(String),
+}
+
+impl Outcome {
+    /// Whether two targets agree; errors agree whatever their messages
+    fn agrees_with(&self, other: &Outcome) -> bool {
+        std::mem::discriminant(self) == std::mem::discriminant(other)
+    }
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Outcome::Passed => f.write_str("ok"),
+            Outcome::Failed => f.write_str("FAILED"),
+            Outcome::Error(message) => write!(f, "ERROR ({})", message),
+            Outcome::Skipped(reason) => write!(f, "skipped ({})", reason),
+        }
+    }
+}
+
+fn is_test(method: &Method) -> bool {
+    method.name.starts_with("test")
+        && method.params.is_empty()
+        && method.return_type == Some(Type::Bool)
+        && method.body.is_some()
+}
+
+/// Test methods of `actors`, in declaration order
+pub fn test_cases(actors: &[&Actor]) -> Vec<TestCase> {
+    actors
+        .iter()
+        .flat_map(|actor| {
+            actor
+                .methods
+                .iter()
+                .filter(|method| is_test(method))
+                .map(|method| TestCase {
+                    actor: actor.name.clone(),
+                    method: method.name.clone(),
+                })
+        })
+        .collect()
+}
+
+/// Runs `tests` with the compile-time evaluator
+pub fn run_interp(actors: &[&Actor], tests: &[TestCase]) -> Vec<Outcome> {
+    tests
+        .iter()
+        .map(|test| {
+            let Some(actor) = actors.iter().find(|actor| actor.name == test.actor) else {
+                return Outcome::Error(format!("{} is not defined", test.actor));
+            };
+            let pure = actor
+                .methods
+                .iter()
+                .any(|method| method.name == test.method && method.is_pure);
+            if !pure {
+                return Outcome::Skipped("not a pure method".to_string());
+            }
+            match ConstEvaluator::new(actor).evaluate_call(&test.method, &[]) {
+                Ok(Value::Bool(true)) => Outcome::Passed,
+                Ok(Value::Bool(false)) => Outcome::Failed,
+                Ok(other) => Outcome::Error(format!("returned {:?}", other)),
+                Err(e) => Outcome::Error(e),
+            }
+        })
+        .collect()
+}
+
+/// Node.js script instantiating the module given as its first argument with
+/// no imports but stubs, then printing `ok`, `fail` or `error <message>` for
+/// each test named by the other arguments
+const BROWSER_HARNESS: &str = r#"
+import { readFile } from "node:fs/promises";
+const [path, ...tests] = process.argv.slice(1);
+const module = await WebAssembly.compile(await readFile(path));
+// ブラウザにはホスト関数がないので、呼ばれたときに失敗するだけの代役を置く
+const imports = {};
+for (const { module: from, name, kind } of WebAssembly.Module.imports(module)) {
+    if (kind !== "function") continue;
+    imports[from] ??= {};
+    imports[from][name] = () => {
+        throw new Error(`${from}.${name} is not available in a browser`);
+    };
+}
+const { exports } = await WebAssembly.instantiate(module, imports);
+for (const test of tests) {
+    try {
+        if (typeof exports[test] !== "function") throw new Error(`${test} is not exported`);
+        // テストはフィールドを使わないので、インスタンスには 0 を渡す
+        console.log(exports[test](0) ? "ok" : "fail");
+    } catch (error) {
+        console.log(`error ${String(error?.message ?? error).split("\n")[0]}`);
+    }
+}
+"#;
+
+/// Last non-empty line of a process's output
+fn last_line(output: &[u8]) -> String {
+    String::from_utf8_lossy(output)
+        .lines()
+        .map(str::trim)
+        .rfind(|line| !line.is_empty())
+        .unwrap_or_default()
+        .to_string()
+}
+
+/// Runs `tests` in the compiled `module` of `target`
+pub fn run_module(target: TestTarget, module: &Path, tests: &[TestCase]) -> Vec<Outcome> {
+    match target {
+        TestTarget::Interp => unreachable!("the interpreter does not run modules"),
+        TestTarget::Wasi => tests
+            .iter()
+            .map(|test| run_wasmtime(module, test))
+            .collect(),
+        TestTarget::BrowserSim => run_node(module, tests),
+    }
+}
+
+/// Invokes the test's export with wasmtime, passing 0 as the instance
+fn run_wasmtime(module: &Path, test: &TestCase) -> Outcome {
+    let output = Command::new("wasmtime")
+        .args(["run", "--invoke", &test.symbol()])
+        .arg(module)
+        .arg("0")
+        .output();
+    match output {
+        Ok(output) if output.status.success() => wasmtime_outcome(&last_line(&output.stdout)),
+        Ok(output) => Outcome::Error(last_line(&output.stderr)),
+        Err(e) => Outcome::Error(format!("Failed to start wasmtime: {}", e)),
+    }
+}
+
+/// Outcome of the value wasmtime printed for a `Bool` result
+fn wasmtime_outcome(result: &str) -> Outcome {
+    match result {
+        "1" => Outcome::Passed,
+        "0" => Outcome::Failed,
+        other => Outcome::Error(format!("unexpected result {:?}", other)),
+    }
+}
+
+fn run_node(module: &Path, tests: &[TestCase]) -> Vec<Outcome> {
+    let output = Command::new("node")
+        .args(["--input-type=module", "-e", BROWSER_HARNESS])
+        .arg(module)
+        .args(tests.iter().map(TestCase::symbol))
+        .output();
+    let error = match output {
+        Ok(output) if output.status.success() => {
+            return node_outcomes(&String::from_utf8_lossy(&output.stdout), tests.len())
+        }
+        Ok(output) => last_line(&output.stderr),
+        Err(e) => format!("Failed to start node: {}", e),
+    };
+    tests
+        .iter()
+        .map(|_| Outcome::Error(error.clone()))
+        .collect()
+}
+
+/// Outcomes printed by [`BROWSER_HARNESS`], one line per test
+fn node_outcomes(stdout: &str, count: usize) -> Vec<Outcome> {
+    let mut lines = stdout.lines();
+    (0..count)
+        .map(|_| match lines.next() {
+            Some("ok") => Outcome::Passed,
+            Some("fail") => Outcome::Failed,
+            Some(line) => Outcome::Error(line.strip_prefix("error ").unwrap_or(line).to_string()),
+            None => Outcome::Error("no result".to_string()),
+        })
+        .collect()
+}
+
+/// Results of a suite on every target it ran on
+pub struct Report {
+    tests: Vec<TestCase>,
+    targets: Vec<(TestTarget, Vec<Outcome>)>,
+}
+
+impl Report {
+    pub fn new(tests: Vec<TestCase>) -> Self {
+        Report {
+            tests,
+            targets: Vec::new(),
+        }
+    }
+
+    pub fn tests(&self) -> &[TestCase] {
+        &self.tests
+    }
+
+    /// Records the outcomes of every test, in order, on `target`
+    pub fn add(&mut self, target: TestTarget, outcomes: Vec<Outcome>) {
+        self.targets.push((target, outcomes));
+    }
+
+    /// Indices of the tests whose outcome differs between targets that ran them
+    fn divergent(&self) -> Vec<usize> {
+        (0..self.tests.len())
+            .filter(|&i| {
+                let mut ran = self
+                    .targets
+                    .iter()
+                    .map(|(_, outcomes)| &outcomes[i])
+                    .filter(|outcome| !matches!(outcome, Outcome::Skipped(_)));
+                let first = ran.next();
+                ran.any(|outcome| first.is_some_and(|first| !first.agrees_with(outcome)))
+            })
+            .collect()
+    }
+
+    /// Whether every test that ran passed on every target
+    pub fn passed(&self) -> bool {
+        self.targets.iter().all(|(_, outcomes)| {
+            outcomes
+                .iter()
+                .all(|outcome| matches!(outcome, Outcome::Passed | Outcome::Skipped(_)))
+        })
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, test) in self.tests.iter().enumerate() {
+            let results: Vec<String> = self
+                .targets
+                .iter()
+                .map(|(target, outcomes)| format!("{}: {}", target, outcomes[i]))
+                .collect();
+            writeln!(
+                f,
+                "test {}.{} ... {}",
+                test.actor,
+                test.method,
+                results.join(", ")
+            )?;
+        }
+
+        writeln!(f)?;
+        for (target, outcomes) in &self.targets {
+            let count =
+                |passed: fn(&Outcome) -> bool| outcomes.iter().filter(|o| passed(o)).count();
+            writeln!(
+                f,
+                "{}: {} passed, {} failed, {} skipped",
+                target,
+                count(|o| matches!(o, Outcome::Passed)),
+                count(|o| matches!(o, Outcome::Failed | Outcome::Error(_))),
+                count(|o| matches!(o, Outcome::Skipped(_)))
+            )?;
+        }
+
+        let divergent: Vec<String> = self
+            .divergent()
+            .into_iter()
+            .map(|i| format!("{}.{}", self.tests[i].actor, self.tests[i].method))
+            .collect();
+        if !divergent.is_empty() {
+            writeln!(
+                f,
+                "{} test(s) diverged between targets: {}",
+                divergent.len(),
+                divergent.join(", ")
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer;
+    use crate::parser::Parser;
+
+    fn parse(source: &str) -> Actor {
+        let (tokens, _) = lexer::tokenize_recovering(source);
+        Parser::with_lines(lexer::token_lines(source, tokens))
+            .parse_actor()
+            .unwrap()
+    }
+
+    fn case(method: &str) -> TestCase {
+        TestCase {
+            actor: "Math".to_string(),
+            method: method.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_parse_targets() {
+        assert_eq!(
+            TestTarget::parse_list("wasi, interp,wasi").unwrap(),
+            vec![TestTarget::Wasi, TestTarget::Interp]
+        );
+        assert_eq!(
+            TestTarget::parse_list("browser-sim").unwrap(),
+            vec![TestTarget::BrowserSim]
+        );
+        let error = TestTarget::parse_list("wasi,jvm").unwrap_err();
+        assert!(error.contains("'jvm'") && error.contains("interp, wasi, browser-sim"));
+    }
+
+    #[test]
+    fn test_run_interp() {
+        let actor = parse(
+            "actor Math {
+                pure func double(n: Int) -> Int {
+                    return n * 2
+                }
+
+                pure func testDouble() -> Bool {
+                    return double(21) == 42
+                }
+
+                pure func testWrong() -> Bool {
+                    return double(1) == 3
+                }
+
+                pure func testTable() -> Bool {
+                    let table = [1, 2]
+                    return table[2] == 0
+                }
+
+                func testEffects() -> Bool {
+                    return double(0) == 0
+                }
+
+                pure func testWithInput(n: Int) -> Bool {
+                    return n == 0
+                }
+            }",
+        );
+        let tests = test_cases(&[&actor]);
+        let names: Vec<&str> = tests.iter().map(|test| test.method.as_str()).collect();
+        assert_eq!(
+            names,
+            ["testDouble", "testWrong", "testTable", "testEffects"]
+        );
+
+        let outcomes = run_interp(&[&actor], &tests);
+        assert_eq!(outcomes[0], Outcome::Passed);
+        assert_eq!(outcomes[1], Outcome::Failed);
+        assert!(matches!(&outcomes[2], Outcome::Error(e) if e.contains("out of bounds")));
+        assert_eq!(
+            outcomes[3],
+            Outcome::Skipped("not a pure method".to_string())
+        );
+    }
+
+    #[test]
+    fn test_engine_output() {
+        assert_eq!(wasmtime_outcome("1"), Outcome::Passed);
+        assert_eq!(wasmtime_outcome("0"), Outcome::Failed);
+        assert!(matches!(wasmtime_outcome(""), Outcome::Error(_)));
+        assert_eq!(
+            node_outcomes("ok\nfail\nerror unreachable\n", 4),
+            vec![
+                Outcome::Passed,
+                Outcome::Failed,
+                Outcome::Error("unreachable".to_string()),
+                Outcome::Error("no result".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_report() {
+        let mut report = Report::new(vec![
+            case("testSame"),
+            case("testDiverges"),
+            case("testPure"),
+        ]);
+        report.add(
+            TestTarget::Interp,
+            vec![
+                Outcome::Passed,
+                Outcome::Passed,
+                Outcome::Skipped("not a pure method".to_string()),
+            ],
+        );
+        report.add(
+            TestTarget::Wasi,
+            vec![Outcome::Passed, Outcome::Failed, Outcome::Passed],
+        );
+        report.add(
+            TestTarget::BrowserSim,
+            vec![
+                Outcome::Passed,
+                Outcome::Error("unreachable".to_string()),
+                Outcome::Passed,
+            ],
+        );
+        assert_eq!(report.divergent(), [1]);
+        assert!(!report.passed());
+        assert_eq!(
+            report.to_string(),
+            "test Math.testSame ... interp: ok, wasi: ok, browser-sim: ok\n\
+             test Math.testDiverges ... interp: ok, wasi: FAILED, browser-sim: ERROR (unreachable)\n\
+             test Math.testPure ... interp: skipped (not a pure method), wasi: ok, browser-sim: ok\n\
+             \n\
+             interp: 2 passed, 0 failed, 1 skipped\n\
+             wasi: 2 passed, 1 failed, 0 skipped\n\
+             browser-sim: 2 passed, 1 failed, 0 skipped\n\
+             1 test(s) diverged between targets: Math.testDiverges\n"
+        );
+
+        let mut report = Report::new(vec![case("testPure")]);
+        report.add(
+            TestTarget::Interp,
+            vec![Outcome::Skipped("not a pure method".to_string())],
+        );
+        report.add(TestTarget::Wasi, vec![Outcome::Passed]);
+        assert!(report.divergent().is_empty());
+        assert!(report.passed());
+    }
+}
@@ -0,0 +1,489 @@
+//! Typestate checking of actor protocols.
+//! An actor declared with `@protocol("open write* close")` may only have its
+//! methods called in that order. Every method body that calls a method named
+//! in the protocol drives a session: along every path its calls must follow
+//! the protocol from the start and reach its end before the method returns.
+//! Only direct calls are followed, not the calls the callee makes in turn.
+
+use crate::ast::{Expression, LiteralValue, MethodBody, Protocol, Repeat, Statement};
+use crate::semantic::SemanticError;
+use std::collections::{BTreeSet, HashMap};
+
+/// Positions in the protocol a session can be at, or `None` on paths that
+/// have not called any of its methods yet
+type Session = Option<BTreeSet<usize>>;
+
+/// Sessions of every path reaching a point of the body
+type States = BTreeSet<Session>;
+
+/// Protocol with `+` steps expanded to a required step and a repeated one
+struct Automaton {
+    steps: Vec<(String, Repeat)>,
+}
+
+impl Automaton {
+    fn new(protocol: &Protocol) -> Self {
+        let mut steps = Vec::new();
+        for (name, repeat) in &protocol.steps {
+            match repeat {
+                Repeat::OneOrMore => {
+                    steps.push((name.clone(), Repeat::Once));
+                    steps.push((name.clone(), Repeat::Many));
+                }
+                _ => steps.push((name.clone(), *repeat)),
+            }
+        }
+        Automaton { steps }
+    }
+
+    fn involves(&self, method: &str) -> bool {
+        self.steps.iter().any(|(name, _)| name == method)
+    }
+
+    /// `positions` together with those reached by skipping optional steps
+    fn closure(&self, positions: impl IntoIterator<Item = usize>) -> BTreeSet<usize> {
+        let mut closure = BTreeSet::new();
+        for mut position in positions {
+            closure.insert(position);
+            while matches!(
+                self.steps.get(position),
+                Some((_, Repeat::Optional | Repeat::Many))
+            ) {
+                position += 1;
+                closure.insert(position);
+            }
+        }
+        closure
+    }
+
+    fn start(&self) -> BTreeSet<usize> {
+        self.closure([0])
+    }
+
+    /// Positions after calling `method`; empty if the call is not allowed
+    fn step(&self, positions: &BTreeSet<usize>, method: &str) -> BTreeSet<usize> {
+        self.closure(
+            positions
+                .iter()
+                .filter_map(|&position| match self.steps.get(position) {
+                    Some((name, Repeat::Many)) if name == method => Some(position),
+                    Some((name, _)) if name == method => Some(position + 1),
+                    _ => None,
+                }),
+        )
+    }
+
+    fn accepts(&self, positions: &BTreeSet<usize>) -> bool {
+        positions.contains(&self.steps.len())
+    }
+
+    /// The calls allowed next, e.g. "`write` or `close`"
+    fn expected(&self, positions: &BTreeSet<usize>) -> String {
+        let mut names: Vec<&str> = Vec::new();
+        for (name, _) in positions.iter().filter_map(|&p| self.steps.get(p)) {
+            if !names.contains(&name.as_str()) {
+                names.push(name);
+            }
+        }
+        let names: Vec<String> = names.iter().map(|name| format!("`{}`", name)).collect();
+        match names.as_slice() {
+            [] => "no further calls".to_string(),
+            [name] => name.clone(),
+            [rest @ .., last] => format!("{} or {}", rest.join(", "), last),
+        }
+    }
+}
+
+/// Calls that may leave a loop, collected while its body is checked
+struct Loop {
+    label: Option<String>,
+    breaks: States,
+    continues: States,
+}
+
+struct Checker<'a> {
+    protocol: &'a Protocol,
+    automaton: Automaton,
+    method: &'a str,
+    loops: Vec<Loop>,
+    /// Index of each protocol call among the calls to the same method, in
+    /// source order; loop bodies are walked more than once
+    occurrences: HashMap<*const Expression, usize>,
+    counts: HashMap<&'a str, usize>,
+}
+
+/// Checks that the calls `method` makes to methods named in `protocol`
+/// follow it on every path through `body`
+pub fn check(protocol: &Protocol, method: &str, body: &MethodBody) -> Result<(), SemanticError> {
+    let mut checker = Checker {
+        protocol,
+        automaton: Automaton::new(protocol),
+        method,
+        loops: Vec::new(),
+        occurrences: HashMap::new(),
+        counts: HashMap::new(),
+    };
+    let states = checker.body(body, BTreeSet::from([None]))?;
+    checker.finish(&states)
+}
+
+impl<'a> Checker<'a> {
+    fn body(&mut self, body: &'a MethodBody, mut states: States) -> Result<States, SemanticError> {
+        for statement in &body.statements {
+            states = self.statement(statement, states)?;
+        }
+        Ok(states)
+    }
+
+    fn statement(
+        &mut self,
+        statement: &'a Statement,
+        states: States,
+    ) -> Result<States, SemanticError> {
+        match statement {
+            Statement::Return(value) => {
+                let states = self.expression(value, states)?;
+                self.finish(&states)?;
+                Ok(BTreeSet::new())
+            }
+            Statement::Expression(value)
+            | Statement::Yield(value)
+            | Statement::Let { value, .. }
+            | Statement::Log { message: value, .. }
+            | Statement::Publish { value, .. } => self.expression(value, states),
+            Statement::Break(label) | Statement::Continue(label) => {
+                let target = self
+                    .loops
+                    .iter_mut()
+                    .rev()
+                    .find(|l| label.is_none() || l.label.as_ref() == label.as_ref());
+                if let Some(target) = target {
+                    if matches!(statement, Statement::Break(_)) {
+                        target.breaks.extend(states);
+                    } else {
+                        target.continues.extend(states);
+                    }
+                }
+                Ok(BTreeSet::new())
+            }
+            Statement::If {
+                condition,
+                then_body,
+                else_body,
+            } => {
+                let states = self.expression(condition, states)?;
+                let mut joined = self.body(then_body, states.clone())?;
+                match else_body {
+                    Some(else_body) => joined.extend(self.body(else_body, states)?),
+                    None => joined.extend(states),
+                }
+                Ok(joined)
+            }
+            // match は網羅的なので、どれか一つの腕を通る
+            Statement::Match { value, arms } => {
+                let states = self.expression(value, states)?;
+                let mut joined = BTreeSet::new();
+                for (_, body) in arms {
+                    joined.extend(self.body(body, states.clone())?);
+                }
+                Ok(joined)
+            }
+            Statement::While {
+                label,
+                condition,
+                body,
+            } => {
+                // 条件は毎回評価され、偽になったときだけループを抜ける
+                let endless = matches!(condition, Expression::Literal(LiteralValue::Bool(true)));
+                let head = self.iterate(label, states, |checker, head| {
+                    let entered = checker.expression(condition, head)?;
+                    checker.body(body, entered)
+                })?;
+                let mut exit = if endless {
+                    BTreeSet::new()
+                } else {
+                    self.expression(condition, head)?
+                };
+                exit.extend(self.loops.pop().map(|l| l.breaks).unwrap_or_default());
+                Ok(exit)
+            }
+            Statement::For {
+                label,
+                start,
+                end,
+                body,
+                ..
+            } => {
+                let states = self.expression(start, states)?;
+                let states = self.expression(end, states)?;
+                let mut exit =
+                    self.iterate(label, states, |checker, head| checker.body(body, head))?;
+                exit.extend(self.loops.pop().map(|l| l.breaks).unwrap_or_default());
+                Ok(exit)
+            }
+            Statement::Assign { target, value, .. } => {
+                let states = self.expression(target, states)?;
+                self.expression(value, states)
+            }
+            Statement::Subscribe(_) => Ok(states),
+        }
+    }
+
+    /// Runs a loop body until the sessions at its head stop changing, leaving
+    /// the loop on the stack so that the caller can collect its `break`s
+    fn iterate(
+        &mut self,
+        label: &Option<String>,
+        states: States,
+        mut pass: impl FnMut(&mut Self, States) -> Result<States, SemanticError>,
+    ) -> Result<States, SemanticError> {
+        self.loops.push(Loop {
+            label: label.clone(),
+            breaks: BTreeSet::new(),
+            continues: BTreeSet::new(),
+        });
+        let mut head = states;
+        loop {
+            let mut next = pass(self, head.clone())?;
+            if let Some(current) = self.loops.last_mut() {
+                next.append(&mut current.continues);
+            }
+            next.extend(head.iter().cloned());
+            if next == head {
+                return Ok(head);
+            }
+            head = next;
+        }
+    }
+
+    fn expression(
+        &mut self,
+        expression: &'a Expression,
+        mut states: States,
+    ) -> Result<States, SemanticError> {
+        match expression {
+            Expression::Call { callee, args, .. } => {
+                let occurrence = self.occurrence(expression, callee);
+                for arg in args {
+                    states = self.expression(arg, states)?;
+                }
+                match occurrence {
+                    Some(occurrence) => self.call(callee, occurrence, states),
+                    None => Ok(states),
+                }
+            }
+            Expression::BinaryOp { left, right, .. } => {
+                let states = self.expression(left, states)?;
+                self.expression(right, states)
+            }
+            Expression::Index { base, index } => {
+                let states = self.expression(base, states)?;
+                self.expression(index, states)
+            }
+            Expression::Conversion { value, .. }
+            | Expression::Member { base: value, .. }
+            | Expression::Unwrap(value)
+            | Expression::Await(value) => self.expression(value, states),
+            // 既定値は値が nil のときだけ評価される
+            Expression::Coalesce { value, default } => {
+                let states = self.expression(value, states)?;
+                let mut joined = self.expression(default, states.clone())?;
+                joined.extend(states);
+                Ok(joined)
+            }
+            Expression::Tuple(items) | Expression::ArrayLiteral(items) => {
+                for item in items {
+                    states = self.expression(item, states)?;
+                }
+                Ok(states)
+            }
+            Expression::Record(entries)
+            | Expression::StructLiteral {
+                fields: entries, ..
+            } => {
+                for (_, value) in entries {
+                    states = self.expression(value, states)?;
+                }
+                Ok(states)
+            }
+            Expression::Atomic { args, .. }
+            | Expression::Random { args, .. }
+            | Expression::EnumCase { args, .. } => {
+                for arg in args {
+                    states = self.expression(arg, states)?;
+                }
+                Ok(states)
+            }
+            Expression::Match { value, arms } => {
+                let states = self.expression(value, states)?;
+                let mut joined = BTreeSet::new();
+                for (_, result) in arms {
+                    joined.extend(self.expression(result, states.clone())?);
+                }
+                Ok(joined)
+            }
+            // comptime 呼び出しはコンパイル時に評価され、実行時には呼ばれない
+            Expression::Comptime { .. }
+            | Expression::Literal(_)
+            | Expression::Variable(_)
+            | Expression::Field(_)
+            | Expression::Inout(_)
+            | Expression::Config(_)
+            | Expression::Nil => Ok(states),
+        }
+    }
+
+    /// Source-order index of a call among the calls to the same protocol method
+    fn occurrence(&mut self, call: &'a Expression, callee: &'a str) -> Option<usize> {
+        if !self.automaton.involves(callee) {
+            return None;
+        }
+        let counts = &mut self.counts;
+        Some(
+            *self
+                .occurrences
+                .entry(call as *const Expression)
+                .or_insert_with(|| {
+                    let count = counts.entry(callee).or_default();
+                    *count += 1;
+                    *count - 1
+                }),
+        )
+    }
+
+    fn call(
+        &self,
+        callee: &str,
+        occurrence: usize,
+        states: States,
+    ) -> Result<States, SemanticError> {
+        let mut next = BTreeSet::new();
+        for session in states {
+            let positions = session.unwrap_or_else(|| self.automaton.start());
+            let after = self.automaton.step(&positions, callee);
+            if after.is_empty() {
+                return Err(SemanticError::ProtocolError {
+                    message: format!(
+                        "{} cannot call {} here; protocol \"{}\" expects {}",
+                        self.method,
+                        callee,
+                        self.protocol,
+                        self.automaton.expected(&positions)
+                    ),
+                    call: Some((callee.to_string(), occurrence)),
+                });
+            }
+            next.insert(Some(after));
+        }
+        Ok(next)
+    }
+
+    /// Fails if a path can return with its session incomplete
+    fn finish(&self, states: &States) -> Result<(), SemanticError> {
+        let incomplete = states
+            .iter()
+            .flatten()
+            .find(|positions| !self.automaton.accepts(positions));
+        match incomplete {
+            Some(positions) => Err(SemanticError::ProtocolError {
+                message: format!(
+                    "{} can return before completing protocol \"{}\"; it still expects {}",
+                    self.method,
+                    self.protocol,
+                    self.automaton.expected(positions)
+                ),
+                call: None,
+            }),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer;
+    use crate::parser::Parser;
+
+    /// Checks every method of `source` against its actor's protocol
+    fn check_source(source: &str) -> Result<(), String> {
+        let tokens = lexer::tokenize_with_lines(source).unwrap();
+        let actor = Parser::with_lines(tokens).parse_actor().unwrap();
+        let protocol = actor.protocol.as_ref().unwrap();
+        for method in &actor.methods {
+            if let Some(body) = &method.body {
+                check(protocol, &method.name, body).map_err(|e| e.to_string())?;
+            }
+        }
+        Ok(())
+    }
+
+    fn actor(session: &str) -> String {
+        format!(
+            r#"@protocol("open write* close")
+            actor File {{
+                func open() {{}}
+                func write(n: Int) {{}}
+                func close() {{}}
+                func session(n: Int) {{
+                    {}
+                }}
+            }}"#,
+            session
+        )
+    }
+
+    #[test]
+    fn test_sessions_following_the_protocol() {
+        for session in [
+            "open() write(1) write(2) close()",
+            "open() close()",
+            "open() if n > 0 { write(n) } close()",
+            "open() for i in 0..n { write(i) } close()",
+            "open() while n > 0 { write(n) if n == 3 { break } } close()",
+            "let unrelated = n + 1",
+        ] {
+            assert_eq!(check_source(&actor(session)), Ok(()), "{}", session);
+        }
+    }
+
+    #[test]
+    fn test_violations() {
+        let error = check_source(&actor("write(1) close()")).unwrap_err();
+        assert_eq!(
+            error,
+            "Protocol error: session cannot call write here; protocol \"open write* close\" expects `open`"
+        );
+
+        let error = check_source(&actor("open() close() write(1)")).unwrap_err();
+        assert!(error.ends_with("expects no further calls"), "{}", error);
+
+        let error = check_source(&actor("open() if n > 0 { close() }")).unwrap_err();
+        assert!(
+            error.contains("session can return before completing"),
+            "{}",
+            error
+        );
+        assert!(
+            error.ends_with("it still expects `write` or `close`"),
+            "{}",
+            error
+        );
+
+        let error = check_source(&actor("open() for i in 0..n { close() }")).unwrap_err();
+        assert!(error.contains("cannot call close here"), "{}", error);
+    }
+
+    #[test]
+    fn test_occurrences() {
+        let source = actor("open() write(1) close() close()");
+        let tokens = lexer::tokenize_with_lines(&source).unwrap();
+        let actor = Parser::with_lines(tokens).parse_actor().unwrap();
+        let body = actor.methods[3].body.as_ref().unwrap();
+        let error = check(actor.protocol.as_ref().unwrap(), "session", body).unwrap_err();
+        assert!(matches!(
+            error,
+            SemanticError::ProtocolError { call: Some((ref callee, 1)), .. } if callee == "close"
+        ));
+    }
+}
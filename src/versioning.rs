@@ -0,0 +1,186 @@
+//! API versions of distributed actors.
+//! `@since(2)` marks a method added in version 2 of an actor's API, and
+//! `@removed(3)` one removed in version 3 whose body stays for older clients.
+//! The actor's current version is the highest version either attribute names.
+//! A client compiled against version `v` numbers messages by position among
+//! the methods that version declared, so the generated `<Actor>.deliver`
+//! translates the tag a client sends into the method the tag meant then, or
+//! rejects a call that version cannot make instead of misreading its payload.
+//! The module's `replica.versions` custom section records the tags of every
+//! version, so hosts and tooling can translate calls the same way.
+
+use crate::ast::Actor;
+use crate::capability::custom_sections;
+use serde_json::{json, Value};
+
+/// Custom section listing the methods each API version can call, one JSON
+/// object per line
+pub const VERSIONS_SECTION: &str = "replica.versions";
+
+/// Methods clients of one API version of an actor can call
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ApiVersion {
+    pub actor: String,
+    pub version: u32,
+    /// Each callable method with the message tag clients of the version send
+    /// for it, in tag order
+    pub methods: Vec<(String, u32)>,
+}
+
+/// Whether any method of the actor is marked `@since` or `@removed`
+pub fn is_versioned(actor: &Actor) -> bool {
+    actor
+        .methods
+        .iter()
+        .any(|method| method.since.is_some() || method.removed.is_some())
+}
+
+/// Newest API version of the actor; 1 when it is not versioned
+pub fn current_version(actor: &Actor) -> u32 {
+    actor
+        .methods
+        .iter()
+        .flat_map(|method| [method.since, method.removed])
+        .flatten()
+        .fold(1, u32::max)
+}
+
+/// Every API version of the actor, oldest first. A method keeps its tag slot
+/// once it is removed, so at the current version the tags are the message
+/// tags the code generator gives: positions among the async methods that are
+/// not streams.
+pub fn api_versions(actor: &Actor) -> Vec<ApiVersion> {
+    (1..=current_version(actor))
+        .map(|version| {
+            let methods = actor
+                .methods
+                .iter()
+                .filter(|method| method.is_async && method.stream_item().is_none())
+                // 後から追加されたメソッドはその版のタグを持たない
+                .filter(|method| method.since.unwrap_or(1) <= version)
+                .enumerate()
+                .filter(|(_, method)| method.is_available_in(version))
+                .map(|(tag, method)| (method.name.clone(), tag as u32))
+                .collect();
+            ApiVersion {
+                actor: actor.name.clone(),
+                version,
+                methods,
+            }
+        })
+        .collect()
+}
+
+/// Contents of the `replica.versions` section. Lines are self-contained, so
+/// the sections of linked modules concatenate.
+pub fn section(versions: &[ApiVersion]) -> String {
+    versions
+        .iter()
+        .map(|version| {
+            let methods: Vec<Value> = version
+                .methods
+                .iter()
+                .map(|(method, tag)| json!({ "method": method, "tag": tag }))
+                .collect();
+            let entry = json!({
+                "actor": version.actor,
+                "version": version.version,
+                "methods": methods,
+            });
+            entry.to_string() + "\n"
+        })
+        .collect()
+}
+
+/// Reads the API versions recorded in a WebAssembly binary
+pub fn read_api_versions(wasm: &[u8]) -> Result<Vec<ApiVersion>, String> {
+    let mut versions = Vec::new();
+    for payload in custom_sections(wasm, VERSIONS_SECTION)? {
+        let text = std::str::from_utf8(payload)
+            .map_err(|_| format!("{} section is not UTF-8", VERSIONS_SECTION))?;
+        for line in text.lines().filter(|line| !line.trim().is_empty()) {
+            let malformed = || format!("malformed {} entry: {}", VERSIONS_SECTION, line);
+            let entry: Value = serde_json::from_str(line).map_err(|_| malformed())?;
+            let number = |value: &Value| {
+                value
+                    .as_u64()
+                    .and_then(|number| u32::try_from(number).ok())
+                    .ok_or_else(malformed)
+            };
+            let methods = entry["methods"]
+                .as_array()
+                .ok_or_else(malformed)?
+                .iter()
+                .map(|method| {
+                    let name = method["method"].as_str().ok_or_else(malformed)?;
+                    Ok((name.to_string(), number(&method["tag"])?))
+                })
+                .collect::<Result<_, String>>()?;
+            versions.push(ApiVersion {
+                actor: entry["actor"].as_str().ok_or_else(malformed)?.to_string(),
+                version: number(&entry["version"])?,
+                methods,
+            });
+        }
+    }
+    Ok(versions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_section_round_trip() {
+        let tokens = crate::lexer::tokenize(
+            "actor Bank {
+    @removed(3)
+    async func open(name: String) {}
+
+    func balances() -> AsyncStream<Int> {
+        yield 0
+    }
+
+    @since(2)
+    async func deposit(amount: Int) {}
+
+    async func audit() {}
+}",
+        )
+        .unwrap();
+        let actor = crate::parser::Parser::new(tokens).parse_actor().unwrap();
+        assert!(is_versioned(&actor));
+        assert_eq!(current_version(&actor), 3);
+        let versions = api_versions(&actor);
+        let tags = |version: usize| {
+            versions[version]
+                .methods
+                .iter()
+                .map(|(method, tag)| (method.as_str(), *tag))
+                .collect::<Vec<_>>()
+        };
+        // 版 1 のクライアントは deposit を知らず、audit をタグ 1 で送る
+        assert_eq!(tags(0), [("open", 0), ("audit", 1)]);
+        assert_eq!(tags(1), [("open", 0), ("deposit", 1), ("audit", 2)]);
+        assert_eq!(tags(2), [("deposit", 1), ("audit", 2)]);
+
+        let payload = section(&versions);
+        let mut body = vec![VERSIONS_SECTION.len() as u8];
+        body.extend_from_slice(VERSIONS_SECTION.as_bytes());
+        body.extend_from_slice(payload.as_bytes());
+        let mut wasm = b"\0asm\x01\0\0\0".to_vec();
+        assert!((128..1 << 14).contains(&body.len()));
+        wasm.extend([0, body.len() as u8 | 0x80, (body.len() >> 7) as u8]);
+        wasm.extend(body);
+        assert_eq!(read_api_versions(&wasm).unwrap(), versions);
+    }
+
+    #[test]
+    fn test_unversioned_actor() {
+        let tokens = crate::lexer::tokenize("actor Bank {\n    async func audit() {}\n}").unwrap();
+        let actor = crate::parser::Parser::new(tokens).parse_actor().unwrap();
+        assert!(!is_versioned(&actor));
+        assert_eq!(current_version(&actor), 1);
+        assert_eq!(api_versions(&actor)[0].methods, [("audit".to_string(), 0)]);
+    }
+}